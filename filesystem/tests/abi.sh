@@ -0,0 +1,5 @@
+echo "abi: uname_abi_mismatch is uname built with the abi_mismatch_test feature of kernel_user_link"
+uname_abi_mismatch
+expect 126 "a program of the next ABI version refuses to run (ABI version mismatch)"
+uname
+expect 0 "uname of the same version"
@@ -0,0 +1,8 @@
+echo "ahci: run with: shell < /tests/ahci.sh, as root, in qemu with a scratch disk on AHCI: -drive if=none,id=d0,format=raw,file=scratch.img -device ahci,id=ahci -device ide-hd,drive=d0,bus=ahci.0 (qemu-img create -f raw scratch.img 64M)"
+echo scatter > /devices/ahci0_info
+expect 0 "scattered reads (8 reads of different sizes in flight, waited in another order)"
+echo "bench 32" > /devices/ahci0_info
+expect 0 "sequential reads of 32MiB, one in flight then eight"
+echo "bench lots" > /devices/ahci0_info
+expect 1 "bench bad size (invalid data)"
+cat /devices/ahci0_info | expect ~ "ncq: yes, queue depth *" ~ "scatter: ok, * in flight" ~ "bench: * sequential, qd1 * KiB/s, qd8 * KiB/s*" !~ "interrupts: 0" "ahci0_info (qd8 is faster on NCQ, the interrupts come on the routed line)"
@@ -0,0 +1,4 @@
+echo "boot_memory: boot with: nomemmap nocmosmem mem=256M, then run with: shell < /tests/boot_memory.sh"
+cat /devices/boot_log | expect ~ "physical memory from the `mem=` option" "boot log (the 640KB and the 255MB after 1MB)"
+lowmem
+expect 0 "lowmem (signaled before running out of the 256MB)"
@@ -0,0 +1,2 @@
+echo "boot_tasks: run with: shell < /tests/boot_tasks.sh, with -smp 2 or more, once normally, once with boot_fail=mouse, and once with boot_tasks=sequential to compare the times"
+cat /devices/boot_tasks | expect ~ "usb (optional): * failed: no USB controller" ~ "ide_primary (optional): start * on cpu *, done, after pci" ~ "ide_secondary (optional): start * on cpu *, after pci" ~ "ide: * done, after ide_primary, ide_secondary" ~ "block: * done, after pci, ide" ~ "critical path: " ~ "on * cpus: * instead of * one after the other, * saved" !~ "skipped" "boot tasks (every other task done, the IDE channels probed in their own tasks on the CPUs, an empty secondary fails without stopping the boot, the time saved against one after the other, 1 cpu with boot_tasks=sequential; mouse failed: forced by boot_fail with boot_fail=mouse)"
@@ -0,0 +1,14 @@
+echo "clock: run with: shell < /tests/clock.sh, as root"
+clock
+expect 0 "clock (both clocks, the syscall and the time page close, or no time page)"
+clock --compare
+expect 0 "clock compare (many samples, some across time slices, none out of order)"
+clock --elapsed 200 | expect ~ "std::time::Instant: 2*ms elapsed, slept 200ms" ~ "std::time::SystemTime: 2*ms elapsed" !~ "[!] clock" "clock elapsed (std::time measures the sleep, and agrees with the clocks)"
+clock --set-loop 2000 &
+clock --torn 2000000
+expect 0 "clock torn (no torn reads while the realtime is set, some retried)"
+clock --bench 100000
+expect 0 "clock bench (the time page much faster than the syscall)"
+cat /devices/time | expect ~ "monotonic: " ~ "realtime: " ~ "time page: yes" ~ "syncs: " "devices time (the realtime back to now after the set loop, many syncs)"
+echo "realtime soon" > /devices/time
+expect 1 "devices time bad write (invalid data)"
@@ -0,0 +1,28 @@
+echo "cp: run with: shell < /tests/cp.sh, the copies must have the checksum of /shell, and -v shows the syscalls and time of each"
+cksum /shell
+expect 0 "cksum /shell"
+cp -v /shell /tmp/cp_fat
+expect 0 "cp FAT -> ramfs"
+cp -v /tmp/cp_fat /tmp/cp_ram
+expect 0 "cp inside ramfs"
+cp -v -n /tmp/cp_fat /tmp/cp_naive
+expect 0 "cp -n read/write loop (with many more syscalls than the ones above)"
+mount -t ramfs scratch /mnt
+cp -v /tmp/cp_ram /mnt/cp_other
+expect 0 "cp between two ramfs"
+cksum /tmp/cp_fat /tmp/cp_ram /tmp/cp_naive /mnt/cp_other
+expect 0 "cksum copies (4 times the checksum of /shell)"
+cp -v /tmp/cp_ram /cp_ram
+expect 0 "cp ramfs -> FAT (the file is created, and its clusters allocated)"
+cp /message.txt /cp_message.txt
+cat /cp_message.txt | expect ~ "Hello." ~ "Nice stuff." "cp FAT -> FAT (the content of /message.txt)"
+cksum /shell /cp_ram
+expect 0 "cksum FAT copy (the checksum of /shell twice)"
+cp -v /devices/pit /tmp/cp_device
+expect 0 "cp from a device (with read/write)"
+cp /message.txt /tmp/cp_ram
+cksum /message.txt /tmp/cp_ram
+expect 0 "cp over a longer file (the same checksum and size twice)"
+rm /tmp/cp_fat /tmp/cp_ram /tmp/cp_naive /tmp/cp_device /mnt/cp_other /cp_ram /cp_message.txt
+mount -u /mnt
+expect 0 "cleanup"
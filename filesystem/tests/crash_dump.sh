@@ -0,0 +1,6 @@
+crashinfo --crash
+expect 139 "crash (killed by the page fault)"
+crashinfo --test
+expect 0 "crashinfo --test"
+crashinfo --core-test
+expect 0 "crashinfo --core-test (the global found in /tmp/core.<pid>, then a core cut to the limit)"
@@ -0,0 +1,26 @@
+echo "flock: run with: shell < /tests/flock.sh, the files it makes are removed first so it can run again"
+rm /flock_test
+rm /flock_wa
+rm /flock_wb
+rm /flock_r1
+rm /flock_r2
+rm /flock_w
+rm /flock_go
+flock -m /flock_wa -a a:50 /flock_test &
+flock -m /flock_wb -a b:50 /flock_test &
+flock -s -W /flock_wa -W /flock_wb -k 100 /flock_test
+expect 0 "flock writers (100 lines, all the a lines then all the b lines or the other way)"
+flock -s -u -m /flock_r1 -h /flock_go /flock_test &
+flock -s -e -m /flock_r2 -h /flock_go /flock_test &
+flock -s -W /flock_r1 -W /flock_r2 /flock_test
+expect 0 "flock third reader (taken next to the other two readers)"
+flock -n /flock_test
+expect 1 "flock nonblocking writer (WouldBlock right away)"
+cat /devices/file_locks | expect ~ "/flock_test: shared " ~ "(flock)/" "file_locks (the two readers as pid(flock)/fd)"
+flock -m /flock_w /flock_test &
+echo go > /flock_go
+flock -s -W /flock_w /flock_test
+expect 0 "flock writer after readers (the writer waited for both readers, one unlocking and one exiting, and this reader for the writer)"
+cat /devices/file_locks | expect !~ "/flock_test" "file_locks empty"
+flock -n /devices/file_locks
+expect 1 "flock device (NotSupported)"
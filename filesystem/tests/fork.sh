@@ -0,0 +1,16 @@
+fork
+expect 0 "fork (4 children see the memory of the parent, and their writes stay their own)"
+fork -n 20
+expect 0 "fork many (the same with 20 children sharing the pages)"
+fork -n 0
+expect 1 "fork no children (usage)"
+fork -w
+expect 0 "fork wait (the exit codes, kept until waited for, returned once to the parent only)"
+fork -e /echo exec ok
+expect 0 "fork exec (the child printed \`exec ok\` as echo, with the files it had)"
+fork -e /fork -n 0
+expect 1 "fork exec code (the exit code of the new program, waited for with the same pid)"
+fork -e /missing
+expect 127 "fork exec missing (exec fails, the child keeps running its program)"
+echo "exec /echo replaced" | shell
+expect 0 "shell exec (the shell was replaced by echo, which printed \`replaced\`)"
@@ -0,0 +1,29 @@
+mkdir /tmp/fs_errors
+mkdir /tmp/fs_errors/dir /tmp/fs_errors/other
+expect 0 "mkdir"
+mkdir /tmp/fs_errors/dir
+expect 1 "mkdir existing (already exists)"
+mkdir /tmp/fs_errors/missing/dir
+expect 1 "mkdir in missing parent (not found)"
+rm /tmp/fs_errors/missing
+expect 1 "rm missing source (not found)"
+rm /tmp/fs_errors/dir
+expect 1 "rm directory (is a directory)"
+mv /tmp/fs_errors/missing /tmp/fs_errors/new
+expect 1 "mv missing source (not found)"
+mv /tmp/fs_errors/dir /tmp/fs_errors/other
+expect 1 "mv existing destination (already exists)"
+mv /tmp/fs_errors/dir /tmp/fs_errors/dir/inside
+expect 1 "mv into itself (invalid path)"
+mv /tmp/fs_errors/dir /fs_errors_dir
+expect 1 "mv across mounts (cross device)"
+mv /tmp/fs_errors/other /tmp/fs_errors/dir/other
+expect 0 "mv"
+rmdir /tmp/fs_errors/dir
+expect 1 "rmdir not empty (not empty)"
+rmdir /tmp/fs_errors/missing
+expect 1 "rmdir missing (not found)"
+rmdir /tmp
+expect 1 "rmdir mount point (busy)"
+rmdir /tmp/fs_errors/dir/other /tmp/fs_errors/dir /tmp/fs_errors
+expect 0 "cleanup"
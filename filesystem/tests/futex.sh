@@ -0,0 +1,4 @@
+futex --test
+expect 0 "futex --test (the rules, woken and interrupted waits, no increment lost with the mutex, the condvar queue, no CPU time used while blocked)"
+futex x
+expect 1 "futex bad argument (usage)"
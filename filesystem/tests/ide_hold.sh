@@ -0,0 +1,5 @@
+echo "ide_hold: run with: shell < /tests/ide_hold.sh, with the root on IDE, and the irqoff feature for the last check"
+cksum /shell
+expect 0 "cksum /shell (read from the disk, or from the page cache)"
+cat /devices/ide0_info | expect ~ "channel held: worst *us" "ide0_info (the longest command, the interrupts stay enabled for it)"
+cat /devices/irq_off | expect ~ "[push_cli]" !~ "ide.rs" "irq_off (the IDE driver is not the worst section anymore)"
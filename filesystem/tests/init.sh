@@ -0,0 +1,6 @@
+echo "init: run by /init_test when booting with init=/init_test, see its doc for the two configurations"
+expect = /:/bin:/initrd "$PATH" "PATH from init"
+expect = amjad_os "$TERM" "TERM from init"
+uname
+expect 0 "uname (found through PATH)"
+cat /devices/processes | expect ~ "PPID" ~ " shell " "processes (the shell with PPID 0)"
@@ -0,0 +1,19 @@
+echo "input: run with: shell < /tests/input.sh, from tty1, the scripts are in /tests/*.input"
+input_script -t /devices/tty2 xz /tests/vt_switch.input
+expect 0 "input vt switch (switched to tty2 and back, the line edited there)"
+cat /devices/vt_dump | expect ~ "== tty1 (active) ==" ~ "xz" "vt_dump (xz on tty2, then back on tty1)"
+input_script /tests/shell_transcript.input
+shell < /devices/tty3
+expect 0 "input shell transcript (typed on tty3 printed by cat)"
+cat /input_transcript | expect ~ "typed on tty3" "input transcript file"
+rm /input_transcript
+cksum /kernel &
+cksum /kernel &
+input_script -t /devices/tty2 "the quick brown fox jumps over the lazy dog 0123456789 THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG" /tests/input_race.input
+expect 0 "input during switches (every char in order, while the two cksum run)"
+input_script /tests/mouse.input
+mouse 4
+expect 0 "input mouse (10 -5 0 000, 0 0 0 001, 0 0 0 000, 0 0 1 000)"
+cat /devices/input_inject | expect ~ "pending: 0" ~ "dropped: 0" "input_inject (with a mouse)"
+echo "key hold 0x1E" > /devices/input_inject
+expect 1 "input bad record (invalid data)"
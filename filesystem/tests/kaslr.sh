@@ -0,0 +1,8 @@
+echo "kaslr: boot normally, then run with: shell < /tests/kaslr.sh, a second boot puts the kernel somewhere else"
+cat /devices/boot_log | expect ~ "kaslr: kernel at 0x* (linked at 0xffffffff80100000), slide 0x*, random" "boot log (the kernel at a random place)"
+echo depth 8 > /devices/profile
+echo start > /devices/profile
+cksum /kernel
+echo stop > /devices/profile
+cat /devices/profile | expect ~ ";kernel::" "profile (the kernel frames are named, the symbols are found without the slide)"
+echo reset > /devices/profile
@@ -0,0 +1,8 @@
+echo "log_limit: run with: shell < /tests/log_limit.sh, as root, needs a clock"
+echo "storm 2000" > /devices/log_limits
+expect 0 "log storm (about 10 of the 2000 printed, the error in the middle)"
+cat /devices/log_limits | expect ~ "errors: 1" ~ "log_limit.rs:* suppressed " "log_limits (about 1990 suppressed at the storm line)"
+echo "storm 10" > /devices/log_limits
+expect 0 "log storm again (the summary of the first storm, few of the 10 printed)"
+echo "storm many" > /devices/log_limits
+expect 1 "log storm bad count (invalid data)"
@@ -0,0 +1,4 @@
+lowmem
+expect 0 "lowmem (signaled before running out of memory)"
+lowmem
+expect 0 "lowmem again (signaled again after giving the memory back)"
@@ -0,0 +1,2 @@
+cat /devices/meminfo | expect ~ "heap_size: " ~ "heap_allocated: " ~ "heap_free: " ~ "live_allocations: " ~ "largest_free_block: " ~ "shared_frames: " ~ "user_frames: " ~ "page_cache_frames: " ~ "shm_frames: " "meminfo (heap_allocated + heap_free = heap_size, live_allocations = allocations - frees, largest_free_block at most heap_free, the frames of each use at most the used frames)"
+cat /devices/heap_track | expect ~ " allocations, " ~ "# seq address size callers" "heap_track (with the heaptrack feature, then the live allocations by seq, each with kernel symbols for its callers)"
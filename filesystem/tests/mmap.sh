@@ -0,0 +1,8 @@
+mmap
+expect 0 "mmap (/shell mapped, read by the parent, a child and the kernel, written privately)"
+mmap /message.txt
+expect 0 "mmap small file (less than a page, the rest zeroed)"
+mmap /missing_file
+expect 1 "mmap missing file"
+mmap -x
+expect 1 "mmap bad argument (usage)"
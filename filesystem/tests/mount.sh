@@ -0,0 +1,21 @@
+echo "mount: run with: shell < /tests/mount.sh in a debug kernel, it uses /devices/selftest_mount left by the fs self tests"
+mount -r /devices/selftest_mount /mnt
+expect 0 "mount read-only"
+mount /devices/selftest_mount /mnt2
+expect 1 "mount same device again (busy)"
+mount -t ext2 /devices/selftest_mount /mnt2
+expect 1 "mount unknown fstype (invalid argument)"
+mount | expect ~ "/devices/selftest_mount /mnt fat ro " "mount list"
+cat /mnt/HELLO.TXT | expect ~ "hello" "cat"
+mkdir /mnt/DIR
+expect 1 "mkdir read-only (read only filesystem)"
+mount -u /
+expect 1 "umount root (busy)"
+mount -u /mnt
+expect 0 "umount"
+cat /mnt/HELLO.TXT
+expect 1 "cat after umount (not found)"
+mount -t ramfs scratch /mnt
+expect 0 "mount ramfs"
+mount -u /mnt
+expect 0 "umount ramfs"
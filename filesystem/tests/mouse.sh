@@ -0,0 +1,7 @@
+echo "mouse: run with: shell < /tests/mouse.sh, in qemu with a monitor (i.e. -monitor telnet::4444,server,nowait), then in the monitor: mouse_move 10 -5, mouse_button 1, mouse_button 0, mouse_move 0 0 1"
+mouse 4
+expect 0 "mouse events (10 -5 0 000, 0 0 0 001, 0 0 0 000, 0 0 1 000, qemu may split the move)"
+cksum /devices/mouse
+expect 0 "mouse empty (a size of 0, the read doesn't wait)"
+mouse x
+expect 1 "mouse bad count"
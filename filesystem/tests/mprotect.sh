@@ -0,0 +1,6 @@
+mprotect
+expect 0 "mprotect (a heap page made read-only, refused to SYS_READ, then writable again)"
+mprotect --crash
+expect 0 "mprotect crash (the same, and a child killed for writing to the read-only page)"
+mprotect -x
+expect 1 "mprotect bad argument (usage)"
@@ -0,0 +1,14 @@
+echo "net: run with: shell < /tests/net.sh, as root, in qemu with -netdev user,id=n0,hostfwd=udp::5555-:7 -device virtio-net-pci,netdev=n0 and ip=10.0.2.15/24,gw=10.0.2.2 log.udp=10.0.2.2:5514 in the cmdline (or in -fw_cfg name=opt/org.os.net,string=...), on the host: nc -u -l 5514 shows the logs, echo hi | nc -u -w1 localhost 5555 must print hi back"
+cat /devices/boot_tasks | expect ~ "net (optional): * done" "boot tasks"
+cat /devices/net | expect ~ "device: virtio-net 52:54:00:12:34:56" ~ "address: 10.0.2.15/24, gateway 10.0.2.2 (" ~ "neighbour 10.0.2.2 at " ~ "udp port 7: echo" "net (rx frames and echo replies after the nc on the host)"
+cat /devices/log_limits | expect ~ "udp 10.0.2.2:5514: " !~ "udp 10.0.2.2:5514: 0 sent" "log sink (the boot messages from net: sending the logs on were received by nc on the host, as <6>os kernel: lines)"
+echo "storm 2000" > /devices/log_limits
+expect 0 "log storm (the lines that passed the limits show on the host too, with the error as <3>)"
+echo "udp off" > /devices/log_limits
+expect 0 "udp off"
+echo "udp 10.0.2.2" > /devices/log_limits
+expect !0 "udp without a port (invalid data)"
+cat /devices/log_limits | expect ~ "udp off: " !~ "udp off: 0 sent" "log sink off (the counters kept)"
+echo "udp 10.0.2.2:5514" > /devices/log_limits
+expect 0 "udp on (the next kernel messages reach the host again)"
+cat /devices/net | expect ~ "interrupt: GSI *, * received" !~ "interrupt: GSI *, 0 received" "net interrupt (the virtio-net interrupts come on the GSI routed by _PRT, the frames above were received through them)"
@@ -0,0 +1,11 @@
+nice --test
+expect 0 "nice --test (the rules, the children have the priority of the parent, the highest of two spinning children counts more)"
+nice -n 30 /nice --expect 30
+expect 0 "nice run (the program runs with priority 30)"
+cat /devices/processes | expect ~ " PRI" "processes (20 for the shell and init)"
+nice -p 0 30
+expect 1 "nice init (PermissionDenied, init is not our child)"
+nice -n 40 /nice
+expect 1 "nice bad priority (InvalidArgument)"
+nice -x
+expect 1 "nice bad argument (usage)"
@@ -0,0 +1,10 @@
+echo "pie: run with: shell < /tests/pie.sh, cksum_pie is cksum linked as a static PIE (ET_DYN), both must give the same output"
+cksum /message.txt > /tmp/exec_out
+expect 0 "cksum (ET_EXEC)"
+cksum_pie /message.txt > /tmp/pie_out
+expect 0 "cksum_pie (ET_DYN)"
+cat /tmp/exec_out /tmp/pie_out
+cksum /tmp/exec_out /tmp/pie_out
+expect 0 "cksum outputs (the same checksum and size twice)"
+rm /tmp/exec_out /tmp/pie_out
+expect 0 "cleanup"
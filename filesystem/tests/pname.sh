@@ -0,0 +1,9 @@
+pname
+expect 0 "pname (pname, the file name of the program)"
+pname --test
+expect 0 "pname --test (the child named in the listing, the crash dump named after it, long names cut)"
+pname 0 not-init
+expect 1 "pname rename init (PermissionDenied, init is not our child)"
+pname --crash custom-name
+expect 139 "pname crash (the crash report and /tmp/crash-<pid>-custom-name.dump with the name)"
+cat /devices/processes | expect ~ "PID NAME " ~ " shell " ~ " init " "processes (a NAME column next to the PID)"
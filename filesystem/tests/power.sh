@@ -0,0 +1,6 @@
+echo "power: run with: shell < /tests/power.sh, in qemu with -device isa-debug-exit,iobase=0xf4,iosize=0x04 and debug_exit in the test config, the last command exits qemu"
+cat /devices/power | expect ~ "hooks: fs sync, block devices, console" "power info"
+echo suspend > /devices/power
+expect !0 "unknown command (invalid data)"
+echo shutdown > /devices/power
+echo "shutdown: not reached, qemu must exit with 33 after the hooks in the order above"
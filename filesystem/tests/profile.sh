@@ -0,0 +1,12 @@
+echo depth 8 > /devices/profile
+expect 0 "profile depth"
+echo depth 65 > /devices/profile
+expect 1 "profile depth too deep (invalid data)"
+echo start > /devices/profile
+cksum_pie /kernel
+cksum /kernel
+echo stop > /devices/profile
+cat /devices/profile | expect ~ "# depth 8, " ~ " kernel symbols from " ~ "# pid* cksum_pie * loaded at 0x" ~ "# pid* cksum * loaded at 0x" ~ "kernel;" "profile (the stacks of cksum and cksum_pie under kernel and pid roots, at most 8 frames after the root)"
+echo reset > /devices/profile
+cat /devices/profile | expect ~ "# depth 8, 0 samples, 0 dropped, stopped" !~ "kernel;" !~ "# pid" "profile reset"
+echo depth 16 > /devices/profile
@@ -0,0 +1,6 @@
+shm
+expect 0 "shm (a named object mapped by the parent and a child, which see the same pages)"
+shm -p 64
+expect 0 "shm big (the same with 64 pages)"
+shm -p 1
+expect 1 "shm bad argument (usage)"
@@ -0,0 +1,10 @@
+signal --test
+expect 0 "signal --test (the handlers run and return, ignoring, the rules, SIGKILL and SIGTERM kill, a SIGSEGV handler, a handled signal interrupts a wait)"
+signal --segv
+expect 139 "signal --segv (killed by SIGSEGV)"
+signal --interrupt
+expect 0 "signal --interrupt (Ctrl+C kills the child, run on the active terminal)"
+signal 0
+expect 1 "signal init (PermissionDenied)"
+signal x
+expect 1 "signal bad argument (usage)"
@@ -0,0 +1,6 @@
+sleep --test
+expect 0 "sleep --test (the lengths, the order of the wakes, a handled signal stops the syscall, no CPU time used, a sleeping process can be killed)"
+sleep 200ms
+expect 0 "sleep 200ms"
+sleep x
+expect 1 "sleep bad argument (usage)"
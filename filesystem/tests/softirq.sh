@@ -0,0 +1,3 @@
+echo "softirq: run with: shell < /tests/softirq.sh, after typing a few keys"
+cat /devices/softirq | expect ~ "tty_input: raised " !~ "tty_input: raised 0," ~ "dropped 0" "softirq (tty_input raised once for each key byte and ran at most as many times)"
+cat /devices/irq_off | expect ~ "[push_cli]" ~ "[interrupts]" ~ "keyboard interrupt" ~ "ide interrupt" "irq_off (with the irqoff feature)"
@@ -0,0 +1,4 @@
+stackgrow
+expect 0 "stackgrow (SYS_READ below the stack grew it, then 2048 KB of it used)"
+stackgrow -k 8192 --overflow
+expect 0 "stackgrow overflow (8 MB used, then the child past the 1 MB limit killed, its crash report says stack overflow)"
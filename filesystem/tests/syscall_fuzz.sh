@@ -0,0 +1,6 @@
+syscall_fuzz --regressions
+expect 0 "syscall_fuzz --regressions"
+syscall_fuzz --seed 1 --iterations 200000
+expect 0 "syscall_fuzz seed 1 (the heartbeat kept answering)"
+syscall_fuzz --seed 2482 --iterations 200000
+expect 0 "syscall_fuzz seed 2482"
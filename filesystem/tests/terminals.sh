@@ -0,0 +1,4 @@
+echo "terminals: run with: shell < /tests/terminals.sh, each line must only be in the dump of its own terminal"
+echo "terminals: on tty3" > /devices/tty3
+echo "terminals: on tty4" > /devices/tty4
+cat /devices/vt_dump
@@ -0,0 +1,6 @@
+threads --test
+expect 0 "threads --test (no increment lost, a TLS for each thread, the results and the rules of the joins, the process runs until its last thread exits)"
+threads -n 8
+expect 0 "threads count (prints 1600000)"
+threads -n x
+expect 1 "threads bad argument (usage)"
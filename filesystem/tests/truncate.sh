@@ -0,0 +1,24 @@
+echo "truncate: run with: shell < /tests/truncate.sh, cksum shows the size after each truncate"
+cp /message.txt /tmp/truncated
+truncate -s 5 /tmp/truncated
+cksum /tmp/truncated
+expect 0 "truncate shrink (a size of 5)"
+cat /tmp/truncated | expect ~ "Hello" !~ "Hello." "cat after shrink (the first 5 bytes of /message.txt)"
+truncate -s 1M /tmp/truncated
+cksum /tmp/truncated
+expect 0 "truncate extend (a size of 1048576)"
+truncate -s 5 /tmp/truncated
+truncate -o -s 5 /tmp/extended
+expect 1 "truncate -o missing file"
+cp /tmp/truncated /tmp/extended
+truncate -o -s 4096 /tmp/extended
+cksum /tmp/truncated /tmp/extended
+expect 0 "truncate -o extend (sizes 5 and 4096)"
+truncate -s 1 /tmp
+expect 1 "truncate a directory"
+truncate -s 1 /devices/pit
+expect 1 "truncate a device"
+truncate -s 5G /tmp/truncated
+expect 1 "truncate past 4G"
+rm /tmp/truncated /tmp/extended
+expect 0 "cleanup"
@@ -0,0 +1,4 @@
+echo "usb_ehci_keyboard: run with only a high speed USB keyboard, in qemu: -machine pc,i8042=off -device usb-ehci,id=ehci -device usb-kbd,bus=ehci.0, then type this on it: shell < /tests/usb_ehci_keyboard.sh"
+cat /devices/usb | expect ~ "ehci *: mmio *, 6 ports" !~ "halted" "ehci controller"
+cat /devices/usb | expect ~ "port 1: 0627:0001, address 1, high speed, boot keyboard" !~ "uhci" "usb devices"
+cat /devices/boot_tasks | expect ~ "usb (optional): * done" "boot tasks"
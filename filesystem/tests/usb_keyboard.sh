@@ -0,0 +1,4 @@
+echo "usb_keyboard: run with only a USB keyboard, in qemu: -machine pc,i8042=off -usb -device usb-kbd, then type this on it: shell < /tests/usb_keyboard.sh"
+cat /devices/usb | expect ~ "port 1: 0627:0001, address 1, full speed, boot keyboard" "usb devices"
+cat /devices/boot_tasks | expect ~ "usb (optional): * done" ~ "mouse (optional): * failed: no mouse" "boot tasks"
+cat /devices/keyboard_layout | expect ~ "* " "keyboard layout (the USB keys go through the same layouts)"
@@ -0,0 +1,8 @@
+echo "version: run with: shell < /tests/version.sh, the three must show the same version, the one of the boot banner"
+uname
+expect 0 "uname (the version from sysinfo)"
+uname -a
+expect 0 "uname -a (the same version, then the lines of /devices/version)"
+cat /devices/version | expect ~ "version: " ~ "git: " ~ "built: " ~ "profile: " ~ "features:" ~ "rustc: " "cat version"
+uname -x
+expect 1 "uname bad flag"
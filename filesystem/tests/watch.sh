@@ -0,0 +1,21 @@
+echo "watch: run with: shell < /tests/watch.sh, the files it makes are removed first so it can run again"
+rm /tmp/watch_ready /tmp/watch_go /watch_ready
+rmdir /tmp/watch_dir /watch_dir
+mkdir /tmp/watch_dir /watch_dir
+watch -W /tmp/watch_ready -r /tmp/watch_dir &
+watch -m /tmp/watch_ready -e create:A.TXT,modify:A.TXT,moved_from:A.TXT,moved_to:B.TXT,create:D/,delete:D/,delete:B.TXT /tmp/watch_dir
+expect 0 "watch ramfs (the changes of the other process in order, the rename paired by its cookie)"
+watch -W /watch_ready -r /watch_dir &
+watch -m /watch_ready -e create:A.TXT,modify:A.TXT,moved_from:A.TXT,moved_to:B.TXT,create:D/,delete:D/,delete:B.TXT /watch_dir
+expect 0 "watch fat (the same events in a FAT directory)"
+rm /tmp/watch_ready
+watch -W /tmp/watch_ready -m /tmp/watch_go -g 100 /tmp/watch_dir &
+watch -m /tmp/watch_ready -W /tmp/watch_go -e create:n#64,overflow:136 /tmp/watch_dir
+expect 0 "watch overflow (the first 64 creates, then an overflow of the 136 dropped)"
+watch -e create:x /tmp/watch_dir/missing
+expect 1 "watch missing dir (FileNotFound)"
+watch -e create:x /message.txt
+expect 1 "watch a file (not a directory)"
+rm /tmp/watch_ready /tmp/watch_go /watch_ready
+rmdir /tmp/watch_dir /watch_dir
+expect 0 "cleanup"
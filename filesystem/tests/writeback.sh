@@ -0,0 +1,7 @@
+echo "writeback: run with: shell < /tests/writeback.sh, in any boot, pid 1 is the kernel thread started after init"
+cat /devices/processes | expect ~ "    1 writeback " "processes (the writeback kernel thread, its own parent)"
+signal 1
+expect 1 "signal writeback (PermissionDenied, the kernel thread takes no signals)"
+sleep 200ms
+expect 0 "sleep 200ms (the writeback thread wakes meanwhile)"
+cat /devices/processes | expect ~ "    1 writeback " "processes (the writeback thread is still there)"
@@ -1,9 +1,127 @@
-use std::path::PathBuf;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The output of `program args...`, `None` if it can't run or fails
+fn command_output(program: &str, args: &[&str], dir: &Path) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(String::from(output.trim()))
+}
+
+/// Reruns this script only when the head commit changes, which is the `HEAD` file, or the
+/// branch it points to (loose, or in `packed-refs`). Only existing files are tracked, cargo
+/// reruns every time for a missing one
+fn track_git_head(git_dir: &Path) {
+    let head = git_dir.join("HEAD");
+    let Ok(content) = std::fs::read_to_string(&head) else {
+        return;
+    };
+    println!("cargo:rerun-if-changed={}", head.display());
+    if let Some(branch) = content.trim().strip_prefix("ref: ") {
+        for file in [git_dir.join(branch), git_dir.join("packed-refs")] {
+            if file.exists() {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
+        }
+    }
+}
+
+/// `seconds` since the epoch as `YYYY-MM-DDTHH:MM:SSZ`
+fn format_utc(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    // the days to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Fills the `KERNEL_BUILD_*` variables of `build_info`, each falls back to `unknown` if
+/// it can't be found, i.e. building from a tarball without git
+fn build_info(manifest_dir: &Path) {
+    let git_dir = command_output("git", &["rev-parse", "--absolute-git-dir"], manifest_dir);
+    let git = match &git_dir {
+        Some(git_dir) => {
+            track_git_head(Path::new(git_dir));
+            command_output(
+                "git",
+                &["describe", "--always", "--dirty", "--tags"],
+                manifest_dir,
+            )
+        }
+        None => None,
+    };
+
+    // reproducible builds set the time
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        });
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = command_output(&rustc, &["-V"], manifest_dir);
+
+    let mut features = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+
+    let unknown = || String::from("unknown");
+    let vars = [
+        ("GIT", git.unwrap_or_else(unknown)),
+        ("TIMESTAMP", format_utc(timestamp)),
+        ("PROFILE", env::var("PROFILE").unwrap_or_else(|_| unknown())),
+        ("FEATURES", features.join(" ")),
+        ("RUSTC", rustc_version.unwrap_or_else(unknown)),
+    ];
+    for (name, value) in vars {
+        println!("cargo:rustc-env=KERNEL_BUILD_{name}={value}");
+    }
+}
 
 fn main() {
-    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
 
     let linker_script = manifest_dir.join("linker.ld").display().to_string();
     println!("cargo:rerun-if-changed={linker_script}");
     println!("cargo:rustc-link-arg=-T{linker_script}");
+    // relocatable, for `kaslr`, and still runs where it is linked without applying the
+    // relocations, the assembly has absolute addresses in `.text`
+    for arg in ["-pie", "--no-dynamic-linker", "-znotext", "--apply-dynamic-relocs"] {
+        println!("cargo:rustc-link-arg={arg}");
+    }
+
+    build_info(&manifest_dir);
 }
@@ -0,0 +1,322 @@
+//! The DSDT as ASL-like text in `/devices/acpi_dsl`, and a search over its namespace.
+//!
+//! The text is rendered a term of the root at a time, so even a large table only needs a
+//! chunk of it in memory, see [`generated`].
+//!
+//! Writing a path or a pattern (i.e. `\_SB.PCI0` or `\_SB.PCI0.*`, see [`PathPattern`]) to
+//! `/devices/acpi_query` makes `/devices/acpi_result` show only the objects matching it, each
+//! with everything inside it, followed by the values of the `Name`s in it that can be
+//! evaluated. The paths are resolved as in the namespace, so a device is found in every
+//! `Scope` that adds to it, and never in the ones of its siblings. An empty line clears the
+//! query.
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use kernel_core::aml::{parse_aml, AmlCode, FoundTerm, Namespace, PathPattern};
+
+use crate::{
+    devices::{
+        self,
+        generated::{self, Cursor, GeneratedFile, Generator},
+        Device,
+    },
+    fs::FileSystemError,
+    sync::spin::mutex::Mutex,
+};
+
+use super::{
+    pci_routing,
+    tables::{BiosTables, Dsdt},
+};
+
+/// The code and its namespace, shared by the open files
+#[derive(Debug)]
+struct Tables {
+    code: Arc<AmlCode>,
+    namespace: Namespace,
+}
+
+type Query = Arc<Mutex<Option<PathPattern>>>;
+
+/// The item of the cursor is the term of the root, the field is the line in it
+#[derive(Debug)]
+struct DslGenerator {
+    tables: Arc<Tables>,
+}
+
+impl Generator for DslGenerator {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let mut written = 0;
+        while let Some(term) = self.tables.code.terms().get(cursor.item) {
+            let (len, done) = generated::render_display_part(
+                &format_args!("{term}\n"),
+                &mut cursor.field,
+                &mut out[written..],
+            );
+            written += len;
+            if !done {
+                break;
+            }
+            cursor.item += 1;
+            cursor.field = 0;
+        }
+        written
+    }
+}
+
+/// The item of the cursor is the match, the field is the line in it
+#[derive(Debug)]
+struct ResultGenerator {
+    tables: Arc<Tables>,
+    pattern: Option<PathPattern>,
+    /// The `// <name> = <value>` lines of each match, evaluated when opened, as evaluating
+    /// changes the namespace
+    values: Vec<String>,
+}
+
+impl ResultGenerator {
+    fn new(tables: Arc<Tables>, pattern: Option<PathPattern>) -> Self {
+        let mut values = Vec::new();
+        if let Some(pattern) = &pattern {
+            let mut namespace = tables.namespace.clone();
+            for found in tables.namespace.find_terms(&tables.code, pattern) {
+                let mut lines = String::new();
+                for name in &found.names {
+                    if let Ok(value) = namespace.evaluate(name, Vec::new()) {
+                        lines.push_str(&format!("// {name} = {value}\n"));
+                    }
+                }
+                values.push(lines);
+            }
+        }
+        Self {
+            tables,
+            pattern,
+            values,
+        }
+    }
+
+    fn render_match(
+        found: &FoundTerm,
+        values: &str,
+        cursor: &mut Cursor,
+        out: &mut [u8],
+    ) -> (usize, bool) {
+        generated::render_display_part(
+            &format_args!("// {}\n{}\n{values}", found.path, found.term),
+            &mut cursor.field,
+            out,
+        )
+    }
+}
+
+impl Generator for ResultGenerator {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let Some(pattern) = &self.pattern else {
+            return 0;
+        };
+        let found = self.tables.namespace.find_terms(&self.tables.code, pattern);
+        if found.is_empty() {
+            return generated::render_display_lines(
+                &format_args!("// nothing matches {pattern}\n"),
+                cursor,
+                out,
+            );
+        }
+        let mut written = 0;
+        while let Some(found) = found.get(cursor.item) {
+            let values = self.values.get(cursor.item).map_or("", String::as_str);
+            let (len, done) = Self::render_match(found, values, cursor, &mut out[written..]);
+            written += len;
+            if !done {
+                break;
+            }
+            cursor.item += 1;
+            cursor.field = 0;
+        }
+        written
+    }
+}
+
+/// `/devices/acpi_dsl`, all of the DSDT
+#[derive(Debug)]
+struct DslDevice {
+    tables: Arc<Tables>,
+}
+
+impl Device for DslDevice {
+    fn name(&self) -> &str {
+        "acpi_dsl"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(DslGenerator {
+            tables: self.tables.clone(),
+        }))
+    }
+}
+
+/// `/devices/acpi_query`, the pattern `acpi_result` shows
+#[derive(Debug)]
+struct QueryDevice {
+    query: Query,
+}
+
+impl Device for QueryDevice {
+    fn name(&self) -> &str {
+        "acpi_query"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let text = match self.query.lock().as_ref() {
+            Some(pattern) => format!("{pattern}\n"),
+            None => String::new(),
+        };
+        Ok(devices::read_bytes(text.as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let text = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        let pattern = if text.is_empty() {
+            None
+        } else {
+            Some(PathPattern::parse(text).ok_or(FileSystemError::InvalidData)?)
+        };
+        *self.query.lock() = pattern;
+        Ok(buf.len() as u64)
+    }
+}
+
+/// `/devices/acpi_result`, the objects matching the query when it was opened
+#[derive(Debug)]
+struct ResultDevice {
+    tables: Arc<Tables>,
+    query: Query,
+}
+
+impl Device for ResultDevice {
+    fn name(&self) -> &str {
+        "acpi_result"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        let pattern = self.query.lock().clone();
+        Some(Box::new(ResultGenerator::new(self.tables.clone(), pattern)))
+    }
+}
+
+fn register(tables: Arc<Tables>) {
+    let query = Arc::new(Mutex::new(None));
+    devices::register_device(Arc::new(DslDevice {
+        tables: tables.clone(),
+    }));
+    devices::register_device(Arc::new(QueryDevice {
+        query: query.clone(),
+    }));
+    devices::register_device(Arc::new(ResultDevice { tables, query }));
+}
+
+/// Adds the files of the DSDT, if there is one
+pub fn init(bios_tables: &BiosTables) {
+    let Some(dsdt) = bios_tables.rsdt.get_table::<Dsdt>() else {
+        return;
+    };
+    let code = dsdt.shared_aml_code();
+    register(Arc::new(Tables {
+        namespace: Namespace::new(&code),
+        code,
+    }));
+}
+
+fn selftest_read_all(generator: Box<dyn Generator>, buf_size: usize) -> String {
+    let mut file = GeneratedFile::new(generator);
+    let mut text = Vec::new();
+    let mut buf = alloc::vec![0; buf_size];
+    loop {
+        let read = file.read(text.len() as u64, &mut buf) as usize;
+        if read == 0 {
+            break;
+        }
+        text.extend_from_slice(&buf[..read]);
+    }
+    String::from_utf8(text).unwrap()
+}
+
+/// Reads the `_PRT` fixture of `pci_routing` through the files, in small reads, and queries
+/// its host bridge, which has a sibling device
+pub fn run_self_tests() {
+    let code = Arc::new(parse_aml(pci_routing::SELFTEST_AML_PRT).unwrap());
+    let tables = Arc::new(Tables {
+        namespace: Namespace::new(&code),
+        code,
+    });
+    let dsl = DslDevice {
+        tables: tables.clone(),
+    };
+    let whole = format!("{}", tables.code);
+    assert_eq!(
+        selftest_read_all(dsl.generator().unwrap(), 256),
+        whole,
+        "acpi self test: the dsl read in 256 bytes is not the document"
+    );
+    generated::check_chunked("acpi_dsl", &|| dsl.generator().unwrap());
+
+    let pattern = |text| PathPattern::parse(text).unwrap();
+    assert!(pattern("\\_SB.PCI0").matches("\\_SB_.PCI0"));
+    assert!(pattern("_sb.pci?").matches("\\_SB_.PCI0"));
+    assert!(pattern("\\_SB.*").matches("\\_SB_.GSIA"));
+    assert!(!pattern("\\_SB.*").matches("\\_SB_.PCI0._PRT"));
+    assert!(!pattern("\\_SB.PCI0").matches("\\_SB_"));
+    for invalid in ["", "\\", "\\_SB..PCI0", "\\_SB.PCI00", "\\_SB.PC-0"] {
+        assert!(PathPattern::parse(invalid).is_none(), "{invalid:?} parsed");
+    }
+
+    let query = QueryDevice {
+        query: Arc::new(Mutex::new(None)),
+    };
+    let result = ResultDevice {
+        tables,
+        query: query.query.clone(),
+    };
+    let result_for = |text: &str| {
+        query.write(0, text.as_bytes()).unwrap();
+        selftest_read_all(result.generator().unwrap(), 256)
+    };
+
+    let text = result_for("\\_SB.PCI0\n");
+    for expected in [
+        "// \\_SB_.PCI0",
+        "Device (PCI0) {",
+        "Method (_PRT, ",
+        "Name(_HID, ",
+        "Name(PRTP, ",
+        "// \\_SB_.PCI0._HID = 0x80AD041",
+        "// \\_SB_.PCI0.PRTP = Package (1) { Package (4) {",
+    ] {
+        assert!(
+            text.lines().any(|line| line.trim().starts_with(expected)),
+            "acpi self test: missing {expected:?} in:\n{text}"
+        );
+    }
+    // `GSIA` is a sibling, `PICF` and `_PIC` are in the root
+    for unexpected in ["Device (GSIA", "_CRS", "Name(PICF", "Method (_PIC"] {
+        assert!(
+            !text.contains(unexpected),
+            "acpi self test: {unexpected:?} in the result of \\_SB.PCI0:\n{text}"
+        );
+    }
+    assert_eq!(text.matches("// \\_SB_.").count(), 4);
+
+    let text = result_for("\\_SB.*");
+    assert!(text.contains("// \\_SB_.PCI0\n") && text.contains("// \\_SB_.GSIA\n"));
+    let text = result_for("_sb.gsia._crs");
+    assert!(text.starts_with("// \\_SB_.GSIA._CRS\n") && text.contains("= Buffer (11) {"));
+    assert_eq!(result_for("\\_SB.NONE"), "// nothing matches \\_SB_.NONE\n");
+    assert!(matches!(
+        query.write(0, b"\\_SB..PCI0"),
+        Err(FileSystemError::InvalidData)
+    ));
+    assert_eq!(result_for("\n"), "");
+    println!("ACPI dsl self tests passed");
+}
@@ -0,0 +1,123 @@
+//! The Generic Address Structure (GAS) of the ACPI tables, a register in the IO or the
+//! memory space, with how wide the accesses to it must be.
+//!
+//! Tables only describe the register, [`GenericAddress::register`] checks that we can use it
+//! and maps it if its in memory, so the accesses after that can't fail, and don't need the
+//! heap, which matters when powering off from a panic.
+
+use core::fmt;
+
+use crate::{cpu, memory_management::virtual_space};
+
+/// The values of `address_space_id` we can access
+pub mod address_space {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// As in the tables, 12 bytes
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    /// `0` for undefined (before ACPI 3.0), then 1 for bytes, up to 4 for 8 bytes
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// The tables use an all-zero structure (or only the address zero) for no register
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// The width of each access in bytes, from the access size, or if undefined, the width
+    /// of the register. `None` if not `1`, `2`, `4` or `8`
+    pub fn access_bytes(&self) -> Option<u8> {
+        let bytes = match self.access_size {
+            0 => (self.register_bit_width as u16 + self.register_bit_offset as u16).div_ceil(8),
+            size @ 1..=4 => 1 << (size - 1),
+            _ => return None,
+        };
+        match bytes {
+            0 => Some(1),
+            1 | 2 | 4 | 8 => Some(bytes as u8),
+            _ => None,
+        }
+    }
+
+    /// Makes the register ready to access, mapping it if its in memory, `None` if there is
+    /// none, or its in a space or with a width we don't support (8 byte IO accesses)
+    pub fn register(&self) -> Option<Register> {
+        if !self.is_present() {
+            return None;
+        }
+        let width = self.access_bytes()?;
+        let space = match self.address_space_id {
+            address_space::SYSTEM_IO if width <= 4 && self.address <= u16::MAX as u64 => {
+                Space::Io(self.address as u16)
+            }
+            address_space::SYSTEM_MEMORY => Space::Memory(virtual_space::allocate_and_map_mmio(
+                self.address,
+                width as u64,
+            )),
+            _ => return None,
+        };
+        Some(Register {
+            space,
+            width,
+            bit_offset: self.register_bit_offset,
+        })
+    }
+}
+
+impl fmt::Debug for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let space = match self.address_space_id {
+            address_space::SYSTEM_MEMORY => "memory",
+            address_space::SYSTEM_IO => "io",
+            _ => "other",
+        };
+        let address = self.address;
+        write!(
+            f,
+            "{space}({}):{address:#x}[{}+{}]",
+            self.address_space_id, self.register_bit_offset, self.register_bit_width
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Space {
+    Io(u16),
+    /// the virtual address it is mapped to
+    Memory(u64),
+}
+
+/// A register from a [`GenericAddress`], ready to be accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    space: Space,
+    width: u8,
+    bit_offset: u8,
+}
+
+impl Register {
+    /// # Safety
+    /// The register must be the one described by the firmware, and writing it must not
+    /// have side effects the caller doesn't expect
+    pub unsafe fn write(&self, value: u64) {
+        let value = value << self.bit_offset;
+        match (self.space, self.width) {
+            (Space::Io(port), 1) => cpu::io_out(port, value as u8),
+            (Space::Io(port), 2) => cpu::io_out(port, value as u16),
+            (Space::Io(port), _) => cpu::io_out(port, value as u32),
+            (Space::Memory(addr), 1) => (addr as *mut u8).write_volatile(value as u8),
+            (Space::Memory(addr), 2) => (addr as *mut u16).write_volatile(value as u16),
+            (Space::Memory(addr), 4) => (addr as *mut u32).write_volatile(value as u32),
+            (Space::Memory(addr), _) => (addr as *mut u64).write_volatile(value),
+        }
+    }
+}
@@ -1,4 +1,12 @@
-mod aml;
+pub mod dsl;
+pub mod generic_address;
+pub mod pci_routing;
 pub mod tables;
 
 pub use tables::get_acpi_tables;
+
+pub fn run_self_tests() {
+    tables::run_self_tests();
+    pci_routing::run_self_tests();
+    dsl::run_self_tests();
+}
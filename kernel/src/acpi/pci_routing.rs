@@ -0,0 +1,413 @@
+//! The routing of the PCI `INTx` interrupts to the IO APIC, from the `_PRT`s in the DSDT
+//!
+//! The `_PRT` of a PCI bridge gives, for each device slot and pin on its bus, either the
+//! global system interrupt directly, or a link device whose `_CRS` has the interrupt it uses now.
+//! `\_PIC(1)` is called first, so the firmware knows we use the IO APIC, and gives the
+//! tables for it instead of the 8259 PIC ones.
+//!
+//! Not handled: the devices behind a bridge without a `_PRT` (no swizzling), and the link
+//! devices whose `_CRS` reads the chipset registers, as the interpreter can't access the
+//! operation regions. These fall back to the interrupt line register.
+
+use core::fmt;
+
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+use kernel_core::aml::{interrupt_resources, parse_aml, AmlEvalError, AmlValue, Namespace};
+
+use crate::{
+    cpu::{
+        interrupts::{apic, InterruptHandler},
+        Cpu,
+    },
+    devices::pci::{self, reg},
+    sync::once::OnceLock,
+};
+
+use super::tables::{BiosTables, Dsdt};
+
+/// `_HID`/`_CID` of the PCI host bridges
+const PCI_HOST_BRIDGE_IDS: [u32; 2] = [eisa_id(b"PNP0A03"), eisa_id(b"PNP0A08")];
+
+const fn eisa_char(c: u8) -> u32 {
+    (c - b'@') as u32 & 0x1F
+}
+
+const fn eisa_hex(c: u8) -> u32 {
+    match c {
+        b'0'..=b'9' => (c - b'0') as u32,
+        _ => (c - b'A' + 10) as u32,
+    }
+}
+
+/// The compressed form of an EISA id, as `EisaId ("PNP0A08")` in ASL
+const fn eisa_id(id: &[u8; 7]) -> u32 {
+    let value = eisa_char(id[0]) << 26
+        | eisa_char(id[1]) << 21
+        | eisa_char(id[2]) << 16
+        | eisa_hex(id[3]) << 12
+        | eisa_hex(id[4]) << 8
+        | eisa_hex(id[5]) << 4
+        | eisa_hex(id[6]);
+    value.swap_bytes()
+}
+
+/// Where an `INTx` pin goes in the IO APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntxRoute {
+    pub gsi: u32,
+    pub level_triggered: bool,
+    pub active_low: bool,
+}
+
+impl IntxRoute {
+    /// Programs the IO APIC entry of this interrupt to go to `handler` on `cpu`
+    pub fn assign<H: InterruptHandler>(&self, handler: H, cpu: &Cpu) {
+        apic::assign_io_gsi(handler, self.gsi, cpu, |b| {
+            b.with_trigger_mode_level(self.level_triggered)
+                .with_interrupt_polartiy_low(self.active_low)
+        })
+    }
+}
+
+impl fmt::Display for IntxRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GSI {}, {}, active {}",
+            self.gsi,
+            if self.level_triggered {
+                "level"
+            } else {
+                "edge"
+            },
+            if self.active_low { "low" } else { "high" }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PrtEntry {
+    bus: u8,
+    device: u8,
+    /// `0` for `INTA`, to `3` for `INTD`
+    pin: u8,
+    route: IntxRoute,
+    /// The link device the interrupt is taken from, `None` if the `_PRT` gives it directly
+    link: Option<String>,
+}
+
+static ROUTES: OnceLock<Vec<PrtEntry>> = OnceLock::new();
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind('.').map(|i| &path[..i])
+}
+
+fn integer(namespace: &mut Namespace, device: &str, name: &str) -> Option<u64> {
+    let path = format!("{device}.{name}");
+    if !namespace.contains(&path) {
+        return None;
+    }
+    namespace
+        .evaluate(&path, Vec::new())
+        .ok()?
+        .as_integer()
+        .ok()
+}
+
+fn is_host_bridge(namespace: &mut Namespace, device: &str) -> bool {
+    ["_HID", "_CID"].iter().any(|name| {
+        let path = format!("{device}.{name}");
+        if !namespace.contains(&path) {
+            return false;
+        }
+        let ids = match namespace.evaluate(&path, Vec::new()) {
+            Ok(AmlValue::Package(ids)) => ids,
+            Ok(id) => vec![id],
+            Err(_) => return false,
+        };
+        ids.iter().any(|id| match id {
+            AmlValue::Integer(id) => PCI_HOST_BRIDGE_IDS.contains(&(*id as u32)),
+            AmlValue::String(id) => id == "PNP0A03" || id == "PNP0A08",
+            _ => false,
+        })
+    })
+}
+
+fn prt_entry(
+    namespace: &mut Namespace,
+    bridge: &str,
+    bus: u8,
+    entry: &AmlValue,
+) -> Result<PrtEntry, AmlEvalError> {
+    let AmlValue::Package(fields) = entry else {
+        return Err(AmlEvalError::InvalidType("package"));
+    };
+    let [address, pin, source, source_index] = fields.as_slice() else {
+        return Err(AmlEvalError::InvalidType("package of 4 elements"));
+    };
+    let device = (address.as_integer()? >> 16) as u8;
+    let pin = pin.as_integer()?;
+    if pin > 3 {
+        return Err(AmlEvalError::InvalidType("pin"));
+    }
+    let source_index = source_index.as_integer()?;
+    let link = match source {
+        AmlValue::Integer(0) => None,
+        AmlValue::Reference(path) => Some(path.clone()),
+        AmlValue::String(name) => Some(
+            namespace
+                .resolve(bridge, name)
+                .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?,
+        ),
+        _ => return Err(AmlEvalError::InvalidType("link device")),
+    };
+
+    let route = match &link {
+        // the defaults of the PCI interrupts
+        None => IntxRoute {
+            gsi: source_index as u32,
+            level_triggered: true,
+            active_low: true,
+        },
+        Some(link) => {
+            let AmlValue::Buffer(template) =
+                namespace.evaluate(&format!("{link}._CRS"), Vec::new())?
+            else {
+                return Err(AmlEvalError::InvalidType("resource template"));
+            };
+            // the index is of the interrupt descriptor the pin uses
+            let resource = interrupt_resources(&template)
+                .into_iter()
+                .nth(source_index as usize)
+                .ok_or(AmlEvalError::IndexOutOfBounds)?;
+            IntxRoute {
+                gsi: *resource
+                    .interrupts
+                    .first()
+                    .ok_or(AmlEvalError::IndexOutOfBounds)?,
+                level_triggered: !resource.edge_triggered,
+                active_low: resource.active_low,
+            }
+        }
+    };
+
+    Ok(PrtEntry {
+        bus,
+        device,
+        pin: pin as u8,
+        route,
+        link,
+    })
+}
+
+/// Evaluates the `_PRT` of `bridge`, whose secondary bus is `bus`
+fn prt_routes(namespace: &mut Namespace, bridge: &str, bus: u8, routes: &mut Vec<PrtEntry>) {
+    let prt = format!("{bridge}._PRT");
+    let entries = match namespace.evaluate(&prt, Vec::new()) {
+        Ok(AmlValue::Package(entries)) => entries,
+        Ok(other) => {
+            println!("WARNING: {prt} is not a package: {other:?}");
+            return;
+        }
+        Err(e) => {
+            println!("WARNING: could not evaluate {prt}: {e:?}");
+            return;
+        }
+    };
+    for entry in &entries {
+        match prt_entry(namespace, bridge, bus, entry) {
+            Ok(entry) => routes.push(entry),
+            Err(e) => {
+                println!("WARNING: skipping the {prt} entry {entry:?}: {e:?}");
+            }
+        }
+    }
+}
+
+/// All the routes of the bridges in `namespace`, `secondary_bus` gives the bus behind
+/// the PCI-to-PCI bridge at `(bus, device, function)`
+fn find_routes(
+    namespace: &mut Namespace,
+    secondary_bus: impl Fn(u8, u8, u8) -> Option<u8>,
+) -> Vec<PrtEntry> {
+    // tell the firmware we use the IO APIC, since `_PRT` can depend on it
+    if namespace.contains("\\_PIC") {
+        if let Err(e) = namespace.evaluate("\\_PIC", vec![AmlValue::Integer(1)]) {
+            println!("WARNING: could not evaluate \\_PIC: {e:?}");
+        }
+    }
+
+    // the bus behind each bridge, the parents come first so their bus is known
+    let mut buses = BTreeMap::new();
+    let mut routes = Vec::new();
+    let devices = namespace.devices().map(String::from).collect::<Vec<_>>();
+    for device in devices {
+        let bus = if is_host_bridge(namespace, &device) {
+            integer(namespace, &device, "_BBN").unwrap_or(0) as u8
+        } else {
+            let Some(&parent_bus) = parent(&device).and_then(|p| buses.get(p)) else {
+                continue;
+            };
+            let Some(address) = integer(namespace, &device, "_ADR") else {
+                continue;
+            };
+            // `0xFFFF` is all the functions
+            let function = (address as u16).min(7) as u8;
+            match secondary_bus(parent_bus, (address >> 16) as u8, function) {
+                Some(bus) => bus,
+                None => continue,
+            }
+        };
+        buses.insert(device.clone(), bus);
+        if namespace.contains(&format!("{device}._PRT")) {
+            prt_routes(namespace, &device, bus, &mut routes);
+        }
+    }
+    routes
+}
+
+/// The bus behind the PCI-to-PCI bridge at `(bus, device, function)`
+fn bridge_secondary_bus(bus: u8, device: u8, function: u8) -> Option<u8> {
+    let vendor_id = pci::read_pci_config::<u16>(bus, device, function, reg::VENDOR_ID);
+    let header_type = pci::read_pci_config::<u8>(bus, device, function, reg::HEADER_TYPE);
+    if vendor_id == 0xFFFF || header_type & 0x7F != 0x01 {
+        return None;
+    }
+    Some(pci::read_pci_config(
+        bus,
+        device,
+        function,
+        reg::SECONDARY_BUS,
+    ))
+}
+
+pub fn init(bios_tables: &BiosTables) {
+    let routes = match bios_tables.rsdt.get_table::<Dsdt>() {
+        Some(dsdt) => find_routes(&mut Namespace::new(dsdt.aml_code()), bridge_secondary_bus),
+        None => {
+            println!("WARNING: no DSDT, the PCI interrupts will be taken from the interrupt line");
+            Vec::new()
+        }
+    };
+    println!("PCI interrupt routing: {} _PRT entries", routes.len());
+    if ROUTES.set(routes).is_err() {
+        panic!("PCI interrupt routing already initialized");
+    }
+}
+
+/// Where `pin` (`0` for `INTA`) of the PCI function goes, `interrupt_line` is used for the
+/// functions not in any `_PRT`, as an ISA interrupt
+pub fn route(bus: u8, device: u8, function: u8, pin: u8, interrupt_line: u8) -> Option<IntxRoute> {
+    let pin_name = (b'A' + pin) as char;
+    let entry = ROUTES.try_get().and_then(|routes| {
+        routes
+            .iter()
+            .find(|e| e.bus == bus && e.device == device && e.pin == pin)
+    });
+    if let Some(entry) = entry {
+        let source = match &entry.link {
+            Some(link) => format!("{link}._CRS"),
+            None => String::from("_PRT"),
+        };
+        println!(
+            "PCI {bus:02X}:{device:02X}.{function} INT{pin_name}: {} from {source}",
+            entry.route
+        );
+        return Some(entry.route);
+    }
+
+    // not connected, or unknown
+    if interrupt_line == 0xFF || interrupt_line >= 16 {
+        println!(
+            "PCI {bus:02X}:{device:02X}.{function} INT{pin_name}: no route, interrupt line {interrupt_line:#X}"
+        );
+        return None;
+    }
+    // the interrupt line is an ISA interrupt, take the settings of its source override,
+    // or the PCI defaults
+    let (gsi, flags) = apic::isa_irq_route(interrupt_line);
+    let route = IntxRoute {
+        gsi,
+        level_triggered: flags & 0b1100 != 0b0100,
+        active_low: flags & 0b11 != 0b01,
+    };
+    println!(
+        "PCI {bus:02X}:{device:02X}.{function} INT{pin_name}: {route} guessed from interrupt line {interrupt_line}"
+    );
+    Some(route)
+}
+
+/// A `_PRT` for the IO APIC and the PIC like the one of QEMU, with a GSI given directly
+/// and one from a link device
+#[rustfmt::skip]
+pub(super) const SELFTEST_AML_PRT: &[u8] = &[
+    // Name (PICF, Zero)
+    0x08, 0x50, 0x49, 0x43, 0x46, 0x00,
+    // Method (_PIC, 1) { PICF = Arg0 }
+    0x14, 0x0C, 0x5F, 0x50, 0x49, 0x43, 0x01, 0x70, 0x68, 0x50, 0x49, 0x43, 0x46,
+    // Scope (\_SB) {
+    0x10, 0x45, 0x08, 0x5C, 0x5F, 0x53, 0x42, 0x5F,
+    //     Device (PCI0) {
+    0x5B, 0x82, 0x41, 0x06, 0x50, 0x43, 0x49, 0x30,
+    //         Name (_HID, EisaId ("PNP0A08"))
+    0x08, 0x5F, 0x48, 0x49, 0x44, 0x0C, 0x41, 0xD0, 0x0A, 0x08,
+    //         Name (PRTP, Package (1) { Package (4) { 0x0001FFFF, Zero, Zero, 0x0B } })
+    0x08, 0x50, 0x52, 0x54, 0x50, 0x12, 0x0E, 0x01,
+    0x12, 0x0B, 0x04, 0x0C, 0xFF, 0xFF, 0x01, 0x00, 0x00, 0x00, 0x0A, 0x0B,
+    //         Name (PRTA, Package (2) {
+    0x08, 0x50, 0x52, 0x54, 0x41, 0x12, 0x1C, 0x02,
+    //             Package (4) { 0x0001FFFF, Zero, Zero, 0x10 },
+    0x12, 0x0B, 0x04, 0x0C, 0xFF, 0xFF, 0x01, 0x00, 0x00, 0x00, 0x0A, 0x10,
+    //             Package (4) { 0x0002FFFF, One, GSIA, Zero } })
+    0x12, 0x0D, 0x04, 0x0C, 0xFF, 0xFF, 0x02, 0x00, 0x01, 0x47, 0x53, 0x49, 0x41, 0x00,
+    //         Method (_PRT, 0) {
+    0x14, 0x1A, 0x5F, 0x50, 0x52, 0x54, 0x00,
+    //             If (PICF == Zero) { Return (PRTP) }
+    0xA0, 0x0C, 0x93, 0x50, 0x49, 0x43, 0x46, 0x00, 0xA4, 0x50, 0x52, 0x54, 0x50,
+    //             Else { Return (PRTA) } } }
+    0xA1, 0x06, 0xA4, 0x50, 0x52, 0x54, 0x41,
+    //     Device (GSIA) {
+    0x5B, 0x82, 0x19, 0x47, 0x53, 0x49, 0x41,
+    //         Name (_CRS, ResourceTemplate () {
+    0x08, 0x5F, 0x43, 0x52, 0x53, 0x11, 0x0E, 0x0A, 0x0B,
+    //             Interrupt (ResourceConsumer, Level, ActiveHigh, Shared) { 0x11 }
+    0x89, 0x06, 0x00, 0x09, 0x01, 0x11, 0x00, 0x00, 0x00,
+    //         }) } }
+    0x79, 0x00,
+];
+
+pub fn run_self_tests() {
+    let code = parse_aml(SELFTEST_AML_PRT).expect("the _PRT fragment must parse to the end");
+    let mut namespace = Namespace::new(&code);
+    let routes = find_routes(&mut namespace, |_, _, _| None);
+
+    let find = |device, pin| {
+        routes
+            .iter()
+            .find(|e| e.bus == 0 && e.device == device && e.pin == pin)
+            .unwrap_or_else(|| panic!("missing the route of {device}:INT{pin} in {routes:?}"))
+    };
+    // the IO APIC table is used after `_PIC(1)`
+    let direct = find(1, 0);
+    assert_eq!(
+        direct.route,
+        IntxRoute {
+            gsi: 0x10,
+            level_triggered: true,
+            active_low: true,
+        }
+    );
+    assert!(direct.link.is_none());
+    // the settings of the link come from its descriptor, not the PCI defaults
+    let linked = find(2, 1);
+    assert_eq!(
+        linked.route,
+        IntxRoute {
+            gsi: 0x11,
+            level_triggered: true,
+            active_low: false,
+        }
+    );
+    assert_eq!(linked.link.as_deref(), Some("\\_SB_.GSIA"));
+    assert_eq!(routes.len(), 2);
+}
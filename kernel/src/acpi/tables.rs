@@ -5,24 +5,33 @@ use core::{
     slice,
 };
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+use kernel_core::aml::{parse_aml, AmlCode};
 
 use crate::{
+    acpi::generic_address::GenericAddress,
     io::{ByteStr, HexArray},
     memory_management::{
-        memory_layout::{align_down, align_up, virtual2physical, KERNEL_BASE, KERNEL_END, PAGE_4K},
+        memory_layout::{
+            align_down, align_up, is_legacy_readable, physical2virtual, virtual2physical,
+            EXTENDED_OFFSET, KERNEL_BASE, KERNEL_END, PAGE_4K,
+        },
         virtual_space,
     },
     multiboot2::MultiBoot2Info,
 };
 
-use super::aml::{parse_aml, AmlCode};
-
 const BIOS_RO_MEM_START: usize = 0x000E0000;
-const BIOS_RO_MEM_END: usize = 0x000FFFFF;
+const BIOS_RO_MEM_END: usize = 0x00100000;
+// where the BIOS data area stores the segment of the EBDA
+const BDA_EBDA_SEGMENT: usize = 0x040E;
+const EBDA_SEARCH_SIZE: usize = 0x400;
 
 fn physical_to_acpi_memory(addr: usize, size: usize) -> usize {
-    if addr < virtual2physical(KERNEL_END) {
+    // only some parts of the low memory are mapped
+    if is_legacy_readable(addr, size) {
+        physical2virtual(addr)
+    } else if (EXTENDED_OFFSET..virtual2physical(KERNEL_END)).contains(&addr) {
         assert!(addr + size <= virtual2physical(KERNEL_END));
         addr + KERNEL_BASE
     } else {
@@ -45,39 +54,50 @@ fn ensure_at_least_size(addr: usize, size: usize) {
     }
 }
 
+/// Look for the RSDP in the low memory, its on 16 bytes boundaries
+fn find_rsdp(start: usize, end: usize) -> Option<Rsdp> {
+    assert!(is_legacy_readable(start, end - start));
+    (start..end).step_by(16).find_map(|addr| {
+        let rsdp_ptr = physical2virtual(addr) as *const u8;
+        let str = unsafe { slice::from_raw_parts(rsdp_ptr, 8) };
+        if str != b"RSD PTR " {
+            return None;
+        }
+        // calculate checksum
+        let sum = unsafe {
+            slice::from_raw_parts(rsdp_ptr, 20)
+                .iter()
+                .fold(0u8, |acc, &x| acc.wrapping_add(x))
+        };
+        if sum != 0 {
+            return None;
+        }
+        let rsdp_ref = unsafe { &*(rsdp_ptr as *const RsdpV2) };
+        if rsdp_ref.rsdp_v1.revision >= 2 {
+            Some(Rsdp::from_v2(rsdp_ref))
+        } else {
+            Some(Rsdp::from_v1(&rsdp_ref.rsdp_v1))
+        }
+    })
+}
+
 // Note: this requires allocation, so it should be called after the heap is initialized
 pub fn get_acpi_tables(multiboot_info: &MultiBoot2Info) -> Result<BiosTables, ()> {
     let rdsp = multiboot_info
         .get_most_recent_rsdp()
         .or_else(|| {
-            // look for RSDP PTR
-            let mut rsdp_ptr =
-                physical_to_acpi_memory(BIOS_RO_MEM_START, mem::size_of::<Rsdp>()) as *const u8;
-            let end = physical_to_acpi_memory(BIOS_RO_MEM_END, mem::size_of::<Rsdp>()) as *const u8;
-
-            while rsdp_ptr < end {
-                let str = unsafe { slice::from_raw_parts(rsdp_ptr, 8) };
-                if str == b"RSD PTR " {
-                    // calculate checksum
-                    let sum = unsafe {
-                        slice::from_raw_parts(rsdp_ptr, 20)
-                            .iter()
-                            .fold(0u8, |acc, &x| acc.wrapping_add(x))
-                    };
-                    if sum == 0 {
-                        let rsdp_ref = unsafe { &*(rsdp_ptr as *const RsdpV2) };
-                        if rsdp_ref.rsdp_v1.revision >= 2 {
-                            return Some(Rsdp::from_v2(rsdp_ref));
-                        } else {
-                            return Some(Rsdp::from_v1(&rsdp_ref.rsdp_v1));
-                        }
-                    }
-                }
-                rsdp_ptr = unsafe { rsdp_ptr.add(1) };
+            // the first KB of the EBDA, its segment is in the BIOS data area
+            let ebda_segment = unsafe {
+                (physical_to_acpi_memory(BDA_EBDA_SEGMENT, 2) as *const u16).read_unaligned()
+            };
+            let ebda = (ebda_segment as usize) << 4;
+            if is_legacy_readable(ebda, EBDA_SEARCH_SIZE) {
+                find_rsdp(ebda, ebda + EBDA_SEARCH_SIZE)
+            } else {
+                None
             }
-
-            None
         })
+        .or_else(|| find_rsdp(BIOS_RO_MEM_START, BIOS_RO_MEM_END))
         .ok_or(())?;
 
     Ok(BiosTables::new(rdsp))
@@ -475,49 +495,107 @@ pub struct Facp {
     iapc_boot_arch: u16,
     reserved2: u8,
     flags: u32,
-    reset_reg: HexArray<[u8; 12]>,
+    reset_reg: GenericAddress,
     reset_value: u8,
     arm_boot_arch: u16,
     fadt_minor_version: u8,
     x_firmware_control: u64,
     x_dsdt: u64,
-    x_pm1a_event_block: HexArray<[u8; 12]>,
-    x_pm1b_event_block: HexArray<[u8; 12]>,
-    x_pm1a_control_block: HexArray<[u8; 12]>,
-    x_pm1b_control_block: HexArray<[u8; 12]>,
-    x_pm2_control_block: HexArray<[u8; 12]>,
-    x_pm_timer_block: HexArray<[u8; 12]>,
-    x_gpe0_block: HexArray<[u8; 12]>,
-    x_gpe1_block: HexArray<[u8; 12]>,
-    sleep_control_reg: HexArray<[u8; 12]>,
-    sleep_status_reg: HexArray<[u8; 12]>,
+    x_pm1a_event_block: GenericAddress,
+    x_pm1b_event_block: GenericAddress,
+    x_pm1a_control_block: GenericAddress,
+    x_pm1b_control_block: GenericAddress,
+    x_pm2_control_block: GenericAddress,
+    x_pm_timer_block: GenericAddress,
+    x_gpe0_block: GenericAddress,
+    x_gpe1_block: GenericAddress,
+    sleep_control_reg: GenericAddress,
+    sleep_status_reg: GenericAddress,
     hypervisor_vendor_id: u64,
 }
 
 impl Facp {
+    const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+    const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+    const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+    const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
     fn from_header(header: &DescriptionHeader) -> Self {
         let facp_ptr = unsafe { (header as *const DescriptionHeader).add(1) as *const u8 };
-        let facp = unsafe { &*(facp_ptr as *const Facp) };
-        // SAFETY: I'm using this to copy from the same struct
-        unsafe { core::mem::transmute_copy(facp) }
+        let len = header.length as usize - size_of::<DescriptionHeader>();
+        Self::from_bytes(unsafe { slice::from_raw_parts(facp_ptr, len) })
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct ApicGenericAddress {
-    pub address_space_id: u8,
-    pub register_bit_width: u8,
-    pub register_bit_offset: u8,
-    pub reserved: u8,
-    pub address: u64,
+    /// Parses the table after the header, older revisions are shorter, the fields they
+    /// don't have are zero
+    fn from_bytes(body: &[u8]) -> Self {
+        let mut bytes = [0u8; size_of::<Facp>()];
+        let len = body.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&body[..len]);
+        // SAFETY: the struct is packed, and any bytes are valid for its fields
+        unsafe { (bytes.as_ptr() as *const Facp).read_unaligned() }
+    }
+
+    /// The hardware-reduced ACPI interface, there is no PM1 block, no `SCI_EN`, and no
+    /// legacy devices unless the boot flags say so, sleeping uses
+    /// [`Facp::sleep_registers`]
+    pub fn is_hw_reduced(&self) -> bool {
+        self.flags & Self::FLAG_HW_REDUCED_ACPI != 0
+    }
+
+    /// Whether there may be an 8254 PIT, reduced hardware only has one if the boot flags
+    /// say there are legacy devices
+    pub fn has_8254(&self) -> bool {
+        !self.is_hw_reduced() || self.iapc_boot_arch & Self::BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    pub fn has_cmos_rtc(&self) -> bool {
+        self.iapc_boot_arch & Self::BOOT_ARCH_CMOS_RTC_NOT_PRESENT == 0
+    }
+
+    /// The IO ports of the PM1a and PM1b control blocks, `0` if there isn't one, as in
+    /// reduced hardware
+    pub fn pm1_control_ports(&self) -> (u16, u16) {
+        if self.is_hw_reduced() {
+            return (0, 0);
+        }
+        (
+            self.pm1a_control_block as u16,
+            self.pm1b_control_block as u16,
+        )
+    }
+
+    /// The sleep control and status registers, only used by reduced hardware, `None` if
+    /// any is missing
+    pub fn sleep_registers(&self) -> Option<(GenericAddress, GenericAddress)> {
+        let (control, status) = (self.sleep_control_reg, self.sleep_status_reg);
+        (self.is_hw_reduced() && control.is_present() && status.is_present())
+            .then_some((control, status))
+    }
+
+    /// The port and the command that switch the hardware from legacy to ACPI mode,
+    /// `None` if its always in ACPI mode, which reduced hardware is
+    pub fn acpi_enable_command(&self) -> Option<(u16, u8)> {
+        let port = self.smi_command_port;
+        (!self.is_hw_reduced() && port != 0 && self.acpi_enable != 0)
+            .then_some((port as u16, self.acpi_enable))
+    }
+
+    /// The reset register and the value that resets the machine, `None` if its not
+    /// supported
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        let reset_reg = self.reset_reg;
+        (self.flags & Self::FLAG_RESET_REG_SUP != 0 && reset_reg.is_present())
+            .then_some((reset_reg, self.reset_value))
+    }
 }
 
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
 pub struct Hpet {
     pub event_timer_block_id: u32,
-    pub base_address: ApicGenericAddress,
+    pub base_address: GenericAddress,
     pub hpet_number: u8,
     pub main_counter_minimum_clock_tick: u16,
     pub page_protection: u8,
@@ -533,9 +611,8 @@ impl Hpet {
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct Dsdt {
-    aml_code: AmlCode,
+    aml_code: Arc<AmlCode>,
 }
 
 impl Dsdt {
@@ -543,9 +620,18 @@ impl Dsdt {
         let dsdt_ptr = unsafe { (header as *const DescriptionHeader).add(1) as *const u8 };
         let data_len = header.length as usize - size_of::<DescriptionHeader>();
         let data = unsafe { slice::from_raw_parts(dsdt_ptr, data_len) };
-        let aml_code = parse_aml(data).unwrap();
+        let aml_code = Arc::new(parse_aml(data).unwrap());
         Self { aml_code }
     }
+
+    pub fn aml_code(&self) -> &AmlCode {
+        &self.aml_code
+    }
+
+    /// The code, for the ones that keep it after the tables are gone
+    pub fn shared_aml_code(&self) -> Arc<AmlCode> {
+        self.aml_code.clone()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -622,3 +708,174 @@ impl fmt::Display for BiosTables {
         Ok(())
     }
 }
+
+/// Nested element references and method calls in target position, reduced from a DSDT
+/// that used to parse an `Index` with a `None` target and then lose track of the terms
+#[rustfmt::skip]
+const SELFTEST_AML_TARGETS: &[u8] = &[
+    // Name (PKG0, Package (2) { Package (2) { One, 2 }, Package (1) { 3 } })
+    0x08, 0x50, 0x4B, 0x47, 0x30, 0x12, 0x0D, 0x02, 0x12, 0x05, 0x02, 0x01, 0x0A, 0x02, 0x12, 0x04, 0x01, 0x0A, 0x03,
+    // Method (MTHD, 1) { Return (Arg0) }
+    0x14, 0x08, 0x4D, 0x54, 0x48, 0x44, 0x01, 0xA4, 0x68,
+    // Method (TEST, 2) {
+    0x14, 0x49, 0x04, 0x54, 0x45, 0x53, 0x54, 0x02,
+    //     Store (Index (DerefOf (Index (PKG0, One)), Zero), Local0)
+    0x70, 0x88, 0x83, 0x88, 0x50, 0x4B, 0x47, 0x30, 0x01, 0x00, 0x00, 0x00, 0x60,
+    //     Store (One, Index (DerefOf (Index (Arg0, Zero)), One))
+    0x70, 0x01, 0x88, 0x83, 0x88, 0x68, 0x00, 0x00, 0x01, 0x00,
+    //     Index (DerefOf (Index (Arg1, Zero)), One, Local1)
+    0x88, 0x83, 0x88, 0x69, 0x00, 0x00, 0x01, 0x61,
+    //     Index (Arg0, Zero, MTHD (Arg1))
+    0x88, 0x68, 0x00, 0x4D, 0x54, 0x48, 0x44, 0x69,
+    //     Store (Local1, MTHD (Local0))
+    0x70, 0x61, 0x4D, 0x54, 0x48, 0x44, 0x60,
+    //     Store (Zero, RefOf (Local2))
+    0x70, 0x00, 0x71, 0x62,
+    //     Index (DerefOf (Index (DerefOf (Index (Arg0, One)), Zero)), MTHD (Zero), Local3)
+    0x88, 0x83, 0x88, 0x83, 0x88, 0x68, 0x01, 0x00, 0x00, 0x00, 0x4D, 0x54, 0x48, 0x44, 0x00, 0x63,
+    // Method (TST2, 1) {
+    0x14, 0x10, 0x54, 0x53, 0x54, 0x32, 0x01,
+    //     Store (One, Index (DerefOf (Index (Arg0, Zero)), One, Local4))
+    0x70, 0x01, 0x88, 0x83, 0x88, 0x68, 0x00, 0x00, 0x01, 0x64,
+];
+
+/// The registers of the generic event device of QEMU's `microvm`, sleep control, sleep status
+/// then reset
+const SELFTEST_GED_REGS: u64 = 0xFEA0_0200;
+
+/// The FADT QEMU builds for `-machine microvm` (revision 5, hardware-reduced, with the sleep
+/// and reset registers of its generic event device in memory), or if not `reduced`, one like
+/// the `pc` machine's, with the PM1 blocks and the reset register in the IO space
+pub fn selftest_fadt(reduced: bool) -> Facp {
+    selftest_fadt_table(reduced, FADT_SIZE)
+}
+
+const FADT_SIZE: usize = size_of::<DescriptionHeader>() + size_of::<Facp>();
+
+/// [`selftest_fadt`] cut to `len` bytes, the header included, as older revisions are shorter
+fn selftest_fadt_table(reduced: bool, len: usize) -> Facp {
+    fn put(table: &mut [u8], offset: usize, bytes: &[u8]) {
+        table[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    fn gas(address_space_id: u8, address: u64) -> [u8; 12] {
+        let mut gas = [0u8; 12];
+        gas[0] = address_space_id;
+        gas[1] = 8;
+        gas[4..].copy_from_slice(&address.to_le_bytes());
+        gas
+    }
+    use crate::acpi::generic_address::address_space::{SYSTEM_IO, SYSTEM_MEMORY};
+
+    // the offsets are from the start of the table, as in the spec
+    let mut table = [0u8; FADT_SIZE];
+    if reduced {
+        put(
+            &mut table,
+            112,
+            &(Facp::FLAG_RESET_REG_SUP | Facp::FLAG_HW_REDUCED_ACPI).to_le_bytes(),
+        );
+        put(&mut table, 116, &gas(SYSTEM_MEMORY, SELFTEST_GED_REGS + 2));
+        table[128] = 0x42;
+        put(&mut table, 244, &gas(SYSTEM_MEMORY, SELFTEST_GED_REGS));
+        put(&mut table, 256, &gas(SYSTEM_MEMORY, SELFTEST_GED_REGS + 1));
+    } else {
+        put(&mut table, 46, &9u16.to_le_bytes());
+        put(&mut table, 48, &0xB2u32.to_le_bytes());
+        table[52] = 0xF1;
+        put(&mut table, 56, &0x600u32.to_le_bytes());
+        put(&mut table, 64, &0x604u32.to_le_bytes());
+        put(&mut table, 76, &0x608u32.to_le_bytes());
+        table[88..92].copy_from_slice(&[4, 2, 0, 4]);
+        // legacy devices and 8042
+        put(&mut table, 109, &0b11u16.to_le_bytes());
+        put(&mut table, 112, &Facp::FLAG_RESET_REG_SUP.to_le_bytes());
+        put(&mut table, 116, &gas(SYSTEM_IO, 0xCF9));
+        table[128] = 0x06;
+    }
+    Facp::from_bytes(&table[size_of::<DescriptionHeader>()..len])
+}
+
+/// The FADT of `microvm` and of `pc`, and an old one without the reset register
+fn selftest_fadt_parsing() {
+    use crate::acpi::generic_address::address_space::{SYSTEM_IO, SYSTEM_MEMORY};
+
+    let register = |address_space_id, address| GenericAddress {
+        address_space_id,
+        register_bit_width: 8,
+        register_bit_offset: 0,
+        access_size: 0,
+        address,
+    };
+
+    let microvm = selftest_fadt(true);
+    assert!(microvm.is_hw_reduced());
+    assert!(!microvm.has_8254() && microvm.has_cmos_rtc());
+    assert_eq!(microvm.pm1_control_ports(), (0, 0));
+    assert_eq!(microvm.acpi_enable_command(), None);
+    assert_eq!(
+        microvm.sleep_registers(),
+        Some((
+            register(SYSTEM_MEMORY, SELFTEST_GED_REGS),
+            register(SYSTEM_MEMORY, SELFTEST_GED_REGS + 1)
+        ))
+    );
+    assert_eq!(
+        microvm.reset_register(),
+        Some((register(SYSTEM_MEMORY, SELFTEST_GED_REGS + 2), 0x42))
+    );
+
+    let pc = selftest_fadt(false);
+    assert!(!pc.is_hw_reduced());
+    assert!(pc.has_8254() && pc.has_cmos_rtc());
+    assert_eq!(pc.pm1_control_ports(), (0x604, 0));
+    assert_eq!(pc.acpi_enable_command(), Some((0xB2, 0xF1)));
+    assert_eq!(pc.sleep_registers(), None);
+    assert_eq!(
+        pc.reset_register(),
+        Some((register(SYSTEM_IO, 0xCF9), 0x06))
+    );
+
+    // revision 1 ends before the reset register, the rest must not be read
+    let old = selftest_fadt_table(false, 116);
+    assert_eq!(old.pm1_control_ports(), (0x604, 0));
+    assert_eq!(old.reset_register(), None);
+
+    for (access_size, bit_width, bytes) in [
+        (0, 8, Some(1)),
+        (0, 12, Some(2)),
+        (0, 32, Some(4)),
+        (0, 0, Some(1)),
+        (3, 8, Some(4)),
+        (4, 0, Some(8)),
+        (5, 8, None),
+        (0, 24, None),
+    ] {
+        let gas = GenericAddress {
+            access_size,
+            register_bit_width: bit_width,
+            ..register(SYSTEM_IO, 0x600)
+        };
+        assert_eq!(gas.access_bytes(), bytes, "{gas:?} size {access_size}");
+    }
+}
+
+pub fn run_self_tests() {
+    selftest_fadt_parsing();
+    let code = parse_aml(SELFTEST_AML_TARGETS).expect("the AML fragment must parse to the end");
+    let text = format!("{code}");
+    for line in [
+        "Local0 = DerefOf (PKG0[One])[Zero]",
+        "DerefOf (Arg0[Zero])[One] = One",
+        "Local1 = DerefOf (Arg1[Zero])[One]",
+        "MTHD (Arg1) = Arg0[Zero]",
+        "MTHD (Local0) = Local1",
+        "RefOf (Local2) = Zero",
+        "Local3 = DerefOf (DerefOf (Arg0[One])[Zero])[MTHD (Zero)]",
+        "(Local4 = DerefOf (Arg0[Zero])[One]) = One",
+    ] {
+        assert!(
+            text.lines().any(|l| l.trim() == line),
+            "missing {line:?} in:\n{text}"
+        );
+    }
+}
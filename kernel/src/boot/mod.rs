@@ -0,0 +1,3 @@
+//! Helpers for the boot sequence of `kernel_main`.
+
+pub mod tasks;
@@ -0,0 +1,610 @@
+//! A stage of the boot as a graph of tasks, each declaring the tasks it needs done before it.
+//!
+//! The tasks are added in the order they should run in when nothing else decides it, and
+//! [`TaskGraph::run`] runs them on all the CPUs, before they are released to the scheduler
+//! (see [`smp::run_on_all`]): each CPU takes the first task whose dependencies are finished,
+//! until none is left. So a slow task, like probing an empty IDE channel, only delays the ones
+//! depending on it. The tasks added with [`TaskGraph::add_exclusive`], the self tests of the
+//! devices, run alone, they use the same devices as the tasks that don't know about them.
+//! With one CPU, or `boot_tasks=sequential` in the cmdline, the tasks run one after the other,
+//! to compare the times.
+//!
+//! A task fails by returning an error. The tasks depending on it are skipped, and the ones
+//! depending on those, unless it was added with [`TaskGraph::add_optional`], then the failure
+//! is only logged. `boot_fail=<task>` in the cmdline makes a task fail without running, to test
+//! that the boot gets to `init` without it.
+//!
+//! The boot graph is kept as a [`Timeline`] in `/devices/boot_tasks`: when and on which CPU
+//! each task started, how long it took, and the critical path, the chain of dependencies that
+//! takes the longest, which is as fast as the stage can get with the tasks in parallel. The
+//! sum of the times of the tasks is how long the stage takes when they run one after the other.
+
+use core::{
+    fmt::{self, Write},
+    hint,
+};
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    cpu::{self, smp},
+    devices::{self, clock, Device},
+    fs::FileSystemError,
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+static BOOT_TIMELINE: OnceLock<Timeline> = OnceLock::new();
+
+type TaskFn<'a> = Box<dyn FnOnce() -> Result<(), String> + Send + 'a>;
+
+struct Task<'a> {
+    name: &'static str,
+    deps: &'static [&'static str],
+    kind: TaskKind,
+    run: TaskFn<'a>,
+}
+
+/// How a task runs with the others, and what its failure does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Its failure skips the tasks depending on it
+    Required,
+    /// The tasks depending on it run even if it fails
+    Optional,
+    /// Same as [`Self::Required`], and no other task runs at the same time
+    Exclusive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    Failed(String),
+    /// A task it depends on failed, or was skipped
+    Skipped {
+        because: &'static str,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskRecord {
+    pub name: &'static str,
+    pub deps: &'static [&'static str],
+    pub kind: TaskKind,
+    pub outcome: Outcome,
+    /// The id of the CPU it ran on
+    pub cpu: usize,
+    pub start_nanos: u64,
+    pub duration_nanos: u64,
+}
+
+impl TaskRecord {
+    /// Whether the tasks depending on this one can run
+    fn allows_dependents(&self) -> bool {
+        self.kind == TaskKind::Optional || self.outcome == Outcome::Done
+    }
+
+    fn end_nanos(&self) -> u64 {
+        self.start_nanos + self.duration_nanos
+    }
+}
+
+/// The tasks of a [`TaskGraph::run`], shared by the CPUs running them
+struct RunState<'a> {
+    pending: Vec<Task<'a>>,
+    // in the order they finished, so the dependencies come first
+    records: Vec<TaskRecord>,
+    running: usize,
+    exclusive_running: bool,
+}
+
+impl RunState<'_> {
+    fn finished(&self, name: &str) -> Option<&TaskRecord> {
+        self.records.iter().find(|record| record.name == name)
+    }
+
+    /// The first of the pending tasks that can start now
+    fn next_ready(&self) -> Option<usize> {
+        if self.exclusive_running {
+            return None;
+        }
+        self.pending.iter().position(|task| {
+            (task.kind != TaskKind::Exclusive || self.running == 0)
+                && task.deps.iter().all(|dep| self.finished(dep).is_some())
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct TaskGraph<'a> {
+    tasks: Vec<Task<'a>>,
+}
+
+impl<'a> TaskGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a task that runs after all of `deps`, and is skipped if any of them fails
+    pub fn add(
+        &mut self,
+        name: &'static str,
+        deps: &'static [&'static str],
+        run: impl FnOnce() -> Result<(), String> + Send + 'a,
+    ) {
+        self.push(name, deps, TaskKind::Required, Box::new(run));
+    }
+
+    /// Same as [`add`](Self::add), but the tasks depending on it run even if it fails
+    pub fn add_optional(
+        &mut self,
+        name: &'static str,
+        deps: &'static [&'static str],
+        run: impl FnOnce() -> Result<(), String> + Send + 'a,
+    ) {
+        self.push(name, deps, TaskKind::Optional, Box::new(run));
+    }
+
+    /// Same as [`add`](Self::add), but it waits for the running tasks to finish, and the others
+    /// wait for it
+    pub fn add_exclusive(
+        &mut self,
+        name: &'static str,
+        deps: &'static [&'static str],
+        run: impl FnOnce() -> Result<(), String> + Send + 'a,
+    ) {
+        self.push(name, deps, TaskKind::Exclusive, Box::new(run));
+    }
+
+    fn push(
+        &mut self,
+        name: &'static str,
+        deps: &'static [&'static str],
+        kind: TaskKind,
+        run: TaskFn<'a>,
+    ) {
+        assert!(
+            self.tasks.iter().all(|task| task.name != name),
+            "boot task {name} added twice"
+        );
+        self.tasks.push(Task {
+            name,
+            deps,
+            kind,
+            run,
+        });
+    }
+
+    /// Runs all the tasks, on all the CPUs if `parallel`, `forced_failure` fails the task with
+    /// this name instead of running it.
+    ///
+    /// Panics if a task depends on one that is not in the graph, or if the dependencies have
+    /// a cycle, since that is a mistake in the boot code
+    pub fn run(self, forced_failure: Option<&str>, parallel: bool) -> Timeline {
+        self.run_logged(forced_failure, parallel, true)
+    }
+
+    /// [`run`](Self::run), printing the failed and skipped tasks if `log`
+    fn run_logged(self, forced_failure: Option<&str>, parallel: bool, log: bool) -> Timeline {
+        for task in &self.tasks {
+            for dep in task.deps {
+                assert!(
+                    self.tasks.iter().any(|other| other.name == *dep),
+                    "boot task {} depends on unknown task {dep}",
+                    task.name
+                );
+            }
+        }
+
+        let state = Mutex::new(RunState {
+            pending: self.tasks,
+            records: Vec::new(),
+            running: 0,
+            exclusive_running: false,
+        });
+        let worker = || run_tasks(&state, forced_failure, log);
+        let workers = if parallel {
+            smp::run_on_all(&worker);
+            smp::online_cpus()
+        } else {
+            worker();
+            1
+        };
+        let records = core::mem::take(&mut state.lock().records);
+        Timeline { records, workers }
+    }
+}
+
+/// Takes the tasks of `state` that are ready and runs them, until none is left, see
+/// [`TaskGraph::run_logged`]. Called on each CPU of the run
+fn run_tasks(state: &Mutex<RunState>, forced_failure: Option<&str>, log: bool) {
+    loop {
+        let mut current = state.lock();
+        if current.pending.is_empty() {
+            return;
+        }
+        let Some(index) = current.next_ready() else {
+            if current.running == 0 {
+                let names = current
+                    .pending
+                    .iter()
+                    .map(|task| task.name)
+                    .collect::<Vec<_>>();
+                panic!("boot tasks {names:?} depend on each other")
+            }
+            // another CPU runs what they wait for
+            drop(current);
+            hint::spin_loop();
+            continue;
+        };
+        let task = current.pending.remove(index);
+        let blocked_by = task.deps.iter().copied().find(|dep| {
+            current
+                .finished(dep)
+                .is_some_and(|record| !record.allows_dependents())
+        });
+        current.running += 1;
+        current.exclusive_running = task.kind == TaskKind::Exclusive;
+        drop(current);
+
+        let start_nanos = clock::uptime_nanos();
+        let outcome = if let Some(because) = blocked_by {
+            Outcome::Skipped { because }
+        } else if forced_failure == Some(task.name) {
+            Outcome::Failed(String::from("forced by `boot_fail`"))
+        } else {
+            match (task.run)() {
+                Ok(()) => Outcome::Done,
+                Err(e) => Outcome::Failed(e),
+            }
+        };
+        let duration_nanos = clock::uptime_nanos() - start_nanos;
+        match &outcome {
+            _ if !log => {}
+            Outcome::Done => {}
+            Outcome::Failed(e) => {
+                println!("boot task {} failed: {e}", task.name);
+            }
+            Outcome::Skipped { because } => {
+                println!("boot task {} skipped, {because} failed", task.name);
+            }
+        }
+        let mut current = state.lock();
+        current.records.push(TaskRecord {
+            name: task.name,
+            deps: task.deps,
+            kind: task.kind,
+            outcome,
+            cpu: cpu::cpu().id,
+            start_nanos,
+            duration_nanos,
+        });
+        current.running -= 1;
+        current.exclusive_running = false;
+    }
+}
+
+/// The tasks of a graph in the order they finished, with their timings
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    records: Vec<TaskRecord>,
+    // the number of CPUs that ran them
+    workers: usize,
+}
+
+impl Timeline {
+    pub fn record(&self, name: &str) -> Option<&TaskRecord> {
+        self.records.iter().find(|record| record.name == name)
+    }
+
+    /// The chain of dependencies with the longest total time, from the first task of it, with
+    /// that total
+    pub fn critical_path(&self) -> (Vec<&'static str>, u64) {
+        // the records are in an order where the dependencies come first, so each one can use
+        // the totals of its dependencies
+        let mut totals: Vec<(u64, Option<usize>)> = Vec::with_capacity(self.records.len());
+        for record in &self.records {
+            let longest_dep = record
+                .deps
+                .iter()
+                .filter_map(|dep| self.records.iter().position(|other| other.name == *dep))
+                .max_by_key(|&dep| totals[dep].0);
+            let before = longest_dep.map_or(0, |dep| totals[dep].0);
+            totals.push((before + record.duration_nanos, longest_dep));
+        }
+        let Some(mut index) = (0..totals.len()).max_by_key(|&index| totals[index].0) else {
+            return (Vec::new(), 0);
+        };
+        let total = totals[index].0;
+        let mut path = alloc::vec![self.records[index].name];
+        while let Some(dep) = totals[index].1 {
+            path.push(self.records[dep].name);
+            index = dep;
+        }
+        path.reverse();
+        (path, total)
+    }
+
+    /// The tasks that must finish for `name` to run, through the tasks that are not optional
+    fn needed_by(&self, name: &str) -> Vec<&'static str> {
+        let mut needed = Vec::new();
+        let mut stack = alloc::vec![name];
+        while let Some(name) = stack.pop() {
+            let Some(record) = self.record(name) else {
+                continue;
+            };
+            for &dep in record.deps {
+                let optional = self
+                    .record(dep)
+                    .is_some_and(|dep| dep.kind == TaskKind::Optional);
+                if !optional && !needed.contains(&dep) {
+                    needed.push(dep);
+                    stack.push(dep);
+                }
+            }
+        }
+        needed
+    }
+
+    /// The time from the start of the first task to the end of the last
+    pub fn total_nanos(&self) -> u64 {
+        let start = self.records.iter().map(|record| record.start_nanos).min();
+        let end = self.records.iter().map(TaskRecord::end_nanos).max();
+        match (start, end) {
+            (Some(start), Some(end)) => end - start,
+            _ => 0,
+        }
+    }
+
+    /// The sum of the times of the tasks, the total if they ran one after the other
+    pub fn sequential_nanos(&self) -> u64 {
+        self.records
+            .iter()
+            .map(|record| record.duration_nanos)
+            .sum()
+    }
+}
+
+/// `12.345ms`
+struct Millis(u64);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.0 / 1000;
+        write!(f, "{}.{:03}ms", micros / 1000, micros % 1000)
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for record in &self.records {
+            let kind = match record.kind {
+                TaskKind::Required => "",
+                TaskKind::Optional => " (optional)",
+                TaskKind::Exclusive => " (exclusive)",
+            };
+            write!(
+                f,
+                "{}{kind}: start {} on cpu {}, took {}, ",
+                record.name,
+                Millis(record.start_nanos),
+                record.cpu,
+                Millis(record.duration_nanos)
+            )?;
+            match &record.outcome {
+                Outcome::Done => f.write_str("done")?,
+                Outcome::Failed(e) => write!(f, "failed: {e}")?,
+                Outcome::Skipped { because } => write!(f, "skipped, {because} failed")?,
+            }
+            if !record.deps.is_empty() {
+                write!(f, ", after {}", record.deps.join(", "))?;
+            }
+            f.write_char('\n')?;
+        }
+        let (path, critical) = self.critical_path();
+        writeln!(
+            f,
+            "critical path: {}, {} of {}",
+            path.join(" -> "),
+            Millis(critical),
+            Millis(self.total_nanos())
+        )?;
+        let (total, sequential) = (self.total_nanos(), self.sequential_nanos());
+        writeln!(
+            f,
+            "on {} cpus: {} instead of {} one after the other, {} saved",
+            self.workers,
+            Millis(total),
+            Millis(sequential),
+            Millis(sequential.saturating_sub(total))
+        )
+    }
+}
+
+/// `/devices/boot_tasks`, the timeline of the boot tasks
+#[derive(Debug)]
+struct BootTasksInfo;
+
+impl Device for BootTasksInfo {
+    fn name(&self) -> &str {
+        "boot_tasks"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let text = BOOT_TIMELINE
+            .try_get()
+            .map(|timeline| format!("{timeline}"))
+            .unwrap_or_default();
+        Ok(devices::read_bytes(text.as_bytes(), offset, buf))
+    }
+}
+
+/// Keeps the timeline of the boot graph for `/devices/boot_tasks`
+pub fn publish(timeline: Timeline) {
+    BOOT_TIMELINE
+        .set(timeline)
+        .unwrap_or_else(|_| panic!("boot timeline already published"));
+    devices::register_device(Arc::new(BootTasksInfo));
+}
+
+/// Checks the order, the skipping and the timeline on graphs of tasks that log what they ran,
+/// one after the other, then the tasks running at the same time on all the CPUs
+pub fn run_self_tests() {
+    let ran = Mutex::new(Vec::new());
+    let task = |name: &'static str, result: Result<(), &'static str>| {
+        let ran = &ran;
+        move || {
+            ran.lock().push(name);
+            result.map_err(String::from)
+        }
+    };
+
+    // added before their dependencies, they must still run after them
+    let mut graph = TaskGraph::new();
+    graph.add("mount", &["disk", "cache"], task("mount", Ok(())));
+    graph.add("disk", &["pci"], task("disk", Ok(())));
+    graph.add_optional("second_disk", &["pci"], task("second_disk", Err("empty")));
+    graph.add("pci", &[], task("pci", Ok(())));
+    graph.add("cache", &[], task("cache", Ok(())));
+    graph.add("shell", &["mount", "second_disk"], task("shell", Ok(())));
+    let timeline = graph.run(None, false);
+    assert_eq!(
+        *ran.lock(),
+        ["pci", "disk", "second_disk", "cache", "mount", "shell"],
+        "boot tasks self test: wrong order"
+    );
+    assert_eq!(
+        timeline.record("second_disk").unwrap().outcome,
+        Outcome::Failed(String::from("empty"))
+    );
+    assert_eq!(timeline.record("shell").unwrap().outcome, Outcome::Done);
+    let (path, total) = timeline.critical_path();
+    assert!(path.last() == Some(&"shell") && total <= timeline.total_nanos());
+    let text = format!("{timeline}");
+    assert!(
+        text.contains("second_disk (optional): start ")
+            && text.contains("failed: empty, after pci\n")
+            && text.contains("critical path: ")
+            && text.contains("on 1 cpus: "),
+        "{text}"
+    );
+
+    // a failed task skips what depends on it, even through other tasks
+    ran.lock().clear();
+    let mut graph = TaskGraph::new();
+    graph.add("disk", &[], task("disk", Ok(())));
+    graph.add("mount", &["disk"], task("mount", Ok(())));
+    graph.add("shell", &["mount"], task("shell", Ok(())));
+    graph.add("keyboard", &[], task("keyboard", Ok(())));
+    let timeline = graph.run(Some("disk"), false);
+    assert_eq!(*ran.lock(), ["keyboard"]);
+    assert_eq!(
+        timeline.record("shell").unwrap().outcome,
+        Outcome::Skipped { because: "mount" }
+    );
+    assert_eq!(
+        timeline.record("mount").unwrap().outcome,
+        Outcome::Skipped { because: "disk" }
+    );
+
+    // without any task
+    assert_eq!(
+        TaskGraph::new().run(None, true).critical_path(),
+        (Vec::new(), 0)
+    );
+    selftest_parallel();
+    println!("Boot tasks self tests passed");
+}
+
+/// With more than one CPU, two tasks that wait for each other both finish, on different
+/// CPUs, and an exclusive task runs alone
+fn selftest_parallel() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const WAIT_NANOS: u64 = 1_000_000_000;
+    if smp::online_cpus() == 1 {
+        println!("boot tasks self test: one CPU, not running tasks in parallel");
+        return;
+    }
+    let started = AtomicUsize::new(0);
+    let meet = || {
+        started.fetch_add(1, Ordering::AcqRel);
+        let start = clock::uptime_nanos();
+        while started.load(Ordering::Acquire) < 2 {
+            if clock::uptime_nanos() - start > WAIT_NANOS {
+                return Err(String::from("the other task didn't start"));
+            }
+            hint::spin_loop();
+        }
+        Ok(())
+    };
+    let mut graph = TaskGraph::new();
+    graph.add("first", &[], meet);
+    graph.add("second", &[], meet);
+    graph.add_exclusive("alone", &[], || Ok(()));
+    graph.add("after", &["first", "second"], || Ok(()));
+    let timeline = graph.run(None, true);
+    let record = |name| timeline.record(name).unwrap();
+    for name in ["first", "second", "alone", "after"] {
+        assert_eq!(
+            record(name).outcome,
+            Outcome::Done,
+            "boot tasks self test: {name} in parallel"
+        );
+    }
+    assert_ne!(record("first").cpu, record("second").cpu);
+    let alone = record("alone");
+    assert!(
+        timeline
+            .records
+            .iter()
+            .filter(|other| other.name != "alone")
+            .all(|other| other.end_nanos() <= alone.start_nanos
+                || other.start_nanos >= alone.end_nanos()),
+        "boot tasks self test: the exclusive task didn't run alone"
+    );
+    assert!(timeline.total_nanos() <= timeline.sequential_nanos());
+}
+
+/// Runs the graph of the boot again with tasks that do nothing, forcing each task to fail in
+/// turn, like `boot_fail` does. `required` is the task the boot panics without, it must finish
+/// unless the failed task is one it needs, so the boot gets to `init` and the shell without the
+/// others
+pub fn run_boot_graph_self_tests(required: &'static str) {
+    println!("Running boot graph self tests...");
+    let timeline = BOOT_TIMELINE.get();
+    let needed = timeline.needed_by(required);
+    assert!(
+        timeline
+            .records
+            .iter()
+            .any(|record| record.kind == TaskKind::Optional),
+        "boot graph self test: no optional task"
+    );
+    for failed in &timeline.records {
+        let mut graph = TaskGraph::new();
+        for record in &timeline.records {
+            graph.push(record.name, record.deps, record.kind, Box::new(|| Ok(())));
+        }
+        let replay = graph.run_logged(Some(failed.name), false, false);
+        let is_needed = failed.name == required || needed.contains(&failed.name);
+        assert_eq!(
+            replay.record(required).unwrap().outcome == Outcome::Done,
+            !is_needed,
+            "boot graph self test: {required} with `boot_fail={}`",
+            failed.name
+        );
+        // the others still run, only what depends on the failed task is skipped
+        for record in &replay.records {
+            let skipped = matches!(record.outcome, Outcome::Skipped { .. });
+            assert_eq!(
+                skipped,
+                record.name != failed.name
+                    && timeline.needed_by(record.name).contains(&failed.name),
+                "boot graph self test: {} with `boot_fail={}`",
+                record.name,
+                failed.name
+            );
+        }
+    }
+    println!("Boot graph self tests passed");
+}
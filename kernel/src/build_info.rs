@@ -0,0 +1,150 @@
+//! What this kernel was built from, filled by `build.rs` at compile time.
+//!
+//! Shown in the boot [`banner`], in `/devices/version` (a `<key>: <value>` line for each of
+//! the values here), and to userspace as the `version` of `SYS_SYSINFO`. The values that
+//! can't be found when building (i.e. without git) are `unknown`.
+//!
+//! The build script runs again only when the git head changes, so the `-dirty` of [`GIT`]
+//! and the [`TIMESTAMP`] are of the last time it did.
+
+use core::fmt;
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use kernel_user_link::sysinfo::SYSINFO_VERSION_LEN;
+
+use crate::devices::{
+    self,
+    generated::{self, Cursor, Generator},
+    Device,
+};
+
+/// `git describe --always --dirty --tags` of the tree
+pub const GIT: &str = env!("KERNEL_BUILD_GIT");
+/// When the build script last ran, as `YYYY-MM-DDTHH:MM:SSZ`, or `SOURCE_DATE_EPOCH`
+pub const TIMESTAMP: &str = env!("KERNEL_BUILD_TIMESTAMP");
+/// The cargo profile, `debug` or `release`
+pub const PROFILE: &str = env!("KERNEL_BUILD_PROFILE");
+/// The enabled cargo features, separated by spaces
+pub const FEATURES: &str = env!("KERNEL_BUILD_FEATURES");
+/// `rustc -V` of the compiler
+pub const RUSTC: &str = env!("KERNEL_BUILD_RUSTC");
+/// The version of the crate and the commit, this is what userspace gets
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("KERNEL_BUILD_GIT"));
+
+pub fn features() -> impl Iterator<Item = &'static str> {
+    FEATURES.split_whitespace()
+}
+
+/// [`VERSION`] for `SysInfo::version`, cut at a character boundary if too long
+pub fn sysinfo_version() -> [u8; SYSINFO_VERSION_LEN] {
+    let mut version = [0; SYSINFO_VERSION_LEN];
+    let mut len = VERSION.len().min(SYSINFO_VERSION_LEN);
+    while !VERSION.is_char_boundary(len) {
+        len -= 1;
+    }
+    version[..len].copy_from_slice(&VERSION.as_bytes()[..len]);
+    version
+}
+
+/// The line printed first at boot
+pub fn banner() -> impl fmt::Display {
+    struct Banner;
+
+    impl fmt::Display for Banner {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "Amjad50/OS kernel {VERSION} ({PROFILE}, built {TIMESTAMP}"
+            )?;
+            if !FEATURES.is_empty() {
+                write!(f, ", features: {FEATURES}")?;
+            }
+            write!(f, ")")
+        }
+    }
+
+    Banner
+}
+
+/// `/devices/version`, fixed for the whole boot
+#[derive(Debug)]
+struct VersionInfo;
+
+impl Device for VersionInfo {
+    fn name(&self) -> &str {
+        "version"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(VersionInfo))
+    }
+}
+
+/// The field of the cursor is the line
+impl Generator for VersionInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        generated::render_display_lines(
+            &format_args!(
+                "version: {VERSION}\ngit: {GIT}\nbuilt: {TIMESTAMP}\nprofile: {PROFILE}\n\
+                 features: {FEATURES}\nrustc: {RUSTC}\n"
+            ),
+            cursor,
+            out,
+        )
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(VersionInfo));
+}
+
+/// `/devices/version`, the banner and the version of `SYS_SYSINFO` agree, and the features
+/// are the ones compiled in
+pub fn run_self_tests() {
+    println!("Running build info self tests...");
+    let content = crate::fs::open("/devices/version")
+        .and_then(|mut file| file.read_to_end())
+        .expect("build info self test: could not read `/devices/version`");
+    let content = String::from_utf8(content).unwrap();
+    let value = |key: &str| {
+        content
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+            .unwrap_or_else(|| panic!("build info self test: no `{key}` in `/devices/version`"))
+    };
+    assert_eq!(value("version"), VERSION);
+    assert_eq!(value("git"), GIT);
+    assert_eq!(value("built"), TIMESTAMP);
+    assert_eq!(value("profile"), PROFILE);
+    assert_eq!(value("features"), FEATURES);
+    assert_eq!(value("rustc"), RUSTC);
+    assert_eq!(content.lines().count(), 6);
+
+    let banner = format!("{}", banner());
+    assert!(banner.contains(VERSION) && banner.contains(TIMESTAMP));
+
+    let version = sysinfo_version();
+    let len = version
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(version.len());
+    assert_eq!(len, VERSION.len().min(SYSINFO_VERSION_LEN));
+    assert!(VERSION.as_bytes().starts_with(&version[..len]));
+
+    let compiled: Vec<&str> = [
+        // sorted, like the build script gives them
+        ("earlycon", cfg!(feature = "earlycon")),
+        ("heaptrack", cfg!(feature = "heaptrack")),
+        ("irqoff", cfg!(feature = "irqoff")),
+        ("kasan", cfg!(feature = "kasan")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    assert_eq!(features().collect::<Vec<_>>(), compiled);
+    for feature in &compiled {
+        assert!(banner.contains(feature));
+    }
+
+    println!("Build info self tests passed");
+}
@@ -1 +1 @@
-pub mod ring;
+pub use kernel_core::{bitset, ring};
@@ -0,0 +1,115 @@
+//! Recovering from faults in the kernel at known instructions.
+//!
+//! An entry of the table is the address of an instruction that can fault, and where to continue
+//! if it does, the exception handler looks it up before panicking.
+//!
+//! Only [`copy_bytes`] has entries, all the accesses to the memory of user processes go through
+//! it: the copies of the syscalls and of the signal frames, the initial stack of a process, the
+//! memory read for the crash logs and the core dumps, and the frames of the profiler. A bad
+//! pointer that got through the checks, or a page another thread unmapped meanwhile, gives an
+//! error instead of a panic. `kdb` reads any address through it too.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// `rep movsb` can be resumed, when it faults `rcx` is still the number of bytes left
+core::arch::global_asm!(
+    r#"
+.section .text.exception_table_copy_bytes
+.global exception_table_copy_bytes
+exception_table_copy_bytes:
+    mov rcx, rdx
+1:
+    rep movsb
+    xor eax, eax
+    ret
+2:
+    mov rax, rcx
+    ret
+
+.section .exception_table, "a"
+.align 8
+    .quad 1b, 2b
+.previous
+"#
+);
+
+extern "C" {
+    /// Returns the number of bytes that were not copied because of a fault
+    fn exception_table_copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    static exception_table_start: ExceptionTableEntry;
+    static exception_table_end: ExceptionTableEntry;
+}
+
+#[repr(C)]
+struct ExceptionTableEntry {
+    instruction: u64,
+    fixup: u64,
+}
+
+/// The number of faults we recovered from, to only log some of them
+static RECOVERED_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+fn entries() -> &'static [ExceptionTableEntry] {
+    // SAFETY: the linker puts all the entries between the two symbols
+    unsafe {
+        let start = core::ptr::addr_of!(exception_table_start);
+        let end = core::ptr::addr_of!(exception_table_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Where to continue if the instruction at `rip` faulted, `None` if its not in the table.
+///
+/// The first faults are logged, then only every power of 2, so a process can't flood the log
+pub fn fixup_for(rip: u64, fault_address: u64) -> Option<u64> {
+    let entry = entries().iter().find(|entry| entry.instruction == rip)?;
+
+    let count = RECOVERED_FAULTS.fetch_add(1, Ordering::Relaxed) + 1;
+    if count <= 8 || count.is_power_of_two() {
+        println!(
+            "[!] recovered from a fault at {rip:#X} on address {fault_address:#X} ({count} so far)"
+        );
+    }
+    Some(entry.fixup)
+}
+
+/// Copies `len` bytes, returns the number of bytes that were not copied because of a fault,
+/// so `0` if all were copied.
+///
+/// # Safety
+/// `dst` and `src` must not overlap, and a fault in them must not be in kernel memory that
+/// is in use, since that would still be corrupted before the fault
+pub unsafe fn copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    exception_table_copy_bytes(dst, src, len)
+}
+
+/// The copies that fault return what is left: from the user half, which the kernel vm doesn't
+/// map, and from a non-canonical address, which is a general protection fault
+pub fn run_self_tests() {
+    let source = [0x5Au8; 16];
+    let mut copy = [0u8; 16];
+    // SAFETY: both are ours
+    assert_eq!(
+        unsafe { copy_bytes(copy.as_mut_ptr(), source.as_ptr(), copy.len()) },
+        0
+    );
+    assert_eq!(copy, source);
+    for bad in [0x1000u64, 0x8000_0000_0000] {
+        let mut copy = [0u8; 16];
+        // SAFETY: not mapped, nothing is written
+        let left = unsafe { copy_bytes(copy.as_mut_ptr(), bad as *const u8, copy.len()) };
+        assert_eq!(
+            left,
+            copy.len(),
+            "exception table self test: read of {bad:#x}"
+        );
+        // SAFETY: not mapped, nothing is written
+        let left = unsafe { copy_bytes(bad as *mut u8, source.as_ptr(), source.len()) };
+        assert_eq!(
+            left,
+            source.len(),
+            "exception table self test: write to {bad:#x}"
+        );
+    }
+}
@@ -1,19 +1,28 @@
-use core::mem;
+use core::{
+    mem,
+    ptr::{self, addr_of, addr_of_mut},
+};
 
 use crate::{
     memory_management::{
         memory_layout::{
-            is_aligned, INTR_STACK_BASE, INTR_STACK_EMPTY_SIZE, INTR_STACK_ENTRY_SIZE,
-            INTR_STACK_SIZE, INTR_STACK_TOTAL_SIZE, PAGE_4K, PROCESS_KERNEL_STACK_END,
+            is_aligned, INTR_STACK_BASE, INTR_STACK_CPU_SIZE, INTR_STACK_EMPTY_SIZE,
+            INTR_STACK_ENTRY_SIZE, INTR_STACK_SIZE, INTR_STACK_TOTAL_SIZE, PAGE_4K,
+            PROCESS_KERNEL_STACK_END,
         },
         virtual_memory_mapper::{self, VirtualMemoryMapEntry},
     },
     sync::spin::mutex::Mutex,
 };
 
-static GDT: Mutex<GlobalDescriptorManager> = Mutex::new(GlobalDescriptorManager::empty());
-/// SAFETY: TSS is only used when `GDT` is locked, so its safe to use as `static mut`
-static mut TSS: TaskStateSegment = TaskStateSegment::empty();
+use super::{AlreadyInitialized, Cpu, CpuTable, MAX_CPUS};
+
+/// The tables of each CPU, by its `id`
+static GDTS: [Mutex<GlobalDescriptorManager>; MAX_CPUS] =
+    [const { Mutex::new(GlobalDescriptorManager::empty()) }; MAX_CPUS];
+/// SAFETY: a TSS is only used when the GDT of the same CPU is locked, so its safe to use
+/// as `static mut`
+static mut TSSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::empty() }; MAX_CPUS];
 
 pub const KERNEL_RING: u8 = 0;
 pub const USER_RING: u8 = 3;
@@ -29,108 +38,106 @@ impl SegmentSelector {
     }
 }
 
-/// This should be called only once, otherwise, it will crash
+/// Builds and loads the GDT and TSS of the running CPU, fails with an [`AlreadyInitialized`]
+/// panic if called again
 pub fn init_kernel_gdt() {
-    let mut manager = GDT.lock();
-    if manager.gdt.index != 1 {
-        panic!("GDT already initialized");
-    }
+    let cpu = super::cpu();
+    let mut manager = GDTS[cpu.id].lock();
+    // SAFETY: the GDT is locked, and the TSS is only loaded after this
+    let tss = unsafe { &mut *addr_of_mut!(TSSS[cpu.id]) };
+    build_for(cpu, &mut manager, tss)
+        .unwrap_or_else(|e| panic!("Could not initialize the GDT: {e}"));
+    drop(manager);
 
-    manager.kernel_code_seg = SegmentSelector::from_index(unsafe {
-        manager.gdt.push_user(UserDescriptorEntry {
-            access: flags::PRESENT | flags::CODE | flags::USER | flags::dpl(KERNEL_RING),
-            flags_and_limit: flags::LONG_MODE,
-            ..UserDescriptorEntry::empty()
-        }) as _
-    });
-    manager.user_code_seg = SegmentSelector::from_index(unsafe {
-        manager.gdt.push_user(UserDescriptorEntry {
-            access: flags::PRESENT | flags::CODE | flags::USER | flags::dpl(USER_RING),
-            flags_and_limit: flags::LONG_MODE,
-            ..UserDescriptorEntry::empty()
-        }) as _
-    });
-    manager.kernel_data_seg = SegmentSelector::from_index(unsafe {
-        manager.gdt.push_user(UserDescriptorEntry {
-            access: flags::PRESENT | flags::USER | flags::WRITE | flags::dpl(KERNEL_RING),
-            ..UserDescriptorEntry::empty()
-        }) as _
-    });
-    manager.user_data_seg = SegmentSelector::from_index(unsafe {
-        manager.gdt.push_user(UserDescriptorEntry {
-            access: flags::PRESENT | flags::USER | flags::WRITE | flags::dpl(USER_RING),
-            ..UserDescriptorEntry::empty()
-        }) as _
+    map_interrupt_stacks(cpu.id);
+    // call the special `run_with` so that we get the `static` lifetime
+    GDTS[cpu.id].run_with(|manager| {
+        manager.gdt.apply_lgdt();
+
+        manager.load_kernel_segments();
+        manager.load_tss();
     });
+}
 
-    // setup TSS
+/// Sets the stack the CPU switches to when entering the kernel from user mode, the end of
+/// the kernel stack of the thread about to run
+pub fn set_kernel_stack(end: usize) {
+    let id = super::cpu().id;
+    let _manager = GDTS[id].lock();
+    // SAFETY: the GDT is locked
+    let tss = unsafe { &mut *addr_of_mut!(TSSS[id]) };
+    tss.rsp[KERNEL_RING as usize] = end as u64;
+}
 
-    // setup stacks, for each use `INTR_STACK_SIZE` bytes, but also allocate another one of these
-    // and use as padding between the stacks, so that we can detect stack overflows
-    for i in 0..7 {
-        unsafe {
-            // allocate after an empty offset, so that we can detect stack overflows
-            let stack_start_virtual =
-                INTR_STACK_BASE + (i * INTR_STACK_ENTRY_SIZE) + INTR_STACK_EMPTY_SIZE;
-            let stack_end_virtual = stack_start_virtual + INTR_STACK_SIZE;
-            assert!(stack_end_virtual <= INTR_STACK_BASE + INTR_STACK_TOTAL_SIZE);
-            if i == 6 {
-                // make sure we have allocated everything
-                assert!(stack_end_virtual == INTR_STACK_BASE + INTR_STACK_TOTAL_SIZE);
-            }
-            // make sure that the stack is aligned, so we can easily allocate pages
-            assert!(
-                is_aligned(INTR_STACK_SIZE as _, PAGE_4K)
-                    && is_aligned(stack_start_virtual as _, PAGE_4K)
-            );
-
-            // map the stack
-            virtual_memory_mapper::map_kernel(&VirtualMemoryMapEntry {
-                virtual_address: stack_start_virtual as u64,
-                physical_address: None,
-                size: INTR_STACK_SIZE as u64,
-                flags: virtual_memory_mapper::flags::PTE_WRITABLE,
-            });
-
-            // set the stack pointer
-            // subtract 8, since the boundary is not mapped
-            TSS.ist[i] = stack_end_virtual as u64 - 8;
-        }
+/// Builds the tables of `cpu` into `manager` and `tss`, without loading them
+fn build_for(
+    cpu: &Cpu,
+    manager: &mut GlobalDescriptorManager,
+    tss: &mut TaskStateSegment,
+) -> Result<(), AlreadyInitialized> {
+    cpu.start_init(CpuTable::Gdt)?;
+    *tss = TaskStateSegment::for_kernel(cpu.id);
+    *manager = GlobalDescriptorManager::new(tss);
+    Ok(())
+}
 
-        // A kernel stack for this process
-        // this will be used on transitions from user to kernel
-        unsafe { TSS.rsp[KERNEL_RING as usize] = PROCESS_KERNEL_STACK_END as u64 - 8 };
+/// Empties the tables built by [`build_for`], so they can be built again for `cpu`.
+/// Must not be the running CPU, since its tables are still loaded
+fn reset_for_tests(cpu: &Cpu, manager: &mut GlobalDescriptorManager, tss: &mut TaskStateSegment) {
+    assert!(
+        !ptr::eq(cpu, super::cpu()),
+        "the tables of the running CPU can't be reset"
+    );
+    cpu.reset_for_tests(CpuTable::Gdt);
+    *manager = GlobalDescriptorManager::empty();
+    *tss = TaskStateSegment::empty();
+}
+
+/// The end of the interrupt stack `i` of the CPU `cpu_id`, each is `INTR_STACK_SIZE` bytes,
+/// after an unmapped padding of the same size, so that we can detect stack overflows
+fn interrupt_stack_end(cpu_id: usize, i: usize) -> usize {
+    let cpu_base = INTR_STACK_BASE + cpu_id * INTR_STACK_CPU_SIZE;
+    let stack_start_virtual = cpu_base + (i * INTR_STACK_ENTRY_SIZE) + INTR_STACK_EMPTY_SIZE;
+    let stack_end_virtual = stack_start_virtual + INTR_STACK_SIZE;
+    assert!(stack_end_virtual <= INTR_STACK_BASE + INTR_STACK_TOTAL_SIZE);
+    if i == 6 {
+        // make sure we have allocated everything
+        assert!(stack_end_virtual == cpu_base + INTR_STACK_CPU_SIZE);
     }
+    // make sure that the stack is aligned, so we can easily allocate pages
+    assert!(
+        is_aligned(INTR_STACK_SIZE as _, PAGE_4K) && is_aligned(stack_start_virtual as _, PAGE_4K)
+    );
+    stack_end_virtual
+}
 
-    let tss_ptr = (unsafe { &TSS } as *const _) as u64;
-
-    manager.tss_seg = SegmentSelector::from_index(unsafe {
-        manager.gdt.push_system(SystemDescriptorEntry {
-            limit: (mem::size_of::<TaskStateSegment>() - 1) as u16,
-            access: flags::PRESENT | flags::TSS_TYPE,
-            base_low: (tss_ptr & 0xFFFF) as u16,
-            base_middle: ((tss_ptr >> 16) & 0xFF) as u8,
-            base_high: ((tss_ptr >> 24) & 0xFF) as u8,
-            base_upper: ((tss_ptr >> 32) & 0xFFFFFFFF) as u32,
-            ..SystemDescriptorEntry::empty()
-        }) as _
-    });
-    drop(manager);
-    // call the special `run_with` so that we get the `static` lifetime
-    GDT.run_with(|manager| {
-        manager.gdt.apply_lgdt();
+fn map_interrupt_stacks(cpu_id: usize) {
+    for i in 0..7 {
+        let stack_end_virtual = interrupt_stack_end(cpu_id, i);
+        virtual_memory_mapper::map_kernel(&VirtualMemoryMapEntry {
+            virtual_address: (stack_end_virtual - INTR_STACK_SIZE) as u64,
+            physical_address: None,
+            size: INTR_STACK_SIZE as u64,
+            flags: virtual_memory_mapper::flags::PTE_WRITABLE
+                | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+        });
+    }
+}
 
-        manager.load_kernel_segments();
-        manager.load_tss();
-    });
+pub fn get_kernel_code_seg_index() -> SegmentSelector {
+    GDTS[super::cpu().id].run_with(|manager| manager.kernel_code_seg)
+}
+
+pub fn get_kernel_data_seg_index() -> SegmentSelector {
+    GDTS[super::cpu().id].run_with(|manager| manager.kernel_data_seg)
 }
 
 pub fn get_user_code_seg_index() -> SegmentSelector {
-    GDT.run_with(|manager| manager.user_code_seg)
+    GDTS[super::cpu().id].run_with(|manager| manager.user_code_seg)
 }
 
 pub fn get_user_data_seg_index() -> SegmentSelector {
-    GDT.run_with(|manager| manager.user_data_seg)
+    GDTS[super::cpu().id].run_with(|manager| manager.user_data_seg)
 }
 mod flags {
     // this is in the flags byte
@@ -231,6 +238,16 @@ impl TaskStateSegment {
             iomap_base: 0,
         }
     }
+
+    /// The interrupt stacks of the CPU `cpu_id`, and the kernel stack of the processes, used
+    /// on transitions from user to kernel. The stacks must be mapped before it is loaded
+    fn for_kernel(cpu_id: usize) -> Self {
+        let mut tss = Self::empty();
+        // subtract 8, since the boundary is not mapped
+        tss.ist = core::array::from_fn(|i| interrupt_stack_end(cpu_id, i) as u64 - 8);
+        tss.rsp[KERNEL_RING as usize] = PROCESS_KERNEL_STACK_END as u64 - 8;
+        tss
+    }
 }
 
 #[repr(C, packed(2))]
@@ -262,6 +279,51 @@ impl GlobalDescriptorManager {
         }
     }
 
+    /// The segments of the kernel and user, and the TSS descriptor pointing to `tss`
+    fn new(tss: &TaskStateSegment) -> Self {
+        let mut manager = Self::empty();
+        manager.kernel_code_seg = SegmentSelector::from_index(unsafe {
+            manager.gdt.push_user(UserDescriptorEntry {
+                access: flags::PRESENT | flags::CODE | flags::USER | flags::dpl(KERNEL_RING),
+                flags_and_limit: flags::LONG_MODE,
+                ..UserDescriptorEntry::empty()
+            }) as _
+        });
+        manager.user_code_seg = SegmentSelector::from_index(unsafe {
+            manager.gdt.push_user(UserDescriptorEntry {
+                access: flags::PRESENT | flags::CODE | flags::USER | flags::dpl(USER_RING),
+                flags_and_limit: flags::LONG_MODE,
+                ..UserDescriptorEntry::empty()
+            }) as _
+        });
+        manager.kernel_data_seg = SegmentSelector::from_index(unsafe {
+            manager.gdt.push_user(UserDescriptorEntry {
+                access: flags::PRESENT | flags::USER | flags::WRITE | flags::dpl(KERNEL_RING),
+                ..UserDescriptorEntry::empty()
+            }) as _
+        });
+        manager.user_data_seg = SegmentSelector::from_index(unsafe {
+            manager.gdt.push_user(UserDescriptorEntry {
+                access: flags::PRESENT | flags::USER | flags::WRITE | flags::dpl(USER_RING),
+                ..UserDescriptorEntry::empty()
+            }) as _
+        });
+
+        let tss_ptr = addr_of!(*tss) as u64;
+        manager.tss_seg = SegmentSelector::from_index(unsafe {
+            manager.gdt.push_system(SystemDescriptorEntry {
+                limit: (mem::size_of::<TaskStateSegment>() - 1) as u16,
+                access: flags::PRESENT | flags::TSS_TYPE,
+                base_low: (tss_ptr & 0xFFFF) as u16,
+                base_middle: ((tss_ptr >> 16) & 0xFF) as u8,
+                base_high: ((tss_ptr >> 24) & 0xFF) as u8,
+                base_upper: ((tss_ptr >> 32) & 0xFFFFFFFF) as u32,
+                ..SystemDescriptorEntry::empty()
+            }) as _
+        });
+        manager
+    }
+
     pub fn load_kernel_segments(&self) {
         assert!(self.kernel_code_seg.0 != 0);
         unsafe {
@@ -298,6 +360,7 @@ impl GlobalDescriptorTable {
     /// Must make sure that the data is a valid descriptor following the spec
     unsafe fn push_user(&mut self, entry: UserDescriptorEntry) -> usize {
         assert!(mem::size_of::<UserDescriptorEntry>() == 8);
+        assert!(self.index < self.data.len(), "GDT is full");
         let index = self.index;
         self.index += 1;
         // SAFETY: This is valid because its 8 bytes and
@@ -310,6 +373,7 @@ impl GlobalDescriptorTable {
         assert!(mem::size_of::<SystemDescriptorEntry>() == 16);
         // SAFETY: This is valid because its 16 bytes and
         let data = core::mem::transmute::<_, [u64; 2]>(entry);
+        assert!(self.index + 1 < self.data.len(), "GDT is full");
         let index = self.index;
         self.index += 2;
         self.data[index] = data[0];
@@ -329,3 +393,63 @@ impl GlobalDescriptorTable {
         }
     }
 }
+
+/// The boot CPU can't initialize its GDT again, and the tables of `other` (a CPU that is
+/// not running) are built, reset and built again, the same as the boot ones except the TSS
+/// and its interrupt stacks
+pub(super) fn run_self_tests(other: &Cpu) {
+    let boot_cpu = super::cpu();
+    assert!(boot_cpu.is_initialized(CpuTable::Gdt));
+
+    let mut manager = GlobalDescriptorManager::empty();
+    let mut tss = TaskStateSegment::empty();
+    assert_eq!(
+        build_for(boot_cpu, &mut manager, &mut tss),
+        Err(AlreadyInitialized {
+            cpu: boot_cpu.id,
+            table: CpuTable::Gdt,
+        })
+    );
+    // nothing is built on failure
+    assert_eq!(manager.gdt.index, 1);
+
+    let (boot_data, boot_index, boot_ist, boot_rsp) = GDTS[boot_cpu.id].run_with(|boot| {
+        // SAFETY: the TSS is only changed when the GDT is locked
+        let boot_tss = unsafe { &*addr_of!(TSSS[boot_cpu.id]) };
+        (boot.gdt.data, boot.gdt.index, boot_tss.ist, boot_tss.rsp)
+    });
+    for _ in 0..3 {
+        build_for(other, &mut manager, &mut tss).unwrap();
+        assert_eq!(
+            build_for(other, &mut manager, &mut tss),
+            Err(AlreadyInitialized {
+                cpu: other.id,
+                table: CpuTable::Gdt,
+            })
+        );
+        assert!(other.is_initialized(CpuTable::Gdt));
+
+        let (data, index) = (manager.gdt.data, manager.gdt.index);
+        assert_eq!(index, boot_index);
+        let tss_index = (manager.tss_seg.0 >> 3) as usize;
+        assert_eq!(tss_index, index - 2);
+        // the segments are the same, the TSS descriptor points to our TSS
+        assert_eq!(data[..tss_index], boot_data[..tss_index]);
+        let tss_ptr = addr_of!(tss) as u64;
+        let base = (data[tss_index] >> 16) & 0xFF_FFFF
+            | (data[tss_index] >> 56) << 24
+            | data[tss_index + 1] << 32;
+        assert_eq!(base, tss_ptr);
+        // the same layout of stacks, right after each other for each CPU
+        let ist = { tss.ist };
+        assert!(ist.iter().zip(boot_ist).all(|(&ist, boot_ist)| {
+            ist - boot_ist == ((other.id - boot_cpu.id) * INTR_STACK_CPU_SIZE) as u64
+        }));
+        assert_eq!({ tss.rsp }, boot_rsp);
+        assert!(manager.kernel_code_seg.0 != 0 && manager.user_data_seg.0 != 0);
+
+        reset_for_tests(other, &mut manager, &mut tss);
+        assert!(!other.is_initialized(CpuTable::Gdt));
+        assert_eq!(manager.gdt.index, 1);
+    }
+}
@@ -1,14 +1,27 @@
-use core::{marker::PhantomData, mem};
+use core::{marker::PhantomData, mem, ptr::addr_of_mut};
 
-use super::interrupts::stack_index;
+use crate::memory_management::{
+    kasan,
+    memory_layout::{
+        legacy_region_of, process_kernel_stack_guard_slot, virtual2physical, KERNEL_BASE,
+        KERNEL_LINK,
+    },
+    virtual_memory_mapper,
+};
+
+use super::{gdt::USER_RING, interrupts::stack_index};
 
 core::arch::global_asm!(include_str!("idt_vectors.S"));
 
 extern "C" {
     static interrupt_vector_table: [u64; 256];
+    static basic_interrupt_vector_table: [u64; 256];
 }
 
 static mut REDIRECTED_INTERRUPTS: [Option<*const u8>; 256] = [None; 256];
+/// The handlers `basic_interrupt_vector_table` jumps to, by vector
+#[no_mangle]
+static mut BASIC_INTERRUPT_HANDLERS: [Option<BasicInterruptHandler>; 256] = [None; 256];
 
 pub type BasicInterruptHandler = extern "x86-interrupt" fn(frame: InterruptStackFrame64);
 pub type InterruptHandlerWithError =
@@ -140,9 +153,30 @@ impl<T> InterruptDescriptorTableEntry<T> {
 }
 
 impl InterruptDescriptorTableEntry<BasicInterruptHandler> {
+    /// The handler itself, only for the ones that don't return, see [`default_handler`], it
+    /// runs with the GS base of the user if the interrupt came from user mode
     pub fn set_handler(&mut self, handler: BasicInterruptHandler) -> &mut Self {
         self.set_handler_ptr(handler as *const u8 as u64)
     }
+
+    /// Through the stub of `vector_n`, which switches to the kernel GS base around `handler`,
+    /// the vector must be of a user interrupt, the stub should be set with [`set_basic_handler`]
+    pub fn set_handler_stub(&mut self, vector_n: u8) -> &mut Self {
+        assert!(vector_n >= 32, "Exceptions don't have a basic stub");
+        unsafe { self.set_handler_ptr(basic_interrupt_vector_table[vector_n as usize]) }
+    }
+}
+
+/// The handler the stub of `vector_n` calls, see [`InterruptDescriptorTableEntry::set_handler_stub`]
+pub(super) fn set_basic_handler(vector_n: u8, handler: BasicInterruptHandler) {
+    unsafe {
+        let slot = &mut (*addr_of_mut!(BASIC_INTERRUPT_HANDLERS))[vector_n as usize];
+        assert!(
+            slot.is_none(),
+            "Interrupt {vector_n} already has a basic handler"
+        );
+        *slot = Some(handler);
+    }
 }
 
 impl InterruptDescriptorTableEntry<InterruptHandlerWithError> {
@@ -159,7 +193,13 @@ impl<T> InterruptDescriptorTableEntry<T> {
     ) -> &mut Self {
         unsafe {
             // save first as it might get called right away
-            REDIRECTED_INTERRUPTS[vector_n as usize] = Some(handler as *const u8);
+            let redirect = &mut (*addr_of_mut!(REDIRECTED_INTERRUPTS))[vector_n as usize];
+            // the same handler is fine, since the IDT of every CPU sets the exceptions
+            assert!(
+                redirect.is_none_or(|old| old == handler as *const u8),
+                "Interrupt {vector_n} is already redirected to another handler"
+            );
+            *redirect = Some(handler as *const u8);
             self.set_handler_ptr(interrupt_vector_table[vector_n as usize] as *const u8 as u64)
         }
     }
@@ -232,20 +272,20 @@ impl InterruptDescriptorTable {
     }
 
     pub fn init_default_handlers(&mut self) {
-        self.divide_by_zero.set_handler(default_handler::<0>);
+        self.divide_by_zero
+            .set_handler_with_number(exception_handler, 0);
         self.debug.set_handler(default_handler::<1>);
-        self.non_maskable_interrupt
-            .set_handler(default_handler::<2>);
+        self.non_maskable_interrupt.set_handler(nmi_handler);
         self.breakpoint.set_handler(default_handler::<3>);
-        self.overflow.set_handler(default_handler::<4>);
+        self.overflow.set_handler_with_number(exception_handler, 4);
         self.bound_range_exceeded
-            .set_handler(default_handler::<5>)
+            .set_handler_with_number(exception_handler, 5)
             .set_stack_index(Some(stack_index::FAULTS_STACK));
         self.invalid_opcode
-            .set_handler(default_handler::<6>)
+            .set_handler_with_number(exception_handler, 6)
             .set_stack_index(Some(stack_index::FAULTS_STACK));
         self.device_not_available
-            .set_handler(default_handler::<7>)
+            .set_handler_with_number(exception_handler, 7)
             .set_stack_index(Some(stack_index::FAULTS_STACK));
         self.double_fault
             .set_handler(default_handler_with_error::<8>)
@@ -255,18 +295,20 @@ impl InterruptDescriptorTable {
         self.segment_not_present
             .set_handler(default_handler_with_error::<11>);
         self.stack_exception
-            .set_handler(default_handler_with_error::<12>);
+            .set_handler_with_number(exception_handler, 12);
         self.general_protection_fault
-            .set_handler(default_handler_with_error::<13>);
+            .set_handler_with_number(exception_handler, 13);
         self.page_fault
-            .set_handler(default_handler_with_error::<14>)
+            .set_handler_with_number(exception_handler, 14)
             .set_stack_index(Some(stack_index::FAULTS_STACK));
-        self.x87_floating_point.set_handler(default_handler::<16>);
+        self.x87_floating_point
+            .set_handler_with_number(exception_handler, 16);
         self.alignment_check
-            .set_handler(default_handler_with_error::<17>)
+            .set_handler_with_number(exception_handler, 17)
             .set_stack_index(Some(stack_index::FAULTS_STACK));
         self.machine_check.set_handler(default_handler::<18>);
-        self.simd_floating_point.set_handler(default_handler::<19>);
+        self.simd_floating_point
+            .set_handler_with_number(exception_handler, 19);
         self.control_protection.set_handler(default_handler::<21>);
         self.hypervisor_injection.set_handler(default_handler::<28>);
         self.vmm_communication.set_handler(default_handler::<29>);
@@ -278,6 +320,13 @@ impl InterruptDescriptorTable {
         }
     }
 
+    /// The raw entries, to compare tables in the self tests
+    pub(super) fn raw_entries(&self) -> &[u128; 256] {
+        const _: () = assert!(mem::size_of::<InterruptDescriptorTable>() == 256 * 16);
+        // SAFETY: the entries are 16 bytes each, there is no padding, checked above
+        unsafe { &*(self as *const Self as *const [u128; 256]) }
+    }
+
     pub(super) fn apply_idt(&'static self) {
         let idt_ptr = InterruptDescriptorTablePointer {
             limit: mem::size_of::<InterruptDescriptorTable>() as u16 - 1,
@@ -309,20 +358,128 @@ pub extern "cdecl" fn rust_interrupt_handler_for_all_state(mut state: InterruptA
 }
 
 extern "x86-interrupt" fn default_handler<const N: u8>(frame: InterruptStackFrame64) {
+    // SAFETY: we panic
+    unsafe { super::ensure_kernel_gs() };
+    crate::kdb::record_exception(N, &frame, None);
     panic!("[{N}] Got exception: \n frame: {:x?}", frame);
 }
 
+/// The TLB flushes asked by the other CPUs, any other NMI is unexpected, see
+/// [`super::smp::flush_tlb_others`]
+extern "x86-interrupt" fn nmi_handler(frame: InterruptStackFrame64) {
+    // SAFETY: this is the NMI handler
+    if unsafe { super::smp::handle_flush_nmi() } {
+        return;
+    }
+    // SAFETY: we panic
+    unsafe { super::ensure_kernel_gs() };
+    crate::kdb::record_exception(2, &frame, None);
+    panic!("[2] Got exception: \n frame: {:x?}", frame);
+}
+
 extern "x86-interrupt" fn default_handler_with_error<const N: u8>(
     frame: InterruptStackFrame64,
     error_code: u64,
 ) {
-    let cr2: u64;
-    unsafe {
-        core::arch:: asm!("mov {}, cr2", out(reg) cr2);
-    }
+    // SAFETY: we panic
+    unsafe { super::ensure_kernel_gs() };
+    crate::kdb::record_exception(N, &frame, Some(error_code));
+    let cr2 = unsafe { super::get_cr2() };
     let current_cpu = super::cpu();
-    let proc_id = current_cpu.context.map(|_| current_cpu.process_id);
+    let proc_id = current_cpu
+        .context
+        .map(|_| (current_cpu.process_id, current_cpu.process_name));
     panic!(
         "[{N}] {proc_id:?} Got exception: \n frame: {frame:x?}\n error: {error_code:016X}\n cr2: {cr2:X}",
     );
 }
+
+/// The bits of the error code of a page fault on a write to a present page
+const PAGE_FAULT_PRESENT_WRITE: u64 = 0b11;
+/// The bit of the error code of a page fault on a present page, for a protection violation
+const PAGE_FAULT_PRESENT: u64 = 0b1;
+
+/// The vectors of the exceptions with an error code
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// The exceptions user programs can cause, they go to the handler of their signal, or the
+/// process is killed (see [`crate::process::crash`]), in the kernel these panic like the
+/// default handlers
+extern "cdecl" fn exception_handler(all_state: &mut InterruptAllSavedState) {
+    if all_state.frame.cs & 0x3 == USER_RING {
+        // a write to a page shared by `SYS_FORK`, it runs again on the copy
+        if all_state.number == 14
+            && all_state.error & PAGE_FAULT_PRESENT_WRITE == PAGE_FAULT_PRESENT_WRITE
+            && virtual_memory_mapper::handle_cow_fault(unsafe { super::get_cr2() })
+        {
+            return;
+        }
+        // below the stack, it grows there, in a file mapping, the page is read, or in the swap,
+        // it's mapped back (read without the scheduler lock), and it runs again
+        if all_state.number == 14
+            && all_state.error & PAGE_FAULT_PRESENT == 0
+            && (crate::process::scheduler::with_current_process(|process| {
+                let addr = unsafe { super::get_cr2() };
+                process.grow_stack(addr) || process.fault_in_file(addr)
+            }) || crate::memory_management::swap::swap_in(unsafe { super::get_cr2() }))
+        {
+            return;
+        }
+        // the handler can fix it, or never return to it
+        if crate::process::signal::deliver_exception(all_state, all_state.number as u8) {
+            return;
+        }
+        crate::process::crash::kill_current_process(all_state);
+        return;
+    }
+
+    let vector = all_state.number as u8;
+    let frame = all_state.frame;
+    if !has_error_code(vector) {
+        crate::kdb::record_exception(vector, &frame, None);
+        panic!("[{vector}] Got exception: \n frame: {:x?}", frame);
+    }
+    let error_code = all_state.error;
+    let cr2 = unsafe { super::get_cr2() };
+    // a fault we expected, i.e. copying from a bad user pointer, a non-canonical address
+    // gives a general protection fault instead
+    if vector == 13 || vector == 14 {
+        let address = if vector == 14 { cr2 } else { 0 };
+        if let Some(fixup) = super::exception_table::fixup_for(frame.rip, address) {
+            all_state.frame.rip = fixup;
+            return;
+        }
+    }
+    crate::kdb::record_exception(vector, &frame, Some(error_code));
+    // most of the low memory is not mapped on purpose, so name what was there
+    if vector == 14 && (KERNEL_BASE..KERNEL_LINK).contains(&(cr2 as usize)) {
+        let physical = virtual2physical(cr2 as usize);
+        let region = legacy_region_of(physical).expect("legacy regions cover the low memory");
+        let access = if error_code & 2 != 0 { "write" } else { "read" };
+        panic!(
+            "[14] Page fault on {access} of protected legacy region `{}` ({:?}) at {cr2:X} (physical {physical:X})\n frame: {frame:x?}\n error: {error_code:016X}",
+            region.name, region.access
+        );
+    }
+    let current_cpu = super::cpu();
+    let proc_id = current_cpu
+        .context
+        .map(|_| (current_cpu.process_id, current_cpu.process_name));
+    // the redzones and the freed pages of the big allocations
+    if let Some(report) = kasan::describe_fault(cr2).filter(|_| vector == 14) {
+        panic!(
+            "[14] {proc_id:?} {report}, at {cr2:X}\n frame: {frame:x?}\n error: {error_code:016X}",
+        );
+    }
+    // the guard pages below the kernel stacks of the threads
+    if let Some(slot) = process_kernel_stack_guard_slot(cr2 as usize).filter(|_| vector == 14) {
+        panic!(
+            "[14] {proc_id:?} Kernel stack overflow of the thread in slot {slot} at {cr2:X}\n frame: {frame:x?}\n error: {error_code:016X}",
+        );
+    }
+    panic!(
+        "[{vector}] {proc_id:?} Got exception: \n frame: {frame:x?}\n error: {error_code:016X}\n cr2: {cr2:X}",
+    );
+}
@@ -1,14 +1,9 @@
-use core::{
-    borrow::{Borrow, BorrowMut},
-    mem,
-};
-
 use alloc::vec::Vec;
 
 use crate::{
     acpi::tables::{self, BiosTables, InterruptControllerStruct, InterruptSourceOverride},
     cpu::{self, idt::InterruptStackFrame64, Cpu, CPUID_FN_FEAT, CPUS, MAX_CPUS},
-    memory_management::virtual_space,
+    memory_management::mmio::MmioRegion,
     sync::spin::mutex::Mutex,
 };
 
@@ -18,6 +13,7 @@ use super::{
 };
 
 const CPUID_FEAT_EDX_APIC: u32 = 1 << 9;
+const CPUID_EBX_APIC_ID_SHIFT: u32 = 24;
 
 const APIC_BAR_ENABLED: u64 = 1 << 11;
 const APIC_BASE_MASK: u64 = 0xFFFF_FFFF_FFFF_F000;
@@ -31,6 +27,28 @@ pub fn init(bios_tables: &BiosTables) {
     }
 }
 
+/// Enables the local APIC of the running CPU if it's not, and returns its physical address
+fn enable_local_apic() -> usize {
+    // do we have APIC in this cpu?
+    let cpuid = unsafe { cpu::cpuid!(CPUID_FN_FEAT) };
+    if cpuid.edx & CPUID_FEAT_EDX_APIC == 0 {
+        panic!("APIC is not supported");
+    }
+    let apic_bar = unsafe { cpu::msr::read(cpu::msr::APIC_BASE) };
+    if apic_bar & APIC_BAR_ENABLED == 0 {
+        // enable APIC
+        unsafe {
+            cpu::msr::write(cpu::msr::APIC_BASE, apic_bar | APIC_BAR_ENABLED);
+        }
+        // recheck
+        let apic_bar = unsafe { cpu::msr::read(cpu::msr::APIC_BASE) };
+        if apic_bar & APIC_BAR_ENABLED == 0 {
+            panic!("APIC is not enabled");
+        }
+    }
+    (apic_bar & APIC_BASE_MASK) as usize
+}
+
 fn disable_pic() {
     unsafe {
         cpu::io_out::<u8>(0x21, 0xFF);
@@ -38,12 +56,49 @@ fn disable_pic() {
     }
 }
 
+/// Enables the local APIC of the running CPU, one of the others started after the boot one,
+/// with the same spurious, error and timer vectors
+pub fn init_local() {
+    unsafe { (*core::ptr::addr_of!(APIC)).lock().init_local() }
+}
+
+/// The CPUs found in the MADT, the boot one is `CPUS[0]`
+pub fn n_cpus() -> usize {
+    unsafe { (*core::ptr::addr_of!(APIC)).lock().n_cpus }
+}
+
+/// The inter-processor interrupts we send, to start the other CPUs, and to flush their TLBs
+#[derive(Debug, Clone, Copy)]
+pub enum Ipi {
+    /// Resets the CPU, which then waits for a [`Ipi::Startup`]
+    Init,
+    /// Starts the CPU in real mode, at the start of the page with this number
+    Startup(u8),
+    /// Interrupts the CPU even with its interrupts disabled, see [`smp::flush_tlb_others`]
+    ///
+    /// [`smp::flush_tlb_others`]: crate::cpu::smp::flush_tlb_others
+    Nmi,
+}
+
+/// Sends `ipi` to the CPU with `apic_id`, and waits until it is delivered
+pub fn send_ipi(apic_id: u8, ipi: Ipi) {
+    unsafe { (*core::ptr::addr_of!(APIC)).lock().send_ipi(apic_id, ipi) }
+}
+
 pub fn return_from_interrupt() {
     unsafe {
         APIC.lock().return_from_interrupt();
     }
 }
 
+/// Stops the local APIC timer of the running CPU, so nothing drives its scheduler, for the
+/// watchdog test
+pub fn mask_timer() {
+    unsafe {
+        (*core::ptr::addr_of!(APIC)).lock().mask_timer();
+    }
+}
+
 pub fn assign_io_irq<H: InterruptHandler>(handler: H, interrupt_num: u8, cpu: &Cpu) {
     unsafe { APIC.lock().assign_io_irq(handler, interrupt_num, cpu) }
 }
@@ -62,37 +117,47 @@ pub fn assign_io_irq_custom<H: InterruptHandler, F>(
     }
 }
 
-#[repr(C, align(4))]
-struct ApicReg {
-    reg: u32,
-    pad: [u32; 3],
-}
-
-impl ApicReg {
-    fn write(&mut self, value: u32) {
-        unsafe { (self.reg.borrow_mut() as *mut u32).write_volatile(value) };
+/// Same as [`assign_io_irq_custom`], but for a global system interrupt, without going through
+/// the ISA interrupt source overrides, i.e. a routed PCI interrupt
+pub fn assign_io_gsi<H: InterruptHandler, F>(handler: H, gsi: u32, cpu: &Cpu, modify_entry: F)
+where
+    F: FnOnce(IoApicRedirectionBuilder) -> IoApicRedirectionBuilder,
+{
+    unsafe {
+        (*core::ptr::addr_of!(APIC))
+            .lock()
+            .assign_io_gsi(handler, gsi, cpu, modify_entry)
     }
+}
 
-    fn read(&self) -> u32 {
-        unsafe { (self.reg.borrow() as *const u32).read_volatile() }
-    }
+/// Whether the global system interrupt `gsi` is not routed to any handler yet
+pub fn is_gsi_free(gsi: u32) -> bool {
+    unsafe { (*core::ptr::addr_of!(APIC)).lock().is_gsi_free(gsi) }
 }
 
-#[repr(C)]
-struct LocalVectorRegister {
-    reg: ApicReg,
+/// The global system interrupt the ISA `irq_num` is connected to, and the MPS INTI flags of
+/// its source override, `0` (the bus defaults) if it has none
+pub fn isa_irq_route(irq_num: u8) -> (u32, u16) {
+    unsafe { (*core::ptr::addr_of!(APIC)).lock().isa_irq_route(irq_num) }
 }
 
-#[allow(dead_code)]
-impl LocalVectorRegister {
-    fn read(&self) -> LocalVectorRegisterBuilder {
-        LocalVectorRegisterBuilder {
-            reg: self.reg.read(),
-        }
+/// Whether `interrupt_num` is not routed to any handler yet
+pub fn is_io_irq_free(interrupt_num: u8) -> bool {
+    unsafe {
+        (*core::ptr::addr_of!(APIC))
+            .lock()
+            .is_io_irq_free(interrupt_num)
     }
+}
 
-    fn write(&mut self, builder: LocalVectorRegisterBuilder) {
-        self.reg.write(builder.reg)
+/// Masks `interrupt_num` and frees its entry, so it can be assigned again.
+///
+/// The vector allocated for the old handler is not freed
+pub fn release_io_irq(interrupt_num: u8) {
+    unsafe {
+        (*core::ptr::addr_of!(APIC))
+            .lock()
+            .release_io_irq(interrupt_num)
     }
 }
 
@@ -104,6 +169,13 @@ const LVT_TIMER_MODE_MASK: u32 = 1 << 17;
 
 const SPURIOUS_ENABLE: u32 = 1 << 8;
 
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SHIFT: u32 = 24;
+
 #[derive(Default, Clone, Copy)]
 struct LocalVectorRegisterBuilder {
     reg: u32,
@@ -137,48 +209,38 @@ impl LocalVectorRegisterBuilder {
     }
 }
 
-#[repr(C, align(16))]
-struct ApicMmio {
-    _pad1: [ApicReg; 2],
-    id: ApicReg,
-    version: ApicReg,
-    _pad2: [ApicReg; 4],
-    task_priority: ApicReg,
-    arbitration_priority: ApicReg,
-    processor_priority: ApicReg,
-    end_of_interrupt: ApicReg,
-    remote_read: ApicReg,
-    logical_destination: ApicReg,
-    destination_format: ApicReg,
-    spurious_interrupt_vector: ApicReg,
-    in_service: [ApicReg; 8],
-    trigger_mode: [ApicReg; 8],
-    interrupt_request: [ApicReg; 8],
-    error_status: ApicReg,
-    _pad3: [ApicReg; 7],
-    interrupt_command_low: ApicReg,
-    interrupt_command_high: ApicReg,
-    timer_local_vector_table: LocalVectorRegister,
-    thermal_local_vector_table: LocalVectorRegister,
-    performance_local_vector_table: LocalVectorRegister,
-    lint0_local_vector_table: LocalVectorRegister,
-    lint1_local_vector_table: LocalVectorRegister,
-    error_local_vector_table: LocalVectorRegister,
-    timer_initial_count: ApicReg,
-    timer_current_count: ApicReg,
-    _pad4: [ApicReg; 4],
-    timer_divide_configuration: ApicReg,
-    _pad5: ApicReg,
-    extended_apic_features: ApicReg,
-    extended_apic_control: ApicReg,
-    specifc_end_of_interrupt: ApicReg,
-    _pad6: [ApicReg; 5],
-    interrupt_enable: [ApicReg; 8],
-    extended_interrupt_local_vector_tables: [ApicReg; 4],
+// the registers are 16 bytes apart, only the first 4 bytes are used
+#[allow(dead_code)]
+mod local_apic {
+    pub const MMIO_SIZE: usize = 0x400;
+
+    pub const ID: usize = 0x20;
+    pub const VERSION: usize = 0x30;
+    pub const TASK_PRIORITY: usize = 0x80;
+    pub const END_OF_INTERRUPT: usize = 0xB0;
+    pub const SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+    pub const ERROR_STATUS: usize = 0x280;
+    pub const INTERRUPT_COMMAND_LOW: usize = 0x300;
+    pub const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+    pub const TIMER_LOCAL_VECTOR_TABLE: usize = 0x320;
+    pub const THERMAL_LOCAL_VECTOR_TABLE: usize = 0x330;
+    pub const PERFORMANCE_LOCAL_VECTOR_TABLE: usize = 0x340;
+    pub const LINT0_LOCAL_VECTOR_TABLE: usize = 0x350;
+    pub const LINT1_LOCAL_VECTOR_TABLE: usize = 0x360;
+    pub const ERROR_LOCAL_VECTOR_TABLE: usize = 0x370;
+    pub const TIMER_INITIAL_COUNT: usize = 0x380;
+    pub const TIMER_CURRENT_COUNT: usize = 0x390;
+    pub const TIMER_DIVIDE_CONFIGURATION: usize = 0x3E0;
 }
 
 #[allow(dead_code)]
 mod io_apic {
+    pub const MMIO_SIZE: usize = 0x50;
+    // the registers are accessed by writing the index to `REGISTER_SELECT`, then
+    // reading or writing `DATA`
+    pub const REGISTER_SELECT: usize = 0x00;
+    pub const DATA: usize = 0x10;
+
     pub const IO_APIC_ID: u32 = 0;
     pub const IO_APIC_VERSION: u32 = 1;
     pub const IO_APIC_ARBITRATION_ID: u32 = 2;
@@ -255,33 +317,12 @@ impl IoApicRedirectionBuilder {
     }
 }
 
-#[repr(C, align(16))]
-struct IoApicMmio {
-    register_select: ApicReg,
-    data: ApicReg,
-    irq_pin_assersion: ApicReg,
-    _pad: ApicReg,
-    return_of_interrupt: ApicReg,
-}
-
-impl IoApicMmio {
-    pub fn read_register(&mut self, register: u32) -> u32 {
-        self.register_select.write(register);
-        self.data.read()
-    }
-
-    pub fn write_register(&mut self, register: u32, value: u32) {
-        self.register_select.write(register);
-        self.data.write(value);
-    }
-}
-
 #[allow(dead_code)]
 struct IoApic {
     id: u8,
     global_irq_base: u32,
     n_entries: u8,
-    mmio: *mut IoApicMmio,
+    mmio: MmioRegion,
 }
 
 impl IoApic {
@@ -294,12 +335,15 @@ impl IoApic {
         }
     }
 
-    fn read_register(&self, register: u32) -> u32 {
-        unsafe { (*self.mmio).read_register(register) }
+    // `&mut`, the select and data accesses must not be interleaved with another user
+    fn read_register(&mut self, register: u32) -> u32 {
+        self.mmio.write_u32(io_apic::REGISTER_SELECT, register);
+        self.mmio.read_u32(io_apic::DATA)
     }
 
-    fn write_register(&self, register: u32, value: u32) {
-        unsafe { (*self.mmio).write_register(register, value) }
+    fn write_register(&mut self, register: u32, value: u32) {
+        self.mmio.write_u32(io_apic::REGISTER_SELECT, register);
+        self.mmio.write_u32(io_apic::DATA, value);
     }
 
     fn write_redirect_entry(&mut self, entry: u8, builder: IoApicRedirectionBuilder) {
@@ -312,7 +356,7 @@ impl IoApic {
         );
     }
 
-    fn is_entry_taken(&self, entry: u8) -> bool {
+    fn is_entry_taken(&mut self, entry: u8) -> bool {
         let lo = self.read_register(io_apic::IO_APIC_REDIRECTION_TABLE + entry as u32 * 2);
         lo as u64 & io_apic::RDR_VECTOR_MASK != 0
     }
@@ -329,10 +373,7 @@ impl From<tables::IoApic> for IoApic {
             id: table.io_apic_id,
             global_irq_base: table.global_system_interrupt_base,
             n_entries: 0, // to be filled next
-            mmio: virtual_space::allocate_and_map_virtual_space(
-                table.io_apic_address as _,
-                mem::size_of::<IoApicMmio>() as _,
-            ) as *mut IoApicMmio,
+            mmio: MmioRegion::map(table.io_apic_address as _, io_apic::MMIO_SIZE),
         };
         s.n_entries = (s.read_register(io_apic::IO_APIC_VERSION) >> 16) as u8 + 1;
         s
@@ -340,43 +381,39 @@ impl From<tables::IoApic> for IoApic {
 }
 
 struct Apic {
-    mmio: *mut ApicMmio,
+    mmio: MmioRegion,
     n_cpus: usize,
     io_apics: Vec<IoApic>,
     source_overrides: Vec<InterruptSourceOverride>,
+    // allocated by the boot CPU, the others use the same vectors
+    spurious_vector: u8,
+    error_vector: u8,
+    timer_vector: u8,
 }
 
 impl Apic {
     const fn empty() -> Self {
         Self {
-            // we should call `init` first, it will cause an exception
-            // if referenced and we should catch that.
-            mmio: core::ptr::null_mut(),
+            // we should call `init` first, any access will panic before that
+            mmio: MmioRegion::empty(),
             n_cpus: 0,
             io_apics: Vec::new(),
             source_overrides: Vec::new(),
+            spurious_vector: 0,
+            error_vector: 0,
+            timer_vector: 0,
         }
     }
 
     fn init(&mut self, bios_tables: &BiosTables) {
-        // do we have APIC in this cpu?
-        let cpuid = unsafe { cpu::cpuid!(CPUID_FN_FEAT) };
-        if cpuid.edx & CPUID_FEAT_EDX_APIC == 0 {
-            panic!("APIC is not supported");
-        }
-        let apic_bar = unsafe { cpu::msr::read(cpu::msr::APIC_BASE) };
-        if apic_bar & APIC_BAR_ENABLED == 0 {
-            // enable APIC
-            unsafe {
-                cpu::msr::write(cpu::msr::APIC_BASE, apic_bar | APIC_BAR_ENABLED);
-            }
-            // recheck
-            let apic_bar = unsafe { cpu::msr::read(cpu::msr::APIC_BASE) };
-            if apic_bar & APIC_BAR_ENABLED == 0 {
-                panic!("APIC is not enabled");
-            }
-        }
-        let mut apic_address = (apic_bar & APIC_BASE_MASK) as usize;
+        let mut apic_address = enable_local_apic();
+        // the boot CPU is always the first, `cpu::cpu()` returns it until the others start
+        let cpuid = cpu::cpuid!(CPUID_FN_FEAT);
+        let boot_apic_id = (cpuid.ebx >> CPUID_EBX_APIC_ID_SHIFT) as u8;
+        let mut boot_in_madt = false;
+        // SAFETY: the others are not started yet, and the boot CPU is `CPUS[0]`, see `kernel_main`
+        unsafe { CPUS[0].init(0, boot_apic_id) };
+        self.n_cpus = 1;
 
         // process the MADT table
         let madt_table = bios_tables
@@ -399,7 +436,9 @@ impl Apic {
                         // this is a disabled processor
                         continue;
                     }
-                    if self.n_cpus >= MAX_CPUS {
+                    if s.apic_id == boot_apic_id {
+                        boot_in_madt = true;
+                    } else if self.n_cpus >= MAX_CPUS {
                         println!(
                             "WARNING: too many CPUs, have {MAX_CPUS} already, ignoring the rest"
                         );
@@ -438,8 +477,8 @@ impl Apic {
         }
 
         assert!(
-            self.n_cpus > 0,
-            "no CPUs found in the MADT table, cannot continue"
+            boot_in_madt,
+            "the boot CPU is not in the MADT table, cannot continue"
         );
         assert!(
             !self.io_apics.is_empty(),
@@ -447,84 +486,113 @@ impl Apic {
         );
         assert!(apic_address != 0, "APIC address is 0, cannot continue");
         assert!(apic_address & 0xF == 0, "APIC address is not aligned");
-        self.mmio = virtual_space::allocate_and_map_virtual_space(
-            apic_address as _,
-            mem::size_of::<ApicMmio>() as _,
-        ) as *mut ApicMmio;
+        self.mmio = MmioRegion::map(apic_address as _, local_apic::MMIO_SIZE);
 
         // reset all interrupts
         self.io_apics.iter_mut().for_each(|io_apic| {
             io_apic.reset_all_interrupts();
         });
 
+        self.spurious_vector = allocate_basic_user_interrupt(spurious_handler);
         self.initialize_spurious_interrupt();
         self.disable_local_interrupts();
+        self.timer_vector = allocate_user_interrupt_all_saved(super::handlers::apic_timer_handler);
         self.initialize_timer();
+        self.error_vector = allocate_basic_user_interrupt(error_interrupt_handler);
         self.setup_error_interrupt();
         // ack any pending interrupts
         self.return_from_interrupt();
     }
 
-    fn return_from_interrupt(&self) {
-        unsafe {
-            (*self.mmio).end_of_interrupt.write(0);
+    /// The same as the end of [`Self::init`] for the local APIC of another CPU, the IO APICs
+    /// and the MMIO mapping are shared
+    fn init_local(&mut self) {
+        enable_local_apic();
+        self.initialize_spurious_interrupt();
+        self.disable_local_interrupts();
+        self.initialize_timer();
+        self.setup_error_interrupt();
+        self.return_from_interrupt();
+    }
+
+    fn send_ipi(&mut self, apic_id: u8, ipi: Ipi) {
+        let command = match ipi {
+            Ipi::Init => ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT,
+            Ipi::Startup(page) => ICR_DELIVERY_STARTUP | page as u32,
+            Ipi::Nmi => ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT,
+        };
+        self.mmio.write_u32(
+            local_apic::INTERRUPT_COMMAND_HIGH,
+            (apic_id as u32) << ICR_DESTINATION_SHIFT,
+        );
+        // writing the low half sends it
+        self.mmio
+            .write_u32(local_apic::INTERRUPT_COMMAND_LOW, command);
+        while self.mmio.read_u32(local_apic::INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
         }
     }
 
+    fn write_local_vector_table(&self, register: usize, builder: LocalVectorRegisterBuilder) {
+        self.mmio.write_u32(register, builder.reg);
+    }
+
+    fn return_from_interrupt(&self) {
+        self.mmio.write_u32(local_apic::END_OF_INTERRUPT, 0);
+    }
+
     fn initialize_spurious_interrupt(&mut self) {
-        let interrupt_num = allocate_basic_user_interrupt(spurious_handler);
-        unsafe {
-            // 1 << 8, to enable spurious interrupts
-            (*self.mmio)
-                .spurious_interrupt_vector
-                .write(SPURIOUS_ENABLE | interrupt_num as u32);
-        }
+        // 1 << 8, to enable spurious interrupts
+        self.mmio.write_u32(
+            local_apic::SPURIOUS_INTERRUPT_VECTOR,
+            SPURIOUS_ENABLE | self.spurious_vector as u32,
+        );
     }
 
     /// disable the Local interrupts 0 and 1
     fn disable_local_interrupts(&mut self) {
-        unsafe {
-            let vector_table = LocalVectorRegisterBuilder::default().with_mask(true);
-            (*self.mmio).lint0_local_vector_table.write(vector_table);
-            (*self.mmio).lint1_local_vector_table.write(vector_table);
-        }
+        let vector_table = LocalVectorRegisterBuilder::default().with_mask(true);
+        self.write_local_vector_table(local_apic::LINT0_LOCAL_VECTOR_TABLE, vector_table);
+        self.write_local_vector_table(local_apic::LINT1_LOCAL_VECTOR_TABLE, vector_table);
     }
 
+    /// Starts the timer of the running CPU, it's per CPU
     fn initialize_timer(&mut self) {
-        let interrupt_num = allocate_user_interrupt_all_saved(super::handlers::apic_timer_handler);
-
-        unsafe {
-            // divide by 1
-            (*self.mmio).timer_divide_configuration.write(0b1011);
-            // just random value, this is based on the CPU clock speed
-            // so its not accurate timing.
-            (*self.mmio).timer_initial_count.write(0x1000000);
-            // periodic mode, not masked, and with the allocated vector number
-            let vector_table = LocalVectorRegisterBuilder::default()
-                .with_periodic_timer(true)
-                .with_mask(false)
-                .with_vector(interrupt_num);
-            (*self.mmio).timer_local_vector_table.write(vector_table);
-        }
+        // divide by 1
+        self.mmio
+            .write_u32(local_apic::TIMER_DIVIDE_CONFIGURATION, 0b1011);
+        // just random value, this is based on the CPU clock speed
+        // so its not accurate timing.
+        self.mmio
+            .write_u32(local_apic::TIMER_INITIAL_COUNT, 0x1000000);
+        // periodic mode, not masked, and with the allocated vector number
+        let vector_table = LocalVectorRegisterBuilder::default()
+            .with_periodic_timer(true)
+            .with_mask(false)
+            .with_vector(self.timer_vector);
+        self.write_local_vector_table(local_apic::TIMER_LOCAL_VECTOR_TABLE, vector_table);
+    }
+
+    fn mask_timer(&mut self) {
+        let vector_table = self.mmio.read_u32(local_apic::TIMER_LOCAL_VECTOR_TABLE);
+        self.mmio.write_u32(
+            local_apic::TIMER_LOCAL_VECTOR_TABLE,
+            vector_table | LVT_MASK_MASK,
+        );
     }
 
     fn setup_error_interrupt(&mut self) {
         // clear the error status and write 0 to it
-        unsafe {
-            // 1- clear the error status
-            (*self.mmio).error_status.write(0);
-            // 2- write 0 to it (yes, we have to do this twice)
-            (*self.mmio).error_status.write(0);
-        }
+        // 1- clear the error status
+        self.mmio.write_u32(local_apic::ERROR_STATUS, 0);
+        // 2- write 0 to it (yes, we have to do this twice)
+        self.mmio.write_u32(local_apic::ERROR_STATUS, 0);
 
-        let interrupt_num = allocate_basic_user_interrupt(error_interrupt_handler);
-        unsafe {
-            // not masked, and with the allocated vector number
-            let vector_table = LocalVectorRegisterBuilder::default()
-                .with_mask(false)
-                .with_vector(interrupt_num);
-            (*self.mmio).error_local_vector_table.write(vector_table);
-        }
+        // not masked, and with the allocated vector number
+        let vector_table = LocalVectorRegisterBuilder::default()
+            .with_mask(false)
+            .with_vector(self.error_vector);
+        self.write_local_vector_table(local_apic::ERROR_LOCAL_VECTOR_TABLE, vector_table);
     }
 
     fn assign_io_irq<H: InterruptHandler>(&mut self, handler: H, irq_num: u8, cpu: &Cpu) {
@@ -540,29 +608,22 @@ impl Apic {
     ) where
         F: FnOnce(IoApicRedirectionBuilder) -> IoApicRedirectionBuilder,
     {
-        assert!(cpu.id < self.n_cpus, "CPU ID is out of range");
         assert!(irq_num < 24, "interrupt number is out of range");
+        let (gsi, _) = self.isa_irq_route(irq_num);
+        self.assign_io_gsi(handler, gsi, cpu, modify_entry)
+    }
 
-        // if we have override mapping for this interrupt, use it.
-        let int_override = self
-            .source_overrides
-            .iter()
-            .find(|int_override| int_override.source == irq_num);
-        let mut interrupt_num = irq_num as u32;
-        if let Some(int_override) = int_override {
-            interrupt_num = int_override.global_system_interrupt;
-        }
-        let io_apic = self
-            .io_apics
-            .iter_mut()
-            .find(|io_apic| {
-                io_apic.global_irq_base <= interrupt_num
-                    && interrupt_num < io_apic.global_irq_base + io_apic.n_entries as u32
-            })
-            .expect("Could not find IO APIC for the interrupt");
-
-        // the location of where we want to
-        let entry_in_ioapic = interrupt_num - io_apic.global_irq_base;
+    fn assign_io_gsi<H: InterruptHandler, F>(
+        &mut self,
+        handler: H,
+        gsi: u32,
+        cpu: &Cpu,
+        modify_entry: F,
+    ) where
+        F: FnOnce(IoApicRedirectionBuilder) -> IoApicRedirectionBuilder,
+    {
+        assert!(cpu.id < self.n_cpus, "CPU ID is out of range");
+        let (io_apic, entry_in_ioapic) = self.gsi_entry(gsi);
 
         let vector_num = allocate_user_interrupt(handler);
 
@@ -578,10 +639,61 @@ impl Apic {
         // TODO: this is added for catching bugs early, later will replace
         // it with a better solution.
         assert!(
-            !io_apic.is_entry_taken(entry_in_ioapic as u8),
+            !io_apic.is_entry_taken(entry_in_ioapic),
             "entry is already taken"
         );
-        io_apic.write_redirect_entry(entry_in_ioapic as u8, b);
+        io_apic.write_redirect_entry(entry_in_ioapic, b);
+    }
+
+    fn isa_irq_route(&self, irq_num: u8) -> (u32, u16) {
+        // if we have override mapping for this interrupt, use it.
+        self.source_overrides
+            .iter()
+            .find(|int_override| int_override.source == irq_num)
+            .map(|int_override| (int_override.global_system_interrupt, int_override.flags))
+            .unwrap_or((irq_num as u32, 0))
+    }
+
+    /// The IO APIC and the entry inside it that `irq_num` is delivered to
+    fn io_irq_entry(&mut self, irq_num: u8) -> (&mut IoApic, u8) {
+        assert!(irq_num < 24, "interrupt number is out of range");
+        let (gsi, _) = self.isa_irq_route(irq_num);
+        self.gsi_entry(gsi)
+    }
+
+    /// The IO APIC and the entry inside it that the global system interrupt `gsi` is
+    /// delivered to
+    fn gsi_entry(&mut self, gsi: u32) -> (&mut IoApic, u8) {
+        let io_apic = self
+            .io_apics
+            .iter_mut()
+            .find(|io_apic| {
+                io_apic.global_irq_base <= gsi
+                    && gsi < io_apic.global_irq_base + io_apic.n_entries as u32
+            })
+            .expect("Could not find IO APIC for the interrupt");
+
+        // the location of where we want to
+        let entry_in_ioapic = (gsi - io_apic.global_irq_base) as u8;
+        (io_apic, entry_in_ioapic)
+    }
+
+    fn is_gsi_free(&mut self, gsi: u32) -> bool {
+        let (io_apic, entry) = self.gsi_entry(gsi);
+        !io_apic.is_entry_taken(entry)
+    }
+
+    fn is_io_irq_free(&mut self, irq_num: u8) -> bool {
+        let (io_apic, entry) = self.io_irq_entry(irq_num);
+        !io_apic.is_entry_taken(entry)
+    }
+
+    fn release_io_irq(&mut self, irq_num: u8) {
+        let (io_apic, entry) = self.io_irq_entry(irq_num);
+        let b = IoApicRedirectionBuilder::default()
+            .with_vector(0)
+            .with_mask(true);
+        io_apic.write_redirect_entry(entry, b);
     }
 }
 
@@ -591,11 +703,11 @@ extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame64) {
 }
 
 extern "x86-interrupt" fn error_interrupt_handler(_frame: InterruptStackFrame64) {
-    let error_status = unsafe { (*APIC.lock().mmio).error_status.read() };
+    let error_status = unsafe { APIC.lock().mmio.read_u32(local_apic::ERROR_STATUS) };
     println!("APIC error: {:#X}", error_status);
     // clear the error
     unsafe {
-        (*APIC.lock().mmio).error_status.write(0);
+        APIC.lock().mmio.write_u32(local_apic::ERROR_STATUS, 0);
     }
     return_from_interrupt();
 }
@@ -1,11 +1,19 @@
 //! Global handlers that have several purposes and doesn't belong in 1 place specifically
 
-use crate::{cpu::idt::InterruptAllSavedState, process::scheduler};
+use crate::{
+    cpu::{self, idt::InterruptAllSavedState},
+    devices::clock::{self, TickSource},
+    sync::barrier,
+};
 
 use super::apic;
 
 pub extern "cdecl" fn apic_timer_handler(all_state: &mut InterruptAllSavedState) {
-    scheduler::yield_current_if_any(all_state);
+    // the test writes from the boot CPU, and expects one reader
+    if cpu::cpu().id == 0 {
+        barrier::selftest_tick();
+    }
+    clock::tick(TickSource::LocalApic, all_state);
 
     apic::return_from_interrupt();
 }
@@ -1,14 +1,22 @@
 pub mod apic;
 mod handlers;
 
+use core::ptr;
+
+use alloc::boxed::Box;
+
 use crate::sync::spin::mutex::Mutex;
 
 use super::{
     gdt::USER_RING,
-    idt::{BasicInterruptHandler, InterruptDescriptorTable, InterruptHandlerWithAllState},
+    idt::{self, BasicInterruptHandler, InterruptDescriptorTable, InterruptHandlerWithAllState},
+    AlreadyInitialized, Cpu, CpuTable, MAX_CPUS,
 };
 
-static INTERRUPTS: Mutex<Interrupts> = Mutex::new(Interrupts::empty());
+/// The IDT of each CPU, by its `id`. The vectors are allocated in the one of the boot CPU,
+/// and copied to the others, so a vector has the same handler on all of them
+static INTERRUPTS: [Mutex<Interrupts>; MAX_CPUS] =
+    [const { Mutex::new(Interrupts::empty()) }; MAX_CPUS];
 
 pub(super) mod stack_index {
     pub const FAULTS_STACK: u8 = 0;
@@ -18,6 +26,8 @@ pub(super) mod stack_index {
 const USER_INTERRUPTS_START: u8 = 0x20;
 const MAX_USER_INTERRUPTS: u8 = 0xe0 - 0x10;
 pub const SPECIAL_SCHEDULER_INTERRUPT: u8 = 0xdf; // last one (0xFF)
+/// For the kernel threads to park themselves, see `process::kernel_thread`
+pub const SPECIAL_KERNEL_THREAD_INTERRUPT: u8 = 0xdd; // (0xFD)
 pub const SPECIAL_SYSCALL_INTERRUPT: u8 =
     kernel_user_link::syscalls::SYSCALL_INTERRUPT_NUMBER - USER_INTERRUPTS_START;
 
@@ -34,12 +44,22 @@ impl Interrupts {
         }
     }
 
-    // only apply init for static context
-    fn init(&'static mut self) {
+    /// Fills the exception handlers for `cpu`, fails if it already has its IDT
+    fn build_for(&mut self, cpu: &Cpu) -> Result<(), AlreadyInitialized> {
+        cpu.start_init(CpuTable::Idt)?;
         self.idt.init_default_handlers();
+        Ok(())
+    }
 
-        // this is only done once
-        self.idt.apply_idt();
+    /// Empties the table and the allocated interrupts, so it can be built again for `cpu`,
+    /// which must not be the running CPU
+    fn reset_for_tests(&mut self, cpu: &Cpu) {
+        assert!(
+            !ptr::eq(cpu, super::cpu()),
+            "the IDT of the running CPU can't be reset"
+        );
+        cpu.reset_for_tests(CpuTable::Idt);
+        *self = Self::empty();
     }
 
     fn get_next_interrupt(&mut self) -> u8 {
@@ -53,10 +73,13 @@ impl Interrupts {
         interrupt as u8
     }
 
-    fn allocate_basic_user_interrupt(&mut self, handler: BasicInterruptHandler) -> u8 {
+    /// The entry goes to the stub of the vector, whose handler is set by the caller, since
+    /// they are shared by all the IDTs, see [`idt::set_basic_handler`]
+    fn allocate_basic_user_interrupt(&mut self) -> u8 {
         let interrupt = self.get_next_interrupt();
 
-        self.idt.user_defined[interrupt as usize].set_handler(handler);
+        self.idt.user_defined[interrupt as usize]
+            .set_handler_stub(interrupt + USER_INTERRUPTS_START);
 
         interrupt + USER_INTERRUPTS_START
     }
@@ -71,12 +94,33 @@ impl Interrupts {
     }
 }
 
+/// Builds and loads the IDT of the running CPU, with the vectors allocated so far, fails
+/// with an [`AlreadyInitialized`] panic if called again
 pub fn init_interrupts() {
-    INTERRUPTS.run_with_mut(|idt| {
-        idt.init();
+    let cpu = super::cpu();
+    // the boot one first, see `copy_to_others`
+    let boot = (cpu.id != 0).then(|| INTERRUPTS[0].lock());
+    INTERRUPTS[cpu.id].run_with_mut(|interrupts| {
+        interrupts
+            .build_for(cpu)
+            .unwrap_or_else(|e| panic!("Could not initialize the IDT: {e}"));
+        if let Some(boot) = &boot {
+            interrupts.idt.user_defined = boot.idt.user_defined;
+            interrupts.last_used_user_interrupt = boot.last_used_user_interrupt;
+        }
+        interrupts.idt.apply_idt();
     });
 }
 
+/// Copies the entry of the user interrupt `interrupt` from `boot` (the locked IDT of the boot
+/// CPU) to the IDTs of the other CPUs, those not started yet copy it when they are
+fn copy_to_others(boot: &Interrupts, interrupt: u8) {
+    for other in &INTERRUPTS[1..] {
+        other.lock().idt.user_defined[interrupt as usize] =
+            boot.idt.user_defined[interrupt as usize];
+    }
+}
+
 // All Types of interrupt handlers
 pub trait InterruptHandler {
     fn allocate_and_set_handler(val: Self) -> u8;
@@ -84,13 +128,20 @@ pub trait InterruptHandler {
 
 impl InterruptHandler for BasicInterruptHandler {
     fn allocate_and_set_handler(handler: Self) -> u8 {
-        INTERRUPTS.lock().allocate_basic_user_interrupt(handler)
+        let mut boot = INTERRUPTS[0].lock();
+        let vector = boot.allocate_basic_user_interrupt();
+        idt::set_basic_handler(vector, handler);
+        copy_to_others(&boot, vector - USER_INTERRUPTS_START);
+        vector
     }
 }
 
 impl InterruptHandler for InterruptHandlerWithAllState {
     fn allocate_and_set_handler(handler: Self) -> u8 {
-        INTERRUPTS.lock().allocate_user_interrupt_all_saved(handler)
+        let mut boot = INTERRUPTS[0].lock();
+        let vector = boot.allocate_user_interrupt_all_saved(handler);
+        copy_to_others(&boot, vector - USER_INTERRUPTS_START);
+        vector
     }
 }
 
@@ -110,16 +161,80 @@ pub(super) fn allocate_user_interrupt_all_saved(handler: InterruptHandlerWithAll
 }
 
 pub fn create_scheduler_interrupt(handler: InterruptHandlerWithAllState) {
-    let mut interrupts = INTERRUPTS.lock();
+    let mut interrupts = INTERRUPTS[0].lock();
     interrupts.idt.user_defined[SPECIAL_SCHEDULER_INTERRUPT as usize]
         .set_handler_with_number(handler, SPECIAL_SCHEDULER_INTERRUPT + USER_INTERRUPTS_START)
         .set_disable_interrupts(true);
+    copy_to_others(&interrupts, SPECIAL_SCHEDULER_INTERRUPT);
+}
+
+pub fn create_kernel_thread_interrupt(handler: InterruptHandlerWithAllState) {
+    let mut interrupts = INTERRUPTS[0].lock();
+    interrupts.idt.user_defined[SPECIAL_KERNEL_THREAD_INTERRUPT as usize]
+        .set_handler_with_number(
+            handler,
+            SPECIAL_KERNEL_THREAD_INTERRUPT + USER_INTERRUPTS_START,
+        )
+        .set_disable_interrupts(true);
+    copy_to_others(&interrupts, SPECIAL_KERNEL_THREAD_INTERRUPT);
 }
 
 pub fn create_syscall_interrupt(handler: InterruptHandlerWithAllState) {
-    let mut interrupts = INTERRUPTS.lock();
+    let mut interrupts = INTERRUPTS[0].lock();
     interrupts.idt.user_defined[SPECIAL_SYSCALL_INTERRUPT as usize]
         .set_handler_with_number(handler, SPECIAL_SYSCALL_INTERRUPT + USER_INTERRUPTS_START)
         .set_privilege_level(USER_RING)
-        .set_disable_interrupts(false);
+        // enabled by the handler after the `swapgs`, see `idt_vectors.S`
+        .set_disable_interrupts(true);
+    copy_to_others(&interrupts, SPECIAL_SYSCALL_INTERRUPT);
+}
+
+/// The boot CPU can't initialize its IDT again, and the IDT of `other` (a CPU that is not
+/// running) is built, reset and built again, with the same exceptions as the boot one
+pub(super) fn run_self_tests(other: &Cpu) {
+    let boot_cpu = super::cpu();
+    assert!(boot_cpu.is_initialized(CpuTable::Idt));
+    let boot_exceptions = {
+        let mut interrupts = INTERRUPTS[boot_cpu.id].lock();
+        assert_eq!(
+            interrupts.build_for(boot_cpu),
+            Err(AlreadyInitialized {
+                cpu: boot_cpu.id,
+                table: CpuTable::Idt,
+            })
+        );
+        let mut exceptions = [0; USER_INTERRUPTS_START as usize];
+        exceptions.copy_from_slice(&interrupts.idt.raw_entries()[..USER_INTERRUPTS_START as usize]);
+        exceptions
+    };
+
+    let mut interrupts = Box::new(Interrupts::empty());
+    for _ in 0..3 {
+        interrupts.build_for(other).unwrap();
+        assert_eq!(
+            interrupts.build_for(other),
+            Err(AlreadyInitialized {
+                cpu: other.id,
+                table: CpuTable::Idt,
+            })
+        );
+        assert!(other.is_initialized(CpuTable::Idt));
+        assert_eq!(
+            interrupts.idt.raw_entries()[..USER_INTERRUPTS_START as usize],
+            boot_exceptions
+        );
+        // the vectors are allocated from the start again after a reset
+        assert_eq!(
+            interrupts.allocate_basic_user_interrupt(),
+            USER_INTERRUPTS_START
+        );
+        assert_eq!(
+            interrupts.allocate_basic_user_interrupt(),
+            USER_INTERRUPTS_START + 1
+        );
+
+        interrupts.reset_for_tests(other);
+        assert!(!other.is_initialized(CpuTable::Idt));
+        assert!(interrupts.idt.raw_entries().iter().all(|&entry| entry == 0));
+    }
 }
@@ -0,0 +1,256 @@
+//! Measures how long the interrupts stay disabled, only with the `irqoff` feature.
+//!
+//! Each CPU times the sections from the [`Cpu::push_cli`] that disabled the interrupts to the
+//! [`Cpu::pop_cli`] that enables them again, the locks disable them too, so these are mostly
+//! the times a lock is held. The syscalls are timed separately, they run with the interrupts
+//! disabled from the interrupt gate until they return. So are the interrupt handlers that
+//! mark themselves with [`interrupt_enter`], the rest of their work is deferred to
+//! [`super::softirq`], which runs with the interrupts enabled and is not timed.
+//!
+//! The worst section is kept with where it started, and all of them are counted in a
+//! histogram by powers of 2 of microseconds, shown in `/devices/irq_off` and at the end of
+//! the boot. Nothing is recorded before the TSC is calibrated.
+
+use core::{fmt, panic::Location};
+
+use alloc::{boxed::Box, sync::Arc};
+use kernel_user_link::syscalls::SYSCALL_NAMES;
+
+use crate::devices::{
+    self,
+    generated::{self, Cursor, Generator},
+    pit, Device,
+};
+
+use super::{cpu, rdtsc};
+
+/// Whether the kernel was built with the tracker, it does nothing otherwise
+pub const ENABLED: bool = cfg!(feature = "irqoff");
+
+/// `< 1us`, `< 2us`, ... `< 2^(BUCKETS - 2)us`, and the rest in the last one
+const BUCKETS: usize = 18;
+
+/// Where the interrupts were disabled
+#[derive(Debug, Clone, Copy)]
+pub enum Origin {
+    /// The caller of the outermost `push_cli`, or of the lock that called it
+    Cli(&'static Location<'static>),
+    /// The syscall number
+    Syscall(u64),
+    /// The device of the interrupt handler
+    Interrupt(&'static str),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Cli(location) => write!(f, "{location}"),
+            Origin::Syscall(number) => match SYSCALL_NAMES.get(*number as usize) {
+                Some(name) => write!(f, "syscall {name}"),
+                None => write!(f, "syscall {number}"),
+            },
+            Origin::Interrupt(device) => write!(f, "{device} interrupt"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrqOffStats {
+    pub count: u64,
+    pub worst_micros: u64,
+    pub worst: Option<Origin>,
+    histogram: [u64; BUCKETS],
+}
+
+impl IrqOffStats {
+    const fn empty() -> Self {
+        Self {
+            count: 0,
+            worst_micros: 0,
+            worst: None,
+            histogram: [0; BUCKETS],
+        }
+    }
+
+    fn record(&mut self, ticks: u64, origin: Origin) {
+        let hz = pit::tsc_hz();
+        if hz == 0 {
+            return;
+        }
+        let micros = (ticks as u128 * 1_000_000 / hz as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.histogram[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        if micros >= self.worst_micros {
+            self.worst_micros = micros;
+            self.worst = Some(origin);
+        }
+    }
+}
+
+impl fmt::Display for IrqOffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "count: {}\nworst: {}us", self.count, self.worst_micros)?;
+        if let Some(origin) = self.worst {
+            write!(f, " at {origin}")?;
+        }
+        writeln!(f)?;
+        for (i, count) in self.histogram.iter().enumerate() {
+            if i == BUCKETS - 1 {
+                writeln!(f, ">= {}us: {count}", 1u64 << (i - 1))?;
+            } else {
+                writeln!(f, "< {}us: {count}", 1u64 << i)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The tracker of a CPU
+#[derive(Debug)]
+pub(super) struct IrqOffTracker {
+    // the TSC when the current section started
+    start: u64,
+    origin: Option<Origin>,
+    cli: IrqOffStats,
+    syscalls: IrqOffStats,
+    interrupts: IrqOffStats,
+}
+
+impl IrqOffTracker {
+    pub const fn empty() -> Self {
+        Self {
+            start: 0,
+            origin: None,
+            cli: IrqOffStats::empty(),
+            syscalls: IrqOffStats::empty(),
+            interrupts: IrqOffStats::empty(),
+        }
+    }
+
+    pub fn start(&mut self, origin: Origin) {
+        self.origin = Some(origin);
+        self.start = rdtsc();
+    }
+
+    pub fn stop(&mut self) {
+        let end = rdtsc();
+        let Some(origin) = self.origin.take() else {
+            return;
+        };
+        let ticks = end.saturating_sub(self.start);
+        match origin {
+            Origin::Cli(_) => self.cli.record(ticks, origin),
+            Origin::Syscall(_) => self.syscalls.record(ticks, origin),
+            Origin::Interrupt(_) => self.interrupts.record(ticks, origin),
+        }
+    }
+}
+
+/// Starts timing a syscall, the interrupts are already disabled by the interrupt gate
+pub fn syscall_enter(number: u64) {
+    if ENABLED {
+        cpu().irq_off.start(Origin::Syscall(number));
+    }
+}
+
+pub fn syscall_exit() {
+    if ENABLED {
+        cpu().irq_off.stop();
+    }
+}
+
+/// Starts timing an interrupt handler, until [`interrupt_exit`]. Nothing else is timed while
+/// it runs, the interrupts only come when no section is disabling them
+pub fn interrupt_enter(device: &'static str) {
+    if ENABLED {
+        cpu().irq_off.start(Origin::Interrupt(device));
+    }
+}
+
+pub fn interrupt_exit() {
+    if ENABLED {
+        cpu().irq_off.stop();
+    }
+}
+
+/// The stats of the sections disabled by `push_cli`, of the syscalls, and of the interrupt
+/// handlers, on this CPU
+pub fn stats() -> (IrqOffStats, IrqOffStats, IrqOffStats) {
+    let cpu = cpu();
+    cpu.push_cli();
+    let stats = (
+        cpu.irq_off.cli,
+        cpu.irq_off.syscalls,
+        cpu.irq_off.interrupts,
+    );
+    cpu.pop_cli();
+    stats
+}
+
+/// Prints the line for the end of the boot, if the tracker is enabled
+pub fn print_summary() {
+    if !ENABLED {
+        return;
+    }
+    let (cli, syscalls, interrupts) = stats();
+    print!(
+        "Interrupts disabled: {} times, worst {}us",
+        cli.count, cli.worst_micros
+    );
+    if let Some(origin) = cli.worst {
+        print!(" at {origin}");
+    }
+    println!(
+        ", {} syscalls, {} interrupts, worst {}us",
+        syscalls.count, interrupts.count, interrupts.worst_micros
+    );
+}
+
+/// `/devices/irq_off`, the stats of the sections disabled by `push_cli`, of the syscalls then
+/// of the interrupt handlers, taken when its opened
+#[derive(Debug)]
+struct IrqOffDevice;
+
+impl Device for IrqOffDevice {
+    fn name(&self) -> &str {
+        "irq_off"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        let (cli, syscalls, interrupts) = stats();
+        Some(Box::new(IrqOffInfo {
+            cli,
+            syscalls,
+            interrupts,
+        }))
+    }
+}
+
+/// The field of the cursor is the line
+#[derive(Debug)]
+struct IrqOffInfo {
+    cli: IrqOffStats,
+    syscalls: IrqOffStats,
+    interrupts: IrqOffStats,
+}
+
+impl Generator for IrqOffInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        generated::render_display_lines(
+            &format_args!(
+                "[push_cli]\n{}[syscalls]\n{}[interrupts]\n{}",
+                self.cli, self.syscalls, self.interrupts
+            ),
+            cursor,
+            out,
+        )
+    }
+}
+
+/// Adds `/devices/irq_off` if the tracker is enabled
+pub fn init_device() {
+    if ENABLED {
+        devices::register_device(Arc::new(IrqOffDevice));
+    }
+}
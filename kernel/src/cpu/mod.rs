@@ -1,16 +1,28 @@
-use crate::process::ProcessContext;
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use crate::process::{scheduler::TimeAccounting, ProcessContext, ProcessName};
 
 use self::{
     gdt::{GlobalDescriptorTablePointer, SegmentSelector},
     idt::InterruptDescriptorTablePointer,
+    irq_off::{IrqOffTracker, Origin},
+    softirq::SoftIrqState,
 };
 
+pub mod exception_table;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
+pub mod irq_off;
+pub mod smp;
+pub mod softirq;
 
 const CPUID_FN_FEAT: u32 = 1;
-const MAX_CPUS: usize = 8;
+pub const MAX_CPUS: usize = 8;
 
 pub mod flags {
     pub const IF: u64 = 1 << 9;
@@ -20,6 +32,9 @@ pub mod flags {
 pub mod msr {
     pub const APIC_BASE: u32 = 0x1b;
     pub const EFER: u32 = 0xc0000080;
+    pub const FS_BASE: u32 = 0xc0000100;
+    pub const GS_BASE: u32 = 0xc0000101;
+    pub const KERNEL_GS_BASE: u32 = 0xc0000102;
 
     pub unsafe fn read(reg: u32) -> u64 {
         let (eax, edx): (u32, u32);
@@ -34,16 +49,48 @@ pub mod msr {
     }
 }
 
-static mut CPUS: [Cpu; MAX_CPUS] = [Cpu::empty(); MAX_CPUS];
+static mut CPUS: [Cpu; MAX_CPUS] = [const { Cpu::empty() }; MAX_CPUS];
+
+/// The descriptor tables every CPU loads for itself, once, see [`Cpu::start_init`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuTable {
+    Gdt = 1 << 0,
+    Idt = 1 << 1,
+}
+
+/// The table was already loaded on the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized {
+    pub cpu: usize,
+    pub table: CpuTable,
+}
 
-#[derive(Debug, Clone, Copy)]
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} of CPU {} already initialized",
+            self.table, self.cpu
+        )
+    }
+}
+
+/// The per-CPU area, the GS base points to the one of the running CPU while in the kernel
+#[derive(Debug)]
+#[repr(C)]
 pub struct Cpu {
+    // first, `cpu()` reads it with `gs:[0]`, see `set_current`
+    this: *mut Cpu,
     // index of myself inside `CPUS`
     pub id: usize,
     apic_id: u8,
     old_interrupt_enable: bool,
-    // number of times we have called `cli`
+    // number of times we have called `cli`, on this CPU, the interrupts are per CPU too
     n_cli: usize,
+    // number of times we have called `push_no_yield`, the timer doesn't switch away from the
+    // current process while it's not `0`
+    n_no_yield: usize,
 
     // saved context, when switching from kernel to user and vice versa
     // if there is a value here, it indicates that we are running a processing now
@@ -53,19 +100,35 @@ pub struct Cpu {
     pub context: Option<ProcessContext>,
     // the process id of the current process
     pub process_id: u64,
-    pub scheduling: bool,
+    // a copy of its name, for the interrupts that can't take the scheduler lock
+    pub process_name: ProcessName,
+    // set by the scheduler while switching to a process, and read by the timer interrupt,
+    // so this must be atomic, see `sync::barrier`
+    scheduling: AtomicBool,
+    pub time_accounting: TimeAccounting,
+    irq_off: IrqOffTracker,
+    softirq: SoftIrqState,
+    // the `CpuTable`s initialized
+    tables: AtomicU8,
 }
 
 impl Cpu {
     const fn empty() -> Self {
         Self {
+            this: core::ptr::null_mut(),
             id: 0,
             apic_id: 0,
             old_interrupt_enable: false,
             n_cli: 0,
+            n_no_yield: 0,
             context: None,
             process_id: 0,
-            scheduling: false,
+            process_name: ProcessName::empty(),
+            scheduling: AtomicBool::new(false),
+            time_accounting: TimeAccounting::empty(),
+            irq_off: IrqOffTracker::empty(),
+            softirq: SoftIrqState::empty(),
+            tables: AtomicU8::new(0),
         }
     }
 
@@ -74,12 +137,39 @@ impl Cpu {
         self.apic_id = apic_id;
     }
 
+    /// Marks `table` as initialized, fails if it already was, so it can only succeed once
+    /// for each table until [`Self::reset_for_tests`]
+    pub fn start_init(&self, table: CpuTable) -> Result<(), AlreadyInitialized> {
+        if self.tables.fetch_or(table as u8, Ordering::AcqRel) & table as u8 != 0 {
+            return Err(AlreadyInitialized {
+                cpu: self.id,
+                table,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_initialized(&self, table: CpuTable) -> bool {
+        self.tables.load(Ordering::Acquire) & table as u8 != 0
+    }
+
+    /// Allows `table` to be initialized again, the table itself must be reset by its owner,
+    /// only for the self tests, which use a CPU that is not running
+    fn reset_for_tests(&self, table: CpuTable) {
+        self.tables.fetch_and(!(table as u8), Ordering::AcqRel);
+    }
+
+    /// Disables the interrupts, until the same number of [`Self::pop_cli`]
+    #[track_caller]
     pub fn push_cli(&mut self) {
         if self.n_cli == 0 {
             let rflags = unsafe { rflags() };
             let old_interrupt_flag = rflags & flags::IF != 0;
             unsafe { clear_interrupts() };
             self.old_interrupt_enable = old_interrupt_flag;
+            if irq_off::ENABLED && old_interrupt_flag {
+                self.irq_off.start(Origin::Cli(Location::caller()));
+            }
         }
         // re-read the flags
         let rflags = unsafe { rflags() };
@@ -95,6 +185,9 @@ impl Cpu {
 
         self.n_cli -= 1;
         if self.n_cli == 0 && self.old_interrupt_enable {
+            if irq_off::ENABLED {
+                self.irq_off.stop();
+            }
             unsafe { set_interrupts() };
         }
     }
@@ -106,11 +199,101 @@ impl Cpu {
     pub fn n_cli(&self) -> usize {
         self.n_cli
     }
+
+    /// Keeps the current process on this CPU, with the interrupts enabled, until the same
+    /// number of [`Self::pop_no_yield`]. For what others may spin on with the interrupts
+    /// disabled, and that is too long to hold a lock
+    pub fn push_no_yield(&mut self) {
+        assert!(self.n_no_yield < usize::MAX);
+        self.n_no_yield += 1;
+    }
+
+    pub fn pop_no_yield(&mut self) {
+        assert!(self.n_no_yield > 0);
+        self.n_no_yield -= 1;
+    }
+
+    pub fn n_no_yield(&self) -> usize {
+        self.n_no_yield
+    }
+
+    /// Whether the scheduler is in the middle of switching to a process
+    pub fn is_scheduling(&self) -> bool {
+        self.scheduling.load(Ordering::Acquire)
+    }
+
+    pub fn set_scheduling(&self, scheduling: bool) {
+        self.scheduling.store(scheduling, Ordering::Release);
+    }
 }
 
+/// The running CPU, from the GS base, which is its [`Cpu`] while in the kernel.
+///
+/// The interrupts from user mode switch to it with `swapgs`, and back on the way out, see
+/// `idt_vectors.S`, so the `gs` of the user is its own
 pub fn cpu() -> &'static mut Cpu {
-    // TODO: use thread local to get the current cpu
-    unsafe { &mut CPUS[0] }
+    let current: *mut Cpu;
+    // SAFETY: the GS base points to one of `CPUS`, whose first field points to itself, written
+    //         by `set_current` before anything runs on the CPU
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) current, options(nostack, readonly, preserves_flags));
+        &mut *current
+    }
+}
+
+/// Makes `CPUS[id]` the one [`cpu`] returns on the running CPU, the user GS base, which
+/// `swapgs` loads on the way to user mode, starts as `0`
+///
+/// # Safety
+/// Must be called once by each CPU when it starts, before anything uses [`cpu`], with its own
+/// `id`, and the boot CPU is `0`
+pub unsafe fn set_current(id: usize) {
+    let current = core::ptr::addr_of_mut!(CPUS[id]);
+    (*current).this = current;
+    msr::write(msr::GS_BASE, current as u64);
+    msr::write(msr::KERNEL_GS_BASE, 0);
+}
+
+/// Switches to the kernel GS base if it's the one of the user, for the exception handlers that
+/// don't return, they can come from the kernel before the `swapgs` of an interrupt from user
+/// mode, or after the one on its way back
+///
+/// # Safety
+/// Must only be called by a handler that doesn't go back to where the exception came from
+unsafe fn ensure_kernel_gs() {
+    // user code can only load a GS base from a selector, so it's in the lower half
+    if (msr::read(msr::GS_BASE) as i64) >= 0 {
+        core::arch::asm!("swapgs", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// The id of the running CPU, from whichever of the two GS bases is the kernel one now, for the
+/// NMI handler, which can come between an interrupt from user mode and its `swapgs`
+///
+/// # Safety
+/// Must be called after [`set_current`]
+unsafe fn id_from_any_gs() -> usize {
+    let gs_base = msr::read(msr::GS_BASE);
+    // see `ensure_kernel_gs`
+    let current = if (gs_base as i64) < 0 {
+        gs_base
+    } else {
+        msr::read(msr::KERNEL_GS_BASE)
+    };
+    (*(current as *const Cpu)).id
+}
+
+/// The GDT and IDT of a second CPU are built and reset a few times, it is never started, then
+/// the deferred work of this one is flooded
+pub fn run_self_tests() {
+    println!("Running CPU self tests...");
+    let mut other = Cpu::empty();
+    other.init(1, 1);
+    gdt::run_self_tests(&other);
+    interrupts::run_self_tests(&other);
+    softirq::run_self_tests();
+    exception_table::run_self_tests();
+    println!("CPU self tests passed");
 }
 
 pub unsafe fn rflags() -> u64 {
@@ -189,6 +372,11 @@ pub unsafe fn io_in<T: IoPortInt>(port: u16) -> T {
     T::io_in(port)
 }
 
+/// The time stamp counter, counts at a constant rate (on all CPUs we care about) since reset
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
 pub unsafe fn clear_interrupts() {
     core::arch::asm!("cli", options(nomem, nostack, preserves_flags));
 }
@@ -204,6 +392,12 @@ pub unsafe fn get_cr0() -> u64 {
     cr0
 }
 
+pub unsafe fn get_cr2() -> u64 {
+    let cr2: u64;
+    core::arch::asm!("mov {0:r}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    cr2
+}
+
 #[allow(dead_code)]
 pub unsafe fn set_cr0(cr0: u64) {
     core::arch::asm!("mov cr0, rax", in("rax") cr0, options(nomem, nostack, preserves_flags));
@@ -264,7 +458,7 @@ unsafe fn set_data_segments(ds: SegmentSelector) {
         "mov es, {0:r}",
         "mov ss, {0:r}",
         "mov fs, {0:r}",
-        "mov gs, {0:r}",
+        // not `gs`, loading it clears the GS base, which is the `Cpu` of this one
         in(reg) ds.0, options(preserves_flags));
 }
 
@@ -0,0 +1,323 @@
+//! Starting the other CPUs (application processors), found in the MADT by [`apic::init`].
+//!
+//! Each one gets its own GDT, TSS, IDT, interrupt stacks and local APIC timer, and
+//! [`super::cpu`] returns its own [`Cpu`]. They wait in `hlt` until the boot CPU is done
+//! booting (see [`release_application_processors`]), then each runs the scheduler on its own
+//! queue. Meanwhile, the boot CPU can give them work, see [`run_on_all`]. The clock and the timers are kept by the boot CPU, the ticks of the others only
+//! switch their processes, see [`clock::tick`].
+//!
+//! A change of the kernel mappings, which all of them use, is flushed from the TLBs of the
+//! others before the caller goes on, see [`flush_tlb_others`].
+
+use core::{
+    alloc::Layout,
+    hint, mem,
+    ptr::{self, addr_of},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::{
+        self, gdt,
+        interrupts::{
+            self,
+            apic::{self, Ipi},
+        },
+        msr, Cpu, CPUS, MAX_CPUS,
+    },
+    devices::{clock, pit},
+    memory_management::memory_layout::{
+        physical2virtual, virtual2physical, AP_TRAMPOLINE_ADDR, PAGE_4K,
+    },
+    process::scheduler,
+    sync::spin::mutex::Mutex,
+};
+
+core::arch::global_asm!(include_str!("ap_trampoline.S"));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static boot_page_tables: u8;
+}
+
+/// The stack each CPU starts on, and stays on while not in a process
+const AP_STACK_SIZE: usize = PAGE_4K * 16;
+/// Where [`TrampolineData`] is, after the code, in the same page
+const DATA_OFFSET: usize = 0xF00;
+
+/// The waits of the INIT-SIPI-SIPI sequence, from the Intel MP spec
+const INIT_DELAY_NANOS: u64 = 10_000_000;
+const STARTUP_DELAY_NANOS: u64 = 200_000;
+/// How long a CPU has to get to [`ap_main`]'s end after the startup IPIs
+const ONLINE_TIMEOUT_NANOS: u64 = 1_000_000_000;
+/// The TSC rate used when it's not measured, as if it was fast, so we wait at least as long
+const ASSUMED_TSC_HZ: u64 = 4_000_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// The CPUs that finished [`ap_main`], without the boot one
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// Set by [`release_application_processors`], the others run the scheduler after it
+static RELEASED: AtomicBool = AtomicBool::new(false);
+/// The work of [`run_on_all`], with a new generation each time, so each CPU runs it once
+static WORK: Mutex<(u64, Option<Work>)> = Mutex::new((0, None));
+/// The CPUs that didn't finish the work of [`run_on_all`] yet
+static WORKING: AtomicUsize = AtomicUsize::new(0);
+/// Taken by the CPU in [`flush_tlb_others`], until all the others flushed
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+/// Set for each CPU asked to flush its TLB, it clears its own when it did
+static FLUSH_REQUESTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+type Work = &'static (dyn Fn() + Sync);
+
+/// Read by `ap_trampoline.S` at `AP_DATA`, most is the state of the boot CPU to copy
+#[repr(C)]
+struct TrampolineData {
+    // physical, 32 bits, the trampoline uses it before long mode
+    boot_cr3: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack_end: u64,
+    cpu_id: u64,
+}
+
+const _: () = assert!(mem::offset_of!(TrampolineData, cpu_id) == 0x30);
+const _: () = assert!(DATA_OFFSET + mem::size_of::<TrampolineData>() <= PAGE_4K);
+
+/// The number of CPUs running, with the boot one
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire) + 1
+}
+
+/// Starts the other CPUs one by one, unless `smp=off` is in `cmdline`.
+///
+/// Needs the clock, to wait for them, and must be called before the trampoline page is
+/// sealed, see [`virtual_memory_mapper::seal_legacy_boot_regions`]
+///
+/// [`virtual_memory_mapper::seal_legacy_boot_regions`]: crate::memory_management::virtual_memory_mapper::seal_legacy_boot_regions
+pub fn start_application_processors(cmdline: &str) {
+    let n_cpus = apic::n_cpus();
+    if n_cpus == 1 {
+        return;
+    }
+    if cmdline.split_whitespace().any(|arg| arg == "smp=off") {
+        println!("[smp] `smp=off`, only the boot CPU of {n_cpus} runs");
+        return;
+    }
+
+    // SAFETY: both are in the same section, the code of the trampoline
+    let code = unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end).offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    assert!(code.len() <= DATA_OFFSET, "the AP trampoline is too big");
+    let page = physical2virtual(AP_TRAMPOLINE_ADDR) as *mut u8;
+    // SAFETY: the page is writable until it's sealed, and no CPU runs from it now
+    unsafe { ptr::copy_nonoverlapping(code.as_ptr(), page, code.len()) };
+
+    // SAFETY: only changed by `apic::init`, before this
+    let cpus = unsafe { &*addr_of!(CPUS) };
+    for cpu in &cpus[1..n_cpus] {
+        // a late one would run with the data of the next, so stop here
+        if !start(cpu) {
+            println!(
+                "WARNING: CPU {} (APIC ID {}) didn't start, not starting the rest",
+                cpu.id, cpu.apic_id
+            );
+            break;
+        }
+    }
+    println!("[smp] {} of {n_cpus} CPUs online", online_cpus());
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to `cpu`, and waits for it to be online
+fn start(cpu: &Cpu) -> bool {
+    let stack =
+        unsafe { alloc::alloc::alloc(Layout::from_size_align(AP_STACK_SIZE, PAGE_4K).unwrap()) };
+    assert!(
+        !stack.is_null(),
+        "no memory for the stack of CPU {}",
+        cpu.id
+    );
+    // SAFETY: we are the boot CPU, and its state is what every CPU should have
+    let data = unsafe {
+        TrampolineData {
+            boot_cr3: virtual2physical(addr_of!(boot_page_tables) as usize) as u64,
+            cr0: cpu::get_cr0(),
+            cr3: cpu::get_cr3(),
+            cr4: cpu::get_cr4(),
+            efer: msr::read(msr::EFER),
+            // the same as a `call`, see `boot.S`
+            stack_end: (stack as usize + AP_STACK_SIZE - 8) as u64,
+            cpu_id: cpu.id as u64,
+        }
+    };
+    // SAFETY: the page is writable until it's sealed, and the last CPU started is done with it
+    unsafe {
+        ptr::write_volatile(
+            physical2virtual(AP_TRAMPOLINE_ADDR + DATA_OFFSET) as *mut TrampolineData,
+            data,
+        )
+    };
+
+    let online = ONLINE.load(Ordering::Acquire);
+    let is_online = || ONLINE.load(Ordering::Acquire) > online;
+    let startup = Ipi::Startup((AP_TRAMPOLINE_ADDR / PAGE_4K) as u8);
+    apic::send_ipi(cpu.apic_id, Ipi::Init);
+    delay(INIT_DELAY_NANOS);
+    // the second is only needed by some CPUs, and ignored by the ones that started
+    for _ in 0..2 {
+        apic::send_ipi(cpu.apic_id, startup);
+        delay(STARTUP_DELAY_NANOS);
+    }
+    let mut waited = 0;
+    while !is_online() && waited < ONLINE_TIMEOUT_NANOS {
+        delay(STARTUP_DELAY_NANOS);
+        waited += STARTUP_DELAY_NANOS;
+    }
+    is_online()
+}
+
+/// Runs `work` on the boot CPU, and at the same time on the others, which are still waiting
+/// to be released, returns when all of them returned from it. They come from `hlt`, so the
+/// ones not running yet start on their next timer tick
+pub fn run_on_all(work: &(dyn Fn() + Sync)) {
+    assert!(
+        !RELEASED.load(Ordering::Acquire),
+        "run_on_all after the CPUs are released"
+    );
+    let others = online_cpus() - 1;
+    if others > 0 {
+        // SAFETY: the others only use it until they count themselves out of `WORKING`, it's
+        //         removed from `WORK` after all of them did, before returning
+        let work = unsafe { mem::transmute::<&(dyn Fn() + Sync), Work>(work) };
+        WORKING.store(others, Ordering::Release);
+        let mut current = WORK.lock();
+        *current = (current.0 + 1, Some(work));
+    }
+    work();
+    while WORKING.load(Ordering::Acquire) != 0 {
+        hint::spin_loop();
+    }
+    WORK.lock().1 = None;
+}
+
+/// The work of [`run_on_all`] that this CPU didn't run yet, `generation` is the last it ran
+fn take_work(generation: &mut u64) -> Option<Work> {
+    let current = WORK.lock();
+    let work = current.1.filter(|_| current.0 != *generation)?;
+    *generation = current.0;
+    Some(work)
+}
+
+/// Lets the other CPUs run the scheduler, the boot CPU is done with what must run alone
+pub fn release_application_processors() {
+    RELEASED.store(true, Ordering::Release);
+}
+
+/// Flushes the TLBs of the other online CPUs, and waits until they did, for the changes of the
+/// shared kernel mappings, before the pages they pointed to are freed (see [`sync::barrier`]).
+///
+/// They are asked with an NMI, so the ones with their interrupts disabled flush too, like one
+/// waiting for a lock the caller holds (see [`handle_flush_nmi`]). One CPU asks at a time, the
+/// others that want to wait for it, and are asked like the rest meanwhile
+///
+/// [`sync::barrier`]: crate::sync::barrier
+pub fn flush_tlb_others() {
+    let online = online_cpus();
+    if online == 1 {
+        return;
+    }
+    let current_cpu = cpu::cpu();
+    let id = current_cpu.id;
+    current_cpu.push_cli();
+    while FLUSHING
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+    // SAFETY: only changed by `apic::init`, and the ones started are the first after the boot one
+    let cpus = unsafe { &*addr_of!(CPUS) };
+    let others = || cpus[..online].iter().filter(|cpu| cpu.id != id);
+    for other in others() {
+        FLUSH_REQUESTED[other.id].store(true, Ordering::Release);
+        apic::send_ipi(other.apic_id, Ipi::Nmi);
+    }
+    for other in others() {
+        while FLUSH_REQUESTED[other.id].load(Ordering::Acquire) {
+            hint::spin_loop();
+        }
+    }
+    FLUSHING.store(false, Ordering::Release);
+    current_cpu.pop_cli();
+}
+
+/// Asked first by the NMI handler, `true` if the NMI was from [`flush_tlb_others`], the TLB of
+/// the running CPU is flushed then
+///
+/// # Safety
+/// Must only be called by the NMI handler, which can come before the `swapgs` of an interrupt
+/// from the user, see [`cpu::id_from_any_gs`]
+pub(super) unsafe fn handle_flush_nmi() -> bool {
+    let id = cpu::id_from_any_gs();
+    if !FLUSH_REQUESTED[id].load(Ordering::Acquire) {
+        return false;
+    }
+    // the kernel doesn't use global pages, so it's all of it
+    cpu::set_cr3(cpu::get_cr3());
+    FLUSH_REQUESTED[id].store(false, Ordering::Release);
+    true
+}
+
+/// Busy waits for `nanos`, with the HPET, or the TSC if there is none
+fn delay(nanos: u64) {
+    let start = clock::uptime_nanos();
+    if start != 0 {
+        while clock::uptime_nanos() - start < nanos {
+            hint::spin_loop();
+        }
+        return;
+    }
+    let hz = match pit::tsc_hz() {
+        0 => ASSUMED_TSC_HZ,
+        hz => hz,
+    };
+    let cycles = (nanos as u128 * hz as u128 / NANOS_PER_SECOND as u128) as u64;
+    let start = cpu::rdtsc();
+    while cpu::rdtsc() - start < cycles {
+        hint::spin_loop();
+    }
+}
+
+/// Where each started CPU goes from `ap_trampoline.S`, with the kernel page tables and on
+/// its own stack
+#[no_mangle]
+extern "C" fn ap_main(id: usize) -> ! {
+    // SAFETY: before anything uses `cpu()`, `id` is of this CPU
+    unsafe { cpu::set_current(id) };
+    let cpu = cpu::cpu();
+    assert_eq!(cpu.id, id);
+    gdt::init_kernel_gdt();
+    interrupts::init_interrupts();
+    apic::init_local();
+    println!("[smp] CPU {id} online, APIC ID {}", cpu.apic_id);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+
+    // the timer wakes us up, our queue is empty until the boot CPU is done
+    unsafe { cpu::set_interrupts() };
+    let mut generation = 0;
+    while !RELEASED.load(Ordering::Acquire) {
+        match take_work(&mut generation) {
+            Some(work) => {
+                work();
+                WORKING.fetch_sub(1, Ordering::AcqRel);
+            }
+            None => unsafe { cpu::halt() },
+        }
+    }
+    scheduler::schedule()
+}
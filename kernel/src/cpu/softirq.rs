@@ -0,0 +1,308 @@
+//! The work that interrupt handlers leave for later, so they return quickly.
+//!
+//! A handler raises one of the static [`SoftIrq`] classes, which sets a bit in the pending
+//! set of its CPU, or queues a closure with [`defer`]. Both run from [`run_pending`] with the
+//! interrupts enabled, which the scheduler loop of the CPU calls between processes, it doesn't
+//! halt while something is pending. A handler can't run the work on its way out, the timer
+//! interrupt would switch away from the interrupted process in the middle of it.
+//!
+//! The classes run in their order, then the closures in the order they were queued. There are
+//! at most [`MAX_DEFERRED`] closures waiting on a CPU, the ones after that are dropped and
+//! counted, the classes keep their own queues with their own bounds.
+
+use core::{fmt, mem};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+
+use crate::{
+    devices::{
+        self,
+        generated::{self, Cursor, Generator},
+        Device,
+    },
+    sync::once::OnceLock,
+};
+
+use super::cpu;
+
+/// The closures that can wait on a CPU
+pub const MAX_DEFERRED: usize = 256;
+
+/// The rounds of a [`run_pending`], the work raised by the work itself can keep it going,
+/// the rest is left for the next call
+const MAX_ROUNDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SoftIrq {
+    /// The block devices that interrupted, their drivers look at what completed
+    BlockComplete,
+    /// The bytes of the keyboard, translated and moved to the console
+    TtyInput,
+    /// The frames the network device received, handled by the network stack
+    NetRx,
+}
+
+impl SoftIrq {
+    pub const COUNT: usize = 3;
+    const ALL: [SoftIrq; Self::COUNT] = [Self::BlockComplete, Self::TtyInput, Self::NetRx];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::BlockComplete => "block_complete",
+            Self::TtyInput => "tty_input",
+            Self::NetRx => "net_rx",
+        }
+    }
+}
+
+static HANDLERS: [OnceLock<fn()>; SoftIrq::COUNT] = [const { OnceLock::new() }; SoftIrq::COUNT];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SoftIrqStats {
+    /// The times each class was raised, raising it again before it runs counts too
+    pub raised: [u64; SoftIrq::COUNT],
+    pub ran: [u64; SoftIrq::COUNT],
+    pub deferred: u64,
+    pub deferred_ran: u64,
+    pub deferred_dropped: u64,
+}
+
+impl fmt::Display for SoftIrqStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for class in SoftIrq::ALL {
+            let i = class as usize;
+            writeln!(
+                f,
+                "{}: raised {}, ran {}",
+                class.name(),
+                self.raised[i],
+                self.ran[i]
+            )?;
+        }
+        writeln!(
+            f,
+            "deferred: {}, ran {}, dropped {}",
+            self.deferred, self.deferred_ran, self.deferred_dropped
+        )
+    }
+}
+
+/// The pending work of a CPU
+pub(super) struct SoftIrqState {
+    pending: u8,
+    deferred: VecDeque<Box<dyn FnOnce() + Send>>,
+    // inside `run_pending`, so an interrupt in the middle doesn't start another
+    running: bool,
+    stats: SoftIrqStats,
+}
+
+impl fmt::Debug for SoftIrqState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftIrqState")
+            .field("pending", &self.pending)
+            .field("deferred", &self.deferred.len())
+            .field("running", &self.running)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl SoftIrqState {
+    pub const fn empty() -> Self {
+        Self {
+            pending: 0,
+            deferred: VecDeque::new(),
+            running: false,
+            stats: SoftIrqStats {
+                raised: [0; SoftIrq::COUNT],
+                ran: [0; SoftIrq::COUNT],
+                deferred: 0,
+                deferred_ran: 0,
+                deferred_dropped: 0,
+            },
+        }
+    }
+}
+
+/// Sets the function that runs when `class` is raised, once
+pub fn register(class: SoftIrq, handler: fn()) {
+    HANDLERS[class as usize]
+        .set(handler)
+        .unwrap_or_else(|_| panic!("softirq {} already registered", class.name()));
+}
+
+/// Marks `class` as pending on this CPU, safe from interrupt handlers
+pub fn raise(class: SoftIrq) {
+    let cpu = cpu();
+    cpu.push_cli();
+    cpu.softirq.pending |= class.bit();
+    cpu.softirq.stats.raised[class as usize] += 1;
+    cpu.pop_cli();
+}
+
+/// Queues `work` to run on this CPU after the classes, returns `false` and drops it if
+/// [`MAX_DEFERRED`] are already waiting
+pub fn defer(work: impl FnOnce() + Send + 'static) -> bool {
+    let cpu = cpu();
+    cpu.push_cli();
+    let softirq = &mut cpu.softirq;
+    let queued = softirq.deferred.len() < MAX_DEFERRED;
+    if queued {
+        softirq.deferred.push_back(Box::new(work));
+        softirq.stats.deferred += 1;
+    } else {
+        softirq.stats.deferred_dropped += 1;
+    }
+    cpu.pop_cli();
+    queued
+}
+
+pub fn has_pending() -> bool {
+    let cpu = cpu();
+    cpu.push_cli();
+    let pending = cpu.softirq.pending != 0 || !cpu.softirq.deferred.is_empty();
+    cpu.pop_cli();
+    pending
+}
+
+/// Runs the work pending on this CPU, for a few rounds, the state is only locked to take
+/// the next piece of work, so the interrupts can come in the middle and add more
+pub fn run_pending() {
+    let cpu = cpu();
+    cpu.push_cli();
+    let already_running = mem::replace(&mut cpu.softirq.running, true);
+    cpu.pop_cli();
+    if already_running {
+        return;
+    }
+
+    for _ in 0..MAX_ROUNDS {
+        cpu.push_cli();
+        let pending = mem::take(&mut cpu.softirq.pending);
+        // only the closures queued before this round, so they can't keep it going forever
+        let deferred = cpu.softirq.deferred.len();
+        cpu.pop_cli();
+        if pending == 0 && deferred == 0 {
+            break;
+        }
+
+        for class in SoftIrq::ALL {
+            if pending & class.bit() == 0 {
+                continue;
+            }
+            // raising a class before its driver is there keeps it pending for nothing
+            if let Some(handler) = HANDLERS[class as usize].try_get() {
+                handler();
+            }
+            cpu.push_cli();
+            cpu.softirq.stats.ran[class as usize] += 1;
+            cpu.pop_cli();
+        }
+        for _ in 0..deferred {
+            cpu.push_cli();
+            let work = cpu.softirq.deferred.pop_front();
+            if work.is_some() {
+                cpu.softirq.stats.deferred_ran += 1;
+            }
+            cpu.pop_cli();
+            match work {
+                Some(work) => work(),
+                None => break,
+            }
+        }
+    }
+
+    cpu.push_cli();
+    cpu.softirq.running = false;
+    cpu.pop_cli();
+}
+
+pub fn stats() -> SoftIrqStats {
+    let cpu = cpu();
+    cpu.push_cli();
+    let stats = cpu.softirq.stats;
+    cpu.pop_cli();
+    stats
+}
+
+/// `/devices/softirq`, the counts of every class and of the deferred closures, taken when
+/// its opened
+#[derive(Debug)]
+struct SoftIrqDevice;
+
+impl Device for SoftIrqDevice {
+    fn name(&self) -> &str {
+        "softirq"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(SoftIrqInfo(stats())))
+    }
+}
+
+/// The field of the cursor is the line
+#[derive(Debug)]
+struct SoftIrqInfo(SoftIrqStats);
+
+impl Generator for SoftIrqInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        generated::render_display_lines(&self.0, cursor, out)
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(SoftIrqDevice));
+}
+
+/// Floods the deferred queue past its bound, the ones that fit run in order and the rest are
+/// counted as dropped
+pub(super) fn run_self_tests() {
+    use alloc::vec::Vec;
+
+    use crate::sync::spin::mutex::Mutex;
+
+    static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    // nothing else is queued this early
+    run_pending();
+    let before = stats();
+    let extra = 10;
+    for i in 0..MAX_DEFERRED + extra {
+        let queued = defer(move || ORDER.lock().push(i));
+        assert_eq!(queued, i < MAX_DEFERRED, "softirq self test: wrong bound");
+    }
+    assert!(has_pending());
+    run_pending();
+    assert!(!has_pending());
+
+    let order = core::mem::take(&mut *ORDER.lock());
+    assert_eq!(order.len(), MAX_DEFERRED, "softirq self test: lost work");
+    assert!(
+        order.iter().enumerate().all(|(i, &done)| i == done),
+        "softirq self test: out of order"
+    );
+    let after = stats();
+    assert_eq!(after.deferred - before.deferred, MAX_DEFERRED as u64);
+    assert_eq!(
+        after.deferred_ran - before.deferred_ran,
+        MAX_DEFERRED as u64
+    );
+    assert_eq!(
+        after.deferred_dropped - before.deferred_dropped,
+        extra as u64
+    );
+
+    // work queued by work runs in the next round of the same call
+    defer(|| {
+        defer(|| ORDER.lock().push(1));
+        ORDER.lock().push(0);
+    });
+    run_pending();
+    assert_eq!(*ORDER.lock(), [0, 1], "softirq self test: nested work");
+    ORDER.lock().clear();
+}
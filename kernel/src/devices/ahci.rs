@@ -0,0 +1,1470 @@
+//! AHCI, the SATA controller of the newer chipsets (qemu `-device ahci`, and the one built in
+//! `-machine q35`), with the ATA disks on its ports as `/devices/ahci<n>`.
+//!
+//! Each port has a command list of up to 32 slots, a slot points to its command table, which
+//! has the command FIS and the list of the memory to transfer (PRDT). With native command
+//! queuing (NCQ) the disk takes all the slots at once and completes them in any order, so a
+//! [`RequestHandle`] is the slot of its request: [`BlockDevice::submit`] fills a free slot and
+//! issues it, and [`BlockDevice::wait`] waits until the port is done with it. Without NCQ
+//! there is one request in flight, with `READ/WRITE DMA EXT`.
+//!
+//! The requests are copied through buffers of their slot, allocated page by page at the
+//! start, so the data of a request (on the heap) doesn't have to be contiguous.
+//!
+//! The completions are taken from the registers of the port, by the interrupt and by the
+//! waiters, which poll without it. A failed NCQ command aborts all the others of the port,
+//! so after the port is restarted each of them is run again alone, and the error goes to
+//! the request that fails again. A request not done after [`COMMAND_TIMEOUT_NANOS`] fails with
+//! all the others in flight.
+//!
+//! Only the ATA disks with 512 bytes sectors are used, the ports with ATAPI drives,
+//! port multipliers and hot plugging are not supported.
+//!
+//! Writing `scatter` to `/devices/ahci<n>_info` checks that scattered requests in flight
+//! together get their own data (the sectors are put back after), and `bench <MiB>` reads that
+//! much one request at a time and then [`BENCH_QUEUE_DEPTH`] at a time, both results are
+//! shown in the file.
+
+use core::{
+    fmt, hint, mem,
+    sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+use kernel_core::sector::Lba;
+
+use crate::{
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+        irq_off,
+    },
+    devices::{
+        self,
+        block::{
+            BlockDevice, BlockDeviceFile, BlockRequest, BlockRequestKind, RequestHandle,
+            StorageErrorKind, MAX_SECTORS_PER_REQUEST,
+        },
+        clock,
+        generated::{self, Cursor, Generator},
+        ide::AtaIdentify,
+        Device, WeakDevice,
+    },
+    fs::FileSystemError,
+    memory_management::{
+        memory_layout::{virtual2physical, MemSize, PAGE_4K},
+        mmio::MmioRegion,
+        physical_page_allocator,
+    },
+    process::scheduler::with_current_process,
+    sync::spin::mutex::Mutex,
+};
+
+use super::pci::{PciDeviceConfig, PciDeviceType};
+
+/// The controllers started, for [`ahci_interrupt`]
+static HBAS: Mutex<Vec<Hba>> = Mutex::new(Vec::new());
+/// The GSIs [`ahci_interrupt`] is assigned to, it serves all the controllers
+static INTERRUPTS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+/// The disks registered and their names, in order
+static DISKS: Mutex<Vec<(String, Arc<AhciDisk>)>> = Mutex::new(Vec::new());
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+const PROG_IF_AHCI: u8 = 0x01;
+/// ABAR, the registers of the controller
+const BAR_ABAR: usize = 5;
+const PCI_COMMAND_MEM_SPACE: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INT_DISABLE: u16 = 1 << 10;
+
+mod reg {
+    pub const CAP: usize = 0x00;
+    pub const GHC: usize = 0x04;
+    pub const IS: usize = 0x08;
+    pub const PI: usize = 0x0C;
+    pub const VS: usize = 0x10;
+
+    pub const PORTS: usize = 0x100;
+    pub const PORT_LEN: usize = 0x80;
+
+    // of each port
+    pub const CLB: usize = 0x00;
+    pub const CLBU: usize = 0x04;
+    pub const FB: usize = 0x08;
+    pub const FBU: usize = 0x0C;
+    pub const P_IS: usize = 0x10;
+    pub const P_IE: usize = 0x14;
+    pub const CMD: usize = 0x18;
+    pub const TFD: usize = 0x20;
+    pub const SIG: usize = 0x24;
+    pub const SSTS: usize = 0x28;
+    pub const SCTL: usize = 0x2C;
+    pub const SERR: usize = 0x30;
+    pub const SACT: usize = 0x34;
+    pub const CI: usize = 0x38;
+}
+
+mod cap {
+    /// The number of ports minus one, bits 4:0
+    pub const NP_MASK: u32 = 0x1F;
+    /// The number of command slots minus one, bits 12:8
+    pub const NCS_SHIFT: u32 = 8;
+    pub const NCS_MASK: u32 = 0x1F;
+    pub const SSS: u32 = 1 << 27;
+    pub const SNCQ: u32 = 1 << 30;
+    pub const S64A: u32 = 1 << 31;
+}
+
+mod ghc {
+    pub const HR: u32 = 1 << 0;
+    pub const IE: u32 = 1 << 1;
+    pub const AE: u32 = 1 << 31;
+}
+
+mod port_cmd {
+    pub const ST: u32 = 1 << 0;
+    pub const SUD: u32 = 1 << 1;
+    pub const POD: u32 = 1 << 2;
+    pub const FRE: u32 = 1 << 4;
+    pub const FR: u32 = 1 << 14;
+    pub const CR: u32 = 1 << 15;
+}
+
+mod port_is {
+    pub const DHRS: u32 = 1 << 0;
+    pub const PSS: u32 = 1 << 1;
+    pub const DSS: u32 = 1 << 2;
+    pub const SDBS: u32 = 1 << 3;
+    pub const IFS: u32 = 1 << 27;
+    pub const HBDS: u32 = 1 << 28;
+    pub const HBFS: u32 = 1 << 29;
+    pub const TFES: u32 = 1 << 30;
+    /// The command failed, or the port stopped
+    pub const ERRORS: u32 = IFS | HBDS | HBFS | TFES;
+    pub const ENABLED: u32 = DHRS | PSS | DSS | SDBS | ERRORS;
+}
+
+mod tfd {
+    pub const ERR: u32 = 1 << 0;
+    pub const DRQ: u32 = 1 << 3;
+    pub const BSY: u32 = 1 << 7;
+    pub const ERROR_SHIFT: u32 = 8;
+}
+
+mod ata {
+    pub const COMMAND_IDENTIFY: u8 = 0xEC;
+    pub const COMMAND_READ_DMA_EXT: u8 = 0x25;
+    pub const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+    pub const COMMAND_READ_FPDMA_QUEUED: u8 = 0x60;
+    pub const COMMAND_WRITE_FPDMA_QUEUED: u8 = 0x61;
+    pub const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+    pub const ERROR_UNCORRECTABLE: u8 = 1 << 6;
+    pub const DEVICE_LBA: u8 = 1 << 6;
+
+    pub const SECTOR_SIZE: u32 = 512;
+}
+
+/// A device on the port (SSTS.DET), and the link is up
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_DET_MASK: u32 = 0xF;
+/// SCTL.DET, sends a COMRESET while set
+const SCTL_DET_INIT: u32 = 1;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// The command FIS is 5 dwords
+const COMMAND_FIS_LEN: u32 = 5;
+const HEADER_LEN: usize = 32;
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDTL_SHIFT: u32 = 16;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const TABLE_PRDT_OFFSET: usize = 0x80;
+const PRDT_ENTRY_LEN: usize = 16;
+const MAX_SLOTS: usize = 32;
+
+/// The buffer of each slot, a page per PRDT entry, as big as the requests of the block layer
+const BUFFER_PAGES: usize = MAX_SECTORS_PER_REQUEST as usize * ata::SECTOR_SIZE as usize / PAGE_4K;
+/// The command table of a slot and its PRDT, 128 bytes aligned
+const TABLE_LEN: usize = 512;
+const TABLES_PER_PAGE: usize = PAGE_4K / TABLE_LEN;
+const _: () = assert!(TABLE_PRDT_OFFSET + BUFFER_PAGES * PRDT_ENTRY_LEN <= TABLE_LEN);
+
+const HBA_RESET_TIMEOUT_NANOS: u64 = 1_000_000_000;
+const PORT_STOP_TIMEOUT_NANOS: u64 = 500_000_000;
+const LINK_TIMEOUT_NANOS: u64 = 10_000_000;
+const COMRESET_NANOS: u64 = 1_000_000;
+const PORT_READY_TIMEOUT_NANOS: u64 = 1_000_000_000;
+pub const COMMAND_TIMEOUT_NANOS: u64 = 5_000_000_000;
+/// How many times the registers are polled instead of the timeouts, when there is no clock
+const POLLS_WITHOUT_CLOCK: u64 = 10_000_000;
+
+/// The queue depth compared to one request at a time by `bench`
+pub const BENCH_QUEUE_DEPTH: usize = 8;
+const MAX_BENCH_MIB: u64 = 1024;
+
+#[derive(Debug)]
+enum AhciError {
+    NoRegisters,
+    ResetTimeout,
+    OutOfMemory,
+    /// The memory is above 4GB, and the controller only has 32 bit addresses
+    HighMemory,
+    PortStuck,
+    NotAta(u32),
+    Identify(StorageErrorKind),
+    InvalidIdentify,
+    NoLba48,
+    SectorSize(u32),
+}
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRegisters => write!(f, "no memory BAR"),
+            Self::ResetTimeout => write!(f, "the controller reset timed out"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::HighMemory => write!(f, "memory above 4GB without 64 bit addresses"),
+            Self::PortStuck => write!(f, "the port does not stop"),
+            Self::NotAta(signature) => write!(f, "not an ATA disk (signature {signature:#010x})"),
+            Self::Identify(error) => write!(f, "identify failed: {error}"),
+            Self::InvalidIdentify => write!(f, "invalid identify data"),
+            Self::NoLba48 => write!(f, "no 48-bit LBA"),
+            Self::SectorSize(size) => write!(f, "{size} bytes sectors are not supported"),
+        }
+    }
+}
+
+/// Polls until `done` or after `timeout_nanos`, returns `done`. When there is no clock it
+/// gives up after [`POLLS_WITHOUT_CLOCK`] polls
+fn poll_until(timeout_nanos: u64, mut done: impl FnMut() -> bool) -> bool {
+    let start = clock::uptime_nanos();
+    let mut polls = 0;
+    loop {
+        if done() {
+            return true;
+        }
+        let expired = if start == 0 {
+            polls >= POLLS_WITHOUT_CLOCK
+        } else {
+            clock::uptime_nanos() - start >= timeout_nanos
+        };
+        if expired {
+            return done();
+        }
+        polls += 1;
+        hint::spin_loop();
+    }
+}
+
+/// `error`, the error register of the task file, for the block layer
+fn storage_error(error: u8) -> StorageErrorKind {
+    if error & ata::ERROR_UNCORRECTABLE != 0 {
+        StorageErrorKind::Media(error)
+    } else {
+        StorageErrorKind::Device(error)
+    }
+}
+
+/// The register FIS of an ATA command, `tag` for the NCQ commands, which have the count in
+/// the features
+fn command_fis(command: u8, lba: u64, count: u16, tag: Option<usize>) -> [u8; 20] {
+    let mut fis = [0; 20];
+    fis[0] = FIS_TYPE_REG_H2D;
+    // a command, not the device control register
+    fis[1] = 1 << 7;
+    fis[2] = command;
+    let lba = lba.to_le_bytes();
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    if command != ata::COMMAND_IDENTIFY {
+        fis[7] = ata::DEVICE_LBA;
+    }
+    let count = count.to_le_bytes();
+    match tag {
+        Some(tag) => {
+            fis[3] = count[0];
+            fis[11] = count[1];
+            fis[12] = (tag as u8) << 3;
+        }
+        None => {
+            fis[12] = count[0];
+            fis[13] = count[1];
+        }
+    }
+    fis
+}
+
+/// What a slot is used for
+#[derive(Debug)]
+enum Slot {
+    Free,
+    /// Issued to the port, a flush has no request
+    InFlight {
+        id: u64,
+        request: Option<BlockRequest>,
+        queued: bool,
+        issued_at: u64,
+    },
+    /// Done, until it's waited
+    Done {
+        id: u64,
+        request: Option<BlockRequest>,
+        result: Result<(), StorageErrorKind>,
+    },
+}
+
+/// The memory of a port and the state of its slots, always used locked
+struct Port {
+    regs: MmioRegion,
+    /// The command list, and the received FIS after it
+    command_list: *mut u8,
+    tables: Vec<*mut u8>,
+    buffers: Vec<[*mut u8; BUFFER_PAGES]>,
+    slots: Vec<Slot>,
+    /// The slots in flight, a bit each
+    issued: u32,
+    next_id: u64,
+    max_in_flight: usize,
+    completions: u64,
+}
+
+// SAFETY: the memory of the port is only used through it, inside the lock
+unsafe impl Send for Port {}
+
+impl Port {
+    /// Stops the port (`regs`) and gives it its memory, it's started by [`Self::start`]
+    fn new(regs: MmioRegion, slots: usize, address_64: bool) -> Result<Self, AhciError> {
+        let mut port = Self {
+            regs,
+            command_list: core::ptr::null_mut(),
+            tables: Vec::new(),
+            buffers: Vec::new(),
+            slots: (0..slots).map(|_| Slot::Free).collect(),
+            issued: 0,
+            next_id: 0,
+            max_in_flight: 0,
+            completions: 0,
+        };
+        port.stop()?;
+
+        // the ones allocated are freed by the drop on failure
+        let alloc = || -> Result<*mut u8, AhciError> {
+            // SAFETY: the allocator is initialized before the PCI probe
+            let page =
+                unsafe { physical_page_allocator::try_alloc() }.ok_or(AhciError::OutOfMemory)?;
+            if !address_64 && virtual2physical(page as usize) >> 32 != 0 {
+                // SAFETY: just allocated, not used
+                unsafe { physical_page_allocator::free(page) };
+                return Err(AhciError::HighMemory);
+            }
+            // SAFETY: just allocated
+            unsafe { page.write_bytes(0, PAGE_4K) };
+            Ok(page)
+        };
+        port.command_list = alloc()?;
+        for _ in 0..slots.div_ceil(TABLES_PER_PAGE) {
+            port.tables.push(alloc()?);
+        }
+        for slot in 0..slots {
+            port.buffers.push([core::ptr::null_mut(); BUFFER_PAGES]);
+            for page in port.buffers[slot].iter_mut() {
+                *page = alloc()?;
+            }
+        }
+        let command_list = virtual2physical(port.command_list as usize) as u64;
+
+        for slot in 0..slots {
+            let table = virtual2physical(port.table(slot) as usize) as u64;
+            let header = port.header(slot);
+            // SAFETY: the header of the slot, in the command list
+            unsafe {
+                header.add(2).write_volatile(table as u32);
+                header.add(3).write_volatile((table >> 32) as u32);
+            }
+            for (i, page) in port.buffers[slot].iter().enumerate() {
+                let physical = virtual2physical(*page as usize) as u64;
+                let entry = port.prdt_entry(slot, i);
+                // SAFETY: the PRDT of the slot, in its table
+                unsafe {
+                    entry.write_volatile(physical as u32);
+                    entry.add(1).write_volatile((physical >> 32) as u32);
+                }
+            }
+        }
+        let fis = command_list + RECEIVED_FIS_OFFSET as u64;
+        port.regs.write_u32(reg::CLB, command_list as u32);
+        port.regs.write_u32(reg::CLBU, (command_list >> 32) as u32);
+        port.regs.write_u32(reg::FB, fis as u32);
+        port.regs.write_u32(reg::FBU, (fis >> 32) as u32);
+        Ok(port)
+    }
+
+    fn header(&self, slot: usize) -> *mut u32 {
+        // SAFETY: the command list has 32 headers
+        unsafe { self.command_list.add(slot * HEADER_LEN) as *mut u32 }
+    }
+
+    fn table(&self, slot: usize) -> *mut u8 {
+        // SAFETY: the pages have `TABLES_PER_PAGE` tables each
+        unsafe { self.tables[slot / TABLES_PER_PAGE].add(slot % TABLES_PER_PAGE * TABLE_LEN) }
+    }
+
+    fn prdt_entry(&self, slot: usize, entry: usize) -> *mut u32 {
+        // SAFETY: the PRDT fits in the table
+        unsafe {
+            self.table(slot)
+                .add(TABLE_PRDT_OFFSET + entry * PRDT_ENTRY_LEN) as *mut u32
+        }
+    }
+
+    /// Stops running the command list and receiving FISes
+    fn stop(&self) -> Result<(), AhciError> {
+        let cmd = self.regs.read_u32(reg::CMD);
+        self.regs.write_u32(reg::CMD, cmd & !port_cmd::ST);
+        let stopped = poll_until(PORT_STOP_TIMEOUT_NANOS, || {
+            self.regs.read_u32(reg::CMD) & port_cmd::CR == 0
+        });
+        let cmd = self.regs.read_u32(reg::CMD);
+        self.regs.write_u32(reg::CMD, cmd & !port_cmd::FRE);
+        let stopped = stopped
+            && poll_until(PORT_STOP_TIMEOUT_NANOS, || {
+                self.regs.read_u32(reg::CMD) & port_cmd::FR == 0
+            });
+        stopped.then_some(()).ok_or(AhciError::PortStuck)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.regs.read_u32(reg::TFD) & (tfd::BSY | tfd::DRQ) == 0
+    }
+
+    /// Resets the link of the stopped port, for a device that stays busy
+    fn comreset(&self) {
+        let sctl = self.regs.read_u32(reg::SCTL) & !SSTS_DET_MASK;
+        self.regs.write_u32(reg::SCTL, sctl | SCTL_DET_INIT);
+        poll_until(COMRESET_NANOS, || false);
+        self.regs.write_u32(reg::SCTL, sctl);
+        poll_until(LINK_TIMEOUT_NANOS, || {
+            self.regs.read_u32(reg::SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT
+        });
+        self.regs.write_u32(reg::SERR, u32::MAX);
+    }
+
+    /// Starts receiving FISes and running the command list, once the device is ready
+    fn start(&self) -> Result<(), AhciError> {
+        let cmd = self.regs.read_u32(reg::CMD);
+        self.regs.write_u32(reg::CMD, cmd | port_cmd::FRE);
+        self.regs.write_u32(reg::SERR, u32::MAX);
+        self.regs.write_u32(reg::P_IS, u32::MAX);
+        if !poll_until(PORT_READY_TIMEOUT_NANOS, || self.is_ready()) {
+            self.comreset();
+            if !poll_until(PORT_READY_TIMEOUT_NANOS, || self.is_ready()) {
+                return Err(AhciError::PortStuck);
+            }
+        }
+        let cmd = self.regs.read_u32(reg::CMD);
+        self.regs.write_u32(reg::CMD, cmd | port_cmd::ST);
+        Ok(())
+    }
+
+    /// Stops and starts the port after an error, everything in flight is lost
+    fn restart(&mut self) {
+        let _ = self.stop();
+        if !self.is_ready() {
+            self.comreset();
+        }
+        if let Err(error) = self.start() {
+            eprintln!("ahci: could not restart a port: {error}");
+        }
+        self.issued = 0;
+    }
+
+    fn free_slot(&self, queue_depth: usize) -> Option<usize> {
+        self.slots[..queue_depth]
+            .iter()
+            .position(|slot| matches!(slot, Slot::Free))
+    }
+
+    fn in_flight(&self) -> usize {
+        self.issued.count_ones() as usize
+    }
+
+    /// NCQ commands can't be in flight with the others
+    fn can_issue(&self, queued: bool) -> bool {
+        self.slots.iter().all(|slot| match slot {
+            Slot::InFlight { queued: other, .. } => queued && *other,
+            _ => true,
+        })
+    }
+
+    /// Writes the command of `slot`, `len` bytes of its buffer are transferred
+    fn prepare(&self, slot: usize, fis: &[u8; 20], len: usize, write: bool) {
+        let table = self.table(slot);
+        // SAFETY: the command FIS is at the start of the table
+        unsafe { core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len()) };
+        let entries = len.div_ceil(PAGE_4K);
+        for entry in 0..entries {
+            let bytes = (len - entry * PAGE_4K).min(PAGE_4K);
+            // SAFETY: the PRDT of the slot, the byte count is 0 based
+            unsafe {
+                self.prdt_entry(slot, entry)
+                    .add(3)
+                    .write_volatile(bytes as u32 - 1)
+            };
+        }
+        let flags = COMMAND_FIS_LEN
+            | if write { HEADER_WRITE } else { 0 }
+            | (entries as u32) << HEADER_PRDTL_SHIFT;
+        // SAFETY: the header of the slot
+        unsafe {
+            self.header(slot).write_volatile(flags);
+            self.header(slot).add(1).write_volatile(0);
+        }
+    }
+
+    /// Starts `slot` prepared with [`Self::prepare`]
+    fn issue(&mut self, slot: usize, queued: bool) {
+        // the command is seen by the controller before it is told
+        atomic::fence(Ordering::SeqCst);
+        if queued {
+            self.regs.write_u32(reg::SACT, 1 << slot);
+        }
+        self.regs.write_u32(reg::CI, 1 << slot);
+        self.issued |= 1 << slot;
+        self.max_in_flight = self.max_in_flight.max(self.in_flight());
+    }
+
+    fn copy_to_buffer(&self, slot: usize, data: &[u8]) {
+        for (chunk, page) in data.chunks(PAGE_4K).zip(self.buffers[slot]) {
+            // SAFETY: the buffer is not used by the controller before the command is issued
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), page, chunk.len()) };
+        }
+    }
+
+    fn copy_from_buffer(&self, slot: usize, data: &mut [u8]) {
+        // what the device wrote is read after it reported the completion
+        atomic::fence(Ordering::SeqCst);
+        for (chunk, page) in data.chunks_mut(PAGE_4K).zip(self.buffers[slot]) {
+            // SAFETY: the command is done
+            unsafe { core::ptr::copy_nonoverlapping(page, chunk.as_mut_ptr(), chunk.len()) };
+        }
+    }
+
+    /// Prepares and issues `request` in `slot`, `None` for a flush, the handle is its id
+    fn submit(&mut self, slot: usize, request: Option<BlockRequest>, queued: bool) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.issue_request(slot, id, request, queued);
+        id
+    }
+
+    fn issue_request(&mut self, slot: usize, id: u64, request: Option<BlockRequest>, queued: bool) {
+        let (fis, len, write) = match &request {
+            Some(request) => {
+                let write = request.kind == BlockRequestKind::Write;
+                let count = (request.data.len() / ata::SECTOR_SIZE as usize) as u16;
+                let command = match (queued, write) {
+                    (true, false) => ata::COMMAND_READ_FPDMA_QUEUED,
+                    (true, true) => ata::COMMAND_WRITE_FPDMA_QUEUED,
+                    (false, false) => ata::COMMAND_READ_DMA_EXT,
+                    (false, true) => ata::COMMAND_WRITE_DMA_EXT,
+                };
+                if write {
+                    self.copy_to_buffer(slot, &request.data);
+                }
+                let tag = queued.then_some(slot);
+                let fis = command_fis(command, request.start_sector.0, count, tag);
+                (fis, request.data.len(), write)
+            }
+            None => (
+                command_fis(ata::COMMAND_FLUSH_CACHE_EXT, 0, 0, None),
+                0,
+                false,
+            ),
+        };
+        self.prepare(slot, &fis, len, write);
+        self.slots[slot] = Slot::InFlight {
+            id,
+            request,
+            queued,
+            issued_at: clock::uptime_nanos(),
+        };
+        self.issue(slot, queued);
+    }
+
+    /// Marks `slot` done with `result`, and copies the data read
+    fn complete(&mut self, slot: usize, result: Result<(), StorageErrorKind>) {
+        if !matches!(self.slots[slot], Slot::InFlight { .. }) {
+            return;
+        }
+        let Slot::InFlight {
+            id, mut request, ..
+        } = mem::replace(&mut self.slots[slot], Slot::Free)
+        else {
+            unreachable!()
+        };
+        if let Some(request) = request.as_mut() {
+            if result.is_ok() && request.kind == BlockRequestKind::Read {
+                self.copy_from_buffer(slot, &mut request.data);
+            }
+            request.complete();
+        }
+        self.issued &= !(1 << slot);
+        self.completions += 1;
+        self.slots[slot] = Slot::Done {
+            id,
+            request,
+            result,
+        };
+    }
+
+    /// Takes the completions and the errors of the port, called by the interrupt and the
+    /// waiters
+    fn reap(&mut self) {
+        let status = self.regs.read_u32(reg::P_IS);
+        self.regs.write_u32(reg::P_IS, status);
+        if status & port_is::ERRORS != 0 {
+            self.recover();
+            return;
+        }
+        let active = self.regs.read_u32(reg::SACT) | self.regs.read_u32(reg::CI);
+        let done = self.issued & !active;
+        for slot in 0..self.slots.len() {
+            if done & (1 << slot) != 0 {
+                self.complete(slot, Ok(()));
+            }
+        }
+    }
+
+    /// A command failed, the port stopped and the others were aborted. The active bits
+    /// don't tell which one failed (the failed one may be cleared), so all the commands that
+    /// were not completed are run again alone, each gets its own result. Running again one
+    /// that was done reads or writes the same sectors
+    fn recover(&mut self) {
+        let issued = self.issued;
+        self.restart();
+        for slot in (0..self.slots.len()).filter(|slot| issued & (1 << slot) != 0) {
+            let result = self.rerun(slot);
+            self.complete(slot, result);
+        }
+    }
+
+    /// Runs the command of `slot` again without NCQ, and waits for it
+    fn rerun(&mut self, slot: usize) -> Result<(), StorageErrorKind> {
+        let Slot::InFlight { id, request, .. } = mem::replace(&mut self.slots[slot], Slot::Free)
+        else {
+            return Ok(());
+        };
+        self.issue_request(slot, id, request, false);
+        self.run_polled(slot)
+    }
+
+    /// Waits for the command just issued in `slot`, with the lock held, only for the errors
+    /// and the start of the port
+    fn run_polled(&mut self, slot: usize) -> Result<(), StorageErrorKind> {
+        let mut status = 0;
+        let done = poll_until(COMMAND_TIMEOUT_NANOS, || {
+            status |= self.regs.read_u32(reg::P_IS);
+            status & port_is::ERRORS != 0 || self.regs.read_u32(reg::CI) & (1 << slot) == 0
+        });
+        self.regs.write_u32(reg::P_IS, status);
+        let result = if !done {
+            Err(StorageErrorKind::Timeout)
+        } else if status & port_is::ERRORS != 0 || self.regs.read_u32(reg::TFD) & tfd::ERR != 0 {
+            Err(storage_error(
+                (self.regs.read_u32(reg::TFD) >> tfd::ERROR_SHIFT) as u8,
+            ))
+        } else {
+            Ok(())
+        };
+        if result.is_err() {
+            self.restart();
+        }
+        self.issued &= !(1 << slot);
+        result
+    }
+
+    /// The requests in flight for longer than [`COMMAND_TIMEOUT_NANOS`] fail, with all the
+    /// others, the port is restarted
+    fn check_timeout(&mut self) {
+        let now = clock::uptime_nanos();
+        let expired = self.slots.iter().any(|slot| {
+            matches!(slot, Slot::InFlight { issued_at, .. }
+                if now != 0 && now - issued_at >= COMMAND_TIMEOUT_NANOS)
+        });
+        if expired {
+            self.restart();
+            self.fail_all(StorageErrorKind::Timeout);
+        }
+    }
+
+    fn fail_all(&mut self, error: StorageErrorKind) {
+        for slot in 0..self.slots.len() {
+            self.complete(slot, Err(error));
+        }
+        self.issued = 0;
+    }
+
+    /// Takes the done request with `id`, `None` if it's still in flight
+    fn take_done(
+        &mut self,
+        id: u64,
+    ) -> Option<(Option<BlockRequest>, Result<(), StorageErrorKind>)> {
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| match slot {
+                Slot::InFlight { id: other, .. } | Slot::Done { id: other, .. } => *other == id,
+                Slot::Free => false,
+            })
+            .unwrap_or_else(|| panic!("AHCI request {id} is not in flight"));
+        match mem::replace(&mut self.slots[slot], Slot::Free) {
+            Slot::Done {
+                request, result, ..
+            } => Some((request, result)),
+            other => {
+                self.slots[slot] = other;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        let pages = self
+            .tables
+            .iter()
+            .chain(self.buffers.iter().flatten())
+            .chain([&self.command_list]);
+        for page in pages.filter(|page| !page.is_null()) {
+            // SAFETY: the port is stopped, nothing uses its memory
+            unsafe { physical_page_allocator::free(*page) };
+        }
+    }
+}
+
+/// The results of the last `scatter` and `bench` of a disk
+#[derive(Debug, Default)]
+struct TestResults {
+    scatter: Option<Result<usize, String>>,
+    bench: Option<BenchResult>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BenchResult {
+    bytes: u64,
+    one_nanos: u64,
+    queued_nanos: u64,
+    queue_depth: usize,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = |nanos: u64| self.bytes * 1_000_000_000 / 1024 / nanos.max(1);
+        write!(
+            f,
+            "{} sequential, qd1 {} KiB/s, qd{} {} KiB/s, {}.{:02}x",
+            MemSize(self.bytes),
+            rate(self.one_nanos),
+            self.queue_depth,
+            rate(self.queued_nanos),
+            self.one_nanos / self.queued_nanos.max(1),
+            self.one_nanos * 100 / self.queued_nanos.max(1) % 100
+        )
+    }
+}
+
+/// An ATA disk on a port of a controller
+pub struct AhciDisk {
+    /// `<bus>.<dev>.<func> port <n>`
+    location: String,
+    identify: AtaIdentify,
+    number_of_sectors: u64,
+    ncq: bool,
+    queue_depth: usize,
+    port: Mutex<Port>,
+    interrupts: AtomicU64,
+    // removed, see `BlockDevice::shutdown`
+    dead: AtomicBool,
+    results: Mutex<TestResults>,
+}
+
+impl fmt::Debug for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AhciDisk")
+            .field("location", &self.location)
+            .field("number_of_sectors", &self.number_of_sectors)
+            .field("queue_depth", &self.queue_depth)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AhciDisk {
+    /// Starts the ATA disk on `regs`, the registers of its port
+    fn new(location: String, regs: MmioRegion, hba_cap: u32) -> Result<Self, AhciError> {
+        let slots = ((hba_cap >> cap::NCS_SHIFT) & cap::NCS_MASK) as usize + 1;
+        let mut port = Port::new(regs, slots, hba_cap & cap::S64A != 0)?;
+        let cmd = port.regs.read_u32(reg::CMD);
+        port.regs
+            .write_u32(reg::CMD, cmd | port_cmd::SUD | port_cmd::POD);
+        let linked = poll_until(LINK_TIMEOUT_NANOS, || {
+            port.regs.read_u32(reg::SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT
+        });
+        if !linked {
+            return Err(AhciError::NotAta(0));
+        }
+        port.start()?;
+        let signature = port.regs.read_u32(reg::SIG);
+        if signature != SIGNATURE_ATA {
+            return Err(AhciError::NotAta(signature));
+        }
+
+        let fis = command_fis(ata::COMMAND_IDENTIFY, 0, 0, None);
+        port.prepare(0, &fis, ata::SECTOR_SIZE as usize, false);
+        port.issue(0, false);
+        port.run_polled(0).map_err(AhciError::Identify)?;
+        let mut data = [0; 512];
+        port.copy_from_buffer(0, &mut data);
+        port.max_in_flight = 0;
+        let identify = AtaIdentify::parse(data).ok_or(AhciError::InvalidIdentify)?;
+        if !identify.lba48_supported {
+            return Err(AhciError::NoLba48);
+        }
+        if identify.logical_sector_size != ata::SECTOR_SIZE {
+            return Err(AhciError::SectorSize(identify.logical_sector_size));
+        }
+
+        let ncq = identify.ncq_supported && hba_cap & cap::SNCQ != 0;
+        let queue_depth = if ncq {
+            slots.min(identify.queue_depth as usize)
+        } else {
+            1
+        };
+        port.regs.write_u32(reg::P_IS, u32::MAX);
+        port.regs.write_u32(reg::P_IE, port_is::ENABLED);
+        Ok(Self {
+            location,
+            number_of_sectors: identify.number_of_sectors(),
+            identify,
+            ncq,
+            queue_depth,
+            port: Mutex::new(port),
+            interrupts: AtomicU64::new(0),
+            dead: AtomicBool::new(false),
+            results: Mutex::new(TestResults::default()),
+        })
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Acquire)
+    }
+
+    /// Called by [`ahci_interrupt`] when the port has something, after the device is removed
+    /// it does nothing, its interrupts are disabled
+    fn interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        if self.is_dead() {
+            return;
+        }
+        self.port.lock().reap();
+    }
+
+    fn check(&self, request: &BlockRequest) -> Result<(), StorageErrorKind> {
+        if self.is_dead() {
+            return Err(StorageErrorKind::DeviceGone);
+        }
+        let len = request.data.len();
+        if len == 0 || !len.is_multiple_of(ata::SECTOR_SIZE as usize) {
+            return Err(StorageErrorKind::Unaligned);
+        }
+        if len > BUFFER_PAGES * PAGE_4K {
+            return Err(StorageErrorKind::NotSupported);
+        }
+        let sectors = (len / ata::SECTOR_SIZE as usize) as u64;
+        if request.start_sector + sectors > Lba(self.number_of_sectors) {
+            return Err(StorageErrorKind::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Issues `request` (`None` for a flush) in a free slot once there is one, waiting for
+    /// the ones in flight without the lock
+    fn issue(&self, request: Option<BlockRequest>) -> Result<u64, Option<BlockRequest>> {
+        let queued = self.ncq && request.is_some();
+        loop {
+            let mut port = self.port.lock();
+            if self.is_dead() {
+                return Err(request);
+            }
+            port.reap();
+            port.check_timeout();
+            if port.can_issue(queued) {
+                if let Some(slot) = port.free_slot(self.queue_depth) {
+                    return Ok(port.submit(slot, request, queued));
+                }
+            }
+            drop(port);
+            hint::spin_loop();
+        }
+    }
+
+    fn wait_id(&self, id: u64) -> (Option<BlockRequest>, Result<(), StorageErrorKind>) {
+        loop {
+            let mut port = self.port.lock();
+            if let Some(done) = port.take_done(id) {
+                return done;
+            }
+            port.reap();
+            port.check_timeout();
+            if let Some(done) = port.take_done(id) {
+                return done;
+            }
+            drop(port);
+            hint::spin_loop();
+        }
+    }
+
+    /// `data` in requests of the block layer size, each waited before the next
+    fn transfer(
+        &self,
+        kind: BlockRequestKind,
+        start_sector: Lba,
+        len: usize,
+        mut each: impl FnMut(usize, &mut Vec<u8>),
+    ) -> Result<(), StorageErrorKind> {
+        let request_len = BUFFER_PAGES * PAGE_4K;
+        for offset in (0..len).step_by(request_len) {
+            let mut data = vec![0; (len - offset).min(request_len)];
+            if kind == BlockRequestKind::Write {
+                each(offset, &mut data);
+            }
+            let sector = start_sector + (offset / ata::SECTOR_SIZE as usize) as u64;
+            let (mut request, result) =
+                self.wait(self.submit(BlockRequest::new(kind, sector, data)));
+            result?;
+            if kind == BlockRequestKind::Read {
+                each(offset, &mut request.data);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_size(&self) -> u32 {
+        ata::SECTOR_SIZE
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        self.number_of_sectors
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        self.transfer(
+            BlockRequestKind::Read,
+            start_sector,
+            data.len(),
+            |offset, read| data[offset..][..read.len()].copy_from_slice(read),
+        )
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        self.transfer(
+            BlockRequestKind::Write,
+            start_sector,
+            data.len(),
+            |offset, write| {
+                let len = write.len();
+                write.copy_from_slice(&data[offset..][..len])
+            },
+        )
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    /// Fills a free slot with `request` and issues it, the handle is the id of the slot.
+    /// Blocks while all the slots are in flight, or not waited yet
+    fn submit(&self, mut request: BlockRequest) -> RequestHandle {
+        if let Err(error) = self.check(&request) {
+            request.complete();
+            return RequestHandle::Completed(request, Err(error));
+        }
+        match self.issue(Some(request)) {
+            Ok(id) => RequestHandle::Pending(id),
+            Err(request) => {
+                let mut request = request.expect("a request was issued");
+                request.complete();
+                RequestHandle::Completed(request, Err(StorageErrorKind::DeviceGone))
+            }
+        }
+    }
+
+    fn wait(&self, handle: RequestHandle) -> (BlockRequest, Result<(), StorageErrorKind>) {
+        match handle {
+            RequestHandle::Completed(request, result) => (request, result),
+            RequestHandle::Pending(id) => {
+                let (request, result) = self.wait_id(id);
+                (request.expect("a flush waited as a request"), result)
+            }
+        }
+    }
+
+    fn flush(&self) -> Result<(), StorageErrorKind> {
+        if !self.identify.write_cache_enabled {
+            return Ok(());
+        }
+        match self.issue(None) {
+            Ok(id) => self.wait_id(id).1,
+            Err(_) => Err(StorageErrorKind::DeviceGone),
+        }
+    }
+
+    /// The requests in flight complete with `DeviceGone`, the interrupts of the port are
+    /// disabled, and `ahci<n>_info` is removed. The memory of the port stays, the controller
+    /// may still write to it
+    fn shutdown(&self) {
+        self.dead.store(true, Ordering::Release);
+        let mut port = self.port.lock();
+        port.regs.write_u32(reg::P_IE, 0);
+        for slot in 0..port.slots.len() {
+            if matches!(port.slots[slot], Slot::InFlight { .. }) {
+                port.complete(slot, Err(StorageErrorKind::DeviceGone));
+            }
+        }
+        drop(port);
+        let mut disks = DISKS.lock();
+        let index = disks
+            .iter()
+            .position(|(_, disk)| core::ptr::eq(Arc::as_ptr(disk), self));
+        if let Some(index) = index {
+            let (name, _) = disks.remove(index);
+            drop(disks);
+            let _ = devices::unregister_device(&format!("{name}_info"));
+        }
+    }
+}
+
+/// The sectors of the scattered requests of [`scattered_reads`], `(sector, count)` in parts
+/// of the disk, so they are far apart
+const SCATTERED: [(u64, u64); 8] = [
+    (7, 3),
+    (1, 1),
+    (4, 2),
+    (0, 1),
+    (2, 128),
+    (6, 1),
+    (3, 5),
+    (5, 8),
+];
+const SCATTERED_PARTS: u64 = 8;
+
+/// Every sector read by [`scattered_reads`] has different content
+fn scattered_pattern(start_sector: u64, sectors: u64) -> Vec<u8> {
+    (start_sector..start_sector + sectors)
+        .flat_map(|sector| {
+            (0..ata::SECTOR_SIZE as u64 / 8).flat_map(move |word| {
+                let value = 0xA5C1 << 48 | sector << 16 | word;
+                value.to_le_bytes()
+            })
+        })
+        .collect()
+}
+
+/// Writes a pattern to scattered sectors of `disk`, then reads all of them at once and waits
+/// them in another order, each must get its own sectors. The sectors are put back after.
+/// Returns the number of requests that were in flight together
+fn scattered_reads(disk: &AhciDisk) -> Result<usize, String> {
+    let part = disk.number_of_sectors / SCATTERED_PARTS;
+    if part < MAX_SECTORS_PER_REQUEST {
+        return Err(format!(
+            "the disk is too small ({} sectors)",
+            disk.number_of_sectors
+        ));
+    }
+    let requests = SCATTERED
+        .iter()
+        .map(|&(part_index, count)| (part_index * part + part / 2 - count, count))
+        .collect::<Vec<_>>();
+    let sector_len = ata::SECTOR_SIZE as usize;
+    let mut saved = Vec::new();
+    for &(sector, count) in &requests {
+        let mut data = vec![0; count as usize * sector_len];
+        disk.read_sectors(Lba(sector), &mut data)
+            .map_err(|e| format!("saving sector {sector}: {e}"))?;
+        saved.push(data);
+    }
+    let result = (|| {
+        for &(sector, count) in &requests {
+            disk.write_sectors(Lba(sector), &scattered_pattern(sector, count))
+                .map_err(|e| format!("writing sector {sector}: {e}"))?;
+        }
+        disk.port.lock().max_in_flight = 0;
+        let mut handles = requests
+            .iter()
+            .map(|&(sector, count)| {
+                let data = vec![0; count as usize * sector_len];
+                Some(disk.submit(BlockRequest::new(BlockRequestKind::Read, Lba(sector), data)))
+            })
+            .collect::<Vec<_>>();
+        let order = (1..requests.len())
+            .step_by(2)
+            .rev()
+            .chain((0..requests.len()).step_by(2));
+        let mut mismatch = None;
+        for i in order {
+            let (request, result) = disk.wait(handles[i].take().expect("waited once"));
+            let (sector, count) = requests[i];
+            result.map_err(|e| format!("reading sector {sector}: {e}"))?;
+            if request.start_sector != Lba(sector)
+                || request.data != scattered_pattern(sector, count)
+            {
+                mismatch.get_or_insert(format!("{count} sectors at {sector} got other data"));
+            }
+        }
+        match mismatch {
+            Some(mismatch) => Err(mismatch),
+            None => Ok(disk.port.lock().max_in_flight),
+        }
+    })();
+    for (&(sector, _), data) in requests.iter().zip(&saved) {
+        disk.write_sectors(Lba(sector), data)
+            .map_err(|e| format!("restoring sector {sector}: {e}"))?;
+    }
+    result
+}
+
+/// Reads the first `bytes` of `disk` with up to `queue_depth` requests in flight, returns
+/// the time it took
+fn sequential_read(disk: &AhciDisk, bytes: u64, queue_depth: usize) -> Result<u64, String> {
+    let request_len = BUFFER_PAGES * PAGE_4K;
+    let requests = bytes.div_ceil(request_len as u64);
+    let start = clock::uptime_nanos();
+    let mut in_flight = alloc::collections::VecDeque::new();
+    let mut spare = Vec::new();
+    for i in 0..requests {
+        if in_flight.len() == queue_depth {
+            let (request, result) = disk.wait(in_flight.pop_front().expect("in flight"));
+            result.map_err(|e| format!("read at {}: {e}", request.start_sector))?;
+            spare.push(request.data);
+        }
+        let data = spare.pop().unwrap_or_else(|| vec![0; request_len]);
+        let sector = Lba(i * (request_len / ata::SECTOR_SIZE as usize) as u64);
+        in_flight.push_back(disk.submit(BlockRequest::new(BlockRequestKind::Read, sector, data)));
+    }
+    for handle in in_flight {
+        let (request, result) = disk.wait(handle);
+        result.map_err(|e| format!("read at {}: {e}", request.start_sector))?;
+    }
+    Ok(clock::uptime_nanos() - start)
+}
+
+/// Compares the sequential reads of `mib` MiB one request at a time, and
+/// [`BENCH_QUEUE_DEPTH`] at a time
+fn bench(disk: &AhciDisk, mib: u64) -> Result<BenchResult, String> {
+    if clock::uptime_nanos() == 0 {
+        return Err("no clock".into());
+    }
+    let request_len = (BUFFER_PAGES * PAGE_4K) as u64;
+    let disk_len = disk.number_of_sectors * ata::SECTOR_SIZE as u64;
+    let bytes = (mib << 20).min(disk_len / request_len * request_len);
+    let queue_depth = BENCH_QUEUE_DEPTH.min(disk.queue_depth);
+    Ok(BenchResult {
+        bytes,
+        one_nanos: sequential_read(disk, bytes, 1)?,
+        queued_nanos: sequential_read(disk, bytes, queue_depth)?,
+        queue_depth,
+    })
+}
+
+/// `/devices/ahci<n>_info`, the identify data and the queue of the disk, and the results of
+/// `scatter` and `bench` written to it
+#[derive(Debug)]
+struct AhciInfo {
+    name: String,
+    disk: WeakDevice<AhciDisk>,
+}
+
+impl Device for AhciInfo {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(AhciInfoGenerator(self.disk.clone())))
+    }
+
+    /// `scatter` or `bench <MiB>`, they go to the driver, and don't see the block cache
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if !with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let disk = self.disk.upgrade().ok_or(FileSystemError::DeviceGone)?;
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        if command == "scatter" {
+            let result = scattered_reads(&disk);
+            disk.results.lock().scatter = Some(result);
+            return Ok(buf.len() as u64);
+        }
+        let mib = command
+            .strip_prefix("bench ")
+            .and_then(|mib| mib.trim().parse::<u64>().ok())
+            .filter(|mib| (1..=MAX_BENCH_MIB).contains(mib))
+            .ok_or(FileSystemError::InvalidData)?;
+        match bench(&disk, mib) {
+            Ok(result) => {
+                println!("{}: bench: {result}", self.name);
+                disk.results.lock().bench = Some(result);
+                Ok(buf.len() as u64)
+            }
+            Err(error) => {
+                eprintln!("{}: bench failed: {error}", self.name);
+                Err(FileSystemError::InvalidData)
+            }
+        }
+    }
+}
+
+/// Renders the info again for every chunk, the field of the cursor is the line
+#[derive(Debug)]
+struct AhciInfoGenerator(WeakDevice<AhciDisk>);
+
+impl Generator for AhciInfoGenerator {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let Some(disk) = self.0.upgrade() else {
+            return 0;
+        };
+        let (in_flight, max_in_flight, completions) = {
+            let port = disk.port.lock();
+            (port.in_flight(), port.max_in_flight, port.completions)
+        };
+        let results = disk.results.lock();
+        let scatter = match &results.scatter {
+            None => String::from("not run"),
+            Some(Ok(in_flight)) => format!("ok, {in_flight} in flight"),
+            Some(Err(error)) => format!("failed: {error}"),
+        };
+        let bench = match &results.bench {
+            None => String::from("not run"),
+            Some(result) => format!("{result}"),
+        };
+        generated::render_display_lines(
+            &format_args!(
+                "port: {}\nsize: {} ({} x {})\nncq: {}, queue depth {}\n\
+                 in flight: {in_flight}, max {max_in_flight}\ncompletions: {completions}\n\
+                 interrupts: {}\nscatter: {scatter}\nbench: {bench}\n{}",
+                disk.location,
+                MemSize(disk.number_of_sectors * ata::SECTOR_SIZE as u64),
+                disk.number_of_sectors,
+                ata::SECTOR_SIZE,
+                if disk.ncq { "yes" } else { "no" },
+                disk.queue_depth,
+                disk.interrupts.load(Ordering::Relaxed),
+                disk.identify,
+            ),
+            cursor,
+            out,
+        )
+    }
+}
+
+/// A controller, its global registers and its disks by port
+struct Hba {
+    regs: MmioRegion,
+    disks: Vec<(usize, Arc<AhciDisk>)>,
+}
+
+impl Hba {
+    /// Gives the interrupts of the ports to their disks, the status of the controller is
+    /// cleared after the ports
+    fn interrupt(&self) {
+        let status = self.regs.read_u32(reg::IS);
+        if status == 0 {
+            return;
+        }
+        for (port, disk) in &self.disks {
+            if status & (1 << port) != 0 {
+                disk.interrupt();
+            }
+        }
+        self.regs.write_u32(reg::IS, status);
+    }
+}
+
+extern "x86-interrupt" fn ahci_interrupt(_stack_frame: InterruptStackFrame64) {
+    // level triggered, the ports are cleared before the EOI or it comes again right away
+    irq_off::interrupt_enter("ahci");
+    for hba in HBAS.lock().iter() {
+        hba.interrupt();
+    }
+    irq_off::interrupt_exit();
+    apic::return_from_interrupt();
+}
+
+/// Resets the controller, and takes it from the BIOS
+fn reset(regs: &MmioRegion) -> Result<(), AhciError> {
+    regs.write_u32(reg::GHC, ghc::AE);
+    regs.write_u32(reg::GHC, ghc::AE | ghc::HR);
+    if !poll_until(HBA_RESET_TIMEOUT_NANOS, || {
+        regs.read_u32(reg::GHC) & ghc::HR == 0
+    }) {
+        return Err(AhciError::ResetTimeout);
+    }
+    regs.write_u32(reg::GHC, ghc::AE);
+    Ok(())
+}
+
+/// Starts an AHCI controller (class `01:06:01`), and registers the ATA disks on its ports
+/// as `/devices/ahci<n>`
+pub fn try_register(config: &PciDeviceConfig) -> bool {
+    if !matches!(
+        config.device_type,
+        PciDeviceType::MassStorageController(0x6, PROG_IF_AHCI, ..)
+    ) {
+        return false;
+    }
+    let name = format!("{:02X}.{:02X}.{:02X}", config.bus, config.dev, config.func);
+    let Some((abar, size, _)) = config.base_address[BAR_ABAR].get_memory() else {
+        eprintln!("ahci {name}: failed to start: {}", AhciError::NoRegisters);
+        return false;
+    };
+    let command = config.read_command() | PCI_COMMAND_MEM_SPACE | PCI_COMMAND_BUS_MASTER;
+    config.write_command(command & !PCI_COMMAND_INT_DISABLE);
+    let regs = MmioRegion::map(abar, (size as usize).min(reg::PORTS));
+    if let Err(error) = reset(&regs) {
+        eprintln!("ahci {name}: failed to start: {error}");
+        return false;
+    }
+    let hba_cap = regs.read_u32(reg::CAP);
+    let implemented = regs.read_u32(reg::PI);
+    let version = regs.read_u32(reg::VS);
+    println!(
+        "ahci {name}: version {}.{}, {} ports, {} slots, ncq {}, staggered spin-up {}",
+        version >> 16,
+        (version >> 8) & 0xFF,
+        (hba_cap & cap::NP_MASK) + 1,
+        ((hba_cap >> cap::NCS_SHIFT) & cap::NCS_MASK) + 1,
+        hba_cap & cap::SNCQ != 0,
+        hba_cap & cap::SSS != 0,
+    );
+
+    let mut disks = Vec::new();
+    for port in (0..MAX_SLOTS).filter(|port| implemented & (1 << port) != 0) {
+        let offset = reg::PORTS + port * reg::PORT_LEN;
+        if offset + reg::PORT_LEN > size as usize {
+            break;
+        }
+        let port_regs = MmioRegion::map(abar + offset as u64, reg::PORT_LEN);
+        let location = format!("{name} port {port}");
+        match AhciDisk::new(location, port_regs, hba_cap) {
+            Ok(disk) => disks.push((port, Arc::new(disk))),
+            Err(AhciError::NotAta(0)) => {}
+            Err(error) => {
+                println!("ahci {name} port {port}: not used: {error}");
+            }
+        }
+    }
+
+    for (_, disk) in &disks {
+        let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        let block_file = BlockDeviceFile::register(format!("ahci{index}"), disk.clone(), true);
+        println!(
+            "{}: {}, {}, ncq {}, queue depth {}",
+            block_file.name(),
+            disk.location,
+            disk.identify.model,
+            disk.ncq,
+            disk.queue_depth
+        );
+        let info = WeakDevice::new(block_file.name(), disk).expect("AHCI disk not registered");
+        devices::register_device(Arc::new(AhciInfo {
+            name: format!("ahci{index}_info"),
+            disk: info,
+        }));
+        DISKS.lock().push((block_file.name().into(), disk.clone()));
+    }
+    HBAS.lock().push(Hba { regs, disks });
+
+    match config.route_intx() {
+        Some(route) => {
+            let mut interrupts = INTERRUPTS.lock();
+            if !interrupts.contains(&route.gsi) {
+                if apic::is_gsi_free(route.gsi) {
+                    route.assign(ahci_interrupt as BasicInterruptHandler, cpu::cpu());
+                    interrupts.push(route.gsi);
+                } else {
+                    println!("WARNING: AHCI interrupt {route} is shared, not supported yet");
+                }
+            }
+        }
+        None => {
+            println!("WARNING: AHCI has no interrupt, the requests are polled");
+        }
+    }
+    let hbas = HBAS.lock();
+    let hba = hbas.last().expect("just added");
+    let ghc = hba.regs.read_u32(reg::GHC);
+    hba.regs.write_u32(reg::GHC, ghc | ghc::IE);
+    true
+}
+
+/// The `/devices` name of the first AHCI disk, `None` if there is none
+pub fn first_disk_name() -> Option<String> {
+    DISKS.lock().first().map(|(name, _)| name.clone())
+}
+
+/// For every AHCI disk: scattered requests in flight together get their own data (the
+/// sectors are put back), the interrupts come if one is routed, and the requests outside
+/// the disk fail at submit. Nothing is done without AHCI disks
+pub fn run_self_tests() {
+    let disks = DISKS.lock().clone();
+    if disks.is_empty() {
+        println!("[ahci] no AHCI disk, skipping the self tests");
+        return;
+    }
+    println!("Running AHCI self tests...");
+    let routed = !INTERRUPTS.lock().is_empty();
+    for (name, disk) in disks {
+        let interrupts = disk.interrupts.load(Ordering::Relaxed);
+        let result = scattered_reads(&disk);
+        let in_flight = result
+            .clone()
+            .unwrap_or_else(|error| panic!("ahci self test: {name}: scattered reads: {error}"));
+        println!(
+            "[ahci] {name}: {} scattered reads, up to {in_flight} in flight",
+            SCATTERED.len()
+        );
+        disk.results.lock().scatter = Some(result);
+        assert!(
+            !routed || disk.interrupts.load(Ordering::Relaxed) > interrupts,
+            "ahci self test: {name}: no interrupt came"
+        );
+
+        let outside = disk.submit(BlockRequest::new(
+            BlockRequestKind::Read,
+            Lba(disk.number_of_sectors),
+            vec![0; ata::SECTOR_SIZE as usize],
+        ));
+        assert!(matches!(
+            disk.wait(outside).1,
+            Err(StorageErrorKind::OutOfRange)
+        ));
+        let unaligned = disk.submit(BlockRequest::new(
+            BlockRequestKind::Read,
+            Lba(0),
+            vec![0; ata::SECTOR_SIZE as usize - 1],
+        ));
+        assert!(matches!(
+            disk.wait(unaligned).1,
+            Err(StorageErrorKind::Unaligned)
+        ));
+        assert_eq!(disk.port.lock().in_flight(), 0);
+    }
+    println!("AHCI self tests passed");
+}
@@ -0,0 +1,1590 @@
+//! Block devices, which are read and written in whole sectors, and the `/devices/<name>`
+//! files for them.
+//!
+//! Opening the file normally gives byte access for reading, which is not aligned and
+//! read-only. Opening it with `OPEN_DIRECT` (see [`BlockDeviceFile::read_direct`]) needs
+//! sector aligned offsets and sizes, and goes directly to the driver, this is the only way
+//! to write to the device.
+//!
+//! The sectors read by the filesystems are kept in the block cache of [`page_cache`] (see
+//! [`BlockDeviceFile::read_sectors`]), which the writes keep up to date.
+//!
+//! Direct writes invalidate everything the filesystems on the device have cached (see
+//! [`BlockDeviceFile::add_cache_user`] and [`BlockDeviceFile::generation`]), we don't keep
+//! which pages come from which sectors, so all of it is dropped.
+//!
+//! Drivers get [`BlockRequest`]s with [`BlockDevice::submit`] and complete them with
+//! [`BlockDevice::wait`], big accesses are split into requests and up to
+//! [`BlockDevice::queue_depth`] of them are in flight at once, for drivers that can overlap them.
+//!
+//! Writes may stay in the volatile cache of the device until [`BlockDeviceFile::flush`], which
+//! is done by `sync`, `fsync`, and by the `writeback` kernel thread (see [`start_writeback`])
+//! for devices written more than `writeback=<ms>` (cmdline, [`DEFAULT_WRITEBACK_AGE_MS`] by
+//! default) ago, so a crash loses a bounded amount of data.
+//!
+//! A removed device (see [`devices::unregister_device`]) is shut down, the filesystems on it
+//! are force-unmounted and every access after that fails with `FileSystemError::DeviceGone`,
+//! without reaching the driver.
+//!
+//! The time each request waits for the queue and spends in the driver is kept per device,
+//! see [`iostats`].
+//!
+//! The drivers fail with a [`StorageErrorKind`], which is given to the filesystems as a
+//! [`StorageError`] with the name of the device and the sector, the filesystems then add
+//! what they were doing to its [`ErrorContext`].
+
+use core::{
+    fmt, mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use kernel_core::{
+    error_context::{ErrorContext, MAX_FRAMES},
+    sector::Lba,
+};
+use kernel_user_link::syscalls::{syscall_result_from_u64, syscall_result_to_u64, SyscallError};
+
+use crate::{
+    devices::{
+        self, clock,
+        iostats::{self, RequestLatency},
+        Device, WeakDevice,
+    },
+    fs::{self, page_cache, FileSystemError},
+    io::NoDebug,
+    memory_management::memory_layout::PAGE_4K,
+    process::kernel_thread,
+    sync::spin::mutex::Mutex,
+    system,
+};
+
+use super::ramdisk::RamDisk;
+
+/// The maximum number of sectors of one request to the driver, bigger accesses are split
+pub const MAX_SECTORS_PER_REQUEST: u64 = 128;
+
+/// How long written data can stay only in the cache of a device by default
+pub const DEFAULT_WRITEBACK_AGE_MS: u64 = 5000;
+
+/// How often the devices are checked for the writeback, a crash loses up to this much more than
+/// the writeback age
+const WRITEBACK_PERIOD_NANOS: u64 = 100_000_000;
+
+static WRITEBACK_AGE_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_WRITEBACK_AGE_MS * 1_000_000);
+/// All the devices registered, to flush them on `sync` and writeback
+static BLOCK_DEVICES: Mutex<Vec<Arc<BlockDeviceFile>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockRequestKind {
+    Read,
+    Write,
+}
+
+/// A request of whole sectors to the driver, the buffer is owned by the request until its
+/// completed, so the driver can fill it in the background
+#[derive(Debug)]
+pub struct BlockRequest {
+    pub kind: BlockRequestKind,
+    pub start_sector: Lba,
+    /// Read into for [`BlockRequestKind::Read`], must be a multiple of the sector size
+    pub data: Vec<u8>,
+    /// The uptime when the driver completed it, see [`Self::complete`]
+    pub completed_at: u64,
+}
+
+impl BlockRequest {
+    pub fn new(kind: BlockRequestKind, start_sector: Lba, data: Vec<u8>) -> Self {
+        Self {
+            kind,
+            start_sector,
+            data,
+            completed_at: 0,
+        }
+    }
+
+    /// Records that the request is done now, for its latency, drivers call it when the device
+    /// reports it, i.e. in the interrupt. Not needed if its done when waited
+    pub fn complete(&mut self) {
+        self.completed_at = clock::uptime_nanos();
+    }
+}
+
+/// A submitted request, it must be given back to [`BlockDevice::wait`] of the same device
+#[must_use]
+#[derive(Debug)]
+pub enum RequestHandle {
+    /// Done at submit, by drivers that can't overlap requests
+    Completed(BlockRequest, Result<(), StorageErrorKind>),
+    /// In flight, the id is given by the driver
+    Pending(u64),
+}
+
+/// How a driver failed, this is all the block layer and the filesystems know of the hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    /// The device didn't complete the request in time
+    Timeout,
+    /// The medium could not be read or written, i.e. a bad sector, with the error code of
+    /// the device
+    Media(u8),
+    /// Any other failure reported by the device, with its error code
+    Device(u8),
+    /// The size is not in whole sectors
+    Unaligned,
+    /// Some of the sectors are outside the device
+    OutOfRange,
+    NotSupported,
+    /// The device was removed, see [`BlockDevice::shutdown`]
+    DeviceGone,
+}
+
+impl fmt::Display for StorageErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageErrorKind::Timeout => write!(f, "timeout"),
+            StorageErrorKind::Media(code) => write!(f, "medium error ({code:#04x})"),
+            StorageErrorKind::Device(code) => write!(f, "device error ({code:#04x})"),
+            StorageErrorKind::Unaligned => write!(f, "unaligned size"),
+            StorageErrorKind::OutOfRange => write!(f, "outside the device"),
+            StorageErrorKind::NotSupported => write!(f, "not supported by the device"),
+            StorageErrorKind::DeviceGone => write!(f, "the device was removed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageOp {
+    Read,
+    Write,
+    Flush,
+}
+
+// longer names are cut, they are only for the logs
+const STORAGE_ERROR_NAME_LEN: usize = 16;
+
+/// A failed access to a block device, shown as
+/// `<context>: <device>: <op> at LBA <lba>: <kind>`, i.e.
+/// `read FAT sector 1234: ide0: read at LBA 1234: timeout`.
+///
+/// It doesn't allocate, and is small enough to be returned everywhere
+#[derive(Debug, Clone, Copy)]
+pub struct StorageError {
+    device: [u8; STORAGE_ERROR_NAME_LEN],
+    device_len: u8,
+    pub op: StorageOp,
+    /// The first sector of the failed request, not used for [`StorageOp::Flush`]
+    pub lba: Lba,
+    pub kind: StorageErrorKind,
+    /// What the filesystems were doing, see [`FileSystemError::context`]
+    pub context: ErrorContext,
+}
+
+const _: () = assert!(core::mem::size_of::<StorageError>() <= 112);
+
+impl StorageError {
+    pub fn new(device: &str, op: StorageOp, lba: Lba, kind: StorageErrorKind) -> Self {
+        let mut len = device.len().min(STORAGE_ERROR_NAME_LEN);
+        while !device.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut name = [0; STORAGE_ERROR_NAME_LEN];
+        name[..len].copy_from_slice(&device.as_bytes()[..len]);
+        Self {
+            device: name,
+            device_len: len as u8,
+            op,
+            lba,
+            kind,
+            context: ErrorContext::new(),
+        }
+    }
+
+    /// The name of the device under `/devices`, cut if too long
+    pub fn device(&self) -> &str {
+        // only cut at a character boundary in `new`
+        core::str::from_utf8(&self.device[..self.device_len as usize]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.context.is_empty() {
+            write!(f, "{}: ", self.context)?;
+        }
+        write!(f, "{}: ", self.device())?;
+        match self.op {
+            StorageOp::Read => write!(f, "read at LBA {}", self.lba)?,
+            StorageOp::Write => write!(f, "write at LBA {}", self.lba)?,
+            StorageOp::Flush => write!(f, "flush")?,
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn sector_size(&self) -> u32;
+    fn number_of_sectors(&self) -> u64;
+    /// Reads whole sectors starting at `start_sector`, `data` must be a multiple of the
+    /// sector size
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind>;
+    /// Writes whole sectors starting at `start_sector`, `data` must be a multiple of the
+    /// sector size
+    fn write_sectors(&self, _start_sector: Lba, _data: &[u8]) -> Result<(), StorageErrorKind> {
+        Err(StorageErrorKind::NotSupported)
+    }
+    /// The maximum number of requests that can be submitted before waiting for any of them
+    fn queue_depth(&self) -> usize {
+        1
+    }
+    /// Starts `request`, by default its done right away with [`Self::read_sectors`] or
+    /// [`Self::write_sectors`], so one request is done at a time.
+    ///
+    /// Drivers with a queue can complete the submitted requests in any order
+    fn submit(&self, mut request: BlockRequest) -> RequestHandle {
+        let result = match request.kind {
+            BlockRequestKind::Read => self.read_sectors(request.start_sector, &mut request.data),
+            BlockRequestKind::Write => self.write_sectors(request.start_sector, &request.data),
+        };
+        request.complete();
+        RequestHandle::Completed(request, result)
+    }
+    /// Blocks until the request of `handle` is completed, and gives it back with its result
+    fn wait(&self, handle: RequestHandle) -> (BlockRequest, Result<(), StorageErrorKind>) {
+        match handle {
+            RequestHandle::Completed(request, result) => (request, result),
+            RequestHandle::Pending(id) => panic!("Block request {id} is not from this device"),
+        }
+    }
+    /// Writes everything in the volatile write cache of the device to its medium, devices
+    /// without a cache have nothing to do
+    fn flush(&self) -> Result<(), StorageErrorKind> {
+        Ok(())
+    }
+    /// Called once when the device is removed, the requests in flight must complete with
+    /// [`StorageErrorKind::DeviceGone`] without waiting for the hardware, and nothing is submitted
+    /// after this. Its interrupts can still come, and must not touch the hardware anymore.
+    ///
+    /// Devices in memory have nothing to do
+    fn shutdown(&self) {}
+}
+
+/// `/devices/<name>`, a block device shared by the filesystems on it and by userspace
+#[derive(Debug)]
+pub struct BlockDeviceFile {
+    name: String,
+    device: NoDebug<Arc<dyn BlockDevice>>,
+    writable: bool,
+    // the page cache ids of the filesystems on this device
+    cache_users: Mutex<Vec<u64>>,
+    // the id of the sectors of this device in the block cache
+    cache_id: u64,
+    // increased on every direct write
+    generation: AtomicU64,
+    // the uptime of the first write since the last flush, `0` if nothing to flush
+    dirty_since: AtomicU64,
+    // the device was removed, see `Device::shutdown`
+    gone: AtomicBool,
+    latency: RequestLatency,
+}
+
+impl BlockDeviceFile {
+    /// Registers `device` as `/devices/<name>`, it can only be written to if `writable`
+    pub fn register(name: String, device: Arc<dyn BlockDevice>, writable: bool) -> Arc<Self> {
+        let file = Arc::new(Self {
+            name,
+            device: NoDebug(device),
+            writable,
+            cache_users: Mutex::new(Vec::new()),
+            cache_id: page_cache::new_cache_id(),
+            generation: AtomicU64::new(0),
+            dirty_since: AtomicU64::new(0),
+            gone: AtomicBool::new(false),
+            latency: RequestLatency::new(),
+        });
+        devices::register_device(file.clone());
+        BLOCK_DEVICES.lock().push(file.clone());
+        file
+    }
+
+    pub fn sector_size(&self) -> u32 {
+        self.device.sector_size()
+    }
+
+    pub fn number_of_sectors(&self) -> u64 {
+        self.device.number_of_sectors()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// The device was removed, nothing reaches the driver anymore
+    pub fn is_gone(&self) -> bool {
+        self.gone.load(Ordering::Acquire)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.number_of_sectors() * self.sector_size() as u64
+    }
+
+    pub fn latency(&self) -> &RequestLatency {
+        &self.latency
+    }
+
+    /// Changes each time the device is written directly, filesystems that keep data from the
+    /// device (other than in the page cache) must read it again when this changes
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The pages of the filesystem with `cache_id` will be dropped from the page cache when
+    /// the device is written directly
+    pub fn add_cache_user(&self, cache_id: u64) {
+        self.cache_users.lock().push(cache_id);
+    }
+
+    pub fn remove_cache_user(&self, cache_id: u64) {
+        self.cache_users.lock().retain(|&id| id != cache_id);
+    }
+
+    /// Splits `len` bytes at `start_sector` into requests, and keeps up to the queue depth of
+    /// the device of them in flight. `on_complete` is called with each successful request, not
+    /// in order.
+    ///
+    /// All the submitted requests are waited even after an error, the error of the first
+    /// request that failed is returned. Nothing more is submitted once the device is gone
+    fn transfer(
+        &self,
+        kind: BlockRequestKind,
+        start_sector: Lba,
+        len: usize,
+        mut new_data: impl FnMut(usize, usize) -> Vec<u8>,
+        mut on_complete: impl FnMut(usize, BlockRequest),
+    ) -> Result<(), (Lba, StorageErrorKind)> {
+        let sector_size = self.sector_size() as usize;
+        let request_len = MAX_SECTORS_PER_REQUEST as usize * sector_size;
+        let queue_depth = self.device.queue_depth().max(1);
+
+        // all the requests are submitted now, they wait for a slot in the queue of the device
+        let submitted = clock::uptime_nanos();
+        let mut requests = (0..len).step_by(request_len).map(|offset| {
+            BlockRequest::new(
+                kind,
+                start_sector + (offset / sector_size) as u64,
+                new_data(offset, request_len.min(len - offset)),
+            )
+        });
+        let mut in_flight = VecDeque::with_capacity(queue_depth);
+        let mut first_error = None;
+        loop {
+            while in_flight.len() < queue_depth && first_error.is_none() {
+                let Some(request) = requests.next() else {
+                    break;
+                };
+                if self.is_gone() {
+                    first_error = Some((request.start_sector, StorageErrorKind::DeviceGone));
+                    break;
+                }
+                let dispatched = clock::uptime_nanos();
+                in_flight.push_back((self.device.submit(request), dispatched));
+            }
+            // waited in submission order, so the first error is the one of the lowest sector
+            let Some((handle, dispatched)) = in_flight.pop_front() else {
+                break;
+            };
+            let (request, result) = self.device.wait(handle);
+            let completed = match request.completed_at {
+                0 => clock::uptime_nanos(),
+                completed => completed,
+            };
+            self.latency.record(submitted, dispatched, completed);
+            match result {
+                Ok(()) => {
+                    let offset = (request.start_sector - start_sector) as usize * sector_size;
+                    on_complete(offset, request);
+                }
+                Err(error) => {
+                    first_error.get_or_insert((request.start_sector, error));
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// A failure of the driver, [`StorageErrorKind::DeviceGone`] is
+    /// `FileSystemError::DeviceGone`, the rest are `FileSystemError::Storage`
+    fn error(&self, op: StorageOp, lba: Lba, kind: StorageErrorKind) -> FileSystemError {
+        StorageError::new(&self.name, op, lba, kind).into()
+    }
+
+    /// Reads whole sectors, used by the filesystems, through the block cache, only the sectors
+    /// not cached reach the driver
+    pub fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), FileSystemError> {
+        if self.is_gone() {
+            return Err(FileSystemError::DeviceGone);
+        }
+        if !self.is_cached() {
+            return self.read_uncached(start_sector, data);
+        }
+        page_cache::read_sectors(
+            self.cache_id,
+            self.sector_size() as usize,
+            start_sector.0,
+            data,
+            |sector, data| self.read_uncached(Lba(sector), data),
+        )
+    }
+
+    /// The block cache keeps whole sectors in its pages
+    fn is_cached(&self) -> bool {
+        let sector_size = self.sector_size() as usize;
+        PAGE_4K.is_multiple_of(sector_size) && PAGE_4K / sector_size <= 64
+    }
+
+    /// Drops the sectors of this device from the block cache, for when the medium changed
+    /// without us, they are read from the driver again
+    pub fn drop_cached_sectors(&self) {
+        page_cache::invalidate_sectors(self.cache_id);
+    }
+
+    fn read_uncached(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), FileSystemError> {
+        self.transfer(
+            BlockRequestKind::Read,
+            start_sector,
+            data.len(),
+            |_, len| vec![0; len],
+            |offset, request| data[offset..][..request.data.len()].copy_from_slice(&request.data),
+        )
+        .map_err(|(sector, kind)| self.error(StorageOp::Read, sector, kind))
+    }
+
+    /// Writes whole sectors, used by the filesystems, the device must be registered as
+    /// writable (see [`Self::is_writable`]).
+    ///
+    /// Unlike [`Self::write_direct`], the caches are not dropped, the filesystem writing is
+    /// expected to keep its own data up to date
+    pub fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), FileSystemError> {
+        if !self.writable {
+            return Err(FileSystemError::WriteNotSupported);
+        }
+        // even if it fails, some sectors may be in the cache of the device
+        self.mark_dirty();
+        let result = self
+            .transfer(
+                BlockRequestKind::Write,
+                start_sector,
+                data.len(),
+                |offset, len| data[offset..][..len].to_vec(),
+                |_, _| {},
+            )
+            .map_err(|(sector, kind)| self.error(StorageOp::Write, sector, kind));
+        if self.is_cached() {
+            page_cache::write_sectors(
+                self.cache_id,
+                self.sector_size() as usize,
+                start_sector.0,
+                data,
+                result.is_ok(),
+            );
+        }
+        result
+    }
+
+    fn mark_dirty(&self) {
+        // `0` means clean, so the uptime before the clock is initialized is moved to `1`
+        let now = clock::uptime_nanos().max(1);
+        let _ = self
+            .dirty_since
+            .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    /// The uptime of the oldest write not flushed yet, `None` if everything is flushed
+    pub fn dirty_since(&self) -> Option<u64> {
+        match self.dirty_since.load(Ordering::Acquire) {
+            0 => None,
+            since => Some(since),
+        }
+    }
+
+    /// Makes all the writes done so far stable, by flushing the cache of the device,
+    /// does nothing if there was no write since the last flush.
+    ///
+    /// Fails with `FileSystemError::DeviceGone` if the device was removed with writes not
+    /// flushed, they are lost
+    pub fn flush(&self) -> Result<(), FileSystemError> {
+        let since = self.dirty_since.swap(0, Ordering::AcqRel);
+        if since == 0 {
+            return Ok(());
+        }
+        if self.is_gone() {
+            return Err(FileSystemError::DeviceGone);
+        }
+        self.device.flush().map_err(|kind| {
+            // still dirty, unless written again, which is newer
+            let _ =
+                self.dirty_since
+                    .compare_exchange(0, since, Ordering::AcqRel, Ordering::Acquire);
+            self.error(StorageOp::Flush, Lba(0), kind)
+        })
+    }
+
+    /// The part of `len` bytes at `offset` that is inside the device, `offset` and `len` must
+    /// be aligned to the sector size
+    fn direct_range(&self, offset: u64, len: usize) -> Result<(Lba, usize), FileSystemError> {
+        let sector_size = self.sector_size() as u64;
+        if !offset.is_multiple_of(sector_size) || !(len as u64).is_multiple_of(sector_size) {
+            return Err(FileSystemError::UnalignedAccess);
+        }
+        let len = self.size().saturating_sub(offset).min(len as u64) as usize;
+        Ok((Lba(offset / sector_size), len))
+    }
+
+    /// Reads directly from the driver, without any cache, `offset` and the size of `buf` must
+    /// be aligned to the sector size.
+    ///
+    /// Returns the bytes read, which is less than `buf` only at the end of the device
+    pub fn read_direct(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let (start_sector, len) = self.direct_range(offset, buf.len())?;
+        self.read_uncached(start_sector, &mut buf[..len])?;
+        Ok(len as u64)
+    }
+
+    /// Writes directly to the driver, same as [`Self::read_direct`], the device must be
+    /// registered as writable.
+    ///
+    /// All the cached data of the filesystems on the device is dropped after the write
+    pub fn write_direct(&self, offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if !self.writable {
+            return Err(FileSystemError::WriteNotSupported);
+        }
+        let (start_sector, len) = self.direct_range(offset, buf.len())?;
+        let result = self.write_sectors(start_sector, &buf[..len]);
+        // even if it failed, some sectors may have been written
+        self.invalidate_caches();
+        result.map(|_| len as u64)
+    }
+
+    /// Reads into or writes `data` with a single request to the driver, up to
+    /// [`MAX_SECTORS_PER_REQUEST`] sectors. The buffer is given to the driver and back, so
+    /// nothing is allocated, this is for the swap, which writes when the memory ran out.
+    ///
+    /// The block cache is not updated, and the writes are not flushed later, they only have
+    /// to be read back while the kernel runs
+    pub fn transfer_in_place(
+        &self,
+        kind: BlockRequestKind,
+        start_sector: Lba,
+        data: &mut Vec<u8>,
+    ) -> Result<(), FileSystemError> {
+        let op = match kind {
+            BlockRequestKind::Read => StorageOp::Read,
+            BlockRequestKind::Write if !self.writable => {
+                return Err(FileSystemError::WriteNotSupported)
+            }
+            BlockRequestKind::Write => StorageOp::Write,
+        };
+        assert!(data.len() as u64 <= MAX_SECTORS_PER_REQUEST * self.sector_size() as u64);
+        if self.is_gone() {
+            return Err(FileSystemError::DeviceGone);
+        }
+        let submitted = clock::uptime_nanos();
+        let request = BlockRequest::new(kind, start_sector, mem::take(data));
+        let (request, result) = self.device.wait(self.device.submit(request));
+        let completed = match request.completed_at {
+            0 => clock::uptime_nanos(),
+            completed => completed,
+        };
+        self.latency.record(submitted, submitted, completed);
+        *data = request.data;
+        result.map_err(|kind| self.error(op, start_sector, kind))
+    }
+
+    fn invalidate_caches(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        let cache_users = self.cache_users.lock().clone();
+        for cache_id in cache_users {
+            page_cache::invalidate(cache_id);
+        }
+    }
+}
+
+impl Device for BlockDeviceFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reads any range of bytes, through a buffer of whole sectors
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        if offset >= self.size() {
+            return Ok(0);
+        }
+        let sector_size = self.sector_size() as u64;
+        let to_read = (self.size() - offset).min(buf.len() as u64);
+        let first_sector = offset / sector_size;
+        let end_sector = (offset + to_read).div_ceil(sector_size);
+        let sectors = (end_sector - first_sector).min(MAX_SECTORS_PER_REQUEST);
+
+        let mut data = vec![0; (sectors * sector_size) as usize];
+        self.read_sectors(Lba(first_sector), &mut data)?;
+
+        let start = (offset - first_sector * sector_size) as usize;
+        let len = (data.len() - start).min(to_read as usize);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len as u64)
+    }
+
+    fn as_block_device(&self) -> Option<&BlockDeviceFile> {
+        Some(self)
+    }
+
+    /// Stops the driver, removes the device from `sync` and the writeback, and force-unmounts
+    /// the filesystems on it
+    fn shutdown(&self) {
+        self.gone.store(true, Ordering::Release);
+        self.device.shutdown();
+        self.drop_cached_sectors();
+        BLOCK_DEVICES
+            .lock()
+            .retain(|device| !ptr::eq(Arc::as_ptr(device), self));
+        fs::mounts::unmount_device(self);
+    }
+}
+
+/// Flushes the devices and stops their drivers, after the filesystems on them are synced
+fn shutdown_all() {
+    let devices = BLOCK_DEVICES.lock().clone();
+    for device in devices {
+        if let Err(e) = device.flush() {
+            println!("WARNING: could not flush {}: {e}", device.name);
+        }
+        device.gone.store(true, Ordering::Release);
+        device.device.shutdown();
+    }
+}
+
+/// Sets the writeback age from `writeback=<ms>` in the cmdline, adds the shutdown hook and
+/// `/devices/iostats`
+pub fn init(cmdline: &str) {
+    system::on_shutdown("block devices", shutdown_all);
+    iostats::init();
+    let Some(age) = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("writeback="))
+    else {
+        return;
+    };
+    match age.parse::<u64>() {
+        Ok(ms) => {
+            set_writeback_age_ms(ms);
+        }
+        Err(_) => {
+            println!("Invalid value for `writeback`: {age}");
+        }
+    }
+}
+
+/// All the registered block devices
+pub fn registered() -> Vec<Arc<BlockDeviceFile>> {
+    BLOCK_DEVICES.lock().clone()
+}
+
+/// The registered block device `/devices/<name>`
+pub fn find(name: &str) -> Option<Arc<BlockDeviceFile>> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name == name)
+        .cloned()
+}
+
+/// Sets how long writes can stay in the cache of the devices, returns the old value
+pub fn set_writeback_age_ms(ms: u64) -> u64 {
+    WRITEBACK_AGE_NANOS.swap(ms.saturating_mul(1_000_000), Ordering::Relaxed) / 1_000_000
+}
+
+/// Flushes all the devices with writes not flushed yet, the error of the first that failed
+/// is returned, but all of them are tried
+pub fn flush_all() -> Result<(), FileSystemError> {
+    let devices = BLOCK_DEVICES.lock().clone();
+    let mut first_error = None;
+    for device in devices {
+        if let Err(e) = device.flush() {
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Flushes the devices written more than the writeback age ago, this is done every
+/// [`WRITEBACK_PERIOD_NANOS`] by the `writeback` kernel thread, see [`start_writeback`]
+pub fn writeback_expired() {
+    let age = WRITEBACK_AGE_NANOS.load(Ordering::Relaxed);
+    let now = clock::uptime_nanos();
+    let devices = BLOCK_DEVICES
+        .lock()
+        .iter()
+        .filter(|device| {
+            device
+                .dirty_since()
+                .is_some_and(|since| now.saturating_sub(since) >= age)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for device in devices {
+        if let Err(e) = device.flush() {
+            println!("[block] writeback of {} failed: {e}", device.name);
+        }
+    }
+}
+
+/// Starts the `writeback` kernel thread, which flushes the expired devices while the processes
+/// run, it can wait for the drivers as long as it takes
+pub fn start_writeback() {
+    kernel_thread::spawn("writeback", || loop {
+        writeback_expired();
+        kernel_thread::sleep_until(clock::monotonic_nanos().saturating_add(WRITEBACK_PERIOD_NANOS));
+    });
+}
+
+pub const SELFTEST_SECTORS: u64 = 128;
+pub const SELFTEST_SECTOR_SIZE: usize = 512;
+const SELFTEST_FILE_NAME: &[u8; 11] = b"HELLO   TXT";
+const SELFTEST_CONTENT: &[u8] = b"written through the direct path\n";
+const SELFTEST_NEW_CONTENT: &[u8] = b"replaced, not from the old cache\n";
+
+/// A tiny FAT12 image, 1 sector per cluster, with one file in the root directory
+/// at cluster 2, this is what a `mkfs` in userspace would write
+pub fn selftest_fat12_image(content: &[u8]) -> Vec<u8> {
+    const FAT_SECTORS: u16 = 1;
+    const ROOT_ENTRIES: u16 = 16;
+    let mut image = vec![0; SELFTEST_SECTORS as usize * SELFTEST_SECTOR_SIZE];
+
+    let boot = &mut image[..SELFTEST_SECTOR_SIZE];
+    boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"AMJADOS ");
+    boot[11..13].copy_from_slice(&(SELFTEST_SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1; // sectors per cluster
+    boot[14..16].copy_from_slice(&1u16.to_le_bytes()); // reserved sectors
+    boot[16] = 1; // number of FATs
+    boot[17..19].copy_from_slice(&ROOT_ENTRIES.to_le_bytes());
+    boot[19..21].copy_from_slice(&(SELFTEST_SECTORS as u16).to_le_bytes());
+    boot[21] = 0xF8; // media type
+    boot[22..24].copy_from_slice(&FAT_SECTORS.to_le_bytes());
+    boot[38] = 0x29; // extended boot signature
+    boot[43..54].copy_from_slice(b"SELFTEST   ");
+    boot[54..62].copy_from_slice(b"FAT12   ");
+    boot[510..512].copy_from_slice(&0xAA55u16.to_le_bytes());
+
+    // entries 0 and 1 are reserved, cluster 2 is the end of its chain
+    let fat = &mut image[SELFTEST_SECTOR_SIZE..][..SELFTEST_SECTOR_SIZE];
+    fat[..5].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF, 0x0F]);
+
+    let root = &mut image[2 * SELFTEST_SECTOR_SIZE..][..32];
+    root[..11].copy_from_slice(SELFTEST_FILE_NAME);
+    root[11] = 0x20; // archive
+    root[26..28].copy_from_slice(&2u16.to_le_bytes()); // first cluster
+    root[28..32].copy_from_slice(&(content.len() as u32).to_le_bytes());
+
+    let data_start = (2 + ROOT_ENTRIES as usize * 32 / SELFTEST_SECTOR_SIZE) * SELFTEST_SECTOR_SIZE;
+    image[data_start..data_start + content.len()].copy_from_slice(content);
+    image
+}
+
+/// A ramdisk with a write cache that keeps the writes in order until [`BlockDevice::flush`],
+/// to check what is left on the medium after a crash
+pub struct SelftestCacheDisk {
+    medium: Mutex<Vec<u8>>,
+    // (start sector, data), in the order they were written
+    cache: Mutex<Vec<(Lba, Vec<u8>)>>,
+    flushes: AtomicU64,
+}
+
+impl SelftestCacheDisk {
+    pub fn new(image: Vec<u8>) -> Self {
+        Self {
+            medium: Mutex::new(image),
+            cache: Mutex::new(Vec::new()),
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    fn check_range(&self, start_sector: Lba, len: usize) -> Result<usize, StorageErrorKind> {
+        if !len.is_multiple_of(SELFTEST_SECTOR_SIZE) {
+            return Err(StorageErrorKind::Unaligned);
+        }
+        let start = start_sector.0 as usize * SELFTEST_SECTOR_SIZE;
+        if start + len > self.medium.lock().len() {
+            return Err(StorageErrorKind::OutOfRange);
+        }
+        Ok(start)
+    }
+
+    pub fn cached_writes(&self) -> usize {
+        self.cache.lock().len()
+    }
+
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// The medium if the device loses power after only the first `reached` writes of its
+    /// cache were written
+    pub fn crash_image(&self, reached: usize) -> Vec<u8> {
+        let mut image = self.medium.lock().clone();
+        for (sector, data) in self.cache.lock().iter().take(reached) {
+            let start = sector.0 as usize * SELFTEST_SECTOR_SIZE;
+            image[start..start + data.len()].copy_from_slice(data);
+        }
+        image
+    }
+
+    /// Loses everything in the cache
+    pub fn crash(&self) {
+        self.cache.lock().clear();
+    }
+
+    /// Replaces the medium with `image`, and empties the cache
+    pub fn reset(&self, image: Vec<u8>) {
+        *self.medium.lock() = image;
+        self.crash();
+    }
+}
+
+impl BlockDevice for SelftestCacheDisk {
+    fn sector_size(&self) -> u32 {
+        SELFTEST_SECTOR_SIZE as u32
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        (self.medium.lock().len() / SELFTEST_SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        let start = self.check_range(start_sector, data.len())?;
+        let end = start + data.len();
+        data.copy_from_slice(&self.medium.lock()[start..end]);
+        // the newer writes are applied last
+        for (sector, write) in self.cache.lock().iter() {
+            let write_start = sector.0 as usize * SELFTEST_SECTOR_SIZE;
+            let from = start.max(write_start);
+            let to = end.min(write_start + write.len());
+            if from < to {
+                data[from - start..to - start]
+                    .copy_from_slice(&write[from - write_start..to - write_start]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        self.check_range(start_sector, data.len())?;
+        self.cache.lock().push((start_sector, data.to_vec()));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageErrorKind> {
+        let mut medium = self.medium.lock();
+        for (sector, data) in self.cache.lock().drain(..) {
+            let start = sector.0 as usize * SELFTEST_SECTOR_SIZE;
+            medium[start..start + data.len()].copy_from_slice(&data);
+        }
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+const SELFTEST_QUEUE_DEPTH: usize = 8;
+// a partial request at the end
+const SELFTEST_QUEUE_SECTORS: u64 = MAX_SECTORS_PER_REQUEST * SELFTEST_QUEUE_DEPTH as u64 + 5;
+
+/// A ramdisk with a queue, it completes the requests in the reverse order they were submitted,
+/// so the callers can't depend on the order. The requests touching `fail_sector` fail, and
+/// all of them after the shutdown
+struct ReorderingDisk {
+    disk: RamDisk,
+    fail_sector: Option<Lba>,
+    queue: Mutex<ReorderingQueue>,
+    gone: AtomicBool,
+}
+
+#[derive(Default)]
+struct ReorderingQueue {
+    next_id: u64,
+    pending: Vec<(u64, BlockRequest)>,
+    // completed but not waited yet
+    completed: BTreeMap<u64, (BlockRequest, Result<(), StorageErrorKind>)>,
+    max_in_flight: usize,
+}
+
+impl ReorderingDisk {
+    fn new(fail_sector: Option<Lba>) -> Self {
+        Self {
+            disk: RamDisk::new((SELFTEST_QUEUE_SECTORS, SELFTEST_SECTOR_SIZE as u32)),
+            fail_sector,
+            queue: Mutex::new(ReorderingQueue::default()),
+            gone: AtomicBool::new(false),
+        }
+    }
+
+    fn execute(&self, request: &mut BlockRequest) -> Result<(), StorageErrorKind> {
+        if self.gone.load(Ordering::Acquire) {
+            return Err(StorageErrorKind::DeviceGone);
+        }
+        let sectors = request.data.len() as u64 / self.sector_size() as u64;
+        let range = request.start_sector..request.start_sector + sectors;
+        if self
+            .fail_sector
+            .is_some_and(|sector| range.contains(&sector))
+        {
+            return Err(StorageErrorKind::Device(0x04));
+        }
+        let result = match request.kind {
+            BlockRequestKind::Read => self
+                .disk
+                .read_sectors(request.start_sector, &mut request.data),
+            BlockRequestKind::Write => self.disk.write_sectors(request.start_sector, &request.data),
+        };
+        request.complete();
+        result
+    }
+}
+
+impl BlockDevice for ReorderingDisk {
+    fn sector_size(&self) -> u32 {
+        self.disk.sector_size()
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        self.disk.number_of_sectors()
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        let (request, result) = self.wait(self.submit(BlockRequest::new(
+            BlockRequestKind::Read,
+            start_sector,
+            vec![0; data.len()],
+        )));
+        data.copy_from_slice(&request.data);
+        result
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        self.wait(self.submit(BlockRequest::new(
+            BlockRequestKind::Write,
+            start_sector,
+            data.to_vec(),
+        )))
+        .1
+    }
+
+    fn queue_depth(&self) -> usize {
+        SELFTEST_QUEUE_DEPTH
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        let mut queue = self.queue.lock();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.pending.push((id, request));
+        let in_flight = queue.pending.len() + queue.completed.len();
+        assert!(
+            in_flight <= SELFTEST_QUEUE_DEPTH,
+            "block self test: {in_flight} requests in flight, more than the queue depth"
+        );
+        queue.max_in_flight = queue.max_in_flight.max(in_flight);
+        RequestHandle::Pending(id)
+    }
+
+    fn wait(&self, handle: RequestHandle) -> (BlockRequest, Result<(), StorageErrorKind>) {
+        let RequestHandle::Pending(id) = handle else {
+            panic!("block self test: the disk never completes on submit");
+        };
+        let mut queue = self.queue.lock();
+        while let Some((pending_id, mut request)) = queue.pending.pop() {
+            let result = self.execute(&mut request);
+            queue.completed.insert(pending_id, (request, result));
+        }
+        queue
+            .completed
+            .remove(&id)
+            .unwrap_or_else(|| panic!("block self test: request {id} waited twice"))
+    }
+
+    fn shutdown(&self) {
+        self.gone.store(true, Ordering::Release);
+    }
+}
+
+/// Every sector has different content, so misplaced data is detected
+fn selftest_sectors_pattern(sectors: u64) -> Vec<u8> {
+    (0..sectors)
+        .flat_map(|sector| {
+            (0..SELFTEST_SECTOR_SIZE as u64 / 8).flat_map(move |word| {
+                let value = sector << 32 | word;
+                value.to_le_bytes()
+            })
+        })
+        .collect()
+}
+
+/// Requests overlapping in the queue, completed out of order, must get their own data,
+/// and the errors must reach the request that failed
+fn selftest_queued_requests() {
+    let disk = Arc::new(ReorderingDisk::new(None));
+    let device = BlockDeviceFile::register("selftest_queue".into(), disk.clone(), true);
+    let image = selftest_sectors_pattern(SELFTEST_QUEUE_SECTORS);
+
+    device.write_sectors(Lba(0), &image).unwrap();
+    let mut check = vec![0; image.len()];
+    disk.disk.read_sectors(Lba(0), &mut check).unwrap();
+    assert!(check == image, "block self test: queued writes mismatch");
+
+    check.fill(0);
+    device.read_sectors(Lba(0), &mut check).unwrap();
+    assert!(check == image, "block self test: queued reads mismatch");
+    assert_eq!(
+        disk.queue.lock().max_in_flight,
+        SELFTEST_QUEUE_DEPTH,
+        "block self test: the requests did not overlap"
+    );
+
+    // interleaved reads of scattered sectors, waited in a different order than submitted
+    const SCATTERED: [(u64, u64); 8] = [
+        (1000, 3),
+        (3, 1),
+        (777, 2),
+        (0, 1),
+        (512, 128),
+        (1028, 1),
+        (64, 5),
+        (129, 1),
+    ];
+    let mut handles = SCATTERED
+        .iter()
+        .map(|&(start_sector, count)| {
+            Some(disk.submit(BlockRequest::new(
+                BlockRequestKind::Read,
+                Lba(start_sector),
+                vec![0; count as usize * SELFTEST_SECTOR_SIZE],
+            )))
+        })
+        .collect::<Vec<_>>();
+    let order = (1..SCATTERED.len())
+        .step_by(2)
+        .chain((0..SCATTERED.len()).step_by(2));
+    for i in order {
+        let (request, result) = disk.wait(handles[i].take().unwrap());
+        result.unwrap();
+        let (start_sector, count) = SCATTERED[i];
+        assert_eq!(request.start_sector, Lba(start_sector));
+        let expected = &image[start_sector as usize * SELFTEST_SECTOR_SIZE..]
+            [..count as usize * SELFTEST_SECTOR_SIZE];
+        assert!(
+            request.data == expected,
+            "block self test: scattered read of {count} sectors at {start_sector} got wrong data"
+        );
+    }
+
+    // the error is of the request of the failing sector, and nothing is left in the queue
+    let failing = Arc::new(ReorderingDisk::new(Some(Lba(300))));
+    let device = BlockDeviceFile::register("selftest_queue_fail".into(), failing.clone(), true);
+    let result = device.read_sectors(Lba(0), &mut check);
+    assert!(
+        matches!(
+            result,
+            Err(FileSystemError::Storage(StorageError {
+                op: StorageOp::Read,
+                lba: Lba(256),
+                kind: StorageErrorKind::Device(_),
+                ..
+            }))
+        ),
+        "block self test: unexpected result of a failing request {result:?}"
+    );
+    let queue = failing.queue.lock();
+    assert!(queue.pending.is_empty() && queue.completed.is_empty());
+}
+
+/// Removes a device with requests in flight, they complete with `DeviceGone` and nothing else
+/// reaches the driver. Then one with a filesystem mounted and files open on it, the mount is
+/// gone and its files get `StaleHandle`, and the references to the old device don't reach a
+/// new one registered with the same name
+fn selftest_removal() {
+    const NAME: &str = "selftest_unplug";
+    const MOUNT: &str = "/selftest_unplug";
+    let queue_name = format!("{NAME}_queue");
+    let disk = Arc::new(ReorderingDisk::new(None));
+    let device = BlockDeviceFile::register(queue_name.clone(), disk.clone(), true);
+    let handles = (0..SELFTEST_QUEUE_DEPTH as u64)
+        .map(|i| {
+            disk.submit(BlockRequest::new(
+                BlockRequestKind::Read,
+                Lba(i * MAX_SECTORS_PER_REQUEST),
+                vec![0; SELFTEST_SECTOR_SIZE],
+            ))
+        })
+        .collect::<Vec<_>>();
+    devices::unregister_device(&queue_name).unwrap();
+    assert!(device.is_gone() && find(&queue_name).is_none());
+    for handle in handles {
+        assert!(matches!(
+            disk.wait(handle).1,
+            Err(StorageErrorKind::DeviceGone)
+        ));
+    }
+    let mut buf = vec![0; SELFTEST_SECTOR_SIZE];
+    assert!(matches!(
+        device.read_sectors(Lba(0), &mut buf),
+        Err(FileSystemError::DeviceGone)
+    ));
+    assert!(matches!(
+        device.write_sectors(Lba(0), &buf),
+        Err(FileSystemError::DeviceGone)
+    ));
+    // the write was not flushed
+    assert!(matches!(device.flush(), Err(FileSystemError::DeviceGone)));
+    let queue = disk.queue.lock();
+    assert!(queue.pending.is_empty() && queue.completed.is_empty());
+    assert_eq!(queue.next_id, SELFTEST_QUEUE_DEPTH as u64);
+    drop(queue);
+
+    let disk = Arc::new(RamDisk::new((
+        SELFTEST_SECTORS,
+        SELFTEST_SECTOR_SIZE as u32,
+    )));
+    disk.write_sectors(Lba(0), &selftest_fat12_image(SELFTEST_CONTENT))
+        .unwrap();
+    let device = BlockDeviceFile::register(NAME.into(), disk.clone(), true);
+    let old = WeakDevice::new(NAME, &device).unwrap();
+    fs::mount_block_device(MOUNT, device).unwrap();
+    let path = format!("{MOUNT}/HELLO.TXT");
+    let mut file = fs::open(&path).unwrap();
+    let mut raw = fs::open(&format!("/devices/{NAME}")).unwrap();
+    assert_eq!(file.read_to_end().unwrap(), SELFTEST_CONTENT);
+    assert_eq!(raw.read(&mut buf).unwrap(), buf.len() as u64);
+
+    devices::unregister_device(NAME).unwrap();
+    file.seek(0).unwrap();
+    assert!(matches!(
+        file.read(&mut buf),
+        Err(FileSystemError::StaleHandle)
+    ));
+    assert!(matches!(
+        raw.read(&mut buf),
+        Err(FileSystemError::DeviceGone)
+    ));
+    assert!(matches!(
+        fs::mounts::unmount_target(MOUNT),
+        Err(FileSystemError::FileNotFound)
+    ));
+    assert!(fs::open(&path).is_err());
+    assert!(matches!(
+        fs::open(&format!("/devices/{NAME}")),
+        Err(FileSystemError::FileNotFound)
+    ));
+    assert!(matches!(
+        devices::unregister_device(NAME),
+        Err(FileSystemError::FileNotFound)
+    ));
+    // still alive, kept by the open files, but not registered
+    assert!(old.upgrade().is_none());
+
+    let new = BlockDeviceFile::register(NAME.into(), disk, true);
+    assert!(old.upgrade().is_none());
+    let current = WeakDevice::new(NAME, &new).unwrap();
+    assert!(current
+        .upgrade()
+        .is_some_and(|device| Arc::ptr_eq(&device, &new)));
+    fs::mount_block_device(MOUNT, new).unwrap();
+    assert_eq!(
+        fs::open(&path).unwrap().read_to_end().unwrap(),
+        SELFTEST_CONTENT
+    );
+    devices::unregister_device(NAME).unwrap();
+    assert!(current.upgrade().is_none());
+    drop(file);
+    drop(raw);
+}
+
+/// A ramdisk that fails the requests touching [`Self::fail_lba`] with a timeout, to check
+/// what the errors of the drivers look like after going through the filesystems, and can
+/// delay all the requests to check their latency
+struct FaultyDisk {
+    disk: RamDisk,
+    // `u64::MAX` for none
+    fail_lba: AtomicU64,
+    delay_nanos: AtomicU64,
+}
+
+impl FaultyDisk {
+    fn fail_lba(&self, lba: Option<u64>) {
+        self.fail_lba
+            .store(lba.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn new() -> Self {
+        Self {
+            disk: RamDisk::new((SELFTEST_SECTORS, SELFTEST_SECTOR_SIZE as u32)),
+            fail_lba: AtomicU64::new(u64::MAX),
+            delay_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn check(&self, start_sector: Lba, len: usize) -> Result<(), StorageErrorKind> {
+        let delay = self.delay_nanos.load(Ordering::Relaxed);
+        if delay != 0 {
+            let end = clock::uptime_nanos() + delay;
+            while clock::uptime_nanos() < end {
+                core::hint::spin_loop();
+            }
+        }
+        let sectors = (len / SELFTEST_SECTOR_SIZE) as u64;
+        let fail_lba = self.fail_lba.load(Ordering::Relaxed);
+        if (start_sector..start_sector + sectors).contains(&Lba(fail_lba)) {
+            return Err(StorageErrorKind::Timeout);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for FaultyDisk {
+    fn sector_size(&self) -> u32 {
+        self.disk.sector_size()
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        self.disk.number_of_sectors()
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        self.check(start_sector, data.len())?;
+        self.disk.read_sectors(start_sector, data)
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        self.check(start_sector, data.len())?;
+        self.disk.write_sectors(start_sector, data)
+    }
+}
+
+/// A failing sector under a mounted FAT, the error has the path, what FAT was reading and
+/// the device with the sector, and userspace only gets `IoError`
+fn selftest_fault_injection() {
+    const NAME: &str = "selftest_faulty";
+    const MOUNT: &str = "/selftest_faulty";
+    // see `selftest_fat12_image`
+    const ROOT_DIR_SECTOR: u64 = 2;
+    const FILE_SECTOR: u64 = 3;
+
+    let disk = Arc::new(FaultyDisk::new());
+    disk.write_sectors(Lba(0), &selftest_fat12_image(SELFTEST_CONTENT))
+        .unwrap();
+    let device = BlockDeviceFile::register(NAME.into(), disk.clone(), true);
+    fs::mount_block_device(MOUNT, device).unwrap();
+    let path = format!("{MOUNT}/HELLO.TXT");
+
+    let check = |op: &str, error: FileSystemError, parts: &[&str]| {
+        let report = error
+            .storage_report(op, &path)
+            .unwrap_or_else(|| panic!("block self test: {op} failed with {error:?}"));
+        for part in parts {
+            assert!(
+                report.contains(part),
+                "block self test: no `{part}` in `{report}`"
+            );
+        }
+        let code = syscall_result_to_u64(Err(SyscallError::from(error)));
+        assert!(matches!(
+            syscall_result_from_u64(code),
+            Err(SyscallError::IoError)
+        ));
+    };
+
+    disk.fail_lba(Some(ROOT_DIR_SECTOR));
+    let Err(error) = fs::open(&path) else {
+        panic!("block self test: opened through a failing directory sector");
+    };
+    check(
+        "open",
+        error,
+        &[
+            &format!("open {path}: "),
+            &format!("read directory sector {ROOT_DIR_SECTOR}: "),
+            &format!("{NAME}: read at LBA {ROOT_DIR_SECTOR}: timeout"),
+        ],
+    );
+
+    disk.fail_lba(Some(FILE_SECTOR));
+    let mut file = fs::open(&path).unwrap();
+    let error = file.read_to_end().unwrap_err();
+    check(
+        "read",
+        error,
+        &[
+            &format!("read {path}: "),
+            &format!("read file sector {FILE_SECTOR}: "),
+            &format!("{NAME}: read at LBA {FILE_SECTOR}: timeout"),
+        ],
+    );
+
+    disk.fail_lba(None);
+    file.seek(0).unwrap();
+    assert_eq!(file.read_to_end().unwrap(), SELFTEST_CONTENT);
+
+    // the frames after the last one kept are counted
+    let mut error = StorageError::new(NAME, StorageOp::Flush, Lba(0), StorageErrorKind::Timeout);
+    for i in 0..=MAX_FRAMES as u32 {
+        error.context.push("frame", Some(i));
+    }
+    assert_eq!(
+        format!("{error}"),
+        format!("(1 more): frame 2: frame 1: frame 0: {NAME}: flush: timeout")
+    );
+
+    drop(file);
+    devices::unregister_device(NAME).unwrap();
+}
+
+/// The sectors read once come from the block cache, even when the driver would fail them
+/// now, only the others reach it. Writes replace the cached sectors, a failed write forgets
+/// them, and a removed device fails even the cached ones
+fn selftest_block_cache() {
+    const NAME: &str = "selftest_block_cache";
+    // across the end of the first page of the device
+    const START: u64 = 6;
+    const SECTORS: usize = 4;
+
+    let disk = Arc::new(FaultyDisk::new());
+    let image = selftest_sectors_pattern(SELFTEST_SECTORS);
+    disk.write_sectors(Lba(0), &image).unwrap();
+    let device = BlockDeviceFile::register(NAME.into(), disk.clone(), true);
+    let sectors = |start: u64, count: usize| {
+        &image[start as usize * SELFTEST_SECTOR_SIZE..][..count * SELFTEST_SECTOR_SIZE]
+    };
+    let read = |start: u64, count: usize| {
+        let mut buf = vec![0; count * SELFTEST_SECTOR_SIZE];
+        device.read_sectors(Lba(start), &mut buf).map(|_| buf)
+    };
+
+    assert!(read(START, SECTORS).unwrap() == sectors(START, SECTORS));
+    disk.fail_lba(Some(START + 1));
+    assert!(read(START, SECTORS).unwrap() == sectors(START, SECTORS));
+    // the two before are read from the driver, the rest from the cache
+    assert!(read(START - 2, SECTORS).unwrap() == sectors(START - 2, SECTORS));
+    disk.fail_lba(Some(START + SECTORS as u64));
+    assert!(read(START, SECTORS + 1).is_err());
+    disk.fail_lba(None);
+
+    // written through the device, the cache has the new content
+    let new = vec![0x5A; 2 * SELFTEST_SECTOR_SIZE];
+    device.write_sectors(Lba(START), &new).unwrap();
+    assert!(read(START, 2).unwrap() == new);
+    // a failed write, the sectors are read from the driver again
+    disk.fail_lba(Some(START + 1));
+    assert!(device.write_sectors(Lba(START), &new).is_err());
+    disk.disk
+        .write_sectors(Lba(START), sectors(START, 1))
+        .unwrap();
+    disk.fail_lba(None);
+    assert!(read(START, 1).unwrap() == sectors(START, 1));
+
+    // changed without the device
+    disk.disk
+        .write_sectors(Lba(START + 2), sectors(START, 1))
+        .unwrap();
+    assert!(read(START + 2, 1).unwrap() == sectors(START + 2, 1));
+    device.drop_cached_sectors();
+    assert!(read(START + 2, 1).unwrap() == sectors(START, 1));
+
+    devices::unregister_device(NAME).unwrap();
+    assert!(matches!(read(START, 1), Err(FileSystemError::DeviceGone)));
+}
+
+/// Fast requests to a ramdisk, then requests delayed by [`FaultyDisk`], the service time
+/// must show both in buckets apart, and `/devices/iostats` must have them
+fn selftest_latency_histograms() {
+    const NAME: &str = "selftest_latency";
+    const FAST: u64 = 16;
+    const SLOW: u64 = 4;
+    // in the `< 5120us` bucket, far from the whole ramdisk request
+    const DELAY_NANOS: u64 = 3_000_000;
+    const FAST_MAX_NANOS: u64 = 1_000_000;
+
+    if clock::uptime_nanos() == 0 {
+        println!("[block] no clock, skipping the latency test");
+        return;
+    }
+
+    let disk = Arc::new(FaultyDisk::new());
+    let device = BlockDeviceFile::register(NAME.into(), disk.clone(), true);
+    let mut buf = vec![0; SELFTEST_SECTOR_SIZE];
+    // direct, so they are not read from the block cache
+    for _ in 0..FAST {
+        device.read_direct(0, &mut buf).unwrap();
+    }
+    disk.delay_nanos.store(DELAY_NANOS, Ordering::Relaxed);
+    for _ in 0..SLOW {
+        device
+            .read_direct(SELFTEST_SECTOR_SIZE as u64, &mut buf)
+            .unwrap();
+    }
+    disk.delay_nanos.store(0, Ordering::Relaxed);
+
+    let queue_wait = device.latency().queue_wait.snapshot();
+    let service = device.latency().service.snapshot();
+    assert_eq!(queue_wait.iter().sum::<u64>(), FAST + SLOW);
+    let slow_bucket = iostats::bucket(DELAY_NANOS);
+    let fast_end = iostats::bucket(FAST_MAX_NANOS);
+    assert_eq!(
+        (
+            service[..fast_end].iter().sum::<u64>(),
+            service[slow_bucket..].iter().sum::<u64>()
+        ),
+        (FAST, SLOW),
+        "block self test: wrong service time buckets {service:?}"
+    );
+    let percentile = |percent| iostats::percentile(&service, percent).unwrap();
+    assert!(percentile(50) < fast_end && percentile(95) >= slow_bucket);
+
+    let info = fs::open("/devices/iostats")
+        .and_then(|mut file| file.read_to_end())
+        .unwrap();
+    let info = String::from_utf8(info).unwrap();
+    let counts = service.iter().map(|c| format!(" {c}")).collect::<String>();
+    for line in [
+        format!("{NAME} service{counts}\n"),
+        format!("# {NAME} service: {} requests", FAST + SLOW),
+    ] {
+        assert!(
+            info.contains(&line),
+            "block self test: no `{line}` in /devices/iostats:\n{info}"
+        );
+    }
+
+    let mut control = fs::open("/devices/iostats").unwrap();
+    assert!(matches!(
+        control.write(b"reset selftest_nothing"),
+        Err(FileSystemError::FileNotFound)
+    ));
+    control.write(format!("reset {NAME}").as_bytes()).unwrap();
+    assert_eq!(device.latency().service.snapshot(), [0; iostats::BUCKETS]);
+    assert_eq!(
+        device.latency().queue_wait.snapshot(),
+        [0; iostats::BUCKETS]
+    );
+
+    drop(control);
+    devices::unregister_device(NAME).unwrap();
+}
+
+/// Formats a ramdisk through the direct path, mounts it and reads the file back, then
+/// changes the file under the mounted filesystem, the new content must be read, not the
+/// cached one. Also checks the errors of the direct path
+pub fn run_self_tests() {
+    println!("Running block devices self tests...");
+    let sizes = (SELFTEST_SECTORS, SELFTEST_SECTOR_SIZE as u32);
+    let device =
+        BlockDeviceFile::register("selftest_ram".into(), Arc::new(RamDisk::new(sizes)), true);
+    BlockDeviceFile::register(
+        "selftest_ram_ro".into(),
+        Arc::new(RamDisk::new(sizes)),
+        false,
+    );
+
+    let open_direct = |path| {
+        let mut file = fs::open(path).expect("block self test: could not open the ramdisk");
+        file.set_direct()
+            .expect("block self test: direct not supported");
+        file
+    };
+
+    // "mkfs", in chunks that are not a multiple of the request size
+    let image = selftest_fat12_image(SELFTEST_CONTENT);
+    let mut file = open_direct("/devices/selftest_ram");
+    for chunk in image.chunks(3 * SELFTEST_SECTOR_SIZE) {
+        assert_eq!(file.write(chunk).unwrap(), chunk.len() as u64);
+    }
+    // at the end
+    assert_eq!(file.write(&image[..SELFTEST_SECTOR_SIZE]).unwrap(), 0);
+
+    let mut check = vec![0; image.len()];
+    assert_eq!(
+        device.read_direct(0, &mut check).unwrap(),
+        image.len() as u64
+    );
+    assert!(check == image, "block self test: direct read mismatch");
+
+    // the mounted filesystem sees what was written
+    fs::mount_block_device("/selftest_ram", device.clone()).unwrap();
+    let read_file = || {
+        fs::open("/selftest_ram/HELLO.TXT")
+            .and_then(|mut file| file.read_to_end())
+            .unwrap()
+    };
+    assert_eq!(read_file(), SELFTEST_CONTENT);
+    // now cached, replace it under the filesystem
+    let new_image = selftest_fat12_image(SELFTEST_NEW_CONTENT);
+    assert_eq!(
+        device.write_direct(0, &new_image).unwrap(),
+        new_image.len() as u64
+    );
+    assert_eq!(
+        read_file(),
+        SELFTEST_NEW_CONTENT,
+        "block self test: stale data after a direct write"
+    );
+    fs::force_unmount("/selftest_ram").unwrap();
+
+    // errors
+    let mut buf = vec![0; SELFTEST_SECTOR_SIZE];
+    assert!(matches!(
+        device.read_direct(1, &mut buf),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+    assert!(matches!(
+        device.read_direct(0, &mut buf[..100]),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+    assert!(matches!(
+        device.write_direct(0, &buf[..SELFTEST_SECTOR_SIZE - 1]),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+    // the position of a file is checked as well
+    let mut file = fs::open("/devices/selftest_ram").unwrap();
+    assert_eq!(file.read(&mut buf[..3]).unwrap(), 3);
+    file.set_direct().unwrap();
+    assert!(matches!(
+        file.read(&mut buf),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+    assert!(matches!(
+        open_direct("/devices/selftest_ram_ro").write(&buf),
+        Err(FileSystemError::WriteNotSupported)
+    ));
+    // normal opens can't write
+    assert!(matches!(
+        fs::open("/devices/selftest_ram").unwrap().write(&buf),
+        Err(FileSystemError::WriteNotSupported)
+    ));
+    assert!(matches!(
+        fs::open("/devices/page_cache").unwrap().set_direct(),
+        Err(FileSystemError::DirectNotSupported)
+    ));
+
+    selftest_queued_requests();
+    selftest_removal();
+    selftest_fault_injection();
+    selftest_block_cache();
+    selftest_latency_histograms();
+
+    println!("Block devices self tests passed");
+}
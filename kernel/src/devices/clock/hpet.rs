@@ -1,55 +1,21 @@
 use core::mem;
 
 use crate::{
-    acpi::{self},
+    acpi::{self, generic_address::address_space},
     cpu::{
         self,
         idt::{InterruptAllSavedState, InterruptHandlerWithAllState},
         interrupts::apic,
     },
-    memory_management::virtual_space,
+    devices::pit,
+    memory_management::{reclaim, virtual_space},
+    process::scheduler,
 };
 
 use super::HPET_CLOCK;
 
-const LEGACY_PIT_IO_PORT_CONTROL: u16 = 0x43;
-const LEGACY_PIT_IO_PORT_CHANNEL_0: u16 = 0x40;
-
 const ONE_SECOND_IN_FEMTOSECONDS: u64 = 1_000_000_000_000_000;
 
-fn disable_pit() {
-    // disable PIT (timer)
-    unsafe {
-        // The value being written:
-        // 0x32 = 0001 0000
-        //        |||| ||||
-        //        |||| |||+- BCD/binary mode: 0 == 16-bit binary (not important)
-        //        |||| +++-- Operating mode: 0b000 == interrupt on terminal count
-        //        ||++------ Access mode: 0b01 == lobyte only
-        //        ++-------- Select channel: 0 == channel 0
-        //
-        // Disable the PIT, we are using HPET instead
-        // Not sure if this is an intended way to do it, but what we do here is:
-        // 1. Select channel 0 (main one)
-        // 2. Set access mode to lobyte (we only need this)
-        // 3. Set operating mode to `interrupt on terminal count` (one shot)
-        // 4. Reload value with 1 only, which will just trigger the interrupt immediately
-        //    and then never again.
-        //
-        // Docs on Mode 0 (interrupt on terminal count):
-        //  the mode/command register is written the output signal goes low and the PIT waits
-        //  for the reload register to be set by software.
-        //  When the current count decrements from one to zero, the output goes high and remains
-        //  high until another mode/command register is written or the reload register is set again.
-        //
-        // How this works is that we select this mode, with the reload value of 1, which will
-        // trigger the interrupt immediately, and then never again.
-        // Since we don't have interrupt handler now, we just ignore it.
-        cpu::io_out(LEGACY_PIT_IO_PORT_CONTROL, 0x10u8);
-        cpu::io_out(LEGACY_PIT_IO_PORT_CHANNEL_0, 1u8);
-    }
-}
-
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed(8))]
 struct HpetInterruptStatus {
@@ -200,21 +166,28 @@ struct HpetMmio {
 #[derive(Debug)]
 pub struct Hpet {
     mmio: &'static mut HpetMmio,
+    // the counter value when we enabled the timer, the start of `uptime`
+    start_counter: u64,
 }
 
 impl Hpet {
     pub fn initialize_from_bios_table(hpet: &acpi::tables::Hpet) -> Option<Self> {
-        disable_pit();
-
-        assert!(hpet.base_address.address_space_id == 0); // memory space
-        let mmio_virtual_addr = virtual_space::allocate_and_map_virtual_space(
+        let base_address = hpet.base_address;
+        if base_address.address_space_id != address_space::SYSTEM_MEMORY {
+            println!("HPET: not in memory, {base_address:?}, ignored");
+            return None;
+        }
+        let mmio_virtual_addr = virtual_space::allocate_and_map_mmio(
             hpet.base_address.address as _,
             mem::size_of::<HpetMmio>() as _,
         );
         let mmio = unsafe { &mut *(mmio_virtual_addr as *mut HpetMmio) };
 
         // enable the timer
-        let mut s = Self { mmio };
+        let mut s = Self {
+            mmio,
+            start_counter: 0,
+        };
         let clock_period = s.counter_clock_period();
 
         // setup interrupts for the first timer only for now
@@ -228,11 +201,17 @@ impl Hpet {
         config.is_periodic = true; // periodic
         config.force_32bit_mode = false; // don't force 32-bit mode
         config.interrupt_via_fsb = false; // don't use FSB
-        let interrupt_route = config
-            .interrupt_route_capabilities
-            .enabled_rounts()
-            .next()
-            .unwrap();
+        let routes = config.interrupt_route_capabilities;
+        let interrupt_route = match routes.enabled_rounts().find(|&r| apic::is_io_irq_free(r)) {
+            Some(route) => route,
+            None => {
+                // the PIT kept as a watchdog is the only other user this early, and it shares
+                // its line with us on most machines, we are more important
+                println!("HPET: no free interrupt route, disabling the PIT watchdog");
+                pit::disable();
+                routes.enabled_rounts().next().unwrap()
+            }
+        };
         config.interrupt_route = interrupt_route;
         config.timer_set_value = true; // write the timer value
         timer.set_config(config);
@@ -250,6 +229,7 @@ impl Hpet {
         s.set_enabled(true);
         // use normal routing
         s.set_enable_legacy_replacement_route(false);
+        s.start_counter = s.current_counter();
 
         Some(s)
     }
@@ -287,12 +267,18 @@ impl Hpet {
         (self.mmio.general_capabilities_id >> 32) & 0xFFFFFFFF
     }
 
-    #[allow(dead_code)]
     fn current_counter(&self) -> u64 {
         // Safety: we know that the counter is 64-bit, aligned, valid pointer
         unsafe { (&self.mmio.main_counter_value as *const u64).read_volatile() }
     }
 
+    /// Nanoseconds since the timer was enabled
+    pub fn current_time_nanos(&self) -> u64 {
+        let ticks = self.current_counter().wrapping_sub(self.start_counter);
+        // period is in femtoseconds
+        (ticks as u128 * self.counter_clock_period() as u128 / 1_000_000) as u64
+    }
+
     fn status_interrupts_iter(&self) -> impl Iterator<Item = u8> {
         self.mmio.general_interrupt_status.set_interrupts_iter()
     }
@@ -309,6 +295,13 @@ extern "cdecl" fn timer0_handler(_all_state: &mut InterruptAllSavedState) {
 
     // clear the interrupt (must for level triggered interrupts)
     clock.ack_interrupt(interrupt);
+    // sampling reads the clock as well
+    drop(clock);
+
+    // this timer is periodic each second, so its a good low frequency point to sample usage
+    scheduler::sample_cpu_usage();
+    // and to give memory back before the allocations run out of it
+    reclaim::background_tick();
 
     apic::return_from_interrupt();
 }
@@ -1,25 +1,116 @@
 mod hpet;
 mod rtc;
+pub mod time_page;
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 
 use alloc::sync::Arc;
 
+use kernel_user_link::time::ClockId;
+
 use crate::{
     acpi::tables::{self, BiosTables, Facp},
+    cpu::{self, idt::InterruptAllSavedState},
+    io,
+    process::{scheduler, timers},
+    profiler,
     sync::{once::OnceLock, spin::mutex::Mutex},
 };
 
 use self::{hpet::Hpet, rtc::Rtc};
 
+use super::pit;
+
+pub use rtc::read_cmos_register;
+
 // hpet clock for now
 static HPET_CLOCK: OnceLock<Option<Arc<Mutex<Hpet>>>> = OnceLock::new();
+// added to the monotonic time to get the realtime, the time page has a copy
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::LocalApic as u8);
+// the number of ticks each source has driven the scheduler with, indexed by `TickSource`
+static DRIVEN_TICKS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// The timers that can drive the scheduler, only one of them does at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    Pit = 0,
+    LocalApic = 1,
+}
+
+impl TickSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TickSource::Pit => "pit",
+            TickSource::LocalApic => "local apic",
+        }
+    }
+}
+
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        0 => TickSource::Pit,
+        _ => TickSource::LocalApic,
+    }
+}
+
+/// Makes `source` the only timer that drives the scheduler, the others keep running
+/// but their ticks are ignored
+pub fn set_tick_source(source: TickSource) {
+    TICK_SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// The number of times `source` has driven the scheduler
+pub fn driven_ticks(source: TickSource) -> u64 {
+    DRIVEN_TICKS[source as usize].load(Ordering::Relaxed)
+}
 
-pub fn init(bios_tables: &BiosTables) {
+/// Called by every timer interrupt, runs the scheduler if `source` is the primary tick
+/// source, returns whether it did. The time, the timers and the rest are kept by the boot
+/// CPU, the local APIC timers of the others only run their schedulers
+pub fn tick(source: TickSource, all_state: &mut InterruptAllSavedState) -> bool {
+    if tick_source() != source {
+        return false;
+    }
+    if cpu::cpu().id == 0 {
+        DRIVEN_TICKS[source as usize].fetch_add(1, Ordering::Relaxed);
+        // the injected input arrives here like a real interrupt would
+        io::input_inject::tick();
+        io::log_limit::tick();
+        time_page::sync();
+        timers::tick();
+    }
+    // before anything switches away from what was interrupted
+    profiler::sample(all_state);
+    // if killed, there is nothing to yield
+    scheduler::enforce_cpu_limit(all_state);
+    scheduler::yield_current_if_any(all_state);
+    true
+}
+
+/// Reads the RTC, starts the HPET and the time page, `time_page=on|off` of the cmdline
+/// decides about the time page instead of the TSC, see [`time_page`]
+pub fn init(cmdline: &str, bios_tables: &BiosTables) {
     let facp = bios_tables.rsdt.get_table::<Facp>();
 
-    let century_reg = facp.map(|facp| facp.century);
-    // TODO: use it later, and provide it to everyone who need it
-    let rtc_time = Rtc::new(century_reg).get_time();
-    println!("Time now: {rtc_time}: UTC");
+    // reduced hardware can say there is none, its ports would read garbage
+    let mut boot_realtime = 0;
+    if facp.is_none_or(Facp::has_cmos_rtc) {
+        let century_reg = facp.map(|facp| facp.century);
+        let rtc_time = Rtc::new(century_reg).get_time();
+        boot_realtime = rtc_time.unix_seconds() * NANOS_PER_SECOND;
+        println!("Time now: {rtc_time}: UTC");
+    } else {
+        println!("No CMOS RTC, the time is unknown");
+    }
+
+    // the local APIC timer drives the scheduler from now on, the HPET may need the PIT's
+    // interrupt line as well
+    pit::hand_off();
 
     let hpet = bios_tables
         .rsdt
@@ -27,4 +118,63 @@ pub fn init(bios_tables: &BiosTables) {
         .and_then(Hpet::initialize_from_bios_table)
         .map(|hpet| Arc::new(Mutex::new(hpet)));
     HPET_CLOCK.set(hpet).expect("clock already initialized");
+
+    // the monotonic time is `0` now
+    REALTIME_OFFSET.store(boot_realtime, Ordering::Relaxed);
+    time_page::init(cmdline, boot_realtime);
+}
+
+/// Monotonic time since the clock was initialized in nanoseconds,
+/// returns `0` if we don't have a clock
+pub fn uptime_nanos() -> u64 {
+    HPET_CLOCK
+        .try_get()
+        .and_then(|hpet| hpet.as_ref())
+        .map(|hpet| hpet.lock().current_time_nanos())
+        .unwrap_or(0)
+}
+
+/// The nanoseconds since boot of [`ClockId::Monotonic`], the same as the time page computes if
+/// there is one, otherwise [`uptime_nanos`]
+pub fn monotonic_nanos() -> u64 {
+    match time_page::page() {
+        Some(page) => page.read().monotonic_at(cpu::rdtsc()),
+        None => uptime_nanos(),
+    }
+}
+
+/// The nanoseconds since the Unix epoch of [`ClockId::Realtime`]
+pub fn realtime_nanos() -> u64 {
+    match time_page::page() {
+        Some(page) => page.read().realtime_at(cpu::rdtsc()),
+        None => uptime_nanos().wrapping_add(REALTIME_OFFSET.load(Ordering::Relaxed)),
+    }
+}
+
+pub fn clock_nanos(clock: ClockId) -> u64 {
+    match clock {
+        ClockId::Monotonic => monotonic_nanos(),
+        ClockId::Realtime => realtime_nanos(),
+    }
+}
+
+/// Makes the realtime `nanos` (since the Unix epoch) now, the monotonic time doesn't change
+pub fn set_realtime(nanos: u64) {
+    // both together, so the time page and the offset agree
+    cpu::cpu().push_cli();
+    let offset = nanos.wrapping_sub(monotonic_nanos());
+    REALTIME_OFFSET.store(offset, Ordering::Relaxed);
+    time_page::set_realtime_offset(offset);
+    cpu::cpu().pop_cli();
+}
+
+/// The same as [`uptime_nanos`], but `0` if the clock is being read, for the logs, which
+/// can be printed while it is
+pub fn try_uptime_nanos() -> u64 {
+    HPET_CLOCK
+        .try_get()
+        .and_then(|hpet| hpet.as_ref())
+        .and_then(|hpet| hpet.try_lock())
+        .map(|hpet| hpet.current_time_nanos())
+        .unwrap_or(0)
 }
@@ -48,6 +48,24 @@ impl fmt::Display for RtcTime {
     }
 }
 
+impl RtcTime {
+    /// The seconds since the Unix epoch, the RTC is in UTC
+    pub fn unix_seconds(&self) -> u64 {
+        // days from the civil date, with the years starting in March, so the leap day is last
+        let (year, month) = match self.month {
+            0..=2 => (self.year as i64 - 1, self.month as i64 + 9),
+            _ => (self.year as i64, self.month as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day_of_month as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = self.hours as i64 * 3600 + self.minutes as i64 * 60 + self.seconds as i64;
+        (days * 86_400 + seconds).max(0) as u64
+    }
+}
+
 impl Rtc {
     pub const fn new(century_reg: Option<u8>) -> Self {
         let century_reg = if let Some(century_reg) = century_reg {
@@ -63,10 +81,7 @@ impl Rtc {
     }
 
     fn read_register(&self, reg: u8) -> u8 {
-        unsafe {
-            cpu::io_out(consts::RTC_ADDRESS, reg);
-            cpu::io_in(consts::RTC_DATA)
-        }
+        read_cmos_register(reg)
     }
 
     fn is_updating(&self) -> bool {
@@ -135,3 +150,11 @@ impl Rtc {
         t
     }
 }
+
+/// Reads a register of the CMOS, the RTC registers and the ones the BIOS sets
+pub fn read_cmos_register(reg: u8) -> u8 {
+    unsafe {
+        cpu::io_out(consts::RTC_ADDRESS, reg);
+        cpu::io_in(consts::RTC_DATA)
+    }
+}
@@ -0,0 +1,285 @@
+//! The time page, mapped read-only in every process, so the clocks can be read without a
+//! syscall, see [`kernel_user_link::time`].
+//!
+//! It is only offered if the TSC is invariant (CPUID says it runs at the same rate in every
+//! power state), was calibrated by the PIT, and there is an HPET to sync it with.
+//! `time_page=on` on the cmdline offers it without the invariant TSC, which VMs rarely
+//! report, and `time_page=off` never does.
+//!
+//! Every timer tick moves the base of the page to the HPET time, but never back from what the
+//! page reads at that moment: if the TSC ran fast, the base stays and the rate is slowed a
+//! bit until the HPET catches up. The rate is measured against the HPET since the first sync,
+//! starting with the one of the PIT calibration.
+//!
+//! `/devices/time` has both clocks and the state of the page, writing `realtime <nanos>`
+//! sets the realtime, only the privileged processes can.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{string::String, sync::Arc};
+use kernel_user_link::time::{ClockId, TimePage, TimeSnapshot, TSC_MULT_SHIFT};
+
+use crate::{
+    cpu,
+    devices::{self, pit, Device},
+    fs::FileSystemError,
+    memory_management::{memory_layout::virtual2physical, physical_page_allocator},
+    process::scheduler::with_current_process,
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+use super::NANOS_PER_SECOND;
+
+const CPUID_FN_EXT_MAX: u32 = 0x8000_0000;
+const CPUID_FN_EXT_POWER: u32 = 0x8000_0007;
+const CPUID_EXT_POWER_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+// the HPET time to measure the TSC rate over before trusting it over the PIT calibration
+const MIN_MEASURE_NANOS: u64 = NANOS_PER_SECOND / 10;
+// a rate slowed by `1 / 2^SLEW_SHIFT` while the page is ahead of the HPET, ~0.02%
+const SLEW_SHIFT: u32 = 12;
+
+static TIME_PAGE: OnceLock<TimeKeeper> = OnceLock::new();
+static SYNCS: AtomicU64 = AtomicU64::new(0);
+
+struct TimeKeeper {
+    page: &'static TimePage,
+    physical: u64,
+    sync: Mutex<SyncState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncState {
+    // the first sync, the rate is measured from it
+    first_tsc: u64,
+    first_nanos: u64,
+    // from the PIT calibration
+    calibrated_mult: u64,
+}
+
+impl SyncState {
+    /// The next contents of the page, synced to HPET time `now` at `tsc`
+    fn next(&self, current: &TimeSnapshot, tsc: u64, now: u64) -> TimeSnapshot {
+        let page_now = current.monotonic_at(tsc);
+        let measured_nanos = now.saturating_sub(self.first_nanos);
+        let measured_tsc = tsc.wrapping_sub(self.first_tsc);
+        let mut tsc_mult = if measured_nanos >= MIN_MEASURE_NANOS && measured_tsc != 0 {
+            (((measured_nanos as u128) << TSC_MULT_SHIFT) / measured_tsc as u128) as u64
+        } else {
+            self.calibrated_mult
+        };
+        if page_now > now {
+            tsc_mult -= tsc_mult >> SLEW_SHIFT;
+        }
+        TimeSnapshot {
+            tsc_base: tsc,
+            monotonic_base: page_now.max(now),
+            tsc_mult,
+            realtime_offset: current.realtime_offset,
+        }
+    }
+}
+
+fn has_invariant_tsc() -> bool {
+    if cpu::cpuid!(CPUID_FN_EXT_MAX).eax < CPUID_FN_EXT_POWER {
+        return false;
+    }
+    let cpuid = cpu::cpuid!(CPUID_FN_EXT_POWER);
+    cpuid.edx & CPUID_EXT_POWER_EDX_INVARIANT_TSC != 0
+}
+
+/// The page, if it is offered
+pub fn page() -> Option<&'static TimePage> {
+    TIME_PAGE.try_get().map(|keeper| keeper.page)
+}
+
+/// The physical address of the page mapped in the processes, if it is offered
+pub fn physical_address() -> Option<u64> {
+    TIME_PAGE.try_get().map(|keeper| keeper.physical)
+}
+
+/// Called on every timer tick, moves the page to the HPET time
+pub fn sync() {
+    let Some(keeper) = TIME_PAGE.try_get() else {
+        return;
+    };
+    let sync = keeper.sync.lock();
+    let now = super::uptime_nanos();
+    let tsc = cpu::rdtsc();
+    keeper.page.write(&sync.next(&keeper.page.read(), tsc, now));
+    SYNCS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn set_realtime_offset(offset: u64) {
+    let Some(keeper) = TIME_PAGE.try_get() else {
+        return;
+    };
+    let _sync = keeper.sync.lock();
+    let mut snapshot = keeper.page.read();
+    snapshot.realtime_offset = offset;
+    keeper.page.write(&snapshot);
+}
+
+pub(super) fn init(cmdline: &str, realtime_offset: u64) {
+    devices::register_device(Arc::new(TimeDevice));
+    let tsc_hz = pit::tsc_hz();
+    let forced = cmdline.split_whitespace().find_map(|arg| match arg {
+        "time_page=on" => Some(true),
+        "time_page=off" => Some(false),
+        _ => None,
+    });
+    let reason = if tsc_hz == 0 {
+        Some("the TSC is not calibrated")
+    } else if !super::HPET_CLOCK.try_get().is_some_and(Option::is_some) {
+        Some("no HPET")
+    } else {
+        match forced {
+            Some(offered) => (!offered).then_some("`time_page=off`"),
+            None => (!has_invariant_tsc()).then_some("the TSC is not invariant"),
+        }
+    };
+    if let Some(reason) = reason {
+        println!("Time page: not offered, {reason}");
+        return;
+    }
+
+    // SAFETY: the page is never freed, and it is zeroed, which is a valid `TimePage`
+    let page = unsafe { &*(physical_page_allocator::alloc_zeroed() as *const TimePage) };
+    let tsc = cpu::rdtsc();
+    let now = super::uptime_nanos();
+    let calibrated_mult = ((NANOS_PER_SECOND as u128) << TSC_MULT_SHIFT) / tsc_hz as u128;
+    page.write(&TimeSnapshot {
+        tsc_base: tsc,
+        monotonic_base: now,
+        tsc_mult: calibrated_mult as u64,
+        realtime_offset,
+    });
+    let keeper = TimeKeeper {
+        page,
+        physical: virtual2physical(page as *const TimePage as usize) as u64,
+        sync: Mutex::new(SyncState {
+            first_tsc: tsc,
+            first_nanos: now,
+            calibrated_mult: calibrated_mult as u64,
+        }),
+    };
+    if TIME_PAGE.set(keeper).is_err() {
+        panic!("time page already initialized");
+    }
+    println!("Time page: offered, TSC at {} MHz", tsc_hz / 1_000_000);
+}
+
+/// `/devices/time`, the clocks and the state of the time page
+#[derive(Debug)]
+struct TimeDevice;
+
+impl Device for TimeDevice {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let mut info = String::new();
+        writeln!(
+            info,
+            "monotonic: {}",
+            super::clock_nanos(ClockId::Monotonic)
+        )
+        .unwrap();
+        writeln!(info, "realtime: {}", super::clock_nanos(ClockId::Realtime)).unwrap();
+        writeln!(info, "uptime: {}", super::uptime_nanos()).unwrap();
+        match page() {
+            Some(page) => {
+                let snapshot = page.read();
+                writeln!(info, "time page: yes").unwrap();
+                writeln!(info, "syncs: {}", SYNCS.load(Ordering::Relaxed)).unwrap();
+                writeln!(info, "tsc mult: {:#x}", snapshot.tsc_mult).unwrap();
+            }
+            None => writeln!(info, "time page: no").unwrap(),
+        }
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+
+    /// `realtime <nanos>` sets the realtime to the nanoseconds since the Unix epoch
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if !with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let nanos = core::str::from_utf8(buf)
+            .ok()
+            .and_then(|command| command.trim().strip_prefix("realtime "))
+            .and_then(|nanos| nanos.trim().parse::<u64>().ok())
+            .filter(|&nanos| nanos <= i64::MAX as u64)
+            .ok_or(FileSystemError::InvalidData)?;
+        super::set_realtime(nanos);
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Syncs a local page with made up times, and reads it in the middle of writes
+pub fn run_self_tests() {
+    println!("Running time page self tests...");
+
+    // 1 GHz, so a tick is a nanosecond
+    let one_ns = 1 << TSC_MULT_SHIFT;
+    let state = SyncState {
+        first_tsc: 1_000,
+        first_nanos: 0,
+        calibrated_mult: one_ns,
+    };
+    let page = TimePage::default();
+    page.write(&TimeSnapshot {
+        tsc_base: 1_000,
+        monotonic_base: 0,
+        tsc_mult: one_ns,
+        realtime_offset: 5 * NANOS_PER_SECOND,
+    });
+    let snapshot = page.read();
+    assert_eq!(snapshot.monotonic_at(1_500), 500);
+    assert_eq!(
+        snapshot.clock_at(ClockId::Realtime, 1_500),
+        5 * NANOS_PER_SECOND + 500
+    );
+
+    // the HPET behind the TSC: the page stays where it is, and slows down
+    let ahead = state.next(&snapshot, 11_000, 9_000);
+    assert_eq!(ahead.monotonic_base, 10_000);
+    assert!(ahead.tsc_mult < one_ns);
+    assert_eq!(ahead.realtime_offset, snapshot.realtime_offset);
+    // the HPET ahead: it jumps to it
+    let behind = state.next(&ahead, 21_000, 30_000);
+    assert_eq!(behind.monotonic_base, 30_000);
+    // measured over long enough, the TSC at a tick every 2 nanoseconds
+    let measured = state.next(&behind, 1_000 + MIN_MEASURE_NANOS, 2 * MIN_MEASURE_NANOS);
+    assert_eq!(
+        measured.tsc_mult,
+        2 * one_ns,
+        "time page self test: the rate was not measured"
+    );
+    // never back, with any sync
+    let mut current = snapshot;
+    let mut last = 0;
+    for step in 1..1000u64 {
+        let tsc = 1_000 + step * 997;
+        // the HPET wanders around the TSC
+        let now = (step * 997 + (step * 7919) % 3000).saturating_sub(1500);
+        assert!(current.monotonic_at(tsc) >= last);
+        current = state.next(&current, tsc, now);
+        last = current.monotonic_base;
+    }
+
+    // a read while a write is not done is refused
+    let sequence = page.sequence.load(Ordering::Relaxed);
+    page.sequence.store(sequence + 1, Ordering::Relaxed);
+    assert!(
+        page.try_read().is_none(),
+        "time page self test: read during a write"
+    );
+    page.sequence.store(sequence + 2, Ordering::Relaxed);
+    assert!(page.try_read().is_some());
+
+    println!("Time page self tests passed");
+}
@@ -0,0 +1,139 @@
+//! Events, a counter that the kernel (or userspace) signals, and a file that reads it.
+//!
+//! Reading the file gives the counter as `u64` and resets it, blocking while its `0`, in
+//! non-blocking mode the read gives `0` bytes instead, which is how the event is polled.
+//! Writing a `u64` adds it to the counter.
+//!
+//! The kernel side is a [`KernelEvent`], which only holds a weak reference, so a subsystem
+//! can keep it after the file is closed, and signaling it then does nothing.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+};
+use kernel_user_link::file::BlockingMode;
+
+use crate::fs::{self, FileAttributes, FileSystemError, INode};
+
+use super::Device;
+
+// the files of an event and its clones share the same inode id
+static NEXT_EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+struct Event {
+    counter: AtomicU64,
+}
+
+/// The side of an event the kernel holds to signal it, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct KernelEvent {
+    event: Weak<Event>,
+}
+
+impl KernelEvent {
+    /// Adds `1` to the counter, does nothing if all the files of the event are closed.
+    ///
+    /// This doesn't allocate or take any lock, so its safe from interrupts and the allocators
+    pub fn signal(&self) {
+        // this can't be the last strong reference, since the files holding the others can't be
+        // dropped while we are here, so this never frees the event
+        if let Some(event) = self.event.upgrade() {
+            event.counter.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// `false` when all the files of the event are closed
+    pub fn is_open(&self) -> bool {
+        self.event.strong_count() != 0
+    }
+}
+
+#[derive(Debug)]
+struct EventDevice {
+    event: Arc<Event>,
+}
+
+impl Device for EventDevice {
+    fn name(&self) -> &str {
+        "event"
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // the size must fit the counter, `UnalignedAccess` is reported as an invalid size
+        let buf = buf.get_mut(..8).ok_or(FileSystemError::UnalignedAccess)?;
+        let counter = self.event.counter.swap(0, Ordering::AcqRel);
+        if counter == 0 {
+            return Ok(0);
+        }
+        buf.copy_from_slice(&counter.to_le_bytes());
+        Ok(8)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let value: [u8; 8] = buf
+            .try_into()
+            .map_err(|_| FileSystemError::UnalignedAccess)?;
+        self.event
+            .counter
+            .fetch_add(u64::from_le_bytes(value), Ordering::AcqRel);
+        Ok(8)
+    }
+}
+
+/// Creates an event with a counter of `0`, the file blocks on reads by default
+pub fn create_event() -> (fs::File, KernelEvent) {
+    let event = Arc::new(Event {
+        counter: AtomicU64::new(0),
+    });
+    let kernel_event = KernelEvent {
+        event: Arc::downgrade(&event),
+    };
+
+    let inode = INode::new_device(
+        String::from("event"),
+        FileAttributes::EMPTY,
+        Some(Arc::new(EventDevice { event })),
+    )
+    .with_id(NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed));
+    let file = fs::inode_to_file(inode, fs::empty_filesystem(), 0, BlockingMode::Block(1));
+
+    (file, kernel_event)
+}
+
+/// Checks the counter through the file, and that the kernel side outlives the file safely
+pub fn run_self_tests() {
+    let (mut file, kernel_event) = create_event();
+    file.set_blocking(BlockingMode::None);
+    let mut buf = [0; 8];
+
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    kernel_event.signal();
+    kernel_event.signal();
+    assert_eq!(file.read(&mut buf).unwrap(), 8);
+    assert_eq!(u64::from_le_bytes(buf), 2);
+    // reading resets it
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+    assert_eq!(file.write(&5u64.to_le_bytes()).unwrap(), 8);
+    kernel_event.signal();
+    assert_eq!(file.read(&mut buf).unwrap(), 8);
+    assert_eq!(u64::from_le_bytes(buf), 6);
+    assert!(matches!(
+        file.read(&mut buf[..4]),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+
+    // the event lives as long as any of its files
+    let clone = file.clone_inherit();
+    drop(file);
+    assert!(kernel_event.is_open());
+    kernel_event.signal();
+    drop(clone);
+    assert!(!kernel_event.is_open());
+    // must not touch the freed event
+    kernel_event.signal();
+    kernel_event.clone().signal();
+}
@@ -0,0 +1,420 @@
+//! QEMU firmware configuration device (fw_cfg)
+//!
+//! QEMU can pass named blobs to the guest with `-fw_cfg name=opt/<name>,file=<path>`,
+//! these are exposed as read-only files under `/devices/fw_cfg`.
+//!
+//! All the fields of the protocol (the file directory and the DMA access structure) are
+//! big-endian, except for the selector and feature bitmap which are little-endian.
+
+use core::{
+    mem,
+    sync::atomic::{self, AtomicBool, Ordering},
+};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    cpu,
+    fs::{self, FileAttributes, FileSystem, FileSystemError, INode},
+    memory_management::{
+        memory_layout::{virtual2physical, PAGE_4K},
+        physical_page_allocator,
+    },
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+static FW_CFG: OnceLock<FwCfg> = OnceLock::new();
+
+const PORT_SELECTOR: u16 = 0x510;
+const PORT_DATA: u16 = 0x511;
+const PORT_DMA_ADDRESS_HIGH: u16 = 0x514;
+const PORT_DMA_ADDRESS_LOW: u16 = 0x518;
+
+const SELECTOR_SIGNATURE: u16 = 0x0000;
+const SELECTOR_FEATURES: u16 = 0x0001;
+const SELECTOR_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+const FEATURE_DMA: u32 = 1 << 1;
+
+const DMA_CTL_ERROR: u32 = 1 << 0;
+const DMA_CTL_READ: u32 = 1 << 1;
+const DMA_CTL_SKIP: u32 = 1 << 2;
+const DMA_CTL_SELECT: u32 = 1 << 3;
+
+const FILE_NAME_LEN: usize = 56;
+
+/// The file (by its fw_cfg name) that can be used to configure tests
+pub const TEST_CONFIG_FILE: &str = "opt/org.os.test-config";
+
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+const DMA_BUFFER_OFFSET: usize = mem::size_of::<DmaAccess>().next_multiple_of(64);
+const DMA_BUFFER_SIZE: usize = PAGE_4K - DMA_BUFFER_OFFSET;
+
+struct FwCfgIo {
+    // a physical page holding the `DmaAccess` structure followed by the transfer buffer,
+    // `None` if DMA is not supported
+    dma_page: Option<*mut u8>,
+}
+
+// SAFETY: the DMA page is only accessed inside the mutex
+unsafe impl Send for FwCfgIo {}
+
+impl FwCfgIo {
+    fn select(&self, selector: u16) {
+        unsafe { cpu::io_out(PORT_SELECTOR, selector) };
+    }
+
+    fn read_port(&self, buf: &mut [u8]) {
+        for b in buf {
+            *b = unsafe { cpu::io_in(PORT_DATA) };
+        }
+    }
+
+    /// Runs one DMA operation, and waits until its done
+    fn dma(&self, page: *mut u8, control: u32, length: u32) -> Result<(), FileSystemError> {
+        let access = page as *mut DmaAccess;
+        let buffer_physical = virtual2physical(page as usize + DMA_BUFFER_OFFSET);
+        unsafe {
+            access.write_volatile(DmaAccess {
+                control: control.to_be(),
+                length: length.to_be(),
+                address: (buffer_physical as u64).to_be(),
+            })
+        };
+        // make sure the structure is written before starting
+        atomic::fence(Ordering::SeqCst);
+
+        let access_physical = virtual2physical(access as usize) as u64;
+        unsafe {
+            cpu::io_out(
+                PORT_DMA_ADDRESS_HIGH,
+                ((access_physical >> 32) as u32).to_be(),
+            );
+            // writing the low part starts the transfer
+            cpu::io_out(PORT_DMA_ADDRESS_LOW, (access_physical as u32).to_be());
+        }
+
+        loop {
+            let control =
+                u32::from_be(unsafe { (&(*access).control as *const u32).read_volatile() });
+            if control & DMA_CTL_ERROR != 0 {
+                return Err(FileSystemError::InvalidData);
+            }
+            if control == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        atomic::fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Read from the item `selector` starting at `offset`
+    fn read(&self, selector: u16, offset: u32, buf: &mut [u8]) -> Result<(), FileSystemError> {
+        match self.dma_page {
+            Some(page) => {
+                let select = (selector as u32) << 16 | DMA_CTL_SELECT;
+                // selecting resets the offset of the item, so skip to where we want
+                self.dma(page, select | DMA_CTL_SKIP, offset)?;
+                for chunk in buf.chunks_mut(DMA_BUFFER_SIZE) {
+                    self.dma(page, DMA_CTL_READ, chunk.len() as u32)?;
+                    let buffer = unsafe {
+                        core::slice::from_raw_parts(page.add(DMA_BUFFER_OFFSET), chunk.len())
+                    };
+                    chunk.copy_from_slice(buffer);
+                }
+            }
+            None => {
+                self.select(selector);
+                for _ in 0..offset {
+                    let _: u8 = unsafe { cpu::io_in(PORT_DATA) };
+                }
+                self.read_port(buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Directory,
+    File { selector: u16, size: u32 },
+}
+
+/// The fw_cfg names are paths, so we build a tree from them. Each node is identified
+/// by its index, which is stored in the `start_cluster` of the inode
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    kind: NodeKind,
+}
+
+struct FwCfg {
+    io: Mutex<FwCfgIo>,
+    // the first node is the root
+    nodes: Vec<Node>,
+}
+
+impl FwCfg {
+    fn probe() -> Option<Self> {
+        let mut io = FwCfgIo { dma_page: None };
+
+        let mut signature = [0; 4];
+        io.select(SELECTOR_SIGNATURE);
+        io.read_port(&mut signature);
+        if &signature != SIGNATURE {
+            return None;
+        }
+
+        let mut features = [0; 4];
+        io.select(SELECTOR_FEATURES);
+        io.read_port(&mut features);
+        // unlike the rest, this is little-endian
+        let features = u32::from_le_bytes(features);
+        if features & FEATURE_DMA != 0 {
+            // SAFETY: this is only used by the DMA transfers and never freed
+            io.dma_page = Some(unsafe { physical_page_allocator::alloc_zeroed() });
+        }
+
+        let mut count = [0; 4];
+        io.read(SELECTOR_FILE_DIR, 0, &mut count).ok()?;
+        let count = u32::from_be_bytes(count);
+
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut entry = [0u8; 8 + FILE_NAME_LEN];
+        for i in 0..count {
+            io.read(SELECTOR_FILE_DIR, 4 + i * entry.len() as u32, &mut entry)
+                .ok()?;
+            let size = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+            let selector = u16::from_be_bytes(entry[4..6].try_into().unwrap());
+            let name = &entry[8..];
+            let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            let Ok(name) = core::str::from_utf8(&name[..name_len]) else {
+                println!(
+                    "[fw_cfg] WARNING: skipping file with invalid name, selector={selector:#x}"
+                );
+                continue;
+            };
+            entries.push((String::from(name), selector, size));
+        }
+
+        let mut s = Self {
+            io: Mutex::new(io),
+            nodes: Vec::new(),
+        };
+        s.nodes.push(Node {
+            name: String::new(),
+            parent: 0,
+            kind: NodeKind::Directory,
+        });
+        for (name, selector, size) in entries {
+            s.add_file(&name, selector, size);
+        }
+
+        Some(s)
+    }
+
+    fn add_file(&mut self, path: &str, selector: u16, size: u32) {
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        let mut parent = 0;
+        while let Some(component) = components.next() {
+            let is_last = components.peek().is_none();
+            if is_last {
+                self.nodes.push(Node {
+                    name: String::from(component),
+                    parent,
+                    kind: NodeKind::File { selector, size },
+                });
+                return;
+            }
+            parent = match self.find_child(parent, component) {
+                Some(index) => index,
+                None => {
+                    self.nodes.push(Node {
+                        name: String::from(component),
+                        parent,
+                        kind: NodeKind::Directory,
+                    });
+                    self.nodes.len() - 1
+                }
+            };
+        }
+    }
+
+    fn find_child(&self, parent: usize, name: &str) -> Option<usize> {
+        // the root is its own parent, so skip it
+        self.nodes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, node)| node.parent == parent && node.name == name)
+            .map(|(i, _)| i)
+    }
+
+    fn find_path(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(0, |parent, component| self.find_child(parent, component))
+    }
+
+    fn node_inode(&self, index: usize) -> INode {
+        let node = &self.nodes[index];
+        match node.kind {
+            NodeKind::Directory => INode::new_file(
+                node.name.clone(),
+                FileAttributes::DIRECTORY | FileAttributes::READ_ONLY,
+                index as u32,
+                0,
+            ),
+            NodeKind::File { size, .. } => INode::new_file(
+                node.name.clone(),
+                FileAttributes::READ_ONLY,
+                index as u32,
+                size,
+            ),
+        }
+    }
+
+    fn list_dir(&self, index: usize) -> Result<Vec<INode>, FileSystemError> {
+        match self.nodes.get(index).map(|node| &node.kind) {
+            Some(NodeKind::Directory) => Ok((1..self.nodes.len())
+                .filter(|&i| self.nodes[i].parent == index)
+                .map(|i| self.node_inode(i))
+                .collect()),
+            Some(NodeKind::File { .. }) => Err(FileSystemError::IsNotDirectory),
+            None => Err(FileSystemError::FileNotFound),
+        }
+    }
+
+    /// Reads the whole content of the file with the fw_cfg name `path`
+    fn read_whole(&self, path: &str) -> Option<Vec<u8>> {
+        let index = self.find_path(path)?;
+        let NodeKind::File { selector, size } = self.nodes[index].kind else {
+            return None;
+        };
+        let mut content = alloc::vec![0; size as usize];
+        self.io.lock().read(selector, 0, &mut content).ok()?;
+        Some(content)
+    }
+}
+
+/// A read-only filesystem exposing the fw_cfg files, mounted at `/devices/fw_cfg`
+struct FwCfgFileSystem {
+    disconnected: AtomicBool,
+}
+
+impl FileSystem for FwCfgFileSystem {
+    fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let fw_cfg = FW_CFG.get();
+        let index = fw_cfg
+            .find_path(path)
+            .ok_or(FileSystemError::FileNotFound)?;
+        fw_cfg.list_dir(index)
+    }
+
+    fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        FW_CFG.get().list_dir(inode.start_cluster() as usize)
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let fw_cfg = FW_CFG.get();
+        let node = fw_cfg
+            .nodes
+            .get(inode.start_cluster() as usize)
+            .ok_or(FileSystemError::FileNotFound)?;
+        let NodeKind::File { selector, size } = node.kind else {
+            return Err(FileSystemError::IsDirectory);
+        };
+
+        if position >= size as u64 {
+            return Ok(0);
+        }
+        let position = position as u32;
+        let to_read = ((size - position) as usize).min(buf.len());
+        fw_cfg
+            .io
+            .lock()
+            .read(selector, position, &mut buf[..to_read])?;
+        Ok(to_read as u64)
+    }
+
+    fn write_file(
+        &self,
+        _inode: &INode,
+        _position: u64,
+        _buf: &[u8],
+    ) -> Result<u64, FileSystemError> {
+        Err(FileSystemError::WriteNotSupported)
+    }
+
+    fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::Release);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+}
+
+/// Detects the fw_cfg device and mounts its files at `/devices/fw_cfg`.
+///
+/// This only needs the heap, so it can be used early in the boot
+pub fn init() {
+    let Some(fw_cfg) = FwCfg::probe() else {
+        return;
+    };
+    println!(
+        "[fw_cfg] found {} files, dma={}",
+        fw_cfg
+            .nodes
+            .iter()
+            .filter(|node| matches!(node.kind, NodeKind::File { .. }))
+            .count(),
+        fw_cfg.io.lock().dma_page.is_some()
+    );
+    if FW_CFG.set(fw_cfg).is_err() {
+        panic!("fw_cfg already initialized");
+    }
+    fs::mount(
+        "/devices/fw_cfg",
+        Arc::new(FwCfgFileSystem {
+            disconnected: AtomicBool::new(false),
+        }),
+        "fw_cfg",
+        "fw_cfg",
+    );
+}
+
+/// The content of [`TEST_CONFIG_FILE`] if present
+pub fn test_config() -> Option<String> {
+    let content = read_file(TEST_CONFIG_FILE)?;
+    String::from_utf8(content).ok()
+}
+
+/// The content of the file with the fw_cfg name `path`, if there is a fw_cfg device
+pub fn read_file(path: &str) -> Option<Vec<u8>> {
+    FW_CFG.try_get()?.read_whole(path)
+}
@@ -0,0 +1,394 @@
+//! Generated files, the text of the state of the kernel rendered a few lines at a time.
+//!
+//! Instead of rendering all of it into a `String` on every read, a device gives a
+//! [`Generator`], and each open file keeps its own with the [`Cursor`] where it stopped, so
+//! reading at increasing offsets continues from there, and only a chunk of the file is in
+//! memory at a time.
+//!
+//! Every chunk is rendered from the state at the time of its read, so the chunks of a file can
+//! be from different times, i.e. a process that exited after the first chunk is missing from the
+//! next ones. But a line is always in a single chunk, so its never torn.
+
+use core::fmt;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use crate::memory_management::{kernel_heap_allocator::ALLOCATOR, memory_layout::MemSize};
+
+/// The space a chunk always has, longer lines are cut to it
+pub const MAX_LINE: usize = 256;
+
+/// Where the next chunk starts, the meaning of the fields is up to the generator,
+/// i.e. the index of a process, and the line of it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub item: usize,
+    pub field: usize,
+}
+
+pub trait Generator: Send + fmt::Debug {
+    /// Renders the lines after `cursor` into `out`, and moves `cursor` after them.
+    ///
+    /// `out` is at least [`MAX_LINE`] long, only whole lines are written, and at least one while
+    /// there are more, `0` is the end of the file
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize;
+}
+
+/// Writes into a slice until its full, and remembers that it overflowed
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let to_write = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + to_write].copy_from_slice(&s.as_bytes()[..to_write]);
+        self.len += to_write;
+        if to_write < s.len() {
+            self.overflow = true;
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+/// The lines of a chunk, in the `out` of [`Generator::render_chunk`]
+pub struct Chunk<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Chunk<'a> {
+    pub fn new(out: &'a mut [u8]) -> Self {
+        Self { out, len: 0 }
+    }
+
+    /// The bytes of the lines added so far
+    pub fn written(&self) -> usize {
+        self.len
+    }
+
+    /// Adds a line, without its `\n`, returns `false` if it doesn't fit, then nothing is added.
+    ///
+    /// A line is cut to [`MAX_LINE`] with its `\n`, so the first line of a chunk always fits
+    pub fn line(&mut self, args: fmt::Arguments) -> bool {
+        let rest = &mut self.out[self.len..];
+        let cap = rest.len().min(MAX_LINE);
+        let mut writer = SliceWriter {
+            buf: &mut rest[..cap],
+            len: 0,
+            overflow: false,
+        };
+        let _ = fmt::write(&mut writer, args);
+        let (len, overflow) = (writer.len, writer.overflow);
+        self.commit(len, overflow)
+    }
+
+    /// Same as [`Chunk::line`], for a line that is already rendered
+    pub fn line_bytes(&mut self, line: &[u8]) -> bool {
+        let rest = &mut self.out[self.len..];
+        let len = line.len().min(rest.len()).min(MAX_LINE);
+        rest[..len].copy_from_slice(&line[..len]);
+        self.commit(len, len < line.len())
+    }
+
+    // `len` bytes of the line are after the end, `overflow` if there was more of it
+    fn commit(&mut self, len: usize, overflow: bool) -> bool {
+        let rest = self.out.len() - self.len;
+        // cut the same way in any chunk, not by the space that is left
+        let len = if overflow {
+            if rest < MAX_LINE {
+                return false;
+            }
+            MAX_LINE - 1
+        } else {
+            len.min(MAX_LINE - 1)
+        };
+        if len + 1 > rest {
+            return false;
+        }
+        self.out[self.len + len] = b'\n';
+        self.len += len + 1;
+        true
+    }
+}
+
+/// Collects the lines of a [`fmt::Display`] into a chunk, skipping the first `skip` lines
+struct LineSplitter<'a, 'b> {
+    chunk: &'b mut Chunk<'a>,
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    skip: usize,
+    // the lines that were added to the chunk
+    added: usize,
+}
+
+impl LineSplitter<'_, '_> {
+    fn end_line(&mut self) -> fmt::Result {
+        if self.skip > 0 {
+            self.skip -= 1;
+        } else if self.chunk.line_bytes(&self.line[..self.line_len]) {
+            self.added += 1;
+        } else {
+            // stop rendering the rest
+            return Err(fmt::Error);
+        }
+        self.line_len = 0;
+        Ok(())
+    }
+}
+
+impl fmt::Write for LineSplitter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                self.end_line()?;
+            } else if self.line_len < MAX_LINE {
+                self.line[self.line_len] = byte;
+                self.line_len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the lines of `document` from the line `cursor.field`, for files that are a single
+/// [`fmt::Display`], its rendered again for every chunk, but without keeping it
+pub fn render_display_lines(
+    document: &dyn fmt::Display,
+    cursor: &mut Cursor,
+    out: &mut [u8],
+) -> usize {
+    render_display_part(document, &mut cursor.field, out).0
+}
+
+/// Renders the lines of `document` from the line `line` into `out`, and moves `line` after
+/// them, for files made of many [`fmt::Display`]s. Returns the bytes written, and whether
+/// the end of `document` was reached, if not, `out` is full
+pub fn render_display_part(
+    document: &dyn fmt::Display,
+    line: &mut usize,
+    out: &mut [u8],
+) -> (usize, bool) {
+    let mut chunk = Chunk::new(out);
+    let mut splitter = LineSplitter {
+        chunk: &mut chunk,
+        line: [0; MAX_LINE],
+        line_len: 0,
+        skip: *line,
+        added: 0,
+    };
+    let mut done = fmt::write(&mut splitter, format_args!("{document}")).is_ok();
+    // the last line without a `\n`
+    if done && splitter.line_len > 0 {
+        done = splitter.end_line().is_ok();
+    }
+    *line += splitter.added;
+    (chunk.written(), done)
+}
+
+/// The state of a generated file for an open file, its generator and the part of the last
+/// chunk that was not read yet
+#[derive(Debug)]
+pub struct GeneratedFile {
+    generator: Box<dyn Generator>,
+    cursor: Cursor,
+    // reads smaller than `MAX_LINE` are rendered here, and given from here
+    pending: Vec<u8>,
+    pending_pos: usize,
+    // the offset in the file of the next byte, reading at another one starts over
+    offset: u64,
+}
+
+impl GeneratedFile {
+    pub fn new(generator: Box<dyn Generator>) -> Self {
+        Self {
+            generator,
+            cursor: Cursor::default(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            offset: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.cursor = Cursor::default();
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.offset = 0;
+    }
+
+    // renders the next chunk into `pending`, returns `false` at the end
+    fn render_pending(&mut self) -> bool {
+        self.pending.resize(MAX_LINE, 0);
+        let len = self
+            .generator
+            .render_chunk(&mut self.cursor, &mut self.pending);
+        self.pending.truncate(len);
+        self.pending_pos = 0;
+        len != 0
+    }
+
+    /// Reads at `offset`, the next read at the offset after this one continues from the same
+    /// cursor, any other starts rendering again from the start
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> u64 {
+        if offset != self.offset {
+            if offset < self.offset {
+                self.restart();
+            }
+            // skip until `offset`
+            while self.offset < offset {
+                if self.pending_pos == self.pending.len() && !self.render_pending() {
+                    return 0;
+                }
+                let skip = (self.pending.len() - self.pending_pos).min((offset - self.offset) as _);
+                self.pending_pos += skip;
+                self.offset += skip as u64;
+            }
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            let rest = &mut buf[read..];
+            if self.pending_pos < self.pending.len() {
+                let len = rest.len().min(self.pending.len() - self.pending_pos);
+                rest[..len].copy_from_slice(&self.pending[self.pending_pos..][..len]);
+                self.pending_pos += len;
+                read += len;
+            } else if rest.len() >= MAX_LINE {
+                let len = self.generator.render_chunk(&mut self.cursor, rest);
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            } else if !self.render_pending() {
+                break;
+            }
+        }
+        self.offset += read as u64;
+        read as u64
+    }
+}
+
+/// Reads `generator` at `offset` from the start, for reads that are not through an open file,
+/// see [`Device::read`](super::Device::read)
+pub fn read_at(generator: Box<dyn Generator>, offset: u64, buf: &mut [u8]) -> u64 {
+    GeneratedFile::new(generator).read(offset, buf)
+}
+
+/// A file of `0..lines`, with a line that is cut, for the self tests
+#[derive(Debug)]
+struct SelfTestGenerator {
+    lines: usize,
+}
+
+impl Generator for SelfTestGenerator {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let mut chunk = Chunk::new(out);
+        while cursor.item < self.lines {
+            let i = cursor.item;
+            let fits = if i == 3 {
+                chunk.line(format_args!("{:x<1$}", "", MAX_LINE * 2))
+            } else {
+                chunk.line(format_args!("{i} {}", MemSize(i as u64 * 4096)))
+            };
+            if !fits {
+                break;
+            }
+            cursor.item += 1;
+        }
+        chunk.written()
+    }
+}
+
+/// Renders all of the file with a single large buffer
+fn render_whole(generator: Box<dyn Generator>) -> Vec<u8> {
+    let mut file = GeneratedFile::new(generator);
+    let mut out = Vec::new();
+    let mut buf = vec![0; 0x1000];
+    loop {
+        let read = file.read(out.len() as u64, &mut buf);
+        if read == 0 {
+            break out;
+        }
+        out.extend_from_slice(&buf[..read as usize]);
+    }
+}
+
+/// Reads `make()` with 64 bytes at a time and checks that it gives the same text as one read,
+/// and that the chunks only need a bounded heap.
+///
+/// The content must not change while this runs
+pub fn check_chunked(name: &str, make: &dyn Fn() -> Box<dyn Generator>) {
+    const MAX_HEAP: usize = 4 * MAX_LINE;
+
+    let whole = render_whole(make());
+    let mut chunked = Vec::with_capacity(whole.len());
+    let mut file = GeneratedFile::new(make());
+    let mut buf = [0; 64];
+
+    let start = ALLOCATOR.stats().allocated;
+    let mut peak = 0;
+    loop {
+        ALLOCATOR.reset_peak();
+        let read = file.read(chunked.len() as u64, &mut buf) as usize;
+        peak = peak.max(ALLOCATOR.peak().saturating_sub(start));
+        if read == 0 {
+            break;
+        }
+        // `chunked` has the capacity already, so it doesn't count
+        chunked.extend_from_slice(&buf[..read]);
+    }
+    assert!(
+        chunked == whole,
+        "generated self test: {name} read in chunks is not the same as in one read"
+    );
+    assert!(
+        whole.is_empty() || whole.last() == Some(&b'\n'),
+        "generated self test: {name} doesn't end with a newline"
+    );
+    assert!(
+        peak <= MAX_HEAP,
+        "generated self test: {name} used {peak} bytes of heap for {} bytes",
+        whole.len()
+    );
+
+    // reading again from the start, and from the middle of a line
+    let middle = whole.len() / 2;
+    for offset in [0, middle, whole.len()] {
+        let read = file.read(offset as u64, &mut buf) as usize;
+        let expected = (whole.len() - offset).min(buf.len());
+        assert_eq!(read, expected, "generated self test: {name} at {offset}");
+        assert!(buf[..read] == whole[offset..offset + read]);
+    }
+}
+
+pub fn run_self_tests() {
+    check_chunked("selftest", &|| Box::new(SelfTestGenerator { lines: 2000 }));
+
+    let whole = render_whole(Box::new(SelfTestGenerator { lines: 5 }));
+    let cut = whole.split(|&b| b == b'\n').nth(3).unwrap();
+    assert_eq!(
+        cut.len(),
+        MAX_LINE - 1,
+        "generated self test: long line not cut"
+    );
+
+    // the `Display` files, with lines in between the writes
+    let mut cursor = Cursor::default();
+    let mut out = [0; MAX_LINE];
+    let len = render_display_lines(&format_args!("a\nb{}\n\nc", 1), &mut cursor, &mut out);
+    assert_eq!(&out[..len], b"a\nb1\n\nc\n");
+    assert_eq!(cursor.field, 4);
+    let len = render_display_lines(&format_args!("a\nb{}\n\nc", 1), &mut cursor, &mut out);
+    assert_eq!(len, 0);
+
+    // a part that doesn't fit is continued in the next chunk
+    let mut line = 0;
+    let (len, done) = render_display_part(&format_args!("a\nb"), &mut line, &mut out[..3]);
+    assert_eq!((&out[..len], done, line), (&b"a\n"[..], false, 1));
+    let (len, done) = render_display_part(&format_args!("a\nb"), &mut line, &mut out);
+    assert_eq!((&out[..len], done, line), (&b"b\n"[..], true, 2));
+}
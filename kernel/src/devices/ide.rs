@@ -1,50 +1,120 @@
-use core::{fmt, hint, mem, sync::atomic::AtomicBool};
+use core::{
+    fmt, hint, mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
 
-use alloc::sync::Arc;
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use kernel_core::sector::Lba;
 
 use crate::{
     cpu::{
         self,
         idt::{BasicInterruptHandler, InterruptStackFrame64},
         interrupts::apic,
+        irq_off,
+        softirq::{self, SoftIrq},
+    },
+    devices::{
+        self,
+        block::{BlockDevice, BlockDeviceFile, StorageErrorKind},
+        clock,
+        generated::{self, Cursor, Generator},
+        Device, WeakDevice,
     },
     memory_management::memory_layout::MemSize,
     sync::spin::mutex::Mutex,
 };
 
-use super::pci::{self, PciDevice, PciDeviceConfig, PropeExtra};
+use super::pci::{self, PciDevice, PciDeviceConfig, PciDeviceType, PropeExtra};
 
-static mut IDE_DEVICES: [Option<Arc<IdeDevice>>; 4] = [None, None, None, None];
+// the device, and its `/devices/ide<n>` file, emptied when the device is removed
+type IdeSlot = Option<(Arc<IdeDevice>, Arc<BlockDeviceFile>)>;
+// (controller, channel, drive)
+type ProbedKey = (usize, u64, u64);
+
+static IDE_DEVICES: Mutex<[IdeSlot; 4]> = Mutex::new([None, None, None, None]);
+/// The IDE controllers found by the PCI probe, their channels are probed by [`probe_channel`]
+static CONTROLLERS: Mutex<Vec<PciDeviceConfig>> = Mutex::new(Vec::new());
+/// The devices found by [`probe_channel`], until [`register_probed`]
+static PROBED: Mutex<Vec<(ProbedKey, IdeDevice)>> = Mutex::new(Vec::new());
 static INTERRUPTS_SETUP: AtomicBool = AtomicBool::new(false);
+static NATIVE_INTERRUPT_SETUP: AtomicBool = AtomicBool::new(false);
+// the legacy interrupts that came since the last `complete_interrupted`
+static INTERRUPTED: AtomicU8 = AtomicU8::new(0);
+const INTERRUPTED_PRIMARY: u8 = 1 << 0;
+const INTERRUPTED_SECONDARY: u8 = 1 << 1;
+/// The command blocks of the channels running a command, the two drives of a channel share
+/// its registers, see [`ChannelClaim`]
+static BUSY_CHANNELS: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+
+/// Keeps `pci_device` if it's an IDE controller, for [`probe_channel`]. Its drives are not
+/// probed here, the empty ones take long to time out, so the PCI probe doesn't wait for them
+pub fn try_add_controller(pci_device: &PciDeviceConfig) -> bool {
+    if !matches!(
+        pci_device.device_type,
+        PciDeviceType::MassStorageController(0x1, ..)
+    ) {
+        return false;
+    }
+    CONTROLLERS.lock().push(pci_device.clone());
+    true
+}
 
-pub fn try_register_ide_device(pci_device: &PciDeviceConfig) -> bool {
-    let mut found_device = false;
-    for i in 0..2 {
-        // i = 0 => primary
-        // i = 1 => secondary
-        for j in 0..2 {
-            // j = 0 => master
-            // j = 1 => slave
-            let extra = PropeExtra { args: [i, j, 0, 0] };
-            let Some(ide_device) = IdeDevice::probe_init(pci_device, extra) else {
-                continue;
+/// Probes the two drives of `channel` (`0` for the primary, `1` for the secondary) of all the
+/// controllers, fails if none of them is there. The channels can be probed at the same time,
+/// the devices are only registered by [`register_probed`]
+pub fn probe_channel(channel: u64) -> Result<(), String> {
+    let controllers = CONTROLLERS.lock().clone();
+    let mut found = 0;
+    for (index, controller) in controllers.iter().enumerate() {
+        // 0 => master, 1 => slave
+        for drive in 0..2 {
+            let extra = PropeExtra {
+                args: [channel, drive, 0, 0],
             };
-
-            // SAFETY: we are muting only to add elements, and we are not accessing the old elements or changing thems
-            let ide_devices = unsafe { &mut IDE_DEVICES };
-            let slot = ide_devices.iter_mut().find(|x| x.is_none());
-
-            if let Some(slot) = slot {
-                // must be done after initializing the heap, i.e. after virtual memory
-                *slot = Some(Arc::new(ide_device));
-                found_device = true;
-            } else {
-                panic!("No more IDE devices can be registered!");
+            if let Some(ide_device) = IdeDevice::probe_init(controller, extra) {
+                PROBED.lock().push(((index, channel, drive), ide_device));
+                found += 1;
             }
         }
     }
+    if found == 0 {
+        return Err(format!(
+            "no device on the {} channel of {} controllers",
+            if channel == 0 { "primary" } else { "secondary" },
+            controllers.len()
+        ));
+    }
+    Ok(())
+}
 
-    found_device
+/// Registers the devices found by [`probe_channel`] as `/devices/ide<n>`, in the order of
+/// their controller, channel and drive, so the names don't depend on which probe was first
+pub fn register_probed() {
+    let mut probed = mem::take(&mut *PROBED.lock());
+    probed.sort_by_key(|&(key, _)| key);
+    for (_, ide_device) in probed {
+        let slot_index = IDE_DEVICES
+            .lock()
+            .iter()
+            .position(Option::is_none)
+            .expect("No more IDE devices can be registered!");
+        // must be done after initializing the heap, i.e. after virtual memory
+        let ide_device = Arc::new(ide_device);
+        // CDs can't be written
+        let block_file = BlockDeviceFile::register(
+            format!("ide{slot_index}"),
+            ide_device.clone(),
+            ide_device.device_type == IdeDeviceType::Ata,
+        );
+        let device =
+            WeakDevice::new(block_file.name(), &ide_device).expect("IDE device not registered");
+        IDE_DEVICES.lock()[slot_index] = Some((ide_device, block_file));
+        devices::register_device(Arc::new(IdeInfo {
+            name: format!("ide{slot_index}_info"),
+            device,
+        }));
+    }
 }
 
 #[repr(u8)]
@@ -60,14 +130,14 @@ pub struct IdeDeviceIndex {
     pub index: usize,
 }
 
-pub fn get_ide_device(index: IdeDeviceIndex) -> Option<Arc<IdeDevice>> {
-    let ide_devices = unsafe { &IDE_DEVICES };
+pub fn get_ide_block_device(index: IdeDeviceIndex) -> Option<Arc<BlockDeviceFile>> {
+    let ide_devices = IDE_DEVICES.lock();
     let mut passed = 0;
     if index.index < ide_devices.len() {
-        for ide_device in ide_devices.iter().filter_map(Option::as_ref) {
+        for (ide_device, block_file) in ide_devices.iter().filter_map(Option::as_ref) {
             if ide_device.device_type == index.ty {
                 if passed == index.index {
-                    return Some(ide_device.clone());
+                    return Some(block_file.clone());
                 }
                 passed += 1;
             }
@@ -156,8 +226,11 @@ mod ata {
     pub const COMMAND_IDENTIFY: u8 = 0xEC;
     pub const COMMAND_PACKET_IDENTIFY: u8 = 0xA1;
     pub const COMMAND_READ_SECTORS: u8 = 0x20;
+    pub const COMMAND_WRITE_SECTORS: u8 = 0x30;
     pub const COMMAND_DEVICE_RESET: u8 = 0x08;
     pub const COMMAND_PACKET: u8 = 0xA0;
+    pub const COMMAND_FLUSH_CACHE: u8 = 0xE7;
+    pub const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
 
     pub const PACKET_FEAT_DMA: u8 = 1 << 0;
     pub const PACKET_FEAT_DMA_DIR_FROM_DEVICE: u8 = 1 << 2;
@@ -265,6 +338,27 @@ impl IdeIo {
 
         Ok(())
     }
+
+    /// Writes `data` one sector at a time, the device asks for each sector
+    pub fn write_data_block(&self, data: &[u8], sector_size: usize) -> Result<(), u8> {
+        for sector in data.chunks(sector_size) {
+            self.wait_until_free();
+            if self.read_status() & ata::STATUS_ERR != 0 {
+                return Err(self.read_error());
+            }
+            for word in sector.as_chunks::<2>().0 {
+                self.write_data(u16::from_le_bytes(*word));
+            }
+        }
+
+        // wait for the last sector to be written
+        self.wait_until_free();
+        if self.read_status() & ata::STATUS_ERR != 0 {
+            return Err(self.read_error());
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -569,45 +663,191 @@ impl CommandIdentifyDataRaw {
         // and not all is 0xFF
         self.serial_number.iter().any(|x| *x != 0) && self.serial_number.iter().any(|x| *x != 0xFF)
     }
+}
 
-    fn is_dma_supported(&self) -> bool {
-        self.capabilities[0] & (1 << 8) != 0
-    }
+/// Convert an ATA string, where each 2 bytes are swapped, and padded with spaces
+fn ata_string(raw: &[u8]) -> String {
+    raw.as_chunks::<2>()
+        .0
+        .iter()
+        .flat_map(|&[a, b]| [b, a])
+        .map(|c| {
+            if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_matches(|c| c == ' ' || c == '?')
+        .into()
+}
 
-    fn is_lba_supported(&self) -> bool {
-        self.capabilities[0] & (1 << 9) != 0
-    }
+/// The parsed data of `IDENTIFY DEVICE` and `IDENTIFY PACKET DEVICE` commands
+#[derive(Debug, Clone)]
+pub struct AtaIdentify {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub lba_supported: bool,
+    pub dma_supported: bool,
+    pub lba48_supported: bool,
+    /// Number of sectors addressable in 28-bit mode
+    pub sectors_28: u32,
+    /// Number of sectors addressable in 48-bit mode, `0` if not supported
+    pub sectors_48: u64,
+    /// Bitmask of the supported UDMA modes
+    pub udma_supported: u8,
+    pub udma_selected: Option<u8>,
+    pub write_cache_supported: bool,
+    pub write_cache_enabled: bool,
+    pub flush_supported: bool,
+    pub flush_ext_supported: bool,
+    /// Logical sector size in bytes
+    pub logical_sector_size: u32,
+    /// Physical sector size in bytes, bigger than the logical for 512e disks
+    pub physical_sector_size: u32,
+    /// Native command queuing, only used through AHCI
+    pub ncq_supported: bool,
+    /// The number of commands the device can queue, `1` without NCQ
+    pub queue_depth: u8,
+}
 
-    fn is_lba48_supported(&self) -> bool {
-        self.command_set_supported_or_enabled[1] & (1 << 10) != 0
+impl AtaIdentify {
+    /// Parses the data of `IDENTIFY DEVICE` read by another driver, `None` if it's not valid
+    pub fn parse(data: [u8; 512]) -> Option<Self> {
+        const _: () = assert!(mem::size_of::<CommandIdentifyDataRaw>() == 512);
+        // SAFETY: the same size, and any bytes are valid for it
+        let raw: CommandIdentifyDataRaw = unsafe { mem::transmute(data) };
+        raw.is_valid().then(|| Self::from_raw(&raw))
     }
 
-    fn user_addressable_sectors(&self) -> u64 {
-        if self.is_lba48_supported() {
-            let extended_number_of_sectors_supported = self.addional_supported & (1 << 3) != 0;
+    fn from_raw(raw: &CommandIdentifyDataRaw) -> Self {
+        let capabilities = raw.capabilities;
+        let command_set = raw.command_set_supported_or_enabled;
+        // word 82..=84 are supported, word 85..=87 are enabled
+        let lba48_supported = command_set[1] & (1 << 10) != 0;
 
+        let sectors_48 = if lba48_supported {
+            // word 69 bit 3, the extended number of sectors field is valid
+            let extended_number_of_sectors_supported = raw.addional_supported & (1 << 3) != 0;
             if extended_number_of_sectors_supported {
-                self.extended_user_addressable_sectors
+                raw.extended_user_addressable_sectors
             } else {
-                self.user_addressable_sectors
+                raw.user_addressable_sectors
             }
         } else {
-            self.user_addressable_sectors_28_mode as u64
-        }
-    }
+            0
+        };
 
-    // Return the size of the logical sector in bytes
-    fn sector_size(&self) -> u32 {
-        let large_logical_sector_supported = self.physical_logical_sector_size & (1 << 12) != 0;
-        if large_logical_sector_supported && self.logical_sector_size != 0 {
-            assert!(self.logical_sector_size >= 256);
-            // the value here is in bytes
-            self.logical_sector_size * 2
+        // word 53 bit 2, word 88 is valid
+        let (udma_supported, udma_selected) = if raw.unk_53 & (1 << 2) != 0 {
+            let ultra_dma_modes = raw.ultra_dma_modes;
+            let selected = (ultra_dma_modes >> 8) as u8 & 0x7F;
+            (
+                ultra_dma_modes as u8 & 0x7F,
+                (selected != 0).then(|| selected.trailing_zeros() as u8),
+            )
+        } else {
+            (0, None)
+        };
+
+        // word 106 is valid if bit 14 is set and bit 15 is cleared
+        let physical_logical_sector_size = raw.physical_logical_sector_size;
+        let sector_size_valid = physical_logical_sector_size & 0xC000 == 0x4000;
+        let large_logical_sector_supported =
+            sector_size_valid && physical_logical_sector_size & (1 << 12) != 0;
+        let logical_sector_size = raw.logical_sector_size;
+        let logical_sector_size = if large_logical_sector_supported && logical_sector_size != 0 {
+            assert!(logical_sector_size >= 256);
+            // the value here is in words
+            logical_sector_size * 2
         } else {
             // default value
             ata::DEFAULT_SECTOR_SIZE
+        };
+        let physical_sector_size =
+            if sector_size_valid && physical_logical_sector_size & (1 << 13) != 0 {
+                // log2 of the number of logical sectors per physical sector
+                logical_sector_size << (physical_logical_sector_size & 0xF)
+            } else {
+                logical_sector_size
+            };
+
+        // word 76 bit 8, and word 75 has the depth minus one
+        let ncq_supported = raw.serial_ata_capabilities[0] & (1 << 8) != 0;
+        let queue_depth = if ncq_supported {
+            (raw.queue_depth & 0x1F) as u8 + 1
+        } else {
+            1
+        };
+
+        Self {
+            model: ata_string(&raw.model_number),
+            serial: ata_string(&raw.serial_number),
+            firmware: ata_string(&raw.firmware_revision),
+            lba_supported: capabilities[0] & (1 << 9) != 0,
+            dma_supported: capabilities[0] & (1 << 8) != 0,
+            lba48_supported,
+            sectors_28: raw.user_addressable_sectors_28_mode,
+            sectors_48,
+            udma_supported,
+            udma_selected,
+            write_cache_supported: command_set[0] & (1 << 5) != 0,
+            write_cache_enabled: command_set[3] & (1 << 5) != 0,
+            flush_supported: command_set[1] & (1 << 12) != 0,
+            flush_ext_supported: command_set[1] & (1 << 13) != 0,
+            logical_sector_size,
+            physical_sector_size,
+            ncq_supported,
+            queue_depth,
         }
     }
+
+    /// The number of user addressable sectors, the 28-bit field can't hold more than 128GB
+    pub fn number_of_sectors(&self) -> u64 {
+        if self.lba48_supported && self.sectors_48 != 0 {
+            self.sectors_48
+        } else {
+            self.sectors_28 as u64
+        }
+    }
+}
+
+impl fmt::Display for AtaIdentify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "model: {}", self.model)?;
+        writeln!(f, "serial: {}", self.serial)?;
+        writeln!(f, "firmware: {}", self.firmware)?;
+        writeln!(
+            f,
+            "sectors: {} (28-bit: {}, 48-bit: {})",
+            self.number_of_sectors(),
+            self.sectors_28,
+            self.sectors_48
+        )?;
+        writeln!(
+            f,
+            "sector size: logical={}, physical={}",
+            self.logical_sector_size, self.physical_sector_size
+        )?;
+        write!(f, "udma modes: supported={:#09b}", self.udma_supported)?;
+        if let Some(selected) = self.udma_selected {
+            writeln!(f, ", selected={selected}")?;
+        } else {
+            writeln!(f, ", selected=none")?;
+        }
+        writeln!(
+            f,
+            "write cache: supported={}, enabled={}",
+            self.write_cache_supported, self.write_cache_enabled
+        )?;
+        writeln!(
+            f,
+            "flush: supported={}, ext={}",
+            self.flush_supported, self.flush_ext_supported
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -615,6 +855,9 @@ pub enum IdeError {
     DeviceError(u8),
     UnalignedSize,
     BoundsExceeded,
+    NotSupported,
+    /// The device was removed, see [`BlockDevice::shutdown`]
+    DeviceGone,
 }
 
 impl fmt::Display for IdeError {
@@ -623,19 +866,69 @@ impl fmt::Display for IdeError {
             IdeError::DeviceError(err) => write!(f, "IDE device error: {}", err),
             IdeError::UnalignedSize => write!(f, "unaligned size"),
             IdeError::BoundsExceeded => write!(f, "bounds exceeded"),
+            IdeError::NotSupported => write!(f, "not supported by the device"),
+            IdeError::DeviceGone => write!(f, "the device was removed"),
         }
     }
 }
 
+/// The channel of a device, for the whole of a command. The PIO transfers are long, so this
+/// doesn't keep the interrupts disabled like a lock, the holder only stays on its CPU (see
+/// [`cpu::Cpu::push_no_yield`]) so the others, which may wait with them disabled, don't wait
+/// for a process that is not running
+struct ChannelClaim<'a> {
+    device: &'a IdeDevice,
+    started: u64,
+}
+
+impl<'a> ChannelClaim<'a> {
+    fn new(device: &'a IdeDevice) -> Self {
+        let port = device.device_impl.io.command_block;
+        loop {
+            {
+                let mut busy = BUSY_CHANNELS.lock();
+                if !busy.contains(&port) {
+                    busy.push(port);
+                    cpu::cpu().push_no_yield();
+                    break;
+                }
+            }
+            hint::spin_loop();
+        }
+        Self {
+            device,
+            started: clock::uptime_nanos(),
+        }
+    }
+}
+
+impl Drop for ChannelClaim<'_> {
+    fn drop(&mut self) {
+        let held = clock::uptime_nanos().saturating_sub(self.started);
+        self.device
+            .worst_hold_nanos
+            .fetch_max(held, Ordering::Relaxed);
+        let port = self.device.device_impl.io.command_block;
+        BUSY_CHANNELS.lock().retain(|&busy| busy != port);
+        cpu::cpu().pop_no_yield();
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct IdeDevice {
-    device_impl: Mutex<IdeDeviceImpl>,
+    // not locked, its registers are used with the channel claimed, see `ChannelClaim`
+    device_impl: IdeDeviceImpl,
+    identify: AtaIdentify,
     device_type: IdeDeviceType,
     number_of_sectors: u64,
     sector_size: u32,
 
     second_device_select: bool,
+    // removed, see `BlockDevice::shutdown`
+    dead: AtomicBool,
+    // the longest a command kept the channel, in `ide<n>_info`
+    worst_hold_nanos: AtomicU64,
 }
 
 impl IdeDevice {
@@ -648,8 +941,17 @@ impl IdeDevice {
         IdeDeviceImpl::init_new(master_io, io, pci_device, second_device_select)
     }
 
+    /// Called for every interrupt of the channel of the device, after the device is removed
+    /// it does nothing, the hardware may not be there anymore
     pub fn interrupt(&self) {
-        self.device_impl.lock().interrupt();
+        if self.is_dead() {
+            return;
+        }
+        self.device_impl.interrupt();
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Acquire)
     }
 
     pub fn is_primary(&self) -> bool {
@@ -660,40 +962,192 @@ impl IdeDevice {
         self.second_device_select
     }
 
-    #[allow(dead_code)]
-    pub fn number_of_sectors(&self) -> u64 {
-        self.number_of_sectors
+    /// Flush the write cache of the device, does nothing if the device doesn't have
+    /// the write cache enabled
+    pub fn flush_cache(&self) -> Result<(), IdeError> {
+        if self.is_dead() {
+            return Err(IdeError::DeviceGone);
+        }
+        if self.device_type != IdeDeviceType::Ata || !self.identify.write_cache_enabled {
+            return Ok(());
+        }
+
+        let command = if self.identify.lba48_supported && self.identify.flush_ext_supported {
+            ata::COMMAND_FLUSH_CACHE_EXT
+        } else if self.identify.flush_supported {
+            ata::COMMAND_FLUSH_CACHE
+        } else {
+            // the cache is enabled, but we can't flush it
+            return Err(IdeError::NotSupported);
+        };
+
+        let _claim = ChannelClaim::new(self);
+        self.device_impl
+            .execute_no_data(command)
+            .map_err(IdeError::DeviceError)
     }
 
-    pub fn sector_size(&self) -> u32 {
-        self.sector_size
+    /// `error` for the block layer, the medium errors are the `UNC` bit of the error
+    /// register for ATA, and the sense key for ATAPI
+    fn storage_error(&self, error: IdeError) -> StorageErrorKind {
+        match error {
+            IdeError::DeviceError(code) => {
+                let media = if self.device_type == IdeDeviceType::Ata {
+                    code & ata::ERROR_UNCORRECTABLE != 0
+                } else {
+                    (code & ata::ERROR_SENSE_KEY) >> 4 == ata::SENSE_MEDIUM_ERROR
+                };
+                if media {
+                    StorageErrorKind::Media(code)
+                } else {
+                    StorageErrorKind::Device(code)
+                }
+            }
+            IdeError::UnalignedSize => StorageErrorKind::Unaligned,
+            IdeError::BoundsExceeded => StorageErrorKind::OutOfRange,
+            IdeError::NotSupported => StorageErrorKind::NotSupported,
+            IdeError::DeviceGone => StorageErrorKind::DeviceGone,
+        }
     }
 
-    pub fn read_sync(&self, start_sector: u64, data: &mut [u8]) -> Result<(), IdeError> {
+    pub fn read_sync(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), IdeError> {
         let sector_size = self.sector_size as u64;
         let buffer_len = data.len() as u64;
 
+        if self.is_dead() {
+            return Err(IdeError::DeviceGone);
+        }
         if buffer_len % sector_size != 0 {
             return Err(IdeError::UnalignedSize);
         }
-        if start_sector >= self.number_of_sectors {
+        if start_sector >= Lba(self.number_of_sectors) {
             return Err(IdeError::BoundsExceeded);
         }
 
         let number_of_sectors = buffer_len / sector_size;
 
+        let _claim = ChannelClaim::new(self);
         if self.device_type == IdeDeviceType::Ata {
             self.device_impl
-                .lock()
-                .read_sync_ata(start_sector, number_of_sectors, data)
+                .read_sync_ata(start_sector.0, number_of_sectors, data)
                 .map_err(IdeError::DeviceError)
         } else {
             self.device_impl
-                .lock()
-                .read_sync_atapi(start_sector, number_of_sectors, data)
+                .read_sync_atapi(start_sector.0, number_of_sectors, data)
                 .map_err(IdeError::DeviceError)
         }
     }
+
+    /// Writes to the disk, only ATA devices can be written. The data may stay in the write
+    /// cache of the device until [`Self::flush_cache`]
+    pub fn write_sync(&self, start_sector: Lba, data: &[u8]) -> Result<(), IdeError> {
+        let sector_size = self.sector_size as u64;
+        let buffer_len = data.len() as u64;
+
+        if self.is_dead() {
+            return Err(IdeError::DeviceGone);
+        }
+        if self.device_type != IdeDeviceType::Ata {
+            return Err(IdeError::NotSupported);
+        }
+        if !buffer_len.is_multiple_of(sector_size) {
+            return Err(IdeError::UnalignedSize);
+        }
+        let number_of_sectors = buffer_len / sector_size;
+        if start_sector + number_of_sectors > Lba(self.number_of_sectors) {
+            return Err(IdeError::BoundsExceeded);
+        }
+
+        let _claim = ChannelClaim::new(self);
+        self.device_impl
+            .write_sync_ata(start_sector.0, number_of_sectors, data)
+            .map_err(IdeError::DeviceError)
+    }
+}
+
+impl BlockDevice for IdeDevice {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        self.number_of_sectors
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        self.read_sync(start_sector, data)
+            .map_err(|e| self.storage_error(e))
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        self.write_sync(start_sector, data)
+            .map_err(|e| self.storage_error(e))
+    }
+
+    fn flush(&self) -> Result<(), StorageErrorKind> {
+        self.flush_cache().map_err(|e| self.storage_error(e))
+    }
+
+    /// The commands run to completion, so nothing is in flight here, the slot of the device
+    /// and its `ide<n>_info` are removed
+    fn shutdown(&self) {
+        self.dead.store(true, Ordering::Release);
+        let slot_index = IDE_DEVICES.lock().iter_mut().position(|slot| {
+            let is_self = slot
+                .as_ref()
+                .is_some_and(|(device, _)| ptr::eq(Arc::as_ptr(device), self));
+            if is_self {
+                *slot = None;
+            }
+            is_self
+        });
+        if let Some(slot_index) = slot_index {
+            let _ = devices::unregister_device(&format!("ide{slot_index}_info"));
+        }
+    }
+}
+
+/// `/devices/ide<n>_info`, the decoded identify data of the device, the files still open
+/// when the device is removed read nothing
+#[derive(Debug)]
+struct IdeInfo {
+    name: String,
+    device: WeakDevice<IdeDevice>,
+}
+
+impl Device for IdeInfo {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(IdeInfoGenerator(self.device.clone())))
+    }
+}
+
+/// Renders the identify data again for every chunk, the field of the cursor is the line
+#[derive(Debug)]
+struct IdeInfoGenerator(WeakDevice<IdeDevice>);
+
+impl Generator for IdeInfoGenerator {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let Some(device) = self.0.upgrade() else {
+            return 0;
+        };
+        generated::render_display_lines(
+            &format_args!(
+                "type: {:?}\nsize: {} ({} x {})\nchannel held: worst {}us\n{}",
+                device.device_type,
+                MemSize(device.number_of_sectors * device.sector_size as u64),
+                device.number_of_sectors,
+                device.sector_size,
+                device.worst_hold_nanos.load(Ordering::Relaxed) / 1000,
+                device.identify,
+            ),
+            cursor,
+            out,
+        )
+    }
 }
 
 #[allow(dead_code)]
@@ -766,11 +1220,13 @@ impl IdeDeviceImpl {
             return None;
         }
 
-        if !identify_data.is_dma_supported() {
+        let identify = AtaIdentify::from_raw(&identify_data);
+
+        if !identify.dma_supported {
             // DMA is not supported
             master_io = None;
         }
-        if !identify_data.is_lba_supported() {
+        if !identify.lba_supported {
             // panic so that its easier to catch
             panic!("IDE device does not support LBA mode");
         }
@@ -779,8 +1235,8 @@ impl IdeDeviceImpl {
         let sector_size;
         match device_type {
             IdeDeviceType::Ata => {
-                number_of_sectors = identify_data.user_addressable_sectors();
-                sector_size = identify_data.sector_size();
+                number_of_sectors = identify.number_of_sectors();
+                sector_size = identify.logical_sector_size;
             }
             IdeDeviceType::Atapi => {
                 let mut capacity_data = [0u8; 8];
@@ -810,27 +1266,31 @@ impl IdeDeviceImpl {
         }
 
         println!(
-            "Initialized IDE device({device_type:?}): size={} ({number_of_sectors} x {sector_size})",
+            "Initialized IDE device({device_type:?}): {:?} size={} ({number_of_sectors} x {sector_size})",
+            identify.model,
             MemSize(number_of_sectors * sector_size as u64),
         );
 
         Some(IdeDevice {
-            device_impl: Mutex::new(Self {
+            device_impl: Self {
                 master_io,
                 io,
                 pci_device: pci_device.clone(),
                 identify_data,
                 second_device_select,
-            }),
+            },
+            identify,
             device_type,
             number_of_sectors,
             sector_size,
             second_device_select,
+            dead: AtomicBool::new(false),
+            worst_hold_nanos: AtomicU64::new(0),
         })
     }
 
     fn read_sync_ata(
-        &mut self,
+        &self,
         start_sector: u64,
         len_sectors: u64,
         data: &mut [u8],
@@ -845,8 +1305,28 @@ impl IdeDeviceImpl {
         command.execute(&self.io, data)
     }
 
+    fn write_sync_ata(&self, start_sector: u64, len_sectors: u64, data: &[u8]) -> Result<(), u8> {
+        // the sector count register is 8 bits, `0` means 256
+        assert!(len_sectors > 0 && len_sectors <= 256);
+        let command = AtaCommand::new(ata::COMMAND_WRITE_SECTORS)
+            .with_lba(start_sector)
+            .with_sector_count(len_sectors as u16)
+            .with_second_drive(self.second_device_select);
+
+        self.io.wait_until_can_command();
+        command.write(&self.io);
+        self.io
+            .write_data_block(data, data.len() / len_sectors as usize)
+    }
+
+    fn execute_no_data(&self, command: u8) -> Result<(), u8> {
+        AtaCommand::new(command)
+            .with_second_drive(self.second_device_select)
+            .execute(&self.io, &mut [])
+    }
+
     fn read_sync_atapi(
-        &mut self,
+        &self,
         start_sector: u64,
         len_sectors: u64,
         data: &mut [u8],
@@ -865,7 +1345,10 @@ impl IdeDeviceImpl {
         command.execute(&self.io, data)
     }
 
-    fn interrupt(&mut self) {}
+    fn interrupt(&self) {
+        // reading the status acknowledges the interrupt, or a level triggered one keeps coming
+        self.io.read_status();
+    }
 }
 
 impl PciDevice for IdeDevice {
@@ -946,8 +1429,25 @@ impl PciDevice for IdeDevice {
                 None
             };
 
+            let native = if extra.args[0] == 0 {
+                prog_if & pci_cfg::PROG_IF_PRIMARY != 0
+            } else {
+                prog_if & pci_cfg::PROG_IF_SECONDARY != 0
+            };
             // setup interrupts if not already done
-            if !INTERRUPTS_SETUP.swap(true, core::sync::atomic::Ordering::SeqCst) {
+            if native {
+                // both channels in native mode share the `INTx` pin of the controller
+                if let Some(route) = config.route_intx() {
+                    if !NATIVE_INTERRUPT_SETUP.swap(true, core::sync::atomic::Ordering::SeqCst) {
+                        if apic::is_gsi_free(route.gsi) {
+                            route.assign(ide_interrupt_native as BasicInterruptHandler, cpu::cpu());
+                        } else {
+                            println!("WARNING: IDE interrupt {route} is shared, not supported yet");
+                        }
+                    }
+                }
+            } else if !INTERRUPTS_SETUP.swap(true, core::sync::atomic::Ordering::SeqCst) {
+                softirq::register(SoftIrq::BlockComplete, complete_interrupted);
                 // setup ide interrupt
                 // TODO: we are assuming that this is the interrupt address.
                 //       at least, can't find a specific place on all specs for to know for sure if its using
@@ -981,22 +1481,41 @@ impl PciDevice for IdeDevice {
     }
 }
 
-extern "x86-interrupt" fn ide_interrupt_primary(_stack_frame: InterruptStackFrame64) {
-    let ide_devices = unsafe { &IDE_DEVICES };
-    for ide_device in ide_devices.iter().filter_map(Option::as_ref) {
-        if ide_device.is_primary() {
+/// The [`SoftIrq::BlockComplete`] handler, gives the legacy interrupts that came to the
+/// devices they are for
+fn complete_interrupted() {
+    let interrupted = INTERRUPTED.swap(0, Ordering::AcqRel);
+    for (ide_device, _) in IDE_DEVICES.lock().iter().filter_map(Option::as_ref) {
+        if (ide_device.is_primary() && interrupted & INTERRUPTED_PRIMARY != 0)
+            || (ide_device.is_secondary() && interrupted & INTERRUPTED_SECONDARY != 0)
+        {
             ide_device.interrupt()
         }
     }
+}
+
+extern "x86-interrupt" fn ide_interrupt_primary(_stack_frame: InterruptStackFrame64) {
+    irq_off::interrupt_enter("ide");
+    INTERRUPTED.fetch_or(INTERRUPTED_PRIMARY, Ordering::AcqRel);
+    softirq::raise(SoftIrq::BlockComplete);
+    irq_off::interrupt_exit();
     apic::return_from_interrupt();
 }
 
-extern "x86-interrupt" fn ide_interrupt_secondary(_stack_frame: InterruptStackFrame64) {
-    let ide_devices = unsafe { &IDE_DEVICES };
-    for ide_device in ide_devices.iter().filter_map(Option::as_ref) {
-        if ide_device.is_secondary() {
-            ide_device.interrupt()
-        }
+extern "x86-interrupt" fn ide_interrupt_native(_stack_frame: InterruptStackFrame64) {
+    // level triggered, if the devices don't see it before the EOI it comes again right away
+    irq_off::interrupt_enter("ide");
+    for (ide_device, _) in IDE_DEVICES.lock().iter().filter_map(Option::as_ref) {
+        ide_device.interrupt()
     }
+    irq_off::interrupt_exit();
+    apic::return_from_interrupt();
+}
+
+extern "x86-interrupt" fn ide_interrupt_secondary(_stack_frame: InterruptStackFrame64) {
+    irq_off::interrupt_enter("ide");
+    INTERRUPTED.fetch_or(INTERRUPTED_SECONDARY, Ordering::AcqRel);
+    softirq::raise(SoftIrq::BlockComplete);
+    irq_off::interrupt_exit();
     apic::return_from_interrupt();
 }
@@ -0,0 +1,216 @@
+//! Latency of the block requests of each device, shown in `/devices/iostats`.
+//!
+//! Every request is timed when the access is given to the block layer, when the request is
+//! given to the driver, and when the driver completes it (see [`BlockRequest::complete`],
+//! drivers completing in their interrupt call it there). The time waiting for a slot in the
+//! queue of the device and the time in the driver go to separate histograms, by powers of 2
+//! from 10us, so recording a request is two atomic increments.
+//!
+//! Nothing is recorded before the clock is initialized, the times are `0` then.
+//!
+//! Reading the file gives the upper bounds of the buckets in a `buckets_us` line, then a
+//! line `<device> <queue_wait|service> <counts>...` for every histogram, and the percentiles
+//! of each in `#` lines. Writing `reset` clears all of them, `reset <device>` only one.
+//!
+//! [`BlockRequest::complete`]: super::block::BlockRequest::complete
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::fs::FileSystemError;
+
+use super::{block, Device};
+
+/// The upper bound of the first bucket
+const FIRST_BOUND_NANOS: u64 = 10_000;
+
+/// `< 10us`, `< 20us`, ... `< 10 * 2^(BUCKETS - 2)us` (1.3s), and the rest in the last one
+pub const BUCKETS: usize = 19;
+
+/// The percentiles shown in the summary
+const PERCENTILES: [u64; 3] = [50, 95, 99];
+
+/// The bucket of a duration of `nanos`
+pub fn bucket(nanos: u64) -> usize {
+    let steps = nanos / FIRST_BOUND_NANOS;
+    ((u64::BITS - steps.leading_zeros()) as usize).min(BUCKETS - 1)
+}
+
+/// The upper bound of `bucket` in microseconds, `None` for the last one
+pub fn bucket_bound_micros(bucket: usize) -> Option<u64> {
+    (bucket < BUCKETS - 1).then(|| (FIRST_BOUND_NANOS << bucket) / 1000)
+}
+
+/// The bucket of the `percent` percentile of `counts`, `None` if nothing was recorded
+pub fn percentile(counts: &[u64; BUCKETS], percent: u64) -> Option<usize> {
+    let total = counts.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let rank = (total * percent).div_ceil(100).max(1);
+    let mut seen = 0;
+    counts.iter().position(|&count| {
+        seen += count;
+        seen >= rank
+    })
+}
+
+/// Shows a bucket as the range of durations it has
+struct BucketRange(usize);
+
+impl fmt::Display for BucketRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match bucket_bound_micros(self.0) {
+            Some(bound) => write!(f, "< {bound}us"),
+            None => write!(f, ">= {}us", bucket_bound_micros(self.0 - 1).unwrap()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    pub fn record(&self, nanos: u64) {
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> [u64; BUCKETS] {
+        core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The latency histograms of a block device
+#[derive(Debug)]
+pub struct RequestLatency {
+    /// From the access to the request given to the driver
+    pub queue_wait: LatencyHistogram,
+    /// From the request given to the driver to its completion
+    pub service: LatencyHistogram,
+}
+
+impl RequestLatency {
+    pub const fn new() -> Self {
+        Self {
+            queue_wait: LatencyHistogram::new(),
+            service: LatencyHistogram::new(),
+        }
+    }
+
+    /// Records a request with the uptimes of its steps, nothing if the clock was not running
+    /// for any of them
+    pub fn record(&self, submitted: u64, dispatched: u64, completed: u64) {
+        if submitted == 0 || dispatched < submitted || completed < dispatched {
+            return;
+        }
+        self.queue_wait.record(dispatched - submitted);
+        self.service.record(completed - dispatched);
+    }
+
+    pub fn reset(&self) {
+        self.queue_wait.reset();
+        self.service.reset();
+    }
+}
+
+impl Default for RequestLatency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn render() -> String {
+    use core::fmt::Write;
+
+    let devices = block::registered();
+    let mut histograms = Vec::new();
+    for device in &devices {
+        let latency = device.latency();
+        histograms.push((device.name(), "queue_wait", latency.queue_wait.snapshot()));
+        histograms.push((device.name(), "service", latency.service.snapshot()));
+    }
+
+    let mut out = String::from("buckets_us:");
+    for bucket in 0..BUCKETS - 1 {
+        let _ = write!(out, " {}", bucket_bound_micros(bucket).unwrap());
+    }
+    out.push_str(" inf\n");
+    for (name, kind, counts) in &histograms {
+        let _ = write!(out, "{name} {kind}");
+        for count in counts {
+            let _ = write!(out, " {count}");
+        }
+        out.push('\n');
+    }
+    for (name, kind, counts) in &histograms {
+        let _ = write!(
+            out,
+            "# {name} {kind}: {} requests",
+            counts.iter().sum::<u64>()
+        );
+        for percent in PERCENTILES {
+            if let Some(bucket) = percentile(counts, percent) {
+                let _ = write!(out, ", p{percent} {}", BucketRange(bucket));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// `/devices/iostats`, the latency histograms of all the block devices
+#[derive(Debug)]
+struct IoStatsDevice;
+
+impl Device for IoStatsDevice {
+    fn name(&self) -> &str {
+        "iostats"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(super::read_bytes(render().as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        let name = match command.strip_prefix("reset") {
+            Some("") => None,
+            Some(name) if name.starts_with(' ') => Some(name.trim()),
+            _ => return Err(FileSystemError::InvalidData),
+        };
+        match name {
+            None => block::registered()
+                .iter()
+                .for_each(|device| device.latency().reset()),
+            Some(name) => block::find(name)
+                .ok_or(FileSystemError::FileNotFound)?
+                .latency()
+                .reset(),
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+pub fn init() {
+    super::register_device(Arc::new(IoStatsDevice));
+}
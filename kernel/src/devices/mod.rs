@@ -1,19 +1,37 @@
 use core::fmt;
 
-use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 
 use crate::{
     fs::{self, FileAttributes, FileSystem, FileSystemError, INode},
     io,
+    memory_management::shm,
     sync::{once::OnceLock, spin::mutex::Mutex},
 };
 
 use self::pci::{PciDeviceConfig, PciDevicePropeIterator};
 
+pub mod ahci;
+pub mod block;
 pub mod clock;
+pub mod event;
+pub mod fw_cfg;
+pub mod generated;
 pub mod ide;
+pub mod iostats;
 pub mod pci;
 pub mod pipe;
+pub mod pit;
+pub mod ramdisk;
+pub mod random;
+pub mod usb;
+pub mod virtio_net;
 
 // TODO: replace with rwlock
 static DEVICES: OnceLock<Arc<Mutex<Devices>>> = OnceLock::new();
@@ -22,15 +40,47 @@ pub(crate) const DEVICES_FILESYSTEM_CLUSTER_MAGIC: u32 = 0xdef1ce5;
 
 #[derive(Debug)]
 struct Devices {
-    devices: BTreeMap<String, Arc<dyn Device>>,
+    // the id of a device is the order of its registration, used as its inode id, so its the
+    // same whatever the order of the listing, the same name registered again gets a new one
+    devices: BTreeMap<String, (u64, Arc<dyn Device>)>,
+    // the name of every id registered, to find the device of an open file by its inode
+    names: BTreeMap<u64, String>,
+    next_id: u64,
+    disconnected: bool,
+}
+
+impl Devices {
+    /// The device registered with `id`, fails with `FileSystemError::DeviceGone` if it was
+    /// removed, even if another device was registered with its name since
+    fn by_id(&self, id: u64) -> Result<&Arc<dyn Device>, FileSystemError> {
+        self.names
+            .get(&id)
+            .and_then(|name| self.devices.get(name))
+            .map(|(_, device)| device)
+            .ok_or(FileSystemError::DeviceGone)
+    }
 }
 
 pub trait Device: Sync + Send + fmt::Debug {
     fn name(&self) -> &str;
-    fn read(&self, _offset: u32, _buf: &mut [u8]) -> Result<u64, FileSystemError> {
-        Err(FileSystemError::ReadNotSupported)
+    /// Reads at `offset` of the device, devices with content must return `0` when reading
+    /// at or after its end, and only the part until the end otherwise, see [`read_bytes`].
+    /// Streams, like pipes, can ignore `offset`.
+    ///
+    /// This is not used directly, but through [`checked_read`], the default reads the
+    /// [`generator`](Device::generator) if there is one
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        match self.generator() {
+            Some(generator) => Ok(generated::read_at(generator, offset, buf)),
+            None => Err(FileSystemError::ReadNotSupported),
+        }
+    }
+    /// If the content is rendered a chunk at a time, each open file reads its own generator
+    /// instead of [`Device::read`], see [`generated`]
+    fn generator(&self) -> Option<Box<dyn generated::Generator>> {
+        None
     }
-    fn write(&self, _offset: u32, _buf: &[u8]) -> Result<u64, FileSystemError> {
+    fn write(&self, _offset: u64, _buf: &[u8]) -> Result<u64, FileSystemError> {
         Err(FileSystemError::WriteNotSupported)
     }
     /// Informs the device that it is closed.
@@ -41,17 +91,54 @@ pub trait Device: Sync + Send + fmt::Debug {
     fn clone_device(&self) -> Result<(), FileSystemError> {
         Ok(())
     }
+    /// If this is a block device, it can be opened with `OPEN_DIRECT`
+    fn as_block_device(&self) -> Option<&block::BlockDeviceFile> {
+        None
+    }
+    /// If this is a watch, directories can be added to it, see [`fs::watch`]
+    fn as_watch(&self) -> Option<&Arc<fs::watch::Watch>> {
+        None
+    }
+    /// If this is a shared memory object, it can be mapped, see [`shm`]
+    fn as_shared_memory(&self) -> Option<&Arc<shm::SharedMemory>> {
+        None
+    }
+    /// If this is a terminal of the console, its index, the processes with it open get its
+    /// `Ctrl+C`, see [`crate::process::signal::send_to_terminal`]
+    fn as_terminal(&self) -> Option<usize> {
+        None
+    }
+    /// Called once when the device is removed (see [`unregister_device`]), every operation
+    /// after it must fail with `FileSystemError::DeviceGone`. The files still open keep the
+    /// device alive until they are closed, but must not reach the hardware anymore
+    fn shutdown(&self) {}
+}
+
+impl Mutex<Devices> {
+    /// The device of `inode` by its id and not its name, the lock is not held while using it,
+    /// a device can register others
+    fn resolve(&self, inode: &INode) -> Result<Arc<dyn Device>, FileSystemError> {
+        let devices = self.lock();
+        if devices.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
+        devices.by_id(self.inode_id(inode)).cloned()
+    }
 }
 
 impl FileSystem for Mutex<Devices> {
     fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        let devices = self.lock();
+        if devices.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
         if path == "/" {
-            Ok(self
-                .lock()
+            Ok(devices
                 .devices
                 .iter()
-                .map(|(name, device)| {
+                .map(|(name, (id, device))| {
                     INode::new_device(name.clone(), FileAttributes::EMPTY, Some(device.clone()))
+                        .with_id(*id)
                 })
                 .collect())
         } else {
@@ -63,16 +150,93 @@ impl FileSystem for Mutex<Devices> {
         assert_eq!(inode.start_cluster(), DEVICES_FILESYSTEM_CLUSTER_MAGIC);
         self.open_dir(inode.name())
     }
+
+    fn validate_inode(&self, inode: &INode) -> Result<(), FileSystemError> {
+        let devices = self.lock();
+        if devices.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
+        // the root directory
+        if inode.device().is_none() {
+            return Ok(());
+        }
+        devices.by_id(self.inode_id(inode)).map(|_| ())
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        if inode.device().is_none() {
+            return Err(FileSystemError::ReadNotSupported);
+        }
+        let device = self.resolve(inode)?;
+        checked_read(device.as_ref(), position, buf)
+    }
+
+    fn write_file(&self, inode: &INode, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if inode.device().is_none() {
+            return Err(FileSystemError::WriteNotSupported);
+        }
+        self.resolve(inode)?.write(position, buf)
+    }
+
+    fn disconnect(&self) {
+        self.lock().disconnected = true;
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.lock().disconnected
+    }
+}
+
+/// Reads `data` at `offset` into `buf`, for devices that give their content as bytes.
+///
+/// Reading at or after the end gives `0`, and reading across it gives only the rest of `data`
+pub fn read_bytes(data: &[u8], offset: u64, buf: &mut [u8]) -> u64 {
+    let Some(data) = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| data.get(offset..))
+    else {
+        return 0;
+    };
+    let to_read = data.len().min(buf.len());
+    buf[..to_read].copy_from_slice(&data[..to_read]);
+    to_read as u64
+}
+
+/// Reads from `device`, all device reads go through here, so a device can never report
+/// more than `buf` can hold, which would move the file position past the data
+pub fn checked_read(
+    device: &dyn Device,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<u64, FileSystemError> {
+    let read = device.read(offset, buf)?;
+    if read > buf.len() as u64 {
+        eprintln!(
+            "Device {} read {read} bytes into a buffer of {}",
+            device.name(),
+            buf.len()
+        );
+    }
+    Ok(read.min(buf.len() as u64))
 }
 
 pub fn init_devices_mapping() {
     DEVICES
         .set(Arc::new(Mutex::new(Devices {
             devices: BTreeMap::new(),
+            names: BTreeMap::new(),
+            // `0` is the id of the root
+            next_id: 1,
+            disconnected: false,
         })))
         .expect("Devices already initialized");
 
-    fs::mount("/devices", DEVICES.get().clone());
+    fs::mount("/devices", DEVICES.get().clone(), "devices", "devices");
 }
 
 pub fn register_device(device: Arc<dyn Device>) {
@@ -82,7 +246,75 @@ pub fn register_device(device: Arc<dyn Device>) {
         "Device {} already registered",
         device.name()
     );
-    devices.devices.insert(String::from(device.name()), device);
+    let id = devices.next_id;
+    devices.next_id += 1;
+    devices.names.insert(id, String::from(device.name()));
+    devices
+        .devices
+        .insert(String::from(device.name()), (id, device));
+}
+
+/// Removes `/devices/<name>`, the device is [shut down](Device::shutdown) and the registry
+/// drops its reference, new devices can be registered with the same name after this.
+///
+/// This locks the filesystems on the device to disconnect them, so it must not be called
+/// from interrupts, a driver that knows of a removal in its interrupt must only mark itself
+/// dead there
+pub fn unregister_device(name: &str) -> Result<(), FileSystemError> {
+    let device = {
+        let mut devices = DEVICES.get().lock();
+        let (id, device) = devices
+            .devices
+            .remove(name)
+            .ok_or(FileSystemError::FileNotFound)?;
+        devices.names.remove(&id);
+        device
+    };
+    println!("Device {name} removed");
+    device.shutdown();
+    Ok(())
+}
+
+/// A reference to a registered device that doesn't keep it alive, and stops resolving when
+/// the device is removed even if something else still has it (i.e. an open file).
+///
+/// The ids of the registry are never reused, so a new device registered with the same name
+/// is not reached through the references to the old one
+#[derive(Debug)]
+pub struct WeakDevice<T: ?Sized> {
+    id: u64,
+    device: Weak<T>,
+}
+
+impl<T: ?Sized> WeakDevice<T> {
+    /// A reference to `device`, which is registered as `name`, it can be the driver behind
+    /// the registered device (i.e. the disk of a block device), `None` if `name` is not
+    /// registered
+    pub fn new(name: &str, device: &Arc<T>) -> Option<Self> {
+        let id = DEVICES.get().lock().devices.get(name)?.0;
+        Some(Self {
+            id,
+            device: Arc::downgrade(device),
+        })
+    }
+
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        let registered = DEVICES.get().lock().by_id(self.id).is_ok();
+        if registered {
+            self.device.upgrade()
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for WeakDevice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            device: self.device.clone(),
+        }
+    }
 }
 
 pub fn prope_pci_devices() {
@@ -113,11 +345,228 @@ pub fn prope_pci_devices() {
 }
 
 pub fn probe_pci_driver(pci_device: &PciDeviceConfig) -> bool {
-    ide::try_register_ide_device(pci_device)
+    // only kept, its channels are probed by boot tasks of their own
+    ide::try_add_controller(pci_device)
+        || ahci::try_register(pci_device)
+        || usb::uhci::try_register(pci_device)
+        || usb::ehci::try_register(pci_device)
+        || virtio_net::try_register(pci_device)
     // add more devices here
 }
 
 /// Devices such as PS/2 keyboard, mouse, serial ports, etc.
 pub fn init_legacy_devices() {
+    // the mouse is a boot task of its own, as it can be missing
     io::keyboard::init_keyboard()
 }
+
+/// A device with fixed content that reports reading more than it was asked for
+#[derive(Debug)]
+struct SelfTestDevice(&'static [u8]);
+
+impl Device for SelfTestDevice {
+    fn name(&self) -> &str {
+        "selftest"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(read_bytes(self.0, offset, buf) + 1)
+    }
+}
+
+/// A device with fixed content under any name, to register it again
+#[derive(Debug)]
+struct SelfTestNamedDevice {
+    name: &'static str,
+    content: &'static [u8],
+}
+
+impl Device for SelfTestNamedDevice {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(read_bytes(self.content, offset, buf))
+    }
+}
+
+/// Offsets around `len` and the integer boundaries
+fn selftest_offsets(len: u64) -> [u64; 9] {
+    [
+        0,
+        len.saturating_sub(1),
+        len,
+        len + 1,
+        u32::MAX as u64,
+        u32::MAX as u64 + 1,
+        u32::MAX as u64 + len,
+        u64::MAX - 1,
+        u64::MAX,
+    ]
+}
+
+fn selftest_read_bytes() {
+    let device = SelfTestDevice(b"0123456789");
+    let len = device.0.len() as u64;
+    let mut buf = [0; 16];
+
+    for buf_len in [0, 1, 3, 10, 16] {
+        let mut last = u64::MAX;
+        let mut offsets = selftest_offsets(len);
+        offsets.sort_unstable();
+        for offset in offsets {
+            let read = checked_read(&device, offset, &mut buf[..buf_len]).unwrap();
+            let expected = if offset < len {
+                (len - offset).min(buf_len as u64)
+            } else {
+                0
+            };
+            // the extra byte the device reports is clamped by `checked_read`
+            let expected = (expected + 1).min(buf_len as u64);
+            assert_eq!(
+                read, expected,
+                "devices self test: read at {offset} with {buf_len} bytes gave {read}"
+            );
+            assert!(
+                read <= last,
+                "devices self test: read at {offset} grew to {read}"
+            );
+            last = read;
+        }
+    }
+}
+
+/// Reads the registered devices around their end, they must not panic or give more than asked
+fn selftest_registered_devices() {
+    let devices: Vec<Arc<dyn Device>> = DEVICES
+        .get()
+        .lock()
+        .devices
+        .values()
+        .map(|(_, device)| device.clone())
+        .collect();
+    let mut buf = [0; 512];
+
+    for device in devices {
+        // reading the console takes the input
+        if device.name() == "console" {
+            continue;
+        }
+        // the devices here are small, this is just to not get stuck on a stream
+        let mut len = 0;
+        while len < 0x10_0000 {
+            match checked_read(device.as_ref(), len, &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => len += read,
+            }
+        }
+        for offset in selftest_offsets(len) {
+            for buf_len in [0, 1, buf.len()] {
+                // the content can change between reads, so only check what is always true
+                let read = checked_read(device.as_ref(), offset, &mut buf[..buf_len]).unwrap_or(0);
+                assert!(read <= buf_len as u64);
+                if offset > u32::MAX as u64 && len <= u32::MAX as u64 {
+                    assert_eq!(
+                        read,
+                        0,
+                        "devices self test: {} read {read} at {offset}, after its end",
+                        device.name()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Reads the registered generated devices in chunks, they must not change while reading
+fn selftest_generated_devices() {
+    generated::run_self_tests();
+
+    let devices: Vec<Arc<dyn Device>> = DEVICES
+        .get()
+        .lock()
+        .devices
+        .values()
+        .filter(|(_, device)| device.generator().is_some())
+        .map(|(_, device)| device.clone())
+        .collect();
+    for device in devices {
+        generated::check_chunked(device.name(), &|| device.generator().unwrap());
+    }
+}
+
+/// The inode id of `name` in the listing of `/devices`
+fn selftest_listed_id(name: &str) -> Option<u64> {
+    let devices = DEVICES.get();
+    let listing = devices.open_dir("/").unwrap();
+    let inode = listing.iter().find(|inode| inode.name() == name)?;
+    Some(devices.inode_id(inode))
+}
+
+/// The ids stay the same while the listing changes, and a device registered again under the
+/// same name is a different file, the files still open on the old one can't reach the new one
+fn selftest_stable_ids() {
+    const NAME: &str = "selftest_ids";
+    const PATH: &str = "/devices/selftest_ids";
+    // listed before `NAME`
+    const BEFORE: &str = "selftest_aaa";
+
+    register_device(Arc::new(SelfTestNamedDevice {
+        name: NAME,
+        content: b"old",
+    }));
+    let mut old = fs::open(PATH).unwrap();
+    let id = old.stat().unwrap().inode_id;
+    assert_eq!(selftest_listed_id(NAME), Some(id));
+
+    register_device(Arc::new(SelfTestNamedDevice {
+        name: BEFORE,
+        content: b"",
+    }));
+    let again = fs::open(PATH).unwrap();
+    assert_eq!(again.stat().unwrap().inode_id, id);
+    assert_eq!(old.stat().unwrap().inode_id, id);
+    assert_eq!(selftest_listed_id(NAME), Some(id));
+    unregister_device(BEFORE).unwrap();
+    assert_eq!(selftest_listed_id(NAME), Some(id));
+
+    unregister_device(NAME).unwrap();
+    register_device(Arc::new(SelfTestNamedDevice {
+        name: NAME,
+        content: b"new",
+    }));
+    let mut buf = [0; 8];
+    assert!(matches!(
+        old.read(&mut buf),
+        Err(FileSystemError::DeviceGone)
+    ));
+    assert!(matches!(old.stat(), Err(FileSystemError::DeviceGone)));
+    assert!(matches!(again.stat(), Err(FileSystemError::DeviceGone)));
+
+    let mut new = fs::open(PATH).unwrap();
+    assert_eq!(new.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], b"new");
+    let new_id = new.stat().unwrap().inode_id;
+    assert_ne!(new_id, id);
+    assert_eq!(selftest_listed_id(NAME), Some(new_id));
+
+    drop((old, again, new));
+    unregister_device(NAME).unwrap();
+}
+
+/// Tests the reads of the devices at and around the end of their content, and the events,
+/// this will panic on failure.
+///
+/// This must be called after all the devices are registered
+pub fn run_self_tests() {
+    println!("Running devices self tests...");
+
+    selftest_read_bytes();
+    selftest_registered_devices();
+    selftest_generated_devices();
+    selftest_stable_ids();
+    event::run_self_tests();
+
+    println!("Devices self tests passed");
+}
@@ -1,8 +1,11 @@
 use core::fmt;
 
-use crate::cpu::{self, IoPortInt};
+use crate::{
+    acpi::pci_routing::{self, IntxRoute},
+    cpu::{self, IoPortInt},
+};
 
-fn read_pci_config<T: IoPortInt>(bus: u8, dev: u8, func: u8, offset: u8) -> T {
+pub(crate) fn read_pci_config<T: IoPortInt>(bus: u8, dev: u8, func: u8, offset: u8) -> T {
     let address = 0x80000000
         | ((bus as u32) << 16)
         | ((dev as u32) << 11)
@@ -45,6 +48,8 @@ pub mod reg {
     pub const CAPABILITIES_PTR: u8 = 0x34;
     pub const INTERRUPT_LINE: u8 = 0x3C;
     pub const INTERRUPT_PIN: u8 = 0x3D;
+    // in the PCI-to-PCI bridge header
+    pub const SECONDARY_BUS: u8 = 0x19;
 }
 
 pub struct PciDevicePropeIterator {
@@ -430,6 +435,25 @@ impl PciDeviceConfig {
         })
     }
 
+    /// Where the `INTx` pin of this function goes, from the `_PRT` of its bus, or guessed from
+    /// the interrupt line register if it has none. `None` if the function doesn't use
+    /// an interrupt pin.
+    ///
+    /// The drivers should use this instead of `interrupt_line`, which is only right for the
+    /// 8259 PIC.
+    pub fn route_intx(&self) -> Option<IntxRoute> {
+        if self.interrupt_pin == 0 || self.interrupt_pin > 4 {
+            return None;
+        }
+        pci_routing::route(
+            self.bus,
+            self.dev,
+            self.func,
+            self.interrupt_pin - 1,
+            self.interrupt_line,
+        )
+    }
+
     pub fn read_config<T: IoPortInt>(&self, offset: u8) -> T {
         read_pci_config(self.bus, self.dev, self.func, offset)
     }
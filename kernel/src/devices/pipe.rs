@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use alloc::{collections::VecDeque, string::String, sync::Arc};
 use kernel_user_link::file::BlockingMode;
@@ -10,6 +10,9 @@ use crate::{
 
 use super::Device;
 
+// both sides of a pipe share the same inode id
+static NEXT_PIPE_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Create a connected pipe pair.
 /// The first returned file is the read side of the pipe.
 /// The second returned file is the write side of the pipe.
@@ -31,16 +34,19 @@ pub fn create_pipe_pair() -> (fs::File, fs::File) {
         clones: AtomicUsize::new(1),
     });
 
+    let id = NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed);
     let read_inode = INode::new_device(
         String::from("read_pipe"),
         FileAttributes::EMPTY,
         Some(read_device),
-    );
+    )
+    .with_id(id);
     let write_inode = INode::new_device(
         String::from("write_pipe"),
         FileAttributes::EMPTY,
         Some(write_device),
-    );
+    )
+    .with_id(id);
     let read_file = fs::inode_to_file(
         read_inode,
         fs::empty_filesystem(),
@@ -76,7 +82,7 @@ impl Device for PipeSide {
         "pipe"
     }
 
-    fn read(&self, _offset: u32, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
         if !self.is_read_side {
             return Err(FileSystemError::ReadNotSupported);
         }
@@ -96,7 +102,7 @@ impl Device for PipeSide {
         Ok(bytes_read as u64)
     }
 
-    fn write(&self, _offset: u32, buf: &[u8]) -> Result<u64, FileSystemError> {
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
         if self.is_read_side {
             return Err(FileSystemError::WriteNotSupported);
         }
@@ -0,0 +1,612 @@
+//! The legacy Programmable Interval Timer (8253/8254).
+//!
+//! It drives the scheduler during boot, until [`hand_off`] gives that to the local APIC
+//! timer, then it is disabled, or kept at a low frequency as a watchdog with `pit_watchdog`
+//! in the cmdline, which reboots if the local APIC timer stops ticking. It is also used to calibrate the TSC, which is how missed ticks are
+//! detected, and can run in one-shot mode as a fallback timer.
+//!
+//! The frequency can be set with `pit_hz=<Hz>` in the cmdline, the statistics are
+//! in `/devices/pit`.
+//!
+//! Hardware-reduced ACPI machines don't have one unless the FADT says there are legacy
+//! devices, its ports are never touched then, it stays disabled and the TSC is not
+//! calibrated.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use alloc::{format, sync::Arc};
+
+use crate::{
+    acpi::tables::{BiosTables, Facp},
+    cpu::{
+        self,
+        idt::{InterruptAllSavedState, InterruptHandlerWithAllState},
+        interrupts::apic,
+    },
+    fs::FileSystemError,
+    system::{self, Reason},
+};
+
+use super::{
+    clock::{self, TickSource},
+    Device,
+};
+
+/// The frequency of the oscillator feeding the PIT
+pub const BASE_FREQUENCY_HZ: u64 = 1_193_182;
+const DEFAULT_FREQUENCY_HZ: u32 = 100;
+const WATCHDOG_FREQUENCY_HZ: u32 = 20;
+/// The watchdog reboots after this many of its ticks without a local APIC timer tick
+const WATCHDOG_TIMEOUT_TICKS: u64 = 5 * WATCHDOG_FREQUENCY_HZ as u64;
+// the reload value is 16 bits, and `0` means this
+const MAX_DIVISOR: u32 = 0x10000;
+
+const IO_PORT_CHANNEL_0: u16 = 0x40;
+const IO_PORT_CHANNEL_2: u16 = 0x42;
+const IO_PORT_COMMAND: u16 = 0x43;
+// bit 0: the gate of channel 2, bit 1: speaker enable, bit 5: the output of channel 2
+const IO_PORT_CONTROL_B: u16 = 0x61;
+const CONTROL_B_GATE_2: u8 = 1 << 0;
+const CONTROL_B_SPEAKER: u8 = 1 << 1;
+const CONTROL_B_OUTPUT_2: u8 = 1 << 5;
+
+const COMMAND_CHANNEL_0: u8 = 0b00 << 6;
+const COMMAND_CHANNEL_2: u8 = 0b10 << 6;
+const COMMAND_ACCESS_LOBYTE_HIBYTE: u8 = 0b11 << 4;
+// mode 0, the output goes high once when the count reaches zero
+const COMMAND_MODE_TERMINAL_COUNT: u8 = 0b000 << 1;
+// mode 2, the count is reloaded when it reaches zero, periodic
+const COMMAND_MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+const IRQ: u8 = 0;
+
+// ~10ms
+const CALIBRATION_COUNT: u16 = 11932;
+// if the PIT is not there, the output never changes
+const CALIBRATION_MAX_POLLS: usize = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Mode {
+    /// The interrupt line is given away, see [`disable`]
+    Disabled = 0,
+    Periodic = 1,
+    OneShot = 2,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Disabled => "disabled",
+            Mode::Periodic => "periodic",
+            Mode::OneShot => "one-shot",
+        }
+    }
+}
+
+// everything is used by the interrupt handler, so they are atomics instead of a lock
+static MODE: AtomicU8 = AtomicU8::new(Mode::Disabled as u8);
+// the BIOS default
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG: AtomicBool = AtomicBool::new(false);
+// from the FADT, if `false` it stays disabled
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static MISSED_TICKS: AtomicU64 = AtomicU64::new(0);
+static ONE_SHOTS: AtomicU64 = AtomicU64::new(0);
+
+// the local APIC timer ticks at the last watchdog tick, and how many ticks it stayed the same
+static WATCHDOG_APIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG_STALLED: AtomicU64 = AtomicU64::new(0);
+
+// missed ticks are found in windows of ~1 second, each starts at a tick, so that the error
+// of the TSC calibration doesn't add up.
+// `0` means the window starts at the next tick
+static WINDOW_START_TSC: AtomicU64 = AtomicU64::new(0);
+// the ticks since the window start, received and missed
+static WINDOW_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A rate the PIT can produce, the base frequency divided by an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PitRate {
+    divisor: u32,
+}
+
+impl PitRate {
+    /// The closest rate to `hz`, clamped to what the PIT can do (~18.2Hz up to the
+    /// base frequency). Returns `None` if `hz` is `0`
+    pub fn for_frequency(hz: u32) -> Option<Self> {
+        if hz == 0 {
+            return None;
+        }
+        let divisor = (BASE_FREQUENCY_HZ + hz as u64 / 2) / hz as u64;
+        Some(Self {
+            divisor: divisor.clamp(1, MAX_DIVISOR as u64) as u32,
+        })
+    }
+
+    pub fn divisor(&self) -> u32 {
+        self.divisor
+    }
+
+    /// The actual frequency, in millihertz
+    pub fn millihertz(&self) -> u64 {
+        (BASE_FREQUENCY_HZ * 1000 + self.divisor as u64 / 2) / self.divisor as u64
+    }
+
+    /// The actual frequency, rounded
+    pub fn hz(&self) -> u32 {
+        ((self.millihertz() + 500) / 1000) as u32
+    }
+
+    pub fn period_nanos(&self) -> u64 {
+        (self.divisor as u64 * 1_000_000_000 + BASE_FREQUENCY_HZ / 2) / BASE_FREQUENCY_HZ
+    }
+
+    /// The ticks expected in `tsc_ticks` of a TSC running at `tsc_hz`, rounded
+    fn ticks_in(&self, tsc_ticks: u64, tsc_hz: u64) -> u64 {
+        let scaled = tsc_ticks as u128 * self.millihertz() as u128 * 2 / (tsc_hz as u128 * 1000);
+        scaled.div_ceil(2) as u64
+    }
+}
+
+fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Periodic,
+        2 => Mode::OneShot,
+        _ => Mode::Disabled,
+    }
+}
+
+/// The rate of the periodic mode (even if in another mode now)
+pub fn rate() -> PitRate {
+    PitRate {
+        divisor: DIVISOR.load(Ordering::Relaxed),
+    }
+}
+
+/// Periodic ticks received since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Periodic ticks that should have been received (going by the TSC), but were not
+pub fn missed_ticks() -> u64 {
+    MISSED_TICKS.load(Ordering::Relaxed)
+}
+
+/// Whether the machine has a PIT, only known after [`init`]
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// The frequency of the TSC, measured with the PIT, `0` if it couldn't be measured
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+fn program(channel_0_command: u8, reload_value: u32) {
+    let command = COMMAND_CHANNEL_0 | COMMAND_ACCESS_LOBYTE_HIBYTE | channel_0_command;
+    // `MAX_DIVISOR` becomes `0`
+    let [low, high] = (reload_value as u16).to_le_bytes();
+    // the interrupt handler must not see a half written rate
+    cpu::cpu().push_cli();
+    unsafe {
+        cpu::io_out(IO_PORT_COMMAND, command);
+        cpu::io_out(IO_PORT_CHANNEL_0, low);
+        cpu::io_out(IO_PORT_CHANNEL_0, high);
+    }
+    cpu::cpu().pop_cli();
+}
+
+fn program_periodic(rate: PitRate) {
+    program(COMMAND_MODE_RATE_GENERATOR, rate.divisor);
+    DIVISOR.store(rate.divisor, Ordering::Relaxed);
+    WINDOW_START_TSC.store(0, Ordering::Relaxed);
+    MODE.store(Mode::Periodic as u8, Ordering::Relaxed);
+}
+
+/// Makes the PIT tick periodically at the closest rate it can get to `hz`, and returns
+/// that rate. Returns `None` if `hz` is `0` or the PIT is disabled
+pub fn set_frequency(hz: u32) -> Option<PitRate> {
+    if mode() == Mode::Disabled {
+        return None;
+    }
+    let rate = PitRate::for_frequency(hz)?;
+    program_periodic(rate);
+    Some(rate)
+}
+
+/// Stops the periodic ticks and fires one interrupt after `nanos` (clamped to what the
+/// PIT can do, at most ~55ms), returns the actual delay in nanoseconds.
+/// Returns `None` if the PIT is disabled
+///
+/// Use [`set_frequency`] to go back to periodic mode.
+pub fn start_one_shot(nanos: u64) -> Option<u64> {
+    if mode() == Mode::Disabled {
+        return None;
+    }
+    let count = (nanos as u128 * BASE_FREQUENCY_HZ as u128).div_ceil(1_000_000_000);
+    let count = count.clamp(1, MAX_DIVISOR as u128) as u32;
+    MODE.store(Mode::OneShot as u8, Ordering::Relaxed);
+    program(COMMAND_MODE_TERMINAL_COUNT, count);
+    Some((count as u64 * 1_000_000_000).div_ceil(BASE_FREQUENCY_HZ))
+}
+
+/// Stops the PIT interrupts and frees its interrupt line for someone else (the HPET uses
+/// the same line on most machines), the PIT can't be used after this
+pub fn disable() {
+    if mode() == Mode::Disabled {
+        return;
+    }
+    if clock::tick_source() == TickSource::Pit {
+        clock::set_tick_source(TickSource::LocalApic);
+    }
+    MODE.store(Mode::Disabled as u8, Ordering::Relaxed);
+    unsafe {
+        // The value being written:
+        // 0x10 = 0001 0000
+        //        |||| ||||
+        //        |||| |||+- BCD/binary mode: 0 == 16-bit binary (not important)
+        //        |||| +++-- Operating mode: 0b000 == interrupt on terminal count
+        //        ||++------ Access mode: 0b01 == lobyte only
+        //        ++-------- Select channel: 0 == channel 0
+        //
+        // Not sure if this is an intended way to do it, but what we do here is:
+        // 1. Select channel 0 (main one)
+        // 2. Set access mode to lobyte (we only need this)
+        // 3. Set operating mode to `interrupt on terminal count` (one shot)
+        // 4. Reload value with 1 only, which will just trigger the interrupt immediately
+        //    and then never again.
+        //
+        // Docs on Mode 0 (interrupt on terminal count):
+        //  the mode/command register is written the output signal goes low and the PIT waits
+        //  for the reload register to be set by software.
+        //  When the current count decrements from one to zero, the output goes high and remains
+        //  high until another mode/command register is written or the reload register is set again.
+        //
+        // How this works is that we select this mode, with the reload value of 1, which will
+        // trigger the interrupt immediately, and then never again.
+        // The line is masked below, so we don't even get that one.
+        cpu::io_out(IO_PORT_COMMAND, 0x10u8);
+        cpu::io_out(IO_PORT_CHANNEL_0, 1u8);
+    }
+    apic::release_io_irq(IRQ);
+}
+
+/// Gives the scheduler to the local APIC timer, the PIT is disabled, or kept at a low
+/// frequency if `pit_watchdog` is in the cmdline
+pub fn hand_off() {
+    hand_off_with(WATCHDOG.load(Ordering::Relaxed));
+}
+
+fn hand_off_with(watchdog: bool) {
+    clock::set_tick_source(TickSource::LocalApic);
+    if watchdog {
+        set_frequency(WATCHDOG_FREQUENCY_HZ);
+    } else {
+        disable();
+    }
+}
+
+/// Measures the TSC frequency with channel 2, which is not connected to any interrupt
+fn calibrate_tsc() -> Option<u64> {
+    let mut ended = false;
+    let (start, end) = unsafe {
+        let control = cpu::io_in::<u8>(IO_PORT_CONTROL_B);
+        // counting (gate high), without the speaker
+        cpu::io_out(
+            IO_PORT_CONTROL_B,
+            (control & !CONTROL_B_SPEAKER) | CONTROL_B_GATE_2,
+        );
+        cpu::io_out(
+            IO_PORT_COMMAND,
+            COMMAND_CHANNEL_2 | COMMAND_ACCESS_LOBYTE_HIBYTE | COMMAND_MODE_TERMINAL_COUNT,
+        );
+        let [low, high] = CALIBRATION_COUNT.to_le_bytes();
+        cpu::io_out(IO_PORT_CHANNEL_2, low);
+        cpu::io_out(IO_PORT_CHANNEL_2, high);
+
+        let start = cpu::rdtsc();
+        for _ in 0..CALIBRATION_MAX_POLLS {
+            if cpu::io_in::<u8>(IO_PORT_CONTROL_B) & CONTROL_B_OUTPUT_2 != 0 {
+                ended = true;
+                break;
+            }
+        }
+        let end = cpu::rdtsc();
+        cpu::io_out(IO_PORT_CONTROL_B, control);
+        (start, end)
+    };
+
+    if !ended {
+        return None;
+    }
+    let tsc_hz = (end - start) as u128 * BASE_FREQUENCY_HZ as u128 / CALIBRATION_COUNT as u128;
+    Some(tsc_hz as u64)
+}
+
+fn periodic_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let tsc_hz = tsc_hz();
+    if tsc_hz == 0 {
+        return;
+    }
+
+    let now = cpu::rdtsc();
+    let rate = rate();
+    let window_start = WINDOW_START_TSC.load(Ordering::Relaxed);
+    if window_start == 0 || WINDOW_TICKS.load(Ordering::Relaxed) >= rate.hz() as u64 {
+        WINDOW_START_TSC.store(now, Ordering::Relaxed);
+        WINDOW_TICKS.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    let accounted = WINDOW_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // rounded, so the latency of the interrupt doesn't count as a missed tick
+    let expected = rate.ticks_in(now.wrapping_sub(window_start), tsc_hz);
+    if expected > accounted {
+        let missed = expected - accounted;
+        MISSED_TICKS.fetch_add(missed, Ordering::Relaxed);
+        WINDOW_TICKS.fetch_add(missed, Ordering::Relaxed);
+    }
+}
+
+/// Reboots if the local APIC timer stopped driving the scheduler for
+/// [`WATCHDOG_TIMEOUT_TICKS`], the interrupts were enabled all this time, or we wouldn't be here
+fn watchdog_tick() {
+    let apic_ticks = clock::driven_ticks(TickSource::LocalApic);
+    if WATCHDOG_APIC_TICKS.swap(apic_ticks, Ordering::Relaxed) != apic_ticks {
+        WATCHDOG_STALLED.store(0, Ordering::Relaxed);
+        return;
+    }
+    if WATCHDOG_STALLED.fetch_add(1, Ordering::Relaxed) + 1 >= WATCHDOG_TIMEOUT_TICKS {
+        println!(
+            "PIT watchdog: no local APIC timer tick for {} s",
+            WATCHDOG_TIMEOUT_TICKS / WATCHDOG_FREQUENCY_HZ as u64
+        );
+        // the scheduler lock could be held by what is stuck, the CPU has a copy of the name
+        let current_cpu = cpu::cpu();
+        if current_cpu.context.is_some() {
+            println!(
+                "PIT watchdog: running process {} ({})",
+                current_cpu.process_id, current_cpu.process_name
+            );
+        } else {
+            println!("PIT watchdog: running the kernel");
+        }
+        system::reboot(Reason::Watchdog);
+    }
+}
+
+extern "cdecl" fn timer_handler(all_state: &mut InterruptAllSavedState) {
+    match mode() {
+        Mode::Periodic => periodic_tick(),
+        Mode::OneShot => {
+            ONE_SHOTS.fetch_add(1, Ordering::Relaxed);
+        }
+        // the line is masked
+        Mode::Disabled => {}
+    }
+    clock::tick(TickSource::Pit, all_state);
+    if WATCHDOG.load(Ordering::Relaxed) && clock::tick_source() == TickSource::LocalApic {
+        watchdog_tick();
+    }
+
+    apic::return_from_interrupt();
+}
+
+/// `/devices/pit`, the state and statistics of the PIT
+#[derive(Debug)]
+struct PitInfo;
+
+impl Device for PitInfo {
+    fn name(&self) -> &str {
+        "pit"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let rate = rate();
+        let millihertz = rate.millihertz();
+        let info = format!(
+            "present: {}\nmode: {}\nfrequency: {}.{:03} Hz\ndivisor: {}\nticks: {}\nmissed: {}\none_shots: {}\ntsc: {} Hz\ntick_source: {}\n",
+            if is_present() { "yes" } else { "no" },
+            mode().name(),
+            millihertz / 1000,
+            millihertz % 1000,
+            rate.divisor(),
+            ticks(),
+            missed_ticks(),
+            ONE_SHOTS.load(Ordering::Relaxed),
+            tsc_hz(),
+            clock::tick_source().name(),
+        );
+        Ok(super::read_bytes(info.as_bytes(), offset, buf))
+    }
+}
+
+/// Calibrates the TSC, and starts the PIT at `pit_hz=<Hz>` from the cmdline (or the
+/// default) as the tick source of the scheduler, if the FADT says there is one
+pub fn init(cmdline: &str, bios_tables: &BiosTables) {
+    let mut frequency = DEFAULT_FREQUENCY_HZ;
+    for arg in cmdline.split_whitespace() {
+        if let Some(hz) = arg.strip_prefix("pit_hz=") {
+            match hz.parse::<u32>() {
+                Ok(hz) if hz > 0 => frequency = hz,
+                _ => {
+                    println!("Invalid value for `pit_hz`: {hz}");
+                }
+            }
+        } else if arg == "pit_watchdog" {
+            WATCHDOG.store(true, Ordering::Relaxed);
+        }
+    }
+
+    super::register_device(Arc::new(PitInfo));
+    let facp = bios_tables.rsdt.get_table::<Facp>();
+    if !facp.is_none_or(Facp::has_8254) {
+        println!("PIT: not present (hardware-reduced ACPI), the TSC is not calibrated");
+        if WATCHDOG.swap(false, Ordering::Relaxed) {
+            println!("WARNING: `pit_watchdog` needs a PIT, ignored");
+        }
+        return;
+    }
+    PRESENT.store(true, Ordering::Relaxed);
+
+    match calibrate_tsc() {
+        Some(tsc_hz) => {
+            TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+            println!("TSC: {} MHz", tsc_hz / 1_000_000);
+        }
+        None => {
+            println!("WARNING: could not calibrate the TSC, missed PIT ticks won't be found");
+        }
+    }
+
+    apic::assign_io_irq(
+        timer_handler as InterruptHandlerWithAllState,
+        IRQ,
+        cpu::cpu(),
+    );
+    let rate = PitRate::for_frequency(frequency).unwrap();
+    program_periodic(rate);
+    clock::set_tick_source(TickSource::Pit);
+    println!(
+        "PIT: {}.{:03} Hz (requested {frequency} Hz)",
+        rate.millihertz() / 1000,
+        rate.millihertz() % 1000
+    );
+}
+
+const SELFTEST_HZ: u32 = 1000;
+const SELFTEST_WINDOW_NANOS: u64 = 300_000_000;
+
+/// Spins (with the interrupts enabled) for `nanos` going by the TSC
+fn selftest_wait(nanos: u64) {
+    let end = cpu::rdtsc() + (nanos as u128 * tsc_hz() as u128 / 1_000_000_000) as u64;
+    while cpu::rdtsc() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs for `SELFTEST_WINDOW_NANOS`, returns the PIT ticks and the scheduler ticks driven
+/// by the PIT and the local APIC timer in that time
+fn selftest_window() -> (u64, u64, u64) {
+    let before = (
+        ticks(),
+        clock::driven_ticks(TickSource::Pit),
+        clock::driven_ticks(TickSource::LocalApic),
+    );
+    selftest_wait(SELFTEST_WINDOW_NANOS);
+    (
+        ticks() - before.0,
+        clock::driven_ticks(TickSource::Pit) - before.1,
+        clock::driven_ticks(TickSource::LocalApic) - before.2,
+    )
+}
+
+fn selftest_check_rate(ticks: u64, rate: PitRate) {
+    let expected = rate.millihertz() * SELFTEST_WINDOW_NANOS / 1_000_000_000_000;
+    assert!(
+        ticks.abs_diff(expected) <= expected / 10 + 1,
+        "PIT self test: got {ticks} ticks at {rate:?}, expected {expected}"
+    );
+}
+
+/// Checks the rates computed for awkward frequencies, then, with the interrupts enabled
+/// for a short time, the tick rate against the TSC, the hand off to the local APIC timer
+/// and the one-shot mode.
+///
+/// Must run after [`init`], and before `clock::init` does the real hand off
+pub fn run_self_tests() {
+    println!("Running PIT self tests...");
+    let rates = [
+        (1000, 1193, 1_000_153, 1000),
+        (100, 11932, 99_998, 100),
+        (3000, 398, 2_997_945, 2998),
+        (WATCHDOG_FREQUENCY_HZ, 59659, 20_000, 20),
+        // clamped to the limits
+        (1, MAX_DIVISOR, 18_207, 18),
+        (
+            u32::MAX,
+            1,
+            BASE_FREQUENCY_HZ * 1000,
+            BASE_FREQUENCY_HZ as u32,
+        ),
+    ];
+    for (hz, divisor, millihertz, actual_hz) in rates {
+        let rate = PitRate::for_frequency(hz).unwrap();
+        assert_eq!(
+            (rate.divisor(), rate.millihertz(), rate.hz()),
+            (divisor, millihertz, actual_hz),
+            "PIT self test: wrong rate for {hz} Hz"
+        );
+    }
+    assert_eq!(
+        PitRate::for_frequency(1000).unwrap().period_nanos(),
+        999_847
+    );
+    assert!(PitRate::for_frequency(0).is_none());
+
+    if !is_present() {
+        assert_eq!(mode(), Mode::Disabled);
+        assert!(set_frequency(SELFTEST_HZ).is_none() && start_one_shot(5_000_000).is_none());
+        println!("PIT self tests: no PIT, skipping the timing tests");
+        return;
+    }
+    if tsc_hz() == 0 {
+        println!("PIT self tests: the TSC is not calibrated, skipping the timing tests");
+        return;
+    }
+    let boot_rate = rate();
+    assert_eq!(clock::tick_source(), TickSource::Pit);
+    unsafe { cpu::set_interrupts() };
+
+    let rate = set_frequency(SELFTEST_HZ).unwrap();
+    let (ticks, pit_driven, apic_driven) = selftest_window();
+    selftest_check_rate(ticks, rate);
+    assert!(pit_driven > 0 && apic_driven == 0);
+
+    // the PIT keeps ticking slowly, but only the local APIC timer drives the scheduler
+    hand_off_with(true);
+    let (ticks, pit_driven, apic_driven) = selftest_window();
+    selftest_check_rate(
+        ticks,
+        PitRate::for_frequency(WATCHDOG_FREQUENCY_HZ).unwrap(),
+    );
+    assert!(pit_driven == 0 && apic_driven > 0);
+
+    let one_shots = ONE_SHOTS.load(Ordering::Relaxed);
+    let ticks_before = self::ticks();
+    let delay = start_one_shot(5_000_000).unwrap();
+    assert!(delay.abs_diff(5_000_000) < 1000);
+    selftest_wait(10 * delay);
+    assert_eq!(ONE_SHOTS.load(Ordering::Relaxed), one_shots + 1);
+    assert_eq!(self::ticks(), ticks_before);
+
+    // back to how `init` left it
+    unsafe { cpu::clear_interrupts() };
+    program_periodic(boot_rate);
+    clock::set_tick_source(TickSource::Pit);
+
+    println!("PIT self tests passed");
+}
+
+/// Stops the local APIC timer, and waits for the watchdog to reboot, needs `pit_watchdog`
+pub fn selftest_watchdog() -> ! {
+    assert!(
+        WATCHDOG.load(Ordering::Relaxed),
+        "PIT watchdog self test: needs `pit_watchdog` in the cmdline"
+    );
+    println!(
+        "Running the PIT watchdog self test, rebooting in {} s...",
+        WATCHDOG_TIMEOUT_TICKS / WATCHDOG_FREQUENCY_HZ as u64
+    );
+    apic::mask_timer();
+    unsafe { cpu::set_interrupts() };
+    loop {
+        unsafe { cpu::halt() };
+    }
+}
@@ -0,0 +1,92 @@
+//! Block devices in memory, created with `ramdisk=<KiB>` in the kernel cmdline as
+//! `/devices/ram0`, the content is lost on reboot.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use kernel_core::sector::Lba;
+
+use crate::sync::spin::mutex::Mutex;
+
+use super::block::{BlockDevice, BlockDeviceFile, StorageErrorKind};
+
+const SECTOR_SIZE: u32 = 512;
+
+pub struct RamDisk {
+    sector_size: u32,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `(number of sectors, sector size)`
+    pub fn new((number_of_sectors, sector_size): (u64, u32)) -> Self {
+        Self {
+            sector_size,
+            data: Mutex::new(vec![0; (number_of_sectors * sector_size as u64) as usize]),
+        }
+    }
+
+    /// The bytes of the sectors, if inside the disk and the size is whole sectors
+    fn range(
+        &self,
+        start_sector: Lba,
+        len: usize,
+        disk_len: usize,
+    ) -> Result<usize, StorageErrorKind> {
+        if !len.is_multiple_of(self.sector_size as usize) {
+            return Err(StorageErrorKind::Unaligned);
+        }
+        let start = start_sector
+            .0
+            .checked_mul(self.sector_size as u64)
+            .and_then(|start| usize::try_from(start).ok())
+            .ok_or(StorageErrorKind::OutOfRange)?;
+        match start.checked_add(len) {
+            Some(end) if end <= disk_len => Ok(start),
+            _ => Err(StorageErrorKind::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn number_of_sectors(&self) -> u64 {
+        self.data.lock().len() as u64 / self.sector_size as u64
+    }
+
+    fn read_sectors(&self, start_sector: Lba, data: &mut [u8]) -> Result<(), StorageErrorKind> {
+        let disk = self.data.lock();
+        let start = self.range(start_sector, data.len(), disk.len())?;
+        data.copy_from_slice(&disk[start..start + data.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: Lba, data: &[u8]) -> Result<(), StorageErrorKind> {
+        let mut disk = self.data.lock();
+        let start = self.range(start_sector, data.len(), disk.len())?;
+        disk[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Creates `/devices/ram0` if `ramdisk=<KiB>` is in the cmdline
+pub fn init(cmdline: &str) {
+    let Some(size) = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("ramdisk="))
+    else {
+        return;
+    };
+    let number_of_sectors = match size.parse::<u64>() {
+        Ok(kib) if kib > 0 => kib * 1024 / SECTOR_SIZE as u64,
+        _ => {
+            println!("Invalid value for `ramdisk`: {size}");
+            return;
+        }
+    };
+
+    let disk = RamDisk::new((number_of_sectors, SECTOR_SIZE));
+    BlockDeviceFile::register("ram0".into(), Arc::new(disk), true);
+    println!("Created ramdisk `ram0` of {number_of_sectors} sectors");
+}
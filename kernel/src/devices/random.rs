@@ -0,0 +1,89 @@
+//! Random bytes for the kernel and for user processes (like `AT_RANDOM`).
+//!
+//! Uses `RDRAND` if the CPU has it, otherwise a generator seeded from the TSC and the
+//! clock. The fallback is not good enough for cryptography, but is fine for seeding hash
+//! tables and randomizing layouts.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu;
+
+use super::clock;
+
+const CPUID_FN_FEAT: u32 = 1;
+const CPUID_FEAT_ECX_RDRAND: u32 = 1 << 30;
+// the CPU can fail to produce a value if too many are requested at once, Intel recommends
+// retrying 10 times before giving up
+const RDRAND_RETRIES: usize = 10;
+
+// the state of the fallback generator, all accesses are a single `fetch_add`, so it can
+// be used from anywhere
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn has_rdrand() -> bool {
+    let cpuid = cpu::cpuid!(CPUID_FN_FEAT);
+    cpuid.ecx & CPUID_FEAT_ECX_RDRAND != 0
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let success: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {value}",
+                "setc {success}",
+                value = out(reg) value,
+                success = out(reg_byte) success,
+                options(nomem, nostack),
+            );
+        }
+        if success != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// `splitmix64`, each call moves the state, and mixes in the TSC so that two boots
+/// don't produce the same sequence
+fn fallback() -> u64 {
+    const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+    let noise = cpu::rdtsc() ^ clock::uptime_nanos().rotate_left(32);
+    let mut z = FALLBACK_STATE
+        .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA)
+        ^ noise;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A random `u64`
+pub fn next_u64() -> u64 {
+    if has_rdrand() {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    fallback()
+}
+
+/// A random `u64` that doesn't need the clock, for the boot before anything is initialized,
+/// it is only the TSC without `RDRAND`
+pub fn early_u64() -> u64 {
+    if has_rdrand() {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    cpu::rdtsc()
+}
+
+/// Fills `buf` with random bytes
+pub fn fill_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let value = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
@@ -0,0 +1,1104 @@
+//! EHCI, the USB 2.0 host controller of the high speed devices, and the one of qemu
+//! `-device usb-ehci`.
+//!
+//! The controller has two schedules. The periodic one is a list of 1024 frames, like UHCI, and
+//! all of them point to the queue head (QH) of the keyboard, which is polled in the first
+//! microframe of each. A QH is one endpoint, with its queue of transfer descriptors (qTD), the
+//! keyboard has one qTD, armed again after each report the controller interrupts for. The
+//! asynchronous schedule is a ring of QHs the controller goes around in the time left, it is
+//! only on during a control transfer, with the QH of the device, so that QH can be changed
+//! between the transfers. These are polled until done, as in [`uhci`](super::uhci).
+//!
+//! The ports are shared with companion controllers (the UHCI ones on the Intel chipsets), the
+//! low and full speed devices are given to them, so [`enumerate`] is called before the one of
+//! the companions. The frame list, the QHs, the qTDs and their buffers are in two pages below
+//! 4GB, the structures have the size of the ones with 64 bit addresses, but the high half of
+//! these is always `0`. Only the devices on the root ports are used, there is no support for
+//! hubs, and a device plugged after the boot is not enumerated.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{self, Ordering},
+};
+
+use alloc::{string::String, vec, vec::Vec};
+
+use kernel_core::hid::{BootKeyboard, BOOT_REPORT_LEN};
+
+use crate::{
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+    },
+    devices::pci::{PciDeviceConfig, PciDeviceType},
+    io::keyboard::{self, LockKeys},
+    memory_management::{memory_layout::PAGE_4K, mmio::MmioRegion},
+    sync::spin::mutex::Mutex,
+};
+
+use super::{
+    descriptor, BootKeyboardInterface, ControlData, DeviceDescriptor, DmaPage, SetupPacket,
+    UsbError, CLASS_HUB, DEVICE_DESCRIPTOR_LEN,
+};
+
+static CONTROLLERS: Mutex<Vec<Ehci>> = Mutex::new(Vec::new());
+/// The GSIs [`ehci_interrupt`] is assigned to, it serves all the controllers
+static INTERRUPTS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+const PCI_COMMAND_MEM_SPACE: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+const BAR_MMIO: usize = 0;
+/// The extended capability the BIOS uses to emulate a PS/2 keyboard from a USB one, in the
+/// PCI configuration
+const LEGACY_SUPPORT_ID: u32 = 1;
+/// The extended capabilities are at least after the PCI header
+const EXTENDED_CAPABILITIES_MIN: u8 = 0x40;
+
+const FRAME_COUNT: usize = 1024;
+const MICROFRAMES_PER_FRAME: u32 = 8;
+/// The frame index counts the microframes, and wraps after 2048 frames
+const FRAME_INDEX_MASK: u32 = 0x3FFF;
+
+/// How many times to poll the controller before it runs, when the frames can't be counted
+const RESET_POLLS: usize = 100_000;
+/// How many times to poll for the BIOS to give the controller, it can take a while
+const BIOS_HANDOFF_POLLS: usize = 1_000_000;
+const PORT_POWER_FRAMES: u16 = 20;
+const PORT_RESET_FRAMES: u16 = 50;
+/// The controller ends the reset of a port in 2ms, the device needs 10ms after it
+const PORT_RECOVERY_FRAMES: u16 = 10;
+const SET_ADDRESS_RECOVERY_FRAMES: u16 = 2;
+const CONTROL_TIMEOUT_FRAMES: u16 = 500;
+/// The schedules are turned on and off at the start of a frame
+const SCHEDULE_SWITCH_FRAMES: u16 = 10;
+/// The failed reports in a row before giving up on the keyboard
+const MAX_KEYBOARD_ERRORS: u32 = 8;
+/// The control endpoint of all the high speed devices
+const HIGH_SPEED_MAX_PACKET0: u16 = 64;
+
+/// The capability registers, the operational ones are after them
+mod cap {
+    /// The length of the capability registers, in the low byte
+    pub const LENGTH: usize = 0x00;
+    pub const STRUCTURAL_PARAMS: usize = 0x04;
+    pub const CAPABILITY_PARAMS: usize = 0x08;
+}
+
+mod params {
+    // of the structural parameters
+    pub const PORT_COUNT_MASK: u32 = 0xF;
+    /// The ports must be powered by the driver
+    pub const PORT_POWER_CONTROL: u32 = 1 << 4;
+    // of the capability parameters
+    pub const ADDRESSING_64: u32 = 1 << 0;
+    pub const EXTENDED_CAPABILITIES_SHIFT: u32 = 8;
+}
+
+mod legacy {
+    pub const BIOS_OWNED: u32 = 1 << 16;
+    pub const OS_OWNED: u32 = 1 << 24;
+    /// The SMIs of the emulation, after the capability
+    pub const CONTROL_STATUS: u8 = 4;
+}
+
+/// The operational registers
+mod reg {
+    pub const COMMAND: usize = 0x00;
+    pub const STATUS: usize = 0x04;
+    pub const INTERRUPT_ENABLE: usize = 0x08;
+    pub const FRAME_INDEX: usize = 0x0C;
+    /// The high 32 bits of the addresses of the structures
+    pub const SEGMENT: usize = 0x10;
+    pub const PERIODIC_LIST_BASE: usize = 0x14;
+    pub const ASYNC_LIST_ADDRESS: usize = 0x18;
+    /// Routes all the ports to this controller, instead of the companions
+    pub const CONFIG_FLAG: usize = 0x40;
+    /// The status and control of the first port, the next ones follow
+    pub const PORTS: usize = 0x44;
+}
+
+mod command {
+    pub const RUN: u32 = 1 << 0;
+    pub const HOST_RESET: u32 = 1 << 1;
+    pub const PERIODIC_ENABLE: u32 = 1 << 4;
+    pub const ASYNC_ENABLE: u32 = 1 << 5;
+    /// At most one interrupt every 8 microframes, 1ms
+    pub const INTERRUPT_THRESHOLD_8: u32 = 8 << 16;
+}
+
+/// The low bits are cleared by writing `1` to them, the others are read only
+#[allow(dead_code)]
+mod status {
+    pub const INTERRUPT: u32 = 1 << 0;
+    pub const ERROR_INTERRUPT: u32 = 1 << 1;
+    pub const PORT_CHANGE: u32 = 1 << 2;
+    pub const FRAME_LIST_ROLLOVER: u32 = 1 << 3;
+    pub const HOST_SYSTEM_ERROR: u32 = 1 << 4;
+    pub const ASYNC_ADVANCE: u32 = 1 << 5;
+    pub const ALL: u32 = 0x3F;
+    pub const HALTED: u32 = 1 << 12;
+    pub const PERIODIC_RUNNING: u32 = 1 << 14;
+    pub const ASYNC_RUNNING: u32 = 1 << 15;
+}
+
+/// The same bits as [`status`]
+mod interrupt_enable {
+    pub const INTERRUPT: u32 = 1 << 0;
+    pub const ERROR_INTERRUPT: u32 = 1 << 1;
+    pub const HOST_SYSTEM_ERROR: u32 = 1 << 4;
+    pub const USED: u32 = INTERRUPT | ERROR_INTERRUPT | HOST_SYSTEM_ERROR;
+}
+
+mod port {
+    pub const CONNECTED: u32 = 1 << 0;
+    pub const CONNECT_CHANGE: u32 = 1 << 1;
+    /// Only set by the controller, at the end of a reset, when the device is high speed
+    pub const ENABLED: u32 = 1 << 2;
+    pub const ENABLE_CHANGE: u32 = 1 << 3;
+    pub const OVER_CURRENT_CHANGE: u32 = 1 << 5;
+    pub const RESET: u32 = 1 << 8;
+    pub const LINE_STATUS_MASK: u32 = 3 << 10;
+    /// The idle state of the lines with a low speed device
+    pub const LINE_STATUS_K: u32 = 1 << 10;
+    pub const POWER: u32 = 1 << 12;
+    pub const COMPANION_OWNED: u32 = 1 << 13;
+    /// The bits cleared by writing `1` to them
+    pub const WRITE_CLEAR: u32 = CONNECT_CHANGE | ENABLE_CHANGE | OVER_CURRENT_CHANGE;
+}
+
+/// The low bits of the links in the frame list and the QHs
+mod link {
+    pub const TERMINATE: u32 = 1 << 0;
+    pub const QH: u32 = 1 << 1;
+}
+
+/// The offsets in a QH, the overlay is the qTD being run, with the same layout
+mod qh {
+    pub const CHARACTERISTICS: usize = 4;
+    pub const CAPABILITIES: usize = 8;
+    pub const CURRENT: usize = 12;
+    pub const OVERLAY: usize = 16;
+}
+
+/// The endpoint characteristics and capabilities of a QH
+mod endpoint {
+    pub const NUMBER_SHIFT: u32 = 8;
+    pub const HIGH_SPEED: u32 = 2 << 12;
+    /// The data toggle is the one of the qTDs, instead of the one kept in the QH
+    pub const TOGGLE_FROM_QTD: u32 = 1 << 14;
+    /// The head of the asynchronous ring
+    pub const HEAD: u32 = 1 << 15;
+    pub const MAX_PACKET_SHIFT: u32 = 16;
+    /// Polled in the first microframe of a frame
+    pub const START_MASK_FIRST: u32 = 1 << 0;
+    /// One transaction each microframe it is polled in
+    pub const MULT_1: u32 = 1 << 30;
+}
+
+/// The offsets in a qTD
+mod qtd {
+    pub const NEXT: usize = 0;
+    /// Where to go on a short packet
+    pub const ALTERNATE: usize = 4;
+    pub const TOKEN: usize = 8;
+    /// The first of the 5 pages of the buffer
+    pub const BUFFER: usize = 12;
+}
+
+/// The token of a qTD, its status in the low bits
+#[allow(dead_code)]
+mod qtd_token {
+    pub const TRANSACTION: u32 = 1 << 3;
+    pub const BABBLE: u32 = 1 << 4;
+    pub const DATA_BUFFER: u32 = 1 << 5;
+    /// Set with the error that stopped the queue, it stays stopped until it's cleared
+    pub const HALTED: u32 = 1 << 6;
+    pub const ACTIVE: u32 = 1 << 7;
+    pub const PID_SHIFT: u32 = 8;
+    /// Retry 3 times before halting
+    pub const ERROR_COUNT_3: u32 = 3 << 10;
+    pub const INTERRUPT_ON_COMPLETE: u32 = 1 << 15;
+    /// The bytes left to transfer, counted down by the controller
+    pub const LEN_SHIFT: u32 = 16;
+    pub const LEN_MASK: u32 = 0x7FFF;
+    pub const TOGGLE: u32 = 1 << 31;
+}
+
+mod pid {
+    pub const OUT: u32 = 0;
+    pub const IN: u32 = 1;
+    pub const SETUP: u32 = 2;
+}
+
+/// The offsets in the schedule page, the QHs and qTDs are aligned to 32 bytes
+mod layout {
+    pub const CONTROL_QH: usize = 0x000;
+    pub const INTERRUPT_QH: usize = 0x080;
+    pub const SETUP_QTD: usize = 0x100;
+    pub const DATA_QTD: usize = 0x140;
+    pub const STATUS_QTD: usize = 0x180;
+    pub const KEYBOARD_QTD: usize = 0x1C0;
+    pub const SETUP_PACKET: usize = 0x200;
+    /// The largest packet of a full speed interrupt endpoint fits, the boot keyboards don't
+    /// need more at high speed
+    pub const REPORT: usize = 0x240;
+    pub const REPORT_MAX_LEN: usize = 64;
+    pub const CONTROL_DATA: usize = 0x400;
+}
+
+/// The sizes with the high halves of the 5 buffer pages
+const QH_SIZE: usize = 68;
+const QTD_SIZE: usize = 52;
+/// The data stage is one qTD, the data is in one page, so only its first buffer page is used
+const MAX_CONTROL_DATA: usize = 1024;
+// the structures don't overlap, and all fit in the page
+const _: () = assert!(
+    layout::CONTROL_QH + QH_SIZE <= layout::INTERRUPT_QH
+        && layout::INTERRUPT_QH + QH_SIZE <= layout::SETUP_QTD
+        && layout::KEYBOARD_QTD + QTD_SIZE <= layout::SETUP_PACKET
+        && layout::REPORT + layout::REPORT_MAX_LEN <= layout::CONTROL_DATA
+        && layout::CONTROL_DATA + MAX_CONTROL_DATA <= PAGE_4K
+);
+
+/// A high speed device on a port, with its control endpoint
+#[derive(Debug, Clone, Copy)]
+struct Target {
+    address: u8,
+    max_packet_size: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PortState {
+    Empty,
+    Failed(UsbError),
+    /// A low or full speed device, given to the companion controller
+    Companion {
+        low_speed: bool,
+    },
+    Device {
+        address: u8,
+        descriptor: DeviceDescriptor,
+        kind: &'static str,
+    },
+    /// The keyboard was there, but stopped answering
+    Removed,
+}
+
+/// The keyboard polled by the interrupt QH
+struct KeyboardPipe {
+    port: usize,
+    target: Target,
+    interface: BootKeyboardInterface,
+    toggle: bool,
+    hid: BootKeyboard,
+    leds: u8,
+    errors: u32,
+}
+
+/// The token of an active qTD of `len` bytes
+fn token(pid: u32, toggle: bool, len: usize, interrupt: bool) -> u32 {
+    let mut token = qtd_token::ACTIVE
+        | qtd_token::ERROR_COUNT_3
+        | pid << qtd_token::PID_SHIFT
+        | (len as u32 & qtd_token::LEN_MASK) << qtd_token::LEN_SHIFT;
+    if interrupt {
+        token |= qtd_token::INTERRUPT_ON_COMPLETE;
+    }
+    if toggle {
+        token |= qtd_token::TOGGLE;
+    }
+    token
+}
+
+/// The length transferred by a qTD of `len` bytes, from its token
+fn actual_len(token: u32, len: usize) -> usize {
+    let left = (token >> qtd_token::LEN_SHIFT) & qtd_token::LEN_MASK;
+    len.saturating_sub(left as usize)
+}
+
+/// The error of a qTD that halted its queue, the transaction errors are also set on the
+/// retries that work after, so they only count with the halt, and a halt alone is a stall
+fn qtd_error(token: u32) -> Option<UsbError> {
+    if token & qtd_token::HALTED == 0 {
+        None
+    } else if token & qtd_token::BABBLE != 0 {
+        Some(UsbError::Babble)
+    } else if token & qtd_token::DATA_BUFFER != 0 {
+        Some(UsbError::DataBuffer)
+    } else if token & qtd_token::TRANSACTION != 0 {
+        Some(UsbError::CrcTimeout)
+    } else {
+        Some(UsbError::Stall)
+    }
+}
+
+/// The endpoint characteristics of a QH for `endpoint` of `target`
+fn characteristics(target: Target, endpoint: u8, max_packet_size: u16) -> u32 {
+    target.address as u32
+        | (endpoint as u32) << endpoint::NUMBER_SHIFT
+        | endpoint::HIGH_SPEED
+        | endpoint::TOGGLE_FROM_QTD
+        | (max_packet_size as u32) << endpoint::MAX_PACKET_SHIFT
+}
+
+/// Takes the controller from the BIOS, if it has the legacy support capability, and stops the
+/// emulation
+fn take_from_bios(config: &PciDeviceConfig, capability_params: u32) {
+    let mut offset = (capability_params >> params::EXTENDED_CAPABILITIES_SHIFT) as u8;
+    while offset >= EXTENDED_CAPABILITIES_MIN {
+        let capability: u32 = config.read_config(offset);
+        if capability & 0xFF == LEGACY_SUPPORT_ID {
+            config.write_config::<u32>(offset, capability | legacy::OS_OWNED);
+            let released = (0..BIOS_HANDOFF_POLLS)
+                .any(|_| config.read_config::<u32>(offset) & legacy::BIOS_OWNED == 0);
+            if !released {
+                eprintln!(
+                    "ehci {:02X}.{:02X}.{:02X}: the BIOS doesn't give the controller, taking it",
+                    config.bus, config.dev, config.func
+                );
+                config.write_config::<u32>(offset, legacy::OS_OWNED);
+            }
+            config.write_config::<u32>(offset + legacy::CONTROL_STATUS, 0);
+            return;
+        }
+        offset = (capability >> 8) as u8;
+    }
+}
+
+pub struct Ehci {
+    regs: MmioRegion,
+    /// The physical address of `regs`
+    mmio: u64,
+    /// Where the operational registers are in `regs`, after the capability ones
+    operational: usize,
+    /// bus, device and function
+    pci: (u8, u8, u8),
+    frame_list: DmaPage,
+    schedule: DmaPage,
+    ports: Vec<PortState>,
+    keyboard: Option<KeyboardPipe>,
+    next_address: u8,
+}
+
+impl Ehci {
+    /// Resets the controller, takes all the ports from the companion controllers, and starts
+    /// running the empty schedule
+    fn new(config: &PciDeviceConfig) -> Result<Self, UsbError> {
+        let (mmio, size, _) = config.base_address[BAR_MMIO]
+            .get_memory()
+            .ok_or(UsbError::NoRegisters)?;
+        config
+            .write_command(config.read_command() | PCI_COMMAND_MEM_SPACE | PCI_COMMAND_BUS_MASTER);
+        if config.read_command() & PCI_COMMAND_MEM_SPACE == 0 {
+            return Err(UsbError::NoRegisters);
+        }
+        let regs = MmioRegion::map(mmio, size as usize);
+        let operational = (regs.read_u32(cap::LENGTH) & 0xFF) as usize;
+        let structural_params = regs.read_u32(cap::STRUCTURAL_PARAMS);
+        let capability_params = regs.read_u32(cap::CAPABILITY_PARAMS);
+        let port_count = (structural_params & params::PORT_COUNT_MASK) as usize;
+        if operational + reg::PORTS + 4 * port_count > size as usize {
+            return Err(UsbError::NoRegisters);
+        }
+        take_from_bios(config, capability_params);
+
+        let controller = Self {
+            regs,
+            mmio,
+            operational,
+            pci: (config.bus, config.dev, config.func),
+            frame_list: DmaPage::alloc()?,
+            schedule: DmaPage::alloc()?,
+            ports: vec![PortState::Empty; port_count],
+            keyboard: None,
+            next_address: 1,
+        };
+        controller.write(reg::INTERRUPT_ENABLE, 0);
+        // it must be stopped before the reset
+        controller.write(reg::COMMAND, 0);
+        if !(0..RESET_POLLS).any(|_| controller.is_halted()) {
+            return Err(UsbError::ResetTimeout);
+        }
+        controller.write(reg::COMMAND, command::HOST_RESET);
+        if !(0..RESET_POLLS).any(|_| controller.read(reg::COMMAND) & command::HOST_RESET == 0) {
+            return Err(UsbError::ResetTimeout);
+        }
+        if capability_params & params::ADDRESSING_64 != 0 {
+            controller.write(reg::SEGMENT, 0);
+        }
+        controller.start(structural_params & params::PORT_POWER_CONTROL != 0);
+        Ok(controller)
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        self.regs.read_u32(self.operational + offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.regs.write_u32(self.operational + offset, value)
+    }
+
+    fn start(&self, power_ports: bool) {
+        // the keyboard QH is all the periodic schedule, idle until the keyboard is attached
+        let interrupt_qh = self.schedule.physical(layout::INTERRUPT_QH);
+        self.schedule.write(layout::INTERRUPT_QH, link::TERMINATE);
+        self.schedule.write(
+            layout::INTERRUPT_QH + qh::OVERLAY + qtd::NEXT,
+            link::TERMINATE,
+        );
+        self.schedule.write(
+            layout::INTERRUPT_QH + qh::OVERLAY + qtd::ALTERNATE,
+            link::TERMINATE,
+        );
+        for frame in 0..FRAME_COUNT {
+            self.frame_list.write(frame * 4, interrupt_qh | link::QH);
+        }
+        atomic::fence(Ordering::SeqCst);
+
+        self.write(reg::PERIODIC_LIST_BASE, self.frame_list.physical(0));
+        self.write(reg::FRAME_INDEX, 0);
+        self.write(reg::STATUS, status::ALL);
+        self.write(reg::INTERRUPT_ENABLE, interrupt_enable::USED);
+        // the frame list size bits `0` are 1024 frames
+        self.write(
+            reg::COMMAND,
+            command::RUN | command::PERIODIC_ENABLE | command::INTERRUPT_THRESHOLD_8,
+        );
+        // the ports are ours from here, `enumerate_port` gives back the slower devices
+        self.write(reg::CONFIG_FLAG, 1);
+        if power_ports {
+            for port in 0..self.ports.len() {
+                self.write(Self::port_reg(port), self.port_bits(port) | port::POWER);
+            }
+            self.wait_frames(PORT_POWER_FRAMES, |_| false);
+        }
+    }
+
+    fn is_halted(&self) -> bool {
+        self.read(reg::STATUS) & status::HALTED != 0
+    }
+
+    /// Waits until `done`, for at most `frames` milliseconds, the frame index counts them
+    /// while the controller runs. Returns whether `done` was reached
+    fn wait_frames(&self, frames: u16, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let start = self.read(reg::FRAME_INDEX);
+        loop {
+            if done(self) {
+                return true;
+            }
+            let elapsed = (self.read(reg::FRAME_INDEX).wrapping_sub(start) & FRAME_INDEX_MASK)
+                / MICROFRAMES_PER_FRAME;
+            // the frames don't move when halted
+            if elapsed >= frames as u32 || self.is_halted() {
+                return done(self);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Turns the schedule of the `enable` command bit on or off, and waits for its `running`
+    /// status bit to follow, the controller can't be told again before
+    fn switch_schedule(&self, enable: u32, running: u32, on: bool) {
+        let command = self.read(reg::COMMAND);
+        let command = if on {
+            command | enable
+        } else {
+            command & !enable
+        };
+        self.write(reg::COMMAND, command);
+        self.wait_frames(SCHEDULE_SWITCH_FRAMES, |ehci| {
+            (ehci.read(reg::STATUS) & running != 0) == on
+        });
+    }
+
+    fn port_reg(port: usize) -> usize {
+        reg::PORTS + 4 * port
+    }
+
+    /// The status and control of `port` to write back with a change, without the bits that
+    /// act when written
+    fn port_bits(&self, port: usize) -> u32 {
+        self.read(Self::port_reg(port)) & !(port::WRITE_CLEAR | port::ENABLED)
+    }
+
+    fn is_connected(&self, port: usize) -> bool {
+        self.read(Self::port_reg(port)) & port::CONNECTED != 0
+    }
+
+    fn give_to_companion(&self, port: usize) {
+        self.write(
+            Self::port_reg(port),
+            self.port_bits(port) | port::COMPANION_OWNED,
+        );
+    }
+
+    /// Resets the device on `port`, returns whether it is high speed, the port is only enabled
+    /// then
+    fn reset_port(&self, port: usize) -> Result<bool, UsbError> {
+        let reg = Self::port_reg(port);
+        // the port is disabled while it resets
+        self.write(reg, self.port_bits(port) | port::RESET);
+        self.wait_frames(PORT_RESET_FRAMES, |_| false);
+        self.write(reg, self.port_bits(port) & !port::RESET);
+        if !self.wait_frames(PORT_RECOVERY_FRAMES, |ehci| {
+            ehci.read(reg) & port::RESET == 0
+        }) {
+            return Err(UsbError::ResetTimeout);
+        }
+        if !self.is_connected(port) {
+            return Err(UsbError::Disconnected);
+        }
+        if self.read(reg) & port::ENABLED == 0 {
+            return Ok(false);
+        }
+        // the device needs this long after its reset before the first request
+        self.wait_frames(PORT_RECOVERY_FRAMES, |_| false);
+        Ok(true)
+    }
+
+    fn write_qtd(&self, offset: usize, next: u32, alternate: u32, token: u32, buffer: u32) {
+        self.schedule.write(offset + qtd::NEXT, next);
+        self.schedule.write(offset + qtd::ALTERNATE, alternate);
+        // the buffers don't cross a page, the other buffer pages stay `0`
+        self.schedule.write(offset + qtd::BUFFER, buffer);
+        self.schedule.write(offset + qtd::TOKEN, token);
+    }
+
+    /// Runs a control transfer on the endpoint `0` of `target`, returns the length of the data
+    /// stage transferred, which can be less than asked for an IN
+    fn control(
+        &self,
+        target: Target,
+        setup: SetupPacket,
+        data: ControlData,
+    ) -> Result<usize, UsbError> {
+        let (data_len, data_in) = match &data {
+            ControlData::None => (0, false),
+            ControlData::In(buf) => (buf.len(), true),
+            ControlData::Out(buf) => (buf.len(), false),
+        };
+        assert!(data_len == setup.length as usize && data_len <= MAX_CONTROL_DATA);
+        if let ControlData::Out(buf) = &data {
+            self.schedule
+                .bytes_mut(layout::CONTROL_DATA, data_len)
+                .copy_from_slice(buf);
+        }
+        self.schedule
+            .bytes_mut(layout::SETUP_PACKET, 8)
+            .copy_from_slice(&setup.to_bytes());
+
+        let status_qtd = self.schedule.physical(layout::STATUS_QTD);
+        let after_setup = if data_len == 0 {
+            status_qtd
+        } else {
+            self.schedule.physical(layout::DATA_QTD)
+        };
+        self.write_qtd(
+            layout::SETUP_QTD,
+            after_setup,
+            link::TERMINATE,
+            token(pid::SETUP, false, 8, false),
+            self.schedule.physical(layout::SETUP_PACKET),
+        );
+        if data_len != 0 {
+            let data_pid = if data_in { pid::IN } else { pid::OUT };
+            // the data starts with DATA1, after the setup, a short packet ends it and the
+            // controller goes on with the alternate, the status stage
+            self.write_qtd(
+                layout::DATA_QTD,
+                status_qtd,
+                status_qtd,
+                token(data_pid, true, data_len, false),
+                self.schedule.physical(layout::CONTROL_DATA),
+            );
+        }
+        let status_pid = if data_in { pid::OUT } else { pid::IN };
+        self.write_qtd(
+            layout::STATUS_QTD,
+            link::TERMINATE,
+            link::TERMINATE,
+            token(status_pid, true, 0, false),
+            0,
+        );
+
+        // the QH is alone in the ring, and starts from the setup with an empty overlay
+        let control_qh = layout::CONTROL_QH;
+        self.schedule
+            .write(control_qh, self.schedule.physical(control_qh) | link::QH);
+        self.schedule.write(
+            control_qh + qh::CHARACTERISTICS,
+            characteristics(target, 0, target.max_packet_size) | endpoint::HEAD,
+        );
+        self.schedule
+            .write(control_qh + qh::CAPABILITIES, endpoint::MULT_1);
+        self.schedule.write(control_qh + qh::CURRENT, 0);
+        self.schedule.write(
+            control_qh + qh::OVERLAY + qtd::NEXT,
+            self.schedule.physical(layout::SETUP_QTD),
+        );
+        self.schedule
+            .write(control_qh + qh::OVERLAY + qtd::ALTERNATE, link::TERMINATE);
+        for offset in (qh::OVERLAY + qtd::TOKEN..QH_SIZE).step_by(4) {
+            self.schedule.write(control_qh + offset, 0);
+        }
+        atomic::fence(Ordering::SeqCst);
+        self.write(reg::ASYNC_LIST_ADDRESS, self.schedule.physical(control_qh));
+        self.switch_schedule(command::ASYNC_ENABLE, status::ASYNC_RUNNING, true);
+
+        let mut result = None;
+        self.wait_frames(CONTROL_TIMEOUT_FRAMES, |ehci| {
+            result = ehci.control_progress(data_len);
+            result.is_some()
+        });
+        // stop the ring whatever happened, the QH and qTDs are used by the next transfer
+        self.switch_schedule(command::ASYNC_ENABLE, status::ASYNC_RUNNING, false);
+        atomic::fence(Ordering::SeqCst);
+
+        let result = result.unwrap_or(Err(if self.is_halted() {
+            UsbError::Halted
+        } else {
+            UsbError::Timeout
+        }));
+        let transferred = result?;
+        if let ControlData::In(buf) = data {
+            buf[..transferred]
+                .copy_from_slice(self.schedule.bytes(layout::CONTROL_DATA, transferred));
+        }
+        Ok(transferred)
+    }
+
+    /// The result of the control transfer with `data_len` bytes of data if it is done, which
+    /// is when the status stage is, or a qTD halted the queue
+    fn control_progress(&self, data_len: usize) -> Option<Result<usize, UsbError>> {
+        let data_qtd = (data_len != 0).then_some(layout::DATA_QTD);
+        let qtds = [Some(layout::SETUP_QTD), data_qtd, Some(layout::STATUS_QTD)];
+        for offset in qtds.into_iter().flatten() {
+            if let Some(e) = qtd_error(self.schedule.read(offset + qtd::TOKEN)) {
+                return Some(Err(e));
+            }
+        }
+        if self.schedule.read(layout::STATUS_QTD + qtd::TOKEN) & qtd_token::ACTIVE != 0 {
+            return None;
+        }
+        Some(Ok(data_qtd.map_or(0, |offset| {
+            actual_len(self.schedule.read(offset + qtd::TOKEN), data_len)
+        })))
+    }
+
+    /// Gives the device on `port` an address, and starts using it if it is a boot keyboard.
+    /// The low and full speed devices are given to the companion controller instead
+    fn enumerate_port(&mut self, port: usize) -> Result<PortState, UsbError> {
+        if !self.is_connected(port) {
+            return Err(UsbError::Disconnected);
+        }
+        // a low speed device is known before the reset, a full speed one after it
+        let port_status = self.read(Self::port_reg(port));
+        if port_status & port::LINE_STATUS_MASK == port::LINE_STATUS_K {
+            self.give_to_companion(port);
+            return Ok(PortState::Companion { low_speed: true });
+        }
+        if !self.reset_port(port)? {
+            self.give_to_companion(port);
+            return Ok(PortState::Companion { low_speed: false });
+        }
+
+        let mut target = Target {
+            address: 0,
+            max_packet_size: HIGH_SPEED_MAX_PACKET0,
+        };
+        let mut device = [0; DEVICE_DESCRIPTOR_LEN];
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::DEVICE, 8),
+            ControlData::In(&mut device[..8]),
+        )?;
+        target.max_packet_size = DeviceDescriptor::parse(&device[..len])?.max_packet_size0 as u16;
+
+        let address = self.next_address;
+        self.control(target, SetupPacket::set_address(address), ControlData::None)?;
+        self.next_address += 1;
+        self.wait_frames(SET_ADDRESS_RECOVERY_FRAMES, |_| false);
+        target.address = address;
+
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::DEVICE, DEVICE_DESCRIPTOR_LEN as u16),
+            ControlData::In(&mut device),
+        )?;
+        let descriptor = DeviceDescriptor::parse(&device[..len])?;
+        let device_state = |kind| PortState::Device {
+            address,
+            descriptor,
+            kind,
+        };
+        if descriptor.class == CLASS_HUB {
+            return Ok(device_state("hub, not supported"));
+        }
+
+        // the descriptors of the interfaces and endpoints come after the configuration, read
+        // its start to know how much there is
+        let mut configuration = [0; MAX_CONTROL_DATA];
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(
+                descriptor::CONFIGURATION,
+                super::CONFIGURATION_DESCRIPTOR_LEN as u16,
+            ),
+            ControlData::In(&mut configuration[..super::CONFIGURATION_DESCRIPTOR_LEN]),
+        )?;
+        let total_len =
+            super::configuration_total_len(&configuration[..len])?.min(MAX_CONTROL_DATA);
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::CONFIGURATION, total_len as u16),
+            ControlData::In(&mut configuration[..total_len]),
+        )?;
+        let kind = match super::find_boot_keyboard(&configuration[..len])? {
+            Some(interface) if self.keyboard.is_none() => {
+                self.attach_keyboard(port, target, interface)?;
+                "boot keyboard"
+            }
+            Some(_) => "boot keyboard, another one is used",
+            None => "not supported",
+        };
+        Ok(device_state(kind))
+    }
+
+    fn attach_keyboard(
+        &mut self,
+        port: usize,
+        target: Target,
+        interface: BootKeyboardInterface,
+    ) -> Result<(), UsbError> {
+        let packet_size = interface.max_packet_size as usize;
+        if !(BOOT_REPORT_LEN..=layout::REPORT_MAX_LEN).contains(&packet_size) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        self.control(
+            target,
+            SetupPacket::set_configuration(interface.configuration),
+            ControlData::None,
+        )?;
+        self.control(
+            target,
+            SetupPacket::hid_set_boot_protocol(interface.interface),
+            ControlData::None,
+        )?;
+        // boot keyboards can refuse it, then they repeat their reports, which changes nothing
+        match self.control(
+            target,
+            SetupPacket::hid_set_idle(interface.interface),
+            ControlData::None,
+        ) {
+            Ok(_) | Err(UsbError::Stall) => {}
+            Err(e) => return Err(e),
+        }
+
+        // the QH is idle, its overlay has no next qTD yet
+        self.schedule.write(
+            layout::INTERRUPT_QH + qh::CHARACTERISTICS,
+            characteristics(target, interface.endpoint, interface.max_packet_size),
+        );
+        self.schedule.write(
+            layout::INTERRUPT_QH + qh::CAPABILITIES,
+            endpoint::MULT_1 | endpoint::START_MASK_FIRST,
+        );
+        self.keyboard = Some(KeyboardPipe {
+            port,
+            target,
+            interface,
+            toggle: false,
+            hid: BootKeyboard::new(),
+            leds: 0,
+            errors: 0,
+        });
+        // the lock keys could have been set by another keyboard
+        let locks = keyboard::get_keyboard().lock().lock_keys();
+        self.set_leds(locks);
+        self.arm_keyboard();
+        Ok(())
+    }
+
+    /// Queues the qTD for the next report of the keyboard
+    fn arm_keyboard(&self) {
+        let Some(pipe) = &self.keyboard else {
+            return;
+        };
+        let len = pipe.interface.max_packet_size as usize;
+        self.write_qtd(
+            layout::KEYBOARD_QTD,
+            link::TERMINATE,
+            link::TERMINATE,
+            token(pid::IN, pipe.toggle, len, true),
+            self.schedule.physical(layout::REPORT),
+        );
+        atomic::fence(Ordering::SeqCst);
+        let overlay = layout::INTERRUPT_QH + qh::OVERLAY;
+        self.schedule.write(
+            overlay + qtd::NEXT,
+            self.schedule.physical(layout::KEYBOARD_QTD),
+        );
+        self.schedule
+            .write(overlay + qtd::ALTERNATE, link::TERMINATE);
+        // clears the halt of a failed report, the controller then takes the next qTD
+        self.schedule.write(overlay + qtd::TOKEN, 0);
+    }
+
+    /// Sends the LEDs to the keyboard, if they changed
+    fn set_leds(&mut self, locks: LockKeys) {
+        let Some(pipe) = &self.keyboard else {
+            return;
+        };
+        let leds = super::leds_report(locks);
+        if leds == pipe.leds {
+            return;
+        }
+        let setup = SetupPacket::hid_set_output_report(pipe.interface.interface, 1);
+        match self.control(pipe.target, setup, ControlData::Out(&[leds])) {
+            Ok(_) => {
+                if let Some(pipe) = &mut self.keyboard {
+                    pipe.leds = leds;
+                }
+            }
+            Err(e) => {
+                eprintln!("usb: failed to set the keyboard LEDs: {e:?}");
+            }
+        }
+    }
+
+    /// Stops polling the keyboard, and returns the scancodes releasing the keys it had down
+    fn detach_keyboard(&mut self) -> Option<Vec<u8>> {
+        let mut pipe = self.keyboard.take()?;
+        // the keyboard is all the periodic schedule
+        self.switch_schedule(command::PERIODIC_ENABLE, status::PERIODIC_RUNNING, false);
+        self.ports[pipe.port] = PortState::Removed;
+        let mut scancodes = Vec::new();
+        pipe.hid.release_all(&mut scancodes);
+        Some(scancodes)
+    }
+
+    /// Handles the interrupt of this controller, returns the scancodes of the keyboard if it
+    /// sent a report
+    fn service(&mut self) -> Option<Vec<u8>> {
+        let pending = self.read(reg::STATUS);
+        if pending & interrupt_enable::USED == 0 {
+            return None;
+        }
+        self.write(reg::STATUS, pending & status::ALL);
+        if pending & status::HOST_SYSTEM_ERROR != 0 {
+            eprintln!(
+                "ehci {}: stopped by an error, status {pending:#X}",
+                self.name()
+            );
+            return self.detach_keyboard();
+        }
+
+        let report_token = self.schedule.read(layout::KEYBOARD_QTD + qtd::TOKEN);
+        let pipe = self.keyboard.as_mut()?;
+        if report_token & qtd_token::ACTIVE != 0 {
+            // a control transfer failed, it is handled where it waits
+            return None;
+        }
+        let mut scancodes = Vec::new();
+        match qtd_error(report_token) {
+            None => {
+                let len = actual_len(report_token, pipe.interface.max_packet_size as usize);
+                pipe.toggle = !pipe.toggle;
+                pipe.errors = 0;
+                pipe.hid
+                    .report(self.schedule.bytes(layout::REPORT, len), &mut scancodes);
+            }
+            Some(error) => {
+                pipe.errors += 1;
+                let (port, target, endpoint) = (pipe.port, pipe.target, pipe.interface.endpoint);
+                if pipe.errors > MAX_KEYBOARD_ERRORS || !self.is_connected(port) {
+                    eprintln!("usb: keyboard on port {} removed: {error:?}", port + 1);
+                    return self.detach_keyboard();
+                }
+                if error == UsbError::Stall {
+                    // the endpoint is halted until cleared, and starts over from DATA0
+                    let setup = SetupPacket::clear_endpoint_halt(endpoint);
+                    if let Err(e) = self.control(target, setup, ControlData::None) {
+                        eprintln!("usb: failed to clear the keyboard halt: {e:?}");
+                    }
+                    if let Some(pipe) = &mut self.keyboard {
+                        pipe.toggle = false;
+                    }
+                }
+            }
+        }
+        self.arm_keyboard();
+        Some(scancodes)
+    }
+
+    fn name(&self) -> String {
+        let (bus, dev, func) = self.pci;
+        alloc::format!("{bus:02X}.{dev:02X}.{func:02X}")
+    }
+}
+
+impl Drop for Ehci {
+    fn drop(&mut self) {
+        self.write(reg::INTERRUPT_ENABLE, 0);
+        self.write(reg::COMMAND, 0);
+        // the pages can only be freed when the controller stopped reading them
+        if !(0..RESET_POLLS).any(|_| self.is_halted()) {
+            eprintln!("ehci {}: doesn't stop, leaking its pages", self.name());
+            self.frame_list.leak();
+            self.schedule.leak();
+        }
+        // the ports go back to the companion controllers
+        self.write(reg::CONFIG_FLAG, 0);
+    }
+}
+
+extern "x86-interrupt" fn ehci_interrupt(_stack_frame: InterruptStackFrame64) {
+    let count = CONTROLLERS.lock().len();
+    for index in 0..count {
+        let Some(scancodes) = CONTROLLERS.lock()[index].service() else {
+            continue;
+        };
+        // the keyboard driver takes the console, the controllers are not held meanwhile
+        if let Some(locks) = keyboard::feed_scancodes(&scancodes) {
+            CONTROLLERS.lock()[index].set_leds(locks);
+        }
+    }
+
+    apic::return_from_interrupt();
+}
+
+/// Takes the EHCI controllers (PCI class `0x0C03`, interface `0x20`), resets and starts them,
+/// their ports are enumerated later by [`enumerate`]
+pub fn try_register(config: &PciDeviceConfig) -> bool {
+    let PciDeviceType::SerialBusController(0x03, 0x20, _) = config.device_type else {
+        return false;
+    };
+    let controller = match Ehci::new(config) {
+        Ok(controller) => controller,
+        Err(e) => {
+            eprintln!(
+                "ehci {:02X}.{:02X}.{:02X}: failed to start: {e:?}",
+                config.bus, config.dev, config.func
+            );
+            return false;
+        }
+    };
+    CONTROLLERS.lock().push(controller);
+
+    match config.route_intx() {
+        Some(route) => {
+            let mut interrupts = INTERRUPTS.lock();
+            if !interrupts.contains(&route.gsi) {
+                if apic::is_gsi_free(route.gsi) {
+                    route.assign(ehci_interrupt as BasicInterruptHandler, cpu::cpu());
+                    interrupts.push(route.gsi);
+                } else {
+                    println!("WARNING: EHCI interrupt {route} is shared, not supported yet");
+                }
+            }
+        }
+        None => {
+            println!("WARNING: EHCI has no interrupt, its keyboard won't work");
+        }
+    }
+    true
+}
+
+/// Enumerates the ports of all the controllers, returns the number of controllers and of
+/// keyboards in use. Must be called before [`uhci::enumerate`](super::uhci::enumerate), the
+/// companion controllers only see the devices they are given here
+pub fn enumerate() -> (usize, usize) {
+    let mut controllers = CONTROLLERS.lock();
+    for controller in controllers.iter_mut() {
+        for port in 0..controller.ports.len() {
+            controller.ports[port] = match controller.enumerate_port(port) {
+                Ok(state) => state,
+                Err(UsbError::Disconnected) => PortState::Empty,
+                Err(e) => {
+                    eprintln!("ehci {}: port {}: {e:?}", controller.name(), port + 1);
+                    PortState::Failed(e)
+                }
+            };
+        }
+    }
+    let keyboards = controllers
+        .iter()
+        .filter(|controller| controller.keyboard.is_some())
+        .count();
+    (controllers.len(), keyboards)
+}
+
+/// The state of the controllers and their ports, for `/devices/usb`
+pub fn describe(out: &mut String) {
+    for controller in CONTROLLERS.lock().iter() {
+        let _ = writeln!(
+            out,
+            "ehci {}: mmio {:#X}, {} ports{}",
+            controller.name(),
+            controller.mmio,
+            controller.ports.len(),
+            if controller.is_halted() {
+                ", halted"
+            } else {
+                ""
+            }
+        );
+        for (port, state) in controller.ports.iter().enumerate() {
+            let _ = write!(out, "  port {}: ", port + 1);
+            let _ = match state {
+                PortState::Empty => writeln!(out, "empty"),
+                PortState::Failed(e) => writeln!(out, "failed: {e:?}"),
+                PortState::Companion { low_speed } => writeln!(
+                    out,
+                    "{} speed, given to the companion controller",
+                    if *low_speed { "low" } else { "full" }
+                ),
+                PortState::Device {
+                    address,
+                    descriptor,
+                    kind,
+                } => writeln!(
+                    out,
+                    "{:04x}:{:04x}, address {address}, high speed, {kind}",
+                    descriptor.vendor_id, descriptor.product_id
+                ),
+                PortState::Removed => writeln!(out, "keyboard removed"),
+            };
+        }
+    }
+}
+
+/// Checks the encoding of the qTDs and QHs
+pub(super) fn run_self_tests() {
+    // the setup, DATA0, 8 bytes
+    let setup = token(pid::SETUP, false, 8, false);
+    assert_eq!(setup, 0x0008_0E80);
+    // a status stage without data
+    assert_eq!(token(pid::IN, true, 0, false), 0x8000_0D80);
+    // the report of a keyboard, interrupting when done
+    assert_eq!(token(pid::IN, false, 8, true), 0x0008_8D80);
+
+    // the length counts down, from the one asked
+    assert_eq!(actual_len(setup, 8), 0);
+    assert_eq!(actual_len(3 << qtd_token::LEN_SHIFT, 8), 5);
+    assert_eq!(actual_len(0, 8), 8);
+    // a transaction error that worked on a retry
+    assert_eq!(qtd_error(qtd_token::TRANSACTION), None);
+    assert_eq!(qtd_error(qtd_token::HALTED), Some(UsbError::Stall));
+    assert_eq!(
+        qtd_error(qtd_token::HALTED | qtd_token::TRANSACTION),
+        Some(UsbError::CrcTimeout)
+    );
+    assert_eq!(
+        qtd_error(qtd_token::HALTED | qtd_token::BABBLE),
+        Some(UsbError::Babble)
+    );
+
+    let target = Target {
+        address: 5,
+        max_packet_size: 64,
+    };
+    assert_eq!(characteristics(target, 0, 64), 0x0040_6005);
+    assert_eq!(characteristics(target, 1, 8), 0x0008_6105);
+}
@@ -0,0 +1,542 @@
+//! USB, for keyboards on machines without a PS/2 controller.
+//!
+//! Only what a boot keyboard on a root port needs is here: the control requests to enumerate
+//! and configure it, and the parsing of its descriptors. The host controller drivers
+//! ([`ehci`] for the high speed devices, [`uhci`] for the others) do the transfers, and give the
+//! reports of the keyboard to [`kernel_core::hid::BootKeyboard`], which makes them scancodes for
+//! the keyboard driver.
+//!
+//! `/devices/usb` lists the controllers and what is on their ports.
+
+pub mod ehci;
+pub mod uhci;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use kernel_core::hid::{self, BootKeyboard};
+
+use crate::{
+    devices::{self, Device},
+    fs::FileSystemError,
+    io::keyboard::LockKeys,
+    memory_management::{
+        memory_layout::{virtual2physical, PAGE_4K},
+        physical_page_allocator,
+    },
+};
+
+/// The lengths of the descriptors we read
+const DEVICE_DESCRIPTOR_LEN: usize = 18;
+const CONFIGURATION_DESCRIPTOR_LEN: usize = 9;
+
+mod descriptor {
+    pub const DEVICE: u8 = 1;
+    pub const CONFIGURATION: u8 = 2;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+}
+
+mod request_type {
+    pub const DEVICE_TO_HOST: u8 = 1 << 7;
+    pub const CLASS: u8 = 1 << 5;
+    pub const INTERFACE: u8 = 1;
+    pub const ENDPOINT: u8 = 2;
+}
+
+mod request {
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_CONFIGURATION: u8 = 9;
+    // of the HID class
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0A;
+    pub const SET_PROTOCOL: u8 = 0x0B;
+}
+
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+const ENDPOINT_IN: u8 = 1 << 7;
+const ENDPOINT_TYPE_INTERRUPT: u8 = 3;
+
+const CLASS_HID: u8 = 3;
+const CLASS_HUB: u8 = 9;
+const HID_SUBCLASS_BOOT: u8 = 1;
+const HID_PROTOCOL_KEYBOARD: u8 = 1;
+const HID_REPORT_OUTPUT: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The device refused the request, or the endpoint is halted
+    Stall,
+    /// The device didn't answer, or the answer was corrupted, on all the retries
+    CrcTimeout,
+    BitStuff,
+    /// The device sent more than the transfer could take
+    Babble,
+    /// The controller couldn't reach the memory in time
+    DataBuffer,
+    /// The transfer didn't finish in time
+    Timeout,
+    /// The controller stopped running, after an error of its own
+    Halted,
+    /// Nothing on the port, or it left during the enumeration
+    Disconnected,
+    InvalidDescriptor,
+    OutOfMemory,
+    /// The controller, or a port, didn't finish its reset
+    ResetTimeout,
+    /// The controller has no IO ports, or didn't enable them
+    NoIoPorts,
+    /// The controller has no memory registers, or didn't enable them
+    NoRegisters,
+}
+
+/// The 8 bytes starting a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// The length of the data stage, and its direction is in `request_type`
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[0] = self.request_type;
+        bytes[1] = self.request;
+        bytes[2..4].copy_from_slice(&self.value.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.index.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the first `length` bytes of the descriptor `kind` (the first one of its kind)
+    pub fn get_descriptor(kind: u8, length: u16) -> Self {
+        Self {
+            request_type: request_type::DEVICE_TO_HOST,
+            request: request::GET_DESCRIPTOR,
+            value: (kind as u16) << 8,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_address(address: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: request::SET_ADDRESS,
+            value: address as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    pub fn set_configuration(configuration: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: request::SET_CONFIGURATION,
+            value: configuration as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Resumes the IN `endpoint` after it stalled
+    pub fn clear_endpoint_halt(endpoint: u8) -> Self {
+        Self {
+            request_type: request_type::ENDPOINT,
+            request: request::CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: (endpoint | ENDPOINT_IN) as u16,
+            length: 0,
+        }
+    }
+
+    /// Selects the boot protocol of a HID `interface`, its reports are then the fixed ones
+    pub fn hid_set_boot_protocol(interface: u8) -> Self {
+        Self {
+            request_type: request_type::CLASS | request_type::INTERFACE,
+            request: request::SET_PROTOCOL,
+            value: 0,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    /// Makes the keyboard report only when its keys change, instead of repeating the last
+    /// report
+    pub fn hid_set_idle(interface: u8) -> Self {
+        Self {
+            request_type: request_type::CLASS | request_type::INTERFACE,
+            request: request::SET_IDLE,
+            value: 0,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    /// Sends an output report of `length` bytes, the LEDs of a boot keyboard
+    pub fn hid_set_output_report(interface: u8, length: u16) -> Self {
+        Self {
+            request_type: request_type::CLASS | request_type::INTERFACE,
+            request: request::SET_REPORT,
+            value: HID_REPORT_OUTPUT << 8,
+            index: interface as u16,
+            length,
+        }
+    }
+}
+
+/// The data stage of a control transfer
+enum ControlData<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+/// A zeroed page the controller can reach, freed when dropped
+struct DmaPage(*mut u8);
+
+// SAFETY: the page is only used by the controller owning it, inside the `CONTROLLERS` of its
+// driver
+unsafe impl Send for DmaPage {}
+
+impl DmaPage {
+    fn alloc() -> Result<Self, UsbError> {
+        // SAFETY: the allocator is initialized before the PCI probe
+        let page = unsafe { physical_page_allocator::try_alloc() }.ok_or(UsbError::OutOfMemory)?;
+        let page = Self(page);
+        if virtual2physical(page.0 as usize) + PAGE_4K > u32::MAX as usize + 1 {
+            return Err(UsbError::OutOfMemory);
+        }
+        unsafe { page.0.write_bytes(0, PAGE_4K) };
+        Ok(page)
+    }
+
+    fn physical(&self, offset: usize) -> u32 {
+        (virtual2physical(self.0 as usize) + offset) as u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= PAGE_4K);
+        unsafe { (self.0.add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        assert!(offset + 4 <= PAGE_4K);
+        unsafe { (self.0.add(offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= PAGE_4K);
+        unsafe { core::slice::from_raw_parts(self.0.add(offset), len) }
+    }
+
+    /// Only taken while the controller is not using this part of the page
+    #[allow(clippy::mut_from_ref)]
+    fn bytes_mut(&self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= PAGE_4K);
+        unsafe { core::slice::from_raw_parts_mut(self.0.add(offset), len) }
+    }
+
+    /// Gives up on the page without freeing it, when the controller may still write to it
+    fn leak(&mut self) {
+        self.0 = core::ptr::null_mut();
+    }
+}
+
+impl Drop for DmaPage {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // SAFETY: allocated in `alloc`, and the controller is stopped
+            unsafe { physical_page_allocator::free(self.0) };
+        }
+    }
+}
+
+/// The fields we use of a device descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub class: u8,
+    /// Of the control endpoint, the first 8 bytes of the descriptor can always be read in one
+    /// packet to get it
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceDescriptor {
+    /// Parses the first 8 bytes of the descriptor, only the class and `max_packet_size0`, or
+    /// all of it
+    pub fn parse(bytes: &[u8]) -> Result<Self, UsbError> {
+        if bytes.len() < 8 || bytes[1] != descriptor::DEVICE {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let max_packet_size0 = bytes[7];
+        if !matches!(max_packet_size0, 8 | 16 | 32 | 64) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let (vendor_id, product_id) = match bytes.get(8..12) {
+            Some(ids) => (
+                u16::from_le_bytes([ids[0], ids[1]]),
+                u16::from_le_bytes([ids[2], ids[3]]),
+            ),
+            None => (0, 0),
+        };
+        Ok(Self {
+            class: bytes[4],
+            max_packet_size0,
+            vendor_id,
+            product_id,
+        })
+    }
+}
+
+/// The interrupt IN endpoint of a boot keyboard interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootKeyboardInterface {
+    /// The value to select the configuration it is in
+    pub configuration: u8,
+    pub interface: u8,
+    pub endpoint: u8,
+    pub max_packet_size: u16,
+}
+
+/// The total length of a configuration, from its descriptor alone
+fn configuration_total_len(bytes: &[u8]) -> Result<usize, UsbError> {
+    if bytes.len() < CONFIGURATION_DESCRIPTOR_LEN || bytes[1] != descriptor::CONFIGURATION {
+        return Err(UsbError::InvalidDescriptor);
+    }
+    Ok(u16::from_le_bytes([bytes[2], bytes[3]]) as usize)
+}
+
+/// Finds the first boot keyboard in a configuration: its descriptor followed by the ones of its
+/// interfaces and their endpoints. `bytes` can be cut short, only what is in it is searched
+pub fn find_boot_keyboard(bytes: &[u8]) -> Result<Option<BootKeyboardInterface>, UsbError> {
+    configuration_total_len(bytes)?;
+    let configuration = bytes[5];
+    let mut keyboard_interface = None;
+    let mut rest = &bytes[bytes[0] as usize..];
+    while rest.len() >= 2 {
+        let len = rest[0] as usize;
+        if len < 2 {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let Some(desc) = rest.get(..len) else {
+            break;
+        };
+        match desc[1] {
+            descriptor::INTERFACE if len >= 9 => {
+                let (class, subclass, protocol) = (desc[5], desc[6], desc[7]);
+                keyboard_interface = (class == CLASS_HID
+                    && subclass == HID_SUBCLASS_BOOT
+                    && protocol == HID_PROTOCOL_KEYBOARD)
+                    .then_some(desc[2]);
+            }
+            descriptor::ENDPOINT if len >= 7 => {
+                let address = desc[2];
+                let is_interrupt_in =
+                    address & ENDPOINT_IN != 0 && desc[3] & 0x3 == ENDPOINT_TYPE_INTERRUPT;
+                if let (Some(interface), true) = (keyboard_interface, is_interrupt_in) {
+                    return Ok(Some(BootKeyboardInterface {
+                        configuration,
+                        interface,
+                        endpoint: address & 0xF,
+                        max_packet_size: u16::from_le_bytes([desc[4], desc[5]]) & 0x7FF,
+                    }));
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[len..];
+    }
+    Ok(None)
+}
+
+/// The output report of a boot keyboard, its LEDs
+pub fn leds_report(locks: LockKeys) -> u8 {
+    let mut leds = 0;
+    if locks.num_lock {
+        leds |= hid::led::NUM_LOCK;
+    }
+    if locks.caps_lock {
+        leds |= hid::led::CAPS_LOCK;
+    }
+    if locks.scroll_lock {
+        leds |= hid::led::SCROLL_LOCK;
+    }
+    leds
+}
+
+/// `/devices/usb`, the controllers and the devices on their ports
+#[derive(Debug)]
+struct UsbInfo;
+
+impl Device for UsbInfo {
+    fn name(&self) -> &str {
+        "usb"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let mut text = String::new();
+        ehci::describe(&mut text);
+        uhci::describe(&mut text);
+        Ok(devices::read_bytes(text.as_bytes(), offset, buf))
+    }
+}
+
+/// Enumerates the devices on the ports of the controllers the PCI probe found, and starts
+/// taking the input of the first keyboard.
+///
+/// Fails if there is no keyboard, so the boot log tells why there is no USB input
+pub fn init_devices() -> Result<(), String> {
+    devices::register_device(Arc::new(UsbInfo));
+    // the EHCI ports first, the slower devices on them are given to the UHCI companions
+    let (ehci_controllers, ehci_keyboards) = ehci::enumerate();
+    let (uhci_controllers, uhci_keyboards) = uhci::enumerate();
+    if ehci_controllers + uhci_controllers == 0 {
+        return Err(String::from("no USB controller"));
+    }
+    if ehci_keyboards + uhci_keyboards == 0 {
+        return Err(String::from("no USB keyboard"));
+    }
+    Ok(())
+}
+
+/// The descriptors of qemu `usb-kbd`
+const SELFTEST_CONFIGURATION: [u8; 34] = [
+    // configuration 1, 1 interface
+    0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x06, 0xA0, 0x32,
+    // interface 0, 1 endpoint, HID boot keyboard
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x07, // the HID descriptor, skipped
+    0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00,
+    // endpoint 1 IN, interrupt, 8 bytes, every 7ms
+    0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x07,
+];
+
+fn selftest_descriptors() {
+    let device = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x27, 0x06, 0x01, 0x00, 0x00, 0x00, 0x01,
+        0x04, 0x0B, 0x01,
+    ];
+    assert_eq!(device.len(), DEVICE_DESCRIPTOR_LEN);
+    let parsed = DeviceDescriptor::parse(&device).unwrap();
+    assert_eq!(
+        (parsed.max_packet_size0, parsed.vendor_id, parsed.product_id),
+        (8, 0x0627, 0x0001)
+    );
+    // the first read only has the start
+    assert_eq!(DeviceDescriptor::parse(&device[..8]).unwrap().vendor_id, 0);
+    let mut bad_packet_size = device;
+    bad_packet_size[7] = 12;
+    assert_eq!(
+        DeviceDescriptor::parse(&bad_packet_size),
+        Err(UsbError::InvalidDescriptor)
+    );
+
+    assert_eq!(configuration_total_len(&SELFTEST_CONFIGURATION), Ok(34));
+    let keyboard = BootKeyboardInterface {
+        configuration: 1,
+        interface: 0,
+        endpoint: 1,
+        max_packet_size: 8,
+    };
+    assert_eq!(
+        find_boot_keyboard(&SELFTEST_CONFIGURATION),
+        Ok(Some(keyboard))
+    );
+    // only the configuration and interface were read
+    assert_eq!(find_boot_keyboard(&SELFTEST_CONFIGURATION[..20]), Ok(None));
+    // a mouse is not a keyboard
+    let mut mouse = SELFTEST_CONFIGURATION;
+    mouse[16] = 2;
+    assert_eq!(find_boot_keyboard(&mouse), Ok(None));
+    let mut zero_length = SELFTEST_CONFIGURATION;
+    zero_length[18] = 0;
+    assert_eq!(
+        find_boot_keyboard(&zero_length),
+        Err(UsbError::InvalidDescriptor)
+    );
+
+    assert_eq!(
+        SetupPacket::get_descriptor(descriptor::CONFIGURATION, 34).to_bytes(),
+        [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x22, 0x00]
+    );
+    assert_eq!(
+        SetupPacket::hid_set_output_report(0, 1).to_bytes(),
+        [0x21, 0x09, 0x00, 0x02, 0x00, 0x00, 0x01, 0x00]
+    );
+    assert_eq!(
+        SetupPacket::clear_endpoint_halt(1).to_bytes(),
+        [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00]
+    );
+}
+
+fn selftest_boot_keyboard() {
+    let mut keyboard = BootKeyboard::new();
+    let report = |keyboard: &mut BootKeyboard, bytes: &[u8]| {
+        let mut scancodes = Vec::new();
+        keyboard.report(bytes, &mut scancodes);
+        scancodes
+    };
+
+    // left shift and `a` in the same report, the shift comes first
+    assert_eq!(
+        report(&mut keyboard, &[0x02, 0, 0x04, 0, 0, 0, 0, 0]),
+        [0x2A, 0x1E]
+    );
+    assert_eq!(report(&mut keyboard, &[0x02, 0, 0, 0, 0, 0, 0, 0]), [0x9E]);
+    // `b` pressed as the shift is released, it still gets the shift
+    assert_eq!(
+        report(&mut keyboard, &[0, 0, 0x05, 0, 0, 0, 0, 0]),
+        [0x30, 0xAA]
+    );
+    // a key keeping its place is not pressed again, the extended ones get the prefix
+    assert_eq!(
+        report(&mut keyboard, &[0, 0, 0x05, 0x52, 0, 0, 0, 0]),
+        [0xE0, 0x48]
+    );
+    // a roll over says nothing about the keys, the state stays
+    assert!(report(&mut keyboard, &[0, 0, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01]).is_empty());
+    assert!(report(&mut keyboard, &[0, 0, 0x05, 0x52, 0, 0, 0, 0]).is_empty());
+    assert!(report(&mut keyboard, &[0, 0, 0x06]).is_empty());
+    // right alt (the extended alt) and keypad enter
+    assert_eq!(
+        report(&mut keyboard, &[0x40, 0, 0x05, 0x52, 0x58, 0, 0, 0]),
+        [0xE0, 0x38, 0xE0, 0x1C]
+    );
+
+    let mut scancodes = Vec::new();
+    keyboard.release_all(&mut scancodes);
+    assert_eq!(scancodes, [0xB0, 0xE0, 0xC8, 0xE0, 0x9C, 0xE0, 0xB8]);
+    assert!(keyboard.is_idle());
+
+    assert_eq!(hid::usage_to_scancode(0x27), Some((false, 0x0B)));
+    assert_eq!(hid::usage_to_scancode(0x2C), Some((false, 0x39)));
+    assert_eq!(hid::usage_to_scancode(0x45), Some((false, 0x58)));
+    assert_eq!(hid::usage_to_scancode(0x63), Some((false, 0x53)));
+    // print screen
+    assert_eq!(hid::usage_to_scancode(0x46), None);
+
+    assert_eq!(
+        leds_report(LockKeys {
+            caps_lock: true,
+            num_lock: false,
+            scroll_lock: true
+        }),
+        hid::led::CAPS_LOCK | hid::led::SCROLL_LOCK
+    );
+}
+
+/// Checks the descriptors parsing, the setup packets, the translation of the boot reports and
+/// the encoding of the UHCI and EHCI transfer descriptors, without a device
+pub fn run_self_tests() {
+    selftest_descriptors();
+    selftest_boot_keyboard();
+    uhci::run_self_tests();
+    ehci::run_self_tests();
+    println!("USB self tests passed");
+}
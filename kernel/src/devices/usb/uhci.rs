@@ -0,0 +1,932 @@
+//! UHCI, the USB 1.1 host controller of the Intel chipsets, and the one of qemu `-usb`.
+//!
+//! The controller walks a list of 1024 frames, one each millisecond, and runs the transfer
+//! descriptors (TD) linked from the frame, through queue heads (QH). All the frames have the
+//! same schedule:
+//!
+//! `interrupt QH -> control QH -> end`
+//!
+//! The interrupt QH holds the one TD polling the keyboard, the controller retries it each frame
+//! while the keyboard NAKs it (so faster than the interval it asks for), and interrupts when it
+//! gets a report, the TD is then armed again. The control QH gets the TDs of one control
+//! transfer at a time, which is polled until done, these are only used to enumerate the devices
+//! and to set the LEDs of the keyboard.
+//!
+//! The frame list, the QHs, the TDs and their buffers are in two pages below 4GB, since the
+//! controller has 32 bit addresses. Only the devices on the root ports are used, there is no
+//! support for hubs, and a device plugged after the boot is not enumerated.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{self, Ordering},
+};
+
+use alloc::{string::String, vec::Vec};
+
+use kernel_core::hid::{BootKeyboard, BOOT_REPORT_LEN};
+
+use crate::{
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+    },
+    devices::pci::{PciDeviceConfig, PciDeviceType},
+    io::keyboard::{self, LockKeys},
+    memory_management::memory_layout::PAGE_4K,
+    sync::spin::mutex::Mutex,
+};
+
+use super::{
+    descriptor, BootKeyboardInterface, ControlData, DeviceDescriptor, DmaPage, SetupPacket,
+    UsbError, CLASS_HUB, DEVICE_DESCRIPTOR_LEN,
+};
+
+static CONTROLLERS: Mutex<Vec<Uhci>> = Mutex::new(Vec::new());
+/// The GSIs [`uhci_interrupt`] is assigned to, it serves all the controllers
+static INTERRUPTS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// The legacy support register, the BIOS uses it to emulate a PS/2 keyboard from a USB one
+const PCI_LEGACY_SUPPORT: u8 = 0xC0;
+/// Clears the status bits, and stops the traps to SMM of the emulation
+const LEGACY_SUPPORT_CLEAR: u16 = 0x8F00;
+/// The interrupts of the controller go to its PCI `INTx` pin
+const LEGACY_SUPPORT_PIRQ: u16 = 0x2000;
+const BAR_IO: usize = 4;
+
+const PORT_COUNT: usize = 2;
+const FRAME_COUNT: usize = 1024;
+/// The frame number counts to 2047, twice the frame list
+const FRAME_NUMBER_MASK: u16 = 0x7FF;
+/// The default length of a frame, 1ms
+const START_OF_FRAME_DEFAULT: u8 = 0x40;
+
+/// How many times to poll the controller before it runs, when the frames can't be counted
+const RESET_POLLS: usize = 100_000;
+const PORT_RESET_FRAMES: u16 = 50;
+const PORT_RECOVERY_FRAMES: u16 = 10;
+const SET_ADDRESS_RECOVERY_FRAMES: u16 = 2;
+const CONTROL_TIMEOUT_FRAMES: u16 = 500;
+/// The failed reports in a row before giving up on the keyboard
+const MAX_KEYBOARD_ERRORS: u32 = 8;
+
+mod reg {
+    pub const COMMAND: u16 = 0x00;
+    pub const STATUS: u16 = 0x02;
+    pub const INTERRUPT_ENABLE: u16 = 0x04;
+    pub const FRAME_NUMBER: u16 = 0x06;
+    pub const FRAME_LIST_BASE: u16 = 0x08;
+    pub const START_OF_FRAME: u16 = 0x0C;
+    /// The status and control of the first port, the next ones follow
+    pub const PORTS: u16 = 0x10;
+}
+
+mod command {
+    pub const RUN: u16 = 1 << 0;
+    pub const HOST_RESET: u16 = 1 << 1;
+    pub const CONFIGURED: u16 = 1 << 6;
+    /// Full speed bandwidth reclamation packets of 64 bytes
+    pub const MAX_PACKET_64: u16 = 1 << 7;
+}
+
+/// All the bits are cleared by writing `1` to them, except `HALTED`
+#[allow(dead_code)]
+mod status {
+    pub const INTERRUPT: u16 = 1 << 0;
+    pub const ERROR_INTERRUPT: u16 = 1 << 1;
+    pub const RESUME: u16 = 1 << 2;
+    pub const HOST_SYSTEM_ERROR: u16 = 1 << 3;
+    pub const PROCESS_ERROR: u16 = 1 << 4;
+    pub const HALTED: u16 = 1 << 5;
+    pub const ALL: u16 = 0x3F;
+}
+
+mod interrupt_enable {
+    pub const TIMEOUT_CRC: u16 = 1 << 0;
+    pub const COMPLETE: u16 = 1 << 2;
+}
+
+mod port {
+    pub const CONNECTED: u16 = 1 << 0;
+    pub const CONNECT_CHANGE: u16 = 1 << 1;
+    pub const ENABLED: u16 = 1 << 2;
+    pub const ENABLE_CHANGE: u16 = 1 << 3;
+    pub const LOW_SPEED: u16 = 1 << 8;
+    pub const RESET: u16 = 1 << 9;
+    /// The bits cleared by writing `1` to them
+    pub const WRITE_CLEAR: u16 = CONNECT_CHANGE | ENABLE_CHANGE;
+}
+
+/// The low bits of the links in the frame list, the QHs and the TDs
+mod link {
+    pub const TERMINATE: u32 = 1 << 0;
+    pub const QUEUE: u32 = 1 << 1;
+    /// Run the next TD of the queue in the same frame
+    pub const DEPTH_FIRST: u32 = 1 << 2;
+}
+
+/// The status and control of a TD, the low bits are the length transferred minus one
+#[allow(dead_code)]
+mod td_status {
+    pub const BITSTUFF: u32 = 1 << 17;
+    pub const CRC_TIMEOUT: u32 = 1 << 18;
+    pub const NAK: u32 = 1 << 19;
+    pub const BABBLE: u32 = 1 << 20;
+    pub const DATA_BUFFER: u32 = 1 << 21;
+    pub const STALLED: u32 = 1 << 22;
+    pub const ACTIVE: u32 = 1 << 23;
+    pub const INTERRUPT_ON_COMPLETE: u32 = 1 << 24;
+    pub const LOW_SPEED: u32 = 1 << 26;
+    /// Retry 3 times before marking the TD as failed
+    pub const ERROR_COUNT_3: u32 = 3 << 27;
+    /// A short packet stops the queue, instead of going on to the next TD
+    pub const SHORT_PACKET_DETECT: u32 = 1 << 29;
+    pub const ERRORS: u32 = BITSTUFF | CRC_TIMEOUT | BABBLE | DATA_BUFFER | STALLED;
+}
+
+mod pid {
+    pub const SETUP: u8 = 0x2D;
+    pub const IN: u8 = 0x69;
+    pub const OUT: u8 = 0xE1;
+}
+
+/// The offsets in the schedule page, the QHs and TDs are aligned to 16 bytes
+mod layout {
+    pub const INTERRUPT_QH: usize = 0x000;
+    pub const CONTROL_QH: usize = 0x010;
+    pub const SETUP_PACKET: usize = 0x020;
+    pub const KEYBOARD_TD: usize = 0x030;
+    /// The largest packet of a full speed interrupt endpoint fits
+    pub const REPORT: usize = 0x040;
+    pub const REPORT_MAX_LEN: usize = 64;
+    pub const CONTROL_TDS: usize = 0x100;
+    pub const CONTROL_TD_COUNT: usize = 48;
+    pub const CONTROL_DATA: usize = 0x400;
+}
+
+/// The link of a QH, then the link to its first TD
+const QH_ELEMENT: usize = 4;
+const TD_SIZE: usize = 16;
+/// A control transfer has a setup and a status TDs, and a TD for every packet of data between,
+/// of at least 8 bytes
+const MAX_CONTROL_DATA: usize = (layout::CONTROL_TD_COUNT - 2) * 8;
+// the control TDs end before their data, which fits in the page
+const _: () = assert!(
+    layout::CONTROL_TDS + layout::CONTROL_TD_COUNT * TD_SIZE <= layout::CONTROL_DATA
+        && layout::CONTROL_DATA + MAX_CONTROL_DATA <= PAGE_4K
+);
+
+/// A device on a port, with its control endpoint
+#[derive(Debug, Clone, Copy)]
+struct Target {
+    address: u8,
+    low_speed: bool,
+    max_packet_size: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PortState {
+    Empty,
+    Failed(UsbError),
+    Device {
+        address: u8,
+        low_speed: bool,
+        descriptor: DeviceDescriptor,
+        kind: &'static str,
+    },
+    /// The keyboard was there, but stopped answering
+    Removed,
+}
+
+/// The keyboard polled by the interrupt QH
+struct KeyboardPipe {
+    port: usize,
+    target: Target,
+    interface: BootKeyboardInterface,
+    toggle: bool,
+    hid: BootKeyboard,
+    leds: u8,
+    errors: u32,
+}
+
+/// The token of a TD, `len` is the most the packet can have
+fn token(pid: u8, target: Target, endpoint: u8, toggle: bool, len: usize) -> u32 {
+    // the length is encoded minus one, so `0x7FF` is a packet without data
+    let max_len = (len as u32).wrapping_sub(1) & 0x7FF;
+    pid as u32
+        | (target.address as u32) << 8
+        | (endpoint as u32) << 15
+        | (toggle as u32) << 19
+        | max_len << 21
+}
+
+fn token_len(token: u32) -> usize {
+    ((token >> 21) + 1) as usize & 0x7FF
+}
+
+/// The length transferred by a TD, from its status
+fn actual_len(status: u32) -> usize {
+    (status + 1) as usize & 0x7FF
+}
+
+/// The error of a TD that is done, the stall bit is also set with the others, when the
+/// retries are exhausted, so it is only a stall alone
+fn td_error(status: u32) -> Option<UsbError> {
+    if status & td_status::ERRORS == 0 {
+        None
+    } else if status & td_status::BABBLE != 0 {
+        Some(UsbError::Babble)
+    } else if status & td_status::DATA_BUFFER != 0 {
+        Some(UsbError::DataBuffer)
+    } else if status & td_status::CRC_TIMEOUT != 0 {
+        Some(UsbError::CrcTimeout)
+    } else if status & td_status::BITSTUFF != 0 {
+        Some(UsbError::BitStuff)
+    } else {
+        Some(UsbError::Stall)
+    }
+}
+
+fn control_td(index: usize) -> usize {
+    layout::CONTROL_TDS + index * TD_SIZE
+}
+
+pub struct Uhci {
+    io: u16,
+    /// bus, device and function
+    pci: (u8, u8, u8),
+    frame_list: DmaPage,
+    schedule: DmaPage,
+    ports: [PortState; PORT_COUNT],
+    keyboard: Option<KeyboardPipe>,
+    next_address: u8,
+}
+
+impl Uhci {
+    /// Resets the controller and starts running the empty schedule
+    fn new(config: &PciDeviceConfig) -> Result<Self, UsbError> {
+        let (io, _) = config.base_address[BAR_IO]
+            .get_io()
+            .ok_or(UsbError::NoIoPorts)?;
+        config.write_command(config.read_command() | PCI_COMMAND_IO_SPACE | PCI_COMMAND_BUS_MASTER);
+        if config.read_command() & PCI_COMMAND_IO_SPACE == 0 {
+            return Err(UsbError::NoIoPorts);
+        }
+        config.write_config::<u16>(PCI_LEGACY_SUPPORT, LEGACY_SUPPORT_CLEAR);
+
+        let controller = Self {
+            io,
+            pci: (config.bus, config.dev, config.func),
+            frame_list: DmaPage::alloc()?,
+            schedule: DmaPage::alloc()?,
+            ports: [PortState::Empty; PORT_COUNT],
+            keyboard: None,
+            next_address: 1,
+        };
+        controller.write16(reg::INTERRUPT_ENABLE, 0);
+        controller.write16(reg::COMMAND, command::HOST_RESET);
+        if !(0..RESET_POLLS).any(|_| controller.read16(reg::COMMAND) & command::HOST_RESET == 0) {
+            return Err(UsbError::ResetTimeout);
+        }
+        controller.start();
+        config.write_config::<u16>(PCI_LEGACY_SUPPORT, LEGACY_SUPPORT_PIRQ);
+        Ok(controller)
+    }
+
+    fn read16(&self, offset: u16) -> u16 {
+        unsafe { cpu::io_in(self.io + offset) }
+    }
+
+    fn write16(&self, offset: u16, value: u16) {
+        unsafe { cpu::io_out(self.io + offset, value) }
+    }
+
+    fn start(&self) {
+        // every frame runs the same schedule, see the module docs
+        let control_qh = self.schedule.physical(layout::CONTROL_QH);
+        self.schedule
+            .write(layout::INTERRUPT_QH, control_qh | link::QUEUE);
+        self.schedule
+            .write(layout::INTERRUPT_QH + QH_ELEMENT, link::TERMINATE);
+        self.schedule.write(layout::CONTROL_QH, link::TERMINATE);
+        self.schedule
+            .write(layout::CONTROL_QH + QH_ELEMENT, link::TERMINATE);
+        let interrupt_qh = self.schedule.physical(layout::INTERRUPT_QH);
+        for frame in 0..FRAME_COUNT {
+            self.frame_list.write(frame * 4, interrupt_qh | link::QUEUE);
+        }
+        atomic::fence(Ordering::SeqCst);
+
+        unsafe {
+            cpu::io_out(self.io + reg::FRAME_LIST_BASE, self.frame_list.physical(0));
+            cpu::io_out(self.io + reg::START_OF_FRAME, START_OF_FRAME_DEFAULT);
+        }
+        self.write16(reg::FRAME_NUMBER, 0);
+        self.write16(reg::STATUS, status::ALL);
+        self.write16(
+            reg::INTERRUPT_ENABLE,
+            interrupt_enable::TIMEOUT_CRC | interrupt_enable::COMPLETE,
+        );
+        self.write16(
+            reg::COMMAND,
+            command::RUN | command::CONFIGURED | command::MAX_PACKET_64,
+        );
+    }
+
+    fn is_halted(&self) -> bool {
+        self.read16(reg::STATUS) & status::HALTED != 0
+    }
+
+    /// Waits until `done`, for at most `frames` milliseconds, the frame number counts them
+    /// while the controller runs. Returns whether `done` was reached
+    fn wait_frames(&self, frames: u16, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let start = self.read16(reg::FRAME_NUMBER);
+        loop {
+            if done(self) {
+                return true;
+            }
+            let elapsed = self.read16(reg::FRAME_NUMBER).wrapping_sub(start) & FRAME_NUMBER_MASK;
+            // the frames don't move when halted
+            if elapsed >= frames || self.is_halted() {
+                return done(self);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn port_reg(port: usize) -> u16 {
+        reg::PORTS + 2 * port as u16
+    }
+
+    fn is_connected(&self, port: usize) -> bool {
+        self.read16(Self::port_reg(port)) & port::CONNECTED != 0
+    }
+
+    /// Resets the device on `port` and enables the port, returns whether the device is low
+    /// speed
+    fn reset_port(&self, port: usize) -> Result<bool, UsbError> {
+        let reg = Self::port_reg(port);
+        if !self.is_connected(port) {
+            return Err(UsbError::Disconnected);
+        }
+        self.write16(reg, port::RESET);
+        self.wait_frames(PORT_RESET_FRAMES, |_| false);
+        self.write16(reg, 0);
+        // the port is only enabled if the device is still there after the reset
+        let enabled = self.wait_frames(PORT_RECOVERY_FRAMES, |uhci| {
+            let state = uhci.read16(reg);
+            if state & port::ENABLED == 0 {
+                uhci.write16(reg, port::ENABLED | (state & port::WRITE_CLEAR));
+            }
+            state & port::ENABLED != 0
+        });
+        if !enabled || !self.is_connected(port) {
+            return Err(UsbError::Disconnected);
+        }
+        // the device needs this long after its reset before the first request
+        self.wait_frames(PORT_RECOVERY_FRAMES, |_| false);
+        Ok(self.read16(reg) & port::LOW_SPEED != 0)
+    }
+
+    fn write_td(&self, offset: usize, link: u32, status: u32, token: u32, buffer: u32) {
+        self.schedule.write(offset, link);
+        self.schedule.write(offset + 4, status);
+        self.schedule.write(offset + 8, token);
+        self.schedule.write(offset + 12, buffer);
+    }
+
+    /// Runs a control transfer on the endpoint `0` of `target`, returns the length of the data
+    /// stage transferred, which can be less than asked for an IN
+    fn control(
+        &self,
+        target: Target,
+        setup: SetupPacket,
+        data: ControlData,
+    ) -> Result<usize, UsbError> {
+        let (data_len, data_in) = match &data {
+            ControlData::None => (0, false),
+            ControlData::In(buf) => (buf.len(), true),
+            ControlData::Out(buf) => (buf.len(), false),
+        };
+        assert!(data_len == setup.length as usize && data_len <= MAX_CONTROL_DATA);
+        if let ControlData::Out(buf) = &data {
+            self.schedule
+                .bytes_mut(layout::CONTROL_DATA, data_len)
+                .copy_from_slice(buf);
+        }
+        self.schedule
+            .bytes_mut(layout::SETUP_PACKET, 8)
+            .copy_from_slice(&setup.to_bytes());
+
+        let max_packet_size = target.max_packet_size as usize;
+        let data_tds = data_len.div_ceil(max_packet_size);
+        let count = data_tds + 2;
+        let mut base_status = td_status::ACTIVE | td_status::ERROR_COUNT_3;
+        if target.low_speed {
+            base_status |= td_status::LOW_SPEED;
+        }
+        let next = |index: usize| {
+            if index + 1 == count {
+                link::TERMINATE
+            } else {
+                self.schedule.physical(control_td(index + 1)) | link::DEPTH_FIRST
+            }
+        };
+
+        self.write_td(
+            control_td(0),
+            next(0),
+            base_status,
+            token(pid::SETUP, target, 0, false, 8),
+            self.schedule.physical(layout::SETUP_PACKET),
+        );
+        for i in 0..data_tds {
+            let offset = i * max_packet_size;
+            let len = max_packet_size.min(data_len - offset);
+            let (data_pid, data_status) = if data_in {
+                (pid::IN, base_status | td_status::SHORT_PACKET_DETECT)
+            } else {
+                (pid::OUT, base_status)
+            };
+            // the data starts with DATA1, after the setup
+            self.write_td(
+                control_td(1 + i),
+                next(1 + i),
+                data_status,
+                token(data_pid, target, 0, i % 2 == 0, len),
+                self.schedule.physical(layout::CONTROL_DATA + offset),
+            );
+        }
+        let status_pid = if data_in { pid::OUT } else { pid::IN };
+        self.write_td(
+            control_td(count - 1),
+            link::TERMINATE,
+            base_status,
+            token(status_pid, target, 0, true, 0),
+            0,
+        );
+        atomic::fence(Ordering::SeqCst);
+        self.schedule.write(
+            layout::CONTROL_QH + QH_ELEMENT,
+            self.schedule.physical(control_td(0)),
+        );
+
+        let mut short_at = None;
+        let mut result = None;
+        self.wait_frames(CONTROL_TIMEOUT_FRAMES, |uhci| {
+            result = uhci.control_progress(count, data_in, &mut short_at);
+            result.is_some()
+        });
+        // stop the queue whatever happened, the TDs are used by the next transfer
+        self.schedule
+            .write(layout::CONTROL_QH + QH_ELEMENT, link::TERMINATE);
+        atomic::fence(Ordering::SeqCst);
+
+        let result = result.unwrap_or(Err(if self.is_halted() {
+            UsbError::Halted
+        } else {
+            UsbError::Timeout
+        }));
+        let transferred = result?;
+        if let ControlData::In(buf) = data {
+            buf[..transferred]
+                .copy_from_slice(self.schedule.bytes(layout::CONTROL_DATA, transferred));
+        }
+        Ok(transferred)
+    }
+
+    /// The result of the control transfer of `count` TDs if it is done.
+    ///
+    /// A short packet of the data stage stops the queue, the status stage is then started
+    /// from here, and the TDs of the data after it are skipped
+    fn control_progress(
+        &self,
+        count: usize,
+        data_in: bool,
+        short_at: &mut Option<usize>,
+    ) -> Option<Result<usize, UsbError>> {
+        let status_index = count - 1;
+        let mut transferred = 0;
+        for i in 0..count {
+            if short_at.is_some_and(|short| i > short && i < status_index) {
+                continue;
+            }
+            let status = self.schedule.read(control_td(i) + 4);
+            if status & td_status::ACTIVE != 0 {
+                return None;
+            }
+            if let Some(e) = td_error(status) {
+                return Some(Err(e));
+            }
+            if i == 0 || i == status_index {
+                continue;
+            }
+            let len = actual_len(status);
+            transferred += len;
+            let expected = token_len(self.schedule.read(control_td(i) + 8));
+            if data_in && len < expected && short_at.is_none() {
+                *short_at = Some(i);
+                self.schedule.write(
+                    layout::CONTROL_QH + QH_ELEMENT,
+                    self.schedule.physical(control_td(status_index)),
+                );
+                return None;
+            }
+        }
+        Some(Ok(transferred))
+    }
+
+    /// Gives the device on `port` an address, and starts using it if it is a boot keyboard
+    fn enumerate_port(&mut self, port: usize) -> Result<PortState, UsbError> {
+        let low_speed = self.reset_port(port)?;
+        let mut target = Target {
+            address: 0,
+            low_speed,
+            max_packet_size: 8,
+        };
+        let mut device = [0; DEVICE_DESCRIPTOR_LEN];
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::DEVICE, 8),
+            ControlData::In(&mut device[..8]),
+        )?;
+        target.max_packet_size = DeviceDescriptor::parse(&device[..len])?.max_packet_size0 as u16;
+
+        let address = self.next_address;
+        self.control(target, SetupPacket::set_address(address), ControlData::None)?;
+        self.next_address += 1;
+        self.wait_frames(SET_ADDRESS_RECOVERY_FRAMES, |_| false);
+        target.address = address;
+
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::DEVICE, DEVICE_DESCRIPTOR_LEN as u16),
+            ControlData::In(&mut device),
+        )?;
+        let descriptor = DeviceDescriptor::parse(&device[..len])?;
+        let device_state = |kind| PortState::Device {
+            address,
+            low_speed,
+            descriptor,
+            kind,
+        };
+        if descriptor.class == CLASS_HUB {
+            return Ok(device_state("hub, not supported"));
+        }
+
+        // the descriptors of the interfaces and endpoints come after the configuration, read
+        // its start to know how much there is
+        let mut configuration = [0; MAX_CONTROL_DATA];
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(
+                descriptor::CONFIGURATION,
+                super::CONFIGURATION_DESCRIPTOR_LEN as u16,
+            ),
+            ControlData::In(&mut configuration[..super::CONFIGURATION_DESCRIPTOR_LEN]),
+        )?;
+        let total_len =
+            super::configuration_total_len(&configuration[..len])?.min(MAX_CONTROL_DATA);
+        let len = self.control(
+            target,
+            SetupPacket::get_descriptor(descriptor::CONFIGURATION, total_len as u16),
+            ControlData::In(&mut configuration[..total_len]),
+        )?;
+        let kind = match super::find_boot_keyboard(&configuration[..len])? {
+            Some(interface) if self.keyboard.is_none() => {
+                self.attach_keyboard(port, target, interface)?;
+                "boot keyboard"
+            }
+            Some(_) => "boot keyboard, another one is used",
+            None => "not supported",
+        };
+        Ok(device_state(kind))
+    }
+
+    fn attach_keyboard(
+        &mut self,
+        port: usize,
+        target: Target,
+        interface: BootKeyboardInterface,
+    ) -> Result<(), UsbError> {
+        let packet_size = interface.max_packet_size as usize;
+        if !(BOOT_REPORT_LEN..=layout::REPORT_MAX_LEN).contains(&packet_size) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        self.control(
+            target,
+            SetupPacket::set_configuration(interface.configuration),
+            ControlData::None,
+        )?;
+        self.control(
+            target,
+            SetupPacket::hid_set_boot_protocol(interface.interface),
+            ControlData::None,
+        )?;
+        // boot keyboards can refuse it, then they repeat their reports, which changes nothing
+        match self.control(
+            target,
+            SetupPacket::hid_set_idle(interface.interface),
+            ControlData::None,
+        ) {
+            Ok(_) | Err(UsbError::Stall) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.keyboard = Some(KeyboardPipe {
+            port,
+            target,
+            interface,
+            toggle: false,
+            hid: BootKeyboard::new(),
+            leds: 0,
+            errors: 0,
+        });
+        // the lock keys could have been set by another keyboard
+        let locks = keyboard::get_keyboard().lock().lock_keys();
+        self.set_leds(locks);
+        self.arm_keyboard();
+        Ok(())
+    }
+
+    /// Queues the TD for the next report of the keyboard
+    fn arm_keyboard(&self) {
+        let Some(pipe) = &self.keyboard else {
+            return;
+        };
+        let mut status =
+            td_status::ACTIVE | td_status::INTERRUPT_ON_COMPLETE | td_status::ERROR_COUNT_3;
+        if pipe.target.low_speed {
+            status |= td_status::LOW_SPEED;
+        }
+        self.write_td(
+            layout::KEYBOARD_TD,
+            link::TERMINATE,
+            status,
+            token(
+                pid::IN,
+                pipe.target,
+                pipe.interface.endpoint,
+                pipe.toggle,
+                pipe.interface.max_packet_size as usize,
+            ),
+            self.schedule.physical(layout::REPORT),
+        );
+        atomic::fence(Ordering::SeqCst);
+        self.schedule.write(
+            layout::INTERRUPT_QH + QH_ELEMENT,
+            self.schedule.physical(layout::KEYBOARD_TD),
+        );
+    }
+
+    /// Sends the LEDs to the keyboard, if they changed
+    fn set_leds(&mut self, locks: LockKeys) {
+        let Some(pipe) = &self.keyboard else {
+            return;
+        };
+        let leds = super::leds_report(locks);
+        if leds == pipe.leds {
+            return;
+        }
+        let setup = SetupPacket::hid_set_output_report(pipe.interface.interface, 1);
+        match self.control(pipe.target, setup, ControlData::Out(&[leds])) {
+            Ok(_) => {
+                if let Some(pipe) = &mut self.keyboard {
+                    pipe.leds = leds;
+                }
+            }
+            Err(e) => {
+                eprintln!("usb: failed to set the keyboard LEDs: {e:?}");
+            }
+        }
+    }
+
+    /// Stops polling the keyboard, and returns the scancodes releasing the keys it had down
+    fn detach_keyboard(&mut self) -> Option<Vec<u8>> {
+        let mut pipe = self.keyboard.take()?;
+        self.schedule
+            .write(layout::INTERRUPT_QH + QH_ELEMENT, link::TERMINATE);
+        self.ports[pipe.port] = PortState::Removed;
+        let mut scancodes = Vec::new();
+        pipe.hid.release_all(&mut scancodes);
+        Some(scancodes)
+    }
+
+    /// Handles the interrupt of this controller, returns the scancodes of the keyboard if it
+    /// sent a report
+    fn service(&mut self) -> Option<Vec<u8>> {
+        let pending = self.read16(reg::STATUS);
+        if pending & status::ALL & !status::HALTED == 0 {
+            return None;
+        }
+        self.write16(reg::STATUS, pending);
+        if pending & (status::HOST_SYSTEM_ERROR | status::PROCESS_ERROR) != 0 {
+            eprintln!(
+                "uhci {}: stopped by an error, status {pending:#X}",
+                self.name()
+            );
+            return self.detach_keyboard();
+        }
+
+        let report_status = self.schedule.read(layout::KEYBOARD_TD + 4);
+        let pipe = self.keyboard.as_mut()?;
+        if report_status & td_status::ACTIVE != 0 {
+            // a control transfer failed, it is handled where it waits
+            return None;
+        }
+        let mut scancodes = Vec::new();
+        match td_error(report_status) {
+            None => {
+                let len = actual_len(report_status).min(layout::REPORT_MAX_LEN);
+                pipe.toggle = !pipe.toggle;
+                pipe.errors = 0;
+                pipe.hid
+                    .report(self.schedule.bytes(layout::REPORT, len), &mut scancodes);
+            }
+            Some(error) => {
+                pipe.errors += 1;
+                let (port, target, endpoint) = (pipe.port, pipe.target, pipe.interface.endpoint);
+                if pipe.errors > MAX_KEYBOARD_ERRORS || !self.is_connected(port) {
+                    eprintln!("usb: keyboard on port {} removed: {error:?}", port + 1);
+                    return self.detach_keyboard();
+                }
+                if error == UsbError::Stall {
+                    // the endpoint is halted until cleared, and starts over from DATA0
+                    let setup = SetupPacket::clear_endpoint_halt(endpoint);
+                    if let Err(e) = self.control(target, setup, ControlData::None) {
+                        eprintln!("usb: failed to clear the keyboard halt: {e:?}");
+                    }
+                    if let Some(pipe) = &mut self.keyboard {
+                        pipe.toggle = false;
+                    }
+                }
+            }
+        }
+        self.arm_keyboard();
+        Some(scancodes)
+    }
+
+    fn name(&self) -> String {
+        let (bus, dev, func) = self.pci;
+        alloc::format!("{bus:02X}.{dev:02X}.{func:02X}")
+    }
+}
+
+impl Drop for Uhci {
+    fn drop(&mut self) {
+        self.write16(reg::INTERRUPT_ENABLE, 0);
+        self.write16(reg::COMMAND, 0);
+        // the pages can only be freed when the controller stopped reading them
+        if !(0..RESET_POLLS).any(|_| self.is_halted()) {
+            eprintln!("uhci {}: doesn't stop, leaking its pages", self.name());
+            self.frame_list.leak();
+            self.schedule.leak();
+        }
+    }
+}
+
+extern "x86-interrupt" fn uhci_interrupt(_stack_frame: InterruptStackFrame64) {
+    let count = CONTROLLERS.lock().len();
+    for index in 0..count {
+        let Some(scancodes) = CONTROLLERS.lock()[index].service() else {
+            continue;
+        };
+        // the keyboard driver takes the console, the controllers are not held meanwhile
+        if let Some(locks) = keyboard::feed_scancodes(&scancodes) {
+            CONTROLLERS.lock()[index].set_leds(locks);
+        }
+    }
+
+    apic::return_from_interrupt();
+}
+
+/// Takes the UHCI controllers (PCI class `0x0C03`, interface `0x00`), resets and starts them,
+/// their ports are enumerated later by [`enumerate`]
+pub fn try_register(config: &PciDeviceConfig) -> bool {
+    let PciDeviceType::SerialBusController(0x03, 0x00, _) = config.device_type else {
+        return false;
+    };
+    let controller = match Uhci::new(config) {
+        Ok(controller) => controller,
+        Err(e) => {
+            eprintln!(
+                "uhci {:02X}.{:02X}.{:02X}: failed to start: {e:?}",
+                config.bus, config.dev, config.func
+            );
+            return false;
+        }
+    };
+    CONTROLLERS.lock().push(controller);
+
+    match config.route_intx() {
+        Some(route) => {
+            let mut interrupts = INTERRUPTS.lock();
+            if !interrupts.contains(&route.gsi) {
+                if apic::is_gsi_free(route.gsi) {
+                    route.assign(uhci_interrupt as BasicInterruptHandler, cpu::cpu());
+                    interrupts.push(route.gsi);
+                } else {
+                    println!("WARNING: UHCI interrupt {route} is shared, not supported yet");
+                }
+            }
+        }
+        None => {
+            println!("WARNING: UHCI has no interrupt, its keyboard won't work");
+        }
+    }
+    true
+}
+
+/// Enumerates the ports of all the controllers, returns the number of controllers and of
+/// keyboards in use
+pub fn enumerate() -> (usize, usize) {
+    let mut controllers = CONTROLLERS.lock();
+    for controller in controllers.iter_mut() {
+        for port in 0..PORT_COUNT {
+            controller.ports[port] = match controller.enumerate_port(port) {
+                Ok(state) => state,
+                Err(UsbError::Disconnected) => PortState::Empty,
+                Err(e) => {
+                    eprintln!("uhci {}: port {}: {e:?}", controller.name(), port + 1);
+                    PortState::Failed(e)
+                }
+            };
+        }
+    }
+    let keyboards = controllers
+        .iter()
+        .filter(|controller| controller.keyboard.is_some())
+        .count();
+    (controllers.len(), keyboards)
+}
+
+/// The state of the controllers and their ports, for `/devices/usb`
+pub fn describe(out: &mut String) {
+    for controller in CONTROLLERS.lock().iter() {
+        let _ = writeln!(
+            out,
+            "uhci {}: io {:#X}{}",
+            controller.name(),
+            controller.io,
+            if controller.is_halted() {
+                ", halted"
+            } else {
+                ""
+            }
+        );
+        for (port, state) in controller.ports.iter().enumerate() {
+            let _ = write!(out, "  port {}: ", port + 1);
+            let _ = match state {
+                PortState::Empty => writeln!(out, "empty"),
+                PortState::Failed(e) => writeln!(out, "failed: {e:?}"),
+                PortState::Device {
+                    address,
+                    low_speed,
+                    descriptor,
+                    kind,
+                } => writeln!(
+                    out,
+                    "{:04x}:{:04x}, address {address}, {} speed, {kind}",
+                    descriptor.vendor_id,
+                    descriptor.product_id,
+                    if *low_speed { "low" } else { "full" }
+                ),
+                PortState::Removed => writeln!(out, "keyboard removed"),
+            };
+        }
+    }
+}
+
+/// Checks the encoding of the TDs
+pub(super) fn run_self_tests() {
+    let target = Target {
+        address: 5,
+        low_speed: true,
+        max_packet_size: 8,
+    };
+    // the setup of address 5, endpoint 0, DATA0, 8 bytes
+    let setup = token(pid::SETUP, target, 0, false, 8);
+    assert_eq!(setup, 0x00E0_052D);
+    assert_eq!(token_len(setup), 8);
+    // a status stage without data
+    let status = token(pid::IN, target, 0, true, 0);
+    assert_eq!(status, 0xFFE8_0569);
+    assert_eq!(token_len(status), 0);
+    assert_eq!(token(pid::IN, target, 1, true, 8), 0x00E8_8569);
+
+    // the actual length is also minus one
+    assert_eq!(actual_len(td_status::ACTIVE | 0x7FF), 0);
+    assert_eq!(actual_len(7), 8);
+    assert_eq!(td_error(td_status::NAK | 7), None);
+    assert_eq!(td_error(td_status::STALLED), Some(UsbError::Stall));
+    assert_eq!(
+        td_error(td_status::STALLED | td_status::CRC_TIMEOUT),
+        Some(UsbError::CrcTimeout)
+    );
+    assert_eq!(
+        td_error(td_status::STALLED | td_status::BABBLE),
+        Some(UsbError::Babble)
+    );
+}
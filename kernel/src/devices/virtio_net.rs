@@ -0,0 +1,431 @@
+//! virtio-net (qemu `-device virtio-net-pci`), through the legacy interface of its I/O BAR.
+//!
+//! The device has two virtqueues, receive (0) and transmit (1), the descriptor `i` of a
+//! queue always points to its own 2KB buffer (two in a page), which holds a whole frame after
+//! the 10 bytes header of virtio-net. No feature that changes that is taken: no offloads and
+//! no merged receive buffers, only the MAC address is read from the device.
+//!
+//! All the receive buffers are given to the device at the start, and each is given back as
+//! soon as its frame is copied out. Sending takes a free transmit buffer, the ones the device
+//! is done with are collected each time, and fails right away if there are none.
+//!
+//! The rings of a queue must be physically contiguous, so they are in a static, which limits
+//! this to one device. The interrupt only acknowledges the device, counts itself and raises
+//! [`SoftIrq::NetRx`], the frames are taken from there by [`net`](crate::net).
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{self, AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::{boxed::Box, format, vec::Vec};
+
+use crate::{
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+        irq_off,
+        softirq::{self, SoftIrq},
+    },
+    memory_management::{
+        memory_layout::{align_up, virtual2physical, PAGE_4K},
+        physical_page_allocator,
+    },
+    net::{self, MacAddress, NetDevice, TxError},
+};
+
+use super::pci::PciDeviceConfig;
+
+const VENDOR_VIRTIO: u16 = 0x1AF4;
+/// The transitional device, which has the legacy interface
+const DEVICE_NET: u16 = 0x1000;
+const BAR_IO: usize = 0;
+const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+mod reg {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const DRIVER_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const ISR_STATUS: u16 = 0x13;
+    /// The device config, without MSI-X
+    pub const MAC: u16 = 0x14;
+}
+
+mod status {
+    pub const ACKNOWLEDGE: u8 = 1 << 0;
+    pub const DRIVER: u8 = 1 << 1;
+    pub const DRIVER_OK: u8 = 1 << 2;
+    pub const FAILED: u8 = 1 << 7;
+}
+
+const FEATURE_MAC: u32 = 1 << 5;
+const ISR_QUEUE: u8 = 1 << 0;
+const NET_HEADER_LEN: usize = 10;
+
+const QUEUE_RX: u16 = 0;
+const QUEUE_TX: u16 = 1;
+/// The largest queue that fits [`QUEUE_MEMORY_PAGES`], the used ring of 256 entries starts in
+/// the third page
+const MAX_QUEUE_SIZE: u16 = 256;
+const QUEUE_MEMORY_PAGES: usize = 3;
+/// The buffers of each queue, the rest of its descriptors are not used
+const BUFFERS: usize = 32;
+const BUFFER_LEN: usize = 2048;
+const BUFFERS_PER_PAGE: usize = PAGE_4K / BUFFER_LEN;
+
+const DESCRIPTOR_LEN: usize = 16;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+#[repr(C, align(4096))]
+struct QueueMemory([u8; QUEUE_MEMORY_PAGES * PAGE_4K]);
+
+// the kernel image is mapped linearly, so these are contiguous, one for each queue
+static mut QUEUE_MEMORY: [QueueMemory; 2] =
+    [const { QueueMemory([0; QUEUE_MEMORY_PAGES * PAGE_4K]) }; 2];
+static TAKEN: AtomicBool = AtomicBool::new(false);
+/// The ISR register of the device, read by the interrupt, `0` before there is one
+static ISR_PORT: AtomicU16 = AtomicU16::new(0);
+/// The GSI the interrupt is routed to, [`NO_GSI`] if it has none
+static GSI: AtomicU32 = AtomicU32::new(NO_GSI);
+/// The interrupts of the device, the ones on the line for another device are not counted
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+const NO_GSI: u32 = u32::MAX;
+
+#[derive(Debug)]
+enum VirtioError {
+    NoIoPorts,
+    NoMac,
+    /// The queue is missing, or too large for the static memory
+    QueueSize(u16, u16),
+    OutOfMemory,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoIoPorts => write!(f, "no I/O ports"),
+            Self::NoMac => write!(f, "no MAC address"),
+            Self::QueueSize(queue, size) => write!(f, "queue {queue} has {size} entries"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// One virtqueue in the layout of the legacy interface: the descriptors, the available ring
+/// right after them, and the used ring at the next page
+struct Virtqueue {
+    memory: *mut u8,
+    size: u16,
+    pages: [*mut u8; BUFFERS / BUFFERS_PER_PAGE],
+    buffers: usize,
+    /// The index of the next entry of the available ring
+    available: u16,
+    /// The entries of the used ring already seen
+    used: u16,
+}
+
+impl Virtqueue {
+    fn new(memory: *mut u8, size: u16) -> Result<Self, VirtioError> {
+        let mut queue = Self {
+            memory,
+            size,
+            pages: [ptr::null_mut(); BUFFERS / BUFFERS_PER_PAGE],
+            buffers: BUFFERS.min(size as usize),
+            available: 0,
+            used: 0,
+        };
+        // the ones allocated are freed by the drop on failure
+        for page in queue.pages.iter_mut() {
+            // SAFETY: the allocator is initialized before the PCI probe
+            *page =
+                unsafe { physical_page_allocator::try_alloc() }.ok_or(VirtioError::OutOfMemory)?;
+        }
+        // SAFETY: the memory is only used by this queue
+        unsafe { memory.write_bytes(0, QUEUE_MEMORY_PAGES * PAGE_4K) };
+        Ok(queue)
+    }
+
+    fn available_offset(&self) -> usize {
+        DESCRIPTOR_LEN * self.size as usize
+    }
+
+    fn used_offset(&self) -> usize {
+        align_up(
+            self.available_offset() + 6 + 2 * self.size as usize,
+            PAGE_4K,
+        )
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= QUEUE_MEMORY_PAGES * PAGE_4K);
+        unsafe { (self.memory.add(offset) as *mut T).write_volatile(value) }
+    }
+
+    fn read<T>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= QUEUE_MEMORY_PAGES * PAGE_4K);
+        unsafe { (self.memory.add(offset) as *const T).read_volatile() }
+    }
+
+    fn buffer(&self, id: usize) -> *mut u8 {
+        unsafe { self.pages[id / BUFFERS_PER_PAGE].add(id % BUFFERS_PER_PAGE * BUFFER_LEN) }
+    }
+
+    fn set_descriptor(&self, id: usize, len: usize, flags: u16) {
+        let offset = id * DESCRIPTOR_LEN;
+        self.write::<u64>(offset, virtual2physical(self.buffer(id) as usize) as u64);
+        self.write::<u32>(offset + 8, len as u32);
+        self.write::<u16>(offset + 12, flags);
+        self.write::<u16>(offset + 14, 0);
+    }
+
+    /// Puts the descriptor `id` in the available ring, the device is notified separately
+    fn make_available(&mut self, id: usize) {
+        let slot = (self.available % self.size) as usize;
+        self.write::<u16>(self.available_offset() + 4 + 2 * slot, id as u16);
+        // the entry is seen before the index that includes it
+        atomic::fence(Ordering::SeqCst);
+        self.available = self.available.wrapping_add(1);
+        self.write::<u16>(self.available_offset() + 2, self.available);
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    /// The next entry of the used ring, its descriptor and the length written to it, the
+    /// entries with descriptors that were never given are skipped
+    fn pop_used(&mut self) -> Option<(usize, usize)> {
+        loop {
+            let used_index = self.read::<u16>(self.used_offset() + 2);
+            if used_index == self.used {
+                return None;
+            }
+            atomic::fence(Ordering::SeqCst);
+            let slot = (self.used % self.size) as usize;
+            let entry = self.used_offset() + 4 + 8 * slot;
+            let id = self.read::<u32>(entry) as usize;
+            let len = self.read::<u32>(entry + 4) as usize;
+            self.used = self.used.wrapping_add(1);
+            if id < self.buffers {
+                return Some((id, len));
+            }
+        }
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        for page in self.pages.iter().filter(|page| !page.is_null()) {
+            // SAFETY: the device is reset before the queue is dropped
+            unsafe { physical_page_allocator::free(*page) };
+        }
+    }
+}
+
+struct VirtioNet {
+    io: u16,
+    mac: MacAddress,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    /// The transmit buffers not in use, a bit each
+    tx_free: u64,
+}
+
+// SAFETY: the queues are only used by the device owning them, inside the interface
+unsafe impl Send for VirtioNet {}
+
+impl VirtioNet {
+    fn new(config: &PciDeviceConfig) -> Result<Self, VirtioError> {
+        let (io, _) = config.base_address[BAR_IO]
+            .get_io()
+            .ok_or(VirtioError::NoIoPorts)?;
+        config.write_command(config.read_command() | PCI_COMMAND_IO_SPACE | PCI_COMMAND_BUS_MASTER);
+        if config.read_command() & PCI_COMMAND_IO_SPACE == 0 {
+            return Err(VirtioError::NoIoPorts);
+        }
+        let io_out8 = |offset: u16, value: u8| unsafe { cpu::io_out(io + offset, value) };
+        io_out8(reg::DEVICE_STATUS, 0);
+        io_out8(reg::DEVICE_STATUS, status::ACKNOWLEDGE);
+        io_out8(reg::DEVICE_STATUS, status::ACKNOWLEDGE | status::DRIVER);
+
+        let result = Self::setup(io);
+        match &result {
+            Ok(_) => io_out8(
+                reg::DEVICE_STATUS,
+                status::ACKNOWLEDGE | status::DRIVER | status::DRIVER_OK,
+            ),
+            Err(_) => io_out8(reg::DEVICE_STATUS, status::FAILED),
+        }
+        result
+    }
+
+    fn setup(io: u16) -> Result<Self, VirtioError> {
+        let features: u32 = unsafe { cpu::io_in(io + reg::DEVICE_FEATURES) };
+        if features & FEATURE_MAC == 0 {
+            return Err(VirtioError::NoMac);
+        }
+        unsafe { cpu::io_out(io + reg::DRIVER_FEATURES, FEATURE_MAC) };
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = unsafe { cpu::io_in(io + reg::MAC + i as u16) };
+        }
+
+        let queue = |index: u16| {
+            unsafe { cpu::io_out(io + reg::QUEUE_SELECT, index) };
+            let size: u16 = unsafe { cpu::io_in(io + reg::QUEUE_SIZE) };
+            if size == 0 || size > MAX_QUEUE_SIZE {
+                return Err(VirtioError::QueueSize(index, size));
+            }
+            // SAFETY: only one device takes the memory, see `TAKEN`
+            let memory = unsafe { ptr::addr_of_mut!(QUEUE_MEMORY[index as usize]) } as *mut u8;
+            let queue = Virtqueue::new(memory, size)?;
+            let pfn = (virtual2physical(memory as usize) / PAGE_4K) as u32;
+            unsafe { cpu::io_out(io + reg::QUEUE_ADDRESS, pfn) };
+            Ok(queue)
+        };
+        let mut net = Self {
+            io,
+            mac,
+            rx: queue(QUEUE_RX)?,
+            tx: queue(QUEUE_TX)?,
+            tx_free: 0,
+        };
+        net.tx_free = (1 << net.tx.buffers) - 1;
+
+        for id in 0..net.rx.buffers {
+            net.rx.set_descriptor(id, BUFFER_LEN, DESCRIPTOR_WRITE);
+            net.rx.make_available(id);
+        }
+        net.notify(QUEUE_RX);
+        Ok(net)
+    }
+
+    fn notify(&self, queue: u16) {
+        unsafe { cpu::io_out(self.io + reg::QUEUE_NOTIFY, queue) };
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, len: usize, fill: &mut dyn FnMut(&mut [u8])) -> Result<(), TxError> {
+        if NET_HEADER_LEN + len > BUFFER_LEN {
+            return Err(TxError::TooLarge);
+        }
+        while let Some((id, _)) = self.tx.pop_used() {
+            self.tx_free |= 1 << id;
+        }
+        if self.tx_free == 0 {
+            return Err(TxError::RingFull);
+        }
+        let id = self.tx_free.trailing_zeros() as usize;
+        self.tx_free &= !(1 << id);
+
+        // SAFETY: the buffer is free, the device is not reading it
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(self.tx.buffer(id), NET_HEADER_LEN + len) };
+        buffer[..NET_HEADER_LEN].fill(0);
+        fill(&mut buffer[NET_HEADER_LEN..]);
+        self.tx.set_descriptor(id, NET_HEADER_LEN + len, 0);
+        self.tx.make_available(id);
+        self.notify(QUEUE_TX);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let (id, len) = self.rx.pop_used()?;
+        let len = len.clamp(NET_HEADER_LEN, BUFFER_LEN);
+        // SAFETY: the device is done with the buffer until it's made available again
+        let frame = unsafe {
+            core::slice::from_raw_parts(
+                self.rx.buffer(id).add(NET_HEADER_LEN),
+                len - NET_HEADER_LEN,
+            )
+        }
+        .to_vec();
+        self.rx.make_available(id);
+        self.notify(QUEUE_RX);
+        Some(frame)
+    }
+
+    fn interrupts(&self) -> Option<(u32, u64)> {
+        let gsi = GSI.load(Ordering::Relaxed);
+        (gsi != NO_GSI).then(|| (gsi, INTERRUPTS.load(Ordering::Relaxed)))
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        // stop the device before its queues are freed
+        unsafe { cpu::io_out(self.io + reg::DEVICE_STATUS, 0u8) };
+    }
+}
+
+extern "x86-interrupt" fn virtio_net_interrupt(_stack_frame: InterruptStackFrame64) {
+    irq_off::interrupt_enter("virtio_net");
+    let port = ISR_PORT.load(Ordering::Relaxed);
+    // reading it acknowledges the interrupt, level triggered
+    if port != 0 && unsafe { cpu::io_in::<u8>(port) } & ISR_QUEUE != 0 {
+        INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        softirq::raise(SoftIrq::NetRx);
+    }
+    irq_off::interrupt_exit();
+    apic::return_from_interrupt();
+}
+
+/// Takes the first virtio-net device (`1AF4:1000`), starts it and attaches it to the
+/// network stack
+pub fn try_register(config: &PciDeviceConfig) -> bool {
+    if config.vendor_id != VENDOR_VIRTIO || config.device_id != DEVICE_NET {
+        return false;
+    }
+    let name = format!("{:02X}.{:02X}.{:02X}", config.bus, config.dev, config.func);
+    if TAKEN.swap(true, Ordering::AcqRel) {
+        println!("virtio-net {}: only one device is supported", name);
+        return false;
+    }
+    let device = match VirtioNet::new(config) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("virtio-net {}: failed to start: {e}", name);
+            TAKEN.store(false, Ordering::Release);
+            return false;
+        }
+    };
+    let io = device.io;
+    println!(
+        "virtio-net {}: mac {}, queues {}/{}",
+        name,
+        net::DisplayMac(device.mac),
+        device.rx.size,
+        device.tx.size
+    );
+    if !net::attach(Box::new(device)) {
+        println!("virtio-net {name}: another network device is in use");
+        return false;
+    }
+
+    match config.route_intx() {
+        Some(route) if apic::is_gsi_free(route.gsi) => {
+            ISR_PORT.store(io + reg::ISR_STATUS, Ordering::Relaxed);
+            GSI.store(route.gsi, Ordering::Relaxed);
+            route.assign(virtio_net_interrupt as BasicInterruptHandler, cpu::cpu());
+        }
+        Some(route) => {
+            println!("WARNING: virtio-net interrupt {route} is shared, not supported yet");
+        }
+        None => {
+            println!("WARNING: virtio-net has no interrupt, nothing will be received");
+        }
+    }
+    true
+}
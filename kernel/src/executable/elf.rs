@@ -1,15 +1,65 @@
-use core::{fmt, mem};
+use core::{fmt, mem, ops::Range};
 
 use alloc::vec::Vec;
 
 use crate::{fs, memory_management::virtual_memory_mapper};
 
+/// Where position independent executables (`ET_DYN`) are loaded, fixed until we randomize it
+pub const PIE_LOAD_BASE: u64 = 0x40_0000;
+
+/// A `PT_LOAD` segment of a loaded program, see [`Elf::loaded_segments`]
+#[derive(Debug, Clone)]
+pub struct LoadedSegment {
+    pub range: Range<u64>,
+    /// The offset of the start of `range` in the file
+    pub file_offset: u64,
+    pub writable: bool,
+}
+
 #[derive(Debug)]
 pub enum ElfLoadError {
     InvalidMagic,
     FileSystemError(fs::FileSystemError),
     InvalidElfOrNotSupported,
     UnexpectedEndOfFile,
+    /// Has an interpreter (`PT_INTERP`), only static executables can be loaded
+    DynamicLinkingNotSupported,
+    /// A relocation table we don't read, with its dynamic tag, i.e. `DT_REL`
+    UnsupportedRelocationTable(u64),
+    /// A relocation type we don't apply, with its number
+    UnsupportedRelocation(u32),
+    /// The dynamic section, or a relocation table or target, is not inside the loaded
+    /// segments, with the address
+    OutsideSegments(u64),
+}
+
+impl fmt::Display for ElfLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfLoadError::InvalidMagic => write!(f, "not an ELF file"),
+            ElfLoadError::FileSystemError(e) => write!(f, "could not read the file: {e}"),
+            ElfLoadError::InvalidElfOrNotSupported => write!(f, "invalid or not supported ELF"),
+            ElfLoadError::UnexpectedEndOfFile => write!(f, "unexpected end of file"),
+            ElfLoadError::DynamicLinkingNotSupported => {
+                write!(
+                    f,
+                    "needs an interpreter, only static executables are supported"
+                )
+            }
+            ElfLoadError::UnsupportedRelocationTable(tag) => {
+                write!(
+                    f,
+                    "relocation table with dynamic tag {tag} is not supported"
+                )
+            }
+            ElfLoadError::UnsupportedRelocation(ty) => {
+                write!(f, "relocation type {ty} is not supported")
+            }
+            ElfLoadError::OutsideSegments(address) => {
+                write!(f, "address {address:#x} is outside the loaded segments")
+            }
+        }
+    }
 }
 
 impl From<fs::FileSystemError> for ElfLoadError {
@@ -34,6 +84,22 @@ mod consts {
     pub const ELF_MACHINE_X86: u16 = 3;
     pub const ELF_MACHINE_X86_64: u16 = 62;
 
+    pub const PROG_TYPE_GNU_RELRO: u32 = 0x6474_e552;
+
+    pub const DYN_TAG_NULL: u64 = 0;
+    pub const DYN_TAG_PLTRELSZ: u64 = 2;
+    pub const DYN_TAG_RELA: u64 = 7;
+    pub const DYN_TAG_RELASZ: u64 = 8;
+    pub const DYN_TAG_RELAENT: u64 = 9;
+    pub const DYN_TAG_REL: u64 = 17;
+    pub const DYN_TAG_PLTREL: u64 = 20;
+    pub const DYN_TAG_JMPREL: u64 = 23;
+    pub const DYN_TAG_RELR: u64 = 36;
+
+    pub const RELOC_X86_64_NONE: u32 = 0;
+    pub const RELOC_X86_64_64: u32 = 1;
+    pub const RELOC_X86_64_RELATIVE: u32 = 8;
+
     pub const PROG_FLAG_EXE: u32 = 0x1;
     pub const PROG_FLAG_WRITE: u32 = 0x2;
     pub const PROG_FLAG_READ: u32 = 0x4;
@@ -365,6 +431,10 @@ impl ElfProgram {
         }
     }
 
+    pub fn is_writable(&self) -> bool {
+        self.flags() & consts::PROG_FLAG_WRITE != 0
+    }
+
     pub fn alignment(&self) -> u64 {
         match self {
             Self::Program32(p) => p.alignment as u64,
@@ -406,7 +476,9 @@ impl Elf {
         if &header.base.magic != consts::ELF_MAGIC {
             return Err(ElfLoadError::InvalidMagic);
         }
-        if !header.is_valid_and_supported() {
+        if !header.is_valid_and_supported()
+            || (header.base.elf_type == consts::ELF_TYPE_SHARED && !header.is_elf64())
+        {
             return Err(ElfLoadError::InvalidElfOrNotSupported);
         }
         file.seek(header.program_header_offset())?;
@@ -415,6 +487,9 @@ impl Elf {
         for _ in 0..header.program_header_entry_count() {
             let program =
                 ElfProgram::load(file, header.is_elf64(), header.program_header_entry_size())?;
+            if let ElfProgramType::Interpreter = program.ty() {
+                return Err(ElfLoadError::DynamicLinkingNotSupported);
+            }
             program_headers.push(program);
         }
 
@@ -424,11 +499,185 @@ impl Elf {
         })
     }
 
+    /// `ET_DYN`, its addresses are relative to [`Elf::load_base`]
+    pub fn is_position_independent(&self) -> bool {
+        self.header.base.elf_type == consts::ELF_TYPE_SHARED
+    }
+
+    /// Added to all the addresses in the file when loading it
+    pub fn load_base(&self) -> u64 {
+        if self.is_position_independent() {
+            PIE_LOAD_BASE
+        } else {
+            0
+        }
+    }
+
+    /// The entry in the loaded image, i.e. with [`Elf::load_base`] added
     pub fn entry_point(&self) -> u64 {
-        self.header.entry()
+        self.load_base() + self.header.entry()
     }
 
     pub fn program_headers(&self) -> &[ElfProgram] {
         &self.prg_headers
     }
+
+    /// The range to make read-only after relocating (`PT_GNU_RELRO`), without the base
+    pub fn relro(&self) -> Option<Range<u64>> {
+        self.prg_headers
+            .iter()
+            .find(|p| {
+                matches!(
+                    p.ty(),
+                    ElfProgramType::OsSpecific(consts::PROG_TYPE_GNU_RELRO)
+                )
+            })
+            .map(|p| p.virtual_address()..p.virtual_address() + p.mem_size())
+    }
+
+    /// The ranges of the `PT_LOAD` segments when loaded at [`Elf::load_base`]
+    pub fn loaded_ranges(&self) -> Vec<Range<u64>> {
+        let base = self.load_base();
+        self.prg_headers
+            .iter()
+            .filter(|p| matches!(p.ty(), ElfProgramType::Load))
+            .map(|p| base + p.virtual_address()..base + p.virtual_address() + p.mem_size())
+            .collect()
+    }
+
+    /// The `PT_LOAD` segments when loaded at [`Elf::load_base`], with where they are in the file
+    pub fn loaded_segments(&self) -> Vec<LoadedSegment> {
+        let base = self.load_base();
+        self.prg_headers
+            .iter()
+            .filter(|p| matches!(p.ty(), ElfProgramType::Load))
+            .map(|p| LoadedSegment {
+                range: base + p.virtual_address()..base + p.virtual_address() + p.mem_size(),
+                file_offset: p.offset(),
+                writable: p.is_writable(),
+            })
+            .collect()
+    }
+
+    /// Applies the relocations of the dynamic section (`PT_DYNAMIC`), returns how many
+    /// were applied, `0` if there is no dynamic section.
+    ///
+    /// Only `R_X86_64_RELATIVE` is supported, which is all a static PIE needs, anything
+    /// else fails before writing, so a failed image is never half relocated.
+    ///
+    /// # Safety
+    /// The image must be loaded at [`Elf::load_base`] in the current vm and be writable
+    pub unsafe fn apply_relocations(&self) -> Result<usize, ElfLoadError> {
+        let Some(dynamic) = self
+            .prg_headers
+            .iter()
+            .find(|p| matches!(p.ty(), ElfProgramType::Dynamic))
+        else {
+            return Ok(0);
+        };
+        let base = self.load_base();
+        let ranges = self.loaded_ranges();
+        let check = |address: u64, len: u64| -> Result<u64, ElfLoadError> {
+            let inside = address
+                .checked_add(len)
+                .is_some_and(|end| ranges.iter().any(|r| r.start <= address && end <= r.end));
+            if inside {
+                Ok(address)
+            } else {
+                Err(ElfLoadError::OutsideSegments(address))
+            }
+        };
+
+        // the tables are read in place, so they must be aligned
+        let check_table = |address: u64, len: u64| -> Result<u64, ElfLoadError> {
+            if !address.is_multiple_of(mem::align_of::<u64>() as u64) {
+                return Err(ElfLoadError::InvalidElfOrNotSupported);
+            }
+            check(address, len)
+        };
+
+        let dynamic_start = check_table(base + dynamic.virtual_address(), dynamic.mem_size())?;
+        let dynamic_entries = unsafe {
+            core::slice::from_raw_parts(
+                dynamic_start as *const [u64; 2],
+                dynamic.mem_size() as usize / mem::size_of::<[u64; 2]>(),
+            )
+        };
+
+        let mut rela = None;
+        let mut rela_size = 0;
+        let mut rela_entry_size = mem::size_of::<ElfRela64>() as u64;
+        let mut jmprel = None;
+        let mut jmprel_size = 0;
+        for &[tag, value] in dynamic_entries {
+            match tag {
+                consts::DYN_TAG_NULL => break,
+                consts::DYN_TAG_RELA => rela = Some(value),
+                consts::DYN_TAG_RELASZ => rela_size = value,
+                consts::DYN_TAG_RELAENT => rela_entry_size = value,
+                consts::DYN_TAG_JMPREL => jmprel = Some(value),
+                consts::DYN_TAG_PLTRELSZ => jmprel_size = value,
+                consts::DYN_TAG_PLTREL if value != consts::DYN_TAG_RELA => {
+                    return Err(ElfLoadError::UnsupportedRelocationTable(value));
+                }
+                consts::DYN_TAG_REL | consts::DYN_TAG_RELR => {
+                    return Err(ElfLoadError::UnsupportedRelocationTable(tag));
+                }
+                _ => {}
+            }
+        }
+        if rela_entry_size != mem::size_of::<ElfRela64>() as u64 {
+            return Err(ElfLoadError::InvalidElfOrNotSupported);
+        }
+
+        let mut tables = Vec::new();
+        for (table, size) in [(rela, rela_size), (jmprel, jmprel_size)] {
+            let Some(table) = table else {
+                continue;
+            };
+            let start = check_table(base + table, size)?;
+            let relocations = unsafe {
+                core::slice::from_raw_parts(
+                    start as *const ElfRela64,
+                    (size / rela_entry_size) as usize,
+                )
+            };
+            // validate all of them first
+            for rela in relocations {
+                match rela.ty() {
+                    consts::RELOC_X86_64_NONE => {}
+                    consts::RELOC_X86_64_RELATIVE => {
+                        check(base + rela.offset, mem::size_of::<u64>() as u64)?;
+                    }
+                    ty => return Err(ElfLoadError::UnsupportedRelocation(ty)),
+                }
+            }
+            tables.push(relocations);
+        }
+
+        let mut applied = 0;
+        for rela in tables.into_iter().flatten() {
+            if rela.ty() == consts::RELOC_X86_64_RELATIVE {
+                let target = (base + rela.offset) as *mut u64;
+                unsafe { target.write_unaligned(base.wrapping_add(rela.addend as u64)) };
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+}
+
+/// An entry of the `DT_RELA` table
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct ElfRela64 {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+impl ElfRela64 {
+    fn ty(&self) -> u32 {
+        self.info as u32
+    }
 }
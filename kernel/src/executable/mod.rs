@@ -1,4 +1,11 @@
-use crate::{cpu, fs, memory_management::virtual_memory_mapper};
+use alloc::{format, string::String, vec, vec::Vec};
+use kernel_user_link::syscalls::{SYSCALL_INTERRUPT_NUMBER, SYS_EXIT};
+
+use crate::{
+    cpu, fs,
+    memory_management::{memory_layout::PAGE_4K, virtual_memory_mapper},
+    process::{Process, ProcessError, ResourceLimits},
+};
 
 pub mod elf;
 
@@ -9,7 +16,7 @@ pub unsafe fn load_elf_to_vm(
     elf: &elf::Elf,
     file: &mut fs::File,
     vm: &mut virtual_memory_mapper::VirtualMemoryMapper,
-) -> Result<(usize, usize), fs::FileSystemError> {
+) -> Result<(usize, usize), elf::ElfLoadError> {
     // we can't be interrupted and load another process vm in the middle of this work
     cpu::cpu().push_cli();
     let old_vm = virtual_memory_mapper::get_current_vm();
@@ -19,42 +26,261 @@ pub unsafe fn load_elf_to_vm(
     //         kernel regions
     vm.switch_to_this();
 
+    let result = load_segments(elf, file, vm);
+
+    // switch back to the old vm, even on failure
+    old_vm.switch_to_this();
+    // we can be interrupted again
+    cpu::cpu().pop_cli();
+
+    result
+}
+
+/// Loads the segments into the current vm, which must be `vm`, applies the relocations and
+/// then gives the pages their final flags
+fn load_segments(
+    elf: &elf::Elf,
+    file: &mut fs::File,
+    vm: &mut virtual_memory_mapper::VirtualMemoryMapper,
+) -> Result<(usize, usize), elf::ElfLoadError> {
+    let base = elf.load_base();
     let mut min_address = u64::MAX;
     let mut max_address = 0;
 
-    for segment in elf.program_headers() {
-        if let elf::ElfProgramType::Load = segment.ty() {
-            let segment_virtual = segment.virtual_address();
-            assert!(segment_virtual == segment.physical_address());
-            let mut flags = elf::to_virtual_memory_flags(segment.flags());
-            flags |= virtual_memory_mapper::flags::PTE_USER;
-            let entry = virtual_memory_mapper::VirtualMemoryMapEntry {
-                virtual_address: segment_virtual,
-                physical_address: None,
-                size: segment.mem_size(),
-                flags,
-            };
-            min_address = min_address.min(entry.virtual_address);
-            max_address = max_address.max(entry.virtual_address + entry.size);
-            eprintln!("Mapping segment: {:x?}", entry);
-            vm.map(&entry);
-
-            // read the file into the memory
-            file.seek(segment.offset())?;
-
-            let ptr = segment_virtual as *mut u8;
-            let slice =
-                unsafe { core::slice::from_raw_parts_mut(ptr, segment.file_size() as usize) };
-
-            // read the whole segment
-            assert_eq!(file.read(slice)?, segment.file_size());
+    let load_segments = || {
+        elf.program_headers()
+            .iter()
+            .filter(|segment| matches!(segment.ty(), elf::ElfProgramType::Load))
+    };
+
+    for segment in load_segments() {
+        let segment_virtual = base + segment.virtual_address();
+        if segment.virtual_address() != segment.physical_address()
+            || segment.file_size() > segment.mem_size()
+        {
+            return Err(elf::ElfLoadError::InvalidElfOrNotSupported);
+        }
+        // writable until relocated, protected below
+        let entry = virtual_memory_mapper::VirtualMemoryMapEntry {
+            virtual_address: segment_virtual,
+            physical_address: None,
+            size: segment.mem_size(),
+            flags: virtual_memory_mapper::flags::PTE_USER
+                | virtual_memory_mapper::flags::PTE_WRITABLE,
+        };
+        min_address = min_address.min(entry.virtual_address);
+        max_address = max_address.max(entry.virtual_address + entry.size);
+        eprintln!("Mapping segment: {:x?}", entry);
+        vm.map(&entry);
+
+        // read the file into the memory
+        file.seek(segment.offset())?;
+
+        let ptr = segment_virtual as *mut u8;
+        let slice = unsafe { core::slice::from_raw_parts_mut(ptr, segment.file_size() as usize) };
+
+        // read the whole segment
+        if file.read(slice)? != segment.file_size() {
+            return Err(elf::ElfLoadError::UnexpectedEndOfFile);
         }
     }
 
-    // switch back to the old vm
-    old_vm.switch_to_this();
-    // we can be interrupted again
-    cpu::cpu().pop_cli();
+    if elf.is_position_independent() {
+        let applied = unsafe { elf.apply_relocations()? };
+        eprintln!("Applied {applied} relocations at {base:#x}");
+    }
+
+    // a page shared with a writable segment stays writable
+    let is_writable_page = |page: u64| {
+        load_segments().any(|segment| {
+            let start = base + segment.virtual_address();
+            segment.is_writable()
+                && start & !(PAGE_4K as u64 - 1) <= page
+                && page < start + segment.mem_size()
+        })
+    };
+    for segment in load_segments() {
+        if segment.is_writable() {
+            continue;
+        }
+        let flags =
+            elf::to_virtual_memory_flags(segment.flags()) | virtual_memory_mapper::flags::PTE_USER;
+        let start = (base + segment.virtual_address()) & !(PAGE_4K as u64 - 1);
+        let end = base + segment.virtual_address() + segment.mem_size();
+        for page in (start..end).step_by(PAGE_4K) {
+            if !is_writable_page(page) {
+                vm.protect(page, PAGE_4K as u64, flags);
+            }
+        }
+    }
+    // only the pages fully inside, the rest of the last page is writable data
+    if let Some(relro) = elf.relro() {
+        let start = (base + relro.start) & !(PAGE_4K as u64 - 1);
+        let end = (base + relro.end) & !(PAGE_4K as u64 - 1);
+        if start < end {
+            vm.protect(start, end - start, virtual_memory_mapper::flags::PTE_USER);
+        }
+    }
 
     Ok((min_address as usize, max_address as usize))
 }
+
+const SELFTEST_PIE_NAME: &str = "selftest_pie";
+const SELFTEST_PIE_PATH: &str = "/tmp/selftest_pie";
+/// `mov eax, SYS_EXIT; mov ecx, 0; int SYSCALL_INTERRUPT_NUMBER`
+const SELFTEST_EXIT_CODE: [u8; 12] = [
+    0xb8,
+    SYS_EXIT as u8,
+    0,
+    0,
+    0,
+    0xb9,
+    0,
+    0,
+    0,
+    0,
+    0xcd,
+    SYSCALL_INTERRUPT_NUMBER,
+];
+
+/// A static PIE with a code page at `0`, and a data page at `0x1000` with its `.bss` after.
+///
+/// The dynamic section is at `0x1000` and its `RELA` table at `0x1100`, relocating
+/// `0x1200` to the entry `0x800` and `0x2000` (in the `.bss`) to `0x1200`, then `0x1000` is
+/// made read-only by the `PT_GNU_RELRO`. `second` is the type and offset of the second
+/// relocation
+fn selftest_pie_image(second: (u64, u64), interpreter: bool) -> Vec<u8> {
+    fn put(image: &mut [u8], offset: usize, values: &[u64]) {
+        for (i, value) in values.iter().enumerate() {
+            image[offset + i * 8..offset + i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    fn program(ty: u32, flags: u32, address: u64, file_size: u64, mem_size: u64) -> Vec<u64> {
+        let offset = if ty == 1 || ty == 2 { address } else { 0 };
+        vec![
+            ((flags as u64) << 32) | ty as u64,
+            offset,
+            address,
+            address,
+            file_size,
+            mem_size,
+            PAGE_4K as u64,
+        ]
+    }
+
+    let mut image = vec![0u8; 0x2000];
+    image[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // type ET_DYN, machine x86_64, version 1
+    put(&mut image, 16, &[0x1_003e_0003, 0x800, 64, 0]);
+    // no flags, header size 64, 4 program headers of 56 bytes, no sections
+    put(&mut image, 48, &[(56 << 48) | (64 << 32), 4]);
+
+    let headers = [
+        program(1, 0b101, 0, 0x1000, 0x1000),
+        program(1, 0b110, 0x1000, 0x1000, 0x2000),
+        program(2, 0b110, 0x1000, 0x40, 0x40),
+        program(
+            if interpreter { 3 } else { 0x6474_e552 },
+            0b100,
+            0x1000,
+            0,
+            0x1000,
+        ),
+    ];
+    for (i, header) in headers.iter().enumerate() {
+        put(&mut image, 64 + i * 56, header);
+    }
+
+    // DT_RELA, DT_RELASZ, DT_RELAENT, DT_NULL
+    put(&mut image, 0x1000, &[7, 0x1100, 8, 48, 9, 24, 0, 0]);
+    let (ty, offset) = second;
+    put(&mut image, 0x1100, &[0x1200, 8, 0x800, offset, ty, 0x1200]);
+    image
+}
+
+fn selftest_spawn(image: Vec<u8>) -> Result<Process, ProcessError> {
+    fs::ramfs::create_tmp_file(SELFTEST_PIE_NAME, image).unwrap();
+    let mut file = fs::open(SELFTEST_PIE_PATH).unwrap();
+    let result = elf::Elf::load(&mut file)
+        .map_err(ProcessError::from)
+        .and_then(|elf| {
+            Process::allocate_process(
+                0,
+                &elf,
+                &mut file,
+                vec![String::from(SELFTEST_PIE_PATH)],
+                Vec::new(),
+                ResourceLimits::unlimited(),
+            )
+        });
+    drop(file);
+    fs::remove_file(SELFTEST_PIE_PATH).unwrap();
+    result
+}
+
+/// A process that exits with `0` as soon as it runs, the PIE of [`selftest_pie_image`] with
+/// code at its entry, for the scheduler self tests
+pub fn selftest_exiting_process() -> Process {
+    let mut image = selftest_pie_image((8, 0x2000), false);
+    image[0x800..0x800 + SELFTEST_EXIT_CODE.len()].copy_from_slice(&SELFTEST_EXIT_CODE);
+    selftest_spawn(image).unwrap()
+}
+
+/// Loads a static PIE at [`elf::PIE_LOAD_BASE`] and checks its relocations and final
+/// permissions, then that the broken ones fail to load
+pub fn run_self_tests() {
+    use elf::ElfLoadError::*;
+
+    println!("Running executable loading self tests...");
+    let base = elf::PIE_LOAD_BASE;
+
+    let mut process = selftest_spawn(selftest_pie_image((8, 0x2000), false)).unwrap();
+    cpu::cpu().push_cli();
+    let old_vm = virtual_memory_mapper::get_current_vm();
+    // SAFETY: the process vm shares the kernel regions with the current one
+    unsafe { process.switch_to_this_vm() };
+    let relocated = [0x1200, 0x2000].map(|offset| {
+        let bytes = process.read_user_memory(base + offset, 8);
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    });
+    unsafe { old_vm.switch_to_this() };
+    cpu::cpu().pop_cli();
+    assert_eq!(relocated, [base + 0x800, base + 0x1200]);
+
+    // the code and the RELRO page are read-only, the `.bss` after them isn't
+    let maps = process.user_maps();
+    for expected in [
+        format!(
+            "{:016x}-{:016x} r-x {SELFTEST_PIE_PATH}",
+            base,
+            base + 0x2000
+        ),
+        format!(
+            "{:016x}-{:016x} rwx {SELFTEST_PIE_PATH}",
+            base + 0x2000,
+            base + 0x3000
+        ),
+    ] {
+        assert!(
+            maps.lines().any(|line| line == expected),
+            "{expected}\n{maps}"
+        );
+    }
+    drop(process);
+
+    // R_X86_64_64 needs symbols, and a target after the image
+    let failures = [
+        ((1, 0x2000), false, UnsupportedRelocation(1)),
+        ((8, 0x5000), false, OutsideSegments(base + 0x5000)),
+        ((8, 0x2000), true, DynamicLinkingNotSupported),
+    ];
+    for (second, interpreter, expected) in failures {
+        let result = selftest_spawn(selftest_pie_image(second, interpreter));
+        let Err(ProcessError::CouldNotLoadElf(e)) = result else {
+            panic!("{second:?}: expected {expected:?}, loaded");
+        };
+        assert_eq!(format!("{e}"), format!("{expected}"), "{second:?}");
+    }
+
+    println!("Executable loading self tests passed");
+}
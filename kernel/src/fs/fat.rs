@@ -2,6 +2,7 @@ use core::{fmt, mem};
 
 use alloc::{
     boxed::Box,
+    format,
     string::{String, ToString},
     sync::Arc,
     vec,
@@ -9,16 +10,36 @@ use alloc::{
 };
 
 use crate::{
-    devices::ide::{self, IdeDeviceIndex},
+    devices::{
+        block::{self, BlockDevice, BlockDeviceFile, SelftestCacheDisk},
+        ramdisk::RamDisk,
+    },
     io::NoDebug,
     memory_management::memory_layout::align_up,
     sync::spin::mutex::Mutex,
 };
 
-use super::{FileAttributes, FileSystem, FileSystemError, INode};
+use kernel_core::{
+    fat::{FatEntry, FatType},
+    sector::{Cluster, FsSector, Lba},
+};
+
+use super::{page_cache, FileAttributes, FileSystem, FileSystemError, INode, ResultContext};
 
 const DIRECTORY_ENTRY_SIZE: u32 = 32;
 
+/// The root directory of FAT12/16 is not in a cluster, so its entries use this in their
+/// inode ids instead, clusters 0 and 1 are reserved and never hold data
+const ROOT_DIR_FAT12_16_CLUSTER: u32 = 1;
+/// The root directory has no entry, and no entry can get this id because cluster 0 is reserved
+const ROOT_INODE_ID: u64 = 0;
+
+/// FAT has no inode numbers, so a file is identified by the location of its directory entry,
+/// this is the same as long as the file is not moved, even if its clusters are changed.
+fn inode_id(dir_cluster: u32, entry_index: u32) -> u64 {
+    (dir_cluster as u64) << 32 | entry_index as u64
+}
+
 fn file_attribute_from_fat(attributes: u8) -> FileAttributes {
     FileAttributes {
         read_only: attributes & attrs::READ_ONLY == attrs::READ_ONLY,
@@ -36,6 +57,169 @@ pub enum FatError {
     UnexpectedFatEntry,
 }
 
+/// What is wrong in a long name, see [`FatViolation::LongName`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongNameProblem {
+    /// An entry that doesn't continue the long name before it, `expected` is the ordinal it
+    /// should have, `None` if there is no long name to continue
+    OutOfOrder { expected: Option<u8>, found: u8 },
+    /// The long name is not followed by its short entry, or stops before its first part
+    Unterminated,
+    /// The checksum in the long name is not the one of the short name after it
+    Checksum { expected: u8, found: u8 },
+}
+
+/// Something [`FatFilesystem::check`] found wrong, the paths are the ones of the short names
+#[derive(Debug)]
+pub enum FatViolation {
+    /// The directory couldn't be read, so its entries are not checked
+    Unreadable {
+        path: String,
+        error: FileSystemError,
+    },
+    /// The chain of `path` goes to a cluster that can't hold data
+    OutOfRange { path: String, cluster: Cluster },
+    /// The chain of `path` goes to a cluster whose entry is not in use
+    NotInUse {
+        path: String,
+        cluster: Cluster,
+        entry: FatEntry,
+    },
+    /// `cluster` is in the chain of `other` too, or twice in the chain of `path`
+    CrossedChains {
+        path: String,
+        other: String,
+        cluster: Cluster,
+    },
+    /// The chain doesn't have the clusters of the size in the entry
+    SizeMismatch {
+        path: String,
+        clusters: u32,
+        size: u32,
+    },
+    /// `.` or `..` of the directory is missing, or doesn't point to `expected`
+    DotEntry {
+        path: String,
+        name: &'static str,
+        expected: Cluster,
+        found: Option<Cluster>,
+    },
+    /// In the directory `path`, at the entry `entry`
+    LongName {
+        path: String,
+        entry: u32,
+        problem: LongNameProblem,
+    },
+    /// `count` entries of the copy `copy` of the FAT are not the same as in the first one
+    FatCopiesDiffer {
+        copy: u8,
+        first: Cluster,
+        count: u32,
+    },
+    /// The free count of the FAT32 `FSInfo` is neither unknown nor the real one
+    FsInfoFreeCount { recorded: u32, actual: u32 },
+    /// Clusters in use but not in any chain, left by a crash in the middle of a change, these
+    /// only waste space
+    LostClusters { first: Cluster, count: u32 },
+}
+
+impl fmt::Display for FatViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreadable { path, error } => write!(f, "{path}: can't be read: {error:?}"),
+            Self::OutOfRange { path, cluster } => {
+                write!(f, "{path}: cluster {cluster} is out of range")
+            }
+            Self::NotInUse {
+                path,
+                cluster,
+                entry,
+            } => write!(f, "{path}: cluster {cluster} in the chain is {entry:?}"),
+            Self::CrossedChains {
+                path,
+                other,
+                cluster,
+            } if path == other => write!(f, "{path}: the chain loops at cluster {cluster}"),
+            Self::CrossedChains {
+                path,
+                other,
+                cluster,
+            } => write!(f, "{path}: cluster {cluster} is in the chain of {other}"),
+            Self::SizeMismatch {
+                path,
+                clusters,
+                size,
+            } => write!(f, "{path}: {clusters} clusters for a size of {size}"),
+            Self::DotEntry {
+                path,
+                name,
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "{path}: `{name}` points to {found} instead of {expected}"
+            ),
+            Self::DotEntry {
+                path,
+                name,
+                found: None,
+                ..
+            } => write!(f, "{path}: no `{name}` entry"),
+            Self::LongName {
+                path,
+                entry,
+                problem,
+            } => write!(f, "{path}: long name at entry {entry}: {problem:?}"),
+            Self::FatCopiesDiffer { copy, first, count } => write!(
+                f,
+                "FAT copy {copy}: {count} entries differ from the first FAT, from cluster {first}"
+            ),
+            Self::FsInfoFreeCount { recorded, actual } => write!(
+                f,
+                "FSInfo: {recorded} free clusters recorded, {actual} are free"
+            ),
+            Self::LostClusters { first, count } => {
+                write!(f, "{count} lost clusters, from cluster {first}")
+            }
+        }
+    }
+}
+
+/// The result of [`FatFilesystem::check`]
+#[derive(Debug, Default)]
+pub struct FatCheck {
+    pub violations: Vec<FatViolation>,
+    pub free_clusters: u32,
+}
+
+impl FatCheck {
+    pub fn lost_clusters(&self) -> u32 {
+        self.violations
+            .iter()
+            .map(|violation| match violation {
+                FatViolation::LostClusters { count, .. } => *count,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Nothing but lost clusters, which is what a crash in the middle of a change leaves
+    pub fn is_consistent(&self) -> bool {
+        self.violations
+            .iter()
+            .all(|violation| matches!(violation, FatViolation::LostClusters { .. }))
+    }
+}
+
+impl fmt::Display for FatCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for violation in &self.violations {
+            writeln!(f, "{violation}")?;
+        }
+        Ok(())
+    }
+}
+
 impl From<FatError> for FileSystemError {
     fn from(e: FatError) -> Self {
         FileSystemError::FatError(e)
@@ -43,12 +227,10 @@ impl From<FatError> for FileSystemError {
 }
 
 pub fn load_fat_filesystem(
-    ide_index: IdeDeviceIndex,
-    start_lba: u32,
+    device: Arc<BlockDeviceFile>,
+    start_lba: Lba,
     size_in_sectors: u32,
 ) -> Result<FatFilesystem, FileSystemError> {
-    let device = ide::get_ide_device(ide_index).ok_or(FileSystemError::DeviceNotFound)?;
-
     let size = align_up(
         mem::size_of::<FatBootSectorRaw>(),
         device.sector_size() as usize,
@@ -56,11 +238,8 @@ pub fn load_fat_filesystem(
     let mut sectors = vec![0; size];
 
     device
-        .read_sync(start_lba as u64, &mut sectors)
-        .map_err(|e| FileSystemError::DiskReadError {
-            sector: start_lba as u64,
-            error: e,
-        })?;
+        .read_sectors(start_lba, &mut sectors)
+        .context("read boot sector", u32::try_from(start_lba.0).ok())?;
 
     // SAFETY: This is a valid allocated memory
     let boot_sector = unsafe { &*(sectors.as_ptr() as *const FatBootSectorRaw) };
@@ -69,6 +248,18 @@ pub fn load_fat_filesystem(
     FatFilesystem::new(start_lba, size_in_sectors, boot_sector, device)
 }
 
+/// Checks the FAT filesystem in `device`, it is loaded again and only read, so it can be
+/// mounted too, see [`FatFilesystem::check`]
+pub fn check(
+    device: Arc<BlockDeviceFile>,
+    start_lba: Lba,
+    size_in_sectors: u32,
+) -> Result<FatCheck, FileSystemError> {
+    let mut filesystem = load_fat_filesystem(device, start_lba, size_in_sectors)?;
+    filesystem.set_read_only();
+    Ok(filesystem.check())
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 struct Fat12_16ExtendedBootSector {
@@ -170,70 +361,6 @@ impl fmt::Debug for FatBootSectorRaw {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum FatType {
-    Fat12,
-    Fat16,
-    Fat32,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum FatEntry {
-    Free,
-    // In use, and point to the next cluster
-    Next(u32),
-    // In use, and this is the last cluster
-    EndOfChain,
-    Bad,
-    Reserved,
-}
-
-impl FatEntry {
-    pub fn from_u32(ty: FatType, entry: u32) -> FatEntry {
-        match ty {
-            FatType::Fat12 => {
-                if entry == 0 {
-                    FatEntry::Free
-                } else if entry >= 0xFF8 {
-                    FatEntry::EndOfChain
-                } else if entry == 0xFF7 {
-                    FatEntry::Bad
-                } else if (0x002..=0xFF6).contains(&entry) {
-                    FatEntry::Next(entry)
-                } else {
-                    FatEntry::Reserved
-                }
-            }
-            FatType::Fat16 => {
-                if entry == 0 {
-                    FatEntry::Free
-                } else if entry >= 0xFFF8 {
-                    FatEntry::EndOfChain
-                } else if entry == 0xFFF7 {
-                    FatEntry::Bad
-                } else if (0x002..=0xFFF6).contains(&entry) {
-                    FatEntry::Next(entry)
-                } else {
-                    FatEntry::Reserved
-                }
-            }
-            FatType::Fat32 => {
-                if entry == 0 {
-                    FatEntry::Free
-                } else if entry >= 0x0FFF_FFF8 {
-                    FatEntry::EndOfChain
-                } else if entry == 0x0FFF_FFF7 {
-                    FatEntry::Bad
-                } else if (0x002..=0x0FFF_FFF6).contains(&entry) {
-                    FatEntry::Next(entry)
-                } else {
-                    FatEntry::Reserved
-                }
-            }
-        }
-    }
-}
-
 #[derive(Debug)]
 struct FatBootSector {
     ty: FatType,
@@ -249,11 +376,10 @@ impl FatBootSector {
 
         let count_of_clusters = size_in_sectors / boot_sector.sectors_per_cluster as u32;
 
-        let fat_type = match count_of_clusters {
-            _ if boot_sector.fat_size_16 == 0 => FatType::Fat32,
-            0..=4084 => FatType::Fat12,
-            4085..=65524 => FatType::Fat16,
-            _ => FatType::Fat32,
+        let fat_type = if boot_sector.fat_size_16 == 0 {
+            FatType::Fat32
+        } else {
+            FatType::from_cluster_count(count_of_clusters)
         };
 
         Ok(FatBootSector {
@@ -298,8 +424,8 @@ impl FatBootSector {
         self.boot_sector.number_of_fats
     }
 
-    pub fn fat_start_sector(&self) -> u32 {
-        self.boot_sector.reserved_sectors_count as u32
+    pub fn fat_start_sector(&self) -> FsSector {
+        FsSector(self.boot_sector.reserved_sectors_count as u32)
     }
 
     pub fn root_dir_sectors(&self) -> u32 {
@@ -308,16 +434,27 @@ impl FatBootSector {
             / self.boot_sector.bytes_per_sector as u32
     }
 
-    pub fn root_dir_start_sector(&self) -> u32 {
+    pub fn root_dir_start_sector(&self) -> FsSector {
         self.fat_start_sector() + self.number_of_fats() as u32 * self.fat_size_in_sectors()
     }
 
-    pub fn data_start_sector(&self) -> u32 {
+    pub fn data_start_sector(&self) -> FsSector {
         self.root_dir_start_sector() + self.root_dir_sectors()
     }
 
     pub fn data_sectors(&self) -> u32 {
-        self.total_sectors() - self.data_start_sector()
+        FsSector(self.total_sectors()) - self.data_start_sector()
+    }
+
+    /// The sector of the FAT32 `FSInfo`, if it has one
+    pub fn fs_info_sector(&self) -> Option<FsSector> {
+        if self.ty != FatType::Fat32 {
+            return None;
+        }
+        match unsafe { self.boot_sector.extended.fat32.fs_info } {
+            0 | 0xFFFF => None,
+            sector => Some(FsSector(sector as u32)),
+        }
     }
 
     pub fn volume_label(&self) -> &[u8; 11] {
@@ -341,10 +478,34 @@ mod attrs {
     pub const LONG_NAME: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
 }
 
+/// The FAT32 `FSInfo` sector, only its free count is used
+mod fs_info {
+    const LEAD_SIGNATURE: u32 = 0x4161_5252;
+    const STRUCT_SIGNATURE: u32 = 0x6141_7272;
+    const TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+    pub const FREE_COUNT: usize = 488;
+    pub const UNKNOWN: u32 = u32::MAX;
+
+    fn u32_at(sector: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn is_valid(sector: &[u8]) -> bool {
+        sector.len() >= 512
+            && u32_at(sector, 0) == LEAD_SIGNATURE
+            && u32_at(sector, 484) == STRUCT_SIGNATURE
+            && u32_at(sector, 508) == TRAIL_SIGNATURE
+    }
+
+    pub fn free_count(sector: &[u8]) -> u32 {
+        u32_at(sector, FREE_COUNT)
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Directory {
     RootFat12_16 {
-        start_sector: u32,
+        start_sector: FsSector,
         size_in_sectors: u32,
     },
     Normal {
@@ -366,9 +527,14 @@ pub struct DirectoryIterator<'a> {
     filesystem: &'a FatFilesystem,
     // only hold one sector
     current_sector: Vec<u8>,
-    current_sector_index: u32,
-    current_cluster: u32,
+    current_sector_index: FsSector,
+    current_cluster: Cluster,
     entry_index_in_sector: u32,
+    // the first cluster of the directory and the number of entries read from it, for the ids
+    dir_cluster: u32,
+    entry_index: u32,
+    // the last volume label entry found, only the root directory should have one
+    volume_label: Option<[u8; 11]>,
 }
 
 impl DirectoryIterator<'_> {
@@ -378,18 +544,28 @@ impl DirectoryIterator<'_> {
     ) -> Result<DirectoryIterator, FileSystemError> {
         let (sector_index, current_cluster, current_sector) = match dir {
             Directory::RootFat12_16 { start_sector, .. } => {
-                (start_sector, 0, filesystem.read_sectors(start_sector, 1)?)
+                let sector = filesystem
+                    .read_sectors(start_sector, 1)
+                    .context("read directory sector", Some(start_sector.0))?;
+                (start_sector, Cluster(0), sector)
             }
             Directory::Normal { ref inode } => {
-                let start_sector = filesystem.first_sector_of_cluster(inode.start_cluster);
+                let start_cluster = Cluster(inode.start_cluster);
+                let start_sector = filesystem.first_sector_of_cluster(start_cluster);
 
                 (
                     start_sector,
-                    inode.start_cluster,
-                    filesystem.read_sectors(start_sector, 1)?,
+                    start_cluster,
+                    filesystem
+                        .read_sectors(start_sector, 1)
+                        .context("read directory sector", Some(start_sector.0))?,
                 )
             }
         };
+        let dir_cluster = match dir {
+            Directory::RootFat12_16 { .. } => ROOT_DIR_FAT12_16_CLUSTER,
+            Directory::Normal { .. } => current_cluster.0,
+        };
         Ok(DirectoryIterator {
             dir,
             filesystem,
@@ -397,6 +573,9 @@ impl DirectoryIterator<'_> {
             current_cluster,
             current_sector_index: sector_index,
             entry_index_in_sector: 0,
+            dir_cluster,
+            entry_index: 0,
+            volume_label: None,
         })
     }
 
@@ -415,15 +594,15 @@ impl DirectoryIterator<'_> {
             }
             Directory::Normal { .. } => {
                 // did we exceed cluster boundary?
-                if next_sector_index % self.filesystem.boot_sector.sectors_per_cluster() as u32 == 0
-                {
+                let sector_in_data =
+                    next_sector_index - self.filesystem.boot_sector.data_start_sector();
+                if sector_in_data % self.filesystem.boot_sector.sectors_per_cluster() as u32 == 0 {
                     // get next cluster
                     let next_cluster = self.filesystem.read_fat_entry(self.current_cluster);
                     match next_cluster {
                         FatEntry::Next(cluster) => {
                             self.current_cluster = cluster;
-                            next_sector_index =
-                                cluster * self.filesystem.boot_sector.sectors_per_cluster() as u32;
+                            next_sector_index = self.filesystem.first_sector_of_cluster(cluster);
                         }
                         FatEntry::EndOfChain => {
                             return Ok(false);
@@ -442,7 +621,10 @@ impl DirectoryIterator<'_> {
             }
         }
 
-        self.current_sector = self.filesystem.read_sectors(next_sector_index, 1)?;
+        self.current_sector = self
+            .filesystem
+            .read_sectors(next_sector_index, 1)
+            .context("read directory sector", Some(next_sector_index.0))?;
         self.current_sector_index = next_sector_index;
         self.entry_index_in_sector = 0;
         Ok(true)
@@ -461,19 +643,279 @@ impl DirectoryIterator<'_> {
         }
         let entry = &self.current_sector[entry_start..entry_end];
         self.entry_index_in_sector += 1;
+        self.entry_index += 1;
 
         assert!(entry.len() == DIRECTORY_ENTRY_SIZE as usize);
         Ok(entry)
     }
 }
 
-impl Iterator for DirectoryIterator<'_> {
-    type Item = INode;
+/// The checksum of the short name stored in each of its long name entries
+fn long_name_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut entry = self.get_next_entry().ok()?;
+fn long_name_part(entry: &[u8]) -> String {
+    // get the multiple parts
+    let name1 = &entry[1..11];
+    let name2 = &entry[14..26];
+    let name3 = &entry[28..32];
+
+    // construct an iterator and fill the string part
+    let name_iter = name1
+        .chunks(2)
+        .chain(name2.chunks(2))
+        .chain(name3.chunks(2))
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| c != &0);
+
+    let mut name_part = String::with_capacity(13);
+    char::decode_utf16(name_iter)
+        .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .for_each(|c| name_part.push(c));
+    name_part
+}
+
+/// The parts of a long name entry, in UTF-16 characters
+const LONG_NAME_PART_RANGES: [(usize, usize); 3] = [(1, 11), (14, 26), (28, 32)];
+const LONG_NAME_CHARS_PER_ENTRY: usize = 13;
+const LONG_NAME_MAX_CHARS: usize = 255;
+const DELETED_ENTRY: u8 = 0xE5;
+
+/// Checks that `name` can be stored in a long name, the characters not allowed are the same
+/// as in Windows. Names ending with ` ` or `.` are not allowed either, since Windows
+/// removes these
+fn check_long_name(name: &str) -> Result<(), FileSystemError> {
+    let invalid_char = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty()
+        || name.encode_utf16().count() > LONG_NAME_MAX_CHARS
+        || name.chars().any(invalid_char)
+        || name.ends_with([' ', '.'])
+    {
+        return Err(FileSystemError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// The characters allowed in the base and extension of short names
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// The short name of `name` if it can be stored without a long name, which is
+/// when it is already in 8.3 uppercase
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base
+            .bytes()
+            .chain(extension.bytes())
+            .all(is_short_name_char)
+    {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// A short name for `name` that is not in `taken`, like `LONGNA~1.TXT`
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let to_short_chars = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_short_name_char(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    // a leading `.` is not an extension
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.trim_start_matches('.').is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let base = to_short_chars(base);
+    let extension = to_short_chars(extension);
+
+    let mut short_name = [b' '; 11];
+    let extension_len = extension.len().min(3);
+    short_name[8..8 + extension_len].copy_from_slice(&extension[..extension_len]);
+    for n in 1..1_000_000u32 {
+        let mut suffix = [0; 7];
+        let suffix_len = {
+            let mut n = n;
+            let mut len = 0;
+            while n != 0 {
+                suffix[len] = b'0' + (n % 10) as u8;
+                n /= 10;
+                len += 1;
+            }
+            suffix[..len].reverse();
+            len
+        };
+        let base_len = base.len().min(8 - 1 - suffix_len);
+        short_name[..8].fill(b' ');
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len] = b'~';
+        short_name[base_len + 1..base_len + 1 + suffix_len].copy_from_slice(&suffix[..suffix_len]);
+        if !taken.contains(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// The long name entries of `name` in the order they are stored, before the short entry
+/// with `short_name`
+fn long_name_entries(
+    name: &str,
+    short_name: &[u8; 11],
+) -> Vec<[u8; DIRECTORY_ENTRY_SIZE as usize]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = chars.len().div_ceil(LONG_NAME_CHARS_PER_ENTRY);
+    // the name is terminated by a null if it doesn't fill the last entry, then padded
+    if !chars.len().is_multiple_of(LONG_NAME_CHARS_PER_ENTRY) {
+        chars.push(0);
+    }
+    chars.resize(count * LONG_NAME_CHARS_PER_ENTRY, 0xFFFF);
+
+    let checksum = long_name_checksum(short_name);
+    (1..=count)
+        .rev()
+        .map(|ordinal| {
+            let mut entry = [0; DIRECTORY_ENTRY_SIZE as usize];
+            entry[0] = ordinal as u8;
+            if ordinal == count {
+                entry[0] |= 0x40;
+            }
+            entry[11] = attrs::LONG_NAME;
+            entry[13] = checksum;
+            let part =
+                &chars[(ordinal - 1) * LONG_NAME_CHARS_PER_ENTRY..][..LONG_NAME_CHARS_PER_ENTRY];
+            let mut part = part.iter();
+            for (start, end) in LONG_NAME_PART_RANGES {
+                for chunk in entry[start..end].as_chunks_mut::<2>().0 {
+                    chunk.copy_from_slice(&part.next().unwrap().to_le_bytes());
+                }
+            }
+            entry
+        })
+        .collect()
+}
+
+/// A short entry without a name, the dates are not set
+fn new_short_entry(
+    attributes: u8,
+    start_cluster: Cluster,
+    size: u32,
+) -> [u8; DIRECTORY_ENTRY_SIZE as usize] {
+    let mut entry = [0; DIRECTORY_ENTRY_SIZE as usize];
+    entry[11] = attributes;
+    set_entry_cluster(&mut entry, start_cluster);
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn entry_cluster(entry: &[u8]) -> Cluster {
+    Cluster(
+        (u16::from_le_bytes([entry[20], entry[21]]) as u32) << 16
+            | u16::from_le_bytes([entry[26], entry[27]]) as u32,
+    )
+}
+
+fn set_entry_cluster(entry: &mut [u8], Cluster(cluster): Cluster) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// All the entries of a directory, with the sectors they are in
+struct DirectoryEntries {
+    sectors: Vec<FsSector>,
+    entries: Vec<[u8; DIRECTORY_ENTRY_SIZE as usize]>,
+}
+
+impl DirectoryEntries {
+    /// The short names in use, including the ones of files with long names
+    fn short_names(&self) -> Vec<[u8; 11]> {
+        self.entries
+            .iter()
+            .filter(|entry| entry[0] != 0 && entry[0] != DELETED_ENTRY)
+            .filter(|entry| entry[11] & attrs::LONG_NAME != attrs::LONG_NAME)
+            .map(|entry| entry[0..11].try_into().unwrap())
+            .collect()
+    }
+
+    /// The index of the first of `count` free entries in a row
+    fn find_free(&self, count: usize) -> Option<u32> {
+        let mut run = 0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry[0] == 0 || entry[0] == DELETED_ENTRY {
+                run += 1;
+                if run == count {
+                    return Some((i + 1 - count) as u32);
+                }
+            } else {
+                run = 0;
+            }
+        }
+        None
+    }
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let base_name = &entry[0..8];
+    let base_name_end = 8 - base_name.iter().rev().position(|&c| c != 0x20).unwrap_or(8);
+    let extension = &entry[8..11];
+
+    let mut name = String::with_capacity(13);
+    let mut i = 0;
+    while i < base_name_end {
+        name.push(base_name[i] as char);
+        i += 1;
+    }
+    let extension_present = extension[0] != 0x20;
+    if extension_present {
+        name.push('.');
+        i = 0;
+        while i < extension.len() && extension[i] != 0x20 {
+            name.push(extension[i] as char);
+            i += 1;
+        }
+    }
+    name
+}
+
+/// The long name entries read so far, they come in reverse order before the short entry
+struct LongName {
+    // the ordinal of the last entry read, the next must be one less
+    ordinal: u8,
+    checksum: u8,
+    parts: Vec<String>,
+    // the index of the first entry, to remove all of them with the short entry
+    first_entry: u32,
+}
+
+impl DirectoryIterator<'_> {
+    /// Same as `next`, but also returns the index of the first directory entry of the
+    /// file (its first long name entry if it has a long name), the index of the short
+    /// entry is in the inode id
+    fn next_with_first_entry(&mut self) -> Option<(INode, u32)> {
+        let mut long_name: Option<LongName> = None;
 
         loop {
+            let entry: [u8; DIRECTORY_ENTRY_SIZE as usize] =
+                self.get_next_entry().ok()?.try_into().unwrap();
+
             match entry[0] {
                 0x00 => {
                     // this is free and all others are free too, so stop
@@ -481,135 +923,242 @@ impl Iterator for DirectoryIterator<'_> {
                 }
                 0xE5 => {
                     // this is free, get next one
-                    entry = self.get_next_entry().ok()?;
+                    long_name = None;
+                    continue;
                 }
-                _ => break,
+                _ => {}
             }
-        }
-        let mut attributes = entry[11];
-
-        let name = if attributes & attrs::LONG_NAME == attrs::LONG_NAME {
-            // long file name
-            // this should be the last
-            assert!(entry[0] & 0x40 == 0x40);
-            let number_of_entries = entry[0] & 0x3F;
-            let mut long_name_enteries = Vec::with_capacity(number_of_entries as usize);
-            // skip all long file name entries
-            for _ in 0..number_of_entries {
-                // get the multiple parts
-                let name1 = &entry[1..11];
-                let name2 = &entry[14..26];
-                let name3 = &entry[28..32];
-
-                // construct an iterator and fill the string part
-                let name_iter = name1
-                    .chunks(2)
-                    .chain(name2.chunks(2))
-                    .chain(name3.chunks(2))
-                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
-                    .take_while(|c| c != &0);
-
-                let mut name_part = String::with_capacity(13);
-                char::decode_utf16(name_iter)
-                    .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
-                    .for_each(|c| name_part.push(c));
-
-                // add to the entries
-                long_name_enteries.push(name_part);
-
-                // next entry
-                entry = self.get_next_entry().ok()?;
-            }
-            attributes = entry[11];
-            let mut name = String::new();
-            long_name_enteries
-                .into_iter()
-                .rev()
-                .for_each(|s| name.push_str(&s));
-            name
-        } else {
-            // short file name
-            let base_name = &entry[0..8];
-            let base_name_end = 8 - base_name.iter().rev().position(|&c| c != 0x20).unwrap();
-            let extension = &entry[8..11];
-
-            let mut name = String::with_capacity(13);
-            let mut i = 0;
-            while i < base_name_end {
-                name.push(base_name[i] as char);
-                i += 1;
-            }
-            let extension_present = extension[0] != 0x20;
-            if extension_present {
-                name.push('.');
-                i = 0;
-                while i < extension.len() && extension[i] != 0x20 {
-                    name.push(extension[i] as char);
-                    i += 1;
+            let attributes = entry[11];
+
+            if attributes & attrs::LONG_NAME == attrs::LONG_NAME {
+                let ordinal = entry[0] & 0x3F;
+                let checksum = entry[13];
+                let is_last = entry[0] & 0x40 == 0x40;
+                long_name = match long_name.take() {
+                    // the last part comes first, and there are at most 20 parts
+                    _ if is_last && (1..=20).contains(&ordinal) => Some(LongName {
+                        ordinal,
+                        checksum,
+                        parts: vec![long_name_part(&entry)],
+                        first_entry: self.entry_index - 1,
+                    }),
+                    Some(mut long_name)
+                        if !is_last
+                            && ordinal != 0
+                            && ordinal + 1 == long_name.ordinal
+                            && checksum == long_name.checksum =>
+                    {
+                        long_name.ordinal = ordinal;
+                        long_name.parts.push(long_name_part(&entry));
+                        Some(long_name)
+                    }
+                    // garbage, ignore it and anything before it
+                    _ => None,
+                };
+                continue;
+            }
+
+            // the short entry is the last one read, even with long names
+            let entry_index = self.entry_index - 1;
+            if attributes & attrs::VOLUME_ID == attrs::VOLUME_ID {
+                self.volume_label = Some(entry[0..11].try_into().unwrap());
+                continue;
+            }
+            // a short name can't start with `.` otherwise
+            if entry[0] == b'.' {
+                continue;
+            }
+
+            let (name, first_entry) = match long_name.take() {
+                Some(long_name)
+                    if long_name.ordinal == 1
+                        && long_name.checksum == long_name_checksum(&entry[0..11]) =>
+                {
+                    let mut name = String::new();
+                    long_name
+                        .parts
+                        .into_iter()
+                        .rev()
+                        .for_each(|s| name.push_str(&s));
+                    (name, long_name.first_entry)
                 }
+                _ => (short_name(&entry), entry_index),
+            };
+
+            let cluster_hi = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+            let cluster_lo = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+            let size = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+
+            let start_cluster = (cluster_hi << 16) | cluster_lo;
+            let is_dir = attributes & attrs::DIRECTORY == attrs::DIRECTORY;
+            // clusters 0 and 1 are reserved, 0 is only used by empty files
+            if start_cluster == 1 || (start_cluster == 0 && (is_dir || size != 0)) {
+                eprintln!("[FAT] skipping {name:?} with invalid start cluster {start_cluster}");
+                continue;
             }
-            name
-        };
 
-        let cluster_hi = unsafe {
-            let ptr = entry.as_ptr().add(20) as *const u16;
-            u16::from_le(*(ptr)) as u32
-        };
-        let cluster_lo = unsafe {
-            let ptr = entry.as_ptr().add(26) as *const u16;
-            u16::from_le(*(ptr)) as u32
-        };
-        let size = unsafe {
-            let ptr = entry.as_ptr().add(28) as *const u32;
-            u32::from_le(*(ptr))
-        };
+            let inode = INode::new_file(
+                name,
+                file_attribute_from_fat(attributes),
+                start_cluster,
+                size,
+            )
+            .with_id(inode_id(self.dir_cluster, entry_index));
 
-        let start_cluster = (cluster_hi << 16) | cluster_lo;
+            return Some((inode, first_entry));
+        }
+    }
+}
 
-        let inode = INode::new_file(
-            name,
-            file_attribute_from_fat(attributes),
-            start_cluster,
-            size,
-        );
+impl Iterator for DirectoryIterator<'_> {
+    type Item = INode;
 
-        Some(inode)
+    /// Returns the next file or directory.
+    ///
+    /// The volume label, `.` and `..` are skipped, the label is available at
+    /// [`FatFilesystem::volume_label`] and `..` is resolved by [`FatFilesystem::open_dir`].
+    /// Long name entries that don't form a valid sequence for their short entry are ignored
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_first_entry().map(|(inode, _)| inode)
     }
 }
 
 #[derive(Debug)]
 pub struct FatFilesystem {
-    start_lba: u32,
+    start_lba: Lba,
     #[allow(dead_code)]
     size_in_sectors: u32,
     boot_sector: Box<FatBootSector>,
     fat: NoDebug<Vec<u8>>,
-    device: NoDebug<Arc<ide::IdeDevice>>,
+    device: Arc<BlockDeviceFile>,
+    // the generation of the device when `fat` was read, see `sync_with_device`
+    device_generation: u64,
+    disconnected: bool,
+    // mounted read-only, even if the device can be written
+    read_only: bool,
+    // counts the changes done by `modify`, so a read done without the lock can tell
+    changes: u64,
+    cache_id: u64,
+    // the label in the root directory, this is the one updated by Windows
+    root_volume_label: Option<[u8; 11]>,
+    // the free count of the FAT32 `FSInfo` was set to unknown, see `forget_free_count`
+    free_count_forgotten: bool,
+}
+
+/// The most sectors read from the device at once by [`FileRead::read`]
+const MAX_READ_SECTORS: u32 = 64;
+/// The most written with the filesystem locked, bigger writes are split
+const MAX_WRITE_BYTES: usize = 32 * 1024;
+
+/// The sectors of a part of a file, found with the filesystem locked and read without it,
+/// so the interrupts are not disabled while waiting for the device
+struct FileRead {
+    device: Arc<BlockDeviceFile>,
+    start_lba: Lba,
+    sector_size: usize,
+    // (first sector, number of sectors), each is contiguous on the device
+    runs: Vec<(FsSector, u32)>,
+    // the bytes before the data in the first sector
+    skip: usize,
+    len: usize,
+}
+
+impl FileRead {
+    fn push(&mut self, sector: FsSector, count: u32) {
+        match self.runs.last_mut() {
+            Some((start, run_count))
+                if *start + *run_count == sector && *run_count + count <= MAX_READ_SECTORS =>
+            {
+                *run_count += count
+            }
+            _ => self.runs.push((sector, count)),
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let mut read = 0;
+        let mut skip = self.skip;
+        for &(sector, count) in &self.runs {
+            let mut sectors = vec![0; count as usize * self.sector_size];
+            self.device
+                .read_sectors(sector.to_lba(self.start_lba), &mut sectors)
+                .context("read file sector", Some(sector.0))?;
+            let data = &sectors[skip..];
+            let to_read = data.len().min(self.len - read);
+            buf[read..read + to_read].copy_from_slice(&data[..to_read]);
+            read += to_read;
+            skip = 0;
+        }
+        Ok(read as u64)
+    }
 }
 
 impl FatFilesystem {
     fn new(
-        start_lba: u32,
+        start_lba: Lba,
         size_in_sectors: u32,
         boot_sector: FatBootSector,
-        device: Arc<ide::IdeDevice>,
+        device: Arc<BlockDeviceFile>,
     ) -> Result<Self, FileSystemError> {
+        let cache_id = page_cache::new_cache_id();
+        device.add_cache_user(cache_id);
         let mut s = FatFilesystem {
             start_lba,
             size_in_sectors,
             boot_sector: Box::new(boot_sector),
             fat: NoDebug(Vec::new()),
-            device: NoDebug(device),
+            device_generation: device.generation(),
+            device,
+            disconnected: false,
+            read_only: false,
+            changes: 0,
+            cache_id,
+            root_volume_label: None,
+            free_count_forgotten: false,
         };
 
+        s.load_metadata()?;
+        Ok(s)
+    }
+
+    fn load_metadata(&mut self) -> Result<(), FileSystemError> {
         // TODO: replace by lazily reading FAT when needed
-        s.load_fat()?;
+        self.load_fat()?;
+        self.free_count_forgotten = false;
 
-        Ok(s)
+        self.root_volume_label = {
+            let mut root = DirectoryIterator::new(self, self.open_root_dir()?)?;
+            while root.volume_label.is_none() && root.next().is_some() {}
+            root.volume_label
+        };
+        Ok(())
     }
 
+    /// Reads the FAT again if the device was written directly since we read it,
+    /// the page cache is already invalidated by the device.
+    ///
+    /// The boot sector is not read again, formatting the device again needs a remount
+    fn sync_with_device(&mut self) -> Result<(), FileSystemError> {
+        let generation = self.device.generation();
+        if generation != self.device_generation {
+            self.fat.0.clear();
+            self.load_metadata()?;
+            self.device_generation = generation;
+        }
+        Ok(())
+    }
+
+    /// Refuses all the writes from now on, even if the device can be written
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// The label in the root directory if present, otherwise the one in the boot sector
     pub fn volume_label(&self) -> String {
-        let label = self.boot_sector.volume_label();
+        let label = self
+            .root_volume_label
+            .as_ref()
+            .unwrap_or_else(|| self.boot_sector.volume_label());
         let mut label = String::from_utf8_lossy(label).to_string();
         label.retain(|c| c != '\0');
         label
@@ -619,21 +1168,24 @@ impl FatFilesystem {
         self.boot_sector.ty
     }
 
-    fn first_sector_of_cluster(&self, cluster: u32) -> u32 {
-        self.boot_sector.data_start_sector()
-            + (cluster - 2) * self.boot_sector.sectors_per_cluster() as u32
+    fn first_sector_of_cluster(&self, cluster: Cluster) -> FsSector {
+        cluster.first_sector(
+            self.boot_sector.data_start_sector(),
+            self.boot_sector.sectors_per_cluster() as u32,
+        )
     }
 
-    fn read_sectors(&self, start_sector: u32, count: u32) -> Result<Vec<u8>, FileSystemError> {
+    /// The sector of the device for `sector` of this filesystem
+    fn lba(&self, sector: FsSector) -> Lba {
+        sector.to_lba(self.start_lba)
+    }
+
+    fn read_sectors(&self, start_sector: FsSector, count: u32) -> Result<Vec<u8>, FileSystemError> {
         let sector_size = self.boot_sector.bytes_per_sector() as usize;
         let mut sectors = vec![0; sector_size * count as usize];
 
         self.device
-            .read_sync((self.start_lba + start_sector) as u64, &mut sectors)
-            .map_err(|e| FileSystemError::DiskReadError {
-                sector: (self.start_lba + start_sector) as u64,
-                error: e,
-            })?;
+            .read_sectors(self.lba(start_sector), &mut sectors)?;
 
         Ok(sectors)
     }
@@ -646,35 +1198,26 @@ impl FatFilesystem {
             self.boot_sector.fat_size_in_sectors() * self.boot_sector.number_of_fats() as u32;
         let fat_start_sector = self.boot_sector.fat_start_sector();
 
-        self.fat.0 = self.read_sectors(fat_start_sector, fats_size_in_sectors)?;
+        self.fat.0 = self
+            .read_sectors(fat_start_sector, fats_size_in_sectors)
+            .context("read FAT sector", Some(fat_start_sector.0))?;
 
         Ok(())
     }
 
-    fn read_fat_entry(&self, entry: u32) -> FatEntry {
-        let fat_offset = match self.boot_sector.ty {
-            FatType::Fat12 => entry * 3 / 2,
-            FatType::Fat16 => entry * 2,
-            FatType::Fat32 => entry * 4,
-        } as usize;
-        assert!(fat_offset < self.fat.0.len(), "FAT entry out of bounds");
-        let ptr = unsafe { self.fat.0.as_ptr().add(fat_offset) };
-
-        let entry = match self.boot_sector.ty {
-            FatType::Fat12 => {
-                let byte1 = self.fat.0[fat_offset];
-                let byte2 = self.fat.0[fat_offset + 1];
-                if entry & 1 == 1 {
-                    ((byte2 as u32) << 4) | ((byte1 as u32) >> 4)
-                } else {
-                    (((byte2 as u32) & 0xF) << 8) | (byte1 as u32)
-                }
-            }
-            FatType::Fat16 => unsafe { (*(ptr as *const u16)) as u32 },
-            FatType::Fat32 => unsafe { (*(ptr as *const u32)) & 0x0FFF_FFFF },
-        };
+    fn read_fat_entry(&self, cluster: Cluster) -> FatEntry {
+        FatEntry::read(self.boot_sector.ty, &self.fat.0, cluster)
+    }
 
-        FatEntry::from_u32(self.boot_sector.ty, entry)
+    /// The FAT32 `FSInfo` sector, `None` if there is none or its not valid
+    fn read_fs_info(&self) -> Result<Option<(FsSector, Vec<u8>)>, FileSystemError> {
+        let Some(sector) = self.boot_sector.fs_info_sector() else {
+            return Ok(None);
+        };
+        let data = self
+            .read_sectors(sector, 1)
+            .context("read FSInfo sector", Some(sector.0))?;
+        Ok(fs_info::is_valid(&data).then_some((sector, data)))
     }
 
     fn open_root_dir(&self) -> Result<Directory, FileSystemError> {
@@ -691,13 +1234,18 @@ impl FatFilesystem {
                     file_attribute_from_fat(attrs::DIRECTORY),
                     root_cluster,
                     0,
-                );
+                )
+                .with_id(ROOT_INODE_ID);
                 Ok(Directory::Normal { inode })
             }
         }
     }
 
     pub fn open_dir(&self, path: &str) -> Result<DirectoryIterator, FileSystemError> {
+        DirectoryIterator::new(self, self.find_dir(path)?)
+    }
+
+    fn find_dir(&self, path: &str) -> Result<Directory, FileSystemError> {
         if path.is_empty() {
             return Err(FileSystemError::InvalidPath);
         }
@@ -706,27 +1254,38 @@ impl FatFilesystem {
         }
         let root = self.open_root_dir()?;
         if path == "/" {
-            return DirectoryIterator::new(self, root);
+            return Ok(root);
         }
 
         let mut dir = root;
+        // the directories before `dir`, the on-disk `..` points to cluster 0 at the FAT32
+        // root (not the root cluster), so we use this instead
+        let mut parents = Vec::new();
         'component_loop: for component in path[1..].split('/') {
-            if component.is_empty() {
-                continue;
+            match component {
+                "" | "." => continue,
+                ".." => {
+                    // `..` at the root is the root
+                    if let Some(parent) = parents.pop() {
+                        dir = parent;
+                    }
+                    continue;
+                }
+                _ => {}
             }
             for entry in dir.iter(self)? {
                 if entry.name() == component {
                     if !entry.is_dir() {
                         return Err(FileSystemError::IsNotDirectory);
                     }
-                    dir = Directory::Normal { inode: entry };
+                    parents.push(mem::replace(&mut dir, Directory::Normal { inode: entry }));
                     continue 'component_loop;
                 }
             }
             // component not found
             return Err(FileSystemError::FileNotFound);
         }
-        DirectoryIterator::new(self, dir)
+        Ok(dir)
     }
 
     pub fn open_dir_inode(&self, inode: &INode) -> Result<DirectoryIterator, FileSystemError> {
@@ -739,64 +1298,1011 @@ impl FatFilesystem {
         DirectoryIterator::new(self, dir)
     }
 
-    pub fn read_file(
+    fn next_cluster(&self, cluster: Cluster) -> Result<Option<Cluster>, FileSystemError> {
+        match self.read_fat_entry(cluster) {
+            FatEntry::Next(next_cluster) => Ok(Some(next_cluster)),
+            FatEntry::EndOfChain => Ok(None),
+            FatEntry::Bad | FatEntry::Reserved | FatEntry::Free => {
+                Err(FatError::UnexpectedFatEntry.into())
+            }
+        }
+    }
+
+    /// The sectors with `len` bytes of the file from `position`, or less if the file ends
+    /// before, to be read with [`FileRead::read`]
+    fn file_read(
         &self,
         inode: &INode,
-        position: u32,
-        buf: &mut [u8],
-    ) -> Result<u64, FileSystemError> {
+        position: u64,
+        len: usize,
+    ) -> Result<FileRead, FileSystemError> {
         if inode.is_dir() {
             return Err(FileSystemError::IsDirectory);
         }
-        if position >= inode.size {
-            return Ok(0);
+        let mut file_read = FileRead {
+            device: self.device.clone(),
+            start_lba: self.start_lba,
+            sector_size: self.boot_sector.bytes_per_sector() as usize,
+            runs: Vec::new(),
+            skip: 0,
+            len: 0,
+        };
+        if position >= inode.size as u64 || len == 0 {
+            return Ok(file_read);
+        }
+        // the size is `u32`, so these fit
+        let position = position as u32;
+        let len = (len as u64).min((inode.size - position) as u64) as u32;
+        let bytes_per_cluster = self.boot_sector.bytes_per_cluster();
+        let bytes_per_sector = self.boot_sector.bytes_per_sector() as u32;
+
+        let mut cluster = match inode.start_cluster {
+            // it got its first cluster after `inode` was taken, see `FatFilesystem::write`
+            0 => entry_cluster(&self.file_entry(inode)?.2),
+            start_cluster => Cluster(start_cluster),
+        };
+        for _ in 0..position / bytes_per_cluster {
+            cluster = self
+                .next_cluster(cluster)?
+                .ok_or(FatError::UnexpectedFatEntry)?;
         }
-        let remaining_file = inode.size - position;
-        let max_to_read = (buf.len() as u32).min(remaining_file);
-
-        let mut cluster = inode.start_cluster;
-        let cluster_index = position / self.boot_sector.bytes_per_cluster();
-        for _ in 0..cluster_index {
-            cluster = match self.read_fat_entry(cluster) {
-                FatEntry::Next(next_cluster) => next_cluster,
-                FatEntry::EndOfChain => return Err(FatError::UnexpectedFatEntry.into()),
-                FatEntry::Bad => return Err(FatError::UnexpectedFatEntry.into()),
-                FatEntry::Reserved => return Err(FatError::UnexpectedFatEntry.into()),
-                FatEntry::Free => return Err(FatError::UnexpectedFatEntry.into()),
+
+        let mut position_in_cluster = position % bytes_per_cluster;
+        file_read.skip = (position_in_cluster % bytes_per_sector) as usize;
+        let mut remaining = len;
+        loop {
+            let in_cluster = (bytes_per_cluster - position_in_cluster).min(remaining);
+            let first = position_in_cluster / bytes_per_sector;
+            let last = (position_in_cluster + in_cluster - 1) / bytes_per_sector;
+            file_read.push(
+                self.first_sector_of_cluster(cluster) + first,
+                last - first + 1,
+            );
+            file_read.len += in_cluster as usize;
+
+            remaining -= in_cluster;
+            if remaining == 0 {
+                break;
+            }
+            position_in_cluster = 0;
+            cluster = match self.next_cluster(cluster)? {
+                Some(next_cluster) => next_cluster,
+                None => break,
             };
         }
+        Ok(file_read)
+    }
+}
 
-        let mut read = 0;
-        let mut position_in_cluster = position % self.boot_sector.bytes_per_cluster();
-        while read < max_to_read as usize {
-            let cluster_start_sector = self.first_sector_of_cluster(cluster);
-            let cluster_offset = position_in_cluster / self.boot_sector.bytes_per_sector() as u32;
+/// Changing the filesystem, done by writing the sectors directly, the caller must
+/// invalidate the page cache afterwards.
+///
+/// The writes are done in the order that keeps the filesystem consistent if only the first
+/// of them reach the medium: the content of new clusters, then the FAT entries linking them,
+/// then the directory entries pointing to them (and the other way around when removing).
+/// Nothing is kept to be written later, so syncing only needs to flush the device
+impl FatFilesystem {
+    fn write_sectors(&self, start_sector: FsSector, data: &[u8]) -> Result<(), FileSystemError> {
+        if self.read_only {
+            return Err(FileSystemError::ReadOnlyFileSystem);
+        }
+        self.device.write_sectors(self.lba(start_sector), data)
+    }
+
+    fn entries_per_sector(&self) -> u32 {
+        self.boot_sector.bytes_per_sector() as u32 / DIRECTORY_ENTRY_SIZE
+    }
 
-            let sector_number = cluster_start_sector + cluster_offset;
-            let sector_offset = position_in_cluster % self.boot_sector.bytes_per_sector() as u32;
-            let sector_offset = sector_offset as usize;
+    /// The last cluster that can be used, limited by the data sectors and the size of the FAT
+    fn max_cluster(&self) -> Cluster {
+        let data_clusters =
+            self.boot_sector.data_sectors() / self.boot_sector.sectors_per_cluster() as u32;
+        let fat_bytes =
+            self.boot_sector.fat_size_in_sectors() * self.boot_sector.bytes_per_sector() as u32;
+        let fat_entries = match self.boot_sector.ty {
+            // the last entry may not fit with its 2 bytes
+            FatType::Fat12 => (fat_bytes - 1) * 2 / 3,
+            FatType::Fat16 => fat_bytes / 2,
+            FatType::Fat32 => fat_bytes / 4,
+        };
+        Cluster((data_clusters + 1).min(fat_entries - 1))
+    }
 
-            let sector = self.read_sectors(sector_number, 1)?;
-            let sector = &sector[sector_offset..];
+    /// The clusters of the chain starting at `start_cluster`
+    fn cluster_chain(&self, start_cluster: Cluster) -> Result<Vec<Cluster>, FileSystemError> {
+        let mut chain = vec![start_cluster];
+        let mut cluster = start_cluster;
+        loop {
+            match self.read_fat_entry(cluster) {
+                // a longer chain must have a loop
+                FatEntry::Next(next) if chain.len() <= self.max_cluster().0 as usize => {
+                    chain.push(next);
+                    cluster = next;
+                }
+                FatEntry::EndOfChain => return Ok(chain),
+                _ => return Err(FatError::UnexpectedFatEntry.into()),
+            }
+        }
+    }
 
-            let to_read = core::cmp::min(sector.len() as u32, max_to_read - read as u32) as usize;
-            buf[read..read + to_read].copy_from_slice(&sector[..to_read]);
+    /// Sets the free count of the FAT32 `FSInfo` to unknown before the first change of the
+    /// FAT, so it is never wrong, the other systems count the free clusters when they need it
+    fn forget_free_count(&mut self) -> Result<(), FileSystemError> {
+        if self.free_count_forgotten {
+            return Ok(());
+        }
+        if let Some((sector, mut data)) = self.read_fs_info()? {
+            if fs_info::free_count(&data) != fs_info::UNKNOWN {
+                data[fs_info::FREE_COUNT..][..4].copy_from_slice(&fs_info::UNKNOWN.to_le_bytes());
+                self.write_sectors(sector, &data)
+                    .context("write FSInfo sector", Some(sector.0))?;
+            }
+        }
+        self.free_count_forgotten = true;
+        Ok(())
+    }
 
-            read += to_read;
-            position_in_cluster += to_read as u32;
-            if position_in_cluster >= self.boot_sector.bytes_per_cluster() {
-                position_in_cluster = 0;
-                cluster = match self.read_fat_entry(cluster) {
-                    FatEntry::Next(next_cluster) => next_cluster,
-                    FatEntry::EndOfChain => break,
-                    FatEntry::Bad => return Err(FatError::UnexpectedFatEntry.into()),
-                    FatEntry::Reserved => return Err(FatError::UnexpectedFatEntry.into()),
-                    FatEntry::Free => return Err(FatError::UnexpectedFatEntry.into()),
+    /// Sets `cluster` in all the copies of the FAT, in memory and in the device
+    fn write_fat_entry(
+        &mut self,
+        cluster: Cluster,
+        entry: FatEntry,
+    ) -> Result<(), FileSystemError> {
+        self.forget_free_count()?;
+        let ty = self.boot_sector.ty;
+        let sector_size = self.boot_sector.bytes_per_sector() as usize;
+        let fat_sectors = self.boot_sector.fat_size_in_sectors();
+        let fat_size = fat_sectors as usize * sector_size;
+
+        let offset = ty.entry_offset(cluster);
+        let len = match ty {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        let first_sector = offset / sector_size;
+        let end_sector = (offset + len).div_ceil(sector_size);
+
+        for copy in 0..self.boot_sector.number_of_fats() as usize {
+            let fat = &mut self.fat.0[copy * fat_size..][..fat_size];
+            entry.write(ty, fat, cluster);
+            let start_sector = self.boot_sector.fat_start_sector()
+                + copy as u32 * fat_sectors
+                + first_sector as u32;
+            let sectors = &self.fat.0[copy * fat_size..]
+                [first_sector * sector_size..end_sector * sector_size];
+            self.write_sectors(start_sector, sectors)
+                .context("write FAT sector", Some(start_sector.0))?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a zeroed cluster, at the end of the chain of `previous` if given
+    fn allocate_cluster(&mut self, previous: Option<Cluster>) -> Result<Cluster, FileSystemError> {
+        let cluster = Cluster::data_clusters(self.max_cluster())
+            .find(|&cluster| self.read_fat_entry(cluster) == FatEntry::Free)
+            .ok_or(FileSystemError::NoSpace)?;
+
+        let zeros = vec![0; self.boot_sector.bytes_per_cluster() as usize];
+        self.write_sectors(self.first_sector_of_cluster(cluster), &zeros)
+            .context("clear cluster", Some(cluster.0))?;
+        self.write_fat_entry(cluster, FatEntry::EndOfChain)?;
+        if let Some(previous) = previous {
+            self.write_fat_entry(previous, FatEntry::Next(cluster))?;
+        }
+        Ok(cluster)
+    }
+
+    /// Frees the whole chain, stops at the first entry that is not part of a chain
+    fn free_chain(&mut self, start_cluster: Cluster) -> Result<(), FileSystemError> {
+        let mut cluster = start_cluster;
+        for _ in 0..self.max_cluster().0 {
+            if !(Cluster::FIRST..=self.max_cluster()).contains(&cluster) {
+                break;
+            }
+            let next = self.read_fat_entry(cluster);
+            self.write_fat_entry(cluster, FatEntry::Free)?;
+            match next {
+                FatEntry::Next(next) => cluster = next,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_entries(&self, dir: &Directory) -> Result<DirectoryEntries, FileSystemError> {
+        let sectors: Vec<FsSector> = match dir {
+            Directory::RootFat12_16 {
+                start_sector,
+                size_in_sectors,
+            } => start_sector.range(*size_in_sectors).collect(),
+            Directory::Normal { inode } => {
+                let sectors_per_cluster = self.boot_sector.sectors_per_cluster() as u32;
+                self.cluster_chain(Cluster(inode.start_cluster))?
+                    .into_iter()
+                    .flat_map(|cluster| {
+                        self.first_sector_of_cluster(cluster)
+                            .range(sectors_per_cluster)
+                    })
+                    .collect()
+            }
+        };
+        let mut entries = Vec::with_capacity(sectors.len() * self.entries_per_sector() as usize);
+        for &sector in &sectors {
+            let data = self
+                .read_sectors(sector, 1)
+                .context("read directory sector", Some(sector.0))?;
+            let (chunks, _) = data.as_chunks::<{ DIRECTORY_ENTRY_SIZE as usize }>();
+            entries.extend_from_slice(chunks);
+        }
+        Ok(DirectoryEntries { sectors, entries })
+    }
+
+    /// Writes `entries` to the directory starting at the entry `first`
+    fn write_entries(
+        &self,
+        sectors: &[FsSector],
+        first: u32,
+        entries: &[[u8; DIRECTORY_ENTRY_SIZE as usize]],
+    ) -> Result<(), FileSystemError> {
+        let per_sector = self.entries_per_sector();
+        let end = first + entries.len() as u32;
+        let mut index = first;
+        while index < end {
+            let sector = sectors[(index / per_sector) as usize];
+            let sector_end = end.min((index / per_sector + 1) * per_sector);
+            let mut data = self
+                .read_sectors(sector, 1)
+                .context("read directory sector", Some(sector.0))?;
+            for i in index..sector_end {
+                let offset = ((i % per_sector) * DIRECTORY_ENTRY_SIZE) as usize;
+                data[offset..offset + DIRECTORY_ENTRY_SIZE as usize]
+                    .copy_from_slice(&entries[(i - first) as usize]);
+            }
+            self.write_sectors(sector, &data)
+                .context("write directory sector", Some(sector.0))?;
+            index = sector_end;
+        }
+        Ok(())
+    }
+
+    /// Finds `name` in `dir`, returns the inode, the first of its entries and how many
+    /// entries it uses
+    fn find_entry(
+        &self,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<(INode, u32, u32), FileSystemError> {
+        let mut iter = dir.iter(self)?;
+        while let Some((inode, first)) = iter.next_with_first_entry() {
+            if inode.name() == name {
+                // the low part of the id is the index of the short entry
+                let count = inode.id as u32 - first + 1;
+                return Ok((inode, first, count));
+            }
+        }
+        Err(FileSystemError::FileNotFound)
+    }
+
+    /// Adds the entry `name` to `dir`, with the rest of the short entry from `short_entry`,
+    /// the directory is extended if needed (except the FAT12/16 root, which has a fixed size)
+    fn add_entry(
+        &mut self,
+        dir: &Directory,
+        name: &str,
+        mut short_entry: [u8; DIRECTORY_ENTRY_SIZE as usize],
+    ) -> Result<(), FileSystemError> {
+        check_long_name(name)?;
+        // names are not case sensitive
+        if dir
+            .iter(self)?
+            .any(|entry| entry.name().eq_ignore_ascii_case(name))
+        {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        let mut dir_entries = self.read_entries(dir)?;
+        let taken = dir_entries.short_names();
+
+        let mut entries = match exact_short_name(name) {
+            Some(short_name) if !taken.contains(&short_name) => {
+                short_entry[..11].copy_from_slice(&short_name);
+                Vec::new()
+            }
+            _ => {
+                let short_name =
+                    generate_short_name(name, &taken).ok_or(FileSystemError::NoSpace)?;
+                short_entry[..11].copy_from_slice(&short_name);
+                long_name_entries(name, &short_name)
+            }
+        };
+        entries.push(short_entry);
+
+        let first = loop {
+            if let Some(first) = dir_entries.find_free(entries.len()) {
+                break first;
+            }
+            let Directory::Normal { inode } = dir else {
+                return Err(FileSystemError::NoSpace);
+            };
+            let last_cluster = *self
+                .cluster_chain(Cluster(inode.start_cluster))?
+                .last()
+                .unwrap();
+            self.allocate_cluster(Some(last_cluster))?;
+            dir_entries = self.read_entries(dir)?;
+        };
+        self.write_entries(&dir_entries.sectors, first, &entries)
+    }
+
+    /// The directory with the entry of the file `inode`, from the location in its id
+    fn entry_dir(&self, inode: &INode) -> Result<Directory, FileSystemError> {
+        let dir_cluster = (inode.id >> 32) as u32;
+        Ok(match self.open_root_dir()? {
+            root @ Directory::RootFat12_16 { .. } if dir_cluster == ROOT_DIR_FAT12_16_CLUSTER => {
+                root
+            }
+            _ => Directory::Normal {
+                inode: INode::new_file(
+                    String::new(),
+                    file_attribute_from_fat(attrs::DIRECTORY),
+                    dir_cluster,
+                    0,
+                ),
+            },
+        })
+    }
+
+    /// The entries of the directory of the file `inode`, the index of its entry and the entry.
+    /// It's read again, `inode` may be from before a change of its size or start cluster
+    #[allow(clippy::type_complexity)]
+    fn file_entry(
+        &self,
+        inode: &INode,
+    ) -> Result<(DirectoryEntries, u32, [u8; DIRECTORY_ENTRY_SIZE as usize]), FileSystemError> {
+        let dir = self.entry_dir(inode)?;
+        let dir_entries = self.read_entries(&dir)?;
+        let index = inode.id as u32;
+        let entry = *dir_entries
+            .entries
+            .get(index as usize)
+            .filter(|entry| ![0, DELETED_ENTRY].contains(&entry[0]))
+            .ok_or(FileSystemError::FileNotFound)?;
+        Ok((dir_entries, index, entry))
+    }
+
+    /// Frees the clusters added to `chain` after its first `old_clusters`, when the entry
+    /// couldn't get them
+    fn free_added(
+        &mut self,
+        chain: &[Cluster],
+        old_clusters: usize,
+    ) -> Result<(), FileSystemError> {
+        if chain.len() <= old_clusters {
+            return Ok(());
+        }
+        if old_clusters != 0 {
+            self.write_fat_entry(chain[old_clusters - 1], FatEntry::EndOfChain)?;
+        }
+        self.free_chain(chain[old_clusters])
+    }
+
+    /// Zeros the last cluster of `chain` after the first `len` bytes of the file, shrinking
+    /// doesn't clear them, and they must read as zeros when extending
+    fn clear_cluster_tail(&self, chain: &[Cluster], len: u32) -> Result<(), FileSystemError> {
+        let bytes_per_sector = self.boot_sector.bytes_per_sector() as u32;
+        let offset = len % self.boot_sector.bytes_per_cluster();
+        let Some(&cluster) = chain.last().filter(|_| offset != 0) else {
+            return Ok(());
+        };
+        let first = offset / bytes_per_sector;
+        let sector = self.first_sector_of_cluster(cluster) + first;
+        let mut data = self
+            .read_sectors(
+                sector,
+                self.boot_sector.sectors_per_cluster() as u32 - first,
+            )
+            .context("read file sector", Some(sector.0))?;
+        data[(offset % bytes_per_sector) as usize..].fill(0);
+        self.write_sectors(sector, &data)
+            .context("clear cluster", Some(cluster.0))
+    }
+
+    /// Sets the size of the file `inode` to `len`, freeing the clusters after it when
+    /// shrinking, or adding zeroed clusters when extending, returns the new start cluster.
+    ///
+    /// The clusters are allocated right away, the size in the entry must always match the
+    /// chain, otherwise the filesystem is inconsistent for [`FatFilesystem::check`] and others
+    fn truncate(&mut self, inode: &INode, len: u32) -> Result<Cluster, FileSystemError> {
+        if inode.is_dir() {
+            return Err(FileSystemError::IsDirectory);
+        }
+        if inode.attributes().read_only {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let (dir_entries, index, mut entry) = self.file_entry(inode)?;
+        let start_cluster = entry_cluster(&entry);
+        let old_len = u32::from_le_bytes(entry[28..32].try_into().unwrap());
+
+        let mut chain = match start_cluster {
+            Cluster(0) => Vec::new(),
+            start_cluster => self.cluster_chain(start_cluster)?,
+        };
+        let old_clusters = chain.len();
+        let clusters = len.div_ceil(self.boot_sector.bytes_per_cluster()) as usize;
+        if len > old_len {
+            self.clear_cluster_tail(&chain, old_len)?;
+        }
+        while chain.len() < clusters {
+            match self.allocate_cluster(chain.last().copied()) {
+                Ok(cluster) => chain.push(cluster),
+                Err(e) => {
+                    // the entry still has the old size
+                    self.free_added(&chain, old_clusters)?;
+                    return Err(e);
+                }
+            }
+        }
+
+        let new_start = chain
+            .first()
+            .copied()
+            .filter(|_| clusters != 0)
+            .unwrap_or(Cluster(0));
+        set_entry_cluster(&mut entry, new_start);
+        entry[28..32].copy_from_slice(&len.to_le_bytes());
+        self.write_entries(&dir_entries.sectors, index, &[entry])?;
+
+        // after the entry has the new size, a crash here only loses the clusters
+        if clusters < chain.len() {
+            if clusters != 0 {
+                self.write_fat_entry(chain[clusters - 1], FatEntry::EndOfChain)?;
+            }
+            self.free_chain(chain[clusters])?;
+        }
+        Ok(new_start)
+    }
+
+    /// Writes `buf` to the file `inode` at `position`, which must not be after its end, the
+    /// clusters for what goes past the end are allocated first. Returns how much was written,
+    /// less than `buf` if the filesystem is full.
+    ///
+    /// The entry gets the new size and start cluster after the data is written, a crash before
+    /// only loses the new clusters
+    fn write(&mut self, inode: &INode, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if inode.is_dir() {
+            return Err(FileSystemError::IsDirectory);
+        }
+        if inode.attributes().read_only {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let (dir_entries, index, mut entry) = self.file_entry(inode)?;
+        let old_len = u32::from_le_bytes(entry[28..32].try_into().unwrap());
+        if position > old_len as u64 {
+            return Err(FileSystemError::InvalidOffset);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // the size is `u32`, nothing is written after its maximum
+        let position = position as u32;
+        let len = (buf.len() as u64).min((u32::MAX - position) as u64) as u32;
+        let bytes_per_cluster = self.boot_sector.bytes_per_cluster();
+        let bytes_per_sector = self.boot_sector.bytes_per_sector() as u32;
+
+        let mut chain = match entry_cluster(&entry) {
+            Cluster(0) => Vec::new(),
+            start_cluster => self.cluster_chain(start_cluster)?,
+        };
+        let old_clusters = chain.len();
+        let clusters = (position as u64 + len as u64).div_ceil(bytes_per_cluster as u64) as usize;
+        while chain.len() < clusters {
+            match self.allocate_cluster(chain.last().copied()) {
+                Ok(cluster) => chain.push(cluster),
+                // what fits is written
+                Err(FileSystemError::NoSpace) => break,
+                Err(e) => {
+                    self.free_added(&chain, old_clusters)?;
+                    return Err(e);
+                }
+            }
+        }
+        let end = (position as u64 + len as u64).min(chain.len() as u64 * bytes_per_cluster as u64)
+            as u32;
+        if end <= position {
+            return Err(FileSystemError::NoSpace);
+        }
+
+        let mut at = position;
+        while at < end {
+            let cluster = chain[(at / bytes_per_cluster) as usize];
+            let in_cluster = at % bytes_per_cluster;
+            let count = (bytes_per_cluster - in_cluster).min(end - at);
+            let first = in_cluster / bytes_per_sector;
+            let sectors = (in_cluster + count).div_ceil(bytes_per_sector) - first;
+            let sector = self.first_sector_of_cluster(cluster) + first;
+            let skip = (in_cluster % bytes_per_sector) as usize;
+
+            let mut data = vec![0; (sectors * bytes_per_sector) as usize];
+            // the rest of the first and last sectors is kept
+            let partial_first = skip != 0;
+            let partial_last = !(skip + count as usize).is_multiple_of(bytes_per_sector as usize);
+            if partial_first || (partial_last && sectors == 1) {
+                data[..bytes_per_sector as usize].copy_from_slice(
+                    &self
+                        .read_sectors(sector, 1)
+                        .context("read file sector", Some(sector.0))?,
+                );
+            }
+            if partial_last && sectors > 1 {
+                let last = sector + (sectors - 1);
+                data[((sectors - 1) * bytes_per_sector) as usize..].copy_from_slice(
+                    &self
+                        .read_sectors(last, 1)
+                        .context("read file sector", Some(last.0))?,
+                );
+            }
+            let from = (at - position) as usize;
+            data[skip..skip + count as usize].copy_from_slice(&buf[from..from + count as usize]);
+            if let Err(e) = self
+                .write_sectors(sector, &data)
+                .context("write file sector", Some(sector.0))
+            {
+                self.free_added(&chain, old_clusters)?;
+                return Err(e);
+            }
+            at += count;
+        }
+
+        if let Some(&start_cluster) = chain.first() {
+            set_entry_cluster(&mut entry, start_cluster);
+        }
+        entry[11] |= attrs::ARCHIVE;
+        entry[28..32].copy_from_slice(&end.max(old_len).to_le_bytes());
+        self.write_entries(&dir_entries.sectors, index, &[entry])?;
+        Ok((end - position) as u64)
+    }
+
+    fn create_file(&mut self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        let dir = self.find_dir(parent)?;
+        // it has no cluster until it's written to
+        self.add_entry(&dir, name, new_short_entry(attrs::ARCHIVE, Cluster(0), 0))
+    }
+
+    /// The cluster `..` points to for entries in `dir`, the root is always `0`
+    fn parent_cluster(dir: &Directory) -> Cluster {
+        match dir {
+            Directory::RootFat12_16 { .. } => Cluster(0),
+            Directory::Normal { inode } if inode.id == ROOT_INODE_ID => Cluster(0),
+            Directory::Normal { inode } => Cluster(inode.start_cluster),
+        }
+    }
+
+    fn create_dir(&mut self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        let dir = self.find_dir(parent)?;
+        let cluster = self.allocate_cluster(None)?;
+
+        let mut dot = new_short_entry(attrs::DIRECTORY, cluster, 0);
+        dot[..11].copy_from_slice(b".          ");
+        let mut dot_dot = new_short_entry(attrs::DIRECTORY, Self::parent_cluster(&dir), 0);
+        dot_dot[..11].copy_from_slice(b"..         ");
+        let sector = self.first_sector_of_cluster(cluster);
+        self.write_entries(&[sector], 0, &[dot, dot_dot])?;
+
+        let result = self.add_entry(&dir, name, new_short_entry(attrs::DIRECTORY, cluster, 0));
+        if result.is_err() {
+            self.free_chain(cluster)?;
+        }
+        result
+    }
+
+    /// Removes the entry `name`, directories must be empty
+    fn remove(&mut self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        let dir = self.find_dir(parent)?;
+        let (inode, first, count) = self.find_entry(&dir, name)?;
+        if inode.attributes().read_only {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if inode.is_dir() && self.open_dir_inode(&inode)?.next().is_some() {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+
+        let dir_entries = self.read_entries(&dir)?;
+        let mut entries = dir_entries.entries[first as usize..][..count as usize].to_vec();
+        entries
+            .iter_mut()
+            .for_each(|entry| entry[0] = DELETED_ENTRY);
+        self.write_entries(&dir_entries.sectors, first, &entries)?;
+        // after removing the entry, a crash here only loses the clusters
+        if inode.start_cluster != 0 {
+            self.free_chain(Cluster(inode.start_cluster))?;
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        old_parent: &str,
+        old_name: &str,
+        new_parent: &str,
+        new_name: &str,
+    ) -> Result<(), FileSystemError> {
+        let old_dir = self.find_dir(old_parent)?;
+        let new_dir = self.find_dir(new_parent)?;
+        let (inode, first, count) = self.find_entry(&old_dir, old_name)?;
+        if inode.attributes().read_only {
+            return Err(FileSystemError::PermissionDenied);
+        }
+
+        // remove the old entries first, so a new name only different in case is not taken,
+        // and put them back if the new one can't be added
+        let old_entries = self.read_entries(&old_dir)?;
+        let saved = old_entries.entries[first as usize..][..count as usize].to_vec();
+        let mut deleted = saved.clone();
+        deleted
+            .iter_mut()
+            .for_each(|entry| entry[0] = DELETED_ENTRY);
+        self.write_entries(&old_entries.sectors, first, &deleted)?;
+
+        let short_entry = *saved.last().unwrap();
+        if let Err(e) = self.add_entry(&new_dir, new_name, short_entry) {
+            self.write_entries(&old_entries.sectors, first, &saved)?;
+            return Err(e);
+        }
+
+        if inode.is_dir() {
+            // `..` is the second entry
+            let sector = self.first_sector_of_cluster(Cluster(inode.start_cluster));
+            let mut dot_dot = self.read_entries(&Directory::Normal { inode })?.entries[1];
+            set_entry_cluster(&mut dot_dot, Self::parent_cluster(&new_dir));
+            self.write_entries(&[sector], 1, &[dot_dot])?;
+        }
+        Ok(())
+    }
+}
+
+/// The clusters of the chains seen by [`FatFilesystem::check`], with the file of each
+struct ChainOwners {
+    // an index in `paths` plus 1, `0` for the clusters not in a chain yet
+    owners: Vec<u32>,
+    paths: Vec<String>,
+}
+
+/// The long name entries read by [`FatFilesystem::check`] before a short entry
+struct CheckedLongName {
+    first_entry: u32,
+    ordinal: u8,
+    checksum: u8,
+}
+
+/// Checking the consistency of the filesystem, only reading it
+impl FatFilesystem {
+    /// Marks the clusters of the chain of `path` at `start_cluster`, returns its length, or
+    /// `None` if its broken or crosses another chain
+    fn check_chain(
+        &self,
+        path: &str,
+        start_cluster: Cluster,
+        chains: &mut ChainOwners,
+        violations: &mut Vec<FatViolation>,
+    ) -> Option<u32> {
+        chains.paths.push(String::from(path));
+        let owner = chains.paths.len() as u32;
+        let mut cluster = start_cluster;
+        let mut len = 1;
+        loop {
+            if !(Cluster::FIRST..=self.max_cluster()).contains(&cluster) {
+                violations.push(FatViolation::OutOfRange {
+                    path: String::from(path),
+                    cluster,
+                });
+                return None;
+            }
+            let other = chains.owners[cluster.0 as usize];
+            if other != 0 {
+                violations.push(FatViolation::CrossedChains {
+                    path: String::from(path),
+                    other: chains.paths[other as usize - 1].clone(),
+                    cluster,
+                });
+                return None;
+            }
+            chains.owners[cluster.0 as usize] = owner;
+            match self.read_fat_entry(cluster) {
+                FatEntry::Next(next) => cluster = next,
+                FatEntry::EndOfChain => return Some(len),
+                entry => {
+                    violations.push(FatViolation::NotInUse {
+                        path: String::from(path),
+                        cluster,
+                        entry,
+                    });
+                    return None;
+                }
+            }
+            len += 1;
+        }
+    }
+
+    /// Checks that `.` and `..` are the first entries of the directory `path` at `cluster`
+    fn check_dot_entries(
+        path: &str,
+        cluster: Cluster,
+        parent: Cluster,
+        entries: &DirectoryEntries,
+        violations: &mut Vec<FatViolation>,
+    ) {
+        for (index, name, expected) in [(0, ".", cluster), (1, "..", parent)] {
+            let mut short_name = [b' '; 11];
+            short_name[..name.len()].copy_from_slice(name.as_bytes());
+            let found = entries
+                .entries
+                .get(index)
+                .filter(|entry| entry[..11] == short_name && entry[11] & attrs::DIRECTORY != 0)
+                .map(|entry| entry_cluster(entry));
+            if found != Some(expected) {
+                violations.push(FatViolation::DotEntry {
+                    path: String::from(path),
+                    name,
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+
+    /// Checks the long names in the entries of the directory `path`, the ones a reader would
+    /// ignore are violations too
+    fn check_long_name(
+        path: &str,
+        index: u32,
+        entry: &[u8; DIRECTORY_ENTRY_SIZE as usize],
+        long_name: &mut Option<CheckedLongName>,
+        violations: &mut Vec<FatViolation>,
+    ) {
+        let mut report = |entry, problem| {
+            violations.push(FatViolation::LongName {
+                path: String::from(path),
+                entry,
+                problem,
+            })
+        };
+        let is_free = entry[0] == 0 || entry[0] == DELETED_ENTRY;
+        if is_free || entry[11] & attrs::LONG_NAME != attrs::LONG_NAME {
+            match long_name.take() {
+                Some(long_name) if is_free || long_name.ordinal != 1 => {
+                    report(long_name.first_entry, LongNameProblem::Unterminated)
+                }
+                Some(long_name) if long_name.checksum != long_name_checksum(&entry[..11]) => {
+                    report(
+                        long_name.first_entry,
+                        LongNameProblem::Checksum {
+                            expected: long_name_checksum(&entry[..11]),
+                            found: long_name.checksum,
+                        },
+                    )
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let ordinal = entry[0] & 0x3F;
+        let checksum = entry[13];
+        if entry[0] & 0x40 == 0x40 {
+            if let Some(long_name) = long_name.take() {
+                report(long_name.first_entry, LongNameProblem::Unterminated);
+            }
+            // the last part comes first, and there are at most 20 parts
+            if (1..=20).contains(&ordinal) {
+                *long_name = Some(CheckedLongName {
+                    first_entry: index,
+                    ordinal,
+                    checksum,
+                });
+            } else {
+                report(
+                    index,
+                    LongNameProblem::OutOfOrder {
+                        expected: None,
+                        found: ordinal,
+                    },
+                );
+            }
+            return;
+        }
+        match long_name {
+            Some(long_name) if ordinal != 0 && ordinal + 1 == long_name.ordinal => {
+                if checksum != long_name.checksum {
+                    report(
+                        index,
+                        LongNameProblem::Checksum {
+                            expected: long_name.checksum,
+                            found: checksum,
+                        },
+                    );
+                }
+                long_name.ordinal = ordinal;
+            }
+            _ => {
+                let expected = long_name.take().map(|long_name| long_name.ordinal - 1);
+                report(
+                    index,
+                    LongNameProblem::OutOfOrder {
+                        expected,
+                        found: ordinal,
+                    },
+                );
+            }
+        }
+    }
+
+    /// The first FAT against the other copies
+    fn check_fat_copies(&self, violations: &mut Vec<FatViolation>) {
+        let ty = self.boot_sector.ty;
+        let fat_size = self.boot_sector.fat_size_in_sectors() as usize
+            * self.boot_sector.bytes_per_sector() as usize;
+        let first_fat = &self.fat.0[..fat_size];
+        for copy in 1..self.boot_sector.number_of_fats() {
+            let fat = &self.fat.0[copy as usize * fat_size..][..fat_size];
+            let mut differ = (0..=self.max_cluster().0).map(Cluster).filter(|&cluster| {
+                FatEntry::read(ty, first_fat, cluster) != FatEntry::read(ty, fat, cluster)
+            });
+            if let Some(first) = differ.next() {
+                violations.push(FatViolation::FatCopiesDiffer {
+                    copy,
+                    first,
+                    count: 1 + differ.count() as u32,
+                });
+            }
+        }
+    }
+
+    /// Walks all the directories and checks that every chain is valid and used only once,
+    /// that the files have the clusters their size needs, and the entries, the copies of the
+    /// FAT and the FAT32 `FSInfo` agree. Nothing is changed
+    pub fn check(&self) -> FatCheck {
+        let mut report = FatCheck::default();
+        let violations = &mut report.violations;
+        let mut chains = ChainOwners {
+            owners: vec![0; self.max_cluster().0 as usize + 1],
+            paths: Vec::new(),
+        };
+        let bytes_per_cluster = self.boot_sector.bytes_per_cluster();
+
+        self.check_fat_copies(violations);
+        let root = match self.open_root_dir() {
+            Ok(root) => root,
+            Err(error) => {
+                violations.push(FatViolation::Unreadable {
+                    path: String::from("/"),
+                    error,
+                });
+                return report;
+            }
+        };
+        if let Directory::Normal { inode } = &root {
+            self.check_chain("/", Cluster(inode.start_cluster), &mut chains, violations);
+        }
+        // with the cluster `..` should point to, for the directories that have one
+        let mut dirs = vec![(String::from("/"), root, None)];
+        while let Some((path, dir, dot_dot)) = dirs.pop() {
+            let entries = match self.read_entries(&dir) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    violations.push(FatViolation::Unreadable { path, error });
+                    continue;
+                }
+            };
+            if let (Directory::Normal { inode }, Some(parent)) = (&dir, dot_dot) {
+                let cluster = Cluster(inode.start_cluster);
+                Self::check_dot_entries(&path, cluster, parent, &entries, violations);
+            }
+            let dir_cluster = match &dir {
+                Directory::RootFat12_16 { .. } => ROOT_DIR_FAT12_16_CLUSTER,
+                Directory::Normal { inode } => inode.start_cluster,
+            };
+
+            let mut long_name = None;
+            for (index, entry) in entries.entries.iter().enumerate() {
+                let index = index as u32;
+                Self::check_long_name(&path, index, entry, &mut long_name, violations);
+                if entry[0] == 0 {
+                    break;
+                }
+                let attributes = entry[11];
+                if entry[0] == DELETED_ENTRY
+                    || entry[0] == b'.'
+                    || attributes & attrs::VOLUME_ID == attrs::VOLUME_ID
+                {
+                    continue;
+                }
+
+                let name = format!("{path}{}", short_name(entry));
+                let start_cluster = entry_cluster(entry);
+                let size = u32::from_le_bytes(entry[28..32].try_into().unwrap());
+                let is_dir = attributes & attrs::DIRECTORY == attrs::DIRECTORY;
+                // an empty file
+                if start_cluster == Cluster(0) && !is_dir {
+                    if size != 0 {
+                        violations.push(FatViolation::SizeMismatch {
+                            path: name,
+                            clusters: 0,
+                            size,
+                        });
+                    }
+                    continue;
+                }
+                let Some(len) = self.check_chain(&name, start_cluster, &mut chains, violations)
+                else {
+                    continue;
                 };
+                if is_dir {
+                    let inode = INode::new_file(
+                        name.clone(),
+                        file_attribute_from_fat(attributes),
+                        start_cluster.0,
+                        0,
+                    )
+                    .with_id(inode_id(dir_cluster, index));
+                    let parent = Self::parent_cluster(&dir);
+                    dirs.push((name + "/", Directory::Normal { inode }, Some(parent)));
+                } else if len != size.div_ceil(bytes_per_cluster) {
+                    violations.push(FatViolation::SizeMismatch {
+                        path: name,
+                        clusters: len,
+                        size,
+                    });
+                }
+            }
+            if let Some(long_name) = long_name {
+                violations.push(FatViolation::LongName {
+                    path,
+                    entry: long_name.first_entry,
+                    problem: LongNameProblem::Unterminated,
+                });
             }
         }
 
-        Ok(read as u64)
+        let mut lost = Cluster::data_clusters(self.max_cluster()).filter(|&cluster| {
+            chains.owners[cluster.0 as usize] == 0
+                && matches!(
+                    self.read_fat_entry(cluster),
+                    FatEntry::Next(_) | FatEntry::EndOfChain
+                )
+        });
+        if let Some(first) = lost.next() {
+            violations.push(FatViolation::LostClusters {
+                first,
+                count: 1 + lost.count() as u32,
+            });
+        }
+        report.free_clusters = Cluster::data_clusters(self.max_cluster())
+            .filter(|&cluster| self.read_fat_entry(cluster) == FatEntry::Free)
+            .count() as u32;
+
+        match self.read_fs_info() {
+            Ok(Some((_, fs_info))) => {
+                let recorded = fs_info::free_count(&fs_info);
+                if recorded != fs_info::UNKNOWN && recorded != report.free_clusters {
+                    report.violations.push(FatViolation::FsInfoFreeCount {
+                        recorded,
+                        actual: report.free_clusters,
+                    });
+                }
+            }
+            Ok(None) => {}
+            Err(error) => report.violations.push(FatViolation::Unreadable {
+                path: String::from("FSInfo"),
+                error,
+            }),
+        }
+        report
+    }
+}
+
+impl Drop for FatFilesystem {
+    fn drop(&mut self) {
+        self.device.remove_cache_user(self.cache_id);
+        page_cache::invalidate(self.cache_id);
     }
 }
 
@@ -804,17 +2310,486 @@ impl FileSystem for Mutex<FatFilesystem> {
     fn read_file(
         &self,
         inode: &INode,
-        position: u32,
+        position: u64,
         buf: &mut [u8],
     ) -> Result<u64, FileSystemError> {
-        self.lock().read_file(inode, position, buf)
+        loop {
+            let (file_read, changes, device_generation) = {
+                let mut fs = self.lock();
+                if fs.disconnected {
+                    return Err(FileSystemError::StaleHandle);
+                }
+                fs.sync_with_device()?;
+                let file_read = fs.file_read(inode, position, buf.len())?;
+                (file_read, fs.changes, fs.device_generation)
+            };
+            let read = file_read.read(buf)?;
+            // a change in the meantime could have given the clusters to another file
+            let fs = self.lock();
+            if fs.changes == changes && fs.device.generation() == device_generation {
+                return Ok(read);
+            }
+        }
     }
 
     fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
-        Ok(self.lock().open_dir(path)?.collect())
+        let mut fs = self.lock();
+        if fs.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
+        fs.sync_with_device()?;
+        Ok(fs.open_dir(path)?.collect())
     }
 
     fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError> {
-        Ok(self.lock().open_dir_inode(inode)?.collect())
+        let mut fs = self.lock();
+        if fs.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
+        fs.sync_with_device()?;
+        Ok(fs.open_dir_inode(inode)?.collect())
+    }
+
+    fn disconnect(&self) {
+        let cache_id = {
+            let mut fs = self.lock();
+            fs.disconnected = true;
+            fs.cache_id
+        };
+        // the pages can't be used anymore
+        page_cache::invalidate(cache_id);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.lock().disconnected
+    }
+
+    fn cache_id(&self) -> Option<u64> {
+        Some(self.lock().cache_id)
+    }
+
+    fn is_read_only(&self) -> bool {
+        let fs = self.lock();
+        fs.read_only || !fs.device.is_writable()
+    }
+
+    fn sync(&self) -> Result<(), FileSystemError> {
+        let fs = self.lock();
+        if fs.disconnected {
+            return Err(FileSystemError::StaleHandle);
+        }
+        fs.device.flush()
+    }
+
+    fn write_file(&self, inode: &INode, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        // in parts, not to keep the filesystem locked for long
+        let mut written = 0;
+        for part in buf.chunks(MAX_WRITE_BYTES) {
+            let mut part_written = 0;
+            let result = modify(self, |fs| {
+                part_written = fs.write(inode, position + written, part)?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => written += part_written,
+                // report what was written before the error
+                Err(_) if written != 0 => break,
+                Err(e) => return Err(e),
+            }
+            if part_written < part.len() as u64 {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    fn create_dir(&self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        modify(self, |fs| fs.create_dir(parent, name))
+    }
+
+    fn create_file(&self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        modify(self, |fs| fs.create_file(parent, name))
+    }
+
+    fn remove_file(
+        &self,
+        parent: &str,
+        name: &str,
+        _still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        modify(self, |fs| fs.remove(parent, name))
+    }
+
+    fn truncate(&self, inode: &INode, len: u64) -> Result<INode, FileSystemError> {
+        let len = u32::try_from(len).map_err(|_| FileSystemError::NoSpace)?;
+        let mut inode = inode.clone();
+        // `modify` drops all the cached pages, with the ones after the new end
+        modify(self, |fs| {
+            inode.start_cluster = fs.truncate(&inode, len)?.0;
+            Ok(())
+        })?;
+        inode.size = len;
+        Ok(inode)
+    }
+
+    fn remove_dir(
+        &self,
+        parent: &str,
+        name: &str,
+        _still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        modify(self, |fs| fs.remove(parent, name))
+    }
+
+    fn rename(
+        &self,
+        old_parent: &str,
+        old_name: &str,
+        new_parent: &str,
+        new_name: &str,
+    ) -> Result<(), FileSystemError> {
+        modify(self, |fs| {
+            fs.rename(old_parent, old_name, new_parent, new_name)
+        })
+    }
+}
+
+/// Runs `f` to change the filesystem, then drops all the cached pages, the inode ids come
+/// from the location of the entries, so they may point to other files now
+fn modify(
+    filesystem: &Mutex<FatFilesystem>,
+    f: impl FnOnce(&mut FatFilesystem) -> Result<(), FileSystemError>,
+) -> Result<(), FileSystemError> {
+    let mut fs = filesystem.lock();
+    if fs.disconnected {
+        return Err(FileSystemError::StaleHandle);
     }
+    fs.sync_with_device()?;
+    fs.changes += 1;
+    let result = f(&mut fs);
+    let cache_id = fs.cache_id;
+    drop(fs);
+    page_cache::invalidate(cache_id);
+    result
+}
+
+const SELFTEST_FAT32: &str = "/selftest_fat32";
+
+/// A FAT32 version of [`block::selftest_fat12_image`], with an empty root directory at
+/// cluster 2 and `free_count` in its `FSInfo`
+fn selftest_fat32_image(free_count: u32) -> Vec<u8> {
+    const SECTOR_SIZE: usize = block::SELFTEST_SECTOR_SIZE;
+    let mut image = block::selftest_fat12_image(b"");
+    image[SECTOR_SIZE..].fill(0);
+
+    let boot = &mut image[..SECTOR_SIZE];
+    boot[14..16].copy_from_slice(&2u16.to_le_bytes()); // reserved sectors, with `FSInfo`
+    boot[17..19].fill(0); // no root entries
+    boot[22..24].fill(0); // no FAT12/16 size
+    boot[36..90].fill(0);
+    boot[36..40].copy_from_slice(&1u32.to_le_bytes()); // FAT size
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes()); // root cluster
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes()); // `FSInfo` sector
+    boot[66] = 0x29; // extended boot signature
+    boot[71..82].copy_from_slice(b"SELFTEST   ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+
+    let info = &mut image[SECTOR_SIZE..][..SECTOR_SIZE];
+    info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    info[488..492].copy_from_slice(&free_count.to_le_bytes());
+    info[492..496].copy_from_slice(&u32::MAX.to_le_bytes());
+    info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    let fat = &mut image[2 * SECTOR_SIZE..][..SECTOR_SIZE];
+    fat[..8].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]);
+    FatEntry::EndOfChain.write(FatType::Fat32, fat, Cluster(2));
+    image
+}
+
+/// [`FatFilesystem::check`] on images broken in one way each, it must find exactly that,
+/// then the `FSInfo` of a FAT32 after it was written
+pub(super) fn run_self_tests() {
+    const SECTOR_SIZE: usize = block::SELFTEST_SECTOR_SIZE;
+    const FAT: usize = SECTOR_SIZE;
+    const ROOT: usize = 2 * SECTOR_SIZE;
+    // 1 sector per cluster after the root directory
+    let cluster_offset = |cluster: u32| (3 + cluster as usize - 2) * SECTOR_SIZE;
+
+    println!("Running FAT check self tests...");
+    let disk = Arc::new(SelftestCacheDisk::new(Vec::new()));
+    let device = BlockDeviceFile::register("selftest_fat_check".into(), disk.clone(), false);
+    let check_image = |image: Vec<u8>| {
+        disk.reset(image);
+        device.drop_cached_sectors();
+        check(device.clone(), Lba(0), block::SELFTEST_SECTORS as u32).unwrap()
+    };
+    let set_fat = |image: &mut [u8], cluster: u32, entry: FatEntry| {
+        entry.write(
+            FatType::Fat12,
+            &mut image[FAT..][..SECTOR_SIZE],
+            Cluster(cluster),
+        )
+    };
+    let add_entry = |image: &mut [u8], index: usize, entry: [u8; DIRECTORY_ENTRY_SIZE as usize]| {
+        image[ROOT + index * DIRECTORY_ENTRY_SIZE as usize..][..DIRECTORY_ENTRY_SIZE as usize]
+            .copy_from_slice(&entry);
+    };
+    let file_entry = |name: &[u8; 11], cluster: u32, size: u32| {
+        let mut entry = new_short_entry(attrs::ARCHIVE, Cluster(cluster), size);
+        entry[..11].copy_from_slice(name);
+        entry
+    };
+    // `/HELLO.TXT` at cluster 2
+    let base = block::selftest_fat12_image(b"hello");
+
+    let report = check_image(base.clone());
+    assert!(report.violations.is_empty(), "{report}");
+    assert_eq!(report.free_clusters, 124);
+
+    // two files with the same cluster, and a chain looping
+    let mut image = base.clone();
+    add_entry(&mut image, 1, file_entry(b"OTHER   TXT", 2, 5));
+    let report = check_image(image);
+    assert!(
+        matches!(
+            &report.violations[..],
+            [FatViolation::CrossedChains { path, other, cluster: Cluster(2) }]
+                if path == "/OTHER.TXT" && other == "/HELLO.TXT"
+        ),
+        "{report}"
+    );
+    assert_eq!(
+        report.to_string(),
+        "/OTHER.TXT: cluster 2 is in the chain of /HELLO.TXT\n"
+    );
+    let mut image = base.clone();
+    set_fat(&mut image, 2, FatEntry::Next(Cluster(3)));
+    set_fat(&mut image, 3, FatEntry::Next(Cluster(2)));
+    let report = check_image(image);
+    assert_eq!(
+        report.to_string(),
+        "/HELLO.TXT: the chain loops at cluster 2\n"
+    );
+
+    // a chain no file has, only lost
+    let mut image = base.clone();
+    set_fat(&mut image, 10, FatEntry::Next(Cluster(11)));
+    set_fat(&mut image, 11, FatEntry::EndOfChain);
+    let report = check_image(image);
+    assert!(
+        matches!(
+            report.violations[..],
+            [FatViolation::LostClusters {
+                first: Cluster(10),
+                count: 2
+            }]
+        ),
+        "{report}"
+    );
+    assert!(report.is_consistent());
+    assert_eq!(report.lost_clusters(), 2);
+    assert_eq!(report.free_clusters, 122);
+
+    // the size needs 2 clusters, pointing to a free, a bad and no cluster
+    let mut image = base.clone();
+    image[ROOT + 28..ROOT + 32].copy_from_slice(&600u32.to_le_bytes());
+    add_entry(&mut image, 1, file_entry(b"FREE    TXT", 5, 1));
+    add_entry(&mut image, 2, file_entry(b"BAD     TXT", 7, 1));
+    set_fat(&mut image, 7, FatEntry::Bad);
+    add_entry(&mut image, 3, file_entry(b"FAR     TXT", 200, 1));
+    add_entry(&mut image, 4, file_entry(b"EMPTY   TXT", 0, 1));
+    let report = check_image(image);
+    assert!(
+        matches!(
+            &report.violations[..],
+            [
+                FatViolation::SizeMismatch {
+                    clusters: 1,
+                    size: 600,
+                    ..
+                },
+                FatViolation::NotInUse {
+                    cluster: Cluster(5),
+                    entry: FatEntry::Free,
+                    ..
+                },
+                FatViolation::NotInUse {
+                    cluster: Cluster(7),
+                    entry: FatEntry::Bad,
+                    ..
+                },
+                FatViolation::OutOfRange {
+                    cluster: Cluster(200),
+                    ..
+                },
+                FatViolation::SizeMismatch {
+                    clusters: 0,
+                    size: 1,
+                    ..
+                },
+            ]
+        ),
+        "{report}"
+    );
+    assert!(!report.is_consistent());
+
+    // the second FAT is not the same, the first one is used for the rest
+    let two_fats = |mut image: Vec<u8>| {
+        image[16] = 2;
+        let fat = image[FAT..][..SECTOR_SIZE].to_vec();
+        image.splice(ROOT..ROOT, fat);
+        image.truncate(block::SELFTEST_SECTORS as usize * SECTOR_SIZE);
+        image
+    };
+    let report = check_image(two_fats(base.clone()));
+    assert!(report.violations.is_empty(), "{report}");
+    let mut image = two_fats(base.clone());
+    let second_fat = &mut image[2 * SECTOR_SIZE..][..SECTOR_SIZE];
+    FatEntry::Free.write(FatType::Fat12, second_fat, Cluster(2));
+    FatEntry::EndOfChain.write(FatType::Fat12, second_fat, Cluster(3));
+    let report = check_image(image);
+    assert!(
+        matches!(
+            report.violations[..],
+            [FatViolation::FatCopiesDiffer {
+                copy: 1,
+                first: Cluster(2),
+                count: 2
+            }]
+        ),
+        "{report}"
+    );
+
+    // a long name, then with its entries broken
+    let short_name = *b"ALONGN~1TXT";
+    let mut with_long_name = base.clone();
+    for (i, entry) in long_name_entries("a long name.txt", &short_name)
+        .into_iter()
+        .enumerate()
+    {
+        add_entry(&mut with_long_name, 1 + i, entry);
+    }
+    add_entry(&mut with_long_name, 3, file_entry(&short_name, 0, 0));
+    let report = check_image(with_long_name.clone());
+    assert!(report.violations.is_empty(), "{report}");
+    let long_name_problems = |image: Vec<u8>| -> Vec<(u32, LongNameProblem)> {
+        check_image(image)
+            .violations
+            .into_iter()
+            .map(|violation| match violation {
+                FatViolation::LongName {
+                    path,
+                    entry,
+                    problem,
+                } if path == "/" => (entry, problem),
+                violation => panic!("FAT check self test: not a long name: {violation}"),
+            })
+            .collect()
+    };
+    let mut image = with_long_name.clone();
+    image[ROOT + 3 * DIRECTORY_ENTRY_SIZE as usize + 7] = b'2';
+    assert_eq!(
+        long_name_problems(image),
+        [(
+            1,
+            LongNameProblem::Checksum {
+                expected: long_name_checksum(b"ALONGN~2TXT"),
+                found: long_name_checksum(&short_name),
+            }
+        )]
+    );
+    let mut image = with_long_name.clone();
+    let entries = long_name_entries("a long name.txt", &short_name);
+    add_entry(&mut image, 1, entries[1]);
+    add_entry(&mut image, 2, entries[0]);
+    assert_eq!(
+        long_name_problems(image),
+        [
+            (
+                1,
+                LongNameProblem::OutOfOrder {
+                    expected: None,
+                    found: 1
+                }
+            ),
+            (2, LongNameProblem::Unterminated)
+        ]
+    );
+    let mut image = with_long_name.clone();
+    image[ROOT + 3 * DIRECTORY_ENTRY_SIZE as usize] = DELETED_ENTRY;
+    assert_eq!(
+        long_name_problems(image),
+        [(1, LongNameProblem::Unterminated)]
+    );
+
+    // a directory at cluster 3, its `..` must be 0 for the root
+    let mut with_dir = base.clone();
+    let mut dir = new_short_entry(attrs::DIRECTORY, Cluster(3), 0);
+    dir[..11].copy_from_slice(b"DIR        ");
+    add_entry(&mut with_dir, 1, dir);
+    set_fat(&mut with_dir, 3, FatEntry::EndOfChain);
+    let mut dot = new_short_entry(attrs::DIRECTORY, Cluster(3), 0);
+    dot[..11].copy_from_slice(b".          ");
+    let mut dot_dot = new_short_entry(attrs::DIRECTORY, Cluster(0), 0);
+    dot_dot[..11].copy_from_slice(b"..         ");
+    with_dir[cluster_offset(3)..][..32].copy_from_slice(&dot);
+    with_dir[cluster_offset(3) + 32..][..32].copy_from_slice(&dot_dot);
+    let report = check_image(with_dir.clone());
+    assert!(report.violations.is_empty(), "{report}");
+    let mut image = with_dir.clone();
+    set_entry_cluster(&mut image[cluster_offset(3) + 32..][..32], Cluster(5));
+    let report = check_image(image);
+    assert_eq!(report.to_string(), "/DIR/: `..` points to 5 instead of 0\n");
+    let mut image = with_dir;
+    image[cluster_offset(3)] = DELETED_ENTRY;
+    let report = check_image(image);
+    assert!(
+        matches!(
+            report.violations[..],
+            [FatViolation::DotEntry {
+                name: ".",
+                expected: Cluster(3),
+                found: None,
+                ..
+            }]
+        ),
+        "{report}"
+    );
+
+    // FAT32, the free count is checked unless unknown
+    let report = check_image(selftest_fat32_image(124));
+    assert!(report.violations.is_empty(), "{report}");
+    assert_eq!(report.free_clusters, 124);
+    let report = check_image(selftest_fat32_image(fs_info::UNKNOWN));
+    assert!(report.violations.is_empty(), "{report}");
+    let report = check_image(selftest_fat32_image(100));
+    assert!(
+        matches!(
+            report.violations[..],
+            [FatViolation::FsInfoFreeCount {
+                recorded: 100,
+                actual: 124
+            }]
+        ),
+        "{report}"
+    );
+
+    // the count is forgotten when writing
+    let ramdisk = RamDisk::new((block::SELFTEST_SECTORS, SECTOR_SIZE as u32));
+    ramdisk
+        .write_sectors(Lba(0), &selftest_fat32_image(124))
+        .unwrap();
+    let fat32 = BlockDeviceFile::register("selftest_fat32_ram".into(), Arc::new(ramdisk), true);
+    super::mount_block_device(SELFTEST_FAT32, fat32.clone()).unwrap();
+    super::create_dir(&format!("{SELFTEST_FAT32}/A long directory name")).unwrap();
+    super::create_dir(&format!("{SELFTEST_FAT32}/A long directory name/INNER")).unwrap();
+    super::force_unmount(SELFTEST_FAT32).unwrap();
+    let report = check(fat32.clone(), Lba(0), block::SELFTEST_SECTORS as u32).unwrap();
+    assert!(report.violations.is_empty(), "{report}");
+    assert_eq!(report.free_clusters, 122);
+    let mut fs_info = vec![0; SECTOR_SIZE];
+    fat32.read_sectors(Lba(1), &mut fs_info).unwrap();
+    assert_eq!(fs_info::free_count(&fs_info), fs_info::UNKNOWN);
+    println!("FAT check self tests passed");
 }
@@ -0,0 +1,417 @@
+//! The modules loaded by the bootloader with the kernel, like an initrd.
+//!
+//! Every module is exposed as `/devices/module<N>` in the order they were given. Modules
+//! compressed with gzip are decompressed at boot into new pages, and the pages of the
+//! compressed data are given back to the physical allocator.
+//! The first module that is a `newc` cpio archive is mounted read-only at `/initrd`.
+
+use core::{
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use kernel_core::{cpio, inflate};
+
+use crate::{
+    devices::{self, clock, Device},
+    memory_management::{
+        memory_layout::{align_up, MemSize, PAGE_4K},
+        physical_page_allocator,
+        virtual_memory_mapper::{self, VirtualMemoryMapEntry},
+        virtual_space,
+    },
+    multiboot2::{BootModule, MultiBoot2Info},
+    sync::once::OnceLock,
+};
+
+use super::{FileAttributes, FileSystem, FileSystemError, INode};
+
+const INITRD_MOUNT_PATH: &str = "/initrd";
+const LZ4_FRAME_MAGIC: &[u8] = &[0x04, 0x22, 0x4D, 0x18];
+
+static INITRD: OnceLock<Initrd> = OnceLock::new();
+
+/// `/devices/module<N>`, the content of a boot module, after decompression
+#[derive(Debug)]
+struct ModuleDevice {
+    name: String,
+    data: &'static [u8],
+}
+
+impl Device for ModuleDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(devices::read_bytes(self.data, offset, buf))
+    }
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Directory,
+    File { data: &'static [u8] },
+}
+
+/// The cpio entries are paths, so we build a tree from them. Each node is identified
+/// by its index, which is stored in the `start_cluster` of the inode
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    kind: NodeKind,
+}
+
+struct Initrd {
+    // the first node is the root
+    nodes: Vec<Node>,
+}
+
+impl Initrd {
+    fn parse(data: &'static [u8]) -> Result<Self, cpio::CpioError> {
+        let mut s = Self {
+            nodes: vec![Node {
+                name: String::new(),
+                parent: 0,
+                kind: NodeKind::Directory,
+            }],
+        };
+        for entry in cpio::entries(data) {
+            let entry = entry?;
+            if entry.is_dir() {
+                s.add_node(entry.name, NodeKind::Directory);
+            } else if entry.is_file() {
+                s.add_node(entry.name, NodeKind::File { data: entry.data });
+            } else {
+                println!(
+                    "[initrd] WARNING: skipping {:?}, unsupported mode {:o}",
+                    entry.name, entry.mode
+                );
+            }
+        }
+        Ok(s)
+    }
+
+    fn add_node(&mut self, path: &str, kind: NodeKind) {
+        let mut components = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .peekable();
+        let mut parent = 0;
+        while let Some(component) = components.next() {
+            let is_last = components.peek().is_none();
+            match self.find_child(parent, component) {
+                // the parent directories can come before or after their content
+                Some(index) if is_last => {
+                    if matches!(kind, NodeKind::File { .. }) {
+                        self.nodes[index].kind = kind;
+                    }
+                    return;
+                }
+                Some(index) => parent = index,
+                None if is_last => {
+                    self.nodes.push(Node {
+                        name: String::from(component),
+                        parent,
+                        kind,
+                    });
+                    return;
+                }
+                None => {
+                    self.nodes.push(Node {
+                        name: String::from(component),
+                        parent,
+                        kind: NodeKind::Directory,
+                    });
+                    parent = self.nodes.len() - 1;
+                }
+            }
+        }
+    }
+
+    fn find_child(&self, parent: usize, name: &str) -> Option<usize> {
+        // the root is its own parent, so skip it
+        self.nodes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, node)| node.parent == parent && node.name == name)
+            .map(|(i, _)| i)
+    }
+
+    fn find_path(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(0, |parent, component| self.find_child(parent, component))
+    }
+
+    fn node_inode(&self, index: usize) -> INode {
+        let node = &self.nodes[index];
+        match node.kind {
+            NodeKind::Directory => INode::new_file(
+                node.name.clone(),
+                FileAttributes::DIRECTORY | FileAttributes::READ_ONLY,
+                index as u32,
+                0,
+            ),
+            NodeKind::File { data } => INode::new_file(
+                node.name.clone(),
+                FileAttributes::READ_ONLY,
+                index as u32,
+                data.len() as u32,
+            ),
+        }
+    }
+
+    fn list_dir(&self, index: usize) -> Result<Vec<INode>, FileSystemError> {
+        match self.nodes.get(index).map(|node| &node.kind) {
+            Some(NodeKind::Directory) => Ok((1..self.nodes.len())
+                .filter(|&i| self.nodes[i].parent == index)
+                .map(|i| self.node_inode(i))
+                .collect()),
+            Some(NodeKind::File { .. }) => Err(FileSystemError::IsNotDirectory),
+            None => Err(FileSystemError::FileNotFound),
+        }
+    }
+}
+
+/// A read-only filesystem with the content of the initrd, mounted at `/initrd`
+struct InitrdFileSystem {
+    disconnected: AtomicBool,
+}
+
+impl FileSystem for InitrdFileSystem {
+    fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let initrd = INITRD.get();
+        let index = initrd
+            .find_path(path)
+            .ok_or(FileSystemError::FileNotFound)?;
+        initrd.list_dir(index)
+    }
+
+    fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        INITRD.get().list_dir(inode.start_cluster() as usize)
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let node = INITRD
+            .get()
+            .nodes
+            .get(inode.start_cluster() as usize)
+            .ok_or(FileSystemError::FileNotFound)?;
+        let NodeKind::File { data } = node.kind else {
+            return Err(FileSystemError::IsDirectory);
+        };
+        Ok(devices::read_bytes(data, position, buf))
+    }
+
+    fn write_file(
+        &self,
+        _inode: &INode,
+        _position: u64,
+        _buf: &[u8],
+    ) -> Result<u64, FileSystemError> {
+        Err(FileSystemError::WriteNotSupported)
+    }
+
+    fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::Release);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+}
+
+/// Decompress the gzip `compressed` data into new pages, panics if the data is corrupt
+fn decompress(index: usize, module: &BootModule, compressed: &[u8]) -> &'static [u8] {
+    // this is modulo 2^32 and can't be trusted, but if its wrong the decompression will fail,
+    // and the output is bounded by it
+    let size = inflate::gzip_size(compressed).unwrap_or(0) as usize;
+    if size == 0 {
+        // still check that its valid
+        inflate::gunzip(compressed, &mut []).unwrap_or_else(|e| {
+            panic!(
+                "Boot module {index} ({:?}) is corrupt: {e:?}",
+                module.cmdline
+            )
+        });
+        return &[];
+    }
+    let (free, used) = physical_page_allocator::stats();
+    let available = MemSize(((free - used) * PAGE_4K) as u64);
+    assert!(
+        (size as u64) < available.0,
+        "Boot module {index} ({:?}) is too large to decompress: {} (available {})",
+        module.cmdline,
+        MemSize(size as u64),
+        available
+    );
+
+    let mapped_size = align_up(size, PAGE_4K) as u64;
+    let virtual_start = virtual_space::reserve_virtual_space(mapped_size);
+    virtual_memory_mapper::map_kernel(&VirtualMemoryMapEntry {
+        virtual_address: virtual_start,
+        physical_address: None,
+        size: mapped_size,
+        flags: virtual_memory_mapper::flags::PTE_WRITABLE
+            | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+    });
+    // SAFETY: we just mapped this, and its never unmapped
+    let output = unsafe { slice::from_raw_parts_mut(virtual_start as *mut u8, size) };
+
+    let start = clock::uptime_nanos();
+    let written = inflate::gunzip(compressed, output).unwrap_or_else(|e| {
+        panic!(
+            "Boot module {index} ({:?}) is corrupt: {e:?}",
+            module.cmdline
+        )
+    });
+    let elapsed = clock::uptime_nanos() - start;
+    println!(
+        "[initrd] module{index}: decompressed {} -> {} in {}.{:03}ms",
+        MemSize(compressed.len() as u64),
+        MemSize(written as u64),
+        elapsed / 1_000_000,
+        elapsed / 1_000 % 1_000
+    );
+
+    &output[..written]
+}
+
+/// Get the content of the module, decompressing it if needed
+fn load_module(index: usize, module: &BootModule) -> &'static [u8] {
+    if module.size() == 0 {
+        return &[];
+    }
+    let virtual_start =
+        virtual_space::allocate_and_map_virtual_space(module.start as u64, module.size() as u64);
+    // SAFETY: the module pages are reserved by the physical allocator, and we just mapped them
+    let data = unsafe { slice::from_raw_parts(virtual_start as *const u8, module.size()) };
+
+    if data.starts_with(LZ4_FRAME_MAGIC) {
+        panic!(
+            "Boot module {index} ({:?}) is compressed with LZ4, which is not supported, use gzip",
+            module.cmdline
+        );
+    }
+    if !inflate::is_gzip(data) {
+        // used in place, the pages stay reserved
+        return data;
+    }
+
+    let decompressed = decompress(index, module, data);
+    virtual_space::deallocate_virtual_space(virtual_start, module.size() as u64);
+    // SAFETY: the compressed data is not used anymore
+    let freed =
+        unsafe { physical_page_allocator::free_boot_range(module.start as _, module.end as _) };
+    println!("[initrd] module{index}: released {freed} pages of compressed data");
+    decompressed
+}
+
+/// Loads the boot modules, registers them as devices, and mounts the initrd if any.
+///
+/// Must be called after the devices mapping, and after the clock so that the decompression
+/// time is recorded
+pub fn init(multiboot_info: &MultiBoot2Info) {
+    let mut mounted = false;
+    for (index, module) in multiboot_info.modules().enumerate() {
+        let data = load_module(index, &module);
+        devices::register_device(Arc::new(ModuleDevice {
+            name: format!("module{index}"),
+            data,
+        }));
+
+        if mounted || !cpio::is_cpio(data) {
+            continue;
+        }
+        let initrd = Initrd::parse(data).unwrap_or_else(|e| {
+            panic!(
+                "Boot module {index} ({:?}) is not a valid cpio archive: {e:?}",
+                module.cmdline
+            )
+        });
+        println!(
+            "[initrd] mounting module{index} ({:?}) at {INITRD_MOUNT_PATH}, {} entries",
+            module.cmdline,
+            initrd.nodes.len() - 1
+        );
+        if INITRD.set(initrd).is_err() {
+            panic!("initrd already initialized");
+        }
+        super::mount(
+            INITRD_MOUNT_PATH,
+            Arc::new(InitrdFileSystem {
+                disconnected: AtomicBool::new(false),
+            }),
+            &format!("module{index}"),
+            "initrd",
+        );
+        mounted = true;
+    }
+}
+
+// the content of `kernel/initrd`, which is packed into the initrd module of the iso
+const REFERENCE_FILES: &[(&str, &[u8])] = &[
+    ("hello.txt", include_bytes!("../../initrd/hello.txt")),
+    (
+        "dir/nested.txt",
+        include_bytes!("../../initrd/dir/nested.txt"),
+    ),
+];
+
+fn selftest_initrd_contents() {
+    let mut files = Vec::new();
+    super::walk::walk(INITRD_MOUNT_PATH, super::walk::DEFAULT_MAX_DEPTH, |entry| {
+        if !entry.inode.is_dir() {
+            files.push(entry.path.clone());
+        }
+        true
+    })
+    .expect("initrd self test: could not walk the initrd");
+    assert_eq!(
+        files.len(),
+        REFERENCE_FILES.len(),
+        "initrd self test: found {files:?}"
+    );
+
+    for (path, expected) in REFERENCE_FILES {
+        let content = super::open(&format!("{INITRD_MOUNT_PATH}/{path}"))
+            .and_then(|mut file| file.read_to_end())
+            .unwrap_or_else(|e| panic!("initrd self test: could not read {path}: {e:?}"));
+        assert!(
+            content == *expected,
+            "initrd self test: {path} doesn't match the reference"
+        );
+    }
+}
+
+/// Checks that the mounted initrd, if any, has the content of `kernel/initrd`, the decoding
+/// of the gzip data is tested on the host with `kernel_core`
+pub fn run_self_tests() {
+    println!("Running initrd self tests...");
+
+    if INITRD.try_get().is_some() {
+        selftest_initrd_contents();
+    } else {
+        println!("[initrd] no initrd mounted, skipping the content test");
+    }
+
+    println!("initrd self tests passed");
+}
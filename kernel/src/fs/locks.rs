@@ -0,0 +1,352 @@
+//! Advisory locks on whole files, taken with `SYS_FLOCK`.
+//!
+//! A lock is on the inode, under the same key as the open files (the filesystem and the inode
+//! id), so all the [`File`]s open on it contend, in the same process or not. It belongs to the
+//! `File` that took it, closing the file releases it, and so does the exit of the process,
+//! which closes all of them.
+//!
+//! The waiters get the lock in the order they came, a shared lock doesn't pass an exclusive
+//! one waiting before it, so the writers can't be starved by a stream of readers. A waiting
+//! process is parked by the scheduler, which resumes it once it's no longer in a queue, see
+//! [`is_waiting`]. Nothing finds two processes waiting on each other's locks.
+
+use core::fmt;
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use kernel_user_link::file::FlockOperation;
+
+use crate::{
+    devices::{
+        self,
+        generated::{self, Cursor, Generator},
+        Device,
+    },
+    process::ProcessName,
+    sync::spin::mutex::Mutex,
+};
+
+use super::FileSystemError;
+
+/// The filesystem and the inode id, see `open_file_key`
+pub(super) type LockKey = (usize, u64);
+
+static LOCKS: Mutex<LockTable> = Mutex::new(LockTable::new());
+
+/// What holds or waits for a lock, the `File` is the owner, the process and the fd are only
+/// shown in `/devices/file_locks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOwner {
+    /// The id of the `File`, unique until the kernel restarts
+    pub file: u64,
+    pub pid: u64,
+    /// The name of the process when it locked, the listing can't look up the processes
+    pub name: ProcessName,
+    pub fd: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    Granted,
+    /// Queued, the caller has to wait until [`is_waiting`] is `false`
+    Waiting,
+}
+
+#[derive(Debug)]
+struct FileLock {
+    // the path of the first file that locked it, for the listing
+    path: String,
+    exclusive: bool,
+    holders: Vec<LockOwner>,
+    // with whether they want it exclusive
+    waiters: VecDeque<(LockOwner, bool)>,
+}
+
+impl FileLock {
+    fn admits(&self, exclusive: bool) -> bool {
+        self.holders.is_empty() || (!exclusive && !self.exclusive)
+    }
+
+    /// Gives the lock to the waiters at the front of the queue that can have it together
+    fn grant(&mut self) {
+        while let Some(&(owner, exclusive)) = self.waiters.front() {
+            if !self.admits(exclusive) {
+                break;
+            }
+            self.waiters.pop_front();
+            self.holders.push(owner);
+            self.exclusive = exclusive;
+        }
+    }
+}
+
+impl fmt::Display for FileLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = |exclusive| if exclusive { "exclusive" } else { "shared" };
+        write!(f, "{}: {}", self.path, kind(self.exclusive))?;
+        for holder in &self.holders {
+            write!(f, " {}({})/{}", holder.pid, holder.name, holder.fd)?;
+        }
+        if !self.waiters.is_empty() {
+            write!(f, ", waiting:")?;
+        }
+        for (waiter, exclusive) in &self.waiters {
+            write!(
+                f,
+                " {}({})/{} {}",
+                waiter.pid,
+                waiter.name,
+                waiter.fd,
+                kind(*exclusive)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct LockTable {
+    locks: BTreeMap<LockKey, FileLock>,
+}
+
+impl LockTable {
+    const fn new() -> Self {
+        Self {
+            locks: BTreeMap::new(),
+        }
+    }
+
+    fn lock(
+        &mut self,
+        key: LockKey,
+        path: &str,
+        owner: LockOwner,
+        exclusive: bool,
+        block: bool,
+    ) -> Result<LockState, FileSystemError> {
+        let held = self.locks.get(&key).is_some_and(|lock| {
+            lock.exclusive == exclusive && lock.holders.iter().any(|h| h.file == owner.file)
+        });
+        if held {
+            return Ok(LockState::Granted);
+        }
+        // converting, like `flock` the old one goes first
+        self.unlock(key, owner.file);
+
+        let lock = self.locks.entry(key).or_insert_with(|| FileLock {
+            path: path.to_string(),
+            exclusive,
+            holders: Vec::new(),
+            waiters: VecDeque::new(),
+        });
+        if lock.waiters.is_empty() && lock.admits(exclusive) {
+            lock.holders.push(owner);
+            lock.exclusive = exclusive;
+            Ok(LockState::Granted)
+        } else if block {
+            lock.waiters.push_back((owner, exclusive));
+            Ok(LockState::Waiting)
+        } else {
+            // `unlock` above may have been the last
+            self.remove_unused(key);
+            Err(FileSystemError::WouldBlock)
+        }
+    }
+
+    /// Releases the lock of `file`, or stops its wait, the next waiters get the lock
+    fn unlock(&mut self, key: LockKey, file: u64) {
+        let Some(lock) = self.locks.get_mut(&key) else {
+            return;
+        };
+        lock.holders.retain(|holder| holder.file != file);
+        lock.waiters.retain(|(waiter, _)| waiter.file != file);
+        lock.grant();
+        self.remove_unused(key);
+    }
+
+    fn remove_unused(&mut self, key: LockKey) {
+        if self
+            .locks
+            .get(&key)
+            .is_some_and(|lock| lock.holders.is_empty() && lock.waiters.is_empty())
+        {
+            self.locks.remove(&key);
+        }
+    }
+
+    fn is_waiting(&self, pid: u64) -> bool {
+        self.locks
+            .values()
+            .any(|lock| lock.waiters.iter().any(|(waiter, _)| waiter.pid == pid))
+    }
+}
+
+/// Does the `SYS_FLOCK` `operation` for the `File` of `owner`, opened from `path`
+pub(super) fn flock(
+    key: LockKey,
+    path: &str,
+    owner: LockOwner,
+    operation: FlockOperation,
+) -> Result<LockState, FileSystemError> {
+    let mut locks = LOCKS.lock();
+    match operation {
+        FlockOperation::Lock { exclusive, block } => locks.lock(key, path, owner, exclusive, block),
+        FlockOperation::Unlock => {
+            locks.unlock(key, owner.file);
+            Ok(LockState::Granted)
+        }
+    }
+}
+
+/// Releases what the `File` `file` holds or waits for, when its closed
+pub(super) fn release(key: LockKey, file: u64) {
+    LOCKS.lock().unlock(key, file);
+}
+
+/// Whether the process `pid` is still waiting for a lock, the scheduler resumes it when not
+pub fn is_waiting(pid: u64) -> bool {
+    LOCKS.lock().is_waiting(pid)
+}
+
+/// `/devices/file_locks`, a line for every locked file, its kind and the `pid(name)/fd` holding
+/// it, then the ones waiting in order
+#[derive(Debug)]
+struct FileLocksInfo;
+
+impl Device for FileLocksInfo {
+    fn name(&self) -> &str {
+        "file_locks"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        let locks = LOCKS.lock();
+        let lines = locks.locks.values().map(ToString::to_string).collect();
+        Some(Box::new(FileLocksSnapshot(lines)))
+    }
+}
+
+/// The field of the cursor is the line
+#[derive(Debug)]
+struct FileLocksSnapshot(Vec<String>);
+
+impl fmt::Display for FileLocksSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.0 {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Generator for FileLocksSnapshot {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        generated::render_display_lines(&*self, cursor, out)
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(FileLocksInfo));
+}
+
+/// The table alone with made up files, which processes are waiting and who gets the lock next
+pub(super) fn run_self_tests() {
+    use FileSystemError::*;
+    use LockState::*;
+
+    let mut table = LockTable::new();
+    let key = (1, 2);
+    let owner = |file| LockOwner {
+        file,
+        pid: file * 10,
+        name: ProcessName::new("test").unwrap(),
+        fd: 3,
+    };
+    let holders = |table: &LockTable| {
+        table.locks.get(&key).map_or(Vec::new(), |lock| {
+            lock.holders.iter().map(|h| h.file).collect::<Vec<_>>()
+        })
+    };
+
+    // the readers share it, a writer waits for all of them
+    assert!(matches!(
+        table.lock(key, "f", owner(1), false, true),
+        Ok(Granted)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(2), false, false),
+        Ok(Granted)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(3), true, false),
+        Err(WouldBlock)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(3), true, true),
+        Ok(Waiting)
+    ));
+    assert!(table.is_waiting(30));
+    // a reader doesn't pass the writer waiting
+    assert!(matches!(
+        table.lock(key, "f", owner(4), false, false),
+        Err(WouldBlock)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(4), false, true),
+        Ok(Waiting)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(5), false, true),
+        Ok(Waiting)
+    ));
+    table.unlock(key, 1);
+    assert!(table.is_waiting(30));
+    table.unlock(key, 2);
+    assert!(!table.is_waiting(30));
+    assert_eq!(holders(&table), [3]);
+    // then both readers behind it together
+    table.unlock(key, 3);
+    assert_eq!(holders(&table), [4, 5]);
+    assert!(!table.is_waiting(40) && !table.is_waiting(50));
+
+    // taking it again is nothing, converting lets the waiter in first
+    assert!(matches!(
+        table.lock(key, "f", owner(4), false, true),
+        Ok(Granted)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(6), true, true),
+        Ok(Waiting)
+    ));
+    table.unlock(key, 5);
+    assert!(matches!(
+        table.lock(key, "f", owner(4), true, true),
+        Ok(Waiting)
+    ));
+    assert_eq!(holders(&table), [6]);
+    // closing a waiter drops it from the queue, the last one removes the lock
+    table.unlock(key, 4);
+    assert!(!table.is_waiting(40));
+    table.unlock(key, 6);
+    assert!(table.locks.is_empty());
+    // failing to convert without waiting loses the old lock, and leaves nothing behind
+    assert!(matches!(
+        table.lock(key, "f", owner(7), false, true),
+        Ok(Granted)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(8), false, true),
+        Ok(Granted)
+    ));
+    assert!(matches!(
+        table.lock(key, "f", owner(7), true, false),
+        Err(WouldBlock)
+    ));
+    assert_eq!(holders(&table), [8]);
+    table.unlock(key, 8);
+    assert!(table.locks.is_empty());
+}
@@ -1,3 +1,5 @@
+use kernel_core::sector::Lba;
+
 use crate::io::NoDebug;
 
 #[repr(C, packed)]
@@ -15,6 +17,13 @@ pub struct PartitionEntry {
     pub size_in_sectors: u32,
 }
 
+impl PartitionEntry {
+    /// The first sector of the partition on the device
+    pub fn first_sector(&self) -> Lba {
+        Lba(self.start_lba as u64)
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug)]
 pub struct MbrRaw {
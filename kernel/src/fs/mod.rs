@@ -1,26 +1,74 @@
-use core::{mem, ops};
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 
-use alloc::{borrow::Cow, string::String, sync::Arc, vec, vec::Vec};
-use kernel_user_link::file::BlockingMode;
+use alloc::{
+    borrow::Cow,
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use kernel_core::{path, sector::Lba};
+use kernel_user_link::{
+    file::{BlockingMode, DirEntryKind, FileStat, FlockOperation},
+    watch::{
+        WATCH_CREATE, WATCH_DELETE, WATCH_IS_DIR, WATCH_MODIFY, WATCH_MOVED_FROM, WATCH_MOVED_TO,
+    },
+};
 
 use crate::{
     devices::{
-        ide::{self, IdeDeviceIndex, IdeDeviceType},
+        self,
+        block::{
+            self, BlockDevice, BlockDeviceFile, SelftestCacheDisk, StorageError, StorageErrorKind,
+        },
+        clock,
+        generated::GeneratedFile,
+        ramdisk::RamDisk,
         Device, DEVICES_FILESYSTEM_CLUSTER_MAGIC,
     },
-    memory_management::memory_layout::align_up,
+    memory_management::{memory_layout::PAGE_4K, shm},
+    process::ProcessName,
     sync::{once::OnceLock, spin::mutex::Mutex},
 };
 
-use self::mbr::MbrRaw;
+pub use self::mounts::mount_block_device;
+use self::ramfs::RamFileSystem;
 
 mod fat;
-mod mbr;
+pub mod initrd;
+pub mod locks;
+pub mod mbr;
+pub mod mounts;
+pub mod page_cache;
+pub mod ramfs;
+pub mod walk;
+pub mod watch;
+
+use walk::{Walker, DEFAULT_MAX_DEPTH};
 
 static FILESYSTEM_MAPPING: Mutex<FileSystemMapping> = Mutex::new(FileSystemMapping {
     mappings: Vec::new(),
+    detached: Vec::new(),
 });
 
+/// The `File`s open for each inode, by the address of the filesystem and the inode id, see
+/// [`FileSystem::open_unlink`]
+static OPEN_FILES: Mutex<BTreeMap<(usize, u64), OpenInode>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct OpenInode {
+    count: usize,
+    /// The start cluster and the size after the last [`truncate`] or [`copy_file_range`],
+    /// the `File`s opened before take them before using their inode, see
+    /// [`File::sync_resized`]
+    resized: Option<(u32, u32)>,
+}
+
 static EMPTY_FILESYSTEM: OnceLock<Arc<EmptyFileSystem>> = OnceLock::new();
 
 pub fn empty_filesystem() -> Arc<EmptyFileSystem> {
@@ -121,6 +169,8 @@ pub struct INode {
     start_cluster: u32,
     size: u32,
     device: Option<Arc<dyn Device>>,
+    // set by the filesystem, see [`FileSystem::inode_id`]
+    id: u64,
 }
 
 impl INode {
@@ -136,6 +186,7 @@ impl INode {
             start_cluster,
             size,
             device: None,
+            id: start_cluster as u64,
         }
     }
 
@@ -150,9 +201,23 @@ impl INode {
             start_cluster: DEVICES_FILESYSTEM_CLUSTER_MAGIC,
             size: 0,
             device,
+            id: 0,
         }
     }
 
+    /// Sets the id returned by [`FileSystem::inode_id`], by default its the start cluster
+    /// for files, and `0` for devices
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Sets the size of a device, for the ones whose files have one
+    pub fn with_size(mut self, size: u32) -> Self {
+        self.size = size;
+        self
+    }
+
     pub fn is_dir(&self) -> bool {
         self.attributes.directory
     }
@@ -165,7 +230,6 @@ impl INode {
         &self.name
     }
 
-    #[allow(dead_code)]
     pub fn attributes(&self) -> FileAttributes {
         self.attributes
     }
@@ -187,25 +251,65 @@ impl Drop for INode {
     }
 }
 
+/// What happens when removing a file (or a directory) that is still open,
+/// see [`FileSystem::open_unlink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenUnlink {
+    /// The removal fails with `FileSystemError::Busy`, renaming the file fails as well,
+    /// since the filesystem can't keep the open files pointing to the right place
+    Busy,
+    /// The name is removed right away, but the content stays until the last `File` using it
+    /// is closed, then [`FileSystem::release`] is called
+    Deferred,
+}
+
 pub trait FileSystem: Send + Sync {
     // TODO: don't use Vector please, use an iterator somehow
     fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError>;
     fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError>;
+    /// Mark the filesystem as disconnected, this is done when forcefully unmounting it.
+    /// All operations after this should fail with `FileSystemError::StaleHandle`
+    /// without touching the underlying device.
+    fn disconnect(&self);
+    fn is_disconnected(&self) -> bool;
+    /// A stable identifier of the file, unique inside this filesystem.
+    ///
+    /// Filesystems without inode numbers build it from where the file is stored, and give
+    /// it to the inode with [`INode::with_id`]
+    fn inode_id(&self, inode: &INode) -> u64 {
+        inode.id
+    }
+    /// Fails if `inode` is not the file it was anymore, for filesystems where another file
+    /// can take its place under the same name, i.e. a device registered again
+    fn validate_inode(&self, _inode: &INode) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+    /// A unique id to cache the files of this filesystem in the [`page_cache`] with,
+    /// `None` if the content can change without us knowing, like devices
+    fn cache_id(&self) -> Option<u64> {
+        None
+    }
     fn read_file(
         &self,
         inode: &INode,
-        position: u32,
+        position: u64,
         buf: &mut [u8],
     ) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
         if let Some(device) = inode.device() {
             assert!(inode.start_cluster == DEVICES_FILESYSTEM_CLUSTER_MAGIC);
-            device.read(position, buf)
+            devices::checked_read(device.as_ref(), position, buf)
         } else {
             Err(FileSystemError::ReadNotSupported)
         }
     }
 
-    fn write_file(&self, inode: &INode, position: u32, buf: &[u8]) -> Result<u64, FileSystemError> {
+    fn write_file(&self, inode: &INode, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
         if let Some(device) = inode.device() {
             assert!(inode.start_cluster == DEVICES_FILESYSTEM_CLUSTER_MAGIC);
             device.write(position, buf)
@@ -213,6 +317,92 @@ pub trait FileSystem: Send + Sync {
             Err(FileSystemError::WriteNotSupported)
         }
     }
+    /// Copies `len` bytes of the file `src` to the file `dst`, both in this filesystem and not
+    /// the same file, for [`copy_file_range`].
+    ///
+    /// `None` if there is no faster way than reading and writing through a buffer
+    fn copy_file_range(
+        &self,
+        _src: &INode,
+        _src_position: u64,
+        _dst: &INode,
+        _dst_position: u64,
+        _len: u64,
+    ) -> Option<Result<u64, FileSystemError>> {
+        None
+    }
+
+    /// Changes the size of the file `inode` to `len`, shrinking frees what is after the new
+    /// end, and extending reads as zeros up to it. Returns the inode with the new size, and
+    /// start cluster if the content moved.
+    ///
+    /// Filesystems with a [`FileSystem::cache_id`] must drop the cached pages after the new
+    /// end, otherwise extending it again would show the old content
+    fn truncate(&self, _inode: &INode, _len: u64) -> Result<INode, FileSystemError> {
+        Err(FileSystemError::WriteNotSupported)
+    }
+
+    /// If `true`, entries can't be created, removed or renamed, and all of these fail with
+    /// `FileSystemError::ReadOnlyFileSystem` before reaching the filesystem
+    fn is_read_only(&self) -> bool {
+        true
+    }
+    /// How removing an open file is handled, the files open are tracked by [`File`]
+    fn open_unlink(&self) -> OpenUnlink {
+        OpenUnlink::Busy
+    }
+    /// Called when the last [`File`] of `inode` is closed
+    fn release(&self, _inode: &INode) {}
+    /// Makes everything written to the filesystem stable, data before the metadata pointing
+    /// to it, and flushes the device it is on
+    fn sync(&self) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    // The operations below get the path of the parent directory (ending with `/`) and the
+    // name of the entry. The name is checked not to exist before `create_dir`, `create_file`
+    // and `rename` (with an exact match, the filesystem can still refuse it), and the entry
+    // to be removed or renamed exists and has the right type
+
+    /// Creates the empty directory `name` in `parent`
+    fn create_dir(&self, _parent: &str, _name: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnlyFileSystem)
+    }
+    /// Creates the empty file `name` in `parent`
+    fn create_file(&self, _parent: &str, _name: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::WriteNotSupported)
+    }
+    /// Removes the file `name` from `parent`, `still_open` is only set for
+    /// [`OpenUnlink::Deferred`] filesystems
+    fn remove_file(
+        &self,
+        _parent: &str,
+        _name: &str,
+        _still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnlyFileSystem)
+    }
+    /// Removes the directory `name` from `parent`, it must be empty, otherwise this fails
+    /// with `FileSystemError::DirectoryNotEmpty`
+    fn remove_dir(
+        &self,
+        _parent: &str,
+        _name: &str,
+        _still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnlyFileSystem)
+    }
+    /// Moves the entry `old_name` in `old_parent` to `new_name` in `new_parent`, directories
+    /// are never moved inside themselves
+    fn rename(
+        &self,
+        _old_parent: &str,
+        _old_name: &str,
+        _new_parent: &str,
+        _new_name: &str,
+    ) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnlyFileSystem)
+    }
 }
 
 pub struct EmptyFileSystem;
@@ -225,10 +415,35 @@ impl FileSystem for EmptyFileSystem {
     fn read_dir(&self, _inode: &INode) -> Result<Vec<INode>, FileSystemError> {
         Err(FileSystemError::FileNotFound)
     }
+
+    fn disconnect(&self) {
+        // nothing to disconnect, this is shared and always empty
+    }
+
+    fn is_disconnected(&self) -> bool {
+        false
+    }
+}
+
+/// A filesystem mounted at `path`, and where it came from, listed in `/devices/mounts`
+struct Mount {
+    // ends with `/`
+    path: String,
+    filesystem: Arc<dyn FileSystem>,
+    // the device, or a name for the filesystems without one
+    source: String,
+    fstype: &'static str,
+    read_only: bool,
+    // the uptime when it was mounted
+    time_nanos: u64,
 }
 
 struct FileSystemMapping {
-    mappings: Vec<(String, Arc<dyn FileSystem>)>,
+    // sorted by the length of the path
+    mappings: Vec<Mount>,
+    // the sources of the filesystems unmounted while files were open in them, until the last
+    // of them is closed, see [`unmount`]
+    detached: Vec<(String, Weak<dyn FileSystem>)>,
 }
 
 impl FileSystemMapping {
@@ -236,12 +451,16 @@ impl FileSystemMapping {
         &mut self,
         path: &'p str,
     ) -> Result<(&'p str, Arc<dyn FileSystem>), FileSystemError> {
-        let (prefix, filesystem) = self
+        let Mount {
+            path: prefix,
+            filesystem,
+            ..
+        } = self
             .mappings
             .iter()
             // look from the back for best match
             .rev()
-            .find(|(fs_path, _)| path.starts_with(fs_path))
+            .find(|mount| path.starts_with(&mount.path))
             .ok_or(FileSystemError::FileNotFound)?;
 
         let prefix_len = if prefix.ends_with('/') {
@@ -254,16 +473,66 @@ impl FileSystemMapping {
 
         Ok((path, filesystem.clone()))
     }
+
+    fn find_mount(&self, path: &str) -> Option<usize> {
+        let mut mapping = String::from(path);
+        if !mapping.ends_with('/') {
+            mapping += "/";
+        }
+        self.mappings.iter().position(|mount| mount.path == mapping)
+    }
+
+    fn add_mount(&mut self, mount: Mount) -> Result<(), FileSystemError> {
+        if self.mappings.iter().any(|m| m.path == mount.path) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        self.mappings.push(mount);
+        // must be kept sorted by length, so we can find the best/correct mapping faster
+        self.mappings.sort_by_key(|mount| mount.path.len());
+        Ok(())
+    }
+
+    fn remove_mapping(&mut self, path: &str) -> Result<Arc<dyn FileSystem>, FileSystemError> {
+        let index = self.find_mount(path).ok_or(FileSystemError::FileNotFound)?;
+
+        // `remove` keeps the order, so the mappings are still sorted
+        Ok(self.mappings.remove(index).filesystem)
+    }
+
+    /// Removes the mount at `index`, but remembers its source while its filesystem is still
+    /// used, the filesystem is returned to be dropped outside the lock
+    fn detach(&mut self, index: usize) -> Arc<dyn FileSystem> {
+        let mount = self.mappings.remove(index);
+        self.detached
+            .push((mount.source, Arc::downgrade(&mount.filesystem)));
+        mount.filesystem
+    }
+
+    /// The filesystems of `source` that were unmounted, and still have files open in them
+    fn detached_of(&mut self, source: &str) -> Vec<Arc<dyn FileSystem>> {
+        self.detached
+            .retain(|(_, filesystem)| filesystem.strong_count() > 0);
+        self.detached
+            .iter()
+            .filter(|(detached, _)| detached == source)
+            .filter_map(|(_, filesystem)| filesystem.upgrade())
+            .collect()
+    }
+
+    /// Whether a filesystem of `source` is mounted, or was and is still used
+    fn is_source_busy(&mut self, source: &str) -> bool {
+        self.mappings.iter().any(|mount| mount.source == source)
+            || !self.detached_of(source).is_empty()
+    }
 }
 
 #[derive(Debug)]
 pub enum FileSystemError {
     PartitionTableNotFound,
     DeviceNotFound,
-    DiskReadError {
-        sector: u64,
-        error: ide::IdeError,
-    },
+    /// The device under the filesystem failed, with what the filesystem was doing. For
+    /// flushes, the writes since the last flush may be lost
+    Storage(StorageError),
     FatError(fat::FatError),
     FileNotFound,
     InvalidPath,
@@ -279,75 +548,173 @@ pub enum FileSystemError {
     ReadNotSupported,
     WriteNotSupported,
     EndOfFile,
+    /// The filesystem this file was opened from was forcefully unmounted
+    StaleHandle,
+    /// Direct access to a block device must be in whole sectors, at sector aligned offsets
+    UnalignedAccess,
+    /// Direct access was requested for a file that is not a block device
+    DirectNotSupported,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    /// Renaming between two filesystems, the file must be copied instead
+    CrossDevice,
+    /// The filesystem is mounted read-only, or its device can't be written
+    ReadOnlyFileSystem,
+    /// The file is open, or the path is a mount point
+    Busy,
+    /// The file has the `read_only` attribute
+    PermissionDenied,
+    NoSpace,
+    /// The file can't be copied by [`copy_file_range`], i.e. a device, it must be read and
+    /// written instead
+    CopyNotSupported,
+    /// The device was removed, see [`Device::shutdown`](crate::devices::Device::shutdown)
+    DeviceGone,
+    /// The lock is held by another file, see [`File::flock`]
+    WouldBlock,
+    /// Devices and directories don't have locks
+    LockNotSupported,
 }
 
-pub fn mount(arg: &str, filesystem: Arc<dyn FileSystem>) {
-    let mut mappings = FILESYSTEM_MAPPING.lock();
+impl FileSystemError {
+    /// Adds what was being done to the chain of a storage error, see
+    /// [`kernel_core::error_context`], the other errors are already about the filesystem,
+    /// and are kept as is
+    pub fn context(mut self, what: &'static str, value: Option<u32>) -> Self {
+        if let FileSystemError::Storage(e) = &mut self {
+            e.context.push(what, value);
+        }
+        self
+    }
 
-    assert!(
-        !mappings.mappings.iter().any(|(fs_path, _)| fs_path == arg),
-        "Mounting {} twice",
-        arg
-    );
+    /// What is logged for a storage error of `<op> <target>`, with the whole chain, i.e.
+    /// `open /etc/config: read FAT sector 1234: ide0: read at LBA 1234: timeout`. `None` for
+    /// the other errors, their code is enough
+    pub fn storage_report(&self, op: &str, target: &str) -> Option<String> {
+        match self {
+            FileSystemError::Storage(e) => Some(format!("{op} {target}: {e}")),
+            _ => None,
+        }
+    }
+}
 
-    let base = String::from(arg);
-    let mapping = if arg.ends_with('/') { base } else { base + "/" };
+/// [`FileSystemError::context`] for results
+pub trait ResultContext {
+    fn context(self, what: &'static str, value: Option<u32>) -> Self;
+}
+
+impl<T> ResultContext for Result<T, FileSystemError> {
+    fn context(self, what: &'static str, value: Option<u32>) -> Self {
+        self.map_err(|e| e.context(what, value))
+    }
+}
 
-    mappings.mappings.push((mapping, filesystem));
-    // must be kept sorted by length, so we can find the best/correct mapping faster
-    mappings
-        .mappings
-        .sort_unstable_by(|(a, _), (b, _)| a.len().cmp(&b.len()));
+/// The block layer already gives [`StorageErrorKind::DeviceGone`] as
+/// `FileSystemError::DeviceGone`, this is for the drivers errors passed as is
+impl From<StorageError> for FileSystemError {
+    fn from(e: StorageError) -> Self {
+        match e.kind {
+            StorageErrorKind::DeviceGone => FileSystemError::DeviceGone,
+            StorageErrorKind::Timeout
+            | StorageErrorKind::Media(_)
+            | StorageErrorKind::Device(_)
+            | StorageErrorKind::Unaligned
+            | StorageErrorKind::OutOfRange
+            | StorageErrorKind::NotSupported => FileSystemError::Storage(e),
+        }
+    }
 }
 
-/// Loads the hard disk specified in the argument
-/// it will load the first partition (MBR) if any, otherwise it will treat the whole disk
-/// as one partition
+/// The storage errors with their whole chain, the rest by their name
+impl fmt::Display for FileSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileSystemError::Storage(e) => write!(f, "{e}"),
+            FileSystemError::FatError(e) => write!(f, "FAT: {e:?}"),
+            e => fmt::Debug::fmt(e, f),
+        }
+    }
+}
+
+/// Mounts `filesystem` at `arg`, `source` and `fstype` are what `/devices/mounts` shows for
+/// it, see [`mounts`].
 ///
-/// Creates a new filesystem mapping for `/` and the filesystem found
-pub fn create_disk_mapping(hard_disk_index: usize) -> Result<(), FileSystemError> {
-    let ide_index = IdeDeviceIndex {
-        ty: IdeDeviceType::Ata,
-        index: hard_disk_index,
+/// This is for the mounts of the kernel, it panics if `arg` is already mounted
+pub fn mount(arg: &str, filesystem: Arc<dyn FileSystem>, source: &str, fstype: &'static str) {
+    try_mount(arg, filesystem, source, fstype).unwrap_or_else(|_| panic!("Mounting {arg} twice"));
+}
+
+/// Same as [`mount`], but fails with `FileSystemError::AlreadyExists` instead
+fn try_mount(
+    arg: &str,
+    filesystem: Arc<dyn FileSystem>,
+    source: &str,
+    fstype: &'static str,
+) -> Result<(), FileSystemError> {
+    let base = String::from(arg);
+    let path = if arg.ends_with('/') { base } else { base + "/" };
+
+    FILESYSTEM_MAPPING.lock().add_mount(Mount {
+        path,
+        read_only: filesystem.is_read_only(),
+        filesystem,
+        source: String::from(source),
+        fstype,
+        time_nanos: clock::uptime_nanos(),
+    })
+}
+
+/// Removes the mapping at `arg`, the filesystem is dropped when the last `File` using it is
+/// closed, until then, the opened files can still be used normally, and its source can't be
+/// mounted again
+pub fn unmount(arg: &str) -> Result<(), FileSystemError> {
+    let filesystem = {
+        let mut mappings = FILESYSTEM_MAPPING.lock();
+        let index = mappings
+            .find_mount(arg)
+            .ok_or(FileSystemError::FileNotFound)?;
+        mappings.detach(index)
     };
+    // dropped now if nothing is open in it
+    drop(filesystem);
+    Ok(())
+}
 
-    let device = ide::get_ide_device(ide_index).ok_or(FileSystemError::DeviceNotFound)?;
-
-    let size = align_up(mem::size_of::<MbrRaw>(), device.sector_size() as usize);
-    let mut sectors = vec![0; size];
-
-    device
-        .read_sync(0, &mut sectors)
-        .map_err(|e| FileSystemError::DiskReadError {
-            sector: 0,
-            error: e,
-        })?;
-
-    // SAFETY: This is a valid allocated memory
-    let mbr = unsafe { &*(sectors.as_ptr() as *const MbrRaw) };
-
-    if mbr.is_valid() {
-        // found MBR
-        let first_partition = &mbr.partition_table[0];
-        let filesystem = fat::load_fat_filesystem(
-            ide_index,
-            first_partition.start_lba,
-            first_partition.size_in_sectors,
-        )?;
-        println!(
-            "Mapping / to FAT filesystem {:?} ({:?})",
-            filesystem.volume_label(),
-            filesystem.fat_type()
-        );
-        mount("/", Arc::new(Mutex::new(filesystem)));
+/// Same as [`unmount`], but also disconnects the filesystem, so any `File` still using it
+/// will get `FileSystemError::StaleHandle` instead of accessing the device. The filesystem is
+/// dropped when the last of them is closed.
+///
+/// This is useful for devices that are removed, where we can't use them anymore.
+pub fn force_unmount(arg: &str) -> Result<(), FileSystemError> {
+    let filesystem = FILESYSTEM_MAPPING.lock().remove_mapping(arg)?;
+    filesystem.disconnect();
+    Ok(())
+}
 
-        Ok(())
-    } else {
-        Err(FileSystemError::PartitionTableNotFound)
+/// Makes everything written so far stable, in all the writable filesystems mounted and all
+/// the block devices. All of them are tried, the first error is returned
+pub fn sync() -> Result<(), FileSystemError> {
+    let mut filesystems: Vec<Arc<dyn FileSystem>> = Vec::new();
+    for Mount { filesystem, .. } in FILESYSTEM_MAPPING.lock().mappings.iter() {
+        // the same filesystem can be mounted more than once
+        if !filesystem.is_read_only() && !filesystems.iter().any(|f| Arc::ptr_eq(f, filesystem)) {
+            filesystems.push(filesystem.clone());
+        }
     }
+
+    let mut first_error = None;
+    for filesystem in filesystems {
+        if let Err(e) = filesystem.sync() {
+            first_error.get_or_insert(e);
+        }
+    }
+    // the devices written directly
+    if let Err(e) = block::flush_all() {
+        first_error.get_or_insert(e);
+    }
+    first_error.map_or(Ok(()), Err)
 }
 
-#[allow(dead_code)]
 pub fn ls_dir(path: &str) -> Result<Vec<INode>, FileSystemError> {
     let mut path = Cow::from(path);
 
@@ -367,6 +734,8 @@ pub(crate) fn open_blocking(
     path: &str,
     blocking_mode: BlockingMode,
 ) -> Result<File, FileSystemError> {
+    let path = path::normalize(path).ok_or(FileSystemError::InvalidPath)?;
+    let path = path.as_str();
     let last_slash = path.rfind('/');
 
     let (parent_dir, basename) = match last_slash {
@@ -377,15 +746,27 @@ pub(crate) fn open_blocking(
     let (parent_dir, filesystem) = FILESYSTEM_MAPPING.lock().get_mapping(parent_dir)?;
 
     let filesystem_clone = filesystem.clone();
-    for entry in filesystem.open_dir(parent_dir)? {
+    let entries = filesystem.open_dir(parent_dir)?;
+    if basename.is_empty() {
+        // opening the directory itself, we don't have an inode for it, but it can only be used
+        // through its path, see [`File::dir_walker`]
+        return Ok(File::new(
+            filesystem_clone,
+            String::from(path),
+            INode::new_file(String::new(), FileAttributes::DIRECTORY, 0, 0),
+            0,
+            blocking_mode,
+        ));
+    }
+    for entry in entries {
         if entry.name() == basename {
-            return Ok(File {
-                filesystem: filesystem_clone,
-                path: String::from(path),
-                inode: entry,
-                position: 0,
+            return Ok(File::new(
+                filesystem_clone,
+                String::from(path),
+                entry,
+                0,
                 blocking_mode,
-            });
+            ));
         }
     }
 
@@ -398,14 +779,218 @@ pub(crate) fn inode_to_file(
     position: u64,
     blocking_mode: BlockingMode,
 ) -> File {
-    File {
-        filesystem,
-        // TODO: this is just the filename I think, not the full path
-        path: String::from(inode.name()),
-        inode,
-        position,
-        blocking_mode,
+    // TODO: this is just the filename I think, not the full path
+    let path = String::from(inode.name());
+    File::new(filesystem, path, inode, position, blocking_mode)
+}
+
+/// A path to be created, removed or renamed, resolved to the filesystem it is in
+struct EntryPath {
+    filesystem: Arc<dyn FileSystem>,
+    // the parent directory inside the filesystem, ends with `/`
+    parent: String,
+    name: String,
+    // the full normalized path, without the trailing `/`
+    path: String,
+}
+
+impl EntryPath {
+    fn resolve(path: &str) -> Result<Self, FileSystemError> {
+        let path = path::normalize(path).ok_or(FileSystemError::InvalidPath)?;
+        // `/a/b/` is the same as `/a/b` here
+        let path = path.trim_end_matches('/');
+        // the root has no name
+        let (parent, name) = path.rsplit_once('/').ok_or(FileSystemError::InvalidPath)?;
+
+        let mut mappings = FILESYSTEM_MAPPING.lock();
+        // a mount point is not an entry of the filesystem it appears in
+        if mappings
+            .mappings
+            .iter()
+            .any(|mount| mount.path.trim_end_matches('/') == path)
+        {
+            return Err(FileSystemError::Busy);
+        }
+        let parent = format!("{parent}/");
+        let (parent, filesystem) = mappings.get_mapping(&parent)?;
+
+        Ok(Self {
+            filesystem,
+            parent: String::from(parent),
+            name: String::from(name),
+            path: String::from(path),
+        })
+    }
+
+    fn find(&self) -> Result<Option<INode>, FileSystemError> {
+        Ok(self
+            .filesystem
+            .open_dir(&self.parent)?
+            .into_iter()
+            .find(|entry| entry.name() == self.name))
+    }
+
+    /// Tells the watches of the parent about a change of the entry, see [`watch`]
+    fn notify(&self, mask: u32, cookie: u32) {
+        watch::notify(&self.filesystem, &self.parent, &self.name, mask, cookie);
+    }
+
+    /// Fails if the entry is open and the filesystem doesn't allow changing it,
+    /// returns if it is still open otherwise
+    fn check_open(&self, inode: &INode) -> Result<bool, FileSystemError> {
+        let still_open = open_file_key(&self.filesystem, inode)
+            .is_some_and(|key| OPEN_FILES.lock().contains_key(&key));
+        if still_open && self.filesystem.open_unlink() == OpenUnlink::Busy {
+            return Err(FileSystemError::Busy);
+        }
+        Ok(still_open)
+    }
+}
+
+/// The key in [`OPEN_FILES`], devices and directories opened with a trailing `/` have
+/// no entry, so they are not tracked
+fn open_file_key(filesystem: &Arc<dyn FileSystem>, inode: &INode) -> Option<(usize, u64)> {
+    if inode.device().is_some() || inode.name().is_empty() {
+        return None;
+    }
+    let filesystem_address = Arc::as_ptr(filesystem) as *const () as usize;
+    Some((filesystem_address, filesystem.inode_id(inode)))
+}
+
+pub fn create_dir(path: &str) -> Result<(), FileSystemError> {
+    let entry = EntryPath::resolve(path)?;
+    if entry.find()?.is_some() {
+        return Err(FileSystemError::AlreadyExists);
+    }
+    if entry.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
     }
+    entry.filesystem.create_dir(&entry.parent, &entry.name)?;
+    entry.notify(WATCH_CREATE | WATCH_IS_DIR, 0);
+    Ok(())
+}
+
+/// Creates an empty file at `path`, its parent must exist
+pub fn create_file(path: &str) -> Result<(), FileSystemError> {
+    let entry = EntryPath::resolve(path)?;
+    if entry.find()?.is_some() {
+        return Err(FileSystemError::AlreadyExists);
+    }
+    if entry.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
+    }
+    entry.filesystem.create_file(&entry.parent, &entry.name)?;
+    entry.notify(WATCH_CREATE, 0);
+    Ok(())
+}
+
+/// Removes the file at `path`, if it is still open, this either fails with
+/// `FileSystemError::Busy` or the file is removed when closed, see [`OpenUnlink`]
+pub fn remove_file(path: &str) -> Result<(), FileSystemError> {
+    let entry = EntryPath::resolve(path)?;
+    let inode = entry.find()?.ok_or(FileSystemError::FileNotFound)?;
+    if inode.is_dir() {
+        return Err(FileSystemError::IsDirectory);
+    }
+    if entry.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
+    }
+    let still_open = entry.check_open(&inode)?;
+    entry
+        .filesystem
+        .remove_file(&entry.parent, &entry.name, still_open)?;
+    entry.notify(WATCH_DELETE, 0);
+    Ok(())
+}
+
+/// Removes the empty directory at `path`, same as [`remove_file`] if it is open
+pub fn remove_dir(path: &str) -> Result<(), FileSystemError> {
+    let entry = EntryPath::resolve(path)?;
+    let inode = entry.find()?.ok_or(FileSystemError::FileNotFound)?;
+    if !inode.is_dir() {
+        return Err(FileSystemError::IsNotDirectory);
+    }
+    if entry.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
+    }
+    let still_open = entry.check_open(&inode)?;
+    entry
+        .filesystem
+        .remove_dir(&entry.parent, &entry.name, still_open)?;
+    watch::forget_dir(&entry.filesystem, &inode);
+    entry.notify(WATCH_DELETE | WATCH_IS_DIR, 0);
+    Ok(())
+}
+
+/// Sets the size of the file at `path` to `len`, see [`File::truncate`]
+pub fn truncate(path: &str, len: u64) -> Result<(), FileSystemError> {
+    let entry = EntryPath::resolve(path)?;
+    let inode = entry.find()?.ok_or(FileSystemError::FileNotFound)?;
+    if inode.is_dir() {
+        return Err(FileSystemError::IsDirectory);
+    }
+    if entry.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
+    }
+    let inode = entry.filesystem.truncate(&inode, len)?;
+    set_resized(&entry.filesystem, &inode);
+    entry.notify(WATCH_MODIFY, 0);
+    Ok(())
+}
+
+/// Tells the `File`s open on `inode` about its new size
+fn set_resized(filesystem: &Arc<dyn FileSystem>, inode: &INode) {
+    let Some(key) = open_file_key(filesystem, inode) else {
+        return;
+    };
+    if let Some(open) = OPEN_FILES.lock().get_mut(&key) {
+        open.resized = Some((inode.start_cluster, inode.size));
+    }
+}
+
+/// Moves `old_path` to `new_path`, which must not exist.
+///
+/// Both must be in the same filesystem, otherwise this fails with
+/// `FileSystemError::CrossDevice`, and the file must be copied instead
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FileSystemError> {
+    let old = EntryPath::resolve(old_path)?;
+    let new = EntryPath::resolve(new_path)?;
+    let inode = old.find()?.ok_or(FileSystemError::FileNotFound)?;
+    if !Arc::ptr_eq(&old.filesystem, &new.filesystem) {
+        return Err(FileSystemError::CrossDevice);
+    }
+    if old.path == new.path {
+        return Ok(());
+    }
+    // a directory can't be moved inside itself
+    if new
+        .path
+        .strip_prefix(old.path.as_str())
+        .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return Err(FileSystemError::InvalidPath);
+    }
+    if new.find()?.is_some() {
+        return Err(FileSystemError::AlreadyExists);
+    }
+    if old.filesystem.is_read_only() {
+        return Err(FileSystemError::ReadOnlyFileSystem);
+    }
+    // `Deferred` filesystems keep the same inode when moving it, so only this can fail
+    old.check_open(&inode)?;
+    old.filesystem
+        .rename(&old.parent, &old.name, &new.parent, &new.name)?;
+
+    let dir_mask = if inode.is_dir() { WATCH_IS_DIR } else { 0 };
+    if inode.is_dir() && watch::is_active() {
+        if let Ok(Some(moved)) = new.find() {
+            watch::move_dir(&old.filesystem, &inode, &moved);
+        }
+    }
+    let cookie = watch::next_cookie();
+    old.notify(WATCH_MOVED_FROM | dir_mask, cookie);
+    new.notify(WATCH_MOVED_TO | dir_mask, cookie);
+    Ok(())
 }
 
 pub struct File {
@@ -414,15 +999,68 @@ pub struct File {
     inode: INode,
     position: u64,
     blocking_mode: BlockingMode,
+    // the traversal state if this is a directory, kept between `dir_walker` calls
+    // (recursive, walker)
+    dir_walker: Option<(bool, Walker)>,
+    // reads and writes go to the block device directly, without any cache
+    direct: bool,
+    // where the reads of a generated device are, so the next read continues from there
+    generated: Option<GeneratedFile>,
+    // owns the locks this file takes, see `locks`
+    lock_owner: u64,
 }
 
 impl File {
+    fn new(
+        filesystem: Arc<dyn FileSystem>,
+        path: String,
+        inode: INode,
+        position: u64,
+        blocking_mode: BlockingMode,
+    ) -> Self {
+        // a fresh one for every open, `clone_inherit` included, the locks of a `File` stay its own
+        static NEXT_LOCK_OWNER: AtomicU64 = AtomicU64::new(1);
+
+        if let Some(key) = open_file_key(&filesystem, &inode) {
+            OPEN_FILES.lock().entry(key).or_default().count += 1;
+        }
+        let generated = inode
+            .device()
+            .and_then(|device| device.generator())
+            .map(GeneratedFile::new);
+        Self {
+            filesystem,
+            path,
+            inode,
+            position,
+            blocking_mode,
+            dir_walker: None,
+            direct: false,
+            generated,
+            lock_owner: NEXT_LOCK_OWNER.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> Result<u64, FileSystemError> {
-        let count = match self.blocking_mode {
-            BlockingMode::None => {
-                self.filesystem
-                    .read_file(&self.inode, self.position as u32, buf)?
+        if let Some(device) = self.direct_device() {
+            let count = device.read_direct(self.position, buf)?;
+            self.position += count;
+            return Ok(count);
+        }
+        // has content, so the blocking mode doesn't matter
+        if let Some(generated) = &mut self.generated {
+            if self.filesystem.is_disconnected() {
+                return Err(FileSystemError::StaleHandle);
             }
+            self.filesystem.validate_inode(&self.inode)?;
+            let count = generated.read(self.position, buf);
+            self.position += count;
+            return Ok(count);
+        }
+        self.sync_resized();
+
+        let count = match self.blocking_mode {
+            BlockingMode::None => self.read_content(self.position, buf)?,
             BlockingMode::Line => {
                 // read until \n or \0
                 let mut i = 0;
@@ -430,7 +1068,7 @@ impl File {
                     let mut char_buf = 0;
                     let read_byte = self.filesystem.read_file(
                         &self.inode,
-                        self.position as u32,
+                        self.position,
                         core::slice::from_mut(&mut char_buf),
                     );
 
@@ -467,9 +1105,7 @@ impl File {
 
                 // try to read until we have something
                 loop {
-                    let read_byte =
-                        self.filesystem
-                            .read_file(&self.inode, self.position as u32, buf);
+                    let read_byte = self.filesystem.read_file(&self.inode, self.position, buf);
 
                     let read_byte = match read_byte {
                         Ok(read_byte) => read_byte,
@@ -497,15 +1133,85 @@ impl File {
         Ok(count)
     }
 
+    /// Reads at `position` without waiting, through the page cache if the filesystem has one
+    fn read_content(&self, position: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        match self.filesystem.cache_id() {
+            Some(cache_id) => page_cache::read(
+                self.filesystem.as_ref(),
+                cache_id,
+                &self.inode,
+                position,
+                buf,
+            ),
+            None => self.filesystem.read_file(&self.inode, position, buf),
+        }
+    }
+
+    /// Whether the content can be read at any position with [`Self::read_at`], only regular
+    /// files, not directories, devices or generated files
+    pub fn is_mappable(&self) -> bool {
+        !self.inode.is_dir() && self.inode.device().is_none() && self.generated.is_none()
+    }
+
+    /// Reads at `position` without moving the position of the file, for the pages of the file
+    /// mappings, which must be [`Self::is_mappable`], returns `0` past the end
+    pub fn read_at(&mut self, position: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        if !self.is_mappable() {
+            return Err(FileSystemError::ReadNotSupported);
+        }
+        self.sync_resized();
+        match self.read_content(position, buf) {
+            Err(FileSystemError::EndOfFile) => Ok(0),
+            result => result,
+        }
+    }
+
+    /// The physical address of the cached page at `position` of a [`Self::is_mappable`] file,
+    /// see [`page_cache::map_page`], `None` if the filesystem has no cache or it's past the
+    /// end, then the page is read with [`Self::read_at`]
+    pub fn map_page(&mut self, position: u64) -> Option<u64> {
+        let cache_id = self.filesystem.cache_id()?;
+        if !self.is_mappable() {
+            return None;
+        }
+        self.sync_resized();
+        let page_index = u32::try_from(position / PAGE_4K as u64).ok()?;
+        page_cache::map_page(self.filesystem.as_ref(), cache_id, &self.inode, page_index).ok()
+    }
+
     pub fn write(&mut self, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if let Some(device) = self.direct_device() {
+            let written = device.write_direct(self.position, buf)?;
+            self.position += written;
+            return Ok(written);
+        }
+        self.sync_resized();
+
         let written = self
             .filesystem
-            .write_file(&self.inode, self.position as u32, buf)?;
+            .write_file(&self.inode, self.position, buf)?;
         self.position += written;
+        if self.inode.device().is_none() && self.position > self.inode.size as u64 {
+            self.inode.size = u32::try_from(self.position).unwrap_or(u32::MAX);
+            set_resized(&self.filesystem, &self.inode);
+            self.notify_modified();
+        }
         Ok(written)
     }
 
+    /// Tells the watches of the directory of this file that it changed, by the path it was
+    /// opened with
+    fn notify_modified(&self) {
+        if !watch::is_active() {
+            return;
+        }
+        if let Ok(entry) = EntryPath::resolve(&self.path) {
+            entry.notify(WATCH_MODIFY, 0);
+        }
+    }
+
     pub fn seek(&mut self, position: u64) -> Result<(), FileSystemError> {
+        self.sync_resized();
         if position > self.inode.size() as u64 {
             return Err(FileSystemError::InvalidOffset);
         }
@@ -514,7 +1220,62 @@ impl File {
     }
 
     pub fn filesize(&self) -> u64 {
-        self.inode.size() as u64
+        self.resized().map_or(self.inode.size, |(_, size)| size) as u64
+    }
+
+    /// Sets the size of the file to `len`, reading after the old end gives zeros. The
+    /// positions of this and the other files open on it don't move, reading past the new end
+    /// gives nothing
+    pub fn truncate(&mut self, len: u64) -> Result<(), FileSystemError> {
+        if self.inode.is_dir() {
+            return Err(FileSystemError::IsDirectory);
+        }
+        if self.inode.device().is_some() {
+            return Err(FileSystemError::WriteNotSupported);
+        }
+        if self.filesystem.is_read_only() {
+            return Err(FileSystemError::ReadOnlyFileSystem);
+        }
+        self.sync_resized();
+        let inode = self.filesystem.truncate(&self.inode, len)?;
+        set_resized(&self.filesystem, &inode);
+        self.inode = inode;
+        self.notify_modified();
+        Ok(())
+    }
+
+    /// The start cluster and size given by a [`truncate`] of the file, maybe through
+    /// another `File`
+    fn resized(&self) -> Option<(u32, u32)> {
+        let key = open_file_key(&self.filesystem, &self.inode)?;
+        OPEN_FILES.lock().get(&key)?.resized
+    }
+
+    /// Takes the new size if the file was truncated since it was opened, the old start
+    /// cluster may be free by now
+    fn sync_resized(&mut self) {
+        if let Some((start_cluster, size)) = self.resized() {
+            self.inode.start_cluster = start_cluster;
+            self.inode.size = size;
+        }
+    }
+
+    /// Fails with `FileSystemError::DeviceGone` for a device that was removed
+    pub fn stat(&self) -> Result<FileStat, FileSystemError> {
+        self.filesystem.validate_inode(&self.inode)?;
+        let kind = if self.inode.is_dir() {
+            DirEntryKind::Directory
+        } else if self.inode.device().is_some() {
+            DirEntryKind::Device
+        } else {
+            DirEntryKind::File
+        };
+        Ok(FileStat {
+            inode_id: self.filesystem.inode_id(&self.inode),
+            size: self.filesize(),
+            kind,
+            _pad: [0; 7],
+        })
     }
 
     pub fn path(&self) -> &str {
@@ -522,6 +1283,7 @@ impl File {
     }
 
     pub fn read_to_end(&mut self) -> Result<Vec<u8>, FileSystemError> {
+        self.sync_resized();
         let mut buf = vec![0; self.inode.size() as usize];
         let mut position = 0;
         loop {
@@ -539,6 +1301,12 @@ impl File {
         String::from_utf8(buf).map_err(|_| FileSystemError::InvalidData)
     }
 
+    /// Whether both are the same file of the same filesystem, even if opened separately
+    pub fn is_same_file(&self, other: &File) -> bool {
+        let key = open_file_key(&self.filesystem, &self.inode);
+        key.is_some() && key == open_file_key(&other.filesystem, &other.inode)
+    }
+
     pub fn is_blocking(&self) -> bool {
         self.blocking_mode != BlockingMode::None
     }
@@ -547,16 +1315,139 @@ impl File {
         self.blocking_mode = blocking_mode;
     }
 
+    /// Makes reads and writes go to the block device directly, they must be in whole
+    /// sectors from now on (and the current position must be sector aligned).
+    ///
+    /// This is the only way to write to a block device.
+    pub fn set_direct(&mut self) -> Result<(), FileSystemError> {
+        if self.block_device().is_none() {
+            return Err(FileSystemError::DirectNotSupported);
+        }
+        self.direct = true;
+        Ok(())
+    }
+
+    /// Makes the content of this file stable, the filesystems keep no file data to write
+    /// later, so this syncs the whole filesystem (or flushes the whole block device)
+    pub fn sync(&self) -> Result<(), FileSystemError> {
+        if let Some(device) = self.block_device() {
+            return device.flush();
+        }
+        if self.filesystem.is_read_only() {
+            return Ok(());
+        }
+        self.filesystem.sync()
+    }
+
+    fn block_device(&self) -> Option<&BlockDeviceFile> {
+        self.inode
+            .device()
+            .and_then(|device| device.as_block_device())
+    }
+
+    /// The watch if this file was made by [`watch::create`]
+    pub fn watch(&self) -> Option<&Arc<watch::Watch>> {
+        self.inode.device().and_then(|device| device.as_watch())
+    }
+
+    /// The index of the terminal if this file is one of the console, see [`Device::as_terminal`]
+    pub fn terminal(&self) -> Option<usize> {
+        self.inode.device().and_then(|device| device.as_terminal())
+    }
+
+    /// The shared memory object if this file was made by [`shm::create`]
+    pub fn shared_memory(&self) -> Option<&Arc<shm::SharedMemory>> {
+        self.inode
+            .device()
+            .and_then(|device| device.as_shared_memory())
+    }
+
+    fn direct_device(&self) -> Option<&BlockDeviceFile> {
+        if self.direct {
+            self.block_device()
+        } else {
+            None
+        }
+    }
+
+    /// Get a walker over the entries of this directory, continuing from `position`,
+    /// which is the number of entries already consumed.
+    ///
+    /// The walker is kept in the file, so continuing from where the last call stopped
+    /// doesn't need to walk the tree again.
+    pub fn dir_walker(
+        &mut self,
+        recursive: bool,
+        position: u64,
+    ) -> Result<&mut Walker, FileSystemError> {
+        if !self.inode.is_dir() {
+            return Err(FileSystemError::IsNotDirectory);
+        }
+
+        let reusable = matches!(
+            &self.dir_walker,
+            Some((walker_recursive, walker))
+                if *walker_recursive == recursive && walker.position() == position
+        );
+        if !reusable {
+            let mut path = Cow::from(self.path.as_str());
+            if !path.ends_with('/') {
+                path += "/";
+            }
+            let (new_path, filesystem) = FILESYSTEM_MAPPING.lock().get_mapping(&path)?;
+            let max_depth = if recursive { DEFAULT_MAX_DEPTH } else { 0 };
+            let mut walker = Walker::new(filesystem, new_path, max_depth)?;
+            // skip what we have already returned
+            for _ in 0..position {
+                if walker.next().transpose()?.is_none() {
+                    break;
+                }
+            }
+            self.dir_walker = Some((recursive, walker));
+        }
+
+        Ok(&mut self.dir_walker.as_mut().unwrap().1)
+    }
+
+    /// Takes or releases the lock of the file, for the process `pid` named `name` that has it
+    /// as `fd`.
+    ///
+    /// A lock on a file another file holds waits for it, unless `operation` doesn't block,
+    /// which fails with [`FileSystemError::WouldBlock`]. If it has to wait, this returns
+    /// [`locks::LockState::Waiting`], and the caller parks until [`locks::is_waiting`] is
+    /// over
+    pub fn flock(
+        &self,
+        pid: u64,
+        name: ProcessName,
+        fd: usize,
+        operation: FlockOperation,
+    ) -> Result<locks::LockState, FileSystemError> {
+        let key = open_file_key(&self.filesystem, &self.inode)
+            .ok_or(FileSystemError::LockNotSupported)?;
+        if self.inode.is_dir() {
+            return Err(FileSystemError::LockNotSupported);
+        }
+        let owner = locks::LockOwner {
+            file: self.lock_owner,
+            pid,
+            name,
+            fd,
+        };
+        locks::flock(key, &self.path, owner, operation)
+    }
+
     /// This is a move verbose method than `Clone::clone`, as I want it to be
     /// more explicit to the user that this is not a normal `clone` operation.
     pub fn clone_inherit(&self) -> Self {
-        let s = Self {
-            filesystem: self.filesystem.clone(),
-            path: self.path.clone(),
-            inode: self.inode.clone(),
-            position: 0,
-            blocking_mode: self.blocking_mode,
-        };
+        let mut s = Self::new(
+            self.filesystem.clone(),
+            self.path.clone(),
+            self.inode.clone(),
+            0,
+            self.blocking_mode,
+        );
+        s.direct = self.direct;
 
         // inform the device of a clone operation
         s.inode.device.as_ref().map(|device| {
@@ -569,3 +1460,855 @@ impl File {
         s
     }
 }
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let Some(key) = open_file_key(&self.filesystem, &self.inode) else {
+            return;
+        };
+        locks::release(key, self.lock_owner);
+        let last = {
+            let mut open_files = OPEN_FILES.lock();
+            let open = open_files
+                .get_mut(&key)
+                .expect("open file is not in the open files");
+            open.count -= 1;
+            open.count == 0 && open_files.remove(&key).is_some()
+        };
+        if last {
+            self.filesystem.release(&self.inode);
+        }
+    }
+}
+
+/// The buffer [`copy_file_range`] copies through when the filesystem can't do it itself
+const COPY_BUFFER_SIZE: usize = PAGE_4K * 4;
+
+/// Copies up to `len` bytes from `src` to `dst` without the data leaving the kernel, at
+/// `src_position` and `dst_position`, or at the positions of the files (advancing them)
+/// for `None`.
+///
+/// Both must be regular files in non-blocking mode, and not the same file, otherwise this
+/// fails with `FileSystemError::CopyNotSupported` and they must be read and written instead.
+/// `dst` grows as needed, but can't be written past its end, so no holes are left.
+///
+/// Returns the number of bytes copied, less than `len` at the end of `src`, or if an
+/// error happened after some were copied
+pub fn copy_file_range(
+    src: &mut File,
+    src_position: Option<u64>,
+    dst: &mut File,
+    dst_position: Option<u64>,
+    len: u64,
+) -> Result<u64, FileSystemError> {
+    if src.inode.is_dir() || dst.inode.is_dir() {
+        return Err(FileSystemError::IsDirectory);
+    }
+    src.sync_resized();
+    dst.sync_resized();
+    let copyable = |file: &File| file.inode.device().is_none() && !file.is_blocking();
+    if !copyable(src) || !copyable(dst) || src.is_same_file(dst) {
+        return Err(FileSystemError::CopyNotSupported);
+    }
+    let src_start = src_position.unwrap_or(src.position);
+    let dst_start = dst_position.unwrap_or(dst.position);
+    if dst_start > dst.filesize() {
+        return Err(FileSystemError::InvalidOffset);
+    }
+
+    let same_filesystem =
+        Arc::as_ptr(&src.filesystem) as *const () == Arc::as_ptr(&dst.filesystem) as *const ();
+    let fast_copy = same_filesystem
+        .then(|| {
+            src.filesystem
+                .copy_file_range(&src.inode, src_start, &dst.inode, dst_start, len)
+        })
+        .flatten();
+    let copied = match fast_copy {
+        Some(result) => result?,
+        None => copy_through_buffer(src, src_start, dst, dst_start, len)?,
+    };
+
+    if src_position.is_none() {
+        src.position += copied;
+    }
+    if dst_position.is_none() {
+        dst.position += copied;
+    }
+    // so the next copy can continue from the new end
+    let dst_end = u32::try_from(dst_start + copied).unwrap_or(u32::MAX);
+    if dst_end > dst.inode.size {
+        dst.inode.size = dst_end;
+        dst.notify_modified();
+    }
+    set_resized(&dst.filesystem, &dst.inode);
+    Ok(copied)
+}
+
+fn copy_through_buffer(
+    src: &File,
+    mut src_position: u64,
+    dst: &File,
+    mut dst_position: u64,
+    len: u64,
+) -> Result<u64, FileSystemError> {
+    let mut buf = vec![0; len.min(COPY_BUFFER_SIZE as u64) as usize];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(buf.len() as u64) as usize;
+        let result = src
+            .read_content(src_position, &mut buf[..chunk])
+            .and_then(|read| {
+                let written = match read {
+                    0 => 0,
+                    _ => dst.filesystem.write_file(
+                        &dst.inode,
+                        dst_position,
+                        &buf[..read as usize],
+                    )?,
+                };
+                Ok((read, written))
+            });
+        let (read, written) = match result {
+            Ok(counts) => counts,
+            // report what was copied before the error
+            Err(_) if copied != 0 => break,
+            Err(e) => return Err(e),
+        };
+        copied += written;
+        src_position += written;
+        dst_position += written;
+        if read == 0 || written < read {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+const SELFTEST_FAT: &str = "/selftest_fat";
+const SELFTEST_FAT_RO: &str = "/selftest_fat_ro";
+const SELFTEST_RAMFS: &str = "/selftest_ramfs";
+const SELFTEST_STALE: &str = "/selftest_stale";
+const SELFTEST_DEFERRED: &str = "/selftest_deferred";
+const SELFTEST_SYNC: &str = "/selftest_sync";
+const SELFTEST_TRUNCATE: &str = "/selftest_truncate";
+const SELFTEST_WRITE: &str = "/selftest_write";
+const SELFTEST_PARTITION: &str = "/selftest_partition";
+const SELFTEST_WATCH: &str = "/selftest_watch";
+const SELFTEST_WATCH_FAT: &str = "/selftest_watch_fat";
+
+static SELFTEST_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+/// A ramfs that counts its drops in [`SELFTEST_DROPS`]
+struct SelftestDropCounted(RamFileSystem);
+
+impl Drop for SelftestDropCounted {
+    fn drop(&mut self) {
+        SELFTEST_DROPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl FileSystem for SelftestDropCounted {
+    fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        self.0.open_dir(path)
+    }
+
+    fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        self.0.read_dir(inode)
+    }
+
+    fn disconnect(&self) {
+        self.0.disconnect()
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.0.is_disconnected()
+    }
+
+    fn inode_id(&self, inode: &INode) -> u64 {
+        self.0.inode_id(inode)
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        self.0.read_file(inode, position, buf)
+    }
+}
+
+/// A FAT12 ramdisk with `HELLO.TXT` and the read-only `LOCKED.TXT` in the root
+fn selftest_fat_device(name: &str, writable: bool) -> Arc<BlockDeviceFile> {
+    let mut image = block::selftest_fat12_image(b"hello");
+    let locked = &mut image[2 * block::SELFTEST_SECTOR_SIZE + 32..][..32];
+    locked[..11].copy_from_slice(b"LOCKED  TXT");
+    locked[11] = 0x21; // read only, archive
+
+    let disk = RamDisk::new((block::SELFTEST_SECTORS, block::SELFTEST_SECTOR_SIZE as u32));
+    disk.write_sectors(Lba(0), &image).unwrap();
+    BlockDeviceFile::register(name.into(), Arc::new(disk), writable)
+}
+
+/// Checks the FAT in `device` after a test changed it, any violation fails the test
+fn selftest_fat_check(device: &Arc<BlockDeviceFile>, start_lba: Lba) -> fat::FatCheck {
+    let report = fat::check(device.clone(), start_lba, block::SELFTEST_SECTORS as u32).unwrap();
+    assert!(
+        report.violations.is_empty(),
+        "FAT check of {}:\n{report}",
+        device.name()
+    );
+    report
+}
+
+fn selftest_names(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = ls_dir(path)
+        .unwrap()
+        .iter()
+        .map(|inode| String::from(inode.name()))
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Every error of creating, removing and renaming, and that the changes to FAT are
+/// really on the device, on a FAT ramdisk (writable and read-only), and a ramfs
+pub fn run_self_tests() {
+    use FileSystemError::*;
+
+    println!("Running filesystem operations self tests...");
+    let device = selftest_fat_device("selftest_fs_ram", true);
+    mount_block_device(SELFTEST_FAT, device.clone()).unwrap();
+    mount_block_device(
+        SELFTEST_FAT_RO,
+        selftest_fat_device("selftest_fs_ram_ro", false),
+    )
+    .unwrap();
+    let ramfs = Arc::new(RamFileSystem::new());
+    ramfs
+        .create_file("/", "open.txt", b"still here".to_vec())
+        .unwrap();
+    mount(SELFTEST_RAMFS, ramfs.clone(), "ramfs", "ramfs");
+
+    let fat = |path: &str| format!("{SELFTEST_FAT}/{path}");
+    let ram = |path: &str| format!("{SELFTEST_RAMFS}/{path}");
+    let read = |path: &str| open(path).and_then(|mut file| file.read_to_end());
+
+    // creating, short and long names, the names are not case sensitive
+    create_dir(&fat("DIR")).unwrap();
+    assert!(matches!(create_dir(&fat("DIR")), Err(AlreadyExists)));
+    assert!(matches!(create_dir(&fat("dir")), Err(AlreadyExists)));
+    assert!(matches!(create_dir(&fat("NOPE/DIR")), Err(FileNotFound)));
+    assert!(matches!(
+        create_dir(&fat("HELLO.TXT/DIR")),
+        Err(IsNotDirectory)
+    ));
+    assert!(matches!(create_dir(&fat("a:b")), Err(InvalidPath)));
+    create_dir(&fat("DIR/A long directory name")).unwrap();
+    // more than a cluster of entries
+    for i in 0..20 {
+        create_dir(&fat(&format!("DIR/sub{i}"))).unwrap();
+    }
+    let names = selftest_names(&fat("DIR/"));
+    assert_eq!(names.len(), 21);
+    assert!(names.iter().any(|name| name == "A long directory name"));
+    assert!(names.iter().any(|name| name == "sub19"));
+
+    // the FAT12/16 root can't grow
+    let mut created = 0;
+    let result = loop {
+        match create_dir(&fat(&format!("R{created}"))) {
+            Ok(()) => created += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(result, NoSpace));
+    // 16 entries, `HELLO.TXT`, `LOCKED.TXT` and `DIR` were there
+    assert_eq!(created, 13);
+    for i in 0..created {
+        remove_dir(&fat(&format!("R{i}"))).unwrap();
+    }
+
+    // removing
+    assert!(matches!(remove_dir(&fat("DIR")), Err(DirectoryNotEmpty)));
+    assert!(matches!(remove_file(&fat("DIR")), Err(IsDirectory)));
+    assert!(matches!(remove_dir(&fat("HELLO.TXT")), Err(IsNotDirectory)));
+    assert!(matches!(remove_file(&fat("NOPE")), Err(FileNotFound)));
+    assert!(matches!(
+        remove_file(&fat("LOCKED.TXT")),
+        Err(PermissionDenied)
+    ));
+    for i in 0..20 {
+        remove_dir(&fat(&format!("DIR/sub{i}"))).unwrap();
+    }
+    assert_eq!(selftest_names(&fat("DIR/")), ["A long directory name"]);
+
+    // renaming
+    assert!(matches!(
+        rename(&fat("NOPE"), &fat("NEW")),
+        Err(FileNotFound)
+    ));
+    assert!(matches!(
+        rename(&fat("HELLO.TXT"), &fat("DIR")),
+        Err(AlreadyExists)
+    ));
+    assert!(matches!(
+        rename(&fat("HELLO.TXT"), &ram("HELLO.TXT")),
+        Err(CrossDevice)
+    ));
+    assert!(matches!(
+        rename(&fat("DIR"), &fat("DIR/A long directory name/DIR")),
+        Err(InvalidPath)
+    ));
+    assert!(matches!(
+        rename(&fat("LOCKED.TXT"), &fat("UNLOCKED.TXT")),
+        Err(PermissionDenied)
+    ));
+    // only the case changes
+    rename(&fat("HELLO.TXT"), &fat("hello.txt")).unwrap();
+    rename(&fat("hello.txt"), &fat("DIR/moved hello.txt")).unwrap();
+    rename(&fat("DIR/A long directory name"), &fat("MOVED")).unwrap();
+    assert_eq!(read(&fat("DIR/moved hello.txt")).unwrap(), b"hello");
+
+    // open files can't be changed in FAT
+    let file = open(&fat("DIR/moved hello.txt")).unwrap();
+    assert!(matches!(
+        remove_file(&fat("DIR/moved hello.txt")),
+        Err(Busy)
+    ));
+    assert!(matches!(
+        rename(&fat("DIR/moved hello.txt"), &fat("HELLO.TXT")),
+        Err(Busy)
+    ));
+    drop(file);
+    remove_file(&fat("DIR/moved hello.txt")).unwrap();
+    assert!(matches!(
+        read(&fat("DIR/moved hello.txt")),
+        Err(FileNotFound)
+    ));
+    remove_dir(&fat("DIR")).unwrap();
+
+    // all of it is on the device, and the clusters are free again
+    force_unmount(SELFTEST_FAT).unwrap();
+    selftest_fat_check(&device, Lba(0));
+    mount_block_device(SELFTEST_FAT, device.clone()).unwrap();
+    assert_eq!(selftest_names(&fat("")), ["LOCKED.TXT", "MOVED"]);
+    for i in 0..100 {
+        create_dir(&fat(&format!("MOVED/{i}"))).unwrap();
+    }
+    force_unmount(SELFTEST_FAT).unwrap();
+    selftest_fat_check(&device, Lba(0));
+
+    // read-only
+    let ro = |path: &str| format!("{SELFTEST_FAT_RO}/{path}");
+    assert!(matches!(create_dir(&ro("DIR")), Err(ReadOnlyFileSystem)));
+    assert!(matches!(
+        remove_file(&ro("HELLO.TXT")),
+        Err(ReadOnlyFileSystem)
+    ));
+    assert!(matches!(
+        rename(&ro("HELLO.TXT"), &ro("NEW.TXT")),
+        Err(ReadOnlyFileSystem)
+    ));
+    assert!(matches!(
+        remove_file("/devices/page_cache"),
+        Err(ReadOnlyFileSystem)
+    ));
+    // these are not entries of any filesystem
+    assert!(matches!(remove_dir(SELFTEST_RAMFS), Err(Busy)));
+    assert!(matches!(rename(SELFTEST_RAMFS, &ram("x")), Err(Busy)));
+    assert!(matches!(remove_dir("/"), Err(InvalidPath)));
+
+    // copying, from FAT through the page cache, and inside ramfs directly
+    assert!(matches!(create_file(&ro("COPY")), Err(ReadOnlyFileSystem)));
+    create_file(&ram("copy")).unwrap();
+    assert!(matches!(create_file(&ram("copy")), Err(AlreadyExists)));
+    let mut hello = open(&ro("HELLO.TXT")).unwrap();
+    let mut copy = open(&ram("copy")).unwrap();
+    assert_eq!(
+        copy_file_range(&mut hello, None, &mut copy, None, 2).unwrap(),
+        2
+    );
+    assert_eq!(
+        copy_file_range(&mut hello, None, &mut copy, None, 100).unwrap(),
+        3
+    );
+    assert_eq!(
+        copy_file_range(&mut hello, None, &mut copy, None, 100).unwrap(),
+        0
+    );
+    // the offsets don't move the positions, and the destination grows
+    assert_eq!(
+        copy_file_range(&mut hello, Some(1), &mut copy, Some(5), 2).unwrap(),
+        2
+    );
+    assert!(matches!(
+        copy_file_range(&mut hello, Some(0), &mut copy, Some(8), 1),
+        Err(InvalidOffset)
+    ));
+    create_file(&ram("copy2")).unwrap();
+    let mut copy2 = open(&ram("copy2")).unwrap();
+    assert_eq!(
+        copy_file_range(&mut copy, Some(0), &mut copy2, None, 100).unwrap(),
+        7
+    );
+    let mut copy_again = open(&ram("copy")).unwrap();
+    assert!(matches!(
+        copy_file_range(&mut copy, Some(0), &mut copy_again, None, 1),
+        Err(CopyNotSupported)
+    ));
+    let mut device = open("/devices/page_cache").unwrap();
+    assert!(matches!(
+        copy_file_range(&mut device, None, &mut copy2, None, 1),
+        Err(CopyNotSupported)
+    ));
+    drop((hello, copy, copy2, copy_again, device));
+    assert_eq!(read(&ram("copy")).unwrap(), b"helloel");
+    assert_eq!(read(&ram("copy2")).unwrap(), b"helloel");
+    remove_file(&ram("copy")).unwrap();
+    remove_file(&ram("copy2")).unwrap();
+    force_unmount(SELFTEST_FAT_RO).unwrap();
+
+    // ramfs removes open files when they are closed
+    let mut file = open(&ram("open.txt")).unwrap();
+    remove_file(&ram("open.txt")).unwrap();
+    assert!(matches!(read(&ram("open.txt")), Err(FileNotFound)));
+    assert_eq!(file.read_to_end().unwrap(), b"still here");
+    // the name can be used again
+    ramfs.create_file("/", "open.txt", b"new".to_vec()).unwrap();
+    assert_eq!(ramfs.nodes_count(), 3);
+    drop(file);
+    assert_eq!(ramfs.nodes_count(), 2);
+    assert_eq!(read(&ram("open.txt")).unwrap(), b"new");
+
+    create_dir(&ram("d")).unwrap();
+    create_dir(&ram("d/e")).unwrap();
+    assert!(matches!(remove_dir(&ram("d")), Err(DirectoryNotEmpty)));
+    assert!(matches!(rename(&ram("d"), &ram("d/e/d")), Err(InvalidPath)));
+    assert!(matches!(
+        rename(&ram("d/e"), &ram("open.txt")),
+        Err(AlreadyExists)
+    ));
+    rename(&ram("d/e"), &ram("e")).unwrap();
+    remove_dir(&ram("d")).unwrap();
+    assert_eq!(selftest_names(&ram("")), ["e", "open.txt"]);
+    force_unmount(SELFTEST_RAMFS).unwrap();
+
+    // the files still open keep the filesystem, usable, until the last of them is closed
+    let counted = Arc::new(SelftestDropCounted(RamFileSystem::new()));
+    counted
+        .0
+        .create_file("/", "kept.txt", b"kept".to_vec())
+        .unwrap();
+    let drops = || SELFTEST_DROPS.load(Ordering::Relaxed);
+    let drops_before = drops();
+    mount(SELFTEST_DEFERRED, counted, "deferred", "ramfs");
+    let kept = format!("{SELFTEST_DEFERRED}/kept.txt");
+    let mut first = open(&kept).unwrap();
+    let mut second = open(&kept).unwrap();
+    unmount(SELFTEST_DEFERRED).unwrap();
+    assert!(matches!(unmount(SELFTEST_DEFERRED), Err(FileNotFound)));
+    assert!(matches!(open(&kept), Err(FileNotFound)));
+    assert!(FILESYSTEM_MAPPING.lock().is_source_busy("deferred"));
+    assert_eq!(first.read_to_end().unwrap(), b"kept");
+    drop(first);
+    assert_eq!(drops(), drops_before);
+    assert_eq!(second.read_to_end().unwrap(), b"kept");
+    drop(second);
+    assert_eq!(drops(), drops_before + 1);
+    assert!(!FILESYSTEM_MAPPING.lock().is_source_busy("deferred"));
+    // nothing open, dropped right away
+    mount(
+        SELFTEST_DEFERRED,
+        Arc::new(SelftestDropCounted(RamFileSystem::new())),
+        "deferred",
+        "ramfs",
+    );
+    unmount(SELFTEST_DEFERRED).unwrap();
+    assert_eq!(drops(), drops_before + 2);
+
+    // the files still open keep the filesystem, stale, until the last of them is closed
+    let stale = Arc::new(RamFileSystem::new());
+    stale
+        .create_file("/", "kept.txt", b"kept".to_vec())
+        .unwrap();
+    let dropped = Arc::downgrade(&stale);
+    mount(SELFTEST_STALE, stale, "ramfs", "ramfs");
+    let kept = format!("{SELFTEST_STALE}/kept.txt");
+    let mut first = open(&kept).unwrap();
+    let mut second = open(&kept).unwrap();
+    assert_eq!(first.read_to_end().unwrap(), b"kept");
+    force_unmount(SELFTEST_STALE).unwrap();
+    assert!(matches!(force_unmount(SELFTEST_STALE), Err(FileNotFound)));
+    assert!(matches!(second.read_to_end(), Err(StaleHandle)));
+    assert!(matches!(first.write(b"x"), Err(StaleHandle)));
+    assert_eq!(dropped.strong_count(), 1);
+    drop(first);
+    assert_eq!(dropped.strong_count(), 1);
+    drop(second);
+    assert_eq!(dropped.strong_count(), 0);
+
+    selftest_truncate();
+    selftest_write();
+    selftest_sync();
+    selftest_partition();
+    fat::run_self_tests();
+    page_cache::run_self_tests();
+    mounts::run_self_tests();
+    locks::run_self_tests();
+    watch::run_self_tests();
+
+    println!("Filesystem operations self tests passed");
+}
+
+/// Shrinking and extending a file while another one is reading it, in a ramfs and in FAT,
+/// where the clusters must be freed and the filesystem stay consistent
+fn selftest_truncate() {
+    use FileSystemError::*;
+
+    let ramfs = Arc::new(RamFileSystem::new());
+    ramfs
+        .create_file("/", "file", b"hello world".to_vec())
+        .unwrap();
+    mount(SELFTEST_TRUNCATE, ramfs, "ramfs", "ramfs");
+    let path = format!("{SELFTEST_TRUNCATE}/file");
+    let read = |path: &str| open(path).and_then(|mut file| file.read_to_end());
+
+    let mut reader = open(&path).unwrap();
+    let mut buf = [0xAA; 8];
+    assert_eq!(reader.read(&mut buf[..3]).unwrap(), 3);
+    truncate(&path, 5).unwrap();
+    assert_eq!(read(&path).unwrap(), b"hello");
+    assert_eq!(reader.filesize(), 5);
+    assert_eq!(reader.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"lo");
+    // the position is left after the end
+    truncate(&path, 2).unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    let mut file = open(&path).unwrap();
+    file.truncate(8).unwrap();
+    assert_eq!(read(&path).unwrap(), b"he\0\0\0\0\0\0");
+    assert_eq!(reader.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], [0; 3]);
+    // nothing is stored for the zeros
+    file.truncate(1 << 31).unwrap();
+    file.seek((1 << 31) - 4).unwrap();
+    assert_eq!(file.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], [0; 4]);
+    assert!(matches!(file.truncate(1 << 32), Err(NoSpace)));
+    create_dir(&format!("{SELFTEST_TRUNCATE}/dir")).unwrap();
+    assert!(matches!(
+        truncate(&format!("{SELFTEST_TRUNCATE}/dir"), 0),
+        Err(IsDirectory)
+    ));
+    assert!(matches!(
+        truncate("/devices/page_cache", 0),
+        Err(ReadOnlyFileSystem)
+    ));
+    drop((reader, file));
+    force_unmount(SELFTEST_TRUNCATE).unwrap();
+
+    // one sector per cluster
+    let device = selftest_fat_device("selftest_truncate_ram", true);
+    let free_clusters = || selftest_fat_check(&device, Lba(0)).free_clusters;
+    mount_block_device(SELFTEST_TRUNCATE, device.clone()).unwrap();
+    let path = format!("{SELFTEST_TRUNCATE}/HELLO.TXT");
+    let cluster = block::SELFTEST_SECTOR_SIZE as u64;
+    let free = free_clusters();
+
+    let mut reader = open(&path).unwrap();
+    assert_eq!(reader.read(&mut buf[..2]).unwrap(), 2);
+    truncate(&path, 100 * cluster).unwrap();
+    assert_eq!(free_clusters(), free - 99);
+    let content = read(&path).unwrap();
+    assert_eq!(content.len() as u64, 100 * cluster);
+    assert!(content.starts_with(b"hello") && content[5..].iter().all(|&b| b == 0));
+    assert_eq!(reader.read(&mut buf).unwrap(), 8);
+    assert_eq!(&buf, b"llo\0\0\0\0\0");
+
+    truncate(&path, 3).unwrap();
+    assert_eq!(free_clusters(), free);
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    // the end of the last cluster is cleared, and the cached pages dropped
+    truncate(&path, 5).unwrap();
+    assert_eq!(read(&path).unwrap(), b"hel\0\0");
+    reader.truncate(0).unwrap();
+    assert_eq!(free_clusters(), free + 1);
+    assert_eq!(read(&path).unwrap(), b"");
+    reader.truncate(cluster + 1).unwrap();
+    assert_eq!(free_clusters(), free - 1);
+    assert_eq!(read(&path).unwrap(), vec![0; cluster as usize + 1]);
+
+    // too large for the disk, what was allocated is given back
+    assert!(matches!(truncate(&path, 1000 * cluster), Err(NoSpace)));
+    assert_eq!(free_clusters(), free - 1);
+    assert_eq!(reader.filesize(), cluster + 1);
+    assert!(matches!(
+        truncate(&format!("{SELFTEST_TRUNCATE}/LOCKED.TXT"), 0),
+        Err(PermissionDenied)
+    ));
+    drop(reader);
+    force_unmount(SELFTEST_TRUNCATE).unwrap();
+}
+
+/// Creating and writing files in FAT, the clusters are allocated as they grow and the entries
+/// get the new size, with a reader open from before, the copies to and from a ramfs, and the
+/// short write when it's full
+fn selftest_write() {
+    use FileSystemError::*;
+
+    // one sector per cluster
+    let device = selftest_fat_device("selftest_write_ram", true);
+    let free_clusters = || selftest_fat_check(&device, Lba(0)).free_clusters;
+    mount_block_device(SELFTEST_WRITE, device.clone()).unwrap();
+    let fat = |path: &str| format!("{SELFTEST_WRITE}/{path}");
+    let read = |path: &str| open(path).and_then(|mut file| file.read_to_end());
+    let cluster = block::SELFTEST_SECTOR_SIZE;
+    let free = free_clusters();
+
+    create_file(&fat("A long file name.txt")).unwrap();
+    assert!(matches!(
+        create_file(&fat("a LONG file name.TXT")),
+        Err(AlreadyExists)
+    ));
+    assert_eq!(read(&fat("A long file name.txt")).unwrap(), b"");
+    assert_eq!(free_clusters(), free);
+
+    // over 3 clusters, not aligned to the sectors
+    let path = fat("A long file name.txt");
+    let content: Vec<u8> = (0..(2 * cluster + 300)).map(|i| (i % 251) as u8).collect();
+    let mut reader = open(&path).unwrap();
+    let mut file = open(&path).unwrap();
+    assert_eq!(file.write(&content[..700]).unwrap(), 700);
+    assert_eq!(
+        file.write(&content[700..]).unwrap(),
+        content.len() as u64 - 700
+    );
+    assert_eq!(free_clusters(), free - 3);
+    assert_eq!(reader.filesize(), content.len() as u64);
+    assert_eq!(reader.read_to_end().unwrap(), content);
+
+    // in the middle, across a cluster end, and appending in the last cluster
+    let mut expected = content.clone();
+    file.seek(cluster as u64 - 2).unwrap();
+    assert_eq!(file.write(b"abcd").unwrap(), 4);
+    expected[cluster - 2..cluster + 2].copy_from_slice(b"abcd");
+    file.seek(content.len() as u64).unwrap();
+    assert_eq!(file.write(b"end").unwrap(), 3);
+    expected.extend_from_slice(b"end");
+    assert_eq!(read(&path).unwrap(), expected);
+    assert_eq!(free_clusters(), free - 3);
+    assert!(matches!(
+        open(&fat("LOCKED.TXT")).unwrap().write(b"x"),
+        Err(PermissionDenied)
+    ));
+    drop((reader, file));
+
+    // the copies through a buffer, FAT to FAT, and ramfs to FAT
+    create_file(&fat("COPY.BIN")).unwrap();
+    let mut src = open(&path).unwrap();
+    let mut dst = open(&fat("COPY.BIN")).unwrap();
+    assert_eq!(
+        copy_file_range(&mut src, None, &mut dst, None, u64::MAX).unwrap(),
+        expected.len() as u64
+    );
+    drop((src, dst));
+    assert_eq!(read(&fat("COPY.BIN")).unwrap(), expected);
+    let ramfs = Arc::new(RamFileSystem::new());
+    ramfs
+        .create_file("/", "ram.txt", b"from the ramfs".to_vec())
+        .unwrap();
+    mount(SELFTEST_RAMFS, ramfs, "ramfs", "ramfs");
+    create_file(&fat("RAM.TXT")).unwrap();
+    let mut src = open(&format!("{SELFTEST_RAMFS}/ram.txt")).unwrap();
+    let mut dst = open(&fat("RAM.TXT")).unwrap();
+    assert_eq!(
+        copy_file_range(&mut src, None, &mut dst, None, 100).unwrap(),
+        14
+    );
+    drop((src, dst));
+    force_unmount(SELFTEST_RAMFS).unwrap();
+    assert_eq!(read(&fat("RAM.TXT")).unwrap(), b"from the ramfs");
+
+    // what fits is written, then nothing
+    let free_now = free_clusters();
+    create_file(&fat("FULL.BIN")).unwrap();
+    let mut full = open(&fat("FULL.BIN")).unwrap();
+    let big = vec![0x5A; (free_now as usize + 10) * cluster];
+    assert_eq!(full.write(&big).unwrap(), free_now as u64 * cluster as u64);
+    assert!(matches!(full.write(b"x"), Err(NoSpace)));
+    assert_eq!(free_clusters(), 0);
+    drop(full);
+    remove_file(&fat("FULL.BIN")).unwrap();
+    assert_eq!(free_clusters(), free_now);
+
+    // all of it is on the device
+    force_unmount(SELFTEST_WRITE).unwrap();
+    selftest_fat_check(&device, Lba(0));
+    mount_block_device(SELFTEST_WRITE, device.clone()).unwrap();
+    assert_eq!(read(&path).unwrap(), expected);
+    assert_eq!(read(&fat("COPY.BIN")).unwrap(), expected);
+    assert_eq!(read(&fat("HELLO.TXT")).unwrap(), b"hello");
+    force_unmount(SELFTEST_WRITE).unwrap();
+}
+
+/// What is synced survives a crash, and a crash after only some of the writes since
+/// leaves a consistent filesystem, on a FAT disk with a write cache
+fn selftest_sync() {
+    let disk = Arc::new(SelftestCacheDisk::new(block::selftest_fat12_image(
+        b"hello",
+    )));
+    let device = BlockDeviceFile::register("selftest_sync_ram".into(), disk.clone(), true);
+    // where the crashed images are checked
+    let check_disk = Arc::new(SelftestCacheDisk::new(Vec::new()));
+    let check_device =
+        BlockDeviceFile::register("selftest_sync_check".into(), check_disk.clone(), false);
+    let check_image = |image: Vec<u8>| {
+        check_disk.reset(image);
+        check_device.drop_cached_sectors();
+        let filesystem =
+            fat::load_fat_filesystem(check_device.clone(), Lba(0), block::SELFTEST_SECTORS as u32)
+                .unwrap();
+        let names: Vec<String> = filesystem
+            .open_dir("/")
+            .unwrap()
+            .map(|inode| String::from(inode.name()))
+            .collect();
+        (filesystem.check(), names)
+    };
+    let path = |path: &str| format!("{SELFTEST_SYNC}/{path}");
+    let remount_after_crash = || {
+        force_unmount(SELFTEST_SYNC).unwrap();
+        disk.crash();
+        device.drop_cached_sectors();
+        mount_block_device(SELFTEST_SYNC, device.clone()).unwrap();
+    };
+
+    mount_block_device(SELFTEST_SYNC, device.clone()).unwrap();
+    create_dir(&path("KEPT")).unwrap();
+    create_dir(&path("KEPT/INNER")).unwrap();
+    assert!(disk.cached_writes() > 0);
+    assert!(device.dirty_since().is_some());
+    // fsync of any file syncs its filesystem
+    open(&path("KEPT")).unwrap().sync().unwrap();
+    assert_eq!(disk.cached_writes(), 0);
+    assert!(device.dirty_since().is_none());
+    // nothing to flush
+    let flushes = disk.flushes();
+    sync().unwrap();
+    assert_eq!(disk.flushes(), flushes);
+
+    // every prefix of the writes since the sync is consistent, at most some clusters are lost
+    create_dir(&path("LOST")).unwrap();
+    create_dir(&path("LOST/A long directory name")).unwrap();
+    rename(&path("HELLO.TXT"), &path("LOST/HELLO.TXT")).unwrap();
+    remove_dir(&path("KEPT/INNER")).unwrap();
+    let writes = disk.cached_writes();
+    for reached in 0..=writes {
+        let (report, names) = check_image(disk.crash_image(reached));
+        assert!(
+            report.is_consistent(),
+            "after {reached} of {writes} writes:\n{report}"
+        );
+        assert!(names.iter().any(|name| name == "KEPT"));
+    }
+    let (report, names) = check_image(disk.crash_image(writes));
+    assert!(report.violations.is_empty(), "{report}");
+    assert_eq!(names, ["KEPT", "LOST"]);
+
+    // losing all of them gives what was synced
+    remount_after_crash();
+    assert_eq!(selftest_names(&path("")), ["HELLO.TXT", "KEPT"]);
+    assert_eq!(selftest_names(&path("KEPT/")), ["INNER"]);
+    assert_eq!(
+        open(&path("HELLO.TXT")).unwrap().read_to_end().unwrap(),
+        b"hello"
+    );
+
+    // `sync` of everything
+    create_dir(&path("SYNCED")).unwrap();
+    sync().unwrap();
+    remount_after_crash();
+    assert_eq!(selftest_names(&path("")), ["HELLO.TXT", "KEPT", "SYNCED"]);
+
+    // the writeback flushes what is older than its age
+    create_dir(&path("WRITTEN BACK")).unwrap();
+    let old_age = block::set_writeback_age_ms(0);
+    block::writeback_expired();
+    block::set_writeback_age_ms(old_age);
+    assert_eq!(disk.cached_writes(), 0);
+    remount_after_crash();
+    assert!(selftest_names(&path("")).contains(&String::from("WRITTEN BACK")));
+    force_unmount(SELFTEST_SYNC).unwrap();
+
+    let (report, _) = check_image(disk.crash_image(0));
+    assert!(report.is_consistent(), "{report}");
+}
+
+/// The conversions between the sector units at the edges of a partition, then a FAT in a
+/// partition that doesn't start at a cluster or page boundary, it must be found through the
+/// MBR, and its writes must stay inside the partition
+fn selftest_partition() {
+    use kernel_core::sector::{Cluster, FsSector};
+
+    const START: u64 = 7;
+    let end = Lba(START) + block::SELFTEST_SECTORS;
+
+    let partition_start = Lba(START);
+    assert_eq!(FsSector(0).to_lba(partition_start), partition_start);
+    assert_eq!(
+        FsSector(block::SELFTEST_SECTORS as u32 - 1).to_lba(partition_start) + 1,
+        end
+    );
+    assert_eq!(end - partition_start, block::SELFTEST_SECTORS);
+    // past what a `u32` of the partition start and the sector could add up to
+    assert_eq!(
+        FsSector(u32::MAX).to_lba(Lba(u32::MAX as u64)),
+        Lba(2 * u32::MAX as u64)
+    );
+    assert_eq!(
+        FsSector(3).range(2).collect::<Vec<_>>(),
+        [FsSector(3), FsSector(4)]
+    );
+    assert_eq!(Cluster::FIRST.first_sector(FsSector(10), 4), FsSector(10));
+    assert_eq!(Cluster(5).first_sector(FsSector(10), 4), FsSector(22));
+    assert_eq!(Cluster::data_clusters(Cluster(3)).count(), 2);
+
+    let sector_size = block::SELFTEST_SECTOR_SIZE;
+    let mut image = vec![0; (end.0 + 1) as usize * sector_size];
+    let entry = &mut image[446..462];
+    entry[4] = 0x01; // FAT12
+    entry[8..12].copy_from_slice(&(START as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(block::SELFTEST_SECTORS as u32).to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+    // around the partition, to check they are not written
+    image[sector_size..START as usize * sector_size].fill(0xAA);
+    image[end.0 as usize * sector_size..].fill(0xBB);
+    image[START as usize * sector_size..end.0 as usize * sector_size]
+        .copy_from_slice(&block::selftest_fat12_image(b"hello"));
+
+    let disk = RamDisk::new((end.0 + 1, sector_size as u32));
+    disk.write_sectors(Lba(0), &image).unwrap();
+    let device = BlockDeviceFile::register("selftest_partition_ram".into(), Arc::new(disk), true);
+    mount_block_device(SELFTEST_PARTITION, device.clone()).unwrap();
+    let path = |path: &str| format!("{SELFTEST_PARTITION}/{path}");
+    assert_eq!(
+        open(&path("HELLO.TXT")).unwrap().read_to_end().unwrap(),
+        b"hello"
+    );
+    create_dir(&path("dir")).unwrap();
+    rename(&path("HELLO.TXT"), &path("dir/moved.txt")).unwrap();
+    truncate(&path("dir/moved.txt"), 3 * sector_size as u64).unwrap();
+    assert_eq!(selftest_names(&path("dir")), ["moved.txt"]);
+    force_unmount(SELFTEST_PARTITION).unwrap();
+
+    let mut after = vec![0; image.len()];
+    device.read_sectors(Lba(0), &mut after).unwrap();
+    assert!(
+        after[..START as usize * sector_size] == image[..START as usize * sector_size]
+            && after[end.0 as usize * sector_size..] == image[end.0 as usize * sector_size..],
+        "partition self test: written outside the partition"
+    );
+    selftest_fat_check(&device, partition_start);
+}
@@ -0,0 +1,382 @@
+//! Mounting the filesystems by the name of their type and the path of their device.
+//!
+//! The kernel mounts its own filesystems directly with [`mount`](super::mount), the ones on
+//! devices go through the drivers here, for the root at boot, and for `SYS_MOUNT`. All the
+//! mounts are listed in `/devices/mounts`, a line for each, as
+//! `<source> <target> <fstype> <ro|rw> <mount uptime in ms>`.
+
+use core::mem;
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+use kernel_core::{path, sector::Lba};
+
+use crate::{
+    devices::{
+        self, ahci, block,
+        block::BlockDeviceFile,
+        generated::{Chunk, Cursor, Generator},
+        ide::{self, IdeDeviceIndex, IdeDeviceType},
+        Device,
+    },
+    memory_management::memory_layout::align_up,
+    sync::spin::mutex::Mutex,
+    system,
+};
+
+use super::{
+    create_dir, fat, mbr::MbrRaw, open, ramfs::RamFileSystem, selftest_fat_device, try_mount,
+    FileSystem, FileSystemError, ResultContext, FILESYSTEM_MAPPING,
+};
+
+type LoadDevice = fn(&Arc<BlockDeviceFile>, bool) -> Result<Arc<dyn FileSystem>, FileSystemError>;
+
+enum Load {
+    /// Loads the filesystem on the device, read-only if `true`
+    Device(LoadDevice),
+    /// A new filesystem in memory, the source is only a name for it
+    Memory(fn() -> Arc<dyn FileSystem>),
+}
+
+/// A type of filesystem that can be mounted by its name
+pub struct FileSystemDriver {
+    pub name: &'static str,
+    load: Load,
+}
+
+const DRIVERS: &[FileSystemDriver] = &[
+    FileSystemDriver {
+        name: "fat",
+        load: Load::Device(load_fat),
+    },
+    FileSystemDriver {
+        name: "ramfs",
+        load: Load::Memory(|| Arc::new(RamFileSystem::new())),
+    },
+];
+
+impl FileSystemDriver {
+    pub fn find(name: &str) -> Option<&'static Self> {
+        DRIVERS.iter().find(|driver| driver.name == name)
+    }
+
+    /// If it can be mounted read-only, the ones in memory are only useful if they are written
+    pub fn supports_read_only(&self) -> bool {
+        matches!(self.load, Load::Device(_))
+    }
+}
+
+/// Loads the FAT filesystem in the first partition (MBR) of `device`, or in the whole device
+/// if it has no partition table
+fn load_fat(
+    device: &Arc<BlockDeviceFile>,
+    read_only: bool,
+) -> Result<Arc<dyn FileSystem>, FileSystemError> {
+    let size = align_up(mem::size_of::<MbrRaw>(), device.sector_size() as usize);
+    let mut sectors = vec![0; size];
+    device
+        .read_sectors(Lba(0), &mut sectors)
+        .context("read partition table", None)?;
+
+    // SAFETY: This is a valid allocated memory
+    let mbr = unsafe { &*(sectors.as_ptr() as *const MbrRaw) };
+
+    let first_partition = &mbr.partition_table[0];
+    let (start_lba, size_in_sectors) = if mbr.is_valid() && first_partition.size_in_sectors != 0 {
+        (
+            first_partition.first_sector(),
+            first_partition.size_in_sectors,
+        )
+    } else {
+        let size_in_sectors =
+            u32::try_from(device.number_of_sectors()).map_err(|_| FileSystemError::InvalidData)?;
+        (Lba(0), size_in_sectors)
+    };
+
+    let mut filesystem = fat::load_fat_filesystem(device.clone(), start_lba, size_in_sectors)?;
+    if read_only {
+        filesystem.set_read_only();
+    }
+    println!(
+        "Loaded FAT filesystem {:?} ({:?}) from {}",
+        filesystem.volume_label(),
+        filesystem.fat_type(),
+        device.name()
+    );
+    Ok(Arc::new(Mutex::new(filesystem)))
+}
+
+/// The block device at `source`, which must be `/devices/<name>`
+fn find_block_device(source: &str) -> Result<Arc<BlockDeviceFile>, FileSystemError> {
+    let source = path::normalize(source).ok_or(FileSystemError::InvalidPath)?;
+    source
+        .strip_prefix("/devices/")
+        .and_then(|name| block::find(name.trim_end_matches('/')))
+        .ok_or(FileSystemError::DeviceNotFound)
+}
+
+/// Mounts the `driver` filesystem of `source` at `target`, `source` is the path of the block
+/// device for the filesystems on devices, a device can only be mounted once.
+///
+/// `read_only` can only be used if the driver [supports it](FileSystemDriver::supports_read_only)
+pub fn mount_source(
+    source: &str,
+    target: &str,
+    driver: &'static FileSystemDriver,
+    read_only: bool,
+) -> Result<(), FileSystemError> {
+    let target = path::normalize(target).ok_or(FileSystemError::InvalidPath)?;
+    let (filesystem, source) = match driver.load {
+        Load::Device(load) => {
+            let device = find_block_device(source)?;
+            let source = format!("/devices/{}", device.name());
+            {
+                let mut mappings = FILESYSTEM_MAPPING.lock();
+                if mappings.find_mount(&target).is_some() {
+                    return Err(FileSystemError::AlreadyExists);
+                }
+                // two filesystems on the same device would overwrite each other, the one
+                // unmounted is still used by the files open in it
+                if mappings.is_source_busy(&source) {
+                    return Err(FileSystemError::Busy);
+                }
+            }
+            (load(&device, read_only)?, source)
+        }
+        Load::Memory(load) => (load(), String::from(source)),
+    };
+    try_mount(&target, filesystem, &source, driver.name)
+}
+
+/// Mounts the FAT filesystem of `device` at `path`, see [`mount_source`]
+pub fn mount_block_device(path: &str, device: Arc<BlockDeviceFile>) -> Result<(), FileSystemError> {
+    let driver = FileSystemDriver::find("fat").expect("no FAT driver");
+    mount_source(&format!("/devices/{}", device.name()), path, driver, false)
+}
+
+/// Mounts `/` from `root=<device>` in the cmdline, or from the first ATA hard disk, on IDE
+/// or else on AHCI
+pub fn mount_root(cmdline: &str) -> Result<(), FileSystemError> {
+    let source = match cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("root="))
+    {
+        Some(name) => format!("/devices/{name}"),
+        None => {
+            let first_disk = IdeDeviceIndex {
+                ty: IdeDeviceType::Ata,
+                index: 0,
+            };
+            let name = ide::get_ide_block_device(first_disk)
+                .map(|device| device.name().into())
+                .or_else(ahci::first_disk_name)
+                .ok_or(FileSystemError::DeviceNotFound)?;
+            format!("/devices/{name}")
+        }
+    };
+    let driver = FileSystemDriver::find("fat").expect("no FAT driver");
+    mount_source(&source, "/", driver, false)
+}
+
+/// Unmounts `target`, it fails with `FileSystemError::Busy` if other filesystems are mounted
+/// inside it, and with `FileSystemError::PermissionDenied` for the kernel's own filesystems
+/// (i.e. `/devices`), which have no driver.
+///
+/// The files still open in it keep working, the filesystem is dropped when the last of them
+/// is closed, and its device can't be mounted again until then, see [`super::unmount`]
+pub fn unmount_target(target: &str) -> Result<(), FileSystemError> {
+    let target = path::normalize(target).ok_or(FileSystemError::InvalidPath)?;
+    let filesystem = {
+        let mut mappings = FILESYSTEM_MAPPING.lock();
+        let index = mappings
+            .find_mount(&target)
+            .ok_or(FileSystemError::FileNotFound)?;
+        let mount = &mappings.mappings[index];
+        if FileSystemDriver::find(mount.fstype).is_none() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if mappings
+            .mappings
+            .iter()
+            .any(|other| other.path != mount.path && other.path.starts_with(&mount.path))
+        {
+            return Err(FileSystemError::Busy);
+        }
+        mappings.detach(index)
+    };
+    // dropped now if nothing is open in it
+    drop(filesystem);
+    Ok(())
+}
+
+/// Force-unmounts all the filesystems mounted from `device`, when it is removed. Unlike
+/// [`unmount_target`], this can't fail, the files still open in them get
+/// `FileSystemError::StaleHandle`, the ones in the filesystems already unmounted from it too.
+/// The filesystems mounted inside them stay
+pub fn unmount_device(device: &BlockDeviceFile) {
+    let source = format!("/devices/{}", device.name());
+    let mut removed = Vec::new();
+    let detached = {
+        let mut mappings = FILESYSTEM_MAPPING.lock();
+        mappings.mappings.retain(|mount| {
+            if mount.source == source {
+                removed.push((mount.path.clone(), mount.filesystem.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        mappings.detached_of(&source)
+    };
+    for (path, filesystem) in removed {
+        println!("Unmounted {path} of the removed device {source}");
+        filesystem.disconnect();
+    }
+    for filesystem in detached {
+        filesystem.disconnect();
+    }
+}
+
+/// `/devices/mounts`, the mounted filesystems, see the [module documentation](self)
+#[derive(Debug)]
+struct MountsInfo;
+
+impl Device for MountsInfo {
+    fn name(&self) -> &str {
+        "mounts"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(MountsInfo))
+    }
+}
+
+/// The item of the cursor is the index of the mount
+impl Generator for MountsInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let mappings = FILESYSTEM_MAPPING.lock();
+        let mut chunk = Chunk::new(out);
+        while let Some(mount) = mappings.mappings.get(cursor.item) {
+            let target = match mount.path.trim_end_matches('/') {
+                "" => "/",
+                path => path,
+            };
+            if !chunk.line(format_args!(
+                "{} {target} {} {} {}",
+                mount.source,
+                mount.fstype,
+                if mount.read_only { "ro" } else { "rw" },
+                mount.time_nanos / 1_000_000,
+            )) {
+                break;
+            }
+            cursor.item += 1;
+        }
+        chunk.written()
+    }
+}
+
+fn sync_on_shutdown() {
+    if let Err(e) = super::sync() {
+        println!("WARNING: could not sync the filesystems: {e:?}");
+    }
+}
+
+/// Adds `/devices/mounts`, and the shutdown hook that syncs the filesystems
+pub fn init() {
+    devices::register_device(Arc::new(MountsInfo));
+    system::on_shutdown("fs sync", sync_on_shutdown);
+}
+
+const SELFTEST_MOUNT: &str = "/selftest_mount";
+
+/// Mounting by the path of the device and the name of the driver, all the errors, and their
+/// lines in `/devices/mounts`.
+///
+/// Leaves the unmounted `/devices/selftest_mount` for the userspace tests of `mount`
+pub(super) fn run_self_tests() {
+    use FileSystemError::*;
+
+    println!("Running mounts self tests...");
+    let fat = FileSystemDriver::find("fat").unwrap();
+    let ramfs = FileSystemDriver::find("ramfs").unwrap();
+    assert!(FileSystemDriver::find("ext2").is_none());
+    assert!(fat.supports_read_only() && !ramfs.supports_read_only());
+
+    let device = selftest_fat_device("selftest_mount", true);
+    let source = format!("/devices/{}", device.name());
+    let read_mounts = || {
+        let mounts = open("/devices/mounts").unwrap().read_to_end().unwrap();
+        String::from_utf8(mounts).unwrap()
+    };
+    let mount_line = |target: &str| {
+        read_mounts()
+            .lines()
+            .find(|line| line.split(' ').nth(1) == Some(target))
+            .map(String::from)
+    };
+    let fields = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+
+    // not block devices, and a target already mounted
+    for not_block in ["/devices/mounts", "/devices/nope", "/devices", "/"] {
+        assert!(matches!(
+            mount_source(not_block, SELFTEST_MOUNT, fat, false),
+            Err(DeviceNotFound)
+        ));
+    }
+    assert!(matches!(
+        mount_source("selftest_mount", SELFTEST_MOUNT, fat, false),
+        Err(InvalidPath)
+    ));
+    assert!(matches!(
+        mount_source(&source, "/", fat, false),
+        Err(AlreadyExists)
+    ));
+    assert_eq!(fields(&mount_line("/").unwrap())[2], "fat");
+
+    mount_source("/devices/./selftest_mount/", SELFTEST_MOUNT, fat, true).unwrap();
+    assert!(matches!(
+        mount_source(&source, "/selftest_mount_again", fat, false),
+        Err(Busy)
+    ));
+    let line = fields(&mount_line(SELFTEST_MOUNT).unwrap());
+    assert_eq!(line[..4], [&source, SELFTEST_MOUNT, "fat", "ro"]);
+    assert!(line[4].parse::<u64>().is_ok());
+
+    let path = format!("{SELFTEST_MOUNT}/HELLO.TXT");
+    let mut file = open(&path).unwrap();
+    assert_eq!(open(&path).unwrap().read_to_end().unwrap(), b"hello");
+    assert!(matches!(
+        create_dir(&format!("{SELFTEST_MOUNT}/DIR")),
+        Err(ReadOnlyFileSystem)
+    ));
+    // the open file keeps the filesystem, and the device, until it's closed
+    unmount_target(SELFTEST_MOUNT).unwrap();
+    assert!(mount_line(SELFTEST_MOUNT).is_none());
+    assert!(matches!(unmount_target(SELFTEST_MOUNT), Err(FileNotFound)));
+    assert!(matches!(open(&path), Err(FileNotFound)));
+    assert_eq!(file.read_to_end().unwrap(), b"hello");
+    assert!(matches!(
+        mount_source(&source, SELFTEST_MOUNT, fat, false),
+        Err(Busy)
+    ));
+    drop(file);
+    assert!(matches!(unmount_target("/"), Err(Busy)));
+    assert!(matches!(unmount_target("/devices"), Err(PermissionDenied)));
+
+    // writable this time, with a ramfs inside it, the source of a ramfs is only its name
+    mount_source(&source, SELFTEST_MOUNT, fat, false).unwrap();
+    let inner = format!("{SELFTEST_MOUNT}/inner");
+    mount_source("selftest memory", &inner, ramfs, false).unwrap();
+    assert!(matches!(
+        mount_source("other", &inner, ramfs, false),
+        Err(AlreadyExists)
+    ));
+    assert_eq!(fields(&mount_line(SELFTEST_MOUNT).unwrap())[3], "rw");
+    assert!(mount_line(&inner).unwrap().starts_with("selftest memory"));
+    create_dir(&format!("{inner}/dir")).unwrap();
+    assert!(matches!(unmount_target(SELFTEST_MOUNT), Err(Busy)));
+    unmount_target(&inner).unwrap();
+    unmount_target(SELFTEST_MOUNT).unwrap();
+
+    println!("Mounts self tests passed");
+}
@@ -0,0 +1,842 @@
+//! A cache for the content of files, in pages.
+//!
+//! Pages are keyed by (filesystem cache id, [`FileSystem::inode_id`], page index), and filled by
+//! [`FileSystem::read_file`], so any filesystem that gives a [`FileSystem::cache_id`] gets
+//! it for free. A page is filled outside the lock of the cache: its slot is reserved first, and
+//! the other readers of the same page wait until it's there, the ones of other pages don't.
+//!
+//! The number of pages is bounded by [`MAX_RAM_PERCENT`] of the physical memory, when
+//! full, pages are evicted with the clock algorithm. If the physical allocator runs out of
+//! memory, the pages not recently used are freed by the same clock, as a [`Shrinker`].
+//!
+//! The pages of the files mapped by processes are the pages of the cache, see [`map_page`],
+//! mapped read-only, a write copies them. Each mapping has a reference to the page, and the
+//! cache doesn't evict or reclaim a page while it's mapped. When the cache drops a mapped
+//! page, the mappings keep it until they are unmapped, as it was.
+//!
+//! The sectors of the block devices have a cache of the same kind, with its own pages and
+//! bound ([`MAX_BLOCK_RAM_PERCENT`]), so the metadata the filesystems read again and again
+//! (directories, the FAT) stays in memory. It's keyed by (device cache id, page of the
+//! device), and only has the sectors that were read or written through it, see
+//! [`read_sectors`]. It's below the cache of the files, which can fill its pages from it.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    devices::{self, Device},
+    memory_management::{
+        frames,
+        memory_layout::{virtual2physical, PAGE_4K},
+        physical_page_allocator,
+        reclaim::{self, Shrinker},
+    },
+    sync::spin::mutex::Mutex,
+};
+
+use super::{FileAttributes, FileSystem, FileSystemError, INode};
+
+/// The maximum percentage of the physical memory the cache can use
+pub const MAX_RAM_PERCENT: usize = 10;
+/// The same for the cache of the sectors of the block devices
+pub const MAX_BLOCK_RAM_PERCENT: usize = 5;
+
+static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::empty(MAX_RAM_PERCENT));
+static BLOCK_CACHE: Mutex<PageCache> = Mutex::new(PageCache::empty(MAX_BLOCK_RAM_PERCENT));
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// (filesystem cache id, inode id, page index), and (device cache id, page index, 0) in the
+/// block cache
+type PageKey = (u64, u64, u32);
+
+struct CachedPage {
+    key: PageKey,
+    // `None` if taken by `reclaim`
+    page: Option<*mut u8>,
+    // number of valid bytes in the page, the last page of a file is not full
+    len: usize,
+    // in the block cache, a bit for each sector of the page that is valid
+    sectors: u64,
+    referenced: bool,
+    // reserved for `key`, the page being read outside the lock, it tells the reservations apart
+    // if the slot is invalidated and reserved again for the same key
+    filling: Option<*mut u8>,
+}
+
+/// Where the page of a key is, see [`PageCache::lookup`]
+enum Lookup {
+    Cached(usize),
+    Filling,
+    Missing,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PageCacheStats {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    reclaimed: u64,
+}
+
+struct PageCache {
+    // the slots are never removed, only reused, so the indices in `map` are stable
+    pages: Vec<CachedPage>,
+    map: BTreeMap<PageKey, usize>,
+    clock_hand: usize,
+    max_percent: usize,
+    max_pages: usize,
+    stats: PageCacheStats,
+}
+
+// SAFETY: the pages are only accessed inside the mutex, except the ones being filled, which
+//         are not in their slots yet
+unsafe impl Send for PageCache {}
+
+impl PageCache {
+    const fn empty(max_percent: usize) -> Self {
+        Self {
+            pages: Vec::new(),
+            map: BTreeMap::new(),
+            clock_hand: 0,
+            max_percent,
+            max_pages: 0,
+            stats: PageCacheStats {
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                reclaimed: 0,
+            },
+        }
+    }
+
+    fn max_pages(&mut self) -> usize {
+        if self.max_pages == 0 {
+            let (free, used) = physical_page_allocator::stats();
+            // at least one page, so we can always make progress
+            self.max_pages = ((free + used) * self.max_percent / 100).max(1);
+        }
+        self.max_pages
+    }
+
+    fn push_slot(&mut self) -> usize {
+        self.pages.push(CachedPage {
+            key: (0, 0, 0),
+            page: None,
+            len: 0,
+            sectors: 0,
+            referenced: false,
+            filling: None,
+        });
+        self.pages.len() - 1
+    }
+
+    /// Get a slot to put a new page in, either a new one or by evicting another page
+    fn get_free_slot(&mut self) -> usize {
+        if self.pages.len() < self.max_pages() {
+            return self.push_slot();
+        }
+
+        // twice around, to take the pages whose second chance we gave on the first
+        for _ in 0..self.pages.len() * 2 {
+            let index = self.clock_hand;
+            self.clock_hand = (self.clock_hand + 1) % self.pages.len();
+
+            let slot = &mut self.pages[index];
+            // there are at most as many as the CPUs
+            if slot.filling.is_some() || slot.page.is_some_and(is_mapped) {
+                continue;
+            }
+            if slot.page.is_some() && slot.referenced {
+                // second chance
+                slot.referenced = false;
+                continue;
+            }
+            if slot.page.is_some() {
+                self.stats.evictions += 1;
+            }
+            let key = slot.key;
+            if self.map.get(&key) == Some(&index) {
+                self.map.remove(&key);
+            }
+            return index;
+        }
+        // all the pages are mapped, the cache goes over the bound until they are unmapped
+        self.push_slot()
+    }
+
+    /// Where the page of `key` is, it's marked as used if it's cached
+    fn lookup(&mut self, key: PageKey) -> Lookup {
+        let Some(&index) = self.map.get(&key) else {
+            return Lookup::Missing;
+        };
+        let slot = &mut self.pages[index];
+        if slot.filling.is_some() {
+            Lookup::Filling
+        } else if slot.page.is_some() {
+            slot.referenced = true;
+            self.stats.hits += 1;
+            Lookup::Cached(index)
+        } else {
+            // reclaimed
+            Lookup::Missing
+        }
+    }
+
+    /// Reserves a slot for `key`, with the page to fill outside the lock, until
+    /// [`Self::publish`] or [`Self::fill_failed`] the other readers of `key` wait
+    fn reserve(&mut self, key: PageKey) -> (usize, *mut u8) {
+        self.stats.misses += 1;
+        let index = match self.map.get(&key) {
+            // reuse the slot of the reclaimed page
+            Some(&index) => index,
+            None => self.get_free_slot(),
+        };
+        let page = self.take_page(index);
+        let slot = &mut self.pages[index];
+        slot.key = key;
+        slot.filling = Some(page);
+        slot.referenced = true;
+        self.map.insert(key, index);
+        (index, page)
+    }
+
+    /// Whether the slot at `index` is still reserved for filling `page`, it's not if it was
+    /// invalidated while filling
+    fn is_reserved(&self, index: usize, page: *mut u8) -> bool {
+        self.pages[index].filling == Some(page)
+    }
+
+    /// Puts `page`, filled with `len` bytes, in the slot reserved for it, returns `false` if
+    /// it's not reserved anymore, then the page is still ours to free
+    fn publish(&mut self, index: usize, page: *mut u8, len: usize) -> bool {
+        if !self.is_reserved(index, page) {
+            return false;
+        }
+        let slot = &mut self.pages[index];
+        slot.filling = None;
+        slot.page = Some(page);
+        slot.len = len;
+        slot.referenced = true;
+        true
+    }
+
+    /// The page of the slot at `index`, a new one if it has none, it's not in the slot anymore
+    fn take_page(&mut self, index: usize) -> *mut u8 {
+        match self.pages[index].page.take() {
+            Some(page) => page,
+            None => {
+                // SAFETY: the allocator is initialized before any filesystem
+                let page = unsafe { physical_page_allocator::alloc() };
+                // cleared when it's freed
+                frames::set_flags(
+                    virtual2physical(page as usize) as u64,
+                    frames::flags::PAGE_CACHE,
+                );
+                page
+            }
+        }
+    }
+
+    /// The slot of `key` in the block cache, if it has a page
+    fn block_slot(&mut self, key: PageKey) -> Option<&mut CachedPage> {
+        let &index = self.map.get(&key)?;
+        Some(&mut self.pages[index]).filter(|slot| slot.page.is_some())
+    }
+
+    /// `sector` of the device with `cache_id`, if it's in the block cache
+    fn cached_sector(&mut self, cache_id: u64, sector: u64, size: usize) -> Option<*const u8> {
+        let per_page = (PAGE_4K / size) as u64;
+        let slot = self.block_slot((cache_id, sector / per_page, 0))?;
+        let bit = sector % per_page;
+        if slot.sectors & (1 << bit) == 0 {
+            return None;
+        }
+        slot.referenced = true;
+        // SAFETY: the sector is inside the page
+        Some(unsafe { slot.page.unwrap().add(bit as usize * size) })
+    }
+
+    /// Puts `data` in the block cache as `sector` of the device with `cache_id`
+    fn insert_sector(&mut self, cache_id: u64, sector: u64, data: &[u8]) {
+        let per_page = (PAGE_4K / data.len()) as u64;
+        let key = (cache_id, sector / per_page, 0);
+        let index = match self.map.get(&key) {
+            // it could have been reclaimed
+            Some(&index) => index,
+            None => {
+                let index = self.get_free_slot();
+                self.map.insert(key, index);
+                index
+            }
+        };
+        let had_page = self.pages[index].page.is_some() && self.pages[index].key == key;
+        let page = self.take_page(index);
+        let bit = sector % per_page;
+        // SAFETY: the page is allocated and only used by us, and the sector is inside it
+        unsafe {
+            page.add(bit as usize * data.len())
+                .copy_from_nonoverlapping(data.as_ptr(), data.len());
+        }
+        let slot = &mut self.pages[index];
+        if !had_page {
+            slot.key = key;
+            slot.sectors = 0;
+        }
+        slot.page = Some(page);
+        slot.sectors |= 1 << bit;
+        slot.referenced = true;
+    }
+
+    /// Forgets `sector` of the device with `cache_id`, or replaces it with `data`
+    fn update_sector(&mut self, cache_id: u64, sector: u64, size: usize, data: Option<&[u8]>) {
+        let per_page = (PAGE_4K / size) as u64;
+        let Some(slot) = self.block_slot((cache_id, sector / per_page, 0)) else {
+            return;
+        };
+        let bit = sector % per_page;
+        match data {
+            Some(data) if slot.sectors & (1 << bit) != 0 => {
+                // SAFETY: the page is only used by the cache, and the sector is inside it
+                unsafe {
+                    let page = slot.page.unwrap().add(bit as usize * size);
+                    page.copy_from_nonoverlapping(data.as_ptr(), size);
+                }
+            }
+            _ => slot.sectors &= !(1 << bit),
+        }
+    }
+
+    /// Gives up the slot reserved for filling `page`, and frees the page
+    fn fill_failed(&mut self, index: usize, page: *mut u8) {
+        if self.is_reserved(index, page) {
+            let slot = &mut self.pages[index];
+            slot.filling = None;
+            self.map.remove(&slot.key);
+        }
+        // SAFETY: we allocated this page and its not used anywhere else
+        unsafe { physical_page_allocator::free(page) };
+    }
+
+    fn invalidate(&mut self, cache_id: u64) {
+        for (index, slot) in self.pages.iter_mut().enumerate() {
+            if slot.key.0 != cache_id {
+                continue;
+            }
+            // the filling CPU frees its page when it sees this
+            slot.filling = None;
+            if let Some(page) = slot.page.take() {
+                release_page(page);
+            }
+            if self.map.get(&slot.key) == Some(&index) {
+                self.map.remove(&slot.key);
+            }
+        }
+    }
+}
+
+/// Allocates a new unique id that a filesystem can use as its [`FileSystem::cache_id`]
+pub fn new_cache_id() -> u64 {
+    NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Read from the file `inode` through the cache, the filesystem must have a `cache_id`
+pub fn read(
+    filesystem: &dyn FileSystem,
+    cache_id: u64,
+    inode: &INode,
+    position: u64,
+    buf: &mut [u8],
+) -> Result<u64, FileSystemError> {
+    if filesystem.is_disconnected() {
+        return Err(FileSystemError::StaleHandle);
+    }
+    if inode.is_dir() {
+        return Err(FileSystemError::IsDirectory);
+    }
+    if position >= inode.size() as u64 {
+        return Ok(0);
+    }
+    // the size is `u32`, so this fits
+    let position = position as u32;
+    let to_read = ((inode.size() - position) as usize).min(buf.len());
+
+    let mut read = 0;
+    while read < to_read {
+        let position = position as usize + read;
+        let page_index = (position / PAGE_4K) as u32;
+        let page_offset = position % PAGE_4K;
+        let copied = with_page(filesystem, cache_id, inode, page_index, |page, page_len| {
+            let len = (page_len - page_offset).min(to_read - read);
+            // SAFETY: the page is valid, and `len` is within the filled part
+            let data = unsafe { core::slice::from_raw_parts(page.add(page_offset), len) };
+            buf[read..read + len].copy_from_slice(data);
+            len
+        })?;
+        read += copied;
+    }
+
+    Ok(read as u64)
+}
+
+/// The physical address of the page at `page_index` of the file `inode`, read through the
+/// cache, for mapping it into a process. The page has one more reference for the mapping,
+/// which drops it with `free_physical`, it must not be written to, and the part after the
+/// end of the file is zeros
+pub fn map_page(
+    filesystem: &dyn FileSystem,
+    cache_id: u64,
+    inode: &INode,
+    page_index: u32,
+) -> Result<u64, FileSystemError> {
+    if filesystem.is_disconnected() {
+        return Err(FileSystemError::StaleHandle);
+    }
+    if inode.is_dir() {
+        return Err(FileSystemError::IsDirectory);
+    }
+    if page_index as u64 * PAGE_4K as u64 >= inode.size() as u64 {
+        return Err(FileSystemError::EndOfFile);
+    }
+    with_page(filesystem, cache_id, inode, page_index, |page, _| {
+        let physical = virtual2physical(page as usize) as u64;
+        physical_page_allocator::share_physical(physical);
+        physical
+    })
+}
+
+/// Calls `f` with the page at `page_index` of `inode` and its number of valid bytes, with the
+/// lock of the cache, the page is filled first if it's not cached
+fn with_page<T>(
+    filesystem: &dyn FileSystem,
+    cache_id: u64,
+    inode: &INode,
+    page_index: u32,
+    f: impl FnOnce(*mut u8, usize) -> T,
+) -> Result<T, FileSystemError> {
+    let key = (cache_id, filesystem.inode_id(inode), page_index);
+    loop {
+        let mut cache = PAGE_CACHE.lock();
+        let (index, page) = match cache.lookup(key) {
+            Lookup::Cached(index) => {
+                let slot = &cache.pages[index];
+                return Ok(f(slot.page.unwrap(), slot.len));
+            }
+            Lookup::Filling => {
+                drop(cache);
+                core::hint::spin_loop();
+                continue;
+            }
+            Lookup::Missing => cache.reserve(key),
+        };
+        drop(cache);
+
+        let filled = fill_page(filesystem, inode, page_index, page);
+        let mut cache = PAGE_CACHE.lock();
+        let page_len = match filled {
+            Ok(page_len) => page_len,
+            Err(e) => {
+                cache.fill_failed(index, page);
+                return Err(e);
+            }
+        };
+        let result = f(page, page_len);
+        if !cache.publish(index, page, page_len) {
+            release_page(page);
+        }
+        return Ok(result);
+    }
+}
+
+/// Whether `page` of the cache is mapped by a process, see [`map_page`]
+fn is_mapped(page: *mut u8) -> bool {
+    physical_page_allocator::physical_refs(virtual2physical(page as usize) as u64) > 1
+}
+
+/// Drops the reference of the cache to `page`, the mappings of it keep it as user memory
+fn release_page(page: *mut u8) {
+    let physical = virtual2physical(page as usize) as u64;
+    if is_mapped(page) {
+        frames::replace_flags(physical, frames::flags::PAGE_CACHE, frames::flags::USER);
+    }
+    // SAFETY: the cache has a reference to the page, and doesn't use it anymore
+    unsafe { physical_page_allocator::free_physical(physical) };
+}
+
+/// Reads the page at `page_index` of `inode` into `page`, without the lock of the cache,
+/// returns the number of valid bytes, the last page of a file is not full, the rest of it is
+/// zeroed for the mappings
+fn fill_page(
+    filesystem: &dyn FileSystem,
+    inode: &INode,
+    page_index: u32,
+    page: *mut u8,
+) -> Result<usize, FileSystemError> {
+    let start = page_index * PAGE_4K as u32;
+    let len = (inode.size() - start).min(PAGE_4K as u32) as usize;
+    // SAFETY: the page is allocated and only used by us until it's published
+    let buf = unsafe { core::slice::from_raw_parts_mut(page, len) };
+    let mut filled = 0;
+    while filled < len {
+        match filesystem.read_file(inode, (start as usize + filled) as u64, &mut buf[filled..]) {
+            Ok(0) => return Err(FileSystemError::EndOfFile),
+            Ok(read) => filled += read as usize,
+            Err(e) => return Err(e),
+        }
+    }
+    // SAFETY: same as above, for the rest of the page
+    unsafe { page.add(len).write_bytes(0, PAGE_4K - len) };
+    Ok(len)
+}
+
+/// Drops all the pages of the filesystem with `cache_id`
+pub fn invalidate(cache_id: u64) {
+    PAGE_CACHE.lock().invalidate(cache_id);
+}
+
+/// The number of pages holding file data now
+pub fn cached_pages() -> usize {
+    PAGE_CACHE
+        .lock()
+        .pages
+        .iter()
+        .filter(|slot| slot.page.is_some())
+        .count()
+}
+
+/// Reads `data`, whole sectors of `sector_size` bytes from `start`, of the device with
+/// `cache_id` through the block cache. The sectors not cached are read with `read`, a run of
+/// consecutive ones at a time, and cached if it succeeds.
+///
+/// `sector_size` must divide [`PAGE_4K`], with at most 64 sectors in a page.
+pub fn read_sectors(
+    cache_id: u64,
+    sector_size: usize,
+    start: u64,
+    data: &mut [u8],
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<(), FileSystemError>,
+) -> Result<(), FileSystemError> {
+    assert!(PAGE_4K.is_multiple_of(sector_size) && PAGE_4K / sector_size <= 64);
+    let count = data.len() / sector_size;
+    let mut cache = BLOCK_CACHE.lock();
+    let mut i = 0;
+    while i < count {
+        let sector = &mut data[i * sector_size..][..sector_size];
+        if let Some(cached) = cache.cached_sector(cache_id, start + i as u64, sector_size) {
+            // SAFETY: the sector is valid in the page
+            let cached = unsafe { core::slice::from_raw_parts(cached, sector_size) };
+            sector.copy_from_slice(cached);
+            cache.stats.hits += 1;
+            i += 1;
+            continue;
+        }
+        let mut end = i + 1;
+        while end < count
+            && cache
+                .cached_sector(cache_id, start + end as u64, sector_size)
+                .is_none()
+        {
+            end += 1;
+        }
+        cache.stats.misses += (end - i) as u64;
+        let run = &mut data[i * sector_size..end * sector_size];
+        read(start + i as u64, run)?;
+        for (j, sector) in run.chunks_exact(sector_size).enumerate() {
+            cache.insert_sector(cache_id, start + (i + j) as u64, sector);
+        }
+        i = end;
+    }
+    Ok(())
+}
+
+/// Updates the cached sectors of the device with `cache_id` after `data` was written from
+/// `start`, or forgets them if the write failed, as we don't know what the device has now.
+/// The sectors not cached are not added
+pub fn write_sectors(cache_id: u64, sector_size: usize, start: u64, data: &[u8], written: bool) {
+    let mut cache = BLOCK_CACHE.lock();
+    for (i, sector) in data.chunks_exact(sector_size).enumerate() {
+        let sector = written.then_some(sector);
+        cache.update_sector(cache_id, start + i as u64, sector_size, sector);
+    }
+}
+
+/// Drops all the sectors of the device with `cache_id`
+pub fn invalidate_sectors(cache_id: u64) {
+    BLOCK_CACHE.lock().invalidate(cache_id);
+}
+
+/// Frees the pages not recently used, following the clock of the evictions, so a page
+/// used since the hand last passed gets a second chance.
+///
+/// This runs when the physical allocator is out of memory, which can be during a heap
+/// allocation, so this must not use the heap. If the cache is in use by this CPU,
+/// nothing is freed.
+struct PageCacheShrinker {
+    name: &'static str,
+    cache: &'static Mutex<PageCache>,
+}
+
+static PAGE_CACHE_SHRINKER: PageCacheShrinker = PageCacheShrinker {
+    name: "page_cache",
+    cache: &PAGE_CACHE,
+};
+static BLOCK_CACHE_SHRINKER: PageCacheShrinker = PageCacheShrinker {
+    name: "block_cache",
+    cache: &BLOCK_CACHE,
+};
+
+impl Shrinker for PageCacheShrinker {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn shrink(&self, pages: usize) -> usize {
+        let Some(mut cache) = self.cache.try_lock() else {
+            return 0;
+        };
+        let cache = &mut *cache;
+        let len = cache.pages.len();
+
+        let mut freed = 0;
+        // twice around, to take the pages whose second chance we gave on the first
+        for _ in 0..len * 2 {
+            if freed == pages {
+                break;
+            }
+            let slot = &mut cache.pages[cache.clock_hand];
+            cache.clock_hand = (cache.clock_hand + 1) % len;
+            if slot.page.is_some_and(is_mapped) {
+                continue;
+            }
+            if slot.referenced {
+                slot.referenced = false;
+                continue;
+            }
+            if let Some(page) = slot.page.take() {
+                // SAFETY: the page is only used by the cache
+                unsafe { physical_page_allocator::free(page) };
+                freed += 1;
+            }
+        }
+        cache.stats.reclaimed += freed as u64;
+        freed
+    }
+}
+
+/// `/devices/page_cache`, the usage and statistics of the cache
+#[derive(Debug)]
+struct PageCacheInfo;
+
+impl Device for PageCacheInfo {
+    fn name(&self) -> &str {
+        "page_cache"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let usage = |cache: &Mutex<PageCache>| {
+            let mut cache = cache.lock();
+            let used = cache
+                .pages
+                .iter()
+                .filter(|slot| slot.page.is_some())
+                .count();
+            let max = cache.max_pages();
+            (used, max, cache.stats)
+        };
+        let (used, max, stats) = usage(&PAGE_CACHE);
+        let (block_used, block_max, block) = usage(&BLOCK_CACHE);
+        // the block cache counts sectors for the hits and misses
+        let info = format!(
+            "pages: {used}/{max}\nhits: {}\nmisses: {}\nevictions: {}\nreclaimed: {}\n\
+             block_pages: {block_used}/{block_max}\nblock_hits: {}\nblock_misses: {}\n\
+             block_evictions: {}\nblock_reclaimed: {}\n",
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.reclaimed,
+            block.hits,
+            block.misses,
+            block.evictions,
+            block.reclaimed
+        );
+        let info = info.as_bytes();
+        Ok(devices::read_bytes(info, offset, buf))
+    }
+}
+
+pub fn init() {
+    reclaim::register_shrinker(reclaim::priority::PAGE_CACHE, &PAGE_CACHE_SHRINKER);
+    // after the files, as their pages are filled from it
+    reclaim::register_shrinker(reclaim::priority::BLOCK_CACHE, &BLOCK_CACHE_SHRINKER);
+    devices::register_device(Arc::new(PageCacheInfo));
+}
+
+const SELFTEST_PAGES: u32 = 3;
+
+/// A file where each byte is its page index. While filling a page, it can read the next one
+/// through the cache, which needs the lock of the cache to be free, or invalidate the cache
+struct SelftestFileSystem {
+    cache_id: u64,
+    reads: AtomicU32,
+    read_next: AtomicBool,
+    invalidate_page: AtomicU32,
+}
+
+impl SelftestFileSystem {
+    fn inode() -> INode {
+        INode::new_file(
+            String::from("pages"),
+            FileAttributes::READ_ONLY,
+            1,
+            SELFTEST_PAGES * PAGE_4K as u32,
+        )
+    }
+
+    fn read_page(&self, page_index: u32) -> Vec<u8> {
+        let mut buf = vec![0xFF; PAGE_4K];
+        let position = (page_index as usize * PAGE_4K) as u64;
+        let cache_id = self.cache_id;
+        assert_eq!(
+            read(self, cache_id, &Self::inode(), position, &mut buf).unwrap(),
+            PAGE_4K as u64
+        );
+        buf
+    }
+}
+
+impl FileSystem for SelftestFileSystem {
+    fn open_dir(&self, _path: &str) -> Result<Vec<INode>, FileSystemError> {
+        Err(FileSystemError::FileNotFound)
+    }
+
+    fn read_dir(&self, _inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        Err(FileSystemError::FileNotFound)
+    }
+
+    fn disconnect(&self) {}
+
+    fn is_disconnected(&self) -> bool {
+        false
+    }
+
+    fn cache_id(&self) -> Option<u64> {
+        Some(self.cache_id)
+    }
+
+    fn read_file(
+        &self,
+        _inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        let page_index = (position / PAGE_4K as u64) as u32;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if self.read_next.load(Ordering::Relaxed) && page_index + 1 < SELFTEST_PAGES {
+            assert!(self
+                .read_page(page_index + 1)
+                .iter()
+                .all(|&b| b == page_index as u8 + 1));
+        }
+        if self
+            .invalidate_page
+            .compare_exchange(page_index, u32::MAX, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            invalidate(self.cache_id);
+        }
+        buf.fill(page_index as u8);
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Filling pages while reading others through the cache, and invalidating the cache while a
+/// page is filled
+pub(super) fn run_self_tests() {
+    println!("Running page cache self tests...");
+    let filesystem = SelftestFileSystem {
+        cache_id: new_cache_id(),
+        reads: AtomicU32::new(0),
+        read_next: AtomicBool::new(true),
+        invalidate_page: AtomicU32::new(u32::MAX),
+    };
+    let reads = || filesystem.reads.load(Ordering::Relaxed);
+
+    // filling the first page fills all the others first, each is read once
+    assert!(filesystem.read_page(0).iter().all(|&b| b == 0));
+    assert_eq!(reads(), SELFTEST_PAGES);
+    for page_index in 0..SELFTEST_PAGES {
+        assert!(filesystem
+            .read_page(page_index)
+            .iter()
+            .all(|&b| b == page_index as u8));
+    }
+    assert_eq!(reads(), SELFTEST_PAGES);
+
+    // the page filled while invalidated is read correctly, but not kept
+    invalidate(filesystem.cache_id);
+    filesystem.read_next.store(false, Ordering::Relaxed);
+    filesystem.invalidate_page.store(1, Ordering::Relaxed);
+    assert!(filesystem.read_page(1).iter().all(|&b| b == 1));
+    assert_eq!(reads(), SELFTEST_PAGES + 1);
+    assert!(filesystem.read_page(1).iter().all(|&b| b == 1));
+    assert_eq!(reads(), SELFTEST_PAGES + 2);
+    assert!(filesystem.read_page(1).iter().all(|&b| b == 1));
+    assert_eq!(reads(), SELFTEST_PAGES + 2);
+
+    selftest_map_page(&filesystem);
+
+    invalidate(filesystem.cache_id);
+    println!("Page cache self tests passed");
+}
+
+/// The mappings get the page of the cache, at the same physical address, which is not evicted
+/// while mapped, and stays for the mappings when the cache drops it
+fn selftest_map_page(filesystem: &SelftestFileSystem) {
+    let inode = SelftestFileSystem::inode();
+    let cache_id = filesystem.cache_id;
+    let physical = map_page(filesystem, cache_id, &inode, 2).unwrap();
+    assert_eq!(
+        map_page(filesystem, cache_id, &inode, 2).unwrap(),
+        physical,
+        "page cache self test: the mappings of a page have different pages"
+    );
+    assert!(filesystem.read_page(2).iter().all(|&b| b == 2));
+    let key = (cache_id, filesystem.inode_id(&inode), 2);
+    let page = {
+        let cache = PAGE_CACHE.lock();
+        cache.pages[cache.map[&key]].page.unwrap()
+    };
+    assert_eq!(
+        virtual2physical(page as usize) as u64,
+        physical,
+        "page cache self test: the mapped page is not the cached page"
+    );
+    assert_eq!(physical_page_allocator::physical_refs(physical), 3);
+    assert!(matches!(
+        map_page(filesystem, cache_id, &inode, SELFTEST_PAGES),
+        Err(FileSystemError::EndOfFile)
+    ));
+
+    // a full cache grows instead of evicting the mapped page, the pages are not ours
+    let mut full = PageCache::empty(0);
+    full.max_pages = 1;
+    let index = full.push_slot();
+    full.pages[index].page = Some(page);
+    assert_ne!(full.get_free_slot(), index);
+    full.pages[index].page = None;
+
+    invalidate(cache_id);
+    assert_eq!(physical_page_allocator::physical_refs(physical), 2);
+    assert_eq!(
+        frames::frame(physical).map(|frame| frame.flags),
+        Some(frames::flags::USER)
+    );
+    // SAFETY: the page is still mapped by us, and not written to
+    assert!(unsafe { core::slice::from_raw_parts(page, PAGE_4K) }
+        .iter()
+        .all(|&b| b == 2));
+    for _ in 0..2 {
+        // SAFETY: our two mappings
+        unsafe { physical_page_allocator::free_physical(physical) };
+    }
+}
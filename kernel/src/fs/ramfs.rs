@@ -0,0 +1,503 @@
+//! A filesystem in memory, mounted at `/tmp`, the content is lost on reboot.
+//!
+//! Removing a file that is still open only removes its name, the content stays until
+//! the last `File` using it is closed (see [`OpenUnlink::Deferred`]).
+//!
+//! Files extended with `truncate` only store the bytes written to them, the rest reads as
+//! zeros (see [`FileData`]), so a large empty file costs nothing until it is written.
+
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    devices,
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+use super::{FileAttributes, FileSystem, FileSystemError, INode, OpenUnlink};
+
+const RAMFS_MOUNT_PATH: &str = "/tmp";
+
+static TMP_FILESYSTEM: OnceLock<Arc<RamFileSystem>> = OnceLock::new();
+
+/// The content of a file, `bytes` has the start of it, everything after up to `len` is zeros
+#[derive(Debug, Default)]
+struct FileData {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl FileData {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            len: bytes.len() as u32,
+            bytes,
+        }
+    }
+
+    /// The range of `len` bytes at `position`, if it fits in the size of a file
+    fn range(position: u64, len: u64) -> Result<(usize, usize), FileSystemError> {
+        let start = usize::try_from(position).map_err(|_| FileSystemError::InvalidOffset)?;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| start.checked_add(len))
+            .filter(|&end| u32::try_from(end).is_ok())
+            .ok_or(FileSystemError::NoSpace)?;
+        Ok((start, end))
+    }
+
+    fn read(&self, position: u64, buf: &mut [u8]) -> u64 {
+        if position >= self.len as u64 {
+            return 0;
+        }
+        let len = (self.len as usize - position as usize).min(buf.len());
+        let buf = &mut buf[..len];
+        let stored = devices::read_bytes(&self.bytes, position, buf) as usize;
+        buf[stored..].fill(0);
+        len as u64
+    }
+
+    fn write(&mut self, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let (start, end) = Self::range(position, buf.len() as u64)?;
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[start..end].copy_from_slice(buf);
+        self.len = self.len.max(end as u32);
+        Ok(buf.len() as u64)
+    }
+
+    /// Same as writing `len` zeros at `position`, without storing the ones after `bytes`
+    fn write_zeros(&mut self, position: u64, len: u64) -> Result<u64, FileSystemError> {
+        let (start, end) = Self::range(position, len)?;
+        let stored_end = end.min(self.bytes.len());
+        if start < stored_end {
+            self.bytes[start..stored_end].fill(0);
+        }
+        self.len = self.len.max(end as u32);
+        Ok(len)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), FileSystemError> {
+        let len = u32::try_from(len).map_err(|_| FileSystemError::NoSpace)?;
+        if (len as usize) < self.bytes.len() {
+            self.bytes.truncate(len as usize);
+            self.bytes.shrink_to_fit();
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Directory,
+    File { data: FileData },
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    kind: NodeKind,
+    // removed from its parent while still open, it is freed on `release`
+    unlinked: bool,
+}
+
+/// Each node is identified by its index, which is stored in the `start_cluster` of the
+/// inode like in the initrd. Removed nodes leave a hole, so the others keep their index
+struct RamFs {
+    // the first node is the root
+    nodes: Vec<Option<Node>>,
+}
+
+impl RamFs {
+    fn node(&self, index: usize) -> Result<&Node, FileSystemError> {
+        self.nodes
+            .get(index)
+            .and_then(Option::as_ref)
+            .ok_or(FileSystemError::FileNotFound)
+    }
+
+    fn node_mut(&mut self, index: usize) -> Result<&mut Node, FileSystemError> {
+        self.nodes
+            .get_mut(index)
+            .and_then(Option::as_mut)
+            .ok_or(FileSystemError::FileNotFound)
+    }
+
+    fn children(&self, parent: usize) -> impl Iterator<Item = (usize, &Node)> {
+        // the root is its own parent, so skip it
+        self.nodes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter_map(|(i, node)| node.as_ref().map(|node| (i, node)))
+            .filter(move |(_, node)| node.parent == parent && !node.unlinked)
+    }
+
+    fn find_child(&self, parent: usize, name: &str) -> Option<usize> {
+        self.children(parent)
+            .find(|(_, node)| node.name == name)
+            .map(|(i, _)| i)
+    }
+
+    /// The index of the directory at `path`
+    fn find_dir(&self, path: &str) -> Result<usize, FileSystemError> {
+        let index = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(0, |parent, component| self.find_child(parent, component))
+            .ok_or(FileSystemError::FileNotFound)?;
+        match self.node(index)?.kind {
+            NodeKind::Directory => Ok(index),
+            NodeKind::File { .. } => Err(FileSystemError::IsNotDirectory),
+        }
+    }
+
+    fn node_inode(&self, index: usize, node: &Node) -> INode {
+        match &node.kind {
+            NodeKind::Directory => INode::new_file(
+                node.name.clone(),
+                FileAttributes::DIRECTORY,
+                index as u32,
+                0,
+            ),
+            NodeKind::File { data } => INode::new_file(
+                node.name.clone(),
+                FileAttributes::EMPTY,
+                index as u32,
+                data.len,
+            ),
+        }
+    }
+
+    fn list_dir(&self, index: usize) -> Result<Vec<INode>, FileSystemError> {
+        match self.node(index)?.kind {
+            NodeKind::Directory => Ok(self
+                .children(index)
+                .map(|(i, node)| self.node_inode(i, node))
+                .collect()),
+            NodeKind::File { .. } => Err(FileSystemError::IsNotDirectory),
+        }
+    }
+
+    fn add_node(
+        &mut self,
+        parent: &str,
+        name: &str,
+        kind: NodeKind,
+    ) -> Result<(), FileSystemError> {
+        let parent = self.find_dir(parent)?;
+        if self.find_child(parent, name).is_some() {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        let node = Some(Node {
+            name: String::from(name),
+            parent,
+            kind,
+            unlinked: false,
+        });
+        match self.nodes.iter().position(Option::is_none) {
+            Some(hole) => self.nodes[hole] = node,
+            None => self.nodes.push(node),
+        }
+        Ok(())
+    }
+
+    fn file_mut(&mut self, index: usize) -> Result<&mut FileData, FileSystemError> {
+        match &mut self.node_mut(index)?.kind {
+            NodeKind::File { data } => Ok(data),
+            NodeKind::Directory => Err(FileSystemError::IsDirectory),
+        }
+    }
+
+    /// Writes the data of `src` to `dst` directly, they must be different files
+    fn copy_range(
+        &mut self,
+        src: usize,
+        src_position: u64,
+        dst: usize,
+        dst_position: u64,
+        len: u64,
+    ) -> Result<u64, FileSystemError> {
+        assert_ne!(src, dst);
+        // taken out while writing to `dst`, without copying it
+        let src_data = mem::take(self.file_mut(src)?);
+        let start = src_position.min(src_data.len as u64);
+        let end = start + (src_data.len as u64 - start).min(len);
+        // the zeros after the stored bytes are not stored in `dst` either
+        let stored_end = end.min(src_data.bytes.len() as u64);
+        let result = self.file_mut(dst).and_then(|dst| {
+            let mut written = 0;
+            if start < stored_end {
+                written = dst.write(
+                    dst_position,
+                    &src_data.bytes[start as usize..stored_end as usize],
+                )?;
+            }
+            let zeros_start = start.max(stored_end);
+            if zeros_start < end {
+                written += dst.write_zeros(dst_position + written, end - zeros_start)?;
+            }
+            Ok(written)
+        });
+        *self.file_mut(src)? = src_data;
+        result
+    }
+
+    fn remove_node(
+        &mut self,
+        parent: &str,
+        name: &str,
+        still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        let parent = self.find_dir(parent)?;
+        let index = self
+            .find_child(parent, name)
+            .ok_or(FileSystemError::FileNotFound)?;
+        if self.children(index).next().is_some() {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+        if still_open {
+            self.node_mut(index)?.unlinked = true;
+        } else {
+            self.nodes[index] = None;
+        }
+        Ok(())
+    }
+}
+
+pub struct RamFileSystem {
+    inner: Mutex<RamFs>,
+    disconnected: AtomicBool,
+}
+
+impl RamFileSystem {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(RamFs {
+                nodes: vec![Some(Node {
+                    name: String::new(),
+                    parent: 0,
+                    kind: NodeKind::Directory,
+                    unlinked: false,
+                })],
+            }),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates the file `name` in `parent` with `data`, for the kernel to put files here
+    pub fn create_file(
+        &self,
+        parent: &str,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<(), FileSystemError> {
+        self.inner.lock().add_node(
+            parent,
+            name,
+            NodeKind::File {
+                data: FileData::new(data),
+            },
+        )
+    }
+
+    /// The number of nodes alive, including the ones unlinked but still open
+    pub fn nodes_count(&self) -> usize {
+        self.inner.lock().nodes.iter().flatten().count()
+    }
+}
+
+impl FileSystem for RamFileSystem {
+    fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let ramfs = self.inner.lock();
+        ramfs.list_dir(ramfs.find_dir(path)?)
+    }
+
+    fn read_dir(&self, inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner.lock().list_dir(inode.start_cluster() as usize)
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let ramfs = self.inner.lock();
+        let NodeKind::File { data } = &ramfs.node(inode.start_cluster() as usize)?.kind else {
+            return Err(FileSystemError::IsDirectory);
+        };
+        Ok(data.read(position, buf))
+    }
+
+    fn write_file(&self, inode: &INode, position: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner
+            .lock()
+            .file_mut(inode.start_cluster() as usize)?
+            .write(position, buf)
+    }
+
+    fn copy_file_range(
+        &self,
+        src: &INode,
+        src_position: u64,
+        dst: &INode,
+        dst_position: u64,
+        len: u64,
+    ) -> Option<Result<u64, FileSystemError>> {
+        if self.is_disconnected() {
+            return Some(Err(FileSystemError::StaleHandle));
+        }
+        Some(self.inner.lock().copy_range(
+            src.start_cluster() as usize,
+            src_position,
+            dst.start_cluster() as usize,
+            dst_position,
+            len,
+        ))
+    }
+
+    fn truncate(&self, inode: &INode, len: u64) -> Result<INode, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let mut ramfs = self.inner.lock();
+        let index = inode.start_cluster() as usize;
+        ramfs.file_mut(index)?.set_len(len)?;
+        Ok(ramfs.node_inode(index, ramfs.node(index)?))
+    }
+
+    fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::Release);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn open_unlink(&self) -> OpenUnlink {
+        OpenUnlink::Deferred
+    }
+
+    fn release(&self, inode: &INode) {
+        let mut ramfs = self.inner.lock();
+        let index = inode.start_cluster() as usize;
+        if ramfs.node(index).is_ok_and(|node| node.unlinked) {
+            ramfs.nodes[index] = None;
+        }
+    }
+
+    fn create_dir(&self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner
+            .lock()
+            .add_node(parent, name, NodeKind::Directory)
+    }
+
+    fn create_file(&self, parent: &str, name: &str) -> Result<(), FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner.lock().add_node(
+            parent,
+            name,
+            NodeKind::File {
+                data: FileData::default(),
+            },
+        )
+    }
+
+    fn remove_file(
+        &self,
+        parent: &str,
+        name: &str,
+        still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner.lock().remove_node(parent, name, still_open)
+    }
+
+    fn remove_dir(
+        &self,
+        parent: &str,
+        name: &str,
+        still_open: bool,
+    ) -> Result<(), FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        self.inner.lock().remove_node(parent, name, still_open)
+    }
+
+    fn rename(
+        &self,
+        old_parent: &str,
+        old_name: &str,
+        new_parent: &str,
+        new_name: &str,
+    ) -> Result<(), FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let mut ramfs = self.inner.lock();
+        let old_parent = ramfs.find_dir(old_parent)?;
+        let index = ramfs
+            .find_child(old_parent, old_name)
+            .ok_or(FileSystemError::FileNotFound)?;
+        let new_parent = ramfs.find_dir(new_parent)?;
+        if ramfs.find_child(new_parent, new_name).is_some() {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        let node = ramfs.node_mut(index)?;
+        node.parent = new_parent;
+        node.name = String::from(new_name);
+        Ok(())
+    }
+}
+
+/// Mounts an empty ramfs at `/tmp`
+pub fn init() {
+    let filesystem = Arc::new(RamFileSystem::new());
+    TMP_FILESYSTEM
+        .set(filesystem.clone())
+        .unwrap_or_else(|_| panic!("ramfs already initialized"));
+    super::mount(RAMFS_MOUNT_PATH, filesystem, "ramfs", "ramfs");
+}
+
+/// Creates the file `/tmp/<name>` with `data`, for the kernel to leave files for userspace
+pub fn create_tmp_file(name: &str, data: Vec<u8>) -> Result<(), FileSystemError> {
+    let filesystem = TMP_FILESYSTEM
+        .try_get()
+        .ok_or(FileSystemError::FileNotFound)?;
+    if filesystem.is_disconnected() {
+        return Err(FileSystemError::StaleHandle);
+    }
+    filesystem.create_file("/", name, data)
+}
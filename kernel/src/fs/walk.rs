@@ -0,0 +1,143 @@
+use alloc::{collections::BTreeSet, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{FileSystem, FileSystemError, INode, FILESYSTEM_MAPPING};
+
+/// The default limit to how deep [`walk`] goes
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// An entry found by the [`Walker`]
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// The path relative to the start of the walk, without a leading `/`
+    pub path: String,
+    pub inode: INode,
+}
+
+struct WalkDir {
+    // the path of this directory relative to the start, empty for the start itself,
+    // otherwise ends with `/`
+    path: String,
+    entries: vec::IntoIter<INode>,
+}
+
+/// A depth-first traversal of a directory tree.
+///
+/// This is iterative, the memory used is bounded by the entries of the directories
+/// in the current path from the start, not the whole tree.
+/// Directories are visited once, so it will terminate even if the filesystem has loops.
+pub struct Walker {
+    filesystem: Arc<dyn FileSystem>,
+    stack: Vec<WalkDir>,
+    // keyed by (filesystem, inode id), not the start cluster, the empty files all have `0`
+    // and the files of ramfs and devices have none
+    visited: BTreeSet<(usize, u64)>,
+    max_depth: usize,
+    // an entry that was returned back with `push_back`
+    pending: Option<WalkEntry>,
+    position: u64,
+}
+
+impl Walker {
+    /// Creates a walker for `path` inside `filesystem`.
+    /// A `max_depth` of `0` will only return the entries of `path` itself
+    pub fn new(
+        filesystem: Arc<dyn FileSystem>,
+        path: &str,
+        max_depth: usize,
+    ) -> Result<Self, FileSystemError> {
+        let entries = filesystem.open_dir(path)?;
+
+        Ok(Self {
+            filesystem,
+            stack: vec![WalkDir {
+                path: String::new(),
+                entries: entries.into_iter(),
+            }],
+            visited: BTreeSet::new(),
+            max_depth,
+            pending: None,
+            position: 0,
+        })
+    }
+
+    /// The number of entries returned so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Return an entry back to the walker, so that the next call to `next` returns it
+    pub fn push_back(&mut self, entry: WalkEntry) {
+        assert!(self.pending.is_none(), "Only one entry can be pushed back");
+        self.pending = Some(entry);
+        self.position -= 1;
+    }
+
+    fn filesystem_id(&self) -> usize {
+        Arc::as_ptr(&self.filesystem) as *const () as usize
+    }
+}
+
+impl Iterator for Walker {
+    type Item = Result<WalkEntry, FileSystemError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.pending.take() {
+            self.position += 1;
+            return Some(Ok(entry));
+        }
+
+        loop {
+            let depth = self.stack.len();
+            let dir = self.stack.last_mut()?;
+            let Some(inode) = dir.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if inode.name() == "." || inode.name() == ".." {
+                continue;
+            }
+
+            let mut path = dir.path.clone();
+            path.push_str(inode.name());
+
+            if inode.is_dir() && depth <= self.max_depth {
+                let key = (self.filesystem_id(), self.filesystem.inode_id(&inode));
+                if self.visited.insert(key) {
+                    let entries = match self.filesystem.read_dir(&inode) {
+                        Ok(entries) => entries,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let mut dir_path = path.clone();
+                    dir_path.push('/');
+                    self.stack.push(WalkDir {
+                        path: dir_path,
+                        entries: entries.into_iter(),
+                    });
+                }
+            }
+
+            self.position += 1;
+            return Some(Ok(WalkEntry { path, inode }));
+        }
+    }
+}
+
+/// Walks the tree under `path` calling `visitor` with every entry,
+/// the traversal stops if `visitor` returns `false`
+pub fn walk<F>(path: &str, max_depth: usize, mut visitor: F) -> Result<(), FileSystemError>
+where
+    F: FnMut(&WalkEntry) -> bool,
+{
+    let mut path = String::from(path);
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    let (new_path, filesystem) = FILESYSTEM_MAPPING.lock().get_mapping(&path)?;
+
+    for entry in Walker::new(filesystem, new_path, max_depth)? {
+        if !visitor(&entry?) {
+            break;
+        }
+    }
+    Ok(())
+}
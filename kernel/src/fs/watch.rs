@@ -0,0 +1,493 @@
+//! Watching directories for changes, with `SYS_WATCH_CREATE` and `SYS_WATCH_ADD`, see
+//! [`kernel_user_link::watch`] for what userspace reads.
+//!
+//! A directory is watched by its identity, the filesystem and the inode id of the directory
+//! (`None` for the root of the filesystem, which has no entry), so a watch doesn't follow a
+//! path that is mounted over later. The events come from the operations by path of
+//! [`super`] (creating, removing, renaming and truncating) and from [`File::write`] and
+//! [`copy_file_range`] when they extend a file, above the filesystems, so all of them give
+//! events without knowing about the watches.
+//!
+//! While there are no watches nothing is looked up, otherwise each change looks for the
+//! identity of its directory, with one more `open_dir` of the directory above it.
+//!
+//! [`File::write`]: super::File::write
+//! [`copy_file_range`]: super::copy_file_range
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use kernel_core::path;
+use kernel_user_link::{
+    file::BlockingMode,
+    watch::{
+        WatchEvent, WATCH_ALL, WATCH_CREATE, WATCH_DELETE, WATCH_IS_DIR, WATCH_MODIFY,
+        WATCH_MOVED_FROM, WATCH_MOVED_TO, WATCH_OVERFLOW,
+    },
+};
+
+use crate::{devices::Device, sync::spin::mutex::Mutex};
+
+use super::{FileAttributes, FileSystem, FileSystemError, INode, FILESYSTEM_MAPPING};
+
+/// The events a watch keeps until they are read, the next ones are dropped
+const QUEUE_LEN: usize = 64;
+/// The directories one watch can have
+const MAX_DIRS: usize = 64;
+
+/// The address of the filesystem, and the inode id of the directory
+type DirKey = (usize, Option<u64>);
+
+/// The watches of each directory, only while the watch is open
+static WATCHES: Mutex<BTreeMap<DirKey, Vec<Watcher>>> = Mutex::new(BTreeMap::new());
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+// the files of a watch and its clones share the same inode id
+static NEXT_WATCH_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+struct Watcher {
+    watch: Weak<Watch>,
+    wd: u32,
+    mask: u32,
+}
+
+/// The queue of a watch file
+#[derive(Debug)]
+pub struct Watch {
+    inner: Mutex<WatchInner>,
+}
+
+#[derive(Debug)]
+struct WatchInner {
+    events: VecDeque<WatchEvent>,
+    /// Events dropped since the queue was full, nothing is queued until the overflow event
+    /// is read, so nothing after it is lost without it saying so
+    dropped: u32,
+    /// The directories added, the `wd` of each is its index plus `1`
+    dirs: Vec<DirKey>,
+}
+
+impl Watch {
+    fn push(&self, event: WatchEvent) {
+        let mut inner = self.inner.lock();
+        if inner.dropped != 0 || inner.events.len() == QUEUE_LEN {
+            inner.dropped = inner.dropped.saturating_add(1);
+            return;
+        }
+        inner.events.push_back(event);
+    }
+
+    /// Moves as many whole events as fit into `buf`, the overflow event goes after the
+    /// events that were kept
+    fn read(&self, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // the size must fit an event, `UnalignedAccess` is reported as an invalid size
+        if buf.len() < WatchEvent::SIZE {
+            return Err(FileSystemError::UnalignedAccess);
+        }
+        let mut inner = self.inner.lock();
+        let mut written = 0;
+        for chunk in buf.as_chunks_mut::<{ WatchEvent::SIZE }>().0 {
+            let event = match inner.events.pop_front() {
+                Some(event) => event,
+                None if inner.dropped != 0 => {
+                    let dropped = core::mem::take(&mut inner.dropped);
+                    WatchEvent::new(WATCH_OVERFLOW, 0, dropped, "")
+                }
+                None => break,
+            };
+            *chunk = *event.as_bytes();
+            written += WatchEvent::SIZE;
+        }
+        Ok(written as u64)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        // this one can't be upgraded anymore
+        let mut watches = WATCHES.lock();
+        watches.retain(|_, watchers| {
+            watchers.retain(|watcher| watcher.watch.strong_count() != 0);
+            !watchers.is_empty()
+        });
+    }
+}
+
+#[derive(Debug)]
+struct WatchDevice {
+    watch: Arc<Watch>,
+}
+
+impl Device for WatchDevice {
+    fn name(&self) -> &str {
+        "watch"
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        self.watch.read(buf)
+    }
+
+    fn as_watch(&self) -> Option<&Arc<Watch>> {
+        Some(&self.watch)
+    }
+}
+
+/// Creates a watch without directories, the file blocks on reads by default
+pub fn create() -> super::File {
+    let watch = Arc::new(Watch {
+        inner: Mutex::new(WatchInner {
+            events: VecDeque::new(),
+            dropped: 0,
+            dirs: Vec::new(),
+        }),
+    });
+    let inode = INode::new_device(
+        String::from("watch"),
+        FileAttributes::EMPTY,
+        Some(Arc::new(WatchDevice { watch })),
+    )
+    .with_id(NEXT_WATCH_ID.fetch_add(1, Ordering::Relaxed));
+    super::inode_to_file(inode, super::empty_filesystem(), 0, BlockingMode::Block(1))
+}
+
+/// Watches the directory at `path` for the events in `mask`, returns its `wd`, adding it
+/// again keeps the `wd` and replaces the mask
+pub fn add(watch: &Arc<Watch>, path: &str, mask: u32) -> Result<u32, FileSystemError> {
+    let path = path::normalize(path).ok_or(FileSystemError::InvalidPath)?;
+    let path = format!("{}/", path.trim_end_matches('/'));
+    let (dir, filesystem) = FILESYSTEM_MAPPING.lock().get_mapping(&path)?;
+    // fails if it's not a directory
+    filesystem.open_dir(dir)?;
+    let key = dir_key(&filesystem, dir)?;
+
+    let wd = {
+        let mut inner = watch.inner.lock();
+        match inner.dirs.iter().position(|dir| *dir == key) {
+            Some(index) => index + 1,
+            None if inner.dirs.len() == MAX_DIRS => return Err(FileSystemError::NoSpace),
+            None => {
+                inner.dirs.push(key);
+                inner.dirs.len()
+            }
+        }
+    } as u32;
+
+    let mut watches = WATCHES.lock();
+    let watchers = watches.entry(key).or_default();
+    match watchers
+        .iter_mut()
+        .find(|watcher| watcher.wd == wd && Weak::as_ptr(&watcher.watch) == Arc::as_ptr(watch))
+    {
+        Some(watcher) => watcher.mask = mask,
+        None => watchers.push(Watcher {
+            watch: Arc::downgrade(watch),
+            wd,
+            mask,
+        }),
+    }
+    Ok(wd)
+}
+
+fn filesystem_address(filesystem: &Arc<dyn FileSystem>) -> usize {
+    Arc::as_ptr(filesystem) as *const () as usize
+}
+
+/// The identity of the directory `dir` (ending with `/`) inside `filesystem`
+fn dir_key(filesystem: &Arc<dyn FileSystem>, dir: &str) -> Result<DirKey, FileSystemError> {
+    let address = filesystem_address(filesystem);
+    let Some((parent, name)) = dir.trim_end_matches('/').rsplit_once('/') else {
+        return Ok((address, None));
+    };
+    let inode = filesystem
+        .open_dir(&format!("{parent}/"))?
+        .into_iter()
+        .find(|entry| entry.name() == name)
+        .ok_or(FileSystemError::FileNotFound)?;
+    Ok((address, Some(filesystem.inode_id(&inode))))
+}
+
+/// Whether any watch has a directory, so the changes must be looked for
+pub(super) fn is_active() -> bool {
+    !WATCHES.lock().is_empty()
+}
+
+/// A cookie to pair the two events of a rename, never `0`
+pub(super) fn next_cookie() -> u32 {
+    loop {
+        let cookie = NEXT_COOKIE.fetch_add(1, Ordering::Relaxed);
+        if cookie != 0 {
+            return cookie;
+        }
+    }
+}
+
+/// Queues the event of `name` in the directory `dir` (ending with `/`) of `filesystem` to
+/// the watches of that directory that asked for it
+pub(super) fn notify(
+    filesystem: &Arc<dyn FileSystem>,
+    dir: &str,
+    name: &str,
+    mask: u32,
+    cookie: u32,
+) {
+    if !is_active() {
+        return;
+    }
+    let Ok(key) = dir_key(filesystem, dir) else {
+        return;
+    };
+    // not queued with the lock held, the watches are dropped with it
+    let watchers: Vec<(Arc<Watch>, u32)> = WATCHES.lock().get(&key).map_or(Vec::new(), |v| {
+        v.iter()
+            .filter(|watcher| watcher.mask & mask & WATCH_ALL != 0)
+            .filter_map(|watcher| Some((watcher.watch.upgrade()?, watcher.wd)))
+            .collect()
+    });
+    for (watch, wd) in watchers {
+        watch.push(WatchEvent::new(mask, wd, cookie, name));
+    }
+}
+
+/// The directory `inode` was removed, its watches are dropped, so another one doesn't get
+/// them if it takes its id
+pub(super) fn forget_dir(filesystem: &Arc<dyn FileSystem>, inode: &INode) {
+    let key = (
+        filesystem_address(filesystem),
+        Some(filesystem.inode_id(inode)),
+    );
+    WATCHES.lock().remove(&key);
+}
+
+/// The directory `old` was moved to `new`, for filesystems where that changes the inode id,
+/// the watches follow it
+pub(super) fn move_dir(filesystem: &Arc<dyn FileSystem>, old: &INode, new: &INode) {
+    let address = filesystem_address(filesystem);
+    let old_key = (address, Some(filesystem.inode_id(old)));
+    let new_key = (address, Some(filesystem.inode_id(new)));
+    if old_key == new_key {
+        return;
+    }
+    // not with the lock held, the watches are dropped with it
+    let Some(watchers) = WATCHES.lock().remove(&old_key) else {
+        return;
+    };
+    // the `wd`s are found by the key in the watches
+    for watch in watchers
+        .iter()
+        .filter_map(|watcher| watcher.watch.upgrade())
+    {
+        for dir in watch.inner.lock().dirs.iter_mut() {
+            if *dir == old_key {
+                *dir = new_key;
+            }
+        }
+    }
+    WATCHES.lock().entry(new_key).or_default().extend(watchers);
+}
+
+/// Reads the events queued in `file` until there are none
+fn selftest_events(file: &mut super::File) -> Vec<WatchEvent> {
+    let mut buf = [0; WatchEvent::SIZE * 4];
+    let mut events = Vec::new();
+    loop {
+        let read = file.read(&mut buf).unwrap() as usize;
+        if read == 0 {
+            return events;
+        }
+        events.extend(
+            buf[..read]
+                .chunks(WatchEvent::SIZE)
+                .map(|bytes| WatchEvent::from_bytes(bytes).unwrap()),
+        );
+    }
+}
+
+/// Every kind of event from the operations by path and from the files, in a ramfs and in
+/// FAT, the rename cookies, and the overflow event when the queue is not read
+pub(super) fn run_self_tests() {
+    use super::{
+        create_dir, create_file, force_unmount, mount, mount_block_device, open, ramfs, remove_dir,
+        remove_file, rename, selftest_fat_device, truncate, SELFTEST_WATCH, SELFTEST_WATCH_FAT,
+    };
+    use FileSystemError::*;
+
+    let ram = |path: &str| format!("{SELFTEST_WATCH}/{path}");
+    let fat = |path: &str| format!("{SELFTEST_WATCH_FAT}/{path}");
+    mount(
+        SELFTEST_WATCH,
+        Arc::new(ramfs::RamFileSystem::new()),
+        "ramfs",
+        "ramfs",
+    );
+    mount_block_device(
+        SELFTEST_WATCH_FAT,
+        selftest_fat_device("selftest_watch_ram", true),
+    )
+    .unwrap();
+    create_dir(&ram("a")).unwrap();
+    create_dir(&ram("b")).unwrap();
+    create_dir(&ram("a/sub")).unwrap();
+
+    let mut file = create();
+    file.set_blocking(BlockingMode::None);
+    let watch = file.watch().unwrap().clone();
+    let summary = |events: &[WatchEvent]| -> Vec<(u32, u32, String)> {
+        events
+            .iter()
+            .map(|event| (event.mask, event.wd, String::from(event.name())))
+            .collect()
+    };
+
+    assert!(!is_active());
+    assert_eq!(add(&watch, &ram("a"), WATCH_ALL).unwrap(), 1);
+    assert_eq!(add(&watch, &ram("b/"), WATCH_ALL).unwrap(), 2);
+    assert_eq!(add(&watch, &ram("a/"), WATCH_ALL).unwrap(), 1);
+    assert_eq!(add(&watch, SELFTEST_WATCH_FAT, WATCH_ALL).unwrap(), 3);
+    assert!(matches!(
+        add(&watch, &ram("nope"), WATCH_ALL),
+        Err(FileNotFound)
+    ));
+    assert!(add(&watch, &fat("HELLO.TXT"), WATCH_ALL).is_err());
+    assert!(selftest_events(&mut file).is_empty());
+
+    // each kind in the ramfs, and nothing from the directory inside
+    create_file(&ram("a/f")).unwrap();
+    let mut f = open(&ram("a/f")).unwrap();
+    f.write(b"hello").unwrap();
+    f.seek(0).unwrap();
+    // writing over what is there is not a change of the directory
+    f.write(b"j").unwrap();
+    f.truncate(2).unwrap();
+    truncate(&ram("a/f"), 0).unwrap();
+    drop(f);
+    create_file(&ram("a/sub/ignored")).unwrap();
+    rename(&ram("a/f"), &ram("a/g")).unwrap();
+    rename(&ram("a/g"), &ram("b/g")).unwrap();
+    remove_file(&ram("b/g")).unwrap();
+    create_dir(&ram("b/d")).unwrap();
+    remove_dir(&ram("b/d")).unwrap();
+    let got = selftest_events(&mut file);
+    let expected = [
+        (WATCH_CREATE, 1, "f"),
+        (WATCH_MODIFY, 1, "f"),
+        (WATCH_MODIFY, 1, "f"),
+        (WATCH_MODIFY, 1, "f"),
+        (WATCH_MOVED_FROM, 1, "f"),
+        (WATCH_MOVED_TO, 1, "g"),
+        (WATCH_MOVED_FROM, 1, "g"),
+        (WATCH_MOVED_TO, 2, "g"),
+        (WATCH_DELETE, 2, "g"),
+        (WATCH_CREATE | WATCH_IS_DIR, 2, "d"),
+        (WATCH_DELETE | WATCH_IS_DIR, 2, "d"),
+    ]
+    .map(|(mask, wd, name)| (mask, wd, String::from(name)));
+    assert_eq!(summary(&got), expected);
+    // the two halves of each rename have the same cookie, and the renames different ones
+    assert!(got[..4].iter().chain(&got[8..]).all(|e| e.cookie == 0));
+    assert!(got[4].cookie != 0 && got[4].cookie == got[5].cookie);
+    assert!(got[6].cookie != 0 && got[6].cookie == got[7].cookie);
+    assert_ne!(got[4].cookie, got[6].cookie);
+
+    // the same in FAT
+    create_file(&fat("NEW.TXT")).unwrap();
+    open(&fat("NEW.TXT")).unwrap().write(b"hello").unwrap();
+    truncate(&fat("NEW.TXT"), 10).unwrap();
+    rename(&fat("NEW.TXT"), &fat("MOVED.TXT")).unwrap();
+    remove_file(&fat("MOVED.TXT")).unwrap();
+    assert_eq!(
+        summary(&selftest_events(&mut file)),
+        [
+            (WATCH_CREATE, 3, "NEW.TXT"),
+            (WATCH_MODIFY, 3, "NEW.TXT"),
+            (WATCH_MODIFY, 3, "NEW.TXT"),
+            (WATCH_MOVED_FROM, 3, "NEW.TXT"),
+            (WATCH_MOVED_TO, 3, "MOVED.TXT"),
+            (WATCH_DELETE, 3, "MOVED.TXT"),
+        ]
+        .map(|(mask, wd, name)| (mask, wd, String::from(name)))
+    );
+
+    // only what the mask asks for, a rename out of a directory that only watches deletes
+    // gives only the half of the other one
+    add(&watch, &ram("a"), WATCH_DELETE).unwrap();
+    create_file(&ram("a/h")).unwrap();
+    rename(&ram("a/h"), &ram("b/h")).unwrap();
+    remove_file(&ram("b/h")).unwrap();
+    assert_eq!(
+        summary(&selftest_events(&mut file)),
+        [(WATCH_MOVED_TO, 2, "h"), (WATCH_DELETE, 2, "h")].map(|(mask, wd, name)| (
+            mask,
+            wd,
+            String::from(name)
+        ))
+    );
+
+    // a moved directory keeps its watches, and a removed one loses them
+    create_dir(&fat("DIR")).unwrap();
+    assert_eq!(add(&watch, &fat("DIR"), WATCH_ALL).unwrap(), 4);
+    selftest_events(&mut file);
+    rename(&fat("DIR"), &fat("OTHER")).unwrap();
+    create_file(&fat("OTHER/IN.TXT")).unwrap();
+    remove_file(&fat("OTHER/IN.TXT")).unwrap();
+    remove_dir(&fat("OTHER")).unwrap();
+    create_dir(&fat("DIR")).unwrap();
+    create_file(&fat("DIR/LOST.TXT")).unwrap();
+    assert_eq!(
+        summary(&selftest_events(&mut file)),
+        [
+            (WATCH_MOVED_FROM | WATCH_IS_DIR, 3, "DIR"),
+            (WATCH_MOVED_TO | WATCH_IS_DIR, 3, "OTHER"),
+            (WATCH_CREATE, 4, "IN.TXT"),
+            (WATCH_DELETE, 4, "IN.TXT"),
+            (WATCH_DELETE | WATCH_IS_DIR, 3, "OTHER"),
+            (WATCH_CREATE | WATCH_IS_DIR, 3, "DIR"),
+        ]
+        .map(|(mask, wd, name)| (mask, wd, String::from(name)))
+    );
+    remove_file(&fat("DIR/LOST.TXT")).unwrap();
+    remove_dir(&fat("DIR")).unwrap();
+    selftest_events(&mut file);
+
+    // a full queue drops the events until it's read, and says how many after the others
+    let extra = 5;
+    for i in 0..QUEUE_LEN + extra {
+        create_file(&ram(&format!("b/{i}"))).unwrap();
+    }
+    let mut small = [0; WatchEvent::SIZE - 1];
+    assert!(matches!(file.read(&mut small), Err(UnalignedAccess)));
+    let got = selftest_events(&mut file);
+    assert_eq!(got.len(), QUEUE_LEN + 1);
+    assert!(got[..QUEUE_LEN]
+        .iter()
+        .enumerate()
+        .all(|(i, e)| e.mask == WATCH_CREATE && e.name() == format!("{i}")));
+    let overflow = got[QUEUE_LEN];
+    assert_eq!((overflow.mask, overflow.wd), (WATCH_OVERFLOW, 0));
+    assert_eq!(overflow.cookie as usize, extra);
+    // and the next ones are queued again
+    remove_file(&ram("b/0")).unwrap();
+    assert_eq!(
+        summary(&selftest_events(&mut file)),
+        [(WATCH_DELETE, 2, String::from("0"))]
+    );
+    for i in 1..QUEUE_LEN + extra {
+        remove_file(&ram(&format!("b/{i}"))).unwrap();
+    }
+
+    // the watch goes with its last file
+    let clone = file.clone_inherit();
+    drop((file, watch));
+    assert!(is_active());
+    drop(clone);
+    assert!(!is_active());
+    create_file(&ram("b/after")).unwrap();
+
+    force_unmount(SELFTEST_WATCH).unwrap();
+    force_unmount(SELFTEST_WATCH_FAT).unwrap();
+}
@@ -1,24 +1,81 @@
+//! The kernel console, and the virtual terminals
+//!
+//! After the heap, the console has [`NUM_TERMINALS`] virtual terminals, `/devices/tty1..tty4`,
+//! each with its own screen and keyboard input, only the active one is drawn and gets the
+//! input, the others keep writing to their [`ShadowBuffer`] and are drawn fully when switched
+//! to with `Alt+F1..F4`.
+//!
+//! The kernel output, and `/devices/console`, go to the log terminal (`tty1` by default, can
+//! be changed with `console=ttyN` in the cmdline), which is also mirrored to the serial port.
+//!
+//! The input of a terminal goes through its [`LineDiscipline`], from the keyboard (for the
+//! active terminal) and the serial port (for the log terminal), each is echoed back to where it
+//! came from, the screen or the serial port. `Ctrl+C` sends `SIGINT` to the processes that
+//! have the terminal open (see [`signal::send_to_terminal`]).
+//!
+//! The output is only rendered into the [`ShadowBuffer`]s and queued for the serial port while
+//! the console is locked, the slow writes to the screen and the serial port are done by
+//! [`flush`] after, with the interrupts enabled.
+
 use core::{
     cell::RefCell,
     fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use alloc::sync::Arc;
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec::Vec};
+use kernel_user_link::signal::SIGINT;
 
 use crate::{
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+        softirq,
+    },
     devices::{self, Device},
-    fs::FileSystemError,
-    sync::spin::{mutex::Mutex, remutex::ReMutex},
+    fs::{self, FileSystemError},
+    process::signal,
+    sync::{
+        once::OnceLock,
+        spin::{mutex::Mutex, remutex::ReMutex},
+    },
+    system,
 };
 
 use super::{
+    input_inject,
     keyboard::{self, Keyboard},
+    line_discipline::{Echo, InputSource, LineDiscipline},
     uart::{Uart, UartPort},
-    video_memory::{VgaBuffer, DEFAULT_ATTRIB},
+    utf8::Utf8Decoder,
+    video_memory::{self, ShadowBuffer, VgaBuffer, DEFAULT_ATTRIB, VGA_WIDTH},
 };
 
+/// The number of virtual terminals, see the [module docs](self)
+pub const NUM_TERMINALS: usize = 4;
+
+/// The bochs/qemu debug port, anything written to it goes to the `debugcon` of the emulator
+const DEBUG_PORT: u16 = 0xE9;
+/// How much of the early output we keep, until we have a heap to store it in
+const RETENTION_SIZE: usize = 4096;
+/// The most serial output waiting for [`flush`], after that the oldest is written right away
+const MAX_SERIAL_QUEUE: usize = 4096;
+/// The serial output [`flush`] takes each time it locks the console
+const FLUSH_CHUNK: usize = 64;
+
 // SAFETY: the console is only used inside a lock or mutex
-static mut CONSOLE: Console = Console::empty_early();
+static mut CONSOLE: Console = Console::empty();
+
+/// The output written before the late console, available at `/devices/boot_log`
+static BOOT_LOG: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// The same as the `Late` state of `CONSOLE`, for the keyboard and serial interrupts, which
+/// can come while `CONSOLE` is being changed
+static LATE_CONSOLE: OnceLock<Arc<ReMutex<RefCell<LateConsole>>>> = OnceLock::new();
+
+/// Set while [`flush`] writes the output with the interrupts enabled, so there is only one
+static FLUSHING: AtomicBool = AtomicBool::new(false);
 
 /// # SAFETY
 /// the caller must assure that this is not called while not being initialized
@@ -32,7 +89,10 @@ where
     unsafe { CONSOLE.run_with(f) }
 }
 
-/// Create an early console, this is used before the kernel heap is initialized
+/// Create an early console, this is used before the kernel heap is initialized.
+///
+/// Before this, the output goes directly to the screen and the debug port.
+/// Calling this again does nothing.
 pub fn early_init() {
     // SAFETY: we are running this initialization at the very startup,
     // without printing anything at the same time since we are only
@@ -40,131 +100,386 @@ pub fn early_init() {
     unsafe { CONSOLE.init_early() };
 }
 
+/// Initialize the serial port and mirror the output to it, this doesn't need anything,
+/// so it can be called before [`early_init`], to see what happens before it.
+///
+/// The early console then uses the port as is, without initializing it again.
+pub fn early_serial_init() {
+    // SAFETY: same as `early_init`, this is called at the very startup, alone
+    unsafe { (*core::ptr::addr_of_mut!(CONSOLE)).init_early_serial() };
+}
+
+/// The log terminal from `console=ttyN` in the cmdline, the first one by default
+fn log_terminal_from_cmdline(cmdline: &str) -> usize {
+    let mut log_terminal = 0;
+    for arg in cmdline.split_whitespace() {
+        if let Some(tty) = arg.strip_prefix("console=tty") {
+            match tty.parse::<usize>() {
+                Ok(n @ 1..=NUM_TERMINALS) => log_terminal = n - 1,
+                _ => {
+                    println!(
+                        "Invalid value for `console`: tty{tty}, there are tty1..tty{NUM_TERMINALS}"
+                    );
+                }
+            }
+        }
+    }
+    log_terminal
+}
+
 /// Create a late console, this is used after the kernel heap is initialized
-/// And also assign a console device
-pub fn init_late_device() {
+/// And also assign a console device, and the virtual terminals devices.
+///
+/// Calling this again does nothing.
+pub fn init_late_device(cmdline: &str) {
+    let log_terminal = log_terminal_from_cmdline(cmdline);
     // SAFETY: we are running this initialization at `kernel_main` and its done alone
     //  without printing anything at the same time since we are only
     //  running 1 CPU at the  time
     //  We are also sure that no one is printing at this time
     let device = unsafe {
-        CONSOLE.init_late();
+        if !CONSOLE.init_late(log_terminal) {
+            return;
+        }
         // Must have a device
         CONSOLE.late_device().unwrap()
     };
-
+    // only set here, after `init_late`
+    let _ = LATE_CONSOLE.set(device.clone());
+
+    for index in 0..NUM_TERMINALS {
+        devices::register_device(Arc::new(TerminalDevice {
+            console: device.clone(),
+            index,
+            name: format!("tty{}", index + 1),
+        }));
+    }
     devices::register_device(device);
+    devices::register_device(Arc::new(BootLog));
+    // the first registered is the last to run
+    system::on_shutdown("console", flush_on_shutdown);
+
+    // only after `LATE_CONSOLE`, which the handler uses
+    let uart = Uart::new(UartPort::COM1);
+    uart.enable_rx_interrupts();
+    apic::assign_io_irq(
+        serial_interrupt_handler as BasicInterruptHandler,
+        uart.interrupt_num(),
+        cpu::cpu(),
+    );
+}
+
+/// Writes the output left by an interrupted [`flush`], the interrupts are disabled while
+/// shutting down, so what the other hooks print is written right away
+fn flush_on_shutdown() {
+    if let Some(console) = LATE_CONSOLE.try_get() {
+        flush(console);
+    }
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame64) {
+    route_input();
+
+    apic::return_from_interrupt();
+}
+
+/// Moves the input from the keyboard and the serial port to the terminals, and switches the
+/// terminals.
+///
+/// Called from the keyboard softirq and the serial interrupt, so the switch and the echo happen even if
+/// no one is reading
+pub(super) fn route_input() {
+    let Some(console) = LATE_CONSOLE.try_get() else {
+        return;
+    };
+    {
+        let console = console.lock();
+        // if its taken, we are inside `panic`, the input stays in the devices until next time
+        if let Ok(mut c) = console.try_borrow_mut() {
+            c.route_input();
+        };
+    }
+    // the echo
+    flush(console);
+}
+
+/// Draws the changed rows of the active terminal and writes the serial output, without
+/// holding the console lock, so the interrupts stay enabled while waiting for the devices.
+///
+/// If the interrupts are disabled already (i.e. in an interrupt handler or `panic`), the
+/// output is written right away, even if a flush was interrupted in the middle, which can
+/// only put it before the part that flush was writing
+fn flush(console: &ReMutex<RefCell<LateConsole>>) {
+    let nested = cpu::cpu().interrupts_disabled();
+    if !nested && FLUSHING.swap(true, Ordering::Acquire) {
+        // the one flushing takes ours too
+        return;
+    }
+
+    let mut serial = [0; FLUSH_CHUNK];
+    let mut row = [0; VGA_WIDTH];
+    let mut painted_switches = None;
+    loop {
+        let (uart, len, row_index, switches) = {
+            let console = console.lock();
+            // its written by whoever is using it after they are done
+            let Ok(mut c) = console.try_borrow_mut() else {
+                break;
+            };
+            // a switch after we took the last row could have been drawn before we drew it
+            if painted_switches.is_some_and(|switches| switches != c.switches) {
+                let active = c.active;
+                c.terminals[active].screen.mark_all_dirty();
+            }
+            let (len, row_index) = c.take_output(&mut serial, &mut row);
+            (c.uart.clone(), len, row_index, c.switches)
+        };
+        if len == 0 && row_index.is_none() {
+            break;
+        }
+        for &byte in &serial[..len] {
+            // SAFETY: the uart is initialized since the early console
+            unsafe { uart.write_byte(byte) };
+        }
+        if let Some(row_index) = row_index {
+            video_memory::paint_row(row_index, &row);
+            painted_switches = Some(switches);
+        }
+    }
+
+    if !nested {
+        FLUSHING.store(false, Ordering::Release);
+        // an interrupt could have added more after our last look, and left it to us
+        let more = console.lock().try_borrow().is_ok_and(|c| c.has_output());
+        if more {
+            flush(console);
+        }
+    }
+}
+
+/// The state of the console, it only moves forward, from `Uninitialized` to `Late`
+// the early console can't be boxed, it is used before the heap
+#[allow(clippy::large_enum_variant)]
 pub(super) enum Console {
+    /// Anything before `early_init`, writes directly to the screen and the debug port
+    Uninitialized(ReMutex<RefCell<DirectConsole>>),
+    /// Same as `Uninitialized` but with the serial port, and it keeps the output around
     Early(ReMutex<RefCell<EarlyConsole>>),
+    /// Supports input as well, and can be used as a device
     Late(Arc<ReMutex<RefCell<LateConsole>>>),
 }
 
+/// Runs `f` with the console inside `console`, if its already in use, then we are
+/// inside `panic`, so use a new direct console
+fn run_with_locked<C, F, U>(console: &ReMutex<RefCell<C>>, uart: Option<Uart>, mut f: F) -> U
+where
+    C: Write,
+    F: FnMut(&mut dyn core::fmt::Write) -> U,
+{
+    let console = console.lock();
+    let x = if let Ok(mut c) = console.try_borrow_mut() {
+        f(&mut *c)
+    } else {
+        // if we can't get the lock, we are inside `panic`
+        //  create a new direct console and print to it
+        f(&mut DirectConsole::new(uart))
+    };
+    x
+}
+
 impl Console {
-    const fn empty_early() -> Self {
-        // SAFETY: this is only called once on static context so nothing is running
-        Self::Early(ReMutex::new(RefCell::new(unsafe { EarlyConsole::empty() })))
+    const fn empty() -> Self {
+        Self::Uninitialized(ReMutex::new(RefCell::new(DirectConsole::new(None))))
     }
 
-    fn init_early(&self) {
-        match self {
-            Self::Early(console) => {
-                let console = console.lock();
-                console.borrow_mut().init();
+    /// # SAFETY
+    /// Must ensure that there is no console is being printed to/running at the same time
+    unsafe fn init_early_serial(&mut self) {
+        // the other states have the serial port already
+        if let Self::Uninitialized(console) = self {
+            let uart = Uart::new(UartPort::COM1);
+            uart.init_early();
+            if uart.is_initialized() {
+                console.lock().borrow_mut().uart = Some(uart);
             }
-            Self::Late(_) => {
-                panic!("Unexpected late console");
+        }
+    }
+
+    /// # SAFETY
+    /// Must ensure that there is no console is being printed to/running at the same time
+    unsafe fn init_early(&mut self) {
+        match self {
+            Self::Uninitialized(console) => {
+                let early = EarlyConsole::migrate_from_direct(&console.lock().borrow());
+                *self = Self::Early(ReMutex::new(RefCell::new(early)));
             }
+            // already initialized
+            Self::Early(_) | Self::Late(_) => {}
         }
     }
 
+    /// Returns `false` if the console was already initialized
+    ///
     /// # SAFETY
     /// Must ensure that there is no console is being printed to/running at the same time
-    unsafe fn init_late(&mut self) {
+    unsafe fn init_late(&mut self, log_terminal: usize) -> bool {
+        self.init_early();
         match self {
             Self::Early(console) => {
                 // SAFETY: we are relying on the caller calling this function alone
                 //  since we are taking ownership of the early console, and we are sure that
                 //  its not being used anywhere, this is fine
-                let late_console = LateConsole::migrate_from_early(&console.lock().borrow());
+                let late_console = {
+                    let early = console.lock();
+                    let early = early.borrow();
+                    // there is only one early console, so this is only set once
+                    let _ = BOOT_LOG.set(early.retention.to_vec());
+                    LateConsole::migrate_from_early(&early, log_terminal)
+                };
                 *self = Self::Late(Arc::new(ReMutex::new(RefCell::new(late_console))));
+                true
             }
-            Self::Late(_) => {
-                panic!("Unexpected late console");
-            }
+            Self::Late(_) => false,
+            Self::Uninitialized(_) => unreachable!("initialized early above"),
         }
     }
 
     fn late_device(&self) -> Option<Arc<ReMutex<RefCell<LateConsole>>>> {
         match self {
-            Self::Early(_) => None,
+            Self::Uninitialized(_) | Self::Early(_) => None,
             Self::Late(console) => Some(console.clone()),
         }
     }
 
-    pub fn run_with<F, U>(&self, mut f: F) -> U
+    pub fn run_with<F, U>(&self, f: F) -> U
     where
         F: FnMut(&mut dyn core::fmt::Write) -> U,
     {
+        // the uart is not initialized before the early console, unless `early_serial_init`
+        let uart = Uart::new(UartPort::COM1);
+        let uart = uart.is_initialized().then_some(uart);
         match self {
-            Console::Early(console) => {
-                let console = console.lock();
-                let x = if let Ok(mut c) = console.try_borrow_mut() {
-                    f(&mut *c)
-                } else {
-                    // if we can't get the lock, we are inside `panic`
-                    //  create a new early console and print to it
-                    let mut console = unsafe { EarlyConsole::empty() };
-                    console.init();
-                    f(&mut console)
-                };
-                x
-            }
-            // we have to use another branch because the types are different
-            // even though we use same function calls
+            Console::Uninitialized(console) => run_with_locked(console, uart, f),
+            Console::Early(console) => run_with_locked(console, uart, f),
             Console::Late(console) => {
-                let console = console.lock();
-                let x = if let Ok(mut c) = console.try_borrow_mut() {
-                    f(&mut *c)
-                } else {
-                    // if we can't get the lock, we are inside `panic`
-                    //  create a new early console and print to it
-                    let mut console = unsafe { EarlyConsole::empty() };
-                    console.init();
-                    f(&mut console)
-                };
-                x
+                let result = run_with_locked(console, uart, f);
+                flush(console);
+                result
             }
         }
     }
 }
 
+/// A console that needs no initialization, and doesn't allocate, so it can be used anytime,
+/// even in `panic`.
+///
+/// It writes to the VGA buffer and the debug port, and to the serial port if its initialized.
+pub(super) struct DirectConsole {
+    uart: Option<Uart>,
+    video_buffer: VgaBuffer,
+    decoder: Utf8Decoder,
+    written: bool,
+}
+
+impl DirectConsole {
+    /// `uart` must be initialized already
+    const fn new(uart: Option<Uart>) -> Self {
+        Self {
+            uart,
+            video_buffer: VgaBuffer::new(),
+            decoder: Utf8Decoder::new(),
+            written: false,
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.written = true;
+        // SAFETY: writing to the debug port has no side effects if its not present
+        unsafe { cpu::io_out(DEBUG_PORT, byte) };
+        if let Some(uart) = &self.uart {
+            // SAFETY: the uart is initialized, see `new`
+            unsafe { uart.write_byte(byte) };
+        }
+        self.decoder
+            .push(byte, |c| self.video_buffer.write_char(c, DEFAULT_ATTRIB));
+    }
+}
+
+impl Write for DirectConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &c in s.as_bytes() {
+            self.write_byte(c);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the last [`RETENTION_SIZE`] bytes written
+pub(super) struct RetentionBuffer {
+    data: [u8; RETENTION_SIZE],
+    // total number of bytes written
+    written: usize,
+}
+
+impl RetentionBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; RETENTION_SIZE],
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.data[self.written % RETENTION_SIZE] = byte;
+        self.written += 1;
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        if self.written <= RETENTION_SIZE {
+            return self.data[..self.written].to_vec();
+        }
+        let split = self.written % RETENTION_SIZE;
+        let mut v = Vec::with_capacity(RETENTION_SIZE);
+        v.extend_from_slice(&self.data[split..]);
+        v.extend_from_slice(&self.data[..split]);
+        v
+    }
+}
+
 pub(super) struct EarlyConsole {
     uart: Uart,
     video_buffer: VgaBuffer,
+    decoder: Utf8Decoder,
+    retention: RetentionBuffer,
 }
 
 impl EarlyConsole {
     /// SAFETY: the console must be used inside a lock or mutex
     ///  as the Video buffer position is global
-    pub const unsafe fn empty() -> Self {
-        Self {
+    unsafe fn migrate_from_direct(direct: &DirectConsole) -> Self {
+        let mut s = Self {
             uart: Uart::new(UartPort::COM1),
-            video_buffer: VgaBuffer::new(),
+            video_buffer: direct.video_buffer.clone(),
+            decoder: direct.decoder,
+            retention: RetentionBuffer::new(),
+        };
+        // keep what was printed before, it could be an error we want to see
+        if !direct.written {
+            s.video_buffer.init();
         }
-    }
-
-    pub fn init(&mut self) {
-        self.video_buffer.init();
-        self.uart.init();
+        // does nothing if `early_serial_init` initialized it
+        s.uart.init();
+        s
     }
 
     /// SAFETY: the caller must assure that this is called from once place at a time
     ///         and should handle synchronization
     unsafe fn write_byte(&mut self, byte: u8) {
-        self.video_buffer.write_byte(byte, DEFAULT_ATTRIB);
+        // the serial terminal handles UTF-8 by itself
         self.uart.write_byte(byte);
+        self.retention.push(byte);
+        self.decoder
+            .push(byte, |c| self.video_buffer.write_char(c, DEFAULT_ATTRIB));
     }
 
     /// SAFETY: the caller must assure that this is called from once place at a time
@@ -184,19 +499,59 @@ impl Write for EarlyConsole {
     }
 }
 
+/// A virtual terminal, see the [module docs](self)
+struct Terminal {
+    screen: ShadowBuffer,
+    decoder: Utf8Decoder,
+    // the input that wasn't read yet, can have the rest of a char that didn't fit in the
+    // last read
+    input: LineDiscipline,
+}
+
+impl Terminal {
+    fn new() -> Self {
+        Self {
+            screen: ShadowBuffer::new(),
+            decoder: Utf8Decoder::new(),
+            input: LineDiscipline::new(),
+        }
+    }
+}
+
 pub(super) struct LateConsole {
     uart: Uart,
-    video_buffer: VgaBuffer,
+    terminals: Vec<Terminal>,
+    // the one on the screen, gets the keyboard input
+    active: usize,
+    // the one the kernel prints to, mirrored to the serial port and gets its input
+    log_terminal: usize,
     keyboard: Arc<Mutex<Keyboard>>,
+    // the output of the log terminal waiting for `flush`, and the echo of its serial input
+    serial_out: VecDeque<u8>,
+    // the number of terminal switches, so `flush` can tell its row is from another terminal
+    switches: u64,
 }
 
 impl LateConsole {
     /// SAFETY: must ensure that there is no console running at the same time
-    unsafe fn migrate_from_early(early: &EarlyConsole) -> Self {
+    unsafe fn migrate_from_early(early: &EarlyConsole, log_terminal: usize) -> Self {
+        let mut terminals = (0..NUM_TERMINALS)
+            .map(|_| Terminal::new())
+            .collect::<Vec<_>>();
+        // the log terminal continues what is on the screen
+        terminals[log_terminal] = Terminal {
+            screen: ShadowBuffer::capture(&early.video_buffer),
+            decoder: early.decoder,
+            input: LineDiscipline::new(),
+        };
         let mut s = Self {
             uart: early.uart.clone(),
-            video_buffer: early.video_buffer.clone(),
+            terminals,
+            active: log_terminal,
+            log_terminal,
             keyboard: keyboard::get_keyboard(),
+            serial_out: VecDeque::new(),
+            switches: 0,
         };
 
         // split inputs
@@ -207,34 +562,127 @@ impl LateConsole {
     /// SAFETY: the caller must assure that this is called from once place at a time
     ///         and should handle synchronization
     unsafe fn write_byte(&mut self, byte: u8) {
-        self.video_buffer.write_byte(byte, DEFAULT_ATTRIB);
-        self.uart.write_byte(byte);
+        self.write_terminal_byte(self.log_terminal, byte);
+    }
+
+    /// SAFETY: the caller must assure that this is called from once place at a time
+    ///         and should handle synchronization
+    unsafe fn write_terminal_byte(&mut self, index: usize, byte: u8) {
+        if index == self.log_terminal {
+            // the serial terminal handles UTF-8 by itself
+            self.queue_serial(byte);
+        }
+        let terminal = &mut self.terminals[index];
+        terminal
+            .decoder
+            .push(byte, |c| terminal.screen.write_char(c, DEFAULT_ATTRIB));
+    }
+
+    /// Adds a byte for [`flush`] to write to the serial port
+    fn queue_serial(&mut self, byte: u8) {
+        if self.serial_out.len() >= MAX_SERIAL_QUEUE {
+            let oldest = self.serial_out.pop_front().unwrap();
+            // SAFETY: the uart is initialized since the early console
+            unsafe { self.uart.write_byte(oldest) };
+        }
+        self.serial_out.push_back(byte);
+    }
+
+    /// Takes the serial output that fits in `serial`, and a changed row of the active terminal
+    fn take_output(
+        &mut self,
+        serial: &mut [u8],
+        row: &mut [u16; VGA_WIDTH],
+    ) -> (usize, Option<usize>) {
+        let len = serial.len().min(self.serial_out.len());
+        for (dst, byte) in serial.iter_mut().zip(self.serial_out.drain(..len)) {
+            *dst = byte;
+        }
+        let row_index = self.terminals[self.active].screen.take_dirty_row(row);
+        (len, row_index)
+    }
+
+    fn has_output(&self) -> bool {
+        !self.serial_out.is_empty() || self.terminals[self.active].screen.has_dirty_rows()
     }
 
     /// SAFETY: the caller must assure that this is called from once place at a time
     ///        and should handle synchronization
     pub unsafe fn write(&mut self, src: &[u8]) -> usize {
+        self.write_terminal(self.log_terminal, src)
+    }
+
+    /// SAFETY: the caller must assure that this is called from once place at a time
+    ///        and should handle synchronization
+    pub unsafe fn write_terminal(&mut self, index: usize, src: &[u8]) -> usize {
         for &c in src {
-            self.write_byte(c);
+            self.write_terminal_byte(index, c);
         }
         src.len()
     }
 
-    pub unsafe fn read(&mut self, dst: &mut [u8]) -> usize {
-        let mut i = 0;
-        let mut keyboard = self.keyboard.lock();
-        while i < dst.len() {
-            if let Some(c) = keyboard.get_next_char() {
-                if let Some(c) = c.virtual_char {
-                    dst[i] = c;
-                    i += 1;
+    pub fn read_terminal(&mut self, index: usize, dst: &mut [u8]) -> usize {
+        self.route_input();
+        self.terminals[index].input.read(dst)
+    }
+
+    /// Moves the keys from the keyboard to the active terminal, the keys before a switch
+    /// go to the terminal that was active when they were typed, and the serial input to the
+    /// log terminal
+    fn route_input(&mut self) {
+        let keyboard = self.keyboard.clone();
+        let mut keyboard = keyboard.lock();
+        while let Some(key) = keyboard.get_next_char() {
+            if let Some(index) = key.switch_terminal {
+                self.switch_terminal(index);
+                continue;
+            }
+            // ignore if its not a valid char
+            if let Some(c) = key.virtual_char {
+                let mut bytes = [0; 4];
+                let bytes = c.encode_utf8(&mut bytes).as_bytes();
+                self.push_input(self.active, InputSource::Keyboard, bytes);
+            }
+        }
+        drop(keyboard);
+
+        // SAFETY: the uart is initialized since the early console
+        while let Some(byte) = unsafe { self.uart.try_read_byte() } {
+            self.push_input(self.log_terminal, InputSource::Serial, &[byte]);
+        }
+    }
+
+    /// Adds the input to terminal `index`, and echoes it back to `source`
+    fn push_input(&mut self, index: usize, source: InputSource, bytes: &[u8]) {
+        for &byte in bytes {
+            let echo = self.terminals[index].input.push(byte, source);
+            if echo == Echo::None {
+                continue;
+            }
+            if echo == Echo::Interrupt {
+                // the scheduler can't be locked inside the console, a dropped one is counted
+                softirq::defer(move || signal::send_to_terminal(index, SIGINT));
+            }
+            let mut buf = [0; 4];
+            let len = echo.bytes(source, &mut buf);
+            for &b in &buf[..len] {
+                match source {
+                    // SAFETY: we are inside the console lock
+                    InputSource::Keyboard => unsafe { self.write_terminal_byte(index, b) },
+                    InputSource::Serial => self.queue_serial(b),
                 }
-                // ignore if its not a valid char
-            } else {
-                break;
             }
         }
-        i
+    }
+
+    /// Makes `index` the active terminal and draws it, safe to do between any writes, since
+    /// all the terminals keep their cells
+    fn switch_terminal(&mut self, index: usize) {
+        if index < self.terminals.len() && index != self.active {
+            self.active = index;
+            self.switches += 1;
+            self.terminals[index].screen.mark_all_dirty();
+        }
     }
 }
 
@@ -247,40 +695,242 @@ impl Write for LateConsole {
 
 impl fmt::Debug for LateConsole {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("LateConsole").finish()
+        f.debug_struct("LateConsole")
+            .field("active", &self.active)
+            .field("log_terminal", &self.log_terminal)
+            .finish()
     }
 }
 
+/// Reads the input of terminal `index`, or the log terminal if `None`
+fn read_locked(
+    console: &ReMutex<RefCell<LateConsole>>,
+    index: Option<usize>,
+    buf: &mut [u8],
+) -> u64 {
+    let guard = console.lock();
+    let x = if let Ok(mut c) = guard.try_borrow_mut() {
+        let index = index.unwrap_or(c.log_terminal);
+        c.read_terminal(index, buf)
+    } else {
+        // cannot read from console if its taken
+        0
+    };
+    drop(guard);
+    // the echo of the input routed while reading
+    flush(console);
+    x as u64
+}
+
+/// Writes to terminal `index`, or the log terminal if `None`
+fn write_locked(console: &ReMutex<RefCell<LateConsole>>, index: Option<usize>, buf: &[u8]) -> u64 {
+    let guard = console.lock();
+    let x = if let Ok(mut c) = guard.try_borrow_mut() {
+        let index = index.unwrap_or(c.log_terminal);
+        unsafe { c.write_terminal(index, buf) }
+    } else {
+        // this should not be reached at all, but just in case
+        //
+        // if we can't get the lock, we are inside `panic`
+        //  create a new direct console and print to it
+        let mut console = DirectConsole::new(Some(Uart::new(UartPort::COM1)));
+        for &b in buf {
+            console.write_byte(b);
+        }
+        buf.len()
+    };
+    drop(guard);
+    flush(console);
+    x as u64
+}
+
 impl Device for ReMutex<RefCell<LateConsole>> {
     fn name(&self) -> &str {
         "console"
     }
 
-    fn read(&self, _offset: u32, buf: &mut [u8]) -> Result<u64, FileSystemError> {
-        let console = self.lock();
-        let x = if let Ok(mut c) = console.try_borrow_mut() {
-            unsafe { c.read(buf) }
-        } else {
-            // cannot read from console if its taken
-            0
-        };
-        Ok(x as u64)
-    }
-
-    fn write(&self, _offset: u32, buf: &[u8]) -> Result<u64, FileSystemError> {
-        let console = self.lock();
-        let x = if let Ok(mut c) = console.try_borrow_mut() {
-            unsafe { c.write(buf) }
-        } else {
-            // this should not be reached at all, but just in case
-            //
-            // if we can't get the lock, we are inside `panic`
-            //  create a new early console and print to it
-            let mut console = unsafe { EarlyConsole::empty() };
-            console.init();
-            unsafe { console.write(buf) }
-        };
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(read_locked(self, None, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        Ok(write_locked(self, None, buf))
+    }
+}
+
+/// `/devices/ttyN`, a virtual terminal of the console
+#[derive(Debug)]
+struct TerminalDevice {
+    console: Arc<ReMutex<RefCell<LateConsole>>>,
+    index: usize,
+    name: String,
+}
+
+impl Device for TerminalDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(read_locked(&self.console, Some(self.index), buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        Ok(write_locked(&self.console, Some(self.index), buf))
+    }
+
+    fn as_terminal(&self) -> Option<usize> {
+        Some(self.index)
+    }
+}
+
+/// `/devices/boot_log`, the output of the kernel before the console device was created
+#[derive(Debug)]
+struct BootLog;
+
+impl Device for BootLog {
+    fn name(&self) -> &str {
+        "boot_log"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let log = BOOT_LOG.get();
+        Ok(devices::read_bytes(log, offset, buf))
+    }
+}
 
-        Ok(x as u64)
+/// `/devices/vt_dump`, the text of all the terminals, only added by [`run_self_tests`]
+#[derive(Debug)]
+struct TerminalDump {
+    console: Arc<ReMutex<RefCell<LateConsole>>>,
+}
+
+impl TerminalDump {
+    fn screens(&self) -> Vec<String> {
+        let console = self.console.lock();
+        let console = console.borrow();
+        console
+            .terminals
+            .iter()
+            .map(|terminal| terminal.screen.text())
+            .collect()
+    }
+
+    fn active(&self) -> usize {
+        self.console.lock().borrow().active
     }
 }
+
+impl Device for TerminalDump {
+    fn name(&self) -> &str {
+        "vt_dump"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let active = self.active();
+        let mut dump = String::new();
+        for (i, screen) in self.screens().iter().enumerate() {
+            let marker = if i == active { " (active)" } else { "" };
+            dump.push_str(&format!("== tty{}{marker} ==\n", i + 1));
+            dump.push_str(screen);
+        }
+        Ok(devices::read_bytes(dump.as_bytes(), offset, buf))
+    }
+}
+
+/// Switches the terminals with the keyboard and checks where the output and the input go.
+///
+/// Adds `/devices/vt_dump`, with the text of all the terminals, for the userspace tests
+pub fn run_self_tests() {
+    println!("Running virtual terminal self tests...");
+
+    let dump = TerminalDump {
+        console: LATE_CONSOLE.get().clone(),
+    };
+    let log_terminal = dump.console.lock().borrow().log_terminal;
+    let started_on = dump.active();
+    // not the log terminal, whichever it is
+    let (first, second) = match log_terminal {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+    let open = |index: usize| fs::open(&format!("/devices/tty{}", index + 1)).unwrap();
+    let mut first_file = open(first);
+    let mut second_file = open(second);
+
+    first_file.write(b"selftest: first terminal\n").unwrap();
+    second_file.write(b"selftest: second terminal\n").unwrap();
+    let screens = dump.screens();
+    assert!(screens[first].contains("selftest: first terminal"));
+    assert!(screens[second].contains("selftest: second terminal"));
+    for (i, screen) in screens.iter().enumerate() {
+        if i != first {
+            assert!(!screen.contains("selftest: first terminal"));
+        }
+        if i != second {
+            assert!(!screen.contains("selftest: second terminal"));
+        }
+    }
+
+    // Alt+Fn of the first, then `x`, `y`, Backspace and Enter, goes to the first
+    let switch_to = |index: usize| {
+        let f_key = 0x3B + index;
+        format!("key press 0x38\nkey press {f_key:#x}\nkey release {f_key:#x}\nkey release 0x38")
+    };
+    // replayed like the keyboard interrupt, with the softirq right after
+    let replay = |script: &str| {
+        input_inject::inject_script(script).unwrap();
+        softirq::run_pending();
+    };
+    replay(&switch_to(first));
+    assert_eq!(dump.active(), first, "vt self test: did not switch");
+    replay("key press 0x2D\nkey release 0x2D\nkey press 0x15\nkey release 0x15");
+    let mut buf = [0; 8];
+    // not a line yet
+    assert_eq!(first_file.read(&mut buf).unwrap(), 0);
+    assert!(dump.screens()[first].contains("selftest: first terminal\nxy"));
+    replay("key press 0x0E\nkey release 0x0E\nkey press 0x1C\nkey release 0x1C");
+    assert_eq!(first_file.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"x\n");
+    assert_eq!(second_file.read(&mut buf).unwrap(), 0);
+    // the echo was erased
+    assert!(dump.screens()[first].contains("selftest: first terminal\nx\n"));
+
+    // the log keeps going to its terminal while its hidden
+    println!("selftest: printed while hidden");
+    assert!(dump.screens()[log_terminal].contains("selftest: printed while hidden"));
+    assert!(!dump.screens()[first].contains("selftest: printed while hidden"));
+
+    replay(&switch_to(started_on));
+    assert_eq!(dump.active(), started_on);
+
+    // the serial input edits the line the same way, and its echo only goes to the serial port
+    let mut line = LineDiscipline::new();
+    let mut echo = Vec::new();
+    for &byte in b"ab\x7fc\x1b[Ad\r\n\xC3\xA9\x08e\r" {
+        let mut buf = [0; 4];
+        let len = line
+            .push(byte, InputSource::Serial)
+            .bytes(InputSource::Serial, &mut buf);
+        echo.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(echo, b"ab\x08 \x08cd\r\n\xC3\xA9\x08 \x08e\r\n");
+    let mut buf = [0; 16];
+    let len = line.read(&mut buf);
+    // the escape sequence is passed through before the line
+    assert_eq!(&buf[..len], b"\x1b[Aacd\ne\n");
+
+    // `Ctrl+C` drops the line being edited, the ended ones stay
+    let mut line = LineDiscipline::new();
+    let mut echos = Vec::new();
+    for &byte in b"a\rbc\x03d\r" {
+        echos.push(line.push(byte, InputSource::Keyboard));
+    }
+    assert_eq!(echos[4], Echo::Interrupt);
+    let len = line.read(&mut buf);
+    assert_eq!(&buf[..len], b"a\nd\n");
+    devices::register_device(Arc::new(dump));
+
+    println!("Virtual terminal self tests passed");
+}
@@ -0,0 +1,241 @@
+//! `/devices/input_inject`, replays keys and mouse packets for the tests.
+//!
+//! A write is a script of [`InputRecord`]s, one per line, blank lines and the ones starting
+//! with `#` are skipped. The whole write is refused if a line is not a record, or if more than
+//! [`MAX_PENDING_RECORDS`] would wait. Only the privileged processes can write it.
+//!
+//! The records go through what the interrupts use: the keys are queued in the ring of the
+//! keyboard with the [`SoftIrq::TtyInput`](crate::cpu::softirq::SoftIrq::TtyInput) softirq
+//! raised, and the mouse packets are fed a byte at a time to the driver. The ones after a
+//! delay are replayed by the timer interrupt once it's over, so they arrive in the middle of
+//! whatever the CPU is doing, like real input. Without a clock the delays are skipped.
+//!
+//! Reading gives the records still waiting, and the counts of the ones replayed.
+
+use core::fmt;
+
+use alloc::{collections::VecDeque, format, sync::Arc, vec::Vec};
+use kernel_user_link::input::InputRecord;
+
+use crate::{
+    devices::{self, clock, Device},
+    fs::FileSystemError,
+    process::scheduler::with_current_process,
+    sync::spin::mutex::Mutex,
+};
+
+use super::{keyboard, mouse};
+
+/// The records that can wait to be replayed
+pub const MAX_PENDING_RECORDS: usize = 4096;
+
+static REPLAY: Mutex<Replay> = Mutex::new(Replay::empty());
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InjectStats {
+    pub keys: u64,
+    pub mouse: u64,
+    pub delays: u64,
+    /// The keys that didn't fit in the ring of the keyboard, or the packets without a mouse
+    pub dropped: u64,
+}
+
+impl fmt::Display for InjectStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "mouse: {}", self.mouse)?;
+        writeln!(f, "delays: {}", self.delays)?;
+        writeln!(f, "dropped: {}", self.dropped)
+    }
+}
+
+#[derive(Debug)]
+struct Replay {
+    records: VecDeque<InputRecord>,
+    // the uptime the delay being waited ends at
+    resume_at: u64,
+    stats: InjectStats,
+}
+
+impl Replay {
+    const fn empty() -> Self {
+        Self {
+            records: VecDeque::new(),
+            resume_at: 0,
+            stats: InjectStats {
+                keys: 0,
+                mouse: 0,
+                delays: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    /// Replays the records until a delay that is not over at `now`
+    fn advance(&mut self, now: u64) {
+        while now >= self.resume_at {
+            let Some(record) = self.records.pop_front() else {
+                break;
+            };
+            match record {
+                InputRecord::Key { scancode, pressed } => {
+                    let (bytes, len) = InputRecord::scancode_bytes(scancode, pressed);
+                    if keyboard::inject_scancodes(&bytes[..len]) == len {
+                        self.stats.keys += 1;
+                    } else {
+                        self.stats.dropped += 1;
+                    }
+                }
+                InputRecord::Mouse(event) => {
+                    if mouse::inject_event(&event) {
+                        self.stats.mouse += 1;
+                    } else {
+                        self.stats.dropped += 1;
+                    }
+                }
+                InputRecord::Delay { micros } => {
+                    self.stats.delays += 1;
+                    // no clock, `now` never moves
+                    if now != 0 {
+                        self.resume_at = now + micros * 1000;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The records of a script, or `None` if a line is not one
+fn parse_script(script: &str) -> Option<Vec<InputRecord>> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(InputRecord::parse)
+        .collect()
+}
+
+/// Queues the records of `script` after the ones waiting, and replays those that don't wait
+/// for a delay right away
+pub fn inject_script(script: &str) -> Result<(), FileSystemError> {
+    let records = parse_script(script).ok_or(FileSystemError::InvalidData)?;
+    let mut replay = REPLAY.lock();
+    if replay.records.len() + records.len() > MAX_PENDING_RECORDS {
+        return Err(FileSystemError::NoSpace);
+    }
+    replay.records.extend(records);
+    replay.advance(clock::uptime_nanos());
+    Ok(())
+}
+
+/// Called by the timer interrupt, replays the records whose delay is over
+pub fn tick() {
+    let mut replay = REPLAY.lock();
+    if !replay.records.is_empty() {
+        replay.advance(clock::uptime_nanos());
+    }
+}
+
+pub fn pending() -> usize {
+    REPLAY.lock().records.len()
+}
+
+#[derive(Debug)]
+struct InputInjectDevice;
+
+impl Device for InputInjectDevice {
+    fn name(&self) -> &str {
+        "input_inject"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let (pending, stats) = {
+            let replay = REPLAY.lock();
+            (replay.records.len(), replay.stats)
+        };
+        let info = format!("pending: {pending}\n{stats}");
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if !with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let script = core::str::from_utf8(buf).map_err(|_| FileSystemError::InvalidData)?;
+        inject_script(script)?;
+        Ok(buf.len() as u64)
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(InputInjectDevice));
+}
+
+/// Replays modifier keys, which don't type anything in the terminals, with made up times
+/// around a delay, and checks the lines that are not records
+pub fn run_self_tests() {
+    println!("Running input injection self tests...");
+
+    assert!(
+        parse_script("key press 0x1E\n# comment\n\n  delay 5ms  \nkey release 0xE038").is_some()
+    );
+    for line in [
+        "key press 0x9E",
+        "key press 0xE11D",
+        "key hold 0x1E",
+        "mouse rel 256 0",
+        "mouse rel 1 2 wheel 8",
+        "mouse rel 1 2 buttons 1 extra",
+        "delay 11000ms",
+        "delay 5",
+    ] {
+        assert!(
+            InputRecord::parse(line).is_none(),
+            "input inject self test: {line:?} taken"
+        );
+    }
+    let record = InputRecord::parse("mouse rel 5 -3 buttons 1").unwrap();
+    assert_eq!(InputRecord::parse(&format!("{record}")), Some(record));
+    assert!(matches!(
+        inject_script("key press 0x2A\nbad"),
+        Err(FileSystemError::InvalidData)
+    ));
+    assert_eq!(
+        pending(),
+        0,
+        "input inject self test: a bad script was queued"
+    );
+
+    // left shift, then right shift after the delay, which needs the time to pass
+    let script = "key press 0x2A\nkey release 0x2A\ndelay 2ms\nkey press 0x36\nkey release 0x36";
+    let mut replay = Replay::empty();
+    replay.records.extend(parse_script(script).unwrap());
+    replay.advance(1_000);
+    assert_eq!(replay.records.len(), 2);
+    assert_eq!(replay.resume_at, 2_001_000);
+    replay.advance(2_000_999);
+    assert_eq!(
+        replay.records.len(),
+        2,
+        "input inject self test: the delay was cut"
+    );
+    replay.advance(2_001_000);
+    assert!(replay.records.is_empty());
+    // without a clock it doesn't wait
+    let mut no_clock = Replay::empty();
+    no_clock.records.extend(parse_script(script).unwrap());
+    no_clock.advance(0);
+    assert!(no_clock.records.is_empty());
+    let expected = InjectStats {
+        keys: 4,
+        mouse: 0,
+        delays: 1,
+        dropped: 0,
+    };
+    assert_eq!(replay.stats, expected);
+    assert_eq!(no_clock.stats, expected);
+    // the shifts were queued for the keyboard
+    crate::cpu::softirq::run_pending();
+
+    println!("Input injection self tests passed");
+}
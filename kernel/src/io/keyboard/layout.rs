@@ -0,0 +1,215 @@
+//! Keyboard layouts, mapping the printable keys (by their set 1 scancode) to chars.
+//!
+//! The keys that are the same in all layouts (Enter, Backspace, the keypad...) are handled
+//! by the keyboard directly.
+
+/// Scancodes handled by the layouts are below this
+pub const LAYOUT_KEYS: usize = 0x59;
+
+/// Unmapped key
+const NO: char = '\0';
+
+/// The chars produced by a key, `'\0'` if there is nothing
+#[derive(Debug, Clone, Copy)]
+pub struct KeyChars {
+    pub normal: char,
+    pub shifted: char,
+    pub altgr: char,
+}
+
+pub struct KeyboardLayout {
+    pub name: &'static str,
+    keys: [KeyChars; LAYOUT_KEYS],
+    /// Chars that don't produce anything by themselves, but modify the next key
+    dead_keys: &'static [char],
+    /// (dead key, key) -> composed char
+    compositions: &'static [(char, char, char)],
+}
+
+/// Builds the key table from rows of `(scancode, [normal, shifted, altgr])`
+const fn keys(rows: &[(u8, [char; 3])]) -> [KeyChars; LAYOUT_KEYS] {
+    let mut keys = [KeyChars {
+        normal: NO,
+        shifted: NO,
+        altgr: NO,
+    }; LAYOUT_KEYS];
+    let mut i = 0;
+    while i < rows.len() {
+        let (scancode, [normal, shifted, altgr]) = rows[i];
+        keys[scancode as usize] = KeyChars {
+            normal,
+            shifted,
+            altgr,
+        };
+        i += 1;
+    }
+    keys
+}
+
+impl KeyboardLayout {
+    pub fn key(&self, scancode: u8) -> Option<KeyChars> {
+        self.keys.get(scancode as usize).copied()
+    }
+
+    pub fn is_dead_key(&self, c: char) -> bool {
+        self.dead_keys.contains(&c)
+    }
+
+    /// The char from combining the dead key `dead` with `c`, if there is one
+    pub fn compose(&self, dead: char, c: char) -> Option<char> {
+        self.compositions
+            .iter()
+            .find(|(d, base, _)| *d == dead && *base == c)
+            .map(|(_, _, composed)| *composed)
+    }
+}
+
+pub static US: KeyboardLayout = KeyboardLayout {
+    name: "us",
+    keys: keys(&[
+        (0x02, ['1', '!', NO]),
+        (0x03, ['2', '@', NO]),
+        (0x04, ['3', '#', NO]),
+        (0x05, ['4', '$', NO]),
+        (0x06, ['5', '%', NO]),
+        (0x07, ['6', '^', NO]),
+        (0x08, ['7', '&', NO]),
+        (0x09, ['8', '*', NO]),
+        (0x0A, ['9', '(', NO]),
+        (0x0B, ['0', ')', NO]),
+        (0x0C, ['-', '_', NO]),
+        (0x0D, ['=', '+', NO]),
+        (0x10, ['q', 'Q', NO]),
+        (0x11, ['w', 'W', NO]),
+        (0x12, ['e', 'E', NO]),
+        (0x13, ['r', 'R', NO]),
+        (0x14, ['t', 'T', NO]),
+        (0x15, ['y', 'Y', NO]),
+        (0x16, ['u', 'U', NO]),
+        (0x17, ['i', 'I', NO]),
+        (0x18, ['o', 'O', NO]),
+        (0x19, ['p', 'P', NO]),
+        (0x1A, ['[', '{', NO]),
+        (0x1B, [']', '}', NO]),
+        (0x1E, ['a', 'A', NO]),
+        (0x1F, ['s', 'S', NO]),
+        (0x20, ['d', 'D', NO]),
+        (0x21, ['f', 'F', NO]),
+        (0x22, ['g', 'G', NO]),
+        (0x23, ['h', 'H', NO]),
+        (0x24, ['j', 'J', NO]),
+        (0x25, ['k', 'K', NO]),
+        (0x26, ['l', 'L', NO]),
+        (0x27, [';', ':', NO]),
+        (0x28, ['\'', '"', NO]),
+        (0x29, ['`', '~', NO]),
+        (0x2B, ['\\', '|', NO]),
+        (0x2C, ['z', 'Z', NO]),
+        (0x2D, ['x', 'X', NO]),
+        (0x2E, ['c', 'C', NO]),
+        (0x2F, ['v', 'V', NO]),
+        (0x30, ['b', 'B', NO]),
+        (0x31, ['n', 'N', NO]),
+        (0x32, ['m', 'M', NO]),
+        (0x33, [',', '<', NO]),
+        (0x34, ['.', '>', NO]),
+        (0x35, ['/', '?', NO]),
+    ]),
+    dead_keys: &[],
+    compositions: &[],
+};
+
+/// German QWERTZ, `^`, `´` and `` ` `` are dead keys
+pub static DE: KeyboardLayout = KeyboardLayout {
+    name: "de",
+    keys: keys(&[
+        (0x02, ['1', '!', NO]),
+        (0x03, ['2', '"', '²']),
+        (0x04, ['3', '§', '³']),
+        (0x05, ['4', '$', NO]),
+        (0x06, ['5', '%', NO]),
+        (0x07, ['6', '&', NO]),
+        (0x08, ['7', '/', '{']),
+        (0x09, ['8', '(', '[']),
+        (0x0A, ['9', ')', ']']),
+        (0x0B, ['0', '=', '}']),
+        (0x0C, ['ß', '?', '\\']),
+        (0x0D, ['´', '`', NO]),
+        (0x10, ['q', 'Q', '@']),
+        (0x11, ['w', 'W', NO]),
+        (0x12, ['e', 'E', '€']),
+        (0x13, ['r', 'R', NO]),
+        (0x14, ['t', 'T', NO]),
+        (0x15, ['z', 'Z', NO]),
+        (0x16, ['u', 'U', NO]),
+        (0x17, ['i', 'I', NO]),
+        (0x18, ['o', 'O', NO]),
+        (0x19, ['p', 'P', NO]),
+        (0x1A, ['ü', 'Ü', NO]),
+        (0x1B, ['+', '*', '~']),
+        (0x1E, ['a', 'A', NO]),
+        (0x1F, ['s', 'S', NO]),
+        (0x20, ['d', 'D', NO]),
+        (0x21, ['f', 'F', NO]),
+        (0x22, ['g', 'G', NO]),
+        (0x23, ['h', 'H', NO]),
+        (0x24, ['j', 'J', NO]),
+        (0x25, ['k', 'K', NO]),
+        (0x26, ['l', 'L', NO]),
+        (0x27, ['ö', 'Ö', NO]),
+        (0x28, ['ä', 'Ä', NO]),
+        (0x29, ['^', '°', NO]),
+        (0x2B, ['#', '\'', NO]),
+        (0x2C, ['y', 'Y', NO]),
+        (0x2D, ['x', 'X', NO]),
+        (0x2E, ['c', 'C', NO]),
+        (0x2F, ['v', 'V', NO]),
+        (0x30, ['b', 'B', NO]),
+        (0x31, ['n', 'N', NO]),
+        (0x32, ['m', 'M', 'µ']),
+        (0x33, [',', ';', NO]),
+        (0x34, ['.', ':', NO]),
+        (0x35, ['-', '_', NO]),
+        (0x56, ['<', '>', '|']),
+    ]),
+    dead_keys: &['^', '´', '`'],
+    compositions: &[
+        ('^', 'a', 'â'),
+        ('^', 'e', 'ê'),
+        ('^', 'i', 'î'),
+        ('^', 'o', 'ô'),
+        ('^', 'u', 'û'),
+        ('^', 'A', 'Â'),
+        ('^', 'E', 'Ê'),
+        ('^', 'I', 'Î'),
+        ('^', 'O', 'Ô'),
+        ('^', 'U', 'Û'),
+        ('´', 'a', 'á'),
+        ('´', 'e', 'é'),
+        ('´', 'i', 'í'),
+        ('´', 'o', 'ó'),
+        ('´', 'u', 'ú'),
+        ('´', 'A', 'Á'),
+        ('´', 'E', 'É'),
+        ('´', 'I', 'Í'),
+        ('´', 'O', 'Ó'),
+        ('´', 'U', 'Ú'),
+        ('`', 'a', 'à'),
+        ('`', 'e', 'è'),
+        ('`', 'i', 'ì'),
+        ('`', 'o', 'ò'),
+        ('`', 'u', 'ù'),
+        ('`', 'A', 'À'),
+        ('`', 'E', 'È'),
+        ('`', 'I', 'Ì'),
+        ('`', 'O', 'Ò'),
+        ('`', 'U', 'Ù'),
+    ],
+};
+
+/// All the available layouts, the first is the default
+pub static LAYOUTS: [&KeyboardLayout; 2] = [&US, &DE];
+
+pub fn find_layout(name: &str) -> Option<&'static KeyboardLayout> {
+    LAYOUTS.iter().copied().find(|layout| layout.name == name)
+}
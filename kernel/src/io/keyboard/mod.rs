@@ -0,0 +1,1004 @@
+//! PS/2 keyboard driver
+//!
+//! The scancodes (set 1) are translated to [`Key`]s using the selected [`KeyboardLayout`],
+//! which can be changed from `/devices/keyboard_layout`. USB keyboards translate their reports
+//! to the same scancodes, and give them with [`feed_scancodes`].
+//!
+//! The interrupt only queues the bytes it reads, they are translated by the
+//! [`SoftIrq::TtyInput`] softirq, with the interrupts enabled, which moves the keys to the
+//! console. At most [`MAX_QUEUED_SCANCODES`] bytes wait, the ones after that are dropped.
+//!
+//! `Alt+F1..F4` don't produce chars, they are keys that switch the virtual terminal
+//! (see [`Key::switch_terminal`]), so the console switches in order with the rest of the input.
+
+mod layout;
+
+use core::fmt;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    collections::ring::RingBuffer,
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+        irq_off,
+        softirq::{self, SoftIrq},
+    },
+    devices::{self, Device},
+    fs::FileSystemError,
+    io::{
+        console, mouse,
+        ps2::{self, status, PortIo, SimulatedPort},
+    },
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+use self::layout::KeyboardLayout;
+
+static KEYBOARD: OnceLock<Arc<Mutex<Keyboard>>> = OnceLock::new();
+static SCANCODES: Mutex<ScancodeQueue> = Mutex::new(ScancodeQueue::empty());
+
+const SCANCODE_QUEUE_LEN: usize = 256;
+/// The bytes that can wait for the softirq
+pub const MAX_QUEUED_SCANCODES: usize = SCANCODE_QUEUE_LEN - 1;
+
+pub fn init_keyboard() {
+    let keyboard = Keyboard::empty();
+
+    // set the state of the keyboard before we get any interrupts, so the responses are not
+    // taken as keys
+    {
+        let mut controller = ps2::CONTROLLER.lock();
+        if let Err(e) =
+            ps2::send_command(&mut *controller, &[command::SET_TYPEMATIC, TYPEMATIC_RATE])
+        {
+            eprintln!("keyboard: failed to set typematic rate: {e:?}");
+        }
+        keyboard.update_leds(&mut *controller);
+    }
+
+    let device = Arc::new(Mutex::new(keyboard));
+    KEYBOARD
+        .set(device)
+        .unwrap_or_else(|_| panic!("keyboard already initialized"));
+
+    devices::register_device(Arc::new(KeyboardLayoutControl));
+    softirq::register(SoftIrq::TtyInput, translate_queued);
+
+    // assign after we have assigned the keyboard
+    apic::assign_io_irq(
+        keyboard_interrupt_handler as BasicInterruptHandler,
+        KEYBOARD_INT_NUM,
+        cpu::cpu(),
+    )
+}
+
+pub fn get_keyboard() -> Arc<Mutex<Keyboard>> {
+    KEYBOARD.get().clone()
+}
+
+/// Polls the keyboard without waiting for its lock, for when interrupts can't
+/// be used (i.e. in `panic`)
+pub fn try_get_next_char() -> Option<char> {
+    let mut keyboard = KEYBOARD.try_get()?.try_lock()?;
+    keyboard.get_next_char()?.virtual_char
+}
+
+// PS/2 keyboard interrupt
+const KEYBOARD_INT_NUM: u8 = 1;
+
+/// 500ms delay, 30 keys per second
+const TYPEMATIC_RATE: u8 = 0b01 << 5;
+
+#[allow(dead_code)]
+mod command {
+    pub const SET_LEDS: u8 = 0xED;
+    pub const SET_TYPEMATIC: u8 = 0xF3;
+}
+
+#[allow(dead_code)]
+mod led {
+    pub const SCROLL_LOCK: u8 = 1 << 0;
+    pub const NUM_LOCK: u8 = 1 << 1;
+    pub const CAPS_LOCK: u8 = 1 << 2;
+}
+
+/// The lock keys that are on, what the LEDs of the keyboard show
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockKeys {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    // normal keys (mapped 1:1 with set 1 scan codes)
+    _None1,
+    Escape,
+    Num1,
+    Num2,
+    Num3,
+    Num4,
+    Num5,
+    Num6,
+    Num7,
+    Num8,
+    Num9,
+    Num0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Enter,
+    LeftCtrl,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    SingleQuote,
+    Backtick,
+    LeftShift,
+    Backslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Dot,
+    Slash,
+    RightShift,
+    KeypadAsterisk,
+    LeftAlt,
+    Space,
+    CapsLock,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    NumLock,
+    ScrollLock,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+    KeypadMinus,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    KeypadPlus,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad0,
+    KeypadDot,
+    _None2,
+    _None3,
+    _None4,
+    F11,
+    F12,
+
+    // extended keys
+    MultimediaPreviousTrack,
+    MultimediaNextTrack,
+    KeypadEnter,
+    RightCtrl,
+    MultimediaMute,
+    Calculator,
+    MultimediaPlayPause,
+    MultimediaStop,
+    VolumeDown,
+    VolumeUp,
+    WWWHome,
+    KeypadSlash,
+    RightAlt,
+    Home,
+    UpArrow,
+    PageUp,
+    LeftArrow,
+    RightArrow,
+    End,
+    DownArrow,
+    PageDown,
+    Insert,
+    Delete,
+    LeftGUI,
+    RightGUI,
+    Application,
+    Power,
+    Sleep,
+    Wake,
+    WWWSearch,
+    WWWFavorites,
+    WWWRefresh,
+    WWWStop,
+    WWWForward,
+    WWWBack,
+    MyComputer,
+    Email,
+    MultimediaSelect,
+}
+
+impl TryFrom<u8> for KeyType {
+    type Error = ();
+
+    // 0x80 means extended key
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value & 0x80 == 0 {
+            if value > Self::F12 as u8 {
+                return Err(());
+            }
+            // not extended.
+            // we know that we are mapping the not extended keys directly
+            // so we can just cast it
+            let k = unsafe { core::mem::transmute(value) };
+            if k == Self::_None1 || k == Self::_None2 || k == Self::_None3 || k == Self::_None4 {
+                return Err(());
+            }
+            Ok(k)
+        } else {
+            // first, strip the extension
+            let key = value & !0x80;
+
+            // use match normally
+            match key {
+                0x10 => Ok(Self::MultimediaPreviousTrack),
+                0x19 => Ok(Self::MultimediaNextTrack),
+                0x1C => Ok(Self::KeypadEnter),
+                0x1D => Ok(Self::RightCtrl),
+                0x20 => Ok(Self::MultimediaMute),
+                0x21 => Ok(Self::Calculator),
+                0x22 => Ok(Self::MultimediaPlayPause),
+                0x24 => Ok(Self::MultimediaStop),
+                0x2E => Ok(Self::VolumeDown),
+                0x30 => Ok(Self::VolumeUp),
+                0x32 => Ok(Self::WWWHome),
+                0x35 => Ok(Self::KeypadSlash),
+                0x38 => Ok(Self::RightAlt),
+                0x47 => Ok(Self::Home),
+                0x48 => Ok(Self::UpArrow),
+                0x49 => Ok(Self::PageUp),
+                0x4B => Ok(Self::LeftArrow),
+                0x4D => Ok(Self::RightArrow),
+                0x4F => Ok(Self::End),
+                0x50 => Ok(Self::DownArrow),
+                0x51 => Ok(Self::PageDown),
+                0x52 => Ok(Self::Insert),
+                0x53 => Ok(Self::Delete),
+                0x5B => Ok(Self::LeftGUI),
+                0x5C => Ok(Self::RightGUI),
+                0x5D => Ok(Self::Application),
+                0x5E => Ok(Self::Power),
+                0x5F => Ok(Self::Sleep),
+                0x63 => Ok(Self::Wake),
+                0x65 => Ok(Self::WWWSearch),
+                0x66 => Ok(Self::WWWFavorites),
+                0x67 => Ok(Self::WWWRefresh),
+                0x68 => Ok(Self::WWWStop),
+                0x69 => Ok(Self::WWWForward),
+                0x6A => Ok(Self::WWWBack),
+                0x6B => Ok(Self::MyComputer),
+                0x6C => Ok(Self::Email),
+                0x6D => Ok(Self::MultimediaSelect),
+                _ => Err(()),
+            }
+        }
+    }
+}
+#[allow(dead_code)]
+mod modifier {
+    pub const SHIFT: u8 = 1 << 0;
+    pub const CTRL: u8 = 1 << 1;
+    pub const ALT: u8 = 1 << 2;
+
+    pub const CAPS_LOCK: u8 = 1 << 3;
+    pub const NUM_LOCK: u8 = 1 << 4;
+    pub const SCROLL_LOCK: u8 = 1 << 5;
+    pub const EXTENDED: u8 = 1 << 6;
+    /// The right alt key
+    pub const ALT_GR: u8 = 1 << 7;
+}
+
+const fn get_modifier(key: u8) -> Option<u8> {
+    match key {
+        0x2A => Some(modifier::SHIFT),
+        0x36 => Some(modifier::SHIFT),
+        0x1D => Some(modifier::CTRL),
+        0x38 => Some(modifier::ALT),
+        _ => None,
+    }
+}
+
+const fn get_extended_modifier(key: u8) -> Option<u8> {
+    match key {
+        0x1D => Some(modifier::CTRL),
+        0x38 => Some(modifier::ALT_GR),
+        _ => None,
+    }
+}
+
+const fn get_toggle(key: u8) -> Option<u8> {
+    match key {
+        0x3A => Some(modifier::CAPS_LOCK),
+        0x45 => Some(modifier::NUM_LOCK),
+        0x46 => Some(modifier::SCROLL_LOCK),
+        _ => None,
+    }
+}
+
+const KEY_PRESSED: u8 = 1 << 7;
+const EXTENDED_PREFIX: u8 = 0xE0;
+
+/// The keypad keys from `Keypad7` to `KeypadDot`, when num lock is on
+const KEYPAD_START: u8 = 0x47;
+const KEYPAD_CHARS: &[u8; 13] = b"789-456+1230.";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Key {
+    pub virtual_char: Option<char>,
+    pub key_type: KeyType,
+    /// The index of the virtual terminal to switch to for `Alt+F1..F4`
+    pub switch_terminal: Option<usize>,
+}
+
+/// Where a queued byte came from, the LED commands of the injected ones are acked by a
+/// simulated controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScancodeSource {
+    Ps2,
+    Injected,
+}
+
+/// The bytes of the interrupt, in order, until they are translated
+struct ScancodeQueue {
+    ring: RingBuffer<(u8, ScancodeSource), SCANCODE_QUEUE_LEN>,
+    dropped: u64,
+}
+
+impl ScancodeQueue {
+    const fn empty() -> Self {
+        Self {
+            ring: RingBuffer::empty(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns `false` and counts the byte as dropped if the queue is full
+    fn push(&mut self, data: u8, source: ScancodeSource) -> bool {
+        let queued = self.ring.try_push((data, source));
+        if !queued {
+            self.dropped += 1;
+        }
+        queued
+    }
+}
+
+// A mini keyboard driver/mapper
+pub struct Keyboard {
+    active_modifiers: u8,
+    active_toggles: u8,
+    // the last byte was `0xE0`
+    extended: bool,
+    // a dead key waiting for the next key to compose with
+    dead_key: Option<(char, KeyType)>,
+    layout: &'static KeyboardLayout,
+    input_ring: RingBuffer<Key>,
+}
+
+impl fmt::Debug for Keyboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyboard").finish()
+    }
+}
+
+impl Keyboard {
+    pub fn empty() -> Self {
+        Self {
+            active_modifiers: 0,
+            active_toggles: 0,
+            extended: false,
+            dead_key: None,
+            layout: layout::LAYOUTS[0],
+            input_ring: RingBuffer::empty(),
+        }
+    }
+
+    pub fn modifiers(&self) -> u8 {
+        // remove the saved toggles (this is used for safe-keeping which toggle are we still pressing)
+        let modifiers_only = self.active_modifiers
+            & !(modifier::CAPS_LOCK | modifier::NUM_LOCK | modifier::SCROLL_LOCK);
+
+        modifiers_only | self.active_toggles
+    }
+
+    pub fn lock_keys(&self) -> LockKeys {
+        LockKeys {
+            caps_lock: self.active_toggles & modifier::CAPS_LOCK != 0,
+            num_lock: self.active_toggles & modifier::NUM_LOCK != 0,
+            scroll_lock: self.active_toggles & modifier::SCROLL_LOCK != 0,
+        }
+    }
+
+    fn update_leds<P: PortIo>(&self, port: &mut P) {
+        let locks = self.lock_keys();
+        let mut leds = 0;
+        if locks.scroll_lock {
+            leds |= led::SCROLL_LOCK;
+        }
+        if locks.num_lock {
+            leds |= led::NUM_LOCK;
+        }
+        if locks.caps_lock {
+            leds |= led::CAPS_LOCK;
+        }
+        if let Err(e) = ps2::send_command(port, &[command::SET_LEDS, leds]) {
+            eprintln!("keyboard: failed to set LEDs: {e:?}");
+        }
+    }
+
+    /// Reads a scancode if there is any, and translates it, bytes of the mouse are given to
+    /// it, since they share the controller
+    fn poll<P: PortIo>(&mut self, port: &mut P) {
+        let status = port.read_status();
+        if status & status::DATA_READY == 0 {
+            return;
+        }
+        let data = port.read_data();
+        if !port.is_own(status) {
+            mouse::handle_byte(data);
+            return;
+        }
+        self.handle_scancode(port, data);
+    }
+
+    fn handle_scancode<P: PortIo>(&mut self, port: &mut P, data: u8) {
+        if self.process_scancode(data) {
+            self.update_leds(port);
+        }
+    }
+
+    /// Translates one byte from the keyboard, the resulting keys are pushed to `input_ring`.
+    ///
+    /// Returns `true` if a toggle (caps lock...) has changed.
+    fn process_scancode(&mut self, data: u8) -> bool {
+        if data == EXTENDED_PREFIX {
+            self.extended = true;
+            return false;
+        }
+        let extended = core::mem::take(&mut self.extended);
+
+        let pressed = data & KEY_PRESSED == 0;
+        let data = data & !KEY_PRESSED; // strip the pressed bit
+
+        let modifier_key = if extended {
+            get_extended_modifier(data)
+        } else {
+            get_modifier(data)
+        };
+        if let Some(modifier_key) = modifier_key {
+            if pressed {
+                self.active_modifiers |= modifier_key;
+            } else {
+                self.active_modifiers &= !modifier_key;
+            }
+            return false;
+        }
+
+        if extended {
+            // some keyboards send fake shifts around the extended keys, ignore them
+            if !pressed || data == 0x2A || data == 0x36 {
+                return false;
+            }
+            let Ok(key_type) = KeyType::try_from(data | 0x80) else {
+                return false;
+            };
+            let virtual_char = match key_type {
+                KeyType::KeypadEnter => Some('\n'),
+                KeyType::KeypadSlash => Some('/'),
+                _ => None,
+            };
+            self.emit(virtual_char, key_type);
+            return false;
+        }
+
+        if let Some(toggle_key) = get_toggle(data) {
+            // keep a copy in the modifier so that we only toggle on a press
+            let should_toggle = pressed && self.active_modifiers & toggle_key == 0;
+            if should_toggle {
+                self.active_toggles ^= toggle_key;
+            }
+
+            // add to the modifier
+            if pressed {
+                self.active_modifiers |= toggle_key;
+            } else {
+                self.active_modifiers &= !toggle_key;
+            }
+            return should_toggle;
+        }
+
+        // this is a normal key
+        if pressed {
+            if let Ok(key_type) = KeyType::try_from(data) {
+                if let Some(terminal) = self.terminal_switch(key_type) {
+                    self.input_ring.push_replace(Key {
+                        virtual_char: None,
+                        key_type,
+                        switch_terminal: Some(terminal),
+                    });
+                } else {
+                    let virtual_char = self.translate(data);
+                    self.emit(virtual_char, key_type);
+                }
+            }
+        }
+        false
+    }
+
+    /// The virtual terminal `Alt+key_type` switches to, if its one of `Alt+F1..F4`
+    fn terminal_switch(&self, key_type: KeyType) -> Option<usize> {
+        if self.modifiers() & (modifier::ALT | modifier::CTRL | modifier::ALT_GR) != modifier::ALT {
+            return None;
+        }
+        let index = (key_type as u8).checked_sub(KeyType::F1 as u8)? as usize;
+        (index < console::NUM_TERMINALS && key_type as u8 <= KeyType::F10 as u8).then_some(index)
+    }
+
+    /// The char of a (not extended) key, based on the layout and the modifiers
+    fn translate(&self, data: u8) -> Option<char> {
+        let modifiers = self.modifiers();
+        let c = match data {
+            0x01 => '\x1b',
+            0x0E => '\x08',
+            0x0F => '\t',
+            0x1C => '\n',
+            0x39 => ' ',
+            0x37 => '*',
+            0x4A => '-',
+            0x4E => '+',
+            0x47..=0x53 => {
+                // without num lock, these are the navigation keys
+                if modifiers & modifier::NUM_LOCK == 0 {
+                    return None;
+                }
+                KEYPAD_CHARS[(data - KEYPAD_START) as usize] as char
+            }
+            _ => {
+                let chars = self.layout.key(data)?;
+                if modifiers & modifier::ALT_GR != 0 {
+                    chars.altgr
+                } else {
+                    let mut shift = modifiers & modifier::SHIFT != 0;
+                    // caps lock only affects letters
+                    if modifiers & modifier::CAPS_LOCK != 0
+                        && chars.normal.is_lowercase()
+                        && chars.shifted.is_uppercase()
+                    {
+                        shift = !shift;
+                    }
+                    if shift {
+                        chars.shifted
+                    } else {
+                        chars.normal
+                    }
+                }
+            }
+        };
+        // the control chars, i.e. `Ctrl+C` is `ETX`
+        if modifiers & (modifier::CTRL | modifier::ALT_GR) == modifier::CTRL
+            && c.is_ascii_alphabetic()
+        {
+            return Some((c.to_ascii_uppercase() as u8 - b'@') as char);
+        }
+        (c != '\0').then_some(c)
+    }
+
+    /// Pushes the key, composing it with the pending dead key if there is one
+    fn emit(&mut self, virtual_char: Option<char>, key_type: KeyType) {
+        let Some(c) = virtual_char else {
+            self.push_key(None, key_type);
+            return;
+        };
+
+        if let Some((dead, dead_type)) = self.dead_key.take() {
+            if c == ' ' {
+                // the dead key by itself
+                self.push_key(Some(dead), dead_type);
+                return;
+            }
+            if let Some(composed) = self.layout.compose(dead, c) {
+                self.push_key(Some(composed), key_type);
+                return;
+            }
+            // can't be composed, output both
+            self.push_key(Some(dead), dead_type);
+        }
+
+        if self.layout.is_dead_key(c) {
+            self.dead_key = Some((c, key_type));
+        } else {
+            self.push_key(Some(c), key_type);
+        }
+    }
+
+    fn push_key(&mut self, virtual_char: Option<char>, key_type: KeyType) {
+        // fill in the buffer, and replace if filled
+        self.input_ring.push_replace(Key {
+            virtual_char,
+            key_type,
+            switch_terminal: None,
+        });
+    }
+
+    pub fn layout(&self) -> &'static KeyboardLayout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: &'static KeyboardLayout) {
+        self.layout = layout;
+        self.dead_key = None;
+    }
+
+    pub fn get_next_char(&mut self) -> Option<Key> {
+        self.input_ring.pop().or_else(|| {
+            // not waiting, as this is used from `panic`
+            self.poll(&mut *ps2::CONTROLLER.try_lock()?);
+            self.input_ring.pop()
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn clear_buffer(&mut self) {
+        self.input_ring.clear();
+    }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame64) {
+    irq_off::interrupt_enter("keyboard");
+    ps2::handle_interrupt();
+    irq_off::interrupt_exit();
+
+    apic::return_from_interrupt();
+}
+
+/// Queues a byte of the keyboard, read by [`ps2::handle_interrupt`], for [`translate_queued`]
+pub(super) fn queue_byte(data: u8) {
+    if SCANCODES.lock().push(data, ScancodeSource::Ps2) {
+        softirq::raise(SoftIrq::TtyInput);
+    }
+}
+
+/// The [`SoftIrq::TtyInput`] handler, translates the bytes queued when it started, then moves
+/// the keys to the console
+fn translate_queued() {
+    let Some(keyboard) = KEYBOARD.try_get() else {
+        return;
+    };
+    let queued = SCANCODES.lock().ring.len();
+    for _ in 0..queued {
+        let Some((data, source)) = SCANCODES.lock().ring.pop() else {
+            break;
+        };
+        match source {
+            ScancodeSource::Ps2 => {
+                let mut controller = ps2::CONTROLLER.lock();
+                keyboard.lock().handle_scancode(&mut *controller, data);
+            }
+            ScancodeSource::Injected => {
+                // acks the LED commands, if the scancode changes a toggle
+                let mut port = SimulatedPort::new(&[ps2::ACK; 2]);
+                keyboard.lock().handle_scancode(&mut port, data);
+            }
+        }
+    }
+    // the console takes the keyboard lock itself
+    console::route_input();
+}
+
+/// Queues the scancodes as if they came from the interrupt, for the keys of [`input_inject`].
+/// They take effect when the softirqs run, see [`softirq::run_pending`].
+///
+/// [`input_inject`]: super::input_inject
+///
+/// Returns how many were queued, the rest were dropped
+pub fn inject_scancodes(scancodes: &[u8]) -> usize {
+    let mut queue = SCANCODES.lock();
+    // once one is dropped, the rest are too
+    let queued = scancodes
+        .iter()
+        .filter(|&&data| queue.push(data, ScancodeSource::Injected))
+        .count();
+    drop(queue);
+    if queued != 0 {
+        softirq::raise(SoftIrq::TtyInput);
+    }
+    queued
+}
+
+/// Feeds the scancodes of a keyboard that is not on the PS/2 controller (i.e. a USB one) to the
+/// same translation, the keys go to the console like the PS/2 ones.
+///
+/// That keyboard sets its LEDs itself, so this returns the lock keys if one of them changed
+pub fn feed_scancodes(scancodes: &[u8]) -> Option<LockKeys> {
+    let changed = {
+        let mut keyboard = KEYBOARD.try_get()?.lock();
+        let mut changed = false;
+        for &data in scancodes {
+            changed |= keyboard.process_scancode(data);
+        }
+        changed.then(|| keyboard.lock_keys())
+    };
+    console::route_input();
+    changed
+}
+
+/// `/devices/keyboard_layout`, reading gives the available layouts with the current one
+/// marked with `*`, writing a layout name selects it
+#[derive(Debug)]
+struct KeyboardLayoutControl;
+
+impl Device for KeyboardLayoutControl {
+    fn name(&self) -> &str {
+        "keyboard_layout"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let current = KEYBOARD.get().lock().layout().name;
+        let mut info = String::new();
+        for layout in layout::LAYOUTS {
+            info.push_str(if layout.name == current { "* " } else { "  " });
+            info.push_str(layout.name);
+            info.push('\n');
+        }
+        let info = info.as_bytes();
+        Ok(devices::read_bytes(info, offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let name = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        let layout = layout::find_layout(name).ok_or(FileSystemError::InvalidData)?;
+        KEYBOARD.get().lock().set_layout(layout);
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Feeds the scancodes to `keyboard`, returns the chars produced
+fn selftest_inject(keyboard: &mut Keyboard, port: &mut SimulatedPort, scancodes: &[u8]) -> String {
+    for &data in scancodes {
+        keyboard.handle_scancode(port, data);
+    }
+    let mut result = String::new();
+    while let Some(key) = keyboard.input_ring.pop() {
+        if let Some(c) = key.virtual_char {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn selftest_commands() {
+    let mut port = SimulatedPort::new(&[ps2::RESEND, ps2::ACK, ps2::ACK]);
+    assert_eq!(
+        ps2::send_command(&mut port, &[command::SET_LEDS, 0x04]),
+        Ok(())
+    );
+    assert_eq!(
+        port.written,
+        [command::SET_LEDS, command::SET_LEDS, 0x04],
+        "keyboard self test: the byte was not resent"
+    );
+
+    let mut port = SimulatedPort::new(&[ps2::RESEND; ps2::COMMAND_RETRIES]);
+    assert_eq!(
+        ps2::send_command(&mut port, &[command::SET_TYPEMATIC, TYPEMATIC_RATE]),
+        Err(ps2::CommandError::TooManyResends)
+    );
+    assert_eq!(port.written, [command::SET_TYPEMATIC; ps2::COMMAND_RETRIES]);
+
+    let mut port = SimulatedPort::new(&[]);
+    assert_eq!(
+        ps2::send_command(&mut port, &[command::SET_LEDS, 0]),
+        Err(ps2::CommandError::Timeout)
+    );
+}
+
+fn selftest_lock_keys() {
+    let mut keyboard = Keyboard::empty();
+    // every LED update is 2 bytes, each acked
+    let mut port = SimulatedPort::new(&[ps2::ACK; 8]);
+
+    // a, CapsLock, a, 1, Shift+a, Shift+1
+    let typed = selftest_inject(
+        &mut keyboard,
+        &mut port,
+        &[
+            0x1E, 0x9E, 0x3A, 0xBA, 0x1E, 0x9E, 0x02, 0x82, 0x2A, 0x1E, 0x9E, 0x02, 0x82, 0xAA,
+        ],
+    );
+    assert_eq!(typed, "aA1a!");
+    assert_eq!(port.written, [command::SET_LEDS, led::CAPS_LOCK]);
+
+    // holding the toggle key doesn't toggle it again
+    let typed = selftest_inject(&mut keyboard, &mut port, &[0x3A, 0x3A, 0xBA, 0x1E, 0x9E]);
+    assert_eq!(typed, "a");
+
+    // keypad 7, NumLock, keypad 7, extended Home, keypad minus
+    port.written.clear();
+    let typed = selftest_inject(
+        &mut keyboard,
+        &mut port,
+        &[
+            0x47, 0xC7, 0x4A, 0xCA, 0x45, 0xC5, 0x47, 0xC7, 0xE0, 0x47, 0xE0, 0xC7,
+        ],
+    );
+    assert_eq!(typed, "-7");
+    assert_eq!(port.written, [command::SET_LEDS, led::NUM_LOCK]);
+    assert_eq!(
+        keyboard.modifiers() & modifier::NUM_LOCK,
+        modifier::NUM_LOCK
+    );
+}
+
+fn selftest_dead_keys() {
+    let mut keyboard = Keyboard::empty();
+    keyboard.set_layout(layout::find_layout("de").unwrap());
+    let mut port = SimulatedPort::new(&[]);
+
+    // ´ e, Shift+´ a, ^ space, ´ x, AltGr+q, z
+    let typed = selftest_inject(
+        &mut keyboard,
+        &mut port,
+        &[
+            0x0D, 0x8D, 0x12, 0x92, 0x2A, 0x0D, 0x8D, 0xAA, 0x1E, 0x9E, 0x29, 0xA9, 0x39, 0xB9,
+            0x0D, 0x8D, 0x2D, 0xAD, 0xE0, 0x38, 0x10, 0x90, 0xE0, 0xB8, 0x15, 0x95,
+        ],
+    );
+    assert_eq!(
+        typed.as_bytes(),
+        b"\xc3\xa9\xc3\xa0^\xc2\xb4x@z",
+        "keyboard self test: wrong composition {typed:?}"
+    );
+}
+
+fn selftest_control_chars() {
+    let mut keyboard = Keyboard::empty();
+    let mut port = SimulatedPort::new(&[]);
+
+    // Ctrl+c, Ctrl+Shift+d, Ctrl+1, c
+    let typed = selftest_inject(
+        &mut keyboard,
+        &mut port,
+        &[
+            0x1D, 0x2E, 0xAE, 0x2A, 0x20, 0xA0, 0xAA, 0x02, 0x82, 0x9D, 0x2E, 0xAE,
+        ],
+    );
+    assert_eq!(
+        typed, "\x03\x041c",
+        "keyboard self test: wrong control chars {typed:?}"
+    );
+}
+
+fn selftest_terminal_switch() {
+    let mut keyboard = Keyboard::empty();
+    let mut port = SimulatedPort::new(&[]);
+
+    // a, Alt+F2, F2, Ctrl+Alt+F3, Alt+F4
+    for &data in &[
+        0x1E, 0x9E, 0x38, 0x3C, 0xBC, 0xB8, 0x3C, 0xBC, 0x1D, 0x38, 0x3D, 0xBD, 0xB8, 0x9D, 0x38,
+        0x3E, 0xBE, 0xB8,
+    ] {
+        keyboard.handle_scancode(&mut port, data);
+    }
+    let mut keys = Vec::new();
+    while let Some(key) = keyboard.input_ring.pop() {
+        keys.push((key.virtual_char, key.switch_terminal));
+    }
+    assert_eq!(
+        keys,
+        [
+            (Some('a'), None),
+            (None, Some(1)),
+            (None, None),
+            (None, None),
+            (None, Some(3))
+        ],
+        "keyboard self test: wrong terminal switches"
+    );
+}
+
+/// Floods a queue past its bound, what fits is translated in order, and queueing a byte takes
+/// less than translating it, which is what moved out of the interrupt
+fn selftest_scancode_flood() {
+    // the top row of letters
+    const LETTERS: &[u8; 10] = b"qwertyuiop";
+    let mut queue = ScancodeQueue::empty();
+    let mut keyboard = Keyboard::empty();
+    let mut port = SimulatedPort::new(&[]);
+    let extra = 16;
+
+    let start = cpu::rdtsc();
+    for i in 0..MAX_QUEUED_SCANCODES + extra {
+        // a press then a release of the next letter
+        let release = if i % 2 == 1 { KEY_PRESSED } else { 0 };
+        let scancode = (0x10 + (i / 2 % LETTERS.len()) as u8) | release;
+        let queued = queue.push(scancode, ScancodeSource::Injected);
+        assert_eq!(
+            queued,
+            i < MAX_QUEUED_SCANCODES,
+            "keyboard self test: wrong bound"
+        );
+    }
+    let queue_ticks = cpu::rdtsc() - start;
+    assert_eq!(queue.dropped, extra as u64);
+
+    let start = cpu::rdtsc();
+    while let Some((data, source)) = queue.ring.pop() {
+        assert_eq!(source, ScancodeSource::Injected);
+        keyboard.handle_scancode(&mut port, data);
+    }
+    let translate_ticks = cpu::rdtsc() - start;
+
+    let mut typed = Vec::new();
+    while let Some(key) = keyboard.input_ring.pop() {
+        typed.push(key.virtual_char.unwrap() as u8);
+    }
+    let expected = (0..MAX_QUEUED_SCANCODES.div_ceil(2))
+        .map(|i| LETTERS[i % LETTERS.len()])
+        .collect::<Vec<_>>();
+    assert_eq!(
+        typed, expected,
+        "keyboard self test: lost or reordered keys"
+    );
+    // only asserted in the builds with the tracker, the ones made for measuring
+    if irq_off::ENABLED {
+        assert!(
+            queue_ticks < translate_ticks,
+            "keyboard self test: queueing took {queue_ticks} ticks, translating {translate_ticks}"
+        );
+    }
+}
+
+pub fn run_self_tests() {
+    println!("Running keyboard self tests...");
+
+    selftest_commands();
+    selftest_lock_keys();
+    selftest_dead_keys();
+    selftest_control_chars();
+    selftest_terminal_switch();
+    selftest_scancode_flood();
+
+    println!("Keyboard self tests passed");
+}
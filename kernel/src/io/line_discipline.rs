@@ -0,0 +1,182 @@
+//! The canonical mode of the terminals, the input is edited a line at a time.
+//!
+//! The bytes can come from any [`InputSource`], they are added to the current line and echoed
+//! back to the source, which the console does, and a line can only be read after its newline.
+//! Backspace and DEL erase the last char of the line, and a `\r` is a newline too (serial
+//! terminals send `\r` for Enter, `\r\n` is a single newline). `Ctrl+C` (`ETX`) drops the line
+//! and interrupts the processes of the terminal, the console sends them the signal.
+//!
+//! The escape sequences from the serial port (i.e. the arrow keys) are passed through raw, they
+//! are readable right away, before the line being edited, and are not echoed.
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// The most input a terminal keeps until its read, the chars after that are dropped
+// but not the newlines, so the line can still be ended
+const MAX_INPUT: usize = 4096;
+
+/// `Ctrl+C`
+const ETX: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7F;
+const ESC: u8 = 0x1B;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum InputSource {
+    /// The translated chars of the keyboard, echoed to the screen
+    Keyboard,
+    /// The bytes from the serial port, echoed back to it
+    Serial,
+}
+
+/// What to show for a byte of input, the console writes it to where the input came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Echo {
+    None,
+    Byte(u8),
+    /// The last char of the line was erased
+    Erase,
+    Newline,
+    /// The line was dropped for `Ctrl+C`, the processes of the terminal are interrupted
+    Interrupt,
+}
+
+impl Echo {
+    /// The bytes to write for this echo, a serial terminal needs `\r` to go back to the start of
+    /// the line
+    pub fn bytes(&self, source: InputSource, buf: &mut [u8; 4]) -> usize {
+        let bytes: &[u8] = match (self, source) {
+            (Echo::None, _) => b"",
+            (Echo::Byte(b), _) => core::slice::from_ref(b),
+            (Echo::Erase, _) => b"\x08 \x08",
+            (Echo::Newline, InputSource::Keyboard) => b"\n",
+            (Echo::Newline, InputSource::Serial) => b"\r\n",
+            (Echo::Interrupt, InputSource::Keyboard) => b"^C\n",
+            (Echo::Interrupt, InputSource::Serial) => b"^C\r\n",
+        };
+        buf[..bytes.len()].copy_from_slice(bytes);
+        bytes.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// After `ESC`
+    Start,
+    /// After `ESC [` or `ESC O`, until the final byte
+    Sequence,
+}
+
+pub(super) struct LineDiscipline {
+    // the line being edited
+    line: Vec<u8>,
+    // what can be read, the lines before `line` and the raw escape sequences
+    ready: VecDeque<u8>,
+    escape: Escape,
+    // a `\r` was the last byte, so a `\n` right after it is the same newline
+    after_cr: bool,
+}
+
+impl LineDiscipline {
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.line.len() + self.ready.len() >= MAX_INPUT
+    }
+
+    /// Adds a byte of input, and returns what to echo for it
+    pub fn push(&mut self, byte: u8, source: InputSource) -> Echo {
+        let after_cr = core::mem::take(&mut self.after_cr);
+        match self.escape {
+            Escape::None => {}
+            Escape::Start => {
+                self.escape = match byte {
+                    b'[' | b'O' => Escape::Sequence,
+                    _ => Escape::None,
+                };
+                self.push_raw(byte);
+                return Echo::None;
+            }
+            Escape::Sequence => {
+                // the parameters and intermediate bytes are before `0x40`
+                if (0x40..=0x7E).contains(&byte) {
+                    self.escape = Escape::None;
+                }
+                self.push_raw(byte);
+                return Echo::None;
+            }
+        }
+
+        match byte {
+            b'\r' => {
+                self.after_cr = true;
+                self.end_line()
+            }
+            b'\n' if after_cr => Echo::None,
+            b'\n' => self.end_line(),
+            BACKSPACE | DEL => self.erase(),
+            ETX => {
+                self.line.clear();
+                Echo::Interrupt
+            }
+            ESC if source == InputSource::Serial => {
+                self.escape = Escape::Start;
+                self.push_raw(byte);
+                Echo::None
+            }
+            // the other control chars are dropped
+            b'\t' | 0x20..=0x7E | 0x80.. => {
+                if self.is_full() {
+                    return Echo::None;
+                }
+                self.line.push(byte);
+                Echo::Byte(byte)
+            }
+            _ => Echo::None,
+        }
+    }
+
+    fn push_raw(&mut self, byte: u8) {
+        if !self.is_full() {
+            self.ready.push_back(byte);
+        }
+    }
+
+    fn end_line(&mut self) -> Echo {
+        self.line.push(b'\n');
+        self.ready.extend(self.line.drain(..));
+        Echo::Newline
+    }
+
+    /// Removes the last char, with all its UTF-8 bytes
+    fn erase(&mut self) -> Echo {
+        let Some(start) = self.line.iter().rposition(|&b| b & 0xC0 != 0x80) else {
+            // only continuation bytes, or empty
+            return if self.line.is_empty() {
+                Echo::None
+            } else {
+                self.line.clear();
+                Echo::Erase
+            };
+        };
+        self.line.truncate(start);
+        Echo::Erase
+    }
+
+    /// Reads the lines that were ended, and the raw escape sequences
+    pub fn read(&mut self, dst: &mut [u8]) -> usize {
+        let len = dst.len().min(self.ready.len());
+        for (d, byte) in dst.iter_mut().zip(self.ready.drain(..len)) {
+            *d = byte;
+        }
+        len
+    }
+}
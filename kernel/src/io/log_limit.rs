@@ -0,0 +1,582 @@
+//! Rate limiting of the kernel logs, so a storm of them doesn't keep the CPU writing to the
+//! console and hide the rest.
+//!
+//! Each callsite of `print!` and `eprint!` (its file and line) has a token bucket of
+//! [`DEFAULT_RATE`] messages a second, `log.rate=<count>` on the cmdline, with a burst of as
+//! many. The messages over it are dropped, and `<file>:<line>: N messages suppressed` is
+//! printed with the next one that passes, or a second after the first dropped if the site
+//! stays quiet.
+//!
+//! On top of that, if all of them together go over [`DEFAULT_BRAKE_RATE`] in a second,
+//! `log.brake=<count>`, the brake engages with a warning: everything is dropped until there
+//! is a second with less than half of it. `0` turns off either of them.
+//!
+//! The errors of [`log_error!`](crate::log_error) and the panics are never dropped, they
+//! don't even take the lock, so they are printed from anywhere.
+//!
+//! The sites are kept in a fixed table, nothing is allocated while logging. A site that
+//! doesn't find a place takes the one of the site that logged last the longest ago, which
+//! prints its summary first. Without a clock nothing is limited.
+//!
+//! What is printed is also forwarded to the [`log_sink`] when it is enabled, `udp
+//! <address>:<port>` written to `/devices/log_limits` starts it, `udp off` stops it.
+//!
+//! `/devices/log_limits` has the counters and the sites that were limited.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{string::String, sync::Arc};
+
+use crate::{
+    devices::{self, clock, Device},
+    fs::FileSystemError,
+    net::{
+        log_sink::{self, Severity},
+        SocketAddress,
+    },
+    process::scheduler::with_current_process,
+    sync::spin::mutex::Mutex,
+};
+
+use super::console;
+
+/// The messages a second each callsite can print
+pub const DEFAULT_RATE: u32 = 10;
+/// The messages a second of all the callsites that engage the brake
+pub const DEFAULT_BRAKE_RATE: u32 = 500;
+
+const SECOND: u64 = 1_000_000_000;
+const SITES: usize = 64;
+// the slots looked at from the hash of a site
+const PROBES: usize = 8;
+// the most a `storm` write to the device prints
+const MAX_STORM: u64 = 100_000;
+
+static LIMITER: Mutex<LogLimiter> = Mutex::new(LogLimiter::new(DEFAULT_RATE, DEFAULT_BRAKE_RATE));
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Where a message is printed from
+#[derive(Debug, Clone, Copy)]
+pub struct Callsite {
+    pub file: &'static str,
+    pub line: u32,
+}
+
+impl Callsite {
+    fn is(&self, other: &Callsite) -> bool {
+        self.line == other.line && self.file == other.file
+    }
+
+    // FNV-1a, the pointers of the same `file!()` can differ
+    fn hash(&self) -> usize {
+        let mut hash = 0xCBF2_9CE4_8422_2325u64;
+        for &b in self.file.as_bytes().iter().chain(&self.line.to_le_bytes()) {
+            hash = (hash ^ b as u64).wrapping_mul(0x0100_0000_01B3);
+        }
+        hash as usize
+    }
+}
+
+impl fmt::Display for Callsite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Printed before the messages, once the limiter is unlocked
+#[derive(Debug, Clone, Copy)]
+enum Notice {
+    Suppressed { site: Callsite, count: u64 },
+    BrakeOn { rate: u32 },
+    BrakeOff { dropped: u64 },
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::Suppressed { site, count } => {
+                writeln!(f, "{site}: {count} messages suppressed")
+            }
+            Notice::BrakeOn { rate } => {
+                writeln!(
+                    f,
+                    "[!] log storm: over {rate} messages a second, dropping the logs"
+                )
+            }
+            Notice::BrakeOff { dropped } => {
+                writeln!(f, "log storm over: {dropped} messages dropped")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Site {
+    callsite: Option<Callsite>,
+    // in nanoseconds, a message costs `SECOND / rate`
+    credit: u64,
+    last: u64,
+    // the first message dropped since the last summary
+    suppressed_at: u64,
+    pending: u64,
+    suppressed: u64,
+}
+
+impl Site {
+    const EMPTY: Self = Self {
+        callsite: None,
+        credit: 0,
+        last: 0,
+        suppressed_at: 0,
+        pending: 0,
+        suppressed: 0,
+    };
+}
+
+/// What happened to a message, and the notices to print before it
+#[derive(Debug, Default)]
+struct Verdict {
+    print: bool,
+    notices: [Option<Notice>; 3],
+}
+
+impl Verdict {
+    fn push(&mut self, notice: Notice) {
+        if let Some(slot) = self.notices.iter_mut().find(|n| n.is_none()) {
+            *slot = Some(notice);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogLimitStats {
+    pub rate: u32,
+    pub brake_rate: u32,
+    pub brake_engaged: bool,
+    /// The times the brake engaged
+    pub brakes: u64,
+    pub brake_dropped: u64,
+    /// The messages dropped by the buckets of the sites
+    pub suppressed: u64,
+    pub passed: u64,
+}
+
+impl fmt::Display for LogLimitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rate: {}", self.rate)?;
+        writeln!(f, "brake rate: {}", self.brake_rate)?;
+        writeln!(f, "brake engaged: {}", self.brake_engaged)?;
+        writeln!(f, "brakes: {}", self.brakes)?;
+        writeln!(f, "brake dropped: {}", self.brake_dropped)?;
+        writeln!(f, "suppressed: {}", self.suppressed)?;
+        writeln!(f, "passed: {}", self.passed)?;
+        writeln!(f, "errors: {}", ERRORS.load(Ordering::Relaxed))
+    }
+}
+
+struct LogLimiter {
+    sites: [Site; SITES],
+    // the global count of the messages in the second from `window_start`
+    window_start: u64,
+    window_count: u64,
+    // the drops since the brake engaged
+    braked: u64,
+    stats: LogLimitStats,
+}
+
+impl LogLimiter {
+    const fn new(rate: u32, brake_rate: u32) -> Self {
+        Self {
+            sites: [Site::EMPTY; SITES],
+            window_start: 0,
+            window_count: 0,
+            braked: 0,
+            stats: LogLimitStats {
+                rate,
+                brake_rate,
+                brake_engaged: false,
+                brakes: 0,
+                brake_dropped: 0,
+                suppressed: 0,
+                passed: 0,
+            },
+        }
+    }
+
+    fn cost(&self) -> u64 {
+        SECOND / self.stats.rate as u64
+    }
+
+    fn summary(&mut self, index: usize) -> Option<Notice> {
+        let site = &mut self.sites[index];
+        let count = core::mem::take(&mut site.pending);
+        (count != 0).then_some(Notice::Suppressed {
+            site: site.callsite?,
+            count,
+        })
+    }
+
+    /// The slot of `callsite`, with the summary of the site it replaced if any
+    fn site_index(&mut self, callsite: Callsite, now: u64) -> (usize, Option<Notice>) {
+        let start = callsite.hash() % SITES;
+        let mut oldest = start;
+        for probe in 0..PROBES {
+            let index = (start + probe) % SITES;
+            match &self.sites[index].callsite {
+                Some(other) if other.is(&callsite) => return (index, None),
+                Some(_) => {
+                    if self.sites[index].last < self.sites[oldest].last {
+                        oldest = index;
+                    }
+                }
+                None => {
+                    self.sites[index] = Site {
+                        callsite: Some(callsite),
+                        credit: SECOND,
+                        last: now,
+                        ..Site::EMPTY
+                    };
+                    return (index, None);
+                }
+            }
+        }
+        let summary = self.summary(oldest);
+        self.sites[oldest] = Site {
+            callsite: Some(callsite),
+            credit: SECOND,
+            last: now,
+            ..Site::EMPTY
+        };
+        (oldest, summary)
+    }
+
+    /// Starts a new window of the brake if the last is over, releasing it if that was quiet
+    fn roll_window(&mut self, now: u64, verdict: &mut Verdict) {
+        if now.saturating_sub(self.window_start) < SECOND {
+            return;
+        }
+        if self.stats.brake_engaged && self.window_count * 2 < self.stats.brake_rate as u64 {
+            self.stats.brake_engaged = false;
+            verdict.push(Notice::BrakeOff {
+                dropped: core::mem::take(&mut self.braked),
+            });
+        }
+        self.window_start = now;
+        self.window_count = 0;
+    }
+
+    fn submit(&mut self, callsite: Callsite, now: u64) -> Verdict {
+        let mut verdict = Verdict::default();
+        if now == 0 {
+            verdict.print = true;
+            return verdict;
+        }
+
+        let mut index = None;
+        if self.stats.rate != 0 {
+            let cost = self.cost();
+            let (site_index, evicted) = self.site_index(callsite, now);
+            if let Some(evicted) = evicted {
+                verdict.push(evicted);
+            }
+            let site = &mut self.sites[site_index];
+            site.credit = (site.credit + now.saturating_sub(site.last)).min(SECOND);
+            site.last = now;
+            if site.credit < cost {
+                if site.pending == 0 {
+                    site.suppressed_at = now;
+                }
+                site.pending += 1;
+                site.suppressed += 1;
+                self.stats.suppressed += 1;
+                return verdict;
+            }
+            site.credit -= cost;
+            index = Some(site_index);
+        }
+
+        // only what the sites let through counts, one site alone can't engage it
+        if self.stats.brake_rate != 0 {
+            self.roll_window(now, &mut verdict);
+            self.window_count += 1;
+            if !self.stats.brake_engaged && self.window_count > self.stats.brake_rate as u64 {
+                self.stats.brake_engaged = true;
+                self.stats.brakes += 1;
+                verdict.push(Notice::BrakeOn {
+                    rate: self.stats.brake_rate,
+                });
+            }
+            if self.stats.brake_engaged {
+                self.braked += 1;
+                self.stats.brake_dropped += 1;
+                return verdict;
+            }
+        }
+
+        if let Some(summary) = index.and_then(|index| self.summary(index)) {
+            verdict.push(summary);
+        }
+        self.stats.passed += 1;
+        verdict.print = true;
+        verdict
+    }
+
+    /// The brake released and the summaries of the quiet sites at `now`, one at a time
+    fn tick(&mut self, now: u64) -> Verdict {
+        let mut verdict = Verdict::default();
+        if now == 0 {
+            return verdict;
+        }
+        if self.stats.brake_rate != 0 {
+            self.roll_window(now, &mut verdict);
+        }
+        let quiet = self
+            .sites
+            .iter()
+            .position(|site| site.pending != 0 && now.saturating_sub(site.suppressed_at) >= SECOND);
+        if let Some(summary) = quiet.and_then(|index| self.summary(index)) {
+            verdict.push(summary);
+        }
+        verdict
+    }
+}
+
+/// Prints the notices of `verdict`, then the message if it passed
+fn print_verdict(verdict: &Verdict, args: Option<fmt::Arguments>) {
+    console::run_with_console(|inner| {
+        for notice in verdict.notices.iter().flatten() {
+            write!(inner, "{notice}")?;
+        }
+        match args {
+            Some(args) if verdict.print => inner.write_fmt(args),
+            _ => Ok(()),
+        }
+    })
+    .unwrap();
+    for notice in verdict.notices.iter().flatten() {
+        log_sink::forward(Severity::Info, format_args!("{notice}"));
+    }
+    if let Some(args) = args.filter(|_| verdict.print) {
+        log_sink::forward(Severity::Info, args);
+    }
+}
+
+/// Prints `args` from `callsite` if its bucket and the brake let it
+pub fn print_limited(callsite: Callsite, args: fmt::Arguments) {
+    // printing from inside the limiter, or while the clock is being read, is not limited
+    let Some(mut limiter) = LIMITER.try_lock() else {
+        console::run_with_console(|inner| inner.write_fmt(args)).unwrap();
+        return;
+    };
+    let verdict = limiter.submit(callsite, clock::try_uptime_nanos());
+    drop(limiter);
+    print_verdict(&verdict, Some(args));
+}
+
+/// Prints an error, which is never dropped
+pub fn print_error(args: fmt::Arguments) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    console::run_with_console(|inner| inner.write_fmt(args)).unwrap();
+    log_sink::forward(Severity::Error, args);
+}
+
+/// Called by the timer interrupt, prints the summaries of the sites that stopped
+pub fn tick() {
+    let Some(mut limiter) = LIMITER.try_lock() else {
+        return;
+    };
+    let verdict = limiter.tick(clock::try_uptime_nanos());
+    drop(limiter);
+    if verdict.notices[0].is_some() {
+        print_verdict(&verdict, None);
+    }
+}
+
+/// Takes the `log.rate=<count>` and `log.brake=<count>` options of the cmdline
+pub fn init(cmdline: &str) {
+    for arg in cmdline.split_whitespace() {
+        let Some((name, value)) = arg.split_once('=') else {
+            continue;
+        };
+        if name != "log.rate" && name != "log.brake" {
+            continue;
+        }
+        let Ok(value) = value.parse::<u32>() else {
+            eprintln!("Invalid value for `{name}`: {value}");
+            continue;
+        };
+        let mut limiter = LIMITER.lock();
+        if name == "log.rate" {
+            limiter.stats.rate = value;
+        } else {
+            limiter.stats.brake_rate = value;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogLimitsDevice;
+
+impl Device for LogLimitsDevice {
+    fn name(&self) -> &str {
+        "log_limits"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let mut info = String::new();
+        {
+            let limiter = LIMITER.lock();
+            write!(info, "{}", limiter.stats).unwrap();
+            for site in limiter.sites.iter().filter(|site| site.suppressed != 0) {
+                if let Some(callsite) = site.callsite {
+                    writeln!(
+                        info,
+                        "{callsite} suppressed {} (pending {})",
+                        site.suppressed, site.pending
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        let (destination, stats) = log_sink::stats();
+        match destination {
+            Some(destination) => writeln!(info, "udp {destination}: {stats}").unwrap(),
+            None => writeln!(info, "udp off: {stats}").unwrap(),
+        }
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+
+    /// `storm <count>` prints that many lines from one place, with an error in the middle,
+    /// `udp <address>:<port>` and `udp off` start and stop the log sink
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        if !with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        if let Some(destination) = command.strip_prefix("udp ") {
+            let destination = match destination.trim() {
+                "off" => None,
+                destination => {
+                    Some(SocketAddress::parse(destination).ok_or(FileSystemError::InvalidData)?)
+                }
+            };
+            log_sink::set_destination(destination).map_err(|_| FileSystemError::DeviceNotFound)?;
+            return Ok(buf.len() as u64);
+        }
+        let count = command
+            .strip_prefix("storm ")
+            .and_then(|count| count.trim().parse::<u64>().ok())
+            .filter(|&count| count <= MAX_STORM)
+            .ok_or(FileSystemError::InvalidData)?;
+        for i in 0..count {
+            if i == count / 2 {
+                log_error!("log storm: the error in the middle of {count}");
+            }
+            println!("log storm: message {i} of {count}");
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(LogLimitsDevice));
+}
+
+/// Hammers one callsite of a local limiter with made up times, then engages its brake
+pub fn run_self_tests() {
+    println!("Running log limit self tests...");
+
+    let storm = Callsite {
+        file: file!(),
+        line: line!(),
+    };
+    let other = Callsite {
+        file: file!(),
+        line: line!(),
+    };
+    // 1ms apart for 3 seconds, then a few at once which can't all pass
+    let mut limiter = LogLimiter::new(10, 0);
+    let mut printed = 0;
+    let mut summarized = 0;
+    let times = (1..=3000u64)
+        .map(|i| i * 1_000_000)
+        .chain([3_000_000_000; 5]);
+    for now in times {
+        let verdict = limiter.submit(storm, now);
+        printed += verdict.print as u64;
+        for notice in verdict.notices.iter().flatten() {
+            match notice {
+                Notice::Suppressed { site, count } if site.is(&storm) => summarized += count,
+                _ => panic!("log limit self test: unexpected {notice:?}"),
+            }
+        }
+    }
+    // the burst, then 10 a second
+    assert!(
+        (10..=10 + 3 * 10 + 1).contains(&printed),
+        "log limit self test: {printed} printed of a storm"
+    );
+    let pending = limiter.sites[limiter.site_index(storm, 3_000_000_000).0].pending;
+    assert!(pending >= 4);
+    assert_eq!(summarized + pending + printed, 3005);
+    assert_eq!(limiter.stats.suppressed, 3005 - printed);
+    // the other site still has its burst
+    assert!(limiter.submit(other, 3_000_000_001).print);
+    // the rest is summarized a second after the first of them
+    assert!(limiter.tick(3_000_500_000).notices[0].is_none());
+    let verdict = limiter.tick(3_000_000_000 + 2 * SECOND);
+    assert!(matches!(
+        verdict.notices[0],
+        Some(Notice::Suppressed { count, .. }) if count == pending
+    ));
+    assert!(limiter.tick(3_000_000_000 + 3 * SECOND).notices[0].is_none());
+
+    // without a clock nothing is dropped
+    let mut no_clock = LogLimiter::new(1, 1);
+    assert!((0..100).all(|_| no_clock.submit(storm, 0).print));
+
+    // twice the sites of the table, all within their burst, in 100ms go over the brake
+    let mut limiter = LogLimiter::new(10, 100);
+    let mut brake_on = 0;
+    let mut now = SECOND;
+    for i in 0..1000u32 {
+        now += 100_000;
+        let site = Callsite {
+            file: "storm.rs",
+            line: i % (SITES as u32 * 2),
+        };
+        let verdict = limiter.submit(site, now);
+        let notices = verdict.notices.iter().flatten();
+        brake_on += notices
+            .filter(|notice| matches!(notice, Notice::BrakeOn { .. }))
+            .count();
+    }
+    assert_eq!(
+        brake_on, 1,
+        "log limit self test: the brake engaged {brake_on} times"
+    );
+    assert!(limiter.stats.brake_engaged);
+    assert_eq!(limiter.stats.passed, 100);
+    assert_eq!(limiter.stats.brake_dropped, 900);
+    // the second of the storm is not quiet, the one after releases it
+    assert!(limiter.tick(now + SECOND).notices[0].is_none());
+    assert!(limiter.stats.brake_engaged);
+    let verdict = limiter.tick(now + 2 * SECOND + 1);
+    assert!(matches!(
+        verdict.notices[0],
+        Some(Notice::BrakeOff { dropped: 900 })
+    ));
+    assert!(!limiter.stats.brake_engaged);
+
+    // the errors go around it all
+    let errors = ERRORS.load(Ordering::Relaxed);
+    log_error!("log limit self test: an error is printed");
+    assert_eq!(ERRORS.load(Ordering::Relaxed), errors + 1);
+
+    println!("Log limit self tests passed");
+}
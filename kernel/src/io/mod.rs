@@ -1,10 +1,17 @@
 use core::{fmt, sync::atomic::AtomicBool};
 
 pub mod console;
+pub mod input_inject;
 pub mod keyboard;
-mod uart;
+mod line_discipline;
+pub mod log_limit;
+pub mod mouse;
+mod ps2;
+pub mod uart;
 mod video_memory;
 
+use kernel_core::utf8;
+
 static PRINT_ERR: AtomicBool = AtomicBool::new(false);
 
 macro_rules! impl_copy_clone_deref_wrapper {
@@ -169,8 +176,12 @@ pub fn hexdump(buf: &[u8]) {
     .unwrap();
 }
 
-pub fn _print(args: ::core::fmt::Arguments) {
-    console::run_with_console(|inner| inner.write_fmt(args)).unwrap();
+pub fn _print(file: &'static str, line: u32, args: ::core::fmt::Arguments) {
+    log_limit::print_limited(log_limit::Callsite { file, line }, args);
+}
+
+pub fn _print_error(args: ::core::fmt::Arguments) {
+    log_limit::print_error(args);
 }
 
 // Enable `eprint!` and `eprintln!` macros
@@ -180,8 +191,8 @@ pub fn set_err_enable(enable: bool) {
     PRINT_ERR.store(enable, core::sync::atomic::Ordering::Release);
 }
 
-pub fn _eprint(args: ::core::fmt::Arguments) {
+pub fn _eprint(file: &'static str, line: u32, args: ::core::fmt::Arguments) {
     if PRINT_ERR.load(core::sync::atomic::Ordering::Acquire) {
-        _print(args);
+        _print(file, line, args);
     }
 }
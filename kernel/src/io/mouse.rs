@@ -0,0 +1,437 @@
+//! PS/2 mouse driver
+//!
+//! The mouse is on the second port of the controller (see [`ps2`]). If it answers the
+//! IntelliMouse knock (sample rates 200, 100 then 80) with the ID `3`, its packets are 4 bytes
+//! with the wheel movement in the last, otherwise they are 3 bytes.
+//!
+//! The first byte of a packet always has bit 3 set, so a byte without it can't start one.
+//! When a byte gets lost, the following ones are skipped until one that can start a packet,
+//! and a packet that still doesn't look right (an overflow, or a wheel byte out of range) is
+//! dropped, so the stream gets back in sync after a few bytes.
+//!
+//! The packets are [`MouseEvent`]s in `/devices/mouse`, see [`kernel_user_link::input`] for
+//! how it is read.
+
+use core::fmt;
+
+use alloc::{sync::Arc, vec::Vec};
+use kernel_user_link::input::MouseEvent;
+
+use crate::{
+    collections::ring::RingBuffer,
+    cpu::{
+        self,
+        idt::{BasicInterruptHandler, InterruptStackFrame64},
+        interrupts::apic,
+    },
+    devices::{self, Device},
+    fs::FileSystemError,
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+use super::ps2::{self, config, controller_command, CommandError, PortIo, SecondPort};
+
+static MOUSE: OnceLock<Arc<Mutex<Mouse>>> = OnceLock::new();
+
+// PS/2 mouse interrupt
+const MOUSE_INT_NUM: u8 = 12;
+
+/// The ID of a mouse with a wheel, after the knock
+const INTELLIMOUSE_ID: u8 = 3;
+const KNOCK_SAMPLE_RATES: [u8; 3] = [200, 100, 80];
+const SAMPLE_RATE: u8 = 100;
+
+#[allow(dead_code)]
+mod command {
+    pub const SET_SAMPLE_RATE: u8 = 0xF3;
+    pub const GET_ID: u8 = 0xF2;
+    pub const ENABLE_REPORTING: u8 = 0xF4;
+    pub const SET_DEFAULTS: u8 = 0xF6;
+}
+
+/// The bits of the first byte of a packet
+mod packet {
+    pub const BUTTONS: u8 = 0b111;
+    pub const ALWAYS_SET: u8 = 1 << 3;
+    pub const X_SIGN: u8 = 1 << 4;
+    pub const Y_SIGN: u8 = 1 << 5;
+    pub const X_OVERFLOW: u8 = 1 << 6;
+    pub const Y_OVERFLOW: u8 = 1 << 7;
+}
+
+/// Enables the second port and the mouse on it, returns whether it has a wheel
+fn probe<P: PortIo>(port: &mut P) -> Result<bool, CommandError> {
+    ps2::send_controller_command(port, controller_command::ENABLE_SECOND_PORT, None)?;
+    ps2::send_controller_command(port, controller_command::READ_CONFIG, None)?;
+    let port_config = ps2::read_byte(port)?;
+    // the clock stays disabled if there is no second port to enable
+    if port_config & config::SECOND_PORT_CLOCK_DISABLED != 0 {
+        return Err(CommandError::NoSecondPort);
+    }
+
+    let mut mouse_port = SecondPort(port);
+    ps2::send_command(&mut mouse_port, &[command::SET_DEFAULTS])?;
+    for rate in KNOCK_SAMPLE_RATES {
+        ps2::send_command(&mut mouse_port, &[command::SET_SAMPLE_RATE, rate])?;
+    }
+    ps2::send_command(&mut mouse_port, &[command::GET_ID])?;
+    let wheel = ps2::read_byte(&mut mouse_port)? == INTELLIMOUSE_ID;
+    ps2::send_command(
+        &mut mouse_port,
+        &[
+            command::SET_SAMPLE_RATE,
+            SAMPLE_RATE,
+            command::ENABLE_REPORTING,
+        ],
+    )?;
+
+    // only now, so the responses above are not taken as packets
+    ps2::send_controller_command(
+        port,
+        controller_command::WRITE_CONFIG,
+        Some(port_config | config::SECOND_PORT_INTERRUPT),
+    )?;
+    Ok(wheel)
+}
+
+/// Fails if there is no mouse, or no second port
+pub fn init_mouse() -> Result<(), CommandError> {
+    let wheel = {
+        let mut controller = ps2::CONTROLLER.lock();
+        ps2::flush(&mut *controller);
+        probe(&mut *controller)?
+    };
+    let mouse = Arc::new(Mutex::new(Mouse::new(wheel)));
+    MOUSE
+        .set(mouse.clone())
+        .unwrap_or_else(|_| panic!("mouse already initialized"));
+    devices::register_device(Arc::new(MouseDevice { mouse }));
+
+    // assign after we have assigned the mouse
+    apic::assign_io_irq(
+        mouse_interrupt_handler as BasicInterruptHandler,
+        MOUSE_INT_NUM,
+        cpu::cpu(),
+    );
+    Ok(())
+}
+
+/// Collects the bytes of the packets, and keeps the events until they are read
+struct Mouse {
+    packet_len: usize,
+    bytes: [u8; 4],
+    len: usize,
+    events: RingBuffer<MouseEvent>,
+    /// Bytes skipped or packets dropped to get back in sync
+    dropped: u64,
+}
+
+impl fmt::Debug for Mouse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mouse")
+            .field("packet_len", &self.packet_len)
+            .field("dropped", &self.dropped)
+            .finish()
+    }
+}
+
+impl Mouse {
+    fn new(wheel: bool) -> Self {
+        Self {
+            packet_len: if wheel { 4 } else { 3 },
+            bytes: [0; 4],
+            len: 0,
+            events: RingBuffer::empty(),
+            dropped: 0,
+        }
+    }
+
+    fn handle_byte(&mut self, data: u8) {
+        if self.len == 0 && !Self::can_start_packet(data) {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes[self.len] = data;
+        self.len += 1;
+        if self.len < self.packet_len {
+            return;
+        }
+        self.len = 0;
+        match self.parse_packet() {
+            Some(event) => self.events.push_replace(event),
+            None => self.dropped += 1,
+        }
+    }
+
+    /// The overflow bits are only set with garbage movement, and mostly if the byte is not
+    /// the first one, so they are taken as a lost sync
+    fn can_start_packet(data: u8) -> bool {
+        data & packet::ALWAYS_SET != 0 && data & (packet::X_OVERFLOW | packet::Y_OVERFLOW) == 0
+    }
+
+    fn parse_packet(&self) -> Option<MouseEvent> {
+        let [flags, x, y, z] = self.bytes;
+        let movement = |value: u8, sign: u8| {
+            let value = value as i16;
+            if flags & sign != 0 {
+                value - 0x100
+            } else {
+                value
+            }
+        };
+        let wheel = if self.packet_len == 4 {
+            // only `-8..=7`, the top bits are the sign
+            let wheel = z as i8;
+            if !(-8..=7).contains(&wheel) {
+                return None;
+            }
+            wheel
+        } else {
+            0
+        };
+        Some(MouseEvent {
+            dx: movement(x, packet::X_SIGN),
+            // the mouse reports up as positive
+            dy: -movement(y, packet::Y_SIGN),
+            wheel,
+            buttons: flags & packet::BUTTONS,
+            reserved: 0,
+        })
+    }
+
+    /// The packet the mouse would send for `event`, with the wheel if it has one. The
+    /// movements must fit in 9 bits
+    fn encode_packet(&self, event: &MouseEvent) -> [u8; 4] {
+        // the mouse reports up as positive
+        let (x, y) = (event.dx, -event.dy);
+        let mut flags = packet::ALWAYS_SET | (event.buttons & packet::BUTTONS);
+        if x < 0 {
+            flags |= packet::X_SIGN;
+        }
+        if y < 0 {
+            flags |= packet::Y_SIGN;
+        }
+        [flags, x as u8, y as u8, event.wheel as u8]
+    }
+
+    /// Fills `buf` with whole events, returns the bytes written
+    fn read_events(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        for record in buf.as_chunks_mut::<{ MouseEvent::SIZE }>().0 {
+            let Some(event) = self.events.pop() else {
+                break;
+            };
+            *record = event.to_bytes();
+            written += MouseEvent::SIZE;
+        }
+        written
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame64) {
+    ps2::handle_interrupt();
+
+    apic::return_from_interrupt();
+}
+
+/// Adds a byte of the mouse, read by [`ps2::handle_interrupt`] or the keyboard, does nothing
+/// if there is no mouse.
+///
+/// Doesn't wait for the lock, as the keyboard can get the bytes while polling in `panic`
+pub(super) fn handle_byte(data: u8) {
+    if let Some(mut mouse) = MOUSE.try_get().and_then(|mouse| mouse.try_lock()) {
+        mouse.handle_byte(data);
+    }
+}
+
+/// Sends the packet of `event` through [`Mouse::handle_byte`], a byte at a time like the
+/// interrupt, returns `false` if there is no mouse
+pub(super) fn inject_event(event: &MouseEvent) -> bool {
+    let Some(mouse) = MOUSE.try_get() else {
+        return false;
+    };
+    let mut mouse = mouse.lock();
+    let packet = mouse.encode_packet(event);
+    for &data in &packet[..mouse.packet_len] {
+        mouse.handle_byte(data);
+    }
+    true
+}
+
+/// `/devices/mouse`, the events of the mouse
+#[derive(Debug)]
+struct MouseDevice {
+    mouse: Arc<Mutex<Mouse>>,
+}
+
+impl Device for MouseDevice {
+    fn name(&self) -> &str {
+        "mouse"
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // the size must fit an event, `UnalignedAccess` is reported as an invalid size
+        if buf.len() < MouseEvent::SIZE {
+            return Err(FileSystemError::UnalignedAccess);
+        }
+        Ok(self.mouse.lock().read_events(buf) as u64)
+    }
+}
+
+fn selftest_events(mouse: &mut Mouse, bytes: &[u8]) -> Vec<MouseEvent> {
+    for &data in bytes {
+        mouse.handle_byte(data);
+    }
+    core::iter::from_fn(|| mouse.events.pop()).collect()
+}
+
+fn event(dx: i16, dy: i16, wheel: i8, buttons: u8) -> MouseEvent {
+    MouseEvent {
+        dx,
+        dy,
+        wheel,
+        buttons,
+        reserved: 0,
+    }
+}
+
+fn selftest_probe() {
+    // the config, then an ack for every byte of the commands, with the ID after its ack
+    let mut port = ps2::SimulatedPort::new(&[0]);
+    port.push_second_port(&[ps2::ACK; 8]);
+    port.push_second_port(&[INTELLIMOUSE_ID]);
+    port.push_second_port(&[ps2::ACK; 3]);
+    assert_eq!(probe(&mut port), Ok(true));
+    assert_eq!(
+        port.written,
+        [
+            command::SET_DEFAULTS,
+            command::SET_SAMPLE_RATE,
+            200,
+            command::SET_SAMPLE_RATE,
+            100,
+            command::SET_SAMPLE_RATE,
+            80,
+            command::GET_ID,
+            command::SET_SAMPLE_RATE,
+            SAMPLE_RATE,
+            command::ENABLE_REPORTING,
+            config::SECOND_PORT_INTERRUPT,
+        ],
+        "mouse self test: wrong commands"
+    );
+    assert_eq!(
+        port.commands[..3],
+        [
+            controller_command::ENABLE_SECOND_PORT,
+            controller_command::READ_CONFIG,
+            controller_command::WRITE_SECOND_PORT
+        ]
+    );
+    assert_eq!(
+        port.commands.last(),
+        Some(&controller_command::WRITE_CONFIG)
+    );
+
+    // without a wheel, with a key (that looks like an ack) before the ack of `GET_ID`
+    let mut port = ps2::SimulatedPort::new(&[0]);
+    port.push_second_port(&[ps2::ACK; 7]);
+    port.push_first_port(&[ps2::ACK]);
+    port.push_second_port(&[ps2::ACK, 0]);
+    port.push_second_port(&[ps2::ACK; 3]);
+    assert_eq!(probe(&mut port), Ok(false));
+
+    let mut port = ps2::SimulatedPort::new(&[config::SECOND_PORT_CLOCK_DISABLED]);
+    assert_eq!(probe(&mut port), Err(CommandError::NoSecondPort));
+    assert!(port.written.is_empty());
+}
+
+fn selftest_packets() {
+    let mut mouse = Mouse::new(false);
+    // right 5 and up 3 with the left button, then left 2 and down 256 (overflowing the byte)
+    let events = selftest_events(&mut mouse, &[0x09, 5, 3, 0x38, 0xFE, 0x00]);
+    assert_eq!(events, [event(5, -3, 0, 1), event(-2, 256, 0, 0)]);
+
+    // the first byte of a packet lost, then an overflowing packet, the stream must resume
+    // from the next packet
+    let events = selftest_events(
+        &mut mouse,
+        &[4, 2, 0xC8, 0x7F, 0x7F, 0x0A, 1, 1, 0x08, 0, 0],
+    );
+    assert_eq!(
+        events,
+        [event(1, -1, 0, 2), event(0, 0, 0, 0)],
+        "mouse self test: didn't get back in sync"
+    );
+    assert_eq!(mouse.dropped, 5);
+
+    let mut mouse = Mouse::new(true);
+    // a wheel scroll down and up, a bad wheel byte drops the packet
+    let events = selftest_events(
+        &mut mouse,
+        &[
+            0x08, 0, 0, 1, 0x08, 0, 0, 0xFF, 0x0C, 1, 0, 0x40, 0x08, 0, 0, 0,
+        ],
+    );
+    assert_eq!(
+        events,
+        [event(0, 0, 1, 0), event(0, 0, -1, 0), event(0, 0, 0, 0)]
+    );
+    assert_eq!(mouse.dropped, 1);
+
+    // the packets made for the injected events read back the same, at the edges of the range
+    for wheel in [false, true] {
+        let mut mouse = Mouse::new(wheel);
+        let sent = [
+            event(255, -255, if wheel { -8 } else { 0 }, 0b101),
+            event(-255, 255, if wheel { 7 } else { 0 }, 0),
+            event(0, 0, 0, 0b010),
+        ];
+        let bytes = sent
+            .iter()
+            .flat_map(|sent| mouse.encode_packet(sent).into_iter().take(mouse.packet_len))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            selftest_events(&mut mouse, &bytes),
+            sent,
+            "mouse self test: wrong injected packets"
+        );
+    }
+}
+
+fn selftest_device() {
+    let mouse = Arc::new(Mutex::new(Mouse::new(false)));
+    let device = MouseDevice {
+        mouse: mouse.clone(),
+    };
+    let mut buf = [0; MouseEvent::SIZE * 2 + 3];
+    assert_eq!(device.read(0, &mut buf).unwrap(), 0);
+    for &data in &[0x09, 1, 0, 0x0A, 2, 0, 0x08, 3, 0] {
+        mouse.lock().handle_byte(data);
+    }
+    assert_eq!(
+        device.read(0, &mut buf).unwrap(),
+        MouseEvent::SIZE as u64 * 2
+    );
+    let first = MouseEvent::from_bytes(buf[..MouseEvent::SIZE].try_into().unwrap());
+    assert_eq!(first, event(1, 0, 0, 1));
+    assert_eq!(device.read(0, &mut buf).unwrap(), MouseEvent::SIZE as u64);
+    assert_eq!(
+        MouseEvent::from_bytes(buf[..MouseEvent::SIZE].try_into().unwrap()),
+        event(3, 0, 0, 0)
+    );
+    assert!(matches!(
+        device.read(0, &mut buf[..MouseEvent::SIZE - 1]),
+        Err(FileSystemError::UnalignedAccess)
+    ));
+}
+
+pub fn run_self_tests() {
+    println!("Running mouse self tests...");
+
+    selftest_probe();
+    selftest_packets();
+    selftest_device();
+
+    println!("Mouse self tests passed");
+}
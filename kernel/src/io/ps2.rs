@@ -0,0 +1,293 @@
+//! The 8042 PS/2 controller, shared by the keyboard (first port, IRQ 1) and the mouse
+//! (second port, IRQ 12).
+//!
+//! Both devices use the same data and status ports, so the bytes of one can be waiting when
+//! the interrupt of the other fires. [`handle_interrupt`] serves both interrupts, and gives
+//! the byte to the driver of the port the status says it came from.
+//!
+//! All accesses go through [`CONTROLLER`], so the bytes of a command to one port never
+//! interleave with a command to the other. Bytes of the other port that come while waiting
+//! for a response are dropped.
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{cpu, sync::spin::mutex::Mutex};
+
+use super::{keyboard, mouse};
+
+pub static CONTROLLER: Mutex<Ps2Controller> = Mutex::new(Ps2Controller);
+
+const DATA_PORT: u16 = 0x60;
+/// Reading it gives the status, writing it sends a command to the controller
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+/// How many times to poll the status before giving up on the device
+const POLL_TIMEOUT: usize = 100_000;
+pub const COMMAND_RETRIES: usize = 3;
+
+#[allow(dead_code)]
+pub mod status {
+    pub const DATA_READY: u8 = 1 << 0;
+    pub const INPUT_BUFFER_FULL: u8 = 1 << 1;
+    pub const SYSTEM_FLAG: u8 = 1 << 2;
+    pub const COMMAND_DATA: u8 = 1 << 3;
+    pub const KEYBOARD_LOCKED: u8 = 1 << 4;
+    /// The waiting byte came from the second port
+    pub const SECOND_PORT_DATA: u8 = 1 << 5;
+    pub const RECEIVE_TIMEOUT: u8 = 1 << 6;
+    pub const PARITY_ERROR: u8 = 1 << 7;
+}
+
+/// The commands to the controller itself
+pub mod controller_command {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const ENABLE_SECOND_PORT: u8 = 0xA8;
+    /// The next data byte goes to the second port
+    pub const WRITE_SECOND_PORT: u8 = 0xD4;
+}
+
+/// The bits of the configuration byte
+#[allow(dead_code)]
+pub mod config {
+    pub const FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+    pub const SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+    pub const FIRST_PORT_CLOCK_DISABLED: u8 = 1 << 4;
+    pub const SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+}
+
+/// The responses of both devices to their commands
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// The device didn't accept the byte, or didn't respond to it
+    Timeout,
+    /// The device kept asking to resend the byte
+    TooManyResends,
+    /// The controller has no second port
+    NoSecondPort,
+}
+
+/// The IO ports of a PS/2 device, so the drivers can be tested without the device
+pub trait PortIo {
+    fn read_status(&mut self) -> u8;
+    fn read_data(&mut self) -> u8;
+    fn write_data(&mut self, data: u8);
+    fn write_command(&mut self, command: u8);
+
+    /// Whether the byte waiting with `status` is for us, and not from the other port
+    fn is_own(&self, status: u8) -> bool {
+        status & status::SECOND_PORT_DATA == 0
+    }
+}
+
+/// The real controller, only reachable through [`CONTROLLER`]
+#[derive(Debug)]
+pub struct Ps2Controller;
+
+impl PortIo for Ps2Controller {
+    fn read_status(&mut self) -> u8 {
+        unsafe { cpu::io_in(STATUS_COMMAND_PORT) }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        unsafe { cpu::io_in(DATA_PORT) }
+    }
+
+    fn write_data(&mut self, data: u8) {
+        unsafe { cpu::io_out(DATA_PORT, data) }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        unsafe { cpu::io_out(STATUS_COMMAND_PORT, command) }
+    }
+}
+
+/// The second port of `P`, the data written goes to the mouse instead of the keyboard
+pub struct SecondPort<'a, P: PortIo>(pub &'a mut P);
+
+impl<P: PortIo> PortIo for SecondPort<'_, P> {
+    fn read_status(&mut self) -> u8 {
+        self.0.read_status()
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.0.read_data()
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.0.write_command(controller_command::WRITE_SECOND_PORT);
+        // the data must wait until the controller took the command
+        if wait_input_empty(self.0).is_ok() {
+            self.0.write_data(data);
+        }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.0.write_command(command);
+    }
+
+    fn is_own(&self, status: u8) -> bool {
+        status & status::SECOND_PORT_DATA != 0
+    }
+}
+
+fn wait_input_empty<P: PortIo>(port: &mut P) -> Result<(), CommandError> {
+    (0..POLL_TIMEOUT)
+        .find(|_| port.read_status() & status::INPUT_BUFFER_FULL == 0)
+        .map(|_| ())
+        .ok_or(CommandError::Timeout)
+}
+
+/// Sends a command with its data bytes, each byte must be acknowledged by the device,
+/// and is resent if the device asks for it.
+///
+/// Any other bytes we get while waiting (keys, or mouse packets) are dropped.
+pub fn send_command<P: PortIo>(port: &mut P, bytes: &[u8]) -> Result<(), CommandError> {
+    for &byte in bytes {
+        let mut acked = false;
+        for _ in 0..COMMAND_RETRIES {
+            wait_input_empty(port)?;
+            port.write_data(byte);
+
+            if wait_response(port)? == ACK {
+                acked = true;
+                break;
+            }
+        }
+        if !acked {
+            return Err(CommandError::TooManyResends);
+        }
+    }
+    Ok(())
+}
+
+/// Waits for an `ACK` or `RESEND` from the device
+fn wait_response<P: PortIo>(port: &mut P) -> Result<u8, CommandError> {
+    for _ in 0..POLL_TIMEOUT {
+        let Some(data) = try_read_own(port) else {
+            continue;
+        };
+        if data == ACK || data == RESEND {
+            return Ok(data);
+        }
+    }
+    Err(CommandError::Timeout)
+}
+
+/// Reads the waiting byte if there is one, `None` if there is none, or if it was from the
+/// other port (which is dropped)
+fn try_read_own<P: PortIo>(port: &mut P) -> Option<u8> {
+    let status = port.read_status();
+    if status & status::DATA_READY == 0 {
+        return None;
+    }
+    let data = port.read_data();
+    port.is_own(status).then_some(data)
+}
+
+/// Waits for a byte that is not a response to a command, i.e. the ID of the device, or the
+/// configuration byte of the controller
+pub fn read_byte<P: PortIo>(port: &mut P) -> Result<u8, CommandError> {
+    (0..POLL_TIMEOUT)
+        .find_map(|_| try_read_own(port))
+        .ok_or(CommandError::Timeout)
+}
+
+/// Drops the bytes waiting in the controller, so they are not taken as responses
+pub fn flush<P: PortIo>(port: &mut P) {
+    // the buffer is a single byte, but the devices may be sending more
+    for _ in 0..16 {
+        if port.read_status() & status::DATA_READY == 0 {
+            break;
+        }
+        port.read_data();
+    }
+}
+
+/// Sends a command to the controller, with its data byte if it has one
+pub fn send_controller_command<P: PortIo>(
+    port: &mut P,
+    command: u8,
+    data: Option<u8>,
+) -> Result<(), CommandError> {
+    wait_input_empty(port)?;
+    port.write_command(command);
+    if let Some(data) = data {
+        wait_input_empty(port)?;
+        port.write_data(data);
+    }
+    Ok(())
+}
+
+/// Reads the byte waiting in the controller, and gives it to the driver of its port
+pub fn handle_interrupt() {
+    let mut controller = CONTROLLER.lock();
+    let status = controller.read_status();
+    if status & status::DATA_READY == 0 {
+        return;
+    }
+    let data = controller.read_data();
+    if status & status::SECOND_PORT_DATA != 0 {
+        drop(controller);
+        mouse::handle_byte(data);
+    } else {
+        drop(controller);
+        keyboard::queue_byte(data);
+    }
+}
+
+/// A controller that answers from a list of responses, and records what was sent
+pub struct SimulatedPort {
+    /// The bytes, with whether they come from the second port
+    responses: VecDeque<(u8, bool)>,
+    pub written: Vec<u8>,
+    pub commands: Vec<u8>,
+}
+
+impl SimulatedPort {
+    /// A controller with `responses` from the first port (or the controller itself)
+    pub fn new(responses: &[u8]) -> Self {
+        Self {
+            responses: responses.iter().map(|&data| (data, false)).collect(),
+            written: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Adds responses that come from the first port after the others
+    pub fn push_first_port(&mut self, responses: &[u8]) {
+        self.responses
+            .extend(responses.iter().map(|&data| (data, false)));
+    }
+
+    /// Adds responses that come from the second port after the others
+    pub fn push_second_port(&mut self, responses: &[u8]) {
+        self.responses
+            .extend(responses.iter().map(|&data| (data, true)));
+    }
+}
+
+impl PortIo for SimulatedPort {
+    fn read_status(&mut self) -> u8 {
+        match self.responses.front() {
+            None => 0,
+            Some((_, false)) => status::DATA_READY,
+            Some((_, true)) => status::DATA_READY | status::SECOND_PORT_DATA,
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.responses.pop_front().map_or(0, |(data, _)| data)
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.written.push(data);
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.commands.push(command);
+    }
+}
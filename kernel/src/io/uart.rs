@@ -1,4 +1,7 @@
-use core::hint;
+use core::{
+    hint,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use crate::cpu;
 
@@ -44,6 +47,14 @@ const LINE_RX_READY: u8 = 1 << 0;
 /// Trasmitter is empty
 const LINE_TX_EMPTY: u8 = 1 << 5;
 
+static COM1_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+fn initialized_flag(port_addr: UartPort) -> &'static AtomicBool {
+    match port_addr {
+        UartPort::COM1 => &COM1_INITIALIZED,
+    }
+}
+
 fn write_reg(port_addr: UartPort, reg: UartReg, val: u8) {
     unsafe { cpu::io_out(port_addr as u16 + reg as u16, val) }
 }
@@ -116,8 +127,46 @@ impl Uart {
         Self { port_addr }
     }
 
+    /// Initializes the port, if it was already initialized by [`Uart::init_early`] its
+    /// used as is, so nothing that was sent is corrupted
     pub fn init(&self) {
+        if self.is_initialized() {
+            return;
+        }
         init_port(self.port_addr);
+        initialized_flag(self.port_addr).store(true, Ordering::Release);
+    }
+
+    /// A minimal initialization, 115200 8N1 without interrupts or FIFO, it doesn't
+    /// panic or allocate, so it can be used before anything else in the kernel.
+    ///
+    /// Does nothing if the port doesn't exist.
+    pub fn init_early(&self) {
+        if self.is_initialized() {
+            return;
+        }
+        // a missing port reads as all ones
+        if read_reg(self.port_addr, UartReg::LineStatus) == 0xFF {
+            return;
+        }
+        write_reg(self.port_addr, UartReg::InterruptEnable, 0);
+        write_reg(self.port_addr, UartReg::InterruptAndFifoControl, 0);
+        // divisor = 1 (115200)
+        write_reg(self.port_addr, UartReg::LineControl, LINE_BAUD_LATCH);
+        write_reg(self.port_addr, UartReg::Data, 0x01);
+        write_reg(self.port_addr, UartReg::InterruptEnable, 0x00);
+        // 8 bits, no parity, one stop bit (8N1)
+        write_reg(self.port_addr, UartReg::LineControl, 0x03);
+        write_reg(
+            self.port_addr,
+            UartReg::ModemControl,
+            MODEM_CTL_DTR | MODEM_CTL_RTS,
+        );
+        initialized_flag(self.port_addr).store(true, Ordering::Release);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        initialized_flag(self.port_addr).load(Ordering::Acquire)
     }
 
     /// SAFETY: `init` must be called before calling this function
@@ -130,8 +179,22 @@ impl Uart {
         write_reg(self.port_addr, UartReg::Data, byte);
     }
 
+    /// Only interrupts when a byte is received, for the input of the console.
+    ///
+    /// Does nothing if the port isn't initialized
+    pub fn enable_rx_interrupts(&self) {
+        if self.is_initialized() {
+            write_reg(self.port_addr, UartReg::InterruptEnable, IE_RX_READY);
+            // `init_early` doesn't connect the interrupt line
+            write_reg(
+                self.port_addr,
+                UartReg::ModemControl,
+                MODEM_CTL_DTR | MODEM_CTL_RTS | MODEM_CTL_OUT1 | MODEM_CTL_OUT2,
+            );
+        }
+    }
+
     /// SAFETY: `init` must be called before calling this function
-    #[allow(dead_code)]
     pub unsafe fn try_read_byte(&self) -> Option<u8> {
         // wait until we can read
         if (read_reg(self.port_addr, UartReg::LineStatus) & LINE_RX_READY) == 0 {
@@ -141,7 +204,6 @@ impl Uart {
         Some(read_reg(self.port_addr, UartReg::Data))
     }
 
-    #[allow(dead_code)]
     pub fn interrupt_num(&self) -> u8 {
         match self.port_addr {
             UartPort::COM1 => 4,
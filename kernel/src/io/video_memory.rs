@@ -1,16 +1,27 @@
 //! A temporary tool to allow for easy printing to the screen.
 //! We are using the VGA text mode buffer to print to the screen.
 //! Which is in the memory address 0xb8000.
+//!
+//! [`ShadowBuffer`] keeps its own copy of the cells, so it can be hidden and drawn again,
+//! which is used for the virtual terminals, its changed rows are drawn later with
+//! [`paint_row`].
 
-use crate::memory_management::memory_layout::physical2virtual;
+use alloc::{string::String, vec, vec::Vec};
 
-const VGA_BUFFER_ADDR: *mut u8 = physical2virtual(0xb8000) as *mut u8;
-const VGA_WIDTH: usize = 80;
+use crate::memory_management::memory_layout::{physical2virtual, VGA_TEXT_BUFFER_ADDR};
+
+use super::utf8::{char_to_cp437, cp437_to_char};
+
+const VGA_BUFFER_ADDR: *mut u8 = physical2virtual(VGA_TEXT_BUFFER_ADDR) as *mut u8;
+pub(super) const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
 
 /// White on black text
 pub(super) const DEFAULT_ATTRIB: u8 = 0x0f;
 
+/// A space with the default attributes, VGA cells are the glyph then the attributes
+const EMPTY_CELL: u16 = (DEFAULT_ATTRIB as u16) << 8 | b' ' as u16;
+
 fn get_index(pos: (usize, usize)) -> isize {
     (pos.0 + pos.1 * VGA_WIDTH) as isize
 }
@@ -68,6 +79,11 @@ impl VgaBuffer {
         self.fix_after_advance();
     }
 
+    /// Writes a char as one cell, translating it to CP437
+    pub fn write_char(&mut self, c: char, attrib: u8) {
+        self.write_byte(char_to_cp437(c), attrib);
+    }
+
     fn clear(&mut self) {
         for i in 0..VGA_HEIGHT {
             self.clear_line(i);
@@ -85,3 +101,128 @@ impl VgaBuffer {
         }
     }
 }
+
+/// A screen that keeps all its cells, and which rows changed since they were drawn, so only
+/// those are drawn with [`paint_row`], or all of them when it becomes visible again
+pub(super) struct ShadowBuffer {
+    cells: Vec<u16>,
+    pos: (usize, usize),
+    // a bit for each row
+    dirty_rows: u32,
+}
+
+impl ShadowBuffer {
+    pub fn new() -> Self {
+        Self {
+            cells: vec![EMPTY_CELL; VGA_WIDTH * VGA_HEIGHT],
+            pos: (0, 0),
+            dirty_rows: 0,
+        }
+    }
+
+    /// Takes what is on the screen now, and continues from the position of `vga`
+    pub fn capture(vga: &VgaBuffer) -> Self {
+        let mut cells = vec![EMPTY_CELL; VGA_WIDTH * VGA_HEIGHT];
+        // SAFETY: the VGA buffer is always mapped, and has all the cells
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                VGA_BUFFER_ADDR as *const u16,
+                cells.as_mut_ptr(),
+                cells.len(),
+            );
+        }
+        Self {
+            cells,
+            pos: vga.pos,
+            dirty_rows: 0,
+        }
+    }
+
+    /// Writes a char as one cell, translating it to CP437.
+    /// `\x08` moves back one cell (to the end of the previous line at the start of one), for
+    /// the echo of the terminals to erase with `"\x08 \x08"`
+    pub fn write_char(&mut self, c: char, attrib: u8) {
+        if c == '\n' {
+            self.pos.0 = 0;
+            self.pos.1 += 1;
+        } else if c == '\r' {
+            self.pos.0 = 0;
+        } else if c == '\x08' {
+            if self.pos.0 > 0 {
+                self.pos.0 -= 1;
+            } else if self.pos.1 > 0 {
+                self.pos = (VGA_WIDTH - 1, self.pos.1 - 1);
+            }
+        } else {
+            let cell = (attrib as u16) << 8 | char_to_cp437(c) as u16;
+            let i = get_index(self.pos);
+            self.cells[i as usize] = cell;
+            self.dirty_rows |= 1 << self.pos.1;
+            self.pos.0 += 1;
+        }
+
+        if self.pos.0 >= VGA_WIDTH {
+            self.pos.0 = 0;
+            self.pos.1 += 1;
+        }
+        if self.pos.1 >= VGA_HEIGHT {
+            // scroll up
+            self.cells.copy_within(VGA_WIDTH.., 0);
+            self.cells[VGA_WIDTH * (VGA_HEIGHT - 1)..].fill(EMPTY_CELL);
+            self.pos.1 = VGA_HEIGHT - 1;
+            self.mark_all_dirty();
+        }
+    }
+
+    /// All the rows need to be drawn, i.e. when it becomes visible
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty_rows = (1 << VGA_HEIGHT) - 1;
+    }
+
+    pub fn has_dirty_rows(&self) -> bool {
+        self.dirty_rows != 0
+    }
+
+    /// Copies the first row that changed since it was taken into `cells`, and returns its
+    /// index, to be drawn with [`paint_row`]
+    pub fn take_dirty_row(&mut self, cells: &mut [u16; VGA_WIDTH]) -> Option<usize> {
+        if self.dirty_rows == 0 {
+            return None;
+        }
+        let row = self.dirty_rows.trailing_zeros() as usize;
+        self.dirty_rows &= !(1 << row);
+        cells.copy_from_slice(&self.cells[row * VGA_WIDTH..][..VGA_WIDTH]);
+        Some(row)
+    }
+
+    /// The chars of the screen, a line for each row without the trailing spaces
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(VGA_WIDTH) {
+            let line = row
+                .iter()
+                .map(|&cell| match cell as u8 {
+                    // never written, could be from before we got the screen
+                    0 => ' ',
+                    glyph => cp437_to_char(glyph),
+                })
+                .collect::<String>();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Draws a row of cells to the VGA buffer
+pub(super) fn paint_row(row: usize, cells: &[u16; VGA_WIDTH]) {
+    assert!(row < VGA_HEIGHT);
+    // SAFETY: the VGA buffer is always mapped, and the row is inside it
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            cells.as_ptr(),
+            (VGA_BUFFER_ADDR as *mut u16).add(row * VGA_WIDTH),
+            VGA_WIDTH,
+        );
+    }
+}
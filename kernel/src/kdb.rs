@@ -0,0 +1,372 @@
+//! A small debugger shell, entered after a panic if the cmdline has `panic=debug`.
+//!
+//! It reads commands from the serial port (and the keyboard if its still working), polled,
+//! since interrupts are disabled.
+//! Nothing here allocates or takes locks, the data structures are read as is, so what is
+//! printed could be inconsistent if the panic happened while changing them.
+
+use core::{
+    hint,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    cpu::{self, exception_table, idt::InterruptStackFrame64},
+    io::{self, keyboard},
+    memory_management::{
+        frames, kaslr, kernel_heap_allocator::ALLOCATOR, physical_page_allocator,
+        virtual_memory_mapper,
+    },
+    process::scheduler,
+    profiler,
+    system::{self, Reason},
+};
+
+const LINE_SIZE: usize = 128;
+/// The maximum bytes `md` prints in one command
+const MAX_DUMP_SIZE: u64 = 0x1000;
+/// The maximum frames `bt` goes through
+const MAX_FRAMES: usize = 64;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// The last exception that reached the default handlers, see [`record_exception`]
+static mut LAST_EXCEPTION: Option<ExceptionReport> = None;
+
+#[derive(Debug, Clone, Copy)]
+struct ExceptionReport {
+    vector: u8,
+    frame: InterruptStackFrame64,
+    error: Option<u64>,
+    cr2: u64,
+}
+
+/// The registers when the shell was entered
+struct PanicRegisters {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Saves the state of an exception, so `regs` can show it, must be called before panicking
+pub fn record_exception(vector: u8, frame: &InterruptStackFrame64, error: Option<u64>) {
+    let report = ExceptionReport {
+        vector,
+        frame: *frame,
+        error,
+        // SAFETY: reading cr2 has no side effects
+        cr2: unsafe { cpu::get_cr2() },
+    };
+    // SAFETY: we only have one CPU, and interrupts are disabled in the exception handlers
+    unsafe { core::ptr::addr_of_mut!(LAST_EXCEPTION).write(Some(report)) };
+}
+
+/// Runs the shell if enabled, this is called by the panic handler after printing the report.
+///
+/// Returns if its not enabled, or if we panicked inside the shell itself
+pub fn enter_on_panic() {
+    if !ENABLED.load(Ordering::Relaxed) || ENTERED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let regs = {
+        let (rsp, rbp): (u64, u64);
+        // SAFETY: only reading registers
+        unsafe {
+            core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        PanicRegisters {
+            rsp,
+            rbp,
+            // SAFETY: only reading flags
+            rflags: unsafe { cpu::rflags() },
+        }
+    };
+
+    println!("\nkdb: entered the debugger, `help` for commands");
+    println!("kdb: data is read without locks, it could be inconsistent");
+    println!(
+        "kdb: kernel slide {:#x}, the ELF addresses are the ones here minus it",
+        kaslr::slide()
+    );
+    let mut line = [0u8; LINE_SIZE];
+    loop {
+        print!("kdb> ");
+        let len = read_line(&mut line);
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            println!("invalid input");
+            continue;
+        };
+        run_command(line, &regs);
+    }
+}
+
+fn read_byte() -> Option<u8> {
+    let uart = io::uart::Uart::new(io::uart::UartPort::COM1);
+    if uart.is_initialized() {
+        // SAFETY: initialized
+        if let Some(byte) = unsafe { uart.try_read_byte() } {
+            return Some(byte);
+        }
+    }
+    // only ASCII, enough for the commands
+    keyboard::try_get_next_char()
+        .filter(char::is_ascii)
+        .map(|c| c as u8)
+}
+
+/// Reads a line into `buf` echoing it, returns its length
+fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(byte) = read_byte() else {
+            hint::spin_loop();
+            continue;
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                println!();
+                return len;
+            }
+            // backspace and delete
+            0x08 | 0x7F => {
+                if len > 0 {
+                    len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            b' '..=b'~' if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses `0x` prefixed hex or decimal numbers
+fn parse_number(arg: Option<&str>) -> Option<u64> {
+    let arg = arg?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn is_canonical(addr: u64) -> bool {
+    let high = addr >> 47;
+    high == 0 || high == 0x1FFFF
+}
+
+/// Returns `true` if all of `addr..addr+len` can be read without faulting
+fn is_readable(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    if !is_canonical(addr) || !is_canonical(end - 1) {
+        return false;
+    }
+    let mut page = addr & !0xFFF;
+    while page < end {
+        if virtual_memory_mapper::get_current_mapping_unlocked(page).is_none() {
+            return false;
+        }
+        page = match page.checked_add(0x1000) {
+            Some(page) => page,
+            None => break,
+        };
+    }
+    true
+}
+
+fn run_command(line: &str, regs: &PanicRegisters) {
+    let mut args = line.split_whitespace();
+    let Some(command) = args.next() else {
+        return;
+    };
+    match command {
+        "help" => {
+            println!("regs              registers at the panic and the last exception");
+            println!("bt                backtrace using the frame pointers");
+            println!("md <addr> <len>   hex dump of memory");
+            println!("pt <addr>         page table mapping of an address");
+            println!("vm                all the mapped ranges of the current page tables");
+            println!("ps                processes");
+            println!("locks             global locks currently held");
+            println!("reboot | halt");
+        }
+        "regs" => print_regs(regs),
+        "bt" => backtrace(regs.rbp),
+        "md" => match (parse_number(args.next()), parse_number(args.next())) {
+            (Some(addr), Some(len)) => memory_dump(addr, len),
+            _ => {
+                println!("usage: md <addr> <len>");
+            }
+        },
+        "pt" => match parse_number(args.next()) {
+            Some(addr) if is_canonical(addr) => {
+                match virtual_memory_mapper::get_current_mapping_unlocked(addr) {
+                    Some(entry) => {
+                        println!("{addr:016X}: {entry:x?}");
+                    }
+                    None => {
+                        println!("{addr:016X}: not mapped");
+                    }
+                }
+            }
+            Some(addr) => {
+                println!("{addr:016X}: not canonical");
+            }
+            None => {
+                println!("usage: pt <addr>");
+            }
+        },
+        "vm" => virtual_memory_mapper::dump(),
+        // SAFETY: nothing else is running, we are in panic
+        "ps" => unsafe { scheduler::print_processes_unlocked() },
+        "locks" => {
+            // the locks that could be shared between the places that panic
+            let locks = [
+                ("scheduler", scheduler::is_locked()),
+                ("kernel vm", virtual_memory_mapper::is_kernel_vm_locked()),
+                ("physical allocator", physical_page_allocator::is_locked()),
+                ("high memory", physical_page_allocator::is_high_locked()),
+                ("frames", frames::is_locked()),
+                ("kernel heap", ALLOCATOR.is_locked()),
+            ];
+            for (name, locked) in locks {
+                println!("{name:<20} {}", if locked { "locked" } else { "free" });
+            }
+            println!("cli depth: {}", cpu::cpu().n_cli());
+        }
+        "reboot" => system::reboot(Reason::Debugger),
+        "halt" => halt(),
+        _ => {
+            println!("unknown command `{command}`, try `help`");
+        }
+    }
+}
+
+fn print_regs(regs: &PanicRegisters) {
+    // SAFETY: only reading control registers
+    let (cr0, cr2, cr3, cr4) = unsafe {
+        (
+            cpu::get_cr0(),
+            cpu::get_cr2(),
+            cpu::get_cr3(),
+            cpu::get_cr4(),
+        )
+    };
+    println!(
+        "rsp: {:016X} rbp: {:016X} rflags: {:016X}",
+        regs.rsp, regs.rbp, regs.rflags
+    );
+    println!("cr0: {cr0:016X} cr2: {cr2:016X} cr3: {cr3:016X} cr4: {cr4:016X}");
+
+    // SAFETY: we are the only one running
+    match unsafe { *addr_of!(LAST_EXCEPTION) } {
+        Some(exception) => {
+            println!("last exception: {}", exception.vector);
+            println!(" frame: {:x?}", exception.frame);
+            if let Some(error) = exception.error {
+                println!(" error: {error:016X}");
+            }
+            println!(" cr2: {:016X}", exception.cr2);
+        }
+        None => {
+            println!("no exception recorded");
+        }
+    }
+}
+
+/// Follows the `rbp` chain, the kernel is not forced to keep frame pointers, so this
+/// can stop early or skip frames.
+///
+/// Shows the address in the ELF too (without the `kaslr` slide), and its symbol if the
+/// profiler loaded them before, reading the file here could need the locks
+fn backtrace(mut rbp: u64) {
+    println!("(best effort, from the frame pointers)");
+    let symbols = profiler::loaded_kernel_symbols();
+    for i in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_readable(rbp, 16) {
+            break;
+        }
+        let mut frame = [0u64; 2];
+        // SAFETY: checked that its mapped above, it can still be a page of a user process
+        //         that is not present, which is caught
+        if unsafe { exception_table::copy_bytes(frame.as_mut_ptr().cast(), rbp as *const u8, 16) }
+            != 0
+        {
+            break;
+        }
+        let [next, return_address] = frame;
+        if return_address == 0 {
+            break;
+        }
+        let link_address = kaslr::link_address(return_address);
+        match symbols.and_then(|symbols| symbols.lookup(link_address)) {
+            Some((name, offset)) => {
+                println!(
+                    "#{i:<2} {return_address:016X} (elf {link_address:016X}) {name}+{offset:#x}"
+                );
+            }
+            None => {
+                println!("#{i:<2} {return_address:016X} (elf {link_address:016X})");
+            }
+        }
+        // the stack grows down, so the frames are at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+fn memory_dump(addr: u64, len: u64) {
+    let len = len.min(MAX_DUMP_SIZE);
+    let mut row = addr & !0xF;
+    let end = addr.saturating_add(len);
+    while row < end {
+        if !is_readable(row, 16) {
+            println!("{row:016X}: unmapped");
+            // skip to the next page
+            row = (row | 0xFFF).saturating_add(1);
+            continue;
+        }
+        print!("{row:016X}:");
+        for i in 0..16 {
+            let byte_addr = row + i;
+            if byte_addr < addr || byte_addr >= end {
+                print!("   ");
+            } else {
+                let mut byte = 0u8;
+                // SAFETY: checked that its mapped above, a fault is caught
+                if unsafe { exception_table::copy_bytes(&mut byte, byte_addr as *const u8, 1) } != 0
+                {
+                    print!(" ??");
+                } else {
+                    print!(" {byte:02X}");
+                }
+            }
+        }
+        println!();
+        row += 16;
+    }
+}
+
+fn halt() -> ! {
+    println!("halted");
+    loop {
+        // SAFETY: interrupts are disabled, so this never returns
+        unsafe { cpu::halt() };
+        hint::spin_loop();
+    }
+}
@@ -1,8 +1,8 @@
-// implement print! and println! macros
+// implement print! and println! macros, limited by their callsite, see `io::log_limit`
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        $crate::io::_print(format_args!($($arg)*));
+        $crate::io::_print(file!(), line!(), format_args!($($arg)*));
     };
 }
 
@@ -16,7 +16,7 @@ macro_rules! println {
 #[macro_export]
 macro_rules! eprint {
     ($($arg:tt)*) => {
-        $crate::io::_eprint(format_args!($($arg)*));
+        $crate::io::_eprint(file!(), line!(), format_args!($($arg)*));
     };
 }
 
@@ -25,3 +25,9 @@ macro_rules! eprintln {
     () => ($crate::eprint!("\n"));
     ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
 }
+
+// the errors, which are never rate limited
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::io::_print_error(format_args!("{}\n", format_args!($($arg)*))));
+}
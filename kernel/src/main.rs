@@ -14,20 +14,27 @@ core::arch::global_asm!(include_str!("boot.S"));
 mod macros;
 
 mod acpi;
+mod boot;
+mod build_info;
 mod collections;
 mod cpu;
 mod devices;
 mod executable;
 mod fs;
 mod io;
+mod kdb;
 mod memory_management;
 mod multiboot2;
+mod net;
 pub mod process;
+mod profiler;
+mod smbios;
 mod sync;
+mod system;
 
 use core::hint;
 
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec};
 use cpu::{
     gdt,
     interrupts::{self, apic},
@@ -43,11 +50,11 @@ use process::scheduler;
 use crate::{
     devices::clock,
     memory_management::{
-        kernel_heap_allocator::ALLOCATOR,
+        kernel_heap_allocator,
         memory_layout::{self, MemSize, KERNEL_HEAP_SIZE, PAGE_4K},
         physical_page_allocator, virtual_space,
     },
-    process::Process,
+    process::{Process, ResourceLimits, INIT_PID},
 };
 
 fn finish_boot() {
@@ -60,7 +67,8 @@ fn finish_boot() {
         allocated,
         free_size,
         heap_size,
-    } = ALLOCATOR.stats();
+        ..
+    } = kernel_heap_allocator::stats();
     println!("\n\nBoot finished!");
     memory_layout::display_kernel_map();
     println!("Free memory: {}", free_mem);
@@ -69,6 +77,12 @@ fn finish_boot() {
         used_mem,
         used_mem.0 as f64 / (used_mem.0 + free_mem.0) as f64 * 100.
     );
+    let (high_free, high_used) = physical_page_allocator::high_stats();
+    println!(
+        "High memory: {} free, {} used",
+        MemSize(((high_free - high_used) * PAGE_4K) as u64),
+        MemSize((high_used * PAGE_4K) as u64)
+    );
     println!("Free heap: {}", MemSize(free_size as u64));
     println!(
         "Used heap: {} ({:0.3}%)",
@@ -81,15 +95,55 @@ fn finish_boot() {
         allocated as f64 / KERNEL_HEAP_SIZE as f64 * 100.
     );
     virtual_space::debug_blocks();
+    cpu::irq_off::print_summary();
     println!();
 }
 
-fn load_init_process() {
-    let mut init_file = fs::open("/init").expect("Could not find `init` file");
+/// Where `init` is looked for in order, unless the cmdline has `init=<path>`. The last is
+/// where the `filesystem` build task puts it
+const INIT_PATHS: &[&str] = &["/initrd/init", "/bin/init", "/init"];
+/// The environment of `init`, which the rest of the processes inherit. There are no current
+/// directories in the kernel, the paths are always absolute, so `PWD` is always `/`
+const INIT_ENV: &[&str] = &["PATH=/:/bin:/initrd", "TERM=amjad_os", "PWD=/"];
+
+/// Opens `init=<path>` of the cmdline, or the first of [`INIT_PATHS`] found
+fn open_init(cmdline: &str) -> fs::File {
+    if let Some(path) = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("init="))
+    {
+        return fs::open(path).unwrap_or_else(|e| panic!("Could not open `init={path}`: {e:?}"));
+    }
+    INIT_PATHS
+        .iter()
+        .find_map(|path| fs::open(path).ok())
+        .unwrap_or_else(|| panic!("Could not find `init`, tried {INIT_PATHS:?}"))
+}
+
+fn load_init_process(cmdline: &str) {
+    let reboot_on_exit = match cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("init_exit="))
+    {
+        None | Some("panic") => false,
+        Some("reboot") => true,
+        Some(policy) => {
+            println!("Unknown `init_exit={policy}`, `init` exiting will panic");
+            false
+        }
+    };
+    scheduler::set_reboot_on_init_exit(reboot_on_exit);
+
+    let mut init_file = open_init(cmdline);
     let elf = Elf::load(&mut init_file).expect("Could not load init file");
-    let mut process = Process::allocate_process(0, &elf, &mut init_file, Vec::new())
+    let argv = vec![String::from(init_file.path())];
+    let env = INIT_ENV.iter().map(|&var| String::from(var)).collect();
+    // the rest of the processes inherit the limits from `init`
+    let limits = ResourceLimits::from_cmdline(cmdline);
+    let mut process = Process::allocate_process(0, &elf, &mut init_file, argv, env, limits)
         .expect("Could not allocate process for `init`");
-    assert!(process.id() == 0, "Must be the first process");
+    assert!(process.id() == INIT_PID, "Must be the first process");
+    process.set_privileged();
 
     // add the console to `init` manually, after that processes will either inherit it or open a pipe or something
     // to act as STDIN/STDOUT/STDERR
@@ -99,38 +153,360 @@ fn load_init_process() {
     process.attach_file_to_fd(FD_STDOUT, console.clone_inherit());
     process.attach_file_to_fd(FD_STDERR, console);
 
-    println!("Added `init` process pid={}", process.id());
+    println!(
+        "Added `init` process pid={} from {}",
+        process.id(),
+        init_file.path()
+    );
     scheduler::push_process(process);
 }
 
 #[link_section = ".text"]
 #[no_mangle]
-pub extern "C" fn kernel_main(multiboot_info: &MultiBoot2Info) -> ! {
+pub extern "C" fn kernel_main(multiboot_info: *const MultiBoot2Info) -> ! {
+    // SAFETY: first, everything that locks uses `cpu::cpu()`
+    unsafe { cpu::set_current(0) };
+    // before anything else, so we can see failures of the early initialization on the serial
+    // port as well, useful for headless machines
+    if cfg!(feature = "earlycon")
+        || MultiBoot2Info::early_cmdline_contains(multiboot_info, "earlycon=serial")
+    {
+        console::early_serial_init();
+    }
+    // before anything that can panic
+    kdb::set_enabled(MultiBoot2Info::early_cmdline_contains(
+        multiboot_info,
+        "panic=debug",
+    ));
+    system::set_panic_reboot(MultiBoot2Info::early_cmdline_contains(
+        multiboot_info,
+        "panic=reboot",
+    ));
+    // to test that the output before the early console reaches the serial port
+    if MultiBoot2Info::early_cmdline_contains(multiboot_info, "earlypanic") {
+        panic!("Early panic requested by `earlypanic`");
+    }
+    // printing before the console is initialized goes directly to the screen, so
+    // this can fail with a readable error, naming the field that is wrong
+    let multiboot_info = MultiBoot2Info::copy_from_ptr(multiboot_info)
+        .unwrap_or_else(|e| panic!("Invalid multiboot info: {e}"));
     // init console first, so if we panicked, we can still see the output
     console::early_init();
+    // the logs of the pure modules go with the rest of the kernel logs
+    kernel_core::set_log_sink(io::_eprint);
+    println!("{}", build_info::banner());
     println!("{}", multiboot_info);
+    memory_management::kaslr::log();
     // must be called before any pages can be allocated
     physical_page_allocator::init(multiboot_info);
     // must be called next, before GDT, and this must be called before any heap allocations
     virtual_memory_mapper::init_kernel_vm();
+    // needs the heap, and must be before any process, see the function
+    unsafe { physical_page_allocator::init_high_memory() };
+    // only needs the heap, and we want the test config as early as possible
+    devices::fw_cfg::init();
+    // test options come from the cmdline, or from the fw_cfg test config file (qemu), which
+    // can be changed without rebuilding the image
+    let test_config = devices::fw_cfg::test_config();
+    let test_option = |name: &str| {
+        multiboot_info
+            .cmdline()
+            .into_iter()
+            .chain(test_config.as_deref())
+            .flat_map(str::split_whitespace)
+            .any(|arg| arg == name)
+    };
+    if test_option("verbose") {
+        io::set_err_enable(true);
+    }
+    // shutdown and reboot exit qemu, with a code for the reason
+    system::set_debug_exit(test_option("debug_exit"));
+    // run by default on debug builds (unless `novmtest`), otherwise, it can be enabled with `vmtest`
+    if (cfg!(debug_assertions) && !test_option("novmtest")) || test_option("vmtest") {
+        virtual_memory_mapper::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("nopagetest")) || test_option("pagetest") {
+        physical_page_allocator::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("noshmtest")) || test_option("shmtest") {
+        memory_management::shm::run_self_tests();
+    }
+    // before the interrupts, nothing else allocates while it counts
+    if (cfg!(debug_assertions) && !test_option("noheaptest")) || test_option("heaptest") {
+        kernel_heap_allocator::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("nobootmemtest")) || test_option("bootmemtest") {
+        memory_management::boot_memory::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("nomultiboottest")) || test_option("multiboottest") {
+        multiboot2::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("nokaslrtest")) || test_option("kaslrtest") {
+        memory_management::kaslr::run_self_tests();
+    }
+    // only computes layouts, so can run anytime
+    if (cfg!(debug_assertions) && !test_option("nostartuptest")) || test_option("startuptest") {
+        process::run_self_tests();
+    }
     // must be called before interrupts
     gdt::init_kernel_gdt();
     interrupts::init_interrupts();
+    // only builds tables for another CPU, after the ones of this CPU are loaded
+    if (cfg!(debug_assertions) && !test_option("nocputest")) || test_option("cputest") {
+        cpu::run_self_tests();
+    }
+    // never returns, it must end with a page fault panic naming the legacy region
+    if test_option("lowmemfaulttest") {
+        virtual_memory_mapper::legacy_region_fault_test();
+    }
     // mount devices map before initializing them
     devices::init_devices_mapping();
+    build_info::init_device();
+    profiler::init_device();
+    // only parses AML from memory, so can run before the real tables are parsed, which
+    // makes a parser bug easier to tell apart from a firmware one
+    if (cfg!(debug_assertions) && !test_option("noamltest")) || test_option("amltest") {
+        acpi::run_self_tests();
+    }
     let bios_tables = acpi::get_acpi_tables(multiboot_info).expect("BIOS tables not found");
     println!("BIOS tables: {}", bios_tables);
+    smbios::init(multiboot_info);
+    // parses its own table, and compares the accessors with the files of the real one
+    if (cfg!(debug_assertions) && !test_option("nosmbiostest")) || test_option("smbiostest") {
+        smbios::run_self_tests();
+    }
     apic::init(&bios_tables);
-    clock::init(&bios_tables);
+    // before any PCI driver asks for its interrupt
+    acpi::pci_routing::init(&bios_tables);
+    acpi::dsl::init(&bios_tables);
+    system::init(&bios_tables);
+    // the tick source until `clock::init` hands off to the local APIC timer
+    devices::pit::init(multiboot_info.cmdline().unwrap_or_default(), &bios_tables);
+    // enables the interrupts for a short time, before anyone else needs an interrupt
+    if (cfg!(debug_assertions) && !test_option("nopittest")) || test_option("pittest") {
+        devices::pit::run_self_tests();
+    }
+    clock::init(multiboot_info.cmdline().unwrap_or_default(), &bios_tables);
+    // needs the clock to wait for them
+    cpu::smp::start_application_processors(multiboot_info.cmdline().unwrap_or_default());
+    // all the CPUs are started
+    virtual_memory_mapper::seal_legacy_boot_regions();
+    // only uses a local page, with made up times
+    if (cfg!(debug_assertions) && !test_option("notimetest")) || test_option("timetest") {
+        clock::time_page::run_self_tests();
+    }
+    // after the clock, so we can see how long the decompression takes
+    fs::initrd::init(multiboot_info);
     unsafe { cpu::set_interrupts() };
-    devices::init_legacy_devices();
-    console::init_late_device();
-    devices::prope_pci_devices();
-    fs::create_disk_mapping(0).expect("Could not load filesystem");
+    // needs the timer interrupt
+    if (cfg!(debug_assertions) && !test_option("nobarriertest")) || test_option("barriertest") {
+        sync::barrier::run_self_tests();
+    }
+    // samples the kernel, needs the timer interrupt
+    if (cfg!(debug_assertions) && !test_option("noprofiletest")) || test_option("profiletest") {
+        profiler::run_self_tests();
+    }
+    // only runs closures, before the boot tasks
+    if (cfg!(debug_assertions) && !test_option("notaskstest")) || test_option("taskstest") {
+        boot::tasks::run_self_tests();
+    }
+    let cmdline = multiboot_info.cmdline().unwrap_or_default();
+    io::log_limit::init(cmdline);
+    // only uses its own limiter, before the storms of the devices
+    if (cfg!(debug_assertions) && !test_option("nologtest")) || test_option("logtest") {
+        io::log_limit::run_self_tests();
+    }
+    let mut boot_tasks = boot::tasks::TaskGraph::new();
+    boot_tasks.add("keyboard", &[], || {
+        devices::init_legacy_devices();
+        cpu::irq_off::init_device();
+        cpu::softirq::init_device();
+        Ok(())
+    });
+    // its interrupt also reads the bytes of the keyboard
+    boot_tasks.add_optional("mouse", &["keyboard"], || {
+        io::mouse::init_mouse().map_err(|e| format!("no mouse: {e:?}"))
+    });
+    // same as `vmtest`, uses a simulated keyboard, so can run anytime after the heap, the
+    // self tests of the devices run alone, the other tasks use the same devices
+    if (cfg!(debug_assertions) && !test_option("nokbdtest")) || test_option("kbdtest") {
+        boot_tasks.add_exclusive("kbdtest", &["keyboard"], || {
+            io::keyboard::run_self_tests();
+            Ok(())
+        });
+    }
+    if (cfg!(debug_assertions) && !test_option("nomousetest")) || test_option("mousetest") {
+        boot_tasks.add_exclusive("mousetest", &["mouse"], || {
+            io::mouse::run_self_tests();
+            Ok(())
+        });
+    }
+    boot_tasks.add("console", &["keyboard"], || {
+        console::init_late_device(cmdline);
+        io::input_inject::init_device();
+        io::log_limit::init_device();
+        Ok(())
+    });
+    // the keys it replays don't type anything, but the console must be there to take them
+    if (cfg!(debug_assertions) && !test_option("noinjecttest")) || test_option("injecttest") {
+        boot_tasks.add_exclusive("injecttest", &["console"], || {
+            io::input_inject::run_self_tests();
+            Ok(())
+        });
+    }
+    // uses the keyboard and the real terminals, and leaves `/devices/vt_dump` for userspace
+    if (cfg!(debug_assertions) && !test_option("novttest")) || test_option("vttest") {
+        boot_tasks.add_exclusive("vttest", &["console"], || {
+            console::run_self_tests();
+            Ok(())
+        });
+    }
+    // the IDE controllers are only kept, their channels are probed by the tasks after it
+    boot_tasks.add("pci", &[], || {
+        devices::prope_pci_devices();
+        Ok(())
+    });
+    // the empty drives take long to time out, so the channels are probed at the same time as
+    // the rest, an empty channel doesn't stop the boot
+    boot_tasks.add_optional("ide_primary", &["pci"], || devices::ide::probe_channel(0));
+    boot_tasks.add_optional("ide_secondary", &["pci"], || devices::ide::probe_channel(1));
+    boot_tasks.add("ide", &["ide_primary", "ide_secondary"], || {
+        devices::ide::register_probed();
+        Ok(())
+    });
+    // the controllers are started by the PCI probe, their ports are slow to reset
+    boot_tasks.add_optional("usb", &["pci", "keyboard"], devices::usb::init_devices);
+    if (cfg!(debug_assertions) && !test_option("nousbtest")) || test_option("usbtest") {
+        boot_tasks.add_exclusive("usbtest", &["usb"], || {
+            devices::usb::run_self_tests();
+            Ok(())
+        });
+    }
+    // the address of the device found by the PCI probe, waits a bit for the log sink
+    boot_tasks.add_optional("net", &["pci"], || net::init(cmdline));
+    if (cfg!(debug_assertions) && !test_option("nonettest")) || test_option("nettest") {
+        boot_tasks.add_exclusive("nettest", &["net"], || {
+            net::run_self_tests();
+            Ok(())
+        });
+    }
+    boot_tasks.add("block", &["pci", "ide"], || {
+        devices::block::init(cmdline);
+        Ok(())
+    });
+    // writes to the AHCI disks and puts the sectors back, nothing else uses them meanwhile
+    if (cfg!(debug_assertions) && !test_option("noahcitest")) || test_option("ahcitest") {
+        boot_tasks.add_exclusive("ahcitest", &["block"], || {
+            devices::ahci::run_self_tests();
+            Ok(())
+        });
+    }
+    boot_tasks.add("ramdisk", &["block"], || {
+        devices::ramdisk::init(cmdline);
+        Ok(())
+    });
+    boot_tasks.add("page_cache", &[], || {
+        memory_management::reclaim::init();
+        fs::page_cache::init();
+        Ok(())
+    });
+    boot_tasks.add("swap", &["block", "ramdisk", "page_cache"], || {
+        memory_management::swap::init(cmdline);
+        Ok(())
+    });
+    boot_tasks.add("meminfo", &[], || {
+        kernel_heap_allocator::init_device();
+        Ok(())
+    });
+    boot_tasks.add("mounts", &[], || {
+        fs::mounts::init();
+        fs::locks::init_device();
+        Ok(())
+    });
+    boot_tasks.add(
+        "root",
+        &["block", "ramdisk", "page_cache", "mounts"],
+        || fs::mounts::mount_root(cmdline).map_err(|e| format!("{e:?}")),
+    );
+    boot_tasks.add("tmp", &["mounts"], || {
+        fs::ramfs::init();
+        Ok(())
+    });
+    let forced_failure = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("boot_fail="));
+    let parallel = !cmdline
+        .split_whitespace()
+        .any(|arg| arg == "boot_tasks=sequential");
+    let timeline = boot_tasks.run(forced_failure, parallel);
+    // without it there is nothing to run `init` from
+    if timeline
+        .record("root")
+        .is_some_and(|record| record.outcome != boot::tasks::Outcome::Done)
+    {
+        panic!("Could not load filesystem");
+    }
+    boot::tasks::publish(timeline);
+    // the same graph with tasks that do nothing, so the real ones don't run twice
+    if (cfg!(debug_assertions) && !test_option("nobootgraphtest")) || test_option("bootgraphtest") {
+        boot::tasks::run_boot_graph_self_tests("root");
+    }
+    // after all the devices are registered
+    if (cfg!(debug_assertions) && !test_option("nodevtest")) || test_option("devtest") {
+        devices::run_self_tests();
+    }
+    if (cfg!(debug_assertions) && !test_option("noversiontest")) || test_option("versiontest") {
+        build_info::run_self_tests();
+    }
+    // after all the shutdown hooks are registered
+    if (cfg!(debug_assertions) && !test_option("nosystest")) || test_option("systest") {
+        system::run_self_tests();
+    }
+    // creates its own ramdisks, and mounts them to `/selftest_ram`
+    if (cfg!(debug_assertions) && !test_option("noblocktest")) || test_option("blocktest") {
+        devices::block::run_self_tests();
+    }
+    // same, with its own FAT ramdisks and ramfs
+    if (cfg!(debug_assertions) && !test_option("nofstest")) || test_option("fstest") {
+        fs::run_self_tests();
+    }
+    // takes all the free memory for a while, after the root is mounted
+    if (cfg!(debug_assertions) && !test_option("noreclaimtest")) || test_option("reclaimtest") {
+        memory_management::reclaim::run_self_tests();
+    }
+    // with its own ramdisk, before any process
+    if (cfg!(debug_assertions) && !test_option("noswaptest")) || test_option("swaptest") {
+        memory_management::swap::run_self_tests();
+    }
+    // loads its programs from `/tmp`
+    if (cfg!(debug_assertions) && !test_option("noexectest")) || test_option("exectest") {
+        executable::run_self_tests();
+    }
+    // uses the initrd from the iso, so must be disabled (with `noinitrdtest`) if booting
+    // with another one
+    if (cfg!(debug_assertions) && !test_option("noinitrdtest")) || test_option("initrdtest") {
+        fs::initrd::run_self_tests();
+    }
+    // the other CPUs run their queues from now on
+    cpu::smp::release_application_processors();
+    // runs processes on the queue of another CPU, while this one doesn't run its own
+    if (cfg!(debug_assertions) && !test_option("noschedtest")) || test_option("schedtest") {
+        scheduler::run_self_tests();
+    }
     finish_boot();
     // -- BOOT FINISHED --
 
-    load_init_process();
+    // these never return, they end in a shutdown and a reboot, best with `debug_exit`
+    if test_option("shutdowntest") {
+        system::selftest_shutdown();
+    }
+    if test_option("watchdogtest") {
+        devices::pit::selftest_watchdog();
+    }
+
+    load_init_process(multiboot_info.cmdline().unwrap_or_default());
+    // after `init`, which has the first pid
+    devices::block::start_writeback();
 
     // this will never return
     scheduler::schedule()
@@ -139,7 +515,13 @@ pub extern "C" fn kernel_main(multiboot_info: &MultiBoot2Info) -> ! {
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     unsafe { cpu::clear_interrupts() };
-    println!("{info}");
+    log_error!("{info}");
+    // the addresses above are where the kernel runs, the ELF has them without it
+    if memory_management::kaslr::slide() != 0 {
+        log_error!("kernel slide: {:#x}", memory_management::kaslr::slide());
+    }
+    system::on_panic();
+    kdb::enter_on_panic();
     loop {
         unsafe {
             cpu::halt();
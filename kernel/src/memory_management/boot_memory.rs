@@ -0,0 +1,322 @@
+//! Where the physical memory is, from the best source the boot gives.
+//!
+//! The multiboot memory map is used when the bootloader gives one. Without it, the basic
+//! memory info (the `mem_lower` and `mem_upper` reported by the BIOS) says how much
+//! conventional memory there is and how much extended memory until the first hole. Without
+//! both, the CMOS has the same sizes, and last the `mem=<size>` option of the command line
+//! says where the memory ends.
+//!
+//! Only the map says where the holes are, the other sources give the conventional memory and
+//! one range from 1MB (two from the CMOS, split at 16MB). The EBDA and the BIOS areas are not
+//! in them, but the allocator clips all the ranges against `LEGACY_REGIONS`, which leaves out
+//! the largest EBDA there can be.
+//!
+//! The `nomemmap` option ignores what the bootloader gives, and `nocmosmem` the CMOS, to
+//! boot like a machine without them.
+
+use core::fmt;
+
+use crate::{
+    devices::clock,
+    memory_management::memory_layout::{MemSize, EXTENDED_OFFSET},
+    multiboot2::{MemoryMap, MemoryMapType, MultiBoot2Info},
+};
+
+/// The available ranges of the memory map kept, the rest are skipped with a warning
+const MAX_RANGES: usize = 32;
+
+/// Only the conventional memory is below this, the rest is the BIOS and the devices
+const CONVENTIONAL_END: u64 = 0xA_0000;
+const EXTENDED_START: u64 = EXTENDED_OFFSET as u64;
+/// Where the CMOS starts counting the memory in 64KB blocks
+const CMOS_HIGH_START: u64 = 16 * 1024 * 1024;
+const CMOS_BLOCK_SIZE: u64 = 64 * 1024;
+
+mod cmos {
+    pub const BASE_MEMORY_LOW: u8 = 0x15;
+    pub const BASE_MEMORY_HIGH: u8 = 0x16;
+    pub const EXTENDED_MEMORY_LOW: u8 = 0x17;
+    pub const EXTENDED_MEMORY_HIGH: u8 = 0x18;
+    // not standard, but set by most BIOSes (and qemu)
+    pub const HIGH_MEMORY_LOW: u8 = 0x34;
+    pub const HIGH_MEMORY_HIGH: u8 = 0x35;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySource {
+    MemoryMap,
+    BasicMemoryInfo,
+    Cmos,
+    CommandLine,
+}
+
+impl fmt::Display for MemorySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MemoryMap => write!(f, "the multiboot memory map"),
+            Self::BasicMemoryInfo => write!(f, "the multiboot basic memory info"),
+            Self::Cmos => write!(f, "the CMOS"),
+            Self::CommandLine => write!(f, "the `mem=` option"),
+        }
+    }
+}
+
+/// The sizes of the memory in the CMOS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmosMemory {
+    /// The conventional memory in KB
+    pub base_kb: u16,
+    /// From 1MB in KB, up to 64MB
+    pub extended_kb: u16,
+    /// From 16MB in 64KB blocks, `0` if the BIOS doesn't say
+    pub high_blocks: u16,
+}
+
+/// The available physical ranges, `(start, end)`, and where they came from
+#[derive(Debug, Clone)]
+pub struct BootMemory {
+    pub source: MemorySource,
+    ranges: [(u64, u64); MAX_RANGES],
+    count: usize,
+}
+
+impl BootMemory {
+    fn new(source: MemorySource) -> Self {
+        Self {
+            source,
+            ranges: [(0, 0); MAX_RANGES],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        if self.count == MAX_RANGES {
+            println!("WARNING: too many memory ranges, skipping [{start:#x}, {end:#x})");
+            return;
+        }
+        self.ranges[self.count] = (start, end);
+        self.count += 1;
+    }
+
+    /// The conventional memory of `low` bytes, and the extended memory until `high`
+    fn with_sizes(source: MemorySource, low: u64, high: u64) -> Self {
+        let mut memory = Self::new(source);
+        memory.push(0, low.min(CONVENTIONAL_END));
+        memory.push(EXTENDED_START, high);
+        memory
+    }
+
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges[..self.count]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.ranges().iter().map(|(start, end)| end - start).sum()
+    }
+}
+
+/// The memory from the first source that has it, in the order of the module docs, `cmos`
+/// is only probed when the bootloader gave nothing. `None` if there is no source at all
+pub fn from_sources(
+    memory_map: Option<impl Iterator<Item = MemoryMap>>,
+    basic: Option<(u32, u32)>,
+    cmos: impl FnOnce() -> Option<CmosMemory>,
+    mem_option: Option<u64>,
+) -> Option<BootMemory> {
+    if let Some(memory_map) = memory_map {
+        let mut memory = BootMemory::new(MemorySource::MemoryMap);
+        for entry in memory_map.filter(|entry| entry.mem_type == MemoryMapType::Available) {
+            memory.push(
+                entry.base_addr,
+                entry.base_addr.saturating_add(entry.length),
+            );
+        }
+        return Some(memory);
+    }
+    if let Some((lower_kb, upper_kb)) = basic {
+        return Some(BootMemory::with_sizes(
+            MemorySource::BasicMemoryInfo,
+            lower_kb as u64 * 1024,
+            EXTENDED_START + upper_kb as u64 * 1024,
+        ));
+    }
+    if let Some(cmos) = cmos() {
+        let extended_end = EXTENDED_START + cmos.extended_kb as u64 * 1024;
+        if cmos.high_blocks == 0 {
+            return Some(BootMemory::with_sizes(
+                MemorySource::Cmos,
+                cmos.base_kb as u64 * 1024,
+                extended_end,
+            ));
+        }
+        // the extended memory count stops at 64MB, after 16MB the blocks are used
+        let mut memory = BootMemory::with_sizes(
+            MemorySource::Cmos,
+            cmos.base_kb as u64 * 1024,
+            extended_end.min(CMOS_HIGH_START),
+        );
+        memory.push(
+            CMOS_HIGH_START,
+            CMOS_HIGH_START + cmos.high_blocks as u64 * CMOS_BLOCK_SIZE,
+        );
+        return Some(memory);
+    }
+    mem_option.map(|size| BootMemory::with_sizes(MemorySource::CommandLine, CONVENTIONAL_END, size))
+}
+
+/// The memory sizes in the CMOS, `None` if there is no CMOS or it says nothing
+fn probe_cmos() -> Option<CmosMemory> {
+    let read_u16 = |low, high| {
+        u16::from_le_bytes([
+            clock::read_cmos_register(low),
+            clock::read_cmos_register(high),
+        ])
+    };
+    let memory = CmosMemory {
+        base_kb: read_u16(cmos::BASE_MEMORY_LOW, cmos::BASE_MEMORY_HIGH),
+        extended_kb: read_u16(cmos::EXTENDED_MEMORY_LOW, cmos::EXTENDED_MEMORY_HIGH),
+        high_blocks: read_u16(cmos::HIGH_MEMORY_LOW, cmos::HIGH_MEMORY_HIGH),
+    };
+    // nothing answers the ports without a CMOS, they read all ones
+    if memory.base_kb == u16::MAX || memory.extended_kb == 0 {
+        return None;
+    }
+    Some(memory)
+}
+
+/// The size of `mem=<size>`, in bytes, with an optional `K`, `M` or `G` suffix
+pub fn parse_mem_option(cmdline: &str) -> Option<u64> {
+    let value = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("mem="))?;
+    let (digits, shift) = match value.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&value[..value.len() - 1], 10),
+        Some(b'M') => (&value[..value.len() - 1], 20),
+        Some(b'G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    let size = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(1 << shift));
+    if size.is_none() {
+        eprintln!("Invalid value for `mem`: {value}");
+    }
+    size
+}
+
+/// The memory of this boot, panics if no source has it
+pub fn find(multiboot_info: &MultiBoot2Info) -> BootMemory {
+    let cmdline = multiboot_info.cmdline().unwrap_or_default();
+    let has_option = |option| cmdline.split_whitespace().any(|arg| arg == option);
+    let from_bootloader = !has_option("nomemmap");
+    let from_cmos = !has_option("nocmosmem");
+
+    let memory = from_sources(
+        multiboot_info.memory_maps().filter(|_| from_bootloader),
+        multiboot_info
+            .basic_memory_info()
+            .filter(|_| from_bootloader)
+            .map(|info| (info.lower_kb(), info.upper_kb())),
+        || probe_cmos().filter(|_| from_cmos),
+        parse_mem_option(cmdline),
+    )
+    .expect("No memory map, basic memory info or CMOS memory sizes, boot with `mem=<size>`");
+    println!(
+        "physical memory from {}: {}",
+        memory.source,
+        MemSize(memory.total())
+    );
+    memory
+}
+
+/// Every source in turn, with the ones before it missing
+pub fn run_self_tests() {
+    println!("Running boot memory self tests...");
+    let map = [
+        MemoryMap {
+            base_addr: 0,
+            length: 0x9_FC00,
+            mem_type: MemoryMapType::Available,
+        },
+        MemoryMap {
+            base_addr: 0xF_0000,
+            length: 0x1_0000,
+            mem_type: MemoryMapType::Reserved,
+        },
+        MemoryMap {
+            base_addr: 0x10_0000,
+            length: 0x1FEE_0000,
+            mem_type: MemoryMapType::Available,
+        },
+    ];
+    let cmos = CmosMemory {
+        base_kb: 640,
+        extended_kb: 0xFC00,
+        high_blocks: 0x1000,
+    };
+    let no_map = None::<core::array::IntoIter<MemoryMap, 0>>;
+
+    let memory = from_sources(Some(map.into_iter()), Some((1, 1)), || Some(cmos), Some(1))
+        .expect("boot memory self test: no memory");
+    assert_eq!(memory.source, MemorySource::MemoryMap);
+    assert_eq!(memory.ranges(), [(0, 0x9_FC00), (0x10_0000, 0x1FFE_0000)]);
+
+    // the basic info is in KB, the upper memory from 1MB
+    let memory = from_sources(no_map.clone(), Some((639, 130_048)), || Some(cmos), Some(1))
+        .expect("boot memory self test: no memory");
+    assert_eq!(memory.source, MemorySource::BasicMemoryInfo);
+    assert_eq!(memory.ranges(), [(0, 0x9_FC00), (0x10_0000, 0x800_0000)]);
+
+    let mut probed = false;
+    let memory = from_sources(
+        no_map.clone(),
+        None,
+        || {
+            probed = true;
+            Some(cmos)
+        },
+        Some(1),
+    )
+    .expect("boot memory self test: no memory");
+    assert!(probed);
+    assert_eq!(memory.source, MemorySource::Cmos);
+    // the extended memory stops at 16MB, where the blocks start
+    assert_eq!(
+        memory.ranges(),
+        [
+            (0, 0xA_0000),
+            (0x10_0000, 0x100_0000),
+            (0x100_0000, 0x1100_0000)
+        ]
+    );
+    let small = CmosMemory {
+        high_blocks: 0,
+        extended_kb: 7 * 1024,
+        ..cmos
+    };
+    let memory = from_sources(no_map.clone(), None, || Some(small), None)
+        .expect("boot memory self test: no memory");
+    assert_eq!(memory.ranges(), [(0, 0xA_0000), (0x10_0000, 0x80_0000)]);
+
+    let memory = from_sources(no_map.clone(), None, || None, Some(256 << 20))
+        .expect("boot memory self test: no memory");
+    assert_eq!(memory.source, MemorySource::CommandLine);
+    assert_eq!(memory.ranges(), [(0, 0xA_0000), (0x10_0000, 0x1000_0000)]);
+    assert!(from_sources(no_map.clone(), None, || None, None).is_none());
+
+    // the map is used even when it has nothing available
+    let memory = from_sources(Some(map[1..2].iter().copied()), None, || None, None);
+    assert!(memory.is_some_and(|memory| memory.ranges().is_empty()));
+
+    assert_eq!(parse_mem_option("a mem=256M b"), Some(256 << 20));
+    assert_eq!(parse_mem_option("mem=2g"), Some(2 << 30));
+    assert_eq!(parse_mem_option("mem=4096k"), Some(4 << 20));
+    assert_eq!(parse_mem_option("mem=1048576"), Some(1 << 20));
+    assert_eq!(parse_mem_option("mem=lots"), None);
+    assert_eq!(parse_mem_option("memory=1M"), None);
+    println!("Boot memory self tests passed");
+}
@@ -0,0 +1,145 @@
+//! A [`Frame`] for each physical page, with the number of users of the page and what it's
+//! used for.
+//!
+//! The pages are claimed by the [`physical_page_allocator`](super::physical_page_allocator)
+//! when they are allocated, with one reference, every vm that maps it again (like the
+//! copy-on-write pages of `fork`) takes one more with [`get`], and the page is only freed when
+//! [`put`] drops the last one. The array is on the heap, so it starts empty, the pages
+//! allocated before [`init`] are not tracked, they act as pages with a single user.
+
+use alloc::vec::Vec;
+
+use super::memory_layout::PAGE_4K;
+use crate::sync::spin::mutex::Mutex;
+
+/// What the page is used for, in [`Frame::flags`]
+pub mod flags {
+    /// Mapped into the user memory of processes
+    pub const USER: u16 = 1 << 0;
+    /// Holds the content of a file in the [`page_cache`](crate::fs::page_cache)
+    pub const PAGE_CACHE: u16 = 1 << 1;
+    /// Part of a [`shm`](crate::memory_management::shm) object
+    pub const SHARED_MEMORY: u16 = 1 << 2;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The number of users of the page, `0` if it's free, or not tracked
+    pub refs: u16,
+    pub flags: u16,
+}
+
+/// The number of frames in each state, only the tracked ones are counted
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameStats {
+    pub tracked: usize,
+    pub used: usize,
+    pub shared: usize,
+    pub user: usize,
+    pub page_cache: usize,
+    pub shared_memory: usize,
+}
+
+// indexed by the physical address / `PAGE_4K`
+static FRAMES: Mutex<Vec<Frame>> = Mutex::new(Vec::new());
+
+fn index(physical: u64) -> usize {
+    physical as usize / PAGE_4K
+}
+
+/// Creates the frames of the physical memory before `physical_end`, must be called once, when
+/// there is a heap
+pub fn init(physical_end: usize) {
+    // allocated before taking the lock, the heap can allocate pages for it, and claim them
+    let frames = alloc::vec![Frame::default(); physical_end.div_ceil(PAGE_4K)];
+    let mut current = FRAMES.lock();
+    assert!(current.is_empty(), "frames initialized twice");
+    *current = frames;
+}
+
+pub fn is_locked() -> bool {
+    FRAMES.is_locked()
+}
+
+/// The frame of the page at `physical`, `None` if it's not tracked
+pub fn frame(physical: u64) -> Option<Frame> {
+    FRAMES.lock().get(index(physical)).copied()
+}
+
+/// The page at `physical` was just allocated, it has one user, and the `flags`
+pub fn claim(physical: u64, flags: u16) {
+    if let Some(frame) = FRAMES.lock().get_mut(index(physical)) {
+        *frame = Frame { refs: 1, flags };
+    }
+}
+
+/// The page at `physical` has one more user, [`put`] only frees it when it's called for all of
+/// them
+pub fn get(physical: u64) {
+    let mut frames = FRAMES.lock();
+    let frame = frames
+        .get_mut(index(physical))
+        .unwrap_or_else(|| panic!("sharing untracked page {physical:#x}"));
+    // the pages from before `init` have no references yet
+    frame.refs = frame
+        .refs
+        .max(1)
+        .checked_add(1)
+        .expect("too many users of a page");
+}
+
+/// Drops one user of the page at `physical`, returns `true` if it was the last one, and the
+/// page must be freed, which is also the case for the pages that are not tracked
+pub fn put(physical: u64) -> bool {
+    let mut frames = FRAMES.lock();
+    let Some(frame) = frames.get_mut(index(physical)) else {
+        return true;
+    };
+    if frame.refs <= 1 {
+        *frame = Frame::default();
+        return true;
+    }
+    frame.refs -= 1;
+    false
+}
+
+/// The number of users of the page at `physical`, the ones not tracked have one
+pub fn refs(physical: u64) -> usize {
+    frame(physical).map_or(1, |frame| frame.refs.max(1) as usize)
+}
+
+/// Forgets the page at `physical`, called when it's freed
+pub fn release(physical: u64) {
+    if let Some(frame) = FRAMES.lock().get_mut(index(physical)) {
+        *frame = Frame::default();
+    }
+}
+
+pub fn set_flags(physical: u64, flags: u16) {
+    if let Some(frame) = FRAMES.lock().get_mut(index(physical)) {
+        frame.flags |= flags;
+    }
+}
+
+/// The page at `physical` is not used for `old` anymore, but for `new`
+pub fn replace_flags(physical: u64, old: u16, new: u16) {
+    if let Some(frame) = FRAMES.lock().get_mut(index(physical)) {
+        frame.flags = (frame.flags & !old) | new;
+    }
+}
+
+pub fn stats() -> FrameStats {
+    let frames = FRAMES.lock();
+    let mut stats = FrameStats {
+        tracked: frames.len(),
+        ..FrameStats::default()
+    };
+    for frame in frames.iter().filter(|frame| frame.refs != 0) {
+        stats.used += 1;
+        stats.shared += (frame.refs > 1) as usize;
+        stats.user += (frame.flags & flags::USER != 0) as usize;
+        stats.page_cache += (frame.flags & flags::PAGE_CACHE != 0) as usize;
+        stats.shared_memory += (frame.flags & flags::SHARED_MEMORY != 0) as usize;
+    }
+    stats
+}
@@ -0,0 +1,285 @@
+//! Records the live allocations of the kernel heap and who made them, only with the
+//! `heaptrack` feature, to find what leaks.
+//!
+//! Every allocation is put in a fixed table by its address, with its size, a sequence number
+//! and the return addresses of its callers, found by following `rbp` like the
+//! [`profiler`](crate::profiler) does, and is removed when it's freed. The table is a static, it
+//! can't use the heap it tracks, so when it's too full the new allocations are only counted as
+//! dropped. `/devices/heap_track` lists the ones still alive, the oldest first, an allocation
+//! that stays there while the number of the others goes up is likely a leak.
+
+use core::fmt::Write;
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    devices::{self, Device},
+    fs::FileSystemError,
+    profiler,
+    sync::spin::mutex::Mutex,
+};
+
+/// Whether the kernel was built with the tracker, it does nothing otherwise
+pub const ENABLED: bool = cfg!(feature = "heaptrack");
+
+/// The callers kept for each allocation
+pub const DEPTH: usize = 4;
+/// The return addresses in the allocator itself, `GlobalAlloc::alloc` and `__rust_alloc`
+const SKIPPED_FRAMES: usize = 2;
+
+/// It takes no space without the feature, the probes get long when it's full, so only 3/4
+/// of it is used
+const SLOTS: usize = if ENABLED { 4096 } else { 1 };
+
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    /// The order the allocations were made in, from the boot
+    pub seq: u64,
+    /// The return addresses of the callers, the nearest first, `0` after the last one
+    pub callers: [u64; DEPTH],
+}
+
+/// Open addressing by the address, with linear probing, `N` is a power of 2
+struct Table<const N: usize> {
+    slots: [Option<Allocation>; N],
+    tracked: usize,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl<const N: usize> Table<N> {
+    const fn new() -> Self {
+        Self {
+            slots: [None; N],
+            tracked: 0,
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // the blocks are 16 bytes aligned
+        ((ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) & (N - 1)
+    }
+
+    fn next(i: usize) -> usize {
+        (i + 1) & (N - 1)
+    }
+
+    fn find(&self, ptr: usize) -> Option<&Allocation> {
+        let mut i = Self::home(ptr);
+        while let Some(allocation) = &self.slots[i] {
+            if allocation.ptr == ptr {
+                return Some(allocation);
+            }
+            i = Self::next(i);
+        }
+        None
+    }
+
+    fn insert(&mut self, ptr: usize, size: usize, callers: [u64; DEPTH]) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.tracked >= N / 4 * 3 {
+            self.dropped += 1;
+            return;
+        }
+        let mut i = Self::home(ptr);
+        while self.slots[i].is_some() {
+            i = Self::next(i);
+        }
+        self.slots[i] = Some(Allocation {
+            ptr,
+            size,
+            seq,
+            callers,
+        });
+        self.tracked += 1;
+    }
+
+    /// Removes `ptr` if its tracked, and moves back the ones probed after it, so the table
+    /// doesn't need tombstones
+    fn remove(&mut self, ptr: usize) {
+        let mut i = Self::home(ptr);
+        loop {
+            match self.slots[i] {
+                None => return,
+                Some(allocation) if allocation.ptr == ptr => break,
+                Some(_) => i = Self::next(i),
+            }
+        }
+        self.slots[i] = None;
+        self.tracked -= 1;
+
+        let mut j = i;
+        loop {
+            j = Self::next(j);
+            let Some(allocation) = self.slots[j] else {
+                return;
+            };
+            // it can move to `i` if its home is not between `i` and `j`, cyclically
+            let home = Self::home(allocation.ptr);
+            let stays = if i <= j {
+                i < home && home <= j
+            } else {
+                i < home || home <= j
+            };
+            if !stays {
+                self.slots[i] = self.slots[j].take();
+                i = j;
+            }
+        }
+    }
+}
+
+static TABLE: Mutex<Table<SLOTS>> = Mutex::new(Table::new());
+
+/// Records the allocation of `size` bytes at `ptr`, called by the allocator after
+/// each `alloc`
+#[inline(never)]
+pub fn record(ptr: *mut u8, size: usize) {
+    if !ENABLED || ptr.is_null() {
+        return;
+    }
+    let rbp: u64;
+    // SAFETY: only reads the register
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+    let mut frames = [0; SKIPPED_FRAMES + DEPTH];
+    let len = profiler::walk(rbp, false, &mut frames);
+    let mut callers = [0; DEPTH];
+    if len > SKIPPED_FRAMES {
+        callers[..len - SKIPPED_FRAMES].copy_from_slice(&frames[SKIPPED_FRAMES..len]);
+    }
+    TABLE.lock().insert(ptr as usize, size, callers);
+}
+
+/// Forgets the allocation at `ptr`, called by the allocator before each `dealloc`
+pub fn forget(ptr: *mut u8) {
+    if ENABLED {
+        TABLE.lock().remove(ptr as usize);
+    }
+}
+
+/// The allocation at `ptr`, if it's tracked
+pub fn find(ptr: *const u8) -> Option<Allocation> {
+    TABLE.lock().find(ptr as usize).copied()
+}
+
+/// The live allocations, the oldest first, and how many were dropped
+pub fn snapshot() -> (Vec<Allocation>, u64) {
+    // allocated outside of the lock, the allocations made until it's taken may not fit
+    let tracked = TABLE.lock().tracked;
+    let mut allocations: Vec<Allocation> = Vec::with_capacity(tracked + 16);
+    let dropped = {
+        let table = TABLE.lock();
+        let space = allocations.capacity();
+        allocations.extend(table.slots.iter().flatten().take(space));
+        table.dropped
+    };
+    allocations.sort_unstable_by_key(|allocation| allocation.seq);
+    (allocations, dropped)
+}
+
+/// `/devices/heap_track`, the live allocations with their callers, one on each line, taken
+/// at every read, so it should be read in one go
+#[derive(Debug)]
+struct HeapTrackDevice;
+
+impl Device for HeapTrackDevice {
+    fn name(&self) -> &str {
+        "heap_track"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // before the snapshot, it reads the filesystem the first time
+        let symbols = profiler::kernel_symbols();
+        let (allocations, dropped) = snapshot();
+        let bytes: usize = allocations.iter().map(|allocation| allocation.size).sum();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# {} allocations, {bytes} bytes, {dropped} dropped\n# seq address size callers",
+            allocations.len()
+        );
+        for allocation in &allocations {
+            let _ = write!(
+                out,
+                "{} {:#x} {}",
+                allocation.seq, allocation.ptr, allocation.size
+            );
+            for &caller in allocation.callers.iter().take_while(|&&caller| caller != 0) {
+                out.push(' ');
+                profiler::kernel_frame_name(symbols, caller, &mut out);
+            }
+            out.push('\n');
+        }
+        Ok(devices::read_bytes(out.as_bytes(), offset, buf))
+    }
+}
+
+/// Adds `/devices/heap_track` if the tracker is enabled
+pub fn init_device() {
+    if ENABLED {
+        devices::register_device(Arc::new(HeapTrackDevice));
+    }
+}
+
+/// Fills the probes of a few slots around the end of a small table, frees from the middle of
+/// them, and checks that the rest are still found
+fn test_table() {
+    let mut table = Table::<8>::new();
+    let with_home = |home: usize| {
+        (1..)
+            .map(|i| i << 4)
+            .filter(move |&ptr| Table::<8>::home(ptr) == home)
+    };
+    let last: Vec<usize> = with_home(7).take(4).collect();
+    let first = with_home(0).next().unwrap();
+    for &ptr in &last {
+        table.insert(ptr, 16, [0; DEPTH]);
+    }
+    table.insert(first, 32, [0; DEPTH]);
+    assert_eq!(table.tracked, 5);
+    // the first 3/4 only
+    table.insert(with_home(3).next().unwrap(), 16, [0; DEPTH]);
+    assert_eq!((table.tracked, table.dropped), (6, 0));
+    table.insert(with_home(4).next().unwrap(), 16, [0; DEPTH]);
+    assert_eq!((table.tracked, table.dropped), (6, 1));
+
+    // they wrapped around the end, and are moved back when the ones before are freed
+    table.remove(last[0]);
+    table.remove(last[2]);
+    table.remove(with_home(7).nth(4).unwrap());
+    for ptr in [last[1], last[3], first] {
+        assert!(
+            table.find(ptr).is_some(),
+            "heap track self test: lost {ptr:#x}"
+        );
+    }
+    assert!(table.find(last[0]).is_none() && table.find(last[2]).is_none());
+    assert_eq!(table.tracked, 4);
+}
+
+/// The table, then an allocation of the heap, with where it was made
+pub fn run_self_tests() {
+    if !ENABLED {
+        return;
+    }
+    test_table();
+
+    let value = Box::new([0u8; 48]);
+    let ptr = value.as_ptr();
+    let allocation = find(ptr).expect("heap track self test: the box is not tracked");
+    assert_eq!(allocation.size, 48);
+    assert!(
+        allocation.callers[0] != 0,
+        "heap track self test: no callers"
+    );
+    drop(value);
+    assert!(
+        find(ptr).is_none(),
+        "heap track self test: freed but tracked"
+    );
+}
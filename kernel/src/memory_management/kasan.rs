@@ -0,0 +1,406 @@
+//! Poisons the freed memory of the kernel to catch the use after free and the overflows of
+//! drivers early, only with the `kasan` feature.
+//!
+//! - The free pages of the [`physical_page_allocator`](super::physical_page_allocator) keep
+//!   their fill, and it's checked when they are taken again.
+//! - The heap blocks are filled with [`UNINIT_POISON`] when allocated, and with
+//!   [`FREED_POISON`] when freed. A freed block is kept in a quarantine for a while, so it
+//!   can't be given again right away. It goes back to the heap when it leaves the quarantine,
+//!   and a write to it while it was there panics then.
+//! - The allocations of [`GUARDED_MIN_SIZE`] or more are not on the heap. Each gets its own
+//!   pages in the kernel space, with an unmapped redzone page on each side, and the object
+//!   at the end of them, so running over its end faults right away. When one is freed, its
+//!   pages are unmapped, but the range stays reserved until its slot is needed again, so a
+//!   use after free faults too, and [`describe_fault`] names the allocation for the panic.
+
+use core::{alloc::Layout, fmt};
+
+use super::{
+    memory_layout::{align_down, align_up, KERNEL_HEAP_BASE, KERNEL_HEAP_SIZE, PAGE_4K},
+    virtual_memory_mapper::{self, VirtualMemoryMapEntry},
+    virtual_space,
+};
+use crate::{profiler, sync::spin::mutex::Mutex};
+
+/// Whether the kernel was built with the poisoning, it does nothing otherwise
+pub const ENABLED: bool = cfg!(feature = "kasan");
+
+/// The fill of the new heap allocations, so reading them before writing stands out
+pub const UNINIT_POISON: u8 = 0x5A;
+/// The fill of the freed heap blocks
+pub const FREED_POISON: u8 = 0x6B;
+/// The allocations that get their own pages and redzones instead of the heap
+pub const GUARDED_MIN_SIZE: usize = PAGE_4K;
+
+/// The callers kept for each freed block
+const DEPTH: usize = 4;
+/// The return addresses in `quarantine`, `GlobalAlloc::dealloc` and `__rust_dealloc`
+const SKIPPED_FRAMES: usize = 2;
+
+// they take no space without the feature
+const QUARANTINE_SLOTS: usize = if ENABLED { 256 } else { 1 };
+const GUARDED_SLOTS: usize = if ENABLED { 256 } else { 1 };
+
+/// The offset of the first byte of `len` bytes at `ptr` that is not `poison`
+///
+/// SAFETY: the bytes must be mapped
+pub unsafe fn find_unpoisoned(ptr: *const u8, len: usize, poison: u8) -> Option<usize> {
+    core::slice::from_raw_parts(ptr, len)
+        .iter()
+        .position(|&byte| byte != poison)
+}
+
+#[derive(Clone, Copy)]
+struct Quarantined {
+    ptr: usize,
+    layout: Layout,
+    /// The return addresses of the callers that freed it, `0` after the last one
+    freed_by: [u64; DEPTH],
+}
+
+/// The freed heap blocks, the oldest first, in a ring
+struct Quarantine {
+    slots: [Option<Quarantined>; QUARANTINE_SLOTS],
+    first: usize,
+    len: usize,
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    slots: [None; QUARANTINE_SLOTS],
+    first: 0,
+    len: 0,
+});
+
+impl Quarantine {
+    /// The slot `offset` after `first`, wrapped around
+    fn slot(&self, offset: usize) -> usize {
+        let slot = self.first + offset;
+        if slot >= QUARANTINE_SLOTS {
+            slot - QUARANTINE_SLOTS
+        } else {
+            slot
+        }
+    }
+
+    fn pop(&mut self) -> Option<Quarantined> {
+        if self.len == 0 {
+            return None;
+        }
+        let block = self.slots[self.first].take();
+        self.first = self.slot(1);
+        self.len -= 1;
+        block
+    }
+
+    /// Returns the oldest block if it had to make space for this one
+    fn push(&mut self, block: Quarantined) -> Option<Quarantined> {
+        let evicted = if self.len == QUARANTINE_SLOTS {
+            self.pop()
+        } else {
+            None
+        };
+        self.slots[self.slot(self.len)] = Some(block);
+        self.len += 1;
+        evicted
+    }
+}
+
+/// Panics if `block` was written since it was freed, returns it to give it to the heap
+fn check_quarantined(block: Quarantined) -> (*mut u8, Layout) {
+    // SAFETY: the heap still has it allocated
+    let written = unsafe { find_unpoisoned(block.ptr as _, block.layout.size(), FREED_POISON) };
+    if let Some(offset) = written {
+        panic!(
+            "kasan: use after free, the heap block {:#x} of {} bytes was written at offset {offset} after it was freed by {:x?}",
+            block.ptr,
+            block.layout.size(),
+            block.freed_by
+        );
+    }
+    (block.ptr as *mut u8, block.layout)
+}
+
+/// Poisons the heap block `ptr` that is being freed and keeps it, returns the block that
+/// must really be freed now, if one left the quarantine for it
+///
+/// SAFETY: `ptr` must be a live heap allocation of `layout`
+#[inline(never)]
+pub unsafe fn quarantine(ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+    ptr.write_bytes(FREED_POISON, layout.size());
+    let rbp: u64;
+    // SAFETY: only reads the register
+    core::arch::asm!("mov {}, rbp", out(reg) rbp);
+    let mut frames = [0; SKIPPED_FRAMES + DEPTH];
+    let len = profiler::walk(rbp, false, &mut frames);
+    let mut freed_by = [0; DEPTH];
+    if len > SKIPPED_FRAMES {
+        freed_by[..len - SKIPPED_FRAMES].copy_from_slice(&frames[SKIPPED_FRAMES..len]);
+    }
+    let evicted = QUARANTINE.lock().push(Quarantined {
+        ptr: ptr as usize,
+        layout,
+        freed_by,
+    });
+    evicted.map(check_quarantined)
+}
+
+/// Empties the quarantine, calling `free` with each block, after checking it
+pub fn flush_quarantine(mut free: impl FnMut(*mut u8, Layout)) {
+    // one at a time, `free` takes the heap lock
+    while let Some(block) = QUARANTINE.lock().pop() {
+        let (ptr, layout) = check_quarantined(block);
+        free(ptr, layout);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GuardedState {
+    /// Being mapped or unmapped, its slot is not given to another one
+    Busy,
+    Live,
+    /// Unmapped, its range is still reserved so using it faults
+    Freed,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Guarded {
+    /// The start of the range, the redzone before the pages
+    start: usize,
+    pages: usize,
+    ptr: usize,
+    size: usize,
+    state: GuardedState,
+    /// The order they were made in, the oldest freed one is evicted first
+    seq: u64,
+}
+
+impl Guarded {
+    fn len(&self) -> usize {
+        (self.pages + 2) * PAGE_4K
+    }
+
+    fn pages_entry(&self) -> VirtualMemoryMapEntry {
+        VirtualMemoryMapEntry {
+            virtual_address: (self.start + PAGE_4K) as u64,
+            physical_address: None,
+            size: (self.pages * PAGE_4K) as u64,
+            flags: virtual_memory_mapper::flags::PTE_WRITABLE
+                | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+        }
+    }
+}
+
+struct GuardedTable {
+    slots: [Option<Guarded>; GUARDED_SLOTS],
+    next_seq: u64,
+}
+
+static GUARDED: Mutex<GuardedTable> = Mutex::new(GuardedTable {
+    slots: [None; GUARDED_SLOTS],
+    next_seq: 0,
+});
+
+impl GuardedTable {
+    /// An empty slot, or the one of the oldest freed allocation, which is returned so its
+    /// range is released. The slot is [`GuardedState::Busy`] until it's filled
+    fn take_slot(&mut self) -> Option<(usize, Option<Guarded>)> {
+        let slot = match self.slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.slots
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, slot)| slot.map(|guarded| (i, guarded)))
+                    .filter(|(_, guarded)| guarded.state == GuardedState::Freed)
+                    .min_by_key(|(_, guarded)| guarded.seq)?
+                    .0
+            }
+        };
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let evicted = self.slots[slot].replace(Guarded {
+            start: 0,
+            pages: 0,
+            ptr: 0,
+            size: 0,
+            state: GuardedState::Busy,
+            seq,
+        });
+        Some((slot, evicted))
+    }
+}
+
+/// Whether the allocations of `layout` get their own pages, see [`alloc_guarded`]
+pub fn is_guarded_layout(layout: Layout) -> bool {
+    ENABLED && layout.size() >= GUARDED_MIN_SIZE && layout.align() <= PAGE_4K
+}
+
+/// Whether `ptr` is from [`alloc_guarded`], all the others are on the heap
+pub fn is_guarded(ptr: *const u8) -> bool {
+    ENABLED && !(KERNEL_HEAP_BASE..KERNEL_HEAP_BASE + KERNEL_HEAP_SIZE).contains(&(ptr as usize))
+}
+
+/// Maps new pages for `layout` between two redzones, the object ends with the pages if its
+/// alignment allows it. `None` if all the slots are live, it must go on the heap then
+pub fn alloc_guarded(layout: Layout) -> Option<*mut u8> {
+    let (slot, evicted) = GUARDED.lock().take_slot()?;
+    if let Some(evicted) = evicted {
+        virtual_space::release_virtual_space(evicted.start as u64, evicted.len() as u64);
+    }
+    let pages = align_up(layout.size(), PAGE_4K) / PAGE_4K;
+    let mut guarded = Guarded {
+        start: 0,
+        pages,
+        ptr: 0,
+        size: layout.size(),
+        state: GuardedState::Live,
+        seq: 0,
+    };
+    guarded.start = virtual_space::reserve_virtual_space(guarded.len() as u64) as usize;
+    virtual_memory_mapper::map_kernel(&guarded.pages_entry());
+    let end = guarded.start + (pages + 1) * PAGE_4K;
+    guarded.ptr = align_down(end - layout.size(), layout.align());
+    // SAFETY: just mapped
+    unsafe { (guarded.ptr as *mut u8).write_bytes(UNINIT_POISON, layout.size()) };
+
+    let mut table = GUARDED.lock();
+    let entry = table.slots[slot]
+        .as_mut()
+        .expect("kasan: lost a guarded slot");
+    guarded.seq = entry.seq;
+    *entry = guarded;
+    Some(guarded.ptr as *mut u8)
+}
+
+/// Unmaps the pages of `ptr`, from [`alloc_guarded`] with `layout`, the range stays reserved
+pub fn free_guarded(ptr: *mut u8, layout: Layout) {
+    let mut table = GUARDED.lock();
+    let next_seq = table.next_seq;
+    let Some(guarded) = table
+        .slots
+        .iter_mut()
+        .flatten()
+        .find(|guarded| guarded.state == GuardedState::Live && guarded.ptr == ptr as usize)
+    else {
+        panic!("kasan: freeing {ptr:p}, which is not a live allocation");
+    };
+    assert_eq!(
+        guarded.size,
+        layout.size(),
+        "kasan: freeing {ptr:p} with the wrong size"
+    );
+    guarded.state = GuardedState::Busy;
+    guarded.seq = next_seq;
+    let entry = guarded.pages_entry();
+    table.next_seq += 1;
+    drop(table);
+
+    virtual_memory_mapper::unmap_kernel(&entry, true);
+    let mut table = GUARDED.lock();
+    if let Some(guarded) = table
+        .slots
+        .iter_mut()
+        .flatten()
+        .find(|guarded| guarded.ptr == ptr as usize && guarded.state == GuardedState::Busy)
+    {
+        guarded.state = GuardedState::Freed;
+    }
+}
+
+/// What a page fault at an address of a guarded allocation hit, for the panic
+#[derive(Debug)]
+pub struct FaultReport {
+    ptr: usize,
+    size: usize,
+    address: usize,
+    freed: bool,
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (ptr, size, address) = (self.ptr, self.size, self.address);
+        if self.freed {
+            write!(
+                f,
+                "kasan: use after free of the allocation {ptr:#x} of {size} bytes"
+            )
+        } else if address < ptr {
+            write!(
+                f,
+                "kasan: {} bytes before the allocation {ptr:#x} of {size} bytes",
+                ptr - address
+            )
+        } else {
+            write!(
+                f,
+                "kasan: {} bytes after the end of the allocation {ptr:#x} of {size} bytes",
+                address - (ptr + size)
+            )
+        }
+    }
+}
+
+/// The guarded allocation that the page fault at `address` hit, its redzones or its freed
+/// pages, `None` if it's not one of them
+pub fn describe_fault(address: u64) -> Option<FaultReport> {
+    if !ENABLED {
+        return None;
+    }
+    let address = address as usize;
+    // the fault could be in here
+    let table = GUARDED.try_lock()?;
+    let guarded = table.slots.iter().flatten().find(|guarded| {
+        guarded.state != GuardedState::Busy
+            && (guarded.start..guarded.start + guarded.len()).contains(&address)
+    })?;
+    let freed = guarded.state == GuardedState::Freed;
+    let pages = guarded.start + PAGE_4K..guarded.start + (guarded.pages + 1) * PAGE_4K;
+    if !freed && pages.contains(&address) {
+        return None;
+    }
+    Some(FaultReport {
+        ptr: guarded.ptr,
+        size: guarded.size,
+        address,
+        freed,
+    })
+}
+
+/// The big allocations get their redzones and are unmapped when freed, and the small ones are
+/// poisoned when freed
+pub fn run_self_tests() {
+    if !ENABLED {
+        return;
+    }
+    let size = GUARDED_MIN_SIZE + 100;
+    let big = alloc::vec![0u8; size].into_boxed_slice();
+    let ptr = big.as_ptr();
+    assert!(
+        is_guarded(ptr),
+        "kasan self test: the big box is on the heap"
+    );
+    // its last byte is the last of its pages
+    let end = ptr as usize + size;
+    assert!(end.is_multiple_of(PAGE_4K));
+    assert!(describe_fault(ptr as u64).is_none());
+    let report = describe_fault(end as u64).expect("kasan self test: no redzone after");
+    assert!(!report.freed && report.ptr == ptr as usize && report.size == size);
+    let before = describe_fault(align_down(ptr as usize, PAGE_4K) as u64 - 1)
+        .expect("kasan self test: no redzone before");
+    assert!(!before.freed);
+    drop(big);
+    assert!(!virtual_memory_mapper::is_address_mapped_in_kernel(
+        ptr as u64
+    ));
+    let report = describe_fault(ptr as u64).expect("kasan self test: forgot the freed one");
+    assert!(report.freed);
+
+    let small = alloc::boxed::Box::new([0u8; 48]);
+    let ptr = small.as_ptr();
+    assert!(!is_guarded(ptr));
+    drop(small);
+    // SAFETY: it's still in the quarantine, so the heap didn't take it back
+    unsafe {
+        assert_eq!(find_unpoisoned(ptr, 48, FREED_POISON), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! Randomizes where the kernel image is (KASLR).
+//!
+//! The kernel is linked as PIE at [`KERNEL_LINK`], and the bootloader loads it there, at 1MB.
+//! Before any other code, `boot.S` calls [`kaslr_relocate`], which copies the image to a random
+//! place of the direct map, applies the relocations of `.rela.dyn` for the new address, and
+//! `boot.S` continues in the copy. The image stays in the direct map, so its virtual and physical
+//! addresses move together, by [`slide`], and [`virtual2physical`] doesn't change for it.
+//!
+//! The places are 2MB apart, in the free memory of the multiboot memory map below
+//! [`KERNEL_MAPPED_SIZE`], after the loaded image and away from the modules and the multiboot
+//! info. The loaded image is free memory after the move, the allocator only skips where the
+//! kernel is.
+//!
+//! The kernel stays where it is linked with the `nokaslr` option, and without a memory map or a
+//! free place. The symbols of the kernel ELF are at the link addresses, so the names of the
+//! frames subtract the slide (see `profiler::kernel_frame_name`), and `kdb` shows both.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{
+    devices::random,
+    memory_management::memory_layout::{
+        align_up, kernel_elf_data_end, kernel_elf_end, kernel_elf_start, virtual2physical,
+        KERNEL_LINK, KERNEL_MAPPED_SIZE, PAGE_2M, PAGE_4K,
+    },
+    multiboot2::{MemoryMapType, MultiBoot2Info},
+};
+
+/// The only relocation type of the kernel, `B + A`, it has no symbols to resolve
+const R_X86_64_RELATIVE: u64 = 8;
+/// The slides are multiples of it, so everything keeps the alignment it was linked with
+const SLIDE_ALIGN: usize = PAGE_2M;
+/// The size of `boot_page_tables` in `boot.S`
+const BOOT_PAGE_TABLES_SIZE: usize = PAGE_4K * 4;
+const PGE_PAGE_SIZE: u64 = 1 << 7;
+const PGE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+extern "C" {
+    static rela_dyn_start: Rela;
+    static rela_dyn_end: Rela;
+    static boot_page_tables: u8;
+}
+
+// only written to the copy by `kaslr_relocate`, before it runs
+static SLIDE: AtomicUsize = AtomicUsize::new(0);
+static PLACEMENT: AtomicU8 = AtomicU8::new(Placement::NotRelocated as u8);
+
+/// An entry of `.rela.dyn`, `Elf64_Rela`
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: u64,
+}
+
+/// Why the kernel is where it is
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// `kaslr_relocate` didn't run, only before `boot.S` calls it
+    NotRelocated,
+    Random,
+    /// The `nokaslr` option
+    Disabled,
+    NoMemoryMap,
+    NoFreePlace,
+    /// The image has a relocation that isn't [`R_X86_64_RELATIVE`]
+    UnknownRelocation,
+}
+
+impl Placement {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Random,
+            2 => Self::Disabled,
+            3 => Self::NoMemoryMap,
+            4 => Self::NoFreePlace,
+            5 => Self::UnknownRelocation,
+            _ => Self::NotRelocated,
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotRelocated => write!(f, "not relocated"),
+            Self::Random => write!(f, "random"),
+            Self::Disabled => write!(f, "disabled by `nokaslr`"),
+            Self::NoMemoryMap => write!(f, "no memory map"),
+            Self::NoFreePlace => write!(f, "no free place"),
+            Self::UnknownRelocation => write!(f, "unknown relocation"),
+        }
+    }
+}
+
+/// How far the kernel is from [`KERNEL_LINK`], the same for the virtual and physical addresses
+pub fn slide() -> usize {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+/// Why the kernel is at [`slide`]
+pub fn placement() -> Placement {
+    Placement::from_u8(PLACEMENT.load(Ordering::Relaxed))
+}
+
+/// The address in the kernel ELF file of the running `address`, for its symbols
+pub fn link_address(address: u64) -> u64 {
+    address.wrapping_sub(slide() as u64)
+}
+
+fn relocations() -> &'static [Rela] {
+    // SAFETY: the linker puts the whole `.rela.dyn` between them
+    unsafe {
+        let start = &raw const rela_dyn_start;
+        let end = &raw const rela_dyn_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Whether `[start, end)` overlaps `[other_start, other_end)`
+fn overlaps(start: usize, end: usize, other_start: usize, other_end: usize) -> bool {
+    start < other_end && other_start < end
+}
+
+/// Whether all of the physical `[start, end)` is in one available range of the memory map, and
+/// none of it is in use, by the loaded image, the modules or the multiboot info
+fn is_free(info: &MultiBoot2Info, start: usize, end: usize) -> bool {
+    let available = info.memory_maps().is_some_and(|mut maps| {
+        maps.any(|map| {
+            map.mem_type == MemoryMapType::Available
+                && map.base_addr <= start as u64
+                && end as u64 <= map.base_addr.saturating_add(map.length)
+        })
+    });
+    let image = virtual2physical(kernel_elf_start());
+    let image_end = virtual2physical(align_up(kernel_elf_end(), PAGE_4K));
+    let info_start = virtual2physical(info as *const MultiBoot2Info as usize);
+    available
+        && !overlaps(start, end, image, image_end)
+        && !overlaps(start, end, info_start, info_start + info.size())
+        && !info
+            .modules()
+            .any(|module| overlaps(start, end, module.start as usize, module.end as usize))
+}
+
+/// The slide of the place picked for the kernel
+fn choose_slide(multiboot_info: *const MultiBoot2Info) -> Result<usize, Placement> {
+    if MultiBoot2Info::early_cmdline_contains(multiboot_info, "nokaslr") {
+        return Err(Placement::Disabled);
+    }
+    if relocations()
+        .iter()
+        .any(|rela| rela.info != R_X86_64_RELATIVE)
+    {
+        return Err(Placement::UnknownRelocation);
+    }
+    let info = MultiBoot2Info::from_ptr(multiboot_info).map_err(|_| Placement::NoMemoryMap)?;
+    if info.memory_maps().is_none() {
+        return Err(Placement::NoMemoryMap);
+    }
+    let image = virtual2physical(kernel_elf_start());
+    let size = align_up(kernel_elf_end(), PAGE_4K) - kernel_elf_start();
+    let slides = || {
+        (SLIDE_ALIGN..)
+            .step_by(SLIDE_ALIGN)
+            .take_while(move |slide| image + slide + size <= KERNEL_MAPPED_SIZE)
+            .filter(|slide| is_free(info, image + slide, image + slide + size))
+    };
+    let count = slides().count();
+    if count == 0 {
+        return Err(Placement::NoFreePlace);
+    }
+    let index = (random::early_u64() % count as u64) as usize;
+    Ok(slides().nth(index).expect("counted above"))
+}
+
+/// Copies the kernel to `slide` after where it is, and applies its relocations there.
+///
+/// # Safety
+/// The destination must be free memory of the direct map, and nothing of the kernel must have
+/// run before, the copy starts from the data as it was loaded
+unsafe fn copy_image(slide: usize) {
+    let start = kernel_elf_start();
+    let data_end = kernel_elf_data_end();
+    let end = kernel_elf_end();
+    core::ptr::copy_nonoverlapping(
+        start as *const u8,
+        (start + slide) as *mut u8,
+        data_end - start,
+    );
+    core::ptr::write_bytes((data_end + slide) as *mut u8, 0, end - data_end);
+
+    for rela in relocations() {
+        let target = (rela.offset as usize + slide) as *mut u64;
+        target.write_unaligned(rela.addend.wrapping_add(slide as u64));
+    }
+
+    // the boot page tables point to each other, by their physical address
+    let tables = &raw const boot_page_tables as usize;
+    let tables_physical = virtual2physical(tables) as u64;
+    let copy = core::slice::from_raw_parts_mut(
+        (tables + slide) as *mut u64,
+        BOOT_PAGE_TABLES_SIZE / core::mem::size_of::<u64>(),
+    );
+    for entry in copy {
+        let address = *entry & PGE_ADDR_MASK;
+        if *entry & PGE_PAGE_SIZE == 0
+            && (tables_physical..tables_physical + BOOT_PAGE_TABLES_SIZE as u64).contains(&address)
+        {
+            *entry += slide as u64;
+        }
+    }
+}
+
+/// Moves the kernel to a random place, called by `boot.S` on the boot stack before anything
+/// else, returns the slide, `0` if the kernel stays where it is.
+///
+/// This runs in the loaded image, which is left behind, so it doesn't write anything of the
+/// kernel but the copy, the results are written to the statics of the copy
+#[no_mangle]
+extern "C" fn kaslr_relocate(multiboot_info: *const MultiBoot2Info) -> usize {
+    let (slide, placement) = match choose_slide(multiboot_info) {
+        Ok(slide) => {
+            // SAFETY: the place is free, and nothing ran before
+            unsafe { copy_image(slide) };
+            (slide, Placement::Random)
+        }
+        Err(placement) => (0, placement),
+    };
+    let moved = |address: usize| address + slide;
+    // SAFETY: the statics of the copy, which is not running yet
+    unsafe {
+        (*(moved(&raw const SLIDE as usize) as *const AtomicUsize)).store(slide, Ordering::Relaxed);
+        (*(moved(&raw const PLACEMENT as usize) as *const AtomicU8))
+            .store(placement as u8, Ordering::Relaxed);
+    }
+    slide
+}
+
+/// Logs where the kernel is
+pub fn log() {
+    println!(
+        "kaslr: kernel at {:#x} (linked at {KERNEL_LINK:#x}), slide {:#x}, {}",
+        kernel_elf_start(),
+        slide(),
+        placement()
+    );
+}
+
+pub fn run_self_tests() {
+    selftest_relocated();
+}
+
+/// The kernel is where the slide says, the absolute addresses (a static pointer, the vtables,
+/// the GOT) were all moved with it, and the symbols see the link addresses
+fn selftest_relocated() {
+    static FUNCTION: fn() = run_self_tests;
+    let slide = slide();
+    assert!(
+        slide.is_multiple_of(SLIDE_ALIGN),
+        "kaslr self test: slide {slide:#x} is not aligned"
+    );
+    assert_eq!(
+        kernel_elf_start(),
+        KERNEL_LINK + slide,
+        "kaslr self test: the kernel is not at the slide"
+    );
+    assert_eq!(placement() == Placement::Random, slide != 0);
+    // SAFETY: a static
+    let stored = unsafe { core::ptr::read_volatile(&raw const FUNCTION) } as usize;
+    assert_eq!(
+        stored, run_self_tests as *const () as usize,
+        "kaslr self test: a static pointer is not relocated"
+    );
+    let object: &dyn fmt::Display = &placement();
+    assert!(
+        !alloc::format!("{object}").is_empty(),
+        "kaslr self test: a vtable is not relocated"
+    );
+    assert!(
+        (KERNEL_LINK as u64..kernel_elf_end() as u64 - slide as u64)
+            .contains(&link_address(stored as u64)),
+        "kaslr self test: the link address of {stored:#x} is outside the ELF"
+    );
+}
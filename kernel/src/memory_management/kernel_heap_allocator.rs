@@ -1,8 +1,14 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use alloc::{format, sync::Arc, vec::Vec};
 use increasing_heap_allocator::{HeapAllocator, HeapStats, PageAllocatorProvider};
 
 use crate::{
+    devices::{self, Device},
+    fs::FileSystemError,
     memory_management::{
         memory_layout::KERNEL_HEAP_SIZE,
         virtual_memory_mapper::{self, flags, VirtualMemoryMapEntry},
@@ -10,7 +16,11 @@ use crate::{
     sync::{once::OnceLock, spin::mutex::Mutex},
 };
 
-use super::memory_layout::{KERNEL_HEAP_BASE, PAGE_4K};
+use super::{
+    frames, heap_track, kasan,
+    memory_layout::{KERNEL_HEAP_BASE, PAGE_4K},
+    physical_page_allocator,
+};
 
 #[global_allocator]
 pub static ALLOCATOR: LockedKernelHeapAllocator = LockedKernelHeapAllocator::empty();
@@ -46,7 +56,7 @@ impl PageAllocatorProvider<PAGE_4K> for PageAllocator {
             virtual_address: current_heap_base as u64,
             physical_address: None,
             size: (PAGE_4K * pages) as u64,
-            flags: flags::PTE_WRITABLE,
+            flags: flags::PTE_WRITABLE | flags::PTE_NO_EXECUTE,
         });
 
         self.mapped_pages += pages;
@@ -61,12 +71,15 @@ impl PageAllocatorProvider<PAGE_4K> for PageAllocator {
 
 pub struct LockedKernelHeapAllocator {
     inner: OnceLock<Mutex<HeapAllocator<PAGE_4K, PageAllocator>>>,
+    // the most that was allocated since `reset_peak`
+    peak: AtomicUsize,
 }
 
 impl LockedKernelHeapAllocator {
     const fn empty() -> Self {
         Self {
             inner: OnceLock::new(),
+            peak: AtomicUsize::new(0),
         }
     }
 
@@ -74,24 +87,148 @@ impl LockedKernelHeapAllocator {
         Mutex::new(HeapAllocator::new(PageAllocator::new()))
     }
 
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_get().is_some_and(|inner| inner.is_locked())
+    }
+
     pub fn stats(&self) -> HeapStats {
         let inner = self.inner.get_or_init(Self::init_mutex).lock();
         inner.stats()
     }
+
+    /// Starts measuring the [`peak`](Self::peak) from the current allocated size
+    pub fn reset_peak(&self) {
+        self.peak.store(self.stats().allocated, Ordering::Relaxed);
+    }
+
+    /// The most the heap had allocated since [`reset_peak`](Self::reset_peak)
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Gives the blocks in the [`kasan`] quarantine back to the heap
+    pub fn flush_quarantine(&self) {
+        kasan::flush_quarantine(|ptr, layout| unsafe {
+            self.inner
+                .get_or_init(Self::init_mutex)
+                .lock()
+                .dealloc(ptr, layout)
+        });
+    }
 }
 
 unsafe impl GlobalAlloc for LockedKernelHeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.inner
-            .get_or_init(Self::init_mutex)
-            .lock()
-            .alloc(layout)
+        if kasan::is_guarded_layout(layout) {
+            if let Some(ptr) = kasan::alloc_guarded(layout) {
+                heap_track::record(ptr, layout.size());
+                return ptr;
+            }
+        }
+        let mut inner = self.inner.get_or_init(Self::init_mutex).lock();
+        let ptr = inner.alloc(layout);
+        self.peak
+            .fetch_max(inner.stats().allocated, Ordering::Relaxed);
+        // under the lock, so the block is not freed and given again before it's recorded
+        heap_track::record(ptr, layout.size());
+        if kasan::ENABLED && !ptr.is_null() {
+            ptr.write_bytes(kasan::UNINIT_POISON, layout.size());
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.inner
-            .get_or_init(Self::init_mutex)
-            .lock()
-            .dealloc(ptr, layout)
+        heap_track::forget(ptr);
+        let (ptr, layout) = if !kasan::ENABLED {
+            (ptr, layout)
+        } else if kasan::is_guarded(ptr) {
+            kasan::free_guarded(ptr, layout);
+            return;
+        } else {
+            // poisoned and kept for a while, the oldest block leaves the quarantine for it
+            match kasan::quarantine(ptr, layout) {
+                Some(block) => block,
+                None => return,
+            }
+        };
+        let mut inner = self.inner.get_or_init(Self::init_mutex).lock();
+        inner.dealloc(ptr, layout)
     }
 }
+
+/// The usage of the kernel heap, and the counts of the allocations
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// `/devices/meminfo`, the usage of the kernel heap, of the physical pages, and what their
+/// [`frames`] are used for, taken at every read
+#[derive(Debug)]
+struct MemInfo;
+
+impl Device for MemInfo {
+    fn name(&self) -> &str {
+        "meminfo"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let heap = stats();
+        let peak = ALLOCATOR.peak();
+        let (pages, used_pages) = physical_page_allocator::stats();
+        let (high_pages, high_used_pages) = physical_page_allocator::high_stats();
+        let frames = frames::stats();
+        let info = format!(
+            "heap_size: {}\nheap_allocated: {}\nheap_free: {}\nheap_peak: {peak}\n\
+             allocations: {}\nfrees: {}\nlive_allocations: {}\n\
+             free_blocks: {}\nlargest_free_block: {}\n\
+             pages: {used_pages}/{pages}\nhigh_pages: {high_used_pages}/{high_pages}\n\
+             frames: {}/{}\nshared_frames: {}\nuser_frames: {}\npage_cache_frames: {}\n\
+             shm_frames: {}\n",
+            heap.heap_size,
+            heap.allocated,
+            heap.free_size,
+            heap.alloc_count,
+            heap.dealloc_count,
+            heap.alloc_count - heap.dealloc_count,
+            heap.free_blocks,
+            heap.largest_free_block,
+            frames.used,
+            frames.tracked,
+            frames.shared,
+            frames.user,
+            frames.page_cache,
+            frames.shared_memory,
+        );
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+}
+
+/// Adds `/devices/meminfo`, and `/devices/heap_track` if the tracker is enabled
+pub fn init_device() {
+    devices::register_device(Arc::new(MemInfo));
+    heap_track::init_device();
+}
+
+/// The counts follow a few allocations, and the free list has a block at least as big as the
+/// biggest one freed
+pub fn run_self_tests() {
+    println!("Running kernel heap self tests...");
+    // the freed blocks only go back to the heap from there
+    ALLOCATOR.flush_quarantine();
+    let before = stats();
+    let blocks: Vec<Vec<u8>> = (1..=8).map(|i| Vec::with_capacity(i * 100)).collect();
+    let during = stats();
+    drop(blocks);
+    ALLOCATOR.flush_quarantine();
+    let after = stats();
+
+    // the outer `Vec` too
+    assert_eq!(during.alloc_count - before.alloc_count, 9);
+    assert_eq!(after.dealloc_count - during.dealloc_count, 9);
+    assert!(during.allocated > before.allocated && after.allocated == before.allocated);
+    assert!(after.largest_free_block >= 800 && after.largest_free_block <= after.free_size);
+    assert!(after.free_blocks > 0);
+    heap_track::run_self_tests();
+    kasan::run_self_tests();
+    println!("Kernel heap self tests passed");
+}
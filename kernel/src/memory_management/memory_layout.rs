@@ -1,5 +1,7 @@
 use core::fmt;
 
+use crate::cpu::MAX_CPUS;
+
 use super::virtual_memory_mapper;
 
 extern "C" {
@@ -16,6 +18,7 @@ extern "C" {
 pub const KERNEL_BASE: usize = 0xFFFF_FFFF_8000_0000;
 // memory extended start (1MB)
 pub const EXTENDED_OFFSET: usize = 0x10_0000;
+// where the kernel is linked and loaded, it runs `kaslr::slide` after it, see `kaslr`
 pub const KERNEL_LINK: usize = KERNEL_BASE + EXTENDED_OFFSET;
 // 128MB (from KERNEL_BASE), and this indicates the address of the end of the kernel
 // every memory used in the kernel, allocated or no, comes from the kernel memory
@@ -35,7 +38,9 @@ pub const INTR_STACK_ENTRY_SIZE: usize = INTR_STACK_SIZE + INTR_STACK_EMPTY_SIZE
 pub const INTR_STACK_BASE: usize = KERNEL_HEAP_BASE + KERNEL_HEAP_SIZE;
 pub const INTR_STACK_COUNT: usize = 7;
 // we are going to setup a spacing at the end of the stack, so that we can detect stack overflows
-pub const INTR_STACK_TOTAL_SIZE: usize = INTR_STACK_ENTRY_SIZE * INTR_STACK_COUNT;
+pub const INTR_STACK_CPU_SIZE: usize = INTR_STACK_ENTRY_SIZE * INTR_STACK_COUNT;
+// each CPU has its own stacks, one after the other, only mapped when the CPU is started
+pub const INTR_STACK_TOTAL_SIZE: usize = INTR_STACK_CPU_SIZE * MAX_CPUS;
 
 // extra space that we can make virtual memory to when we don't care where we want to map it
 // this is only in kernel space, as userspace programs should be mapped into the rest of the memory range
@@ -49,12 +54,16 @@ pub const KERNEL_EXTRA_MEMORY_SIZE: usize = KERNEL_LAST_POSSIBLE_ADDR - KERNEL_E
 pub const KERNEL_PROCESS_VIRTUAL_ADDRESS_START: usize =
     virtual_memory_mapper::KERNEL_PROCESS_VIRTUAL_ADDRESS_START;
 pub const PROCESS_KERNEL_STACK_GUARD: usize = PAGE_4K;
-// process specific kernel stack, this will be where the process is running while in the kernel
-// the process can be interrupted while in the kernel, so we want to save it into a specific stack
-// space so that other processes don't override it when being run
-pub const PROCESS_KERNEL_STACK_BASE: usize =
-    KERNEL_PROCESS_VIRTUAL_ADDRESS_START + PROCESS_KERNEL_STACK_GUARD;
+// process specific kernel stacks, this will be where the threads are running while in the kernel
+// a thread can be interrupted while in the kernel, so we want to save it into a specific stack
+// space so that other threads and processes don't override it when being run.
+// the stacks are in slots, each is an unmapped guard page then the stack, one slot for each thread
 pub const PROCESS_KERNEL_STACK_SIZE: usize = PAGE_4K * 8;
+pub const PROCESS_KERNEL_STACK_SLOT_SIZE: usize =
+    PROCESS_KERNEL_STACK_GUARD + PROCESS_KERNEL_STACK_SIZE;
+pub const PROCESS_KERNEL_STACK_SLOTS: usize = 64;
+// the stack of the slot 0, the first thread of every process
+pub const PROCESS_KERNEL_STACK_BASE: usize = process_kernel_stack_base(0);
 pub const PROCESS_KERNEL_STACK_END: usize = PROCESS_KERNEL_STACK_BASE + PROCESS_KERNEL_STACK_SIZE;
 
 #[allow(dead_code)]
@@ -63,6 +72,29 @@ pub const MB: usize = 0x100_000;
 pub const GB: usize = 0x400_00000;
 pub const PAGE_4K: usize = 0x1000;
 pub const PAGE_2M: usize = 0x20_0000;
+pub const PAGE_1G: usize = 0x4000_0000;
+
+/// The lowest address of the kernel stack in `slot`, after its guard page
+pub const fn process_kernel_stack_base(slot: usize) -> usize {
+    assert!(slot < PROCESS_KERNEL_STACK_SLOTS);
+    KERNEL_PROCESS_VIRTUAL_ADDRESS_START
+        + slot * PROCESS_KERNEL_STACK_SLOT_SIZE
+        + PROCESS_KERNEL_STACK_GUARD
+}
+
+/// The slot of the kernel stack guard page that `addr` is in, if it is in one
+pub fn process_kernel_stack_guard_slot(addr: usize) -> Option<usize> {
+    let offset = addr.checked_sub(KERNEL_PROCESS_VIRTUAL_ADDRESS_START)?;
+    let slot = offset / PROCESS_KERNEL_STACK_SLOT_SIZE;
+    (slot < PROCESS_KERNEL_STACK_SLOTS
+        && offset % PROCESS_KERNEL_STACK_SLOT_SIZE < PROCESS_KERNEL_STACK_GUARD)
+        .then_some(slot)
+}
+
+/// Where the kernel image starts, [`KERNEL_LINK`] moved by `kaslr`
+pub fn kernel_elf_start() -> usize {
+    (unsafe { &begin } as *const usize as usize)
+}
 
 pub fn kernel_elf_end() -> usize {
     (unsafe { &end } as *const usize as usize)
@@ -123,12 +155,123 @@ pub const fn physical2virtual(addr: usize) -> usize {
     addr + KERNEL_BASE
 }
 
+/// How a region of the low memory (first MB) is mapped into kernel space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyAccess {
+    /// Not mapped, any access is a bug and will page fault
+    Unmapped,
+    ReadOnly,
+    Writable,
+    /// Writable while starting the other CPUs, then read-only,
+    /// see [`virtual_memory_mapper::seal_legacy_boot_regions`]
+    WritableOnBoot,
+    /// Writable, the parts the memory map says are available are given to the physical page
+    /// allocator, kept for the devices that can only use memory below 1MB
+    Allocatable,
+}
+
+#[derive(Debug)]
+pub struct LegacyRegion {
+    pub name: &'static str,
+    /// Physical address of the first byte
+    pub start: usize,
+    /// Physical address after the last byte
+    pub end: usize,
+    pub access: LegacyAccess,
+}
+
+// The regions are page aligned, sorted and cover the whole low memory
+pub const LEGACY_REGIONS: &[LegacyRegion] = &[
+    // the BIOS data area is at 0x400..0x500, but it shares the page with the IVT
+    LegacyRegion {
+        name: "IVT and BIOS data area",
+        start: 0x0000_0000,
+        end: 0x0000_1000,
+        access: LegacyAccess::ReadOnly,
+    },
+    LegacyRegion {
+        name: "conventional memory",
+        start: 0x0000_1000,
+        end: AP_TRAMPOLINE_ADDR,
+        access: LegacyAccess::Allocatable,
+    },
+    // the startup IPI vector can only point to a page in the low memory
+    LegacyRegion {
+        name: "AP trampoline",
+        start: AP_TRAMPOLINE_ADDR,
+        end: AP_TRAMPOLINE_ADDR + PAGE_4K,
+        access: LegacyAccess::WritableOnBoot,
+    },
+    LegacyRegion {
+        name: "conventional memory",
+        start: AP_TRAMPOLINE_ADDR + PAGE_4K,
+        end: 0x0008_0000,
+        access: LegacyAccess::Allocatable,
+    },
+    // the EBDA size depends on the BIOS, but it is at most 128KB below the VGA memory
+    LegacyRegion {
+        name: "extended BIOS data area",
+        start: 0x0008_0000,
+        end: 0x000A_0000,
+        access: LegacyAccess::ReadOnly,
+    },
+    LegacyRegion {
+        name: "VGA graphics memory",
+        start: 0x000A_0000,
+        end: VGA_TEXT_BUFFER_ADDR,
+        access: LegacyAccess::Unmapped,
+    },
+    LegacyRegion {
+        name: "VGA text buffer",
+        start: VGA_TEXT_BUFFER_ADDR,
+        end: 0x000C_0000,
+        access: LegacyAccess::Writable,
+    },
+    LegacyRegion {
+        name: "option ROMs",
+        start: 0x000C_0000,
+        end: 0x000E_0000,
+        access: LegacyAccess::Unmapped,
+    },
+    // searched for the ACPI and SMBIOS entry points
+    LegacyRegion {
+        name: "BIOS ROM",
+        start: 0x000E_0000,
+        end: EXTENDED_OFFSET,
+        access: LegacyAccess::ReadOnly,
+    },
+];
+
+pub const AP_TRAMPOLINE_ADDR: usize = 0x8000;
+pub const VGA_TEXT_BUFFER_ADDR: usize = 0xB8000;
+
+/// The legacy region of a physical address in the low memory
+pub fn legacy_region_of(addr: usize) -> Option<&'static LegacyRegion> {
+    LEGACY_REGIONS
+        .iter()
+        .find(|region| (region.start..region.end).contains(&addr))
+}
+
+/// Whether the physical range is inside the low memory and can be read from
+/// the kernel low mapping
+pub fn is_legacy_readable(addr: usize, size: usize) -> bool {
+    let Some(addr_end) = addr.checked_add(size) else {
+        return false;
+    };
+    addr_end <= EXTENDED_OFFSET
+        && LEGACY_REGIONS
+            .iter()
+            .filter(|region| region.start < addr_end && addr < region.end)
+            .all(|region| region.access != LegacyAccess::Unmapped)
+}
+
 pub fn display_kernel_map() {
     println!("Kernel map:");
-    let nothing = KERNEL_BASE..KERNEL_LINK;
+    let low_memory = KERNEL_BASE..KERNEL_LINK;
     let kernel_elf_end = align_up(kernel_elf_end(), PAGE_4K);
-    let kernel_elf = KERNEL_LINK..kernel_elf_end;
-    let kernel_elf_text = KERNEL_LINK..kernel_text_end();
+    let kernel_before = KERNEL_LINK..kernel_elf_start();
+    let kernel_elf = kernel_elf_start()..kernel_elf_end;
+    let kernel_elf_text = kernel_elf_start()..kernel_text_end();
     let kernel_elf_rodata = kernel_text_end()..kernel_elf_rodata_end();
     let kernel_elf_data = kernel_elf_rodata_end()..kernel_elf_data_end();
     let kernel_elf_bss = kernel_elf_data_end()..kernel_elf_end;
@@ -139,11 +282,30 @@ pub fn display_kernel_map() {
         KERNEL_EXTRA_MEMORY_BASE..KERNEL_EXTRA_MEMORY_BASE + KERNEL_EXTRA_MEMORY_SIZE;
 
     println!(
-        "  range={:016x}..{:016x}, len={:4}  nothing",
-        nothing.start,
-        nothing.end,
-        MemSize(nothing.len() as u64)
+        "  range={:016x}..{:016x}, len={:4}  low memory",
+        low_memory.start,
+        low_memory.end,
+        MemSize(low_memory.len() as u64)
     );
+    // inner map for the legacy regions
+    for region in LEGACY_REGIONS {
+        println!(
+            "    range={:016x}..{:016x}, len={:4}  {} ({:?})",
+            physical2virtual(region.start),
+            physical2virtual(region.end),
+            MemSize((region.end - region.start) as u64),
+            region.name,
+            region.access
+        );
+    }
+    if !kernel_before.is_empty() {
+        println!(
+            "  range={:016x}..{:016x}, len={:4}  before the kernel (kaslr)",
+            kernel_before.start,
+            kernel_before.end,
+            MemSize(kernel_before.len() as u64)
+        );
+    }
     println!(
         "  range={:016x}..{:016x}, len={:4}  kernel elf",
         kernel_elf.start,
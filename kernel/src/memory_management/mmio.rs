@@ -0,0 +1,75 @@
+//! Access to the registers of a device mapped into memory, see [`crate::sync::barrier`]
+//! for the rules.
+
+use core::mem;
+
+use super::virtual_space;
+
+/// A range of device registers, mapped uncacheable.
+///
+/// All accesses are volatile, and checked to be inside the region and aligned
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: *mut u8,
+    size: usize,
+}
+
+// SAFETY: this is device memory, it doesn't belong to any thread, the owner decides how to
+// synchronize the accesses
+unsafe impl Send for MmioRegion {}
+
+impl MmioRegion {
+    /// A region with no registers, any access will panic
+    pub const fn empty() -> Self {
+        Self {
+            base: core::ptr::null_mut(),
+            size: 0,
+        }
+    }
+
+    /// Maps `size` bytes of registers starting at the physical address `physical_start`
+    pub fn map(physical_start: u64, size: usize) -> Self {
+        assert!(size > 0);
+        let base = virtual_space::allocate_and_map_mmio(physical_start, size as u64);
+        Self {
+            base: base as *mut u8,
+            size,
+        }
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + mem::size_of::<T>() <= self.size,
+            "MMIO access at {offset:#x} is outside the region of size {:#x}",
+            self.size
+        );
+        assert!(
+            offset.is_multiple_of(mem::align_of::<T>()),
+            "MMIO access at {offset:#x} is not aligned"
+        );
+        // SAFETY: inside the region
+        unsafe { self.base.add(offset) as *mut T }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        // SAFETY: the region is mapped, and the register is inside it
+        unsafe { self.register::<u32>(offset).read_volatile() }
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        // SAFETY: the region is mapped, and the register is inside it
+        unsafe { self.register::<u32>(offset).write_volatile(value) }
+    }
+
+    #[allow(dead_code)]
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        // SAFETY: the region is mapped, and the register is inside it
+        unsafe { self.register::<u64>(offset).read_volatile() }
+    }
+
+    #[allow(dead_code)]
+    pub fn write_u64(&self, offset: usize, value: u64) {
+        // SAFETY: the region is mapped, and the register is inside it
+        unsafe { self.register::<u64>(offset).write_volatile(value) }
+    }
+}
@@ -1,5 +1,14 @@
+pub mod boot_memory;
+pub mod frames;
+pub mod heap_track;
+pub mod kasan;
+pub mod kaslr;
 pub mod kernel_heap_allocator;
 pub mod memory_layout;
+pub mod mmio;
 pub mod physical_page_allocator;
+pub mod reclaim;
+pub mod shm;
+pub mod swap;
 pub mod virtual_memory_mapper;
 pub mod virtual_space;
@@ -1,18 +1,55 @@
-use super::memory_layout::{align_down, align_up, is_aligned, PAGE_4K};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{vec, vec::Vec};
+
+use super::memory_layout::{align_down, align_up, is_aligned, PAGE_2M, PAGE_4K};
 use crate::{
-    memory_management::memory_layout::{
-        kernel_elf_end, physical2virtual, virtual2physical, EXTENDED_OFFSET, KERNEL_END,
-        KERNEL_LINK,
+    devices::event::KernelEvent,
+    memory_management::{
+        boot_memory,
+        frames::{self, flags as frame_flags},
+        kasan,
+        memory_layout::{
+            kernel_elf_end, kernel_elf_start, physical2virtual, virtual2physical, LegacyAccess,
+            EXTENDED_OFFSET, KERNEL_END, KERNEL_MAPPED_SIZE, LEGACY_REGIONS,
+        },
+        reclaim,
+        virtual_memory_mapper::{self, VirtualMemoryMapEntry},
+        virtual_space,
     },
-    multiboot2::{MemoryMapType, MultiBoot2Info},
+    multiboot2::MultiBoot2Info,
     sync::spin::mutex::Mutex,
 };
 
+// the modules, the multiboot info is copied into the kernel
+const MAX_BOOT_RESERVED: usize = 16;
+// the conventional memory ones, and the boot memory ranges after it, split by the kernel
+const MAX_RANGES: usize = 16;
+
+// the free pages are filled with it, checked when they are taken with `kasan`
+const FREE_FILL: u8 = 2;
+
+/// The low-water mark, in percent of the pages available after `init`, going under it
+/// signals the events of [`subscribe_low_memory`]
+pub const LOW_MEMORY_PERCENT: usize = 5;
+
+/// The `below_addr` of [`alloc_contiguous`] for devices that only take 32 bits addresses
+pub const DMA_32BIT_LIMIT: u64 = 1 << 32;
+
 struct FreePage {
     next: *mut FreePage,
 }
 
 static mut ALLOCATOR: Mutex<PhysicalPageAllocator> = Mutex::new(PhysicalPageAllocator::empty());
+static HIGH_MEMORY: Mutex<HighMemory> = Mutex::new(HighMemory::empty());
+
+static LOW_MEMORY_EVENTS: Mutex<Vec<KernelEvent>> = Mutex::new(Vec::new());
+// set when going under the low-water mark, until the events are signaled
+static LOW_MEMORY_PENDING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_locked() -> bool {
+    unsafe { (*core::ptr::addr_of!(ALLOCATOR)).is_locked() }
+}
 
 pub fn init(multiboot_info: &MultiBoot2Info) {
     unsafe {
@@ -20,12 +57,66 @@ pub fn init(multiboot_info: &MultiBoot2Info) {
     }
 }
 
+/// SAFETY: this must be called once, after `init` and `virtual_memory_mapper::init_kernel_vm`,
+/// and before any process is created
+///
+/// Manages the memory above [`KERNEL_MAPPED_SIZE`] that `init` found, see [`HighMemory`],
+/// and creates the [`frames`] of all the pages, from here the allocated pages are tracked.
+/// Their metadata is on the heap, and the page the high memory is reached through is mapped
+/// here, before the processes copy the kernel page tables
+pub unsafe fn init_high_memory() {
+    let allocator = (*core::ptr::addr_of!(ALLOCATOR)).lock();
+    let ranges = allocator.high_ranges;
+    let reserved = allocator.reserved;
+    let reserved = &reserved[..allocator.reserved_count];
+    let range_count = allocator.high_range_count;
+    let low_end = allocator.ranges[..allocator.range_count]
+        .iter()
+        .map(|&(_, end)| virtual2physical(end))
+        .max()
+        .unwrap_or(0);
+    drop(allocator);
+    let high_end = ranges[..range_count]
+        .iter()
+        .map(|&(_, end)| end)
+        .max()
+        .unwrap_or(0);
+    frames::init(low_end.max(high_end));
+    HIGH_MEMORY.lock().init(&ranges[..range_count], reserved);
+}
+
 /// SAFETY: this must be called after `init`
 ///
 /// Allocates a 4K page of memory, the returned address is guaranteed to be aligned to 4K, and is mapped into virtual space
 /// Please use `virtual2physical` to get the physical address
 pub unsafe fn alloc() -> *mut u8 {
-    ALLOCATOR.lock().alloc()
+    try_alloc().expect("out of memory")
+}
+
+/// SAFETY: this must be called after `init`
+///
+/// Like [`alloc`], but returns `None` when out of memory, after all the caches were asked to
+/// give pages back (see [`reclaim`])
+pub unsafe fn try_alloc() -> Option<*mut u8> {
+    let mut page = ALLOCATOR.lock().try_alloc();
+    // the lock must not be held while reclaiming, the shrinkers free pages
+    while page.is_none() && reclaim::reclaim_for_allocation() {
+        page = ALLOCATOR.lock().try_alloc();
+    }
+    signal_low_memory_if_pending();
+    if let Some(page) = page {
+        frames::claim(virtual2physical(page as usize) as u64, 0);
+    }
+    page
+}
+
+/// SAFETY: this must be called after `init`
+///
+/// Takes a free page without reclaiming anything, for tests that need to run out of memory
+pub(super) unsafe fn alloc_without_reclaim() -> Option<*mut u8> {
+    let page = (*core::ptr::addr_of!(ALLOCATOR)).lock().try_alloc()?;
+    frames::claim(virtual2physical(page as usize) as u64, 0);
+    Some(page)
 }
 
 /// SAFETY: this must be called after `init`
@@ -46,139 +137,571 @@ pub unsafe fn alloc_zeroed() -> *mut u8 {
 /// - `page` is not in the range of the allocator
 /// - `page` is not aligned to 4K
 pub unsafe fn free(page: *mut u8) {
+    frames::release(virtual2physical(page as usize) as u64);
     ALLOCATOR.lock().free(page);
 }
 
+/// SAFETY: this must be called after `init_high_memory`
+///
+/// Allocates a zeroed 4K page for the user memory, and returns its physical address. It is
+/// taken above [`KERNEL_MAPPED_SIZE`] while there are pages there, so it's not mapped in the
+/// kernel, and can only be used through the page tables it's mapped into.
+/// It must be freed with [`free_physical`]
+pub unsafe fn alloc_user_zeroed() -> u64 {
+    let mut high = HIGH_MEMORY.lock();
+    let page = match high.alloc() {
+        Some(page) => {
+            high.map_window(page).write_bytes(0, PAGE_4K);
+            page as u64
+        }
+        None => {
+            drop(high);
+            virtual2physical(alloc_zeroed() as usize) as u64
+        }
+    };
+    frames::claim(page, frame_flags::USER);
+    page
+}
+
+/// SAFETY: this must be called after `init_high_memory`
+///
+/// Same as [`alloc_user_zeroed`] for a 2MB page, aligned to 2MB, `None` if there are not
+/// enough free pages in a row after [`KERNEL_MAPPED_SIZE`], it's never taken from below it.
+/// Its 4K pages are freed one by one with [`free_physical`], so part of it can be unmapped
+pub unsafe fn alloc_user_huge_zeroed() -> Option<u64> {
+    let mut high = HIGH_MEMORY.lock();
+    let start = high.alloc_contiguous(PAGE_2M / PAGE_4K, PAGE_2M, usize::MAX)?;
+    for page in (start..start + PAGE_2M).step_by(PAGE_4K) {
+        high.map_window(page).write_bytes(0, PAGE_4K);
+        frames::claim(page as u64, frame_flags::USER);
+    }
+    Some(start as u64)
+}
+
+/// SAFETY: this must be called after `init_high_memory`, and `source` must be a mapped page
+///
+/// Same as [`alloc_user_zeroed`], but the page is a copy of the 4K at `source`
+pub unsafe fn alloc_user_copy(source: *const u8) -> u64 {
+    let mut high = HIGH_MEMORY.lock();
+    let page = match high.alloc() {
+        Some(page) => {
+            high.map_window(page)
+                .copy_from_nonoverlapping(source, PAGE_4K);
+            page as u64
+        }
+        None => {
+            drop(high);
+            let page = alloc();
+            page.copy_from_nonoverlapping(source, PAGE_4K);
+            virtual2physical(page as usize) as u64
+        }
+    };
+    frames::claim(page, frame_flags::USER);
+    page
+}
+
+/// The page at the `physical` address is mapped once more, [`free_physical`] only frees it
+/// when it's called for all of them, see [`frames::get`]
+pub fn share_physical(physical: u64) {
+    frames::get(physical);
+}
+
+/// The number of times the page at `physical` is mapped, see [`share_physical`]
+pub fn physical_refs(physical: u64) -> usize {
+    frames::refs(physical)
+}
+
+/// Calls `f` with the bytes of the user page at `physical`, through the window if it's high
+///
+/// # Safety
+/// `physical` must be a page from [`alloc_user_zeroed`] or the others, not freed yet
+pub(super) unsafe fn with_user_page(physical: u64, f: impl FnOnce(&[u8])) {
+    let physical = physical as usize;
+    if physical < KERNEL_MAPPED_SIZE {
+        f(core::slice::from_raw_parts(
+            physical2virtual(physical) as *const u8,
+            PAGE_4K,
+        ));
+    } else {
+        let high = HIGH_MEMORY.lock();
+        f(core::slice::from_raw_parts(
+            high.map_window(physical),
+            PAGE_4K,
+        ));
+    }
+}
+
+/// SAFETY: this must be called after `init`
+///
+/// Frees the page at the `physical` address, from [`alloc_user_zeroed`] or one of the
+/// others, same as [`free`] for the pages below [`KERNEL_MAPPED_SIZE`].
+/// If it's shared, this only drops one of its [`physical_refs`]
+pub unsafe fn free_physical(physical: u64) {
+    if !frames::put(physical) {
+        return;
+    }
+    let physical = physical as usize;
+    if physical < KERNEL_MAPPED_SIZE {
+        free(physical2virtual(physical) as *mut u8);
+    } else {
+        HIGH_MEMORY.lock().free(physical);
+    }
+}
+
+/// SAFETY: this must be called after `init`, and nothing must be using the range anymore
+///
+/// Gives the pages of a range skipped by `init`, like a boot module, to the allocator.
+/// Only the pages fully inside the range are freed, returns the number of pages freed
+pub unsafe fn free_boot_range(physical_start: usize, physical_end: usize) -> usize {
+    let low = (*core::ptr::addr_of!(ALLOCATOR))
+        .lock()
+        .free_boot_range(physical_start, physical_end);
+    low + HIGH_MEMORY
+        .lock()
+        .free_boot_range(physical_start, physical_end)
+}
+
+/// Signals `event` when the available memory goes under the low-water mark, and now
+/// if its already under it, until all the files of the event are closed
+pub fn subscribe_low_memory(event: KernelEvent) {
+    let mut events = LOW_MEMORY_EVENTS.lock();
+    // the closed events are dropped here and not when signaling, since that can be during a
+    // heap allocation, and dropping them can free to the heap
+    events.retain(KernelEvent::is_open);
+    if is_below_low_water() {
+        event.signal();
+    }
+    events.push(event);
+    drop(events);
+    // pushing could have allocated under the mark while we held the events
+    signal_low_memory_if_pending();
+}
+
+/// Whether the available memory is under the low-water mark, see [`LOW_MEMORY_PERCENT`]
+pub fn is_below_low_water() -> bool {
+    unsafe { (*core::ptr::addr_of!(ALLOCATOR)).lock().below_low_water }
+}
+
+/// This is called on every allocation, so it must not allocate, and it gives up if the
+/// events are in use (by `subscribe_low_memory`), which will call it again when done
+fn signal_low_memory_if_pending() {
+    if !LOW_MEMORY_PENDING.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(events) = LOW_MEMORY_EVENTS.try_lock() else {
+        return;
+    };
+    if LOW_MEMORY_PENDING.swap(false, Ordering::AcqRel) {
+        for event in events.iter() {
+            event.signal();
+        }
+    }
+}
+
+/// SAFETY: this must be called after `init`
+///
+/// Allocates a 4K page below 1MB, for devices that can only address that, these pages are only
+/// given by [`alloc`] when the rest run out. Returns `None` if there is none left
+pub unsafe fn alloc_legacy() -> Option<*mut u8> {
+    let page = (*core::ptr::addr_of!(ALLOCATOR)).lock().try_alloc_legacy();
+    signal_low_memory_if_pending();
+    if let Some(page) = page {
+        frames::claim(virtual2physical(page as usize) as u64, 0);
+    }
+    page
+}
+
+/// Physically contiguous pages for devices, from [`alloc_contiguous`], freed with
+/// [`free_contiguous`]
+#[derive(Debug)]
+pub struct ContiguousPages {
+    /// Where the kernel reaches the pages, contiguous too
+    pub virtual_address: *mut u8,
+    /// What to give to the device
+    pub physical_address: u64,
+    pub count: usize,
+}
+
+impl ContiguousPages {
+    pub fn size(&self) -> usize {
+        self.count * PAGE_4K
+    }
+}
+
+/// SAFETY: this must be called after `init_high_memory`
+///
+/// Allocates `count` zeroed pages that are next to each other in physical memory, the first
+/// aligned to `align` bytes (a power of 2, at least 4K), and all of them before `below_addr`
+/// if given, like [`DMA_32BIT_LIMIT`]. They are taken after [`KERNEL_MAPPED_SIZE`] while they
+/// fit there, and mapped in the kernel space, otherwise from the direct map, the pages below
+/// 1MB last. Returns `None` if there are not enough free pages in a row, without reclaiming
+/// anything, so it's better done once, when the device is set up
+pub unsafe fn alloc_contiguous(
+    count: usize,
+    below_addr: Option<u64>,
+    align: usize,
+) -> Option<ContiguousPages> {
+    assert!(count > 0, "allocating no contiguous pages");
+    assert!(
+        align.is_power_of_two() && align >= PAGE_4K,
+        "bad alignment of contiguous pages: {align:#x}"
+    );
+    let below = below_addr.map_or(usize::MAX, |below| below as usize);
+    let size = count * PAGE_4K;
+
+    let high = HIGH_MEMORY.lock().alloc_contiguous(count, align, below);
+    let (virtual_address, physical) = match high {
+        Some(physical) => {
+            let virtual_address =
+                virtual_space::allocate_and_map_virtual_space(physical as u64, size as u64);
+            (virtual_address as *mut u8, physical)
+        }
+        None => {
+            // a bit for each page of the direct map, taken before the lock, since the heap
+            // could need a page for it
+            let mut free = vec![0; (KERNEL_MAPPED_SIZE / PAGE_4K).div_ceil(64)];
+            let physical = (*core::ptr::addr_of!(ALLOCATOR))
+                .lock()
+                .try_alloc_contiguous(count, align, below, &mut free);
+            signal_low_memory_if_pending();
+            let physical = physical?;
+            (physical2virtual(physical) as *mut u8, physical)
+        }
+    };
+    virtual_address.write_bytes(0, size);
+    for page in (physical..physical + size).step_by(PAGE_4K) {
+        frames::claim(page as u64, 0);
+    }
+    Some(ContiguousPages {
+        virtual_address,
+        physical_address: physical as u64,
+        count,
+    })
+}
+
+/// SAFETY: the pages must be from [`alloc_contiguous`], and the device must be done with them
+pub unsafe fn free_contiguous(pages: ContiguousPages) {
+    if pages.physical_address as usize >= KERNEL_MAPPED_SIZE {
+        virtual_space::deallocate_virtual_space(pages.virtual_address as u64, pages.size() as u64);
+    }
+    for page in (pages.physical_address..)
+        .step_by(PAGE_4K)
+        .take(pages.count)
+    {
+        free_physical(page);
+    }
+}
+
+/// The physical start of the kernel image, it moves with `kaslr`
+fn physical_kernel_start() -> usize {
+    virtual2physical(kernel_elf_start())
+}
+
+/// The physical end of the kernel image, the page after its last byte
+fn physical_kernel_end() -> usize {
+    virtual2physical(align_up(kernel_elf_end(), PAGE_4K))
+}
+
+/// Panics if the physical range overlaps the kernel image, or a legacy region that is not
+/// [`LegacyAccess::Allocatable`]
+fn assert_allocatable(start: usize, end: usize) {
+    let overlaps =
+        |region_start: usize, region_end: usize| region_start < end && start < region_end;
+    assert!(
+        !overlaps(physical_kernel_start(), physical_kernel_end()),
+        "physical range [{start:#x}, {end:#x}) overlaps the kernel"
+    );
+    if let Some(region) = LEGACY_REGIONS.iter().find(|region| {
+        region.access != LegacyAccess::Allocatable && overlaps(region.start, region.end)
+    }) {
+        panic!(
+            "physical range [{start:#x}, {end:#x}) overlaps the legacy region `{}`",
+            region.name
+        );
+    }
+}
+
 pub fn stats() -> (usize, usize) {
     let allocator = unsafe { ALLOCATOR.lock() };
     (allocator.free_count, allocator.used_count)
 }
 
+/// Same as [`stats`], for the pages above [`KERNEL_MAPPED_SIZE`]
+pub fn high_stats() -> (usize, usize) {
+    let high = HIGH_MEMORY.lock();
+    (high.free_count, high.used_count)
+}
+
+pub fn is_high_locked() -> bool {
+    HIGH_MEMORY.is_locked()
+}
+
 struct PhysicalPageAllocator {
-    low_mem_free_list_head: *mut FreePage,
-    #[allow(dead_code)]
-    // TODO: handle more memory
-    high_mem_start: *mut u8,
-    start: *mut u8,
-    end: *mut u8,
+    free_list_head: *mut FreePage,
+    // the pages of the conventional memory, below 1MB, which devices with a small DMA
+    // address space need, only used by the rest when `free_list_head` is empty
+    legacy_free_list_head: *mut FreePage,
+    // the virtual ranges of the pages we manage, see `add_range`
+    ranges: [(usize, usize); MAX_RANGES],
+    range_count: usize,
+    // the physical ranges after the direct map, given to `HighMemory` once there is a heap,
+    // without the `reserved` pages
+    high_ranges: [(usize, usize); MAX_RANGES],
+    high_range_count: usize,
+    reserved: [(usize, usize); MAX_BOOT_RESERVED],
+    reserved_count: usize,
     free_count: usize,
     used_count: usize,
+    low_water: usize,
+    // re-armed when going back to the mark, so each time under it is signaled once
+    below_low_water: bool,
 }
 
 impl PhysicalPageAllocator {
     const fn empty() -> Self {
         Self {
-            low_mem_free_list_head: core::ptr::null_mut(),
-            high_mem_start: core::ptr::null_mut(),
-            start: core::ptr::null_mut(),
-            end: core::ptr::null_mut(),
+            free_list_head: core::ptr::null_mut(),
+            legacy_free_list_head: core::ptr::null_mut(),
+            ranges: [(0, 0); MAX_RANGES],
+            range_count: 0,
+            high_ranges: [(0, 0); MAX_RANGES],
+            high_range_count: 0,
+            reserved: [(0, 0); MAX_BOOT_RESERVED],
+            reserved_count: 0,
             free_count: 0,
             used_count: 0,
+            low_water: 0,
+            below_low_water: false,
         }
     }
 
     fn init(&mut self, multiboot_info: &MultiBoot2Info) {
-        const PHYSICAL_KERNEL_START: usize = virtual2physical(KERNEL_LINK);
-        // get the end of the kernel, align, and add 5 PAGES of alignment as well
-        // because the multiboot info might be stored there by grub
-        let mut physical_kernel_end: usize = virtual2physical(align_up(kernel_elf_end(), PAGE_4K));
-        let multiboot_end = align_up(
-            virtual2physical(multiboot_info.end_address() as usize),
-            PAGE_4K,
-        );
-        println!("multiboot end: {multiboot_end:x}",);
         println!(
             "physical_kernel_start: {:p}",
-            PHYSICAL_KERNEL_START as *mut u8
+            physical_kernel_start() as *mut u8
+        );
+        println!(
+            "physical_kernel_end: {:p}",
+            physical_kernel_end() as *mut u8
         );
-        println!("physical_kernel_end: {:p}", physical_kernel_end as *mut u8);
 
-        // if the multiboot info is after the kernel, make sure we are not allocating it
-        if multiboot_end > physical_kernel_end {
-            // if its after the kernel by a lot, then panic, so we can handle it, we don't want
-            // to make this more complex if we don't need to
+        // the bootloader could put the modules anywhere, skip these pages, they can be
+        // released later with `free_boot_range`
+        for module in multiboot_info.modules() {
             assert!(
-                multiboot_end - physical_kernel_end < PAGE_4K * 5,
-                "Multiboot is after the kernel by a lot",
+                self.reserved_count < MAX_BOOT_RESERVED,
+                "Too many boot modules, max is {MAX_BOOT_RESERVED}"
+            );
+            println!(
+                "boot module: [{:#x}, {:#x}) {:?}",
+                module.start, module.end, module.cmdline
             );
-            physical_kernel_end = multiboot_end;
+            self.reserved[self.reserved_count] = (
+                align_down(module.start as usize, PAGE_4K),
+                align_up(module.end as usize, PAGE_4K),
+            );
+            self.reserved_count += 1;
         }
+        let reserved_copy = self.reserved;
+        let reserved = &reserved_copy[..self.reserved_count];
 
-        for memory in multiboot_info.memory_maps().unwrap() {
-            // skip all the memory before the kernel, it could be used by the bootloader
-            // its generally not a lot, just 1 MB, so its fine to skip it
-            if (memory.base_addr + memory.length) < EXTENDED_OFFSET as u64 {
-                continue;
-            }
-            if memory.mem_type != MemoryMapType::Available {
-                continue;
+        let mapped_end = virtual2physical(KERNEL_END);
+        for &(start, end) in boot_memory::find(multiboot_info).ranges() {
+            let start = align_up(start as usize, PAGE_4K);
+            let mut end = align_down(end as usize, PAGE_4K);
+            if end > mapped_end {
+                self.add_high_range(start.max(mapped_end), end);
+                end = mapped_end;
             }
-            // if this is the range where the kernel is mapped, skip the pages the kernel use
-            // and start after that
-            let start_physical;
-            let end_physical;
-            if memory.base_addr <= PHYSICAL_KERNEL_START as u64
-                && (memory.base_addr + memory.length) >= physical_kernel_end as u64
+
+            // the conventional memory, without the BIOS data and the pages we use there
+            for region in LEGACY_REGIONS
+                .iter()
+                .filter(|region| region.access == LegacyAccess::Allocatable)
             {
-                start_physical = physical_kernel_end as u64;
-                end_physical =
-                    align_down(memory.base_addr as usize + memory.length as usize, PAGE_4K) as u64;
-                self.start = physical2virtual(physical_kernel_end as _) as _;
-            } else {
-                assert!(memory.base_addr >= physical_kernel_end as u64);
-
-                start_physical = align_up(memory.base_addr as usize, PAGE_4K) as u64;
-                end_physical =
-                    align_down(memory.base_addr as usize + memory.length as usize, PAGE_4K) as u64;
+                self.add_range(start.max(region.start), end.min(region.end), reserved);
             }
-            let mut high_mem_start = core::ptr::null_mut();
-            let end_virtual = if end_physical >= virtual2physical(KERNEL_END) as _ {
-                high_mem_start = KERNEL_END as *mut u8;
-                KERNEL_END as *mut u8
-            } else {
-                physical2virtual(end_physical as _) as _
-            };
-            let start_virtual = physical2virtual(start_physical as _) as _;
+            // the extended memory, around the kernel, where it was loaded is free if it moved
+            let start = start.max(EXTENDED_OFFSET);
+            self.add_range(start, end.min(physical_kernel_start()), reserved);
+            self.add_range(start.max(physical_kernel_end()), end, reserved);
+        }
+        self.low_water = self.available() * LOW_MEMORY_PERCENT / 100;
+        println!(
+            "physical pages: {} free, {} of them below 1MB",
+            self.available(),
+            self.legacy_free()
+        );
+    }
 
-            if start_virtual < end_virtual {
-                self.end = end_virtual;
+    fn available(&self) -> usize {
+        self.free_count - self.used_count
+    }
 
-                self.init_range(start_virtual, end_virtual);
-                if !high_mem_start.is_null() {
-                    self.high_mem_start = high_mem_start;
-                    break;
-                }
+    /// The free pages below 1MB, this walks the list, so its only for the logs and tests
+    fn legacy_free(&self) -> usize {
+        let mut count = 0;
+        let mut page = self.legacy_free_list_head;
+        while !page.is_null() {
+            count += 1;
+            page = unsafe { (*page).next };
+        }
+        count
+    }
+
+    /// Whether the page is inside one of the ranges of the allocator
+    fn contains(&self, page: *const u8) -> bool {
+        let page = page as usize;
+        self.ranges[..self.range_count]
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&page))
+    }
+
+    /// Keeps the physical range after the direct map for [`init_high_memory`]
+    fn add_high_range(&mut self, start: usize, end: usize) {
+        if self.high_range_count == MAX_RANGES {
+            println!("WARNING: too many high memory ranges, skipping [{start:#x}, {end:#x})");
+            return;
+        }
+        println!("high physical pages: [{start:#x}, {end:#x})");
+        self.high_ranges[self.high_range_count] = (start, end);
+        self.high_range_count += 1;
+    }
+
+    /// Manages the pages of the physical range, and frees them, except the ones in the
+    /// physical `reserved` ranges. Does nothing if the range is empty
+    fn add_range(&mut self, start: usize, end: usize, reserved: &[(usize, usize)]) {
+        if start >= end {
+            return;
+        }
+        assert_allocatable(start, end);
+        if self.range_count == MAX_RANGES {
+            println!("WARNING: too many physical memory ranges, skipping [{start:#x}, {end:#x})");
+            return;
+        }
+        let (start, end) = (physical2virtual(start), physical2virtual(end));
+        self.ranges[self.range_count] = (start, end);
+        self.range_count += 1;
+
+        println!("init physical pages: [{:#x}, {:#x})", start, end);
+        for page in (start..end).step_by(PAGE_4K) {
+            let physical = virtual2physical(page);
+            if !reserved
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&physical))
+            {
+                unsafe { self.free(page as *mut u8) };
+            }
+        }
+    }
+
+    /// Free the pages fully inside the physical range that are managed by the allocator,
+    /// returns the number of pages freed
+    unsafe fn free_boot_range(&mut self, physical_start: usize, physical_end: usize) -> usize {
+        let start = align_up(physical_start, PAGE_4K);
+        let end = align_down(physical_end, PAGE_4K);
+        let mut freed = 0;
+        for physical in (start..end).step_by(PAGE_4K) {
+            if physical >= virtual2physical(KERNEL_END) {
+                break;
+            }
+            let page = physical2virtual(physical) as *mut u8;
+            if !self.contains(page) {
+                continue;
             }
+            self.free(page);
+            freed += 1;
         }
+        freed
     }
 
-    fn init_range(&mut self, start: *mut u8, end: *mut u8) {
-        println!("init physical pages: [{:p}, {:p})", start, end);
-        let start = align_up(start as usize, PAGE_4K) as _;
-        let end = align_down(end as usize, PAGE_4K) as _;
-        assert!(start < end);
-        let mut page = start;
-        while page < end {
-            unsafe { self.free(page) };
-            page = unsafe { page.add(PAGE_4K) };
+    unsafe fn try_alloc(&mut self) -> Option<*mut u8> {
+        if self.free_list_head.is_null() {
+            return self.try_alloc_legacy();
         }
+        let page = self.free_list_head;
+        self.free_list_head = (*page).next;
+        Some(self.take(page as *mut u8))
     }
 
     /// SAFETY: this must be called after `init`
     ///
-    /// Allocates a 4K page of memory
-    unsafe fn alloc(&mut self) -> *mut u8 {
-        if self.low_mem_free_list_head.is_null() {
-            panic!("out of memory");
+    /// Allocates a 4K page below 1MB
+    unsafe fn try_alloc_legacy(&mut self) -> Option<*mut u8> {
+        if self.legacy_free_list_head.is_null() {
+            return None;
         }
+        let page = self.legacy_free_list_head;
+        self.legacy_free_list_head = (*page).next;
+        Some(self.take(page as *mut u8))
+    }
 
-        let page = self.low_mem_free_list_head;
-        self.low_mem_free_list_head = (*page).next;
+    /// Takes `count` free pages in a row that end before the physical `below`, the first
+    /// aligned to `align`, returns the physical address of the first. The highest ones that
+    /// fit are taken, so the pages below 1MB are the last to go.
+    /// `free` is zeroed, with a bit for each page of the direct map, this can't allocate it
+    unsafe fn try_alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        below: usize,
+        free: &mut [u64],
+    ) -> Option<usize> {
+        for head in [self.free_list_head, self.legacy_free_list_head] {
+            let mut page = head;
+            while !page.is_null() {
+                let index = virtual2physical(page as usize) / PAGE_4K;
+                free[index / 64] |= 1 << (index % 64);
+                page = (*page).next;
+            }
+        }
+        let is_free = |index: usize| free[index / 64] & (1 << (index % 64)) != 0;
+        let end = below.min(KERNEL_MAPPED_SIZE) / PAGE_4K;
+        let step = align / PAGE_4K;
+        let first = (0..=end.checked_sub(count)? / step)
+            .rev()
+            .map(|i| i * step)
+            .find(|&first| (first..first + count).all(is_free))?;
 
-        let page = page as *mut u8;
+        let taken = first * PAGE_4K..(first + count) * PAGE_4K;
+        for head in [&mut self.free_list_head, &mut self.legacy_free_list_head] {
+            let mut link: *mut *mut FreePage = head;
+            while !(*link).is_null() {
+                let page = *link;
+                if taken.contains(&virtual2physical(page as usize)) {
+                    *link = (*page).next;
+                } else {
+                    link = &mut (*page).next;
+                }
+            }
+        }
+        for physical in taken.clone().step_by(PAGE_4K) {
+            self.take(physical2virtual(physical) as *mut u8);
+        }
+        Some(taken.start)
+    }
+
+    /// Accounts for `page`, just taken out of a free list
+    unsafe fn take(&mut self, page: *mut u8) -> *mut u8 {
+        if kasan::ENABLED {
+            // the link of the free list is the only part written while it's free
+            let link = core::mem::size_of::<FreePage>();
+            if let Some(offset) = kasan::find_unpoisoned(page.add(link), PAGE_4K - link, FREE_FILL)
+            {
+                panic!(
+                    "kasan: use after free, the free physical page {:#x} was written at offset {:#x}",
+                    virtual2physical(page as usize),
+                    link + offset
+                );
+            }
+        }
         // fill with random data to catch dangling pointer bugs
         page.write_bytes(1, PAGE_4K);
         self.used_count += 1;
+        if !self.below_low_water && self.available() < self.low_water {
+            self.below_low_water = true;
+            LOW_MEMORY_PENDING.store(true, Ordering::Release);
+        }
         page
     }
 
@@ -191,23 +714,365 @@ impl PhysicalPageAllocator {
     /// - `page` is not aligned to 4K
     unsafe fn free(&mut self, page: *mut u8) {
         // fill with random data to catch dangling pointer bugs
-        page.write_bytes(2, PAGE_4K);
+        page.write_bytes(FREE_FILL, PAGE_4K);
 
         let page = page as *mut FreePage;
 
         if page.is_null()
             || !is_aligned(page as _, PAGE_4K)
             || page > unsafe { page.add(1) }
-            || page >= self.end as _
-            || page < self.start as _
+            || !self.contains(page as _)
         {
             panic!("freeing invalid page: {:p}", page);
         }
-        // TODO: for now make sure we are not freeing the high memory for now
-        assert!(self.high_mem_start.is_null() || page < self.high_mem_start as _);
+        let list = if (page as usize) < physical2virtual(EXTENDED_OFFSET) {
+            &mut self.legacy_free_list_head
+        } else {
+            &mut self.free_list_head
+        };
+        (*page).next = *list;
+        *list = page;
+        self.free_count += 1;
+        if self.below_low_water && self.available() >= self.low_water {
+            self.below_low_water = false;
+        }
+    }
+}
+
+/// The pages after the direct map of the kernel, [`KERNEL_MAPPED_SIZE`], i.e. everything
+/// after the first 128MB. They are not mapped in the kernel, so the free ones are kept in a
+/// bitmap on the heap, a bit for each page, instead of a list inside the pages
+struct HighMemory {
+    ranges: Vec<HighRange>,
+    free_count: usize,
+    used_count: usize,
+    // a page of the kernel virtual space, mapped to the high page being written to
+    window: usize,
+}
+
+struct HighRange {
+    // physical
+    start: usize,
+    end: usize,
+    // a bit for each page, set if its free
+    free: Vec<u64>,
+    // the words before this one have no free pages
+    hint: usize,
+}
+
+impl HighMemory {
+    const fn empty() -> Self {
+        Self {
+            ranges: Vec::new(),
+            free_count: 0,
+            used_count: 0,
+            window: 0,
+        }
+    }
+
+    fn init(&mut self, ranges: &[(usize, usize)], reserved: &[(usize, usize)]) {
+        assert!(self.ranges.is_empty(), "high memory initialized twice");
+        for &(start, end) in ranges {
+            let pages = (end - start) / PAGE_4K;
+            let mut range = HighRange {
+                start,
+                end,
+                free: vec![0; pages.div_ceil(64)],
+                hint: 0,
+            };
+            for physical in (start..end).step_by(PAGE_4K) {
+                if !reserved
+                    .iter()
+                    .any(|&(start, end)| (start..end).contains(&physical))
+                {
+                    range.free(physical);
+                    self.free_count += 1;
+                }
+            }
+            self.ranges.push(range);
+        }
+        if self.ranges.is_empty() {
+            return;
+        }
+        self.window = virtual_space::reserve_virtual_space(PAGE_4K as u64) as usize;
+        // so the page tables of the window exist before the processes copy the kernel ones
+        self.map_window(self.ranges[0].start);
+        println!("high physical pages: {} free", self.available());
+    }
+
+    fn available(&self) -> usize {
+        self.free_count - self.used_count
+    }
 
-        (*page).next = self.low_mem_free_list_head;
-        self.low_mem_free_list_head = page;
+    fn alloc(&mut self) -> Option<usize> {
+        let page = self.ranges.iter_mut().find_map(HighRange::alloc)?;
+        self.used_count += 1;
+        Some(page)
+    }
+
+    /// `count` free pages in a row, the first aligned to `align` bytes, ending before the
+    /// physical `below`
+    fn alloc_contiguous(&mut self, count: usize, align: usize, below: usize) -> Option<usize> {
+        let start = self
+            .ranges
+            .iter_mut()
+            .find_map(|range| range.alloc_contiguous(count, align, below))?;
+        self.used_count += count;
+        Some(start)
+    }
+
+    /// panics if `physical` is not a page of the ranges, or is already free
+    fn free(&mut self, physical: usize) {
+        let range = self
+            .ranges
+            .iter_mut()
+            .find(|range| (range.start..range.end).contains(&physical));
+        match range {
+            Some(range) if is_aligned(physical, PAGE_4K) => range.free(physical),
+            _ => panic!("freeing invalid high page: {physical:#x}"),
+        }
         self.free_count += 1;
     }
+
+    /// Frees the pages fully inside the physical range that are in the ranges, these must
+    /// be `reserved` in `init`, returns the number of pages freed
+    fn free_boot_range(&mut self, physical_start: usize, physical_end: usize) -> usize {
+        let start = align_up(physical_start, PAGE_4K).max(KERNEL_MAPPED_SIZE);
+        let end = align_down(physical_end, PAGE_4K);
+        let mut freed = 0;
+        for physical in (start..end).step_by(PAGE_4K) {
+            if self
+                .ranges
+                .iter()
+                .any(|range| (range.start..range.end).contains(&physical))
+            {
+                self.free(physical);
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    /// Maps the window to the page at `physical`, returns the window
+    fn map_window(&self, physical: usize) -> *mut u8 {
+        // replacing the mapping drops the old translation
+        virtual_memory_mapper::map_kernel(&VirtualMemoryMapEntry {
+            virtual_address: self.window as u64,
+            physical_address: Some(physical as u64),
+            size: PAGE_4K as u64,
+            flags: virtual_memory_mapper::flags::PTE_WRITABLE
+                | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+        });
+        self.window as *mut u8
+    }
+}
+
+impl HighRange {
+    fn alloc(&mut self) -> Option<usize> {
+        let (index, word) = self
+            .free
+            .iter_mut()
+            .enumerate()
+            .skip(self.hint)
+            .find(|(_, word)| **word != 0)?;
+        let bit = word.trailing_zeros() as usize;
+        *word &= !(1 << bit);
+        self.hint = index;
+        Some(self.start + (index * 64 + bit) * PAGE_4K)
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align: usize, below: usize) -> Option<usize> {
+        let pages = self.end.min(below).saturating_sub(self.start) / PAGE_4K;
+        let first = (align_up(self.start, align) - self.start) / PAGE_4K;
+        let is_free = |page: usize| self.free[page / 64] & (1 << (page % 64)) != 0;
+        let found = (first..pages.saturating_sub(count - 1))
+            .step_by(align / PAGE_4K)
+            .find(|&start| (start..start + count).all(is_free))?;
+        for page in found..found + count {
+            self.free[page / 64] &= !(1 << (page % 64));
+        }
+        Some(self.start + found * PAGE_4K)
+    }
+
+    fn free(&mut self, physical: usize) {
+        let page = (physical - self.start) / PAGE_4K;
+        let (index, bit) = (page / 64, page % 64);
+        assert!(
+            self.free[index] & (1 << bit) == 0,
+            "freeing free high page: {physical:#x}"
+        );
+        self.free[index] |= 1 << bit;
+        self.hint = self.hint.min(index);
+    }
+}
+
+/// The ranges are only allocatable memory, the pages below 1MB are only given when asked
+/// for, or when nothing else is left, and they can be written to, and the user pages come
+/// from after the direct map while there are some there
+pub fn run_self_tests() {
+    println!("Running physical page allocator self tests...");
+    let (ranges, range_count) = {
+        let allocator = unsafe { (*core::ptr::addr_of!(ALLOCATOR)).lock() };
+        (allocator.ranges, allocator.range_count)
+    };
+    assert!(range_count > 0);
+    for &(start, end) in &ranges[..range_count] {
+        assert_allocatable(virtual2physical(start), virtual2physical(end));
+    }
+    for (i, &(start, end)) in ranges[..range_count].iter().enumerate() {
+        assert!(start < end && is_aligned(start, PAGE_4K) && is_aligned(end, PAGE_4K));
+        assert!(
+            ranges[..i]
+                .iter()
+                .all(|&(other_start, other_end)| end <= other_start || other_end <= start),
+            "physical page self test: range [{start:#x}, {end:#x}) overlaps another"
+        );
+    }
+
+    let legacy_end = physical2virtual(EXTENDED_OFFSET) as *mut u8;
+    unsafe {
+        let page = alloc();
+        assert!(
+            page >= legacy_end,
+            "a page below 1MB was given before the rest ran out"
+        );
+        free(page);
+        match alloc_legacy() {
+            Some(page) => {
+                assert!(page < legacy_end);
+                page.write_bytes(0xAB, PAGE_4K);
+                free(page);
+            }
+            None => {
+                println!("physical page self test: no free page below 1MB");
+            }
+        }
+    }
+    selftest_high_memory();
+    selftest_shared_pages();
+    selftest_contiguous();
+    println!("Physical page allocator self tests passed");
+}
+
+/// The user pages are above the direct map, zeroed, and given back, the lowest free one
+/// is always taken first
+fn selftest_high_memory() {
+    let (free_before, used_before) = high_stats();
+    if free_before == used_before {
+        println!("physical page self test: no memory after the first 128MB");
+        return;
+    }
+    unsafe {
+        let first = alloc_user_zeroed();
+        let second = alloc_user_zeroed();
+        assert!(first as usize >= KERNEL_MAPPED_SIZE && second as usize >= KERNEL_MAPPED_SIZE);
+        assert!(first != second && is_aligned(first as usize, PAGE_4K));
+        assert_eq!(high_stats().1, used_before + 2);
+
+        let high = HIGH_MEMORY.lock();
+        let window = high.map_window(first as usize);
+        assert!(core::slice::from_raw_parts(window, PAGE_4K)
+            .iter()
+            .all(|&byte| byte == 0));
+        window.write_bytes(0xAB, PAGE_4K);
+        drop(high);
+
+        free_physical(first);
+        free_physical(second);
+        // the lowest free page is taken first, so its the same one, zeroed again
+        assert_eq!(alloc_user_zeroed(), first);
+        let high = HIGH_MEMORY.lock();
+        let window = high.map_window(first as usize);
+        assert!(core::slice::from_raw_parts(window, PAGE_4K)
+            .iter()
+            .all(|&byte| byte == 0));
+        drop(high);
+        free_physical(first);
+    }
+    let (free_after, used_after) = high_stats();
+    assert_eq!(free_after - used_after, free_before - used_before);
+}
+
+/// A shared page is only freed with its last reference, which its frame follows, and a copy
+/// has the same bytes
+fn selftest_shared_pages() {
+    let stats_before = (stats(), high_stats());
+    unsafe {
+        let source = alloc();
+        for (i, byte) in core::slice::from_raw_parts_mut(source, PAGE_4K)
+            .iter_mut()
+            .enumerate()
+        {
+            *byte = i as u8;
+        }
+        let copy = alloc_user_copy(source);
+        free(source);
+        with_user_page(copy, |bytes| {
+            assert!(bytes.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+        });
+
+        let claimed = frames::Frame {
+            refs: 1,
+            flags: frame_flags::USER,
+        };
+        assert_eq!(frames::frame(copy), Some(claimed));
+        share_physical(copy);
+        share_physical(copy);
+        assert_eq!(physical_refs(copy), 3);
+        let freed = (stats().0, high_stats().0);
+        free_physical(copy);
+        free_physical(copy);
+        assert_eq!(physical_refs(copy), 1);
+        assert_eq!(
+            (stats().0, high_stats().0),
+            freed,
+            "a shared page was freed early"
+        );
+        free_physical(copy);
+        assert_eq!(frames::frame(copy), Some(frames::Frame::default()));
+    }
+    let (low, high) = (stats(), high_stats());
+    assert_eq!(low.0 - low.1, stats_before.0 .0 - stats_before.0 .1);
+    assert_eq!(high.0 - high.1, stats_before.1 .0 - stats_before.1 .1);
+}
+
+/// The contiguous pages are where they were asked for, zeroed, and only taken from the direct
+/// map when they must be below it
+fn selftest_contiguous() {
+    let check = |pages: &ContiguousPages, align: usize, below: u64| {
+        let physical = pages.physical_address;
+        assert!(is_aligned(physical as usize, align));
+        assert!(physical + pages.size() as u64 <= below);
+        // SAFETY: just allocated, and mapped for the kernel
+        let bytes = unsafe { core::slice::from_raw_parts_mut(pages.virtual_address, pages.size()) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+        bytes.fill(0xAB);
+        for page in (physical..).step_by(PAGE_4K).take(pages.count) {
+            assert_eq!(frames::refs(page), 1);
+        }
+    };
+    unsafe {
+        let pages = alloc_contiguous(4, Some(DMA_32BIT_LIMIT), 4 * PAGE_4K)
+            .expect("no 4 contiguous pages below 4GB");
+        check(&pages, 4 * PAGE_4K, DMA_32BIT_LIMIT);
+        let physical = pages.physical_address;
+        free_contiguous(pages);
+        assert_eq!(frames::frame(physical), Some(frames::Frame::default()));
+
+        match alloc_contiguous(3, Some(KERNEL_MAPPED_SIZE as u64), PAGE_4K) {
+            Some(pages) => {
+                check(&pages, PAGE_4K, KERNEL_MAPPED_SIZE as u64);
+                assert_eq!(
+                    pages.virtual_address as usize,
+                    physical2virtual(pages.physical_address as usize)
+                );
+                free_contiguous(pages);
+            }
+            None => {
+                println!("physical page self test: no 3 contiguous pages in the direct map");
+            }
+        }
+        // the first page is never free
+        assert!(alloc_contiguous(1, Some(PAGE_4K as u64), PAGE_4K).is_none());
+    }
 }
@@ -0,0 +1,314 @@
+//! Taking memory back from the caches when the physical pages run out.
+//!
+//! Anything keeping pages it can drop registers a [`Shrinker`] with a priority, the lower ones
+//! are asked first, as they are the cheapest to get back. When an allocation fails, the shrinkers
+//! are asked in order for [`ALLOCATION_BATCH`] pages, and the allocation is tried again after
+//! each round, it only fails when none of them could free anything.
+//!
+//! Every second (see [`background_tick`]), if the memory stayed under the low-water mark of
+//! the allocator for [`BACKGROUND_DELAY_SECONDS`], a smaller round is done, so the allocations
+//! rarely have to wait for it.
+//!
+//! The shrinkers run inside allocations, which can be heap allocations, or in an interrupt,
+//! so they must not allocate, and should give up instead of waiting for a lock, unless
+//! [`holds_no_lock`].
+//!
+//! `/devices/reclaim` shows the shrinkers, with how many times they ran and the pages they
+//! freed, and the rounds of each kind.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{string::String, sync::Arc};
+
+use crate::{
+    cpu,
+    devices::{self, Device},
+    fs::FileSystemError,
+    sync::spin::mutex::Mutex,
+};
+
+use super::{memory_layout::PAGE_4K, physical_page_allocator};
+
+/// The most shrinkers that can be registered, they are kept without the heap
+const MAX_SHRINKERS: usize = 8;
+
+/// The pages asked for in a round when an allocation fails
+pub const ALLOCATION_BATCH: usize = 32;
+/// The pages asked for in a background round
+pub const BACKGROUND_BATCH: usize = 64;
+/// The seconds under the low-water mark before the background rounds start
+pub const BACKGROUND_DELAY_SECONDS: u64 = 2;
+
+/// The priorities of the shrinkers, lower ones are asked first
+pub mod priority {
+    pub const PAGE_CACHE: u8 = 10;
+    pub const BLOCK_CACHE: u8 = 20;
+    /// The pages of the processes, they have to be written to get them back
+    pub const SWAP: u8 = 30;
+}
+
+pub trait Shrinker: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Frees up to `pages` pages, returns how many were freed
+    fn shrink(&self, pages: usize) -> usize;
+}
+
+#[derive(Clone, Copy)]
+struct Registered {
+    priority: u8,
+    shrinker: &'static dyn Shrinker,
+    runs: u64,
+    freed: u64,
+}
+
+struct Registry {
+    // sorted by priority
+    shrinkers: [Option<Registered>; MAX_SHRINKERS],
+    len: usize,
+}
+
+static SHRINKERS: Mutex<Registry> = Mutex::new(Registry {
+    shrinkers: [None; MAX_SHRINKERS],
+    len: 0,
+});
+
+static ALLOCATION_ROUNDS: AtomicU64 = AtomicU64::new(0);
+static BACKGROUND_ROUNDS: AtomicU64 = AtomicU64::new(0);
+// the allocations that failed after all the shrinkers were exhausted
+static EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+// seconds in a row spent under the low-water mark
+static SECONDS_UNDER_LOW_WATER: AtomicU64 = AtomicU64::new(0);
+
+/// Adds `shrinker` to be asked for pages after the ones of lower `priority`, and after the
+/// ones already registered with the same
+pub fn register_shrinker(priority: u8, shrinker: &'static dyn Shrinker) {
+    let mut registry = SHRINKERS.lock();
+    let len = registry.len;
+    assert!(
+        len < MAX_SHRINKERS,
+        "too many shrinkers, max is {MAX_SHRINKERS}"
+    );
+    let index = registry.shrinkers[..len]
+        .iter()
+        .position(|entry| entry.is_some_and(|entry| entry.priority > priority))
+        .unwrap_or(len);
+    registry.shrinkers[index..=len].rotate_right(1);
+    registry.shrinkers[index] = Some(Registered {
+        priority,
+        shrinker,
+        runs: 0,
+        freed: 0,
+    });
+    registry.len += 1;
+}
+
+/// Asks the shrinkers in order until `pages` pages are freed, returns the pages freed,
+/// `0` if there is nothing left to free, or if a reclaim is already running
+pub fn reclaim(pages: usize) -> usize {
+    let Some(mut registry) = SHRINKERS.try_lock() else {
+        return 0;
+    };
+    let len = registry.len;
+    let mut freed = 0;
+    for entry in registry.shrinkers[..len].iter_mut().flatten() {
+        if freed >= pages {
+            break;
+        }
+        let shrinker_freed = entry.shrinker.shrink(pages - freed);
+        entry.runs += 1;
+        entry.freed += shrinker_freed as u64;
+        freed += shrinker_freed;
+    }
+    freed
+}
+
+/// Inside [`Shrinker::shrink`], `true` if only the lock of the shrinkers is held, whoever
+/// needed the pages holds none, so the shrinker can wait for a lock, or for a device
+pub fn holds_no_lock() -> bool {
+    cpu::cpu().n_cli() == 1
+}
+
+/// Called by the allocator when it has no free page, returns `false` when all the shrinkers
+/// are exhausted, otherwise the allocation should be tried again
+pub(super) fn reclaim_for_allocation() -> bool {
+    ALLOCATION_ROUNDS.fetch_add(1, Ordering::Relaxed);
+    let freed = reclaim(ALLOCATION_BATCH);
+    if freed == 0 {
+        EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+    }
+    freed != 0
+}
+
+/// Called every second, runs a background round if the memory stayed low
+pub fn background_tick() {
+    if !physical_page_allocator::is_below_low_water() {
+        SECONDS_UNDER_LOW_WATER.store(0, Ordering::Relaxed);
+        return;
+    }
+    let seconds = SECONDS_UNDER_LOW_WATER.fetch_add(1, Ordering::Relaxed) + 1;
+    if seconds >= BACKGROUND_DELAY_SECONDS {
+        BACKGROUND_ROUNDS.fetch_add(1, Ordering::Relaxed);
+        reclaim(BACKGROUND_BATCH);
+    }
+}
+
+/// The pages freed by the shrinker with `name` so far
+pub fn freed_by(name: &str) -> u64 {
+    let registry = SHRINKERS.lock();
+    registry.shrinkers[..registry.len]
+        .iter()
+        .flatten()
+        .filter(|entry| entry.shrinker.name() == name)
+        .map(|entry| entry.freed)
+        .sum()
+}
+
+fn render() -> String {
+    use core::fmt::Write;
+
+    // copied, so we don't format (and allocate) while holding it
+    let (shrinkers, len) = {
+        let registry = SHRINKERS.lock();
+        (registry.shrinkers, registry.len)
+    };
+    let mut out = String::new();
+    for entry in shrinkers[..len].iter().flatten() {
+        let _ = writeln!(
+            out,
+            "{} priority {}: {} runs, {} pages freed",
+            entry.shrinker.name(),
+            entry.priority,
+            entry.runs,
+            entry.freed
+        );
+    }
+    let _ = writeln!(
+        out,
+        "allocation rounds: {}\nbackground rounds: {}\nexhausted: {}",
+        ALLOCATION_ROUNDS.load(Ordering::Relaxed),
+        BACKGROUND_ROUNDS.load(Ordering::Relaxed),
+        EXHAUSTED.load(Ordering::Relaxed)
+    );
+    out
+}
+
+/// `/devices/reclaim`, the shrinkers and what they freed
+#[derive(Debug)]
+struct ReclaimInfo;
+
+impl Device for ReclaimInfo {
+    fn name(&self) -> &str {
+        "reclaim"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        Ok(devices::read_bytes(render().as_bytes(), offset, buf))
+    }
+}
+
+pub fn init() {
+    devices::register_device(Arc::new(ReclaimInfo));
+}
+
+/// A list of pages linked through their first bytes, to hold many pages without the heap
+struct PageList {
+    head: *mut u8,
+    len: usize,
+}
+
+impl PageList {
+    fn new() -> Self {
+        Self {
+            head: core::ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// # Safety
+    /// `page` must be an allocated page not used anywhere else
+    unsafe fn push(&mut self, page: *mut u8) {
+        (page as *mut *mut u8).write(self.head);
+        self.head = page;
+        self.len += 1;
+    }
+
+    fn free_all(&mut self) {
+        while !self.head.is_null() {
+            let page = self.head;
+            // SAFETY: the pages were pushed with `push`
+            unsafe {
+                self.head = (page as *mut *mut u8).read();
+                physical_page_allocator::free(page);
+            }
+        }
+        self.len = 0;
+    }
+}
+
+/// The largest file in the root, it must go through the page cache to be useful
+fn selftest_largest_root_file() -> Option<String> {
+    let files = crate::fs::ls_dir("/").ok()?;
+    let file = files
+        .iter()
+        .filter(|inode| !inode.is_dir())
+        .max_by_key(|inode| inode.size())?;
+    Some(alloc::format!("/{}", file.name()))
+}
+
+/// Fills the page cache with the largest file of the root, takes all the free pages, then
+/// allocates a region as large as half the cache, which only works if the allocation took
+/// the pages from the cache
+pub fn run_self_tests() {
+    println!("Running memory reclaim self tests...");
+    let Some(path) = selftest_largest_root_file() else {
+        println!("memory reclaim self test: no file in the root, skipping");
+        return;
+    };
+    let mut file = crate::fs::open(&path).unwrap();
+    let mut buf = [0u8; 512];
+    while file.read(&mut buf).unwrap() != 0 {}
+    drop(file);
+
+    let cached = crate::fs::page_cache::cached_pages();
+    if cached < 2 {
+        println!("memory reclaim self test: {path} is not cached, skipping");
+        return;
+    }
+    let freed_before = freed_by("page_cache");
+    let rounds_before = ALLOCATION_ROUNDS.load(Ordering::Relaxed);
+
+    let mut taken = PageList::new();
+    let mut region = PageList::new();
+    // SAFETY: the pages are only kept in the lists, and freed at the end
+    unsafe {
+        while let Some(page) = physical_page_allocator::alloc_without_reclaim() {
+            taken.push(page);
+        }
+        for _ in 0..cached / 2 {
+            let page = physical_page_allocator::alloc();
+            page.write_bytes(0xAB, PAGE_4K);
+            region.push(page);
+        }
+    }
+    let rounds = ALLOCATION_ROUNDS.load(Ordering::Relaxed) - rounds_before;
+    let freed = freed_by("page_cache") - freed_before;
+    let cached_after = crate::fs::page_cache::cached_pages();
+    let (taken_len, region_len) = (taken.len, region.len);
+    region.free_all();
+    taken.free_all();
+
+    assert_eq!(region_len, cached / 2);
+    assert!(rounds > 0, "the allocations never ran out of memory");
+    assert!(
+        freed as usize >= region_len,
+        "only {freed} pages were reclaimed for {region_len}"
+    );
+    assert!(cached_after + region_len <= cached);
+    let info = render();
+    assert!(info.contains("page_cache priority"), "{info}");
+    println!(
+        "Memory reclaim self tests passed ({taken_len} free pages taken, {region_len} pages \
+         allocated from {cached} cached, {freed} reclaimed in {rounds} rounds)"
+    );
+}
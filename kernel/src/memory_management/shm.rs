@@ -0,0 +1,222 @@
+//! Shared memory objects, `SYS_SHM_CREATE` gives a file for one, and `SYS_SHM_MAP` maps all
+//! of its pages into the process, the same pages in every process that maps it.
+//!
+//! An object is reached through its files, which `fork` and `spawn` pass like any other, and
+//! through its name if it has one, until the last of its files is closed. Each mapping takes a
+//! reference of the [`frames`](super::frames) of the pages, so the pages stay after the object
+//! is gone, until the last process unmaps them.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use kernel_user_link::{
+    file::BlockingMode,
+    process::{SHM_CREATE, SHM_EXCLUSIVE, SHM_MAX_SIZE, SHM_NAME_LEN},
+};
+
+use super::{
+    frames,
+    memory_layout::{align_up, PAGE_4K},
+    physical_page_allocator,
+};
+use crate::{
+    devices::Device,
+    fs::{self, FileAttributes, INode},
+    sync::spin::mutex::Mutex,
+};
+
+/// The objects with a name, only while they have files
+static NAMED: Mutex<BTreeMap<String, Weak<SharedMemory>>> = Mutex::new(BTreeMap::new());
+// the files of an object and their clones share the same inode id
+static NEXT_SHM_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    InvalidName,
+    InvalidSize,
+    InvalidFlags,
+    NotFound,
+    AlreadyExists,
+    NoMemory,
+}
+
+#[derive(Debug)]
+pub struct SharedMemory {
+    name: Option<String>,
+    // physical, each holds a reference for the object
+    pages: Vec<u64>,
+}
+
+impl SharedMemory {
+    fn new(name: Option<String>, size: u64) -> Result<Self, ShmError> {
+        if size == 0 || size > SHM_MAX_SIZE {
+            return Err(ShmError::InvalidSize);
+        }
+        let count = align_up(size as usize, PAGE_4K) / PAGE_4K;
+        let (low, high) = (
+            physical_page_allocator::stats(),
+            physical_page_allocator::high_stats(),
+        );
+        if count > (low.0 - low.1) + (high.0 - high.1) {
+            return Err(ShmError::NoMemory);
+        }
+        let pages = (0..count)
+            .map(|_| {
+                // SAFETY: the processes run after the high memory is initialized
+                let page = unsafe { physical_page_allocator::alloc_user_zeroed() };
+                frames::set_flags(page, frames::flags::SHARED_MEMORY);
+                page
+            })
+            .collect();
+        Ok(Self { name, pages })
+    }
+
+    /// The size in bytes, in whole pages
+    pub fn size(&self) -> u64 {
+        (self.pages.len() * PAGE_4K) as u64
+    }
+
+    /// The physical addresses of the pages, in order
+    pub fn pages(&self) -> &[u64] {
+        &self.pages
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        if let Some(name) = &self.name {
+            let mut named = NAMED.lock();
+            // the name could have been taken by a new object already
+            if named.get(name).is_some_and(|shm| shm.strong_count() == 0) {
+                named.remove(name);
+            }
+        }
+        for &page in &self.pages {
+            // SAFETY: only drops the reference of the object, the mappings have their own
+            unsafe { physical_page_allocator::free_physical(page) };
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= SHM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && c != '/')
+}
+
+/// Opens the object named `name`, or creates it with `size` bytes if `flags` has
+/// [`SHM_CREATE`], see `SYS_SHM_CREATE`. Without a name, a new object is always created
+pub fn open_or_create(
+    name: Option<&str>,
+    size: u64,
+    flags: u64,
+) -> Result<Arc<SharedMemory>, ShmError> {
+    if flags & !(SHM_CREATE | SHM_EXCLUSIVE) != 0 {
+        return Err(ShmError::InvalidFlags);
+    }
+    let Some(name) = name else {
+        return SharedMemory::new(None, size).map(Arc::new);
+    };
+    if !is_valid_name(name) {
+        return Err(ShmError::InvalidName);
+    }
+
+    let mut named = NAMED.lock();
+    let existing = named.get(name).and_then(Weak::upgrade);
+    let result = match existing {
+        Some(_) if flags & (SHM_CREATE | SHM_EXCLUSIVE) == SHM_CREATE | SHM_EXCLUSIVE => {
+            Err(ShmError::AlreadyExists)
+        }
+        Some(shm) => Ok(shm),
+        None if flags & SHM_CREATE == 0 => Err(ShmError::NotFound),
+        None => SharedMemory::new(Some(String::from(name)), size).map(|shm| {
+            let shm = Arc::new(shm);
+            named.insert(String::from(name), Arc::downgrade(&shm));
+            shm
+        }),
+    };
+    // the object we upgraded could be dropped with the result, which takes the lock
+    drop(named);
+    result
+}
+
+#[derive(Debug)]
+struct SharedMemoryDevice {
+    shm: Arc<SharedMemory>,
+}
+
+impl Device for SharedMemoryDevice {
+    fn name(&self) -> &str {
+        "shm"
+    }
+
+    fn as_shared_memory(&self) -> Option<&Arc<SharedMemory>> {
+        Some(&self.shm)
+    }
+}
+
+/// A file for `shm`, its size is the size of the object, it can't be read or written, only
+/// mapped
+pub fn create_file(shm: Arc<SharedMemory>) -> fs::File {
+    let size = shm.size() as u32;
+    let name = shm.name.clone().unwrap_or_else(|| String::from("shm"));
+    let inode = INode::new_device(
+        name,
+        FileAttributes::EMPTY,
+        Some(Arc::new(SharedMemoryDevice { shm })),
+    )
+    .with_id(NEXT_SHM_ID.fetch_add(1, Ordering::Relaxed))
+    .with_size(size);
+    fs::inode_to_file(inode, fs::empty_filesystem(), 0, BlockingMode::None)
+}
+
+/// The names while the objects are alive, the pages with the last reference
+pub fn run_self_tests() {
+    const NAME: &str = "selftest_shm";
+
+    println!("Running shared memory self tests...");
+    let shm = open_or_create(Some(NAME), PAGE_4K as u64 + 1, SHM_CREATE).unwrap();
+    assert_eq!(shm.size(), 2 * PAGE_4K as u64);
+    let frame = frames::frame(shm.pages()[0]).unwrap();
+    assert_eq!(frame.refs, 1);
+    assert!(frame.flags & frames::flags::SHARED_MEMORY != 0);
+
+    let opened = open_or_create(Some(NAME), 0, 0).unwrap();
+    assert!(Arc::ptr_eq(&shm, &opened));
+    assert_eq!(
+        open_or_create(Some(NAME), PAGE_4K as u64, SHM_CREATE | SHM_EXCLUSIVE).unwrap_err(),
+        ShmError::AlreadyExists
+    );
+    let anonymous = open_or_create(None, PAGE_4K as u64, 0).unwrap();
+    assert!(!Arc::ptr_eq(&shm, &anonymous));
+    for (name, size, error) in [
+        (Some("a/b"), 1, ShmError::InvalidName),
+        (Some(""), 1, ShmError::InvalidName),
+        (None, 0, ShmError::InvalidSize),
+        (None, SHM_MAX_SIZE + 1, ShmError::InvalidSize),
+    ] {
+        assert_eq!(open_or_create(name, size, SHM_CREATE).unwrap_err(), error);
+    }
+
+    // a mapping keeps the page after the object is gone
+    let [first, second] = [shm.pages()[0], shm.pages()[1]];
+    physical_page_allocator::share_physical(second);
+    drop((shm, opened, anonymous));
+    assert_eq!(
+        open_or_create(Some(NAME), 0, 0).unwrap_err(),
+        ShmError::NotFound
+    );
+    let refs = |page| frames::frame(page).unwrap().refs;
+    assert_eq!((refs(first), refs(second)), (0, 1));
+    // SAFETY: the reference we took above
+    unsafe { physical_page_allocator::free_physical(second) };
+    assert_eq!(refs(second), 0);
+    println!("Shared memory self tests passed");
+}
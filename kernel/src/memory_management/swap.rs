@@ -0,0 +1,602 @@
+//! Moving the pages of the processes to a swap partition when the physical memory runs out.
+//!
+//! The swap is a partition of type [`PARTITION_TYPE`] in the MBR of a block device, given by
+//! `swap=<device>` in the cmdline, or by writing `on <device>` to `/devices/swap` (`off` only
+//! works when nothing is in it). It's split into slots of a page.
+//!
+//! It's the last [`Shrinker`] asked (see [`reclaim::priority::SWAP`]), so the clean pages of
+//! the caches go first. It moves the private pages of the processes that were not used since
+//! its last round (see [`VirtualMemoryMapper::pick_swap_out`]), their entries keep the slot, and
+//! the page is read back on its first access, in the page fault or before the kernel accesses
+//! it (see [`swap_in`]). A fork shares the slots, each process gets its own copy when it's read
+//! back.
+//!
+//! Writing to the device doesn't allocate (see [`BlockDeviceFile::transfer_in_place`]), but
+//! the driver takes its locks, so the shrinker only runs when nothing else is locked (see
+//! [`reclaim::holds_no_lock`]), mostly in the background rounds, the allocations of the
+//! processes are done with the scheduler locked.
+//!
+//! The transfers are done without the scheduler lock and the lock of the swap, with the
+//! interrupts on. The pages are picked with the scheduler locked, and their process doesn't
+//! run until they are written (see [`Process::pick_swap_out`]), then they are replaced by their
+//! slots. A page read back has its entry marked while it's read, the others that fault on it
+//! wait (see [`VirtualMemoryMapper::start_swap_in`]).
+//!
+//! `/devices/swap` shows the partition, the slots used, and the pages moved each way.
+
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use kernel_core::sector::Lba;
+
+use super::{
+    memory_layout::{align_down, align_up, PAGE_4K},
+    physical_page_allocator,
+    reclaim::{self, Shrinker},
+    virtual_memory_mapper::{
+        self, SwapIn, VirtualMemoryMapEntry, VirtualMemoryMapper, MAX_SWAP_SLOTS,
+    },
+};
+use crate::{
+    cpu,
+    devices::{
+        self,
+        block::{self, BlockDevice, BlockDeviceFile, BlockRequestKind},
+        ramdisk::RamDisk,
+        Device,
+    },
+    fs::{
+        mbr::{MbrRaw, PartitionEntry},
+        FileSystemError,
+    },
+    process::{scheduler, Process},
+    sync::spin::mutex::Mutex,
+};
+
+/// The partition type of the Linux swap, only the type is used, not its format
+pub const PARTITION_TYPE: u8 = 0x82;
+/// The most pages the shrinker picks from a process at a time, they are kept on its stack
+const SWAP_OUT_BATCH: usize = 16;
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+static SWAPPED_OUT: AtomicU64 = AtomicU64::new(0);
+static SWAPPED_IN: AtomicU64 = AtomicU64::new(0);
+// the transfers that failed, the pages stay where they were
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+struct SwapArea {
+    device: Arc<BlockDeviceFile>,
+    // the index in the partition table, for `/devices/swap`
+    partition: usize,
+    first_sector: Lba,
+    sectors_per_slot: u64,
+    // the entries of the vms pointing to each slot, `0` if it's free
+    refs: Vec<u16>,
+    used: usize,
+    // where to look for a free slot first
+    hint: usize,
+    // a page, given to the driver and back, so nothing is allocated to move a page, empty
+    // while a page is written
+    buffer: Vec<u8>,
+}
+
+impl SwapArea {
+    /// The first swap partition of `device`, with a slot for each page that fits in it
+    fn new(device: Arc<BlockDeviceFile>) -> Result<Self, FileSystemError> {
+        if !device.is_writable() {
+            return Err(FileSystemError::ReadOnlyFileSystem);
+        }
+        let sector_size = device.sector_size() as usize;
+        if !PAGE_4K.is_multiple_of(sector_size) {
+            return Err(FileSystemError::UnalignedAccess);
+        }
+        let mut sectors = vec![0; align_up(mem::size_of::<MbrRaw>(), sector_size)];
+        device.read_direct(0, &mut sectors)?;
+        // SAFETY: This is a valid allocated memory
+        let mbr = unsafe { &*(sectors.as_ptr() as *const MbrRaw) };
+        if !mbr.is_valid() {
+            return Err(FileSystemError::PartitionTableNotFound);
+        }
+        let (partition, entry) = mbr
+            .partition_table
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| entry.partition_type == PARTITION_TYPE && entry.size_in_sectors != 0)
+            .ok_or(FileSystemError::PartitionTableNotFound)?;
+        let first_sector = entry.first_sector();
+        let size_in_sectors = entry.size_in_sectors as u64;
+        if first_sector.0 + size_in_sectors > device.number_of_sectors() {
+            return Err(FileSystemError::InvalidData);
+        }
+        let sectors_per_slot = (PAGE_4K / sector_size) as u64;
+        let slots = (size_in_sectors / sectors_per_slot).min(MAX_SWAP_SLOTS) as usize;
+        if slots == 0 {
+            return Err(FileSystemError::NoSpace);
+        }
+        Ok(Self {
+            device,
+            partition,
+            first_sector,
+            sectors_per_slot,
+            refs: vec![0; slots],
+            used: 0,
+            hint: 0,
+            buffer: vec![0; PAGE_4K],
+        })
+    }
+
+    fn sector(&self, slot: usize) -> Lba {
+        Lba(self.first_sector.0 + slot as u64 * self.sectors_per_slot)
+    }
+
+    /// Takes a free slot for the user page at `physical`, and the buffer with a copy of it,
+    /// `None` if the swap is full, or another page is being written
+    fn start_write(&mut self, physical: u64) -> Option<(u64, Arc<BlockDeviceFile>, Lba, Vec<u8>)> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let slot = (self.hint..self.refs.len())
+            .chain(0..self.hint)
+            .find(|&slot| self.refs[slot] == 0)?;
+        let mut buffer = mem::take(&mut self.buffer);
+        // SAFETY: the page is mapped in the vm it's moved from, which doesn't run
+        unsafe {
+            physical_page_allocator::with_user_page(physical, |page| buffer.copy_from_slice(page))
+        };
+        self.refs[slot] = 1;
+        self.used += 1;
+        self.hint = slot + 1;
+        Some((slot as u64, self.device.clone(), self.sector(slot), buffer))
+    }
+
+    fn put(&mut self, slot: u64) {
+        let refs = &mut self.refs[slot as usize];
+        assert!(*refs > 0, "swap slot {slot} freed twice");
+        *refs -= 1;
+        if *refs == 0 {
+            self.used -= 1;
+        }
+    }
+}
+
+/// Writes the user page at `physical` to a free slot, without the locks, `None` if the swap is
+/// off or full, or if the write failed
+fn write_page(physical: u64) -> Option<u64> {
+    let (slot, device, sector, mut buffer) = SWAP.lock().as_mut()?.start_write(physical)?;
+    let result = device.transfer_in_place(BlockRequestKind::Write, sector, &mut buffer);
+    with_swap(|area| {
+        area.buffer = buffer;
+        if result.is_err() {
+            area.put(slot);
+        }
+    });
+    if result.is_err() {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+    Some(slot)
+}
+
+/// Reads `slot` into a new user page, without the locks, `None` if the read failed
+fn read_page(slot: u64) -> Option<u64> {
+    let (device, sector) = with_swap(|area| (area.device.clone(), area.sector(slot as usize)));
+    // not the buffer of the area, a page can be read while another is written
+    let mut buffer = vec![0; PAGE_4K];
+    if device
+        .transfer_in_place(BlockRequestKind::Read, sector, &mut buffer)
+        .is_err()
+    {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    // SAFETY: the processes run after the high memory is initialized, and the buffer is a page
+    Some(unsafe { physical_page_allocator::alloc_user_copy(buffer.as_ptr()) })
+}
+
+/// Runs `f` with the swap, which must be on, there is a page in it
+fn with_swap<U>(f: impl FnOnce(&mut SwapArea) -> U) -> U {
+    f(SWAP
+        .lock()
+        .as_mut()
+        .expect("a page in the swap, but it's off"))
+}
+
+/// Another entry points to `slot`, after a fork
+pub(super) fn share_slot(slot: u64) {
+    with_swap(|area| {
+        let refs = &mut area.refs[slot as usize];
+        *refs = refs
+            .checked_add(1)
+            .expect("too many references to a swap slot");
+    });
+}
+
+/// An entry pointing to `slot` went away, it's free after the last one
+pub(super) fn free_slot(slot: u64) {
+    with_swap(|area| area.put(slot));
+}
+
+/// Maps back the page at `addr` of the current process if it was moved to the swap, `false`
+/// otherwise, or if it couldn't be read. Called on the faults of the user, and before the
+/// kernel accesses user memory, without the scheduler lock
+pub fn swap_in(addr: u64) -> bool {
+    swap_in_with(addr, |f| {
+        scheduler::with_current_process(|process| process.with_vm(f))
+    })
+}
+
+/// [`swap_in`] for all the pages of `start..start + len`
+pub fn swap_in_range(start: u64, len: u64) {
+    let end = start.saturating_add(len);
+    let mut page = align_down(start as usize, PAGE_4K) as u64;
+    while page < end {
+        swap_in(page);
+        page += PAGE_4K as u64;
+    }
+}
+
+/// [`swap_in`] for the vm that `with_vm` runs its argument with, it's locked by it, and only
+/// to mark the entry and to map the page, the page is read between the two
+fn swap_in_with(
+    addr: u64,
+    mut with_vm: impl FnMut(&mut dyn FnMut(&mut VirtualMemoryMapper)),
+) -> bool {
+    let slot = loop {
+        let mut state = SwapIn::NotSwapped;
+        with_vm(&mut |vm| state = vm.start_swap_in(addr));
+        match state {
+            SwapIn::NotSwapped => return false,
+            SwapIn::Reading => core::hint::spin_loop(),
+            SwapIn::Slot(slot) => break slot,
+        }
+    };
+    let physical = read_page(slot);
+    let mut mapped = false;
+    with_vm(&mut |vm| mapped = vm.finish_swap_in(addr, slot, physical));
+    if mapped {
+        // the entry doesn't point to it anymore
+        free_slot(slot);
+    } else if let Some(physical) = physical {
+        // SAFETY: it's not mapped anywhere
+        unsafe { physical_page_allocator::free_physical(physical) };
+    }
+    mapped
+}
+
+/// Starts swapping to the swap partition of `device`, returns the number of slots in it
+pub fn swap_on(device: Arc<BlockDeviceFile>) -> Result<usize, FileSystemError> {
+    let area = SwapArea::new(device)?;
+    // the sectors read through the device before would be old
+    area.device.drop_cached_sectors();
+    let slots = area.refs.len();
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        return Err(FileSystemError::Busy);
+    }
+    *swap = Some(area);
+    Ok(slots)
+}
+
+/// Stops swapping, nothing must be in the swap
+pub fn swap_off() -> Result<(), FileSystemError> {
+    let area = {
+        let mut swap = SWAP.lock();
+        match swap.as_ref() {
+            None => return Err(FileSystemError::DeviceNotFound),
+            Some(area) if area.used != 0 => return Err(FileSystemError::Busy),
+            Some(_) => swap.take(),
+        }
+    };
+    // freed without the lock
+    drop(area);
+    Ok(())
+}
+
+struct SwapShrinker;
+
+impl Shrinker for SwapShrinker {
+    fn name(&self) -> &'static str {
+        "swap"
+    }
+
+    /// The pages are picked from a process at a time, in the order of the pids, and written
+    /// with the scheduler unlocked
+    fn shrink(&self, pages: usize) -> usize {
+        // the driver takes its locks, the caller could have them
+        if !reclaim::holds_no_lock() || SWAP.lock().is_none() {
+            return 0;
+        }
+        let mut moved = 0;
+        let mut next_pid = 0;
+        while moved < pages {
+            let mut batch = [(0, 0); SWAP_OUT_BATCH];
+            let max = (pages - moved).min(SWAP_OUT_BATCH);
+            let Some((pid, picked)) = scheduler::try_with_processes(|processes| {
+                pick_swap_out(processes, &mut next_pid, &mut batch[..max])
+            })
+            .flatten() else {
+                break;
+            };
+
+            let mut slots = [None; SWAP_OUT_BATCH];
+            for (slot, &(_, physical)) in slots.iter_mut().zip(&batch[..picked]) {
+                *slot = write_page(physical);
+                if slot.is_none() {
+                    break;
+                }
+            }
+            let written = slots.iter().take_while(|slot| slot.is_some()).count();
+            // it didn't run, so it's still there, and they are still its pages
+            scheduler::with_process(pid, |process| {
+                for (&(addr, physical), slot) in batch.iter().zip(slots.iter().flatten()) {
+                    if process.finish_swap_out(addr, physical, *slot) {
+                        moved += 1;
+                    } else {
+                        free_slot(*slot);
+                    }
+                }
+                process.end_swap_out();
+            })
+            .expect("the process swapped out exited");
+            if written < picked {
+                // full, or the device failed
+                break;
+            }
+            if picked < max {
+                // nothing more for now in this one
+                next_pid = pid + 1;
+            }
+        }
+        moved
+    }
+}
+
+static SWAP_SHRINKER: SwapShrinker = SwapShrinker;
+
+/// Picks pages of the first process from `next_pid` that has some into `batch`, returns its
+/// pid with the number picked, `next_pid` skips the ones before it
+fn pick_swap_out(
+    processes: &mut [Process],
+    next_pid: &mut u64,
+    batch: &mut [(u64, u64)],
+) -> Option<(u64, usize)> {
+    loop {
+        let process = processes
+            .iter_mut()
+            .filter(|process| process.id() >= *next_pid)
+            .min_by_key(|process| process.id())?;
+        let picked = process.pick_swap_out(batch);
+        if picked != 0 {
+            return Some((process.id(), picked));
+        }
+        *next_pid = process.id() + 1;
+    }
+}
+
+/// `/devices/swap`, the swap partition and its slots. Writing `on <device>` or `off` to it
+/// starts or stops swapping, for the privileged processes
+#[derive(Debug)]
+struct SwapInfo;
+
+impl Device for SwapInfo {
+    fn name(&self) -> &str {
+        "swap"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // copied, so we don't format (and allocate) while holding it
+        let area = SWAP.lock().as_ref().map(|area| {
+            (
+                area.device.clone(),
+                area.partition,
+                area.refs.len(),
+                area.used,
+            )
+        });
+        let mut info = match area {
+            Some((device, partition, slots, used)) => format!(
+                "device: {} partition {partition}\nslots: {slots}\nused: {used}\n",
+                device.name()
+            ),
+            None => String::from("off\n"),
+        };
+        info += &format!(
+            "swapped out: {}\nswapped in: {}\nerrors: {}\n",
+            SWAPPED_OUT.load(Ordering::Relaxed),
+            SWAPPED_IN.load(Ordering::Relaxed),
+            ERRORS.load(Ordering::Relaxed)
+        );
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        if !scheduler::with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        match command.split_once(' ') {
+            Some(("on", name)) => {
+                let device = block::find(name.trim()).ok_or(FileSystemError::DeviceNotFound)?;
+                swap_on(device)?;
+            }
+            None if command == "off" => swap_off()?,
+            _ => return Err(FileSystemError::InvalidData),
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Registers the shrinker and `/devices/swap`, and starts swapping to the device of
+/// `swap=<device>` in the cmdline, after the block devices are registered
+pub fn init(cmdline: &str) {
+    reclaim::register_shrinker(reclaim::priority::SWAP, &SWAP_SHRINKER);
+    devices::register_device(Arc::new(SwapInfo));
+    let Some(name) = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("swap="))
+    else {
+        return;
+    };
+    let result = block::find(name)
+        .ok_or(FileSystemError::DeviceNotFound)
+        .and_then(swap_on);
+    match result {
+        Ok(slots) => {
+            println!("Swapping to {name}, {slots} pages");
+        }
+        Err(e) => {
+            println!("Could not swap to {name}: {e}");
+        }
+    }
+}
+
+/// Runs `f` with `vm` loaded, to reach its user pages
+fn selftest_with_vm<U>(vm: &VirtualMemoryMapper, f: impl FnOnce() -> U) -> U {
+    cpu::cpu().push_cli();
+    let old_vm = virtual_memory_mapper::get_current_vm();
+    // SAFETY: the vm is a clone of this one, without process specific mappings
+    unsafe { vm.switch_to_this() };
+    let result = f();
+    unsafe { old_vm.switch_to_this() };
+    cpu::cpu().pop_cli();
+    result
+}
+
+/// The first byte of each page is its index, and the others are `0`, `None` if one isn't
+fn selftest_page_index(vm: &VirtualMemoryMapper, addr: u64) -> Option<u8> {
+    selftest_with_vm(vm, || {
+        // SAFETY: the page is mapped in the vm
+        let page = unsafe { core::slice::from_raw_parts(addr as *const u8, PAGE_4K) };
+        page[1..].iter().all(|&byte| byte == 0).then_some(page[0])
+    })
+}
+
+/// Moves the pages of a vm to a swap partition on a ramdisk until it's full, shares them with
+/// a fork, and reads them back in both, the slots are freed with the last entry of each
+pub fn run_self_tests() {
+    const NAME: &str = "selftest_swap";
+    const SLOTS: usize = 4;
+    const USER_ADDR: u64 = 0x40_0000;
+    // one more than the slots, it stays mapped
+    const PAGES: usize = SLOTS + 1;
+    const SIZE: u64 = (PAGES * PAGE_4K) as u64;
+
+    println!("Running swap self tests...");
+    if SWAP.lock().is_some() {
+        println!("swap self test: the swap is on, skipping");
+        return;
+    }
+    let sector_size = block::SELFTEST_SECTOR_SIZE;
+    let sectors_per_slot = (PAGE_4K / sector_size) as u32;
+    let disk = Arc::new(RamDisk::new((
+        (SLOTS as u64 + 1) * sectors_per_slot as u64,
+        sector_size as u32,
+    )));
+    let mut mbr = vec![0; sector_size];
+    let entry = mem::offset_of!(MbrRaw, partition_table);
+    mbr[entry + mem::offset_of!(PartitionEntry, partition_type)] = PARTITION_TYPE;
+    let start_lba = entry + mem::offset_of!(PartitionEntry, start_lba);
+    mbr[start_lba..start_lba + 4].copy_from_slice(&sectors_per_slot.to_le_bytes());
+    let size = entry + mem::offset_of!(PartitionEntry, size_in_sectors);
+    mbr[size..size + 4].copy_from_slice(&(SLOTS as u32 * sectors_per_slot).to_le_bytes());
+    let signature = mem::offset_of!(MbrRaw, signature);
+    mbr[signature..signature + 2].copy_from_slice(&0xAA55u16.to_le_bytes());
+    disk.write_sectors(Lba(0), &mbr).unwrap();
+    let device = BlockDeviceFile::register(NAME.into(), disk, true);
+    assert_eq!(swap_on(device.clone()).unwrap(), SLOTS);
+    assert!(matches!(swap_on(device), Err(FileSystemError::Busy)));
+
+    let used = || with_swap(|area| area.used);
+    let swap_out = |vm: &mut VirtualMemoryMapper| {
+        let mut pages = [(0, 0); PAGES];
+        let picked = vm.pick_swap_out(USER_ADDR, SIZE, &mut pages, 0);
+        pages[..picked]
+            .iter()
+            .map_while(|&(addr, physical)| Some((addr, physical, write_page(physical)?)))
+            .filter(|&(addr, physical, slot)| vm.finish_swap_out(addr, physical, slot))
+            .count()
+    };
+    let swap_in = |vm: &mut VirtualMemoryMapper, addr| swap_in_with(addr, |f| f(vm));
+    let mut parent = virtual_memory_mapper::clone_current_vm_as_user();
+    parent.map(&VirtualMemoryMapEntry {
+        virtual_address: USER_ADDR,
+        physical_address: None,
+        size: SIZE,
+        flags: virtual_memory_mapper::flags::PTE_USER | virtual_memory_mapper::flags::PTE_WRITABLE,
+    });
+    selftest_with_vm(&parent, || {
+        for i in 0..PAGES {
+            // SAFETY: the page is mapped and writable
+            unsafe { ((USER_ADDR + (i * PAGE_4K) as u64) as *mut u8).write_volatile(i as u8) };
+        }
+    });
+    let page = |i: usize| USER_ADDR + (i * PAGE_4K) as u64;
+
+    // just written, so they only lose their accessed bit the first time
+    assert_eq!(swap_out(&mut parent), 0);
+    assert_eq!(swap_out(&mut parent), SLOTS);
+    assert_eq!(used(), SLOTS);
+    assert!(parent.get_mapping(page(0)).is_none());
+    assert!(parent.get_mapping(page(SLOTS)).is_some());
+    assert!(matches!(swap_off(), Err(FileSystemError::Busy)));
+
+    let mut child = virtual_memory_mapper::clone_current_vm_as_user();
+    parent.clone_user_memory_cow(&mut child, &[]);
+    assert!(swap_in(&mut child, page(0)));
+    assert!(!swap_in(&mut child, page(0)), "read back twice");
+    assert!(swap_in(&mut parent, page(1)));
+    // the other one still has them there
+    assert_eq!(used(), SLOTS);
+    assert_eq!(selftest_page_index(&child, page(0)), Some(0));
+    assert_eq!(selftest_page_index(&parent, page(1)), Some(1));
+    // the one that stayed is shared like before
+    let shared = child.get_mapping(page(SLOTS)).unwrap();
+    assert_eq!(
+        physical_page_allocator::physical_refs(shared.physical_address.unwrap()),
+        2
+    );
+    assert_eq!(selftest_page_index(&child, page(SLOTS)), Some(SLOTS as u8));
+
+    parent.unmap(
+        &VirtualMemoryMapEntry {
+            virtual_address: USER_ADDR,
+            physical_address: None,
+            size: SIZE,
+            flags: 0,
+        },
+        true,
+    );
+    // the one of the first page was only the parent's
+    assert_eq!(used(), SLOTS - 1);
+    virtual_memory_mapper::selftest_free_vm(parent);
+    // a fault on it while it's read waits, and it's still there if the read fails
+    let SwapIn::Slot(slot) = child.start_swap_in(page(2)) else {
+        panic!("page 2 not in the swap");
+    };
+    assert!(matches!(child.start_swap_in(page(2)), SwapIn::Reading));
+    assert!(!child.finish_swap_in(page(2), slot, None));
+    assert!(swap_in(&mut child, page(2)));
+    assert_eq!(selftest_page_index(&child, page(2)), Some(2));
+    // and the rest go with the vm
+    virtual_memory_mapper::selftest_free_vm(child);
+    assert_eq!(used(), 0);
+    let mut buf = [0; 256];
+    let len = SwapInfo.read(0, &mut buf).unwrap() as usize;
+    let info = core::str::from_utf8(&buf[..len]).unwrap();
+    assert!(info.contains(&format!("{NAME} partition 0")) && info.contains("used: 0"));
+    let (swapped_out, swapped_in) = (
+        SWAPPED_OUT.load(Ordering::Relaxed),
+        SWAPPED_IN.load(Ordering::Relaxed),
+    );
+
+    swap_off().unwrap();
+    devices::unregister_device(NAME).unwrap();
+    println!("Swap self tests passed ({swapped_out} pages swapped out, {swapped_in} swapped in)");
+}
@@ -1,23 +1,32 @@
 //! This very specific to 64-bit x86 architecture, if this is to be ported to other architectures
 //! this will need to be changed
 
-use core::{ops::RangeBounds, slice::IterMut};
+use core::{
+    fmt,
+    ops::{Range, RangeBounds},
+    slice::IterMut,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
 
 use crate::{
-    cpu,
+    collections::bitset::BitSet,
+    cpu::{self, smp},
     memory_management::{
         memory_layout::{
-            align_range, align_up, is_aligned, kernel_elf_rodata_end, physical2virtual,
-            virtual2physical, MemSize, EXTENDED_OFFSET, KERNEL_BASE, KERNEL_LINK,
-            KERNEL_MAPPED_SIZE, PAGE_2M, PAGE_4K,
+            align_down, align_range, align_up, is_aligned, kernel_elf_rodata_end, kernel_elf_start,
+            kernel_text_end, physical2virtual, virtual2physical, LegacyAccess, LegacyRegion,
+            MemSize, EXTENDED_OFFSET, KERNEL_BASE, KERNEL_LINK, KERNEL_MAPPED_SIZE, LEGACY_REGIONS,
+            PAGE_1G, PAGE_2M, PAGE_4K,
         },
-        physical_page_allocator,
+        physical_page_allocator, swap, virtual_space,
     },
     sync::spin::mutex::Mutex,
 };
 
 use super::memory_layout::{
-    stack_guard_page_ptr, PROCESS_KERNEL_STACK_BASE, PROCESS_KERNEL_STACK_SIZE,
+    process_kernel_stack_base, stack_guard_page_ptr, PROCESS_KERNEL_STACK_SIZE,
 };
 
 // TODO: replace by some sort of bitfield
@@ -32,11 +41,23 @@ pub mod flags {
     pub(super) const PTE_DIRTY: u64 = 1 << 6;
     pub(super) const PTE_HUGE_PAGE: u64 = 1 << 7;
     pub(super) const PTE_GLOBAL: u64 = 1 << 8;
-    pub(super) const PTE_NO_EXECUTE: u64 = 1 << 63;
+    /// Available to the OS, a read-only user page that is writable once it's copied, see
+    /// [`VirtualMemoryMapper::clone_user_memory_cow`](super::VirtualMemoryMapper::clone_user_memory_cow)
+    pub const PTE_COW: u64 = 1 << 9;
+    /// Available to the OS, in an entry that is not present, the page is in the swap, the
+    /// address bits are its slot, see [`swap_entry`](super::swap_entry)
+    pub(super) const PTE_SWAPPED: u64 = 1 << 10;
+    /// Available to the OS, in an entry of the swap, its page is being read back, see
+    /// [`VirtualMemoryMapper::start_swap_in`](super::VirtualMemoryMapper::start_swap_in)
+    pub(super) const PTE_SWAP_READING: u64 = 1 << 11;
+    pub const PTE_NO_EXECUTE: u64 = 1 << 63;
 }
 
 const ADDR_MASK: u64 = 0x0000_0000_FFFF_F000;
 
+/// The slots of the swap that fit in the address bits of an entry, see [`swap_entry`]
+pub const MAX_SWAP_SLOTS: u64 = (ADDR_MASK >> 12) + 1;
+
 // only use the last index for the kernel
 // all the other indexes are free to use by the user
 const KERNEL_L4_INDEX: usize = 0x1FF;
@@ -55,6 +76,11 @@ pub const KERNEL_PROCESS_VIRTUAL_ADDRESS_START: usize =
 
 // the user can use all the indexes except the last one
 const NUM_USER_L4_INDEXES: usize = KERNEL_L4_INDEX;
+/// The end of the user address space (exclusive)
+pub const USER_ADDRESS_END: u64 = (NUM_USER_L4_INDEXES as u64) << 39;
+/// The end of the 48 bits of the address, the indexes of all the tables, the addresses
+/// above the half of it are the canonical ones of the kernel (see [`canonical`])
+const ADDRESS_SPACE_END: u64 = 1 << 48;
 
 pub const MAX_USER_VIRTUAL_ADDRESS: usize =
     // sign extension
@@ -64,6 +90,53 @@ pub const MAX_USER_VIRTUAL_ADDRESS: usize =
         | (0x1FF << 21)
         | (0x1FF << 12);
 
+/// `addr` with the bits above the 48 bits of the address space as the highest of them,
+/// for the addresses made from the indexes of the tables
+const fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+/// The entry of a 4K page moved to the swap `slot`, not present, with the `flags` of the
+/// page, so it's mapped back the same by [`VirtualMemoryMapper::finish_swap_in`]
+const fn swap_entry(flags: u64, slot: u64) -> u64 {
+    const DROPPED: u64 = ADDR_MASK | flags::PTE_PRESENT | flags::PTE_ACCESSED | flags::PTE_DIRTY;
+    (flags & !DROPPED) | flags::PTE_SWAPPED | (slot << 12)
+}
+
+/// Where the page of [`VirtualMemoryMapper::start_swap_in`] is
+pub enum SwapIn {
+    NotSwapped,
+    /// Another CPU is reading it back, it's mapped soon
+    Reading,
+    /// In this slot, the entry is marked until [`VirtualMemoryMapper::finish_swap_in`]
+    Slot(u64),
+}
+
+/// The swap slot of `entry`, if its page was moved there, see [`swap_entry`]
+const fn swap_slot(entry: u64) -> Option<u64> {
+    if entry & (flags::PTE_PRESENT | flags::PTE_SWAPPED) == flags::PTE_SWAPPED {
+        Some((entry & ADDR_MASK) >> 12)
+    } else {
+        None
+    }
+}
+
+/// Whether `addr` is in the kernel mappings shared by all the vms, not the ones of each process,
+/// every CPU can have them cached
+const fn is_shared_kernel_address(addr: u64) -> bool {
+    get_l4(addr) == KERNEL_L4_INDEX as u64 && get_l3(addr) >= KERNEL_L3_INDEX_START as u64
+}
+
+/// Drops the cached translation of `virtual_address`, on the other CPUs too if it's a shared
+/// kernel one. The user and per process mappings are only used by the CPU of the process, and
+/// the others load another `cr3` before using theirs, see [`smp::flush_tlb_others`]
+fn invalidate(virtual_address: u64) {
+    unsafe { cpu::invalidate_tlp(virtual_address) };
+    if is_shared_kernel_address(virtual_address) {
+        smp::flush_tlb_others();
+    }
+}
+
 #[inline(always)]
 const fn get_l4(addr: u64) -> u64 {
     (addr >> 39) & 0x1FF
@@ -140,11 +213,223 @@ impl PageDirectoryTablePtr {
     }
 }
 
+const CPUID_FN_EXT_MAX: u32 = 0x8000_0000;
+const CPUID_FN_EXT_FEAT: u32 = 0x8000_0001;
+const CPUID_EXT_FEAT_EDX_PDPE1GB: u32 = 1 << 26;
+const CPUID_EXT_FEAT_EDX_NX: u32 = 1 << 20;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: u64 = 1 << 16;
+
+/// Set in [`init_kernel_vm`] if the CPU supports 1GB pages
+static HUGE_1GB_PAGES: AtomicBool = AtomicBool::new(false);
+/// Set in [`init_kernel_vm`] if the CPU supports [`flags::PTE_NO_EXECUTE`], which is
+/// enabled then
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+fn ext_features_edx() -> u32 {
+    if cpu::cpuid!(CPUID_FN_EXT_MAX).eax < CPUID_FN_EXT_FEAT {
+        return 0;
+    }
+    cpu::cpuid!(CPUID_FN_EXT_FEAT).edx
+}
+
+fn has_1gb_pages() -> bool {
+    ext_features_edx() & CPUID_EXT_FEAT_EDX_PDPE1GB != 0
+}
+
+/// Enables [`flags::PTE_NO_EXECUTE`] if the CPU has it, without it the bit is reserved
+fn enable_no_execute() -> bool {
+    if ext_features_edx() & CPUID_EXT_FEAT_EDX_NX == 0 {
+        return false;
+    }
+    // SAFETY: the CPU supports it, and nothing is mapped with the bit yet
+    unsafe {
+        let efer = cpu::msr::read(cpu::msr::EFER);
+        cpu::msr::write(cpu::msr::EFER, efer | EFER_NXE);
+    }
+    true
+}
+
+/// [`flags::PTE_NO_EXECUTE`] if it's enabled, `0` otherwise
+fn no_execute() -> u64 {
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        flags::PTE_NO_EXECUTE
+    } else {
+        0
+    }
+}
+
+/// The kernel maps the memory it owns at [`physical2virtual`] of it, these mappings can use
+/// huge pages. Uncached or write-through mappings are for devices, and keep 4K pages
+fn is_direct_map(virtual_address: u64, physical_address: Option<u64>, flags: u64) -> bool {
+    const DEVICE_FLAGS: u64 = flags::PTE_WRITETHROUGH | flags::PTE_NOT_CACHEABLE | flags::PTE_USER;
+
+    physical_address
+        .is_some_and(|physical| physical.wrapping_add(KERNEL_BASE as u64) == virtual_address)
+        && flags & DEVICE_FLAGS == 0
+}
+
+/// The biggest page that can map `size` bytes of the direct map at `virtual_address`
+fn direct_map_page_size(virtual_address: u64, size: u64) -> u64 {
+    let fits =
+        |page_size: usize| is_aligned(virtual_address as _, page_size) && size >= page_size as u64;
+
+    if HUGE_1GB_PAGES.load(Ordering::Relaxed) && fits(PAGE_1G) {
+        PAGE_1G as u64
+    } else if fits(PAGE_2M) {
+        PAGE_2M as u64
+    } else {
+        PAGE_4K as u64
+    }
+}
+
+/// Makes `entry` point to a table of the next level: a new one if not present, and the split
+/// of the page if it was a huge page of `huge_size` mapped at `virtual_address`
+fn ensure_table(entry: &mut u64, huge_size: u64, virtual_address: u64, upper_level_flags: u64) {
+    if *entry & flags::PTE_PRESENT == 0 {
+        let table = PageDirectoryTablePtr::alloc_new();
+        *entry = (table.to_physical() & ADDR_MASK) | flags::PTE_PRESENT;
+    } else if *entry & flags::PTE_HUGE_PAGE != 0 {
+        split_huge_entry(entry, huge_size, virtual_address);
+    }
+    // add new flags
+    *entry |= upper_level_flags;
+}
+
+/// Replaces the huge page of `huge_size` in `entry` by a table mapping the same memory with the
+/// same flags, with pages of the next level, 2MB pages for 1GB and 4K pages for 2MB.
+fn split_huge_entry(entry: &mut u64, huge_size: u64, virtual_address: u64) {
+    let physical_address = *entry & ADDR_MASK & !(huge_size - 1);
+    let leaf_flags = *entry & !ADDR_MASK;
+    let (page_size, page_flags) = if huge_size == PAGE_1G as u64 {
+        (PAGE_2M as u64, leaf_flags)
+    } else {
+        // `HUGE_PAGE` is the `PAT` bit in the last level
+        (PAGE_4K as u64, leaf_flags & !flags::PTE_HUGE_PAGE)
+    };
+
+    let mut table = PageDirectoryTablePtr::alloc_new();
+    for (i, page) in table.as_mut().entries.iter_mut().enumerate() {
+        *page = (physical_address + i as u64 * page_size) | page_flags;
+    }
+    *entry = (table.to_physical() & ADDR_MASK)
+        | flags::PTE_PRESENT
+        | (leaf_flags & (flags::PTE_WRITABLE | flags::PTE_USER));
+    // the translation is the same, but the CPU must not have both page sizes cached for it
+    invalidate(virtual_address as _);
+}
+
+/// Maps a huge page of `level` (2 for 2MB, 3 for 1GB) in `entry`, the tables that were
+/// under it are freed, but not the pages they mapped
+fn set_huge_entry(entry: &mut u64, level: u8, virtual_address: u64, physical_and_flags: u64) {
+    let old = *entry;
+    *entry = physical_and_flags | flags::PTE_PRESENT | flags::PTE_HUGE_PAGE;
+
+    if old & flags::PTE_PRESENT == 0 {
+        return;
+    }
+    if old & flags::PTE_HUGE_PAGE != 0 {
+        invalidate(virtual_address as _);
+    } else {
+        // any of the pages under it can be cached, and the tables themselves, flush all of them
+        // before freeing
+        unsafe { cpu::set_cr3(cpu::get_cr3()) };
+        if is_shared_kernel_address(virtual_address) {
+            smp::flush_tlb_others();
+        }
+        unsafe { free_table_tree(old, level) };
+    }
+}
+
+/// Frees the table `entry` of `level` points to, and the tables under it, but not the pages
+/// that they map
+///
+/// # Safety
+/// The tables must not be used after this
+unsafe fn free_table_tree(entry: u64, level: u8) {
+    let table = PageDirectoryTablePtr::from_entry(entry);
+    if level > 2 {
+        for &child in table.as_ref().entries.iter() {
+            if child & flags::PTE_PRESENT != 0 && child & flags::PTE_HUGE_PAGE == 0 {
+                unsafe { free_table_tree(child, level - 1) };
+            }
+        }
+    }
+    unsafe { table.free() };
+}
+
+/// Unmaps the huge page of `huge_size` in `entry` if the `size` bytes at `virtual_address`
+/// cover it, or splits it so the next level can unmap part of it.
+/// Returns the size unmapped, `None` if its left for the next level
+fn unmap_huge_entry(
+    entry: &mut u64,
+    huge_size: u64,
+    virtual_address: u64,
+    size: u64,
+    is_allocated: bool,
+) -> Option<u64> {
+    if *entry & flags::PTE_HUGE_PAGE == 0 {
+        return None;
+    }
+    if !is_aligned(virtual_address as _, huge_size as _) || size < huge_size {
+        split_huge_entry(entry, huge_size, virtual_address);
+        return None;
+    }
+    let old = *entry;
+    *entry = 0;
+    invalidate(virtual_address as _);
+    if is_allocated {
+        unsafe { free_user_huge_page(old) };
+    }
+    Some(huge_size)
+}
+
+/// Frees the 4K pages of the huge page in `entry`, they are freed one by one like after a split
+///
+/// # Safety
+/// The page must not be mapped anymore
+unsafe fn free_user_huge_page(entry: u64) {
+    assert!(
+        entry & flags::PTE_USER != 0,
+        "Huge pages are only allocated for the user, the others are given to `map`"
+    );
+    // `HUGE_PAGE` is the last level, the `PAT` bit after it is in the address mask
+    let start = entry & ADDR_MASK & !(PAGE_2M as u64 - 1);
+    for page in (start..start + PAGE_2M as u64).step_by(PAGE_4K) {
+        unsafe { physical_page_allocator::free_physical(page) };
+    }
+}
+
+/// Frees the page of the last level `entry` and clears it, used when a whole vm goes away
+fn free_process_page(entry: &mut u64) {
+    // the pages of the user can be after the direct map, see `map`
+    if let Some(slot) = swap_slot(*entry) {
+        swap::free_slot(slot);
+    } else if *entry & flags::PTE_HUGE_PAGE != 0 {
+        unsafe { free_user_huge_page(*entry) };
+    } else {
+        unsafe { physical_page_allocator::free_physical(*entry & ADDR_MASK) };
+    }
+    *entry = 0;
+}
+
 static KERNEL_VIRTUAL_MEMORY_MANAGER: Mutex<VirtualMemoryMapper> =
     Mutex::new(VirtualMemoryMapper::boot_vm());
 
 pub fn init_kernel_vm() {
+    HUGE_1GB_PAGES.store(has_1gb_pages(), Ordering::Relaxed);
+    NO_EXECUTE.store(enable_no_execute(), Ordering::Relaxed);
+    // the read-only pages are read-only for the kernel too
+    // SAFETY: the kernel never writes to its read-only pages, and copies the pages shared by
+    //         `SYS_FORK` before writing to them, see `user_memory::copy_to_user`
+    unsafe { cpu::set_cr0(cpu::get_cr0() | CR0_WP) };
     let new_kernel_manager = VirtualMemoryMapper::new_kernel_vm();
+    println!(
+        "[vm] kernel: {} page tables, 1GB pages: {}, no execute: {}",
+        new_kernel_manager.page_tables_count(),
+        HUGE_1GB_PAGES.load(Ordering::Relaxed),
+        NO_EXECUTE.load(Ordering::Relaxed)
+    );
     let mut manager = KERNEL_VIRTUAL_MEMORY_MANAGER.lock();
     *manager = new_kernel_manager;
     // SAFETY: this is the start VM, so we are sure that we are not inside a process, so its safe to switch
@@ -157,6 +442,27 @@ pub unsafe fn switch_to_kernel() {
     KERNEL_VIRTUAL_MEMORY_MANAGER.lock().switch_to_this();
 }
 
+/// Flip the [`LegacyAccess::WritableOnBoot`] regions to read-only, must be called after
+/// all the CPUs are started
+pub fn seal_legacy_boot_regions() {
+    let mut manager = KERNEL_VIRTUAL_MEMORY_MANAGER.lock();
+    for region in LEGACY_REGIONS
+        .iter()
+        .filter(|region| region.access == LegacyAccess::WritableOnBoot)
+    {
+        manager.map(&region_map_entry(region, 0));
+    }
+}
+
+fn region_map_entry(region: &LegacyRegion, flags: u64) -> VirtualMemoryMapEntry {
+    VirtualMemoryMapEntry {
+        virtual_address: physical2virtual(region.start) as u64,
+        physical_address: Some(region.start as u64),
+        size: (region.end - region.start) as u64,
+        flags,
+    }
+}
+
 pub fn map_kernel(entry: &VirtualMemoryMapEntry) {
     // make sure we are only mapping to kernel memory
     assert!(entry.virtual_address >= KERNEL_BASE as u64);
@@ -180,6 +486,85 @@ pub fn is_address_mapped_in_kernel(addr: u64) -> bool {
     KERNEL_VIRTUAL_MEMORY_MANAGER.lock().is_address_mapped(addr)
 }
 
+pub fn is_kernel_vm_locked() -> bool {
+    KERNEL_VIRTUAL_MEMORY_MANAGER.is_locked()
+}
+
+/// The current VM from `cr3`, without taking any locks
+fn current_vm_unlocked() -> VirtualMemoryMapper {
+    let cr3 = physical2virtual(unsafe { cpu::get_cr3() } as _) as _;
+    VirtualMemoryMapper {
+        page_map_l4: PageDirectoryTablePtr(cr3),
+        is_user: false,
+    }
+}
+
+/// Same as [`VirtualMemoryMapper::get_mapping`] for the current VM, without taking any locks,
+/// so it can be used in `panic`
+pub fn get_current_mapping_unlocked(addr: u64) -> Option<VirtualMemoryMapEntry> {
+    current_vm_unlocked().get_mapping(addr)
+}
+
+/// Prints every range mapped in the current VM, with its physical address and flags, see
+/// [`VirtualMemoryMapper::for_each_mapped_range`]. Doesn't allocate nor take any locks, so it
+/// can be used in `panic`
+pub fn dump() {
+    let mut ranges = 0;
+    let mut total = 0;
+    current_vm_unlocked().for_each_mapped_range(0, ADDRESS_SPACE_END, |range| {
+        println!(
+            "{:016x}-{:016x} {:016x} {:>10} {}",
+            range.virtual_address,
+            range.virtual_address + range.size,
+            range.physical_address.unwrap_or(0),
+            MemSize(range.size),
+            MappingFlags(range.flags)
+        );
+        ranges += 1;
+        total += range.size;
+    });
+    println!("{ranges} ranges, {} mapped", MemSize(total));
+}
+
+/// The flags of a mapping as `rwx`, then `u` for user or `k` for kernel, and the caching,
+/// global and copy on write ones if set
+struct MappingFlags(u64);
+
+impl fmt::Display for MappingFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |bit: u64, set: char, unset: char| {
+            if self.0 & bit != 0 {
+                set
+            } else {
+                unset
+            }
+        };
+        let executable = if self.0 & flags::PTE_NO_EXECUTE == 0 {
+            'x'
+        } else {
+            '-'
+        };
+        write!(
+            f,
+            "r{}{} {}",
+            flag(flags::PTE_WRITABLE, 'w', '-'),
+            executable,
+            flag(flags::PTE_USER, 'u', 'k')
+        )?;
+        for (bit, name) in [
+            (flags::PTE_NOT_CACHEABLE, " uncached"),
+            (flags::PTE_WRITETHROUGH, " write-through"),
+            (flags::PTE_GLOBAL, " global"),
+            (flags::PTE_COW, " cow"),
+        ] {
+            if self.0 & bit != 0 {
+                f.write_str(name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn clone_current_vm_as_user() -> VirtualMemoryMapper {
     // precaution, a sort of manual lock
     cpu::cpu().push_cli();
@@ -194,6 +579,13 @@ pub fn get_current_vm() -> VirtualMemoryMapper {
     VirtualMemoryMapper::get_current_vm()
 }
 
+/// Resolves a write fault of the user at `addr`, `true` if it was on a page shared by
+/// `SYS_FORK`, which is now writable, see [`VirtualMemoryMapper::resolve_cow`]
+pub fn handle_cow_fault(addr: u64) -> bool {
+    let mut vm = get_current_vm();
+    vm.is_user && vm.resolve_cow(addr)
+}
+
 pub struct VirtualMemoryMapper {
     page_map_l4: PageDirectoryTablePtr,
     is_user: bool,
@@ -250,17 +642,40 @@ impl VirtualMemoryMapper {
         for i in KERNEL_L3_PROCESS_INDEX_START..=KERNEL_L3_PROCESS_INDEX_END {
             this_kernel_l4.as_mut().entries[i] = 0;
         }
-        // set it temporarily so we can map kernel range
-        // TODO: fix this hack
-        self.is_user = false;
-        // load new kernel stack for this process
-        self.map(&VirtualMemoryMapEntry {
-            virtual_address: PROCESS_KERNEL_STACK_BASE as u64,
+        // load new kernel stack for the first thread of this process, the others are mapped
+        // when the threads are created
+        self.map_kernel_stack(0);
+    }
+
+    fn kernel_stack_entry(slot: usize) -> VirtualMemoryMapEntry {
+        VirtualMemoryMapEntry {
+            virtual_address: process_kernel_stack_base(slot) as u64,
             physical_address: None, // allocate
             size: PROCESS_KERNEL_STACK_SIZE as u64,
-            flags: flags::PTE_WRITABLE,
-        });
-        self.is_user = true;
+            flags: flags::PTE_WRITABLE | flags::PTE_NO_EXECUTE,
+        }
+    }
+
+    /// Allocates and maps the kernel stack of `slot` (see [`process_kernel_stack_base`]), its
+    /// guard page stays unmapped
+    pub fn map_kernel_stack(&mut self, slot: usize) {
+        // set it temporarily so we can map kernel range
+        // TODO: fix this hack
+        let is_user = core::mem::replace(&mut self.is_user, false);
+        self.map(&Self::kernel_stack_entry(slot));
+        self.is_user = is_user;
+    }
+
+    /// Unmaps and frees the kernel stack of `slot`, it must not be the one we are running on
+    pub fn unmap_kernel_stack(&mut self, slot: usize) {
+        // no flags, the tables above it are shared with the other stacks
+        self.unmap(
+            &VirtualMemoryMapEntry {
+                flags: 0,
+                ..Self::kernel_stack_entry(slot)
+            },
+            true,
+        );
     }
 
     fn load_vm(base: &PageDirectoryTablePtr) {
@@ -291,29 +706,50 @@ impl VirtualMemoryMapper {
     // This replicate what is done in the assembly code
     // but it will be stored
     fn new_kernel_vm() -> Self {
+        let rodata_start = align_up(kernel_text_end(), PAGE_4K);
         let data_start = align_up(kernel_elf_rodata_end(), PAGE_4K);
+        // Low memory (has some BIOS stuff): only the regions we use are mapped to kernel space,
+        // so stray accesses to the rest fault
+        let low_memory = LEGACY_REGIONS.iter().filter_map(|region| {
+            let flags = match region.access {
+                LegacyAccess::Unmapped => return None,
+                LegacyAccess::ReadOnly => 0,
+                LegacyAccess::Writable
+                | LegacyAccess::WritableOnBoot
+                | LegacyAccess::Allocatable => flags::PTE_WRITABLE,
+            };
+            Some(region_map_entry(region, flags))
+        });
+        let kernel_start = kernel_elf_start();
         let kernel_vm = [
-            // Low memory (has some BIOS stuff): mapped to kernel space
-            VirtualMemoryMapEntry {
-                virtual_address: KERNEL_BASE as u64,
-                physical_address: Some(0),
-                size: EXTENDED_OFFSET as u64,
-                flags: flags::PTE_WRITABLE,
-            },
-            // Extended memory: kernel .text and .rodata sections
+            // Extended memory: before the kernel, where it was loaded if `kaslr` moved it
             VirtualMemoryMapEntry {
                 virtual_address: KERNEL_LINK as u64,
                 physical_address: Some(virtual2physical(KERNEL_LINK) as u64),
-                size: virtual2physical(data_start) as u64 - virtual2physical(KERNEL_LINK) as u64,
+                size: (kernel_start - KERNEL_LINK) as u64,
+                flags: flags::PTE_WRITABLE | flags::PTE_NO_EXECUTE,
+            },
+            // Extended memory: kernel .text section
+            VirtualMemoryMapEntry {
+                virtual_address: kernel_start as u64,
+                physical_address: Some(virtual2physical(kernel_start) as u64),
+                size: (rodata_start - kernel_start) as u64,
                 flags: 0, // read-only
             },
+            // Extended memory: kernel .rodata section
+            VirtualMemoryMapEntry {
+                virtual_address: rodata_start as u64,
+                physical_address: Some(virtual2physical(rodata_start) as u64),
+                size: (data_start - rodata_start) as u64,
+                flags: flags::PTE_NO_EXECUTE,
+            },
             // Extended memory: kernel .data and .bss sections and the rest of the data for the `whole` memory
             // we decided to use in the kernel
             VirtualMemoryMapEntry {
                 virtual_address: data_start as u64,
                 physical_address: Some(virtual2physical(data_start) as u64),
                 size: KERNEL_MAPPED_SIZE as u64 - virtual2physical(data_start) as u64,
-                flags: flags::PTE_WRITABLE,
+                flags: flags::PTE_WRITABLE | flags::PTE_NO_EXECUTE,
             },
         ];
 
@@ -321,8 +757,8 @@ impl VirtualMemoryMapper {
         // SAFETY: we are calling the virtual memory manager after initializing the physical page allocator
         let mut s = Self::new();
 
-        for entry in kernel_vm.iter() {
-            s.map(entry);
+        for entry in low_memory.chain(kernel_vm).filter(|entry| entry.size != 0) {
+            s.map(&entry);
         }
 
         // unmap stack guard
@@ -339,6 +775,18 @@ impl VirtualMemoryMapper {
         s
     }
 
+    /// Maps `entry`, replacing what was mapped there before.
+    ///
+    /// The direct map of the kernel (see [`is_direct_map`]) uses the biggest pages the
+    /// alignment and the size allow: 1GB if the CPU supports them, then 2MB, then 4K.
+    /// The user memory allocated here uses 2MB pages for the aligned 2MB parts of the range
+    /// while there are free ones (see [`physical_page_allocator::alloc_user_huge_zeroed`]).
+    /// Everything else uses 4K pages. A huge page is split when only part of it is mapped
+    /// again or unmapped.
+    ///
+    /// The kernel L3 entries are copied into every process (see [`Self::clone_kernel_mem`]), so
+    /// the kernel must only create or split 1GB pages, which are L3 entries, before any
+    /// process exists
     pub fn map(&mut self, entry: &VirtualMemoryMapEntry) {
         let VirtualMemoryMapEntry {
             mut virtual_address,
@@ -346,6 +794,8 @@ impl VirtualMemoryMapper {
             size: requested_size,
             flags,
         } = entry;
+        // without it enabled the bit is reserved, the pages are executable then
+        let flags = &(*flags & !(flags::PTE_NO_EXECUTE & !no_execute()));
 
         assert!(!self.page_map_l4.as_ptr().is_null());
         assert!(is_aligned(self.page_map_l4.0 as _, PAGE_4K));
@@ -371,6 +821,15 @@ impl VirtualMemoryMapper {
 
         // keep track of current address and size
         let mut physical_address = start_physical_address;
+        let direct_map = !self.is_user && is_direct_map(virtual_address, physical_address, *flags);
+
+        // only the permissions are needed in the upper levels, the caching flags there
+        // would apply to the page tables themselves and not the mapped memory
+        let mut upper_level_flags = flags & (flags::PTE_WRITABLE | flags::PTE_USER);
+        // the page is made writable later without coming here, see `resolve_cow`
+        if flags & flags::PTE_COW != 0 {
+            upper_level_flags |= flags::PTE_WRITABLE;
+        }
 
         assert!(size > 0);
 
@@ -386,9 +845,33 @@ impl VirtualMemoryMapper {
         );
 
         while size > 0 {
-            let current_physical_address = physical_address.unwrap_or_else(|| {
-                virtual2physical(unsafe { physical_page_allocator::alloc_zeroed() as _ }) as _
-            });
+            let huge_user_page = if self.is_user
+                && *flags & flags::PTE_USER != 0
+                && physical_address.is_none()
+                && is_aligned(virtual_address as _, PAGE_2M)
+                && size >= PAGE_2M as u64
+            {
+                unsafe { physical_page_allocator::alloc_user_huge_zeroed() }
+            } else {
+                None
+            };
+            let page_size = if direct_map {
+                direct_map_page_size(virtual_address, size)
+            } else if huge_user_page.is_some() {
+                PAGE_2M as u64
+            } else {
+                PAGE_4K as u64
+            };
+            let current_physical_address =
+                huge_user_page.or(physical_address).unwrap_or_else(|| {
+                    if *flags & flags::PTE_USER != 0 {
+                        // only reached through these page tables, so it can be after the direct map
+                        unsafe { physical_page_allocator::alloc_user_zeroed() }
+                    } else {
+                        virtual2physical(unsafe { physical_page_allocator::alloc_zeroed() as _ })
+                            as _
+                    }
+                });
             eprintln!(
                 "[!] Mapping {:p} to {:p}",
                 virtual_address as *const u8, current_physical_address as *const u8
@@ -407,7 +890,7 @@ impl VirtualMemoryMapper {
                     (page_directory_pointer_table.to_physical() & ADDR_MASK) | flags::PTE_PRESENT;
             }
             // add new flags if any
-            *page_map_l4_entry |= flags;
+            *page_map_l4_entry |= upper_level_flags;
             eprintln!(
                 "L4[{}]: {:p} = {:x}",
                 page_map_l4_index, page_map_l4_entry, *page_map_l4_entry
@@ -420,112 +903,97 @@ impl VirtualMemoryMapper {
             let page_directory_pointer_entry =
                 &mut page_directory_pointer_table.as_mut().entries[page_directory_pointer_index];
 
-            if *page_directory_pointer_entry & flags::PTE_PRESENT == 0 {
-                let page_directory_table = PageDirectoryTablePtr::alloc_new();
-                *page_directory_pointer_entry =
-                    (page_directory_table.to_physical() & ADDR_MASK) | flags::PTE_PRESENT;
-            }
-
-            // add new flags
-            *page_directory_pointer_entry |= flags;
-            eprintln!(
-                "L3[{}]: {:p} = {:x}",
-                page_directory_pointer_index,
-                page_directory_pointer_entry,
-                *page_directory_pointer_entry
-            );
-
-            // Level 2
-            let mut page_directory_table =
-                PageDirectoryTablePtr::from_entry(*page_directory_pointer_entry);
-            let page_directory_entry =
-                &mut page_directory_table.as_mut().entries[page_directory_index];
-
-            // here we have an intersection, if we can map a 2MB page, we will, otherwise we will map a 4K page
-            // if we are providing the pages (the user didn't provide), then we can't use 2MB pages
-            // let can_map_2mb_page = physical_address
-            //     .map(|phy_addr| {
-            //         is_aligned(phy_addr as _, PAGE_2M)
-            //             && is_aligned(virtual_address as _, PAGE_2M)
-            //             && size >= PAGE_2M as u64
-            //     })
-            //     .unwrap_or(false);
-            // TODO: we have disabled 2MB as its not easy to unmap in the middle, all pages must be the sames
-
-            let can_map_2mb_page = false;
-            if can_map_2mb_page {
-                // we already have an entry here
-                if *page_directory_entry & flags::PTE_PRESENT != 0 {
-                    // did we have a mapping here that lead to 4k pages?
-                    // if so, we should free the physical page allocation for them
-                    if *page_directory_entry & flags::PTE_HUGE_PAGE == 0 {
-                        let page_table_ptr =
-                            PageDirectoryTablePtr::from_entry(*page_directory_entry);
-
-                        unsafe { page_table_ptr.free() };
-                    }
-                }
-
-                // Level 1
-                *page_directory_entry = (current_physical_address & ADDR_MASK)
-                    | flags
-                    | flags::PTE_PRESENT
-                    | flags::PTE_HUGE_PAGE;
-
+            if page_size == PAGE_1G as u64 {
+                set_huge_entry(
+                    page_directory_pointer_entry,
+                    3,
+                    virtual_address,
+                    current_physical_address | flags,
+                );
                 eprintln!(
-                    "L2[{}] huge: {:p} = {:x}",
-                    page_directory_index, page_directory_entry, *page_directory_entry
+                    "L3[{}] huge: {:p} = {:x}",
+                    page_directory_pointer_index,
+                    page_directory_pointer_entry,
+                    *page_directory_pointer_entry
                 );
-
-                size -= PAGE_2M as u64;
-                // do not overflow the address
-                if size == 0 {
-                    break;
-                }
-                virtual_address += PAGE_2M as u64;
-                if let Some(physical_address) = physical_address.as_mut() {
-                    *physical_address += PAGE_2M as u64;
-                }
             } else {
-                // continue mapping 4K pages
-                if *page_directory_entry & flags::PTE_PRESENT == 0 {
-                    let page_table = PageDirectoryTablePtr::alloc_new();
-                    *page_directory_entry =
-                        (page_table.to_physical() & ADDR_MASK) | flags::PTE_PRESENT;
-                }
-                // add new flags
-                *page_directory_entry |= flags;
-                eprintln!(
-                    "L2[{}]: {:p} = {:x}",
-                    page_directory_index, page_directory_entry, *page_directory_entry
+                ensure_table(
+                    page_directory_pointer_entry,
+                    PAGE_1G as u64,
+                    virtual_address,
+                    upper_level_flags,
                 );
-
-                // Level 1
-                let mut page_table = PageDirectoryTablePtr::from_entry(*page_directory_entry);
-                let page_table_entry = &mut page_table.as_mut().entries[page_table_index];
-                *page_table_entry =
-                    (current_physical_address & ADDR_MASK) | flags | flags::PTE_PRESENT;
                 eprintln!(
-                    "L1[{}]: {:p} = {:x}",
-                    page_table_index, page_table_entry, *page_table_entry
+                    "L3[{}]: {:p} = {:x}",
+                    page_directory_pointer_index,
+                    page_directory_pointer_entry,
+                    *page_directory_pointer_entry
                 );
 
-                size -= PAGE_4K as u64;
-                // do not overflow the address
-                if size == 0 {
-                    break;
-                }
-                virtual_address += PAGE_4K as u64;
-                if let Some(physical_address) = physical_address.as_mut() {
-                    *physical_address += PAGE_4K as u64;
+                // Level 2
+                let mut page_directory_table =
+                    PageDirectoryTablePtr::from_entry(*page_directory_pointer_entry);
+                let page_directory_entry =
+                    &mut page_directory_table.as_mut().entries[page_directory_index];
+
+                if page_size == PAGE_2M as u64 {
+                    set_huge_entry(
+                        page_directory_entry,
+                        2,
+                        virtual_address,
+                        current_physical_address | flags,
+                    );
+                    eprintln!(
+                        "L2[{}] huge: {:p} = {:x}",
+                        page_directory_index, page_directory_entry, *page_directory_entry
+                    );
+                } else {
+                    ensure_table(
+                        page_directory_entry,
+                        PAGE_2M as u64,
+                        virtual_address,
+                        upper_level_flags,
+                    );
+                    eprintln!(
+                        "L2[{}]: {:p} = {:x}",
+                        page_directory_index, page_directory_entry, *page_directory_entry
+                    );
+
+                    // Level 1
+                    let mut page_table = PageDirectoryTablePtr::from_entry(*page_directory_entry);
+                    let page_table_entry = &mut page_table.as_mut().entries[page_table_index];
+                    let was_present = *page_table_entry & flags::PTE_PRESENT != 0;
+                    *page_table_entry =
+                        (current_physical_address & ADDR_MASK) | flags | flags::PTE_PRESENT;
+                    if was_present {
+                        // the old translation may still be cached
+                        invalidate(virtual_address as _);
+                    }
+                    eprintln!(
+                        "L1[{}]: {:p} = {:x}",
+                        page_table_index, page_table_entry, *page_table_entry
+                    );
                 }
             }
 
+            size -= page_size;
+            // do not overflow the address
+            if size == 0 {
+                break;
+            }
+            virtual_address += page_size;
+            if let Some(physical_address) = physical_address.as_mut() {
+                *physical_address += page_size;
+            }
+
             eprintln!();
         }
     }
 
-    /// Removes mapping of a virtual entry, it will free it from physical memory if it was allocated
+    /// Removes mapping of a virtual entry, it will free it from physical memory if it was allocated.
+    ///
+    /// Huge pages are removed whole if the range covers them, and split otherwise, the
+    /// allocated ones are user pages, freed one 4K page at a time
     pub fn unmap(&mut self, entry: &VirtualMemoryMapEntry, is_allocated: bool) {
         let VirtualMemoryMapEntry {
             mut virtual_address,
@@ -555,10 +1023,6 @@ impl VirtualMemoryMapper {
         );
 
         while size > 0 {
-            unsafe {
-                cpu::invalidate_tlp(virtual_address as _);
-            }
-
             let page_map_l4_index = get_l4(virtual_address) as usize;
             let page_directory_pointer_index = get_l3(virtual_address) as usize;
             let page_directory_index = get_l2(virtual_address) as usize;
@@ -587,50 +1051,116 @@ impl VirtualMemoryMapper {
             if *page_directory_pointer_entry & flags::PTE_PRESENT == 0 {
                 panic!("Trying to unmap a non-mapped address");
             }
-            // remove flags
-            *page_directory_pointer_entry &= !flags;
-            eprintln!(
-                "L3[{}]: {:p} = {:x}",
-                page_directory_pointer_index,
+            let unmapped = unmap_huge_entry(
                 page_directory_pointer_entry,
-                *page_directory_pointer_entry
+                PAGE_1G as u64,
+                virtual_address,
+                size,
+                is_allocated,
             );
+            let page_size = if let Some(page_size) = unmapped {
+                page_size
+            } else {
+                // remove flags
+                *page_directory_pointer_entry &= !flags;
+                eprintln!(
+                    "L3[{}]: {:p} = {:x}",
+                    page_directory_pointer_index,
+                    page_directory_pointer_entry,
+                    *page_directory_pointer_entry
+                );
 
-            // Level 2
-            let mut page_directory_table =
-                PageDirectoryTablePtr::from_entry(*page_directory_pointer_entry);
-            let page_directory_entry =
-                &mut page_directory_table.as_mut().entries[page_directory_index];
-
-            if *page_directory_entry & flags::PTE_PRESENT == 0 {
-                panic!("Trying to unmap a non-mapped address");
-            }
-            // remove flags
-            *page_directory_entry &= !flags;
+                // Level 2
+                let mut page_directory_table =
+                    PageDirectoryTablePtr::from_entry(*page_directory_pointer_entry);
+                let page_directory_entry =
+                    &mut page_directory_table.as_mut().entries[page_directory_index];
 
-            // Level 1
-            let mut page_table = PageDirectoryTablePtr::from_entry(*page_directory_entry);
-            let page_table_entry = &mut page_table.as_mut().entries[page_table_index];
-            if *page_table_entry & flags::PTE_PRESENT == 0 {
-                panic!("Trying to unmap a non-mapped address");
-            }
-            let physical_entry = PageDirectoryTablePtr::from_entry(*page_table_entry);
-            if is_allocated {
-                unsafe { physical_entry.free() };
-            }
-            // remove whole entry
-            *page_table_entry = 0;
-            eprintln!(
-                "L1[{}]: {:p} = {:x}",
-                page_table_index, page_table_entry, *page_table_entry
-            );
+                if *page_directory_entry & flags::PTE_PRESENT == 0 {
+                    panic!("Trying to unmap a non-mapped address");
+                }
+                let unmapped = unmap_huge_entry(
+                    page_directory_entry,
+                    PAGE_2M as u64,
+                    virtual_address,
+                    size,
+                    is_allocated,
+                );
+                if let Some(page_size) = unmapped {
+                    page_size
+                } else {
+                    // remove flags
+                    *page_directory_entry &= !flags;
+
+                    // Level 1
+                    let mut page_table = PageDirectoryTablePtr::from_entry(*page_directory_entry);
+                    let page_table_entry = &mut page_table.as_mut().entries[page_table_index];
+                    if let Some(slot) = swap_slot(*page_table_entry) {
+                        // not present, so never cached
+                        *page_table_entry = 0;
+                        if is_allocated {
+                            swap::free_slot(slot);
+                        }
+                    } else if *page_table_entry & flags::PTE_PRESENT == 0 {
+                        panic!("Trying to unmap a non-mapped address");
+                    } else {
+                        let physical_page = *page_table_entry & ADDR_MASK;
+                        // remove whole entry, then drop the cached translation, and only then free the page,
+                        // otherwise a stale TLB entry can still reach it (see `sync::barrier`)
+                        *page_table_entry = 0;
+                        invalidate(virtual_address as _);
+                        if is_allocated {
+                            unsafe { physical_page_allocator::free_physical(physical_page) };
+                        }
+                    }
+                    eprintln!(
+                        "L1[{}]: {:p} = {:x}",
+                        page_table_index, page_table_entry, *page_table_entry
+                    );
+                    PAGE_4K as u64
+                }
+            };
 
-            size -= PAGE_4K as u64;
+            size -= page_size;
             // do not overflow the address
             if size == 0 {
                 break;
             }
-            virtual_address += PAGE_4K as u64;
+            virtual_address += page_size;
+        }
+    }
+
+    /// Changes the flags of the pages mapped in the range, keeping the memory they map,
+    /// the pages that are not mapped are skipped, the ones in the swap get them when they
+    /// are mapped back
+    pub fn protect(&mut self, virtual_address: u64, size: u64, flags: u64) {
+        let (start, size, _) = align_range(virtual_address as _, size as _, PAGE_4K);
+        let start = start as u64;
+        self.for_each_leaf(start, size as u64, true, |_, _, entry| {
+            if let Some(slot) = swap_slot(entry.load(Ordering::Relaxed)) {
+                entry.store(swap_entry(flags, slot), Ordering::Relaxed);
+            }
+        });
+        for page in (start..start + size as u64).step_by(PAGE_4K) {
+            let Some(mapping) = self.get_mapping(page) else {
+                continue;
+            };
+            let physical_address =
+                mapping.physical_address.unwrap() + (page - mapping.virtual_address);
+            let mut flags = flags;
+            // another vm still maps it, so it can only be written after it's copied
+            if flags & flags::PTE_WRITABLE != 0
+                && physical_page_allocator::physical_refs(physical_address) > 1
+            {
+                flags = (flags & !flags::PTE_WRITABLE) | flags::PTE_COW;
+            }
+            self.map(&VirtualMemoryMapEntry {
+                virtual_address: page,
+                // inside a huge page, only this part of it is mapped again
+                physical_address: Some(physical_address),
+                size: PAGE_4K as u64,
+                flags,
+            });
         }
     }
 
@@ -659,6 +1189,9 @@ impl VirtualMemoryMapper {
         if *page_directory_pointer_entry & flags::PTE_PRESENT == 0 {
             return false;
         }
+        if *page_directory_pointer_entry & flags::PTE_HUGE_PAGE != 0 {
+            return true;
+        }
         eprintln!(
             "L3[{}]: {:p} = {:x}",
             page_directory_pointer_index,
@@ -694,6 +1227,296 @@ impl VirtualMemoryMapper {
         true
     }
 
+    /// Returns the mapping of the page containing `addr` if its mapped.
+    /// The returned `flags` are the flags of the last level entry, without
+    /// the internal `PRESENT`, `ACCESSED`, `DIRTY` and `HUGE_PAGE` flags
+    pub fn get_mapping(&self, addr: u64) -> Option<VirtualMemoryMapEntry> {
+        const INTERNAL_FLAGS: u64 =
+            flags::PTE_PRESENT | flags::PTE_ACCESSED | flags::PTE_DIRTY | flags::PTE_HUGE_PAGE;
+
+        let page_map_l4_index = get_l4(addr) as usize;
+        let page_directory_pointer_index = get_l3(addr) as usize;
+        let page_directory_index = get_l2(addr) as usize;
+        let page_table_index = get_l1(addr) as usize;
+
+        // Level 4
+        let page_map_l4_entry = self.page_map_l4.as_ref().entries[page_map_l4_index];
+        if page_map_l4_entry & flags::PTE_PRESENT == 0 {
+            return None;
+        }
+
+        // Level 3
+        let page_directory_pointer_table = PageDirectoryTablePtr::from_entry(page_map_l4_entry);
+        let page_directory_pointer_entry =
+            page_directory_pointer_table.as_ref().entries[page_directory_pointer_index];
+        if page_directory_pointer_entry & flags::PTE_PRESENT == 0 {
+            return None;
+        }
+        if page_directory_pointer_entry & flags::PTE_HUGE_PAGE != 0 {
+            return Some(VirtualMemoryMapEntry {
+                virtual_address: addr & !(PAGE_1G as u64 - 1),
+                physical_address: Some(page_directory_pointer_entry & ADDR_MASK),
+                size: PAGE_1G as u64,
+                flags: page_directory_pointer_entry & !ADDR_MASK & !INTERNAL_FLAGS,
+            });
+        }
+
+        // Level 2
+        let page_directory_table = PageDirectoryTablePtr::from_entry(page_directory_pointer_entry);
+        let page_directory_entry = page_directory_table.as_ref().entries[page_directory_index];
+        if page_directory_entry & flags::PTE_PRESENT == 0 {
+            return None;
+        }
+        if page_directory_entry & flags::PTE_HUGE_PAGE != 0 {
+            return Some(VirtualMemoryMapEntry {
+                virtual_address: addr & !(PAGE_2M as u64 - 1),
+                physical_address: Some(page_directory_entry & ADDR_MASK),
+                size: PAGE_2M as u64,
+                flags: page_directory_entry & !ADDR_MASK & !INTERNAL_FLAGS,
+            });
+        }
+
+        // Level 1
+        let page_table = PageDirectoryTablePtr::from_entry(page_directory_entry);
+        let page_table_entry = page_table.as_ref().entries[page_table_index];
+        if page_table_entry & flags::PTE_PRESENT == 0 {
+            return None;
+        }
+
+        Some(VirtualMemoryMapEntry {
+            virtual_address: addr & !(PAGE_4K as u64 - 1),
+            physical_address: Some(page_table_entry & ADDR_MASK),
+            size: PAGE_4K as u64,
+            flags: page_table_entry & !ADDR_MASK & !INTERNAL_FLAGS,
+        })
+    }
+
+    /// Calls `f` with every present last level entry that maps part of the range,
+    /// with the virtual address and the size of the page it maps.
+    ///
+    /// The entry is given as atomic, since the CPU can update the accessed/dirty bits
+    /// while we are looking at it.
+    fn for_each_present_leaf(
+        &self,
+        virtual_address: u64,
+        size: u64,
+        f: impl FnMut(u64, u64, &AtomicU64),
+    ) {
+        self.for_each_leaf(virtual_address, size, false, f);
+    }
+
+    /// Same as [`Self::for_each_present_leaf`], with the 4K entries of the pages in the swap
+    /// too if `swapped`, see [`swap_entry`]
+    fn for_each_leaf(
+        &self,
+        virtual_address: u64,
+        size: u64,
+        swapped: bool,
+        mut f: impl FnMut(u64, u64, &AtomicU64),
+    ) {
+        const L3_SPAN: u64 = 1 << 39;
+        const L2_SPAN: u64 = 1 << 30;
+
+        // go to the start of the next block of `span` bytes, `None` if we reached the end
+        let next = |addr: u64, span: u64| (addr | (span - 1)).checked_add(1);
+        let end = virtual_address.saturating_add(size);
+        let mut addr = align_down(virtual_address as _, PAGE_4K) as u64;
+
+        while addr < end {
+            // Level 4
+            let page_map_l4_entry = self.page_map_l4.as_ref().entries[get_l4(addr) as usize];
+            if page_map_l4_entry & flags::PTE_PRESENT == 0 {
+                let Some(n) = next(addr, L3_SPAN) else { break };
+                addr = n;
+                continue;
+            }
+
+            // Level 3
+            let page_directory_pointer_table = PageDirectoryTablePtr::from_entry(page_map_l4_entry);
+            // SAFETY: the table is valid, we only need a pointer to the entry
+            let page_directory_pointer_entry_ptr = unsafe {
+                &raw mut (*page_directory_pointer_table.as_ptr()).entries[get_l3(addr) as usize]
+            };
+            // SAFETY: the entry is valid
+            let page_directory_pointer_entry = unsafe { *page_directory_pointer_entry_ptr };
+            if page_directory_pointer_entry & flags::PTE_PRESENT == 0 {
+                let Some(n) = next(addr, L2_SPAN) else { break };
+                addr = n;
+                continue;
+            }
+            if page_directory_pointer_entry & flags::PTE_HUGE_PAGE != 0 {
+                let page_start = addr & !(PAGE_1G as u64 - 1);
+                // SAFETY: the entry is a valid aligned `u64` inside the page table
+                f(page_start, PAGE_1G as u64, unsafe {
+                    AtomicU64::from_ptr(page_directory_pointer_entry_ptr)
+                });
+                let Some(n) = next(addr, L2_SPAN) else { break };
+                addr = n;
+                continue;
+            }
+
+            // Level 2
+            let page_directory_table =
+                PageDirectoryTablePtr::from_entry(page_directory_pointer_entry);
+            // SAFETY: the table is valid, we only need a pointer to the entry
+            let page_directory_entry =
+                unsafe { &raw mut (*page_directory_table.as_ptr()).entries[get_l2(addr) as usize] };
+            // SAFETY: the entry is valid
+            let page_directory_value = unsafe { *page_directory_entry };
+            if page_directory_value & flags::PTE_PRESENT == 0 {
+                let Some(n) = next(addr, PAGE_2M as u64) else {
+                    break;
+                };
+                addr = n;
+                continue;
+            }
+            if page_directory_value & flags::PTE_HUGE_PAGE != 0 {
+                let page_start = addr & !(PAGE_2M as u64 - 1);
+                // SAFETY: the entry is a valid aligned `u64` inside the page table
+                f(page_start, PAGE_2M as u64, unsafe {
+                    AtomicU64::from_ptr(page_directory_entry)
+                });
+                let Some(n) = next(addr, PAGE_2M as u64) else {
+                    break;
+                };
+                addr = n;
+                continue;
+            }
+
+            // Level 1
+            let page_table = PageDirectoryTablePtr::from_entry(page_directory_value);
+            // SAFETY: the table is valid, we only need a pointer to the entry
+            let page_table_entry =
+                unsafe { &raw mut (*page_table.as_ptr()).entries[get_l1(addr) as usize] };
+            // SAFETY: the entry is valid
+            let page_table_value = unsafe { *page_table_entry };
+            if page_table_value & flags::PTE_PRESENT != 0
+                || (swapped && swap_slot(page_table_value).is_some())
+            {
+                // SAFETY: the entry is a valid aligned `u64` inside the page table
+                f(addr, PAGE_4K as u64, unsafe {
+                    AtomicU64::from_ptr(page_table_entry)
+                });
+            }
+            let Some(n) = addr.checked_add(PAGE_4K as u64) else {
+                break;
+            };
+            addr = n;
+        }
+    }
+
+    /// Calls `f` with the mapped ranges that have a page inside the range, in order. The
+    /// neighbouring pages mapping neighbouring physical memory with the same flags are merged,
+    /// the `flags` are the ones of the last level entries, same as [`Self::get_mapping`].
+    ///
+    /// The range can be across the hole of the non canonical addresses, the ranges given have
+    /// canonical addresses
+    fn for_each_mapped_range(
+        &self,
+        virtual_address: u64,
+        size: u64,
+        mut f: impl FnMut(&VirtualMemoryMapEntry),
+    ) {
+        const INTERNAL_FLAGS: u64 =
+            flags::PTE_PRESENT | flags::PTE_ACCESSED | flags::PTE_DIRTY | flags::PTE_HUGE_PAGE;
+
+        let mut current: Option<VirtualMemoryMapEntry> = None;
+        self.for_each_present_leaf(virtual_address, size, |addr, page_size, entry| {
+            let entry = entry.load(Ordering::Relaxed);
+            let addr = canonical(addr);
+            let physical = entry & ADDR_MASK;
+            let flags = entry & !ADDR_MASK & !INTERNAL_FLAGS;
+            if let Some(range) = current.as_mut() {
+                let end = range.virtual_address + range.size;
+                let physical_end = range.physical_address.unwrap() + range.size;
+                if end == addr && physical_end == physical && range.flags == flags {
+                    range.size += page_size;
+                    return;
+                }
+                f(range);
+            }
+            current = Some(VirtualMemoryMapEntry {
+                virtual_address: addr,
+                physical_address: Some(physical),
+                size: page_size,
+                flags,
+            });
+        });
+        if let Some(range) = current {
+            f(&range);
+        }
+    }
+
+    /// Reads the `bit` of the present pages in the range, calling `f` with the part of the
+    /// range (start, end) of every page that has it set, and clears it if `clear`.
+    fn scan_leaf_bit(
+        &self,
+        virtual_address: u64,
+        size: u64,
+        bit: u64,
+        clear: bool,
+        mut f: impl FnMut(u64, u64),
+    ) {
+        let end = virtual_address.saturating_add(size);
+        self.for_each_present_leaf(virtual_address, size, |page, page_size, entry| {
+            let old = if clear {
+                entry.fetch_and(!bit, Ordering::AcqRel)
+            } else {
+                entry.load(Ordering::Acquire)
+            };
+            if old & bit == 0 {
+                return;
+            }
+            if clear {
+                // the TLB entry would keep the bit set without writing it to the table again
+                invalidate(page);
+            }
+            f(page.max(virtual_address), (page + page_size).min(end));
+        });
+    }
+
+    /// Reads and clears the accessed bits of the 4K pages in the range, the result has
+    /// a bit for every page, starting from `virtual_address` (aligned down).
+    ///
+    /// Huge pages report all the pages they cover in the range.
+    pub fn harvest_accessed(&mut self, virtual_address: u64, size: u64) -> BitSet {
+        let start = align_down(virtual_address as _, PAGE_4K) as u64;
+        let size = size + (virtual_address - start);
+        let mut result = BitSet::new(size.div_ceil(PAGE_4K as u64) as usize);
+        self.scan_leaf_bit(start, size, flags::PTE_ACCESSED, true, |s, e| {
+            result.set_range(
+                ((s - start) / PAGE_4K as u64) as usize,
+                (e - start).div_ceil(PAGE_4K as u64) as usize,
+            )
+        });
+        result
+    }
+
+    /// Same as [`harvest_accessed`](Self::harvest_accessed), but only returns the number of
+    /// accessed 4K pages, this is used to scan the whole user space
+    pub fn harvest_accessed_count(&mut self, virtual_address: u64, size: u64) -> u64 {
+        let mut count = 0;
+        self.scan_leaf_bit(virtual_address, size, flags::PTE_ACCESSED, true, |s, e| {
+            count += (e - s).div_ceil(PAGE_4K as u64);
+        });
+        count
+    }
+
+    /// Reads the dirty bits of the 4K pages in the range, like
+    /// [`harvest_accessed`](Self::harvest_accessed), and only clear them if `clear`
+    pub fn collect_dirty(&mut self, virtual_address: u64, size: u64, clear: bool) -> BitSet {
+        let start = align_down(virtual_address as _, PAGE_4K) as u64;
+        let size = size + (virtual_address - start);
+        let mut result = BitSet::new(size.div_ceil(PAGE_4K as u64) as usize);
+        self.scan_leaf_bit(start, size, flags::PTE_DIRTY, clear, |s, e| {
+            result.set_range(
+                ((s - start) / PAGE_4K as u64) as usize,
+                (e - start).div_ceil(PAGE_4K as u64) as usize,
+            )
+        });
+        result
+    }
+
     // TODO: add tests for this
     fn do_for_ranges_enteries<R1, R2, F>(&mut self, l4_ranges: R1, l3_ranges: R2, mut f: F)
     where
@@ -710,17 +1533,20 @@ impl VirtualMemoryMapper {
             page_directory_table.entries.iter_mut()
         }
 
-        // handle 2MB pages and below
-        let handle_2mb_pages = |page_directory_entry: &mut u64| {
-            // handle 2MB pages
-            if *page_directory_entry & flags::PTE_HUGE_PAGE != 0 {
-                f(page_directory_entry);
-            } else {
-                as_page_directory_table_flat(page_directory_entry)
-                    .filter(present)
-                    .for_each(&mut f);
+        // calls `f` with the last level entries under `entry` of `level`, the huge pages included
+        fn for_each_leaf(entry: &mut u64, level: u8, f: &mut impl FnMut(&mut u64)) {
+            if level == 1 || *entry & flags::PTE_HUGE_PAGE != 0 {
+                f(entry);
+                return;
             }
-        };
+            // the pages in the swap are freed too
+            as_page_directory_table_flat(entry)
+                .filter(|entry| {
+                    **entry & flags::PTE_PRESENT != 0
+                        || (level == 2 && swap_slot(**entry).is_some())
+                })
+                .for_each(|entry| for_each_leaf(entry, level - 1, f));
+        }
 
         let l4_start = match l4_ranges.start_bound() {
             core::ops::Bound::Included(&start) => start,
@@ -757,9 +1583,7 @@ impl VirtualMemoryMapper {
             .skip(l3_skip)
             .take(l3_take)
             .filter(present)
-            .flat_map(as_page_directory_table_flat)
-            .filter(present)
-            .for_each(handle_2mb_pages);
+            .for_each(|entry| for_each_leaf(entry, 3, &mut f));
     }
 
     // the handler function definition is `fn(page_entry: &mut u64)`
@@ -779,17 +1603,1128 @@ impl VirtualMemoryMapper {
     // search for all the pages that are mapped to the user ranges and unmap them and free their memory
     // also unmap any process specific kernel memory
     pub fn unmap_process_memory(&mut self) {
-        let free_page = |entry: &mut u64| {
+        self.do_for_every_user_entry(free_process_page);
+        self.unmap_process_kernel_memory();
+    }
+
+    /// Unmaps and frees only the process specific kernel memory, the process unmaps its user
+    /// memory itself, as it knows where it is
+    pub fn unmap_process_kernel_memory(&mut self) {
+        self.do_for_kernel_process_entry(free_process_page);
+    }
+
+    /// Maps all the user pages of this vm in `child` too, for `SYS_FORK`, except the ones in the
+    /// `not_owned` ranges, which are not private to the vm (i.e. the time page), the caller
+    /// maps them.
+    ///
+    /// The pages are shared, the writable ones become read-only and [`flags::PTE_COW`] in both
+    /// until they are written to, see [`Self::resolve_cow`].
+    /// This must be the current vm, their old translations are dropped
+    pub fn clone_user_memory_cow(&self, child: &mut Self, not_owned: &[Range<u64>]) {
+        assert!(self.is_user && child.is_user);
+        self.for_each_leaf(0, USER_ADDRESS_END, true, |addr, size, entry| {
+            // the stack is in the upper half
+            let addr = canonical(addr);
+            let value = entry.load(Ordering::Relaxed);
+            if let Some(slot) = swap_slot(value) {
+                if !not_owned.iter().any(|range| range.contains(&addr)) {
+                    // each one gets its own copy when it's mapped back
+                    swap::share_slot(slot);
+                    let mut upper_level_flags = value & (flags::PTE_WRITABLE | flags::PTE_USER);
+                    if value & flags::PTE_COW != 0 {
+                        upper_level_flags |= flags::PTE_WRITABLE;
+                    }
+                    // the child reads it itself
+                    *child.leaf_entry(addr, upper_level_flags) = value & !flags::PTE_SWAP_READING;
+                }
+                return;
+            }
+            if size == PAGE_4K as u64 {
+                Self::share_page_cow(child, addr, entry, not_owned);
+                return;
+            }
+            assert_eq!(
+                size, PAGE_2M as u64,
+                "only the user memory is in huge pages"
+            );
+            // the 4K pages are shared, so only the one written to is copied
+            // SAFETY: the entry is valid, and nothing else is using it
+            split_huge_entry(unsafe { &mut *entry.as_ptr() }, size, addr);
+            let table = PageDirectoryTablePtr::from_entry(entry.load(Ordering::Relaxed));
+            for i in 0..512 {
+                // SAFETY: the entry is a valid aligned `u64` inside the page table
+                let page = unsafe { AtomicU64::from_ptr(&raw mut (*table.as_ptr()).entries[i]) };
+                Self::share_page_cow(child, addr + (i * PAGE_4K) as u64, page, not_owned);
+            }
+        });
+    }
+
+    /// The last level entry of the 4K page at `addr`, the tables to it are created if missing,
+    /// and get the `upper_level_flags`, like in [`Self::map`]
+    fn leaf_entry(&mut self, addr: u64, upper_level_flags: u64) -> &mut u64 {
+        let page_map_l4_entry = &mut self.page_map_l4.as_mut().entries[get_l4(addr) as usize];
+        // the top level has no huge pages
+        ensure_table(page_map_l4_entry, 0, addr, upper_level_flags);
+        let page_directory_pointer_entry =
+            &mut PageDirectoryTablePtr::enteries_from_mut_entry(page_map_l4_entry).entries
+                [get_l3(addr) as usize];
+        ensure_table(
+            page_directory_pointer_entry,
+            PAGE_1G as u64,
+            addr,
+            upper_level_flags,
+        );
+        let page_directory_entry =
+            &mut PageDirectoryTablePtr::enteries_from_mut_entry(page_directory_pointer_entry)
+                .entries[get_l2(addr) as usize];
+        ensure_table(
+            page_directory_entry,
+            PAGE_2M as u64,
+            addr,
+            upper_level_flags,
+        );
+        &mut PageDirectoryTablePtr::enteries_from_mut_entry(page_directory_entry).entries
+            [get_l1(addr) as usize]
+    }
+
+    /// Picks the 4K user pages in the range to move to the swap, the ones not accessed since
+    /// the last call, the others lose their accessed bit, so they go the next time if they are
+    /// not used until then. The address and the physical page of each are put in `pages`
+    /// after the `picked` ones already there, returns the new count.
+    ///
+    /// The pages are written without the locks, the owner of the vm must not run until
+    /// [`Self::finish_swap_out`]. The huge pages, and the pages that another vm maps too, are
+    /// kept. This must not be the current vm, the translations of the others are not cached
+    pub fn pick_swap_out(
+        &mut self,
+        virtual_address: u64,
+        size: u64,
+        pages: &mut [(u64, u64)],
+        mut picked: usize,
+    ) -> usize {
+        assert!(self.is_user);
+        assert_ne!(
+            get_current_vm().page_map_l4.0,
+            self.page_map_l4.0,
+            "swapping out the current vm"
+        );
+        self.for_each_present_leaf(virtual_address, size, |addr, page_size, entry| {
+            if picked >= pages.len() || page_size != PAGE_4K as u64 {
+                return;
+            }
+            let value = entry.load(Ordering::Relaxed);
+            if value & flags::PTE_USER == 0 {
+                return;
+            }
+            if value & flags::PTE_ACCESSED != 0 {
+                entry.fetch_and(!flags::PTE_ACCESSED, Ordering::Relaxed);
+                return;
+            }
+            let physical = value & ADDR_MASK;
+            if physical_page_allocator::physical_refs(physical) > 1 {
+                return;
+            }
+            pages[picked] = (canonical(addr), physical);
+            picked += 1;
+        });
+        picked
+    }
+
+    /// Points the entry of the page at `addr`, picked by [`Self::pick_swap_out`], to `slot`
+    /// it was written to, and frees the page. Returns `false` if the entry doesn't have
+    /// `physical` anymore, then the slot isn't used
+    pub fn finish_swap_out(&mut self, addr: u64, physical: u64, slot: u64) -> bool {
+        let mut moved = false;
+        self.for_each_present_leaf(addr, PAGE_4K as u64, |_, page_size, entry| {
+            let value = entry.load(Ordering::Relaxed);
+            if page_size != PAGE_4K as u64 || value & ADDR_MASK != physical {
+                return;
+            }
+            entry.store(swap_entry(value, slot), Ordering::Relaxed);
+            // SAFETY: it was only mapped here
+            unsafe { physical_page_allocator::free_physical(physical) };
+            moved = true;
+        });
+        moved
+    }
+
+    /// Marks the entry of the page at `addr` as being read back if it's in the swap, and gives
+    /// its slot. The page is read without the locks, then mapped with [`Self::finish_swap_in`]
+    pub fn start_swap_in(&mut self, addr: u64) -> SwapIn {
+        let page = align_down(addr as _, PAGE_4K) as u64;
+        let mut result = SwapIn::NotSwapped;
+        self.for_each_leaf(page, PAGE_4K as u64, true, |_, _, entry| {
+            let value = entry.load(Ordering::Relaxed);
+            let Some(slot) = swap_slot(value) else {
+                return;
+            };
+            result = if value & flags::PTE_SWAP_READING != 0 {
+                SwapIn::Reading
+            } else {
+                entry.store(value | flags::PTE_SWAP_READING, Ordering::Relaxed);
+                SwapIn::Slot(slot)
+            };
+        });
+        result
+    }
+
+    /// Maps `physical`, the content of `slot` read after [`Self::start_swap_in`], at `addr`
+    /// with the flags the page had, or only unmarks the entry if it's `None`. Returns `false`
+    /// if the entry is not the marked one of `slot` anymore, then the page isn't used
+    pub fn finish_swap_in(&mut self, addr: u64, slot: u64, physical: Option<u64>) -> bool {
+        let page = align_down(addr as _, PAGE_4K) as u64;
+        let mut mapped = false;
+        self.for_each_leaf(page, PAGE_4K as u64, true, |_, _, entry| {
+            let value = entry.load(Ordering::Relaxed);
+            if swap_slot(value) != Some(slot) || value & flags::PTE_SWAP_READING == 0 {
+                return;
+            }
+            let flags = value & !ADDR_MASK & !flags::PTE_SWAPPED & !flags::PTE_SWAP_READING;
+            match physical {
+                Some(physical) => {
+                    entry.store(physical | flags | flags::PTE_PRESENT, Ordering::Relaxed);
+                    mapped = true;
+                }
+                None => entry.store(value & !flags::PTE_SWAP_READING, Ordering::Relaxed),
+            }
+        });
+        mapped
+    }
+
+    /// Maps the 4K page of `entry` at `addr` in `child` too, see
+    /// [`Self::clone_user_memory_cow`]
+    fn share_page_cow(child: &mut Self, addr: u64, entry: &AtomicU64, not_owned: &[Range<u64>]) {
+        const INTERNAL_FLAGS: u64 = flags::PTE_PRESENT
+            | flags::PTE_ACCESSED
+            | flags::PTE_DIRTY
+            | flags::PTE_HUGE_PAGE
+            | flags::PTE_GLOBAL;
+
+        if not_owned.iter().any(|range| range.contains(&addr)) {
+            return;
+        }
+        let mut value = entry.load(Ordering::Relaxed);
+        if value & flags::PTE_WRITABLE != 0 {
+            entry.fetch_and(!flags::PTE_WRITABLE, Ordering::Relaxed);
+            value = entry.fetch_or(flags::PTE_COW, Ordering::Relaxed) | flags::PTE_COW;
+            invalidate(addr);
+        }
+        let physical = value & ADDR_MASK;
+        physical_page_allocator::share_physical(physical);
+        child.map(&VirtualMemoryMapEntry {
+            virtual_address: addr,
+            physical_address: Some(physical),
+            size: PAGE_4K as u64,
+            flags: value & !ADDR_MASK & !INTERNAL_FLAGS,
+        });
+    }
+
+    /// Makes the [`flags::PTE_COW`] page at `addr` writable, it's copied first if another vm
+    /// still maps it, returns `false` if it's not a `COW` page.
+    ///
+    /// This must be the current vm, the page is copied through `addr`
+    pub fn resolve_cow(&mut self, addr: u64) -> bool {
+        let page = align_down(addr as _, PAGE_4K) as u64;
+        let mut resolved = false;
+        self.for_each_present_leaf(page, PAGE_4K as u64, |_, _, entry| {
+            let value = entry.load(Ordering::Relaxed);
+            if value & (flags::PTE_COW | flags::PTE_USER) != flags::PTE_COW | flags::PTE_USER {
+                return;
+            }
+            let physical = value & ADDR_MASK;
+            let shared = physical_page_allocator::physical_refs(physical) > 1;
+            let new_physical = if shared {
+                // SAFETY: the page is mapped at `page`, this is the current vm
+                unsafe { physical_page_allocator::alloc_user_copy(page as *const u8) }
+            } else {
+                physical
+            };
+            let flags = (value & !ADDR_MASK & !flags::PTE_COW) | flags::PTE_WRITABLE;
+            entry.store(new_physical | flags, Ordering::Relaxed);
+            invalidate(page);
+            if shared {
+                // only drops our reference
+                unsafe { physical_page_allocator::free_physical(physical) };
+            }
+            resolved = true;
+        });
+        resolved
+    }
+
+    /// The user mappings of this vm, neighbouring pages with the same flags are merged.
+    /// The `flags` are only `WRITABLE` (the `COW` pages included), `USER` and `NO_EXECUTE`
+    pub fn user_mappings(&self) -> Vec<VirtualMemoryMapEntry> {
+        const KEPT_FLAGS: u64 = flags::PTE_WRITABLE | flags::PTE_USER | flags::PTE_NO_EXECUTE;
+
+        let mut mappings = Vec::<VirtualMemoryMapEntry>::new();
+        self.for_each_present_leaf(0, USER_ADDRESS_END, |addr, size, entry| {
+            let mut entry = entry.load(Ordering::Relaxed);
+            if entry & flags::PTE_COW != 0 {
+                entry |= flags::PTE_WRITABLE;
+            }
+            let flags = entry & KEPT_FLAGS;
+            match mappings.last_mut() {
+                Some(last) if last.virtual_address + last.size == addr && last.flags == flags => {
+                    last.size += size
+                }
+                _ => mappings.push(VirtualMemoryMapEntry {
+                    virtual_address: addr,
+                    physical_address: None,
+                    size,
+                    flags,
+                }),
+            }
+        });
+        mappings
+    }
+
+    /// The number of page tables of this vm, the top level one included
+    pub fn page_tables_count(&self) -> u64 {
+        // `entry` of `level` points to a table
+        fn count(entry: u64, level: u8) -> u64 {
+            let table = PageDirectoryTablePtr::from_entry(entry);
+            let children = if level > 2 {
+                table
+                    .as_ref()
+                    .entries
+                    .iter()
+                    .filter(|&&e| e & flags::PTE_PRESENT != 0 && e & flags::PTE_HUGE_PAGE == 0)
+                    .map(|&e| count(e, level - 1))
+                    .sum()
+            } else {
+                0
+            };
+            1 + children
+        }
+
+        1 + self
+            .page_map_l4
+            .as_ref()
+            .entries
+            .iter()
+            .filter(|&&e| e & flags::PTE_PRESENT != 0)
+            .map(|&e| count(e, 4))
+            .sum::<u64>()
+    }
+
+    /// The number of 4K pages used by the user part of this vm, the mapped pages and the
+    /// page tables used to map them
+    pub fn user_pages_count(&self) -> u64 {
+        let present = |entry: &&u64| **entry & flags::PTE_PRESENT != 0;
+        let mut count = 0;
+
+        let page_map_l4 = self.page_map_l4.as_ref();
+        for l4_entry in page_map_l4.entries[..NUM_USER_L4_INDEXES]
+            .iter()
+            .filter(present)
+        {
+            count += 1;
+            let page_directory_pointer_table = PageDirectoryTablePtr::from_entry(*l4_entry);
+            for l3_entry in page_directory_pointer_table
+                .as_ref()
+                .entries
+                .iter()
+                .filter(present)
+            {
+                if l3_entry & flags::PTE_HUGE_PAGE != 0 {
+                    count += (PAGE_1G / PAGE_4K) as u64;
+                    continue;
+                }
+                count += 1;
+                let page_directory_table = PageDirectoryTablePtr::from_entry(*l3_entry);
+                for l2_entry in page_directory_table.as_ref().entries.iter().filter(present) {
+                    if l2_entry & flags::PTE_HUGE_PAGE != 0 {
+                        count += (PAGE_2M / PAGE_4K) as u64;
+                        continue;
+                    }
+                    let page_table = PageDirectoryTablePtr::from_entry(*l2_entry);
+                    count += 1 + page_table.as_ref().entries.iter().filter(present).count() as u64;
+                }
+            }
+        }
+        count
+    }
+}
+
+/// An upper bound of the number of page tables that mapping a range of `size` bytes can
+/// allocate, each level can be split at both ends of the range
+pub const fn max_page_tables_for(size: u64) -> u64 {
+    let pages = size.div_ceil(PAGE_4K as u64);
+    let page_tables = pages.div_ceil(512) + 1;
+    let page_directories = page_tables.div_ceil(512) + 1;
+    let page_directory_pointers = page_directories.div_ceil(512) + 1;
+    page_tables + page_directories + page_directory_pointers
+}
+
+/// Run self tests on the kernel virtual memory mapper, this will panic on failure
+/// with the parameters of the failing case.
+///
+/// This must be called after `init_kernel_vm` and before any processes are created
+pub fn run_self_tests() {
+    // the most pages the tests below use
+    const SCRATCH_SIZE: u64 = PAGE_4K as u64 * 8;
+
+    println!("Running virtual memory mapper self tests...");
+
+    let scratch = virtual_space::reserve_virtual_space(SCRATCH_SIZE);
+
+    // the page tables created for the scratch range are never freed (they are shared with
+    // every VM cloned later), so create them once before recording the stats
+    selftest_map(scratch, None, SCRATCH_SIZE, flags::PTE_WRITABLE);
+    selftest_unmap(scratch, SCRATCH_SIZE, true);
+
+    let stats_before = physical_page_allocator::stats();
+    let high_stats_before = physical_page_allocator::high_stats();
+
+    selftest_leaf_flags(scratch);
+    selftest_remap(scratch);
+    selftest_split_huge_range();
+    selftest_huge_direct_map();
+    selftest_cloned_vm_kernel_flags();
+    selftest_cow();
+    selftest_huge_user_pages();
+    selftest_accessed_dirty(scratch);
+    selftest_legacy_regions();
+    selftest_kernel_regions();
+
+    let stats_after = physical_page_allocator::stats();
+    // `free_count` and `used_count` are counters of operations, their difference is
+    // what we have free currently
+    assert_eq!(
+        stats_before.0 - stats_before.1,
+        stats_after.0 - stats_after.1,
+        "vm self test: physical pages leaked, stats before={:?}, after={:?}",
+        stats_before,
+        stats_after
+    );
+    // the user pages come from there
+    let high_stats_after = physical_page_allocator::high_stats();
+    assert_eq!(
+        high_stats_before.0 - high_stats_before.1,
+        high_stats_after.0 - high_stats_after.1,
+        "vm self test: high physical pages leaked, stats before={:?}, after={:?}",
+        high_stats_before,
+        high_stats_after
+    );
+
+    virtual_space::release_virtual_space(scratch, SCRATCH_SIZE);
+    println!("Virtual memory mapper self tests passed");
+}
+
+/// Writes to an unmapped page of the low memory, this must end in a page fault panic
+/// naming the legacy region
+pub fn legacy_region_fault_test() -> ! {
+    let region = LEGACY_REGIONS
+        .iter()
+        .find(|region| region.access == LegacyAccess::Unmapped)
+        .expect("no unmapped legacy region");
+    let addr = physical2virtual(region.start) as *mut u8;
+    println!(
+        "Writing to {addr:p}, expecting a page fault in the legacy region `{}`",
+        region.name
+    );
+    // SAFETY: not safe at all, this is unmapped and must fault
+    unsafe { addr.write_volatile(0xAA) };
+    panic!("legacy region fault test: writing to {addr:p} did not fault");
+}
+
+fn selftest_map(virtual_address: u64, physical_address: Option<u64>, size: u64, flags: u64) {
+    KERNEL_VIRTUAL_MEMORY_MANAGER
+        .lock()
+        .map(&VirtualMemoryMapEntry {
+            virtual_address,
+            physical_address,
+            size,
+            flags,
+        });
+}
+
+fn selftest_unmap(virtual_address: u64, size: u64, is_allocated: bool) {
+    KERNEL_VIRTUAL_MEMORY_MANAGER.lock().unmap(
+        &VirtualMemoryMapEntry {
+            virtual_address,
+            physical_address: None,
+            size,
+            // don't remove any flags from the upper levels, they are shared with the whole kernel
+            flags: 0,
+        },
+        is_allocated,
+    );
+}
+
+fn selftest_get_mapping(addr: u64) -> Option<VirtualMemoryMapEntry> {
+    KERNEL_VIRTUAL_MEMORY_MANAGER.lock().get_mapping(addr)
+}
+
+/// Map pages with different flags and check that the last level reports exactly these flags
+fn selftest_leaf_flags(scratch: u64) {
+    const FLAGS_CASES: [u64; 8] = [
+        0,
+        flags::PTE_WRITABLE,
+        flags::PTE_WRITETHROUGH,
+        flags::PTE_NOT_CACHEABLE,
+        flags::PTE_WRITABLE | flags::PTE_WRITETHROUGH,
+        flags::PTE_WRITABLE | flags::PTE_NOT_CACHEABLE,
+        flags::PTE_WRITETHROUGH | flags::PTE_NOT_CACHEABLE,
+        flags::PTE_WRITABLE | flags::PTE_WRITETHROUGH | flags::PTE_NOT_CACHEABLE,
+    ];
+
+    for (i, &flags) in FLAGS_CASES.iter().enumerate() {
+        let addr = scratch + (i * PAGE_4K) as u64;
+        selftest_map(addr, None, PAGE_4K as u64, flags);
+    }
+    for (i, &flags) in FLAGS_CASES.iter().enumerate() {
+        let addr = scratch + (i * PAGE_4K) as u64;
+        let mapping = selftest_get_mapping(addr).unwrap_or_else(|| {
+            panic!("vm self test: page {addr:#X} with flags {flags:#X} is not mapped")
+        });
+        assert_eq!(
+            mapping.flags, flags,
+            "vm self test: page {addr:#X} mapped with flags {flags:#X}, got {:#X}",
+            mapping.flags
+        );
+        assert_eq!(mapping.size, PAGE_4K as u64);
+    }
+    for i in 0..FLAGS_CASES.len() {
+        let addr = scratch + (i * PAGE_4K) as u64;
+        selftest_unmap(addr, PAGE_4K as u64, true);
+        assert!(
+            selftest_get_mapping(addr).is_none(),
+            "vm self test: page {addr:#X} is still mapped after unmap"
+        );
+    }
+}
+
+/// Map, unmap and remap the same page to different physical pages, and make sure
+/// we see the new content (i.e. the TLB was invalidated)
+fn selftest_remap(scratch: u64) {
+    // SAFETY: the allocator is initialized, and we free these at the end
+    let (first, second) = unsafe {
+        (
+            physical_page_allocator::alloc_zeroed(),
+            physical_page_allocator::alloc_zeroed(),
+        )
+    };
+    unsafe {
+        first.write_bytes(0xAA, PAGE_4K);
+        second.write_bytes(0x55, PAGE_4K);
+    }
+    let scratch_ptr = scratch as *mut u8;
+
+    for (page, value) in [(first, 0xAA), (second, 0x55)] {
+        let physical = virtual2physical(page as _) as u64;
+        selftest_map(scratch, Some(physical), PAGE_4K as u64, flags::PTE_WRITABLE);
+        let mapping = selftest_get_mapping(scratch)
+            .unwrap_or_else(|| panic!("vm self test: remap of {scratch:#X} is not mapped"));
+        assert_eq!(
+            mapping.physical_address,
+            Some(physical),
+            "vm self test: remap of {scratch:#X} points to the wrong physical page"
+        );
+
+        // SAFETY: we have just mapped this page
+        let read = unsafe { scratch_ptr.add(PAGE_4K - 1).read_volatile() };
+        assert_eq!(
+            read, value,
+            "vm self test: remap of {scratch:#X} to {physical:#X} read {read:#X}, expected {value:#X}"
+        );
+        // write through the scratch mapping and read back from the kernel mapping
+        unsafe { scratch_ptr.write_volatile(!value) };
+        let read_back = unsafe { page.read_volatile() };
+        assert_eq!(
+            read_back, !value,
+            "vm self test: write through {scratch:#X} is not visible in {physical:#X}"
+        );
+
+        selftest_unmap(scratch, PAGE_4K as u64, false);
+    }
+
+    unsafe {
+        physical_page_allocator::free(first);
+        physical_page_allocator::free(second);
+    }
+}
+
+/// Map 2MB of real memory as a direct map, which is a 2MB page, in a VM that is never loaded,
+/// and unmap a hole in the middle of it. The page must be split into 4K pages of the same
+/// memory and flags, with only the hole missing
+fn selftest_split_huge_range() {
+    const PHYSICAL: u64 = PAGE_2M as u64;
+    const PAGES: u64 = (PAGE_2M / PAGE_4K) as u64;
+    let start = KERNEL_BASE as u64 + PHYSICAL;
+    let hole = start + PAGE_2M as u64 / 2;
+
+    let mut vm = VirtualMemoryMapper::new();
+    vm.map(&VirtualMemoryMapEntry {
+        virtual_address: start,
+        physical_address: Some(PHYSICAL),
+        size: PAGE_2M as u64,
+        flags: flags::PTE_WRITABLE,
+    });
+    let mapping = vm.get_mapping(hole);
+    assert_eq!(
+        mapping.map(|m| (m.virtual_address, m.physical_address, m.size)),
+        Some((start, Some(PHYSICAL), PAGE_2M as u64)),
+        "vm self test: {start:#X} is not mapped with a 2MB page, got {mapping:08X?}"
+    );
+    let huge_tables = vm.page_tables_count();
+
+    vm.unmap(
+        &VirtualMemoryMapEntry {
+            virtual_address: hole,
+            physical_address: None,
+            size: PAGE_4K as u64,
+            flags: 0,
+        },
+        false,
+    );
+    assert_eq!(
+        vm.page_tables_count(),
+        huge_tables + 1,
+        "vm self test: the 2MB page at {start:#X} was not split into a table"
+    );
+    for page in 0..PAGES {
+        let addr = start + page * PAGE_4K as u64;
+        let mapping = vm.get_mapping(addr);
+        if addr == hole {
+            assert!(
+                mapping.is_none(),
+                "vm self test: hole {hole:#X} in {start:#X} is still mapped"
+            );
+            continue;
+        }
+        let mapping = mapping.unwrap_or_else(|| {
+            panic!("vm self test: page {addr:#X} got unmapped with the hole {hole:#X}")
+        });
+        assert_eq!(
+            (
+                mapping.virtual_address,
+                mapping.physical_address,
+                mapping.size
+            ),
+            (addr, Some(PHYSICAL + page * PAGE_4K as u64), PAGE_4K as u64),
+            "vm self test: page {addr:#X} of the split is wrong, got {mapping:08X?}"
+        );
+        assert_eq!(
+            mapping.flags,
+            flags::PTE_WRITABLE,
+            "vm self test: page {addr:#X} flags changed after unmapping the hole {hole:#X}"
+        );
+    }
+    let mut leaves = 0;
+    vm.for_each_present_leaf(start, PAGE_2M as u64, |_, _, _| leaves += 1);
+    assert_eq!(
+        leaves,
+        PAGES - 1,
+        "vm self test: wrong pages after the split"
+    );
+
+    let kernel_l4 = &mut vm.page_map_l4.as_mut().entries[KERNEL_L4_INDEX];
+    // SAFETY: the vm was never loaded
+    unsafe { free_table_tree(*kernel_l4, 4) };
+    *kernel_l4 = 0;
+    unsafe { vm.page_map_l4.free() };
+}
+
+/// Map the first 1GB of memory as a direct map in a VM that is never loaded, with a 1GB page
+/// or 2MB pages depending on the CPU, then unmap a hole in it and map it whole again.
+/// The live kernel direct map must use 2MB pages where it can as well
+fn selftest_huge_direct_map() {
+    let huge_1gb = HUGE_1GB_PAGES.load(Ordering::Relaxed);
+    let largest = if huge_1gb { PAGE_1G } else { PAGE_2M } as u64;
+    let start = KERNEL_BASE as u64;
+    let whole = VirtualMemoryMapEntry {
+        virtual_address: start,
+        physical_address: Some(0),
+        size: PAGE_1G as u64,
+        flags: flags::PTE_WRITABLE,
+    };
+
+    let check_page = |vm: &VirtualMemoryMapper, addr: u64, page_size: u64| {
+        let mapping = vm
+            .get_mapping(addr)
+            .unwrap_or_else(|| panic!("vm self test: direct map page {addr:#X} is not mapped"));
+        let page_start = addr & !(page_size - 1);
+        assert_eq!(
+            (mapping.virtual_address, mapping.size),
+            (page_start, page_size),
+            "vm self test: direct map page {addr:#X} has the wrong size, got {mapping:08X?}"
+        );
+        assert_eq!(
+            mapping.physical_address,
+            Some(page_start - start),
+            "vm self test: direct map page {addr:#X} is not mapped 1:1"
+        );
+        assert_eq!(
+            mapping.flags,
+            flags::PTE_WRITABLE,
+            "vm self test: direct map page {addr:#X} has the wrong flags"
+        );
+    };
+
+    let mut vm = VirtualMemoryMapper::new();
+    vm.map(&whole);
+    // the top level and L3, and the L2 for 2MB pages
+    let whole_tables = vm.page_tables_count();
+    assert_eq!(
+        whole_tables,
+        if huge_1gb { 2 } else { 3 },
+        "vm self test: wrong number of page tables for the direct map"
+    );
+    check_page(
+        &vm,
+        start + PAGE_1G as u64 / 2 + PAGE_4K as u64 * 5,
+        largest,
+    );
+
+    let hole = start + PAGE_1G as u64 / 2 + PAGE_2M as u64 + PAGE_4K as u64 * 3;
+    vm.unmap(
+        &VirtualMemoryMapEntry {
+            virtual_address: hole,
+            physical_address: None,
+            size: PAGE_4K as u64,
+            flags: 0,
+        },
+        false,
+    );
+    assert!(
+        vm.get_mapping(hole).is_none(),
+        "vm self test: direct map hole {hole:#X} is still mapped"
+    );
+    check_page(&vm, hole - PAGE_4K as u64, PAGE_4K as u64);
+    check_page(&vm, hole + PAGE_4K as u64, PAGE_4K as u64);
+    check_page(&vm, hole - PAGE_2M as u64, PAGE_2M as u64);
+    check_page(&vm, start, PAGE_2M as u64);
+    // the L1 of the hole, and the L2 the 1GB page was split into
+    assert_eq!(
+        vm.page_tables_count(),
+        whole_tables + if huge_1gb { 2 } else { 1 },
+        "vm self test: wrong number of page tables after splitting the direct map"
+    );
+
+    // mapping it again replaces the split tables
+    vm.map(&whole);
+    assert_eq!(
+        vm.page_tables_count(),
+        whole_tables,
+        "vm self test: the split direct map tables are not freed when mapped again"
+    );
+    check_page(&vm, hole, largest);
+    let mut leaves = 0;
+    vm.for_each_present_leaf(start, PAGE_1G as u64, |_, _, _| leaves += 1);
+    assert_eq!(leaves, PAGE_1G as u64 / largest);
+
+    let kernel_l4 = &mut vm.page_map_l4.as_mut().entries[KERNEL_L4_INDEX];
+    // SAFETY: the vm was never loaded
+    unsafe { free_table_tree(*kernel_l4, 4) };
+    *kernel_l4 = 0;
+    unsafe { vm.page_map_l4.free() };
+
+    let kernel_end = physical2virtual(KERNEL_MAPPED_SIZE) as u64;
+    let mapping = selftest_get_mapping(kernel_end - PAGE_4K as u64)
+        .expect("vm self test: the end of the kernel direct map is not mapped");
+    assert_eq!(
+        mapping.size, PAGE_2M as u64,
+        "vm self test: the kernel direct map doesn't use 2MB pages"
+    );
+}
+
+/// Touch some of the pages of a range, and make sure exactly these are reported
+/// as accessed/dirty, then harvest again to make sure the bits got cleared
+fn selftest_accessed_dirty(scratch: u64) {
+    const PAGES: usize = 8;
+    const READ_PAGES: [usize; 2] = [1, 3];
+    const WRITTEN_PAGE: usize = 5;
+    let size = (PAGES * PAGE_4K) as u64;
+
+    selftest_map(scratch, None, size, flags::PTE_WRITABLE);
+    // not accessed through this range yet, but just to be sure we start clean
+    KERNEL_VIRTUAL_MEMORY_MANAGER
+        .lock()
+        .harvest_accessed(scratch, size);
+    KERNEL_VIRTUAL_MEMORY_MANAGER
+        .lock()
+        .collect_dirty(scratch, size, true);
+
+    let scratch_ptr = scratch as *mut u8;
+    for page in READ_PAGES {
+        // SAFETY: we have just mapped these pages
+        unsafe { scratch_ptr.add(page * PAGE_4K).read_volatile() };
+    }
+    unsafe { scratch_ptr.add(WRITTEN_PAGE * PAGE_4K).write_volatile(1) };
+
+    let harvest = || {
+        let accessed = KERNEL_VIRTUAL_MEMORY_MANAGER
+            .lock()
+            .harvest_accessed(scratch, size);
+        accessed.iter_ones().collect::<Vec<_>>()
+    };
+    let dirty = |clear| {
+        let dirty = KERNEL_VIRTUAL_MEMORY_MANAGER
+            .lock()
+            .collect_dirty(scratch, size, clear);
+        dirty.iter_ones().collect::<Vec<_>>()
+    };
+
+    let accessed = harvest();
+    assert_eq!(
+        accessed,
+        [READ_PAGES[0], READ_PAGES[1], WRITTEN_PAGE],
+        "vm self test: wrong accessed pages"
+    );
+    assert_eq!(
+        dirty(false),
+        [WRITTEN_PAGE],
+        "vm self test: wrong dirty pages"
+    );
+    let accessed = harvest();
+    assert!(
+        accessed.is_empty(),
+        "vm self test: accessed bits not cleared {accessed:?}"
+    );
+    // not cleared before, so still there
+    assert_eq!(
+        dirty(true),
+        [WRITTEN_PAGE],
+        "vm self test: dirty bit got cleared"
+    );
+    let dirty_after = dirty(false);
+    assert!(
+        dirty_after.is_empty(),
+        "vm self test: dirty bits not cleared {dirty_after:?}"
+    );
+
+    selftest_unmap(scratch, size, true);
+}
+
+/// Map a user range in a cloned VM, and make sure the kernel upper levels don't get `PTE_USER`
+fn selftest_cloned_vm_kernel_flags() {
+    const USER_ADDR: u64 = 0x40_0000;
+
+    let mut vm = clone_current_vm_as_user();
+    vm.map(&VirtualMemoryMapEntry {
+        virtual_address: USER_ADDR,
+        physical_address: None,
+        size: PAGE_4K as u64 * 4,
+        flags: flags::PTE_USER | flags::PTE_WRITABLE,
+    });
+
+    let mapping = vm
+        .get_mapping(USER_ADDR)
+        .expect("vm self test: user page is not mapped in the cloned VM");
+    assert_eq!(mapping.flags, flags::PTE_USER | flags::PTE_WRITABLE);
+
+    let check_kernel_l4 = |vm: &VirtualMemoryMapper, name: &str| {
+        let kernel_l4_entry = vm.page_map_l4.as_ref().entries[KERNEL_L4_INDEX];
+        assert!(
+            kernel_l4_entry & flags::PTE_USER == 0,
+            "vm self test: {name} kernel L4 entry got PTE_USER: {kernel_l4_entry:#X}"
+        );
+        let kernel_l3 = PageDirectoryTablePtr::from_entry(kernel_l4_entry);
+        for (i, entry) in kernel_l3.as_ref().entries.iter().enumerate() {
             assert!(
-                *entry & flags::PTE_HUGE_PAGE == 0,
-                "We haven't implemented 2MB physical pages for user allocation"
+                *entry & flags::PTE_USER == 0,
+                "vm self test: {name} kernel L3[{i}] entry got PTE_USER: {entry:#X}"
+            );
+        }
+    };
+    check_kernel_l4(&vm, "cloned");
+    check_kernel_l4(&KERNEL_VIRTUAL_MEMORY_MANAGER.lock(), "original");
+
+    selftest_free_vm(vm);
+}
+
+/// Frees the pages, then the tables of a VM cloned with [`clone_current_vm_as_user`]
+pub fn selftest_free_vm(mut vm: VirtualMemoryMapper) {
+    vm.unmap_process_memory();
+    let free_table = |entry: &mut u64| {
+        assert!(*entry & flags::PTE_HUGE_PAGE == 0);
+        let table = PageDirectoryTablePtr::from_entry(*entry);
+        unsafe { table.free() };
+        *entry = 0;
+    };
+    let page_map_l4 = vm.page_map_l4.as_mut();
+    for l4_entry in page_map_l4.entries[..NUM_USER_L4_INDEXES]
+        .iter_mut()
+        .filter(|e| **e & flags::PTE_PRESENT != 0)
+    {
+        for l3_entry in PageDirectoryTablePtr::enteries_from_mut_entry(l4_entry)
+            .entries
+            .iter_mut()
+            .filter(|e| **e & flags::PTE_PRESENT != 0)
+        {
+            PageDirectoryTablePtr::enteries_from_mut_entry(l3_entry)
+                .entries
+                .iter_mut()
+                .filter(|e| **e & flags::PTE_PRESENT != 0)
+                .for_each(free_table);
+            free_table(l3_entry);
+        }
+        free_table(l4_entry);
+    }
+    // the kernel L3 table is a copy, so only free it, not the shared tables it points to
+    free_table(&mut page_map_l4.entries[KERNEL_L4_INDEX]);
+    unsafe { vm.page_map_l4.free() };
+}
+
+/// Shares a user page between two VMs, the first write copies it, and the second finds
+/// it alone, so it's only made writable again
+fn selftest_cow() {
+    const USER_ADDR: u64 = 0x40_0000;
+
+    let mut parent = clone_current_vm_as_user();
+    parent.map(&VirtualMemoryMapEntry {
+        virtual_address: USER_ADDR,
+        physical_address: None,
+        size: PAGE_4K as u64 * 2,
+        flags: flags::PTE_USER | flags::PTE_WRITABLE,
+    });
+    // the read-only ones are shared as they are
+    parent.protect(USER_ADDR + PAGE_4K as u64, PAGE_4K as u64, flags::PTE_USER);
+    let mut child = clone_current_vm_as_user();
+    parent.clone_user_memory_cow(&mut child, &[]);
+
+    let physical = |vm: &VirtualMemoryMapper, addr: u64| {
+        let mapping = vm.get_mapping(addr).unwrap();
+        (mapping.physical_address.unwrap(), mapping.flags)
+    };
+    let (shared, _) = physical(&parent, USER_ADDR);
+    for vm in [&parent, &child] {
+        assert_eq!(
+            physical(vm, USER_ADDR),
+            (shared, flags::PTE_USER | flags::PTE_COW)
+        );
+        let (read_only, flags) = physical(vm, USER_ADDR + PAGE_4K as u64);
+        assert_eq!(flags, flags::PTE_USER);
+        assert_eq!(physical_page_allocator::physical_refs(read_only), 2);
+    }
+    assert_eq!(physical_page_allocator::physical_refs(shared), 2);
+    // writable again, but not before it's copied
+    child.protect(
+        USER_ADDR,
+        PAGE_4K as u64,
+        flags::PTE_USER | flags::PTE_WRITABLE,
+    );
+    assert_eq!(
+        physical(&child, USER_ADDR).1,
+        flags::PTE_USER | flags::PTE_COW
+    );
+
+    cpu::cpu().push_cli();
+    let old_vm = get_current_vm();
+    // SAFETY: the VMs are clones of this one, without process specific mappings
+    unsafe { parent.switch_to_this() };
+    assert!(!parent.resolve_cow(USER_ADDR + PAGE_4K as u64));
+    assert!(parent.resolve_cow(USER_ADDR));
+    // SAFETY: the page is mapped and writable now
+    unsafe { (USER_ADDR as *mut u8).write_volatile(0xAB) };
+    unsafe { child.switch_to_this() };
+    // SAFETY: the page is mapped
+    let value = unsafe { (USER_ADDR as *const u8).read_volatile() };
+    assert!(child.resolve_cow(USER_ADDR));
+    assert!(!child.resolve_cow(USER_ADDR));
+    unsafe { old_vm.switch_to_this() };
+    cpu::cpu().pop_cli();
+
+    assert_eq!(value, 0, "vm self test: the write went to the shared page");
+    let (copy, flags) = physical(&parent, USER_ADDR);
+    assert!(copy != shared && flags == flags::PTE_USER | flags::PTE_WRITABLE);
+    assert_eq!(
+        physical(&child, USER_ADDR),
+        (shared, flags::PTE_USER | flags::PTE_WRITABLE)
+    );
+    assert_eq!(physical_page_allocator::physical_refs(shared), 1);
+
+    selftest_free_vm(parent);
+    selftest_free_vm(child);
+}
+
+/// The aligned 2MB of a user allocation is one huge page, unmapping a page in it splits it,
+/// and so does sharing it with a child
+fn selftest_huge_user_pages() {
+    const USER_ADDR: u64 = 0x4000_0000;
+    const PAGE: u64 = PAGE_4K as u64;
+    const HUGE: u64 = PAGE_2M as u64;
+
+    let mut parent = clone_current_vm_as_user();
+    parent.map(&VirtualMemoryMapEntry {
+        virtual_address: USER_ADDR,
+        physical_address: None,
+        size: HUGE * 2 + PAGE,
+        flags: flags::PTE_USER | flags::PTE_WRITABLE,
+    });
+    let mapping = parent.get_mapping(USER_ADDR).unwrap();
+    if mapping.size != HUGE {
+        println!("vm self test: no free 2MB pages after the first 128MB");
+        selftest_free_vm(parent);
+        return;
+    }
+    let first = mapping.physical_address.unwrap();
+    assert!(is_aligned(first as _, PAGE_2M));
+    assert_eq!(parent.get_mapping(USER_ADDR + HUGE * 2).unwrap().size, PAGE);
+
+    let middle = USER_ADDR + HUGE / 2;
+    parent.unmap(
+        &VirtualMemoryMapEntry {
+            virtual_address: middle,
+            physical_address: None,
+            size: PAGE,
+            flags: 0,
+        },
+        true,
+    );
+    assert!(parent.get_mapping(middle).is_none());
+    for addr in [USER_ADDR, middle - PAGE, middle + PAGE] {
+        let mapping = parent.get_mapping(addr).unwrap();
+        assert_eq!(
+            (mapping.size, mapping.physical_address),
+            (PAGE, Some(first + addr - USER_ADDR))
+        );
+    }
+
+    let second = parent.get_mapping(USER_ADDR + HUGE).unwrap();
+    assert_eq!(second.size, HUGE);
+    let second = second.physical_address.unwrap();
+    let mut child = clone_current_vm_as_user();
+    parent.clone_user_memory_cow(&mut child, &[]);
+    for addr in [USER_ADDR + HUGE, USER_ADDR + HUGE * 2 - PAGE] {
+        let physical = second + addr - USER_ADDR - HUGE;
+        for vm in [&parent, &child] {
+            let mapping = vm.get_mapping(addr).unwrap();
+            assert_eq!(
+                (mapping.physical_address, mapping.size, mapping.flags),
+                (Some(physical), PAGE, flags::PTE_USER | flags::PTE_COW)
+            );
+        }
+        assert_eq!(physical_page_allocator::physical_refs(physical), 2);
+    }
+    assert!(child.get_mapping(middle).is_none());
+
+    selftest_free_vm(parent);
+    selftest_free_vm(child);
+}
+
+/// The kernel `.text` is read-only, `.rodata` is read-only and not executable (if the CPU can),
+/// its data, the rest of the direct map and the memory before it (where `kaslr` moved it from)
+/// are writable and not executable, all of them mapped 1:1 except for the stack guard page. The
+/// heap and the kernel can't write to read-only pages
+fn selftest_kernel_regions() {
+    let rodata_start = align_up(kernel_text_end(), PAGE_4K) as u64;
+    let data_start = align_up(kernel_elf_rodata_end(), PAGE_4K) as u64;
+    let data_end = physical2virtual(KERNEL_MAPPED_SIZE) as u64;
+    let guard = stack_guard_page_ptr() as u64;
+    let vm = current_vm_unlocked();
+
+    // `name` from `start` is all mapped, except `hole`, the ranges are checked by `check`
+    let check_region =
+        |name: &str, start: u64, end: u64, hole: u64, check: &dyn Fn(u64) -> bool| {
+            let mut mapped = 0;
+            vm.for_each_mapped_range(start, end - start, |range| {
+                assert!(
+                    check(range.flags),
+                    "vm self test: kernel {name} range has the wrong flags, got {range:08X?}"
+                );
+                assert_eq!(
+                    range.physical_address,
+                    Some(virtual2physical(range.virtual_address as usize) as u64),
+                    "vm self test: kernel {name} range is not mapped 1:1, got {range:08X?}"
+                );
+                mapped += range
+                    .virtual_address
+                    .max(start)
+                    .abs_diff((range.virtual_address + range.size).min(end));
+            });
+            assert_eq!(
+                mapped,
+                end - start - hole,
+                "vm self test: kernel {name} is not all mapped"
             );
-            let page_table_ptr = PageDirectoryTablePtr::from_entry(*entry);
-            unsafe { page_table_ptr.free() };
-            *entry = 0;
         };
+    let permissions =
+        |flags: u64| flags & (flags::PTE_WRITABLE | flags::PTE_USER | flags::PTE_NO_EXECUTE);
+    let kernel_start = kernel_elf_start() as u64;
+    if kernel_start != KERNEL_LINK as u64 {
+        check_region("before", KERNEL_LINK as u64, kernel_start, 0, &|flags| {
+            permissions(flags) == flags::PTE_WRITABLE | no_execute()
+        });
+    }
+    check_region("text", kernel_start, rodata_start, 0, &|flags| {
+        permissions(flags) == 0
+    });
+    check_region("rodata", rodata_start, data_start, 0, &|flags| {
+        permissions(flags) == no_execute()
+    });
+    check_region("data", data_start, data_end, PAGE_4K as u64, &|flags| {
+        permissions(flags) == flags::PTE_WRITABLE | no_execute()
+    });
+    let heap = alloc::boxed::Box::new(0u64);
+    let heap_mapping = vm.get_mapping(&raw const *heap as u64).unwrap();
+    assert_eq!(
+        permissions(heap_mapping.flags),
+        flags::PTE_WRITABLE | no_execute(),
+        "vm self test: the kernel heap has the wrong flags, got {heap_mapping:08X?}"
+    );
+    assert!(
+        unsafe { cpu::get_cr0() } & CR0_WP != 0,
+        "vm self test: the kernel can write to read-only pages"
+    );
+
+    assert!(
+        vm.get_mapping(guard).is_none(),
+        "vm self test: the stack guard page {guard:#X} is mapped"
+    );
+    // what must be where
+    let text = run_self_tests as *const () as u64;
+    assert!(text < kernel_text_end() as u64 && rodata_start <= data_start);
+    let data = &raw const KERNEL_VIRTUAL_MEMORY_MANAGER as u64;
+    assert!((data_start..data_end).contains(&data) && (data_start..data_end).contains(&guard));
+}
 
-        self.do_for_every_user_entry(free_page);
-        self.do_for_kernel_process_entry(free_page);
+/// The low memory must be mapped exactly as the legacy regions table says
+fn selftest_legacy_regions() {
+    let mut expected_start = 0;
+    for region in LEGACY_REGIONS {
+        assert_eq!(
+            region.start, expected_start,
+            "vm self test: legacy region {region:X?} doesn't follow the previous one"
+        );
+        assert!(
+            region.start < region.end
+                && is_aligned(region.start, PAGE_4K)
+                && is_aligned(region.end, PAGE_4K),
+            "vm self test: legacy region {region:X?} is not page aligned"
+        );
+        expected_start = region.end;
+
+        for physical in (region.start..region.end).step_by(PAGE_4K) {
+            let addr = physical2virtual(physical) as u64;
+            let mapping = selftest_get_mapping(addr);
+            let writable = mapping.is_some_and(|m| m.flags & flags::PTE_WRITABLE != 0);
+            let ok = match region.access {
+                LegacyAccess::Unmapped => mapping.is_none(),
+                LegacyAccess::ReadOnly => mapping.is_some() && !writable,
+                LegacyAccess::Writable => writable,
+                // depends on if the CPUs are started already
+                LegacyAccess::WritableOnBoot => mapping.is_some(),
+                LegacyAccess::Allocatable => writable,
+            };
+            assert!(
+                ok,
+                "vm self test: page {addr:#X} of legacy region `{}` should be {:?}, got {mapping:08X?}",
+                region.name, region.access
+            );
+            if let Some(mapping) = mapping {
+                assert_eq!(
+                    mapping.physical_address,
+                    Some(physical as u64),
+                    "vm self test: page {addr:#X} of legacy region `{}` is not mapped 1:1",
+                    region.name
+                );
+            }
+        }
     }
+    assert_eq!(
+        expected_start, EXTENDED_OFFSET,
+        "vm self test: legacy regions don't cover the low memory"
+    );
 }
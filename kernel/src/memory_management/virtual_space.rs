@@ -23,7 +23,8 @@ pub fn get_virtual_for_physical(physical_start: u64, size: u64) -> u64 {
         virtual_address: virtual_addr,
         physical_address: Some(aligned_start as u64),
         size: size as _,
-        flags: virtual_memory_mapper::flags::PTE_WRITABLE,
+        flags: virtual_memory_mapper::flags::PTE_WRITABLE
+            | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
     });
     // to make sure no one else play around with the space while we are mapping it
     drop(allocator);
@@ -44,7 +45,8 @@ pub fn ensure_at_least_size(virtual_start: u64, size: u64) {
                 virtual_address: virtual_start,
                 physical_address: Some(physical_addr),
                 size: size as _,
-                flags: virtual_memory_mapper::flags::PTE_WRITABLE,
+                flags: virtual_memory_mapper::flags::PTE_WRITABLE
+                    | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
             });
         }
     } else {
@@ -54,6 +56,27 @@ pub fn ensure_at_least_size(virtual_start: u64, size: u64) {
 }
 
 pub fn allocate_and_map_virtual_space(physical_start: u64, size: u64) -> u64 {
+    allocate_and_map(
+        physical_start,
+        size,
+        virtual_memory_mapper::flags::PTE_WRITABLE | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+    )
+}
+
+/// Same as `allocate_and_map_virtual_space`, but the memory is mapped uncacheable, this must
+/// be used for device registers, see [`crate::sync::barrier`]
+pub fn allocate_and_map_mmio(physical_start: u64, size: u64) -> u64 {
+    allocate_and_map(
+        physical_start,
+        size,
+        virtual_memory_mapper::flags::PTE_WRITABLE
+            | virtual_memory_mapper::flags::PTE_NOT_CACHEABLE
+            | virtual_memory_mapper::flags::PTE_WRITETHROUGH
+            | virtual_memory_mapper::flags::PTE_NO_EXECUTE,
+    )
+}
+
+fn allocate_and_map(physical_start: u64, size: u64, flags: u64) -> u64 {
     let (aligned_start, size, offset) = align_range(physical_start as _, size as _, PAGE_4K);
 
     let mut allocator = VIRTUAL_SPACE_ALLOCATOR.lock();
@@ -63,7 +86,7 @@ pub fn allocate_and_map_virtual_space(physical_start: u64, size: u64) -> u64 {
         virtual_address: virtual_addr,
         physical_address: Some(aligned_start as u64),
         size: size as _,
-        flags: virtual_memory_mapper::flags::PTE_WRITABLE,
+        flags,
     });
     // to make sure no one else play around with the space while we are mapping it
     drop(allocator);
@@ -71,7 +94,6 @@ pub fn allocate_and_map_virtual_space(physical_start: u64, size: u64) -> u64 {
     virtual_addr + offset as u64
 }
 
-#[allow(dead_code)]
 pub fn deallocate_virtual_space(virtual_start: u64, size: u64) {
     let (aligned_start, size, _) = align_range(virtual_start as _, size as _, PAGE_4K);
 
@@ -91,6 +113,23 @@ pub fn deallocate_virtual_space(virtual_start: u64, size: u64) {
     drop(allocator);
 }
 
+/// Reserve a virtual range in the kernel extra space without mapping it,
+/// the caller is responsible for mapping and unmapping the range.
+pub fn reserve_virtual_space(size: u64) -> u64 {
+    let (_, size, _) = align_range(0, size as _, PAGE_4K);
+
+    let mut allocator = VIRTUAL_SPACE_ALLOCATOR.lock();
+    allocator.reserve(size as u64)
+}
+
+/// Release a range reserved by `reserve_virtual_space`, the range must be unmapped by now
+pub fn release_virtual_space(virtual_start: u64, size: u64) {
+    let (aligned_start, size, _) = align_range(virtual_start as _, size as _, PAGE_4K);
+
+    let mut allocator = VIRTUAL_SPACE_ALLOCATOR.lock();
+    allocator.deallocate(aligned_start as u64, size as u64);
+}
+
 pub fn debug_blocks() {
     let allocator = VIRTUAL_SPACE_ALLOCATOR.lock();
     allocator.debug_blocks();
@@ -100,6 +139,8 @@ struct VirtualSpaceEntry {
     physical_start: Option<u64>,
     virtual_start: u64,
     size: u64,
+    // an entry can be taken without a physical address if its reserved
+    taken: bool,
 }
 
 struct VirtualSpaceAllocator {
@@ -186,12 +227,13 @@ impl VirtualSpaceAllocator {
                     // try to allocate from the next entry only if it is not allocated
                     let current = cursor.remove_current().unwrap();
                     if let Some(next_entry) = cursor.current() {
-                        if next_entry.physical_start.is_none() {
+                        if !next_entry.taken {
                             // next is not taken, take part of it
                             let new_entry = VirtualSpaceEntry {
                                 physical_start: Some(current_phy_start),
                                 virtual_start: current.virtual_start,
                                 size: new_size,
+                                taken: true,
                             };
                             next_entry.size -= addition_size;
                             next_entry.virtual_start += addition_size;
@@ -214,14 +256,22 @@ impl VirtualSpaceAllocator {
     }
 
     fn allocate(&mut self, phy_start: u64, size: u64) -> u64 {
-        assert!(size > 0);
         assert!(is_aligned(phy_start as _, PAGE_4K));
+        self.allocate_entry(Some(phy_start), size)
+    }
+
+    fn reserve(&mut self, size: u64) -> u64 {
+        self.allocate_entry(None, size)
+    }
+
+    fn allocate_entry(&mut self, phy_start: Option<u64>, size: u64) -> u64 {
+        assert!(size > 0);
         assert!(is_aligned(size as _, PAGE_4K));
 
         let mut cursor = self.entries.cursor_front_mut();
         // find largest fitting entry and allocate from it
         while let Some(entry) = cursor.current() {
-            if entry.physical_start.is_none() && entry.size >= size {
+            if !entry.taken && entry.size >= size {
                 // found it, split into two, and add to the list
 
                 // the new entry (after this)
@@ -229,10 +279,12 @@ impl VirtualSpaceAllocator {
                     physical_start: None,
                     virtual_start: entry.virtual_start + size,
                     size: entry.size - size,
+                    taken: false,
                 };
                 // shrink this entry
                 entry.size = size;
-                entry.physical_start = Some(phy_start);
+                entry.physical_start = phy_start;
+                entry.taken = true;
                 let virtual_address = entry.virtual_start;
 
                 // add the new entry
@@ -248,8 +300,9 @@ impl VirtualSpaceAllocator {
                 physical_start: None,
                 virtual_start: KERNEL_EXTRA_MEMORY_BASE as u64,
                 size: KERNEL_EXTRA_MEMORY_SIZE as u64,
+                taken: false,
             });
-            self.allocate(phy_start, size)
+            self.allocate_entry(phy_start, size)
         } else {
             panic!("Out of virtual space");
         }
@@ -273,8 +326,9 @@ impl VirtualSpaceAllocator {
                 }
 
                 // found it, deallocate it
-                assert!(entry.physical_start.is_some());
+                assert!(entry.taken);
                 entry.physical_start = None;
+                entry.taken = false;
 
                 // try to merge with after and before
                 // extract the current so we can play around with values easily
@@ -282,7 +336,7 @@ impl VirtualSpaceAllocator {
 
                 // merge with next
                 if let Some(next_entry) = cursor.current() {
-                    if next_entry.physical_start.is_none() {
+                    if !next_entry.taken {
                         // merge with next
                         current.size += next_entry.size;
                         // here `cursor` is pointing to `next_entry`
@@ -293,7 +347,7 @@ impl VirtualSpaceAllocator {
                 cursor.move_prev();
                 // merge with prev
                 if let Some(prev_entry) = cursor.current() {
-                    if prev_entry.physical_start.is_none() {
+                    if !prev_entry.taken {
                         // merge with prev
                         prev_entry.size += current.size;
                         // no need to remove the `current` since its already removed
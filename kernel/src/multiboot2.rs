@@ -1,13 +1,21 @@
-use core::{ffi, fmt, mem};
+use core::{
+    ffi, fmt, mem,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{vec, vec::Vec};
 
 use crate::{
     acpi::tables::{Rsdp, RsdpV1, RsdpV2},
-    io::NoDebug,
-    memory_management::memory_layout::{align_up, MemSize, PAGE_4K},
+    io::{HexArray, NoDebug},
+    memory_management::memory_layout::{
+        align_up, MemSize, EXTENDED_OFFSET, KERNEL_BASE, KERNEL_END, PAGE_4K,
+    },
 };
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryMapType {
     Available = 1,
     Reserved = 2,
@@ -17,7 +25,7 @@ pub enum MemoryMapType {
     Undefined(u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct MemoryMap {
     pub base_addr: u64,
     pub length: u64,
@@ -330,6 +338,18 @@ pub struct BasicMemoryInfo {
     mem_upper: u32,
 }
 
+impl BasicMemoryInfo {
+    /// The conventional memory, from 0, in KB
+    pub fn lower_kb(&self) -> u32 {
+        self.mem_lower
+    }
+
+    /// The memory from 1MB until the first hole, in KB
+    pub fn upper_kb(&self) -> u32 {
+        self.mem_upper
+    }
+}
+
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
 pub struct AdvancedPowerManagementTable {
@@ -344,6 +364,22 @@ pub struct AdvancedPowerManagementTable {
     dseg_len: u16,
 }
 
+/// A file loaded by the bootloader along with the kernel, like an initrd
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule<'a> {
+    /// Physical address of the first byte
+    pub start: u32,
+    /// Physical address after the last byte
+    pub end: u32,
+    pub cmdline: &'a str,
+}
+
+impl BootModule<'_> {
+    pub fn size(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MultiBootTag<'a> {
     BootCommandLine {
@@ -352,6 +388,7 @@ pub enum MultiBootTag<'a> {
     BootLoaderName {
         name: &'a str,
     },
+    Module(BootModule<'a>),
     BasicMemoryInfo(&'a BasicMemoryInfo),
     AdvancedPowerManagementTable(&'a AdvancedPowerManagementTable),
     ImageLoadBasePhysical {
@@ -371,11 +408,19 @@ pub enum MultiBootTag<'a> {
     Efi64SystemTablePtr {
         ptr: u64,
     },
+    SmBiosTables {
+        major: u8,
+        minor: u8,
+        tables: HexArray<&'a [u8]>,
+    },
     EfiBootServicesNotTerminated,
     Efi64ImageHandle {
         ptr: u64,
     },
     VbeInfo(&'a VbeInfo),
+    Unknown {
+        ty: u32,
+    },
 }
 
 pub struct MultiBootTagIter<'a> {
@@ -415,6 +460,17 @@ impl<'a> Iterator for MultiBootTagIter<'a> {
                 let name = unsafe { ffi::CStr::from_ptr(str_ptr).to_str().expect("invalid utf8") };
                 MultiBootTag::BootLoaderName { name }
             }
+            3 => {
+                let range = unsafe { core::slice::from_raw_parts(ptr.add(1) as *const u32, 2) };
+                let str_ptr = unsafe { (ptr.add(1) as *const u32).add(2) as *const i8 };
+                let cmdline =
+                    unsafe { ffi::CStr::from_ptr(str_ptr).to_str().expect("invalid utf8") };
+                MultiBootTag::Module(BootModule {
+                    start: range[0],
+                    end: range[1],
+                    cmdline,
+                })
+            }
             4 => {
                 let tag = unsafe { &*(ptr.add(1) as *const BasicMemoryInfo) };
                 MultiBootTag::BasicMemoryInfo(tag)
@@ -475,6 +531,17 @@ impl<'a> Iterator for MultiBootTagIter<'a> {
                 let efi64_ptr = unsafe { &*(ptr.add(1) as *const u64) };
                 MultiBootTag::Efi64SystemTablePtr { ptr: *efi64_ptr }
             }
+            13 => {
+                let data = unsafe { ptr.add(1) as *const u8 };
+                // major, minor, then 6 reserved bytes
+                let tables_len = tag.size as usize - mem::size_of::<MultiBootTagRaw>() - 8;
+                let tables = unsafe { core::slice::from_raw_parts(data.add(8), tables_len) };
+                MultiBootTag::SmBiosTables {
+                    major: unsafe { *data },
+                    minor: unsafe { *data.add(1) },
+                    tables: HexArray(tables),
+                }
+            }
             14 => {
                 let old_rsdp = unsafe { &*(ptr.add(1) as *const RsdpV1) };
                 assert!(
@@ -515,12 +582,442 @@ impl<'a> Iterator for MultiBootTagIter<'a> {
                 let tag = unsafe { &*(ptr.add(1) as *const u32) };
                 MultiBootTag::ImageLoadBasePhysical { base_addr: *tag }
             }
-            t => unimplemented!("tag {t}"),
+            // the spec says to skip the tags we don't know
+            ty => MultiBootTag::Unknown { ty },
+        };
+        Some(tag)
+    }
+}
+
+/// The largest structure the kernel keeps a copy of, see [`MultiBoot2Info::copy_from_ptr`]
+const MAX_INFO_SIZE: usize = 64 * 1024;
+/// The longest string of a tag, with its null terminator
+const MAX_STRING_LEN: usize = 4096;
+/// The type of [`MemoryMapType::Available`] in the memory map entries
+const MEMORY_MAP_AVAILABLE: u32 = 1;
+
+#[repr(C, align(8))]
+struct InfoCopy([u8; MAX_INFO_SIZE]);
+
+/// The validated structure, used instead of the one the bootloader left in the memory, which
+/// can be given to the allocator
+static mut INFO_COPY: InfoCopy = InfoCopy([0; MAX_INFO_SIZE]);
+static INFO_COPIED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiBootError {
+    NotAligned(usize),
+    /// The info is not inside the memory mapped by `boot.S`,
+    /// which could mean that the pointer is garbage
+    OutOfMappedMemory(usize),
+    InvalidSize(u32),
+    /// The tags go outside `total_size`, or don't end with the end tag
+    InvalidTags,
+    /// Larger than [`MAX_INFO_SIZE`], the copy we keep
+    TooLarge(u32),
+    /// A field of the tag at `offset` from the start of the structure is wrong
+    InvalidField {
+        field: &'static str,
+        offset: usize,
+        problem: FieldProblem,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldProblem {
+    /// The tag is smaller than its fields
+    Truncated {
+        size: u32,
+        min: usize,
+    },
+    /// No null terminator inside the tag or in the first [`MAX_STRING_LEN`] bytes
+    Unterminated,
+    NotUtf8,
+    /// Ends before it starts, or wraps around
+    InvalidRange {
+        start: u64,
+        end: u64,
+    },
+    /// Not inside the memory of the memory map or the basic memory info
+    OutsideMemory {
+        start: u64,
+        end: u64,
+    },
+    EntrySize(u32),
+    /// The entries don't fill the tag, there are `remaining` bytes after the last one
+    PartialEntry {
+        remaining: usize,
+    },
+    UnknownType(u8),
+    /// The lines are shorter than `width` pixels of `bpp` bits
+    Pitch {
+        pitch: u32,
+        width: u32,
+        bpp: u8,
+    },
+}
+
+impl fmt::Display for FieldProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { size, min } => {
+                write!(f, "the tag is {size} bytes, needs at least {min}")
+            }
+            Self::Unterminated => write!(f, "no null terminator in {MAX_STRING_LEN} bytes"),
+            Self::NotUtf8 => write!(f, "not valid utf8"),
+            Self::InvalidRange { start, end } => write!(f, "invalid range {start:#x}..{end:#x}"),
+            Self::OutsideMemory { start, end } => {
+                write!(f, "{start:#x}..{end:#x} is outside the physical memory")
+            }
+            Self::EntrySize(size) => write!(f, "invalid entry size {size}"),
+            Self::PartialEntry { remaining } => {
+                write!(f, "{remaining} bytes after the last entry")
+            }
+            Self::UnknownType(ty) => write!(f, "unknown type {ty}"),
+            Self::Pitch { pitch, width, bpp } => {
+                write!(
+                    f,
+                    "pitch {pitch} is too small for {width} pixels of {bpp} bits"
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for MultiBootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAligned(addr) => write!(f, "the pointer {addr:#x} is not 8 bytes aligned"),
+            Self::OutOfMappedMemory(addr) => {
+                write!(f, "the pointer {addr:#x} is outside the mapped memory")
+            }
+            Self::InvalidSize(size) => write!(f, "invalid total_size {size}"),
+            Self::InvalidTags => write!(f, "the tags don't end with the end tag at total_size"),
+            Self::TooLarge(size) => {
+                write!(f, "total_size {size} is larger than {MAX_INFO_SIZE} bytes")
+            }
+            Self::InvalidField {
+                field,
+                offset,
+                problem,
+            } => write!(f, "{field} (tag at {offset:#x}): {problem}"),
+        }
+    }
+}
+
+/// A tag header and its data, of a structure whose tags are inside it
+#[derive(Clone, Copy)]
+struct RawTag<'a> {
+    ty: u32,
+    size: u32,
+    offset: usize,
+    data: &'a [u8],
+}
+
+impl RawTag<'_> {
+    fn error(&self, field: &'static str, problem: FieldProblem) -> MultiBootError {
+        MultiBootError::InvalidField {
+            field,
+            offset: self.offset,
+            problem,
+        }
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.data[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The null terminated string at `offset`, to the end of the tag
+    fn string_at(&self, field: &'static str, offset: usize) -> Result<&str, MultiBootError> {
+        let bytes = &self.data[offset..];
+        let bytes = &bytes[..bytes.len().min(MAX_STRING_LEN)];
+        let len = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(self.error(field, FieldProblem::Unterminated))?;
+        core::str::from_utf8(&bytes[..len]).map_err(|_| self.error(field, FieldProblem::NotUtf8))
+    }
+
+    /// The entries of a memory map tag after its `entry_size` and version, checking that
+    /// they are at least `min_entry` bytes and fill the tag
+    fn entries(
+        &self,
+        field: &'static str,
+        min_entry: usize,
+    ) -> Result<impl Iterator<Item = &[u8]> + Clone, MultiBootError> {
+        let entry_size = self.u32_at(0);
+        if (entry_size as usize) < min_entry || !entry_size.is_multiple_of(8) {
+            return Err(self.error(field, FieldProblem::EntrySize(entry_size)));
+        }
+        let entries = &self.data[mem::size_of::<MemoryMapTagRaw>()..];
+        let remaining = entries.len() % entry_size as usize;
+        if remaining != 0 {
+            return Err(self.error(field, FieldProblem::PartialEntry { remaining }));
+        }
+        Ok(entries.chunks_exact(entry_size as usize))
+    }
+}
+
+/// The tags of `info`, which must have passed the walk of `validate_structure`
+fn raw_tags(info: &[u8]) -> impl Iterator<Item = RawTag<'_>> {
+    let mut offset = mem::size_of::<MultiBoot2Info>();
+    core::iter::from_fn(move || {
+        let header = &info[offset..offset + mem::size_of::<MultiBootTagRaw>()];
+        let ty = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if ty == 0 {
+            return None;
+        }
+        let tag = RawTag {
+            ty,
+            size,
+            offset,
+            data: &info[offset + header.len()..offset + size as usize],
         };
+        offset += align_up(size as usize, 8);
         Some(tag)
+    })
+}
+
+/// Walks the tags without parsing them, to be sure we never go outside the structure
+fn validate_structure(info: &[u8]) -> Result<(), MultiBootError> {
+    let header_size = mem::size_of::<MultiBoot2Info>();
+    let end = info.len();
+    let mut current = header_size;
+    loop {
+        if end - current < mem::size_of::<MultiBootTagRaw>() {
+            return Err(MultiBootError::InvalidTags);
+        }
+        let ty = u32::from_le_bytes(info[current..current + 4].try_into().unwrap());
+        let size = u32::from_le_bytes(info[current + 4..current + 8].try_into().unwrap());
+        let tag_size = align_up(size as usize, 8);
+        if (size as usize) < mem::size_of::<MultiBootTagRaw>() || end - current < tag_size {
+            return Err(MultiBootError::InvalidTags);
+        }
+        current += tag_size;
+        if ty == 0 {
+            break;
+        }
+    }
+    if current != end {
+        return Err(MultiBootError::InvalidTags);
+    }
+    Ok(())
+}
+
+/// The size of the fields of each tag we parse, after the tag header
+fn min_tag_data(ty: u32) -> usize {
+    match ty {
+        // the null terminator
+        1 | 2 => 1,
+        3 => 8 + 1,
+        4 => mem::size_of::<BasicMemoryInfo>(),
+        5 => 12,
+        6 | 17 => mem::size_of::<MemoryMapTagRaw>(),
+        7 => mem::size_of::<VbeInfo>(),
+        8 => mem::size_of::<FramebufferRaw>(),
+        9 | 21 => 4,
+        10 => mem::size_of::<AdvancedPowerManagementTable>(),
+        12 | 20 => 8,
+        // major, minor, then 6 reserved bytes
+        13 => 8,
+        14 => mem::size_of::<RsdpV1>(),
+        15 => mem::size_of::<RsdpV2>(),
+        _ => 0,
+    }
+}
+
+/// Where the physical memory is, to check the ranges of the other tags against, from the
+/// memory map, or the basic memory info without it. `None` if there is neither, the memory
+/// sizes come from somewhere else then (see `boot_memory`), and the ranges are not checked
+fn physical_memory(info: &[u8]) -> Result<Option<PhysicalMemory<'_>>, MultiBootError> {
+    let mut basic = None;
+    for tag in raw_tags(info) {
+        match tag.ty {
+            4 => basic = Some(tag),
+            6 => {
+                let entries = tag.entries("memory map", mem::size_of::<MemoryMapsRaw>())?;
+                for entry in entries.clone() {
+                    let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+                    let length = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+                    if base.checked_add(length).is_none() {
+                        return Err(tag.error(
+                            "memory map entry",
+                            FieldProblem::InvalidRange {
+                                start: base,
+                                end: base.wrapping_add(length),
+                            },
+                        ));
+                    }
+                }
+                return Ok(Some(PhysicalMemory::Map(tag)));
+            }
+            _ => {}
+        }
+    }
+    let Some(tag) = basic else {
+        return Ok(None);
+    };
+    let lower_kb = tag.u32_at(0);
+    // the conventional memory can't pass 1MB
+    if lower_kb as u64 * 1024 > EXTENDED_OFFSET as u64 {
+        return Err(tag.error(
+            "basic memory info mem_lower",
+            FieldProblem::InvalidRange {
+                start: 0,
+                end: lower_kb as u64 * 1024,
+            },
+        ));
+    }
+    Ok(Some(PhysicalMemory::Basic {
+        lower_end: lower_kb as u64 * 1024,
+        upper_end: EXTENDED_OFFSET as u64 + tag.u32_at(4) as u64 * 1024,
+    }))
+}
+
+enum PhysicalMemory<'a> {
+    /// The memory map tag, validated
+    Map(RawTag<'a>),
+    Basic {
+        lower_end: u64,
+        upper_end: u64,
+    },
+}
+
+impl PhysicalMemory<'_> {
+    fn contains(&self, start: u64, end: u64) -> bool {
+        match self {
+            Self::Map(tag) => tag
+                .entries("", 0)
+                .into_iter()
+                .flatten()
+                .filter(|entry| entry[16..20] == MEMORY_MAP_AVAILABLE.to_le_bytes())
+                .any(|entry| {
+                    let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+                    let length = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+                    base <= start && end <= base + length
+                }),
+            Self::Basic {
+                lower_end,
+                upper_end,
+            } => end <= *lower_end || (EXTENDED_OFFSET as u64 <= start && end <= *upper_end),
+        }
     }
 }
 
+/// Checks the fields of every tag we parse: the tags are large enough for their fields, the
+/// strings end inside them, the entries of the memory maps fill them, and the modules are in
+/// the physical memory. The modules are 32-bit addresses, so they are always below 4GB.
+///
+/// The framebuffer is device memory, so it is not in the memory, its range is only checked
+/// to not wrap around. The addresses of the EFI tables are not checked, we don't use them.
+fn validate(info: &[u8]) -> Result<(), MultiBootError> {
+    validate_structure(info)?;
+    for tag in raw_tags(info) {
+        let min = min_tag_data(tag.ty);
+        if tag.data.len() < min {
+            return Err(tag.error(
+                "tag size",
+                FieldProblem::Truncated {
+                    size: tag.size,
+                    min: min + mem::size_of::<MultiBootTagRaw>(),
+                },
+            ));
+        }
+    }
+    let memory = physical_memory(info)?;
+
+    for tag in raw_tags(info) {
+        match tag.ty {
+            1 => {
+                tag.string_at("command line", 0)?;
+            }
+            2 => {
+                tag.string_at("bootloader name", 0)?;
+            }
+            3 => {
+                let (start, end) = (tag.u32_at(0) as u64, tag.u32_at(4) as u64);
+                tag.string_at("module cmdline", 8)?;
+                if start > end {
+                    return Err(tag.error(
+                        "module start..end",
+                        FieldProblem::InvalidRange { start, end },
+                    ));
+                }
+                if memory
+                    .as_ref()
+                    .is_some_and(|memory| !memory.contains(start, end))
+                {
+                    return Err(tag.error(
+                        "module start..end",
+                        FieldProblem::OutsideMemory { start, end },
+                    ));
+                }
+            }
+            8 => {
+                let addr = tag.u64_at(0);
+                let (pitch, width, height) = (tag.u32_at(8), tag.u32_at(12), tag.u32_at(16));
+                let (bpp, ty) = (tag.data[20], tag.data[21]);
+                let color_info_len = tag.data.len() - mem::size_of::<FramebufferRaw>();
+                let min_color_info = match ty {
+                    0 => 4,
+                    1 => 6,
+                    2 => 0,
+                    _ => return Err(tag.error("framebuffer type", FieldProblem::UnknownType(ty))),
+                };
+                if color_info_len < min_color_info {
+                    return Err(tag.error(
+                        "framebuffer color info",
+                        FieldProblem::Truncated {
+                            size: tag.size,
+                            min: tag.size as usize - color_info_len + min_color_info,
+                        },
+                    ));
+                }
+                if (pitch as u64) * 8 < width as u64 * bpp as u64 {
+                    return Err(tag.error(
+                        "framebuffer pitch",
+                        FieldProblem::Pitch { pitch, width, bpp },
+                    ));
+                }
+                let size = pitch as u64 * height as u64;
+                if addr.checked_add(size).is_none() {
+                    return Err(tag.error(
+                        "framebuffer addr",
+                        FieldProblem::InvalidRange {
+                            start: addr,
+                            end: addr.wrapping_add(size),
+                        },
+                    ));
+                }
+            }
+            14 | 15 => {
+                // must be exactly the table, its parsed as one
+                let expected = min_tag_data(tag.ty);
+                if tag.data.len() != expected {
+                    return Err(tag.error(
+                        "rsdp",
+                        FieldProblem::Truncated {
+                            size: tag.size,
+                            min: expected + mem::size_of::<MultiBootTagRaw>(),
+                        },
+                    ));
+                }
+            }
+            17 => {
+                tag.entries("efi memory map", mem::size_of::<EfiMemoryMapsRaw>())
+                    .map(drop)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 #[repr(C, packed(4))]
 pub struct MultiBoot2Info {
     total_size: u32,
@@ -528,6 +1025,116 @@ pub struct MultiBoot2Info {
 }
 
 impl MultiBoot2Info {
+    /// Validates the structure passed by the bootloader before using it.
+    ///
+    /// This doesn't need the console or the heap, so it can be used before anything else.
+    /// It only checks the tags are inside the structure, the kernel uses the copy of
+    /// [`MultiBoot2Info::copy_from_ptr`], which checks the fields too.
+    pub fn from_ptr(ptr: *const MultiBoot2Info) -> Result<&'static Self, MultiBootError> {
+        let addr = ptr as usize;
+        if !addr.is_multiple_of(8) {
+            return Err(MultiBootError::NotAligned(addr));
+        }
+        let header_size = mem::size_of::<MultiBoot2Info>();
+        if !(KERNEL_BASE..KERNEL_END - header_size).contains(&addr) {
+            return Err(MultiBootError::OutOfMappedMemory(addr));
+        }
+        // SAFETY: the header is inside the mapped memory
+        let total_size = unsafe { (*ptr).total_size };
+        // must at least have the end tag
+        if (total_size as usize) < header_size + mem::size_of::<MultiBootTagRaw>()
+            || !total_size.is_multiple_of(8)
+            || KERNEL_END - addr < total_size as usize
+        {
+            return Err(MultiBootError::InvalidSize(total_size));
+        }
+        // SAFETY: the structure is inside the mapped memory
+        let info = unsafe { core::slice::from_raw_parts(ptr as *const u8, total_size as usize) };
+        validate_structure(info)?;
+
+        // SAFETY: validated above
+        Ok(unsafe { &*ptr })
+    }
+
+    /// Validates all the fields of the structure passed by the bootloader (see [`validate`]),
+    /// and copies it into the kernel, so nothing uses the memory the bootloader left it in.
+    ///
+    /// Can only be called once, the copy is never changed after.
+    pub fn copy_from_ptr(ptr: *const MultiBoot2Info) -> Result<&'static Self, MultiBootError> {
+        let info = Self::from_ptr(ptr)?;
+        if info.total_size as usize > MAX_INFO_SIZE {
+            return Err(MultiBootError::TooLarge(info.total_size));
+        }
+        let info = info.bytes();
+        validate(info)?;
+        assert!(
+            !INFO_COPIED.swap(true, Ordering::AcqRel),
+            "multiboot info copied twice"
+        );
+        // SAFETY: this is the only write, and there are no references to the copy before it
+        let copy = unsafe { &mut (*addr_of_mut!(INFO_COPY)).0 };
+        copy[..info.len()].copy_from_slice(info);
+        // SAFETY: validated above, aligned by `InfoCopy`
+        Ok(unsafe { &*(copy.as_ptr() as *const Self) })
+    }
+
+    /// The size of the whole structure, with the header
+    pub fn size(&self) -> usize {
+        self.total_size as usize
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: `total_size` is validated to be inside the mapped memory
+        unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, self.total_size as usize)
+        }
+    }
+
+    /// Checks if `arg` is one of the words of the command line, without parsing the other
+    /// tags or allocating, so it can be used before anything is initialized.
+    ///
+    /// Returns `false` if the structure or the command line are invalid.
+    pub fn early_cmdline_contains(ptr: *const MultiBoot2Info, arg: &str) -> bool {
+        let Ok(info) = Self::from_ptr(ptr) else {
+            return false;
+        };
+        let Some(cmdline) = info.raw_cmdline() else {
+            return false;
+        };
+        cmdline
+            .split(|b| b.is_ascii_whitespace())
+            .any(|word| word == arg.as_bytes())
+    }
+
+    /// The bytes of the command line tag, up to the null terminator if present
+    fn raw_cmdline(&self) -> Option<&[u8]> {
+        let addr = self as *const Self as usize;
+        let end = addr + self.total_size as usize;
+        let mut current = addr + mem::size_of::<MultiBoot2Info>();
+        // the sizes are validated in `from_ptr`
+        while current < end {
+            // SAFETY: the tag header is inside the structure
+            let tag = unsafe { &*(current as *const MultiBootTagRaw) };
+            match tag.ty {
+                0 => break,
+                1 => {
+                    let header_size = mem::size_of::<MultiBootTagRaw>();
+                    // SAFETY: the tag is inside the structure
+                    let data = unsafe {
+                        core::slice::from_raw_parts(
+                            (current + header_size) as *const u8,
+                            tag.size as usize - header_size,
+                        )
+                    };
+                    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    return Some(&data[..len]);
+                }
+                _ => current += align_up(tag.size as usize, 8),
+            }
+        }
+        None
+    }
+
     fn data_ptr(&self) -> *const u8 {
         unsafe { (self as *const Self as *const u8).add(8) }
     }
@@ -542,10 +1149,6 @@ impl MultiBoot2Info {
         }
     }
 
-    pub fn end_address(&self) -> u64 {
-        unsafe { (self as *const Self as *const u8).add(self.total_size as _) as _ }
-    }
-
     pub fn tags(&self) -> MultiBootTagIter<'_> {
         MultiBootTagIter {
             current: unsafe { (self as *const Self as *const u8).add(8) as _ },
@@ -554,6 +1157,29 @@ impl MultiBoot2Info {
         }
     }
 
+    pub fn cmdline(&self) -> Option<&str> {
+        self.tags().find_map(|tag| match tag {
+            MultiBootTag::BootCommandLine { cmdline } => Some(cmdline),
+            _ => None,
+        })
+    }
+
+    /// The modules loaded by the bootloader, in the order they were given
+    pub fn modules(&self) -> impl Iterator<Item = BootModule<'_>> + '_ {
+        self.tags().filter_map(|tag| match tag {
+            MultiBootTag::Module(module) => Some(module),
+            _ => None,
+        })
+    }
+
+    /// The SMBIOS entry point if provided by the bootloader
+    pub fn smbios_tables(&self) -> Option<&[u8]> {
+        self.tags().find_map(|tag| match tag {
+            MultiBootTag::SmBiosTables { tables, .. } => Some(tables.0),
+            _ => None,
+        })
+    }
+
     pub fn memory_maps(&self) -> Option<impl Iterator<Item = MemoryMap> + '_> {
         self.tags().find_map(|tag| match tag {
             MultiBootTag::MemoryMap(mmap) => Some(mmap),
@@ -561,6 +1187,13 @@ impl MultiBoot2Info {
         })
     }
 
+    pub fn basic_memory_info(&self) -> Option<&BasicMemoryInfo> {
+        self.tags().find_map(|tag| match tag {
+            MultiBootTag::BasicMemoryInfo(info) => Some(info),
+            _ => None,
+        })
+    }
+
     pub fn framebuffer(&self) -> Option<Framebuffer> {
         self.tags().find_map(|tag| match tag {
             MultiBootTag::FrameBufferInfo(fb) => Some(fb),
@@ -615,3 +1248,210 @@ impl fmt::Display for MultiBoot2Info {
         Ok(())
     }
 }
+
+/// Builds a structure like the bootloader would, for the self tests
+struct Fixture(Vec<u8>);
+
+impl Fixture {
+    fn new() -> Self {
+        Self(vec![0; mem::size_of::<MultiBoot2Info>()])
+    }
+
+    fn tag(mut self, ty: u32, data: &[u8]) -> Self {
+        let size = (mem::size_of::<MultiBootTagRaw>() + data.len()) as u32;
+        self.0.extend_from_slice(&ty.to_le_bytes());
+        self.0.extend_from_slice(&size.to_le_bytes());
+        self.0.extend_from_slice(data);
+        self.0.resize(align_up(self.0.len(), 8), 0);
+        self
+    }
+
+    fn memory_map(self, entry_size: u32, entries: &[(u64, u64, u32)]) -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(&entry_size.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for &(base, length, ty) in entries {
+            data.extend_from_slice(&base.to_le_bytes());
+            data.extend_from_slice(&length.to_le_bytes());
+            data.extend_from_slice(&ty.to_le_bytes());
+            data.extend_from_slice(&0u32.to_le_bytes());
+        }
+        self.tag(6, &data)
+    }
+
+    fn module(self, start: u32, end: u32, cmdline: &[u8]) -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(&start.to_le_bytes());
+        data.extend_from_slice(&end.to_le_bytes());
+        data.extend_from_slice(cmdline);
+        self.tag(3, &data)
+    }
+
+    fn framebuffer(self, pitch: u32, width: u32, bpp: u8, ty: u8, color_info: &[u8]) -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xFD00_0000u64.to_le_bytes());
+        data.extend_from_slice(&pitch.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&600u32.to_le_bytes());
+        data.extend_from_slice(&[bpp, ty, 0, 0]);
+        data.extend_from_slice(color_info);
+        self.tag(8, &data)
+    }
+
+    /// Ends the structure, and validates it
+    fn validate(self) -> Result<(), MultiBootError> {
+        let mut info = self.tag(0, &[]).0;
+        let total_size = info.len() as u32;
+        info[0..4].copy_from_slice(&total_size.to_le_bytes());
+        validate(&info)
+    }
+}
+
+/// Validates bad structures, each must fail with the field that is wrong
+pub fn run_self_tests() {
+    println!("Running multiboot self tests...");
+    const AVAILABLE: u32 = MEMORY_MAP_AVAILABLE;
+    const RESERVED: u32 = 2;
+    let memory = [
+        (0, 0x9_FC00, AVAILABLE),
+        (0xF_0000, 0x1_0000, RESERVED),
+        (0x10_0000, 0x800_0000, AVAILABLE),
+    ];
+    let field = |result: Result<(), MultiBootError>| match result {
+        Err(MultiBootError::InvalidField { field, problem, .. }) => Some((field, problem)),
+        _ => None,
+    };
+
+    let good = || {
+        Fixture::new()
+            .tag(1, b"console=serial\0")
+            .tag(2, b"GRUB 2.12\0")
+            .memory_map(24, &memory)
+            .module(0x20_0000, 0x30_0000, b"initrd\0")
+            .framebuffer(4 * 800, 800, 32, 1, &[16, 8, 8, 8, 0, 8])
+            // unknown tags are skipped
+            .tag(99, &[1, 2, 3])
+    };
+    assert_eq!(good().validate(), Ok(()));
+    // only the basic memory info, the module is in the upper memory
+    let basic = || {
+        let mut data = Vec::new();
+        data.extend_from_slice(&639u32.to_le_bytes());
+        data.extend_from_slice(&(127 * 1024u32).to_le_bytes());
+        Fixture::new().tag(4, &data)
+    };
+    assert_eq!(
+        basic().module(0x20_0000, 0x30_0000, b"\0").validate(),
+        Ok(())
+    );
+
+    // the memory map entries don't fill the tag, two and a half entries
+    let mut entries = 24u32.to_le_bytes().to_vec();
+    entries.resize(8 + 24 * 2 + 12, 0);
+    let truncated = Fixture::new().tag(6, &entries);
+    assert_eq!(
+        field(truncated.validate()),
+        Some(("memory map", FieldProblem::PartialEntry { remaining: 12 })),
+        "multiboot self test: truncated memory map"
+    );
+    assert_eq!(
+        field(Fixture::new().memory_map(16, &[]).validate()),
+        Some(("memory map", FieldProblem::EntrySize(16)))
+    );
+    // past the end of the memory, and in a hole of it
+    let past_end =
+        Fixture::new()
+            .memory_map(24, &memory)
+            .module(0x7F0_0000, 0x900_0000, b"initrd\0");
+    assert_eq!(
+        field(past_end.validate()),
+        Some((
+            "module start..end",
+            FieldProblem::OutsideMemory {
+                start: 0x7F0_0000,
+                end: 0x900_0000
+            }
+        )),
+        "multiboot self test: module past the end of the memory"
+    );
+    let in_hole = basic().module(0xA_0000, 0xB_0000, b"\0");
+    assert!(matches!(
+        field(in_hole.validate()),
+        Some(("module start..end", FieldProblem::OutsideMemory { .. }))
+    ));
+    assert_eq!(
+        field(basic().module(0x30_0000, 0x20_0000, b"\0").validate()),
+        Some((
+            "module start..end",
+            FieldProblem::InvalidRange {
+                start: 0x30_0000,
+                end: 0x20_0000
+            }
+        ))
+    );
+    // strings without an end
+    assert_eq!(
+        field(Fixture::new().tag(1, b"console=serial").validate()),
+        Some(("command line", FieldProblem::Unterminated)),
+        "multiboot self test: unterminated command line"
+    );
+    let mut long = vec![b'a'; MAX_STRING_LEN];
+    long.push(0);
+    assert_eq!(
+        field(Fixture::new().tag(1, &long).validate()),
+        Some(("command line", FieldProblem::Unterminated))
+    );
+    assert_eq!(
+        field(basic().module(0x20_0000, 0x30_0000, b"init").validate()),
+        Some(("module cmdline", FieldProblem::Unterminated))
+    );
+    assert_eq!(
+        field(Fixture::new().tag(2, b"\xFF\0").validate()),
+        Some(("bootloader name", FieldProblem::NotUtf8))
+    );
+    // tags smaller than their fields
+    assert_eq!(
+        field(Fixture::new().tag(4, &[0; 4]).validate()),
+        Some(("tag size", FieldProblem::Truncated { size: 12, min: 16 }))
+    );
+    assert_eq!(
+        field(
+            Fixture::new()
+                .framebuffer(3200, 800, 32, 1, &[16, 8])
+                .validate()
+        ),
+        Some((
+            "framebuffer color info",
+            FieldProblem::Truncated { size: 34, min: 38 }
+        ))
+    );
+    assert_eq!(
+        field(Fixture::new().framebuffer(3200, 800, 32, 7, &[]).validate()),
+        Some(("framebuffer type", FieldProblem::UnknownType(7)))
+    );
+    assert_eq!(
+        field(Fixture::new().framebuffer(800, 800, 32, 2, &[]).validate()),
+        Some((
+            "framebuffer pitch",
+            FieldProblem::Pitch {
+                pitch: 800,
+                width: 800,
+                bpp: 32
+            }
+        ))
+    );
+    // the tags don't reach the end
+    let mut short = good().tag(0, &[]).0;
+    short.truncate(short.len() - 8);
+    let total_size = short.len() as u32;
+    short[0..4].copy_from_slice(&total_size.to_le_bytes());
+    assert_eq!(validate(&short), Err(MultiBootError::InvalidTags));
+
+    let error = Fixture::new().tag(1, b"console").validate().unwrap_err();
+    assert_eq!(
+        alloc::format!("{error}"),
+        alloc::format!("command line (tag at 0x8): no null terminator in {MAX_STRING_LEN} bytes")
+    );
+
+    println!("Multiboot self tests passed");
+}
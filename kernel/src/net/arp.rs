@@ -0,0 +1,165 @@
+//! ARP over Ethernet, and the table of the neighbours it finds.
+//!
+//! The table has [`TABLE_LEN`] entries, each forgotten [`ENTRY_TIMEOUT`] after it was last
+//! heard from, and a full table replaces the oldest. An address that is looked up and not
+//! known gets a request, at most one each [`REQUEST_INTERVAL`].
+
+use super::{Ipv4Address, MacAddress};
+
+pub const PACKET_LEN: usize = 28;
+pub const TABLE_LEN: usize = 16;
+pub const ENTRY_TIMEOUT: u64 = 60 * SECOND;
+pub const REQUEST_INTERVAL: u64 = SECOND;
+
+const SECOND: u64 = 1_000_000_000;
+const HARDWARE_ETHERNET: u16 = 1;
+const PROTOCOL_IPV4: u16 = 0x0800;
+pub const OPERATION_REQUEST: u16 = 1;
+pub const OPERATION_REPLY: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    /// The Ethernet/IPv4 packet at the start of `data`
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..PACKET_LEN)?;
+        let hardware = u16::from_be_bytes([data[0], data[1]]);
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+        if hardware != HARDWARE_ETHERNET
+            || protocol != PROTOCOL_IPV4
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return None;
+        }
+        Some(Self {
+            operation: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac: data[8..14].try_into().unwrap(),
+            sender_ip: Ipv4Address(data[14..18].try_into().unwrap()),
+            target_mac: data[18..24].try_into().unwrap(),
+            target_ip: Ipv4Address(data[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        let buf = &mut buf[..PACKET_LEN];
+        buf[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        buf[2..4].copy_from_slice(&PROTOCOL_IPV4.to_be_bytes());
+        buf[4] = 6;
+        buf[5] = 4;
+        buf[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        buf[8..14].copy_from_slice(&self.sender_mac);
+        buf[14..18].copy_from_slice(&self.sender_ip.0);
+        buf[18..24].copy_from_slice(&self.target_mac);
+        buf[24..28].copy_from_slice(&self.target_ip.0);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArpEntry {
+    pub ip: Ipv4Address,
+    /// `None` while a request is waiting for the reply
+    pub mac: Option<MacAddress>,
+    /// When it was heard from, or when the request was sent
+    pub updated: u64,
+}
+
+#[derive(Debug)]
+pub struct ArpTable {
+    entries: [Option<ArpEntry>; TABLE_LEN],
+}
+
+impl ArpTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; TABLE_LEN],
+        }
+    }
+
+    fn is_expired(entry: &ArpEntry, now: u64) -> bool {
+        now.saturating_sub(entry.updated) >= ENTRY_TIMEOUT
+    }
+
+    fn position(&self, ip: Ipv4Address) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.is_some_and(|entry| entry.ip == ip))
+    }
+
+    /// The slot of `ip`, or the one to replace for it: an empty or expired one, the oldest
+    /// otherwise
+    fn slot_for(&self, ip: Ipv4Address, now: u64) -> usize {
+        self.position(ip)
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .position(|entry| entry.is_none_or(|entry| Self::is_expired(&entry, now)))
+            })
+            .unwrap_or_else(|| {
+                (0..TABLE_LEN)
+                    .min_by_key(|&i| self.entries[i].map_or(0, |entry| entry.updated))
+                    .unwrap()
+            })
+    }
+
+    pub fn lookup(&self, ip: Ipv4Address, now: u64) -> Option<MacAddress> {
+        let entry = self.entries[self.position(ip)?]?;
+        if Self::is_expired(&entry, now) {
+            return None;
+        }
+        entry.mac
+    }
+
+    /// Records that `ip` is at `mac`, heard at `now`
+    pub fn insert(&mut self, ip: Ipv4Address, mac: MacAddress, now: u64) {
+        let slot = self.slot_for(ip, now);
+        self.entries[slot] = Some(ArpEntry {
+            ip,
+            mac: Some(mac),
+            updated: now,
+        });
+    }
+
+    /// Only updates `ip` if it is in the table already, the senders of requests that are
+    /// not for us are not worth a slot
+    pub fn refresh(&mut self, ip: Ipv4Address, mac: MacAddress, now: u64) {
+        if self.position(ip).is_some() {
+            self.insert(ip, mac, now);
+        }
+    }
+
+    /// Whether a request for the unknown `ip` should be sent now, it is marked as
+    /// requested if so
+    pub fn should_request(&mut self, ip: Ipv4Address, now: u64) -> bool {
+        if let Some(entry) = self.position(ip).and_then(|i| self.entries[i]) {
+            let known = entry.mac.is_some() && !Self::is_expired(&entry, now);
+            let waiting =
+                entry.mac.is_none() && now.saturating_sub(entry.updated) < REQUEST_INTERVAL;
+            if known || waiting {
+                return false;
+            }
+        }
+        let slot = self.slot_for(ip, now);
+        self.entries[slot] = Some(ArpEntry {
+            ip,
+            mac: None,
+            updated: now,
+        });
+        true
+    }
+
+    /// The entries that are not expired at `now`
+    pub fn entries(&self, now: u64) -> impl Iterator<Item = &ArpEntry> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(move |entry| !Self::is_expired(entry, now))
+    }
+}
@@ -0,0 +1,173 @@
+//! The static configuration of the interface, there is no DHCP.
+//!
+//! `ip=<address>/<prefix>[,gw=<address>]` sets the address, and `log.udp=<address>:<port>`
+//! starts the log sink. They are taken from the cmdline, and from the fw_cfg file
+//! [`CONFIG_FILE`] (whitespace separated like the cmdline) for the ones the cmdline doesn't
+//! have, so an image can be moved to another network without rebuilding it.
+
+use core::fmt;
+
+use alloc::string::String;
+
+use crate::devices::fw_cfg;
+
+use super::{Ipv4Address, SocketAddress};
+
+/// The fw_cfg name of the file with the options the cmdline doesn't have
+pub const CONFIG_FILE: &str = "opt/org.os.net";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    InvalidAddress,
+    MissingPrefix,
+    InvalidPrefix,
+    InvalidGateway,
+    /// The gateway is not in the subnet of the address
+    GatewayOffLink,
+    UnknownOption,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidAddress => "invalid address",
+            Self::MissingPrefix => "missing the `/<prefix>` of the address",
+            Self::InvalidPrefix => "the prefix must be 1 to 32",
+            Self::InvalidGateway => "invalid gateway address",
+            Self::GatewayOffLink => "the gateway is not in the subnet",
+            Self::UnknownOption => "unknown option, expected `gw=<address>`",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Cmdline,
+    FwCfg,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cmdline => "cmdline",
+            Self::FwCfg => "fw_cfg",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetConfig {
+    pub address: Ipv4Address,
+    pub prefix: u8,
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl NetConfig {
+    /// Parses the value of `ip=`, `<address>/<prefix>[,gw=<address>]`
+    pub fn parse(value: &str) -> Result<Self, ConfigError> {
+        let mut parts = value.split(',');
+        let (address, prefix) = parts
+            .next()
+            .unwrap_or_default()
+            .split_once('/')
+            .ok_or(ConfigError::MissingPrefix)?;
+        let address = Ipv4Address::parse(address).ok_or(ConfigError::InvalidAddress)?;
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| (1..=32).contains(prefix))
+            .ok_or(ConfigError::InvalidPrefix)?;
+        let mut config = Self {
+            address,
+            prefix,
+            gateway: None,
+        };
+        for part in parts {
+            let gateway = part.strip_prefix("gw=").ok_or(ConfigError::UnknownOption)?;
+            let gateway = Ipv4Address::parse(gateway).ok_or(ConfigError::InvalidGateway)?;
+            if !config.is_local(gateway) {
+                return Err(ConfigError::GatewayOffLink);
+            }
+            config.gateway = Some(gateway);
+        }
+        Ok(config)
+    }
+
+    pub fn netmask(&self) -> u32 {
+        u32::MAX << (32 - self.prefix as u32)
+    }
+
+    /// Whether `ip` is in the subnet
+    pub fn is_local(&self, ip: Ipv4Address) -> bool {
+        (ip.to_u32() ^ self.address.to_u32()) & self.netmask() == 0
+    }
+
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !self.netmask())
+    }
+
+    /// Whether a packet to `ip` is for us
+    pub fn accepts(&self, ip: Ipv4Address) -> bool {
+        ip == self.address || ip == self.broadcast() || ip == Ipv4Address::BROADCAST
+    }
+
+    /// The neighbour a packet to `ip` is sent to, the gateway if `ip` is not in the subnet
+    pub fn next_hop(&self, ip: Ipv4Address) -> Option<Ipv4Address> {
+        if self.is_local(ip) {
+            Some(ip)
+        } else {
+            self.gateway
+        }
+    }
+}
+
+impl fmt::Display for NetConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)?;
+        match self.gateway {
+            Some(gateway) => write!(f, ", gateway {gateway}"),
+            None => write!(f, ", no gateway"),
+        }
+    }
+}
+
+/// The options of the cmdline and of [`CONFIG_FILE`]
+pub struct Options {
+    pub(super) fw_cfg: Option<String>,
+}
+
+impl Options {
+    pub fn load() -> Self {
+        let fw_cfg =
+            fw_cfg::read_file(CONFIG_FILE).and_then(|content| String::from_utf8(content).ok());
+        Self { fw_cfg }
+    }
+
+    /// The value of the option `name`, and where it came from, the cmdline wins
+    pub fn get<'a>(&'a self, cmdline: &'a str, name: &str) -> Option<(&'a str, ConfigSource)> {
+        let find = |text: &'a str| {
+            text.split_whitespace()
+                .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+        };
+        find(cmdline)
+            .map(|value| (value, ConfigSource::Cmdline))
+            .or_else(|| Some((find(self.fw_cfg.as_deref()?)?, ConfigSource::FwCfg)))
+    }
+
+    pub fn config(&self, cmdline: &str) -> Option<(Result<NetConfig, ConfigError>, ConfigSource)> {
+        self.get(cmdline, "ip")
+            .map(|(value, source)| (NetConfig::parse(value), source))
+    }
+
+    pub fn log_destination(
+        &self,
+        cmdline: &str,
+    ) -> Option<(Result<SocketAddress, ConfigError>, ConfigSource)> {
+        self.get(cmdline, "log.udp").map(|(value, source)| {
+            (
+                SocketAddress::parse(value).ok_or(ConfigError::InvalidAddress),
+                source,
+            )
+        })
+    }
+}
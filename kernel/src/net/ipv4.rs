@@ -0,0 +1,143 @@
+//! IPv4 headers and the internet checksum, and the ICMP echo that goes with them.
+//!
+//! Only the 20 bytes header is written, the options of a received one are skipped. The
+//! fragments are refused, nothing sent here is large enough to need them.
+
+use super::Ipv4Address;
+
+pub const HEADER_LEN: usize = 20;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const DEFAULT_TTL: u8 = 64;
+
+const VERSION: u8 = 4;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+// don't fragment, no offset
+const FLAGS_DONT_FRAGMENT: u16 = 1 << 14;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4Error {
+    Truncated,
+    Version(u8),
+    HeaderLength(usize),
+    /// The total length is shorter than the header, or longer than the frame
+    TotalLength(usize),
+    Checksum,
+    Fragmented,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub identification: u16,
+}
+
+/// Adds the 16 bit big-endian words of `data` to `sum`, an odd byte at the end is padded
+/// with a zero
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let (words, rest) = data.as_chunks::<2>();
+    for word in words {
+        sum += u16::from_be_bytes(*word) as u32;
+    }
+    if let [last] = rest {
+        sum += u16::from_be_bytes([*last, 0]) as u32;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` back into it, and takes its complement
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The internet checksum of `data`, `0` when computed over data containing its own valid
+/// checksum
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// Validates the header at the start of `packet`, returns it with the payload, without the
+/// padding the frame may have after it
+pub fn parse(packet: &[u8]) -> Result<(Ipv4Header, &[u8]), Ipv4Error> {
+    if packet.len() < HEADER_LEN {
+        return Err(Ipv4Error::Truncated);
+    }
+    let version = packet[0] >> 4;
+    if version != VERSION {
+        return Err(Ipv4Error::Version(version));
+    }
+    let header_len = (packet[0] & 0xF) as usize * 4;
+    if header_len < HEADER_LEN || header_len > packet.len() {
+        return Err(Ipv4Error::HeaderLength(header_len));
+    }
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if total_len < header_len || total_len > packet.len() {
+        return Err(Ipv4Error::TotalLength(total_len));
+    }
+    if checksum(&packet[..header_len]) != 0 {
+        return Err(Ipv4Error::Checksum);
+    }
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+        return Err(Ipv4Error::Fragmented);
+    }
+
+    let header = Ipv4Header {
+        source: Ipv4Address(packet[12..16].try_into().unwrap()),
+        destination: Ipv4Address(packet[16..20].try_into().unwrap()),
+        protocol: packet[9],
+        ttl: packet[8],
+        identification: u16::from_be_bytes([packet[4], packet[5]]),
+    };
+    Ok((header, &packet[header_len..total_len]))
+}
+
+/// Writes `header` to the start of `buf` for a payload of `payload_len` bytes, with its
+/// checksum
+pub fn write(buf: &mut [u8], header: &Ipv4Header, payload_len: usize) {
+    let total_len = (HEADER_LEN + payload_len) as u16;
+    let buf = &mut buf[..HEADER_LEN];
+    buf[0] = (VERSION << 4) | (HEADER_LEN / 4) as u8;
+    buf[1] = 0;
+    buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+    buf[4..6].copy_from_slice(&header.identification.to_be_bytes());
+    buf[6..8].copy_from_slice(&FLAGS_DONT_FRAGMENT.to_be_bytes());
+    buf[8] = header.ttl;
+    buf[9] = header.protocol;
+    buf[10..12].fill(0);
+    buf[12..16].copy_from_slice(&header.source.0);
+    buf[16..20].copy_from_slice(&header.destination.0);
+    let sum = checksum(buf);
+    buf[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// The length of the reply to the ICMP message `message`, if it is a valid echo request
+pub fn icmp_echo_reply_len(message: &[u8]) -> Option<usize> {
+    (message.len() >= ICMP_HEADER_LEN
+        && message[0] == ICMP_ECHO_REQUEST
+        && message[1] == 0
+        && checksum(message) == 0)
+        .then_some(message.len())
+}
+
+/// Writes the reply to the echo request `request` to `buf`, the same identifier, sequence
+/// number and data
+pub fn write_icmp_echo_reply(buf: &mut [u8], request: &[u8]) {
+    let buf = &mut buf[..request.len()];
+    buf.copy_from_slice(request);
+    buf[0] = ICMP_ECHO_REPLY;
+    buf[2..4].fill(0);
+    let sum = checksum(buf);
+    buf[2..4].copy_from_slice(&sum.to_be_bytes());
+}
@@ -0,0 +1,223 @@
+//! Forwards the kernel logs to a host over UDP, one datagram a message, in a syslog-like
+//! line from the `kern` facility:
+//!
+//! `<6>os kernel: [   12.345678] <message>`
+//!
+//! with the severity `info` (6) for the prints and `err` (3) for the errors. It starts with
+//! `log.udp=<address>:<port>` (see [`config`](super::config)), or by writing
+//! `udp <address>:<port>` to `/devices/log_limits`, and `udp off` stops it.
+//!
+//! This is called from the logging path, so it never waits and doesn't allocate: the line is
+//! formatted on the stack, cut at [`MAX_LINE_LEN`], and dropped if the interface is busy (the
+//! messages printed while sending are too), if the neighbour is not resolved yet (it is
+//! requested instead), if the TX ring is full, or past [`RATE`] lines a second (with a burst
+//! as large). Each is counted.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{devices::clock, sync::spin::mutex::Mutex};
+
+use super::{SendError, SocketAddress};
+
+/// The lines a second that are sent, and the burst
+pub const RATE: u32 = 200;
+pub const MAX_LINE_LEN: usize = 512;
+/// The port the lines are sent from, the one of syslog
+pub const SOURCE_PORT: u16 = 514;
+
+const SECOND: u64 = 1_000_000_000;
+const HOSTNAME: &str = "os";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<LogSink> = Mutex::new(LogSink::new());
+// when the sink itself is busy, so it can't be counted inside
+static BUSY: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error = 3,
+    Info = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkError {
+    /// There is no device, or it has no address
+    NotConfigured,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SinkStats {
+    pub sent: u64,
+    pub rate_limited: u64,
+    pub ring_full: u64,
+    pub unresolved: u64,
+    /// The interface was busy, the message was printed while sending
+    pub busy: u64,
+    /// No route, or not configured anymore
+    pub failed: u64,
+}
+
+struct LogSink {
+    destination: Option<SocketAddress>,
+    tokens: u32,
+    refilled: u64,
+    stats: SinkStats,
+}
+
+impl LogSink {
+    const fn new() -> Self {
+        Self {
+            destination: None,
+            tokens: RATE,
+            refilled: 0,
+            stats: SinkStats {
+                sent: 0,
+                rate_limited: 0,
+                ring_full: 0,
+                unresolved: 0,
+                busy: 0,
+                failed: 0,
+            },
+        }
+    }
+
+    /// Whether a line can be sent at `now`, without a clock all of them can
+    fn take_token(&mut self, now: u64) -> bool {
+        if now == 0 {
+            return true;
+        }
+        let elapsed = now.saturating_sub(self.refilled);
+        if elapsed >= SECOND / RATE as u64 {
+            let new = (elapsed / (SECOND / RATE as u64)).min(RATE as u64) as u32;
+            self.tokens = (self.tokens + new).min(RATE);
+            self.refilled = now;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// A line on the stack, the writes past its end are cut at a character
+pub struct LineBuffer {
+    bytes: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MAX_LINE_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Formats the line of a message printed at `now`, without its newline, whether there was
+/// something else in the message
+pub fn format_line(
+    line: &mut LineBuffer,
+    severity: Severity,
+    now: u64,
+    args: fmt::Arguments,
+) -> bool {
+    let micros = now / 1000;
+    let _ = write!(
+        line,
+        "<{}>{HOSTNAME} kernel: [{:5}.{:06}] ",
+        severity as u8,
+        micros / 1_000_000,
+        micros % 1_000_000
+    );
+    let header_len = line.len;
+    let _ = line.write_fmt(args);
+    while line.len > header_len && matches!(line.bytes[line.len - 1], b'\n' | b'\r') {
+        line.len -= 1;
+    }
+    line.len > header_len
+}
+
+/// Sends `args` to the destination if there is one, see the module docs for when it is
+/// dropped
+pub fn forward(severity: Severity, args: fmt::Arguments) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(mut sink) = SINK.try_lock() else {
+        BUSY.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let Some(destination) = sink.destination else {
+        return;
+    };
+    let now = clock::try_uptime_nanos();
+    let mut line = LineBuffer::new();
+    // the newlines printed after a fragment are not sent
+    if !format_line(&mut line, severity, now, args) {
+        return;
+    }
+    if !sink.take_token(now) {
+        sink.stats.rate_limited += 1;
+        return;
+    }
+    let result = super::try_with_interface(|interface| {
+        interface.send_udp(SOURCE_PORT, destination, None, line.as_bytes())
+    });
+    match result {
+        Some(Ok(())) => sink.stats.sent += 1,
+        Some(Err(SendError::RingFull)) => sink.stats.ring_full += 1,
+        Some(Err(SendError::Unresolved)) => sink.stats.unresolved += 1,
+        Some(Err(_)) => sink.stats.failed += 1,
+        None => sink.stats.busy += 1,
+    }
+}
+
+/// Starts sending the logs to `destination`, or stops with `None`
+pub fn set_destination(destination: Option<SocketAddress>) -> Result<(), SinkError> {
+    if destination.is_some()
+        && super::with_interface(|interface| interface.config().is_some()) != Some(true)
+    {
+        return Err(SinkError::NotConfigured);
+    }
+    SINK.lock().destination = destination;
+    ENABLED.store(destination.is_some(), Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn stats() -> (Option<SocketAddress>, SinkStats) {
+    let sink = SINK.lock();
+    let mut stats = sink.stats;
+    stats.busy += BUSY.load(Ordering::Relaxed);
+    (sink.destination, stats)
+}
+
+impl fmt::Display for SinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, dropped: {} rate limited, {} ring full, {} unresolved, {} busy, {} failed",
+            self.sent, self.rate_limited, self.ring_full, self.unresolved, self.busy, self.failed
+        )
+    }
+}
@@ -0,0 +1,1070 @@
+//! A small IPv4 stack over one network device, configured statically (see [`config`]).
+//!
+//! The device driver attaches itself with [`attach`] when the PCI probe finds it, and raises
+//! [`SoftIrq::NetRx`] when it received frames, they are taken and handled from there one at
+//! a time: the ARP requests for our address are answered and the neighbours are kept (see
+//! [`arp`]), the ICMP echo requests are answered, and the UDP datagrams go to the handler of
+//! their port (see [`udp`]). Only the unfragmented IPv4 packets to our address or to a
+//! broadcast are taken, the rest is dropped and counted.
+//!
+//! Replies go to the MAC address the request came from, the other packets to their
+//! neighbour, or to the gateway when off the subnet. A packet to a neighbour that isn't known
+//! yet is dropped, and an ARP request is sent for it instead, nothing is queued.
+//!
+//! Sending never waits for the device, a full TX ring drops the frame, so it can be done
+//! from anywhere the interface lock can be taken, the [`log_sink`] sends from the logging
+//! path.
+//!
+//! `/devices/net` has the configuration, the counters, the neighbours and the bound ports.
+
+pub mod arp;
+pub mod config;
+pub mod ipv4;
+pub mod log_sink;
+pub mod udp;
+
+use core::fmt::{self, Write};
+
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    cpu::softirq::{self, SoftIrq},
+    devices::{self, clock, Device},
+    fs::FileSystemError,
+    sync::spin::mutex::Mutex,
+};
+
+use self::{
+    arp::{ArpPacket, ArpTable},
+    config::{ConfigSource, NetConfig, Options},
+    ipv4::{Ipv4Error, Ipv4Header},
+    udp::{Datagram, UdpError, UdpHandler},
+};
+
+pub type MacAddress = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddress = [0xFF; 6];
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// The largest frame sent or received, without the checksum the device adds
+pub const MAX_FRAME_LEN: usize = 1514;
+/// The largest payload of a UDP datagram that fits a frame
+pub const MAX_UDP_PAYLOAD: usize =
+    MAX_FRAME_LEN - ETHERNET_HEADER_LEN - ipv4::HEADER_LEN - udp::HEADER_LEN;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// The frames handled in one run of the softirq, it is raised again if there may be more
+const RX_BATCH: usize = 64;
+/// How long the boot waits for the neighbours it needs right away, the gateway of the log
+/// sink
+const BOOT_RESOLVE_TIMEOUT: u64 = 200_000_000;
+// in case the clock doesn't run
+const BOOT_RESOLVE_POLLS: usize = 1_000_000;
+/// How long the self test waits for the device to interrupt after a frame is sent
+const SELFTEST_INTERRUPT_TIMEOUT: u64 = 1_000_000_000;
+
+static INTERFACE: Mutex<Option<Interface>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const BROADCAST: Self = Self([255; 4]);
+
+    /// Parses the dotted form `a.b.c.d`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next()?;
+            // no signs, and not more than 3 digits
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *octet = part.parse().ok()?;
+        }
+        parts.next().is_none().then_some(Self(octets))
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        Self(value.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+impl fmt::Debug for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketAddress {
+    pub ip: Ipv4Address,
+    pub port: u16,
+}
+
+impl SocketAddress {
+    /// Parses `a.b.c.d:port`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (ip, port) = s.split_once(':')?;
+        Some(Self {
+            ip: Ipv4Address::parse(ip)?,
+            port: port.parse().ok().filter(|&port| port != 0)?,
+        })
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+pub struct DisplayMac(pub MacAddress);
+
+impl fmt::Display for DisplayMac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    /// All the buffers of the device are waiting to be sent
+    RingFull,
+    TooLarge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    NotConfigured,
+    /// Off the subnet, and there is no gateway
+    NoRoute,
+    /// The MAC address of the neighbour is not known yet, it was requested
+    Unresolved,
+    TooLarge,
+    RingFull,
+}
+
+pub trait NetDevice: Send {
+    fn name(&self) -> &str;
+    fn mac(&self) -> MacAddress;
+    /// Queues a frame of `len` bytes written by `fill` into the buffer of the device, doesn't
+    /// wait for a buffer or for it to be sent
+    fn transmit(&mut self, len: usize, fill: &mut dyn FnMut(&mut [u8])) -> Result<(), TxError>;
+    /// The next received frame, its buffer is given back to the device
+    fn receive(&mut self) -> Option<Vec<u8>>;
+    /// The GSI of the interrupt of the device and how many it raised, `None` if it has none
+    fn interrupts(&self) -> Option<(u32, u64)> {
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetStats {
+    pub rx_frames: u64,
+    /// Malformed, or with a wrong checksum
+    pub rx_invalid: u64,
+    /// Not for us, or of a protocol that is not handled
+    pub rx_ignored: u64,
+    /// UDP datagrams to a port without a handler
+    pub rx_closed_port: u64,
+    pub tx_frames: u64,
+    pub tx_ring_full: u64,
+    pub tx_unresolved: u64,
+    pub arp_requests: u64,
+    pub arp_replies: u64,
+    pub echo_replies: u64,
+}
+
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "rx: {} frames, {} invalid, {} ignored, {} to closed ports",
+            self.rx_frames, self.rx_invalid, self.rx_ignored, self.rx_closed_port
+        )?;
+        writeln!(
+            f,
+            "tx: {} frames, {} ring full, {} unresolved",
+            self.tx_frames, self.tx_ring_full, self.tx_unresolved
+        )?;
+        writeln!(
+            f,
+            "arp: {} requests sent, {} replies sent",
+            self.arp_requests, self.arp_replies
+        )?;
+        writeln!(f, "icmp: {} echo replies", self.echo_replies)
+    }
+}
+
+pub struct Interface {
+    device: Box<dyn NetDevice>,
+    mac: MacAddress,
+    config: Option<(NetConfig, ConfigSource)>,
+    arp: ArpTable,
+    ports: BTreeMap<u16, (&'static str, UdpHandler)>,
+    stats: NetStats,
+    next_identification: u16,
+}
+
+impl Interface {
+    pub fn new(device: Box<dyn NetDevice>) -> Self {
+        Self {
+            mac: device.mac(),
+            device,
+            config: None,
+            arp: ArpTable::new(),
+            ports: BTreeMap::new(),
+            stats: NetStats::default(),
+            next_identification: 0,
+        }
+    }
+
+    pub fn configure(&mut self, config: NetConfig, source: ConfigSource) {
+        self.config = Some((config, source));
+    }
+
+    pub fn config(&self) -> Option<NetConfig> {
+        self.config.map(|(config, _)| config)
+    }
+
+    /// Sends the datagrams to `port` to `handler`, fails if the port is taken
+    pub fn bind(&mut self, port: u16, name: &'static str, handler: UdpHandler) -> bool {
+        if self.ports.contains_key(&port) {
+            return false;
+        }
+        self.ports.insert(port, (name, handler));
+        true
+    }
+
+    /// Handles up to `max` received frames, whether there may be more
+    pub fn poll(&mut self, max: usize) -> bool {
+        for _ in 0..max {
+            let Some(frame) = self.device.receive() else {
+                return false;
+            };
+            self.handle_frame(&frame, clock::try_uptime_nanos());
+        }
+        true
+    }
+
+    pub fn handle_frame(&mut self, frame: &[u8], now: u64) {
+        self.stats.rx_frames += 1;
+        if frame.len() < ETHERNET_HEADER_LEN {
+            self.stats.rx_invalid += 1;
+            return;
+        }
+        let destination: MacAddress = frame[0..6].try_into().unwrap();
+        let source: MacAddress = frame[6..12].try_into().unwrap();
+        if destination != self.mac && destination != BROADCAST_MAC {
+            self.stats.rx_ignored += 1;
+            return;
+        }
+        let payload = &frame[ETHERNET_HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.handle_arp(payload, now),
+            ETHERTYPE_IPV4 => self.handle_ipv4(source, payload, now),
+            _ => self.stats.rx_ignored += 1,
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8], now: u64) {
+        let Some(packet) = ArpPacket::parse(payload) else {
+            self.stats.rx_invalid += 1;
+            return;
+        };
+        let Some(config) = self.config() else {
+            self.stats.rx_ignored += 1;
+            return;
+        };
+        if packet.target_ip != config.address {
+            // keep what we know current, even from the traffic not for us
+            self.arp.refresh(packet.sender_ip, packet.sender_mac, now);
+            self.stats.rx_ignored += 1;
+            return;
+        }
+        self.arp.insert(packet.sender_ip, packet.sender_mac, now);
+        if packet.operation == arp::OPERATION_REQUEST {
+            let reply = ArpPacket {
+                operation: arp::OPERATION_REPLY,
+                sender_mac: self.mac,
+                sender_ip: config.address,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            };
+            if self.send_arp(packet.sender_mac, &reply).is_ok() {
+                self.stats.arp_replies += 1;
+            }
+        }
+    }
+
+    fn handle_ipv4(&mut self, source_mac: MacAddress, payload: &[u8], now: u64) {
+        let (header, payload) = match ipv4::parse(payload) {
+            Ok(packet) => packet,
+            Err(Ipv4Error::Fragmented) => {
+                self.stats.rx_ignored += 1;
+                return;
+            }
+            Err(_) => {
+                self.stats.rx_invalid += 1;
+                return;
+            }
+        };
+        let Some(config) = self.config() else {
+            self.stats.rx_ignored += 1;
+            return;
+        };
+        if !config.accepts(header.destination) {
+            self.stats.rx_ignored += 1;
+            return;
+        }
+        if config.is_local(header.source) {
+            self.arp.refresh(header.source, source_mac, now);
+        }
+
+        match header.protocol {
+            ipv4::PROTOCOL_ICMP => self.handle_icmp(source_mac, &header, payload),
+            ipv4::PROTOCOL_UDP => self.handle_udp(source_mac, &header, payload),
+            _ => self.stats.rx_ignored += 1,
+        }
+    }
+
+    fn handle_icmp(&mut self, source_mac: MacAddress, header: &Ipv4Header, message: &[u8]) {
+        let Some(len) = ipv4::icmp_echo_reply_len(message) else {
+            self.stats.rx_ignored += 1;
+            return;
+        };
+        // no replies to broadcasts
+        if header.destination != self.config().unwrap().address {
+            self.stats.rx_ignored += 1;
+            return;
+        }
+        let result = self.send_ipv4(
+            source_mac,
+            header.source,
+            ipv4::PROTOCOL_ICMP,
+            len,
+            &mut |buf| ipv4::write_icmp_echo_reply(buf, message),
+        );
+        if result.is_ok() {
+            self.stats.echo_replies += 1;
+        }
+    }
+
+    fn handle_udp(&mut self, source_mac: MacAddress, header: &Ipv4Header, packet: &[u8]) {
+        let (source_port, destination_port, payload) = match udp::parse(header, packet) {
+            Ok(datagram) => datagram,
+            Err(UdpError::Truncated | UdpError::Length(_) | UdpError::Checksum) => {
+                self.stats.rx_invalid += 1;
+                return;
+            }
+        };
+        let Some(&(_, handler)) = self.ports.get(&destination_port) else {
+            self.stats.rx_closed_port += 1;
+            return;
+        };
+        let datagram = Datagram {
+            source: SocketAddress {
+                ip: header.source,
+                port: source_port,
+            },
+            source_mac,
+            destination: SocketAddress {
+                ip: header.destination,
+                port: destination_port,
+            },
+            payload,
+        };
+        handler(self, &datagram);
+    }
+
+    fn transmit(&mut self, len: usize, fill: &mut dyn FnMut(&mut [u8])) -> Result<(), SendError> {
+        match self.device.transmit(len, fill) {
+            Ok(()) => {
+                self.stats.tx_frames += 1;
+                Ok(())
+            }
+            Err(TxError::RingFull) => {
+                self.stats.tx_ring_full += 1;
+                Err(SendError::RingFull)
+            }
+            Err(TxError::TooLarge) => Err(SendError::TooLarge),
+        }
+    }
+
+    fn ethernet_header(
+        &self,
+        destination: MacAddress,
+        ethertype: u16,
+    ) -> [u8; ETHERNET_HEADER_LEN] {
+        let mut header = [0; ETHERNET_HEADER_LEN];
+        header[0..6].copy_from_slice(&destination);
+        header[6..12].copy_from_slice(&self.mac);
+        header[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        header
+    }
+
+    fn send_arp(&mut self, destination: MacAddress, packet: &ArpPacket) -> Result<(), SendError> {
+        let ethernet = self.ethernet_header(destination, ETHERTYPE_ARP);
+        self.transmit(ETHERNET_HEADER_LEN + arp::PACKET_LEN, &mut |buf| {
+            buf[..ETHERNET_HEADER_LEN].copy_from_slice(&ethernet);
+            packet.write(&mut buf[ETHERNET_HEADER_LEN..]);
+        })
+    }
+
+    /// The MAC address of the neighbour for `ip`, requests it if it is not known
+    pub fn resolve(&mut self, ip: Ipv4Address) -> Result<MacAddress, SendError> {
+        let config = self.config().ok_or(SendError::NotConfigured)?;
+        if ip == Ipv4Address::BROADCAST || ip == config.broadcast() {
+            return Ok(BROADCAST_MAC);
+        }
+        let neighbour = config.next_hop(ip).ok_or(SendError::NoRoute)?;
+        let now = clock::try_uptime_nanos();
+        if let Some(mac) = self.arp.lookup(neighbour, now) {
+            return Ok(mac);
+        }
+        if self.arp.should_request(neighbour, now) {
+            let request = ArpPacket {
+                operation: arp::OPERATION_REQUEST,
+                sender_mac: self.mac,
+                sender_ip: config.address,
+                target_mac: [0; 6],
+                target_ip: neighbour,
+            };
+            if self.send_arp(BROADCAST_MAC, &request).is_ok() {
+                self.stats.arp_requests += 1;
+            }
+        }
+        Err(SendError::Unresolved)
+    }
+
+    fn send_ipv4(
+        &mut self,
+        destination_mac: MacAddress,
+        destination: Ipv4Address,
+        protocol: u8,
+        payload_len: usize,
+        fill: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<(), SendError> {
+        let config = self.config().ok_or(SendError::NotConfigured)?;
+        let len = ETHERNET_HEADER_LEN + ipv4::HEADER_LEN + payload_len;
+        if len > MAX_FRAME_LEN {
+            return Err(SendError::TooLarge);
+        }
+        let header = Ipv4Header {
+            source: config.address,
+            destination,
+            protocol,
+            ttl: ipv4::DEFAULT_TTL,
+            identification: self.next_identification,
+        };
+        self.next_identification = self.next_identification.wrapping_add(1);
+        let ethernet = self.ethernet_header(destination_mac, ETHERTYPE_IPV4);
+        self.transmit(len, &mut |buf| {
+            buf[..ETHERNET_HEADER_LEN].copy_from_slice(&ethernet);
+            let buf = &mut buf[ETHERNET_HEADER_LEN..];
+            ipv4::write(buf, &header, payload_len);
+            fill(&mut buf[ipv4::HEADER_LEN..]);
+        })
+    }
+
+    /// Sends `payload` from our `source_port` to `destination`, at `destination_mac` if
+    /// known (a reply), otherwise at the neighbour that is resolved for it
+    pub fn send_udp(
+        &mut self,
+        source_port: u16,
+        destination: SocketAddress,
+        destination_mac: Option<MacAddress>,
+        payload: &[u8],
+    ) -> Result<(), SendError> {
+        let config = self.config().ok_or(SendError::NotConfigured)?;
+        if payload.len() > MAX_UDP_PAYLOAD {
+            return Err(SendError::TooLarge);
+        }
+        let destination_mac = match destination_mac {
+            Some(mac) => mac,
+            None => self.resolve(destination.ip).inspect_err(|e| {
+                if *e == SendError::Unresolved {
+                    self.stats.tx_unresolved += 1;
+                }
+            })?,
+        };
+        let source = SocketAddress {
+            ip: config.address,
+            port: source_port,
+        };
+        self.send_ipv4(
+            destination_mac,
+            destination.ip,
+            ipv4::PROTOCOL_UDP,
+            udp::HEADER_LEN + payload.len(),
+            &mut |buf| udp::write(buf, source, destination, payload),
+        )
+    }
+
+    fn describe(&self, out: &mut String) {
+        let _ = writeln!(
+            out,
+            "device: {} {}",
+            self.device.name(),
+            DisplayMac(self.mac)
+        );
+        let _ = match self.config {
+            Some((config, source)) => writeln!(out, "address: {config} ({source})"),
+            None => writeln!(out, "address: none"),
+        };
+        let _ = match self.device.interrupts() {
+            Some((gsi, count)) => writeln!(out, "interrupt: GSI {gsi}, {count} received"),
+            None => writeln!(out, "interrupt: none"),
+        };
+        let _ = write!(out, "{}", self.stats);
+        let now = clock::try_uptime_nanos();
+        for entry in self.arp.entries(now) {
+            let age = now.saturating_sub(entry.updated) / 1_000_000_000;
+            let _ = match entry.mac {
+                Some(mac) => writeln!(
+                    out,
+                    "neighbour {} at {}, {age}s ago",
+                    entry.ip,
+                    DisplayMac(mac)
+                ),
+                None => writeln!(out, "neighbour {} requested {age}s ago", entry.ip),
+            };
+        }
+        for (port, (name, _)) in self.ports.iter() {
+            let _ = writeln!(out, "udp port {port}: {name}");
+        }
+    }
+}
+
+/// Takes the network device found by the PCI probe, only one is used
+pub fn attach(device: Box<dyn NetDevice>) -> bool {
+    let mut interface = INTERFACE.lock();
+    if interface.is_some() {
+        return false;
+    }
+    *interface = Some(Interface::new(device));
+    true
+}
+
+/// Runs `f` with the interface, `None` if there is no device
+pub fn with_interface<R>(f: impl FnOnce(&mut Interface) -> R) -> Option<R> {
+    INTERFACE.lock().as_mut().map(f)
+}
+
+/// Like [`with_interface`], but `None` if the interface is busy, from where it can't wait
+pub fn try_with_interface<R>(f: impl FnOnce(&mut Interface) -> R) -> Option<R> {
+    INTERFACE.try_lock()?.as_mut().map(f)
+}
+
+fn net_rx() {
+    if with_interface(|interface| interface.poll(RX_BATCH)) == Some(true) {
+        softirq::raise(SoftIrq::NetRx);
+    }
+}
+
+/// Waits (at boot) for the MAC address of the neighbour for `ip`, polling the device
+fn resolve_blocking(ip: Ipv4Address) -> Result<MacAddress, SendError> {
+    let start = clock::uptime_nanos();
+    let mut result = Err(SendError::Unresolved);
+    for _ in 0..BOOT_RESOLVE_POLLS {
+        result = with_interface(|interface| {
+            interface.poll(RX_BATCH);
+            interface.resolve(ip)
+        })
+        .unwrap_or(Err(SendError::NotConfigured));
+        if result != Err(SendError::Unresolved)
+            || clock::uptime_nanos().saturating_sub(start) >= BOOT_RESOLVE_TIMEOUT
+        {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    result
+}
+
+#[derive(Debug)]
+struct NetDeviceFile;
+
+impl Device for NetDeviceFile {
+    fn name(&self) -> &str {
+        "net"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let mut info = String::new();
+        with_interface(|interface| interface.describe(&mut info));
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+}
+
+/// Configures the interface from the cmdline or the fw_cfg file, and starts the echo
+/// responder and the log sink if asked
+pub fn init(cmdline: &str) -> Result<(), String> {
+    if INTERFACE.lock().is_none() {
+        return Err(String::from("no network device"));
+    }
+    let options = Options::load();
+    let (config, source) = match options.config(cmdline) {
+        Some((Ok(config), source)) => (config, source),
+        Some((Err(e), source)) => return Err(format!("invalid `ip=` from the {source}: {e}")),
+        None => return Err(String::from("no address, `ip=<address>/<prefix>`")),
+    };
+    with_interface(|interface| {
+        interface.configure(config, source);
+        interface.bind(udp::ECHO_PORT, "echo", udp::echo);
+    });
+    softirq::register(SoftIrq::NetRx, net_rx);
+    devices::register_device(Arc::new(NetDeviceFile));
+    println!("net: {config} ({source})");
+
+    match options.log_destination(cmdline) {
+        Some((Ok(destination), _)) => {
+            if let Err(e) = resolve_blocking(destination.ip) {
+                println!("net: {} is not answering yet: {e:?}", destination.ip);
+            }
+            log_sink::set_destination(Some(destination)).map_err(|e| format!("{e:?}"))?;
+            println!("net: sending the logs to {destination}");
+        }
+        Some((Err(e), source)) => {
+            eprintln!("net: invalid `log.udp=` from the {source}: {e}");
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Records the frames sent, and replays the frames given to it
+struct SelfTestDevice {
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    ring_full: bool,
+}
+
+impl NetDevice for SelfTestDevice {
+    fn name(&self) -> &str {
+        "selftest"
+    }
+
+    fn mac(&self) -> MacAddress {
+        [0x02, 0, 0, 0, 0, 0x01]
+    }
+
+    fn transmit(&mut self, len: usize, fill: &mut dyn FnMut(&mut [u8])) -> Result<(), TxError> {
+        if self.ring_full {
+            return Err(TxError::RingFull);
+        }
+        let mut frame = alloc::vec![0; len];
+        fill(&mut frame);
+        self.sent.lock().push(frame);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// A frame from `source` at `source_mac` to the test device, with a UDP datagram
+fn self_test_udp_frame(
+    source: SocketAddress,
+    source_mac: MacAddress,
+    destination: SocketAddress,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = udp::HEADER_LEN + payload.len();
+    let mut frame = alloc::vec![0; ETHERNET_HEADER_LEN + ipv4::HEADER_LEN + udp_len];
+    frame[0..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame[6..12].copy_from_slice(&source_mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    let header = Ipv4Header {
+        source: source.ip,
+        destination: destination.ip,
+        protocol: ipv4::PROTOCOL_UDP,
+        ttl: ipv4::DEFAULT_TTL,
+        identification: 1,
+    };
+    ipv4::write(&mut frame[ETHERNET_HEADER_LEN..], &header, udp_len);
+    udp::write(
+        &mut frame[ETHERNET_HEADER_LEN + ipv4::HEADER_LEN..],
+        source,
+        destination,
+        payload,
+    );
+    frame
+}
+
+/// The parsing of the configuration, the checksums, the ARP table, and an interface over a
+/// made up device answering ARP, ICMP and UDP echo, the log lines, then the interrupts of the
+/// real device
+pub fn run_self_tests() {
+    println!("Running net self tests...");
+
+    let ip = |s| Ipv4Address::parse(s).unwrap();
+    assert_eq!(ip("10.0.2.15").0, [10, 0, 2, 15]);
+    for bad in [
+        "10.0.2",
+        "10.0.2.15.1",
+        "10.0.2.256",
+        "10.0.2.+1",
+        "10..2.15",
+        "",
+    ] {
+        assert!(Ipv4Address::parse(bad).is_none(), "{bad}");
+    }
+    assert_eq!(
+        SocketAddress::parse("10.0.2.2:5514"),
+        Some(SocketAddress {
+            ip: ip("10.0.2.2"),
+            port: 5514
+        })
+    );
+    assert!(SocketAddress::parse("10.0.2.2:0").is_none());
+    assert!(SocketAddress::parse("10.0.2.2").is_none());
+
+    let config = NetConfig::parse("10.0.2.15/24,gw=10.0.2.2").unwrap();
+    assert_eq!(config.gateway, Some(ip("10.0.2.2")));
+    assert_eq!(config.broadcast(), ip("10.0.2.255"));
+    assert!(config.accepts(ip("10.0.2.255")) && config.accepts(Ipv4Address::BROADCAST));
+    assert!(!config.accepts(ip("10.0.2.16")));
+    assert_eq!(config.next_hop(ip("10.0.2.3")), Some(ip("10.0.2.3")));
+    assert_eq!(config.next_hop(ip("192.168.1.1")), Some(ip("10.0.2.2")));
+    let no_gateway = NetConfig::parse("192.168.0.10/16").unwrap();
+    assert_eq!(
+        no_gateway.next_hop(ip("192.168.200.1")),
+        Some(ip("192.168.200.1"))
+    );
+    assert_eq!(no_gateway.next_hop(ip("10.0.0.1")), None);
+    use config::ConfigError;
+    for (bad, error) in [
+        ("10.0.2.15", ConfigError::MissingPrefix),
+        ("10.0.2/24", ConfigError::InvalidAddress),
+        ("10.0.2.15/33", ConfigError::InvalidPrefix),
+        ("10.0.2.15/0", ConfigError::InvalidPrefix),
+        ("10.0.2.15/24,gw=10.0.2", ConfigError::InvalidGateway),
+        ("10.0.2.15/24,gw=10.0.3.1", ConfigError::GatewayOffLink),
+        ("10.0.2.15/24,mtu=1500", ConfigError::UnknownOption),
+    ] {
+        assert_eq!(NetConfig::parse(bad), Err(error), "{bad}");
+    }
+    let options = Options {
+        fw_cfg: Some(String::from("ip=10.0.2.15/24 log.udp=10.0.2.2:514")),
+    };
+    assert_eq!(
+        options.config("quiet ip=10.1.0.2/8"),
+        Some((NetConfig::parse("10.1.0.2/8"), ConfigSource::Cmdline))
+    );
+    assert_eq!(
+        options.config("quiet"),
+        Some((NetConfig::parse("10.0.2.15/24"), ConfigSource::FwCfg))
+    );
+    assert_eq!(
+        options.log_destination("ip=10.1.0.2/8"),
+        Some((
+            Ok(SocketAddress::parse("10.0.2.2:514").unwrap()),
+            ConfigSource::FwCfg
+        ))
+    );
+
+    // a header with a known checksum
+    let header: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(ipv4::checksum(&header), 0);
+    let mut zeroed = header;
+    zeroed[10..12].fill(0);
+    assert_eq!(ipv4::checksum(&zeroed), 0xb861);
+    // the odd byte is padded
+    assert_eq!(ipv4::checksum(&[0x01]), !0x0100);
+
+    // a datagram round trip, then broken in each layer
+    let source = SocketAddress::parse("10.0.2.2:40000").unwrap();
+    let destination = SocketAddress::parse("10.0.2.15:7").unwrap();
+    let frame = self_test_udp_frame(source, [0x52; 6], destination, b"hello");
+    let (header, packet) = ipv4::parse(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(
+        (header.source, header.destination),
+        (source.ip, destination.ip)
+    );
+    assert_eq!(udp::parse(&header, packet), Ok((40000, 7, &b"hello"[..])));
+    let mut broken = frame.clone();
+    broken[ETHERNET_HEADER_LEN + 8] ^= 1;
+    assert_eq!(
+        ipv4::parse(&broken[ETHERNET_HEADER_LEN..]).err(),
+        Some(Ipv4Error::Checksum)
+    );
+    let mut broken = frame.clone();
+    *broken.last_mut().unwrap() ^= 1;
+    let (header, packet) = ipv4::parse(&broken[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(udp::parse(&header, packet), Err(UdpError::Checksum));
+    let mut fragment = frame.clone();
+    fragment[ETHERNET_HEADER_LEN + 6] |= 0x20;
+    let sum = ipv4::checksum(&{
+        let mut header = [0; ipv4::HEADER_LEN];
+        header.copy_from_slice(&fragment[ETHERNET_HEADER_LEN..][..ipv4::HEADER_LEN]);
+        header[10..12].fill(0);
+        header
+    });
+    fragment[ETHERNET_HEADER_LEN + 10..ETHERNET_HEADER_LEN + 12]
+        .copy_from_slice(&sum.to_be_bytes());
+    assert_eq!(
+        ipv4::parse(&fragment[ETHERNET_HEADER_LEN..]).err(),
+        Some(Ipv4Error::Fragmented)
+    );
+    assert_eq!(
+        ipv4::parse(&frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + 30]).err(),
+        Some(Ipv4Error::TotalLength(33))
+    );
+
+    // the table forgets, and replaces the oldest when full
+    let mut table = ArpTable::new();
+    let second = 1_000_000_000;
+    table.insert(ip("10.0.2.2"), [1; 6], second);
+    assert_eq!(table.lookup(ip("10.0.2.2"), 2 * second), Some([1; 6]));
+    assert_eq!(
+        table.lookup(ip("10.0.2.2"), second + arp::ENTRY_TIMEOUT),
+        None
+    );
+    assert!(!table.should_request(ip("10.0.2.2"), 2 * second));
+    assert!(table.should_request(ip("10.0.2.2"), second + arp::ENTRY_TIMEOUT));
+    assert!(!table.should_request(ip("10.0.2.2"), second + arp::ENTRY_TIMEOUT + 1));
+    assert!(table.should_request(
+        ip("10.0.2.2"),
+        second + arp::ENTRY_TIMEOUT + arp::REQUEST_INTERVAL
+    ));
+    let mut table = ArpTable::new();
+    for i in 0..arp::TABLE_LEN as u8 {
+        table.insert(Ipv4Address([10, 0, 2, 10 + i]), [i; 6], second + i as u64);
+    }
+    table.insert(ip("10.0.2.200"), [0xAA; 6], 100 * second / 2);
+    assert_eq!(table.lookup(ip("10.0.2.10"), 100 * second / 2), None);
+    assert_eq!(
+        table.lookup(ip("10.0.2.11"), 100 * second / 2),
+        Some([1; 6])
+    );
+    assert_eq!(
+        table.lookup(ip("10.0.2.200"), 100 * second / 2),
+        Some([0xAA; 6])
+    );
+    assert_eq!(table.entries(100 * second / 2).count(), arp::TABLE_LEN);
+    table.refresh(ip("10.0.2.201"), [0xBB; 6], 100 * second / 2);
+    assert_eq!(table.lookup(ip("10.0.2.201"), 100 * second / 2), None);
+
+    // an interface on a made up device
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let mut interface = Interface::new(Box::new(SelfTestDevice {
+        sent: sent.clone(),
+        ring_full: false,
+    }));
+    let host_mac = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+    let mac = interface.mac;
+    interface.configure(config, ConfigSource::Cmdline);
+    assert!(interface.bind(udp::ECHO_PORT, "echo", udp::echo));
+    assert!(!interface.bind(udp::ECHO_PORT, "echo", udp::echo));
+    let now = clock::try_uptime_nanos();
+
+    // ARP
+    let request = ArpPacket {
+        operation: arp::OPERATION_REQUEST,
+        sender_mac: host_mac,
+        sender_ip: ip("10.0.2.2"),
+        target_mac: [0; 6],
+        target_ip: config.address,
+    };
+    let mut frame = alloc::vec![0; ETHERNET_HEADER_LEN + arp::PACKET_LEN];
+    frame[0..6].copy_from_slice(&BROADCAST_MAC);
+    frame[6..12].copy_from_slice(&host_mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    request.write(&mut frame[ETHERNET_HEADER_LEN..]);
+    interface.handle_frame(&frame, now);
+    let reply = sent.lock().pop().expect("no ARP reply");
+    assert_eq!(&reply[0..6], &host_mac);
+    assert_eq!(
+        ArpPacket::parse(&reply[ETHERNET_HEADER_LEN..]),
+        Some(ArpPacket {
+            operation: arp::OPERATION_REPLY,
+            sender_mac: mac,
+            sender_ip: config.address,
+            target_mac: host_mac,
+            target_ip: ip("10.0.2.2"),
+        })
+    );
+    assert_eq!(interface.arp.lookup(ip("10.0.2.2"), now), Some(host_mac));
+
+    // UDP echo, from off the subnet through the gateway
+    let remote = SocketAddress::parse("192.168.1.20:40000").unwrap();
+    interface.handle_frame(
+        &self_test_udp_frame(remote, host_mac, destination, b"ping"),
+        now,
+    );
+    let reply = sent.lock().pop().expect("no echo reply");
+    assert_eq!((&reply[0..6], &reply[6..12]), (&host_mac[..], &mac[..]));
+    let (header, packet) = ipv4::parse(&reply[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(
+        (header.source, header.destination),
+        (config.address, remote.ip)
+    );
+    assert_eq!(udp::parse(&header, packet), Ok((7, 40000, &b"ping"[..])));
+    // a closed port, and not for us
+    let closed = SocketAddress::parse("10.0.2.15:9").unwrap();
+    interface.handle_frame(&self_test_udp_frame(remote, host_mac, closed, b"x"), now);
+    let other = SocketAddress::parse("10.0.2.16:7").unwrap();
+    interface.handle_frame(&self_test_udp_frame(remote, host_mac, other, b"x"), now);
+    assert!(sent.lock().is_empty());
+    assert_eq!(interface.stats.rx_closed_port, 1);
+    assert_eq!(interface.stats.rx_ignored, 1);
+
+    // ICMP echo
+    let mut ping = alloc::vec![0; ETHERNET_HEADER_LEN + ipv4::HEADER_LEN + 12];
+    ping[0..6].copy_from_slice(&mac);
+    ping[6..12].copy_from_slice(&host_mac);
+    ping[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    let ping_header = Ipv4Header {
+        source: ip("10.0.2.2"),
+        destination: config.address,
+        protocol: ipv4::PROTOCOL_ICMP,
+        ttl: ipv4::DEFAULT_TTL,
+        identification: 2,
+    };
+    ipv4::write(&mut ping[ETHERNET_HEADER_LEN..], &ping_header, 12);
+    let message = &mut ping[ETHERNET_HEADER_LEN + ipv4::HEADER_LEN..];
+    message.copy_from_slice(&[8, 0, 0, 0, 0x12, 0x34, 0, 1, b'd', b'a', b't', b'a']);
+    let sum = ipv4::checksum(message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    interface.handle_frame(&ping, now);
+    let reply = sent.lock().pop().expect("no ICMP reply");
+    let (_, message) = ipv4::parse(&reply[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!((message[0], ipv4::checksum(message)), (0, 0));
+    assert_eq!(&message[4..], &[0x12, 0x34, 0, 1, b'd', b'a', b't', b'a']);
+
+    // an unknown neighbour is requested, once
+    let neighbour = SocketAddress::parse("10.0.2.99:514").unwrap();
+    assert_eq!(
+        interface.send_udp(514, neighbour, None, b"x"),
+        Err(SendError::Unresolved)
+    );
+    assert_eq!(
+        interface.send_udp(514, neighbour, None, b"x"),
+        Err(SendError::Unresolved)
+    );
+    let request = sent.lock().pop().expect("no ARP request");
+    assert!(sent.lock().is_empty());
+    assert_eq!(&request[0..6], &BROADCAST_MAC);
+    let request = ArpPacket::parse(&request[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(
+        (request.operation, request.target_ip),
+        (arp::OPERATION_REQUEST, neighbour.ip)
+    );
+    assert_eq!(interface.stats.tx_unresolved, 2);
+    let large = [0; MAX_UDP_PAYLOAD + 1];
+    assert_eq!(
+        interface.send_udp(514, remote, None, &large),
+        Err(SendError::TooLarge)
+    );
+
+    // a full ring drops
+    let mut full = Interface::new(Box::new(SelfTestDevice {
+        sent: sent.clone(),
+        ring_full: true,
+    }));
+    full.configure(config, ConfigSource::Cmdline);
+    assert_eq!(
+        full.send_udp(514, remote, Some(host_mac), b"x"),
+        Err(SendError::RingFull)
+    );
+    assert_eq!(full.stats.tx_ring_full, 1);
+
+    // log lines
+    let mut line = log_sink::LineBuffer::new();
+    assert!(log_sink::format_line(
+        &mut line,
+        log_sink::Severity::Error,
+        12_345_678_901,
+        format_args!("disk {} failed\n", 1)
+    ));
+    assert_eq!(
+        line.as_bytes(),
+        b"<3>os kernel: [   12.345678] disk 1 failed"
+    );
+    let mut line = log_sink::LineBuffer::new();
+    assert!(!log_sink::format_line(
+        &mut line,
+        log_sink::Severity::Info,
+        0,
+        format_args!("\n")
+    ));
+    let mut line = log_sink::LineBuffer::new();
+    let long = [b'x'; log_sink::MAX_LINE_LEN];
+    let long = core::str::from_utf8(&long[..log_sink::MAX_LINE_LEN - 30]).unwrap();
+    log_sink::format_line(
+        &mut line,
+        log_sink::Severity::Info,
+        0,
+        format_args!("{long}äää"),
+    );
+    assert_eq!(line.as_bytes().len(), log_sink::MAX_LINE_LEN - 1);
+    assert!(core::str::from_utf8(line.as_bytes()).is_ok());
+
+    selftest_device_interrupts();
+}
+
+/// The device interrupts on the line it's routed to: it does when it's done with a frame sent,
+/// here an ARP probe for our address, which is answered by no one
+fn selftest_device_interrupts() {
+    let Some(Some((gsi, before))) = with_interface(|interface| interface.device.interrupts())
+    else {
+        println!("net self test: no device with an interrupt, skipped");
+        return;
+    };
+    with_interface(|interface| {
+        let probe = ArpPacket {
+            operation: arp::OPERATION_REQUEST,
+            sender_mac: interface.mac,
+            sender_ip: Ipv4Address([0; 4]),
+            target_mac: [0; 6],
+            target_ip: interface
+                .config()
+                .map_or(Ipv4Address([0; 4]), |config| config.address),
+        };
+        interface
+            .send_arp(BROADCAST_MAC, &probe)
+            .expect("net self test: sending the ARP probe");
+    });
+    let interrupts = || {
+        with_interface(|interface| interface.device.interrupts())
+            .flatten()
+            .map_or(0, |(_, count)| count)
+    };
+    let start = clock::uptime_nanos();
+    let mut polls = 0;
+    while interrupts() == before {
+        let expired = if start == 0 {
+            polls >= BOOT_RESOLVE_POLLS
+        } else {
+            clock::uptime_nanos() - start >= SELFTEST_INTERRUPT_TIMEOUT
+        };
+        assert!(
+            !expired,
+            "net self test: no interrupt on GSI {gsi} after a frame was sent"
+        );
+        polls += 1;
+        core::hint::spin_loop();
+    }
+    println!(
+        "net self test: device interrupts on GSI {gsi}, {} received",
+        interrupts()
+    );
+}
@@ -0,0 +1,90 @@
+//! UDP datagrams, demultiplexed to a handler by their destination port.
+//!
+//! The checksum over the pseudo header is always sent, and checked on the datagrams that have
+//! one (it is optional in IPv4, `0` is none). The datagrams to a port without a handler are
+//! dropped and counted, no ICMP error is sent back.
+
+use super::{
+    ipv4::{self, Ipv4Header},
+    Interface, Ipv4Address, MacAddress, SocketAddress,
+};
+
+pub const HEADER_LEN: usize = 8;
+/// The port of the echo responder, the standard one
+pub const ECHO_PORT: u16 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    Truncated,
+    /// The length field is shorter than the header, or longer than the packet
+    Length(usize),
+    Checksum,
+}
+
+/// A received datagram, `payload` borrows from the frame
+#[derive(Debug)]
+pub struct Datagram<'a> {
+    pub source: SocketAddress,
+    pub source_mac: MacAddress,
+    pub destination: SocketAddress,
+    pub payload: &'a [u8],
+}
+
+/// What a bound port does with its datagrams, with the interface to send replies from
+pub type UdpHandler = fn(&mut Interface, &Datagram);
+
+fn pseudo_header_sum(source: Ipv4Address, destination: Ipv4Address, len: usize) -> u32 {
+    let sum = ipv4::checksum_add(0, &source.0);
+    let sum = ipv4::checksum_add(sum, &destination.0);
+    sum + ipv4::PROTOCOL_UDP as u32 + len as u32
+}
+
+/// Validates the datagram `packet` that came with `header`, returns its ports and payload
+pub fn parse<'a>(header: &Ipv4Header, packet: &'a [u8]) -> Result<(u16, u16, &'a [u8]), UdpError> {
+    if packet.len() < HEADER_LEN {
+        return Err(UdpError::Truncated);
+    }
+    let len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    if len < HEADER_LEN || len > packet.len() {
+        return Err(UdpError::Length(len));
+    }
+    let packet = &packet[..len];
+    if u16::from_be_bytes([packet[6], packet[7]]) != 0 {
+        let sum = pseudo_header_sum(header.source, header.destination, len);
+        if ipv4::checksum_finish(ipv4::checksum_add(sum, packet)) != 0 {
+            return Err(UdpError::Checksum);
+        }
+    }
+    let source_port = u16::from_be_bytes([packet[0], packet[1]]);
+    let destination_port = u16::from_be_bytes([packet[2], packet[3]]);
+    Ok((source_port, destination_port, &packet[HEADER_LEN..]))
+}
+
+/// Writes the datagram from `source` to `destination` with `payload` to `buf`
+pub fn write(buf: &mut [u8], source: SocketAddress, destination: SocketAddress, payload: &[u8]) {
+    let len = HEADER_LEN + payload.len();
+    let buf = &mut buf[..len];
+    buf[0..2].copy_from_slice(&source.port.to_be_bytes());
+    buf[2..4].copy_from_slice(&destination.port.to_be_bytes());
+    buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[6..8].fill(0);
+    buf[HEADER_LEN..].copy_from_slice(payload);
+    let sum = pseudo_header_sum(source.ip, destination.ip, len);
+    let sum = match ipv4::checksum_finish(ipv4::checksum_add(sum, buf)) {
+        // `0` is no checksum, its other form is sent instead
+        0 => 0xFFFF,
+        sum => sum,
+    };
+    buf[6..8].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Sends the payload back where it came from, dropping it if the ring is full
+pub fn echo(interface: &mut Interface, datagram: &Datagram) {
+    // nothing to do on failure, the sender retries if it cares, it's counted already
+    let _ = interface.send_udp(
+        datagram.destination.port,
+        datagram.source,
+        Some(datagram.source_mac),
+        datagram.payload,
+    );
+}
@@ -0,0 +1,445 @@
+//! ELF core files of the crashed processes, written to `/tmp/core.<pid>` when the
+//! `Resource::CoreSize` limit of the process allows it, see [`kernel_user_link::core_dump`]
+//! for the layout.
+//!
+//! The file is written while the crashed process is still the current one, a chunk of its
+//! memory at a time, so the core is never whole in the kernel heap.
+
+use alloc::{format, string::String, vec, vec::Vec};
+use kernel_user_link::{
+    core_dump::{
+        self, CoreInfo, PrStatus, UserRegs, CORE_NOTE_NAME, ELF_HEADER_SIZE, EM_X86_64, ET_CORE,
+        KERNEL_NOTE_NAME, NT_CORE_INFO, NT_FILE, NT_PRSTATUS, PF_R, PF_W, PF_X,
+        PROGRAM_HEADER_SIZE, PT_LOAD, PT_NOTE,
+    },
+    process::Resource,
+    signal,
+};
+
+use crate::{
+    executable::elf::LoadedSegment,
+    fs::{self, FileSystemError},
+    memory_management::{
+        memory_layout::{align_down, align_up, KB, PAGE_4K},
+        virtual_memory_mapper::{flags, VirtualMemoryMapEntry},
+    },
+};
+
+use super::{crash::CrashInfo, scheduler::with_current_process, Process};
+
+/// The most of the user memory copied at once while writing
+const CHUNK_SIZE: usize = 64 * KB;
+
+/// A user mapping of the process, a `PT_LOAD` in the core file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Region {
+    start: u64,
+    end: u64,
+    /// `PF_*`
+    flags: u32,
+    /// The contents are written, the others are read-only parts of the program
+    dumped: bool,
+    /// The offset in the program file, for the mappings of the program
+    file_offset: Option<u64>,
+}
+
+/// Splits the mappings at the segments of the program they come from
+fn regions(mappings: &[VirtualMemoryMapEntry], segments: &[LoadedSegment]) -> Vec<Region> {
+    let page_down = |address: u64| align_down(address as usize, PAGE_4K) as u64;
+    let page_up = |address: u64| align_up(address as usize, PAGE_4K) as u64;
+
+    let mut regions = Vec::new();
+    for mapping in mappings {
+        let writable = mapping.flags & flags::PTE_WRITABLE != 0;
+        let mut region_flags = PF_R;
+        if writable {
+            region_flags |= PF_W;
+        }
+        if mapping.flags & flags::PTE_NO_EXECUTE == 0 {
+            region_flags |= PF_X;
+        }
+
+        let end = mapping.virtual_address + mapping.size;
+        let mut start = mapping.virtual_address;
+        while start < end {
+            let segment = segments.iter().find(|segment| {
+                page_down(segment.range.start) <= start && start < page_up(segment.range.end)
+            });
+            let (region_end, file_offset, from_writable) = match segment {
+                Some(segment) => (
+                    end.min(page_up(segment.range.end)),
+                    Some(page_down(segment.file_offset) + start - page_down(segment.range.start)),
+                    segment.writable,
+                ),
+                None => (end, None, true),
+            };
+            regions.push(Region {
+                start,
+                end: region_end,
+                flags: region_flags,
+                // the relocated parts of the program are read-only, but not as in the file
+                dumped: writable || from_writable,
+                file_offset,
+            });
+            start = region_end;
+        }
+    }
+    regions
+}
+
+/// Where the parts of the file go, the contents of the regions are after the headers and
+/// the notes, at page boundaries
+#[derive(Debug)]
+struct CoreLayout {
+    notes_offset: u64,
+    /// The offset and the size in the file of each region
+    contents: Vec<(u64, u64)>,
+    /// The bytes of the regions left out to fit in `limit`
+    truncated: u64,
+}
+
+impl CoreLayout {
+    fn new(regions: &[Region], notes_len: usize, limit: u64) -> Self {
+        let notes_offset = (ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (regions.len() + 1)) as u64;
+        let mut offset = align_up(notes_offset as usize + notes_len, PAGE_4K) as u64;
+        let mut truncated = 0;
+        let mut contents = Vec::with_capacity(regions.len());
+        for region in regions {
+            let size = if region.dumped {
+                region.end - region.start
+            } else {
+                0
+            };
+            let room = align_down(limit.saturating_sub(offset) as usize, PAGE_4K) as u64;
+            let written = size.min(room);
+            truncated += size - written;
+            contents.push((offset, written));
+            offset += written;
+        }
+        Self {
+            notes_offset,
+            contents,
+            truncated,
+        }
+    }
+}
+
+/// What is needed from the crashed process, taken while it is locked
+pub struct CoreSource {
+    limit: u64,
+    ppid: u64,
+    path: String,
+    regions: Vec<Region>,
+}
+
+impl CoreSource {
+    /// `None` if the process doesn't want a core file
+    pub fn new(process: &Process) -> Option<Self> {
+        let limit = process.limits().get(Resource::CoreSize);
+        if limit == 0 {
+            return None;
+        }
+        Some(Self {
+            limit,
+            ppid: process.parent_id,
+            path: String::from(process.path()),
+            regions: regions(&process.vm.user_mappings(), &process.program_segments),
+        })
+    }
+}
+
+fn push_note(notes: &mut Vec<u8>, name: &str, ty: u32, desc: &[u8]) {
+    let mut header = [0; 32];
+    let len = core_dump::note_header(&mut header, name, desc.len(), ty);
+    notes.extend_from_slice(&header[..len]);
+    notes.extend_from_slice(desc);
+    notes.resize(core_dump::note_align(notes.len()), 0);
+}
+
+fn build_notes(info: &CrashInfo, source: &CoreSource, truncated: u64) -> Vec<u8> {
+    let r = &info.registers;
+    let signal = signal::signal_for_exception(info.vector) as i32;
+    let status = PrStatus {
+        signal,
+        current_signal: signal as u16,
+        pid: info.pid as u32,
+        ppid: source.ppid as u32,
+        regs: UserRegs {
+            r15: r.r15,
+            r14: r.r14,
+            r13: r.r13,
+            r12: r.r12,
+            rbp: r.rbp,
+            rbx: r.rbx,
+            r11: r.r11,
+            r10: r.r10,
+            r9: r.r9,
+            r8: r.r8,
+            rax: r.rax,
+            rcx: r.rcx,
+            rdx: r.rdx,
+            rsi: r.rsi,
+            rdi: r.rdi,
+            orig_rax: u64::MAX,
+            rip: r.rip,
+            cs: r.cs,
+            rflags: r.rflags,
+            rsp: r.rsp,
+            ss: r.ss,
+            // the data segments are all the user data one
+            ds: r.ss,
+            es: r.ss,
+            ..UserRegs::default()
+        },
+        ..PrStatus::default()
+    };
+
+    let files = source
+        .regions
+        .iter()
+        .filter_map(|region| Some((region, region.file_offset?)))
+        .collect::<Vec<_>>();
+    let mut file_desc = Vec::new();
+    for value in [files.len() as u64, PAGE_4K as u64] {
+        file_desc.extend_from_slice(&value.to_le_bytes());
+    }
+    for (region, file_offset) in &files {
+        for value in [region.start, region.end, file_offset / PAGE_4K as u64] {
+            file_desc.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for _ in &files {
+        file_desc.extend_from_slice(source.path.as_bytes());
+        file_desc.push(0);
+    }
+
+    let core_info = CoreInfo {
+        size_limit: source.limit,
+        truncated,
+        vector: info.vector as u64,
+        fault_address: info.fault_address,
+    };
+
+    let mut notes = Vec::new();
+    push_note(&mut notes, CORE_NOTE_NAME, NT_PRSTATUS, status.as_bytes());
+    push_note(&mut notes, CORE_NOTE_NAME, NT_FILE, &file_desc);
+    push_note(
+        &mut notes,
+        KERNEL_NOTE_NAME,
+        NT_CORE_INFO,
+        core_info.as_bytes(),
+    );
+    notes
+}
+
+fn elf_header(program_headers: usize) -> [u8; ELF_HEADER_SIZE] {
+    let mut header = [0; ELF_HEADER_SIZE];
+    // 64 bit, little endian, version 1
+    header[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    header[16..18].copy_from_slice(&ET_CORE.to_le_bytes());
+    header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[32..40].copy_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    header[52..54].copy_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header[56..58].copy_from_slice(&(program_headers as u16).to_le_bytes());
+    header
+}
+
+/// `values` are `offset, virtual address, physical address, file size, memory size, align`
+fn program_header(ty: u32, flags: u32, values: [u64; 6]) -> [u8; PROGRAM_HEADER_SIZE] {
+    let mut header = [0; PROGRAM_HEADER_SIZE];
+    header[..4].copy_from_slice(&ty.to_le_bytes());
+    header[4..8].copy_from_slice(&flags.to_le_bytes());
+    for (i, value) in values.iter().enumerate() {
+        header[8 + i * 8..16 + i * 8].copy_from_slice(&value.to_le_bytes());
+    }
+    header
+}
+
+/// The headers and the notes, everything before the contents of the regions
+fn build_headers(info: &CrashInfo, source: &CoreSource) -> (Vec<u8>, CoreLayout) {
+    // the size of the notes doesn't depend on the truncation
+    let notes_len = build_notes(info, source, 0).len();
+    let layout = CoreLayout::new(&source.regions, notes_len, source.limit);
+    let notes = build_notes(info, source, layout.truncated);
+
+    let mut headers = Vec::with_capacity(layout.notes_offset as usize + notes.len());
+    headers.extend_from_slice(&elf_header(source.regions.len() + 1));
+    let notes_values = [layout.notes_offset, 0, 0, notes_len as u64, 0, 4];
+    headers.extend_from_slice(&program_header(PT_NOTE, 0, notes_values));
+    for (region, &(offset, size)) in source.regions.iter().zip(&layout.contents) {
+        let memory_size = region.end - region.start;
+        let values = [offset, region.start, 0, size, memory_size, PAGE_4K as u64];
+        headers.extend_from_slice(&program_header(PT_LOAD, region.flags, values));
+    }
+    headers.extend_from_slice(&notes);
+    (headers, layout)
+}
+
+fn write_all(file: &mut fs::File, mut data: &[u8]) -> Result<(), FileSystemError> {
+    while !data.is_empty() {
+        let written = file.write(data)? as usize;
+        if written == 0 {
+            return Err(FileSystemError::NoSpace);
+        }
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+/// What was written, for the report
+pub struct CoreSummary {
+    pub path: String,
+    pub size: u64,
+    pub truncated: u64,
+}
+
+/// Writes the core file of the current process, which crashed with `info`
+pub fn write_core(info: &CrashInfo, source: &CoreSource) -> Result<CoreSummary, FileSystemError> {
+    let path = format!("/tmp/core.{}", info.pid);
+    match fs::create_file(&path) {
+        Ok(()) => {}
+        // not from an older crash, the pids are not reused, but someone made it
+        Err(FileSystemError::AlreadyExists) => fs::truncate(&path, 0)?,
+        Err(e) => return Err(e),
+    }
+    let mut file = fs::open(&path)?;
+
+    let (headers, layout) = build_headers(info, source);
+    write_all(&mut file, &headers)?;
+    let mut position = headers.len() as u64;
+    for (region, &(offset, size)) in source.regions.iter().zip(&layout.contents) {
+        if size == 0 {
+            continue;
+        }
+        write_all(&mut file, &vec![0; (offset - position) as usize])?;
+        let mut address = region.start;
+        while address < region.start + size {
+            let len = CHUNK_SIZE.min((region.start + size - address) as usize);
+            let mut chunk = with_current_process(|process| process.read_user_memory(address, len));
+            // it was mapped when the regions were taken, nothing should have changed
+            chunk.resize(len, 0);
+            write_all(&mut file, &chunk)?;
+            address += len as u64;
+        }
+        position = offset + size;
+    }
+
+    Ok(CoreSummary {
+        path,
+        size: position,
+        truncated: layout.truncated,
+    })
+}
+
+/// Builds the headers of a made up process, and checks the layout and the notes
+pub(super) fn run_self_tests() {
+    use kernel_user_link::crash_dump::CrashRegisters;
+
+    println!("Running core dump self tests...");
+
+    let mapping = |start: u64, size: u64, flags: u64| VirtualMemoryMapEntry {
+        virtual_address: start,
+        physical_address: None,
+        size,
+        flags: flags | flags::PTE_USER,
+    };
+    let segment = |start: u64, end: u64, file_offset: u64, writable: bool| LoadedSegment {
+        range: start..end,
+        file_offset,
+        writable,
+    };
+    let data = flags::PTE_WRITABLE | flags::PTE_NO_EXECUTE;
+    let mappings = [
+        // the code, then the data sharing the page of the file after it
+        mapping(0x40_0000, 0x2000, 0),
+        mapping(0x40_2000, 0x2000, data),
+        // the heap
+        mapping(0x80_0000, 0x3000, data),
+    ];
+    let segments = [
+        segment(0x40_0000, 0x40_1500, 0, false),
+        segment(0x40_2500, 0x40_4000, 0x1500, true),
+    ];
+    let regions = regions(&mappings, &segments);
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0].file_offset, Some(0));
+    assert!(!regions[0].dumped && regions[0].flags == PF_R | PF_X);
+    assert_eq!(regions[1].file_offset, Some(0x1000));
+    assert!(regions[1].dumped && regions[1].flags == PF_R | PF_W);
+    assert_eq!(regions[2].file_offset, None);
+
+    let mut source = CoreSource {
+        limit: u64::MAX,
+        ppid: 1,
+        path: String::from("/program"),
+        regions,
+    };
+    let info = CrashInfo {
+        pid: 7,
+        name: String::from("program"),
+        path: source.path.clone(),
+        vector: 14,
+        error_code: 6,
+        fault_address: 0xDEAD_0000,
+        stack_overflow: false,
+        registers: CrashRegisters {
+            rip: 0x40_0123,
+            rsp: 0x7FFF_F000,
+            ..CrashRegisters::default()
+        },
+    };
+
+    let (headers, layout) = build_headers(&info, &source);
+    let notes_offset = layout.notes_offset as usize;
+    assert_eq!(notes_offset, ELF_HEADER_SIZE + 4 * PROGRAM_HEADER_SIZE);
+    assert_eq!(layout.truncated, 0);
+    let data_start = align_up(headers.len(), PAGE_4K) as u64;
+    assert_eq!(
+        layout.contents,
+        [
+            (data_start, 0),
+            (data_start, 0x2000),
+            (data_start + 0x2000, 0x3000)
+        ]
+    );
+
+    // the notes are found again, and each starts 4 byte aligned
+    let notes = core_dump::notes(&headers[notes_offset..]).collect::<Vec<_>>();
+    assert_eq!(notes.len(), 3);
+    assert!(notes[0].name == "CORE" && notes[0].ty == NT_PRSTATUS);
+    let status = PrStatus::from_bytes(notes[0].desc).unwrap();
+    assert_eq!((status.pid, status.ppid, status.signal), (7, 1, 11));
+    assert_eq!((status.regs.rip, status.regs.rsp), (0x40_0123, 0x7FFF_F000));
+    assert!(notes[1].name == "CORE" && notes[1].ty == NT_FILE);
+    let file_values =
+        |i: usize| u64::from_le_bytes(notes[1].desc[i * 8..i * 8 + 8].try_into().unwrap());
+    assert_eq!((file_values(0), file_values(1)), (2, PAGE_4K as u64));
+    assert_eq!(
+        (file_values(5), file_values(6), file_values(7)),
+        (0x40_2000, 0x40_4000, 1)
+    );
+    assert_eq!(&notes[1].desc[8 * 8..], b"/program\0/program\0");
+    assert!(notes[2].name == "KERNEL" && notes[2].ty == NT_CORE_INFO);
+    let core_info = CoreInfo::from_bytes(notes[2].desc).unwrap();
+    assert_eq!(
+        (core_info.truncated, core_info.fault_address),
+        (0, 0xDEAD_0000)
+    );
+
+    // with a limit, the data is cut at a page, the heap is left out, and the headers stay
+    source.limit = data_start + 0x1800;
+    let (cut_headers, cut) = build_headers(&info, &source);
+    assert_eq!(cut_headers.len(), headers.len());
+    assert_eq!(cut.contents[1], (data_start, 0x1000));
+    assert_eq!(cut.contents[2], (data_start + 0x1000, 0));
+    assert_eq!(cut.truncated, 0x1000 + 0x3000);
+    let notes = core_dump::notes(&cut_headers[notes_offset..]).collect::<Vec<_>>();
+    assert_eq!(
+        CoreInfo::from_bytes(notes[2].desc).unwrap().truncated,
+        cut.truncated
+    );
+
+    println!("Core dump self tests passed");
+}
@@ -0,0 +1,206 @@
+//! Killing user processes on CPU exceptions.
+//!
+//! A report is printed on the console, and a dump with the registers, the top of the user
+//! stack and the mappings of the process is left in `/tmp/crash-<pid>-<name>.dump` for
+//! post-mortem debugging (see [`kernel_user_link::crash_dump`] for the format). An ELF core
+//! file for debuggers is written too if the process asked for it, see [`core_dump`].
+
+use core::fmt;
+
+use alloc::{format, string::String, vec::Vec};
+use kernel_user_link::{
+    crash_dump::{self, CrashHeader, CrashRegisters, SectionKind},
+    process::EXIT_CODE_CRASH,
+};
+
+use crate::{
+    cpu::{self, idt::InterruptAllSavedState},
+    fs::ramfs,
+    memory_management::memory_layout::KB,
+};
+
+use super::{
+    core_dump::{self, CoreSource},
+    scheduler::{exit_current_process, with_current_process},
+    Process,
+};
+
+/// The most of the user stack that is put in the dump
+const STACK_DUMP_SIZE: usize = 16 * KB;
+
+fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide by zero",
+        4 => "overflow",
+        5 => "bound range exceeded",
+        6 => "invalid opcode",
+        7 => "device not available",
+        12 => "stack segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating point",
+        17 => "alignment check",
+        19 => "SIMD floating point",
+        _ => "unknown exception",
+    }
+}
+
+/// What we know about a process that was killed by an exception
+pub struct CrashInfo {
+    pub pid: u64,
+    pub name: String,
+    pub path: String,
+    pub vector: u8,
+    pub error_code: u64,
+    /// `cr2`, only meaningful for page faults
+    pub fault_address: u64,
+    /// The page fault was below where the stack can grow, see [`Process::is_stack_overflow`]
+    pub stack_overflow: bool,
+    pub registers: CrashRegisters,
+}
+
+impl CrashInfo {
+    fn new(process: &Process, all_state: &InterruptAllSavedState) -> Self {
+        let rest = &all_state.rest;
+        let frame = &all_state.frame;
+        // SAFETY: reading cr2 has no side effects
+        let fault_address = unsafe { cpu::get_cr2() };
+        Self {
+            pid: process.id(),
+            name: String::from(process.name()),
+            path: String::from(process.path()),
+            vector: all_state.number as u8,
+            error_code: all_state.error,
+            fault_address,
+            stack_overflow: all_state.number == 14 && process.is_stack_overflow(fault_address),
+            registers: CrashRegisters {
+                rip: frame.rip,
+                rsp: frame.rsp,
+                rflags: frame.rflags,
+                rax: rest.rax,
+                rbx: rest.rbx,
+                rcx: rest.rcx,
+                rdx: rest.rdx,
+                rsi: rest.rsi,
+                rdi: rest.rdi,
+                rbp: rest.rbp,
+                r8: rest.r8,
+                r9: rest.r9,
+                r10: rest.r10,
+                r11: rest.r11,
+                r12: rest.r12,
+                r13: rest.r13,
+                r14: rest.r14,
+                r15: rest.r15,
+                cs: frame.cs as u64,
+                ss: frame.ss as u64,
+            },
+        }
+    }
+
+    fn header(&self) -> CrashHeader {
+        CrashHeader {
+            pid: self.pid,
+            vector: self.vector as u64,
+            error_code: self.error_code,
+            fault_address: self.fault_address,
+        }
+    }
+}
+
+impl fmt::Display for CrashInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Process {} ({}, {}) killed by {} [{}], error: {:#X}",
+            self.pid,
+            self.name,
+            self.path,
+            exception_name(self.vector),
+            self.vector,
+            self.error_code
+        )?;
+        if self.vector == 14 {
+            write!(f, ", address: {:#X}", self.fault_address)?;
+            if self.stack_overflow {
+                write!(f, " (stack overflow)")?;
+            }
+        }
+        for (i, (name, value)) in self.registers.named().iter().enumerate() {
+            let separator = if i % 4 == 0 { "\n " } else { " " };
+            write!(f, "{separator}{name:>6}: {value:016X}")?;
+        }
+        Ok(())
+    }
+}
+
+fn push_section(dump: &mut Vec<u8>, kind: SectionKind, parts: &[&[u8]]) {
+    let len = parts.iter().map(|part| part.len()).sum();
+    dump.extend_from_slice(&crash_dump::section_header(kind, len));
+    for part in parts {
+        dump.extend_from_slice(part);
+    }
+}
+
+fn build_dump(info: &CrashInfo, stack: &[u8], maps: &str) -> Vec<u8> {
+    let mut dump = Vec::new();
+    dump.extend_from_slice(&crash_dump::file_header());
+    push_section(
+        &mut dump,
+        SectionKind::Header,
+        &[info.header().as_bytes(), info.path.as_bytes()],
+    );
+    push_section(
+        &mut dump,
+        SectionKind::Registers,
+        &[info.registers.as_bytes()],
+    );
+    push_section(
+        &mut dump,
+        SectionKind::Stack,
+        &[&info.registers.rsp.to_le_bytes(), stack],
+    );
+    push_section(&mut dump, SectionKind::Maps, &[maps.as_bytes()]);
+    dump
+}
+
+/// Kills the current process after it caused a CPU exception in user mode.
+///
+/// Writing the dump is best-effort, the process is killed even if it fails
+pub fn kill_current_process(all_state: &mut InterruptAllSavedState) {
+    let (info, stack, maps, core) = with_current_process(|process| {
+        let info = CrashInfo::new(process, all_state);
+        let stack = process.read_user_memory(info.registers.rsp, STACK_DUMP_SIZE);
+        (info, stack, process.user_maps(), CoreSource::new(process))
+    });
+    println!("{info}");
+
+    let file_name = format!("crash-{}-{}.dump", info.pid, info.name);
+    match ramfs::create_tmp_file(&file_name, build_dump(&info, &stack, &maps)) {
+        Ok(()) => {
+            println!("Crash dump written to /tmp/{file_name}");
+        }
+        Err(e) => {
+            println!("Could not write the crash dump /tmp/{file_name}: {e:?}");
+        }
+    }
+    if let Some(core) = core {
+        match core_dump::write_core(&info, &core) {
+            Ok(summary) => {
+                print!(
+                    "Core file written to {} ({} bytes",
+                    summary.path, summary.size
+                );
+                if summary.truncated != 0 {
+                    print!(", {} bytes over the limit left out", summary.truncated);
+                }
+                println!(")");
+            }
+            Err(e) => {
+                println!("Could not write the core file: {e:?}");
+            }
+        }
+    }
+
+    exit_current_process(EXIT_CODE_CRASH, all_state);
+}
@@ -0,0 +1,149 @@
+//! The kernel stacks of the threads of a process.
+//!
+//! Each thread gets its own stack in a slot of the process specific kernel range (see
+//! [`process_kernel_stack_base`]), with an unmapped guard page below it, so an overflow
+//! faults instead of writing into the stack of another thread. The first thread uses slot
+//! `0`, mapped with the process, the others are allocated and mapped when asked for, and
+//! freed one by one when they are done.
+
+use alloc::vec::Vec;
+
+use crate::memory_management::{
+    memory_layout::{
+        process_kernel_stack_base, PROCESS_KERNEL_STACK_GUARD, PROCESS_KERNEL_STACK_SIZE,
+        PROCESS_KERNEL_STACK_SLOTS,
+    },
+    physical_page_allocator,
+    virtual_memory_mapper::{self, VirtualMemoryMapper},
+};
+
+use super::ProcessError;
+
+const _: () = assert!(PROCESS_KERNEL_STACK_SLOTS <= u64::BITS as usize);
+
+/// A kernel stack of a thread, in `slot`
+#[derive(Debug, PartialEq, Eq)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// The stack of the first thread, mapped by
+    /// [`VirtualMemoryMapper::add_process_specific_mappings`]
+    pub const fn first() -> Self {
+        Self { slot: 0 }
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// The stack pointer to start from when entering the kernel in this thread
+    pub fn end(&self) -> usize {
+        process_kernel_stack_base(self.slot) + PROCESS_KERNEL_STACK_SIZE - 8
+    }
+}
+
+/// The slots used by the threads of a process
+#[derive(Debug)]
+pub struct KernelStacks {
+    used: u64,
+}
+
+impl KernelStacks {
+    /// Only the slot of [`KernelStack::first`] is used
+    pub const fn new() -> Self {
+        Self { used: 1 }
+    }
+
+    pub fn used_count(&self) -> usize {
+        self.used.count_ones() as usize
+    }
+
+    /// Maps the stack of a new thread in the lowest free slot, fails with
+    /// [`ProcessError::TooManyThreads`] if they are all used
+    pub fn allocate(&mut self, vm: &mut VirtualMemoryMapper) -> Result<KernelStack, ProcessError> {
+        let slot = (!self.used).trailing_zeros() as usize;
+        if slot >= PROCESS_KERNEL_STACK_SLOTS {
+            return Err(ProcessError::TooManyThreads);
+        }
+        self.used |= 1 << slot;
+        vm.map_kernel_stack(slot);
+        Ok(KernelStack { slot })
+    }
+
+    /// Unmaps and frees `stack`, it must not be the one running, and not the first, which
+    /// is freed with the process
+    pub fn free(&mut self, vm: &mut VirtualMemoryMapper, stack: KernelStack) {
+        assert_ne!(
+            stack.slot, 0,
+            "the first kernel stack is freed with the process"
+        );
+        assert!(self.used & (1 << stack.slot) != 0);
+        vm.unmap_kernel_stack(stack.slot);
+        self.used &= !(1 << stack.slot);
+    }
+}
+
+/// Allocates the stacks of a VM until they run out and frees them again, a few times, the
+/// rounds after the first (which creates the page tables) must not use any more memory
+pub fn run_self_tests() {
+    const ROUNDS: usize = 4;
+    println!("Running kernel stacks self tests...");
+
+    let mut vm = virtual_memory_mapper::clone_current_vm_as_user();
+    // SAFETY: the VM is never switched to
+    unsafe { vm.add_process_specific_mappings() };
+    let mut stacks = KernelStacks::new();
+    let mut stats_after_first = None;
+
+    for round in 0..ROUNDS {
+        let mut allocated = Vec::new();
+        loop {
+            match stacks.allocate(&mut vm) {
+                Ok(stack) => allocated.push(stack),
+                Err(ProcessError::TooManyThreads) => break,
+                Err(e) => panic!("kernel stack allocation failed: {e:?}"),
+            }
+        }
+        assert_eq!(allocated.len(), PROCESS_KERNEL_STACK_SLOTS - 1);
+        assert_eq!(stacks.used_count(), PROCESS_KERNEL_STACK_SLOTS);
+
+        for (i, stack) in allocated.iter().enumerate() {
+            assert_eq!(stack.slot(), i + 1);
+            let base = process_kernel_stack_base(stack.slot());
+            assert!(vm.is_address_mapped(base as u64));
+            assert!(vm.is_address_mapped(stack.end() as u64));
+            // the guard page below it, and the end of the one below that
+            assert!(!vm.is_address_mapped(base as u64 - 1));
+            assert!(vm.is_address_mapped((base - PROCESS_KERNEL_STACK_GUARD - 8) as u64));
+        }
+
+        // free them out of order, then the lowest free slot is used again
+        while !allocated.is_empty() {
+            let stack = allocated.swap_remove(round % allocated.len());
+            let slot = stack.slot();
+            stacks.free(&mut vm, stack);
+            assert!(!vm.is_address_mapped(process_kernel_stack_base(slot) as u64));
+        }
+        assert_eq!(stacks.used_count(), 1);
+        let stack = stacks.allocate(&mut vm).unwrap();
+        assert_eq!(stack.slot(), 1);
+        stacks.free(&mut vm, stack);
+
+        let (free, used) = physical_page_allocator::stats();
+        match stats_after_first {
+            None => stats_after_first = Some(free - used),
+            Some(expected) => assert_eq!(
+                free - used,
+                expected,
+                "kernel stacks leaked physical pages in round {round}"
+            ),
+        }
+    }
+
+    assert!(vm.is_address_mapped(KernelStack::first().end() as u64));
+    vm.unmap_process_memory();
+
+    println!("Kernel stacks self tests passed ({ROUNDS} rounds)");
+}
@@ -0,0 +1,53 @@
+//! Threads of the kernel, for the work that is done in the background, i.e. the writeback of
+//! the block devices.
+//!
+//! A kernel thread is a [`Process`] like the others for the scheduler, in its queue and with
+//! its own turns, but it runs in ring 0 on its kernel stack, and has no memory of the user. It
+//! can be switched out by the timer anywhere it could be in a syscall, i.e. not while holding
+//! a spin lock, and parks itself with [`sleep_until`], which goes through its own interrupt,
+//! as it has no syscalls. It never exits, and no signal is sent to it.
+
+use crate::cpu::{self, idt::InterruptAllSavedState};
+
+use super::{scheduler, Process, ProcessName};
+
+/// Starts `entry` in a new kernel thread named `name`, it runs after the processes before it
+/// in the queue of the CPU, returns its pid
+pub fn spawn(name: &str, entry: fn() -> !) -> u64 {
+    let name = ProcessName::new(name).expect("invalid kernel thread name");
+    let process =
+        Process::new_kernel_thread(name, start as *const () as u64, entry as usize as u64);
+    let id = process.id();
+    scheduler::push_process(process);
+    id
+}
+
+/// The first code of the kernel threads, with the `entry` given to [`spawn`]
+extern "C" fn start(entry: usize) -> ! {
+    // SAFETY: made from a `fn() -> !` by `spawn`
+    let entry: fn() -> ! = unsafe { core::mem::transmute(entry) };
+    entry()
+}
+
+/// Parks the current kernel thread until the monotonic time is `deadline`, the others run
+/// meanwhile.
+///
+/// Must be called from a kernel thread, with the interrupts enabled, and nothing keeping it
+/// on this CPU
+pub fn sleep_until(deadline: u64) {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.n_cli() == 0 && current_cpu.n_no_yield() == 0);
+    // call `interrupt_handler`, at `SPECIAL_KERNEL_THREAD_INTERRUPT`, it's `0` in `rax` when
+    // it's woken, like the result of `SYS_SLEEP`
+    unsafe { core::arch::asm!("int 0xfd", in("rdi") deadline, out("rax") _) }
+}
+
+pub extern "cdecl" fn interrupt_handler(all_state: &mut InterruptAllSavedState) {
+    assert!(all_state.frame.cs & 0x3 == 0, "must be from kernel only");
+    assert!(
+        scheduler::with_current_process(|process| process.is_kernel_thread()),
+        "must be from a kernel thread"
+    );
+    let deadline = all_state.rest.rdi;
+    scheduler::sleep_current(all_state, deadline);
+}
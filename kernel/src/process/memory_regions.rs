@@ -0,0 +1,294 @@
+//! The ranges of the user memory of a process, recorded as they are mapped, so they can be
+//! changed and freed without looking through the page tables
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::memory_management::{
+    memory_layout::PAGE_4K,
+    virtual_memory_mapper::{flags, VirtualMemoryMapEntry},
+};
+
+/// What the pages of a region are used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The segments of the program file
+    Elf,
+    Stack,
+    Heap,
+    /// The time page, shared by all the processes, see [`time_page`](crate::devices::clock::time_page)
+    TimePage,
+    /// A mapping of a [`shm`](crate::memory_management::shm) object, with a free page on each
+    /// side, so it's never merged with another
+    SharedMemory,
+    /// A mapping of a file, like [`Self::SharedMemory`], its pages are read from the file on
+    /// the first access, so only some of them can be mapped
+    File,
+}
+
+impl RegionKind {
+    /// The process has a reference to the pages, they were allocated for it or shared with
+    /// it, so they are freed when it unmaps them, and the last one unmapping them
+    pub fn is_allocated(self) -> bool {
+        !matches!(self, Self::TimePage)
+    }
+
+    /// The pages are only this process', `fork` shares them copy-on-write, and they can be
+    /// protected, the others are the same pages in all the processes mapping them
+    pub fn is_private(self) -> bool {
+        matches!(self, Self::Elf | Self::Stack | Self::Heap)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    /// The flags the pages were mapped with, a shared page is still `WRITABLE` here when the
+    /// page table has it copy-on-write
+    pub flags: u64,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    pub fn as_entry(&self) -> VirtualMemoryMapEntry {
+        VirtualMemoryMapEntry {
+            virtual_address: self.start,
+            physical_address: None,
+            size: self.end - self.start,
+            flags: self.flags,
+        }
+    }
+}
+
+/// The regions of a process by their start, they don't overlap, and the ones next to each
+/// other with the same kind and flags are merged
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRegions {
+    regions: BTreeMap<u64, MemoryRegion>,
+}
+
+impl MemoryRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.values()
+    }
+
+    /// The region containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&MemoryRegion> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| addr < region.end)
+    }
+
+    /// Records a new region, the range must be page aligned and not overlap the others
+    pub fn insert(&mut self, region: MemoryRegion) {
+        let MemoryRegion {
+            mut start,
+            mut end,
+            flags,
+            kind,
+        } = region;
+        assert!(start < end && (start | end) % PAGE_4K as u64 == 0);
+        assert!(
+            self.overlapping(start, end).next().is_none(),
+            "region {region:x?} overlaps another"
+        );
+
+        let same = |other: &MemoryRegion| other.flags == flags && other.kind == kind;
+        let before = start.checked_sub(1).and_then(|addr| self.find(addr));
+        if let Some(before) = before.copied().filter(same) {
+            self.regions.remove(&before.start);
+            start = before.start;
+        }
+        if let Some(after) = self.regions.get(&end).copied().filter(same) {
+            self.regions.remove(&after.start);
+            end = after.end;
+        }
+        self.regions.insert(
+            start,
+            MemoryRegion {
+                start,
+                end,
+                flags,
+                kind,
+            },
+        );
+    }
+
+    /// Forgets `start..end`, the regions on its edges are cut, returns the parts removed
+    pub fn remove(&mut self, start: u64, end: u64) -> Vec<MemoryRegion> {
+        let overlapping: Vec<MemoryRegion> = self.overlapping(start, end).copied().collect();
+        let mut removed = Vec::with_capacity(overlapping.len());
+        for region in overlapping {
+            self.regions.remove(&region.start);
+            if region.start < start {
+                self.regions.insert(
+                    region.start,
+                    MemoryRegion {
+                        end: start,
+                        ..region
+                    },
+                );
+            }
+            if end < region.end {
+                self.regions.insert(
+                    end,
+                    MemoryRegion {
+                        start: end,
+                        ..region
+                    },
+                );
+            }
+            removed.push(MemoryRegion {
+                start: region.start.max(start),
+                end: region.end.min(end),
+                ..region
+            });
+        }
+        removed
+    }
+
+    /// Whether all of `start..end` is recorded, with no gaps
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        let mut next = start;
+        for region in self.overlapping(start, end) {
+            if region.start > next {
+                return false;
+            }
+            next = region.end;
+        }
+        next >= end
+    }
+
+    /// Gives `start..end` the new `flags`, it must be all recorded
+    pub fn protect(&mut self, start: u64, end: u64, flags: u64) {
+        assert!(self.covers(start, end));
+        for region in self.remove(start, end) {
+            self.insert(MemoryRegion { flags, ..region });
+        }
+    }
+
+    /// The lowest address in `start..end` with `size` bytes free after it, page aligned
+    pub fn find_gap(&self, start: u64, end: u64, size: u64) -> Option<u64> {
+        let mut next = start;
+        for region in self.overlapping(start, end) {
+            if region.start >= next.checked_add(size)? {
+                break;
+            }
+            next = next.max(region.end);
+        }
+        (next.checked_add(size)? <= end).then_some(next)
+    }
+
+    /// The regions with part of `start..end` in them
+    pub fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &MemoryRegion> {
+        // the one before `start` can go into the range
+        let first = self.find(start).map_or(start, |region| region.start);
+        self.regions
+            .range(first..end)
+            .map(|(_, region)| region)
+            .filter(move |region| region.end > start)
+    }
+}
+
+/// Cuts, merges and protects a few regions, checking all of them after each step
+pub fn run_self_tests() {
+    const PAGE: u64 = PAGE_4K as u64;
+    const RW: u64 = flags::PTE_USER | flags::PTE_WRITABLE;
+    const RO: u64 = flags::PTE_USER;
+
+    println!("Running memory regions self tests...");
+    let region = |start: u64, end: u64, flags: u64, kind: RegionKind| MemoryRegion {
+        start: start * PAGE,
+        end: end * PAGE,
+        flags,
+        kind,
+    };
+    let check = |regions: &MemoryRegions, expected: &[MemoryRegion]| {
+        let got: Vec<MemoryRegion> = regions.iter().copied().collect();
+        assert_eq!(got, expected, "memory regions self test");
+    };
+
+    let mut regions = MemoryRegions::new();
+    regions.insert(region(4, 6, RW, RegionKind::Heap));
+    // merged with the one before, but not with another kind, or other flags
+    regions.insert(region(6, 8, RW, RegionKind::Heap));
+    regions.insert(region(2, 4, RW, RegionKind::Elf));
+    regions.insert(region(8, 9, RO, RegionKind::Heap));
+    regions.insert(region(0, 1, RO, RegionKind::Elf));
+    check(
+        &regions,
+        &[
+            region(0, 1, RO, RegionKind::Elf),
+            region(2, 4, RW, RegionKind::Elf),
+            region(4, 8, RW, RegionKind::Heap),
+            region(8, 9, RO, RegionKind::Heap),
+        ],
+    );
+    assert!(regions.covers(2 * PAGE, 9 * PAGE) && regions.covers(5 * PAGE, 6 * PAGE));
+    assert!(!regions.covers(0, 3 * PAGE) && !regions.covers(8 * PAGE, 10 * PAGE));
+    assert_eq!(
+        regions.find(5 * PAGE + 8),
+        Some(&region(4, 8, RW, RegionKind::Heap))
+    );
+    assert!(regions.find(PAGE).is_none() && regions.find(9 * PAGE).is_none());
+
+    // the middle of one, then the one after is merged back
+    regions.protect(5 * PAGE, 6 * PAGE, RO);
+    regions.protect(7 * PAGE, 8 * PAGE, RO);
+    check(
+        &regions,
+        &[
+            region(0, 1, RO, RegionKind::Elf),
+            region(2, 4, RW, RegionKind::Elf),
+            region(4, 5, RW, RegionKind::Heap),
+            region(5, 6, RO, RegionKind::Heap),
+            region(6, 7, RW, RegionKind::Heap),
+            region(7, 9, RO, RegionKind::Heap),
+        ],
+    );
+    regions.protect(3 * PAGE, 9 * PAGE, RW);
+    check(
+        &regions,
+        &[
+            region(0, 1, RO, RegionKind::Elf),
+            region(2, 4, RW, RegionKind::Elf),
+            region(4, 9, RW, RegionKind::Heap),
+        ],
+    );
+
+    // the parts removed are given back, as they were
+    let removed = regions.remove(3 * PAGE, 6 * PAGE);
+    assert_eq!(
+        removed,
+        [
+            region(3, 4, RW, RegionKind::Elf),
+            region(4, 6, RW, RegionKind::Heap)
+        ]
+    );
+    check(
+        &regions,
+        &[
+            region(0, 1, RO, RegionKind::Elf),
+            region(2, 3, RW, RegionKind::Elf),
+            region(6, 9, RW, RegionKind::Heap),
+        ],
+    );
+    assert!(regions.remove(10 * PAGE, 20 * PAGE).is_empty());
+
+    // the holes between them, and after the last one
+    assert_eq!(regions.find_gap(0, 20 * PAGE, PAGE), Some(PAGE));
+    assert_eq!(regions.find_gap(0, 20 * PAGE, 3 * PAGE), Some(3 * PAGE));
+    assert_eq!(regions.find_gap(0, 20 * PAGE, 4 * PAGE), Some(9 * PAGE));
+    assert_eq!(regions.find_gap(7 * PAGE, 20 * PAGE, PAGE), Some(9 * PAGE));
+    assert_eq!(regions.find_gap(0, 12 * PAGE, 4 * PAGE), None);
+
+    println!("Memory regions self tests passed");
+}
@@ -1,41 +1,181 @@
+mod core_dump;
+pub mod crash;
+mod kernel_stack;
+pub mod kernel_thread;
+mod memory_regions;
 pub mod scheduler;
+pub mod signal;
 mod syscalls;
+mod thread;
+pub mod timers;
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::{
+    fmt::{self, Write},
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use kernel_user_link::{
+    process::{Resource, PRIORITY_DEFAULT, PROCESS_NAME_LEN, RLIMIT_INFINITY},
+    startup::{
+        AuxEntry, StackLayout, AT_ENTRY, AT_EXECFN, AT_IGNORE, AT_NULL, AT_PAGESZ, AT_RANDOM,
+        AT_RANDOM_SIZE, AT_TIME_PAGE, STACK_ALIGNMENT,
+    },
+};
+
+use memory_regions::{MemoryRegion, MemoryRegions, RegionKind};
 
 use crate::{
-    cpu::{self, gdt},
+    cpu::{self, exception_table, gdt, idt::InterruptAllSavedState},
+    devices::{clock::time_page, random},
     executable::{elf, load_elf_to_vm},
     fs,
     memory_management::{
-        memory_layout::{align_up, is_aligned, GB, KERNEL_BASE, MB, PAGE_2M, PAGE_4K},
+        memory_layout::{align_down, align_up, is_aligned, GB, KERNEL_BASE, MB, PAGE_2M, PAGE_4K},
+        physical_page_allocator,
+        shm::SharedMemory,
         virtual_memory_mapper::{
-            self, VirtualMemoryMapEntry, VirtualMemoryMapper, MAX_USER_VIRTUAL_ADDRESS,
+            self, max_page_tables_for, VirtualMemoryMapEntry, VirtualMemoryMapper,
+            MAX_USER_VIRTUAL_ADDRESS,
         },
     },
 };
 
 static PROCESS_ID_ALLOCATOR: GoingUpAllocator = GoingUpAllocator::new();
+/// The first process, its the only one allowed to raise resource limits
+pub const INIT_PID: u64 = 0;
 const INITIAL_STACK_SIZE_PAGES: usize = 4;
+/// The top of the stack of the main thread, it grows down from here
+const USER_STACK_END: usize = MAX_USER_VIRTUAL_ADDRESS - PAGE_4K;
+/// The space kept below [`USER_STACK_END`] for the stack to grow into, nothing else is mapped
+/// there. Its lowest page is the guard, which is never mapped, so a stack with no
+/// `Resource::StackSize` limit still can't grow past it
+const STACK_RESERVED_SIZE: usize = 256 * MB;
+// the entries in the auxiliary vector, without `AT_NULL`
+const AUXV_ENTRIES: usize = 5;
+/// Where the time page is mapped read-only, if the kernel offers it, far from the stack and
+/// the heap
+const TIME_PAGE_ADDRESS: usize = MAX_USER_VIRTUAL_ADDRESS - GB;
+/// Where the shared memory and the files are mapped, below the time page
+const MAPPINGS_END: usize = TIME_PAGE_ADDRESS;
+const MAPPINGS_START: usize = MAPPINGS_END - 64 * GB;
 
 #[allow(clippy::identity_op)]
 const HEAP_OFFSET_FROM_ELF_END: usize = 1 * MB;
 #[allow(clippy::identity_op)]
 const DEAFULT_MAX_HEAP_SIZE: usize = 1 * GB;
 
+/// The lowest address the stack can grow down to with the `limit` in bytes, above the guard
+/// page
+fn stack_growth_start(limit: u64) -> u64 {
+    let guard_end = USER_STACK_END - STACK_RESERVED_SIZE + PAGE_4K;
+    let limit = limit.min(STACK_RESERVED_SIZE as u64) as usize;
+    let limited = USER_STACK_END - align_down(limit, PAGE_4K);
+    guard_end.max(limited) as u64
+}
+
 #[derive(Debug)]
 pub enum ProcessError {
-    CouldNotLoadElf(fs::FileSystemError),
+    CouldNotLoadElf(elf::ElfLoadError),
+    HeapRangesExceeded,
+    MemoryLimitExceeded,
+    OpenFilesLimitExceeded,
+    /// The arguments don't fit in the initial stack
+    ArgumentsTooLarge,
+    /// All the kernel stack slots are used by threads
+    TooManyThreads,
+    /// Part of the range is not in the memory regions of the process
+    RangeNotMapped,
+    /// The range has memory the process doesn't own, i.e. the time page, shared memory or files
+    RangeNotOwned,
+    /// There is no free space in the area of the mappings for the mapping
+    NoAddressSpace,
 }
 
 impl From<fs::FileSystemError> for ProcessError {
     fn from(e: fs::FileSystemError) -> Self {
+        Self::CouldNotLoadElf(e.into())
+    }
+}
+
+impl From<elf::ElfLoadError> for ProcessError {
+    fn from(e: elf::ElfLoadError) -> Self {
         Self::CouldNotLoadElf(e)
     }
 }
 
+/// The limits of the resources a process can use, these are inherited by the children
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    memory_pages: u64,
+    open_files: u64,
+    cpu_time_ms: u64,
+    core_size: u64,
+    stack_size: u64,
+}
+
+impl ResourceLimits {
+    /// Nothing is limited, and no core files are written, the stack still only grows in the
+    /// space kept for it
+    pub const fn unlimited() -> Self {
+        Self {
+            memory_pages: RLIMIT_INFINITY,
+            open_files: RLIMIT_INFINITY,
+            cpu_time_ms: RLIMIT_INFINITY,
+            core_size: 0,
+            stack_size: RLIMIT_INFINITY,
+        }
+    }
+
+    /// The limits for `init`, from the `rlimit.memory=<pages>`, `rlimit.files=<count>`,
+    /// `rlimit.cpu=<ms>`, `rlimit.core=<bytes>` and `rlimit.stack=<bytes>` options of the
+    /// kernel cmdline, the rest are as [`ResourceLimits::unlimited`]
+    pub fn from_cmdline(cmdline: &str) -> Self {
+        let mut limits = Self::unlimited();
+        for arg in cmdline.split_whitespace() {
+            let Some((name, value)) = arg.split_once('=') else {
+                continue;
+            };
+            let resource = match name {
+                "rlimit.memory" => Resource::MemoryPages,
+                "rlimit.files" => Resource::OpenFiles,
+                "rlimit.cpu" => Resource::CpuTimeMs,
+                "rlimit.core" => Resource::CoreSize,
+                "rlimit.stack" => Resource::StackSize,
+                _ => continue,
+            };
+            match value.parse::<u64>() {
+                Ok(value) => limits.set(resource, value.min(RLIMIT_INFINITY)),
+                Err(_) => {
+                    eprintln!("Invalid value for `{name}`: {value}");
+                }
+            }
+        }
+        limits
+    }
+
+    pub fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::MemoryPages => self.memory_pages,
+            Resource::OpenFiles => self.open_files,
+            Resource::CpuTimeMs => self.cpu_time_ms,
+            Resource::CoreSize => self.core_size,
+            Resource::StackSize => self.stack_size,
+        }
+    }
+
+    pub fn set(&mut self, resource: Resource, value: u64) {
+        match resource {
+            Resource::MemoryPages => self.memory_pages = value,
+            Resource::OpenFiles => self.open_files = value,
+            Resource::CpuTimeMs => self.cpu_time_ms = value,
+            Resource::CoreSize => self.core_size = value,
+            Resource::StackSize => self.stack_size = value,
+        }
+    }
+}
+
 struct GoingUpAllocator {
     next_id: AtomicU64,
 }
@@ -89,20 +229,107 @@ pub struct ProcessContext {
     pub r13: u64,
     pub r14: u64,
     pub r15: u64,
+    // the TLS of the user, set with `SYS_THREAD_CREATE`
+    pub fs_base: u64,
     pub fxsave: FxSave,
 }
 
+/// The name of a process in the diagnostics, see [`PROCESS_NAME_LEN`].
+///
+/// Its a fixed array, so it can be copied without allocating, i.e. into the CPU for the
+/// interrupts that can't lock the scheduler
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ProcessName {
+    bytes: [u8; PROCESS_NAME_LEN],
+    len: u8,
+}
+
+impl ProcessName {
+    pub const fn empty() -> Self {
+        Self {
+            bytes: [0; PROCESS_NAME_LEN],
+            len: 0,
+        }
+    }
+
+    fn is_valid_char(c: char) -> bool {
+        !c.is_whitespace() && !c.is_control() && c != '/'
+    }
+
+    /// `name` cut to [`PROCESS_NAME_LEN`] bytes, `None` if it is empty or has a character
+    /// that can't be in a name
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || !name.chars().all(Self::is_valid_char) {
+            return None;
+        }
+        let mut len = name.len().min(PROCESS_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; PROCESS_NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Some(Self {
+            bytes,
+            len: len as u8,
+        })
+    }
+
+    /// The file name of `path`, with the characters that can't be in a name replaced by `_`
+    pub fn from_path(path: &str) -> Self {
+        let file_name = path.rsplit('/').next().unwrap_or_default();
+        let mut name = String::with_capacity(file_name.len());
+        for c in file_name.chars() {
+            name.push(if Self::is_valid_char(c) { c } else { '_' });
+        }
+        Self::new(&name).unwrap_or(Self::new("?").unwrap())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: only made from a `str`, cut at a character boundary
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len as usize]) }
+    }
+}
+
+impl fmt::Display for ProcessName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for ProcessName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
     Running,
     Yielded, // Not used now, but should be scheduled next
     Scheduled,
-    Sleeping,
+    /// Until the monotonic time in nanoseconds, see [`timers`]
+    Sleeping(u64),
     Exited,
     WaitingForPid(u64),
+    /// In the queue of a file lock, see [`crate::fs::locks`]
+    WaitingForLock,
+    /// Waiting for the thread of the process with this id to exit
+    WaitingForThread(u64),
+    /// Waiting for `SYS_FUTEX_WAKE` of this user address
+    WaitingForFutex(u64),
+    /// Only this thread exited, the scheduler gives the turn to another, see
+    /// [`Process::reap_thread`]
+    ThreadExited,
+}
+
+/// The file of a [`RegionKind::File`] region, it stays open while it's mapped, even after
+/// its descriptor is closed
+struct FileMapping {
+    file: fs::File,
+    // the position in the file of the start of the region
+    offset: u64,
 }
 
-// TODO: implement threads, for now each process acts as a thread also
 #[allow(dead_code)]
 pub struct Process {
     vm: VirtualMemoryMapper,
@@ -115,18 +342,57 @@ pub struct Process {
     file_index_allocator: GoingUpAllocator,
 
     argv: Vec<String>,
+    // `NAME=value` strings, inherited by the children
+    env: Vec<String>,
+    // the program file
+    path: String,
+    // the file name of the program, unless the process changed it
+    name: ProcessName,
+    // the lowest address of the program
+    load_base: usize,
+    // where the program is mapped from, for the core files
+    program_segments: Vec<elf::LoadedSegment>,
 
     stack_ptr_end: usize,
+    // what is mapped of the stack, it grows down from `USER_STACK_END`, see `grow_stack`
     stack_size: usize,
+    // the slots of the kernel stacks of the threads, and the one of the running thread
+    kernel_stacks: kernel_stack::KernelStacks,
+    kernel_stack: kernel_stack::KernelStack,
+    // the thread of `context`, `state` and `kernel_stack`, the first has the id of the process
+    thread_id: u64,
+    threads: thread::Threads,
 
     heap_start: usize,
     heap_size: usize,
     heap_max: usize,
+    // every range of the user memory, with the flags it's mapped with
+    memory_regions: MemoryRegions,
+    // by the start of their regions
+    file_mappings: BTreeMap<u64, FileMapping>,
 
     state: ProcessState,
+    // its pages picked by `pick_swap_out` are being written to the swap, it can't run until
+    // they are, see `end_swap_out`
+    swapping_out: bool,
+    // the CPU whose queue it's in, set when it's pushed to the scheduler, it stays there
+    cpu: usize,
+    // see `PRIORITY_DEFAULT`, the lower runs first
+    priority: u64,
+    // the times another process was run while this one was waiting to, since it last ran,
+    // it's that much closer to the highest priority
+    waited: u64,
     // split from the state, so that we can keep it as a simple enum
     exit_code: i32,
     children_exits: BTreeMap<u64, i32>,
+    signals: signal::Signals,
+
+    cpu_time: scheduler::ProcessCpuTime,
+    limits: ResourceLimits,
+    // can change the system, i.e. mount filesystems, `init` is and its children inherit it
+    privileged: bool,
+    // runs in the kernel only, see `kernel_thread`
+    kernel_thread: bool,
 }
 
 impl Process {
@@ -135,10 +401,55 @@ impl Process {
         elf: &elf::Elf,
         file: &mut fs::File,
         argv: Vec<String>,
+        env: Vec<String>,
+        limits: ResourceLimits,
     ) -> Result<Self, ProcessError> {
         let id = PROCESS_ID_ALLOCATOR.allocate();
+        Self::load_program(id, parent_id, elf, file, argv, env, limits)
+    }
+
+    /// The program of `file` for `SYS_EXEC` of the process `id`, which keeps running until
+    /// it's replaced, the rest of the process is moved to it then, see
+    /// [`Self::take_exec_state`]
+    pub fn exec_image(
+        id: u64,
+        elf: &elf::Elf,
+        file: &mut fs::File,
+        argv: Vec<String>,
+        env: Vec<String>,
+        limits: ResourceLimits,
+    ) -> Result<Self, ProcessError> {
+        Self::load_program(id, INIT_PID, elf, file, argv, env, limits)
+    }
+
+    /// Moves what stays through `SYS_EXEC` from `old`, the process this image replaces: the
+    /// parent, the files, the exits of the children, the CPU time, the limits, the priority, the
+    /// privilege, the queue it's in, and the signals but their handlers
+    pub fn take_exec_state(&mut self, old: &mut Self) {
+        self.open_files = mem::take(&mut old.open_files);
+        self.file_index_allocator =
+            mem::replace(&mut old.file_index_allocator, GoingUpAllocator::new());
+        self.children_exits = mem::take(&mut old.children_exits);
+        self.signals = old.signals.for_exec();
+        self.cpu_time = old.cpu_time;
+        self.limits = old.limits;
+        self.cpu = old.cpu;
+        self.parent_id = old.parent_id;
+        self.priority = old.priority;
+        self.privileged = old.privileged;
+    }
+
+    fn load_program(
+        id: u64,
+        parent_id: u64,
+        elf: &elf::Elf,
+        file: &mut fs::File,
+        argv: Vec<String>,
+        env: Vec<String>,
+        limits: ResourceLimits,
+    ) -> Result<Self, ProcessError> {
         let mut vm = virtual_memory_mapper::clone_current_vm_as_user();
-        let stack_end = MAX_USER_VIRTUAL_ADDRESS - PAGE_4K;
+        let stack_end = USER_STACK_END;
         let stack_size = INITIAL_STACK_SIZE_PAGES * PAGE_4K;
         let stack_start = stack_end - stack_size;
         vm.map(&VirtualMemoryMapEntry {
@@ -149,23 +460,59 @@ impl Process {
                 | virtual_memory_mapper::flags::PTE_WRITABLE,
         });
 
-        let rsp = stack_end as u64 - 8;
-        let (new_rsp, argc, argv_ptr) =
-            Self::load_argv_into_stack(&mut vm, &argv, rsp, stack_start as u64);
+        let entry = elf.entry_point();
+        let layout = Self::load_startup_into_stack(
+            &mut vm,
+            (stack_start as u64, stack_end as u64),
+            &argv,
+            &env,
+            file.path(),
+            entry,
+        )?;
 
         // SAFETY: we know that the vm passed is an exact kernel copy of this vm, so its safe to switch to it
         // TODO: maybe it would be best to create the new vm inside this function?
-        let (_min_addr, max_addr) = unsafe { load_elf_to_vm(elf, file, &mut vm)? };
+        let (min_addr, max_addr) = match unsafe { load_elf_to_vm(elf, file, &mut vm) } {
+            Ok(range) => range,
+            Err(e) => {
+                // free what was loaded before failing, and the stack
+                vm.unmap_process_memory();
+                return Err(e.into());
+            }
+        };
         // SAFETY: we know that the vm is never used after this point until scheduling
         unsafe { vm.add_process_specific_mappings() };
 
+        let mut memory_regions = MemoryRegions::new();
+        memory_regions.insert(MemoryRegion {
+            start: stack_start as u64,
+            end: stack_end as u64,
+            flags: virtual_memory_mapper::flags::PTE_USER
+                | virtual_memory_mapper::flags::PTE_WRITABLE,
+            kind: RegionKind::Stack,
+        });
+        // the pages of the program with their final flags, after the relocations
+        let program = align_down(min_addr, PAGE_4K) as u64..max_addr as u64;
+        for mapping in vm.user_mappings() {
+            if program.contains(&mapping.virtual_address) {
+                memory_regions.insert(MemoryRegion {
+                    start: mapping.virtual_address,
+                    end: mapping.virtual_address + mapping.size,
+                    flags: mapping.flags,
+                    kind: RegionKind::Elf,
+                });
+            }
+        }
+        if let Some(region) = Self::map_time_page(&mut vm) {
+            memory_regions.insert(region);
+        }
+
         // set it quite a distance from the elf and align it to 2MB pages (we are not using 2MB virtual memory, so its not related)
         let heap_start = align_up(max_addr + HEAP_OFFSET_FROM_ELF_END, PAGE_2M);
         let heap_size = 0; // start at 0, let user space programs control it
         let heap_max = DEAFULT_MAX_HEAP_SIZE;
 
         let mut context = ProcessContext::default();
-        let entry = elf.entry_point();
         assert!(vm.is_address_mapped(entry as _) && entry < KERNEL_BASE as u64);
 
         context.rip = entry;
@@ -174,13 +521,15 @@ impl Process {
         context.ss = context.ds;
         context.rflags = cpu::flags::IF;
 
-        // setup main function arguments and stack
-        context.rsp = new_rsp;
+        // setup the entry arguments and stack
+        context.rsp = layout.rsp;
         // NOTE: This is very specific to x86_64 SYSV abi
-        context.rdi = argc;
-        context.rsi = argv_ptr;
+        context.rdi = argv.len() as u64;
+        context.rsi = layout.argv;
+        context.rdx = layout.envp;
+        context.rcx = layout.auxv;
 
-        Ok(Self {
+        let process = Self {
             vm,
             context,
             id,
@@ -188,15 +537,212 @@ impl Process {
             open_files: BTreeMap::new(),
             file_index_allocator: GoingUpAllocator::new(),
             argv,
+            env,
+            path: String::from(file.path()),
+            name: ProcessName::from_path(file.path()),
+            load_base: min_addr,
+            program_segments: elf.loaded_segments(),
             stack_ptr_end: stack_end - 8, // 8 bytes for padding
             stack_size,
+            kernel_stacks: kernel_stack::KernelStacks::new(),
+            kernel_stack: kernel_stack::KernelStack::first(),
+            thread_id: id,
+            threads: thread::Threads::default(),
             heap_start,
             heap_size,
             heap_max,
+            memory_regions,
+            file_mappings: BTreeMap::new(),
             state: ProcessState::Scheduled,
+            swapping_out: false,
+            cpu: 0,
+            priority: PRIORITY_DEFAULT,
+            waited: 0,
             exit_code: 0,
             children_exits: BTreeMap::new(),
-        })
+            signals: signal::Signals::default(),
+            cpu_time: scheduler::ProcessCpuTime::default(),
+            limits,
+            privileged: false,
+            kernel_thread: false,
+        };
+
+        // the stack and the elf are already mapped, dropping the process frees them
+        if process.resident_pages() > limits.memory_pages {
+            return Err(ProcessError::MemoryLimitExceeded);
+        }
+        Ok(process)
+    }
+
+    /// A process of the kernel only, that starts at `entry` in ring 0 on its kernel stack, with
+    /// `arg` in `rdi`, see [`kernel_thread::spawn`]. It has no memory of the user, nor files, and
+    /// it's nobody's child, so the syscalls that take a pid of a child can't change it
+    fn new_kernel_thread(name: ProcessName, entry: u64, arg: u64) -> Self {
+        let mut vm = virtual_memory_mapper::clone_current_vm_as_user();
+        // SAFETY: we know that the vm is never used after this point until scheduling
+        unsafe { vm.add_process_specific_mappings() };
+
+        let kernel_stack = kernel_stack::KernelStack::first();
+        let mut context = ProcessContext::default();
+        context.rip = entry;
+        context.cs = gdt::get_kernel_code_seg_index().0 | gdt::KERNEL_RING as u64;
+        context.ds = gdt::get_kernel_data_seg_index().0 | gdt::KERNEL_RING as u64;
+        context.es = context.ds;
+        // the same as the scheduler, so it's not loaded again, see `idt_vectors.S`
+        context.fs = context.ds;
+        context.ss = context.ds;
+        context.rflags = cpu::flags::IF;
+        // as if `entry` was called, it's aligned (mod 16) to 8
+        context.rsp = kernel_stack.end() as u64;
+        context.rdi = arg;
+
+        let id = PROCESS_ID_ALLOCATOR.allocate();
+        Self {
+            vm,
+            context,
+            id,
+            parent_id: id,
+            open_files: BTreeMap::new(),
+            file_index_allocator: GoingUpAllocator::new(),
+            argv: Vec::new(),
+            env: Vec::new(),
+            path: String::new(),
+            name,
+            load_base: 0,
+            program_segments: Vec::new(),
+            stack_ptr_end: 0,
+            stack_size: 0,
+            kernel_stacks: kernel_stack::KernelStacks::new(),
+            kernel_stack,
+            thread_id: id,
+            threads: thread::Threads::default(),
+            heap_start: 0,
+            heap_size: 0,
+            heap_max: 0,
+            memory_regions: MemoryRegions::new(),
+            file_mappings: BTreeMap::new(),
+            state: ProcessState::Scheduled,
+            swapping_out: false,
+            cpu: 0,
+            priority: PRIORITY_DEFAULT,
+            waited: 0,
+            exit_code: 0,
+            children_exits: BTreeMap::new(),
+            signals: signal::Signals::default(),
+            cpu_time: scheduler::ProcessCpuTime::default(),
+            limits: ResourceLimits::unlimited(),
+            privileged: false,
+            kernel_thread: true,
+        }
+    }
+
+    /// Shared by all, its region is not allocated, so it's never freed with the process
+    fn map_time_page(vm: &mut VirtualMemoryMapper) -> Option<MemoryRegion> {
+        let physical = time_page::physical_address()?;
+        let region = MemoryRegion {
+            start: TIME_PAGE_ADDRESS as u64,
+            end: (TIME_PAGE_ADDRESS + PAGE_4K) as u64,
+            flags: virtual_memory_mapper::flags::PTE_USER,
+            kind: RegionKind::TimePage,
+        };
+        vm.map(&VirtualMemoryMapEntry {
+            physical_address: Some(physical),
+            ..region.as_entry()
+        });
+        Some(region)
+    }
+
+    /// A copy of this process for `SYS_FORK`, with a new pid, that continues from
+    /// `all_state` of the calling thread, only `rax` is `0`.
+    ///
+    /// The memory is shared until it's written to (see
+    /// [`VirtualMemoryMapper::clone_user_memory_cow`]), the files are inherited like the std
+    /// files of `spawn`, and the rest is copied, except the exits of the children and the pending
+    /// signals.
+    /// This must be the current process
+    pub fn fork(&mut self, all_state: &InterruptAllSavedState) -> Result<Self, ProcessError> {
+        let mut vm = virtual_memory_mapper::clone_current_vm_as_user();
+        // SAFETY: we know that the vm is never used after this point until scheduling
+        unsafe { vm.add_process_specific_mappings() };
+        let not_owned: Vec<_> = self
+            .memory_regions
+            .iter()
+            .filter(|region| !region.kind.is_private())
+            .map(|region| region.start..region.end)
+            .collect();
+        self.vm.clone_user_memory_cow(&mut vm, &not_owned);
+        // already in the regions
+        Self::map_time_page(&mut vm);
+        for region in self
+            .memory_regions
+            .iter()
+            .filter(|region| matches!(region.kind, RegionKind::SharedMemory | RegionKind::File))
+        {
+            self.share_region(&mut vm, region);
+        }
+
+        let mut context = scheduler::copy_context(all_state);
+        context.rax = 0;
+
+        let id = PROCESS_ID_ALLOCATOR.allocate();
+        let process = Self {
+            vm,
+            context,
+            id,
+            parent_id: self.id,
+            open_files: self
+                .open_files
+                .iter()
+                .map(|(&fd, file)| (fd, file.clone_inherit()))
+                .collect(),
+            file_index_allocator: GoingUpAllocator {
+                next_id: AtomicU64::new(self.file_index_allocator.next_id.load(Ordering::SeqCst)),
+            },
+            argv: self.argv.clone(),
+            env: self.env.clone(),
+            path: self.path.clone(),
+            name: self.name,
+            load_base: self.load_base,
+            program_segments: self.program_segments.clone(),
+            stack_ptr_end: self.stack_ptr_end,
+            stack_size: self.stack_size,
+            kernel_stacks: kernel_stack::KernelStacks::new(),
+            kernel_stack: kernel_stack::KernelStack::first(),
+            // only the thread that forks is copied
+            thread_id: id,
+            threads: thread::Threads::default(),
+            heap_start: self.heap_start,
+            heap_size: self.heap_size,
+            heap_max: self.heap_max,
+            memory_regions: self.memory_regions.clone(),
+            file_mappings: self
+                .file_mappings
+                .iter()
+                .map(|(&start, mapping)| {
+                    let file = mapping.file.clone_inherit();
+                    let offset = mapping.offset;
+                    (start, FileMapping { file, offset })
+                })
+                .collect(),
+            state: ProcessState::Scheduled,
+            swapping_out: false,
+            cpu: 0,
+            priority: self.priority,
+            waited: 0,
+            exit_code: 0,
+            children_exits: BTreeMap::new(),
+            signals: self.signals.for_fork(),
+            cpu_time: scheduler::ProcessCpuTime::default(),
+            limits: self.limits,
+            privileged: self.privileged,
+            kernel_thread: false,
+        };
+
+        // the pages are counted in both, dropping the process gives them back to us
+        if process.resident_pages() > process.limits.memory_pages {
+            return Err(ProcessError::MemoryLimitExceeded);
+        }
+        Ok(process)
     }
 
     /// # Safety
@@ -205,16 +751,624 @@ impl Process {
         self.vm.switch_to_this();
     }
 
+    /// Where the TSS must point to enter the kernel while this process runs
+    pub fn kernel_stack_end(&self) -> usize {
+        self.kernel_stack.end()
+    }
+
+    /// The id of the running thread, the first has the id of the process
+    pub fn thread_id(&self) -> u64 {
+        self.thread_id
+    }
+
+    /// Adds a thread to the process, that starts at `entry` with `stack_end` as the stack
+    /// pointer, `arg` in `rdi` and `tls` as the FS base, it runs after the others
+    pub fn create_thread(
+        &mut self,
+        entry: u64,
+        stack_end: u64,
+        arg: u64,
+        tls: u64,
+    ) -> Result<u64, ProcessError> {
+        let kernel_stack = self.kernel_stacks.allocate(&mut self.vm)?;
+
+        let mut context = ProcessContext::default();
+        // the FPU state of the thread creating it, i.e. the control words
+        unsafe { core::arch::x86_64::_fxsave64(&mut context.fxsave as *mut FxSave as _) };
+        context.rip = entry;
+        context.cs = gdt::get_user_code_seg_index().0 | gdt::USER_RING as u64;
+        context.ds = gdt::get_user_data_seg_index().0 | gdt::USER_RING as u64;
+        context.ss = context.ds;
+        context.rflags = cpu::flags::IF;
+        context.rsp = stack_end;
+        context.rdi = arg;
+        context.fs_base = tls;
+
+        let id = PROCESS_ID_ALLOCATOR.allocate();
+        self.threads.push(thread::Thread {
+            id,
+            context,
+            state: ProcessState::Scheduled,
+            kernel_stack,
+        });
+        Ok(id)
+    }
+
+    /// Whether any of the threads can be run
+    pub fn can_run(&self) -> bool {
+        !self.swapping_out
+            && (self.state == ProcessState::Scheduled || self.threads.has_scheduled())
+    }
+
+    /// Swaps in the first of the other threads that can run, the one running before goes
+    /// after the others, so they take turns
+    pub fn next_thread(&mut self) {
+        if let Some(mut thread) = self.threads.take_scheduled() {
+            thread.swap_with(self);
+            self.threads.push(thread);
+        }
+    }
+
+    /// Frees the thread that exited with `SYS_THREAD_EXIT` and swaps in another, the ones
+    /// joining it are woken, otherwise it's kept until it's joined. Must be called by the
+    /// scheduler, once we are not on its kernel stack anymore
+    pub fn reap_thread(&mut self) {
+        if self.state != ProcessState::ThreadExited {
+            return;
+        }
+        let mut thread = self
+            .threads
+            .take_first()
+            .expect("the last thread exits with the process");
+        thread.swap_with(self);
+
+        let id = thread.id;
+        if thread.kernel_stack != kernel_stack::KernelStack::first() {
+            self.kernel_stacks.free(&mut self.vm, thread.kernel_stack);
+        }
+        let mut joined = false;
+        for (state, context) in self.thread_states_mut() {
+            if *state == ProcessState::WaitingForThread(id) {
+                // `SYS_THREAD_JOIN` returns 0
+                context.rax = 0;
+                *state = ProcessState::Scheduled;
+                joined = true;
+            }
+        }
+        if !joined {
+            self.threads.add_exited(id);
+        }
+    }
+
+    /// Wakes `count` of the threads waiting for the futex `addr`, in the order they take turns,
+    /// their `SYS_FUTEX_WAIT` returns 0. Returns how many were woken
+    pub fn wake_futex(&mut self, addr: u64, count: u64) -> u64 {
+        let mut woken = 0;
+        for (state, context) in self.thread_states_mut() {
+            if woken == count {
+                break;
+            }
+            if *state == ProcessState::WaitingForFutex(addr) {
+                context.rax = 0;
+                *state = ProcessState::Scheduled;
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// Wakes the threads sleeping until `now` or before, their `SYS_SLEEP` returns 0
+    pub fn wake_sleeping(&mut self, now: u64) {
+        for (state, context) in self.thread_states_mut() {
+            if matches!(*state, ProcessState::Sleeping(deadline) if deadline <= now) {
+                context.rax = 0;
+                *state = ProcessState::Scheduled;
+            }
+        }
+    }
+
+    /// The state and the context of every thread, the running one first
+    fn thread_states_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&mut ProcessState, &mut ProcessContext)> {
+        core::iter::once((&mut self.state, &mut self.context)).chain(
+            self.threads
+                .iter_mut()
+                .map(|thread| (&mut thread.state, &mut thread.context)),
+        )
+    }
+
     pub fn id(&self) -> u64 {
         self.id
     }
 
+    pub fn is_kernel_thread(&self) -> bool {
+        self.kernel_thread
+    }
+
     pub fn parent_id(&self) -> u64 {
         self.parent_id
     }
 
-    pub fn is_user_address_mapped(&self, address: u64) -> bool {
-        self.vm.is_address_mapped(address)
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    pub fn limits_mut(&mut self) -> &mut ResourceLimits {
+        &mut self.limits
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    pub fn set_privileged(&mut self) {
+        self.privileged = true;
+    }
+
+    pub fn priority(&self) -> u64 {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: u64) {
+        self.priority = priority;
+    }
+
+    /// The number of pages used by the user memory of the process, including the page tables
+    pub fn resident_pages(&self) -> u64 {
+        self.vm.user_pages_count()
+    }
+
+    /// The path of the program file
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The lowest address the program was loaded at
+    pub fn load_base(&self) -> usize {
+        self.load_base
+    }
+
+    pub fn env(&self) -> &[String] {
+        &self.env
+    }
+
+    /// The file name of the program, or what the process set with `SYS_SET_NAME`
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn process_name(&self) -> ProcessName {
+        self.name
+    }
+
+    pub fn set_name(&mut self, name: ProcessName) {
+        self.name = name;
+    }
+
+    /// Copies up to `max_len` bytes of the user memory at `start`, stopping at the first page
+    /// not mapped for the user.
+    ///
+    /// This must be the current process, as the memory is read through the current vm
+    pub fn read_user_memory(&self, start: u64, max_len: usize) -> Vec<u8> {
+        let end = start
+            .saturating_add(max_len as u64)
+            .min(MAX_USER_VIRTUAL_ADDRESS as u64 + PAGE_4K as u64);
+        let mut data = Vec::new();
+        let mut addr = start;
+        while addr < end {
+            let is_user = self
+                .vm
+                .get_mapping(addr)
+                .is_some_and(|mapping| mapping.flags & virtual_memory_mapper::flags::PTE_USER != 0);
+            if !is_user {
+                break;
+            }
+            let page_end = (align_down(addr as usize, PAGE_4K) + PAGE_4K) as u64;
+            let len = (page_end.min(end) - addr) as usize;
+            let start = data.len();
+            data.resize(start + len, 0);
+            // SAFETY: the page is mapped in the current vm (see above), a fault is caught
+            let left = unsafe {
+                exception_table::copy_bytes(data[start..].as_mut_ptr(), addr as *const u8, len)
+            };
+            if left != 0 {
+                data.truncate(start + len - left);
+                break;
+            }
+            addr += len as u64;
+        }
+        data
+    }
+
+    /// Maps the pages of `region` in `vm` too, the same ones, with a reference for it. The
+    /// pages of a file not read yet are read again by the other process
+    fn share_region(&self, vm: &mut VirtualMemoryMapper, region: &MemoryRegion) {
+        for page in (region.start..region.end).step_by(PAGE_4K) {
+            let Some(mapping) = self.vm.get_mapping(page) else {
+                assert!(region.kind == RegionKind::File, "shared region not mapped");
+                continue;
+            };
+            let physical = mapping.physical_address.unwrap() + (page - mapping.virtual_address);
+            physical_page_allocator::share_physical(physical);
+            vm.map(&VirtualMemoryMapEntry {
+                virtual_address: page,
+                physical_address: Some(physical),
+                size: PAGE_4K as u64,
+                flags: region.flags,
+            });
+        }
+    }
+
+    /// Maps all the pages of `shm` with the `flags`, for `SYS_SHM_MAP`, returns the address.
+    ///
+    /// Each page gets a reference for the process, which is dropped when it's unmapped, with
+    /// [`Self::unmap_shared_memory`] or when the process goes away
+    pub fn map_shared_memory(
+        &mut self,
+        shm: &SharedMemory,
+        flags: u64,
+    ) -> Result<u64, ProcessError> {
+        let size = shm.size();
+        let needed = size / PAGE_4K as u64 + max_page_tables_for(size);
+        if self.resident_pages() + needed > self.limits.memory_pages {
+            return Err(ProcessError::MemoryLimitExceeded);
+        }
+        let start = self.find_mapping_space(size)?;
+        for (i, &physical) in shm.pages().iter().enumerate() {
+            physical_page_allocator::share_physical(physical);
+            self.vm.map(&VirtualMemoryMapEntry {
+                virtual_address: start + (i * PAGE_4K) as u64,
+                physical_address: Some(physical),
+                size: PAGE_4K as u64,
+                flags,
+            });
+        }
+        self.memory_regions.insert(MemoryRegion {
+            start,
+            end: start + size,
+            flags,
+            kind: RegionKind::SharedMemory,
+        });
+        Ok(start)
+    }
+
+    /// Unmaps the shared memory mapped at `start` by [`Self::map_shared_memory`]
+    pub fn unmap_shared_memory(&mut self, start: u64) -> Result<(), ProcessError> {
+        let region = self
+            .memory_regions
+            .find(start)
+            .copied()
+            .filter(|region| region.kind == RegionKind::SharedMemory && region.start == start)
+            .ok_or(ProcessError::RangeNotMapped)?;
+        Self::unmap_region_pages(&mut self.vm, &region);
+        self.memory_regions.remove(region.start, region.end);
+        Ok(())
+    }
+
+    /// The start of `size` free bytes in the area of the mappings, with a free page on each
+    /// side
+    fn find_mapping_space(&self, size: u64) -> Result<u64, ProcessError> {
+        let with_guards = size
+            .checked_add(2 * PAGE_4K as u64)
+            .ok_or(ProcessError::NoAddressSpace)?;
+        let start = self
+            .memory_regions
+            .find_gap(MAPPINGS_START as u64, MAPPINGS_END as u64, with_guards)
+            .ok_or(ProcessError::NoAddressSpace)?;
+        Ok(start + PAGE_4K as u64)
+    }
+
+    /// Unmaps the pages of `region` that are mapped, one by one. Unlike unmapping the whole
+    /// region, it works for the files, which can have pages not read yet, and leaves the flags
+    /// of the page tables above them to the other pages in there
+    fn unmap_region_pages(vm: &mut VirtualMemoryMapper, region: &MemoryRegion) {
+        for page in (region.start..region.end).step_by(PAGE_4K) {
+            if vm.get_mapping(page).is_none() {
+                continue;
+            }
+            let entry = VirtualMemoryMapEntry {
+                virtual_address: page,
+                physical_address: None,
+                size: PAGE_4K as u64,
+                flags: 0,
+            };
+            vm.unmap(&entry, region.kind.is_allocated());
+        }
+    }
+
+    /// Maps `len` bytes of `file` from `offset` with the `flags`, for `SYS_MMAP`, returns the
+    /// address. Nothing is read here, each page is read on its first access by
+    /// [`Self::fault_in_file`], the part after the end of the file is zeroed.
+    ///
+    /// The pages of a file in the page cache are the pages of the cache, so all the mappings of
+    /// it share them, they are copied when written to (see [`Self::fault_in_file`]). The file is
+    /// only read, writing to the pages doesn't change it, and the pages read before are not
+    /// updated when the file is written to
+    pub fn map_file(
+        &mut self,
+        file: fs::File,
+        offset: u64,
+        len: u64,
+        flags: u64,
+    ) -> Result<u64, ProcessError> {
+        assert!(file.is_mappable() && is_aligned(offset as usize, PAGE_4K));
+        if len == 0 || len > (MAPPINGS_END - MAPPINGS_START) as u64 {
+            return Err(ProcessError::NoAddressSpace);
+        }
+        let size = align_up(len as usize, PAGE_4K) as u64;
+        let start = self.find_mapping_space(size)?;
+        self.memory_regions.insert(MemoryRegion {
+            start,
+            end: start + size,
+            flags,
+            kind: RegionKind::File,
+        });
+        self.file_mappings
+            .insert(start, FileMapping { file, offset });
+        Ok(start)
+    }
+
+    /// Unmaps the file mapped at `start` by [`Self::map_file`], and closes it
+    pub fn unmap_file(&mut self, start: u64) -> Result<(), ProcessError> {
+        let region = self
+            .memory_regions
+            .find(start)
+            .copied()
+            .filter(|region| region.kind == RegionKind::File && region.start == start)
+            .ok_or(ProcessError::RangeNotMapped)?;
+        Self::unmap_region_pages(&mut self.vm, &region);
+        self.memory_regions.remove(region.start, region.end);
+        self.file_mappings.remove(&start);
+        Ok(())
+    }
+
+    /// Maps the page of `addr` with its content in the file, if it's in a file mapping and
+    /// not mapped yet, `false` otherwise, or if the limits don't allow it, or the file can't be
+    /// read. Called on the faults of the user, and before the kernel accesses user memory
+    pub fn fault_in_file(&mut self, addr: u64) -> bool {
+        let Some(region) = self
+            .memory_regions
+            .find(addr)
+            .copied()
+            .filter(|region| region.kind == RegionKind::File)
+        else {
+            return false;
+        };
+        let page = align_down(addr as usize, PAGE_4K) as u64;
+        if self.vm.get_mapping(page).is_some() {
+            return false;
+        }
+        let needed = 1 + max_page_tables_for(PAGE_4K as u64);
+        if self.resident_pages() + needed > self.limits.memory_pages {
+            return false;
+        }
+
+        let mapping = self
+            .file_mappings
+            .get_mut(&region.start)
+            .expect("file region without a file");
+        let position = mapping.offset + (page - region.start);
+        if let Some(physical) = mapping.file.map_page(position) {
+            // the page is the cache's, it's copied on the first write, see `resolve_cow`
+            let mut flags = region.flags;
+            if flags & virtual_memory_mapper::flags::PTE_WRITABLE != 0 {
+                flags = (flags & !virtual_memory_mapper::flags::PTE_WRITABLE)
+                    | virtual_memory_mapper::flags::PTE_COW;
+            }
+            self.vm.map(&VirtualMemoryMapEntry {
+                virtual_address: page,
+                physical_address: Some(physical),
+                size: PAGE_4K as u64,
+                flags,
+            });
+            return true;
+        }
+
+        let mut data = alloc::vec![0; PAGE_4K];
+        let mut position = position;
+        let mut filled = 0;
+        while filled < PAGE_4K {
+            match mapping.file.read_at(position, &mut data[filled..]) {
+                Ok(0) => break,
+                Ok(read) => {
+                    filled += read as usize;
+                    position += read;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "could not read {} for its mapping: {e:?}",
+                        mapping.file.path()
+                    );
+                    return false;
+                }
+            }
+        }
+        // SAFETY: the processes run after the high memory is initialized, and `data` is a page
+        let physical = unsafe { physical_page_allocator::alloc_user_copy(data.as_ptr()) };
+        self.vm.map(&VirtualMemoryMapEntry {
+            virtual_address: page,
+            physical_address: Some(physical),
+            size: PAGE_4K as u64,
+            flags: region.flags,
+        });
+        true
+    }
+
+    /// [`Self::fault_in_file`] for all the pages of `start..start + len`
+    pub fn fault_in_file_range(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        let mut page = align_down(start as usize, PAGE_4K) as u64;
+        while page < end {
+            self.fault_in_file(page);
+            page += PAGE_4K as u64;
+        }
+    }
+
+    /// Picks up to `pages.len()` pages of the private regions to move to the swap (see
+    /// [`VirtualMemoryMapper::pick_swap_out`]), returns how many. Nothing is picked from the
+    /// running process, it can be in the middle of a syscall using its memory. If some are,
+    /// the process doesn't run until [`Self::end_swap_out`], so they don't change while they
+    /// are written without the scheduler lock
+    pub fn pick_swap_out(&mut self, pages: &mut [(u64, u64)]) -> usize {
+        if self.swapping_out || matches!(self.state, ProcessState::Running | ProcessState::Exited) {
+            return 0;
+        }
+        let mut picked = 0;
+        for region in self.memory_regions.iter() {
+            if picked >= pages.len() {
+                break;
+            }
+            if region.kind.is_private() {
+                let size = region.end - region.start;
+                picked = self.vm.pick_swap_out(region.start, size, pages, picked);
+            }
+        }
+        self.swapping_out = picked != 0;
+        picked
+    }
+
+    /// The page at `addr` was written to `slot`, see [`VirtualMemoryMapper::finish_swap_out`]
+    pub fn finish_swap_out(&mut self, addr: u64, physical: u64, slot: u64) -> bool {
+        self.vm.finish_swap_out(addr, physical, slot)
+    }
+
+    /// The pages of [`Self::pick_swap_out`] are written, or not, the process can run again
+    pub fn end_swap_out(&mut self) {
+        self.swapping_out = false;
+    }
+
+    /// Runs `f` with the vm of the process, for [`crate::memory_management::swap::swap_in`],
+    /// which only takes the scheduler lock to mark the entry of the page and to map it
+    pub fn with_vm<U>(&mut self, f: impl FnOnce(&mut VirtualMemoryMapper) -> U) -> U {
+        f(&mut self.vm)
+    }
+
+    /// The user mappings of the process as text, a line for each of its memory regions
+    pub fn user_maps(&self) -> String {
+        let mut maps = String::new();
+        for region in self.memory_regions.iter() {
+            let MemoryRegion {
+                start, end, flags, ..
+            } = *region;
+            let label = match region.kind {
+                RegionKind::Elf => self.path(),
+                RegionKind::Stack => "[stack]",
+                RegionKind::Heap => "[heap]",
+                RegionKind::TimePage => "[time]",
+                RegionKind::SharedMemory => "[shm]",
+                RegionKind::File => self.file_mappings[&start].file.path(),
+            };
+            let writable = flags & virtual_memory_mapper::flags::PTE_WRITABLE != 0;
+            let executable = flags & virtual_memory_mapper::flags::PTE_NO_EXECUTE == 0;
+            let _ = writeln!(
+                maps,
+                "{start:016x}-{end:016x} r{}{} {label}",
+                if writable { 'w' } else { '-' },
+                if executable { 'x' } else { '-' },
+            );
+        }
+        maps
+    }
+
+    /// Checks that all the pages of `start..start + len` are mapped for the user, and writable
+    /// if `write`, `false` if the range goes after the user memory
+    pub fn is_user_range_accessible(&self, start: u64, len: u64, write: bool) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        if end > MAX_USER_VIRTUAL_ADDRESS as u64 + PAGE_4K as u64 {
+            return false;
+        }
+        let mut needed = virtual_memory_mapper::flags::PTE_USER;
+        if write {
+            needed |= virtual_memory_mapper::flags::PTE_WRITABLE;
+        }
+
+        let mut page = align_down(start as usize, PAGE_4K) as u64;
+        while page < end {
+            let accessible = self.vm.get_mapping(page).is_some_and(|mapping| {
+                let mut flags = mapping.flags;
+                // writable once it's copied, see `resolve_cow_range`
+                if flags & virtual_memory_mapper::flags::PTE_COW != 0 {
+                    flags |= virtual_memory_mapper::flags::PTE_WRITABLE;
+                }
+                flags & needed == needed
+            });
+            if !accessible {
+                return false;
+            }
+            page += PAGE_4K as u64;
+        }
+        true
+    }
+
+    /// Gives the pages of `start..start + len` the `flags`, for `SYS_MPROTECT`, in the memory
+    /// regions and the page tables. The range must be page aligned, and all of it in regions
+    /// private to this process
+    pub fn protect_memory(&mut self, start: u64, len: u64, flags: u64) -> Result<(), ProcessError> {
+        assert!(is_aligned(start as usize, PAGE_4K) && is_aligned(len as usize, PAGE_4K));
+        let end = start.checked_add(len).ok_or(ProcessError::RangeNotMapped)?;
+        if !self.memory_regions.covers(start, end) {
+            return Err(ProcessError::RangeNotMapped);
+        }
+        if self
+            .memory_regions
+            .overlapping(start, end)
+            .any(|region| !region.kind.is_private())
+        {
+            return Err(ProcessError::RangeNotOwned);
+        }
+        self.memory_regions.protect(start, end, flags);
+        self.vm.protect(start, len, flags);
+        Ok(())
+    }
+
+    /// Maps the stack down to the page of `addr`, if it's below the stack and the limits allow
+    /// it, `false` otherwise. Called on the faults of the user, and before the kernel accesses
+    /// user memory, as it must not fault there
+    pub fn grow_stack(&mut self, addr: u64) -> bool {
+        let stack_start = (USER_STACK_END - self.stack_size) as u64;
+        if addr >= stack_start || addr < stack_growth_start(self.limits.stack_size) {
+            return false;
+        }
+        let start = align_down(addr as usize, PAGE_4K) as u64;
+        let size = stack_start - start;
+        let needed = size / PAGE_4K as u64 + max_page_tables_for(size);
+        if self.resident_pages() + needed > self.limits.memory_pages {
+            return false;
+        }
+        let region = MemoryRegion {
+            start,
+            end: stack_start,
+            flags: virtual_memory_mapper::flags::PTE_USER
+                | virtual_memory_mapper::flags::PTE_WRITABLE,
+            kind: RegionKind::Stack,
+        };
+        self.vm.map(&region.as_entry());
+        self.memory_regions.insert(region);
+        self.stack_size += size as usize;
+        true
+    }
+
+    /// Whether `addr` is in the space of the stack, below where it can grow to, on the guard
+    /// page or past the limit
+    pub fn is_stack_overflow(&self, addr: u64) -> bool {
+        let reserved_start = (USER_STACK_END - STACK_RESERVED_SIZE) as u64;
+        (reserved_start..stack_growth_start(self.limits.stack_size)).contains(&addr)
+    }
+
+    /// Copies the pages shared by `SYS_FORK` in `start..start + len`, the kernel must do it
+    /// before writing there, as its writes ignore the pages being read-only.
+    ///
+    /// This must be the current process, as the pages are copied through the current vm
+    pub fn resolve_cow_range(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        let mut page = align_down(start as usize, PAGE_4K) as u64;
+        while page < end {
+            self.vm.resolve_cow(page);
+            page += PAGE_4K as u64;
+        }
     }
 
     pub fn finish_stdio(&mut self) {
@@ -227,13 +1381,16 @@ impl Process {
         }
     }
 
-    pub fn push_file(&mut self, file: fs::File) -> usize {
+    pub fn push_file(&mut self, file: fs::File) -> Result<usize, ProcessError> {
+        if self.open_files.len() as u64 >= self.limits.open_files {
+            return Err(ProcessError::OpenFilesLimitExceeded);
+        }
         let fd = self.file_index_allocator.allocate() as usize;
         assert!(
             self.open_files.insert(fd, file).is_none(),
             "fd already exists"
         );
-        fd
+        Ok(fd)
     }
 
     pub fn attach_file_to_fd(&mut self, fd: usize, file: fs::File) -> bool {
@@ -241,10 +1398,11 @@ impl Process {
         if self.open_files.contains_key(&fd) {
             return false;
         }
-        // update allocator so that next push_file will not overwrite this fd
+        // update allocator so that next push_file will not overwrite this fd, or any fd
+        // attached before it (the mappings of `spawn` are in any order)
         self.file_index_allocator
             .next_id
-            .store(fd as u64 + 1, Ordering::SeqCst);
+            .fetch_max(fd as u64 + 1, Ordering::SeqCst);
         // must always return `true`
         self.open_files.insert(fd, file).is_none()
     }
@@ -253,6 +1411,13 @@ impl Process {
         self.open_files.get_mut(&fd)
     }
 
+    /// Whether one of the files is the terminal `index` of the console
+    pub fn has_terminal_open(&self, index: usize) -> bool {
+        self.open_files
+            .values()
+            .any(|file| file.terminal() == Some(index))
+    }
+
     pub fn take_file(&mut self, fd: usize) -> Option<fs::File> {
         self.open_files.remove(&fd)
     }
@@ -267,6 +1432,8 @@ impl Process {
     pub fn exit(&mut self, exit_code: i32) {
         self.state = ProcessState::Exited;
         self.exit_code = exit_code;
+        // the locks go now, not when the scheduler drops the process
+        self.open_files.clear();
     }
 
     pub fn add_child_exit(&mut self, pid: u64, exit_code: i32) {
@@ -284,16 +1451,26 @@ impl Process {
     /// If this is an `Add`, it will return the address of the new block
     /// If this is a `Remove`, the result will generally be useless
     /// Use with `0` to get the current heap end
-    pub fn add_to_heap(&mut self, increment: isize) -> Option<usize> {
+    ///
+    /// The memory limit is checked with the most page tables the mapping can need, so it can
+    /// fail a bit before the limit is actually reached
+    pub fn add_to_heap(&mut self, increment: isize) -> Result<usize, ProcessError> {
         if increment == 0 {
-            return Some(self.heap_start + self.heap_size);
+            return Ok(self.heap_start + self.heap_size);
         }
 
         assert!(is_aligned(increment.unsigned_abs(), PAGE_4K));
 
         let new_size = self.heap_size as isize + increment;
         if new_size < 0 || new_size as usize > self.heap_max {
-            return None;
+            return Err(ProcessError::HeapRangesExceeded);
+        }
+        if increment > 0 {
+            let size = increment as u64;
+            let needed = size / PAGE_4K as u64 + max_page_tables_for(size);
+            if self.resident_pages() + needed > self.limits.memory_pages {
+                return Err(ProcessError::MemoryLimitExceeded);
+            }
         }
         let old_end = self.heap_start + self.heap_size;
         self.heap_size = new_size as usize;
@@ -307,6 +1484,12 @@ impl Process {
                     | virtual_memory_mapper::flags::PTE_WRITABLE,
             };
             self.vm.map(&entry);
+            self.memory_regions.insert(MemoryRegion {
+                start: entry.virtual_address,
+                end: entry.virtual_address + entry.size,
+                flags: entry.flags,
+                kind: RegionKind::Heap,
+            });
         } else {
             let new_end = old_end - increment.unsigned_abs();
             // unmap old heap
@@ -319,90 +1502,230 @@ impl Process {
             };
             // `true` because we allocated physical memory using `map`
             self.vm.unmap(&entry, true);
+            self.memory_regions.remove(new_end as u64, old_end as u64);
         }
 
-        Some(old_end)
+        Ok(old_end)
     }
 }
 
 impl Process {
-    // NOTE: this is very specific to 64bit x86
-    fn load_argv_into_stack(
+    /// Builds the initial stack of the process, see [`kernel_user_link::startup`] for the layout
+    fn load_startup_into_stack(
         vm: &mut VirtualMemoryMapper,
+        stack: (u64, u64),
         argv: &[String],
-        mut rsp: u64,
-        stack_top: u64,
-    ) -> (u64, u64, u64) {
+        envp: &[String],
+        execfn: &str,
+        entry: u64,
+    ) -> Result<StackLayout, ProcessError> {
+        let (stack_start, stack_end) = stack;
+        let strings_size = argv
+            .iter()
+            .chain(envp)
+            .map(|s| s.as_str())
+            .chain(core::iter::once(execfn))
+            .map(|s| s.len() + 1)
+            .sum();
+        let layout = StackLayout::new(
+            stack_start,
+            stack_end,
+            argv.len(),
+            envp.len(),
+            AUXV_ENTRIES,
+            strings_size,
+        )
+        .ok_or(ProcessError::ArgumentsTooLarge)?;
+
+        let mut random = [0; AT_RANDOM_SIZE];
+        random::fill_bytes(&mut random);
+
         // dealing with vm, so we must disable interrupts
         cpu::cpu().push_cli();
         let old_vm = virtual_memory_mapper::get_current_vm();
 
-        // switch temporaily so we can map the elf
+        // switch temporaily so we can write to the stack
         // SAFETY: this must be called while the current vm and this new vm must share the same
         //         kernel regions
         unsafe { vm.switch_to_this() };
 
-        let argc = argv.len();
-
-        let mut argv_ptrs = Vec::with_capacity(argv.len());
-        for arg in argv.iter() {
-            let arg_ptr = rsp - arg.len() as u64 - 1;
-            rsp = arg_ptr;
-            // align to 8 bytes
-            rsp -= rsp % 8;
-            assert!(rsp >= stack_top);
-
-            // convert arg_ptr to slice
-            let arg_ptr_slice =
-                unsafe { core::slice::from_raw_parts_mut(arg_ptr as *mut u8, arg.len() + 1) };
-            // copy the arg
-            arg_ptr_slice[..arg.len()].copy_from_slice(arg.as_bytes());
-            // put null terminator
-            arg_ptr_slice[arg.len()] = 0;
-
-            argv_ptrs.push(arg_ptr);
-        }
-        // align to 8 bytes
-        rsp -= rsp % 8;
-        assert!(rsp >= stack_top);
-        // add null terminator
-        let null_ptr = rsp - 1;
-        rsp = null_ptr;
-        unsafe { (null_ptr as *mut u8).write(0) };
-        argv_ptrs.push(null_ptr);
-        // align to 8 bytes
-        rsp -= rsp % 8;
-        assert!(rsp >= stack_top);
-
-        // write the argv array
-        let argv_array_ptr = rsp - (argv_ptrs.len() * 8) as u64;
-        rsp = argv_array_ptr;
-        let argv_array_ptr_slice =
-            unsafe { core::slice::from_raw_parts_mut(argv_array_ptr as *mut u64, argv_ptrs.len()) };
-        argv_array_ptr_slice.copy_from_slice(&argv_ptrs);
-
-        // these are not needed really, since in x86_64 we are using the registers to pass arguments
-        // but we can keep it for the future
-        // add pointer to argv array
-        rsp -= 8;
-        assert!(rsp >= stack_top);
-        unsafe { (rsp as *mut u64).write(argv_array_ptr) };
-        // add argc
-        rsp -= 8;
-        assert!(rsp >= stack_top);
-        unsafe { (rsp as *mut u64).write(argc as u64) };
+        // the stack was just mapped, so nothing should fault, but like all the writes to user
+        // memory, one that does is an error
+        let faulted = core::cell::Cell::new(false);
+        let write = |address: u64, bytes: &[u8]| {
+            // SAFETY: all the addresses come from the layout, which is inside the mapped stack
+            let left = unsafe {
+                exception_table::copy_bytes(address as *mut u8, bytes.as_ptr(), bytes.len())
+            };
+            if left != 0 {
+                faulted.set(true);
+            }
+        };
+        let write_u64 = |address: u64, value: u64| write(address, &value.to_le_bytes());
+        let mut next_string = layout.strings;
+        let mut write_string = |s: &str| {
+            let ptr = next_string;
+            // the strings are inside the stack, `strings_size` is the size of all of them
+            write(ptr, s.as_bytes());
+            write(ptr + s.len() as u64, &[0]);
+            next_string += s.len() as u64 + 1;
+            ptr
+        };
+
+        // the return address of the entry, no one should return there
+        write_u64(layout.rsp, 0);
+        write_u64(layout.argc, argv.len() as u64);
+        for (array, strings) in [(layout.argv, argv), (layout.envp, envp)] {
+            for (i, s) in strings.iter().enumerate() {
+                write_u64(array + i as u64 * 8, write_string(s));
+            }
+            write_u64(array + strings.len() as u64 * 8, 0);
+        }
+        let execfn_ptr = write_string(execfn);
+        // the random bytes are inside the stack
+        write(layout.random, &random);
+
+        let auxv: [AuxEntry; AUXV_ENTRIES + 1] = [
+            AuxEntry {
+                key: AT_PAGESZ,
+                value: PAGE_4K as u64,
+            },
+            AuxEntry {
+                key: AT_ENTRY,
+                value: entry,
+            },
+            AuxEntry {
+                key: AT_RANDOM,
+                value: layout.random,
+            },
+            AuxEntry {
+                key: AT_EXECFN,
+                value: execfn_ptr,
+            },
+            match time_page::physical_address() {
+                Some(_) => AuxEntry {
+                    key: AT_TIME_PAGE,
+                    value: TIME_PAGE_ADDRESS as u64,
+                },
+                None => AuxEntry {
+                    key: AT_IGNORE,
+                    value: 0,
+                },
+            },
+            AuxEntry {
+                key: AT_NULL,
+                value: 0,
+            },
+        ];
+        for (i, aux) in auxv.iter().enumerate() {
+            let address = layout.auxv + (i * mem::size_of::<AuxEntry>()) as u64;
+            write_u64(address, aux.key);
+            write_u64(address + 8, aux.value);
+        }
 
         // switch back to the old vm
         unsafe { old_vm.switch_to_this() };
         // we can be interrupted again
         cpu::cpu().pop_cli();
 
-        (rsp, argc as u64, argv_array_ptr)
+        if faulted.get() {
+            return Err(ProcessError::RangeNotMapped);
+        }
+        Ok(layout)
     }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        self.vm.unmap_process_memory();
+        for region in self.memory_regions.iter() {
+            if region.kind == RegionKind::File {
+                Self::unmap_region_pages(&mut self.vm, region);
+            } else {
+                self.vm
+                    .unmap(&region.as_entry(), region.kind.is_allocated());
+            }
+        }
+        self.vm.unmap_process_kernel_memory();
+    }
+}
+
+/// Checks the initial stack layout for different numbers and sizes of arguments, the entry
+/// must see `rsp + 8` aligned, and the parts must not overlap
+pub fn run_self_tests() {
+    println!("Running process startup self tests...");
+    let stack_end = USER_STACK_END as u64;
+    let stack_start = stack_end - (INITIAL_STACK_SIZE_PAGES * PAGE_4K) as u64;
+    let mut checked = 0;
+
+    for argc in 0..9 {
+        for envc in 0..5 {
+            for strings_size in (0..48).chain([255, 1000, 4097]) {
+                let layout = StackLayout::new(
+                    stack_start,
+                    stack_end,
+                    argc,
+                    envc,
+                    AUXV_ENTRIES,
+                    strings_size,
+                )
+                .expect("small arguments must fit");
+                let auxv_end =
+                    layout.auxv + ((AUXV_ENTRIES + 1) * mem::size_of::<AuxEntry>()) as u64;
+
+                assert!(layout.rsp >= stack_start);
+                assert_eq!((layout.rsp + 8) % STACK_ALIGNMENT, 0, "{layout:?}");
+                assert_eq!(layout.argc, layout.rsp + 8);
+                assert_eq!(layout.argv, layout.argc + 8);
+                assert_eq!(layout.envp, layout.argv + (argc as u64 + 1) * 8);
+                assert_eq!(layout.auxv, layout.envp + (envc as u64 + 1) * 8);
+                assert_eq!(layout.auxv % 8, 0);
+                assert!(auxv_end <= layout.random, "{layout:?}");
+                assert_eq!(layout.random % STACK_ALIGNMENT, 0);
+                assert!(layout.random + AT_RANDOM_SIZE as u64 <= layout.strings);
+                assert_eq!(layout.strings + strings_size as u64, stack_end);
+                // no more padding than needed for the alignments
+                assert!(layout.random - auxv_end < STACK_ALIGNMENT);
+                assert!(layout.strings - (layout.random + AT_RANDOM_SIZE as u64) < STACK_ALIGNMENT);
+                checked += 1;
+            }
+        }
     }
+
+    // too large strings, too many arguments, and sizes that would overflow
+    let stack_size = INITIAL_STACK_SIZE_PAGES * PAGE_4K;
+    let fits = |argc, strings_size| {
+        StackLayout::new(stack_start, stack_end, argc, 0, AUXV_ENTRIES, strings_size).is_some()
+    };
+    assert!(!fits(1, stack_size));
+    assert!(!fits(stack_size / 8, 0));
+    assert!(!fits(0, usize::MAX / 2));
+
+    // the stack grows by whole pages, and never onto its guard page
+    let guard_end = (USER_STACK_END - STACK_RESERVED_SIZE + PAGE_4K) as u64;
+    assert_eq!(stack_growth_start(RLIMIT_INFINITY), guard_end);
+    assert_eq!(stack_growth_start(STACK_RESERVED_SIZE as u64), guard_end);
+    assert_eq!(
+        stack_growth_start(8 * MB as u64 + 100),
+        stack_end - 8 * MB as u64
+    );
+    // less than the initial stack doesn't take it away
+    assert_eq!(stack_growth_start(0), stack_end);
+
+    // the first names, from the paths of the programs
+    assert_eq!(ProcessName::from_path("/shell").as_str(), "shell");
+    assert_eq!(ProcessName::from_path("/a/b c\td").as_str(), "b_c_d");
+    assert_eq!(
+        ProcessName::from_path("/a_very_long_program_name").as_str(),
+        "a_very_long_prog"
+    );
+    assert_eq!(ProcessName::from_path("/dir/").as_str(), "?");
+    assert_eq!(ProcessName::new("ééééééééé").unwrap().as_str(), "éééééééé");
+    assert!(ProcessName::new("").is_none() && ProcessName::new("a/b").is_none());
+
+    println!("Process startup self tests passed ({checked} layouts)");
+
+    core_dump::run_self_tests();
+    kernel_stack::run_self_tests();
+    memory_regions::run_self_tests();
+    timers::run_self_tests();
 }
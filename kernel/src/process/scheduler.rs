@@ -1,21 +1,138 @@
-use core::mem;
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, collections::BTreeSet, string::String, sync::Arc, vec::Vec};
+use kernel_user_link::process::{Resource, EXIT_CODE_CPU_LIMIT, RLIMIT_INFINITY};
 
 use crate::{
-    cpu::{self, idt::InterruptAllSavedState, interrupts},
-    memory_management::virtual_memory_mapper,
-    process::{syscalls, FxSave},
+    cpu::{
+        self, gdt, idt::InterruptAllSavedState, interrupts, irq_off, msr, softirq, Cpu, MAX_CPUS,
+    },
+    devices::{
+        self, clock,
+        generated::{Chunk, Cursor, Generator},
+        Device,
+    },
+    fs::{self, FileSystemError},
+    memory_management::{
+        memory_layout::{MemSize, PAGE_4K},
+        virtual_memory_mapper::{self, USER_ADDRESS_END},
+    },
+    process::{
+        syscalls::{self, user_memory},
+        FxSave,
+    },
     sync::spin::mutex::Mutex,
+    system::{self, Reason},
 };
 
-use super::{Process, ProcessContext, ProcessState};
+use super::{
+    kernel_thread, signal, timers, Process, ProcessContext, ProcessName, ProcessState,
+    ResourceLimits, INIT_PID,
+};
 
 static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+// What to do when `init` exits, nothing can be run without it, so we panic by default
+static REBOOT_ON_INIT_EXIT: AtomicBool = AtomicBool::new(false);
+// The state of the processes for the listing, updated on every usage sample, and rendered on read.
+// Reading files can happen while we hold the scheduler lock, so we can't use the processes there
+static PROCESSES_INFO: Mutex<ProcessesSnapshot> = Mutex::new(ProcessesSnapshot::empty());
+// The processes to estimate the working set of, harvesting the accessed bits needs a TLB flush
+// for every accessed page, so its only enabled by writing the pid to `/devices/working_set`
+static WORKING_SET_TRACKED: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+// Same as `PROCESSES_INFO`, the `(pid, pages)` of the tracked processes
+static WORKING_SET_INFO: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+/// Fixed point scale for the usage and load averages, `USAGE_SCALE` is `1.0`
+const USAGE_SCALE: u64 = 1000;
+/// Decay factors for samples taken every second, `exp(-1/window) * USAGE_SCALE`
+const DECAY_1S: u64 = 368;
+const DECAY_5S: u64 = 819;
+
+fn decay(old: u64, sample: u64, factor: u64) -> u64 {
+    (old * factor + sample * (USAGE_SCALE - factor)) / USAGE_SCALE
+}
+
+/// Per CPU time accounting, the elapsed time since the last mark is charged
+/// on every context switch and syscall boundary to the current bucket.
+///
+/// The times of the current process are kept here, and are moved to the process
+/// when its switched out, so syscalls don't need to lock the scheduler.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeAccounting {
+    last_mark: u64,
+    in_kernel: bool,
+    user: u64,
+    kernel: u64,
+    idle: u64,
+}
+
+impl TimeAccounting {
+    pub const fn empty() -> Self {
+        Self {
+            last_mark: 0,
+            in_kernel: false,
+            user: 0,
+            kernel: 0,
+            idle: 0,
+        }
+    }
+
+    /// Charge the time since the last mark, if `has_process` is false, its charged as idle
+    fn mark(&mut self, has_process: bool) {
+        let now = clock::uptime_nanos();
+        let elapsed = now.saturating_sub(self.last_mark);
+        self.last_mark = now;
+
+        if !has_process {
+            self.idle += elapsed;
+        } else if self.in_kernel {
+            self.kernel += elapsed;
+        } else {
+            self.user += elapsed;
+        }
+    }
+
+    fn take_process_times(&mut self) -> (u64, u64) {
+        (mem::take(&mut self.user), mem::take(&mut self.kernel))
+    }
+}
+
+/// CPU time used by a process, all in nanoseconds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessCpuTime {
+    user: u64,
+    kernel: u64,
+    // the total at the last sample, see [`sample_cpu_usage`]
+    last_sample_total: u64,
+    // usage over the last sampling windows in `USAGE_SCALE` units
+    usage: u64,
+}
+
+impl ProcessCpuTime {
+    fn total(&self) -> u64 {
+        self.user + self.kernel
+    }
+}
 
 struct Scheduler {
     interrupt_initialized: bool,
     processes: Vec<Process>,
+    // the processes replaced by `SYS_EXEC`, dropped by the scheduler of their CPU, since the
+    // syscall returns on their kernel stack
+    replaced: Vec<Process>,
+    // the CPUs running `schedule`, a bit for each id, they only run the processes of their
+    // queue, see `Process::cpu`
+    cpus: u64,
+
+    last_sample_time: u64,
+    last_sample_idle: u64,
+    idle_usage: u64,
+    // averages of the number of runnable processes, in `USAGE_SCALE` units
+    load_1s: u64,
+    load_5s: u64,
 }
 
 impl Scheduler {
@@ -23,13 +140,31 @@ impl Scheduler {
         Self {
             interrupt_initialized: false,
             processes: Vec::new(),
+            replaced: Vec::new(),
+            cpus: 0,
+            last_sample_time: 0,
+            last_sample_idle: 0,
+            idle_usage: 0,
+            load_1s: 0,
+            load_5s: 0,
         }
     }
 
-    pub fn push_process(&mut self, process: Process) {
+    /// Queues `process` on the CPU with the fewest processes
+    pub fn push_process(&mut self, mut process: Process) {
+        process.cpu = self.least_loaded_cpu();
         self.processes.push(process);
     }
 
+    /// Of the CPUs running the scheduler, or the running one before any is, i.e. for `init`
+    fn least_loaded_cpu(&self) -> usize {
+        let queue_len = |cpu| self.processes.iter().filter(|p| p.cpu == cpu).count();
+        (0..MAX_CPUS)
+            .filter(|&cpu| self.cpus & (1 << cpu) != 0)
+            .min_by_key(|&cpu| queue_len(cpu))
+            .unwrap_or_else(|| cpu::cpu().id)
+    }
+
     fn init_interrupt(&mut self) {
         if self.interrupt_initialized {
             return;
@@ -38,6 +173,10 @@ impl Scheduler {
 
         interrupts::create_scheduler_interrupt(scheduler_interrupt_handler);
         interrupts::create_syscall_interrupt(syscall_interrupt_handler);
+        interrupts::create_kernel_thread_interrupt(kernel_thread::interrupt_handler);
+
+        devices::register_device(Arc::new(ProcessesInfo));
+        devices::register_device(Arc::new(WorkingSetInfo));
     }
 }
 
@@ -46,45 +185,88 @@ pub fn push_process(process: Process) {
 }
 
 pub fn schedule() -> ! {
-    SCHEDULER.lock().init_interrupt();
+    SCHEDULER.run_with_mut(|scheduler| {
+        scheduler.init_interrupt();
+        scheduler.cpus |= 1 << cpu::cpu().id;
+    });
 
     loop {
         let current_cpu = cpu::cpu();
         assert!(current_cpu.context.is_none());
+        // what the interrupts left, with the interrupts enabled and before taking a process,
+        // so the timer can't switch away in the middle
+        softirq::run_pending();
 
         let mut scheduler = SCHEDULER.lock();
-        // no context holding, i.e. free to take a new process
-        for process in scheduler.processes.iter_mut() {
-            match process.state {
-                // only schedule another if we don't have current process ready to be run
-                ProcessState::Scheduled if current_cpu.context.is_none() => {
-                    // found a process to run
-                    current_cpu.push_cli();
-                    process.state = ProcessState::Running;
-                    // SAFETY: we are the scheduler and running in kernel space, so its safe to switch to this vm
-                    // as it has clones of our kernel mappings
-                    unsafe { process.switch_to_this_vm() };
-                    current_cpu.process_id = process.id;
-                    current_cpu.context = Some(process.context);
-                    current_cpu.scheduling = true;
-                    current_cpu.pop_cli();
+        // no context holding, i.e. free to take a new process, of our queue only
+        let cpu_id = current_cpu.id;
+        for process in scheduler.processes.iter_mut().filter(|p| p.cpu == cpu_id) {
+            process.reap_thread();
+            let pid = process.id;
+            for (state, context) in process.thread_states_mut() {
+                match *state {
+                    ProcessState::Yielded => {
+                        // it can run again, it has waited the least, so after the others of
+                        // its priority
+                        *state = ProcessState::Scheduled;
+                    }
+                    ProcessState::WaitingForLock if !fs::locks::is_waiting(pid) => {
+                        // the lock is taken, `SYS_FLOCK` returns 0
+                        context.rax = 0;
+                        *state = ProcessState::Scheduled;
+                    }
+                    ProcessState::Exited => {
+                        // keep the process for one time, it will be deleted later.
+                        // this is if we want to do extra stuff later
+                    }
+                    _ => {}
                 }
-                ProcessState::Yielded => {
-                    // schedule for next time
-                    process.state = ProcessState::Scheduled;
-                }
-                ProcessState::Exited => {
-                    // keep the process for one time, it will be deleted later.
-                    // this is if we want to do extra stuff later
+            }
+        }
+        // the highest priority, the waiting ones get closer to it, see `Process::waited`
+        let next = scheduler
+            .processes
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.cpu == cpu_id && p.can_run())
+            .min_by_key(|(_, p)| p.priority.saturating_sub(p.waited))
+            .map(|(i, _)| i);
+        if let Some(next) = next {
+            for (i, process) in scheduler.processes.iter_mut().enumerate() {
+                if i != next && process.cpu == cpu_id && process.can_run() {
+                    process.waited += 1;
                 }
-                _ => {}
             }
+            let process = &mut scheduler.processes[next];
+            process.next_thread();
+            process.waited = 0;
+            current_cpu.push_cli();
+            account_switch_in(current_cpu, process);
+            process.state = ProcessState::Running;
+            // SAFETY: we are the scheduler and running in kernel space, so its safe to switch to this vm
+            // as it has clones of our kernel mappings
+            unsafe { process.switch_to_this_vm() };
+            gdt::set_kernel_stack(process.kernel_stack_end());
+            current_cpu.process_id = process.id;
+            current_cpu.process_name = process.process_name();
+            current_cpu.context = Some(process.context);
+            current_cpu.set_scheduling(true);
+            current_cpu.pop_cli();
         }
+        let init_exit = scheduler
+            .processes
+            .iter()
+            .find(|p| p.id == INIT_PID && p.state == ProcessState::Exited)
+            .map(|p| p.exit_code);
+        // only ours, the CPU of another one can still be on its page tables
         scheduler
             .processes
-            .retain(|p| p.state != ProcessState::Exited);
+            .retain(|p| p.cpu != cpu_id || p.state != ProcessState::Exited);
+        scheduler.replaced.retain(|p| p.cpu != cpu_id);
         drop(scheduler);
-
+        if let Some(exit_code) = init_exit {
+            init_exited(exit_code);
+        }
         if current_cpu.context.is_some() {
             // call scheduler_interrupt_handler
             // we are using interrupts to switch context since it allows us to save the registers of exit, which is
@@ -95,7 +277,7 @@ pub fn schedule() -> ! {
             unsafe { core::arch::asm!("int 0xff", out("rax") _) }
             // SAFETY: we are not running in any process context, so its safe to go back to the kernel
             unsafe { virtual_memory_mapper::switch_to_kernel() };
-        } else {
+        } else if !softirq::has_pending() {
             // no process to run, just wait for interrupts
             unsafe { cpu::halt() };
         }
@@ -117,6 +299,35 @@ where
     f(process)
 }
 
+/// Run `f` with all the processes, `None` without waiting if the scheduler is locked, for the
+/// code that must not wait for it, i.e. the shrinkers of
+/// [`reclaim`](crate::memory_management::reclaim)
+pub fn try_with_processes<F, U>(f: F) -> Option<U>
+where
+    F: FnOnce(&mut [Process]) -> U,
+{
+    let mut scheduler = SCHEDULER.try_lock()?;
+    Some(f(&mut scheduler.processes))
+}
+
+/// Run `f` with all the processes
+pub fn with_processes<F, U>(f: F) -> U
+where
+    F: FnOnce(&mut [Process]) -> U,
+{
+    let mut scheduler = SCHEDULER.lock();
+    f(&mut scheduler.processes)
+}
+
+/// Run `f` with the process `pid`, `None` if there is no such process
+pub fn with_process<F, U>(pid: u64, f: F) -> Option<U>
+where
+    F: FnOnce(&mut Process) -> U,
+{
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.processes.iter_mut().find(|p| p.id == pid).map(f)
+}
+
 /// Exit the current process, and move the `all_state` to the scheduler.
 /// The caller of this function (i.e. interrupt) will use the `all_state` to go back to the scheduler.
 /// This function will remove the context from the CPU, and thus the value in `all_state` will be dropped.
@@ -130,8 +341,14 @@ pub fn exit_current_process(exit_code: i32, all_state: &mut InterruptAllSavedSta
         assert!(process.state == ProcessState::Running);
         current_cpu.push_cli();
         ppid = process.parent_id;
-        eprintln!("Process {} exited with code {}", process.id, exit_code);
+        eprintln!(
+            "Process {} ({}) exited with code {}",
+            process.id,
+            process.name(),
+            exit_code
+        );
 
+        account_switch_out(current_cpu, process);
         swap_context(current_cpu.context.as_mut().unwrap(), all_state);
         // clear context from the CPU
         // move the cpu context,
@@ -144,32 +361,150 @@ pub fn exit_current_process(exit_code: i32, all_state: &mut InterruptAllSavedSta
     // TODO: do it better with general waiting mechanism not just for pids
     let mut scheduler = SCHEDULER.lock();
     for proc in scheduler.processes.iter_mut() {
-        if proc.state == ProcessState::WaitingForPid(pid) {
-            // put the exit code in rax
-            // this should return to user mode directly
-            assert!(proc.context.cs & 0x3 == 3, "must be from user only");
-            proc.context.rax = exit_code as u64;
-            proc.state = ProcessState::Scheduled;
+        // only the threads of the parent can be waiting, see `wait_for_child`
+        let mut reaped = false;
+        for (state, context) in proc.thread_states_mut() {
+            if *state == ProcessState::WaitingForPid(pid) {
+                // put the exit code in rax
+                // this should return to user mode directly
+                assert!(context.cs & 0x3 == 3, "must be from user only");
+                context.rax = exit_code as u64;
+                *state = ProcessState::Scheduled;
+                reaped = true;
+            }
         }
-        if proc.id == ppid {
+        if !reaped && proc.id == ppid {
+            // kept until it's waited for, the one waiting now reaps it already
             proc.add_child_exit(pid, exit_code);
         }
+        // the orphans can still be waited for, by `init`
+        if proc.parent_id == pid {
+            proc.parent_id = INIT_PID;
+        }
     }
 
     current_cpu.pop_cli();
     // go back to the kernel after the scheduler interrupt
 }
 
+/// Replaces the current process with `image` from [`Process::exec_image`], and moves the
+/// `all_state` to the scheduler like [`exit_current_process`], `image` starts when it is
+/// scheduled. Nothing is told to the ones waiting for the pid, it's still running
+pub fn exec_current_process(mut image: Process, all_state: &mut InterruptAllSavedState) {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
+
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = &mut *scheduler;
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_cpu.process_id)
+        .expect("current process not found");
+    assert!(process.state == ProcessState::Running);
+    current_cpu.push_cli();
+    account_switch_out(current_cpu, process);
+    swap_context(current_cpu.context.as_mut().unwrap(), all_state);
+    // the context of the old program, not needed anymore
+    current_cpu.context = None;
+    image.take_exec_state(process);
+    let mut old = mem::replace(process, image);
+    old.state = ProcessState::Exited;
+    scheduler.replaced.push(old);
+    current_cpu.pop_cli();
+    // go back to the kernel after the scheduler interrupt
+}
+
+/// Exits the current thread, and moves the `all_state` to the scheduler like
+/// [`exit_current_process`], the scheduler frees it with [`Process::reap_thread`].
+/// Nothing is done if it's the last thread, false is returned, and the process must exit
+pub fn exit_current_thread(all_state: &mut InterruptAllSavedState) -> bool {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
+
+    let mut scheduler = SCHEDULER.lock();
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_cpu.process_id)
+        .expect("current process not found");
+    if process.threads.is_empty() {
+        return false;
+    }
+    current_cpu.push_cli();
+    park(current_cpu, process, all_state, ProcessState::ThreadExited);
+    drop(scheduler);
+    current_cpu.pop_cli();
+    true
+}
+
+/// What [`join_thread`] found of the thread
+pub enum ThreadJoin {
+    /// It had exited, and is joined now
+    Joined,
+    /// The current thread is parked until it exits
+    Waiting,
+    /// It's not a thread of the current process, or it was joined before
+    NotThread,
+}
+
+/// Joins the thread `id` of the current process, or waits for it to exit, with the scheduler
+/// locked like [`wait_for_child`]. It must not be the current thread
+pub fn join_thread(all_state: &mut InterruptAllSavedState, id: u64) -> ThreadJoin {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
+
+    let mut scheduler = SCHEDULER.lock();
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_cpu.process_id)
+        .expect("current process not found");
+    assert_ne!(process.thread_id(), id, "a thread can't join itself");
+    if process.threads.take_exited(id) {
+        return ThreadJoin::Joined;
+    }
+    if !process.threads.contains(id) {
+        return ThreadJoin::NotThread;
+    }
+    current_cpu.push_cli();
+    park(
+        current_cpu,
+        process,
+        all_state,
+        ProcessState::WaitingForThread(id),
+    );
+    drop(scheduler);
+    current_cpu.pop_cli();
+    ThreadJoin::Waiting
+}
+
+/// Reboot instead of panicking when `init` exits, from `init_exit=reboot` in the cmdline
+pub fn set_reboot_on_init_exit(enabled: bool) {
+    REBOOT_ON_INIT_EXIT.store(enabled, Ordering::Relaxed);
+}
+
+fn init_exited(exit_code: i32) -> ! {
+    println!("`init` exited with code {exit_code}");
+    if REBOOT_ON_INIT_EXIT.load(Ordering::Relaxed) {
+        system::reboot(Reason::InitExited);
+    }
+    panic!("`init` exited with code {exit_code}, nothing is left to run");
+}
+
 pub fn yield_current_if_any(all_state: &mut InterruptAllSavedState) {
     let current_cpu = cpu::cpu();
-    // do not yield if we don't have context or we are in the middle of scheduling
-    if current_cpu.context.is_none() || current_cpu.scheduling {
+    // do not yield if we don't have context, we are in the middle of scheduling, or the
+    // kernel must not leave this CPU (see `Cpu::push_no_yield`)
+    if current_cpu.context.is_none() || current_cpu.is_scheduling() || current_cpu.n_no_yield() != 0
+    {
         return;
     }
     // save context of this process and mark is as scheduled
     with_current_process(|process| {
         assert!(process.state == ProcessState::Running);
         current_cpu.push_cli();
+        account_switch_out(current_cpu, process);
         swap_context(current_cpu.context.as_mut().unwrap(), all_state);
         // clear context from the CPU
         process.context = current_cpu.context.take().unwrap();
@@ -179,34 +514,228 @@ pub fn yield_current_if_any(all_state: &mut InterruptAllSavedState) {
     // go back to the kernel after the scheduler interrupt
 }
 
-pub fn is_process_running(pid: u64) -> bool {
-    let scheduler = SCHEDULER.lock();
-    scheduler.processes.iter().any(|p| p.id == pid)
+/// Kill the current process if it has used more CPU time than its limit, this is called on
+/// the timer tick.
+pub fn enforce_cpu_limit(all_state: &mut InterruptAllSavedState) {
+    let current_cpu = cpu::cpu();
+    // only kill from user mode, in the kernel the process can be holding locks
+    if current_cpu.context.is_none() || current_cpu.is_scheduling() || all_state.frame.cs & 0x3 != 3
+    {
+        return;
+    }
+    current_cpu.time_accounting.mark(true);
+    let over_limit = with_current_process(|process| {
+        let limit = process.limits().get(Resource::CpuTimeMs);
+        // not yet moved to the process
+        let used = process.cpu_time.total()
+            + current_cpu.time_accounting.user
+            + current_cpu.time_accounting.kernel;
+        limit != RLIMIT_INFINITY && used / 1_000_000 > limit
+    });
+    if over_limit {
+        eprintln!(
+            "Process {} ({}) killed, CPU time limit exceeded",
+            current_cpu.process_id, current_cpu.process_name
+        );
+        exit_current_process(EXIT_CODE_CPU_LIMIT, all_state);
+    }
+}
+
+pub fn is_locked() -> bool {
+    SCHEDULER.is_locked()
+}
+
+/// Prints the processes without taking the scheduler lock or allocating, for the panic shell.
+///
+/// # Safety
+/// If the lock is held, the list could be in the middle of a change, and the output
+/// inconsistent, this must only be used when nothing else is running
+pub unsafe fn print_processes_unlocked() {
+    let current_cpu = cpu::cpu();
+    let scheduler = SCHEDULER.get_unlocked();
+    // the state is last, since padding it needs formatting it into a string first
+    println!(
+        "{:>5} {:<16} {:>5} {:>10} {:>10} {:>7} STATE",
+        "PID", "NAME", "PPID", "USER(ms)", "KERNEL(ms)", "PAGES"
+    );
+    for process in scheduler.processes.iter() {
+        println!(
+            "{:>5} {:<16} {:>5} {:>10} {:>10} {:>7} {:?}",
+            process.id,
+            process.name(),
+            process.parent_id,
+            process.cpu_time.user / 1_000_000,
+            process.cpu_time.kernel / 1_000_000,
+            process.resident_pages(),
+            process.state,
+        );
+    }
+    if current_cpu.context.is_some() {
+        println!(
+            "current: {} ({})",
+            current_cpu.process_id, current_cpu.process_name
+        );
+    }
+}
+
+/// What [`wait_for_child`] found of the child
+pub enum ChildWait {
+    /// It had exited, with this code, its exit is reaped
+    Exited(i32),
+    /// It's still running, and we didn't wait
+    Running,
+    /// The current process is parked until it exits, its code is put in `rax` then
+    Waiting,
+    /// It's not a child of the current process, or its exit was reaped before
+    NotChild,
 }
 
-pub fn wait_for_pid(all_state: &mut InterruptAllSavedState, pid: u64) -> bool {
+/// Reaps the exit of the child `pid` of the current process, or waits for it if `block`.
+///
+/// It's all done with the scheduler locked, so the child can't exit between the check and the
+/// wait without us being woken. The exits are kept by the parents until they are reaped here,
+/// see [`exit_current_process`]
+pub fn wait_for_child(all_state: &mut InterruptAllSavedState, pid: u64, block: bool) -> ChildWait {
     let current_cpu = cpu::cpu();
     assert!(current_cpu.context.is_some());
 
-    // we can't wait for a process that doesn't exist now, unless we are a parent of a process that has exited
-    // see [`exit_current_process`]
-    let process_found = is_process_running(pid);
-    if !process_found {
+    let mut scheduler = SCHEDULER.lock();
+    let current_pid = current_cpu.process_id;
+    // only children can be waited for, otherwise a process could wait for itself or `init`
+    let is_child = scheduler
+        .processes
+        .iter()
+        .any(|p| p.id == pid && p.parent_id == current_pid);
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_pid)
+        .expect("current process not found");
+    if let Some(exit_code) = process.get_child_exit(pid) {
+        return ChildWait::Exited(exit_code);
+    }
+    if !is_child {
+        return ChildWait::NotChild;
+    }
+    if !block {
+        return ChildWait::Running;
+    }
+    current_cpu.push_cli();
+    park(
+        current_cpu,
+        process,
+        all_state,
+        ProcessState::WaitingForPid(pid),
+    );
+    drop(scheduler);
+    current_cpu.pop_cli();
+    ChildWait::Waiting
+}
+
+/// Parks the current thread until `SYS_FUTEX_WAKE` of `addr`, if the `u32` there is `expected`,
+/// returns whether it did. The value is read with the scheduler locked, so no thread can
+/// change it and wake the futex before we wait. It counts as changed if its page is not
+/// mapped now.
+///
+/// # Safety
+/// `addr` must be checked with [`user_memory::check_user_range`] before
+pub unsafe fn wait_for_futex(
+    all_state: &mut InterruptAllSavedState,
+    addr: u64,
+    expected: u32,
+) -> bool {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
+
+    let mut scheduler = SCHEDULER.lock();
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_cpu.process_id)
+        .expect("current process not found");
+    // SAFETY: checked by the caller
+    if unsafe { user_memory::read_user_u32_mapped(addr) } != Some(expected) {
         return false;
     }
+    current_cpu.push_cli();
+    park(
+        current_cpu,
+        process,
+        all_state,
+        ProcessState::WaitingForFutex(addr),
+    );
+    drop(scheduler);
+    current_cpu.pop_cli();
+    true
+}
+
+/// Parks the current thread until the monotonic time is `deadline`, its timer is added with
+/// the scheduler locked, see [`timers`]
+pub fn sleep_current(all_state: &mut InterruptAllSavedState, deadline: u64) {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
 
-    // save context of this process and mark it as waiting
+    let mut scheduler = SCHEDULER.lock();
+    let process = scheduler
+        .processes
+        .iter_mut()
+        .find(|p| p.id == current_cpu.process_id)
+        .expect("current process not found");
+    current_cpu.push_cli();
+    park(
+        current_cpu,
+        process,
+        all_state,
+        ProcessState::Sleeping(deadline),
+    );
+    timers::add(deadline, process.id);
+    drop(scheduler);
+    current_cpu.pop_cli();
+}
+
+/// Parks the current process until the lock it's queued for is taken, see [`fs::locks`]
+pub fn wait_for_lock(all_state: &mut InterruptAllSavedState) {
+    let current_cpu = cpu::cpu();
+    assert!(current_cpu.context.is_some());
+
+    park_current_process(all_state, ProcessState::WaitingForLock);
+}
+
+/// Saves the context of the current process and marks it as `state`, the interrupt then goes
+/// back to the kernel
+fn park_current_process(all_state: &mut InterruptAllSavedState, state: ProcessState) {
+    let current_cpu = cpu::cpu();
     with_current_process(|process| {
-        assert!(process.state == ProcessState::Running);
         current_cpu.push_cli();
-        swap_context(current_cpu.context.as_mut().unwrap(), all_state);
-        // clear context from the CPU
-        process.context = current_cpu.context.take().unwrap();
-        process.state = ProcessState::WaitingForPid(pid);
+        park(current_cpu, process, all_state, state);
     });
     current_cpu.pop_cli();
-    // go back to the kernel after the scheduler interrupt
-    true
+}
+
+/// [`park_current_process`] with the current `process` found already
+fn park(
+    current_cpu: &mut Cpu,
+    process: &mut Process,
+    all_state: &mut InterruptAllSavedState,
+    state: ProcessState,
+) {
+    assert!(process.state == ProcessState::Running);
+    account_switch_out(current_cpu, process);
+    swap_context(current_cpu.context.as_mut().unwrap(), all_state);
+    // clear context from the CPU
+    process.context = current_cpu.context.take().unwrap();
+    process.state = state;
+}
+
+/// The context to continue from `all_state`, with the FPU state of now, for the child of
+/// `SYS_FORK`
+pub fn copy_context(all_state: &InterruptAllSavedState) -> ProcessContext {
+    let mut context = ProcessContext::default();
+    // `swap_context` restores this, so the FPU state stays as it is
+    unsafe { core::arch::x86_64::_fxsave64(&mut context.fxsave as *mut FxSave as _) };
+    context.fs_base = unsafe { msr::read(msr::FS_BASE) };
+    swap_context(&mut context, &mut all_state.clone());
+    context
 }
 
 pub fn swap_context(context: &mut ProcessContext, all_state: &mut InterruptAllSavedState) {
@@ -214,6 +743,10 @@ pub fn swap_context(context: &mut ProcessContext, all_state: &mut InterruptAllSa
     unsafe { core::arch::x86_64::_fxsave64(&mut fxsave as *mut FxSave as _) };
     unsafe { core::arch::x86_64::_fxrstor64(context.fxsave.0.as_ptr() as _) };
     context.fxsave = fxsave;
+    // the kernel doesn't use it, and the interrupts keep it, see `idt_vectors.S`
+    let fs_base = unsafe { msr::read(msr::FS_BASE) };
+    unsafe { msr::write(msr::FS_BASE, context.fs_base) };
+    context.fs_base = fs_base;
 
     mem::swap(&mut all_state.frame.rflags, &mut context.rflags);
     mem::swap(&mut all_state.frame.rip, &mut context.rip);
@@ -252,19 +785,392 @@ extern "cdecl" fn scheduler_interrupt_handler(all_state: &mut InterruptAllSavedS
     assert!(all_state.frame.cs & 0x3 == 0, "must be from kernel only");
     let current_cpu = cpu::cpu();
     assert!(current_cpu.context.is_some());
-    assert!(current_cpu.scheduling);
+    assert!(current_cpu.is_scheduling());
     assert!(current_cpu.interrupts_disabled());
 
     // we can yield at this point after we go to the process
-    current_cpu.scheduling = false;
+    current_cpu.set_scheduling(false);
 
     swap_context(current_cpu.context.as_mut().unwrap(), all_state);
+    // on the way back to the user, it could be killed here, then we go back to the scheduler
+    signal::deliver_pending(all_state);
 }
 
 extern "cdecl" fn syscall_interrupt_handler(all_state: &mut InterruptAllSavedState) {
+    // the gate disables them until we have the kernel GS base, syscalls can be interrupted
+    unsafe { cpu::set_interrupts() };
     assert!(all_state.frame.cs & 0x3 == 3, "must be from user only");
     let current_cpu = cpu::cpu();
     assert!(current_cpu.context.is_some());
 
+    current_cpu.time_accounting.mark(true);
+    current_cpu.time_accounting.in_kernel = true;
+
+    irq_off::syscall_enter(all_state.rest.rax);
     syscalls::handle_syscall(all_state);
+    irq_off::syscall_exit();
+}
+
+fn account_switch_in(cpu: &mut Cpu, process: &Process) {
+    // until now, we were in the scheduler
+    cpu.time_accounting.mark(false);
+    // the process could have been switched out while in the kernel
+    cpu.time_accounting.in_kernel = process.context.cs & 0x3 == 0;
+}
+
+fn account_switch_out(cpu: &mut Cpu, process: &mut Process) {
+    cpu.time_accounting.mark(true);
+    let (user, kernel) = cpu.time_accounting.take_process_times();
+    process.cpu_time.user += user;
+    process.cpu_time.kernel += kernel;
+}
+
+/// Called when a syscall is done, and we are going back to the user
+pub fn account_syscall_exit() {
+    let current_cpu = cpu::cpu();
+    current_cpu
+        .time_accounting
+        .mark(current_cpu.context.is_some());
+    current_cpu.time_accounting.in_kernel = false;
+}
+
+/// Update the usage and load averages, this should be called periodically each second
+pub fn sample_cpu_usage() {
+    let current_cpu = cpu::cpu();
+    let mut scheduler = SCHEDULER.lock();
+
+    let has_process = current_cpu.context.is_some();
+    current_cpu.time_accounting.mark(has_process);
+    let now = current_cpu.time_accounting.last_mark;
+    let window = now.saturating_sub(scheduler.last_sample_time);
+    if window == 0 {
+        return;
+    }
+    scheduler.last_sample_time = now;
+
+    let runnable = scheduler
+        .processes
+        .iter()
+        .filter(|p| {
+            matches!(
+                p.state,
+                ProcessState::Running | ProcessState::Scheduled | ProcessState::Yielded
+            )
+        })
+        .count() as u64
+        * USAGE_SCALE;
+    scheduler.load_1s = decay(scheduler.load_1s, runnable, DECAY_1S);
+    scheduler.load_5s = decay(scheduler.load_5s, runnable, DECAY_5S);
+
+    let idle = current_cpu.time_accounting.idle;
+    let idle_sample = (idle - scheduler.last_sample_idle) * USAGE_SCALE / window;
+    scheduler.last_sample_idle = idle;
+    scheduler.idle_usage = decay(scheduler.idle_usage, idle_sample, DECAY_1S);
+
+    for process in scheduler.processes.iter_mut() {
+        let mut total = process.cpu_time.total();
+        if has_process && process.id == current_cpu.process_id {
+            // not yet moved to the process
+            total += current_cpu.time_accounting.user + current_cpu.time_accounting.kernel;
+        }
+        let sample =
+            (total - process.cpu_time.last_sample_total).min(window) * USAGE_SCALE / window;
+        process.cpu_time.last_sample_total = total;
+        process.cpu_time.usage = decay(process.cpu_time.usage, sample, DECAY_1S);
+    }
+
+    PROCESSES_INFO.lock().update(&scheduler, current_cpu);
+    sample_working_set(&mut scheduler);
+}
+
+/// The pages accessed since the last sample are the working set of the process
+fn sample_working_set(scheduler: &mut Scheduler) {
+    let mut tracked = WORKING_SET_TRACKED.lock();
+    // forget the exited ones
+    tracked.retain(|pid| scheduler.processes.iter().any(|p| p.id == *pid));
+
+    let mut info = WORKING_SET_INFO.lock();
+    info.clear();
+    for process in scheduler.processes.iter_mut() {
+        if !tracked.contains(&process.id) {
+            continue;
+        }
+        let pages = process.vm.harvest_accessed_count(0, USER_ADDRESS_END);
+        info.push((process.id, pages));
+    }
+}
+
+struct FixedPoint(u64);
+
+impl core::fmt::Display for FixedPoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // use `pad` so that width and alignment work
+        f.pad(&alloc::format!(
+            "{}.{:02}",
+            self.0 / USAGE_SCALE,
+            self.0 % USAGE_SCALE / 10
+        ))
+    }
+}
+
+/// A process in [`ProcessesSnapshot`]
+#[derive(Debug, Clone, Copy)]
+struct ProcessRow {
+    id: u64,
+    name: ProcessName,
+    parent_id: u64,
+    state: ProcessState,
+    cpu_time: ProcessCpuTime,
+    pages: u64,
+    limits: ResourceLimits,
+    priority: u64,
+}
+
+/// What `/devices/processes` shows, a copy of the state at the last sample
+struct ProcessesSnapshot {
+    last_sample_time: u64,
+    load_1s: u64,
+    load_5s: u64,
+    idle: u64,
+    idle_usage: u64,
+    processes: Vec<ProcessRow>,
+}
+
+impl ProcessesSnapshot {
+    const fn empty() -> Self {
+        Self {
+            last_sample_time: 0,
+            load_1s: 0,
+            load_5s: 0,
+            idle: 0,
+            idle_usage: 0,
+            processes: Vec::new(),
+        }
+    }
+
+    /// Copies the state of the processes, reusing the space of the last sample
+    fn update(&mut self, scheduler: &Scheduler, current_cpu: &Cpu) {
+        self.last_sample_time = scheduler.last_sample_time;
+        self.load_1s = scheduler.load_1s;
+        self.load_5s = scheduler.load_5s;
+        self.idle = current_cpu.time_accounting.idle;
+        self.idle_usage = scheduler.idle_usage;
+
+        self.processes.clear();
+        self.processes
+            .extend(scheduler.processes.iter().map(|process| {
+                let mut cpu_time = process.cpu_time;
+                if current_cpu.context.is_some() && process.id == current_cpu.process_id {
+                    cpu_time.user += current_cpu.time_accounting.user;
+                    cpu_time.kernel += current_cpu.time_accounting.kernel;
+                }
+                ProcessRow {
+                    id: process.id,
+                    name: process.process_name(),
+                    parent_id: process.parent_id,
+                    state: process.state,
+                    cpu_time,
+                    pages: process.resident_pages(),
+                    limits: *process.limits(),
+                    priority: process.priority,
+                }
+            }));
+    }
+
+    /// Adds the line `line` to `chunk`, the first two are the header,
+    /// `None` if there is no such line
+    fn render_line(&self, line: usize, chunk: &mut Chunk) -> Option<bool> {
+        let columns = format_args!(
+            "{:>5} {:<16} {:>5} {:<16} {:>10} {:>10} {:>7} {:>7} {:<17} {:>3}",
+            "PID",
+            "NAME",
+            "PPID",
+            "STATE",
+            "USER(ms)",
+            "KERNEL(ms)",
+            "CPU%",
+            "PAGES",
+            "LIMITS(mem/fd/ms)",
+            "PRI"
+        );
+        let process = match line {
+            0 => {
+                return Some(chunk.line(format_args!(
+                    "uptime: {}s, load: {} (1s) {} (5s), idle: {}ms ({}%)",
+                    FixedPoint(self.last_sample_time / 1_000_000),
+                    FixedPoint(self.load_1s),
+                    FixedPoint(self.load_5s),
+                    self.idle / 1_000_000,
+                    FixedPoint(self.idle_usage * 100),
+                )))
+            }
+            1 => return Some(chunk.line(columns)),
+            _ => self.processes.get(line - 2)?,
+        };
+
+        let limit = |resource| match process.limits.get(resource) {
+            RLIMIT_INFINITY => String::from("-"),
+            value => alloc::format!("{value}"),
+        };
+        Some(chunk.line(format_args!(
+            "{:>5} {:<16} {:>5} {:<16} {:>10} {:>10} {:>7} {:>7} {:<17} {:>3}",
+            process.id,
+            process.name,
+            process.parent_id,
+            alloc::format!("{:?}", process.state),
+            process.cpu_time.user / 1_000_000,
+            process.cpu_time.kernel / 1_000_000,
+            FixedPoint(process.cpu_time.usage * 100),
+            process.pages,
+            alloc::format!(
+                "{}/{}/{}",
+                limit(Resource::MemoryPages),
+                limit(Resource::OpenFiles),
+                limit(Resource::CpuTimeMs)
+            ),
+            process.priority,
+        )))
+    }
+}
+
+/// `/devices/processes`, a listing of the processes and their CPU usage
+#[derive(Debug)]
+struct ProcessesInfo;
+
+impl Device for ProcessesInfo {
+    fn name(&self) -> &str {
+        "processes"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(ProcessesInfo))
+    }
+}
+
+/// The item of the cursor is the line
+impl Generator for ProcessesInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let info = PROCESSES_INFO.lock();
+        let mut chunk = Chunk::new(out);
+        while let Some(true) = info.render_line(cursor.item, &mut chunk) {
+            cursor.item += 1;
+        }
+        chunk.written()
+    }
+}
+
+/// `/devices/working_set`, the estimated working set of the tracked processes,
+/// `pid pages size` per line.
+///
+/// Writing `<pid>` starts tracking the process, and `-<pid>` stops it
+#[derive(Debug)]
+struct WorkingSetInfo;
+
+impl Device for WorkingSetInfo {
+    fn name(&self) -> &str {
+        "working_set"
+    }
+
+    fn generator(&self) -> Option<Box<dyn Generator>> {
+        Some(Box::new(WorkingSetInfo))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        let (enable, pid) = match command.strip_prefix('-') {
+            Some(pid) => (false, pid),
+            None => (true, command),
+        };
+        let pid = pid
+            .parse::<u64>()
+            .map_err(|_| FileSystemError::InvalidData)?;
+
+        let mut tracked = WORKING_SET_TRACKED.lock();
+        if enable {
+            tracked.insert(pid);
+        } else {
+            tracked.remove(&pid);
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+/// The item of the cursor is the index of the tracked process
+impl Generator for WorkingSetInfo {
+    fn render_chunk(&mut self, cursor: &mut Cursor, out: &mut [u8]) -> usize {
+        let info = WORKING_SET_INFO.lock();
+        let mut chunk = Chunk::new(out);
+        while let Some(&(pid, pages)) = info.get(cursor.item) {
+            if !chunk.line(format_args!(
+                "{pid} {pages} {}",
+                MemSize(pages * PAGE_4K as u64)
+            )) {
+                break;
+            }
+            cursor.item += 1;
+        }
+        chunk.written()
+    }
+}
+
+/// The processes [`run_self_tests`] puts on the queue of the second CPU
+const SELFTEST_PROCESSES: usize = 4;
+/// How long the second CPU has to start its scheduler, and to run them to their exit
+const SELFTEST_TIMEOUT_NANOS: u64 = 5_000_000_000;
+
+/// Puts processes that exit right away on the queue of the second CPU, while the boot CPU
+/// doesn't run its own yet, so they only go away if it runs them from its queue. They stay in
+/// it meanwhile. Needs the other CPUs released, and is skipped with only one
+pub fn run_self_tests() {
+    if cpu::smp::online_cpus() < 2 {
+        println!("Scheduler self tests skipped, only one CPU is online");
+        return;
+    }
+    println!("Running scheduler self tests...");
+    const SECOND: usize = 1;
+    let start = clock::uptime_nanos();
+    let wait = |what: &str| {
+        assert!(
+            clock::uptime_nanos() - start < SELFTEST_TIMEOUT_NANOS,
+            "scheduler self test: CPU {SECOND} {what}"
+        );
+        // the timer wakes us up
+        unsafe { cpu::halt() };
+    };
+    while SCHEDULER.lock().cpus & (1 << SECOND) == 0 {
+        wait("didn't start its scheduler");
+    }
+    assert_eq!(
+        SCHEDULER.lock().cpus & (1 << cpu::cpu().id),
+        0,
+        "the boot CPU runs its queue already"
+    );
+
+    let mut pids = Vec::new();
+    while pids.len() < SELFTEST_PROCESSES {
+        let mut process = crate::executable::selftest_exiting_process();
+        // its exit would be the one of `init`
+        if process.id == INIT_PID {
+            continue;
+        }
+        process.cpu = SECOND;
+        pids.push(process.id);
+        SCHEDULER.lock().processes.push(process);
+    }
+    loop {
+        let left = with_processes(|processes| {
+            processes
+                .iter()
+                .filter(|p| pids.contains(&p.id))
+                .inspect(|p| assert_eq!(p.cpu, SECOND, "process {} moved", p.id))
+                .count()
+        });
+        if left == 0 {
+            break;
+        }
+        wait("didn't run its queue");
+    }
+    println!("Scheduler self tests passed ({SELFTEST_PROCESSES} processes run by CPU {SECOND})");
 }
@@ -0,0 +1,351 @@
+//! The signals of the processes, see [`kernel_user_link::signal`].
+//!
+//! They are sent to the process, not to a thread: [`send`] makes them pending, and the scheduler
+//! delivers them with [`deliver_pending`] to the thread it switches to, when it goes back to
+//! user mode. Every syscall and timer tick switches, so a process gets them when it returns
+//! from the one it's in. The threads waiting for a child, a thread or a futex, or sleeping, are
+//! woken for them, their wait fails with `SyscallError::Interrupted`, the ones queued for a
+//! file lock keep waiting.
+//!
+//! The CPU exceptions go to the handler of their signal if there is one (see
+//! [`deliver_exception`]), otherwise the process is killed by [`super::crash`].
+
+use core::mem;
+
+use kernel_user_link::{
+    signal::{
+        exit_code, signal_for_exception, SignalFrame, SignalRegisters, NUM_SIGNALS, SIGKILL,
+        SIGSEGV, SIG_DFL, SIG_IGN,
+    },
+    syscalls::{syscall_result_to_u64, SyscallArgError, SyscallError},
+};
+
+use crate::{
+    cpu::{self, gdt::USER_RING, idt::InterruptAllSavedState},
+    memory_management::virtual_memory_mapper::USER_ADDRESS_END,
+};
+
+use super::{
+    scheduler::{self, exit_current_process, with_current_process},
+    syscalls::user_memory,
+    Process, ProcessState, INIT_PID,
+};
+
+/// Below the stack pointer, the code can keep data there without moving it (the red zone of the
+/// System V ABI), the frame goes below it
+const RED_ZONE: u64 = 128;
+/// The flags `SYS_SIGRETURN` takes from the frame, the arithmetic ones and the direction flag
+const RESTORED_FLAGS: u64 = 0xCD5;
+const DIRECTION_FLAG: u64 = 1 << 10;
+
+/// Whether `signal` is one of `1..NUM_SIGNALS`
+pub fn is_valid(signal: u64) -> bool {
+    (1..NUM_SIGNALS).contains(&signal)
+}
+
+/// What a signal does when it's delivered, set with `SYS_SIGACTION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignalAction {
+    /// Exit with [`exit_code`]
+    #[default]
+    Default,
+    Ignore,
+    Handler {
+        handler: u64,
+        restorer: u64,
+    },
+}
+
+impl SignalAction {
+    /// From the arguments of `SYS_SIGACTION`, `restorer` is only used with a handler
+    pub fn new(handler: u64, restorer: u64) -> Self {
+        match handler {
+            SIG_DFL => Self::Default,
+            SIG_IGN => Self::Ignore,
+            handler => Self::Handler { handler, restorer },
+        }
+    }
+
+    /// The `handler` argument of `SYS_SIGACTION` for this action
+    pub fn handler(&self) -> u64 {
+        match self {
+            Self::Default => SIG_DFL,
+            Self::Ignore => SIG_IGN,
+            Self::Handler { handler, .. } => *handler,
+        }
+    }
+}
+
+/// The signals of a process, a bit for each in the sets, all with the default action for a
+/// spawned process
+#[derive(Debug, Clone, Default)]
+pub struct Signals {
+    actions: [SignalAction; NUM_SIGNALS as usize],
+    pending: u64,
+    // the ones being handled, until their `SYS_SIGRETURN`
+    blocked: u64,
+}
+
+impl Signals {
+    /// For the child of `SYS_FORK`, only the actions and the blocked ones are copied
+    pub fn for_fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// For the new program of `SYS_EXEC`, the handlers are gone, the ignored signals stay
+    /// ignored and the pending ones pending
+    pub fn for_exec(&self) -> Self {
+        let mut actions = self.actions;
+        for action in actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+        Self {
+            actions,
+            pending: self.pending,
+            blocked: 0,
+        }
+    }
+
+    /// Sets the action of `signal`, which can't be [`SIGKILL`], and returns the one before
+    pub fn set_action(&mut self, signal: u64, action: SignalAction) -> SignalAction {
+        assert!(is_valid(signal) && signal != SIGKILL);
+        if action == SignalAction::Ignore {
+            self.pending &= !(1 << signal);
+        }
+        mem::replace(&mut self.actions[signal as usize], action)
+    }
+
+    /// Makes `signal` pending, unless it's ignored, returns whether it is
+    fn add_pending(&mut self, signal: u64) -> bool {
+        if self.actions[signal as usize] == SignalAction::Ignore {
+            return false;
+        }
+        self.pending |= 1 << signal;
+        true
+    }
+
+    /// Takes the next pending signal that is not blocked, [`SIGKILL`] first, with its action
+    /// and the blocked signals before it. A handled signal is blocked until its
+    /// `SYS_SIGRETURN`
+    fn take_next(&mut self) -> Option<(u64, SignalAction, u64)> {
+        let ready = self.pending & !self.blocked;
+        if ready == 0 {
+            return None;
+        }
+        let signal = if ready & (1 << SIGKILL) != 0 {
+            SIGKILL
+        } else {
+            ready.trailing_zeros() as u64
+        };
+        self.pending &= !(1 << signal);
+        let blocked = self.blocked;
+        let action = self.actions[signal as usize];
+        if let SignalAction::Handler { .. } = action {
+            self.blocked |= 1 << signal;
+        }
+        Some((signal, action, blocked))
+    }
+
+    /// The handler of `signal` and the blocked signals before it, which blocks it now, `None`
+    /// if it has none or it's blocked
+    fn take_handler(&mut self, signal: u64) -> Option<(u64, u64, u64)> {
+        let SignalAction::Handler { handler, restorer } = self.actions[signal as usize] else {
+            return None;
+        };
+        if self.blocked & (1 << signal) != 0 {
+            return None;
+        }
+        let blocked = self.blocked;
+        self.blocked |= 1 << signal;
+        Some((handler, restorer, blocked))
+    }
+
+    fn set_blocked(&mut self, blocked: u64) {
+        self.blocked = blocked & !(1 << SIGKILL);
+    }
+}
+
+/// Makes `signal` pending for `process`, and wakes its threads waiting for a child, a thread or
+/// a futex, or sleeping, unless it's ignored
+pub fn send(process: &mut Process, signal: u64) {
+    assert!(is_valid(signal));
+    // it's never in the user to handle them, nor to be killed
+    if process.is_kernel_thread() {
+        return;
+    }
+    if !process.signals.add_pending(signal) {
+        return;
+    }
+    for (state, context) in process.thread_states_mut() {
+        if matches!(
+            *state,
+            ProcessState::WaitingForPid(_)
+                | ProcessState::WaitingForThread(_)
+                | ProcessState::Sleeping(_)
+                | ProcessState::WaitingForFutex(_)
+        ) {
+            // they were parked from the syscall, so this is its result
+            assert!(context.cs & 0x3 == 3, "must be from user only");
+            context.rax = syscall_result_to_u64(Err(SyscallError::Interrupted));
+            *state = ProcessState::Scheduled;
+        }
+    }
+}
+
+/// Sends `signal` to the processes with the terminal `index` of the console open, but
+/// `init`, i.e. [`kernel_user_link::signal::SIGINT`] for `Ctrl+C`, the shell ignores it so
+/// only the programs it runs are interrupted
+pub fn send_to_terminal(index: usize, signal: u64) {
+    scheduler::with_processes(|processes| {
+        for process in processes
+            .iter_mut()
+            .filter(|process| process.id != INIT_PID && process.has_terminal_open(index))
+        {
+            send(process, signal);
+        }
+    });
+}
+
+/// Delivers the next pending signal of the current process, called by the scheduler with the
+/// context of the thread it switched to in `all_state`: the handler is called, or the process
+/// is killed and `all_state` moves back to the scheduler like [`exit_current_process`]
+pub fn deliver_pending(all_state: &mut InterruptAllSavedState) {
+    if all_state.frame.cs & 0x3 != USER_RING {
+        return;
+    }
+    let Some((signal, action, blocked)) =
+        with_current_process(|process| process.signals.take_next())
+    else {
+        return;
+    };
+    match action {
+        SignalAction::Handler { handler, restorer } => {
+            if push_frame(all_state, signal, handler, restorer, blocked).is_err() {
+                // no room for the frame on the stack
+                kill_current_process(SIGSEGV, all_state);
+            }
+        }
+        SignalAction::Default => kill_current_process(signal, all_state),
+        // not made pending, see `Signals::add_pending`
+        SignalAction::Ignore => {}
+    }
+}
+
+/// Calls the handler of the signal of the CPU exception `vector`, which the current process
+/// caused in user mode, false if it has none, or it's blocked (i.e. the handler caused it), or
+/// the frame doesn't fit on the stack
+pub fn deliver_exception(all_state: &mut InterruptAllSavedState, vector: u8) -> bool {
+    let signal = signal_for_exception(vector);
+    let Some((handler, restorer, blocked)) =
+        with_current_process(|process| process.signals.take_handler(signal))
+    else {
+        return false;
+    };
+    push_frame(all_state, signal, handler, restorer, blocked).is_ok()
+}
+
+/// Puts back the registers and the blocked signals from the frame of [`push_frame`], for
+/// `SYS_SIGRETURN`. If the frame can't be read, or it would go to the kernel, the process is
+/// killed with [`SIGSEGV`]
+pub fn return_from_handler(all_state: &mut InterruptAllSavedState) {
+    // after the `ret` of the handler
+    let address = all_state.frame.rsp.wrapping_sub(8);
+    // SAFETY: the frame is only integers
+    let frame = unsafe { user_memory::copy_value_from_user::<SignalFrame>(address) };
+    let registers = match frame {
+        Ok(frame)
+            if frame.registers.rip < USER_ADDRESS_END && frame.registers.rsp < USER_ADDRESS_END =>
+        {
+            with_current_process(|process| process.signals.set_blocked(frame.blocked));
+            frame.registers
+        }
+        _ => {
+            kill_current_process(SIGSEGV, all_state);
+            return;
+        }
+    };
+
+    let rest = &mut all_state.rest;
+    rest.rax = registers.rax;
+    rest.rbx = registers.rbx;
+    rest.rcx = registers.rcx;
+    rest.rdx = registers.rdx;
+    rest.rsi = registers.rsi;
+    rest.rdi = registers.rdi;
+    rest.rbp = registers.rbp;
+    rest.r8 = registers.r8;
+    rest.r9 = registers.r9;
+    rest.r10 = registers.r10;
+    rest.r11 = registers.r11;
+    rest.r12 = registers.r12;
+    rest.r13 = registers.r13;
+    rest.r14 = registers.r14;
+    rest.r15 = registers.r15;
+    let frame = &mut all_state.frame;
+    frame.rip = registers.rip;
+    frame.rsp = registers.rsp;
+    frame.rflags = (frame.rflags & !RESTORED_FLAGS) | (registers.rflags & RESTORED_FLAGS);
+}
+
+/// Saves the registers of `all_state` in a [`SignalFrame`] below its stack, and makes it call
+/// `handler` with `signal`
+fn push_frame(
+    all_state: &mut InterruptAllSavedState,
+    signal: u64,
+    handler: u64,
+    restorer: u64,
+    blocked: u64,
+) -> Result<(), SyscallArgError> {
+    let rest = &all_state.rest;
+    let frame = SignalFrame {
+        restorer,
+        signal,
+        blocked,
+        registers: SignalRegisters {
+            rax: rest.rax,
+            rbx: rest.rbx,
+            rcx: rest.rcx,
+            rdx: rest.rdx,
+            rsi: rest.rsi,
+            rdi: rest.rdi,
+            rbp: rest.rbp,
+            rsp: all_state.frame.rsp,
+            r8: rest.r8,
+            r9: rest.r9,
+            r10: rest.r10,
+            r11: rest.r11,
+            r12: rest.r12,
+            r13: rest.r13,
+            r14: rest.r14,
+            r15: rest.r15,
+            rip: all_state.frame.rip,
+            rflags: all_state.frame.rflags,
+        },
+    };
+    let size = mem::size_of::<SignalFrame>() as u64;
+    // aligned like after a `call`, `rsp + 8` is a multiple of 16
+    let address = (all_state.frame.rsp.saturating_sub(RED_ZONE + size) & !0xF).saturating_sub(8);
+    // SAFETY: the frame is only integers, no padding
+    unsafe { user_memory::copy_value_to_user(address, &frame) }?;
+
+    all_state.frame.rsp = address;
+    all_state.frame.rip = handler;
+    // the ABI expects it clear at the start of a function
+    all_state.frame.rflags &= !DIRECTION_FLAG;
+    all_state.rest.rdi = signal;
+    Ok(())
+}
+
+fn kill_current_process(signal: u64, all_state: &mut InterruptAllSavedState) {
+    let current_cpu = cpu::cpu();
+    eprintln!(
+        "Process {} ({}) killed by signal {signal}",
+        current_cpu.process_id, current_cpu.process_name
+    );
+    exit_current_process(exit_code(signal), all_state);
+}
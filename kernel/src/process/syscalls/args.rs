@@ -0,0 +1,100 @@
+//! Decoding the arguments of the [`SyscallArg`] kinds of the syscall table, the user memory
+//! ones are checked here, before the handler is called.
+
+use core::marker::PhantomData;
+
+use alloc::string::String;
+use kernel_user_link::syscalls::{
+    syscall_arg_to_u64, SyscallArg, SyscallArgError, UserIn, UserOut, UserOutValue, UserStr,
+};
+
+use super::user_memory;
+
+/// A kind of argument of the table, as the handlers get it
+pub trait KernelArg: SyscallArg {
+    type Value;
+
+    /// Decodes the argument in the first [`SyscallArg::REGISTERS`] of `registers`
+    fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError>;
+}
+
+macro_rules! impl_integer_arg {
+    ($($ty:ty),*) => {
+        $(
+            impl KernelArg for $ty {
+                type Value = $ty;
+
+                fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError> {
+                    syscall_arg_to_u64(registers[0])
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_arg![u64, usize, i64, i32];
+
+impl KernelArg for UserStr {
+    type Value = String;
+
+    fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError> {
+        user_memory::read_user_str(registers[0])
+    }
+}
+
+/// A range of user memory that was accessible when the syscall started
+#[derive(Debug, Clone, Copy)]
+pub struct UserBuffer {
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl KernelArg for UserIn {
+    type Value = UserBuffer;
+
+    fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError> {
+        let (addr, len) = (registers[0], registers[1]);
+        user_memory::check_user_range(addr, len, false)?;
+        Ok(UserBuffer { addr, len })
+    }
+}
+
+impl KernelArg for UserOut {
+    type Value = UserBuffer;
+
+    fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError> {
+        let (addr, len) = (registers[0], registers[1]);
+        user_memory::check_user_range(addr, len, true)?;
+        Ok(UserBuffer { addr, len })
+    }
+}
+
+/// A writable `T` in user memory
+#[derive(Debug, Clone, Copy)]
+pub struct UserValue<T> {
+    addr: u64,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Copy> UserValue<T> {
+    /// Copies `value` to the user
+    ///
+    /// # Safety
+    /// `T` must have no padding, see [`user_memory::copy_value_to_user`]
+    pub unsafe fn write(&self, value: &T) -> Result<(), SyscallArgError> {
+        user_memory::copy_value_to_user(self.addr, value)
+    }
+}
+
+impl<T> KernelArg for UserOutValue<T> {
+    type Value = UserValue<T>;
+
+    fn decode(registers: &[u64]) -> Result<Self::Value, SyscallArgError> {
+        let addr = registers[0];
+        user_memory::check_user_range(addr, core::mem::size_of::<T>() as u64, true)?;
+        Ok(UserValue {
+            addr,
+            phantom: PhantomData,
+        })
+    }
+}
@@ -1,41 +1,106 @@
-use core::{ffi::CStr, mem};
+use core::mem;
 
-use alloc::{string::String, vec::Vec};
+use alloc::{string::String, vec, vec::Vec};
 use kernel_user_link::{
-    process::SpawnFileMapping,
-    sys_arg,
+    file::{
+        DirEntryHeader, DirEntryKind, FileStat, FlockOperation, EVENT_SOURCE_LOW_MEMORY,
+        EVENT_SOURCE_NONE, MOUNT_READ_ONLY, OPEN_CREATE, OPEN_DIRECT, READ_DIR_RECURSIVE,
+    },
+    process::{
+        Resource, SpawnFileMapping, PRIORITY_LOWEST, PROT_EXEC, PROT_READ, PROT_WRITE,
+        RLIMIT_INFINITY,
+    },
+    signal::SIGKILL,
     syscalls::{
-        syscall_arg_to_u64, syscall_handler_wrapper, SyscallArgError, SyscallError, SyscallResult,
-        NUM_SYSCALLS,
+        invalid_arguments, syscall_handler_wrapper, SyscallArg, SyscallArgError, SyscallError,
+        SyscallResult, SyscallReturn, NUM_SYSCALLS, SYSCALL_ARG_REGISTERS, SYS_SIGRETURN,
     },
-    to_arg_err, verify_args, FD_STDERR,
+    sysinfo::SysInfo,
+    time::ClockId,
+    to_arg_err,
+    watch::WATCH_ALL,
+    ABI_VERSION, FD_STDERR,
 };
 
 use crate::{
-    cpu::idt::InterruptAllSavedState,
-    devices,
+    build_info,
+    cpu::{self, idt::InterruptAllSavedState},
+    devices::{self, clock},
     executable::elf::Elf,
-    fs::{self, FileSystemError},
-    memory_management::memory_layout::{is_aligned, PAGE_4K},
-    process::{scheduler, Process},
+    fs::{self, mounts::FileSystemDriver, FileSystemError},
+    memory_management::{
+        memory_layout::{is_aligned, KB, PAGE_4K},
+        physical_page_allocator,
+        shm::{self, ShmError},
+        virtual_memory_mapper,
+    },
+    process::{
+        scheduler,
+        signal::{self, SignalAction},
+        Process, ProcessError, ProcessName, ResourceLimits, INIT_PID,
+    },
 };
 
-use super::scheduler::{exit_current_process, with_current_process};
+use super::scheduler::{exit_current_process, with_current_process, ChildWait, ThreadJoin};
+
+use args::{KernelArg, UserBuffer, UserValue};
+
+mod args;
+pub(super) mod user_memory;
 
 type Syscall = fn(&mut InterruptAllSavedState) -> SyscallResult;
 
-const SYSCALLS: [Syscall; NUM_SYSCALLS] = [
-    sys_open,          // kernel_user_link::syscalls::SYS_OPEN
-    sys_write,         // kernel_user_link::syscalls::SYS_WRITE
-    sys_read,          // kernel_user_link::syscalls::SYS_READ
-    sys_close,         // kernel_user_link::syscalls::SYS_CLOSE
-    sys_blocking_mode, // kernel_user_link::syscalls::SYS_BLOCKING_MODE
-    sys_exit,          // kernel_user_link::syscalls::SYS_EXIT
-    sys_spawn,         // kernel_user_link::syscalls::SYS_SPAWN
-    sys_inc_heap,      // kernel_user_link::syscalls::SYS_INC_HEAP
-    sys_create_pipe,   // kernel_user_link::syscalls::SYS_CREATE_PIPE
-    sys_wait_pid,      // kernel_user_link::syscalls::SYS_WAIT_PID
-];
+/// Generates the handlers of [`SYSCALLS`] from the table, each decodes the arguments with
+/// [`KernelArg`] and calls the function with the name of the syscall with them, the errors of
+/// all the arguments are returned together
+macro_rules! syscall_dispatchers {
+    ($($(#[doc = $doc:literal])* $number:literal => $constant:ident: fn $name:ident($($arg:ident: $kind:ty),* $(,)?) -> $ret:ty;)*) => {
+        const SYSCALLS: [Syscall; NUM_SYSCALLS] = {
+            let mut syscalls: [Syscall; NUM_SYSCALLS] = [not_found; NUM_SYSCALLS];
+            $(
+                syscalls[$number] = {
+                    #[allow(unused_mut, unused_variables)]
+                    fn dispatch(all_state: &mut InterruptAllSavedState) -> SyscallResult {
+                        let registers = syscall_registers(all_state);
+                        let mut errors = [None; SYSCALL_ARG_REGISTERS];
+                        let mut register = 0;
+                        $(
+                            let $arg = <$kind as KernelArg>::decode(&registers[register..]);
+                            if let Err(error) = &$arg {
+                                errors[register] = Some(*error);
+                            }
+                            register += <$kind as SyscallArg>::REGISTERS;
+                        )*
+                        let _ = register;
+                        if errors.iter().any(Option::is_some) {
+                            return Err(invalid_arguments(errors));
+                        }
+                        $(let Ok($arg) = $arg else { unreachable!() };)*
+                        let result: Result<$ret, SyscallError> = self::$name(all_state, $($arg),*);
+                        result.map(SyscallReturn::to_u64)
+                    }
+                    dispatch
+                };
+            )*
+            syscalls
+        };
+    };
+}
+
+kernel_user_link::syscall_table!(syscall_dispatchers);
+
+/// Every number has a handler, the table is checked to have no gaps
+fn not_found(_all_state: &mut InterruptAllSavedState) -> SyscallResult {
+    Err(SyscallError::SyscallNotFound)
+}
+
+/// The argument registers, in the order of the table
+fn syscall_registers(all_state: &InterruptAllSavedState) -> [u64; SYSCALL_ARG_REGISTERS] {
+    let rest = &all_state.rest;
+    [
+        rest.rcx, rest.rdx, rest.rsi, rest.rdi, rest.r8, rest.r9, rest.r10,
+    ]
+}
 
 impl From<FileSystemError> for SyscallError {
     fn from(e: FileSystemError) -> Self {
@@ -45,98 +110,133 @@ impl From<FileSystemError> for SyscallError {
             FileSystemError::ReadNotSupported => SyscallError::CouldNotReadFromFile,
             FileSystemError::WriteNotSupported => SyscallError::CouldNotWriteToFile,
             FileSystemError::EndOfFile => SyscallError::EndOfFile,
-            FileSystemError::IsNotDirectory
-            | FileSystemError::IsDirectory
-            | FileSystemError::DeviceNotFound => todo!(),
-            FileSystemError::DiskReadError { .. }
-            | FileSystemError::InvalidOffset
-            | FileSystemError::FatError(_)
-            | FileSystemError::InvalidData
-            | FileSystemError::PartitionTableNotFound => panic!("should not happen?"),
+            // the file is not usable anymore, its similar to using a closed file
+            FileSystemError::StaleHandle => SyscallError::InvalidFileIndex,
+            // the size and the position of `read`/`write`, both in the 3rd argument's place
+            FileSystemError::UnalignedAccess => to_arg_err!(2, SyscallArgError::GeneralInvalid),
+            FileSystemError::DirectNotSupported => SyscallError::CouldNotOpenFile,
+            // the details are logged, see `fs_error`
+            FileSystemError::Storage(_) => SyscallError::IoError,
+            FileSystemError::IsNotDirectory => SyscallError::IsNotDirectory,
+            FileSystemError::IsDirectory => SyscallError::IsDirectory,
+            FileSystemError::AlreadyExists => SyscallError::AlreadyExists,
+            FileSystemError::DirectoryNotEmpty => SyscallError::DirectoryNotEmpty,
+            FileSystemError::CrossDevice => SyscallError::CrossDevice,
+            FileSystemError::ReadOnlyFileSystem => SyscallError::ReadOnlyFileSystem,
+            FileSystemError::Busy => SyscallError::Busy,
+            FileSystemError::PermissionDenied => SyscallError::PermissionDenied,
+            FileSystemError::NoSpace => SyscallError::NoSpace,
+            FileSystemError::CopyNotSupported => SyscallError::NotSupported,
+            FileSystemError::DeviceGone => SyscallError::DeviceGone,
+            FileSystemError::WouldBlock => SyscallError::WouldBlock,
+            FileSystemError::LockNotSupported => SyscallError::NotSupported,
+            FileSystemError::DeviceNotFound => SyscallError::FileNotFound,
+            FileSystemError::InvalidOffset => SyscallError::EndOfFile,
+            // the data written by the user, such as a wrong name to a device
+            FileSystemError::InvalidData => to_arg_err!(1, SyscallArgError::GeneralInvalid),
+            FileSystemError::FatError(_) | FileSystemError::PartitionTableNotFound => {
+                SyscallError::CouldNotReadFromFile
+            }
         }
     }
 }
 
-#[inline]
-fn check_ptr(arg: *const u8, len: u64) -> Result<(), SyscallArgError> {
-    if arg.is_null() {
-        return Err(SyscallArgError::InvalidUserPointer);
-    }
-    if !with_current_process(|process| {
-        process.is_user_address_mapped(arg as _)
-        // very basic check, just check the last byte
-        // TODO: check all mapped pages
-            && process.is_user_address_mapped((arg as usize + len as usize - 1) as _)
-    }) {
-        return Err(SyscallArgError::InvalidUserPointer);
+/// `error` for userspace, which only gets `IoError` for the failures of the devices, so
+/// those are logged with what the syscall was doing, see [`FileSystemError::storage_report`]
+fn fs_error(op: &str, target: &str, error: FileSystemError) -> SyscallError {
+    if let Some(report) = error.storage_report(op, target) {
+        println!("[io] {report}");
     }
-    Ok(())
-}
-
-// expects null terminated string
-fn sys_arg_to_str<'a>(arg: *const u8) -> Result<&'a str, SyscallArgError> {
-    check_ptr(arg, 1)?;
-
-    let slice = unsafe { CStr::from_ptr(arg as _) };
-    let string = CStr::to_str(slice).map_err(|_| SyscallArgError::NotValidUtf8)?;
-    Ok(string)
+    error.into()
 }
 
-fn sys_arg_to_byte_slice<'a>(buf: *const u8, size: u64) -> Result<&'a [u8], SyscallArgError> {
-    check_ptr(buf, size)?;
-
-    let slice = unsafe { core::slice::from_raw_parts(buf as _, size as _) };
-    Ok(slice)
+impl From<ProcessError> for SyscallError {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::CouldNotLoadElf(_) => SyscallError::CouldNotLoadElf,
+            ProcessError::HeapRangesExceeded => SyscallError::HeapRangesExceeded,
+            ProcessError::MemoryLimitExceeded => SyscallError::NoMemory,
+            ProcessError::OpenFilesLimitExceeded => SyscallError::TooManyOpenFiles,
+            ProcessError::ArgumentsTooLarge | ProcessError::TooManyThreads => {
+                SyscallError::CouldNotAllocateProcess
+            }
+            ProcessError::RangeNotMapped => to_arg_err!(0, SyscallArgError::InvalidUserPointer),
+            ProcessError::RangeNotOwned => SyscallError::PermissionDenied,
+            ProcessError::NoAddressSpace => SyscallError::NoMemory,
+        }
+    }
 }
 
-fn sys_arg_to_mut_byte_slice<'a>(buf: *mut u8, size: u64) -> Result<&'a mut [u8], SyscallArgError> {
-    check_ptr(buf, size)?;
-
-    let slice = unsafe { core::slice::from_raw_parts_mut(buf as _, size as _) };
-    Ok(slice)
+impl From<ShmError> for SyscallError {
+    fn from(e: ShmError) -> Self {
+        match e {
+            ShmError::InvalidName => to_arg_err!(0, SyscallArgError::GeneralInvalid),
+            ShmError::InvalidSize => to_arg_err!(1, SyscallArgError::GeneralInvalid),
+            ShmError::InvalidFlags => to_arg_err!(2, SyscallArgError::GeneralInvalid),
+            ShmError::NotFound => SyscallError::FileNotFound,
+            ShmError::AlreadyExists => SyscallError::AlreadyExists,
+            ShmError::NoMemory => SyscallError::NoMemory,
+        }
+    }
 }
 
-/// Allocates space for the strings and copies them
-fn sys_arg_to_str_array(array_ptr: *const u8) -> Result<Vec<String>, SyscallArgError> {
-    check_ptr(array_ptr, 8)?;
-    let array_ptr = array_ptr as *const *const u8;
+/// The most bytes moved by one `read` or `write`, larger ones are split for `write` and
+/// return less for `read`, since the data goes through the kernel heap
+const MAX_IO_CHUNK: usize = 64 * KB;
+/// The most arguments for `spawn`
+const MAX_SPAWN_ARGS: usize = 1024;
 
+/// Copies the strings of a null terminated array of pointers
+fn sys_arg_to_str_array(array_ptr: u64) -> Result<Vec<String>, SyscallArgError> {
     let mut array = Vec::new();
-    let mut i = 0;
+    let mut ptr_addr = array_ptr;
     loop {
-        let ptr = unsafe { *array_ptr.offset(i) };
-        if ptr.is_null() {
+        if array.len() == MAX_SPAWN_ARGS {
+            return Err(SyscallArgError::GeneralInvalid);
+        }
+        let ptr = user_memory::read_user_u64(ptr_addr)?;
+        if ptr == 0 {
             break;
         }
-        let str = sys_arg_to_str(ptr)?;
+        let str = user_memory::read_user_str(ptr)?;
         if str.is_empty() {
             break;
         }
-        array.push(String::from(str));
-        i += 1;
+        array.push(str);
+        ptr_addr = ptr_addr
+            .checked_add(8)
+            .ok_or(SyscallArgError::InvalidUserPointer)?;
     }
 
     Ok(array)
 }
 
-/// Allocates space fro the mapping and copies them
+/// Copies the mappings, the destinations must be under the open files limit of `limits`
 fn sys_arg_to_file_mappings_array(
-    array_ptr: *const u8,
+    array_ptr: u64,
     array_size: usize,
+    limits: &ResourceLimits,
 ) -> Result<Vec<SpawnFileMapping>, SyscallArgError> {
     if array_size == 0 {
         return Ok(Vec::new());
     }
-    check_ptr(
-        array_ptr,
-        (array_size * mem::size_of::<SpawnFileMapping>()) as u64,
-    )?;
-
-    let array_ptr = array_ptr as *const SpawnFileMapping;
+    // can't have more than the open files anyway, and this bounds the copy
+    if array_size as u64 > limits.open_files {
+        return Err(SyscallArgError::GeneralInvalid);
+    }
+    let mapping_size = mem::size_of::<SpawnFileMapping>();
+    let bytes = user_memory::copy_vec_from_user(array_ptr, array_size * mapping_size)?;
 
     let mut array: Vec<SpawnFileMapping> = Vec::new();
-    for i in 0..array_size {
-        let mapping = unsafe { array_ptr.add(i).read() };
+    for mapping_bytes in bytes.chunks_exact(mapping_size) {
+        let field = |i: usize| u64::from_le_bytes(mapping_bytes[i..i + 8].try_into().unwrap());
+        let mapping = SpawnFileMapping {
+            src_fd: field(0) as usize,
+            dst_fd: field(8) as usize,
+        };
+        if mapping.dst_fd as u64 >= limits.open_files {
+            return Err(SyscallArgError::GeneralInvalid);
+        }
 
         // before doing push check that we don't have duplicates
         if array
@@ -152,45 +252,79 @@ fn sys_arg_to_file_mappings_array(
     Ok(array)
 }
 
-fn sys_open(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (path, _access_mode, flags, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => sys_arg_to_str(*const u8)),
-        sys_arg!(1, all_state.rest => u64),
-        sys_arg!(2, all_state.rest => u64),
-    };
+fn open(
+    _all_state: &mut InterruptAllSavedState,
+    path: String,
+    _access_mode: u64,
+    flags: u64,
+) -> Result<usize, SyscallError> {
     let blocking_mode = kernel_user_link::file::parse_flags(flags)
         .ok_or(to_arg_err!(2, SyscallArgError::GeneralInvalid))?;
-    // TODO: implement flags and access_mode, for now just open file for reading
-    let file = fs::open_blocking(path, blocking_mode)?;
-    let file_index = with_current_process(|process| process.push_file(file));
+    if flags & OPEN_CREATE != 0 {
+        match fs::create_file(&path) {
+            Ok(()) | Err(FileSystemError::AlreadyExists) => {}
+            Err(e) => return Err(fs_error("create", &path, e)),
+        }
+    }
+    // TODO: implement access_mode, for now files can be read and written
+    let mut file =
+        fs::open_blocking(&path, blocking_mode).map_err(|e| fs_error("open", &path, e))?;
+    if flags & OPEN_DIRECT != 0 {
+        // direct access bypasses the filesystems on the device, only `init` can do that
+        if with_current_process(|process| process.id) != INIT_PID {
+            return Err(SyscallError::PermissionDenied);
+        }
+        file.set_direct()?;
+    }
+    let file_index = with_current_process(|process| process.push_file(file))?;
 
-    SyscallResult::Ok(file_index as u64)
+    Ok(file_index)
 }
 
-fn sys_write(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (file_index, buf, size, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => usize),
-        sys_arg!(1, all_state.rest => *const u8),
-        sys_arg!(2, all_state.rest => u64),
-    };
-    let buf = sys_arg_to_byte_slice(buf, size).map_err(|err| to_arg_err!(0, err))?;
-    let bytes_written = with_current_process(|process| -> Result<u64, SyscallError> {
-        let file = process
-            .get_file(file_index)
-            .ok_or(SyscallError::InvalidFileIndex)?;
+fn write(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    buf: UserBuffer,
+) -> Result<u64, SyscallError> {
+    let size = buf.len;
+    let mut bytes_written = 0;
+    loop {
+        let len = (size - bytes_written).min(MAX_IO_CHUNK as u64) as usize;
+        let result = user_memory::copy_vec_from_user(buf.addr + bytes_written, len)
+            .map_err(|err| to_arg_err!(1, err))
+            .and_then(|chunk| {
+                with_current_process(|process| -> Result<u64, SyscallError> {
+                    let file = process
+                        .get_file(file_index)
+                        .ok_or(SyscallError::InvalidFileIndex)?;
 
-        file.write(buf).map_err(|e| e.into())
-    })?;
-    SyscallResult::Ok(bytes_written)
+                    file.write(&chunk)
+                        .map_err(|e| fs_error("write", file.path(), e))
+                })
+            });
+        match result {
+            Ok(written) => {
+                bytes_written += written;
+                if written < len as u64 || bytes_written == size {
+                    break;
+                }
+            }
+            // report what was written before the error
+            Err(_) if bytes_written != 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(bytes_written)
 }
 
-fn sys_read(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (file_index, buf, size, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => usize),
-        sys_arg!(1, all_state.rest => *mut u8),
-        sys_arg!(2, all_state.rest => u64),
-    };
-    let buf = sys_arg_to_mut_byte_slice(buf, size).map_err(|err| to_arg_err!(0, err))?;
+fn read(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    buf: UserBuffer,
+) -> Result<u64, SyscallError> {
+    // the whole buffer is checked before reading, otherwise the data would be lost
+    let size = buf.len.min(MAX_IO_CHUNK as u64);
+    let mut data = vec![0; size as usize];
 
     // TODO: fix this hack
     //
@@ -216,43 +350,42 @@ fn sys_read(all_state: &mut InterruptAllSavedState) -> SyscallResult {
                 .ok_or(SyscallError::InvalidFileIndex)?;
             Ok((0, Some(file)))
         } else {
-            let bytes_read = file.read(buf)?;
+            let bytes_read = file
+                .read(&mut data)
+                .map_err(|e| fs_error("read", file.path(), e))?;
             Ok::<_, SyscallError>((bytes_read, None))
         }
     })?;
 
     let bytes_read = if let Some(mut file) = file {
-        let bytes_read = file.read(buf)?;
+        let result = file
+            .read(&mut data)
+            .map_err(|e| fs_error("read", file.path(), e));
         // put file back
         with_current_process(|process| process.put_file(file_index, file));
-        bytes_read
+        result?
     } else {
         bytes_read
     };
-    SyscallResult::Ok(bytes_read as u64)
+    user_memory::copy_to_user(buf.addr, &data[..bytes_read as usize])
+        .map_err(|err| to_arg_err!(1, err))?;
+    Ok(bytes_read as u64)
 }
 
-fn sys_close(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (file_index, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => usize),
-    };
-
+fn close(_all_state: &mut InterruptAllSavedState, file_index: usize) -> Result<(), SyscallError> {
     with_current_process(|process| {
         process
             .take_file(file_index)
             .ok_or(SyscallError::InvalidFileIndex)?;
-        Ok::<_, SyscallError>(())
-    })?;
-
-    SyscallResult::Ok(0)
+        Ok(())
+    })
 }
 
-fn sys_blocking_mode(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (file_index, blocking_mode, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => usize),
-        sys_arg!(1, all_state.rest => u64),
-    };
-
+fn blocking_mode(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    blocking_mode: u64,
+) -> Result<(), SyscallError> {
     let blocking_mode = kernel_user_link::file::parse_blocking_mode(blocking_mode)
         .ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
 
@@ -261,51 +394,73 @@ fn sys_blocking_mode(all_state: &mut InterruptAllSavedState) -> SyscallResult {
             .get_file(file_index)
             .ok_or(SyscallError::InvalidFileIndex)?;
         file.set_blocking(blocking_mode);
-        Ok::<_, SyscallError>(())
-    })?;
-
-    SyscallResult::Ok(0)
+        Ok(())
+    })
 }
 
-fn sys_exit(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (exit_code, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => i32),
-    };
-
+fn exit(all_state: &mut InterruptAllSavedState, exit_code: i32) -> Result<(), SyscallError> {
     // modify the all_state to go back to the kernel, the current all_state will be dropped
     exit_current_process(exit_code, all_state);
-    SyscallResult::Ok(exit_code as u64)
+    Ok(())
 }
 
-fn sys_spawn(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (path, argv, file_mappings, file_mappings_size, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => sys_arg_to_str(*const u8)),
-        sys_arg!(1, all_state.rest => *const u8),   // array of pointers
-        sys_arg!(2, all_state.rest => *const u8),   // array of mappings or null
-        sys_arg!(3, all_state.rest => usize),       // size of the array
-    };
+fn spawn(
+    _all_state: &mut InterruptAllSavedState,
+    path: String,
+    argv: u64,
+    file_mappings: u64,
+    file_mappings_len: usize,
+) -> Result<u64, SyscallError> {
     let argv = sys_arg_to_str_array(argv).map_err(|err| to_arg_err!(1, err))?;
-    let file_mappings = sys_arg_to_file_mappings_array(file_mappings, file_mappings_size)
+    let (current_pid, limits, privileged, priority, env) = with_current_process(|process| {
+        (
+            process.id,
+            *process.limits(),
+            process.is_privileged(),
+            process.priority(),
+            process.env().to_vec(),
+        )
+    });
+    let file_mappings = sys_arg_to_file_mappings_array(file_mappings, file_mappings_len, &limits)
         .map_err(|err| to_arg_err!(2, err))?;
 
-    // don't go into lock if no need to
-    if !file_mappings.is_empty() {
-        // a bit unoptimal, but check all files first before taking them and doing any action
-        with_current_process(|process| {
-            for mapping in &file_mappings {
-                process
-                    .get_file(mapping.src_fd as _)
-                    .ok_or(SyscallError::InvalidFileIndex)?;
+    // a bit unoptimal, but check all files first before taking them and doing any action
+    with_current_process(|process| {
+        for mapping in &file_mappings {
+            process
+                .get_file(mapping.src_fd as _)
+                .ok_or(SyscallError::InvalidFileIndex)?;
+        }
+        // the std files that are not mapped are inherited
+        for fd in 0..=FD_STDERR {
+            if !file_mappings.iter().any(|m| m.dst_fd == fd) {
+                process.get_file(fd).ok_or(SyscallError::InvalidFileIndex)?;
             }
-            Ok::<_, SyscallError>(())
-        })?;
-    }
+        }
+        Ok::<_, SyscallError>(())
+    })?;
 
-    let mut file = fs::open(path).map_err(|_| SyscallError::CouldNotOpenFile)?;
-    let elf = Elf::load(&mut file).map_err(|_| SyscallError::CouldNotLoadElf)?;
-    let current_pid = with_current_process(|process| process.id);
-    let mut new_process = Process::allocate_process(current_pid, &elf, &mut file, argv)
-        .map_err(|_| SyscallError::CouldNotAllocateProcess)?;
+    let mut file = fs::open(&path).map_err(|_| SyscallError::CouldNotOpenFile)?;
+    let elf = Elf::load(&mut file).map_err(|e| {
+        println!("[exec] {path}: {e}");
+        SyscallError::CouldNotLoadElf
+    })?;
+    // the environment is inherited, there is no way to pass another one yet
+    let mut new_process =
+        Process::allocate_process(current_pid, &elf, &mut file, argv, env, limits).map_err(
+            |e| match e {
+                ProcessError::MemoryLimitExceeded => SyscallError::NoMemory,
+                ProcessError::CouldNotLoadElf(e) => {
+                    println!("[exec] {path}: {e}");
+                    SyscallError::CouldNotLoadElf
+                }
+                _ => SyscallError::CouldNotAllocateProcess,
+            },
+        )?;
+    if privileged {
+        new_process.set_privileged();
+    }
+    new_process.set_priority(priority);
 
     let mut std_needed = [true; 3];
     with_current_process(|process| {
@@ -337,73 +492,789 @@ fn sys_spawn(all_state: &mut InterruptAllSavedState) -> SyscallResult {
     new_process.finish_stdio();
     scheduler::push_process(new_process);
 
-    SyscallResult::Ok(new_pid)
+    Ok(new_pid)
 }
 
-fn sys_inc_heap(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (increment, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => i64),
-    };
+fn fork(all_state: &mut InterruptAllSavedState) -> Result<u64, SyscallError> {
+    let child = with_current_process(|process| process.fork(all_state)).map_err(|e| match e {
+        ProcessError::MemoryLimitExceeded => SyscallError::NoMemory,
+        _ => SyscallError::CouldNotAllocateProcess,
+    })?;
+    let pid = child.id();
+    scheduler::push_process(child);
+    Ok(pid)
+}
+
+fn exec(
+    all_state: &mut InterruptAllSavedState,
+    path: String,
+    argv: u64,
+) -> Result<(), SyscallError> {
+    let argv = sys_arg_to_str_array(argv).map_err(|err| to_arg_err!(1, err))?;
+    let mut file = fs::open(&path).map_err(|_| SyscallError::CouldNotOpenFile)?;
+    let elf = Elf::load(&mut file).map_err(|e| {
+        println!("[exec] {path}: {e}");
+        SyscallError::CouldNotLoadElf
+    })?;
+    let (current_pid, limits, env) =
+        with_current_process(|process| (process.id, *process.limits(), process.env().to_vec()));
+    // loaded next to the old program, which stays if it fails
+    let image = Process::exec_image(current_pid, &elf, &mut file, argv, env, limits).map_err(
+        |e| match e {
+            ProcessError::MemoryLimitExceeded => SyscallError::NoMemory,
+            ProcessError::CouldNotLoadElf(e) => {
+                println!("[exec] {path}: {e}");
+                SyscallError::CouldNotLoadElf
+            }
+            _ => SyscallError::CouldNotAllocateProcess,
+        },
+    )?;
+    // like `exit`, the `all_state` goes back to the kernel, and the old program is dropped
+    scheduler::exec_current_process(image, all_state);
+    Ok(())
+}
+
+fn thread_create(
+    _all_state: &mut InterruptAllSavedState,
+    entry: u64,
+    stack_end: u64,
+    arg: u64,
+    tls: u64,
+) -> Result<u64, SyscallError> {
+    user_memory::check_user_range(entry, 1, false).map_err(|err| to_arg_err!(0, err))?;
+    // where the first `push` goes
+    let stack_top = stack_end
+        .checked_sub(8)
+        .ok_or(to_arg_err!(1, SyscallArgError::InvalidUserPointer))?;
+    user_memory::check_user_range(stack_top, 8, true).map_err(|err| to_arg_err!(1, err))?;
+    // loading a non canonical FS base would fault in the kernel
+    if tls != 0 {
+        user_memory::check_user_range(tls, 8, false).map_err(|err| to_arg_err!(3, err))?;
+    }
+
+    let id = with_current_process(|process| process.create_thread(entry, stack_end, arg, tls))?;
+    Ok(id)
+}
+
+fn thread_exit(all_state: &mut InterruptAllSavedState) -> Result<(), SyscallError> {
+    if !scheduler::exit_current_thread(all_state) {
+        exit_current_process(0, all_state);
+    }
+    Ok(())
+}
 
+fn thread_join(all_state: &mut InterruptAllSavedState, id: u64) -> Result<(), SyscallError> {
+    if id == with_current_process(|process| process.thread_id()) {
+        return Err(to_arg_err!(0, SyscallArgError::GeneralInvalid));
+    }
+    match scheduler::join_thread(all_state, id) {
+        // when waiting, `0` is put in `rax` once it exits
+        ThreadJoin::Joined | ThreadJoin::Waiting => Ok(()),
+        ThreadJoin::NotThread => Err(SyscallError::PidNotFound),
+    }
+}
+
+fn kill(_all_state: &mut InterruptAllSavedState, pid: u64, sig: u64) -> Result<(), SyscallError> {
+    if !signal::is_valid(sig) {
+        return Err(to_arg_err!(1, SyscallArgError::GeneralInvalid));
+    }
+    // nothing would be left to run
+    if pid == INIT_PID {
+        return Err(SyscallError::PermissionDenied);
+    }
+
+    let (current_pid, privileged) =
+        with_current_process(|process| (process.id, process.is_privileged()));
+    scheduler::with_process(pid, |process| {
+        // like the priorities, only itself or its children
+        if process.id != current_pid && process.parent_id != current_pid && !privileged {
+            return Err(SyscallError::PermissionDenied);
+        }
+        // the kernel needs them running
+        if process.is_kernel_thread() {
+            return Err(SyscallError::PermissionDenied);
+        }
+        signal::send(process, sig);
+        Ok(())
+    })
+    .ok_or(SyscallError::PidNotFound)?
+}
+
+fn sigaction(
+    _all_state: &mut InterruptAllSavedState,
+    sig: u64,
+    handler: u64,
+    restorer: u64,
+) -> Result<u64, SyscallError> {
+    if !signal::is_valid(sig) || sig == SIGKILL {
+        return Err(to_arg_err!(0, SyscallArgError::GeneralInvalid));
+    }
+    let action = SignalAction::new(handler, restorer);
+    if let SignalAction::Handler { handler, restorer } = action {
+        user_memory::check_user_range(handler, 1, false).map_err(|err| to_arg_err!(1, err))?;
+        user_memory::check_user_range(restorer, 1, false).map_err(|err| to_arg_err!(2, err))?;
+    }
+
+    let old = with_current_process(|process| process.signals.set_action(sig, action));
+    Ok(old.handler())
+}
+
+fn sigreturn(all_state: &mut InterruptAllSavedState) -> Result<(), SyscallError> {
+    // all the registers are the ones of before the handler now, see `handle_syscall`
+    signal::return_from_handler(all_state);
+    Ok(())
+}
+
+fn inc_heap(_all_state: &mut InterruptAllSavedState, increment: i64) -> Result<u64, SyscallError> {
     if !is_aligned(increment.unsigned_abs() as usize, PAGE_4K) {
         return Err(to_arg_err!(0, SyscallArgError::InvalidHeapIncrement));
     }
 
-    let old_heap_end = with_current_process(|process| process.add_to_heap(increment as isize))
-        .ok_or(SyscallError::HeapRangesExceeded)?;
+    let old_heap_end = with_current_process(|process| process.add_to_heap(increment as isize))?;
 
-    SyscallResult::Ok(old_heap_end as u64)
+    Ok(old_heap_end as u64)
 }
 
-fn sys_create_pipe(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (read_fd_ptr, write_fd_ptr, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => *mut usize),
-        sys_arg!(1, all_state.rest => *mut usize),
+fn mprotect(
+    _all_state: &mut InterruptAllSavedState,
+    addr: u64,
+    len: u64,
+    prot: u64,
+) -> Result<(), SyscallError> {
+    if !is_aligned(addr as usize, PAGE_4K) {
+        return Err(to_arg_err!(0, SyscallArgError::InvalidUserPointer));
+    }
+    let flags = prot_to_flags(prot).ok_or(to_arg_err!(2, SyscallArgError::GeneralInvalid))?;
+    if len == 0 {
+        return Ok(());
+    }
+    let Some(len) = len.checked_next_multiple_of(PAGE_4K as u64) else {
+        return Err(to_arg_err!(1, SyscallArgError::GeneralInvalid));
     };
-    check_ptr(read_fd_ptr as *const u8, 8).map_err(|err| to_arg_err!(0, err))?;
-    check_ptr(write_fd_ptr as *const u8, 8).map_err(|err| to_arg_err!(1, err))?;
 
+    with_current_process(|process| process.protect_memory(addr, len, flags))?;
+    Ok(())
+}
+
+/// The page flags of the `PROT_*` in `prot`, `None` if it has unknown bits or no `PROT_READ`
+fn prot_to_flags(prot: u64) -> Option<u64> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot & PROT_READ == 0 {
+        return None;
+    }
+    let mut flags = virtual_memory_mapper::flags::PTE_USER;
+    if prot & PROT_WRITE != 0 {
+        flags |= virtual_memory_mapper::flags::PTE_WRITABLE;
+    }
+    Some(flags)
+}
+
+fn shm_create(
+    _all_state: &mut InterruptAllSavedState,
+    name: String,
+    size: u64,
+    flags: u64,
+) -> Result<usize, SyscallError> {
+    let name = (!name.is_empty()).then_some(name.as_str());
+    let file = shm::create_file(shm::open_or_create(name, size, flags)?);
+    let fd = with_current_process(|process| process.push_file(file))?;
+    Ok(fd)
+}
+
+fn shm_map(
+    _all_state: &mut InterruptAllSavedState,
+    fd: usize,
+    prot: u64,
+) -> Result<u64, SyscallError> {
+    let flags = prot_to_flags(prot).ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+    with_current_process(|process| {
+        let shm = process
+            .get_file(fd)
+            .ok_or(SyscallError::InvalidFileIndex)?
+            .shared_memory()
+            .cloned()
+            .ok_or(to_arg_err!(0, SyscallArgError::GeneralInvalid))?;
+        Ok(process.map_shared_memory(&shm, flags)?)
+    })
+}
+
+fn shm_unmap(_all_state: &mut InterruptAllSavedState, addr: u64) -> Result<(), SyscallError> {
+    with_current_process(|process| process.unmap_shared_memory(addr))?;
+    Ok(())
+}
+
+fn mmap(
+    _all_state: &mut InterruptAllSavedState,
+    fd: usize,
+    offset: u64,
+    len: u64,
+    prot: u64,
+) -> Result<u64, SyscallError> {
+    if !is_aligned(offset as usize, PAGE_4K) {
+        return Err(to_arg_err!(1, SyscallArgError::GeneralInvalid));
+    }
+    if len == 0 || offset.checked_add(len).is_none() {
+        return Err(to_arg_err!(2, SyscallArgError::GeneralInvalid));
+    }
+    let flags = prot_to_flags(prot).ok_or(to_arg_err!(3, SyscallArgError::GeneralInvalid))?;
+    // the writable mappings are private, their pages are copied when written to
+    // TODO: shared writable mappings, writing the pages back to the file
+    with_current_process(|process| {
+        let file = process.get_file(fd).ok_or(SyscallError::InvalidFileIndex)?;
+        if !file.is_mappable() {
+            return Err(to_arg_err!(0, SyscallArgError::GeneralInvalid));
+        }
+        let file = file.clone_inherit();
+        Ok(process.map_file(file, offset, len, flags)?)
+    })
+}
+
+fn munmap(_all_state: &mut InterruptAllSavedState, addr: u64) -> Result<(), SyscallError> {
+    with_current_process(|process| process.unmap_file(addr))?;
+    Ok(())
+}
+
+fn create_pipe(
+    _all_state: &mut InterruptAllSavedState,
+    read_fd: UserValue<u64>,
+    write_fd: UserValue<u64>,
+) -> Result<(), SyscallError> {
     let (read_file, write_file) = devices::pipe::create_pipe_pair();
-    let (read_fd, write_fd) = with_current_process(|process| {
-        (process.push_file(read_file), process.push_file(write_file))
-    });
+    let (read_fd_value, write_fd_value) = with_current_process(|process| {
+        let read_fd = process.push_file(read_file)?;
+        match process.push_file(write_file) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                // don't keep half of the pipe
+                process.take_file(read_fd);
+                Err(e)
+            }
+        }
+    })?;
 
+    // checked when decoding, so this only fails if another thread of the process unmapped it
+    // meanwhile, and the copy returns the error
+    // SAFETY: `u64` has no padding
     unsafe {
-        *read_fd_ptr = read_fd;
-        *write_fd_ptr = write_fd;
+        read_fd
+            .write(&(read_fd_value as u64))
+            .map_err(|err| to_arg_err!(0, err))?;
+        write_fd
+            .write(&(write_fd_value as u64))
+            .map_err(|err| to_arg_err!(1, err))?;
     }
+    Ok(())
+}
+
+fn wait_pid(
+    all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    block: u64,
+) -> Result<u64, SyscallError> {
+    if block > 1 {
+        return Err(to_arg_err!(1, SyscallArgError::GeneralInvalid));
+    }
+    let block = block != 0;
 
-    SyscallResult::Ok(0)
+    match scheduler::wait_for_child(all_state, pid, block) {
+        ChildWait::Exited(exit_code) => Ok(exit_code as u64),
+        ChildWait::Running => Err(SyscallError::ProcessStillRunning),
+        // if we are waiting by the scheduler, this result is not important since it will be
+        // overwritten when we get back
+        ChildWait::Waiting => Ok(0),
+        ChildWait::NotChild => Err(SyscallError::PidNotFound),
+    }
 }
 
-fn sys_wait_pid(all_state: &mut InterruptAllSavedState) -> SyscallResult {
-    let (pid, block, ..) = verify_args! {
-        sys_arg!(0, all_state.rest => u64),
-        sys_arg!(1, all_state.rest => u64),
+fn sysinfo(_all_state: &mut InterruptAllSavedState, info_ptr: u64) -> Result<(), SyscallError> {
+    // only the part the program knows of is written, `0` is before `size` was added
+    let mut header = [0; 8];
+    user_memory::copy_from_user(&mut header, info_ptr).map_err(|err| to_arg_err!(0, err))?;
+    let size_field = mem::offset_of!(SysInfo, size);
+    let size = u32::from_ne_bytes(header[size_field..size_field + 4].try_into().unwrap());
+    let size = match size as usize {
+        0 => mem::offset_of!(SysInfo, version),
+        size => size.min(mem::size_of::<SysInfo>()),
     };
-    let block = block != 0;
 
-    // see if this is a child process
-    let process_exit = with_current_process(|process| process.get_child_exit(pid));
-    if let Some(exit_code) = process_exit {
-        return SyscallResult::Ok(exit_code as u64);
+    let info = SysInfo {
+        abi_version: ABI_VERSION,
+        size: size as u32,
+        version: build_info::sysinfo_version(),
+    };
+    // SAFETY: `SysInfo` has no padding, and is only read as bytes
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &info as *const SysInfo as *const u8,
+            mem::size_of::<SysInfo>(),
+        )
+    };
+    user_memory::copy_to_user(info_ptr, &bytes[..size]).map_err(|err| to_arg_err!(0, err))
+}
+
+fn read_dir(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    buf: UserBuffer,
+    flags: u64,
+    position: u64,
+) -> Result<u64, SyscallError> {
+    let size = buf.len.min(MAX_IO_CHUNK as u64);
+    let mut data = vec![0; size as usize];
+    if flags & !READ_DIR_RECURSIVE != 0 {
+        return Err(to_arg_err!(3, SyscallArgError::GeneralInvalid));
+    }
+    let recursive = flags & READ_DIR_RECURSIVE != 0;
+
+    let written = with_current_process(|process| -> Result<usize, SyscallError> {
+        let file = process
+            .get_file(file_index)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        let walker = file.dir_walker(recursive, position)?;
+
+        let mut written = 0;
+        while let Some(entry) = walker.next().transpose()? {
+            let kind = if entry.inode.is_dir() {
+                DirEntryKind::Directory
+            } else if entry.inode.device().is_some() {
+                DirEntryKind::Device
+            } else {
+                DirEntryKind::File
+            };
+            let Some(len) = DirEntryHeader::write_entry(
+                &mut data[written..],
+                kind,
+                entry.inode.size() as u64,
+                &entry.path,
+            ) else {
+                // continue from this entry next time
+                walker.push_back(entry);
+                if written == 0 {
+                    // the buffer can't even hold one entry
+                    return Err(to_arg_err!(2, SyscallArgError::GeneralInvalid));
+                }
+                break;
+            };
+            written += len;
+        }
+        Ok(written)
+    })?;
+    user_memory::copy_to_user(buf.addr, &data[..written]).map_err(|err| to_arg_err!(1, err))?;
+
+    Ok(written as u64)
+}
+
+fn set_rlimit(
+    _all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    resource: u64,
+    value: u64,
+) -> Result<(), SyscallError> {
+    let resource =
+        Resource::from_u64(resource).ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+    if value > RLIMIT_INFINITY {
+        return Err(to_arg_err!(2, SyscallArgError::GeneralInvalid));
+    }
+
+    let current_pid = with_current_process(|process| process.id);
+    scheduler::with_process(pid, |process| {
+        // a process can only limit itself or its children
+        if process.id != current_pid && process.parent_id != current_pid {
+            return Err(SyscallError::PermissionDenied);
+        }
+        // anyone can lower a limit, but only `init` can raise it, the core size only decides
+        // if the memory of the crashed process is written, so it goes up for everyone
+        let raising = value > process.limits().get(resource);
+        if raising && current_pid != INIT_PID && resource != Resource::CoreSize {
+            return Err(SyscallError::PermissionDenied);
+        }
+        process.limits_mut().set(resource, value);
+        Ok(())
+    })
+    .ok_or(SyscallError::PidNotFound)?
+}
+
+fn get_rlimit(
+    _all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    resource: u64,
+) -> Result<u64, SyscallError> {
+    let resource =
+        Resource::from_u64(resource).ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+
+    scheduler::with_process(pid, |process| process.limits().get(resource))
+        .ok_or(SyscallError::PidNotFound)
+}
+
+fn set_priority(
+    _all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    priority: u64,
+) -> Result<(), SyscallError> {
+    if priority > PRIORITY_LOWEST {
+        return Err(to_arg_err!(1, SyscallArgError::GeneralInvalid));
+    }
+
+    let (current_pid, privileged) =
+        with_current_process(|process| (process.id, process.is_privileged()));
+    scheduler::with_process(pid, |process| {
+        // a process can only change itself or its children
+        if process.id != current_pid && process.parent_id != current_pid {
+            return Err(SyscallError::PermissionDenied);
+        }
+        // anyone can lower it, raising it takes the CPU from the others
+        if priority < process.priority() && !privileged {
+            return Err(SyscallError::PermissionDenied);
+        }
+        process.set_priority(priority);
+        Ok(())
+    })
+    .ok_or(SyscallError::PidNotFound)?
+}
+
+fn get_priority(_all_state: &mut InterruptAllSavedState, pid: u64) -> Result<u64, SyscallError> {
+    scheduler::with_process(pid, |process| process.priority()).ok_or(SyscallError::PidNotFound)
+}
+
+fn stat(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    stat_out: UserValue<FileStat>,
+) -> Result<(), SyscallError> {
+    let stat = with_current_process(|process| {
+        let file = process
+            .get_file(file_index)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        file.stat().map_err(SyscallError::from)
+    })?;
+
+    // SAFETY: `FileStat` has no padding
+    unsafe { stat_out.write(&stat) }.map_err(|err| to_arg_err!(1, err))?;
+
+    Ok(())
+}
+
+fn unlink(_all_state: &mut InterruptAllSavedState, path: String) -> Result<(), SyscallError> {
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn mkdir(_all_state: &mut InterruptAllSavedState, path: String) -> Result<(), SyscallError> {
+    fs::create_dir(&path)?;
+    Ok(())
+}
+
+fn rmdir(_all_state: &mut InterruptAllSavedState, path: String) -> Result<(), SyscallError> {
+    fs::remove_dir(&path)?;
+    Ok(())
+}
+
+fn rename(
+    _all_state: &mut InterruptAllSavedState,
+    old_path: String,
+    new_path: String,
+) -> Result<(), SyscallError> {
+    fs::rename(&old_path, &new_path)?;
+    Ok(())
+}
+
+fn sync(_all_state: &mut InterruptAllSavedState) -> Result<(), SyscallError> {
+    fs::sync()?;
+    Ok(())
+}
+
+fn fsync(_all_state: &mut InterruptAllSavedState, file_index: usize) -> Result<(), SyscallError> {
+    with_current_process(|process| -> Result<(), SyscallError> {
+        let file = process
+            .get_file(file_index)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        file.sync().map_err(|e| e.into())
+    })?;
+    Ok(())
+}
+
+fn event_create(
+    _all_state: &mut InterruptAllSavedState,
+    source: u64,
+) -> Result<usize, SyscallError> {
+    if ![EVENT_SOURCE_NONE, EVENT_SOURCE_LOW_MEMORY].contains(&source) {
+        return Err(to_arg_err!(0, SyscallArgError::GeneralInvalid));
+    }
+
+    let (file, kernel_event) = devices::event::create_event();
+    let fd = with_current_process(|process| process.push_file(file))?;
+    // only after we have the fd, so a failure doesn't leave an event nobody can read
+    if source == EVENT_SOURCE_LOW_MEMORY {
+        physical_page_allocator::subscribe_low_memory(kernel_event);
+    }
+
+    Ok(fd)
+}
+
+fn mount(
+    _all_state: &mut InterruptAllSavedState,
+    source: String,
+    target: String,
+    fstype: String,
+    flags: u64,
+) -> Result<(), SyscallError> {
+    if !with_current_process(|process| process.is_privileged()) {
+        return Err(SyscallError::PermissionDenied);
+    }
+    let driver =
+        FileSystemDriver::find(&fstype).ok_or(to_arg_err!(2, SyscallArgError::GeneralInvalid))?;
+    let read_only = flags & MOUNT_READ_ONLY != 0;
+    if flags & !MOUNT_READ_ONLY != 0 || (read_only && !driver.supports_read_only()) {
+        return Err(to_arg_err!(3, SyscallArgError::GeneralInvalid));
+    }
+    fs::mounts::mount_source(&source, &target, driver, read_only)?;
+    Ok(())
+}
+
+fn umount(_all_state: &mut InterruptAllSavedState, target: String) -> Result<(), SyscallError> {
+    if !with_current_process(|process| process.is_privileged()) {
+        return Err(SyscallError::PermissionDenied);
+    }
+    fs::mounts::unmount_target(&target)?;
+    Ok(())
+}
+
+fn copy_file_range(
+    _all_state: &mut InterruptAllSavedState,
+    in_file_index: usize,
+    in_offset_ptr: u64,
+    out_file_index: usize,
+    out_offset_ptr: u64,
+    len: u64,
+) -> Result<u64, SyscallError> {
+    // a null offset pointer means using (and advancing) the position of the file
+    let mut in_offset = None;
+    if in_offset_ptr != 0 {
+        user_memory::check_user_range(in_offset_ptr, 8, true).map_err(|err| to_arg_err!(1, err))?;
+        in_offset =
+            Some(user_memory::read_user_u64(in_offset_ptr).map_err(|err| to_arg_err!(1, err))?);
+    }
+    let mut out_offset = None;
+    if out_offset_ptr != 0 {
+        user_memory::check_user_range(out_offset_ptr, 8, true)
+            .map_err(|err| to_arg_err!(3, err))?;
+        out_offset =
+            Some(user_memory::read_user_u64(out_offset_ptr).map_err(|err| to_arg_err!(3, err))?);
+    }
+    if in_file_index == out_file_index {
+        return Err(to_arg_err!(2, SyscallArgError::GeneralInvalid));
     }
 
-    if !block {
-        if scheduler::is_process_running(pid) {
-            return Err(SyscallError::ProcessStillRunning);
+    let mut bytes_copied = 0;
+    loop {
+        let chunk = (len - bytes_copied).min(MAX_IO_CHUNK as u64);
+        let result = with_current_process(|process| -> Result<u64, SyscallError> {
+            // taken out, so both can be borrowed
+            let mut src = process
+                .take_file(in_file_index)
+                .ok_or(SyscallError::InvalidFileIndex)?;
+            let result = match process.get_file(out_file_index) {
+                Some(dst) => fs::copy_file_range(&mut src, in_offset, dst, out_offset, chunk)
+                    .map_err(|e| e.into()),
+                None => Err(SyscallError::InvalidFileIndex),
+            };
+            process.put_file(in_file_index, src);
+            result
+        });
+        match result {
+            Ok(copied) => {
+                bytes_copied += copied;
+                in_offset = in_offset.map(|offset| offset + copied);
+                out_offset = out_offset.map(|offset| offset + copied);
+                if copied < chunk || bytes_copied == len {
+                    break;
+                }
+            }
+            // report what was copied before the error
+            Err(_) if bytes_copied != 0 => break,
+            Err(e) => return Err(e),
         }
-        return Err(SyscallError::PidNotFound);
     }
 
-    // if not, wait for it
-    // this stash the current process until the other process exits
-    if !scheduler::wait_for_pid(all_state, pid) {
-        return Err(SyscallError::PidNotFound);
+    // checked above, and the process didn't run since
+    if let Some(offset) = in_offset {
+        user_memory::copy_to_user(in_offset_ptr, &offset.to_le_bytes())
+            .map_err(|err| to_arg_err!(1, err))?;
     }
-    // if we are waiting by the scheduler, this result is not important since it will be overwritten
-    // when we get back
-    SyscallResult::Ok(0)
+    if let Some(offset) = out_offset {
+        user_memory::copy_to_user(out_offset_ptr, &offset.to_le_bytes())
+            .map_err(|err| to_arg_err!(3, err))?;
+    }
+    Ok(bytes_copied)
+}
+
+fn ftruncate(
+    _all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    len: u64,
+) -> Result<(), SyscallError> {
+    with_current_process(|process| -> Result<(), SyscallError> {
+        let file = process
+            .get_file(file_index)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        file.truncate(len)
+            .map_err(|e| fs_error("truncate", file.path(), e))
+    })?;
+    Ok(())
+}
+
+fn truncate(
+    _all_state: &mut InterruptAllSavedState,
+    path: String,
+    len: u64,
+) -> Result<(), SyscallError> {
+    fs::truncate(&path, len).map_err(|e| fs_error("truncate", &path, e))?;
+    Ok(())
+}
+
+fn flock(
+    all_state: &mut InterruptAllSavedState,
+    file_index: usize,
+    operation: u64,
+) -> Result<(), SyscallError> {
+    let operation = FlockOperation::from_u64(operation)
+        .ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+
+    let state = with_current_process(|process| {
+        let (pid, name) = (process.id, process.process_name());
+        let file = process
+            .get_file(file_index)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        file.flock(pid, name, file_index, operation)
+            .map_err(|e| fs_error("flock", file.path(), e))
+    })?;
+
+    if state == fs::locks::LockState::Waiting {
+        // the result is set by the scheduler when the lock is ours
+        scheduler::wait_for_lock(all_state);
+    }
+    Ok(())
+}
+
+fn clock_gettime(_all_state: &mut InterruptAllSavedState, clock: u64) -> Result<u64, SyscallError> {
+    let clock = ClockId::from_u64(clock).ok_or(to_arg_err!(0, SyscallArgError::GeneralInvalid))?;
+
+    // a realtime set before the epoch wraps, it can't be returned
+    Ok(clock::clock_nanos(clock).min(i64::MAX as u64))
+}
+
+fn sleep(all_state: &mut InterruptAllSavedState, nanos: u64) -> Result<(), SyscallError> {
+    // the syscall gives the turn to the others already
+    if nanos == 0 {
+        return Ok(());
+    }
+    let deadline = clock::monotonic_nanos().saturating_add(nanos);
+    // `0` is put in `rax` when it's woken by its timer
+    scheduler::sleep_current(all_state, deadline);
+    Ok(())
+}
+
+/// Checks the address of a futex, a `u32` aligned to 4
+fn check_futex(addr: u64) -> Result<(), SyscallError> {
+    if !is_aligned(addr as usize, 4) {
+        return Err(to_arg_err!(0, SyscallArgError::InvalidUserPointer));
+    }
+    user_memory::check_user_range(addr, 4, false).map_err(|err| to_arg_err!(0, err))
+}
+
+fn futex_wait(
+    all_state: &mut InterruptAllSavedState,
+    addr: u64,
+    expected: u64,
+) -> Result<(), SyscallError> {
+    check_futex(addr)?;
+    let expected =
+        u32::try_from(expected).map_err(|_| to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+    // SAFETY: checked above
+    if !unsafe { scheduler::wait_for_futex(all_state, addr, expected) } {
+        return Err(SyscallError::WouldBlock);
+    }
+    // `0` is put in `rax` when it's woken
+    Ok(())
+}
+
+fn futex_wake(
+    _all_state: &mut InterruptAllSavedState,
+    addr: u64,
+    count: u64,
+) -> Result<u64, SyscallError> {
+    check_futex(addr)?;
+    Ok(with_current_process(|process| {
+        process.wake_futex(addr, count)
+    }))
+}
+
+fn set_name(
+    _all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    name: String,
+) -> Result<(), SyscallError> {
+    let name = ProcessName::new(&name).ok_or(to_arg_err!(1, SyscallArgError::GeneralInvalid))?;
+
+    let current_pid = with_current_process(|process| process.id);
+    scheduler::with_process(pid, |process| {
+        // a process can only name itself or its children
+        if process.id != current_pid && process.parent_id != current_pid {
+            return Err(SyscallError::PermissionDenied);
+        }
+        process.set_name(name);
+        Ok(())
+    })
+    .ok_or(SyscallError::PidNotFound)??;
+    if pid == current_pid {
+        cpu::cpu().process_name = name;
+    }
+
+    Ok(())
+}
+
+fn get_name(
+    _all_state: &mut InterruptAllSavedState,
+    pid: u64,
+    buf: UserBuffer,
+) -> Result<usize, SyscallError> {
+    let name = scheduler::with_process(pid, |process| process.process_name())
+        .ok_or(SyscallError::PidNotFound)?;
+    let name = name.as_str().as_bytes();
+    if (buf.len as usize) < name.len() {
+        return Err(to_arg_err!(2, SyscallArgError::GeneralInvalid));
+    }
+    user_memory::copy_to_user(buf.addr, name).map_err(|err| to_arg_err!(1, err))?;
+
+    Ok(name.len())
+}
+
+fn watch_create(_all_state: &mut InterruptAllSavedState) -> Result<usize, SyscallError> {
+    let file = fs::watch::create();
+    let fd = with_current_process(|process| process.push_file(file))?;
+    Ok(fd)
+}
+
+fn watch_add(
+    _all_state: &mut InterruptAllSavedState,
+    watch_fd: usize,
+    path: String,
+    mask: u64,
+) -> Result<u64, SyscallError> {
+    let mask = u32::try_from(mask)
+        .ok()
+        .filter(|mask| *mask != 0 && mask & !WATCH_ALL == 0)
+        .ok_or(to_arg_err!(2, SyscallArgError::GeneralInvalid))?;
+
+    let watch = with_current_process(|process| {
+        let file = process
+            .get_file(watch_fd)
+            .ok_or(SyscallError::InvalidFileIndex)?;
+        file.watch()
+            .cloned()
+            .ok_or(to_arg_err!(0, SyscallArgError::GeneralInvalid))
+    })?;
+    let wd = fs::watch::add(&watch, &path, mask).map_err(|e| fs_error("watch", &path, e))?;
+    Ok(wd as u64)
 }
 
 pub fn handle_syscall(all_state: &mut InterruptAllSavedState) {
@@ -411,10 +1282,15 @@ pub fn handle_syscall(all_state: &mut InterruptAllSavedState) {
 
     // `syscall_handler_wrapper` will check the syscall number and return error if it exceed the
     // number of syscalls (NUM_SYSCALLS)
-    all_state.rest.rax = syscall_handler_wrapper(syscall_number, || {
+    let result = syscall_handler_wrapper(syscall_number, || {
         let syscall_func = SYSCALLS[syscall_number as usize];
         syscall_func(all_state)
     });
+    // it put back the `rax` of the code the signal interrupted
+    if syscall_number != SYS_SIGRETURN {
+        all_state.rest.rax = result;
+    }
 
-    crate::scheduler::yield_current_if_any(all_state);
+    scheduler::account_syscall_exit();
+    scheduler::yield_current_if_any(all_state);
 }
@@ -0,0 +1,141 @@
+//! Copying to and from the memory of the current process for the syscalls.
+//!
+//! The handlers never keep references to user memory, all of it is copied through here. The
+//! ranges must be mapped for the user (and writable to write to them, the copy-on-write pages
+//! of a fork are copied before), the stack grows to a range below it first, the pages of
+//! the file mappings are read, and the ones moved to the swap are mapped back. The copy itself
+//! is covered by the [exception table](crate::cpu::exception_table), so a fault that still
+//! happens is an error instead of a panic.
+
+use alloc::{string::String, vec::Vec};
+use kernel_user_link::syscalls::SyscallArgError;
+
+use crate::{
+    cpu::exception_table,
+    memory_management::{memory_layout::PAGE_4K, swap},
+};
+
+use super::with_current_process;
+
+/// The longest string argument, including the null terminator
+pub const MAX_STRING_ARG: usize = PAGE_4K;
+
+/// Checks that all of `ptr..ptr + len` can be accessed by the user, a null `ptr` is invalid
+/// even if `len` is `0`
+pub fn check_user_range(ptr: u64, len: u64, write: bool) -> Result<(), SyscallArgError> {
+    if ptr == 0 {
+        return Err(SyscallArgError::InvalidUserPointer);
+    }
+    if len == 0 {
+        return Ok(());
+    }
+    // a buffer on the stack can be below what it touched so far, one in a file mapping can
+    // have pages not read yet, and any can have pages in the swap, they are read without the
+    // scheduler lock
+    with_current_process(|process| {
+        process.grow_stack(ptr);
+        process.fault_in_file_range(ptr, len);
+    });
+    swap::swap_in_range(ptr, len);
+    let accessible =
+        with_current_process(|process| process.is_user_range_accessible(ptr, len, write));
+    if !accessible {
+        return Err(SyscallArgError::InvalidUserPointer);
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), SyscallArgError> {
+    check_user_range(src, dst.len() as u64, false)?;
+    // SAFETY: the source is user memory (checked above), and the destination is ours
+    let left = unsafe { exception_table::copy_bytes(dst.as_mut_ptr(), src as _, dst.len()) };
+    if left != 0 {
+        return Err(SyscallArgError::InvalidUserPointer);
+    }
+    Ok(())
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), SyscallArgError> {
+    check_user_range(dst, src.len() as u64, true)?;
+    with_current_process(|process| process.resolve_cow_range(dst, src.len() as u64));
+    // SAFETY: the destination is writable user memory (checked above)
+    let left = unsafe { exception_table::copy_bytes(dst as _, src.as_ptr(), src.len()) };
+    if left != 0 {
+        return Err(SyscallArgError::InvalidUserPointer);
+    }
+    Ok(())
+}
+
+pub fn copy_vec_from_user(src: u64, len: usize) -> Result<Vec<u8>, SyscallArgError> {
+    let mut data = alloc::vec![0; len];
+    copy_from_user(&mut data, src)?;
+    Ok(data)
+}
+
+/// Reads a `u64`, i.e. a pointer in an array of pointers
+pub fn read_user_u64(src: u64) -> Result<u64, SyscallArgError> {
+    let mut bytes = [0; 8];
+    copy_from_user(&mut bytes, src)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Copies the bytes of `value`
+///
+/// # Safety
+/// `T` must have no padding, the ABI structs have explicit `_pad` fields for that
+pub unsafe fn copy_value_to_user<T: Copy>(dst: u64, value: &T) -> Result<(), SyscallArgError> {
+    let bytes =
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>());
+    copy_to_user(dst, bytes)
+}
+
+/// Reads a `T` from its bytes
+///
+/// # Safety
+/// Any bytes must be a valid `T`, i.e. the ABI structs of integers
+pub unsafe fn copy_value_from_user<T: Copy>(src: u64) -> Result<T, SyscallArgError> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let bytes =
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>());
+    copy_from_user(bytes, src)?;
+    Ok(value.assume_init())
+}
+
+/// Reads a `u32` without checking the range or bringing its page in, for the code that holds
+/// the scheduler lock, `None` if it's not mapped now
+///
+/// # Safety
+/// `src` must be a user address checked with [`check_user_range`] before
+pub unsafe fn read_user_u32_mapped(src: u64) -> Option<u32> {
+    let mut bytes = [0; 4];
+    // SAFETY: user memory (checked by the caller), a fault is caught
+    let left = unsafe { exception_table::copy_bytes(bytes.as_mut_ptr(), src as _, bytes.len()) };
+    (left == 0).then_some(u32::from_le_bytes(bytes))
+}
+
+/// Copies a null terminated string, of at most [`MAX_STRING_ARG`] bytes with the terminator
+pub fn read_user_str(src: u64) -> Result<String, SyscallArgError> {
+    if src == 0 {
+        return Err(SyscallArgError::InvalidUserPointer);
+    }
+    let mut bytes = Vec::new();
+    let mut addr = src;
+    loop {
+        // a page at a time, the string can end right before an unmapped page
+        let page_left = PAGE_4K - (addr as usize % PAGE_4K);
+        let len = page_left.min(MAX_STRING_ARG - bytes.len());
+        if len == 0 {
+            return Err(SyscallArgError::GeneralInvalid);
+        }
+        let start = bytes.len();
+        bytes.resize(start + len, 0);
+        copy_from_user(&mut bytes[start..], addr)?;
+        if let Some(end) = bytes[start..].iter().position(|&b| b == 0) {
+            bytes.truncate(start + end);
+            return String::from_utf8(bytes).map_err(|_| SyscallArgError::NotValidUtf8);
+        }
+        addr = addr
+            .checked_add(len as u64)
+            .ok_or(SyscallArgError::InvalidUserPointer)?;
+    }
+}
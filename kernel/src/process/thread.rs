@@ -0,0 +1,86 @@
+//! The threads of a process, made with `SYS_THREAD_CREATE`.
+//!
+//! They share everything of the process but the registers, the state and the kernel stack.
+//! The thread that runs (or ran last) has these in the fields of the [`Process`] itself, so
+//! all the code that saves or parks the current process saves that thread. The others wait
+//! in [`Threads`] for their turn, the scheduler swaps the next one in before the process runs
+//! (see [`Process::next_thread`]). So the threads of a process take turns on its CPU, they
+//! never run at the same time.
+
+use alloc::collections::{BTreeSet, VecDeque};
+
+use super::{kernel_stack::KernelStack, Process, ProcessContext, ProcessState};
+
+/// A thread not in the fields of its process
+pub struct Thread {
+    pub id: u64,
+    pub context: ProcessContext,
+    pub state: ProcessState,
+    pub kernel_stack: KernelStack,
+}
+
+impl Thread {
+    /// Exchanges this thread with the one in the fields of `process`
+    pub fn swap_with(&mut self, process: &mut Process) {
+        core::mem::swap(&mut self.id, &mut process.thread_id);
+        core::mem::swap(&mut self.context, &mut process.context);
+        core::mem::swap(&mut self.state, &mut process.state);
+        core::mem::swap(&mut self.kernel_stack, &mut process.kernel_stack);
+    }
+}
+
+/// The threads of a process other than the one in its fields
+#[derive(Default)]
+pub struct Threads {
+    // in the order they take turns
+    waiting: VecDeque<Thread>,
+    // the ids of the ones that exited, until they are joined
+    exited: BTreeSet<u64>,
+}
+
+impl Threads {
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    pub fn push(&mut self, thread: Thread) {
+        self.waiting.push_back(thread);
+    }
+
+    pub fn has_scheduled(&self) -> bool {
+        self.waiting
+            .iter()
+            .any(|thread| thread.state == ProcessState::Scheduled)
+    }
+
+    /// Takes the first thread that can run
+    pub fn take_scheduled(&mut self) -> Option<Thread> {
+        let index = self
+            .waiting
+            .iter()
+            .position(|thread| thread.state == ProcessState::Scheduled)?;
+        self.waiting.remove(index)
+    }
+
+    /// Takes the first thread, any state
+    pub fn take_first(&mut self) -> Option<Thread> {
+        self.waiting.pop_front()
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.waiting.iter().any(|thread| thread.id == id)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Thread> {
+        self.waiting.iter_mut()
+    }
+
+    pub fn add_exited(&mut self, id: u64) {
+        self.exited.insert(id);
+    }
+
+    /// Whether `id` exited and wasn't joined yet, it is joined now
+    pub fn take_exited(&mut self, id: u64) -> bool {
+        self.exited.remove(&id)
+    }
+}
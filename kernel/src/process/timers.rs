@@ -0,0 +1,182 @@
+//! The timers of the sleeping threads, see `SYS_SLEEP`.
+//!
+//! A thread sleeps with [`ProcessState::Sleeping`](super::ProcessState::Sleeping) and its
+//! deadline, and a [`Timer`] is added to the wheel for its process. The wheel has a slot for
+//! each [`SLOT_NANOS`] of the monotonic clock, the timer is in the one of its deadline, and the
+//! timers of later turns share it. Every timer tick looks at the slots up to the time now, so
+//! only the timers that are close are looked at, and wakes the threads of the ones that
+//! expired (see [`Process::wake_sleeping`](super::Process::wake_sleeping)). A thread sleeps
+//! until the first tick after its deadline.
+//!
+//! A timer of a thread woken before (i.e. by a signal), or of a process that exited, stays
+//! until its deadline, it then finds nothing to wake.
+
+use crate::{devices::clock, sync::spin::mutex::Mutex};
+
+use alloc::vec::Vec;
+
+use super::scheduler;
+
+static TIMERS: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// The time of a slot of the wheel
+const SLOT_NANOS: u64 = 1_000_000;
+const NUM_SLOTS: usize = 64;
+/// The expired timers taken out of the wheel at once, the rest are taken after these are woken
+const EXPIRE_BATCH: usize = 16;
+
+/// Wakes the sleeping threads of `pid` once the time is `deadline`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timer {
+    pub deadline: u64,
+    pub pid: u64,
+}
+
+struct TimerWheel {
+    slots: [Vec<Timer>; NUM_SLOTS],
+    // the slot of the last `expire`, counted from `0` not wrapped, the slots before it have no
+    // expired timers
+    current: u64,
+    len: usize,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            slots: [const { Vec::new() }; NUM_SLOTS],
+            current: 0,
+            len: 0,
+        }
+    }
+
+    fn slot_mut(&mut self, slot: u64) -> &mut Vec<Timer> {
+        &mut self.slots[(slot % NUM_SLOTS as u64) as usize]
+    }
+
+    /// Adds `timer`, one for a time that passed already expires on the next [`Self::expire`]
+    fn add(&mut self, timer: Timer) {
+        let slot = (timer.deadline / SLOT_NANOS).max(self.current);
+        self.slot_mut(slot).push(timer);
+        self.len += 1;
+    }
+
+    /// Moves the timers with a deadline of `now` or before to `out`, as many as fit, and
+    /// returns how many it did. If `out` is full, there can be more
+    fn expire(&mut self, now: u64, out: &mut [Timer]) -> usize {
+        let now_slot = now / SLOT_NANOS;
+        // after a full turn all the slots were looked at
+        let last = now_slot.min(self.current + NUM_SLOTS as u64 - 1);
+        let mut count = 0;
+        while count < self.len {
+            let current = self.current;
+            let slot = self.slot_mut(current);
+            let mut i = 0;
+            while i < slot.len() && count < out.len() {
+                if slot[i].deadline <= now {
+                    out[count] = slot.swap_remove(i);
+                    count += 1;
+                } else {
+                    i += 1;
+                }
+            }
+            if count == out.len() {
+                // this slot may have more
+                self.len -= count;
+                return count;
+            }
+            if self.current >= last {
+                break;
+            }
+            self.current += 1;
+        }
+        self.len -= count;
+        self.current = self.current.max(now_slot);
+        count
+    }
+}
+
+/// Wakes the threads of `pid` sleeping until `deadline` then. The thread must be parked
+/// before, with the scheduler still locked, so the tick can't miss it
+pub fn add(deadline: u64, pid: u64) {
+    TIMERS.lock().add(Timer { deadline, pid });
+}
+
+/// Called by the timer interrupt, wakes the threads whose timers expired
+pub fn tick() {
+    let now = clock::monotonic_nanos();
+    let mut expired = [Timer::default(); EXPIRE_BATCH];
+    loop {
+        // not locked together with the scheduler, `add` locks them the other way around
+        let count = TIMERS.lock().expire(now, &mut expired);
+        if count == 0 {
+            break;
+        }
+        scheduler::with_processes(|processes| {
+            for timer in &expired[..count] {
+                if let Some(process) = processes.iter_mut().find(|p| p.id == timer.pid) {
+                    process.wake_sleeping(now);
+                }
+            }
+        });
+        if count < EXPIRE_BATCH {
+            break;
+        }
+    }
+}
+
+/// The wheel alone with made up times: which timers expire and when, over the turns, after a
+/// gap of more than a turn, and more of them than fit at once
+pub(super) fn run_self_tests() {
+    println!("Running timer wheel self tests...");
+    const MS: u64 = SLOT_NANOS;
+    let turn = NUM_SLOTS as u64 * MS;
+    let timer = |deadline, pid| Timer { deadline, pid };
+    let expire = |wheel: &mut TimerWheel, now| {
+        let mut out = [Timer::default(); 4];
+        let count = wheel.expire(now, &mut out);
+        let mut pids = out[..count].iter().map(|t| t.pid).collect::<Vec<_>>();
+        pids.sort();
+        pids
+    };
+
+    let mut wheel = TimerWheel::new();
+    let start = 10 * turn;
+    assert!(expire(&mut wheel, start).is_empty());
+    wheel.add(timer(start + 5 * MS, 1));
+    // in the same slot as the first, but later
+    wheel.add(timer(start + 5 * MS + MS / 2, 2));
+    // in the same slot too, in the next turn
+    wheel.add(timer(start + 5 * MS + turn, 3));
+    wheel.add(timer(start + 2 * turn + MS, 4));
+    // passed already
+    wheel.add(timer(start - MS, 5));
+
+    assert_eq!(expire(&mut wheel, start), [5]);
+    assert!(expire(&mut wheel, start + 5 * MS - 1).is_empty());
+    assert_eq!(expire(&mut wheel, start + 5 * MS + 1), [1]);
+    assert_eq!(expire(&mut wheel, start + 5 * MS + MS / 2), [2]);
+    assert!(expire(&mut wheel, start + turn).is_empty());
+    assert_eq!(expire(&mut wheel, start + turn + 6 * MS), [3]);
+    // more than a turn later, the slot of the last was passed over
+    assert_eq!(expire(&mut wheel, start + 5 * turn), [4]);
+    assert_eq!(wheel.len, 0);
+
+    // more than fit in a batch, the rest come next time, even without the time moving
+    let now = start + 6 * turn;
+    for pid in 0..6 {
+        wheel.add(timer(now + pid * MS / 2, pid));
+    }
+    assert!(expire(&mut wheel, now - 1).is_empty());
+    let mut first = expire(&mut wheel, now + 3 * MS);
+    assert_eq!(first.len(), 4);
+    first.extend(expire(&mut wheel, now + 3 * MS));
+    first.sort();
+    assert_eq!(first, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(wheel.len, 0);
+
+    // the timers of the slot of now stay there, it's looked at again
+    wheel.add(timer(now + 3 * MS + MS / 2, 7));
+    assert!(expire(&mut wheel, now + 3 * MS).is_empty());
+    assert_eq!(expire(&mut wheel, now + 4 * MS), [7]);
+    println!("Timer wheel self tests passed");
+}
@@ -0,0 +1,482 @@
+//! A sampling profiler, every timer tick records where the CPU is with its call chain, shown
+//! in `/devices/profile` in the collapsed stack format of flame graphs.
+//!
+//! The interrupted `rip` and the return addresses found by following `rbp` up the stack are
+//! pushed to the ring of the CPU, up to the configured depth (all the code is built with frame
+//! pointers, see `x86-64-os.json`). The stack is only read where it is mapped, with
+//! [`copy_bytes`] in case it is not anymore, and the walk stops at the first frame that doesn't
+//! look like one: not aligned, not further up the stack, or on the other side of the address
+//! space. A process is walked the same way, the interrupt runs with its page tables.
+//!
+//! The rings are drained into the folded stacks when the file is read, so it has to be read
+//! often enough for them to not fill up, the samples that didn't fit are counted as dropped.
+//! The kernel frames are named with the symbols of [`KERNEL_ELF_PATH`], the build copies the
+//! kernel there, without it they are addresses. The frames of a process are always addresses,
+//! under a `pid<N>` root, and its load address is in a `#` line to symbolize them offline.
+//!
+//! Writing `start`, `stop`, `reset` or `depth <N>` to the file controls it.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use kernel_core::profile::{self, Collapsed, MAX_DEPTH};
+use kernel_core::symbols::{self, Symbol, SymbolTable};
+
+use crate::{
+    collections::ring::RingBuffer,
+    cpu::{self, exception_table::copy_bytes, idt::InterruptAllSavedState, MAX_CPUS},
+    devices::{self, Device},
+    fs::{self, FileSystemError},
+    memory_management::{
+        kaslr,
+        virtual_memory_mapper::{self, MAX_USER_VIRTUAL_ADDRESS},
+    },
+    process::{scheduler, ProcessName},
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+/// Where the kernel ELF is in the filesystem, for its symbols
+pub const KERNEL_ELF_PATH: &str = "/kernel";
+
+/// The words of the ring of each CPU, about 480 samples of the default depth
+const RING_WORDS: usize = 8192;
+
+/// A frame further than this from the one before it is not believed
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DEPTH: AtomicUsize = AtomicUsize::new(profile::DEFAULT_DEPTH);
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static RINGS: [Mutex<RingBuffer<u64, RING_WORDS>>; MAX_CPUS] =
+    [const { Mutex::new(RingBuffer::empty()) }; MAX_CPUS];
+
+static FOLDED: Mutex<Folded> = Mutex::new(Folded::new());
+
+/// `None` if the file is missing or has no symbols, loaded once
+static KERNEL_SYMBOLS: OnceLock<Option<SymbolTable>> = OnceLock::new();
+
+/// The samples drained from the rings so far
+struct Folded {
+    collapsed: Collapsed,
+    /// The name, path and load address of the processes seen, `None` if it exited before
+    processes: BTreeMap<u64, Option<(ProcessName, String, usize)>>,
+}
+
+impl Folded {
+    const fn new() -> Self {
+        Self {
+            collapsed: Collapsed::new(),
+            processes: BTreeMap::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.collapsed.clear();
+        self.processes.clear();
+    }
+}
+
+/// Whether `len` bytes at `address` are mapped in the current page tables
+fn is_mapped(address: u64, len: u64) -> bool {
+    let last = address + len - 1;
+    virtual_memory_mapper::get_current_mapping_unlocked(address).is_some()
+        && virtual_memory_mapper::get_current_mapping_unlocked(last).is_some()
+}
+
+/// Follows the frame pointers from `rbp`, puts the return addresses in `out` and returns
+/// how many there are
+pub(crate) fn walk(mut rbp: u64, user: bool, out: &mut [u64]) -> usize {
+    let mut len = 0;
+    while len < out.len() {
+        let same_side = if user {
+            rbp < MAX_USER_VIRTUAL_ADDRESS as u64
+        } else {
+            rbp > MAX_USER_VIRTUAL_ADDRESS as u64
+        };
+        if rbp == 0 || !rbp.is_multiple_of(8) || !same_side || !is_mapped(rbp, 16) {
+            break;
+        }
+        let mut frame = [0u64; 2];
+        // SAFETY: the stack is only read, and a fault is recovered from
+        if unsafe { copy_bytes(frame.as_mut_ptr().cast(), rbp as *const u8, 16) } != 0 {
+            break;
+        }
+        let [next, return_address] = frame;
+        if return_address == 0 {
+            break;
+        }
+        out[len] = return_address;
+        len += 1;
+        // the stack grows down, so the frames of the callers are at higher addresses
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+    len
+}
+
+/// Records where the CPU was interrupted, called on every tick of the scheduler
+pub fn sample(all_state: &InterruptAllSavedState) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let user = all_state.frame.cs & 3 == 3;
+    let depth = DEPTH.load(Ordering::Relaxed).clamp(1, MAX_DEPTH);
+    let mut frames = [0; MAX_DEPTH];
+    frames[0] = all_state.frame.rip;
+    let len = 1 + walk(all_state.rest.rbp, user, &mut frames[1..depth]);
+
+    let cpu = cpu::cpu();
+    let pid = if user { cpu.process_id } else { 0 };
+    if profile::push_sample(&mut RINGS[cpu.id].lock(), user, pid, &frames[..len]) {
+        SAMPLES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn load_kernel_symbols() -> Option<SymbolTable> {
+    let mut file = fs::open(KERNEL_ELF_PATH).ok()?;
+    let symbols = SymbolTable::from_elf(|offset, buf| {
+        file.seek(offset).is_ok() && file.read(buf).is_ok_and(|read| read == buf.len() as u64)
+    });
+    match symbols {
+        Ok(symbols) if !symbols.is_empty() => Some(symbols),
+        Ok(_) => None,
+        Err(e) => {
+            println!("[!] profiler: no symbols from {KERNEL_ELF_PATH}: {e:?}");
+            None
+        }
+    }
+}
+
+/// The symbols of [`KERNEL_ELF_PATH`], loaded on the first call, it reads the filesystem
+pub(crate) fn kernel_symbols() -> Option<&'static SymbolTable> {
+    match KERNEL_SYMBOLS.try_get() {
+        Some(symbols) => symbols.as_ref(),
+        None => {
+            let _ = KERNEL_SYMBOLS.set(load_kernel_symbols());
+            KERNEL_SYMBOLS.get().as_ref()
+        }
+    }
+}
+
+/// The symbols of [`KERNEL_ELF_PATH`] if [`kernel_symbols`] loaded them already, for where
+/// the filesystem can't be used
+pub(crate) fn loaded_kernel_symbols() -> Option<&'static SymbolTable> {
+    KERNEL_SYMBOLS.try_get().and_then(Option::as_ref)
+}
+
+/// Names the kernel frames with the symbol table, or shows their address, the symbols are at
+/// the addresses of the ELF, before the `kaslr` slide
+pub(crate) fn kernel_frame_name(symbols: Option<&SymbolTable>, address: u64, out: &mut String) {
+    match symbols.and_then(|symbols| symbols.lookup(kaslr::link_address(address))) {
+        Some((name, _)) => out.push_str(name),
+        None => {
+            let _ = write!(out, "{address:#x}");
+        }
+    }
+}
+
+/// Moves the samples of all the rings to `folded`
+fn drain(folded: &mut Folded, kernel_name: &dyn Fn(u64, &mut String)) {
+    let mut frames = [0; MAX_DEPTH];
+    for ring in &RINGS {
+        while let Some(header) = profile::pop_sample(&mut ring.lock(), &mut frames) {
+            let frames = &frames[..header.depth];
+            if header.user {
+                folded.processes.entry(header.pid).or_insert_with(|| {
+                    scheduler::with_process(header.pid, |process| {
+                        (
+                            process.process_name(),
+                            String::from(process.path()),
+                            process.load_base(),
+                        )
+                    })
+                });
+                let root = alloc::format!("pid{}", header.pid);
+                folded.collapsed.add(&root, frames, |address, out| {
+                    let _ = write!(out, "{address:#x}");
+                });
+            } else {
+                folded.collapsed.add("kernel", frames, kernel_name);
+            }
+        }
+    }
+}
+
+fn clear_rings() {
+    for ring in &RINGS {
+        ring.lock().clear();
+    }
+}
+
+fn render(folded: &Folded) -> String {
+    let mut out = String::new();
+    // the numbers are not at the end of the `#` lines, or they would be read as a count
+    let _ = writeln!(
+        out,
+        "# depth {}, {} samples, {} dropped, {}",
+        DEPTH.load(Ordering::Relaxed),
+        folded.collapsed.samples(),
+        DROPPED.load(Ordering::Relaxed),
+        if ENABLED.load(Ordering::Relaxed) {
+            "running"
+        } else {
+            "stopped"
+        }
+    );
+    match KERNEL_SYMBOLS.try_get().and_then(Option::as_ref) {
+        Some(symbols) => {
+            let _ = writeln!(
+                out,
+                "# {} kernel symbols from {KERNEL_ELF_PATH}",
+                symbols.len()
+            );
+        }
+        None => {
+            let _ = writeln!(out, "# no kernel symbols, the kernel frames are addresses");
+        }
+    }
+    for (pid, process) in &folded.processes {
+        match process {
+            Some((name, path, base)) => {
+                let _ = writeln!(out, "# pid{pid} {name} {path} loaded at {base:#x}");
+            }
+            None => {
+                let _ = writeln!(out, "# pid{pid} exited before it was read");
+            }
+        }
+    }
+    let _ = write!(out, "{}", folded.collapsed);
+    out
+}
+
+/// `/devices/profile`, the folded stacks of all the samples since the last `reset`
+#[derive(Debug)]
+struct ProfileDevice;
+
+impl Device for ProfileDevice {
+    fn name(&self) -> &str {
+        "profile"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        // outside of the lock, it reads the filesystem
+        let symbols = kernel_symbols();
+        let mut folded = FOLDED.lock();
+        // the rest of the file is read from the same stacks
+        if offset == 0 {
+            drain(&mut folded, &|address, out| {
+                kernel_frame_name(symbols, address, out)
+            });
+        }
+        Ok(devices::read_bytes(render(&folded).as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        match command {
+            "start" => ENABLED.store(true, Ordering::Relaxed),
+            "stop" => ENABLED.store(false, Ordering::Relaxed),
+            "reset" => {
+                let mut folded = FOLDED.lock();
+                clear_rings();
+                folded.clear();
+                DROPPED.store(0, Ordering::Relaxed);
+            }
+            _ => {
+                let depth = command
+                    .strip_prefix("depth ")
+                    .and_then(|depth| depth.trim().parse::<usize>().ok())
+                    .filter(|depth| (1..=MAX_DEPTH).contains(depth))
+                    .ok_or(FileSystemError::InvalidData)?;
+                DEPTH.store(depth, Ordering::Relaxed);
+            }
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+pub fn init_device() {
+    devices::register_device(Arc::new(ProfileDevice));
+}
+
+/// How many samples the self test profiles
+const SELFTEST_SAMPLES: u64 = 20;
+const SELFTEST_SPIN_LIMIT: usize = 2_000_000_000;
+/// Bigger than the functions of the self test, to name the addresses in them
+const SELFTEST_FUNCTION_SIZE: u64 = 0x400;
+
+// each returns something used after the call, so its not a tail call that would replace
+// the frame of the caller
+
+#[inline(never)]
+fn selftest_outer(until: u64) -> u64 {
+    core::hint::black_box(selftest_middle(until))
+}
+
+#[inline(never)]
+fn selftest_middle(until: u64) -> u64 {
+    core::hint::black_box(selftest_inner(until))
+}
+
+#[inline(never)]
+fn selftest_inner(until: u64) -> u64 {
+    let mut spins = 0;
+    loop {
+        let samples = SAMPLES.load(Ordering::Relaxed);
+        if samples >= until {
+            return samples;
+        }
+        spins += 1;
+        assert!(
+            spins < SELFTEST_SPIN_LIMIT,
+            "profiler self test: the timer took no samples"
+        );
+        core::hint::spin_loop();
+    }
+}
+
+fn test_encoding() {
+    let mut ring = RingBuffer::<u64, 8>::empty();
+    assert!(profile::push_sample(&mut ring, true, 42, &[1, 2, 3]));
+    // 4 words used of the 7, there is no space for another of 3 frames
+    assert!(!profile::push_sample(&mut ring, false, 0, &[4, 5, 6]));
+    assert!(profile::push_sample(&mut ring, false, 0, &[7, 8]));
+    assert_eq!(ring.free(), 0);
+
+    let mut frames = [0; MAX_DEPTH];
+    let header = profile::pop_sample(&mut ring, &mut frames).unwrap();
+    assert_eq!((header.user, header.pid, header.depth), (true, 42, 3));
+    assert_eq!(frames[..3], [1, 2, 3]);
+    let header = profile::pop_sample(&mut ring, &mut frames).unwrap();
+    assert_eq!((header.user, header.pid, header.depth), (false, 0, 2));
+    assert_eq!(frames[..2], [7, 8]);
+    assert!(profile::pop_sample(&mut ring, &mut frames).is_none());
+
+    let mut collapsed = Collapsed::new();
+    let name = |address: u64, out: &mut String| {
+        let _ = write!(out, "f{address:x}");
+    };
+    // the innermost first, the return addresses are named 1 byte before
+    collapsed.add("kernel", &[0x10, 0x21, 0x31], name);
+    collapsed.add("kernel", &[0x10, 0x21, 0x31], name);
+    collapsed.add("kernel", &[0x11, 0x31], name);
+    assert_eq!(
+        alloc::format!("{collapsed}"),
+        "kernel;f30;f11 1\nkernel;f30;f20;f10 2\n"
+    );
+    assert_eq!(collapsed.samples(), 3);
+}
+
+fn test_symbols() {
+    assert_eq!(
+        symbols::demangle("_ZN4core3fmt5write17h0123456789abcdefE"),
+        "core::fmt::write"
+    );
+    assert_eq!(
+        symbols::demangle(
+            "_ZN53_$LT$kernel..fs..File$u20$as$u20$core..fmt..Debug$GT$3fmt17hfedcba9876543210E"
+        ),
+        "<kernel::fs::File as core::fmt::Debug>::fmt"
+    );
+    assert_eq!(symbols::demangle("memcpy"), "memcpy");
+
+    let symbol = |start, size, name: &str| Symbol {
+        start,
+        size,
+        name: String::from(name),
+    };
+    let table = SymbolTable::new(alloc::vec![
+        symbol(0x2000, 0x10, "b"),
+        symbol(0x1000, 0x100, "a"),
+        symbol(0x3000, 0, "c"),
+    ]);
+    assert_eq!(table.lookup(0x1000), Some(("a", 0)));
+    assert_eq!(table.lookup(0x10FF), Some(("a", 0xFF)));
+    assert_eq!(table.lookup(0x1100), None);
+    assert_eq!(table.lookup(0x2008), Some(("b", 8)));
+    assert_eq!(table.lookup(0x3000), Some(("c", 0)));
+    assert_eq!(table.lookup(0x3001), None);
+    assert_eq!(table.lookup(0xFFF), None);
+}
+
+/// Profiles three nested functions spinning until the timer took some samples, all of them
+/// must have the full chain
+fn test_nested_loop() {
+    let functions: [(u64, &str); 3] = [
+        (
+            selftest_outer as fn(u64) -> u64 as usize as u64,
+            "selftest_outer",
+        ),
+        (
+            selftest_middle as fn(u64) -> u64 as usize as u64,
+            "selftest_middle",
+        ),
+        (
+            selftest_inner as fn(u64) -> u64 as usize as u64,
+            "selftest_inner",
+        ),
+    ];
+    let name = |address: u64, out: &mut String| {
+        let function = functions
+            .iter()
+            .filter(|(start, _)| *start <= address && address - start < SELFTEST_FUNCTION_SIZE)
+            .max_by_key(|(start, _)| *start);
+        match function {
+            Some((_, name)) => out.push_str(name),
+            None => {
+                let _ = write!(out, "{address:#x}");
+            }
+        }
+    };
+
+    let old_depth = DEPTH.swap(profile::DEFAULT_DEPTH, Ordering::Relaxed);
+    clear_rings();
+
+    let until = SAMPLES.load(Ordering::Relaxed) + SELFTEST_SAMPLES;
+    ENABLED.store(true, Ordering::Relaxed);
+    selftest_outer(until);
+    ENABLED.store(false, Ordering::Relaxed);
+    DEPTH.store(old_depth, Ordering::Relaxed);
+
+    let mut test = Folded::new();
+    drain(&mut test, &name);
+
+    let collapsed = &test.collapsed;
+    let chain = "selftest_outer;selftest_middle;selftest_inner";
+    let with_chain = collapsed
+        .stacks()
+        .filter(|(stack, _)| stack.starts_with("kernel;") && stack.contains(chain))
+        .map(|(_, count)| count)
+        .sum::<u64>();
+    assert!(
+        collapsed.samples() >= SELFTEST_SAMPLES,
+        "profiler self test: {} samples",
+        collapsed.samples()
+    );
+    // the few others were taken before getting into the loop, or after leaving it
+    assert!(
+        with_chain * 4 >= collapsed.samples() * 3,
+        "profiler self test: only {with_chain} of {} samples in the nested loop:\n{collapsed}",
+        collapsed.samples()
+    );
+}
+
+/// Interrupts and the timer must be enabled
+pub fn run_self_tests() {
+    println!("Running profiler self tests...");
+    test_encoding();
+    test_symbols();
+    test_nested_loop();
+    println!("Profiler self tests passed");
+}
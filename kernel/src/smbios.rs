@@ -0,0 +1,805 @@
+//! SMBIOS (DMI) tables, these contain information about the machine we are running on.
+//!
+//! The whole structure table is copied into memory on `init`, and all parsing is done on the copy,
+//! so malformed tables will only result in partial data and warnings.
+
+use core::{
+    fmt, slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    devices,
+    fs::{self, FileAttributes, FileSystem, FileSystemError, INode},
+    memory_management::{
+        memory_layout::{
+            is_legacy_readable, physical2virtual, virtual2physical, EXTENDED_OFFSET, KERNEL_END,
+        },
+        virtual_space,
+    },
+    multiboot2::MultiBoot2Info,
+    sync::once::OnceLock,
+};
+
+const SMBIOS_SEARCH_START: usize = 0x000F0000;
+const SMBIOS_SEARCH_END: usize = 0x00100000;
+
+const SMBIOS_2_ANCHOR: &[u8] = b"_SM_";
+const SMBIOS_2_INTERMEDIATE_ANCHOR: &[u8] = b"_DMI_";
+const SMBIOS_3_ANCHOR: &[u8] = b"_SM3_";
+
+mod structure_type {
+    pub const BIOS_INFORMATION: u8 = 0;
+    pub const SYSTEM_INFORMATION: u8 = 1;
+    pub const PROCESSOR_INFORMATION: u8 = 4;
+    pub const END_OF_TABLE: u8 = 127;
+}
+
+static SMBIOS: OnceLock<SmBios> = OnceLock::new();
+
+/// Looks for the SMBIOS tables and parse them, then mount the `/smbios` directory
+///
+/// Note: this requires allocation, so it should be called after the heap is initialized
+pub fn init(multiboot_info: &MultiBoot2Info) {
+    let Some(entry_point) = find_entry_point(multiboot_info) else {
+        println!("WARNING: SMBIOS entry point not found");
+        return;
+    };
+
+    let raw = read_structure_table(&entry_point);
+    SMBIOS
+        .set(SmBios::parse(entry_point, raw))
+        .unwrap_or_else(|_| panic!("SMBIOS already initialized"));
+
+    if let Some(system) = system_info() {
+        println!(
+            "SMBIOS {}.{}: {} {}",
+            entry_point.major, entry_point.minor, system.manufacturer, system.product
+        );
+    }
+
+    fs::mount(
+        "/smbios",
+        Arc::new(SmBiosFileSystem {
+            disconnected: AtomicBool::new(false),
+        }),
+        "smbios",
+        "smbios",
+    );
+}
+
+pub fn system_info() -> Option<&'static SystemInfo> {
+    SMBIOS.try_get().and_then(|smbios| smbios.system.as_ref())
+}
+
+pub fn bios_info() -> Option<&'static BiosInfo> {
+    SMBIOS.try_get().and_then(|smbios| smbios.bios.as_ref())
+}
+
+pub fn processors_info() -> &'static [ProcessorInfo] {
+    SMBIOS
+        .try_get()
+        .map(|smbios| smbios.processors.as_slice())
+        .unwrap_or(&[])
+}
+
+fn physical_to_smbios_memory(addr: usize, size: usize) -> usize {
+    // only some parts of the low memory are mapped
+    if is_legacy_readable(addr, size)
+        || (addr >= EXTENDED_OFFSET && addr + size <= virtual2physical(KERNEL_END))
+    {
+        physical2virtual(addr)
+    } else {
+        virtual_space::get_virtual_for_physical(addr as _, size as _) as usize
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EntryPoint {
+    major: u8,
+    minor: u8,
+    table_address: u64,
+    // for 64-bit entry point, this is the maximum size, and the table ends with `END_OF_TABLE`
+    table_length: u32,
+    is_64bit: bool,
+}
+
+impl EntryPoint {
+    /// Parse the entry point from `bytes`, `bytes` can have extra bytes at the end
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(SMBIOS_3_ANCHOR) {
+            let len = *bytes.get(6)? as usize;
+            if len < 0x18 || bytes.len() < len {
+                println!("WARNING: SMBIOS 3 entry point has invalid length {len:#X}");
+                return None;
+            }
+            if checksum(&bytes[..len]) != 0 {
+                println!("WARNING: SMBIOS 3 entry point has invalid checksum");
+                return None;
+            }
+            Some(Self {
+                major: bytes[7],
+                minor: bytes[8],
+                table_length: u32::from_le_bytes(bytes[0xC..0x10].try_into().unwrap()),
+                table_address: u64::from_le_bytes(bytes[0x10..0x18].try_into().unwrap()),
+                is_64bit: true,
+            })
+        } else if bytes.starts_with(SMBIOS_2_ANCHOR) {
+            let len = *bytes.get(5)? as usize;
+            if len < 0x1F || bytes.len() < len {
+                println!("WARNING: SMBIOS entry point has invalid length {len:#X}");
+                return None;
+            }
+            if checksum(&bytes[..len]) != 0 {
+                println!("WARNING: SMBIOS entry point has invalid checksum");
+                return None;
+            }
+            let intermediate = &bytes[0x10..0x1F];
+            if !intermediate.starts_with(SMBIOS_2_INTERMEDIATE_ANCHOR)
+                || checksum(intermediate) != 0
+            {
+                println!("WARNING: SMBIOS entry point has invalid intermediate anchor");
+                return None;
+            }
+            Some(Self {
+                major: bytes[6],
+                minor: bytes[7],
+                table_length: u16::from_le_bytes(bytes[0x16..0x18].try_into().unwrap()) as u32,
+                table_address: u32::from_le_bytes(bytes[0x18..0x1C].try_into().unwrap()) as u64,
+                is_64bit: false,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+fn find_entry_point(multiboot_info: &MultiBoot2Info) -> Option<EntryPoint> {
+    if let Some(entry_point) = multiboot_info.smbios_tables().and_then(EntryPoint::parse) {
+        return Some(entry_point);
+    }
+
+    // scan the BIOS area, its on 16 bytes boundaries
+    let start = physical2virtual(SMBIOS_SEARCH_START) as *const u8;
+    let area = unsafe { slice::from_raw_parts(start, SMBIOS_SEARCH_END - SMBIOS_SEARCH_START) };
+
+    let mut found_32bit = None;
+    for offset in (0..area.len()).step_by(16) {
+        let bytes = &area[offset..];
+        if bytes.starts_with(SMBIOS_3_ANCHOR) {
+            if let Some(entry_point) = EntryPoint::parse(bytes) {
+                // 64-bit is preferred when both are present
+                return Some(entry_point);
+            }
+        } else if found_32bit.is_none() && bytes.starts_with(SMBIOS_2_ANCHOR) {
+            found_32bit = EntryPoint::parse(bytes);
+        }
+    }
+
+    found_32bit
+}
+
+fn read_structure_table(entry_point: &EntryPoint) -> Vec<u8> {
+    let size = entry_point.table_length as usize;
+    if size == 0 || entry_point.table_address == 0 {
+        println!("WARNING: SMBIOS structure table is empty");
+        return Vec::new();
+    }
+    let table = physical_to_smbios_memory(entry_point.table_address as _, size) as *const u8;
+    // SAFETY: this is mapped above, and the firmware tells us its valid
+    unsafe { slice::from_raw_parts(table, size) }.to_vec()
+}
+
+/// A structure with its strings taken from the table
+struct Structure<'a> {
+    ty: u8,
+    handle: u16,
+    formatted: &'a [u8],
+    strings: Vec<&'a [u8]>,
+}
+
+impl Structure<'_> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.formatted.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn qword(&self, offset: usize) -> Option<u64> {
+        let bytes = self.formatted.get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Strings are referenced by a 1-based index byte in the formatted area, 0 means no string.
+    fn string(&self, offset: usize) -> String {
+        match self.byte(offset) {
+            None | Some(0) => String::new(),
+            Some(index) => match self.strings.get(index as usize - 1) {
+                Some(s) => String::from_utf8_lossy(s).trim().to_string(),
+                None => {
+                    println!(
+                        "WARNING: SMBIOS structure type {} handle {:#X} references missing string {}",
+                        self.ty, self.handle, index
+                    );
+                    String::new()
+                }
+            },
+        }
+    }
+}
+
+struct StructureIter<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Iterator for StructureIter<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // header: type, length, handle
+        if self.remaining.len() < 4 {
+            return None;
+        }
+        let ty = self.remaining[0];
+        let len = self.remaining[1] as usize;
+        let handle = u16::from_le_bytes(self.remaining[2..4].try_into().unwrap());
+        if len < 4 || len > self.remaining.len() {
+            println!(
+                "WARNING: SMBIOS structure type {ty} handle {handle:#X} has invalid length {len:#X}"
+            );
+            self.remaining = &[];
+            return None;
+        }
+
+        let formatted = &self.remaining[..len];
+        let mut strings_area = &self.remaining[len..];
+
+        // the strings set is terminated by double null, if there are no strings, its just two nulls
+        let mut strings = Vec::new();
+        let mut terminated = false;
+        if strings_area.starts_with(&[0, 0]) {
+            strings_area = &strings_area[2..];
+            terminated = true;
+        } else {
+            while let Some(end) = strings_area.iter().position(|&c| c == 0) {
+                strings.push(&strings_area[..end]);
+                strings_area = &strings_area[end + 1..];
+                if strings_area.first() == Some(&0) {
+                    strings_area = &strings_area[1..];
+                    terminated = true;
+                    break;
+                }
+            }
+        }
+        if !terminated {
+            println!(
+                "WARNING: SMBIOS structure type {ty} handle {handle:#X} strings run past the table"
+            );
+            strings_area = &[];
+        }
+        self.remaining = strings_area;
+
+        Some(Structure {
+            ty,
+            handle,
+            formatted,
+            strings,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BiosInfo {
+    pub vendor: String,
+    pub version: String,
+    pub release_date: String,
+    pub characteristics: u64,
+    pub release: Option<(u8, u8)>,
+    pub firmware_release: Option<(u8, u8)>,
+}
+
+impl BiosInfo {
+    fn from_structure(s: &Structure) -> Self {
+        let release = s.byte(0x14).zip(s.byte(0x15));
+        let firmware_release = s.byte(0x16).zip(s.byte(0x17));
+        Self {
+            vendor: s.string(0x04),
+            version: s.string(0x05),
+            release_date: s.string(0x08),
+            characteristics: s.qword(0x0A).unwrap_or(0),
+            // 0xFF means not supported
+            release: release.filter(|&r| r != (0xFF, 0xFF)),
+            firmware_release: firmware_release.filter(|&r| r != (0xFF, 0xFF)),
+        }
+    }
+}
+
+impl fmt::Display for BiosInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "vendor: {}", self.vendor)?;
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "release_date: {}", self.release_date)?;
+        writeln!(f, "characteristics: {:#018X}", self.characteristics)?;
+        if let Some((major, minor)) = self.release {
+            writeln!(f, "release: {major}.{minor}")?;
+        }
+        if let Some((major, minor)) = self.firmware_release {
+            writeln!(f, "firmware_release: {major}.{minor}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemInfo {
+    pub manufacturer: String,
+    pub product: String,
+    pub version: String,
+    pub serial_number: String,
+    pub uuid: Option<[u8; 16]>,
+    pub sku_number: String,
+    pub family: String,
+}
+
+impl SystemInfo {
+    fn from_structure(s: &Structure, version: (u8, u8)) -> Self {
+        let uuid = s.formatted.get(0x08..0x18).and_then(|bytes| {
+            let mut uuid: [u8; 16] = bytes.try_into().unwrap();
+            // all 0xFF means not present, all 0 means not set
+            if uuid.iter().all(|&b| b == 0xFF) || uuid.iter().all(|&b| b == 0) {
+                return None;
+            }
+            // starting from 2.6, the first 3 fields are little endian
+            if version >= (2, 6) {
+                uuid[0..4].reverse();
+                uuid[4..6].reverse();
+                uuid[6..8].reverse();
+            }
+            Some(uuid)
+        });
+        Self {
+            manufacturer: s.string(0x04),
+            product: s.string(0x05),
+            version: s.string(0x06),
+            serial_number: s.string(0x07),
+            uuid,
+            sku_number: s.string(0x19),
+            family: s.string(0x1A),
+        }
+    }
+}
+
+impl fmt::Display for SystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "manufacturer: {}", self.manufacturer)?;
+        writeln!(f, "product: {}", self.product)?;
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "serial_number: {}", self.serial_number)?;
+        if let Some(uuid) = &self.uuid {
+            write!(f, "uuid: ")?;
+            for (i, b) in uuid.iter().enumerate() {
+                if i == 4 || i == 6 || i == 8 || i == 10 {
+                    write!(f, "-")?;
+                }
+                write!(f, "{b:02x}")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "sku_number: {}", self.sku_number)?;
+        writeln!(f, "family: {}", self.family)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessorInfo {
+    pub socket: String,
+    pub processor_type: u8,
+    pub family: u8,
+    pub manufacturer: String,
+    pub id: u64,
+    pub version: String,
+    pub max_speed_mhz: u16,
+    pub current_speed_mhz: u16,
+    pub core_count: Option<u8>,
+    pub thread_count: Option<u8>,
+}
+
+impl ProcessorInfo {
+    fn from_structure(s: &Structure) -> Self {
+        Self {
+            socket: s.string(0x04),
+            processor_type: s.byte(0x05).unwrap_or(0),
+            family: s.byte(0x06).unwrap_or(0),
+            manufacturer: s.string(0x07),
+            id: s.qword(0x08).unwrap_or(0),
+            version: s.string(0x10),
+            max_speed_mhz: s.word(0x14).unwrap_or(0),
+            current_speed_mhz: s.word(0x16).unwrap_or(0),
+            // 0 means unknown
+            core_count: s.byte(0x23).filter(|&c| c != 0),
+            thread_count: s.byte(0x25).filter(|&c| c != 0),
+        }
+    }
+}
+
+impl fmt::Display for ProcessorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "socket: {}", self.socket)?;
+        writeln!(f, "type: {:#X}", self.processor_type)?;
+        writeln!(f, "family: {:#X}", self.family)?;
+        writeln!(f, "manufacturer: {}", self.manufacturer)?;
+        writeln!(f, "id: {:#018X}", self.id)?;
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "max_speed: {}MHz", self.max_speed_mhz)?;
+        writeln!(f, "current_speed: {}MHz", self.current_speed_mhz)?;
+        if let Some(core_count) = self.core_count {
+            writeln!(f, "core_count: {core_count}")?;
+        }
+        if let Some(thread_count) = self.thread_count {
+            writeln!(f, "thread_count: {thread_count}")?;
+        }
+        Ok(())
+    }
+}
+
+struct SmBios {
+    raw: Vec<u8>,
+    bios: Option<BiosInfo>,
+    system: Option<SystemInfo>,
+    processors: Vec<ProcessorInfo>,
+    // generated content of the files in `/smbios`
+    files: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl SmBios {
+    fn parse(entry_point: EntryPoint, mut raw: Vec<u8>) -> Self {
+        let version = (entry_point.major, entry_point.minor);
+
+        let mut bios = None;
+        let mut system = None;
+        let mut processors = Vec::new();
+        let mut table_len = 0;
+        let mut structures = StructureIter { remaining: &raw };
+        while let Some(structure) = structures.next() {
+            match structure.ty {
+                structure_type::BIOS_INFORMATION => {
+                    bios = Some(BiosInfo::from_structure(&structure));
+                }
+                structure_type::SYSTEM_INFORMATION => {
+                    system = Some(SystemInfo::from_structure(&structure, version));
+                }
+                structure_type::PROCESSOR_INFORMATION => {
+                    processors.push(ProcessorInfo::from_structure(&structure));
+                }
+                structure_type::END_OF_TABLE => {
+                    table_len = raw.len() - structures.remaining.len();
+                    break;
+                }
+                _ => {}
+            }
+            table_len = raw.len() - structures.remaining.len();
+        }
+        if entry_point.is_64bit {
+            // the length is the maximum size, only keep what we used
+            raw.truncate(table_len);
+        }
+
+        let mut files = Vec::new();
+        if let Some(bios) = &bios {
+            files.push(("bios", bios.to_string().into_bytes()));
+        }
+        if let Some(system) = &system {
+            files.push(("system", system.to_string().into_bytes()));
+        }
+        if !processors.is_empty() {
+            let content = processors
+                .iter()
+                .enumerate()
+                .map(|(i, p)| format!("[processor {i}]\n{p}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            files.push(("processor", content.into_bytes()));
+        }
+
+        Self {
+            raw,
+            bios,
+            system,
+            processors,
+            files,
+        }
+    }
+
+    /// the file content by index, the last one after the generated files is the raw table
+    fn file(&self, index: usize) -> Option<(&str, &[u8])> {
+        if index == self.files.len() {
+            Some(("raw", &self.raw))
+        } else {
+            self.files
+                .get(index)
+                .map(|(name, content)| (*name, content.as_slice()))
+        }
+    }
+}
+
+/// A read-only filesystem exposing the SMBIOS data, mounted at `/smbios`
+struct SmBiosFileSystem {
+    disconnected: AtomicBool,
+}
+
+impl FileSystem for SmBiosFileSystem {
+    fn open_dir(&self, path: &str) -> Result<Vec<INode>, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        if path != "/" {
+            return Err(FileSystemError::FileNotFound);
+        }
+        let smbios = SMBIOS.get();
+        Ok((0..=smbios.files.len())
+            .filter_map(|i| smbios.file(i).map(|file| (i, file)))
+            .map(|(i, (name, content))| {
+                INode::new_file(
+                    String::from(name),
+                    FileAttributes::READ_ONLY,
+                    i as u32,
+                    content.len() as u32,
+                )
+            })
+            .collect())
+    }
+
+    fn read_dir(&self, _inode: &INode) -> Result<Vec<INode>, FileSystemError> {
+        // we don't have directories
+        Err(FileSystemError::IsNotDirectory)
+    }
+
+    fn read_file(
+        &self,
+        inode: &INode,
+        position: u64,
+        buf: &mut [u8],
+    ) -> Result<u64, FileSystemError> {
+        if self.is_disconnected() {
+            return Err(FileSystemError::StaleHandle);
+        }
+        let (_, content) = SMBIOS
+            .get()
+            .file(inode.start_cluster() as usize)
+            .ok_or(FileSystemError::FileNotFound)?;
+
+        Ok(devices::read_bytes(content, position, buf))
+    }
+
+    fn write_file(
+        &self,
+        _inode: &INode,
+        _position: u64,
+        _buf: &[u8],
+    ) -> Result<u64, FileSystemError> {
+        Err(FileSystemError::WriteNotSupported)
+    }
+
+    fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::Release);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+}
+
+/// A structure table laid out like the one of QEMU's `pc` machine
+#[rustfmt::skip]
+const SELFTEST_TABLE: &[u8] = &[
+    // BIOS information
+    0x00, 0x18, 0x00, 0x00, 0x01, 0x02, 0x00, 0xE8, 0x03, 0x00, 0x08, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0xFF, 0xFF,
+    // "SeaBIOS", "rel-1.16.3-0-ga6ed6b701f0a-prebuilt.qemu.org", "04/01/2014"
+    0x53, 0x65, 0x61, 0x42, 0x49, 0x4F, 0x53, 0x00, 0x72, 0x65, 0x6C, 0x2D,
+    0x31, 0x2E, 0x31, 0x36, 0x2E, 0x33, 0x2D, 0x30, 0x2D, 0x67, 0x61, 0x36,
+    0x65, 0x64, 0x36, 0x62, 0x37, 0x30, 0x31, 0x66, 0x30, 0x61, 0x2D, 0x70,
+    0x72, 0x65, 0x62, 0x75, 0x69, 0x6C, 0x74, 0x2E, 0x71, 0x65, 0x6D, 0x75,
+    0x2E, 0x6F, 0x72, 0x67, 0x00, 0x30, 0x34, 0x2F, 0x30, 0x31, 0x2F, 0x32,
+    0x30, 0x31, 0x34, 0x00, 0x00,
+    // system information, with the UUID
+    0x01, 0x1B, 0x00, 0x01, 0x01, 0x02, 0x03, 0x00, 0x2A, 0x4F, 0x4B, 0x1C,
+    0x3D, 0x8E, 0x4F, 0x6A, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6, 0x07, 0x18,
+    0x06, 0x00, 0x00,
+    // "QEMU", "Standard PC (i440FX + PIIX, 1996)", "pc-i440fx-8.2"
+    0x51, 0x45, 0x4D, 0x55, 0x00, 0x53, 0x74, 0x61, 0x6E, 0x64, 0x61, 0x72,
+    0x64, 0x20, 0x50, 0x43, 0x20, 0x28, 0x69, 0x34, 0x34, 0x30, 0x46, 0x58,
+    0x20, 0x2B, 0x20, 0x50, 0x49, 0x49, 0x58, 0x2C, 0x20, 0x31, 0x39, 0x39,
+    0x36, 0x29, 0x00, 0x70, 0x63, 0x2D, 0x69, 0x34, 0x34, 0x30, 0x66, 0x78,
+    0x2D, 0x38, 0x2E, 0x32, 0x00, 0x00,
+    // processor information
+    0x04, 0x2A, 0x00, 0x04, 0x01, 0x03, 0x01, 0x02, 0xA9, 0x06, 0x03, 0x00,
+    0xFF, 0xFB, 0x8B, 0x0F, 0x03, 0x00, 0x00, 0x00, 0xD0, 0x07, 0xD0, 0x07,
+    0x41, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01,
+    0x01, 0x01, 0x02, 0x00, 0x01, 0x00,
+    // "CPU 0", "QEMU", "pc-i440fx-8.2"
+    0x43, 0x50, 0x55, 0x20, 0x30, 0x00, 0x51, 0x45, 0x4D, 0x55, 0x00, 0x70,
+    0x63, 0x2D, 0x69, 0x34, 0x34, 0x30, 0x66, 0x78, 0x2D, 0x38, 0x2E, 0x32,
+    0x00, 0x00,
+    // end of table
+    0x7F, 0x04, 0x00, 0x7F, 0x00, 0x00,
+];
+
+/// Where the structures of [`SELFTEST_TABLE`] end
+const SELFTEST_STRUCTURE_ENDS: [usize; 4] = [89, 170, 238, 244];
+
+/// A 64-bit entry point with a valid checksum, pointing nowhere
+fn selftest_entry_point(table_length: u32) -> [u8; 0x18] {
+    let mut bytes = [0; 0x18];
+    bytes[..5].copy_from_slice(SMBIOS_3_ANCHOR);
+    bytes[6] = 0x18;
+    bytes[7] = 3;
+    bytes[9] = 1;
+    bytes[0xC..0x10].copy_from_slice(&table_length.to_le_bytes());
+    bytes[5] = 0u8.wrapping_sub(checksum(&bytes));
+    bytes
+}
+
+/// Parses [`SELFTEST_TABLE`], then cuts and garbage made from it, which must never panic, and
+/// checks the accessors against the files of the real table if there is one
+pub fn run_self_tests() {
+    println!("Running SMBIOS self tests...");
+    let entry_point_bytes = selftest_entry_point(SELFTEST_TABLE.len() as u32 + 16);
+    let entry_point = EntryPoint::parse(&entry_point_bytes).expect("smbios self test: entry point");
+    assert_eq!((entry_point.major, entry_point.minor), (3, 0));
+    assert!(entry_point.is_64bit);
+    let mut bad_checksum = entry_point_bytes;
+    bad_checksum[5] ^= 1;
+    assert!(EntryPoint::parse(&bad_checksum).is_none());
+    for len in 0..entry_point_bytes.len() {
+        assert!(EntryPoint::parse(&entry_point_bytes[..len]).is_none());
+    }
+
+    // the maximum size is past the end of table, the rest is dropped
+    let mut raw = SELFTEST_TABLE.to_vec();
+    raw.extend_from_slice(&[0xAA; 16]);
+    let smbios = SmBios::parse(entry_point, raw);
+    assert_eq!(smbios.raw, SELFTEST_TABLE);
+
+    let bios = smbios
+        .bios
+        .as_ref()
+        .expect("smbios self test: no BIOS information");
+    assert_eq!(bios.vendor, "SeaBIOS");
+    assert_eq!(bios.version, "rel-1.16.3-0-ga6ed6b701f0a-prebuilt.qemu.org");
+    assert_eq!(bios.release_date, "04/01/2014");
+    assert_eq!(bios.characteristics, 0x08);
+    assert_eq!(bios.release, Some((0, 0)));
+    assert_eq!(bios.firmware_release, None);
+
+    let system = smbios
+        .system
+        .as_ref()
+        .expect("smbios self test: no system information");
+    assert_eq!(system.manufacturer, "QEMU");
+    assert_eq!(system.product, "Standard PC (i440FX + PIIX, 1996)");
+    assert_eq!(system.version, "pc-i440fx-8.2");
+    assert_eq!(system.serial_number, "");
+    assert!(system
+        .to_string()
+        .contains("uuid: 1c4b4f2a-8e3d-6a4f-a1b2-c3d4e5f60718\n"));
+
+    let [processor] = smbios.processors.as_slice() else {
+        panic!("smbios self test: expected one processor");
+    };
+    assert_eq!(processor.socket, "CPU 0");
+    assert_eq!(processor.manufacturer, "QEMU");
+    assert_eq!(processor.id, 0x0F8B_FBFF_0003_06A9);
+    assert_eq!(processor.max_speed_mhz, 2000);
+    assert_eq!(processor.core_count, Some(1));
+    assert_eq!(processor.thread_count, Some(1));
+
+    let names: Vec<&str> = (0..=smbios.files.len())
+        .map(|i| smbios.file(i).unwrap().0)
+        .collect();
+    assert_eq!(names, ["bios", "system", "processor", "raw"]);
+    assert!(smbios.file(names.len()).is_none());
+
+    // before 2.6 the UUID is kept as it is
+    let old = EntryPoint {
+        major: 2,
+        minor: 4,
+        ..entry_point
+    };
+    let system = SmBios::parse(old, SELFTEST_TABLE.to_vec()).system.unwrap();
+    assert_eq!(system.uuid.unwrap()[..4], [0x2A, 0x4F, 0x4B, 0x1C]);
+
+    // cut in the header, the formatted area, the strings, and right at the end of each structure
+    let [bios_end, system_end, processor_end, _] = SELFTEST_STRUCTURE_ENDS;
+    let starts = [0, bios_end, system_end, processor_end];
+    for (&start, &end) in starts.iter().zip(&SELFTEST_STRUCTURE_ENDS) {
+        let formatted_len = SELFTEST_TABLE[start + 1] as usize;
+        for cut in [start + 2, start + 5, start + formatted_len + 1, end] {
+            let smbios = SmBios::parse(entry_point, SELFTEST_TABLE[..cut].to_vec());
+            let has_formatted = |structure_start: usize| {
+                cut >= structure_start + SELFTEST_TABLE[structure_start + 1] as usize
+            };
+            assert_eq!(smbios.bios.is_some(), has_formatted(0), "cut at {cut}");
+            assert_eq!(
+                smbios.system.is_some(),
+                has_formatted(bios_end),
+                "cut at {cut}"
+            );
+            assert_eq!(
+                smbios.processors.len(),
+                has_formatted(system_end) as usize,
+                "cut at {cut}"
+            );
+            // only the strings before the cut are there
+            if let Some(bios) = &smbios.bios {
+                assert_eq!(bios.vendor.is_empty(), cut < 0x18 + b"SeaBIOS\0".len());
+                assert_eq!(bios.release_date.is_empty(), cut < bios_end);
+            }
+            assert!(smbios.raw.len() <= cut);
+        }
+    }
+
+    // garbage, with and without a valid first header
+    let mut seed = 0x2545_F491_4F6C_DD1Du64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as u8
+    };
+    for round in 0..32 {
+        let mut raw: Vec<u8> = (0..256).map(|_| next()).collect();
+        if round % 2 == 0 {
+            raw[..4].copy_from_slice(&SELFTEST_TABLE[..4]);
+        }
+        let smbios = SmBios::parse(entry_point, raw);
+        for i in 0..=smbios.files.len() {
+            smbios.file(i).unwrap();
+        }
+    }
+    for _ in 0..32 {
+        let mut garbage_entry_point = [0; 0x20];
+        garbage_entry_point.iter_mut().for_each(|b| *b = next());
+        garbage_entry_point[..5].copy_from_slice(SMBIOS_3_ANCHOR);
+        let _ = EntryPoint::parse(&garbage_entry_point);
+    }
+
+    // the accessors are the data of the files
+    if let Some(smbios) = SMBIOS.try_get() {
+        let file = |name| {
+            (0..smbios.files.len())
+                .filter_map(|i| smbios.file(i))
+                .find(|(n, _)| *n == name)
+                .map(|(_, content)| content)
+        };
+        assert_eq!(
+            file("bios"),
+            bios_info()
+                .map(|bios| bios.to_string())
+                .as_deref()
+                .map(str::as_bytes)
+        );
+        assert_eq!(
+            file("system"),
+            system_info()
+                .map(|system| system.to_string())
+                .as_deref()
+                .map(str::as_bytes)
+        );
+        assert_eq!(file("processor").is_some(), !processors_info().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! Memory ordering helpers, and the rules for memory shared with devices, interrupt handlers
+//! and other CPUs.
+//!
+//! x86 is strongly ordered for normal memory, the only reordering the CPU does is a load
+//! passing an older store to another address. Most bugs come from the compiler instead, it
+//! can cache, merge, remove or move plain loads and stores as long as the current thread
+//! can't tell the difference.
+//!
+//! # Rules
+//!
+//! 1. Device registers are mapped with [`virtual_space::allocate_and_map_mmio`] (uncacheable)
+//!    and only accessed with the volatile accessors of [`MmioRegion`], never through
+//!    references to structures. Uncacheable accesses are not reordered with each other by
+//!    the CPU, and volatile accesses are not reordered with each other by the compiler.
+//! 2. Indexed register windows (a select register, then a data register, like the IOAPIC)
+//!    are two accesses that must not be interleaved with another user of the window, the
+//!    owner must be locked for the whole sequence.
+//! 3. Memory read by a device (DMA descriptors, rings) is written before the register write or
+//!    port write that tells the device about it. Stores to normal memory are not reordered
+//!    with the later uncacheable store or `out` by the CPU, put a [`compiler_fence`] (or use
+//!    atomics) so the compiler doesn't either. An [`sfence`] is needed only if the memory is
+//!    write-combining or written with non-temporal stores. Reading what the device wrote after
+//!    it reports completion needs the same on the other side ([`lfence`] for the CPU side).
+//! 4. Data shared with interrupt handlers or other CPUs is either behind a lock, or an atomic.
+//!    A flag that publishes other data is stored with [`Ordering::Release`] after writing the
+//!    data, and loaded with [`Ordering::Acquire`] before reading it. Never use a plain field
+//!    (or a `static mut`) as a flag, the compiler can hoist the load out of a waiting loop.
+//! 5. Page table entries are changed first, then the TLB entry is invalidated
+//!    (`cpu::invalidate_tlp`), and only after that the page they pointed to is freed.
+//!    Changing a present entry (not just creating one) needs an invalidation as well.
+//!    When there are other CPUs, they must be told to invalidate as well before freeing.
+//! 6. [`mfence`] is only needed for store-then-load ordering of different addresses between
+//!    CPUs (like Dekker style flags), atomics with [`Ordering::SeqCst`] stores do that already.
+//!
+//! # Audit
+//!
+//! - LAPIC: uses [`MmioRegion`], mapped uncacheable.
+//! - IOAPIC: uses [`MmioRegion`], mapped uncacheable, the register window is only used
+//!   inside the `APIC` lock.
+//! - HPET: mapped uncacheable, its registers are accessed with volatile accessors already.
+//! - fw_cfg DMA: the access structure is written with volatile and fenced with `SeqCst`
+//!   before the port write that starts it.
+//! - Page tables: `unmap` invalidates after clearing the entry and before freeing the page,
+//!   `map` invalidates entries that were present. The accessed/dirty bits, which the CPU
+//!   writes, are read and cleared with atomics.
+//! - `Cpu::scheduling`, the flag shared between the scheduler and the timer interrupt, is an
+//!   atomic with release/acquire accesses.
+//! - TLB shootdown: the changes of the kernel mappings shared by all the vms are flushed from
+//!   the other CPUs before the page is freed, with [`smp::flush_tlb_others`]. The user
+//!   mappings are only loaded on the CPU of their process. The per-CPU data is reached through
+//!   the GS base.
+//!
+//! [`virtual_space::allocate_and_map_mmio`]: crate::memory_management::virtual_space::allocate_and_map_mmio
+//! [`MmioRegion`]: crate::memory_management::mmio::MmioRegion
+//! [`smp::flush_tlb_others`]: crate::cpu::smp::flush_tlb_others
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize},
+};
+
+// re-exported so drivers have one place to get all the ordering tools from
+#[allow(unused_imports)]
+pub use core::sync::atomic::{compiler_fence, fence, Ordering};
+
+/// Orders all the loads and stores before it with the ones after it, also a compiler fence
+#[inline]
+#[allow(dead_code)]
+pub fn mfence() {
+    unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Orders the loads before it with the loads after it, also a compiler fence
+#[inline]
+#[allow(dead_code)]
+pub fn lfence() {
+    unsafe { core::arch::asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// Orders the stores before it with the stores after it, also a compiler fence
+#[inline]
+#[allow(dead_code)]
+pub fn sfence() {
+    unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+// number of times to round trip the flag with the interrupt handler
+const SELFTEST_ROUNDS: u64 = 10;
+// a timer tick is a few milliseconds, this is a lot more than that
+const SELFTEST_SPIN_LIMIT: usize = 200_000_000;
+
+/// Plain data published by `SELFTEST_FLAG`, not atomic on purpose
+struct SelfTestPayload(UnsafeCell<u64>);
+
+// SAFETY: accesses are ordered by `SELFTEST_FLAG`
+unsafe impl Sync for SelfTestPayload {}
+
+static SELFTEST_ACTIVE: AtomicBool = AtomicBool::new(false);
+static SELFTEST_FLAG: AtomicBool = AtomicBool::new(false);
+static SELFTEST_PAYLOAD: SelfTestPayload = SelfTestPayload(UnsafeCell::new(0));
+static SELFTEST_SEEN: AtomicU64 = AtomicU64::new(0);
+static SELFTEST_MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Called on every timer tick, the other side of [`run_self_tests`]
+pub fn selftest_tick() {
+    if !SELFTEST_ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if SELFTEST_FLAG.load(Ordering::Acquire) {
+        // SAFETY: the flag is set after the payload is written, and it won't be written
+        // again until we clear the flag
+        let payload = unsafe { *SELFTEST_PAYLOAD.0.get() };
+        let expected = SELFTEST_SEEN.load(Ordering::Relaxed) + 1;
+        if payload != expected {
+            SELFTEST_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+        }
+        SELFTEST_SEEN.store(payload, Ordering::Relaxed);
+        SELFTEST_FLAG.store(false, Ordering::Release);
+    }
+}
+
+/// Passes data between the kernel and the timer interrupt through a flag, the same way
+/// the scheduler does with `Cpu::scheduling`.
+///
+/// The interrupt handler acts as another CPU that can run at any point. With a plain `bool`
+/// flag the compiler moves the load out of the waiting loop, and this fails by never
+/// seeing the flag cleared.
+///
+/// Interrupts and the timer must be enabled
+pub fn run_self_tests() {
+    println!("Running memory barrier self tests...");
+    SELFTEST_SEEN.store(0, Ordering::Relaxed);
+    SELFTEST_MISMATCHES.store(0, Ordering::Relaxed);
+    SELFTEST_ACTIVE.store(true, Ordering::Relaxed);
+
+    for round in 1..=SELFTEST_ROUNDS {
+        // SAFETY: the flag is clear, so the handler is not reading it
+        unsafe { *SELFTEST_PAYLOAD.0.get() = round };
+        SELFTEST_FLAG.store(true, Ordering::Release);
+
+        let mut spins = 0;
+        while SELFTEST_FLAG.load(Ordering::Acquire) {
+            spins += 1;
+            assert!(
+                spins < SELFTEST_SPIN_LIMIT,
+                "barrier self test: the flag was never cleared by the interrupt, round {round}"
+            );
+            core::hint::spin_loop();
+        }
+    }
+
+    SELFTEST_ACTIVE.store(false, Ordering::Relaxed);
+    assert_eq!(
+        SELFTEST_MISMATCHES.load(Ordering::Relaxed),
+        0,
+        "barrier self test: the interrupt saw stale data"
+    );
+    assert_eq!(SELFTEST_SEEN.load(Ordering::Relaxed), SELFTEST_ROUNDS);
+    println!("Memory barrier self tests passed");
+}
@@ -1,2 +1,3 @@
+pub mod barrier;
 pub mod once;
 pub mod spin;
@@ -61,6 +61,7 @@ impl<T> Mutex<T> {
         }
     }
 
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<T> {
         let cpu = cpu::cpu();
         cpu.push_cli(); // disable interrupts to avoid deadlock
@@ -79,6 +80,7 @@ impl<T> Mutex<T> {
         }
     }
 
+    #[track_caller]
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
         let cpu = cpu::cpu();
         cpu.push_cli(); // disable interrupts to avoid deadlock
@@ -124,6 +126,20 @@ impl<T> Mutex<T> {
         f(d)
     }
 
+    /// Returns `true` if the mutex is locked by any CPU
+    pub fn is_locked(&self) -> bool {
+        self.owner_cpu.load(Ordering::Relaxed) != -1
+    }
+
+    /// Access the data without locking, for debugging when the lock could be held
+    /// forever, i.e. in `panic`.
+    ///
+    /// # Safety
+    /// The data could be modified at the same time, the result could be inconsistent
+    pub unsafe fn get_unlocked(&self) -> &T {
+        &*self.data.get()
+    }
+
     /// We know statically that no one else is accessing the lock, so we can
     /// just return a reference to the data without acquiring the lock.
     #[allow(dead_code)]
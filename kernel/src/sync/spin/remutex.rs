@@ -67,6 +67,7 @@ impl<T> ReMutex<T> {
         }
     }
 
+    #[track_caller]
     pub fn lock(&self) -> ReMutexGuard<T> {
         let cpu = cpu::cpu();
         cpu.push_cli(); // disable interrupts to avoid deadlock
@@ -96,6 +97,7 @@ impl<T> ReMutex<T> {
         }
     }
 
+    #[track_caller]
     pub fn try_lock(&self) -> Option<ReMutexGuard<T>> {
         let cpu = cpu::cpu();
         cpu.push_cli(); // disable interrupts to avoid deadlock
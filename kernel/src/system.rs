@@ -0,0 +1,694 @@
+//! Shutting down and rebooting the machine.
+//!
+//! [`shutdown`] and [`reboot`] log the reason, run the hooks registered with [`on_shutdown`]
+//! in the reverse order of registration (the last initialized is stopped first, so the
+//! filesystems are synced before the block devices under them are shut down), then power off
+//! or reset the machine.
+//!
+//! A hook that panics doesn't stop the others, the panic handler logs it and continues with
+//! the next one. The hooks are skipped after a panic (`panic=reboot` in the cmdline) and from
+//! the debugger, since the locks they need could be held by the code that stopped.
+//!
+//! Powering off enters the ACPI `S5` state (`\_S5_` of the DSDT, written to the PM1 control
+//! blocks of the FADT), or uses the ports of QEMU and Bochs if that didn't work. Resetting
+//! uses the reset register of the FADT, then the keyboard controller, then a triple fault.
+//!
+//! On hardware-reduced ACPI (QEMU's `microvm`, some ARM-like x86 boards), there is no PM1
+//! block, `S5` is written to the sleep control register instead, and the legacy ports
+//! (the keyboard controller and the emulator ones) are not touched, as nothing says they
+//! are there.
+//!
+//! With the `debug_exit` test option, both write [`debug_exit_code`] to QEMU's
+//! `isa-debug-exit` device first (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`), which
+//! exits QEMU with `code * 2 + 1`, so the tests can tell why the kernel stopped:
+//!
+//! | reason      | shutdown | reboot |
+//! |-------------|----------|--------|
+//! | requested   | 33       | 65     |
+//! | watchdog    | 35       | 67     |
+//! | panic       | 37       | 69     |
+//! | debugger    | 39       | 71     |
+//! | self test   | 41       | 73     |
+//! | init exited | 43       | 75     |
+//!
+//! The `shutdowntest` test option ends the boot with a shutdown through hooks that check
+//! their order, one of them panics, and a failed check exits with `3` instead.
+
+use core::{
+    fmt, hint,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use kernel_core::aml::{AmlValue, Namespace};
+
+use crate::{
+    acpi::{
+        generic_address::{GenericAddress, Register},
+        tables::{selftest_fadt, BiosTables, Dsdt, Facp},
+    },
+    cpu,
+    devices::{self, Device},
+    fs::FileSystemError,
+    process::scheduler::with_current_process,
+    sync::{once::OnceLock, spin::mutex::Mutex},
+};
+
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+/// Exits QEMU with `3`, for the self tests that can only be checked while shutting down
+const DEBUG_EXIT_SELFTEST_FAILED: u8 = 0x01;
+
+const KEYBOARD_STATUS_PORT: u16 = 0x64;
+const KEYBOARD_RESET_COMMAND: u8 = 0xFE;
+
+/// The power off ports of QEMU (`-machine q35` and newer `pc`), Bochs and older QEMU, and
+/// VirtualBox, with the value to write
+const EMULATOR_POWER_OFF_PORTS: [(u16, u16); 3] =
+    [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+const PM1_CONTROL_SCI_EN: u16 = 1 << 0;
+const PM1_CONTROL_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CONTROL_SLP_TYP_MASK: u16 = 0x7 << PM1_CONTROL_SLP_TYP_SHIFT;
+const PM1_CONTROL_SLP_EN: u16 = 1 << 13;
+/// The sleep control and status registers of reduced hardware, a byte each
+const SLEEP_CONTROL_SLP_TYP_SHIFT: u8 = 2;
+const SLEEP_CONTROL_SLP_TYP_MASK: u8 = 0x7 << SLEEP_CONTROL_SLP_TYP_SHIFT;
+const SLEEP_CONTROL_SLP_EN: u8 = 1 << 5;
+const SLEEP_STATUS_WAK_STS: u8 = 1 << 7;
+/// How many times to read PM1a for `SCI_EN` after asking for ACPI mode
+const ACPI_ENABLE_MAX_POLLS: usize = 1_000_000;
+
+/// The time given to each mechanism before trying the next one
+const RESET_WAIT_SPINS: usize = 1_000_000;
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+static DEBUG_EXIT: AtomicBool = AtomicBool::new(false);
+static PANIC_REBOOT: AtomicBool = AtomicBool::new(false);
+static ACPI_POWER: OnceLock<AcpiPower> = OnceLock::new();
+
+/// Set when the teardown starts, until then, the panics are not ours
+static TEARDOWN: OnceLock<Teardown> = OnceLock::new();
+/// The index (from the end of the hooks) of the next one to run
+static NEXT_HOOK: AtomicUsize = AtomicUsize::new(0);
+/// The hooks are done, and the machine is being powered off or reset
+static FINISHING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reason {
+    /// Asked for, by writing to `/devices/power`
+    Requested = 0,
+    /// The PIT watchdog found the scheduler timer stopped, see `pit_watchdog`
+    Watchdog = 1,
+    /// `panic=reboot` in the cmdline
+    Panic = 2,
+    /// `reboot` in the debugger
+    Debugger = 3,
+    SelfTest = 4,
+    /// `init_exit=reboot` in the cmdline
+    InitExited = 5,
+}
+
+impl Reason {
+    const ALL: [Reason; 6] = [
+        Reason::Requested,
+        Reason::Watchdog,
+        Reason::Panic,
+        Reason::Debugger,
+        Reason::SelfTest,
+        Reason::InitExited,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::Requested => "requested",
+            Reason::Watchdog => "watchdog",
+            Reason::Panic => "panic",
+            Reason::Debugger => "debugger",
+            Reason::SelfTest => "self test",
+            Reason::InitExited => "init exited",
+        }
+    }
+
+    /// The state can't be trusted after these, the hooks could wait forever on a lock
+    fn runs_hooks(self) -> bool {
+        !matches!(self, Reason::Panic | Reason::Debugger)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    Shutdown = 1,
+    Reboot = 2,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Shutdown => "shutdown",
+            Action::Reboot => "reboot",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Hook {
+    name: &'static str,
+    run: fn(),
+}
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+struct Teardown {
+    action: Action,
+    reason: Reason,
+    hooks: Vec<Hook>,
+}
+
+impl Teardown {
+    /// The hooks in the order they run
+    fn hook(&self, index: usize) -> Option<&Hook> {
+        self.hooks.iter().rev().nth(index)
+    }
+}
+
+/// Where the sleep type is written to enter `S5`, `R` is a [`GenericAddress`] from the
+/// tables, or the [`Register`] mapped from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SleepMethod<R> {
+    /// The PM1a and PM1b control blocks, `0` if there is no `b`
+    Pm1Control(u16, u16),
+    /// The sleep control and status registers of reduced hardware
+    SleepRegisters {
+        control: R,
+        status: R,
+    },
+    None,
+}
+
+/// What [`init`] found in the ACPI tables to power off and reset the machine
+#[derive(Debug)]
+struct AcpiPower {
+    sleep: SleepMethod<Register>,
+    /// `SLP_TYPa` and `SLP_TYPb` of `\_S5_`
+    sleep_types: Option<(u16, u16)>,
+    enable_command: Option<(u16, u8)>,
+    reset: Option<(Register, u8)>,
+    /// Not on reduced hardware, the keyboard controller and the emulator ports may not exist
+    legacy_ports: bool,
+}
+
+/// The value written to `isa-debug-exit`, QEMU exits with `code * 2 + 1`
+pub const fn debug_exit_code(action: Action, reason: Reason) -> u8 {
+    (action as u8) << 4 | reason as u8
+}
+
+/// Runs `hook` when shutting down or rebooting, after the ones registered after it,
+/// the errors must be logged by the hook itself
+pub fn on_shutdown(name: &'static str, hook: fn()) {
+    HOOKS.lock().push(Hook { name, run: hook });
+}
+
+/// Makes [`shutdown`] and [`reboot`] exit QEMU with [`debug_exit_code`]
+pub fn set_debug_exit(enabled: bool) {
+    DEBUG_EXIT.store(enabled, Ordering::Relaxed);
+}
+
+/// Makes the panics reboot, instead of halting, set by `panic=reboot` in the cmdline
+pub fn set_panic_reboot(enabled: bool) {
+    PANIC_REBOOT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn shutdown(reason: Reason) -> ! {
+    teardown(Action::Shutdown, reason)
+}
+
+pub fn reboot(reason: Reason) -> ! {
+    teardown(Action::Reboot, reason)
+}
+
+fn teardown(action: Action, reason: Reason) -> ! {
+    // nothing else runs from now on, the hooks can only poll the devices
+    unsafe { cpu::clear_interrupts() };
+    let hooks = if reason.runs_hooks() {
+        HOOKS.lock().clone()
+    } else {
+        Vec::new()
+    };
+    let teardown = Teardown {
+        action,
+        reason,
+        hooks,
+    };
+    if TEARDOWN.set(teardown).is_err() {
+        // a hook asked for it, the first one continues
+        println!(
+            "{} ({}) during the teardown, ignored",
+            action.name(),
+            reason.name()
+        );
+        continue_teardown();
+    }
+    println!("System {}: {}", action.name(), reason.name());
+    if !reason.runs_hooks() {
+        println!("Skipping the shutdown hooks");
+    }
+    continue_teardown()
+}
+
+/// Runs the hooks left, then powers off or resets
+fn continue_teardown() -> ! {
+    let teardown = TEARDOWN.get();
+    while let Some(hook) = teardown.hook(NEXT_HOOK.fetch_add(1, Ordering::Relaxed)) {
+        println!("Shutdown hook `{}`", hook.name);
+        (hook.run)();
+    }
+    FINISHING.store(true, Ordering::Relaxed);
+    match teardown.action {
+        Action::Shutdown => power_off(teardown.reason),
+        Action::Reboot => reset(teardown.reason),
+    }
+}
+
+/// Called by the panic handler after printing the report, continues the teardown if a hook
+/// panicked, or reboots with `panic=reboot`.
+///
+/// Returns if the panic should be handled as usual
+pub fn on_panic() {
+    if let Some(teardown) = TEARDOWN.try_get() {
+        // the platform code panicked, there is nothing more to try
+        if FINISHING.load(Ordering::Relaxed) {
+            return;
+        }
+        let index = NEXT_HOOK.load(Ordering::Relaxed).saturating_sub(1);
+        let name = teardown.hook(index).map_or("", |hook| hook.name);
+        println!("Shutdown hook `{name}` panicked, continuing with the rest");
+        continue_teardown();
+    }
+    if PANIC_REBOOT.load(Ordering::Relaxed) {
+        reboot(Reason::Panic);
+    }
+}
+
+fn debug_exit(code: u8) {
+    if DEBUG_EXIT.load(Ordering::Relaxed) {
+        println!("debug exit: {code:#04x}");
+        // SAFETY: the device exits QEMU, without it nothing is on the port
+        unsafe { cpu::io_out(DEBUG_EXIT_PORT, code as u32) };
+    }
+}
+
+fn spin_wait() {
+    for _ in 0..RESET_WAIT_SPINS {
+        hint::spin_loop();
+    }
+}
+
+/// Whether the ports that are only on PCs can be written, if [`init`] didn't run, we
+/// can only hope they are there
+fn legacy_ports() -> bool {
+    ACPI_POWER.try_get().is_none_or(|power| power.legacy_ports)
+}
+
+fn halt_forever() -> ! {
+    loop {
+        // SAFETY: interrupts are disabled, so this never returns
+        unsafe { cpu::halt() };
+        hint::spin_loop();
+    }
+}
+
+/// Switches to ACPI mode if the firmware didn't, `SLP_EN` does nothing in legacy mode
+fn acpi_enable(power: &AcpiPower, pm1a: u16) {
+    let Some((port, command)) = power.enable_command else {
+        return;
+    };
+    // SAFETY: the ports are from the FADT
+    unsafe {
+        if cpu::io_in::<u16>(pm1a) & PM1_CONTROL_SCI_EN != 0 {
+            return;
+        }
+        cpu::io_out(port, command);
+        for _ in 0..ACPI_ENABLE_MAX_POLLS {
+            if cpu::io_in::<u16>(pm1a) & PM1_CONTROL_SCI_EN != 0 {
+                return;
+            }
+            hint::spin_loop();
+        }
+    }
+    println!("WARNING: the firmware didn't switch to ACPI mode");
+}
+
+/// The value of the sleep control register that enters `sleep_type`
+fn sleep_control_value(sleep_type: u16) -> u8 {
+    ((sleep_type as u8) << SLEEP_CONTROL_SLP_TYP_SHIFT) & SLEEP_CONTROL_SLP_TYP_MASK
+        | SLEEP_CONTROL_SLP_EN
+}
+
+fn acpi_power_off(power: &AcpiPower) {
+    let Some((type_a, type_b)) = power.sleep_types else {
+        return;
+    };
+    let (pm1a, pm1b) = match power.sleep {
+        SleepMethod::Pm1Control(pm1a, pm1b) if pm1a != 0 => (pm1a, pm1b),
+        SleepMethod::SleepRegisters { control, status } => {
+            // SAFETY: the registers are from the FADT, clearing `WAK_STS` then writing
+            // `SLP_EN` powers off
+            unsafe {
+                status.write(SLEEP_STATUS_WAK_STS as u64);
+                control.write(sleep_control_value(type_a) as u64);
+            }
+            spin_wait();
+            return;
+        }
+        _ => return,
+    };
+    acpi_enable(power, pm1a);
+    let sleep = |port: u16, sleep_type: u16| {
+        // SAFETY: the ports are from the FADT, writing `SLP_EN` powers off
+        unsafe {
+            let control = cpu::io_in::<u16>(port) & !PM1_CONTROL_SLP_TYP_MASK;
+            cpu::io_out(
+                port,
+                control | sleep_type << PM1_CONTROL_SLP_TYP_SHIFT | PM1_CONTROL_SLP_EN,
+            );
+        }
+    };
+    // the `b` block must be written first, the `a` one ends the sleep sequence
+    if pm1b != 0 {
+        sleep(pm1b, type_b);
+    }
+    sleep(pm1a, type_a);
+    spin_wait();
+}
+
+fn power_off(reason: Reason) -> ! {
+    debug_exit(debug_exit_code(Action::Shutdown, reason));
+    if let Some(power) = ACPI_POWER.try_get() {
+        acpi_power_off(power);
+    }
+    if legacy_ports() {
+        for (port, value) in EMULATOR_POWER_OFF_PORTS {
+            // SAFETY: on real hardware, nothing is on these ports
+            unsafe { cpu::io_out(port, value) };
+        }
+        spin_wait();
+    }
+    println!("Could not power off, halting");
+    halt_forever()
+}
+
+fn reset(reason: Reason) -> ! {
+    debug_exit(debug_exit_code(Action::Reboot, reason));
+    if let Some((register, value)) = ACPI_POWER.try_get().and_then(|power| power.reset) {
+        // SAFETY: the register is from the FADT, and resets the machine
+        unsafe { register.write(value as u64) };
+        spin_wait();
+    }
+    if legacy_ports() {
+        // SAFETY: pulsing the reset line of the keyboard controller resets the CPU
+        unsafe { cpu::io_out(KEYBOARD_STATUS_PORT, KEYBOARD_RESET_COMMAND) };
+        spin_wait();
+    }
+    // didn't work, cause a triple fault with an empty IDT
+    let empty_idt = [0u64; 2];
+    // SAFETY: we want to reset
+    unsafe {
+        core::arch::asm!("lidt [{}]", "int3", in(reg) empty_idt.as_ptr(), options(noreturn));
+    }
+}
+
+/// `SLP_TYPa` and `SLP_TYPb` of the `\_S5_` package
+fn s5_sleep_types(dsdt: &Dsdt) -> Option<(u16, u16)> {
+    let package = match Namespace::new(dsdt.aml_code()).evaluate("\\_S5_", Vec::new()) {
+        Ok(AmlValue::Package(package)) => package,
+        Ok(other) => {
+            println!("WARNING: \\_S5_ is not a package: {other:?}");
+            return None;
+        }
+        Err(e) => {
+            println!("WARNING: could not evaluate \\_S5_: {e:?}");
+            return None;
+        }
+    };
+    let sleep_type = |index: usize| match package.get(index) {
+        Some(AmlValue::Integer(value)) => Some(*value as u16 & 0x7),
+        _ => None,
+    };
+    // some firmware put both in the first one, a byte each
+    match (sleep_type(0), sleep_type(1)) {
+        (Some(a), Some(b)) => Some((a, b)),
+        (Some(a), None) => Some((a, a)),
+        _ => None,
+    }
+}
+
+/// `/devices/power`, writing `shutdown` or `reboot` to it does that, for the privileged
+/// processes. Reading it gives the hooks in the order they run and the mechanisms found
+#[derive(Debug)]
+struct PowerDevice;
+
+impl Device for PowerDevice {
+    fn name(&self) -> &str {
+        "power"
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<u64, FileSystemError> {
+        let hooks = HOOKS
+            .lock()
+            .iter()
+            .rev()
+            .map(|hook| hook.name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let power = ACPI_POWER.try_get();
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        let info = format!(
+            "hooks: {hooks}\nacpi_s5: {}\nreset_register: {}\nhw_reduced: {}\ndebug_exit: {}\n\
+             panic_reboot: {}\n",
+            yes_no(power.is_some_and(|power| {
+                power.sleep_types.is_some() && power.sleep != SleepMethod::None
+            })),
+            yes_no(power.is_some_and(|power| power.reset.is_some())),
+            yes_no(power.is_some_and(|power| !power.legacy_ports)),
+            yes_no(DEBUG_EXIT.load(Ordering::Relaxed)),
+            yes_no(PANIC_REBOOT.load(Ordering::Relaxed)),
+        );
+        Ok(devices::read_bytes(info.as_bytes(), offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<u64, FileSystemError> {
+        let command = core::str::from_utf8(buf)
+            .map_err(|_| FileSystemError::InvalidData)?
+            .trim();
+        let action = match command {
+            "shutdown" => Action::Shutdown,
+            "reboot" => Action::Reboot,
+            _ => return Err(FileSystemError::InvalidData),
+        };
+        if !with_current_process(|process| process.is_privileged()) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        teardown(action, Reason::Requested)
+    }
+}
+
+/// The PM1 control blocks, or the sleep registers on reduced hardware
+fn sleep_method(facp: &Facp) -> SleepMethod<GenericAddress> {
+    if facp.is_hw_reduced() {
+        return match facp.sleep_registers() {
+            Some((control, status)) => SleepMethod::SleepRegisters { control, status },
+            None => SleepMethod::None,
+        };
+    }
+    match facp.pm1_control_ports() {
+        (0, _) => SleepMethod::None,
+        (pm1a, pm1b) => SleepMethod::Pm1Control(pm1a, pm1b),
+    }
+}
+
+/// Finds the ACPI power off and reset registers, and adds `/devices/power`
+pub fn init(bios_tables: &BiosTables) {
+    let facp = bios_tables.rsdt.get_table::<Facp>();
+    // mapped now, powering off could happen in a panic, without the heap
+    let sleep = match facp.map_or(SleepMethod::None, sleep_method) {
+        SleepMethod::Pm1Control(pm1a, pm1b) => SleepMethod::Pm1Control(pm1a, pm1b),
+        SleepMethod::SleepRegisters { control, status } => {
+            match (control.register(), status.register()) {
+                (Some(control), Some(status)) => SleepMethod::SleepRegisters { control, status },
+                _ => {
+                    println!("WARNING: unsupported sleep registers {control:?} {status:?}");
+                    SleepMethod::None
+                }
+            }
+        }
+        SleepMethod::None => SleepMethod::None,
+    };
+    let reset = facp
+        .and_then(Facp::reset_register)
+        .and_then(|(register, value)| match register.register() {
+            Some(mapped) => Some((mapped, value)),
+            None => {
+                println!("WARNING: unsupported reset register {register:?}");
+                None
+            }
+        });
+    let power = AcpiPower {
+        sleep,
+        sleep_types: bios_tables
+            .rsdt
+            .get_table::<Dsdt>()
+            .and_then(s5_sleep_types),
+        enable_command: facp.and_then(Facp::acpi_enable_command),
+        reset,
+        legacy_ports: !facp.is_some_and(Facp::is_hw_reduced),
+    };
+    let s5 = power.sleep_types.is_some();
+    println!(
+        "Power{}: {}, reset: {}",
+        if power.legacy_ports {
+            ""
+        } else {
+            " (hardware-reduced ACPI)"
+        },
+        match power.sleep {
+            SleepMethod::Pm1Control(..) if s5 => "ACPI S5",
+            SleepMethod::SleepRegisters { .. } if s5 => "ACPI S5 sleep registers",
+            _ if power.legacy_ports => "emulator ports",
+            _ => "none",
+        },
+        match power.reset {
+            Some(_) => "reset register",
+            None if power.legacy_ports => "keyboard controller",
+            None => "triple fault",
+        },
+    );
+    if ACPI_POWER.set(power).is_err() {
+        panic!("Power already initialized");
+    }
+    devices::register_device(Arc::new(PowerDevice));
+}
+
+/// The order the hooks of [`selftest_shutdown`] ran in, a digit for each
+static SELFTEST_ORDER: Mutex<String> = Mutex::new(String::new());
+static SELFTEST_RUNS: AtomicU8 = AtomicU8::new(0);
+
+fn selftest_record(digit: char) {
+    SELFTEST_RUNS.fetch_add(1, Ordering::Relaxed);
+    SELFTEST_ORDER.lock().push(digit);
+}
+
+/// The exit codes are different for every action and reason, and the hooks are kept in
+/// the order they are registered
+pub fn run_self_tests() {
+    println!("Running system self tests...");
+    let mut codes = Vec::new();
+    for action in [Action::Shutdown, Action::Reboot] {
+        for reason in Reason::ALL {
+            let code = debug_exit_code(action, reason);
+            // the host gets `code * 2 + 1` as a byte
+            assert!(code < 0x80 && code != DEBUG_EXIT_SELFTEST_FAILED);
+            codes.push(code);
+        }
+    }
+    codes.sort_unstable();
+    codes.dedup();
+    assert_eq!(codes.len(), 2 * Reason::ALL.len());
+    assert_eq!(
+        debug_exit_code(Action::Shutdown, Reason::Requested) * 2 + 1,
+        33
+    );
+    assert_eq!(
+        debug_exit_code(Action::Reboot, Reason::Watchdog) * 2 + 1,
+        67
+    );
+    assert!(Reason::Requested.runs_hooks() && Reason::Watchdog.runs_hooks());
+    assert!(!Reason::Panic.runs_hooks() && !Reason::Debugger.runs_hooks());
+
+    // `microvm` only has the sleep registers, `pc` the PM1 blocks
+    let microvm = selftest_fadt(true);
+    let (control, status) = microvm.sleep_registers().unwrap();
+    assert_eq!(
+        sleep_method(&microvm),
+        SleepMethod::SleepRegisters { control, status }
+    );
+    assert_eq!(
+        sleep_method(&selftest_fadt(false)),
+        SleepMethod::Pm1Control(0x604, 0)
+    );
+    // QEMU's generic event device powers off with `SLP_TYP` 5
+    assert_eq!(sleep_control_value(5), 0x34);
+    assert_eq!(sleep_control_value(0xF), 0x3C);
+
+    // registered while initializing, the console first, then the block devices and the
+    // filesystems on them
+    let registered = HOOKS
+        .lock()
+        .iter()
+        .map(|hook| hook.name)
+        .collect::<Vec<_>>();
+    let position = |name: &str| {
+        registered
+            .iter()
+            .position(|&hook| hook == name)
+            .unwrap_or_else(|| panic!("system self test: no shutdown hook `{name}`"))
+    };
+    assert!(position("console") < position("block devices"));
+    assert!(position("block devices") < position("fs sync"));
+
+    // the same order `continue_teardown` goes through
+    let teardown = Teardown {
+        action: Action::Shutdown,
+        reason: Reason::SelfTest,
+        hooks: vec![
+            Hook {
+                name: "1",
+                run: || selftest_record('1'),
+            },
+            Hook {
+                name: "2",
+                run: || selftest_record('2'),
+            },
+        ],
+    };
+    let mut index = 0;
+    while let Some(hook) = teardown.hook(index) {
+        (hook.run)();
+        index += 1;
+    }
+    assert_eq!(SELFTEST_ORDER.lock().as_str(), "21");
+    SELFTEST_ORDER.lock().clear();
+    SELFTEST_RUNS.store(0, Ordering::Relaxed);
+    println!("System self tests passed");
+}
+
+/// Shuts down through three more hooks, the middle one panics, and the first registered
+/// (so the last to run) checks that the others ran in reverse, exiting with
+/// [`DEBUG_EXIT_SELFTEST_FAILED`] if not
+pub fn selftest_shutdown() -> ! {
+    println!("Running the shutdown self test...");
+    on_shutdown("selftest check", || {
+        let order = SELFTEST_ORDER.lock().clone();
+        if order == "32" && SELFTEST_RUNS.load(Ordering::Relaxed) == 2 {
+            println!("Shutdown self test passed");
+        } else {
+            println!("Shutdown self test failed: the hooks ran as `{order}`, expected `32`");
+            debug_exit(DEBUG_EXIT_SELFTEST_FAILED);
+        }
+    });
+    on_shutdown("selftest 2", || {
+        selftest_record('2');
+    });
+    on_shutdown("selftest panic", || {
+        panic!("shutdown self test: panicking in a hook");
+    });
+    on_shutdown("selftest 3", || {
+        selftest_record('3');
+    });
+    shutdown(Reason::SelfTest)
+}
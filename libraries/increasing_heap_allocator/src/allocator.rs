@@ -29,6 +29,8 @@ pub struct HeapAllocator<const PAGE_SIZE: usize, T: PageAllocatorProvider<PAGE_S
     free_list_addr: *mut HeapFreeBlock,
     free_size: usize,
     used_size: usize,
+    alloc_count: u64,
+    dealloc_count: u64,
     page_allocator: T,
 }
 
@@ -308,15 +310,27 @@ where
             total_heap_size: 0,
             free_size: 0,
             used_size: 0,
+            alloc_count: 0,
+            dealloc_count: 0,
             page_allocator,
         }
     }
 
+    /// Walks the free list for the blocks, so it takes longer the more fragmented the heap is
     pub fn stats(&self) -> HeapStats {
+        let (free_blocks, largest_free_block) = self
+            .iter_free_blocks()
+            .fold((0, 0), |(count, largest), block| {
+                (count + 1, largest.max(block.size))
+            });
         HeapStats {
             allocated: self.used_size,
             free_size: self.free_size,
             heap_size: self.total_heap_size,
+            alloc_count: self.alloc_count,
+            dealloc_count: self.dealloc_count,
+            free_blocks,
+            largest_free_block,
         }
     }
 
@@ -393,6 +407,7 @@ where
         }
         self.free_size -= this_allocation_size;
         self.used_size += this_allocation_size;
+        self.alloc_count += 1;
 
         // TODO: add flag to control when to enable this runtime checking
         if self.check_issues() {
@@ -455,6 +470,7 @@ where
         self.free_block(freeing_block, this_allocation_size);
         self.used_size -= this_allocation_size;
         self.free_size += this_allocation_size;
+        self.dealloc_count += 1;
 
         // TODO: add flag to control when to enable this runtime checking
         if self.check_issues() {
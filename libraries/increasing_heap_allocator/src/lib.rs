@@ -18,6 +18,13 @@ pub struct HeapStats {
     pub allocated: usize,
     pub free_size: usize,
     pub heap_size: usize,
+    /// The calls to `alloc` and `dealloc` since the heap was made
+    pub alloc_count: u64,
+    pub dealloc_count: u64,
+    /// The blocks in the free list, and the size of the biggest, the most that can be allocated
+    /// without asking for more pages
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
 }
 
 pub trait PageAllocatorProvider<const PAGE_SIZE: usize> {
@@ -8,6 +8,13 @@ use alloc::{
     vec::Vec,
 };
 
+mod namespace;
+
+pub use namespace::{
+    interrupt_resources, AmlEvalError, AmlValue, FoundTerm, InterruptResource, Namespace,
+    PathPattern,
+};
+
 #[derive(Debug, Clone)]
 pub enum AmlParseError {
     UnexpectedEndOfCode,
@@ -139,7 +146,11 @@ pub enum Target {
     Debug,
     DerefOf(TermArg),
     RefOf(Box<Target>),
+    /// The target and the reference may be another `Index`, or a `DerefOf` of one, for
+    /// nested element references, i.e. `Index(DerefOf(Index(PKG0, 1)), 2, Local0)`
     Index(TermArg, TermArg, Box<Target>),
+    /// A method returning the reference to store into
+    MethodCall(String, Vec<TermArg>),
 }
 
 #[derive(Debug, Clone)]
@@ -372,7 +383,7 @@ impl<'a> State<'a> {
     }
 
     /// Renamed to not be confused with `Clone::clone`
-    fn clone_state(&mut self) -> State {
+    fn clone_state(&mut self) -> State<'_> {
         State {
             methods: self.methods,
             names: self.names,
@@ -446,7 +457,9 @@ impl Parser<'_> {
         let mut length: usize;
         if following_bytes == 0 {
             // subtract the bytes used for the length
-            return Ok((lead_byte & 0b0011_1111) as usize - 1);
+            return ((lead_byte & 0b0011_1111) as usize)
+                .checked_sub(1)
+                .ok_or(AmlParseError::InvalidPkgLengthLead);
         } else {
             // bits 4-5 must be zero
             if (lead_byte >> 4) & 0b11 != 0 {
@@ -462,12 +475,17 @@ impl Parser<'_> {
             eprintln!("len now: {:x}", length);
         }
         // subtract the bytes used for the length
-        Ok(length - following_bytes as usize - 1)
+        length
+            .checked_sub(following_bytes as usize + 1)
+            .ok_or(AmlParseError::InvalidPkgLengthLead)
     }
 
-    fn get_inner_parser(&mut self) -> Result<Parser, AmlParseError> {
+    fn get_inner_parser(&mut self) -> Result<Parser<'_>, AmlParseError> {
         let pkg_length = self.get_pkg_length()?;
         eprintln!("inner pkg length: {:x}", pkg_length);
+        if pkg_length > self.remaining_bytes() {
+            return Err(AmlParseError::UnexpectedEndOfCode);
+        }
 
         let inner_parser = Parser {
             code: &self.code[self.pos..self.pos + pkg_length],
@@ -479,7 +497,7 @@ impl Parser<'_> {
     }
 
     /// Renamed to not be confused with `Clone::clone`
-    fn clone_parser(&mut self) -> Parser {
+    fn clone_parser(&mut self) -> Parser<'_> {
         Parser {
             code: self.code,
             pos: self.pos,
@@ -1089,10 +1107,6 @@ impl Parser<'_> {
                 assert!(next_byte == 0x31);
                 Ok(Target::Debug)
             }
-            0x71 => {
-                // typeref opcode
-                panic!("typeref opcode")
-            }
             _ => {
                 if let Some(local) = self.try_parse_local(lead_byte)? {
                     self.forward(1)?;
@@ -1101,8 +1115,18 @@ impl Parser<'_> {
                     self.forward(1)?;
                     Ok(Target::Arg(arg))
                 } else if let Some(name) = self.try_parse_name()? {
-                    self.state.names.insert(name.clone());
-                    Ok(Target::Name(name))
+                    // the arguments must be consumed here, or we lose track of where the
+                    // next term starts
+                    if let Some(n_args) = self.state.find_method(&name) {
+                        let mut args = Vec::new();
+                        for _ in 0..n_args {
+                            args.push(self.parse_term_arg_for_method_arg()?);
+                        }
+                        Ok(Target::MethodCall(name, args))
+                    } else {
+                        self.state.names.insert(name.clone());
+                        Ok(Target::Name(name))
+                    }
                 } else {
                     self.forward(1)?;
                     if let Some(term) =
@@ -1241,9 +1265,33 @@ fn display_target(target: &Target, f: &mut fmt::Formatter<'_>, depth: usize) ->
             write!(f, ")")
         }
         Target::Index(term_arg1, term_arg2, target) => {
-            display_index(term_arg1, term_arg2, target, f, depth)
+            // keep the inner store grouped, so it doesn't look like a chain of stores
+            if matches!(target.as_ref(), Target::None) {
+                display_index(term_arg1, term_arg2, target, f, depth)
+            } else {
+                write!(f, "(")?;
+                display_index(term_arg1, term_arg2, target, f, depth)?;
+                write!(f, ")")
+            }
+        }
+        Target::MethodCall(name, args) => display_method_call(name, args, f, depth),
+    }
+}
+
+fn display_method_call(
+    name: &str,
+    args: &[TermArg],
+    f: &mut fmt::Formatter<'_>,
+    depth: usize,
+) -> fmt::Result {
+    write!(f, "{} (", name)?;
+    for (i, arg) in args.iter().enumerate() {
+        display_term_arg(arg, f, depth)?;
+        if i != args.len() - 1 {
+            write!(f, ", ")?;
         }
     }
+    write!(f, ")")
 }
 
 fn display_call_term_target(
@@ -1689,14 +1737,7 @@ fn display_term(term: &AmlTerm, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt
             )?;
         }
         AmlTerm::MethodCall(name, args) => {
-            write!(f, "{} (", name)?;
-            for (i, arg) in args.iter().enumerate() {
-                display_term_arg(arg, f, depth)?;
-                if i != args.len() - 1 {
-                    write!(f, ", ")?;
-                }
-            }
-            write!(f, ")")?;
+            display_method_call(name, args, f, depth)?;
         }
         AmlTerm::Concat(term1, term2, target) => {
             display_call_term_target("Concat", &[term1, term2], &[target], f, depth)?;
@@ -1726,9 +1767,199 @@ impl fmt::Display for AmlCode {
     }
 }
 
+/// A term as in the display of [`AmlCode`] at the root, without the newline after it
+impl fmt::Display for AmlTerm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_term(self, f, 0)
+    }
+}
+
 impl AmlCode {
+    /// The terms at the root of the table, its display is each of them in a line
+    pub fn terms(&self) -> &[AmlTerm] {
+        &self.term_list
+    }
+
     #[allow(dead_code)]
     pub fn display_with_depth(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
         display_terms(&self.term_list, f, depth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `f` on a parser of `code`, with no known names
+    fn with_parser<T>(code: &[u8], f: impl FnOnce(&mut Parser) -> T) -> T {
+        let mut methods = BTreeMap::new();
+        let mut names = BTreeSet::new();
+        let mut parser = Parser {
+            code,
+            pos: 0,
+            state: State::new(&mut methods, &mut names),
+        };
+        f(&mut parser)
+    }
+
+    fn pkg_length(code: &[u8]) -> Result<usize, AmlParseError> {
+        with_parser(code, |parser| parser.get_pkg_length())
+    }
+
+    fn name(code: &[u8]) -> String {
+        with_parser(code, |parser| {
+            let name = parser.parse_name().unwrap();
+            assert_eq!(parser.remaining_bytes(), 0);
+            name
+        })
+    }
+
+    #[test]
+    fn pkg_lengths() {
+        // the length includes the bytes of the length itself
+        assert_eq!(pkg_length(&[0x05]).unwrap(), 4);
+        assert_eq!(pkg_length(&[0x3F]).unwrap(), 0x3E);
+        // the low 4 bits of the lead, then the following bytes
+        assert_eq!(pkg_length(&[0x41, 0x02]).unwrap(), 0x21 - 2);
+        assert_eq!(pkg_length(&[0x82, 0x34, 0x01]).unwrap(), 0x1342 - 3);
+        assert_eq!(pkg_length(&[0xC0, 0, 0, 0x01]).unwrap(), 0x10_0000 - 4);
+    }
+
+    #[test]
+    fn invalid_pkg_lengths() {
+        // bits 4-5 set with following bytes
+        assert!(matches!(
+            pkg_length(&[0x70, 0]),
+            Err(AmlParseError::InvalidPkgLengthLead)
+        ));
+        // shorter than the length itself
+        assert!(matches!(
+            pkg_length(&[0x00]),
+            Err(AmlParseError::InvalidPkgLengthLead)
+        ));
+        assert!(matches!(
+            pkg_length(&[0x41]),
+            Err(AmlParseError::UnexpectedEndOfCode)
+        ));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(name(b"ABCD"), "ABCD");
+        assert_eq!(name(b"_SB_"), "_SB_");
+        assert_eq!(name(b"\\_SB_"), "\\_SB_");
+        assert_eq!(name(b"^^PCI0"), "^^PCI0");
+        assert_eq!(name(b".PCI0_PRT"), "PCI0._PRT");
+        assert_eq!(name(b"\\/\x03_SB_PCI0_PRT"), "\\_SB_.PCI0._PRT");
+        assert_eq!(name(b"\0"), "");
+    }
+
+    #[test]
+    fn data_objects() {
+        let arg = |code: &[u8]| with_parser(code, |parser| parser.parse_term_arg().unwrap());
+        assert!(matches!(
+            arg(&[0x00]),
+            TermArg::DataObject(DataObject::ConstZero)
+        ));
+        assert!(matches!(
+            arg(&[0xFF]),
+            TermArg::DataObject(DataObject::ConstOnes)
+        ));
+        assert!(matches!(
+            arg(&[0x0A, 0x12]),
+            TermArg::DataObject(DataObject::ByteConst(0x12))
+        ));
+        assert!(matches!(
+            arg(&[0x0B, 0x34, 0x12]),
+            TermArg::DataObject(DataObject::WordConst(0x1234))
+        ));
+        assert!(matches!(
+            arg(&[0x0C, 0x78, 0x56, 0x34, 0x12]),
+            TermArg::DataObject(DataObject::DWordConst(0x1234_5678))
+        ));
+        assert!(matches!(arg(&[0x62]), TermArg::Local(2)));
+        assert!(matches!(arg(&[0x69]), TermArg::Arg(1)));
+    }
+
+    #[test]
+    fn name_and_string() {
+        // Name (ABCD, 0x05), Name (STR0, "hi")
+        let code = parse_aml(b"\x08ABCD\x0A\x05\x08STR0\x0Dhi\0").unwrap();
+        match code.terms() {
+            [AmlTerm::NameObj(a, TermArg::DataObject(DataObject::ByteConst(5))), AmlTerm::NameObj(b, TermArg::Expression(string))] =>
+            {
+                assert_eq!(a, "ABCD");
+                assert_eq!(b, "STR0");
+                assert!(matches!(string.as_ref(), AmlTerm::String(s) if s == "hi"));
+            }
+            terms => panic!("parsed {terms:?}"),
+        }
+    }
+
+    #[test]
+    fn scope_and_method() {
+        // Scope (\_SB) { Method (MTH0, 1) { Return (Add (Arg0, One)) } }
+        let method = b"\x14\x0BMTH0\x01\xA4\x72\x68\x01\x00";
+        let mut code = vec![0x10, 6 + method.len() as u8];
+        code.extend_from_slice(b"\\_SB_");
+        code.extend_from_slice(method);
+        let code = parse_aml(&code).unwrap();
+        let [AmlTerm::Scope(scope)] = code.terms() else {
+            panic!("parsed {:?}", code.terms());
+        };
+        assert_eq!(scope.name, "\\_SB_");
+        let [AmlTerm::Method(method)] = scope.term_list.as_slice() else {
+            panic!("parsed {:?}", scope.term_list);
+        };
+        assert_eq!(method.name, "MTH0");
+        assert_eq!(method.arg_count(), 1);
+
+        let mut namespace = Namespace::new(&code);
+        assert!(namespace.contains("\\_SB_.MTH0"));
+        assert_eq!(
+            namespace
+                .evaluate("\\_SB_.MTH0", vec![AmlValue::Integer(41)])
+                .unwrap(),
+            AmlValue::Integer(42)
+        );
+    }
+
+    #[test]
+    fn method_calls_take_their_args() {
+        // Method (MTH1, 2) { Return (Arg1) }, Name (RES0, MTH1 (One, 0x07))
+        let code = parse_aml(b"\x14\x08MTH1\x02\xA4\x69\x08RES0MTH1\x01\x0A\x07").unwrap();
+        let [_, AmlTerm::NameObj(name, TermArg::Expression(call))] = code.terms() else {
+            panic!("parsed {:?}", code.terms());
+        };
+        assert_eq!(name, "RES0");
+        assert!(
+            matches!(call.as_ref(), AmlTerm::MethodCall(m, args) if m == "MTH1" && args.len() == 2)
+        );
+    }
+
+    #[test]
+    fn truncated_code() {
+        for code in [
+            &b"\x08AB"[..],
+            b"\x08ABCD",
+            b"\x08ABCD\x0B\x01",
+            b"\x0Dno end",
+            // the scope is longer than the code
+            b"\x10\x10\\_SB_",
+            // the method ends before its flags
+            b"\x14\x05MTH0",
+        ] {
+            assert!(
+                matches!(parse_aml(code), Err(AmlParseError::UnexpectedEndOfCode)),
+                "{code:x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn display() {
+        let code = parse_aml(b"\x08ABCD\x0A\x05").unwrap();
+        let text = format!("{code}");
+        assert!(text.contains("Name(ABCD, 0x05)"), "{text}");
+    }
+}
@@ -0,0 +1,1935 @@
+//! The ACPI namespace built from the parsed AML, and a small interpreter to evaluate its objects
+//!
+//! This only handles what is needed to read the configuration objects the kernel uses, like
+//! `_PRT` and `_CRS`: integers, strings, buffers, packages, the control flow, the arithmetic and
+//! logical operators and method calls. Anything touching the hardware, like the operation
+//! regions and their fields, fails with [`AmlEvalError::Unsupported`].
+//!
+//! The interrupts in the resource templates returned by `_CRS` are read with
+//! [`interrupt_resources`].
+//!
+//! [`Namespace::find_terms`] finds the terms defining the objects matching a [`PathPattern`],
+//! with the paths resolved the same way as when loading, to show a part of the tables.
+
+use core::fmt;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use super::{AmlCode, AmlTerm, DataObject, MethodObj, Target, TermArg};
+
+/// How deep the method calls can go before giving up, to not overflow the kernel stack
+const MAX_CALL_DEPTH: usize = 32;
+/// How many times a `While` can loop before giving up
+const MAX_LOOP_ITERATIONS: usize = 0x10000;
+
+const ONES: u64 = u64::MAX;
+
+#[derive(Debug, Clone)]
+pub enum AmlEvalError {
+    NotFound(String),
+    /// A term the interpreter doesn't handle, i.e. an operation region access
+    Unsupported(&'static str),
+    InvalidType(&'static str),
+    IndexOutOfBounds,
+    WrongArgCount,
+    TooDeep,
+    TooManyIterations,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmlValue {
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    /// The absolute path of a named object, the names inside a package are kept as references
+    Reference(String),
+}
+
+impl AmlValue {
+    pub fn as_integer(&self) -> Result<u64, AmlEvalError> {
+        match self {
+            AmlValue::Integer(i) => Ok(*i),
+            AmlValue::Buffer(b) => {
+                let mut bytes = [0; 8];
+                let len = b.len().min(8);
+                bytes[..len].copy_from_slice(&b[..len]);
+                Ok(u64::from_le_bytes(bytes))
+            }
+            _ => Err(AmlEvalError::InvalidType("integer")),
+        }
+    }
+}
+
+/// As in ASL, with the packages in a single line
+impl fmt::Display for AmlValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmlValue::Integer(i) => write!(f, "0x{i:X}"),
+            AmlValue::String(s) => write!(f, "\"{s}\""),
+            AmlValue::Buffer(bytes) => {
+                write!(f, "Buffer ({}) {{", bytes.len())?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    write!(f, "{separator} 0x{byte:02X}")?;
+                }
+                f.write_str(" }")
+            }
+            AmlValue::Package(elements) => {
+                write!(f, "Package ({}) {{", elements.len())?;
+                for (i, element) in elements.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    write!(f, "{separator} {element}")?;
+                }
+                f.write_str(" }")
+            }
+            AmlValue::Reference(path) => f.write_str(path),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum NamedObject {
+    /// `Scope`s, `Processor`s and `PowerResource`s
+    Scope,
+    Device,
+    Value(AmlValue),
+    /// A `Name` evaluated the first time its read, so it can refer to the objects after it
+    Unevaluated(TermArg),
+    Method(MethodObj),
+    Alias(String),
+    /// The objects that can't be evaluated here, i.e. operation regions and fields
+    Other(&'static str),
+}
+
+enum Flow {
+    Next,
+    Return(AmlValue),
+    Break,
+}
+
+struct Frame {
+    /// The path of the running method, the names inside it are looked up from there
+    scope: String,
+    args: Vec<AmlValue>,
+    locals: [AmlValue; 8],
+}
+
+impl Frame {
+    fn new(scope: String, args: Vec<AmlValue>) -> Self {
+        const ZERO: AmlValue = AmlValue::Integer(0);
+        Self {
+            scope,
+            args,
+            locals: [ZERO; 8],
+        }
+    }
+}
+
+fn join(scope: &str, name: &str) -> String {
+    if scope == "\\" {
+        alloc::format!("\\{name}")
+    } else {
+        alloc::format!("{scope}.{name}")
+    }
+}
+
+fn parent(path: &str) -> Option<&str> {
+    match path.rfind('.') {
+        Some(i) => Some(&path[..i]),
+        None if path == "\\" => None,
+        None => Some("\\"),
+    }
+}
+
+/// The absolute path of `name` when its defined in `scope`, without searching the parents
+fn absolute_path(scope: &str, name: &str) -> String {
+    if let Some(rest) = name.strip_prefix('\\') {
+        return if rest.is_empty() {
+            "\\".to_string()
+        } else {
+            join("\\", rest)
+        };
+    }
+    let rest = name.trim_start_matches('^');
+    let mut scope = scope;
+    for _ in 0..name.len() - rest.len() {
+        scope = parent(scope).unwrap_or("\\");
+    }
+    if rest.is_empty() {
+        scope.to_string()
+    } else {
+        join(scope, rest)
+    }
+}
+
+/// An absolute path where the segments can have `*` (any characters) and `?` (any one),
+/// i.e. `\_SB.PCI0.*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    segments: Vec<String>,
+}
+
+impl PathPattern {
+    /// Parses `pattern`, from the root even without the `\`. The names are uppercased, and
+    /// padded with `_` to 4 characters as in AML, so `\_SB.PCI0` finds `\_SB_.PCI0`.
+    ///
+    /// `None` if a segment is empty, has characters a name can't have, or is longer than a
+    /// name without a wildcard, the root alone is not a pattern either
+    pub fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+        let segments = pattern
+            .split('.')
+            .map(|segment| {
+                let segment = segment.to_ascii_uppercase();
+                let valid = !segment.is_empty()
+                    && segment.bytes().all(|b| {
+                        b.is_ascii_uppercase()
+                            || b.is_ascii_digit()
+                            || matches!(b, b'_' | b'*' | b'?')
+                    });
+                if !valid {
+                    None
+                } else if segment.contains(['*', '?']) {
+                    Some(segment)
+                } else if segment.len() <= 4 {
+                    Some(alloc::format!("{segment:_<4}"))
+                } else {
+                    None
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { segments })
+    }
+
+    /// Whether the absolute `path` matches
+    pub fn matches(&self, path: &str) -> bool {
+        let mut segments = path_segments(path);
+        self.segments.iter().all(|pattern| {
+            segments
+                .next()
+                .is_some_and(|name| glob_matches(pattern.as_bytes(), name.as_bytes()))
+        }) && segments.next().is_none()
+    }
+
+    /// Whether the objects inside `path` can match, `path` being shorter and matching the
+    /// start of the pattern
+    fn matches_inside(&self, path: &str) -> bool {
+        let mut patterns = self.segments.iter();
+        path_segments(path).all(|name| {
+            patterns
+                .next()
+                .is_some_and(|pattern| glob_matches(pattern.as_bytes(), name.as_bytes()))
+        }) && patterns.next().is_some()
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\{}", self.segments.join("."))
+    }
+}
+
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('\\')
+        .unwrap_or(path)
+        .split('.')
+        .filter(|segment| !segment.is_empty())
+}
+
+/// `*` is any run of characters, `?` any one
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_matches(rest, name) || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => glob_matches(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) => p == n && glob_matches(rest, name_rest),
+        _ => false,
+    }
+}
+
+/// A term defining an object that matched a [`PathPattern`]
+#[derive(Debug, Clone)]
+pub struct FoundTerm<'a> {
+    pub path: String,
+    pub term: &'a AmlTerm,
+    /// The absolute paths of the `Name`s it defines, itself or inside it, not counting the
+    /// ones in methods
+    pub names: Vec<String>,
+}
+
+/// All the named objects of the DSDT and SSDTs
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    objects: BTreeMap<String, NamedObject>,
+}
+
+impl Namespace {
+    pub fn new(code: &AmlCode) -> Self {
+        let mut namespace = Self {
+            objects: BTreeMap::new(),
+        };
+        namespace
+            .objects
+            .insert("\\".to_string(), NamedObject::Scope);
+        namespace.load(code);
+        namespace
+    }
+
+    /// Adds the objects of another table, i.e. an SSDT
+    pub fn load(&mut self, code: &AmlCode) {
+        self.load_terms(&code.term_list, "\\");
+    }
+
+    fn load_terms(&mut self, terms: &[AmlTerm], scope: &str) {
+        for term in terms {
+            match term {
+                AmlTerm::Scope(s) => {
+                    let path = self
+                        .resolve(scope, &s.name)
+                        .unwrap_or_else(|| absolute_path(scope, &s.name));
+                    self.objects
+                        .entry(path.clone())
+                        .or_insert(NamedObject::Scope);
+                    self.load_terms(&s.term_list, &path);
+                }
+                AmlTerm::Device(s) => {
+                    let path = absolute_path(scope, &s.name);
+                    self.objects.insert(path.clone(), NamedObject::Device);
+                    self.load_terms(&s.term_list, &path);
+                }
+                AmlTerm::Processor(p) => {
+                    let path = absolute_path(scope, &p.name);
+                    self.objects.insert(path.clone(), NamedObject::Scope);
+                    self.load_terms(&p.term_list, &path);
+                }
+                AmlTerm::PowerResource(p) => {
+                    let path = absolute_path(scope, &p.name);
+                    self.objects.insert(path.clone(), NamedObject::Scope);
+                    self.load_terms(&p.term_list, &path);
+                }
+                AmlTerm::Method(m) => {
+                    let path = absolute_path(scope, &m.name);
+                    self.objects.insert(path, NamedObject::Method(m.clone()));
+                }
+                AmlTerm::NameObj(name, arg) => {
+                    self.objects.insert(
+                        absolute_path(scope, name),
+                        NamedObject::Unevaluated(arg.clone()),
+                    );
+                }
+                AmlTerm::Alias(source, alias) => {
+                    let source = absolute_path(scope, source);
+                    self.objects
+                        .insert(absolute_path(scope, alias), NamedObject::Alias(source));
+                }
+                AmlTerm::Region(r) => {
+                    self.objects
+                        .insert(absolute_path(scope, &r.name), NamedObject::Other("region"));
+                }
+                AmlTerm::Field(f) => self.load_fields(scope, &f.fields),
+                AmlTerm::IndexField(f) => self.load_fields(scope, &f.fields),
+                AmlTerm::Mutex(name, _) | AmlTerm::Event(name) => {
+                    self.objects
+                        .insert(absolute_path(scope, name), NamedObject::Other("sync"));
+                }
+                // code outside methods, i.e. `If` blocks at the top of the table, is not run
+                _ => {}
+            }
+        }
+    }
+
+    fn load_fields(&mut self, scope: &str, fields: &[super::FieldElement]) {
+        for field in fields {
+            if let super::FieldElement::NamedField(name, _) = field {
+                self.objects
+                    .insert(absolute_path(scope, name), NamedObject::Other("field"));
+            }
+        }
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.objects.contains_key(path)
+    }
+
+    /// The paths of all the devices, each one comes before the devices inside it
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.objects
+            .iter()
+            .filter(|(_, object)| matches!(object, NamedObject::Device))
+            .map(|(path, _)| path.as_str())
+    }
+
+    /// The terms of `code` defining the objects that match `pattern`, in the order of the
+    /// code, a `Scope` opened many times is found every time. The terms inside a match are
+    /// part of it, so they are not searched
+    pub fn find_terms<'a>(&self, code: &'a AmlCode, pattern: &PathPattern) -> Vec<FoundTerm<'a>> {
+        let mut found = Vec::new();
+        self.find_in(&code.term_list, "\\", pattern, &mut found);
+        found
+    }
+
+    fn find_in<'a>(
+        &self,
+        terms: &'a [AmlTerm],
+        scope: &str,
+        pattern: &PathPattern,
+        found: &mut Vec<FoundTerm<'a>>,
+    ) {
+        for term in terms {
+            let Some((path, inner)) = self.term_path(scope, term) else {
+                continue;
+            };
+            if pattern.matches(&path) {
+                let mut names = Vec::new();
+                self.names_in(scope, term, &mut names);
+                found.push(FoundTerm { path, term, names });
+            } else if pattern.matches_inside(&path) {
+                self.find_in(inner, &path, pattern, found);
+            }
+        }
+    }
+
+    fn names_in(&self, scope: &str, term: &AmlTerm, names: &mut Vec<String>) {
+        let Some((path, inner)) = self.term_path(scope, term) else {
+            return;
+        };
+        if let AmlTerm::NameObj(..) = term {
+            names.push(path);
+            return;
+        }
+        for term in inner {
+            self.names_in(&path, term, names);
+        }
+    }
+
+    /// The absolute path of the object `term` defines in `scope`, as [`Namespace::load`]
+    /// finds it, and the terms inside it, `None` if it doesn't define one
+    fn term_path<'a>(&self, scope: &str, term: &'a AmlTerm) -> Option<(String, &'a [AmlTerm])> {
+        let (name, inner): (&str, &[AmlTerm]) = match term {
+            AmlTerm::Scope(s) => {
+                let path = self
+                    .resolve(scope, &s.name)
+                    .unwrap_or_else(|| absolute_path(scope, &s.name));
+                return Some((path, &s.term_list));
+            }
+            AmlTerm::Device(s) => (&s.name, &s.term_list),
+            AmlTerm::Processor(p) => (&p.name, &p.term_list),
+            AmlTerm::PowerResource(p) => (&p.name, &p.term_list),
+            AmlTerm::Method(m) => (&m.name, &[]),
+            AmlTerm::NameObj(name, _) => (name, &[]),
+            AmlTerm::Alias(_, alias) => (alias, &[]),
+            AmlTerm::Region(r) => (&r.name, &[]),
+            AmlTerm::Mutex(name, _) | AmlTerm::Event(name) => (name, &[]),
+            _ => return None,
+        };
+        Some((absolute_path(scope, name), inner))
+    }
+
+    /// The absolute path of `name` used in `scope`, single segment names are searched in
+    /// the parents of `scope` too
+    pub fn resolve(&self, scope: &str, name: &str) -> Option<String> {
+        if name.starts_with('\\') || name.starts_with('^') || name.contains('.') {
+            let path = absolute_path(scope, name);
+            return self.objects.contains_key(&path).then_some(path);
+        }
+        let mut scope = Some(scope);
+        while let Some(s) = scope {
+            let path = join(s, name);
+            if self.objects.contains_key(&path) {
+                return Some(path);
+            }
+            scope = parent(s);
+        }
+        None
+    }
+
+    /// Evaluates the object at the absolute `path`, methods are called with `args`
+    pub fn evaluate(&mut self, path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlEvalError> {
+        self.read_object(path, args, 0)
+    }
+
+    fn read_object(
+        &mut self,
+        path: &str,
+        args: Vec<AmlValue>,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        match self.objects.get(path) {
+            None => Err(AmlEvalError::NotFound(path.to_string())),
+            Some(NamedObject::Value(value)) => Ok(value.clone()),
+            Some(NamedObject::Unevaluated(arg)) => {
+                let arg = arg.clone();
+                let scope = parent(path).unwrap_or("\\").to_string();
+                let mut frame = Frame::new(scope, Vec::new());
+                let value = self.eval_package_element(&arg, &mut frame, depth)?;
+                self.objects
+                    .insert(path.to_string(), NamedObject::Value(value.clone()));
+                Ok(value)
+            }
+            Some(NamedObject::Method(method)) => {
+                let method = method.clone();
+                self.invoke(path, &method, args, depth)
+            }
+            // a cycle of aliases would never end
+            Some(NamedObject::Alias(_)) if depth >= MAX_CALL_DEPTH => Err(AmlEvalError::TooDeep),
+            Some(NamedObject::Alias(source)) => {
+                let source = source.clone();
+                self.read_object(&source, args, depth + 1)
+            }
+            Some(NamedObject::Scope | NamedObject::Device) => {
+                Ok(AmlValue::Reference(path.to_string()))
+            }
+            Some(NamedObject::Other(kind)) => Err(AmlEvalError::Unsupported(kind)),
+        }
+    }
+
+    fn invoke(
+        &mut self,
+        path: &str,
+        method: &MethodObj,
+        args: Vec<AmlValue>,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        if depth >= MAX_CALL_DEPTH {
+            return Err(AmlEvalError::TooDeep);
+        }
+        if args.len() != method.arg_count() {
+            return Err(AmlEvalError::WrongArgCount);
+        }
+        let mut frame = Frame::new(path.to_string(), args);
+        match self.exec_terms(&method.term_list, &mut frame, depth + 1)? {
+            Flow::Return(value) => Ok(value),
+            Flow::Next | Flow::Break => Ok(AmlValue::Integer(0)),
+        }
+    }
+
+    fn exec_terms(
+        &mut self,
+        terms: &[AmlTerm],
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<Flow, AmlEvalError> {
+        // whether the `If` right before was taken, for the `Else` after it
+        let mut if_taken = None;
+        for term in terms {
+            let flow = match term {
+                AmlTerm::If(block) => {
+                    let taken = self.eval_integer(&block.predicate, frame, depth)? != 0;
+                    let flow = if taken {
+                        self.exec_terms(&block.term_list, frame, depth)?
+                    } else {
+                        Flow::Next
+                    };
+                    if let Flow::Next = flow {
+                        if_taken = Some(taken);
+                        continue;
+                    }
+                    flow
+                }
+                AmlTerm::Else(body) => match if_taken {
+                    Some(false) => self.exec_terms(body, frame, depth)?,
+                    _ => Flow::Next,
+                },
+                AmlTerm::While(block) => {
+                    let mut iterations = 0;
+                    let mut flow = Flow::Next;
+                    while self.eval_integer(&block.predicate, frame, depth)? != 0 {
+                        iterations += 1;
+                        if iterations > MAX_LOOP_ITERATIONS {
+                            return Err(AmlEvalError::TooManyIterations);
+                        }
+                        match self.exec_terms(&block.term_list, frame, depth)? {
+                            Flow::Next => {}
+                            Flow::Break => break,
+                            f @ Flow::Return(_) => {
+                                flow = f;
+                                break;
+                            }
+                        }
+                    }
+                    flow
+                }
+                AmlTerm::Return(arg) => Flow::Return(self.eval_arg(arg, frame, depth)?),
+                AmlTerm::Break => Flow::Break,
+                AmlTerm::NameObj(name, arg) => {
+                    let value = self.eval_package_element(arg, frame, depth)?;
+                    self.objects
+                        .insert(absolute_path(&frame.scope, name), NamedObject::Value(value));
+                    Flow::Next
+                }
+                term => {
+                    self.eval_term(term, frame, depth)?;
+                    Flow::Next
+                }
+            };
+            if_taken = None;
+            if !matches!(flow, Flow::Next) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn eval_integer(
+        &mut self,
+        arg: &TermArg,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<u64, AmlEvalError> {
+        self.eval_arg(arg, frame, depth)?.as_integer()
+    }
+
+    /// The names inside packages are references to the objects, and are not evaluated
+    fn eval_package_element(
+        &mut self,
+        arg: &TermArg,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        let name = match arg {
+            TermArg::Name(name) => name,
+            // the parser can't tell a name from a method call without arguments, if a method
+            // with the same last segment exists somewhere
+            TermArg::Expression(term) => match term.as_ref() {
+                AmlTerm::MethodCall(name, args) if args.is_empty() => name,
+                _ => return self.eval_arg(arg, frame, depth),
+            },
+            _ => return self.eval_arg(arg, frame, depth),
+        };
+        let path = self
+            .resolve(&frame.scope, name)
+            .unwrap_or_else(|| absolute_path(&frame.scope, name));
+        Ok(AmlValue::Reference(path))
+    }
+
+    fn eval_arg(
+        &mut self,
+        arg: &TermArg,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        match arg {
+            TermArg::Expression(term) => self.eval_term(term, frame, depth),
+            TermArg::DataObject(data) => Ok(AmlValue::Integer(match data {
+                DataObject::ConstZero => 0,
+                DataObject::ConstOne => 1,
+                DataObject::ConstOnes => ONES,
+                DataObject::ByteConst(b) => *b as u64,
+                DataObject::WordConst(w) => *w as u64,
+                DataObject::DWordConst(d) => *d as u64,
+                DataObject::QWordConst(q) => *q,
+            })),
+            TermArg::Arg(i) => frame
+                .args
+                .get(*i as usize)
+                .cloned()
+                .ok_or(AmlEvalError::WrongArgCount),
+            TermArg::Local(i) => Ok(frame.locals[*i as usize].clone()),
+            TermArg::Name(name) => {
+                let path = self
+                    .resolve(&frame.scope, name)
+                    .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?;
+                self.read_object(&path, Vec::new(), depth)
+            }
+        }
+    }
+
+    fn read_target(
+        &mut self,
+        target: &Target,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        match target {
+            Target::Arg(i) => self.eval_arg(&TermArg::Arg(*i), frame, depth),
+            Target::Local(i) => Ok(frame.locals[*i as usize].clone()),
+            Target::Name(name) => self.eval_arg(&TermArg::Name(name.clone()), frame, depth),
+            _ => Err(AmlEvalError::Unsupported("target")),
+        }
+    }
+
+    fn store(
+        &mut self,
+        target: &Target,
+        value: AmlValue,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<(), AmlEvalError> {
+        match target {
+            Target::None => {}
+            Target::Debug => eprintln!("AML debug: {value:?}"),
+            Target::Local(i) => frame.locals[*i as usize] = value,
+            Target::Arg(i) => {
+                let i = *i as usize;
+                if frame.args.len() <= i {
+                    frame.args.resize(i + 1, AmlValue::Integer(0));
+                }
+                frame.args[i] = value;
+            }
+            Target::Name(name) => {
+                let path = self
+                    .resolve(&frame.scope, name)
+                    .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?;
+                match self.objects.get_mut(&path) {
+                    Some(object @ (NamedObject::Value(_) | NamedObject::Unevaluated(_))) => {
+                        *object = NamedObject::Value(value)
+                    }
+                    _ => return Err(AmlEvalError::Unsupported("store to this object")),
+                }
+            }
+            Target::Index(object, index, inner) => {
+                let index = self.eval_integer(index, frame, depth)? as usize;
+                self.store(inner, value.clone(), frame, depth)?;
+                let element = match object {
+                    TermArg::Local(i) => &mut frame.locals[*i as usize],
+                    TermArg::Arg(i) => frame
+                        .args
+                        .get_mut(*i as usize)
+                        .ok_or(AmlEvalError::WrongArgCount)?,
+                    TermArg::Name(name) => {
+                        let path = self
+                            .resolve(&frame.scope, name)
+                            .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?;
+                        // evaluates it if its not yet
+                        self.read_object(&path, Vec::new(), depth)?;
+                        match self.objects.get_mut(&path) {
+                            Some(NamedObject::Value(v)) => v,
+                            _ => return Err(AmlEvalError::Unsupported("store to this object")),
+                        }
+                    }
+                    _ => return Err(AmlEvalError::Unsupported("nested index store")),
+                };
+                match element {
+                    AmlValue::Package(elements) => {
+                        *elements
+                            .get_mut(index)
+                            .ok_or(AmlEvalError::IndexOutOfBounds)? = value;
+                    }
+                    AmlValue::Buffer(bytes) => {
+                        *bytes.get_mut(index).ok_or(AmlEvalError::IndexOutOfBounds)? =
+                            value.as_integer()? as u8;
+                    }
+                    _ => return Err(AmlEvalError::InvalidType("package or buffer")),
+                }
+            }
+            _ => return Err(AmlEvalError::Unsupported("target")),
+        }
+        Ok(())
+    }
+
+    fn binary_op(
+        &mut self,
+        a: &TermArg,
+        b: &TermArg,
+        target: &Target,
+        frame: &mut Frame,
+        depth: usize,
+        op: fn(u64, u64) -> u64,
+    ) -> Result<AmlValue, AmlEvalError> {
+        let a = self.eval_integer(a, frame, depth)?;
+        let b = self.eval_integer(b, frame, depth)?;
+        let result = AmlValue::Integer(op(a, b));
+        self.store(target, result.clone(), frame, depth)?;
+        Ok(result)
+    }
+
+    fn compare(
+        &mut self,
+        a: &TermArg,
+        b: &TermArg,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<core::cmp::Ordering, AmlEvalError> {
+        let a = self.eval_arg(a, frame, depth)?;
+        let b = self.eval_arg(b, frame, depth)?;
+        match (&a, &b) {
+            (AmlValue::String(a), AmlValue::String(b)) => Ok(a.cmp(b)),
+            (AmlValue::Buffer(a), AmlValue::Buffer(b)) => Ok(a.cmp(b)),
+            _ => Ok(a.as_integer()?.cmp(&b.as_integer()?)),
+        }
+    }
+
+    fn eval_term(
+        &mut self,
+        term: &AmlTerm,
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        use core::cmp::Ordering;
+
+        fn logical(value: bool) -> AmlValue {
+            AmlValue::Integer(if value { ONES } else { 0 })
+        }
+
+        let value = match term {
+            AmlTerm::String(s) => AmlValue::String(s.clone()),
+            AmlTerm::Buffer(size, data) => {
+                let size = self.eval_integer(size, frame, depth)? as usize;
+                let mut data = data.clone();
+                if data.len() < size {
+                    data.resize(size, 0);
+                }
+                AmlValue::Buffer(data)
+            }
+            AmlTerm::Package(size, elements) => {
+                self.eval_package(*size as usize, elements, frame, depth)?
+            }
+            AmlTerm::VarPackage(size, elements) => {
+                let size = self.eval_integer(size, frame, depth)? as usize;
+                self.eval_package(size, elements, frame, depth)?
+            }
+            AmlTerm::Store(arg, target) => {
+                let value = self.eval_arg(arg, frame, depth)?;
+                self.store(target, value.clone(), frame, depth)?;
+                value
+            }
+            AmlTerm::Add(a, b, t) => self.binary_op(a, b, t, frame, depth, u64::wrapping_add)?,
+            AmlTerm::Subtract(a, b, t) => {
+                self.binary_op(a, b, t, frame, depth, u64::wrapping_sub)?
+            }
+            AmlTerm::Multiply(a, b, t) => {
+                self.binary_op(a, b, t, frame, depth, u64::wrapping_mul)?
+            }
+            AmlTerm::ShiftLeft(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| {
+                a.checked_shl(b as u32).unwrap_or(0)
+            })?,
+            AmlTerm::ShiftRight(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| {
+                a.checked_shr(b as u32).unwrap_or(0)
+            })?,
+            AmlTerm::And(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| a & b)?,
+            AmlTerm::Nand(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| !(a & b))?,
+            AmlTerm::Or(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| a | b)?,
+            AmlTerm::Nor(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| !(a | b))?,
+            AmlTerm::Xor(a, b, t) => self.binary_op(a, b, t, frame, depth, |a, b| a ^ b)?,
+            AmlTerm::Mod(a, b, t) => {
+                if self.eval_integer(b, frame, depth)? == 0 {
+                    return Err(AmlEvalError::InvalidType("non zero divisor"));
+                }
+                self.binary_op(a, b, t, frame, depth, |a, b| a % b)?
+            }
+            AmlTerm::Divide(a, b, remainder, quotient) => {
+                let a = self.eval_integer(a, frame, depth)?;
+                let b = self.eval_integer(b, frame, depth)?;
+                if b == 0 {
+                    return Err(AmlEvalError::InvalidType("non zero divisor"));
+                }
+                self.store(remainder, AmlValue::Integer(a % b), frame, depth)?;
+                let result = AmlValue::Integer(a / b);
+                self.store(quotient, result.clone(), frame, depth)?;
+                result
+            }
+            AmlTerm::Not(a, t) => {
+                let result = AmlValue::Integer(!self.eval_integer(a, frame, depth)?);
+                self.store(t, result.clone(), frame, depth)?;
+                result
+            }
+            AmlTerm::Increment(t) | AmlTerm::Decrement(t) => {
+                let value = self.read_target(t, frame, depth)?.as_integer()?;
+                let value = if let AmlTerm::Increment(_) = term {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                self.store(t, AmlValue::Integer(value), frame, depth)?;
+                AmlValue::Integer(value)
+            }
+            AmlTerm::LAnd(a, b) => logical(
+                self.eval_integer(a, frame, depth)? != 0
+                    && self.eval_integer(b, frame, depth)? != 0,
+            ),
+            AmlTerm::LOr(a, b) => logical(
+                self.eval_integer(a, frame, depth)? != 0
+                    || self.eval_integer(b, frame, depth)? != 0,
+            ),
+            AmlTerm::LNot(a) => logical(self.eval_integer(a, frame, depth)? == 0),
+            AmlTerm::LEqual(a, b) => logical(self.compare(a, b, frame, depth)? == Ordering::Equal),
+            AmlTerm::LNotEqual(a, b) => {
+                logical(self.compare(a, b, frame, depth)? != Ordering::Equal)
+            }
+            AmlTerm::LLess(a, b) => logical(self.compare(a, b, frame, depth)? == Ordering::Less),
+            AmlTerm::LLessEqual(a, b) => {
+                logical(self.compare(a, b, frame, depth)? != Ordering::Greater)
+            }
+            AmlTerm::LGreater(a, b) => {
+                logical(self.compare(a, b, frame, depth)? == Ordering::Greater)
+            }
+            AmlTerm::LGreaterEqual(a, b) => {
+                logical(self.compare(a, b, frame, depth)? != Ordering::Less)
+            }
+            AmlTerm::SizeOf(t) => AmlValue::Integer(match self.read_target(t, frame, depth)? {
+                AmlValue::String(s) => s.len(),
+                AmlValue::Buffer(b) => b.len(),
+                AmlValue::Package(p) => p.len(),
+                _ => return Err(AmlEvalError::InvalidType("string, buffer or package")),
+            } as u64),
+            AmlTerm::Index(object, index, target) => {
+                let object = self.eval_arg(object, frame, depth)?;
+                let index = self.eval_integer(index, frame, depth)? as usize;
+                let element = match object {
+                    AmlValue::Package(mut p) if index < p.len() => p.swap_remove(index),
+                    AmlValue::Buffer(b) if index < b.len() => AmlValue::Integer(b[index] as u64),
+                    AmlValue::String(s) if index < s.len() => {
+                        AmlValue::Integer(s.as_bytes()[index] as u64)
+                    }
+                    AmlValue::Package(_) | AmlValue::Buffer(_) | AmlValue::String(_) => {
+                        return Err(AmlEvalError::IndexOutOfBounds)
+                    }
+                    _ => return Err(AmlEvalError::InvalidType("string, buffer or package")),
+                };
+                self.store(target, element.clone(), frame, depth)?;
+                element
+            }
+            // `Index` gives the element itself, not a reference to it
+            AmlTerm::DerefOf(arg) => match self.eval_arg(arg, frame, depth)? {
+                AmlValue::Reference(path) => self.read_object(&path, Vec::new(), depth)?,
+                value => value,
+            },
+            AmlTerm::RefOf(target) => match target.as_ref() {
+                Target::Name(name) => AmlValue::Reference(
+                    self.resolve(&frame.scope, name)
+                        .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?,
+                ),
+                _ => return Err(AmlEvalError::Unsupported("reference to a local")),
+            },
+            AmlTerm::CondRefOf(target, result) => match target.as_ref() {
+                Target::Name(name) => match self.resolve(&frame.scope, name) {
+                    Some(path) => {
+                        self.store(result, AmlValue::Reference(path), frame, depth)?;
+                        logical(true)
+                    }
+                    None => logical(false),
+                },
+                _ => return Err(AmlEvalError::Unsupported("reference to a local")),
+            },
+            AmlTerm::ToInteger(arg, target) => {
+                let value = match self.eval_arg(arg, frame, depth)? {
+                    AmlValue::String(s) => {
+                        let s = s.trim();
+                        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                            Some(hex) => u64::from_str_radix(hex, 16),
+                            None => s.parse(),
+                        };
+                        parsed.map_err(|_| AmlEvalError::InvalidType("integer string"))?
+                    }
+                    value => value.as_integer()?,
+                };
+                let value = AmlValue::Integer(value);
+                self.store(target, value.clone(), frame, depth)?;
+                value
+            }
+            AmlTerm::MethodCall(name, args) => {
+                let path = self
+                    .resolve(&frame.scope, name)
+                    .ok_or_else(|| AmlEvalError::NotFound(name.clone()))?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval_arg(arg, frame, depth))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.read_object(&path, args, depth)?
+            }
+            // the waits finish right away, there is nothing else running the AML
+            AmlTerm::Aquire(_, _) | AmlTerm::Wait(_, _) => AmlValue::Integer(0),
+            AmlTerm::Noop
+            | AmlTerm::Notify(_, _)
+            | AmlTerm::Release(_)
+            | AmlTerm::Signal(_)
+            | AmlTerm::Reset(_)
+            | AmlTerm::Sleep(_)
+            | AmlTerm::Stall(_) => AmlValue::Integer(0),
+            AmlTerm::Region(_) | AmlTerm::Field(_) | AmlTerm::IndexField(_) => {
+                return Err(AmlEvalError::Unsupported("operation region"))
+            }
+            AmlTerm::CreateBitField(..)
+            | AmlTerm::CreateByteField(..)
+            | AmlTerm::CreateWordField(..)
+            | AmlTerm::CreateDWordField(..)
+            | AmlTerm::CreateQWordField(..) => {
+                return Err(AmlEvalError::Unsupported("buffer field"))
+            }
+            _ => return Err(AmlEvalError::Unsupported("term")),
+        };
+        Ok(value)
+    }
+
+    fn eval_package(
+        &mut self,
+        size: usize,
+        elements: &[TermArg],
+        frame: &mut Frame,
+        depth: usize,
+    ) -> Result<AmlValue, AmlEvalError> {
+        let mut values = elements
+            .iter()
+            .map(|element| self.eval_package_element(element, frame, depth))
+            .collect::<Result<Vec<_>, _>>()?;
+        // the elements not given are uninitialized, zero is close enough
+        if values.len() < size {
+            values.resize(size, AmlValue::Integer(0));
+        }
+        Ok(AmlValue::Package(values))
+    }
+}
+
+/// The interrupts of a resource template, i.e. the `_CRS` of a PCI interrupt link device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptResource {
+    pub interrupts: Vec<u32>,
+    pub edge_triggered: bool,
+    pub active_low: bool,
+    pub shared: bool,
+}
+
+/// The IRQ and extended interrupt descriptors of the resource template `buffer`, in order
+pub fn interrupt_resources(buffer: &[u8]) -> Vec<InterruptResource> {
+    let mut resources = vec![];
+    let mut pos = 0;
+    while let Some(&tag) = buffer.get(pos) {
+        if tag & 0x80 == 0 {
+            // small resource
+            let len = (tag & 0b111) as usize;
+            let Some(data) = buffer.get(pos + 1..pos + 1 + len) else {
+                break;
+            };
+            match tag >> 3 {
+                // IRQ descriptor, without the flags its edge triggered and active high
+                0x4 if len >= 2 => {
+                    let mask = u16::from_le_bytes([data[0], data[1]]);
+                    let flags = data.get(2).copied().unwrap_or(1);
+                    resources.push(InterruptResource {
+                        interrupts: (0..16).filter(|i| mask & (1 << i) != 0).collect(),
+                        edge_triggered: flags & 1 != 0,
+                        active_low: flags & (1 << 3) != 0,
+                        shared: flags & (1 << 4) != 0,
+                    });
+                }
+                // end tag
+                0xF => break,
+                _ => {}
+            }
+            pos += 1 + len;
+        } else {
+            // large resource
+            let Some(len) = buffer.get(pos + 1..pos + 3) else {
+                break;
+            };
+            let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+            let Some(data) = buffer.get(pos + 3..pos + 3 + len) else {
+                break;
+            };
+            // extended interrupt descriptor
+            if tag & 0x7F == 0x9 && len >= 2 {
+                let flags = data[0];
+                let count = data[1] as usize;
+                let interrupts = data[2..]
+                    .as_chunks::<4>()
+                    .0
+                    .iter()
+                    .take(count)
+                    .map(|b| u32::from_le_bytes(*b))
+                    .collect();
+                resources.push(InterruptResource {
+                    interrupts,
+                    edge_triggered: flags & (1 << 1) != 0,
+                    active_low: flags & (1 << 2) != 0,
+                    shared: flags & (1 << 3) != 0,
+                });
+            }
+            pos += 3 + len;
+        }
+    }
+    resources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::aml::parse_aml;
+
+    /// `op`, then the package length of `body`, then `body`
+    fn pkg(op: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut code = op.to_vec();
+        if body.len() < 0x3F {
+            code.push(body.len() as u8 + 1);
+        } else {
+            let len = body.len() + 2;
+            assert!(len <= 0xFFF);
+            code.extend_from_slice(&[0x40 | (len & 0xF) as u8, (len >> 4) as u8]);
+        }
+        code.extend_from_slice(body);
+        code
+    }
+
+    fn cat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn scope(name: &[u8], body: &[u8]) -> Vec<u8> {
+        pkg(&[0x10], &cat(&[name, body]))
+    }
+
+    fn device(name: &[u8], body: &[u8]) -> Vec<u8> {
+        pkg(&[0x5B, 0x82], &cat(&[name, body]))
+    }
+
+    fn method(name: &[u8], args: u8, body: &[u8]) -> Vec<u8> {
+        pkg(&[0x14], &cat(&[name, &[args], body]))
+    }
+
+    fn name(name: &[u8], value: &[u8]) -> Vec<u8> {
+        cat(&[&[0x08], name, value])
+    }
+
+    fn ret(value: &[u8]) -> Vec<u8> {
+        cat(&[&[0xA4], value])
+    }
+
+    fn byte(value: u8) -> Vec<u8> {
+        vec![0x0A, value]
+    }
+
+    fn dword(value: u32) -> Vec<u8> {
+        cat(&[&[0x0C], &value.to_le_bytes()])
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        cat(&[&[0x0D], s.as_bytes(), &[0]])
+    }
+
+    fn buffer(bytes: &[u8]) -> Vec<u8> {
+        pkg(&[0x11], &cat(&[&byte(bytes.len() as u8), bytes]))
+    }
+
+    fn package(elements: &[&[u8]]) -> Vec<u8> {
+        pkg(
+            &[0x12],
+            &cat(&[&[elements.len() as u8], &elements.concat()]),
+        )
+    }
+
+    fn if_(predicate: &[u8], body: &[u8]) -> Vec<u8> {
+        pkg(&[0xA0], &cat(&[predicate, body]))
+    }
+
+    fn else_(body: &[u8]) -> Vec<u8> {
+        pkg(&[0xA1], body)
+    }
+
+    fn while_(predicate: &[u8], body: &[u8]) -> Vec<u8> {
+        pkg(&[0xA2], &cat(&[predicate, body]))
+    }
+
+    const ZERO: u8 = 0x00;
+    const ONE: u8 = 0x01;
+    const LOCAL0: u8 = 0x60;
+    const LOCAL1: u8 = 0x61;
+    const ARG0: u8 = 0x68;
+    const ARG1: u8 = 0x69;
+
+    fn load(code: &[u8]) -> Namespace {
+        Namespace::new(&parse_aml(code).unwrap())
+    }
+
+    /// Runs `body` as a method without arguments
+    fn run(body: &[u8]) -> Result<AmlValue, AmlEvalError> {
+        load(&method(b"TEST", 0, body)).evaluate("\\TEST", Vec::new())
+    }
+
+    fn integer(body: &[u8]) -> u64 {
+        match run(body) {
+            Ok(AmlValue::Integer(value)) => value,
+            result => panic!("{body:x?} gave {result:?}"),
+        }
+    }
+
+    /// `Return (op (a, b, Zero))`
+    fn binary(op: u8, a: &[u8], b: &[u8]) -> u64 {
+        integer(&ret(&cat(&[&[op], a, b, &[ZERO]])))
+    }
+
+    #[test]
+    fn joined_paths() {
+        assert_eq!(join("\\", "_SB_"), "\\_SB_");
+        assert_eq!(join("\\_SB_", "PCI0"), "\\_SB_.PCI0");
+        assert_eq!(parent("\\_SB_.PCI0"), Some("\\_SB_"));
+        assert_eq!(parent("\\_SB_"), Some("\\"));
+        assert_eq!(parent("\\"), None);
+    }
+
+    #[test]
+    fn absolute_paths() {
+        assert_eq!(absolute_path("\\_SB_", "PCI0"), "\\_SB_.PCI0");
+        assert_eq!(absolute_path("\\_SB_.PCI0", "\\_GPE"), "\\_GPE");
+        assert_eq!(absolute_path("\\_SB_.PCI0", "\\"), "\\");
+        assert_eq!(absolute_path("\\_SB_.PCI0", "^LNKA"), "\\_SB_.LNKA");
+        assert_eq!(absolute_path("\\_SB_.PCI0", "^^"), "\\");
+        // the parent of the root is itself
+        assert_eq!(absolute_path("\\_SB_", "^^^ABCD"), "\\ABCD");
+        assert_eq!(absolute_path("\\_SB_", "PCI0.ISA_"), "\\_SB_.PCI0.ISA_");
+    }
+
+    #[test]
+    fn pattern_parsing() {
+        let pattern = PathPattern::parse("\\_sb.pci0.*").unwrap();
+        assert_eq!(pattern.to_string(), "\\_SB_.PCI0.*");
+        assert_eq!(PathPattern::parse("_SB").unwrap().to_string(), "\\_SB_");
+        assert_eq!(PathPattern::parse("LNK?").unwrap().to_string(), "\\LNK?");
+        for invalid in ["", "\\", "_SB..PCI0", "TOOLONG", "A-B", "_SB."] {
+            assert_eq!(PathPattern::parse(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn pattern_matching() {
+        let pattern = PathPattern::parse("\\_SB.PCI0.*").unwrap();
+        assert!(pattern.matches("\\_SB_.PCI0._PRT"));
+        assert!(!pattern.matches("\\_SB_.PCI0"));
+        assert!(!pattern.matches("\\_SB_.PCI0.ISA_.RTC_"));
+        assert!(!pattern.matches("\\_SB_.PCI1._PRT"));
+        assert!(pattern.matches_inside("\\_SB_"));
+        assert!(pattern.matches_inside("\\_SB_.PCI0"));
+        assert!(!pattern.matches_inside("\\_SB_.PCI0._PRT"));
+        assert!(!pattern.matches_inside("\\_GPE"));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"*", b"ABCD"));
+        assert!(glob_matches(b"L*A", b"LNKA"));
+        assert!(glob_matches(b"?NK?", b"LNKB"));
+        assert!(glob_matches(b"*K*", b"LNKA"));
+        assert!(!glob_matches(b"?", b""));
+        assert!(!glob_matches(b"L*B", b"LNKA"));
+        assert!(!glob_matches(b"LNK", b"LNKA"));
+    }
+
+    #[test]
+    fn buffers_as_integers() {
+        assert_eq!(AmlValue::Integer(5).as_integer().unwrap(), 5);
+        assert_eq!(
+            AmlValue::Buffer(vec![0x34, 0x12]).as_integer().unwrap(),
+            0x1234
+        );
+        // only the first 8 bytes
+        assert_eq!(
+            AmlValue::Buffer(vec![1; 10]).as_integer().unwrap(),
+            ONES / 0xFF
+        );
+        assert!(matches!(
+            AmlValue::String("1".to_string()).as_integer(),
+            Err(AmlEvalError::InvalidType(_))
+        ));
+    }
+
+    #[test]
+    fn values_display_as_asl() {
+        let value = AmlValue::Package(vec![
+            AmlValue::Integer(0x1F),
+            AmlValue::String("PNP0A03".to_string()),
+            AmlValue::Buffer(vec![1, 0xAB]),
+            AmlValue::Reference("\\_SB_.LNKA".to_string()),
+            AmlValue::Package(Vec::new()),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            "Package (5) { 0x1F, \"PNP0A03\", Buffer (2) { 0x01, 0xAB }, \\_SB_.LNKA, Package (0) { } }"
+        );
+    }
+
+    #[test]
+    fn loads_the_objects() {
+        let namespace = load(&scope(
+            b"\\_SB_",
+            &cat(&[
+                &name(b"ABCD", &byte(1)),
+                &device(b"PCI0", &device(b"ISA_", &name(b"_HID", &dword(0x0A)))),
+                &method(b"MTH0", 0, &ret(&[ONE])),
+            ]),
+        ));
+        for path in [
+            "\\",
+            "\\_SB_",
+            "\\_SB_.ABCD",
+            "\\_SB_.PCI0",
+            "\\_SB_.PCI0.ISA_",
+            "\\_SB_.PCI0.ISA_._HID",
+            "\\_SB_.MTH0",
+        ] {
+            assert!(namespace.contains(path), "{path}");
+        }
+        assert!(!namespace.contains("\\ABCD"));
+        assert_eq!(
+            namespace.devices().collect::<Vec<_>>(),
+            ["\\_SB_.PCI0", "\\_SB_.PCI0.ISA_"]
+        );
+    }
+
+    #[test]
+    fn scope_reopened_is_the_same_object() {
+        let code = cat(&[
+            &scope(b"\\_SB_", &device(b"PCI0", &[])),
+            &scope(b"\\_SB_", &scope(b"PCI0", &name(b"ABCD", &byte(2)))),
+        ]);
+        let namespace = load(&code);
+        // still a device, and the scope found from `\_SB_`, not a new one
+        assert_eq!(namespace.devices().collect::<Vec<_>>(), ["\\_SB_.PCI0"]);
+        assert!(namespace.contains("\\_SB_.PCI0.ABCD"));
+    }
+
+    #[test]
+    fn another_table_is_added() {
+        let mut namespace = load(&name(b"AAAA", &byte(1)));
+        namespace.load(&parse_aml(&name(b"BBBB", &byte(2))).unwrap());
+        assert!(namespace.contains("\\AAAA"));
+        assert_eq!(
+            namespace.evaluate("\\BBBB", Vec::new()).unwrap(),
+            AmlValue::Integer(2)
+        );
+    }
+
+    #[test]
+    fn resolve_searches_the_parents() {
+        let namespace = load(&scope(
+            b"\\_SB_",
+            &cat(&[
+                &name(b"ABCD", &byte(1)),
+                &device(b"PCI0", &name(b"INNR", &byte(2))),
+            ]),
+        ));
+        let inner = "\\_SB_.PCI0";
+        assert_eq!(
+            namespace.resolve(inner, "INNR").unwrap(),
+            "\\_SB_.PCI0.INNR"
+        );
+        assert_eq!(namespace.resolve(inner, "ABCD").unwrap(), "\\_SB_.ABCD");
+        assert_eq!(namespace.resolve(inner, "^ABCD").unwrap(), "\\_SB_.ABCD");
+        assert_eq!(namespace.resolve("\\", "_SB_.PCI0").unwrap(), inner);
+        assert_eq!(namespace.resolve(inner, "NONE"), None);
+        // paths with more than one segment are not searched
+        assert_eq!(namespace.resolve(inner, "PCI0.INNR"), None);
+        assert_eq!(namespace.resolve(inner, "^INNR"), None);
+    }
+
+    #[test]
+    fn names_are_evaluated_when_read() {
+        let mut namespace = load(&cat(&[
+            &name(b"INT0", &dword(0x1234_5678)),
+            &name(b"STR0", &string("PNP0C0F")),
+            &name(b"BUF0", &buffer(&[1, 2, 3])),
+            &name(b"PKG0", &package(&[&[ONE], &string("x")])),
+        ]));
+        let mut read = |path| namespace.evaluate(path, Vec::new()).unwrap();
+        assert_eq!(read("\\INT0"), AmlValue::Integer(0x1234_5678));
+        assert_eq!(read("\\STR0"), AmlValue::String("PNP0C0F".to_string()));
+        assert_eq!(read("\\BUF0"), AmlValue::Buffer(vec![1, 2, 3]));
+        assert_eq!(
+            read("\\PKG0"),
+            AmlValue::Package(vec![
+                AmlValue::Integer(1),
+                AmlValue::String("x".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn names_in_packages_are_references() {
+        let mut namespace = load(&scope(
+            b"\\_SB_",
+            &cat(&[
+                &device(b"LNKA", &[]),
+                &name(b"_PRT", &package(&[&package(&[&[ONE], b"LNKA"])])),
+            ]),
+        ));
+        assert_eq!(
+            namespace.evaluate("\\_SB_._PRT", Vec::new()).unwrap(),
+            AmlValue::Package(vec![AmlValue::Package(vec![
+                AmlValue::Integer(1),
+                AmlValue::Reference("\\_SB_.LNKA".to_string())
+            ])])
+        );
+    }
+
+    #[test]
+    fn devices_read_as_references() {
+        let mut namespace = load(&device(b"DEV0", &[]));
+        assert_eq!(
+            namespace.evaluate("\\DEV0", Vec::new()).unwrap(),
+            AmlValue::Reference("\\DEV0".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_object() {
+        let mut namespace = load(&[]);
+        assert!(matches!(
+            namespace.evaluate("\\NONE", Vec::new()),
+            Err(AmlEvalError::NotFound(path)) if path == "\\NONE"
+        ));
+        assert!(matches!(
+            run(&ret(b"NONE")),
+            Err(AmlEvalError::NotFound(name)) if name == "NONE"
+        ));
+    }
+
+    #[test]
+    fn alias_reads_the_source() {
+        let code = cat(&[&name(b"SRC0", &byte(7)), &[0x06], b"SRC0", b"ALS0"]);
+        let mut namespace = load(&code);
+        assert_eq!(
+            namespace.evaluate("\\ALS0", Vec::new()).unwrap(),
+            AmlValue::Integer(7)
+        );
+    }
+
+    #[test]
+    fn alias_cycle_is_too_deep() {
+        let code = cat(&[&[0x06], b"AAAA", b"BBBB", &[0x06], b"BBBB", b"AAAA"]);
+        let mut namespace = load(&code);
+        assert!(matches!(
+            namespace.evaluate("\\AAAA", Vec::new()),
+            Err(AmlEvalError::TooDeep)
+        ));
+    }
+
+    #[test]
+    fn hardware_objects_are_unsupported() {
+        // OperationRegion (REG0, SystemIO, 0x80, 1), Mutex (MUT0, 0)
+        let code = cat(&[
+            &[0x5B, 0x80],
+            b"REG0",
+            &[0x01],
+            &byte(0x80),
+            &[ONE],
+            &[0x5B, 0x01],
+            b"MUT0",
+            &[0],
+        ]);
+        let mut namespace = load(&code);
+        assert!(matches!(
+            namespace.evaluate("\\REG0", Vec::new()),
+            Err(AmlEvalError::Unsupported("region"))
+        ));
+        assert!(matches!(
+            namespace.evaluate("\\MUT0", Vec::new()),
+            Err(AmlEvalError::Unsupported("sync"))
+        ));
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(integer(&ret(&[ZERO])), 0);
+        assert_eq!(integer(&ret(&[ONE])), 1);
+        assert_eq!(integer(&ret(&[0xFF])), ONES);
+        assert_eq!(integer(&ret(&[0x0B, 0x34, 0x12])), 0x1234);
+        assert_eq!(integer(&ret(&dword(0xDEAD_BEEF))), 0xDEAD_BEEF);
+        let qword = cat(&[&[0x0E], &0x0123_4567_89AB_CDEFu64.to_le_bytes()]);
+        assert_eq!(integer(&ret(&qword)), 0x0123_4567_89AB_CDEF);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(binary(0x72, &byte(40), &byte(2)), 42);
+        assert_eq!(binary(0x74, &byte(40), &byte(2)), 38);
+        assert_eq!(binary(0x77, &byte(6), &byte(7)), 42);
+        assert_eq!(binary(0x85, &byte(47), &byte(5)), 2);
+        // wrapping
+        assert_eq!(binary(0x72, &[0xFF], &[ONE]), 0);
+        assert_eq!(binary(0x74, &[ZERO], &[ONE]), ONES);
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(binary(0x7B, &byte(0b1100), &byte(0b1010)), 0b1000);
+        assert_eq!(binary(0x7D, &byte(0b1100), &byte(0b1010)), 0b1110);
+        assert_eq!(binary(0x7F, &byte(0b1100), &byte(0b1010)), 0b0110);
+        assert_eq!(binary(0x7C, &byte(0b1100), &byte(0b1010)), !0b1000);
+        assert_eq!(binary(0x7E, &byte(0b1100), &byte(0b1010)), !0b1110);
+        assert_eq!(integer(&ret(&[0x80, ZERO, ZERO])), ONES);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(binary(0x79, &[ONE], &byte(4)), 16);
+        assert_eq!(binary(0x7A, &byte(0x80), &byte(4)), 8);
+        // shifting everything out
+        assert_eq!(binary(0x79, &[ONE], &byte(64)), 0);
+        assert_eq!(binary(0x7A, &[0xFF], &byte(200)), 0);
+    }
+
+    #[test]
+    fn divide_stores_both() {
+        // Divide (47, 5, Local0, Local1), Return (Add (Multiply (Local1, 100), Local0))
+        let code = cat(&[
+            &[0x78],
+            &byte(47),
+            &byte(5),
+            &[LOCAL0, LOCAL1],
+            &ret(&cat(&[
+                &[0x72, 0x77, LOCAL1],
+                &byte(100),
+                &[ZERO, LOCAL0, ZERO],
+            ])),
+        ]);
+        assert_eq!(integer(&code), 902);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        for op in [0x78, 0x85] {
+            let code = if op == 0x78 {
+                ret(&[op, ONE, ZERO, ZERO, ZERO])
+            } else {
+                ret(&[op, ONE, ZERO, ZERO])
+            };
+            assert!(matches!(run(&code), Err(AmlEvalError::InvalidType(_))));
+        }
+    }
+
+    #[test]
+    fn logical() {
+        let cases: [(&[u8], u64); 12] = [
+            (&[0x90, ONE, ONE], ONES),
+            (&[0x90, ONE, ZERO], 0),
+            (&[0x91, ZERO, ONE], ONES),
+            (&[0x91, ZERO, ZERO], 0),
+            (&[0x92, ZERO], ONES),
+            (&[0x92, 0x0A, 5], 0),
+            (&[0x93, ONE, ONE], ONES),
+            (&[0x94, ONE, ZERO], ONES),
+            (&[0x95, ONE, ZERO], 0),
+            (&[0x92, 0x93, ONE, ZERO], ONES),
+            (&[0x92, 0x94, ONE, ONE], ONES),
+            (&[0x92, 0x95, ZERO, ONE], 0),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(integer(&ret(code)), expected, "{code:x?}");
+        }
+    }
+
+    #[test]
+    fn compares_strings_and_buffers() {
+        let equal = |a: &[u8], b: &[u8]| integer(&ret(&cat(&[&[0x93], a, b])));
+        let less = |a: &[u8], b: &[u8]| integer(&ret(&cat(&[&[0x95], a, b])));
+        assert_eq!(equal(&string("abc"), &string("abc")), ONES);
+        assert_eq!(equal(&string("abc"), &string("abd")), 0);
+        assert_eq!(less(&string("abc"), &string("abd")), ONES);
+        assert_eq!(equal(&buffer(&[1, 2]), &buffer(&[1, 2])), ONES);
+        assert_eq!(less(&buffer(&[1, 2]), &buffer(&[1, 3])), ONES);
+        // a buffer and an integer compare as integers
+        assert_eq!(equal(&buffer(&[5]), &byte(5)), ONES);
+        assert!(matches!(
+            run(&ret(&cat(&[&[0x93], &string("1"), &[ONE]]))),
+            Err(AmlEvalError::InvalidType(_))
+        ));
+    }
+
+    #[test]
+    fn if_and_else() {
+        let branch = |predicate: u8| {
+            integer(&cat(&[
+                &if_(&[predicate], &ret(&byte(1))),
+                &else_(&ret(&byte(2))),
+            ]))
+        };
+        assert_eq!(branch(ONE), 1);
+        assert_eq!(branch(ZERO), 2);
+    }
+
+    #[test]
+    fn else_only_after_its_if() {
+        // If (One) { Store (1, Local0) } Else { Store (2, Local0) }, Return (Local0)
+        let code = cat(&[
+            &if_(&[ONE], &cat(&[&[0x70], &byte(1), &[LOCAL0]])),
+            &else_(&cat(&[&[0x70], &byte(2), &[LOCAL0]])),
+            &ret(&[LOCAL0]),
+        ]);
+        assert_eq!(integer(&code), 1);
+    }
+
+    #[test]
+    fn while_with_increment() {
+        // While (LLess (Local0, 10)) { Increment (Local0) }, Return (Local0)
+        let code = cat(&[
+            &while_(&cat(&[&[0x95, LOCAL0], &byte(10)]), &[0x75, LOCAL0]),
+            &ret(&[LOCAL0]),
+        ]);
+        assert_eq!(integer(&code), 10);
+    }
+
+    #[test]
+    fn while_break_and_return() {
+        // While (One) { If (LEqual (Local0, 3)) { Break }, Increment (Local0) }
+        let code = cat(&[
+            &while_(
+                &[ONE],
+                &cat(&[
+                    &if_(&cat(&[&[0x93, LOCAL0], &byte(3)]), &[0xA5]),
+                    &[0x75, LOCAL0],
+                ]),
+            ),
+            &ret(&[LOCAL0]),
+        ]);
+        assert_eq!(integer(&code), 3);
+
+        let code = cat(&[&while_(&[ONE], &ret(&byte(9))), &ret(&[ZERO])]);
+        assert_eq!(integer(&code), 9);
+    }
+
+    #[test]
+    fn endless_while() {
+        assert!(matches!(
+            run(&while_(&[ONE], &[0xA3])),
+            Err(AmlEvalError::TooManyIterations)
+        ));
+    }
+
+    #[test]
+    fn decrement_wraps() {
+        assert_eq!(integer(&cat(&[&[0x76, LOCAL0], &ret(&[LOCAL0])])), ONES);
+    }
+
+    #[test]
+    fn no_return_is_zero() {
+        assert_eq!(integer(&[0xA3]), 0);
+        assert_eq!(integer(&[]), 0);
+    }
+
+    #[test]
+    fn method_args() {
+        let mut namespace = load(&method(b"SUB0", 2, &ret(&[0x74, ARG0, ARG1, ZERO])));
+        let args = vec![AmlValue::Integer(10), AmlValue::Integer(3)];
+        assert_eq!(
+            namespace.evaluate("\\SUB0", args).unwrap(),
+            AmlValue::Integer(7)
+        );
+        assert!(matches!(
+            namespace.evaluate("\\SUB0", vec![AmlValue::Integer(1)]),
+            Err(AmlEvalError::WrongArgCount)
+        ));
+    }
+
+    #[test]
+    fn store_to_an_arg() {
+        // Store (5, Arg0), Return (Arg0)
+        let mut namespace = load(&method(
+            b"MTH0",
+            1,
+            &cat(&[&[0x70], &byte(5), &[ARG0], &ret(&[ARG0])]),
+        ));
+        assert_eq!(
+            namespace
+                .evaluate("\\MTH0", vec![AmlValue::Integer(1)])
+                .unwrap(),
+            AmlValue::Integer(5)
+        );
+    }
+
+    #[test]
+    fn method_calls() {
+        let code = cat(&[
+            &method(b"DBL0", 1, &ret(&[0x72, ARG0, ARG0, ZERO])),
+            &method(b"TEST", 0, &ret(&cat(&[b"DBL0", &byte(21)]))),
+        ]);
+        assert_eq!(
+            load(&code).evaluate("\\TEST", Vec::new()).unwrap(),
+            AmlValue::Integer(42)
+        );
+    }
+
+    #[test]
+    fn methods_see_their_scope() {
+        let code = scope(
+            b"\\_SB_",
+            &cat(&[
+                &name(b"VAL0", &byte(3)),
+                &device(b"DEV0", &method(b"MTH0", 0, &ret(b"VAL0"))),
+            ]),
+        );
+        assert_eq!(
+            load(&code)
+                .evaluate("\\_SB_.DEV0.MTH0", Vec::new())
+                .unwrap(),
+            AmlValue::Integer(3)
+        );
+    }
+
+    #[test]
+    fn recursion_is_too_deep() {
+        // the first definition gives the parser the number of arguments, the second replaces it
+        let code = cat(&[
+            &method(b"RECU", 1, &ret(&[ZERO])),
+            &method(b"RECU", 1, &ret(&cat(&[b"RECU", &[ARG0]]))),
+        ]);
+        assert!(matches!(
+            load(&code).evaluate("\\RECU", vec![AmlValue::Integer(0)]),
+            Err(AmlEvalError::TooDeep)
+        ));
+    }
+
+    #[test]
+    fn store_to_a_name() {
+        // Name (VAL0, 1), Method (TEST) { Store (5, VAL0), Return (VAL0) }
+        let code = cat(&[
+            &name(b"VAL0", &[ONE]),
+            &method(
+                b"TEST",
+                0,
+                &cat(&[&[0x70], &byte(5), b"VAL0", &ret(b"VAL0")]),
+            ),
+        ]);
+        let mut namespace = load(&code);
+        assert_eq!(
+            namespace.evaluate("\\TEST", Vec::new()).unwrap(),
+            AmlValue::Integer(5)
+        );
+        // the new value is kept
+        assert_eq!(
+            namespace.evaluate("\\VAL0", Vec::new()).unwrap(),
+            AmlValue::Integer(5)
+        );
+    }
+
+    #[test]
+    fn store_to_a_method_is_unsupported() {
+        let code = cat(&[
+            &method(b"MTH0", 0, &ret(&[ONE])),
+            &method(b"TEST", 0, &cat(&[&[0x70, ONE], b"MTH0"])),
+        ]);
+        assert!(matches!(
+            load(&code).evaluate("\\TEST", Vec::new()),
+            Err(AmlEvalError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn names_inside_methods_are_created() {
+        let code = method(b"TEST", 0, &name(b"NEW0", &byte(4)));
+        let mut namespace = load(&code);
+        assert!(!namespace.contains("\\TEST.NEW0"));
+        namespace.evaluate("\\TEST", Vec::new()).unwrap();
+        assert_eq!(
+            namespace.evaluate("\\TEST.NEW0", Vec::new()).unwrap(),
+            AmlValue::Integer(4)
+        );
+    }
+
+    #[test]
+    fn index_and_size_of() {
+        // Store (Package { 1, 2, 3 }, Local0)
+        let store = cat(&[&[0x70], &package(&[&[ONE], &byte(2), &byte(3)]), &[LOCAL0]]);
+        let index = cat(&[&store, &ret(&[0x88, LOCAL0, ONE, ZERO])]);
+        assert_eq!(integer(&index), 2);
+        let size = cat(&[&store, &ret(&[0x87, LOCAL0])]);
+        assert_eq!(integer(&size), 3);
+
+        // the object of `SizeOf` is a name or a local
+        let size = cat(&[&[0x70], &string("ab"), &[LOCAL1], &ret(&[0x87, LOCAL1])]);
+        assert_eq!(integer(&size), 2);
+        let buffer_index = ret(&cat(&[&[0x88], &buffer(&[7, 8]), &[ONE, ZERO]]));
+        assert_eq!(integer(&buffer_index), 8);
+        let string_index = ret(&cat(&[&[0x88], &string("AB"), &[ZERO, ZERO]]));
+        assert_eq!(integer(&string_index), b'A' as u64);
+    }
+
+    #[test]
+    fn index_out_of_bounds() {
+        let code = ret(&cat(&[&[0x88], &buffer(&[7, 8]), &byte(2), &[ZERO]]));
+        assert!(matches!(run(&code), Err(AmlEvalError::IndexOutOfBounds)));
+        let code = ret(&[0x88, ONE, ZERO, ZERO]);
+        assert!(matches!(run(&code), Err(AmlEvalError::InvalidType(_))));
+    }
+
+    #[test]
+    fn store_to_an_index() {
+        // Store (Buffer { 1, 2 }, Local0), Store (9, Index (Local0, One)), Return (Local0)
+        let code = cat(&[
+            &[0x70],
+            &buffer(&[1, 2]),
+            &[LOCAL0],
+            &[0x70],
+            &byte(9),
+            &[0x88, LOCAL0, ONE, ZERO],
+            &ret(&[LOCAL0]),
+        ]);
+        assert_eq!(run(&code).unwrap(), AmlValue::Buffer(vec![1, 9]));
+    }
+
+    #[test]
+    fn buffer_is_padded_to_its_size() {
+        let code = ret(&pkg(&[0x11], &cat(&[&byte(4), &[1, 2]])));
+        assert_eq!(run(&code).unwrap(), AmlValue::Buffer(vec![1, 2, 0, 0]));
+    }
+
+    #[test]
+    fn package_is_padded_to_its_size() {
+        let code = ret(&pkg(&[0x12], &[3, ONE]));
+        assert_eq!(
+            run(&code).unwrap(),
+            AmlValue::Package(vec![
+                AmlValue::Integer(1),
+                AmlValue::Integer(0),
+                AmlValue::Integer(0)
+            ])
+        );
+    }
+
+    #[test]
+    fn references() {
+        // RefOf (VAL0), DerefOf (RefOf (VAL0))
+        let code = cat(&[
+            &name(b"VAL0", &byte(6)),
+            &method(b"TEST", 0, &ret(&cat(&[&[0x71], b"VAL0"]))),
+        ]);
+        assert_eq!(
+            load(&code).evaluate("\\TEST", Vec::new()).unwrap(),
+            AmlValue::Reference("\\VAL0".to_string())
+        );
+        let code = cat(&[
+            &name(b"VAL0", &byte(6)),
+            &method(b"TEST", 0, &ret(&cat(&[&[0x83, 0x71], b"VAL0"]))),
+        ]);
+        assert_eq!(
+            load(&code).evaluate("\\TEST", Vec::new()).unwrap(),
+            AmlValue::Integer(6)
+        );
+    }
+
+    #[test]
+    fn cond_ref_of() {
+        let exists = |target: &[u8]| {
+            let code = cat(&[
+                &name(b"VAL0", &byte(6)),
+                &method(b"TEST", 0, &ret(&cat(&[&[0x5B, 0x12], target, &[LOCAL0]]))),
+            ]);
+            load(&code).evaluate("\\TEST", Vec::new()).unwrap()
+        };
+        assert_eq!(exists(b"VAL0"), AmlValue::Integer(ONES));
+        assert_eq!(exists(b"NONE"), AmlValue::Integer(0));
+    }
+
+    #[test]
+    fn to_integer() {
+        let convert = |value: &[u8]| run(&ret(&cat(&[&[0x99], value, &[ZERO]])));
+        assert_eq!(convert(&string("0x1F")).unwrap(), AmlValue::Integer(0x1F));
+        assert_eq!(convert(&string(" 42 ")).unwrap(), AmlValue::Integer(42));
+        assert_eq!(convert(&buffer(&[1, 1])).unwrap(), AmlValue::Integer(0x101));
+        assert!(matches!(
+            convert(&string("nope")),
+            Err(AmlEvalError::InvalidType(_))
+        ));
+    }
+
+    #[test]
+    fn waits_and_notifications_do_nothing() {
+        // Sleep (10), Stall (10), Noop
+        let code = cat(&[
+            &[0x5B, 0x22],
+            &byte(10),
+            &[0x5B, 0x21],
+            &byte(10),
+            &[0xA3],
+            &ret(&[ONE]),
+        ]);
+        assert_eq!(integer(&code), 1);
+    }
+
+    #[test]
+    fn find_terms_by_pattern() {
+        let code = parse_aml(&cat(&[
+            &scope(
+                b"\\_SB_",
+                &cat(&[
+                    &device(b"LNKA", &name(b"_UID", &[ONE])),
+                    &device(b"LNKB", &name(b"_UID", &byte(2))),
+                    &device(b"PCI0", &[]),
+                ]),
+            ),
+            &scope(b"\\_SB_", &device(b"LNKC", &[])),
+        ]))
+        .unwrap();
+        let namespace = Namespace::new(&code);
+        let pattern = PathPattern::parse("\\_SB.LNK*").unwrap();
+        let found = namespace.find_terms(&code, &pattern);
+        let paths = found.iter().map(|f| f.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["\\_SB_.LNKA", "\\_SB_.LNKB", "\\_SB_.LNKC"]);
+        assert_eq!(found[0].names, ["\\_SB_.LNKA._UID"]);
+        assert!(found[2].names.is_empty());
+
+        let pattern = PathPattern::parse("\\*.*._UID").unwrap();
+        let found = namespace.find_terms(&code, &pattern);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].names, ["\\_SB_.LNKB._UID"]);
+    }
+
+    #[test]
+    fn irq_descriptor() {
+        // IRQ (Level, ActiveLow, Shared) { 5, 11 }, then the end tag
+        let resources = interrupt_resources(&[0x23, 0x20, 0x08, 0x18, 0x79, 0x00]);
+        assert_eq!(
+            resources,
+            [InterruptResource {
+                interrupts: vec![5, 11],
+                edge_triggered: false,
+                active_low: true,
+                shared: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn irq_descriptor_without_flags() {
+        let resources = interrupt_resources(&[0x22, 0x02, 0x00]);
+        assert_eq!(resources[0].interrupts, [1]);
+        assert!(resources[0].edge_triggered);
+        assert!(!resources[0].active_low);
+    }
+
+    #[test]
+    fn extended_interrupt_descriptor() {
+        // Interrupt (ResourceConsumer, Level, ActiveLow, Shared) { 16, 17 }
+        let mut buffer = vec![0x89, 10, 0, 0b1101, 2];
+        buffer.extend_from_slice(&16u32.to_le_bytes());
+        buffer.extend_from_slice(&17u32.to_le_bytes());
+        buffer.extend_from_slice(&[0x79, 0]);
+        assert_eq!(
+            interrupt_resources(&buffer),
+            [InterruptResource {
+                interrupts: vec![16, 17],
+                edge_triggered: false,
+                active_low: true,
+                shared: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn other_descriptors_are_skipped() {
+        // an IO port descriptor, a DWord address space, then an IRQ
+        let mut buffer = vec![0x47, 1, 0x60, 0, 0x60, 0, 1, 1];
+        buffer.extend_from_slice(&[0x87, 3, 0, 1, 2, 3]);
+        buffer.extend_from_slice(&[0x22, 0x10, 0x00]);
+        let resources = interrupt_resources(&buffer);
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].interrupts, [4]);
+    }
+
+    #[test]
+    fn truncated_descriptors_stop() {
+        assert!(interrupt_resources(&[0x23, 0x20]).is_empty());
+        assert!(interrupt_resources(&[0x89, 10]).is_empty());
+        assert!(interrupt_resources(&[0x89, 10, 0, 0, 2, 16]).is_empty());
+        assert!(interrupt_resources(&[]).is_empty());
+    }
+}
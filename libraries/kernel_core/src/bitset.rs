@@ -0,0 +1,165 @@
+use alloc::{vec, vec::Vec};
+
+/// A fixed size set of bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitSet {
+    /// Creates a set of `len` bits, all cleared
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Panics if `index` is out of bounds
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "bit {index} out of bounds {}", self.len);
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Sets all the bits in `start..end`, clamped to the length of the set
+    pub fn set_range(&mut self, start: usize, end: usize) {
+        for index in start..end.min(self.len) {
+            self.set(index);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// The number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// The indices of the set bits, in order
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.get(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_is_cleared() {
+        let set = BitSet::new(100);
+        assert_eq!(set.len(), 100);
+        assert!(!set.is_empty());
+        assert_eq!(set.count_ones(), 0);
+        assert!((0..100).all(|i| !set.get(i)));
+        assert_eq!(set.iter_ones().next(), None);
+    }
+
+    #[test]
+    fn empty_set() {
+        let set = BitSet::new(0);
+        assert!(set.is_empty());
+        assert!(!set.get(0));
+        assert_eq!(set.count_ones(), 0);
+    }
+
+    #[test]
+    fn set_and_get_across_words() {
+        let mut set = BitSet::new(130);
+        for index in [0, 1, 63, 64, 65, 127, 128, 129] {
+            set.set(index);
+        }
+        for index in 0..130 {
+            assert_eq!(
+                set.get(index),
+                [0, 1, 63, 64, 65, 127, 128, 129].contains(&index),
+                "bit {index}"
+            );
+        }
+        assert_eq!(set.count_ones(), 8);
+    }
+
+    #[test]
+    fn set_twice_counts_once() {
+        let mut set = BitSet::new(10);
+        set.set(3);
+        set.set(3);
+        assert_eq!(set.count_ones(), 1);
+    }
+
+    #[test]
+    fn get_out_of_bounds_is_false() {
+        let mut set = BitSet::new(64);
+        set.set_range(0, 64);
+        assert!(set.get(63));
+        assert!(!set.get(64));
+        assert!(!set.get(usize::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "bit 10 out of bounds 10")]
+    fn set_out_of_bounds_panics() {
+        BitSet::new(10).set(10);
+    }
+
+    #[test]
+    fn set_range_is_half_open() {
+        let mut set = BitSet::new(200);
+        set.set_range(60, 70);
+        assert_eq!(
+            set.iter_ones().collect::<Vec<_>>(),
+            (60..70).collect::<Vec<_>>()
+        );
+        assert!(!set.get(59));
+        assert!(!set.get(70));
+    }
+
+    #[test]
+    fn set_range_is_clamped() {
+        let mut set = BitSet::new(10);
+        set.set_range(8, 1000);
+        assert_eq!(set.iter_ones().collect::<Vec<_>>(), [8, 9]);
+        // start after the end, nothing
+        set.set_range(5, 2);
+        assert_eq!(set.count_ones(), 2);
+    }
+
+    #[test]
+    fn last_partial_word() {
+        let mut set = BitSet::new(65);
+        set.set_range(0, 65);
+        assert_eq!(set.count_ones(), 65);
+        assert_eq!(set.iter_ones().last(), Some(64));
+    }
+
+    #[test]
+    fn iter_ones_in_order() {
+        let mut set = BitSet::new(300);
+        for index in [299, 5, 150, 64, 0] {
+            set.set(index);
+        }
+        assert_eq!(set.iter_ones().collect::<Vec<_>>(), [0, 5, 64, 150, 299]);
+    }
+
+    #[test]
+    fn equal_by_content() {
+        let mut a = BitSet::new(70);
+        let mut b = BitSet::new(70);
+        a.set(69);
+        assert_ne!(a, b);
+        b.set(69);
+        assert_eq!(a, b);
+        assert_ne!(BitSet::new(70), BitSet::new(71));
+    }
+}
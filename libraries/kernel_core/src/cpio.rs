@@ -0,0 +1,328 @@
+//! A parser for cpio archives in the `newc` format, the format used for initrd images.
+//!
+//! Each entry is a header of ASCII hex fields, followed by the name and the file data,
+//! both padded to 4 bytes. The archive ends with an entry named [`TRAILER_NAME`].
+
+const MAGIC_NEWC: &[u8; 6] = b"070701";
+// same as `newc`, but with a checksum of the data in the `check` field, which we ignore
+const MAGIC_NEWC_CRC: &[u8; 6] = b"070702";
+const HEADER_SIZE: usize = 110;
+const ALIGNMENT: usize = 4;
+
+pub const TRAILER_NAME: &str = "TRAILER!!!";
+
+mod mode {
+    pub const TYPE_MASK: u32 = 0o170000;
+    pub const DIRECTORY: u32 = 0o040000;
+    pub const REGULAR: u32 = 0o100000;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    InvalidMagic {
+        offset: usize,
+    },
+    InvalidHeader {
+        offset: usize,
+    },
+    InvalidName {
+        offset: usize,
+    },
+    /// The archive ended before the trailer
+    UnexpectedEnd {
+        offset: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// The path of the entry, without any leading `./` or `/`
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & mode::TYPE_MASK == mode::DIRECTORY
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & mode::TYPE_MASK == mode::REGULAR
+    }
+}
+
+/// Whether `data` starts with a `newc` cpio header
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NEWC) || data.starts_with(MAGIC_NEWC_CRC)
+}
+
+/// Iterates over the entries of the archive in `data`, stops after the trailer
+/// or the first error
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries {
+        data,
+        offset: 0,
+        done: false,
+    }
+}
+
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// Parses the field `index` of the header, each is 8 hex digits
+fn header_field(header: &[u8], index: usize) -> Option<u32> {
+    let start = MAGIC_NEWC.len() + index * 8;
+    let field = core::str::from_utf8(header.get(start..start + 8)?).ok()?;
+    u32::from_str_radix(field, 16).ok()
+}
+
+impl<'a> Entries<'a> {
+    fn parse_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let offset = self.offset;
+        let header = self
+            .data
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(CpioError::UnexpectedEnd { offset })?;
+        if !is_cpio(header) {
+            return Err(CpioError::InvalidMagic { offset });
+        }
+
+        let field = |index| header_field(header, index).ok_or(CpioError::InvalidHeader { offset });
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        // the size includes the zero terminator
+        let name = name_size
+            .checked_sub(1)
+            .and_then(|len| self.data.get(name_start..name_start + len))
+            .ok_or(CpioError::UnexpectedEnd { offset })?;
+        let name = core::str::from_utf8(name).map_err(|_| CpioError::InvalidName { offset })?;
+
+        let data_start = (name_start + name_size).next_multiple_of(ALIGNMENT);
+        let data = data_start
+            .checked_add(file_size)
+            .and_then(|data_end| self.data.get(data_start..data_end))
+            .ok_or(CpioError::UnexpectedEnd { offset })?;
+        self.offset = (data_start + file_size).next_multiple_of(ALIGNMENT);
+
+        if name == TRAILER_NAME {
+            return Ok(None);
+        }
+
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{format, string::String, vec::Vec};
+
+    /// Appends an entry in the `newc` format, with the padding
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+        archive.extend_from_slice(MAGIC_NEWC);
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08X}").as_bytes());
+        }
+        archive.extend_from_slice(format!("{:08X}", name.len() + 1).as_bytes());
+        // check
+        archive.extend_from_slice(b"00000000");
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(ALIGNMENT), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(ALIGNMENT), 0);
+    }
+
+    fn archive(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for &(name, mode, data) in files {
+            push_entry(&mut archive, name, mode, data);
+        }
+        push_entry(&mut archive, TRAILER_NAME, 0, &[]);
+        archive
+    }
+
+    const FILE: u32 = mode::REGULAR | 0o644;
+    const DIR: u32 = mode::DIRECTORY | 0o755;
+
+    #[test]
+    fn header_is_the_size_of_the_fields() {
+        let mut entry = Vec::new();
+        push_entry(&mut entry, "", 0, &[]);
+        // 110 bytes of header, the terminator, then the padding
+        assert_eq!(entry.len(), 112);
+        assert!(is_cpio(&entry));
+    }
+
+    #[test]
+    fn detects_both_magics() {
+        assert!(is_cpio(b"070701"));
+        assert!(is_cpio(b"070702more"));
+        assert!(!is_cpio(b"070707"));
+        assert!(!is_cpio(b"0707"));
+        assert!(!is_cpio(&[]));
+    }
+
+    #[test]
+    fn lists_the_entries() {
+        let data = archive(&[
+            ("dir", DIR, &[]),
+            ("dir/a.txt", FILE, b"hello"),
+            ("b", FILE, b"1234"),
+        ]);
+        let entries = entries(&data).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, "dir");
+        assert!(entries[0].is_dir());
+        assert!(!entries[0].is_file());
+        assert_eq!(entries[1].name, "dir/a.txt");
+        assert!(entries[1].is_file());
+        assert_eq!(entries[1].data, b"hello");
+        assert_eq!(entries[2].data, b"1234");
+        assert_eq!(entries[2].mode, FILE);
+    }
+
+    #[test]
+    fn data_of_every_padding() {
+        // names and data of every length modulo the alignment
+        let contents: Vec<Vec<u8>> = (0..8).map(|len| (0..len).collect()).collect();
+        let names: Vec<String> = (0..8).map(|len| "n".repeat(len + 1)).collect();
+        let files: Vec<(&str, u32, &[u8])> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| (name.as_str(), FILE, data.as_slice()))
+            .collect();
+        let data = archive(&files);
+        let entries = entries(&data).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), files.len());
+        for (entry, (name, _, data)) in entries.iter().zip(&files) {
+            assert_eq!(entry.name, *name);
+            assert_eq!(entry.data, *data);
+        }
+    }
+
+    #[test]
+    fn leading_dot_slash_is_removed() {
+        let data = archive(&[("./a", FILE, &[]), ("/b", FILE, &[]), ("./", DIR, &[])]);
+        let names = entries(&data)
+            .map(|entry| entry.unwrap().name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", ""]);
+    }
+
+    #[test]
+    fn stops_at_the_trailer() {
+        let mut data = archive(&[("a", FILE, b"x")]);
+        // anything after the trailer is not read
+        data.extend_from_slice(b"garbage");
+        let mut entries = entries(&data);
+        assert!(entries.next().unwrap().is_ok());
+        assert!(entries.next().is_none());
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn empty_archive() {
+        let data = archive(&[]);
+        assert_eq!(entries(&data).count(), 0);
+    }
+
+    #[test]
+    fn invalid_magic() {
+        let mut data = archive(&[("a", FILE, b"x")]);
+        let second = 112 + 4;
+        data[second] = b'9';
+        let mut entries = entries(&data);
+        assert!(entries.next().unwrap().is_ok());
+        assert_eq!(
+            entries.next().unwrap().unwrap_err(),
+            CpioError::InvalidMagic { offset: second }
+        );
+        // stops after the first error
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn invalid_header_field() {
+        let mut data = archive(&[("a", FILE, b"x")]);
+        // a digit of the file size
+        data[6 + 6 * 8] = b'G';
+        assert_eq!(
+            entries(&data).next().unwrap().unwrap_err(),
+            CpioError::InvalidHeader { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn invalid_name() {
+        let mut data = archive(&[("ab", FILE, b"x")]);
+        data[HEADER_SIZE] = 0xFF;
+        assert_eq!(
+            entries(&data).next().unwrap().unwrap_err(),
+            CpioError::InvalidName { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn zero_name_size() {
+        let mut data = archive(&[("a", FILE, b"x")]);
+        data[6 + 11 * 8..6 + 12 * 8].copy_from_slice(b"00000000");
+        assert_eq!(
+            entries(&data).next().unwrap().unwrap_err(),
+            CpioError::UnexpectedEnd { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn huge_file_size() {
+        let mut data = archive(&[("a", FILE, b"x")]);
+        data[6 + 6 * 8..6 + 7 * 8].copy_from_slice(b"FFFFFFFF");
+        assert_eq!(
+            entries(&data).next().unwrap().unwrap_err(),
+            CpioError::UnexpectedEnd { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn truncated_anywhere_is_an_error() {
+        let data = archive(&[("dir", DIR, &[]), ("dir/file", FILE, b"contents")]);
+        for len in 0..data.len() {
+            let result = entries(&data[..len]).collect::<Result<Vec<_>, _>>();
+            assert!(
+                matches!(result, Err(CpioError::UnexpectedEnd { .. })),
+                "truncated at {len}: {result:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! A short chain of what was being done when an error happened, kept inside the error.
+//!
+//! Each layer the error goes through can push a [`Frame`], from the innermost to the
+//! outermost, and they are shown the other way around separated by `: `, i.e.
+//! `read directory cluster 4: read FAT sector 1234`.
+//!
+//! The chain is fixed-size and never allocates, so it can be added on any error path,
+//! including the ones that fail because of low memory. The frames after [`MAX_FRAMES`] are
+//! only counted.
+
+use core::fmt;
+
+/// The most frames kept, the innermost ones are kept, as they are closer to the failure
+pub const MAX_FRAMES: usize = 3;
+
+/// One step of the chain, `what` is shown followed by `value` if given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub what: &'static str,
+    pub value: Option<u32>,
+}
+
+impl Frame {
+    const EMPTY: Self = Self {
+        what: "",
+        value: None,
+    };
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Some(value) => write!(f, "{} {value}", self.what),
+            None => write!(f, "{}", self.what),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorContext {
+    // innermost first
+    frames: [Frame; MAX_FRAMES],
+    len: u8,
+    dropped: u8,
+}
+
+impl ErrorContext {
+    pub const fn new() -> Self {
+        Self {
+            frames: [Frame::EMPTY; MAX_FRAMES],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Adds a frame outside the ones already pushed, only counted if full
+    pub fn push(&mut self, what: &'static str, value: Option<u32>) {
+        match self.frames.get_mut(self.len as usize) {
+            Some(frame) => {
+                *frame = Frame { what, value };
+                self.len += 1;
+            }
+            None => self.dropped = self.dropped.saturating_add(1),
+        }
+    }
+
+    /// The frames kept, from the innermost
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames[..self.len as usize]
+    }
+
+    /// The number of frames pushed when the chain was full
+    pub fn dropped(&self) -> usize {
+        self.dropped as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for ErrorContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outermost frame first, nothing if empty
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dropped != 0 {
+            write!(f, "({} more)", self.dropped)?;
+        }
+        for (i, frame) in self.frames().iter().rev().enumerate() {
+            if i != 0 || self.dropped != 0 {
+                f.write_str(": ")?;
+            }
+            write!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::string::ToString;
+
+    #[test]
+    fn empty_shows_nothing() {
+        let context = ErrorContext::new();
+        assert!(context.is_empty());
+        assert_eq!(context.frames(), []);
+        assert_eq!(context.dropped(), 0);
+        assert_eq!(context.to_string(), "");
+    }
+
+    #[test]
+    fn frame_with_and_without_value() {
+        let with = Frame {
+            what: "read FAT sector",
+            value: Some(1234),
+        };
+        let without = Frame {
+            what: "open root",
+            value: None,
+        };
+        assert_eq!(with.to_string(), "read FAT sector 1234");
+        assert_eq!(without.to_string(), "open root");
+    }
+
+    #[test]
+    fn outermost_is_shown_first() {
+        let mut context = ErrorContext::default();
+        context.push("read FAT sector", Some(1234));
+        context.push("read directory cluster", Some(4));
+        assert!(!context.is_empty());
+        assert_eq!(context.frames()[0].what, "read FAT sector");
+        assert_eq!(
+            context.to_string(),
+            "read directory cluster 4: read FAT sector 1234"
+        );
+    }
+
+    #[test]
+    fn keeps_the_innermost_when_full() {
+        let mut context = ErrorContext::new();
+        for i in 0..MAX_FRAMES as u32 + 2 {
+            context.push("step", Some(i));
+        }
+        assert_eq!(context.frames().len(), MAX_FRAMES);
+        assert_eq!(context.dropped(), 2);
+        assert_eq!(context.frames()[0].value, Some(0));
+        assert_eq!(context.to_string(), "(2 more): step 2: step 1: step 0");
+    }
+
+    #[test]
+    fn dropped_saturates() {
+        let mut context = ErrorContext::new();
+        for _ in 0..MAX_FRAMES + 1000 {
+            context.push("step", None);
+        }
+        assert_eq!(context.dropped(), u8::MAX as usize);
+        assert_eq!(context.frames().len(), MAX_FRAMES);
+    }
+
+    #[test]
+    fn copies_are_independent() {
+        let mut context = ErrorContext::new();
+        context.push("inner", None);
+        let copy = context;
+        context.push("outer", None);
+        assert_eq!(copy.to_string(), "inner");
+        assert_eq!(context.to_string(), "outer: inner");
+    }
+}
@@ -0,0 +1,300 @@
+//! The pure parts of FAT, the entries of the allocation table
+
+use crate::sector::Cluster;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatEntry {
+    Free,
+    // In use, and point to the next cluster
+    Next(Cluster),
+    // In use, and this is the last cluster
+    EndOfChain,
+    Bad,
+    Reserved,
+}
+
+impl FatType {
+    /// The type of the FAT from the number of data clusters, this is the only
+    /// correct way to determine it according to the specification
+    pub fn from_cluster_count(count_of_clusters: u32) -> FatType {
+        match count_of_clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    /// The offset of the entry of `cluster` inside the FAT, in bytes
+    pub fn entry_offset(self, cluster: Cluster) -> usize {
+        let entry = cluster.0;
+        (match self {
+            FatType::Fat12 => entry * 3 / 2,
+            FatType::Fat16 => entry * 2,
+            FatType::Fat32 => entry * 4,
+        }) as usize
+    }
+}
+
+impl FatEntry {
+    /// Reads the entry of `cluster` from the FAT table `fat`
+    ///
+    /// Panics if the entry is outside `fat`
+    pub fn read(ty: FatType, fat: &[u8], cluster: Cluster) -> FatEntry {
+        let fat_offset = ty.entry_offset(cluster);
+        let entry = match ty {
+            FatType::Fat12 => {
+                let byte1 = fat[fat_offset];
+                let byte2 = fat[fat_offset + 1];
+                if cluster.0 & 1 == 1 {
+                    ((byte2 as u32) << 4) | ((byte1 as u32) >> 4)
+                } else {
+                    (((byte2 as u32) & 0xF) << 8) | (byte1 as u32)
+                }
+            }
+            FatType::Fat16 => {
+                u16::from_le_bytes(fat[fat_offset..fat_offset + 2].try_into().unwrap()) as u32
+            }
+            FatType::Fat32 => {
+                u32::from_le_bytes(fat[fat_offset..fat_offset + 4].try_into().unwrap())
+                    & 0x0FFF_FFFF
+            }
+        };
+
+        FatEntry::from_u32(ty, entry)
+    }
+
+    /// Writes this entry as the one of `cluster` into the FAT table `fat`, for FAT12 the
+    /// other entry sharing the byte is kept, and for FAT32 the reserved upper 4 bits are kept
+    ///
+    /// Panics if the entry is outside `fat`
+    pub fn write(self, ty: FatType, fat: &mut [u8], cluster: Cluster) {
+        let fat_offset = ty.entry_offset(cluster);
+        let value = self.to_u32(ty);
+        match ty {
+            FatType::Fat12 => {
+                if cluster.0 & 1 == 1 {
+                    fat[fat_offset] = (fat[fat_offset] & 0x0F) | ((value << 4) as u8);
+                    fat[fat_offset + 1] = (value >> 4) as u8;
+                } else {
+                    fat[fat_offset] = value as u8;
+                    fat[fat_offset + 1] = (fat[fat_offset + 1] & 0xF0) | ((value >> 8) as u8);
+                }
+            }
+            FatType::Fat16 => {
+                fat[fat_offset..fat_offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+            }
+            FatType::Fat32 => {
+                let old = u32::from_le_bytes(fat[fat_offset..fat_offset + 4].try_into().unwrap());
+                let value = (old & 0xF000_0000) | value;
+                fat[fat_offset..fat_offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    /// The value stored in the table for this entry, the inverse of [`FatEntry::from_u32`]
+    pub fn to_u32(self, ty: FatType) -> u32 {
+        let (end_of_chain, bad) = match ty {
+            FatType::Fat12 => (0xFFF, 0xFF7),
+            FatType::Fat16 => (0xFFFF, 0xFFF7),
+            FatType::Fat32 => (0x0FFF_FFFF, 0x0FFF_FFF7),
+        };
+        match self {
+            FatEntry::Free => 0,
+            FatEntry::Next(cluster) => cluster.0,
+            FatEntry::EndOfChain => end_of_chain,
+            FatEntry::Bad => bad,
+            // the only reserved value we can produce is `1`, used by no cluster
+            FatEntry::Reserved => 1,
+        }
+    }
+
+    pub fn from_u32(ty: FatType, entry: u32) -> FatEntry {
+        match ty {
+            FatType::Fat12 => {
+                if entry == 0 {
+                    FatEntry::Free
+                } else if entry >= 0xFF8 {
+                    FatEntry::EndOfChain
+                } else if entry == 0xFF7 {
+                    FatEntry::Bad
+                } else if (0x002..=0xFF6).contains(&entry) {
+                    FatEntry::Next(Cluster(entry))
+                } else {
+                    FatEntry::Reserved
+                }
+            }
+            FatType::Fat16 => {
+                if entry == 0 {
+                    FatEntry::Free
+                } else if entry >= 0xFFF8 {
+                    FatEntry::EndOfChain
+                } else if entry == 0xFFF7 {
+                    FatEntry::Bad
+                } else if (0x002..=0xFFF6).contains(&entry) {
+                    FatEntry::Next(Cluster(entry))
+                } else {
+                    FatEntry::Reserved
+                }
+            }
+            FatType::Fat32 => {
+                if entry == 0 {
+                    FatEntry::Free
+                } else if entry >= 0x0FFF_FFF8 {
+                    FatEntry::EndOfChain
+                } else if entry == 0x0FFF_FFF7 {
+                    FatEntry::Bad
+                } else if (0x002..=0x0FFF_FFF6).contains(&entry) {
+                    FatEntry::Next(Cluster(entry))
+                } else {
+                    FatEntry::Reserved
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [FatType; 3] = [FatType::Fat12, FatType::Fat16, FatType::Fat32];
+
+    #[test]
+    fn type_from_cluster_count() {
+        assert_eq!(FatType::from_cluster_count(0), FatType::Fat12);
+        assert_eq!(FatType::from_cluster_count(4084), FatType::Fat12);
+        assert_eq!(FatType::from_cluster_count(4085), FatType::Fat16);
+        assert_eq!(FatType::from_cluster_count(65524), FatType::Fat16);
+        assert_eq!(FatType::from_cluster_count(65525), FatType::Fat32);
+        assert_eq!(FatType::from_cluster_count(u32::MAX), FatType::Fat32);
+    }
+
+    #[test]
+    fn entry_offsets() {
+        assert_eq!(FatType::Fat12.entry_offset(Cluster(0)), 0);
+        assert_eq!(FatType::Fat12.entry_offset(Cluster(1)), 1);
+        assert_eq!(FatType::Fat12.entry_offset(Cluster(2)), 3);
+        assert_eq!(FatType::Fat12.entry_offset(Cluster(3)), 4);
+        assert_eq!(FatType::Fat16.entry_offset(Cluster(3)), 6);
+        assert_eq!(FatType::Fat32.entry_offset(Cluster(3)), 12);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for ty in TYPES {
+            for entry in [
+                FatEntry::Free,
+                FatEntry::Next(Cluster(2)),
+                FatEntry::Next(Cluster(0xFF6)),
+                FatEntry::EndOfChain,
+                FatEntry::Bad,
+                FatEntry::Reserved,
+            ] {
+                assert_eq!(FatEntry::from_u32(ty, entry.to_u32(ty)), entry, "{ty:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn special_values() {
+        assert_eq!(
+            FatEntry::from_u32(FatType::Fat12, 0xFF8),
+            FatEntry::EndOfChain
+        );
+        assert_eq!(FatEntry::from_u32(FatType::Fat12, 0xFF7), FatEntry::Bad);
+        assert_eq!(FatEntry::from_u32(FatType::Fat12, 1), FatEntry::Reserved);
+        assert_eq!(
+            FatEntry::from_u32(FatType::Fat16, 0xFFF8),
+            FatEntry::EndOfChain
+        );
+        assert_eq!(
+            FatEntry::from_u32(FatType::Fat16, 0xFF8),
+            FatEntry::Next(Cluster(0xFF8))
+        );
+        assert_eq!(
+            FatEntry::from_u32(FatType::Fat32, 0x0FFF_FFF8),
+            FatEntry::EndOfChain
+        );
+        assert_eq!(
+            FatEntry::from_u32(FatType::Fat32, 0xFFF8),
+            FatEntry::Next(Cluster(0xFFF8))
+        );
+    }
+
+    #[test]
+    fn fat12_shares_bytes() {
+        let mut fat = [0u8; 6];
+        FatEntry::Next(Cluster(0xABC)).write(FatType::Fat12, &mut fat, Cluster(2));
+        FatEntry::Next(Cluster(0x123)).write(FatType::Fat12, &mut fat, Cluster(3));
+        assert_eq!(fat[3..], [0xBC, 0x3A, 0x12]);
+        assert_eq!(
+            FatEntry::read(FatType::Fat12, &fat, Cluster(2)),
+            FatEntry::Next(Cluster(0xABC))
+        );
+        assert_eq!(
+            FatEntry::read(FatType::Fat12, &fat, Cluster(3)),
+            FatEntry::Next(Cluster(0x123))
+        );
+
+        // rewriting one keeps the other
+        FatEntry::EndOfChain.write(FatType::Fat12, &mut fat, Cluster(2));
+        assert_eq!(
+            FatEntry::read(FatType::Fat12, &fat, Cluster(3)),
+            FatEntry::Next(Cluster(0x123))
+        );
+        FatEntry::Free.write(FatType::Fat12, &mut fat, Cluster(3));
+        assert_eq!(
+            FatEntry::read(FatType::Fat12, &fat, Cluster(2)),
+            FatEntry::EndOfChain
+        );
+        assert_eq!(
+            FatEntry::read(FatType::Fat12, &fat, Cluster(3)),
+            FatEntry::Free
+        );
+    }
+
+    #[test]
+    fn fat16_little_endian() {
+        let mut fat = [0u8; 8];
+        FatEntry::Next(Cluster(0x1234)).write(FatType::Fat16, &mut fat, Cluster(2));
+        assert_eq!(fat[4..6], [0x34, 0x12]);
+        assert_eq!(
+            FatEntry::read(FatType::Fat16, &fat, Cluster(2)),
+            FatEntry::Next(Cluster(0x1234))
+        );
+    }
+
+    #[test]
+    fn fat32_keeps_the_upper_bits() {
+        let mut fat = [0u8; 16];
+        fat[8..12].copy_from_slice(&0xA000_0000u32.to_le_bytes());
+        FatEntry::Next(Cluster(0x0123_4567)).write(FatType::Fat32, &mut fat, Cluster(2));
+        assert_eq!(
+            u32::from_le_bytes(fat[8..12].try_into().unwrap()),
+            0xA123_4567
+        );
+        // and the reads ignore them
+        assert_eq!(
+            FatEntry::read(FatType::Fat32, &fat, Cluster(2)),
+            FatEntry::Next(Cluster(0x0123_4567))
+        );
+        fat[12..16].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        assert_eq!(
+            FatEntry::read(FatType::Fat32, &fat, Cluster(3)),
+            FatEntry::EndOfChain
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn read_outside_panics() {
+        FatEntry::read(FatType::Fat16, &[0; 4], Cluster(2));
+    }
+}
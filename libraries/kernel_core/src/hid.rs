@@ -0,0 +1,369 @@
+//! The boot protocol of USB keyboards, translated to the scancodes (set 1) of a PS/2 keyboard.
+//!
+//! A boot keyboard sends a report of [`BOOT_REPORT_LEN`] bytes whenever its keys change: a
+//! bitmap of the modifiers, a reserved byte, then the usages (HID key codes) of up to 6 keys
+//! that are down. There are no press and release events, [`BootKeyboard`] keeps the last
+//! report and gives the scancodes of the difference, so the keyboard driver gets the same
+//! stream a PS/2 keyboard would send.
+
+use alloc::vec::Vec;
+
+pub const BOOT_REPORT_LEN: usize = 8;
+
+const KEY_SLOTS: usize = 6;
+
+/// The usages `ErrorRollOver`, `POSTFail` and `ErrorUndefined`, all the slots have one of them
+/// when the keyboard can't say which keys are down
+const ERROR_USAGES: core::ops::RangeInclusive<u8> = 0x01..=0x03;
+
+const EXTENDED_PREFIX: u8 = 0xE0;
+const KEY_RELEASED: u8 = 0x80;
+
+/// The bits of the output report, the LEDs of the keyboard
+pub mod led {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+}
+
+/// The scancodes of the bits of the modifiers byte, with whether they are extended: left
+/// ctrl, shift, alt, gui, then the same on the right
+const MODIFIERS: [(bool, u8); 8] = [
+    (false, 0x1D),
+    (false, 0x2A),
+    (false, 0x38),
+    (true, 0x5B),
+    (true, 0x1D),
+    (false, 0x36),
+    (true, 0x38),
+    (true, 0x5C),
+];
+
+/// `A` to `Z`, the usages `0x04..=0x1D`
+const LETTERS: [u8; 26] = [
+    0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32, 0x31, 0x18, 0x19,
+    0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C,
+];
+
+/// `Enter` to `/`, the usages `0x28..=0x38`, the non-US `#` is the same key as `\`
+const PUNCTUATION: [u8; 17] = [
+    0x1C, 0x01, 0x0E, 0x0F, 0x39, 0x0C, 0x0D, 0x1A, 0x1B, 0x2B, 0x2B, 0x27, 0x28, 0x29, 0x33, 0x34,
+    0x35,
+];
+
+/// Keypad `1` to `9`, `0` and `.`, the usages `0x59..=0x63`
+const KEYPAD: [u8; 11] = [
+    0x4F, 0x50, 0x51, 0x4B, 0x4C, 0x4D, 0x47, 0x48, 0x49, 0x52, 0x53,
+];
+
+/// The set 1 scancode of a usage of the keyboard page, with whether it is extended (has the
+/// `0xE0` prefix). `None` for the keys without one, and `PrintScreen` and `Pause`, whose
+/// scancodes are sequences the keyboard driver doesn't use
+pub fn usage_to_scancode(usage: u8) -> Option<(bool, u8)> {
+    let code = match usage {
+        0x04..=0x1D => LETTERS[(usage - 0x04) as usize],
+        // `1` to `9`
+        0x1E..=0x26 => usage - 0x1E + 0x02,
+        0x27 => 0x0B,
+        0x28..=0x38 => PUNCTUATION[(usage - 0x28) as usize],
+        // caps lock
+        0x39 => 0x3A,
+        // `F1` to `F10`
+        0x3A..=0x43 => usage - 0x3A + 0x3B,
+        0x44 => 0x57,
+        0x45 => 0x58,
+        // scroll lock
+        0x47 => 0x46,
+        // num lock
+        0x53 => 0x45,
+        // keypad `*`, `-`, `+`
+        0x55 => 0x37,
+        0x56 => 0x4A,
+        0x57 => 0x4E,
+        0x59..=0x63 => KEYPAD[(usage - 0x59) as usize],
+        // the non-US `\`, next to the left shift
+        0x64 => 0x56,
+        _ => {
+            let extended = match usage {
+                // insert, home, page up, delete, end, page down
+                0x49 => 0x52,
+                0x4A => 0x47,
+                0x4B => 0x49,
+                0x4C => 0x53,
+                0x4D => 0x4F,
+                0x4E => 0x51,
+                // right, left, down, up
+                0x4F => 0x4D,
+                0x50 => 0x4B,
+                0x51 => 0x50,
+                0x52 => 0x48,
+                // keypad `/` and enter
+                0x54 => 0x35,
+                0x58 => 0x1C,
+                // the menu key
+                0x65 => 0x5D,
+                _ => return None,
+            };
+            return Some((true, extended));
+        }
+    };
+    Some((false, code))
+}
+
+fn push_scancode(extended: bool, code: u8, pressed: bool, out: &mut Vec<u8>) {
+    if extended {
+        out.push(EXTENDED_PREFIX);
+    }
+    out.push(if pressed { code } else { code | KEY_RELEASED });
+}
+
+/// The state of a boot keyboard, what its last report said is down
+#[derive(Debug, Clone, Default)]
+pub struct BootKeyboard {
+    modifiers: u8,
+    keys: [u8; KEY_SLOTS],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes to `out` the scancodes of what changed from the last report to `report`.
+    ///
+    /// The modifiers pressed come first and the ones released last, so the keys of the same
+    /// report get them, i.e. a shift and a letter pressed together give an uppercase letter.
+    /// A report shorter than [`BOOT_REPORT_LEN`] or of an error (too many keys down) is ignored,
+    /// the last one stays the state.
+    pub fn report(&mut self, report: &[u8], out: &mut Vec<u8>) {
+        let Some(report) = report.get(..BOOT_REPORT_LEN) else {
+            return;
+        };
+        let modifiers = report[0];
+        let keys: [u8; KEY_SLOTS] = report[2..].try_into().unwrap();
+        if keys.iter().any(|usage| ERROR_USAGES.contains(usage)) {
+            return;
+        }
+
+        let pressed_modifiers = modifiers & !self.modifiers;
+        let released_modifiers = self.modifiers & !modifiers;
+        for (bit, &(extended, code)) in MODIFIERS.iter().enumerate() {
+            if pressed_modifiers & (1 << bit) != 0 {
+                push_scancode(extended, code, true, out);
+            }
+        }
+        for &usage in self.keys.iter().filter(|&&usage| usage != 0) {
+            if !keys.contains(&usage) {
+                if let Some((extended, code)) = usage_to_scancode(usage) {
+                    push_scancode(extended, code, false, out);
+                }
+            }
+        }
+        for &usage in keys.iter().filter(|&&usage| usage != 0) {
+            if !self.keys.contains(&usage) {
+                if let Some((extended, code)) = usage_to_scancode(usage) {
+                    push_scancode(extended, code, true, out);
+                }
+            }
+        }
+        for (bit, &(extended, code)) in MODIFIERS.iter().enumerate() {
+            if released_modifiers & (1 << bit) != 0 {
+                push_scancode(extended, code, false, out);
+            }
+        }
+
+        self.modifiers = modifiers;
+        self.keys = keys;
+    }
+
+    /// Releases all the keys that are down, for when the keyboard is gone
+    pub fn release_all(&mut self, out: &mut Vec<u8>) {
+        self.report(&[0; BOOT_REPORT_LEN], out);
+    }
+
+    /// Whether no key is down
+    pub fn is_idle(&self) -> bool {
+        self.modifiers == 0 && self.keys.iter().all(|&usage| usage == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+
+    const LEFT_SHIFT: u8 = 1 << 1;
+    const RIGHT_CTRL: u8 = 1 << 4;
+    const USAGE_A: u8 = 0x04;
+    const USAGE_B: u8 = 0x05;
+
+    fn report(modifiers: u8, keys: &[u8]) -> [u8; BOOT_REPORT_LEN] {
+        let mut report = [0; BOOT_REPORT_LEN];
+        report[0] = modifiers;
+        report[2..2 + keys.len()].copy_from_slice(keys);
+        report
+    }
+
+    /// The scancodes of `reports` given in order to a new keyboard
+    fn scancodes(reports: &[[u8; BOOT_REPORT_LEN]]) -> Vec<u8> {
+        let mut keyboard = BootKeyboard::new();
+        let mut out = Vec::new();
+        for report in reports {
+            keyboard.report(report, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn letters_and_digits() {
+        assert_eq!(usage_to_scancode(USAGE_A), Some((false, 0x1E)));
+        assert_eq!(usage_to_scancode(0x1D), Some((false, 0x2C)));
+        // `1` and `0`
+        assert_eq!(usage_to_scancode(0x1E), Some((false, 0x02)));
+        assert_eq!(usage_to_scancode(0x26), Some((false, 0x0A)));
+        assert_eq!(usage_to_scancode(0x27), Some((false, 0x0B)));
+    }
+
+    #[test]
+    fn function_keys() {
+        assert_eq!(usage_to_scancode(0x3A), Some((false, 0x3B)));
+        assert_eq!(usage_to_scancode(0x43), Some((false, 0x44)));
+        assert_eq!(usage_to_scancode(0x44), Some((false, 0x57)));
+        assert_eq!(usage_to_scancode(0x45), Some((false, 0x58)));
+    }
+
+    #[test]
+    fn extended_keys() {
+        // up and delete
+        assert_eq!(usage_to_scancode(0x52), Some((true, 0x48)));
+        assert_eq!(usage_to_scancode(0x4C), Some((true, 0x53)));
+        // keypad enter is extended, the main enter isn't
+        assert_eq!(usage_to_scancode(0x58), Some((true, 0x1C)));
+        assert_eq!(usage_to_scancode(0x28), Some((false, 0x1C)));
+    }
+
+    #[test]
+    fn keys_without_scancodes() {
+        // none, print screen, pause and the last usages
+        for usage in [0x00, 0x46, 0x48, 0x66, 0xE0, 0xFF] {
+            assert_eq!(usage_to_scancode(usage), None, "usage {usage:#x}");
+        }
+    }
+
+    #[test]
+    fn every_scancode_is_a_make_code() {
+        for usage in 0..=u8::MAX {
+            if let Some((_, code)) = usage_to_scancode(usage) {
+                assert!(code != 0 && code & KEY_RELEASED == 0, "usage {usage:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn press_and_release() {
+        let out = scancodes(&[report(0, &[USAGE_A]), report(0, &[])]);
+        assert_eq!(out, [0x1E, 0x9E]);
+    }
+
+    #[test]
+    fn same_report_twice_is_nothing() {
+        let out = scancodes(&[report(0, &[USAGE_A]), report(0, &[USAGE_A])]);
+        assert_eq!(out, [0x1E]);
+    }
+
+    #[test]
+    fn shift_comes_before_the_letter() {
+        let out = scancodes(&[report(LEFT_SHIFT, &[USAGE_A]), report(0, &[])]);
+        assert_eq!(out, [0x2A, 0x1E, 0x9E, 0xAA]);
+    }
+
+    #[test]
+    fn extended_modifier() {
+        let out = scancodes(&[report(RIGHT_CTRL, &[]), report(0, &[])]);
+        assert_eq!(out, [0xE0, 0x1D, 0xE0, 0x9D]);
+    }
+
+    #[test]
+    fn extended_key_release_has_the_prefix() {
+        let out = scancodes(&[report(0, &[0x52]), report(0, &[])]);
+        assert_eq!(out, [0xE0, 0x48, 0xE0, 0xC8]);
+    }
+
+    #[test]
+    fn released_before_pressed() {
+        // `A` goes up as `B` goes down, in the same report
+        let out = scancodes(&[report(0, &[USAGE_A]), report(0, &[USAGE_B])]);
+        assert_eq!(out, [0x1E, 0x9E, 0x30]);
+    }
+
+    #[test]
+    fn slot_of_a_key_doesnt_matter() {
+        let out = scancodes(&[
+            report(0, &[USAGE_A, USAGE_B]),
+            report(0, &[USAGE_B, USAGE_A]),
+            report(0, &[0, 0, 0, 0, 0, USAGE_A]),
+        ]);
+        assert_eq!(out, [0x1E, 0x30, 0xB0]);
+    }
+
+    #[test]
+    fn six_keys() {
+        let keys = [0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
+        let out = scancodes(&[report(0, &keys)]);
+        assert_eq!(out, [0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21]);
+    }
+
+    #[test]
+    fn rollover_error_is_ignored() {
+        let mut keyboard = BootKeyboard::new();
+        let mut out = Vec::new();
+        keyboard.report(&report(0, &[USAGE_A]), &mut out);
+        keyboard.report(&report(0, &[0x01; KEY_SLOTS]), &mut out);
+        assert_eq!(out, [0x1E]);
+        // the state is still the report before
+        keyboard.report(&report(0, &[]), &mut out);
+        assert_eq!(out, [0x1E, 0x9E]);
+    }
+
+    #[test]
+    fn short_report_is_ignored() {
+        let mut keyboard = BootKeyboard::new();
+        let mut out = Vec::new();
+        keyboard.report(&[LEFT_SHIFT, 0, USAGE_A], &mut out);
+        assert!(out.is_empty());
+        assert!(keyboard.is_idle());
+    }
+
+    #[test]
+    fn longer_report_uses_the_start() {
+        let mut long = vec![0; 16];
+        long[2] = USAGE_A;
+        long[15] = USAGE_B;
+        let mut keyboard = BootKeyboard::new();
+        let mut out = Vec::new();
+        keyboard.report(&long, &mut out);
+        assert_eq!(out, [0x1E]);
+    }
+
+    #[test]
+    fn unknown_usage_is_tracked_but_silent() {
+        let out = scancodes(&[report(0, &[0x46, USAGE_A]), report(0, &[0x46])]);
+        assert_eq!(out, [0x1E, 0x9E]);
+    }
+
+    #[test]
+    fn release_all() {
+        let mut keyboard = BootKeyboard::new();
+        let mut out = Vec::new();
+        keyboard.report(&report(LEFT_SHIFT, &[USAGE_A]), &mut out);
+        assert!(!keyboard.is_idle());
+        out.clear();
+        keyboard.release_all(&mut out);
+        assert_eq!(out, [0x9E, 0xAA]);
+        assert!(keyboard.is_idle());
+        out.clear();
+        keyboard.release_all(&mut out);
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,1075 @@
+//! A small decoder for DEFLATE (RFC 1951) streams, and the gzip (RFC 1952) format around them.
+//!
+//! The input is not trusted, every read from it is bounds checked and malformed data is
+//! reported as an error, never a panic. The output goes into a buffer given by the caller,
+//! so the memory used is bounded by it no matter what the stream says.
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+
+mod gzip_flags {
+    pub const HEADER_CRC: u8 = 1 << 1;
+    pub const EXTRA: u8 = 1 << 2;
+    pub const NAME: u8 = 1 << 3;
+    pub const COMMENT: u8 = 1 << 4;
+    pub const RESERVED: u8 = 0xE0;
+}
+
+const MAX_CODE_BITS: usize = 15;
+const MAX_LITERAL_LENGTH_CODES: usize = 286;
+const MAX_DISTANCE_CODES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order the code lengths of the code lengths alphabet are stored in
+const CODE_LENGTHS_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    /// The input ended before the end of the stream
+    UnexpectedEnd,
+    InvalidGzipHeader,
+    UnsupportedMethod(u8),
+    InvalidBlockType,
+    /// The length of a stored block doesn't match its complement
+    InvalidStoredLength,
+    /// The code lengths of a dynamic block don't form a valid Huffman code
+    InvalidCodeLengths,
+    /// A code that is not assigned to any symbol
+    InvalidCode,
+    InvalidSymbol(u16),
+    /// A back reference to before the start of the output
+    InvalidDistance {
+        distance: usize,
+        position: usize,
+    },
+    /// The decompressed data doesn't fit in the output buffer
+    OutputFull,
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    SizeMismatch {
+        expected: u32,
+        found: u32,
+    },
+}
+
+type Result<T> = core::result::Result<T, InflateError>;
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Reads `count` bits (at most 16), the first bit of the stream is the lowest
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        debug_assert!(count <= 16);
+        while self.bit_count < count {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or(InflateError::UnexpectedEnd)?;
+            self.position += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits until the next byte boundary, and returns the whole bytes still in
+    /// the bit buffer to the input
+    fn align_to_byte(&mut self) {
+        self.position -= (self.bit_count / 8) as usize;
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+
+    /// Must be byte aligned
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        debug_assert!(self.bit_count == 0);
+        let end = self
+            .position
+            .checked_add(count)
+            .ok_or(InflateError::UnexpectedEnd)?;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(InflateError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// The number of bytes used so far, including the last partially used one
+    fn consumed(&self) -> usize {
+        self.position - (self.bit_count / 8) as usize
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the
+/// symbols sorted by their code
+struct Huffman<const N: usize> {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        debug_assert!(lengths.len() <= N);
+        let mut counts = [0u16; MAX_CODE_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+
+        // more codes than the lengths can represent
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left <<= 1;
+            left -= count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+        // incomplete codes are allowed (a single distance code is common), the unused codes
+        // are reported when decoded
+
+        let mut offsets = [0u16; MAX_CODE_BITS + 1];
+        for length in 1..MAX_CODE_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = [0u16; N];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // codes are stored starting from the most significant bit
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidCode)
+    }
+}
+
+struct Output<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, byte: u8) -> Result<()> {
+        let slot = self
+            .buffer
+            .get_mut(self.position)
+            .ok_or(InflateError::OutputFull)?;
+        *slot = byte;
+        self.position += 1;
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self.position + bytes.len();
+        self.buffer
+            .get_mut(self.position..end)
+            .ok_or(InflateError::OutputFull)?
+            .copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    fn copy_back(&mut self, distance: usize, length: usize) -> Result<()> {
+        if distance > self.position {
+            return Err(InflateError::InvalidDistance {
+                distance,
+                position: self.position,
+            });
+        }
+        if self.position + length > self.buffer.len() {
+            return Err(InflateError::OutputFull);
+        }
+        // the ranges can overlap, that is how runs are encoded, so copy byte by byte
+        for i in self.position..self.position + length {
+            self.buffer[i] = self.buffer[i - distance];
+        }
+        self.position += length;
+        Ok(())
+    }
+}
+
+fn fixed_codes() -> (Huffman<288>, Huffman<MAX_DISTANCE_CODES>) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let distance_lengths = [5u8; MAX_DISTANCE_CODES];
+    // these are valid codes
+    (
+        Huffman::new(&lengths).unwrap(),
+        Huffman::new(&distance_lengths).unwrap(),
+    )
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman<288>, Huffman<MAX_DISTANCE_CODES>)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_LENGTH_CODES || distance_count > MAX_DISTANCE_CODES {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTHS_ORDER[..code_length_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths_code = Huffman::<19>::new(&code_lengths)?;
+
+    let total = literal_count + distance_count;
+    let mut lengths = [0u8; MAX_LITERAL_LENGTH_CODES + MAX_DISTANCE_CODES];
+    let mut index = 0;
+    while index < total {
+        let symbol = code_lengths_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = index
+                    .checked_sub(1)
+                    .map(|i| lengths[i])
+                    .ok_or(InflateError::InvalidCodeLengths)?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            18 => (0, 11 + reader.bits(7)? as usize),
+            _ => return Err(InflateError::InvalidSymbol(symbol)),
+        };
+        if index + repeat > total {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+
+    // a block without an end is never valid
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..total])?,
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Output,
+    literals: &Huffman<288>,
+    distances: &Huffman<MAX_DISTANCE_CODES>,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        match symbol {
+            0..=255 => output.push(symbol as u8)?,
+            END_OF_BLOCK => return Ok(()),
+            257..=285 => {
+                let index = (symbol - 257) as usize;
+                let length =
+                    LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+                let distance_symbol = distances.decode(reader)? as usize;
+                if distance_symbol >= MAX_DISTANCE_CODES {
+                    return Err(InflateError::InvalidSymbol(distance_symbol as u16));
+                }
+                let distance = DISTANCE_BASE[distance_symbol] as usize
+                    + reader.bits(DISTANCE_EXTRA[distance_symbol] as u32)? as usize;
+
+                output.copy_back(distance, length)?;
+            }
+            _ => return Err(InflateError::InvalidSymbol(symbol)),
+        }
+    }
+}
+
+/// Decompresses the raw DEFLATE stream at the start of `input` into `output`.
+///
+/// Returns the number of bytes written to `output`, and the number of bytes of `input` used
+/// by the stream
+pub fn inflate(input: &[u8], output: &mut [u8]) -> Result<(usize, usize)> {
+    let mut reader = BitReader::new(input);
+    let mut output = Output {
+        buffer: output,
+        position: 0,
+    };
+
+    loop {
+        let is_last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let header = reader.bytes(4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                let length_complement = u16::from_le_bytes([header[2], header[3]]);
+                if length != !length_complement {
+                    return Err(InflateError::InvalidStoredLength);
+                }
+                output.extend(reader.bytes(length as usize)?)?;
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    Ok((output.position, reader.consumed()))
+}
+
+/// Whether `data` starts with the gzip magic
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// The size of the uncompressed data as recorded at the end of the gzip data, this is
+/// modulo 2^32, and is not validated until [`gunzip`]
+pub fn gzip_size(data: &[u8]) -> Option<u32> {
+    let start = data.len().checked_sub(4)?;
+    Some(u32::from_le_bytes(data[start..].try_into().unwrap()))
+}
+
+/// Skips the gzip header, returns the offset of the compressed data
+fn gzip_header(data: &[u8]) -> Result<usize> {
+    let header = data
+        .get(..GZIP_HEADER_SIZE)
+        .ok_or(InflateError::UnexpectedEnd)?;
+    if !is_gzip(header) {
+        return Err(InflateError::InvalidGzipHeader);
+    }
+    if header[2] != GZIP_METHOD_DEFLATE {
+        return Err(InflateError::UnsupportedMethod(header[2]));
+    }
+    let flags = header[3];
+    if flags & gzip_flags::RESERVED != 0 {
+        return Err(InflateError::InvalidGzipHeader);
+    }
+
+    let mut offset = GZIP_HEADER_SIZE;
+    if flags & gzip_flags::EXTRA != 0 {
+        let length = data
+            .get(offset..offset + 2)
+            .ok_or(InflateError::UnexpectedEnd)?;
+        offset += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    // zero terminated strings
+    for flag in [gzip_flags::NAME, gzip_flags::COMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(offset..).ok_or(InflateError::UnexpectedEnd)?;
+            let length = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(InflateError::UnexpectedEnd)?;
+            offset += length + 1;
+        }
+    }
+    if flags & gzip_flags::HEADER_CRC != 0 {
+        offset += 2;
+    }
+    if offset > data.len() {
+        return Err(InflateError::UnexpectedEnd);
+    }
+    Ok(offset)
+}
+
+/// Decompresses the gzip data in `input` into `output`, and checks it against the
+/// checksum and size at the end of it.
+///
+/// Returns the number of bytes written into `output`, only the first member is decompressed,
+/// anything after it is ignored
+pub fn gunzip(input: &[u8], output: &mut [u8]) -> Result<usize> {
+    let start = gzip_header(input)?;
+    let (written, consumed) = inflate(&input[start..], output)?;
+
+    let trailer_start = start + consumed;
+    let trailer = input
+        .get(trailer_start..trailer_start + GZIP_TRAILER_SIZE)
+        .ok_or(InflateError::UnexpectedEnd)?;
+    let expected_crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+    let expected_size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+
+    if expected_size != written as u32 {
+        return Err(InflateError::SizeMismatch {
+            expected: expected_size,
+            found: written as u32,
+        });
+    }
+    let crc = crc32(&output[..written]);
+    if expected_crc != crc {
+        return Err(InflateError::ChecksumMismatch {
+            expected: expected_crc,
+            found: crc,
+        });
+    }
+
+    Ok(written)
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 (IEEE) of `data`, as used by gzip
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{format, vec, vec::Vec};
+
+    /// Writes a stream bit by bit, the first bit is the lowest of the first byte
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bit_count: u32,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u32) {
+            for i in 0..count {
+                if self.bit_count.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i) as u8 & 1;
+                *self.bytes.last_mut().unwrap() |= bit << (self.bit_count % 8);
+                self.bit_count += 1;
+            }
+        }
+
+        /// A Huffman code, they are stored from their most significant bit
+        fn code(&mut self, code: u32, length: u32) {
+            for i in (0..length).rev() {
+                self.bits(code >> i, 1);
+            }
+        }
+
+        fn fixed_header(&mut self, is_last: bool) {
+            self.bits(is_last as u32, 1);
+            self.bits(1, 2);
+        }
+
+        fn fixed_symbol(&mut self, symbol: u16) {
+            let symbol = symbol as u32;
+            match symbol {
+                0..=143 => self.code(0x30 + symbol, 8),
+                144..=255 => self.code(0x190 + symbol - 144, 9),
+                256..=279 => self.code(symbol - 256, 7),
+                _ => self.code(0xC0 + symbol - 280, 8),
+            }
+        }
+
+        fn fixed_copy(&mut self, length: usize, distance: usize) {
+            let index = LENGTH_BASE
+                .iter()
+                .rposition(|&base| base as usize <= length)
+                .unwrap();
+            self.fixed_symbol(257 + index as u16);
+            self.bits(
+                (length - LENGTH_BASE[index] as usize) as u32,
+                LENGTH_EXTRA[index] as u32,
+            );
+            let index = DISTANCE_BASE
+                .iter()
+                .rposition(|&base| base as usize <= distance)
+                .unwrap();
+            self.code(index as u32, 5);
+            self.bits(
+                (distance - DISTANCE_BASE[index] as usize) as u32,
+                DISTANCE_EXTRA[index] as u32,
+            );
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.bytes
+        }
+    }
+
+    /// A single fixed block of `literals`, ended
+    fn fixed_literals(literals: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        for &literal in literals {
+            writer.fixed_symbol(literal as u16);
+        }
+        writer.fixed_symbol(END_OF_BLOCK);
+        writer.finish()
+    }
+
+    fn stored_block(is_last: bool, data: &[u8]) -> Vec<u8> {
+        let mut block = vec![is_last as u8];
+        block.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        block.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        block.extend_from_slice(data);
+        block
+    }
+
+    /// A gzip stream of `data` in a single stored block
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let mut gzip = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF];
+        gzip.extend(stored_block(true, data));
+        gzip.extend_from_slice(&crc32(data).to_le_bytes());
+        gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        gzip
+    }
+
+    fn inflate_all(input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = vec![0; 1 << 16];
+        let (written, _) = inflate(input, &mut output)?;
+        output.truncate(written);
+        Ok(output)
+    }
+
+    // `zlib` with `Z_FIXED`, of `hello hello hello hello`
+    const ZLIB_FIXED: [u8; 10] = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0x01];
+
+    // `zlib` at level 9, of `dynamic_text`
+    const ZLIB_DYNAMIC: [u8; 114] = [
+        0x9D, 0xD2, 0xCB, 0x0D, 0xC2, 0x30, 0x14, 0x05, 0xD1, 0x3D, 0x55, 0xDC, 0x02, 0x58, 0x70,
+        0xF9, 0x53, 0x4E, 0x88, 0x1F, 0x8A, 0x85, 0x13, 0xA3, 0xC4, 0x52, 0x44, 0xF7, 0x11, 0x25,
+        0x30, 0x05, 0xCC, 0x6A, 0x4E, 0xC9, 0x53, 0xE8, 0xA0, 0xFA, 0x52, 0x1B, 0x42, 0xE9, 0x3B,
+        0x75, 0x63, 0xEE, 0xF5, 0x2C, 0xB5, 0x7F, 0xEF, 0xB5, 0xE6, 0x36, 0x68, 0xA9, 0x63, 0x68,
+        0x8E, 0x4F, 0x74, 0x2D, 0x92, 0xD6, 0x3A, 0xA7, 0x65, 0x57, 0x7E, 0x95, 0x51, 0x75, 0x44,
+        0xD5, 0x09, 0x55, 0x67, 0x54, 0x5D, 0x50, 0x75, 0x45, 0xD5, 0x0D, 0x55, 0x77, 0x54, 0x3D,
+        0xD8, 0x65, 0x88, 0x83, 0xE9, 0x30, 0xE3, 0x61, 0xE6, 0xC3, 0x0C, 0x88, 0x99, 0x10, 0x33,
+        0x22, 0x66, 0x46, 0xCC, 0x90, 0xF8, 0x6F, 0x25, 0x1B,
+    ];
+
+    fn dynamic_text() -> Vec<u8> {
+        (0..20)
+            .flat_map(|i| {
+                format!("line {i} of the dynamic block, with some repeated words\n").into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc32_check_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn single_stored_block() {
+        let stream = stored_block(true, b"stored data");
+        assert_eq!(inflate_all(&stream).unwrap(), b"stored data");
+    }
+
+    #[test]
+    fn empty_stored_block() {
+        assert_eq!(inflate_all(&stored_block(true, &[])).unwrap(), b"");
+    }
+
+    #[test]
+    fn several_blocks() {
+        let mut stream = stored_block(false, b"first ");
+        stream.extend(stored_block(false, b"second "));
+        stream.extend(stored_block(true, b"last"));
+        assert_eq!(inflate_all(&stream).unwrap(), b"first second last");
+    }
+
+    #[test]
+    fn consumed_stops_at_the_end_of_the_stream() {
+        let mut stream = stored_block(true, b"abc");
+        let len = stream.len();
+        stream.extend_from_slice(b"trailing");
+        let mut output = [0; 3];
+        assert_eq!(inflate(&stream, &mut output), Ok((3, len)));
+
+        // the last byte of a fixed block is partially used
+        let mut stream = fixed_literals(b"abc");
+        let len = stream.len();
+        stream.extend_from_slice(&[0xFF; 4]);
+        assert_eq!(inflate(&stream, &mut output), Ok((3, len)));
+    }
+
+    #[test]
+    fn fixed_block_from_zlib() {
+        assert_eq!(
+            inflate_all(&ZLIB_FIXED).unwrap(),
+            b"hello hello hello hello"
+        );
+    }
+
+    #[test]
+    fn dynamic_block_from_zlib() {
+        let (is_last, block_type) = (ZLIB_DYNAMIC[0] & 1, (ZLIB_DYNAMIC[0] >> 1) & 3);
+        assert_eq!((is_last, block_type), (1, 2));
+        assert_eq!(inflate_all(&ZLIB_DYNAMIC).unwrap(), dynamic_text());
+    }
+
+    #[test]
+    fn fixed_literals_of_every_code_length() {
+        let literals = (0..=u8::MAX).collect::<Vec<_>>();
+        assert_eq!(inflate_all(&fixed_literals(&literals)).unwrap(), literals);
+    }
+
+    #[test]
+    fn overlapping_copy_is_a_run() {
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        writer.fixed_symbol(b'x' as u16);
+        writer.fixed_copy(258, 1);
+        writer.fixed_symbol(b'y' as u16);
+        writer.fixed_symbol(b'z' as u16);
+        writer.fixed_copy(7, 2);
+        writer.fixed_symbol(END_OF_BLOCK);
+        let output = inflate_all(&writer.finish()).unwrap();
+        let mut expected = vec![b'x'; 259];
+        expected.extend_from_slice(b"yzyzyzyzy");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn copies_of_every_length() {
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        writer.fixed_symbol(b'a' as u16);
+        writer.fixed_symbol(b'b' as u16);
+        let mut expected = b"ab".to_vec();
+        for length in 3..=258 {
+            writer.fixed_copy(length, 2);
+            for _ in 0..length {
+                expected.push(expected[expected.len() - 2]);
+            }
+        }
+        writer.fixed_symbol(END_OF_BLOCK);
+        assert_eq!(inflate_all(&writer.finish()).unwrap(), expected);
+    }
+
+    #[test]
+    fn far_distances() {
+        let data = (0..40000u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut stream = stored_block(false, &data[..32768]);
+        stream.extend(stored_block(false, &data[32768..]));
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        for distance in [32768, 24577, 4097, 1025, 5, 1] {
+            writer.fixed_copy(3, distance);
+        }
+        writer.fixed_symbol(END_OF_BLOCK);
+        stream.extend(writer.finish());
+
+        let output = inflate_all(&stream).unwrap();
+        let mut expected = data.clone();
+        for distance in [32768, 24577, 4097, 1025, 5, 1] {
+            for _ in 0..3 {
+                expected.push(expected[expected.len() - distance]);
+            }
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn distance_before_the_start() {
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        writer.fixed_symbol(b'a' as u16);
+        writer.fixed_copy(3, 2);
+        writer.fixed_symbol(END_OF_BLOCK);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidDistance {
+                distance: 2,
+                position: 1
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_length_symbols() {
+        for symbol in [286, 287] {
+            let mut writer = BitWriter::default();
+            writer.fixed_header(true);
+            writer.fixed_symbol(symbol);
+            assert_eq!(
+                inflate_all(&writer.finish()),
+                Err(InflateError::InvalidSymbol(symbol))
+            );
+        }
+    }
+
+    #[test]
+    fn unassigned_distance_codes() {
+        // the fixed distance code has 30 symbols of 5 bits, `30` and `31` are not any of them
+        for code in [30, 31] {
+            let mut writer = BitWriter::default();
+            writer.fixed_header(true);
+            writer.fixed_symbol(b'a' as u16);
+            writer.fixed_symbol(257);
+            writer.code(code, 5);
+            writer.bits(0, 16);
+            assert_eq!(
+                inflate_all(&writer.finish()),
+                Err(InflateError::InvalidCode)
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_block_type() {
+        assert_eq!(inflate_all(&[0b111]), Err(InflateError::InvalidBlockType));
+    }
+
+    #[test]
+    fn stored_length_complement() {
+        let mut stream = stored_block(true, b"abc");
+        stream[3] ^= 1;
+        assert_eq!(inflate_all(&stream), Err(InflateError::InvalidStoredLength));
+    }
+
+    #[test]
+    fn output_full() {
+        let mut output = [0; 2];
+        assert_eq!(
+            inflate(&stored_block(true, b"abc"), &mut output),
+            Err(InflateError::OutputFull)
+        );
+        assert_eq!(
+            inflate(&fixed_literals(b"abc"), &mut output),
+            Err(InflateError::OutputFull)
+        );
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        writer.fixed_symbol(b'a' as u16);
+        writer.fixed_copy(3, 1);
+        writer.fixed_symbol(END_OF_BLOCK);
+        assert_eq!(
+            inflate(&writer.finish(), &mut output),
+            Err(InflateError::OutputFull)
+        );
+        // exactly the size fits
+        let mut output = [0; 3];
+        assert_eq!(inflate(&fixed_literals(b"abc"), &mut output).unwrap().0, 3);
+    }
+
+    #[test]
+    fn missing_end_of_block() {
+        let mut writer = BitWriter::default();
+        writer.fixed_header(true);
+        writer.fixed_symbol(b'a' as u16);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    /// The header of a dynamic block, with the code lengths alphabet given in its stored order
+    fn dynamic_header(literals: u32, distances: u32, code_lengths: &[u32]) -> BitWriter {
+        let mut writer = BitWriter::default();
+        writer.bits(1, 1);
+        writer.bits(2, 2);
+        writer.bits(literals - 257, 5);
+        writer.bits(distances - 1, 5);
+        writer.bits(code_lengths.len() as u32 - 4, 4);
+        for &length in code_lengths {
+            writer.bits(length, 3);
+        }
+        writer
+    }
+
+    #[test]
+    fn oversubscribed_code_lengths() {
+        // `16`, `17` and `18` all of 1 bit
+        let writer = dynamic_header(257, 1, &[1, 1, 1, 0]);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidCodeLengths)
+        );
+    }
+
+    #[test]
+    fn repeat_before_any_length() {
+        // `16` and `0` of 1 bit, `16` is `1`
+        let mut writer = dynamic_header(257, 1, &[1, 0, 0, 1]);
+        writer.code(1, 1);
+        writer.bits(0, 2);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidCodeLengths)
+        );
+    }
+
+    #[test]
+    fn repeat_past_the_end() {
+        // `18` and `0` of 1 bit, `18` is `1`, ask for 138 zeros twice, with 258 lengths
+        let mut writer = dynamic_header(257, 1, &[0, 0, 1, 1]);
+        writer.code(1, 1);
+        writer.bits(127, 7);
+        writer.code(1, 1);
+        writer.bits(127, 7);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidCodeLengths)
+        );
+    }
+
+    #[test]
+    fn dynamic_block_without_end_of_block() {
+        // all the lengths are zero, `138 + 120` of them
+        let mut writer = dynamic_header(257, 1, &[0, 0, 1, 1]);
+        writer.code(1, 1);
+        writer.bits(127, 7);
+        writer.code(1, 1);
+        writer.bits(109, 7);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidCodeLengths)
+        );
+    }
+
+    #[test]
+    fn too_many_literal_codes() {
+        let writer = dynamic_header(287, 1, &[0, 0, 0, 0]);
+        assert_eq!(
+            inflate_all(&writer.finish()),
+            Err(InflateError::InvalidCodeLengths)
+        );
+    }
+
+    #[test]
+    fn truncated_anywhere_is_an_error() {
+        for stream in [ZLIB_FIXED.to_vec(), ZLIB_DYNAMIC.to_vec()] {
+            for len in 0..stream.len() {
+                assert!(
+                    inflate_all(&stream[..len]).is_err(),
+                    "truncated stream at {len} was accepted"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_bits_never_panic() {
+        for bit in 0..ZLIB_DYNAMIC.len() * 8 {
+            let mut stream = ZLIB_DYNAMIC;
+            stream[bit / 8] ^= 1 << (bit % 8);
+            let _ = inflate_all(&stream);
+        }
+    }
+
+    #[test]
+    fn gunzip_stored() {
+        const DATA: &[u8] = b"initrd self test data";
+        let gzip = gzip_stored(DATA);
+        let mut output = [0; DATA.len()];
+        assert!(is_gzip(&gzip));
+        assert_eq!(gzip_size(&gzip), Some(DATA.len() as u32));
+        assert_eq!(gunzip(&gzip, &mut output), Ok(DATA.len()));
+        assert_eq!(&output, DATA);
+        assert_eq!(
+            gunzip(&gzip, &mut output[..DATA.len() - 1]),
+            Err(InflateError::OutputFull)
+        );
+    }
+
+    #[test]
+    fn gunzip_truncated_anywhere_is_an_error() {
+        const DATA: &[u8] = b"initrd self test data";
+        let gzip = gzip_stored(DATA);
+        let mut output = [0; DATA.len()];
+        for len in 0..gzip.len() {
+            assert!(
+                gunzip(&gzip[..len], &mut output).is_err(),
+                "truncated gzip at {len} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn gunzip_corrupt() {
+        const DATA: &[u8] = b"initrd self test data";
+        const BLOCK_START: usize = GZIP_HEADER_SIZE;
+        let gzip = gzip_stored(DATA);
+        let mut output = [0; DATA.len()];
+
+        let mut corrupt = gzip.clone();
+        corrupt[BLOCK_START + 5] ^= 0xFF;
+        assert!(matches!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::ChecksumMismatch { .. })
+        ));
+
+        let mut corrupt = gzip.clone();
+        let size_start = corrupt.len() - 4;
+        corrupt[size_start] ^= 1;
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::SizeMismatch {
+                expected: DATA.len() as u32 ^ 1,
+                found: DATA.len() as u32
+            })
+        );
+
+        let mut corrupt = gzip.clone();
+        corrupt[BLOCK_START + 3] ^= 1;
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::InvalidStoredLength)
+        );
+
+        let mut corrupt = gzip.clone();
+        // final, with the reserved block type
+        corrupt[BLOCK_START] = 0b111;
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::InvalidBlockType)
+        );
+
+        let mut corrupt = gzip.clone();
+        corrupt[2] = 7;
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::UnsupportedMethod(7))
+        );
+
+        let mut corrupt = gzip.clone();
+        corrupt[3] = 0x20;
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::InvalidGzipHeader)
+        );
+
+        let mut corrupt = gzip;
+        corrupt[0] = 0;
+        assert!(!is_gzip(&corrupt));
+        assert_eq!(
+            gunzip(&corrupt, &mut output),
+            Err(InflateError::InvalidGzipHeader)
+        );
+    }
+
+    #[test]
+    fn gunzip_optional_header_fields() {
+        const DATA: &[u8] = b"with all the header fields";
+        let stored = gzip_stored(DATA);
+        let mut gzip = stored[..GZIP_HEADER_SIZE].to_vec();
+        gzip[3] =
+            gzip_flags::EXTRA | gzip_flags::NAME | gzip_flags::COMMENT | gzip_flags::HEADER_CRC;
+        gzip.extend_from_slice(&3u16.to_le_bytes());
+        gzip.extend_from_slice(b"xyz");
+        gzip.extend_from_slice(b"initrd.cpio\0");
+        gzip.extend_from_slice(b"a comment\0");
+        // the header crc, not checked
+        gzip.extend_from_slice(&[0xAB, 0xCD]);
+        gzip.extend_from_slice(&stored[GZIP_HEADER_SIZE..]);
+
+        let mut output = [0; DATA.len()];
+        assert_eq!(gunzip(&gzip, &mut output), Ok(DATA.len()));
+        assert_eq!(&output, DATA);
+    }
+
+    #[test]
+    fn gunzip_unterminated_name() {
+        let mut gzip = gzip_stored(b"")[..GZIP_HEADER_SIZE].to_vec();
+        gzip[3] = gzip_flags::NAME;
+        gzip.extend_from_slice(b"no terminator");
+        assert_eq!(gunzip(&gzip, &mut []), Err(InflateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn gunzip_only_the_first_member() {
+        let mut gzip = gzip_stored(b"first");
+        gzip.extend(gzip_stored(b"second"));
+        let mut output = [0; 16];
+        assert_eq!(gunzip(&gzip, &mut output), Ok(5));
+        assert_eq!(&output[..5], b"first");
+    }
+
+    #[test]
+    fn gzip_size_of_short_data() {
+        assert_eq!(gzip_size(&[1, 2, 3]), None);
+        assert_eq!(gzip_size(&[1, 0, 0, 0]), Some(1));
+    }
+}
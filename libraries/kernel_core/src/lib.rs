@@ -0,0 +1,55 @@
+//! The parts of the kernel that don't depend on the hardware or the kernel environment.
+//!
+//! These only need `core` and `alloc`, so they can be built and used on the host as well.
+//! Anything environment dependent is given through a small seam, like [`set_log_sink`]
+//! for the debug logs.
+//!
+//! The tests run on the host, with `cargo make test_core`.
+
+// the host tests use `std`, for the test harness
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// the logs go to the kernel console, see `set_log_sink`
+macro_rules! eprintln {
+    () => ($crate::_log(file!(), line!(), format_args!("\n")));
+    ($($arg:tt)*) => ($crate::_log(file!(), line!(), format_args!("{}\n", format_args!($($arg)*))));
+}
+
+pub mod aml;
+pub mod bitset;
+pub mod cpio;
+pub mod error_context;
+pub mod fat;
+pub mod hid;
+pub mod inflate;
+pub mod path;
+pub mod profile;
+pub mod ring;
+pub mod sector;
+pub mod symbols;
+pub mod utf8;
+
+// a `fn(fmt::Arguments)`, or `0` if not set
+static LOG_SINK: AtomicUsize = AtomicUsize::new(0);
+
+/// Set where the debug logs of this crate go, until this is called, they are dropped
+pub fn set_log_sink(sink: fn(&'static str, u32, fmt::Arguments)) {
+    LOG_SINK.store(sink as usize, Ordering::Release);
+}
+
+#[doc(hidden)]
+pub fn _log(file: &'static str, line: u32, args: fmt::Arguments) {
+    let sink = LOG_SINK.load(Ordering::Acquire);
+    if sink != 0 {
+        // SAFETY: this is only set from a valid `fn` of this type in `set_log_sink`
+        let sink: fn(&'static str, u32, fmt::Arguments) = unsafe { core::mem::transmute(sink) };
+        sink(file, line, args);
+    }
+}
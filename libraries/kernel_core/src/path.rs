@@ -0,0 +1,85 @@
+//! Path handling for the kernel filesystem paths, which are always absolute
+
+use alloc::{string::String, vec::Vec};
+
+/// Normalizes an absolute `path`, removing empty components, `.` and resolving `..`.
+///
+/// `..` at the root stays at the root, and the trailing `/` is kept if present, since
+/// that is used to mean the directory itself.
+/// Returns `None` if the path is not absolute.
+pub fn normalize(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+
+    let mut result = String::with_capacity(path.len());
+    for component in &components {
+        result.push('/');
+        result.push_str(component);
+    }
+    let is_dir = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if components.is_empty() || is_dir {
+        result.push('/');
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_is_rejected() {
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("a/b"), None);
+        assert_eq!(normalize("./a"), None);
+    }
+
+    #[test]
+    fn root() {
+        for path in ["/", "//", "/.", "/..", "/../..", "/./."] {
+            assert_eq!(normalize(path).as_deref(), Some("/"), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn removes_empty_and_dot_components() {
+        assert_eq!(normalize("/a//b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize("/a/./b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize("///a").as_deref(), Some("/a"));
+    }
+
+    #[test]
+    fn resolves_parent() {
+        assert_eq!(normalize("/a/b/../c").as_deref(), Some("/a/c"));
+        assert_eq!(normalize("/a/../../b").as_deref(), Some("/b"));
+        assert_eq!(normalize("/a/b/..").as_deref(), Some("/a/"));
+    }
+
+    #[test]
+    fn keeps_the_directory_slash() {
+        assert_eq!(normalize("/a/b/").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize("/a/b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize("/a/b/.").as_deref(), Some("/a/b/"));
+    }
+
+    #[test]
+    fn names_with_dots_are_kept() {
+        assert_eq!(normalize("/a/...").as_deref(), Some("/a/..."));
+        assert_eq!(
+            normalize("/.hidden/x.txt").as_deref(),
+            Some("/.hidden/x.txt")
+        );
+    }
+}
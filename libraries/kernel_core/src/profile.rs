@@ -0,0 +1,280 @@
+//! The call chains sampled by the profiler, and folding them into the collapsed stack format.
+//!
+//! A sample is a header word, with the depth of the chain, whether it was taken in user mode
+//! and the process, followed by the frames: the interrupted instruction, then the return
+//! addresses going outward. It is pushed to a [`RingBuffer`] of words whole or not at all, so
+//! the reader never finds half of one.
+//!
+//! [`Collapsed`] is what `flamegraph.pl` and `inferno` read, a line `root;outer;...;inner count`
+//! for every different chain.
+
+use core::fmt;
+
+use alloc::{collections::BTreeMap, string::String};
+
+use crate::ring::RingBuffer;
+
+/// The deepest chain a sample can have, including the interrupted instruction
+pub const MAX_DEPTH: usize = 64;
+pub const DEFAULT_DEPTH: usize = 16;
+
+const DEPTH_MASK: u64 = 0xFF;
+const USER_FLAG: u64 = 1 << 8;
+const PID_SHIFT: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleHeader {
+    /// Whether the CPU was running the process, and not the kernel
+    pub user: bool,
+    pub pid: u64,
+    /// The number of frames that follow
+    pub depth: usize,
+}
+
+impl SampleHeader {
+    fn encode(&self) -> u64 {
+        let user = if self.user { USER_FLAG } else { 0 };
+        self.depth as u64 | user | self.pid << PID_SHIFT
+    }
+
+    fn decode(word: u64) -> Self {
+        Self {
+            user: word & USER_FLAG != 0,
+            pid: word >> PID_SHIFT,
+            depth: ((word & DEPTH_MASK) as usize).min(MAX_DEPTH),
+        }
+    }
+}
+
+/// Pushes a sample with the chain `frames`, at most [`MAX_DEPTH`] of them, returns `false`
+/// and pushes nothing if the ring doesn't have space for all of it
+pub fn push_sample<const N: usize>(
+    ring: &mut RingBuffer<u64, N>,
+    user: bool,
+    pid: u64,
+    frames: &[u64],
+) -> bool {
+    let frames = &frames[..frames.len().min(MAX_DEPTH)];
+    if ring.free() < frames.len() + 1 {
+        return false;
+    }
+    let header = SampleHeader {
+        user,
+        pid,
+        depth: frames.len(),
+    };
+    ring.push(header.encode());
+    for &frame in frames {
+        ring.push(frame);
+    }
+    true
+}
+
+/// Pops the oldest sample, its chain is put at the start of `frames`
+pub fn pop_sample<const N: usize>(
+    ring: &mut RingBuffer<u64, N>,
+    frames: &mut [u64; MAX_DEPTH],
+) -> Option<SampleHeader> {
+    let header = SampleHeader::decode(ring.pop()?);
+    for frame in &mut frames[..header.depth] {
+        *frame = ring.pop().unwrap_or(0);
+    }
+    Some(header)
+}
+
+/// The chains folded by the names of their frames, with the number of samples of each
+#[derive(Debug, Default)]
+pub struct Collapsed {
+    stacks: BTreeMap<String, u64>,
+    samples: u64,
+}
+
+impl Collapsed {
+    pub const fn new() -> Self {
+        Self {
+            stacks: BTreeMap::new(),
+            samples: 0,
+        }
+    }
+
+    /// Adds a sample with the chain `frames`, from the innermost, under the frame `root`.
+    ///
+    /// `name` writes the name of the function of an address. The return addresses are given
+    /// moved back by 1, so they are inside the call instruction, the one after it can be in
+    /// the next function when the call is the last instruction. A `;` in a name would split
+    /// it, its replaced with `,`
+    pub fn add(&mut self, root: &str, frames: &[u64], mut name: impl FnMut(u64, &mut String)) {
+        let mut stack = String::from(root);
+        let mut frame_name = String::new();
+        for (i, &frame) in frames.iter().enumerate().rev() {
+            let address = if i == 0 { frame } else { frame.wrapping_sub(1) };
+            frame_name.clear();
+            name(address, &mut frame_name);
+            stack.push(';');
+            stack.extend(frame_name.chars().map(|c| if c == ';' { ',' } else { c }));
+        }
+        *self.stacks.entry(stack).or_default() += 1;
+        self.samples += 1;
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// The different stacks with the number of samples of each, sorted
+    pub fn stacks(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.stacks
+            .iter()
+            .map(|(stack, &count)| (stack.as_str(), count))
+    }
+
+    pub fn clear(&mut self) {
+        self.stacks.clear();
+        self.samples = 0;
+    }
+}
+
+impl fmt::Display for Collapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (stack, count) in self.stacks() {
+            writeln!(f, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{format, string::ToString, vec::Vec};
+
+    fn ring() -> RingBuffer<u64, 16> {
+        RingBuffer::empty()
+    }
+
+    /// Names `0x1000 + n` as `fn_n`
+    fn name(address: u64, out: &mut String) {
+        out.push_str(&format!("fn_{:x}", address - 0x1000));
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for header in [
+            SampleHeader {
+                user: false,
+                pid: 0,
+                depth: 0,
+            },
+            SampleHeader {
+                user: true,
+                pid: 12345,
+                depth: MAX_DEPTH,
+            },
+            SampleHeader {
+                user: true,
+                pid: u64::MAX >> PID_SHIFT,
+                depth: 1,
+            },
+        ] {
+            assert_eq!(SampleHeader::decode(header.encode()), header);
+        }
+    }
+
+    #[test]
+    fn decoded_depth_is_bounded() {
+        assert_eq!(SampleHeader::decode(0xFF).depth, MAX_DEPTH);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut ring = ring();
+        let mut frames = [0; MAX_DEPTH];
+        assert!(push_sample(&mut ring, true, 7, &[10, 20, 30]));
+        assert!(push_sample(&mut ring, false, 0, &[40]));
+        assert_eq!(ring.len(), 6);
+
+        let header = pop_sample(&mut ring, &mut frames).unwrap();
+        assert_eq!(
+            header,
+            SampleHeader {
+                user: true,
+                pid: 7,
+                depth: 3
+            }
+        );
+        assert_eq!(frames[..3], [10, 20, 30]);
+        let header = pop_sample(&mut ring, &mut frames).unwrap();
+        assert!(!header.user);
+        assert_eq!(frames[..header.depth], [40]);
+        assert_eq!(pop_sample(&mut ring, &mut frames), None);
+    }
+
+    #[test]
+    fn whole_sample_or_nothing() {
+        let mut ring = ring();
+        // 15 words of space, a sample of 12 frames takes 13
+        assert!(push_sample(&mut ring, false, 1, &[1; 12]));
+        assert!(!push_sample(&mut ring, false, 2, &[2; 2]));
+        assert_eq!(ring.len(), 13);
+        assert!(push_sample(&mut ring, false, 3, &[3]));
+        assert_eq!(ring.free(), 0);
+    }
+
+    #[test]
+    fn chain_is_cut_at_max_depth() {
+        let mut ring = RingBuffer::<u64, 128>::empty();
+        let chain = (0..MAX_DEPTH as u64 + 10).collect::<Vec<_>>();
+        assert!(push_sample(&mut ring, false, 1, &chain));
+        assert_eq!(ring.len(), MAX_DEPTH + 1);
+        let mut frames = [0; MAX_DEPTH];
+        let header = pop_sample(&mut ring, &mut frames).unwrap();
+        assert_eq!(header.depth, MAX_DEPTH);
+        assert_eq!(frames[..], chain[..MAX_DEPTH]);
+    }
+
+    #[test]
+    fn collapsed_from_the_root() {
+        let mut collapsed = Collapsed::new();
+        // the interrupted instruction, then return addresses
+        collapsed.add("kernel", &[0x1003, 0x1011, 0x1021], name);
+        // return addresses are moved back by one, into the call
+        assert_eq!(collapsed.to_string(), "kernel;fn_20;fn_10;fn_3 1\n");
+        assert_eq!(collapsed.samples(), 1);
+    }
+
+    #[test]
+    fn same_chains_are_counted() {
+        let mut collapsed = Collapsed::new();
+        collapsed.add("kernel", &[0x1003, 0x1011], name);
+        collapsed.add("user", &[0x1003, 0x1011], name);
+        collapsed.add("kernel", &[0x1003, 0x1011], name);
+        collapsed.add("kernel", &[], name);
+        assert_eq!(collapsed.samples(), 4);
+        assert_eq!(
+            collapsed.stacks().collect::<Vec<_>>(),
+            [
+                ("kernel", 1),
+                ("kernel;fn_10;fn_3", 2),
+                ("user;fn_10;fn_3", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn semicolons_in_names_are_replaced() {
+        let mut collapsed = Collapsed::new();
+        collapsed.add("kernel", &[0], |_, out| out.push_str("a;b"));
+        assert_eq!(collapsed.to_string(), "kernel;a,b 1\n");
+    }
+
+    #[test]
+    fn clear() {
+        let mut collapsed = Collapsed::new();
+        collapsed.add("kernel", &[0x1000], name);
+        collapsed.clear();
+        assert_eq!(collapsed.samples(), 0);
+        assert_eq!(collapsed.stacks().count(), 0);
+        assert_eq!(collapsed.to_string(), "");
+    }
+}
@@ -0,0 +1,165 @@
+use core::mem::MaybeUninit;
+
+/// A fixed size ring buffer, it holds at most `N - 1` values
+#[allow(dead_code)]
+pub struct RingBuffer<T, const N: usize = 1024> {
+    buffer: [MaybeUninit<T>; N],
+    read_index: usize,
+    write_index: usize,
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> RingBuffer<T, N> {
+    /// The number of values in the buffer
+    pub fn len(&self) -> usize {
+        (self.write_index + N - self.read_index) % N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read_index == self.write_index
+    }
+
+    /// The number of values that can be pushed before it is full
+    pub fn free(&self) -> usize {
+        N - 1 - self.len()
+    }
+
+    pub fn try_push(&mut self, value: T) -> bool {
+        let next_index = (self.write_index + 1) % self.buffer.len();
+        if next_index == self.read_index {
+            return false;
+        }
+
+        self.buffer[self.write_index] = MaybeUninit::new(value);
+        self.write_index = next_index;
+
+        true
+    }
+
+    pub fn push(&mut self, value: T) {
+        let next_index = (self.write_index + 1) % self.buffer.len();
+        if next_index == self.read_index {
+            panic!("Ring buffer overflow");
+        }
+
+        self.buffer[self.write_index] = MaybeUninit::new(value);
+        self.write_index = next_index;
+    }
+
+    pub fn push_replace(&mut self, value: T) {
+        let next_index = (self.write_index + 1) % self.buffer.len();
+        // if the buffer is full, replace the oldest value
+        if next_index == self.read_index {
+            // drop the oldest value, and advance the read index past it
+            unsafe { self.buffer[self.read_index].assume_init_drop() };
+            self.read_index = (self.read_index + 1) % self.buffer.len();
+        }
+        self.buffer[self.write_index] = MaybeUninit::new(value);
+        self.write_index = next_index;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.read_index == self.write_index {
+            return None;
+        }
+
+        let value = unsafe { self.buffer[self.read_index].assume_init_read() };
+        self.read_index = (self.read_index + 1) % self.buffer.len();
+
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.read_index = 0;
+        self.write_index = 0;
+    }
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn empty() -> Self {
+        Self {
+            buffer: [MaybeUninit::<T>::uninit(); N],
+            read_index: 0,
+            write_index: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_order() {
+        let mut ring = RingBuffer::<u32, 4>::empty();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+        ring.push(1);
+        ring.push(2);
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(1));
+        ring.push(3);
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn holds_one_less_than_its_size() {
+        let mut ring = RingBuffer::<u32, 4>::empty();
+        assert_eq!(ring.free(), 3);
+        for i in 0..3 {
+            assert!(ring.try_push(i));
+        }
+        assert_eq!(ring.free(), 0);
+        assert!(!ring.try_push(3));
+        assert_eq!(ring.len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Ring buffer overflow")]
+    fn push_when_full_panics() {
+        let mut ring = RingBuffer::<u32, 2>::empty();
+        ring.push(0);
+        ring.push(1);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut ring = RingBuffer::<u32, 4>::empty();
+        for i in 0..10 {
+            ring.push(i);
+            ring.push(i + 100);
+            assert_eq!(ring.len(), 2);
+            assert_eq!(ring.pop(), Some(i));
+            assert_eq!(ring.pop(), Some(i + 100));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn push_replace_drops_the_oldest() {
+        let mut ring = RingBuffer::<u32, 4>::empty();
+        for i in 0..3 {
+            ring.push_replace(i);
+        }
+        ring.push_replace(3);
+        ring.push_replace(4);
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), Some(4));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn clear_empties() {
+        let mut ring = RingBuffer::<u32, 4>::empty();
+        ring.push(1);
+        ring.push(2);
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.free(), 3);
+        assert_eq!(ring.pop(), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! The units of the storage stack, so one can't be given where another is expected.
+//!
+//! - [`Lba`] is a sector of the device, from its start, what the drivers use.
+//! - [`FsSector`] is a sector of a filesystem, from the start of its partition.
+//! - [`Cluster`] is a FAT cluster, the data area starts at cluster `2`.
+//!
+//! Going from one to the other needs the layout relating them, i.e. the start of the
+//! partition for [`FsSector::to_lba`]. Only the arithmetic that stays in the same unit is
+//! there: moving by a number of sectors, or the distance between two of them.
+
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub},
+};
+
+/// A sector of the device, counted from its first one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Lba(pub u64);
+
+/// A sector of a filesystem, counted from the first one of its partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FsSector(pub u32);
+
+/// A cluster of FAT, the index of its entry in the allocation table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Cluster(pub u32);
+
+impl Add<u64> for Lba {
+    type Output = Lba;
+
+    fn add(self, sectors: u64) -> Lba {
+        Lba(self.0 + sectors)
+    }
+}
+
+impl AddAssign<u64> for Lba {
+    fn add_assign(&mut self, sectors: u64) {
+        self.0 += sectors;
+    }
+}
+
+/// The number of sectors from `other` to `self`
+impl Sub for Lba {
+    type Output = u64;
+
+    fn sub(self, other: Lba) -> u64 {
+        self.0 - other.0
+    }
+}
+
+impl fmt::Display for Lba {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FsSector {
+    /// The sector of the device, for a filesystem whose partition starts at `partition_start`
+    pub fn to_lba(self, partition_start: Lba) -> Lba {
+        partition_start + self.0 as u64
+    }
+
+    /// `count` sectors starting at this one
+    pub fn range(self, count: u32) -> impl Iterator<Item = FsSector> {
+        (self.0..self.0 + count).map(FsSector)
+    }
+}
+
+impl Add<u32> for FsSector {
+    type Output = FsSector;
+
+    fn add(self, sectors: u32) -> FsSector {
+        FsSector(self.0 + sectors)
+    }
+}
+
+/// The number of sectors from `other` to `self`
+impl Sub for FsSector {
+    type Output = u32;
+
+    fn sub(self, other: FsSector) -> u32 {
+        self.0 - other.0
+    }
+}
+
+impl fmt::Display for FsSector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Cluster {
+    /// The first cluster of the data area, `0` and `1` are reserved
+    pub const FIRST: Cluster = Cluster(2);
+
+    /// The first sector of this cluster, with the data area starting at `data_start`.
+    ///
+    /// Panics if this is one of the reserved clusters, they have no sectors
+    pub fn first_sector(self, data_start: FsSector, sectors_per_cluster: u32) -> FsSector {
+        assert!(self >= Self::FIRST, "cluster {self} has no sectors");
+        data_start + (self.0 - Self::FIRST.0) * sectors_per_cluster
+    }
+
+    /// The clusters from the first of the data area to `last`, included
+    pub fn data_clusters(last: Cluster) -> impl Iterator<Item = Cluster> {
+        (Self::FIRST.0..=last.0).map(Cluster)
+    }
+}
+
+impl fmt::Display for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{string::ToString, vec::Vec};
+
+    #[test]
+    fn lba_arithmetic() {
+        let mut lba = Lba(100) + 28;
+        assert_eq!(lba, Lba(128));
+        lba += 2;
+        assert_eq!(lba, Lba(130));
+        assert_eq!(lba - Lba(100), 30);
+        assert_eq!(Lba(5) - Lba(5), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn lba_distance_backwards_panics() {
+        let _ = Lba(1) - Lba(2);
+    }
+
+    #[test]
+    fn fs_sector_to_lba() {
+        assert_eq!(FsSector(0).to_lba(Lba(2048)), Lba(2048));
+        assert_eq!(FsSector(17).to_lba(Lba(2048)), Lba(2065));
+        // the partition can start past 2^32, the filesystem sectors can't
+        assert_eq!(
+            FsSector(u32::MAX).to_lba(Lba(1 << 32)),
+            Lba((1 << 32) + u32::MAX as u64)
+        );
+    }
+
+    #[test]
+    fn fs_sector_arithmetic() {
+        assert_eq!(FsSector(10) + 5, FsSector(15));
+        assert_eq!(FsSector(15) - FsSector(10), 5);
+    }
+
+    #[test]
+    fn fs_sector_range() {
+        let sectors = FsSector(32).range(4).collect::<Vec<_>>();
+        assert_eq!(
+            sectors,
+            [FsSector(32), FsSector(33), FsSector(34), FsSector(35)]
+        );
+        assert_eq!(FsSector(32).range(0).count(), 0);
+    }
+
+    #[test]
+    fn cluster_first_sector() {
+        let data_start = FsSector(600);
+        assert_eq!(Cluster::FIRST.first_sector(data_start, 8), data_start);
+        assert_eq!(Cluster(3).first_sector(data_start, 8), FsSector(608));
+        assert_eq!(Cluster(10).first_sector(data_start, 1), FsSector(608));
+        assert_eq!(Cluster(10).first_sector(data_start, 64), FsSector(1112));
+    }
+
+    #[test]
+    #[should_panic(expected = "cluster 1 has no sectors")]
+    fn reserved_cluster_has_no_sectors() {
+        Cluster(1).first_sector(FsSector(0), 1);
+    }
+
+    #[test]
+    fn data_clusters_start_at_first() {
+        assert_eq!(
+            Cluster::data_clusters(Cluster(5)).collect::<Vec<_>>(),
+            [Cluster(2), Cluster(3), Cluster(4), Cluster(5)]
+        );
+        assert_eq!(Cluster::data_clusters(Cluster(2)).count(), 1);
+        // before the data area, nothing
+        assert_eq!(Cluster::data_clusters(Cluster(1)).count(), 0);
+    }
+
+    #[test]
+    fn ordered_by_value() {
+        assert!(Lba(1) < Lba(2));
+        assert!(FsSector(9) > FsSector(3));
+        assert!(Cluster(1) < Cluster::FIRST);
+        assert_eq!(Lba::default(), Lba(0));
+    }
+
+    #[test]
+    fn displayed_as_the_number() {
+        assert_eq!(Lba(123456789).to_string(), "123456789");
+        assert_eq!(FsSector(42).to_string(), "42");
+        assert_eq!(Cluster(7).to_string(), "7");
+    }
+}
@@ -0,0 +1,417 @@
+//! The functions of an ELF file from its `.symtab`, to name the addresses in it.
+//!
+//! Only the section headers, the symbol table and its strings are read, through a callback
+//! reading at an offset, so the file doesn't have to be in memory. The names of Rust are
+//! demangled when they use the legacy mangling (`_ZN...E`), with the hash removed, so all
+//! the copies of a generic function have the same name.
+
+use alloc::{string::String, vec, vec::Vec};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE: u8 = 1;
+const ELF_HEADER_LEN: usize = 64;
+
+const SECTION_HEADER_LEN: usize = 64;
+const SHT_SYMTAB: u32 = 2;
+
+const SYMBOL_LEN: usize = 24;
+const STT_FUNC: u8 = 2;
+
+/// The biggest symbol table or string table read, with its strings
+const MAX_TABLE_LEN: u64 = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolsError {
+    /// Not a 64-bit little endian ELF
+    NotElf,
+    NoSymbolTable,
+    TooLarge,
+    /// The read callback failed, or the file is shorter than what its headers say
+    Read,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub start: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    // sorted by `start`
+    symbols: Vec<Symbol>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_table(
+    read: &mut impl FnMut(u64, &mut [u8]) -> bool,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, SymbolsError> {
+    if len > MAX_TABLE_LEN {
+        return Err(SymbolsError::TooLarge);
+    }
+    let mut table = vec![0; len as usize];
+    if !read(offset, &mut table) {
+        return Err(SymbolsError::Read);
+    }
+    Ok(table)
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.start);
+        Self { symbols }
+    }
+
+    /// The functions of the ELF file that `read` reads, it fills the buffer with the bytes at
+    /// the offset, or returns `false`
+    pub fn from_elf(mut read: impl FnMut(u64, &mut [u8]) -> bool) -> Result<Self, SymbolsError> {
+        let mut header = [0; ELF_HEADER_LEN];
+        if !read(0, &mut header) || header[..4] != ELF_MAGIC {
+            return Err(SymbolsError::NotElf);
+        }
+        if header[4] != ELF_CLASS_64 || header[5] != ELF_DATA_LITTLE {
+            return Err(SymbolsError::NotElf);
+        }
+        let section_headers_offset = u64_at(&header, 0x28);
+        let section_header_len = u16_at(&header, 0x3A) as u64;
+        let sections_count = u16_at(&header, 0x3C) as u64;
+        if section_header_len < SECTION_HEADER_LEN as u64 {
+            return Err(SymbolsError::NotElf);
+        }
+
+        let section_at = |read: &mut dyn FnMut(u64, &mut [u8]) -> bool, index: u64| {
+            let mut section = [0; SECTION_HEADER_LEN];
+            let offset = index
+                .checked_mul(section_header_len)?
+                .checked_add(section_headers_offset)?;
+            read(offset, &mut section).then_some(section)
+        };
+        let mut symtab = None;
+        for index in 0..sections_count {
+            let section = section_at(&mut read, index).ok_or(SymbolsError::Read)?;
+            if u32_at(&section, 4) == SHT_SYMTAB {
+                symtab = Some(section);
+                break;
+            }
+        }
+        let symtab = symtab.ok_or(SymbolsError::NoSymbolTable)?;
+        let strtab_index = u32_at(&symtab, 0x28) as u64;
+        if strtab_index >= sections_count {
+            return Err(SymbolsError::NoSymbolTable);
+        }
+        let strtab = section_at(&mut read, strtab_index).ok_or(SymbolsError::Read)?;
+
+        let symbols_bytes = read_table(&mut read, u64_at(&symtab, 0x18), u64_at(&symtab, 0x20))?;
+        let strings = read_table(&mut read, u64_at(&strtab, 0x18), u64_at(&strtab, 0x20))?;
+
+        let mut symbols = Vec::new();
+        for entry in symbols_bytes.as_chunks::<SYMBOL_LEN>().0 {
+            let start = u64_at(entry, 8);
+            if entry[4] & 0xF != STT_FUNC || start == 0 {
+                continue;
+            }
+            let name_start = u32_at(entry, 0) as usize;
+            let Some(name) = strings.get(name_start..) else {
+                continue;
+            };
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let Ok(name) = core::str::from_utf8(&name[..name_len]) else {
+                continue;
+            };
+            symbols.push(Symbol {
+                start,
+                size: u64_at(entry, 16),
+                name: demangle(name),
+            });
+        }
+        Ok(Self::new(symbols))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The function containing `address`, with the offset of the address in it. A symbol
+    /// without a size only has its first address
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let after = self
+            .symbols
+            .partition_point(|symbol| symbol.start <= address);
+        let symbol = self.symbols[..after].last()?;
+        let offset = address - symbol.start;
+        (offset < symbol.size.max(1)).then_some((symbol.name.as_str(), offset))
+    }
+}
+
+/// The characters of the `$...$` escapes of the legacy mangling
+fn unescape(escape: &str) -> Option<char> {
+    let c = match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => {
+            let code = u32::from_str_radix(escape.strip_prefix('u')?, 16).ok()?;
+            return char::from_u32(code);
+        }
+    };
+    Some(c)
+}
+
+fn demangle_ident(ident: &str, out: &mut String) {
+    // an identifier can't start with `$`, so it gets a `_` before it
+    let mut rest = match ident.strip_prefix("_$") {
+        Some(_) => &ident[1..],
+        None => ident,
+    };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+        } else if let Some((c, after)) = rest
+            .strip_prefix('$')
+            .and_then(|after| after.split_once('$'))
+            .and_then(|(escape, after)| Some((unescape(escape)?, after)))
+        {
+            out.push(c);
+            rest = after;
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+}
+
+/// The path of a legacy Rust symbol without its hash, i.e. `_ZN4core3fmt5write17h0123456789abcdefE`
+/// is `core::fmt::write`. Other names are kept as they are
+pub fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return String::from(name);
+    };
+    let mut idents = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Some(len) = rest[..digits].parse::<usize>().ok() else {
+            return String::from(name);
+        };
+        let Some(ident) = digits
+            .checked_add(len)
+            .and_then(|end| rest.get(digits..end))
+        else {
+            return String::from(name);
+        };
+        idents.push(ident);
+        rest = &rest[digits + len..];
+    }
+    let is_hash = |ident: &&str| {
+        ident.len() == 17
+            && ident.starts_with('h')
+            && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if idents.last().is_some_and(is_hash) {
+        idents.pop();
+    }
+
+    let mut out = String::new();
+    for (i, ident) in idents.iter().enumerate() {
+        if i != 0 {
+            out.push_str("::");
+        }
+        demangle_ident(ident, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle_legacy() {
+        assert_eq!(
+            demangle("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "core::fmt::write"
+        );
+        // no hash at the end
+        assert_eq!(demangle("_ZN6kernel4mainE"), "kernel::main");
+        assert_eq!(demangle("_ZN10_$LT$T$GT$3fooE"), "<T>::foo");
+    }
+
+    #[test]
+    fn other_names_are_kept() {
+        for name in [
+            "memcpy",
+            "_ZN",
+            "_ZN4co",
+            "_ZNxE",
+            // the lengths overflow
+            "_ZN18446744073709551615xE",
+            "_ZN99999999999999999999999xE",
+        ] {
+            assert_eq!(demangle(name), name);
+        }
+    }
+
+    /// An ELF header with the section headers at `offset`, `count` of them
+    fn elf_header(offset: u64, count: u16) -> [u8; ELF_HEADER_LEN] {
+        let mut header = [0; ELF_HEADER_LEN];
+        header[..4].copy_from_slice(&ELF_MAGIC);
+        header[4] = ELF_CLASS_64;
+        header[5] = ELF_DATA_LITTLE;
+        header[0x28..0x30].copy_from_slice(&offset.to_le_bytes());
+        header[0x3A..0x3C].copy_from_slice(&(SECTION_HEADER_LEN as u16).to_le_bytes());
+        header[0x3C..0x3E].copy_from_slice(&count.to_le_bytes());
+        header
+    }
+
+    fn read_from(file: &[u8]) -> impl FnMut(u64, &mut [u8]) -> bool + '_ {
+        |offset, buf| {
+            let Some(bytes) = usize::try_from(offset)
+                .ok()
+                .and_then(|start| file.get(start..start.checked_add(buf.len())?))
+            else {
+                return false;
+            };
+            buf.copy_from_slice(bytes);
+            true
+        }
+    }
+
+    #[test]
+    fn not_elf() {
+        assert_eq!(
+            SymbolTable::from_elf(read_from(b"\x7FELF")).unwrap_err(),
+            SymbolsError::NotElf
+        );
+        let mut header = elf_header(0, 0);
+        header[4] = 1;
+        assert_eq!(
+            SymbolTable::from_elf(read_from(&header)).unwrap_err(),
+            SymbolsError::NotElf
+        );
+    }
+
+    #[test]
+    fn section_headers_outside_the_file() {
+        assert_eq!(
+            SymbolTable::from_elf(read_from(&elf_header(0, 0))).unwrap_err(),
+            SymbolsError::NoSymbolTable
+        );
+        // the offsets of the last sections overflow
+        assert_eq!(
+            SymbolTable::from_elf(read_from(&elf_header(u64::MAX - 64, 3))).unwrap_err(),
+            SymbolsError::Read
+        );
+    }
+
+    #[test]
+    fn functions_of_a_symtab() {
+        let strings = b"\0main\0_ZN3foo3bar17h0123456789abcdefE\0data\0";
+        let symbol = |name: u32, info: u8, start: u64, size: u64| {
+            let mut entry = [0; SYMBOL_LEN];
+            entry[0..4].copy_from_slice(&name.to_le_bytes());
+            entry[4] = info;
+            entry[8..16].copy_from_slice(&start.to_le_bytes());
+            entry[16..24].copy_from_slice(&size.to_le_bytes());
+            entry
+        };
+        let symbols = [
+            symbol(1, STT_FUNC, 0x2000, 0x20),
+            symbol(6, STT_FUNC, 0x1000, 0x10),
+            // an object, and a function without an address
+            symbol(37, 1, 0x3000, 8),
+            symbol(1, STT_FUNC, 0, 8),
+        ];
+        let symbols_offset = ELF_HEADER_LEN + 2 * SECTION_HEADER_LEN;
+        let strings_offset = symbols_offset + symbols.len() * SYMBOL_LEN;
+        let section = |ty: u32, offset: usize, len: usize, link: u32| {
+            let mut section = [0; SECTION_HEADER_LEN];
+            section[4..8].copy_from_slice(&ty.to_le_bytes());
+            section[0x18..0x20].copy_from_slice(&(offset as u64).to_le_bytes());
+            section[0x20..0x28].copy_from_slice(&(len as u64).to_le_bytes());
+            section[0x28..0x2C].copy_from_slice(&link.to_le_bytes());
+            section
+        };
+
+        let mut file = elf_header(ELF_HEADER_LEN as u64, 2).to_vec();
+        file.extend(section(
+            SHT_SYMTAB,
+            symbols_offset,
+            symbols.len() * SYMBOL_LEN,
+            1,
+        ));
+        file.extend(section(3, strings_offset, strings.len(), 0));
+        file.extend(symbols.iter().flatten());
+        file.extend(strings);
+
+        let table = SymbolTable::from_elf(read_from(&file)).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.lookup(0x1008), Some(("foo::bar", 8)));
+        assert_eq!(table.lookup(0x2000), Some(("main", 0)));
+        assert_eq!(table.lookup(0x3000), None);
+    }
+
+    fn symbol(start: u64, size: u64, name: &str) -> Symbol {
+        Symbol {
+            start,
+            size,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn lookup_is_sorted_by_start() {
+        let table = SymbolTable::new(vec![
+            symbol(0x3000, 0x10, "c"),
+            symbol(0x1000, 0x100, "a"),
+            symbol(0x2000, 0x10, "b"),
+        ]);
+        assert_eq!(table.lookup(0xFFF), None);
+        assert_eq!(table.lookup(0x1000), Some(("a", 0)));
+        assert_eq!(table.lookup(0x10FF), Some(("a", 0xFF)));
+        // between two functions
+        assert_eq!(table.lookup(0x1100), None);
+        assert_eq!(table.lookup(0x2004), Some(("b", 4)));
+        assert_eq!(table.lookup(0x300F), Some(("c", 0xF)));
+        assert_eq!(table.lookup(0x3010), None);
+    }
+
+    #[test]
+    fn symbol_without_size_has_one_address() {
+        let table = SymbolTable::new(vec![symbol(0x1000, 0, "start")]);
+        assert_eq!(table.lookup(0x1000), Some(("start", 0)));
+        assert_eq!(table.lookup(0x1001), None);
+    }
+
+    #[test]
+    fn empty_table() {
+        let table = SymbolTable::new(Vec::new());
+        assert!(table.is_empty());
+        assert_eq!(table.lookup(0), None);
+        assert_eq!(table.lookup(u64::MAX), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! A streaming UTF-8 decoder for the console.
+//!
+//! Output to the console comes as bytes, and a single character can be split between
+//! different `write` calls, so we keep the incomplete sequence around until its completed.
+
+/// The CP437 characters from `0x80` to `0xFF`, the lower half is ASCII
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅ\
+                          ÉæÆôöòûùÿÖÜ¢£¥₧ƒ\
+                          áíóúñÑªº¿⌐¬½¼¡«»\
+                          ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+                          └┴┬├─┼╞╟╚╔╩╦╠═╬╧\
+                          ╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+                          αßΓπΣσµτΦΘΩδ∞φε∩\
+                          ≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// The glyph used for characters that don't have a CP437 equivalent, `■`
+pub const CP437_REPLACEMENT: u8 = 0xFE;
+
+/// Converts a char into a CP437 glyph, or [`CP437_REPLACEMENT`] if there is no equivalent
+pub fn char_to_cp437(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+
+    CP437_HIGH
+        .chars()
+        .position(|x| x == c)
+        .map(|i| 0x80 + i as u8)
+        .unwrap_or(CP437_REPLACEMENT)
+}
+
+/// The char of a CP437 glyph, the reverse of [`char_to_cp437`]
+pub fn cp437_to_char(glyph: u8) -> char {
+    if glyph.is_ascii() {
+        return glyph as char;
+    }
+
+    CP437_HIGH
+        .chars()
+        .nth((glyph - 0x80) as usize)
+        .expect("CP437_HIGH has all the upper half")
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Utf8Decoder {
+    buf: [u8; 4],
+    len: usize,
+    needed: usize,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; 4],
+            len: 0,
+            needed: 0,
+        }
+    }
+
+    /// Number of bytes in a sequence starting with `byte`, or `None` if it can't start one
+    fn sequence_len(byte: u8) -> Option<usize> {
+        match byte {
+            0x00..=0x7F => Some(1),
+            0xC2..=0xDF => Some(2),
+            0xE0..=0xEF => Some(3),
+            0xF0..=0xF4 => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Feeds a byte into the decoder, calling `f` for every char that got completed.
+    ///
+    /// Invalid sequences produce [`char::REPLACEMENT_CHARACTER`], one for each
+    /// invalid sequence, so that every char takes one cell on the screen.
+    pub fn push(&mut self, byte: u8, mut f: impl FnMut(char)) {
+        if self.needed != 0 {
+            if byte & 0xC0 == 0x80 {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                if self.len == self.needed {
+                    // validates overlong encodings and surrogates as well
+                    let c = core::str::from_utf8(&self.buf[..self.len])
+                        .ok()
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.reset();
+                    f(c);
+                }
+                return;
+            }
+            // the sequence got interrupted, drop it and start a new one with this byte
+            self.reset();
+            f(char::REPLACEMENT_CHARACTER);
+        }
+
+        match Self::sequence_len(byte) {
+            Some(1) => f(byte as char),
+            Some(needed) => {
+                self.buf[0] = byte;
+                self.len = 1;
+                self.needed = needed;
+            }
+            None => f(char::REPLACEMENT_CHARACTER),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.needed = 0;
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The glyphs of the bytes written in `writes`, one write after the other into the same
+    /// decoder, as the console does, each glyph is a cell
+    fn cells(writes: &[&[u8]]) -> Vec<u8> {
+        let mut decoder = Utf8Decoder::new();
+        let mut cells = Vec::new();
+        for write in writes {
+            for &byte in *write {
+                decoder.push(byte, |c| cells.push(char_to_cp437(c)));
+            }
+        }
+        cells
+    }
+
+    /// Every byte of `s` in its own write
+    fn byte_writes(s: &[u8]) -> Vec<&[u8]> {
+        s.chunks(1).collect()
+    }
+
+    #[test]
+    fn ascii_is_passed_through() {
+        assert_eq!(cells(&[b"hello, world"]), b"hello, world");
+        assert_eq!(cells(&[b"\n\t\x1b[0m"]), b"\n\t\x1b[0m");
+    }
+
+    #[test]
+    fn two_byte_split_across_writes() {
+        // é is C3 A9
+        assert_eq!(cells(&[b"caf\xC3", b"\xA9"]), [b'c', b'a', b'f', 0x82]);
+        assert_eq!(cells(&byte_writes("ñÑ".as_bytes())), [0xA4, 0xA5]);
+    }
+
+    #[test]
+    fn three_byte_split_across_writes() {
+        // ─ is E2 94 80
+        assert_eq!(cells(&[b"\xE2", b"\x94", b"\x80"]), [0xC4]);
+        assert_eq!(cells(&[b"\xE2\x94", b"\x80x"]), [0xC4, b'x']);
+        assert_eq!(cells(&byte_writes("┌─┐".as_bytes())), [0xDA, 0xC4, 0xBF]);
+    }
+
+    #[test]
+    fn every_split_gives_the_same_cells() {
+        let text = "a├─b é ½ ░ €x 😀!".as_bytes();
+        let whole = cells(&[text]);
+        for split in 0..=text.len() {
+            let (first, second) = text.split_at(split);
+            assert_eq!(cells(&[first, second]), whole, "split at {split}");
+        }
+        assert_eq!(cells(&byte_writes(text)), whole);
+    }
+
+    #[test]
+    fn one_cell_per_char() {
+        let text = "a├─b é ½ ░ €x 😀!";
+        assert_eq!(cells(&[text.as_bytes()]).len(), text.chars().count());
+        assert_eq!(
+            cells(&byte_writes(text.as_bytes())).len(),
+            text.chars().count()
+        );
+    }
+
+    #[test]
+    fn no_equivalent_is_the_replacement() {
+        // €, a 3 byte char, and 😀, a 4 byte one
+        assert_eq!(cells(&[b"\xE2\x82", b"\xAC"]), [CP437_REPLACEMENT]);
+        assert_eq!(cells(&byte_writes("😀".as_bytes())), [CP437_REPLACEMENT]);
+        assert_eq!(
+            char_to_cp437(char::REPLACEMENT_CHARACTER),
+            CP437_REPLACEMENT
+        );
+    }
+
+    #[test]
+    fn invalid_bytes_are_one_replacement_each() {
+        // a continuation without a start, and bytes that can't be in UTF-8
+        assert_eq!(cells(&[b"\x80"]), [CP437_REPLACEMENT]);
+        assert_eq!(cells(&[b"a\xBFb"]), [b'a', CP437_REPLACEMENT, b'b']);
+        assert_eq!(
+            cells(&[b"\xF5\xFF"]),
+            [CP437_REPLACEMENT, CP437_REPLACEMENT]
+        );
+    }
+
+    #[test]
+    fn interrupted_sequence_is_replaced() {
+        // the start of é, then ASCII, the ASCII is kept
+        assert_eq!(cells(&[b"\xC3", b"a"]), [CP437_REPLACEMENT, b'a']);
+        // the start of a 3 byte char, then the start of é
+        assert_eq!(
+            cells(&[b"\xE2\x94", b"\xC3\xA9"]),
+            [CP437_REPLACEMENT, 0x82]
+        );
+    }
+
+    #[test]
+    fn overlong_and_surrogates_are_replaced() {
+        // `/` as 2 bytes, C0 can't start a sequence so both bytes are replaced
+        assert_eq!(
+            cells(&[b"\xC0\xAF"]),
+            [CP437_REPLACEMENT, CP437_REPLACEMENT]
+        );
+        // `/` as 3 bytes, and U+D800, one replacement for the whole sequence
+        assert_eq!(cells(&[b"\xE0\x80", b"\xAF"]), [CP437_REPLACEMENT]);
+        assert_eq!(cells(&byte_writes(b"\xED\xA0\x80")), [CP437_REPLACEMENT]);
+        // past U+10FFFF
+        assert_eq!(cells(&[b"\xF4\x90\x80\x80"]), [CP437_REPLACEMENT]);
+    }
+
+    #[test]
+    fn cp437_round_trip() {
+        for glyph in 0..=u8::MAX {
+            assert_eq!(char_to_cp437(cp437_to_char(glyph)), glyph, "{glyph:#x}");
+        }
+        assert_eq!(cp437_to_char(0xFF), '\u{a0}');
+        assert_eq!(cp437_to_char(CP437_REPLACEMENT), '■');
+    }
+}
@@ -0,0 +1,211 @@
+//! The ELF core files the kernel writes to `/tmp/core.<pid>` when it kills a process for a
+//! CPU exception, along with the [`crash_dump`](crate::crash_dump). They are only written if
+//! the [`Resource::CoreSize`](crate::process::Resource::CoreSize) limit of the process is not
+//! `0`, which it is by default.
+//!
+//! The layout is the one of Linux, so `readelf` and `gdb` can read them:
+//! - the ELF header (`ET_CORE`), then the program headers, the `PT_NOTE` first
+//! - the notes, each is `namesz: u32, descsz: u32, type: u32`, then the name with its NUL and
+//!   the descriptor, both padded to 4 bytes:
+//!   - `CORE` [`NT_PRSTATUS`] is a [`PrStatus`]
+//!   - `CORE` [`NT_FILE`] is the mappings of the program file: their count and the page size
+//!     as `u64`, then `start, end, offset in pages` for each, then their paths, NUL terminated
+//!   - `KERNEL` [`NT_CORE_INFO`] is a [`CoreInfo`]
+//! - a `PT_LOAD` for each user mapping, their contents are at page aligned offsets. The
+//!   read-only mappings of the program have no contents in the file (`p_filesz` is `0`), they
+//!   are the same as the program, which is in `NT_FILE`
+//!
+//! The limit is on the whole file, the contents of the last mappings are cut to fit, with
+//! `p_filesz` smaller than `p_memsz`, and [`CoreInfo::truncated`] is the bytes left out.
+//! The headers and the notes are always written.
+
+use crate::crash_dump::PlainData;
+
+pub const ELF_HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+pub const ET_CORE: u16 = 4;
+pub const EM_X86_64: u16 = 62;
+pub const PT_LOAD: u32 = 1;
+pub const PT_NOTE: u32 = 4;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const CORE_NOTE_NAME: &str = "CORE";
+pub const NT_PRSTATUS: u32 = 1;
+pub const NT_FILE: u32 = 0x4649_4C45;
+pub const KERNEL_NOTE_NAME: &str = "KERNEL";
+pub const NT_CORE_INFO: u32 = 1;
+
+/// The registers in `PrStatus`, as `struct user_regs_struct` on Linux
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserRegs {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The syscall number on Linux, `u64::MAX` as we are not in one
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+abi_layout!(UserRegs, size = 216, {
+    r15 @ 0,
+    rax @ 80,
+    orig_rax @ 120,
+    rip @ 128,
+    rsp @ 152,
+    gs @ 208,
+});
+
+/// The descriptor of `NT_PRSTATUS`, as `struct elf_prstatus` of Linux on x86_64, the padding
+/// is in fields so that any bytes are valid
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PrStatus {
+    pub signal: i32,
+    pub signal_code: i32,
+    pub errno: i32,
+    /// The same as `signal`
+    pub current_signal: u16,
+    pub _pad0: u16,
+    pub pending_signals: u64,
+    pub held_signals: u64,
+    pub pid: u32,
+    pub ppid: u32,
+    pub process_group: u32,
+    pub session: u32,
+    /// The user, system and children times as `timeval`s, not filled
+    pub times: [u64; 8],
+    pub regs: UserRegs,
+    pub fp_valid: u32,
+    pub _pad1: u32,
+}
+
+abi_layout!(PrStatus, size = 336, {
+    signal @ 0,
+    current_signal @ 12,
+    pending_signals @ 16,
+    pid @ 32,
+    ppid @ 36,
+    times @ 48,
+    regs @ 112,
+    fp_valid @ 328,
+});
+
+/// The descriptor of `NT_CORE_INFO`
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CoreInfo {
+    /// The `Resource::CoreSize` limit the file was written with
+    pub size_limit: u64,
+    /// The bytes of the mappings that didn't fit in the limit
+    pub truncated: u64,
+    /// The CPU exception vector
+    pub vector: u64,
+    /// `cr2`, only meaningful for page faults
+    pub fault_address: u64,
+}
+
+abi_layout!(CoreInfo, size = 32, {
+    size_limit @ 0,
+    truncated @ 8,
+    vector @ 16,
+    fault_address @ 24,
+});
+
+impl PlainData for PrStatus {}
+impl PlainData for CoreInfo {}
+
+impl PrStatus {
+    pub fn as_bytes(&self) -> &[u8] {
+        PlainData::as_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        PlainData::from_bytes(bytes)
+    }
+}
+
+impl CoreInfo {
+    pub fn as_bytes(&self) -> &[u8] {
+        PlainData::as_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        PlainData::from_bytes(bytes)
+    }
+}
+
+/// Rounds `len` up to the 4 bytes the names and descriptors of the notes are padded to
+pub const fn note_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The size of a note with a NUL terminated `name` and `desc_len` bytes of descriptor
+pub const fn note_size(name: &str, desc_len: usize) -> usize {
+    12 + note_align(name.len() + 1) + note_align(desc_len)
+}
+
+/// The bytes of a note before its descriptor: the header and the padded name. `out` must be
+/// at least `note_size(name, 0)` long, returns how much was written
+pub fn note_header(out: &mut [u8], name: &str, desc_len: usize, ty: u32) -> usize {
+    let name_len = name.len() + 1;
+    out[..4].copy_from_slice(&(name_len as u32).to_le_bytes());
+    out[4..8].copy_from_slice(&(desc_len as u32).to_le_bytes());
+    out[8..12].copy_from_slice(&ty.to_le_bytes());
+    let end = 12 + note_align(name_len);
+    out[12..end].fill(0);
+    out[12..12 + name.len()].copy_from_slice(name.as_bytes());
+    end
+}
+
+/// A note of a core file
+#[derive(Debug, Clone, Copy)]
+pub struct Note<'a> {
+    pub name: &'a str,
+    pub ty: u32,
+    pub desc: &'a [u8],
+}
+
+/// The notes in the contents of a `PT_NOTE`, stops at the first that doesn't fit
+pub fn notes<'a>(mut data: &'a [u8]) -> impl Iterator<Item = Note<'a>> {
+    core::iter::from_fn(move || {
+        let bytes: &'a [u8] = data;
+        let u32_at = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+        let name_len = u32_at(0)? as usize;
+        let desc_len = u32_at(4)? as usize;
+        let ty = u32_at(8)?;
+        let desc_start = 12 + note_align(name_len);
+        let name = core::str::from_utf8(bytes.get(12..12 + name_len)?).ok()?;
+        let desc = bytes.get(desc_start..desc_start + desc_len)?;
+        data = &bytes[(desc_start + note_align(desc_len)).min(bytes.len())..];
+        Some(Note {
+            name: name.trim_end_matches('\0'),
+            ty,
+            desc,
+        })
+    })
+}
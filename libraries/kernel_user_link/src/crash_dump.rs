@@ -0,0 +1,250 @@
+//! The format of the crash dumps the kernel writes to `/tmp/crash-<pid>-<name>.dump` when it
+//! kills a process for a CPU exception.
+//!
+//! The file is [`CRASH_DUMP_MAGIC`], the [`CRASH_DUMP_VERSION`] as `u32`, then sections of
+//! `kind: u32, length: u32` followed by `length` bytes, all little endian:
+//! - [`SectionKind::Header`] is a [`CrashHeader`], then the path of the program
+//! - [`SectionKind::Registers`] is a [`CrashRegisters`]
+//! - [`SectionKind::Stack`] is the address of the first byte as `u64`, then the bytes of the
+//!   user stack starting from `rsp`
+//! - [`SectionKind::Maps`] is the mappings of the process as text, one per line
+//!
+//! Unknown sections are skipped when parsing.
+
+use core::{mem, ptr, slice};
+
+pub const CRASH_DUMP_MAGIC: [u8; 8] = *b"CRASHDMP";
+pub const CRASH_DUMP_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SectionKind {
+    Header = 1,
+    Registers = 2,
+    Stack = 3,
+    Maps = 4,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CrashHeader {
+    pub pid: u64,
+    /// The CPU exception vector
+    pub vector: u64,
+    pub error_code: u64,
+    /// `cr2`, only meaningful for page faults
+    pub fault_address: u64,
+}
+
+abi_layout!(CrashHeader, size = 32, {
+    pid @ 0,
+    vector @ 8,
+    error_code @ 16,
+    fault_address @ 24,
+});
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CrashRegisters {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub cs: u64,
+    pub ss: u64,
+}
+
+abi_layout!(CrashRegisters, size = 160, {
+    rip @ 0,
+    rsp @ 8,
+    rflags @ 16,
+    rax @ 24,
+    rbp @ 72,
+    r15 @ 136,
+    cs @ 144,
+    ss @ 152,
+});
+
+/// Types made only of integers and without padding, so any bytes are valid
+pub(crate) trait PlainData: Copy + Default {
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the type has no padding, see `PlainData`
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < mem::size_of::<Self>() {
+            return None;
+        }
+        // SAFETY: the size is checked, and any bytes are valid, see `PlainData`
+        Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+    }
+}
+
+impl PlainData for CrashHeader {}
+impl PlainData for CrashRegisters {}
+
+impl CrashHeader {
+    pub fn as_bytes(&self) -> &[u8] {
+        PlainData::as_bytes(self)
+    }
+}
+
+impl CrashRegisters {
+    pub fn as_bytes(&self) -> &[u8] {
+        PlainData::as_bytes(self)
+    }
+
+    /// The general purpose registers with their names, in the order of the struct
+    pub fn named(&self) -> [(&'static str, u64); 20] {
+        [
+            ("rip", self.rip),
+            ("rsp", self.rsp),
+            ("rflags", self.rflags),
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("cs", self.cs),
+            ("ss", self.ss),
+        ]
+    }
+}
+
+/// The start of the file, before the sections
+pub fn file_header() -> [u8; 12] {
+    let mut header = [0; 12];
+    header[..8].copy_from_slice(&CRASH_DUMP_MAGIC);
+    header[8..].copy_from_slice(&CRASH_DUMP_VERSION.to_le_bytes());
+    header
+}
+
+/// The bytes before the content of a section of `len` bytes
+pub fn section_header(kind: SectionKind, len: usize) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(kind as u32).to_le_bytes());
+    header[4..].copy_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashDumpError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// A section goes after the end of the file
+    Truncated,
+    /// The section is smaller than its content, or not valid UTF-8 for text
+    InvalidSection(u32),
+    MissingSection(SectionKind),
+}
+
+/// A parsed crash dump, borrowing from the bytes of the file
+#[derive(Debug, Clone, Copy)]
+pub struct CrashDump<'a> {
+    pub header: CrashHeader,
+    pub path: &'a str,
+    pub registers: CrashRegisters,
+    /// The address of the first byte of `stack`
+    pub stack_start: u64,
+    pub stack: &'a [u8],
+    pub maps: &'a str,
+}
+
+impl<'a> CrashDump<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, CrashDumpError> {
+        if bytes.len() < 12 || bytes[..8] != CRASH_DUMP_MAGIC {
+            return Err(CrashDumpError::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != CRASH_DUMP_VERSION {
+            return Err(CrashDumpError::UnsupportedVersion(version));
+        }
+
+        let mut header = None;
+        let mut registers = None;
+        let mut stack = None;
+        let mut maps = None;
+
+        let mut rest = &bytes[12..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(CrashDumpError::Truncated);
+            }
+            let kind = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let data = rest[8..].get(..len).ok_or(CrashDumpError::Truncated)?;
+            rest = &rest[8 + len..];
+
+            let invalid = CrashDumpError::InvalidSection(kind);
+            let text = |bytes| core::str::from_utf8(bytes).map_err(|_| invalid);
+            match kind {
+                k if k == SectionKind::Header as u32 => {
+                    let fixed = CrashHeader::from_bytes(data).ok_or(invalid)?;
+                    let path = text(&data[mem::size_of::<CrashHeader>()..])?;
+                    header = Some((fixed, path));
+                }
+                k if k == SectionKind::Registers as u32 => {
+                    registers = Some(CrashRegisters::from_bytes(data).ok_or(invalid)?);
+                }
+                k if k == SectionKind::Stack as u32 => {
+                    let start = data.get(..8).ok_or(invalid)?;
+                    stack = Some((u64::from_le_bytes(start.try_into().unwrap()), &data[8..]));
+                }
+                k if k == SectionKind::Maps as u32 => maps = Some(text(data)?),
+                _ => {}
+            }
+        }
+
+        let missing = CrashDumpError::MissingSection;
+        let (header, path) = header.ok_or(missing(SectionKind::Header))?;
+        let registers = registers.ok_or(missing(SectionKind::Registers))?;
+        let (stack_start, stack) = stack.ok_or(missing(SectionKind::Stack))?;
+        let maps = maps.ok_or(missing(SectionKind::Maps))?;
+        Ok(Self {
+            header,
+            path,
+            registers,
+            stack_start,
+            stack,
+            maps,
+        })
+    }
+
+    /// The aligned 8 byte values of the stack, with their addresses
+    pub fn stack_words(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let stack_start = self.stack_start;
+        let stack = self.stack;
+        (0..stack.len() / 8).map(move |i| {
+            let word = &stack[i * 8..i * 8 + 8];
+            (
+                stack_start + i as u64 * 8,
+                u64::from_le_bytes(word.try_into().unwrap()),
+            )
+        })
+    }
+}
@@ -42,11 +42,101 @@ impl BlockingMode {
     }
 }
 
+/// Flag for [`crate::syscalls::SYS_OPEN`], open a block device for direct access: reads and
+/// writes skip all caches and go to the device, they must be in whole sectors at sector aligned
+/// offsets (otherwise they fail with an invalid argument error).
+///
+/// This is the only way to write to a block device, and only `init` can use it.
+pub const OPEN_DIRECT: u64 = 1 << 1;
+
+/// Flag for [`crate::syscalls::SYS_OPEN`], create an empty file if there is none at the path,
+/// its parent directory must exist.
+///
+/// Only some filesystems can create files (i.e. `ramfs`), the others fail with
+/// [`crate::syscalls::SyscallError::CouldNotWriteToFile`].
+pub const OPEN_CREATE: u64 = 1 << 2;
+
+/// Source for [`crate::syscalls::SYS_EVENT_CREATE`], the event is only signaled by writing
+/// to it.
+///
+/// Reading an event gives its counter as `u64` and resets it, blocking until its not `0`,
+/// in non-blocking mode the read gives `0` bytes instead. Writing a `u64` adds to it.
+pub const EVENT_SOURCE_NONE: u64 = 0;
+/// Source for [`crate::syscalls::SYS_EVENT_CREATE`], the kernel signals the event when the
+/// free physical memory goes under the low-water mark, and right away if its already under it.
+pub const EVENT_SOURCE_LOW_MEMORY: u64 = 1;
+
+/// Flag for [`crate::syscalls::SYS_MOUNT`], mount the filesystem read-only, its writes fail
+/// with [`crate::syscalls::SyscallError::ReadOnlyFileSystem`].
+///
+/// Only the filesystems on block devices can use it.
+pub const MOUNT_READ_ONLY: u64 = 1 << 0;
+
+/// Operation for [`crate::syscalls::SYS_FLOCK`], take a shared lock on the whole file, many
+/// files can hold one at the same time
+pub const LOCK_SHARED: u64 = 1 << 0;
+/// Operation for [`crate::syscalls::SYS_FLOCK`], take an exclusive lock, no other file can hold
+/// a lock on the file at the same time
+pub const LOCK_EXCLUSIVE: u64 = 1 << 1;
+/// Flag for [`LOCK_SHARED`] and [`LOCK_EXCLUSIVE`], fail with
+/// [`crate::syscalls::SyscallError::WouldBlock`] instead of waiting for the lock
+pub const LOCK_NONBLOCK: u64 = 1 << 2;
+/// Operation for [`crate::syscalls::SYS_FLOCK`], release the lock of the file, closing the file
+/// releases it as well
+pub const LOCK_UNLOCK: u64 = 1 << 3;
+
+/// An operation of [`crate::syscalls::SYS_FLOCK`].
+///
+/// The locks are advisory, they don't stop anyone from reading or writing, only the other
+/// locks. Taking a lock of the other kind on a file that has one converts it, the old lock is
+/// released first, so others may get in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlockOperation {
+    Lock { exclusive: bool, block: bool },
+    Unlock,
+}
+
+impl FlockOperation {
+    pub fn from_u64(operation: u64) -> Option<Self> {
+        let block = operation & LOCK_NONBLOCK == 0;
+        match operation & !LOCK_NONBLOCK {
+            LOCK_SHARED => Some(Self::Lock {
+                exclusive: false,
+                block,
+            }),
+            LOCK_EXCLUSIVE => Some(Self::Lock {
+                exclusive: true,
+                block,
+            }),
+            LOCK_UNLOCK if block => Some(Self::Unlock),
+            _ => None,
+        }
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        match self {
+            Self::Lock { exclusive, block } => {
+                let kind = if *exclusive {
+                    LOCK_EXCLUSIVE
+                } else {
+                    LOCK_SHARED
+                };
+                if *block {
+                    kind
+                } else {
+                    kind | LOCK_NONBLOCK
+                }
+            }
+            Self::Unlock => LOCK_UNLOCK,
+        }
+    }
+}
+
 /// Will extract all the information from the flags, will return `None` if the argument
 /// is invalid
 pub fn parse_flags(flags: u64) -> Option<BlockingMode> {
     let blocking_mode = BlockingMode::from_flags(flags);
-    let flags = flags & !1;
+    let flags = flags & !(1 | OPEN_DIRECT | OPEN_CREATE);
     // must be 0 at the end
     if flags == 0 {
         Some(blocking_mode)
@@ -58,3 +148,117 @@ pub fn parse_flags(flags: u64) -> Option<BlockingMode> {
 pub fn parse_blocking_mode(blocking_mode: u64) -> Option<BlockingMode> {
     BlockingMode::from_blocking_mode_num(blocking_mode)
 }
+
+/// Flag for [`crate::syscalls::SYS_READ_DIR`], walk the whole tree under the directory
+/// instead of only its direct entries, the names will then be paths relative to the directory
+pub const READ_DIR_RECURSIVE: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum DirEntryKind {
+    #[default]
+    File = 0,
+    Directory = 1,
+    Device = 2,
+}
+
+/// The header of an entry returned by [`crate::syscalls::SYS_READ_DIR`].
+///
+/// Its followed by `name_len` bytes of the name (UTF-8, not null terminated),
+/// and the next entry starts `record_len` bytes after the start of this one.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DirEntryHeader {
+    pub record_len: u32,
+    pub name_len: u16,
+    pub kind: DirEntryKind,
+    pub _pad: u8,
+    pub size: u64,
+}
+
+abi_layout!(DirEntryHeader, size = 16, {
+    record_len @ 0,
+    name_len @ 4,
+    kind @ 6,
+    _pad @ 7,
+    size @ 8,
+});
+
+/// Information about an open file, filled by [`crate::syscalls::SYS_STAT`]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct FileStat {
+    /// Identifies the file inside its filesystem, its kept when the file is reopened,
+    /// but can change if the file is moved
+    pub inode_id: u64,
+    pub size: u64,
+    pub kind: DirEntryKind,
+    pub _pad: [u8; 7],
+}
+
+abi_layout!(FileStat, size = 24, { inode_id @ 0, size @ 8, kind @ 16, _pad @ 17 });
+
+/// Alignment of each entry in the buffer of [`crate::syscalls::SYS_READ_DIR`]
+pub const DIR_ENTRY_ALIGN: usize = 8;
+
+impl DirEntryHeader {
+    const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Writes an entry to the start of `buf`, returns the number of bytes used,
+    /// or `None` if it doesn't fit
+    pub fn write_entry(buf: &mut [u8], kind: DirEntryKind, size: u64, name: &str) -> Option<usize> {
+        let name_len = u16::try_from(name.len()).ok()?;
+        let record_len = (Self::SIZE + name.len()).next_multiple_of(DIR_ENTRY_ALIGN);
+        if record_len > buf.len() {
+            return None;
+        }
+        let header = Self {
+            record_len: record_len as u32,
+            name_len,
+            kind,
+            _pad: 0,
+            size,
+        };
+        // SAFETY: we checked the size, and we don't require `buf` to be aligned
+        unsafe { (buf.as_mut_ptr() as *mut Self).write_unaligned(header) };
+        buf[Self::SIZE..Self::SIZE + name.len()].copy_from_slice(name.as_bytes());
+        buf[Self::SIZE + name.len()..record_len].fill(0);
+        Some(record_len)
+    }
+}
+
+/// Iterates over the entries written by [`crate::syscalls::SYS_READ_DIR`],
+/// stops on the first malformed entry
+pub struct DirEntryIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> DirEntryIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for DirEntryIter<'a> {
+    type Item = (DirEntryHeader, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < DirEntryHeader::SIZE {
+            return None;
+        }
+        // make sure `kind` is valid before reading the header
+        if self.buf[core::mem::offset_of!(DirEntryHeader, kind)] > DirEntryKind::Device as u8 {
+            return None;
+        }
+        // SAFETY: we checked the size, and the `kind` is valid
+        let header = unsafe { (self.buf.as_ptr() as *const DirEntryHeader).read_unaligned() };
+        let record_len = header.record_len as usize;
+        let name_end = DirEntryHeader::SIZE + header.name_len as usize;
+        if record_len < name_end || record_len > self.buf.len() {
+            return None;
+        }
+        let name = core::str::from_utf8(&self.buf[DirEntryHeader::SIZE..name_end]).ok()?;
+        self.buf = &self.buf[record_len..];
+        Some((header, name))
+    }
+}
@@ -0,0 +1,184 @@
+//! The records read from the input devices.
+//!
+//! `/devices/mouse` gives whole [`MouseEvent`]s, as many as fit in the read, a read smaller
+//! than one event fails, and when there is no event a non-blocking read gives `0` bytes.
+//!
+//! `/devices/input_inject` takes [`InputRecord`]s the other way, one per line in the text of
+//! [`InputRecord::parse`], and replays them as if the keyboard and the mouse sent them.
+
+use core::fmt;
+
+/// The left, right and middle buttons in [`MouseEvent::buttons`]
+pub mod mouse_button {
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+}
+
+/// One packet of the mouse, the movement since the last one, and the buttons held
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MouseEvent {
+    /// Positive to the right
+    pub dx: i16,
+    /// Positive down, as on the screen (the mouse itself reports it the other way)
+    pub dy: i16,
+    /// Positive when scrolled down, always `0` for mice without a wheel
+    pub wheel: i8,
+    /// The [`mouse_button`]s held
+    pub buttons: u8,
+    pub reserved: u16,
+}
+
+abi_layout!(MouseEvent, size = 8, {
+    dx @ 0,
+    dy @ 2,
+    wheel @ 4,
+    buttons @ 5,
+    reserved @ 6,
+});
+
+impl MouseEvent {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..2].copy_from_slice(&self.dx.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.dy.to_le_bytes());
+        bytes[4] = self.wheel as u8;
+        bytes[5] = self.buttons;
+        bytes[6..8].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self {
+            dx: i16::from_le_bytes([bytes[0], bytes[1]]),
+            dy: i16::from_le_bytes([bytes[2], bytes[3]]),
+            wheel: bytes[4] as i8,
+            buttons: bytes[5],
+            reserved: u16::from_le_bytes([bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// The longest [`InputRecord::Delay`], so a script can't hold the input forever
+pub const MAX_INPUT_DELAY_MICROS: u64 = 10_000_000;
+/// The prefix of the extended scancodes, a key `0xE0xx` is sent as the 2 bytes
+pub const EXTENDED_SCANCODE: u16 = 0xE0;
+
+/// A line of `/devices/input_inject`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputRecord {
+    /// `key press 0x1E` or `key release 0x1E`, a scancode of set 1 without the release bit,
+    /// `0xE0xx` for the extended ones
+    Key { scancode: u16, pressed: bool },
+    /// `mouse rel <dx> <dy> [wheel <w>] [buttons <b>]`, a packet with the fields of a
+    /// [`MouseEvent`], the movements in `-255..=255`
+    Mouse(MouseEvent),
+    /// `delay 10ms` or `delay 500us`, the next records wait for it
+    Delay { micros: u64 },
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+impl InputRecord {
+    /// The bytes the keyboard sends for a [`InputRecord::Key`], the release bit is set on the
+    /// last one
+    pub fn scancode_bytes(scancode: u16, pressed: bool) -> ([u8; 2], usize) {
+        let code = scancode as u8 | if pressed { 0 } else { 0x80 };
+        if scancode >> 8 == EXTENDED_SCANCODE {
+            ([EXTENDED_SCANCODE as u8, code], 2)
+        } else {
+            ([code, 0], 1)
+        }
+    }
+
+    /// A record from its line, `None` if it's not one or a value is out of range
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let record = match words.next()? {
+            "key" => {
+                let pressed = match words.next()? {
+                    "press" => true,
+                    "release" => false,
+                    _ => return None,
+                };
+                let scancode = u16::try_from(parse_number(words.next()?)?).ok()?;
+                let code = scancode & 0xFF;
+                let prefix = scancode >> 8;
+                if code == 0 || code >= 0x80 || (prefix != 0 && prefix != EXTENDED_SCANCODE) {
+                    return None;
+                }
+                Self::Key { scancode, pressed }
+            }
+            "mouse" => {
+                if words.next()? != "rel" {
+                    return None;
+                }
+                let movement = |word: Option<&str>| {
+                    let value = parse_number(word?)?;
+                    (-255..=255).contains(&value).then_some(value as i16)
+                };
+                let mut event = MouseEvent {
+                    dx: movement(words.next())?,
+                    dy: movement(words.next())?,
+                    ..MouseEvent::default()
+                };
+                while let Some(field) = words.next() {
+                    let value = parse_number(words.next()?)?;
+                    match field {
+                        "wheel" if (-8..=7).contains(&value) => event.wheel = value as i8,
+                        "buttons" if (0..=7).contains(&value) => event.buttons = value as u8,
+                        _ => return None,
+                    }
+                }
+                Self::Mouse(event)
+            }
+            "delay" => {
+                let text = words.next()?;
+                let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+                    (number, 1000)
+                } else {
+                    (text.strip_suffix("us")?, 1)
+                };
+                let micros = number.parse::<u64>().ok()?.checked_mul(scale)?;
+                if micros > MAX_INPUT_DELAY_MICROS {
+                    return None;
+                }
+                Self::Delay { micros }
+            }
+            _ => return None,
+        };
+        // nothing after the record
+        words.next().is_none().then_some(record)
+    }
+}
+
+/// The line [`InputRecord::parse`] reads back
+impl fmt::Display for InputRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key { scancode, pressed } => {
+                let action = if *pressed { "press" } else { "release" };
+                write!(f, "key {action} {scancode:#04x}")
+            }
+            Self::Mouse(event) => write!(
+                f,
+                "mouse rel {} {} wheel {} buttons {}",
+                event.dx, event.dy, event.wheel, event.buttons
+            ),
+            Self::Delay { micros } => write!(f, "delay {micros}us"),
+        }
+    }
+}
@@ -1,9 +1,50 @@
 #![no_std]
 
+/// Compile-time checks on the layout of a type that crosses the user-kernel boundary.
+///
+/// Any change to the size or field offsets of such a type breaks both the kernel and
+/// userspace builds, instead of silently corrupting data between them.
+///
+/// ```ignore
+/// abi_layout!(SpawnFileMapping, size = 16, { src_fd @ 0, dst_fd @ 8 });
+/// ```
+macro_rules! abi_layout {
+    ($ty:ty, size = $size:expr, { $($field:ident @ $offset:expr),* $(,)? }) => {
+        const _: () = {
+            assert!(::core::mem::size_of::<$ty>() == $size, "ABI size mismatch");
+            $(
+                assert!(
+                    ::core::mem::offset_of!($ty, $field) == $offset,
+                    "ABI field offset mismatch"
+                );
+            )*
+        };
+    };
+}
+
+pub mod core_dump;
+pub mod crash_dump;
 pub mod file;
+pub mod input;
 pub mod process;
+pub mod signal;
+pub mod startup;
 pub mod syscalls;
+pub mod sysinfo;
+pub mod time;
+pub mod watch;
 
 pub const FD_STDIN: usize = 0;
 pub const FD_STDOUT: usize = 1;
 pub const FD_STDERR: usize = 2;
+
+/// The version of the user-kernel interface.
+///
+/// This must be bumped whenever any type in this crate that crosses the boundary changes,
+/// or a syscall changes its arguments or semantics.
+/// The kernel reports its version through `SYS_SYSINFO`, and userspace refuses to run if
+/// it doesn't match the version it was built with.
+///
+/// The `abi_mismatch_test` feature gives the next version, to build a program the check
+/// must reject.
+pub const ABI_VERSION: u32 = 31 + cfg!(feature = "abi_mismatch_test") as u32;
@@ -4,3 +4,87 @@ pub struct SpawnFileMapping {
     pub src_fd: usize,
     pub dst_fd: usize,
 }
+
+abi_layout!(SpawnFileMapping, size = 16, { src_fd @ 0, dst_fd @ 8 });
+
+/// The resources that are limited per process, used with `SYS_SET_RLIMIT` and `SYS_GET_RLIMIT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Resource {
+    /// The pages mapped in the user memory of the process, including its page tables
+    MemoryPages = 0,
+    OpenFiles = 1,
+    /// Total CPU time (user and kernel) in milliseconds
+    CpuTimeMs = 2,
+    /// The most bytes of the core file written when the process crashes, `0` (the default)
+    /// writes none, see [`core_dump`](crate::core_dump). Unlike the others anyone can raise it
+    CoreSize = 3,
+    /// The most bytes the stack of the main thread grows to, it starts small and grows down
+    /// when the process touches the pages below it, so going past this (or past the space
+    /// kept for the stack) kills the process instead of reaching other memory
+    StackSize = 4,
+}
+
+impl Resource {
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::MemoryPages),
+            1 => Some(Self::OpenFiles),
+            2 => Some(Self::CpuTimeMs),
+            3 => Some(Self::CoreSize),
+            4 => Some(Self::StackSize),
+            _ => None,
+        }
+    }
+}
+
+/// The most bytes of a process name, set with `SYS_SET_NAME`, longer names are cut at the
+/// last character that fits.
+///
+/// The names are shown everywhere the kernel prints a pid, so they can't have whitespace,
+/// control characters or `/`. A process starts with the file name of its program, with these
+/// replaced by `_`
+pub const PROCESS_NAME_LEN: usize = 16;
+
+/// The value of a resource limit that is not limited
+pub const RLIMIT_INFINITY: u64 = i64::MAX as u64;
+
+/// The scheduling priority of a process, given to `SYS_SET_PRIORITY`, like the nice levels but
+/// from `0`, the lower ones run first. A process waiting to run gets one closer to the highest
+/// every time another runs before it, so the lower ones still run, only less often.
+///
+/// The children start with the priority of their parent, and `init` with [`PRIORITY_DEFAULT`]
+pub const PRIORITY_HIGHEST: u64 = 0;
+/// For the programs waiting on the user, which should run as soon as they can
+pub const PRIORITY_INTERACTIVE: u64 = 10;
+pub const PRIORITY_DEFAULT: u64 = 20;
+/// For the programs using the CPU for a long time, which can wait for the others
+pub const PRIORITY_BATCH: u64 = 30;
+pub const PRIORITY_LOWEST: u64 = 39;
+
+/// The access of the pages given to `SYS_MPROTECT`, `SYS_SHM_MAP` and `SYS_MMAP`,
+/// [`PROT_READ`] must always be set, as a page can't be mapped without it. The pages are
+/// always executable, as the kernel doesn't use the no-execute bit, so [`PROT_EXEC`] changes
+/// nothing
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// For `SYS_SHM_CREATE`, creates the object if there is none with the name
+pub const SHM_CREATE: u64 = 1 << 0;
+/// With [`SHM_CREATE`], fails with `AlreadyExists` if there is an object with the name
+pub const SHM_EXCLUSIVE: u64 = 1 << 1;
+/// The most bytes of the name of a shared memory object, which can't have `/`, whitespace
+/// or control characters
+pub const SHM_NAME_LEN: usize = 32;
+/// The largest shared memory object, its size is rounded up to pages
+pub const SHM_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The exit code of a process killed for going over its CPU time limit
+pub const EXIT_CODE_CPU_LIMIT: i32 = 128 + 9;
+/// The exit code of a program built for another [`ABI_VERSION`](crate::ABI_VERSION) than the
+/// one of the kernel, it exits before `main`
+pub const EXIT_CODE_ABI_MISMATCH: i32 = 126;
+/// The exit code of a process killed for a CPU exception (i.e. page fault),
+/// see [`crash_dump`](crate::crash_dump)
+pub const EXIT_CODE_CRASH: i32 = 128 + 11;
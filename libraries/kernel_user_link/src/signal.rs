@@ -0,0 +1,90 @@
+//! Signals, sent to a process with `SYS_KILL`, and by the kernel: [`SIGINT`] to the processes of
+//! a terminal on `Ctrl+C`, and the signal of [`signal_for_exception`] for a CPU exception.
+//!
+//! The numbers are the ones of Linux. Each signal is ignored, handled or gets the default
+//! action, set with `SYS_SIGACTION`. The default of all of them is to exit with
+//! [`exit_code`], [`SIGKILL`] always does. A handler is called as `extern "C" fn(signal: u64)`
+//! on the stack of the thread, below a [`SignalFrame`], when the process next returns to the
+//! user. It returns to the `restorer` given with it, which must do `SYS_SIGRETURN` right away,
+//! the interrupted code then continues. The signal isn't delivered again until then.
+//!
+//! The FPU state isn't in the frame, the programs are built without SSE, so the handlers
+//! don't touch it.
+
+/// The interrupt from the terminal, `Ctrl+C`
+pub const SIGINT: u64 = 2;
+pub const SIGILL: u64 = 4;
+pub const SIGBUS: u64 = 7;
+pub const SIGFPE: u64 = 8;
+/// Can't be ignored or handled
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGTERM: u64 = 15;
+
+/// The signals are `1..NUM_SIGNALS`, a bit of a `u64` each
+pub const NUM_SIGNALS: u64 = 32;
+
+/// The `handler` of `SYS_SIGACTION` for the default action
+pub const SIG_DFL: u64 = 0;
+/// The `handler` of `SYS_SIGACTION` to ignore the signal
+pub const SIG_IGN: u64 = 1;
+
+/// The exit code of a process killed by `signal`, like the shells show them
+pub const fn exit_code(signal: u64) -> i32 {
+    128 + signal as i32
+}
+
+/// The signal Linux would send for the CPU exception `vector`
+pub fn signal_for_exception(vector: u8) -> u64 {
+    match vector {
+        0 | 16 | 19 => SIGFPE,
+        6 => SIGILL,
+        12 | 17 => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+/// The registers of the interrupted code, restored by `SYS_SIGRETURN`
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SignalRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    /// Only the arithmetic flags and the direction flag are restored
+    pub rflags: u64,
+}
+
+abi_layout!(SignalRegisters, size = 144, { rax @ 0, rsp @ 56, rip @ 128, rflags @ 136 });
+
+/// What the kernel pushes on the stack of the thread for a handler, the handler starts with
+/// the stack pointer at `restorer`, like after a `call`, so `SYS_SIGRETURN` finds it 8 bytes
+/// below the stack pointer after the `ret`
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SignalFrame {
+    /// The return address of the handler
+    pub restorer: u64,
+    pub signal: u64,
+    /// The signals blocked before the handler, a bit each
+    pub blocked: u64,
+    pub registers: SignalRegisters,
+}
+
+abi_layout!(SignalFrame, size = 168, { restorer @ 0, signal @ 8, blocked @ 16, registers @ 24 });
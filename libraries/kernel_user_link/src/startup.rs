@@ -0,0 +1,122 @@
+//! The state of a new process when it starts running at the entry point of its ELF.
+//!
+//! The entry point is called like an `extern "C"` function (x86_64 SysV ABI):
+//!
+//! ```ignore
+//! extern "C" fn _start(argc: usize, argv: *const *const u8, envp: *const *const u8, auxv: *const AuxEntry) -> !
+//! ```
+//!
+//! The same data is on the stack as well, from the lowest address:
+//!
+//! | address               | content                                              |
+//! |-----------------------|------------------------------------------------------|
+//! | `rsp`                 | `0`, the return address of the entry "call"          |
+//! | `rsp + 8`             | `argc`, this address is aligned to 16 bytes          |
+//! | `rsp + 16`            | `argv[0]`..`argv[argc - 1]`, then a null pointer     |
+//! | after `argv`          | `envp[0]`.., then a null pointer                     |
+//! | after `envp`          | [`AuxEntry`]s, the last one has the key [`AT_NULL`]  |
+//! | aligned to 16 bytes   | the 16 bytes pointed to by [`AT_RANDOM`]             |
+//! |                       | the strings of `argv`, `envp` and [`AT_EXECFN`]      |
+//! | stack end             |                                                      |
+//!
+//! So `rsp + 8` is aligned to 16 bytes at the entry, exactly as it would be after a `call`
+//! instruction, and a program that looks at the stack sees `argc` at a 16 bytes aligned
+//! address like on other SysV systems.
+//!
+//! [`StackLayout`] computes these addresses, it is used by the kernel to build the stack.
+
+/// Marks the end of the auxiliary vector
+pub const AT_NULL: u64 = 0;
+/// An entry to skip, in the place of one that is not given to this process
+pub const AT_IGNORE: u64 = 1;
+/// The size of a page in bytes
+pub const AT_PAGESZ: u64 = 6;
+/// The entry point of the program
+pub const AT_ENTRY: u64 = 9;
+/// The address of 16 random bytes, to seed hash tables and such
+pub const AT_RANDOM: u64 = 25;
+/// The address of the path the program was loaded from, as a C string
+pub const AT_EXECFN: u64 = 31;
+/// The address of the [`TimePage`](crate::time::TimePage), only there if the kernel offers
+/// it, this one is not from Linux
+pub const AT_TIME_PAGE: u64 = 0x100;
+
+/// The number of bytes pointed to by [`AT_RANDOM`]
+pub const AT_RANDOM_SIZE: usize = 16;
+
+/// The alignment of `argc` on the stack
+pub const STACK_ALIGNMENT: u64 = 16;
+
+/// An entry of the auxiliary vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AuxEntry {
+    pub key: u64,
+    pub value: u64,
+}
+
+abi_layout!(AuxEntry, size = 16, { key @ 0, value @ 8 });
+
+/// The addresses of every part of the initial stack, see the [module docs](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLayout {
+    /// The value of `rsp` at the entry
+    pub rsp: u64,
+    pub argc: u64,
+    pub argv: u64,
+    pub envp: u64,
+    pub auxv: u64,
+    pub random: u64,
+    /// The start of the strings, they are packed (with their null terminators) until the end
+    pub strings: u64,
+}
+
+impl StackLayout {
+    /// Computes the layout for `argc` arguments, `envc` environment variables and
+    /// `auxc` auxiliary entries (without the [`AT_NULL`] entry), with `strings_size` bytes
+    /// of strings (including their null terminators).
+    ///
+    /// The stack is `stack_start..stack_end`, and `stack_end` must be aligned to
+    /// [`STACK_ALIGNMENT`]. Returns `None` if all of this doesn't fit in the stack
+    pub fn new(
+        stack_start: u64,
+        stack_end: u64,
+        argc: usize,
+        envc: usize,
+        auxc: usize,
+        strings_size: usize,
+    ) -> Option<Self> {
+        assert!(stack_end & (STACK_ALIGNMENT - 1) == 0);
+
+        let strings = stack_end.checked_sub(strings_size as u64)?;
+        let random = align_down(strings.checked_sub(AT_RANDOM_SIZE as u64)?, STACK_ALIGNMENT);
+
+        // argc, argv + null, envp + null
+        let pointers = 1 + (argc + 1) + (envc + 1);
+        let auxv_size = (auxc + 1) * core::mem::size_of::<AuxEntry>();
+        let block_size = (pointers * 8 + auxv_size) as u64;
+
+        let argc_address = align_down(random.checked_sub(block_size)?, STACK_ALIGNMENT);
+        let rsp = argc_address.checked_sub(8)?;
+        if rsp < stack_start {
+            return None;
+        }
+        let argv = argc_address + 8;
+        let envp = argv + (argc as u64 + 1) * 8;
+        let auxv = envp + (envc as u64 + 1) * 8;
+
+        Some(Self {
+            rsp,
+            argc: argc_address,
+            argv,
+            envp,
+            auxv,
+            random,
+            strings,
+        })
+    }
+}
+
+const fn align_down(value: u64, alignment: u64) -> u64 {
+    value & !(alignment - 1)
+}
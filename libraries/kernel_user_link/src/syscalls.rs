@@ -1,3 +1,4 @@
+mod table;
 mod types_conversions;
 
 /// must be one of user interrupts, i.e. 0x20+
@@ -6,21 +7,13 @@ mod types_conversions;
 /// user-kernel
 pub const SYSCALL_INTERRUPT_NUMBER: u8 = 0xFE;
 
-pub const NUM_SYSCALLS: usize = 10;
-
 mod numbers {
-    pub const SYS_OPEN: u64 = 0;
-    pub const SYS_WRITE: u64 = 1;
-    pub const SYS_READ: u64 = 2;
-    pub const SYS_CLOSE: u64 = 3;
-    pub const SYS_BLOCKING_MODE: u64 = 4;
-    pub const SYS_EXIT: u64 = 5;
-    pub const SYS_SPAWN: u64 = 6;
-    pub const SYS_INC_HEAP: u64 = 7;
-    pub const SYS_CREATE_PIPE: u64 = 8;
-    pub const SYS_WAIT_PID: u64 = 9;
+    use crate::define_syscalls;
+
+    crate::syscall_table!(define_syscalls);
 }
 pub use numbers::*;
+pub use table::*;
 
 /// Creates a syscall, the first argument is the syscall number (in RAX), then the arguments are as follows
 /// RCX, RDX, RSI, RDI, R8, R9, R10 (7 arguments max)
@@ -71,45 +64,6 @@ macro_rules! call_syscall {
     };
 }
 
-/// Get the syscall arguments from the interrupt state, the arguments come from
-/// the registers RCX, RDX, RSI, RDI, R8, R9, R10
-#[macro_export]
-macro_rules! sys_arg {
-    ($num:tt, $context_struct:expr) => {
-        sys_arg!(@impl $num, $context_struct => u64)
-    };
-    ($num:tt, $context_struct:expr => $func:ident($ty:ty)) => {
-        sys_arg!($num, $context_struct => $ty).and_then($func)
-    };
-    ($num:tt, $context_struct:expr => $ty:ty) => {
-        syscall_arg_to_u64::<$ty>(sys_arg!(@impl $num, $context_struct))
-    };
-    (@impl 0, $context_struct:expr) => {
-        $context_struct.rcx
-    };
-    (@impl 1, $context_struct:expr) => {
-        $context_struct.rdx
-    };
-    (@impl 2, $context_struct:expr) => {
-        $context_struct.rsi
-    };
-    (@impl 3, $context_struct:expr) => {
-        $context_struct.rdi
-    };
-    (@impl 4, $context_struct:expr) => {
-        $context_struct.r8
-    };
-    (@impl 5, $context_struct:expr) => {
-        $context_struct.r9
-    };
-    (@impl 6, $context_struct:expr) => {
-        $context_struct.r10
-    };
-    (@impl $rest:tt, $context_struct:expr) => {
-        compile_error!("Not valid argument number")
-    };
-}
-
 #[macro_export]
 macro_rules! to_arg_err {
     ($num:tt, $err:expr) => {
@@ -141,38 +95,6 @@ macro_rules! to_arg_err {
     };
 }
 
-#[macro_export]
-macro_rules! verify_args {
-    () => {((), (), (), (), (), (), ())};
-    ($arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr, $arg6:expr, $arg7:expr) => {
-        match ($arg1, $arg2, $arg3, $arg4, $arg5, $arg6, $arg7) {
-            (
-                Ok(a),
-                Ok(b),
-                Ok(c),
-                Ok(d),
-                Ok(e),
-                Ok(f),
-                Ok(g),
-            ) => {(a, b, c, d, e, f, g)}
-            err => {
-                return $crate::syscalls::SyscallResult::Err(
-                    $crate::syscalls::SyscallError::InvalidArgument(
-                        err.0.err(), err.1.err(), err.2.err(), err.3.err(), err.4.err(), err.5.err(), err.6.err(),
-                    ),
-                )
-            }
-        }
-    };
-    ($arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr, $arg6:expr, $arg7:expr, $extra:expr) => {
-        compile_error!("Too many arguments for syscall")
-    };
-    // general
-    ($($args:expr),* $(,)?) => {
-        verify_args!($($args ,)* Ok(()))
-    };
-}
-
 pub trait FromSyscallArgU64 {
     fn from_syscall_arg_u64(value: u64) -> Result<Self, SyscallArgError>
     where
@@ -225,6 +147,31 @@ pub enum SyscallError {
     FileNotFound = 10,
     PidNotFound = 11,
     ProcessStillRunning = 12,
+    /// A resource limit of the process doesn't allow the allocation
+    NoMemory = 13,
+    TooManyOpenFiles = 14,
+    PermissionDenied = 15,
+    AlreadyExists = 16,
+    DirectoryNotEmpty = 17,
+    /// Renaming between two filesystems, the file must be copied and removed instead
+    CrossDevice = 18,
+    ReadOnlyFileSystem = 19,
+    /// The file is open (and the filesystem can't remove it until closed), or is a mount point
+    Busy = 20,
+    NoSpace = 21,
+    IsDirectory = 22,
+    IsNotDirectory = 23,
+    /// The operation can't be done on this kind of file, i.e. copying from a device, the
+    /// caller has to do it another way
+    NotSupported = 24,
+    /// The device of the file was removed, the file can only be closed
+    DeviceGone = 25,
+    /// The device failed to read or write, the details are in the kernel log
+    IoError = 26,
+    /// The lock is held by another file, and the operation asked not to wait for it
+    WouldBlock = 27,
+    /// A signal came while waiting, it was handled before the syscall returned
+    Interrupted = 28,
     InvalidArgument(
         Option<SyscallArgError>,
         Option<SyscallArgError>,
@@ -311,6 +258,22 @@ pub fn syscall_result_to_u64(result: SyscallResult) -> u64 {
                 SyscallError::FileNotFound => 10 << 56,
                 SyscallError::PidNotFound => 11 << 56,
                 SyscallError::ProcessStillRunning => 12 << 56,
+                SyscallError::NoMemory => 13 << 56,
+                SyscallError::TooManyOpenFiles => 14 << 56,
+                SyscallError::PermissionDenied => 15 << 56,
+                SyscallError::AlreadyExists => 16 << 56,
+                SyscallError::DirectoryNotEmpty => 17 << 56,
+                SyscallError::CrossDevice => 18 << 56,
+                SyscallError::ReadOnlyFileSystem => 19 << 56,
+                SyscallError::Busy => 20 << 56,
+                SyscallError::NoSpace => 21 << 56,
+                SyscallError::IsDirectory => 22 << 56,
+                SyscallError::IsNotDirectory => 23 << 56,
+                SyscallError::NotSupported => 24 << 56,
+                SyscallError::DeviceGone => 25 << 56,
+                SyscallError::IoError => 26 << 56,
+                SyscallError::WouldBlock => 27 << 56,
+                SyscallError::Interrupted => 28 << 56,
             };
 
             err_upper | (1 << 63)
@@ -360,6 +323,22 @@ pub fn syscall_result_from_u64(value: u64) -> SyscallResult {
             10 => SyscallError::FileNotFound,
             11 => SyscallError::PidNotFound,
             12 => SyscallError::ProcessStillRunning,
+            13 => SyscallError::NoMemory,
+            14 => SyscallError::TooManyOpenFiles,
+            15 => SyscallError::PermissionDenied,
+            16 => SyscallError::AlreadyExists,
+            17 => SyscallError::DirectoryNotEmpty,
+            18 => SyscallError::CrossDevice,
+            19 => SyscallError::ReadOnlyFileSystem,
+            20 => SyscallError::Busy,
+            21 => SyscallError::NoSpace,
+            22 => SyscallError::IsDirectory,
+            23 => SyscallError::IsNotDirectory,
+            24 => SyscallError::NotSupported,
+            25 => SyscallError::DeviceGone,
+            26 => SyscallError::IoError,
+            27 => SyscallError::WouldBlock,
+            28 => SyscallError::Interrupted,
             _ => invalid_error_code(()),
         };
         SyscallResult::Err(err)
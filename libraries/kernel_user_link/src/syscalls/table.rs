@@ -0,0 +1,485 @@
+//! The syscalls, declared once in [`syscall_table!`](crate::syscall_table).
+//!
+//! Everything that must agree between the kernel and userspace is generated from the table:
+//! the numbers and [`SYSCALL_NAMES`](super::SYSCALL_NAMES) (with
+//! [`define_syscalls!`](crate::define_syscalls)), the kernel dispatcher that decodes and
+//! checks the arguments before calling the handler of the same name, and the typed wrappers
+//! of `user_std` (with [`syscall_wrappers!`](crate::syscall_wrappers)).
+//!
+//! The arguments are one of the [`SyscallArg`] kinds: the integers, which are passed as
+//! they are, and the user memory ones, which the kernel checks before the handler runs.
+//! They take the registers in order, see [`call_syscall!`](crate::call_syscall).
+
+use core::{ffi::CStr, fmt, marker::PhantomData};
+
+use crate::call_syscall;
+
+use super::{SyscallArgError, SyscallError, SyscallResult};
+
+/// The syscalls, with their numbers, arguments and return values.
+///
+/// Calls `$generate!` with the entries, each is
+/// `<number> => <constant>: fn <name>(<arg>: <kind>, ...) -> <return>;` with its docs before
+/// it. The numbers must go from `0` up without gaps, the generated code fails to build
+/// otherwise.
+///
+/// Adding a syscall is an entry here and a handler with its name in the kernel.
+#[macro_export]
+macro_rules! syscall_table {
+    ($generate:ident) => {
+        $generate! {
+            /// Opens the file at `path`, returns its descriptor
+            0 => SYS_OPEN: fn open(path: $crate::syscalls::UserStr, access_mode: u64, flags: u64) -> usize;
+            /// Writes `buf` to `fd`, returns the number of bytes written
+            1 => SYS_WRITE: fn write(fd: usize, buf: $crate::syscalls::UserIn) -> u64;
+            /// Reads from `fd` into `buf`, returns the number of bytes read
+            2 => SYS_READ: fn read(fd: usize, buf: $crate::syscalls::UserOut) -> u64;
+            /// Closes `fd`
+            3 => SYS_CLOSE: fn close(fd: usize) -> ();
+            /// Sets the [`BlockingMode`](crate::file::BlockingMode) of `fd`
+            4 => SYS_BLOCKING_MODE: fn blocking_mode(fd: usize, mode: u64) -> ();
+            /// Exits the current process, it doesn't return
+            5 => SYS_EXIT: fn exit(code: i32) -> ();
+            /// Runs the program at `path`, `argv` is a null terminated array of string
+            /// pointers and `file_mappings` an array of `file_mappings_len`
+            /// [`SpawnFileMapping`](crate::process::SpawnFileMapping), returns the pid
+            6 => SYS_SPAWN: fn spawn(path: $crate::syscalls::UserStr, argv: u64, file_mappings: u64, file_mappings_len: usize) -> u64;
+            /// Grows (or shrinks) the heap by `increment` bytes, returns the old end of it
+            7 => SYS_INC_HEAP: fn inc_heap(increment: i64) -> u64;
+            /// Creates a pipe, its descriptors are written to `read_fd` and `write_fd`
+            8 => SYS_CREATE_PIPE: fn create_pipe(read_fd: $crate::syscalls::UserOutValue<u64>, write_fd: $crate::syscalls::UserOutValue<u64>) -> ();
+            /// Returns the exit code of the child `pid`, waits for it if `block` is `1`, the exit
+            /// is kept until then, and returned once
+            9 => SYS_WAIT_PID: fn wait_pid(pid: u64, block: u64) -> u64;
+            /// Fills the [`SysInfo`](crate::sysinfo::SysInfo) at `info`, as much of it as its
+            /// `size` says
+            10 => SYS_SYSINFO: fn sysinfo(info: u64) -> ();
+            /// Reads the entries of the directory `fd` into `buf`, after the first `position`
+            /// ones, returns the number of bytes written
+            11 => SYS_READ_DIR: fn read_dir(fd: usize, buf: $crate::syscalls::UserOut, flags: u64, position: u64) -> u64;
+            /// Sets the limit of `resource` of `pid`
+            12 => SYS_SET_RLIMIT: fn set_rlimit(pid: u64, resource: u64, value: u64) -> ();
+            /// Returns the limit of `resource` of `pid`
+            13 => SYS_GET_RLIMIT: fn get_rlimit(pid: u64, resource: u64) -> u64;
+            /// Fills `stat` with the information of `fd`
+            14 => SYS_STAT: fn stat(fd: usize, stat: $crate::syscalls::UserOutValue<$crate::file::FileStat>) -> ();
+            /// Removes the file at `path`
+            15 => SYS_UNLINK: fn unlink(path: $crate::syscalls::UserStr) -> ();
+            /// Creates the directory `path`
+            16 => SYS_MKDIR: fn mkdir(path: $crate::syscalls::UserStr) -> ();
+            /// Removes the empty directory `path`
+            17 => SYS_RMDIR: fn rmdir(path: $crate::syscalls::UserStr) -> ();
+            /// Moves `old_path` to `new_path`
+            18 => SYS_RENAME: fn rename(old_path: $crate::syscalls::UserStr, new_path: $crate::syscalls::UserStr) -> ();
+            /// Writes everything cached to the devices
+            19 => SYS_SYNC: fn sync() -> ();
+            /// Writes everything cached of the filesystem of `fd` to its device
+            20 => SYS_FSYNC: fn fsync(fd: usize) -> ();
+            /// Creates an event signaled by `source`, returns its descriptor
+            21 => SYS_EVENT_CREATE: fn event_create(source: u64) -> usize;
+            /// Mounts `source` at `target` with the filesystem `fstype`
+            22 => SYS_MOUNT: fn mount(source: $crate::syscalls::UserStr, target: $crate::syscalls::UserStr, fstype: $crate::syscalls::UserStr, flags: u64) -> ();
+            /// Unmounts the filesystem at `target`
+            23 => SYS_UMOUNT: fn umount(target: $crate::syscalls::UserStr) -> ();
+            /// Copies `len` bytes from `fd_in` to `fd_out`, the offsets are pointers to `u64`
+            /// or null, returns the number of bytes copied
+            24 => SYS_COPY_FILE_RANGE: fn copy_file_range(fd_in: usize, off_in: u64, fd_out: usize, off_out: u64, len: u64) -> u64;
+            /// Sets the size of the file `fd`
+            25 => SYS_FTRUNCATE: fn ftruncate(fd: usize, len: u64) -> ();
+            /// Sets the size of the file at `path`
+            26 => SYS_TRUNCATE: fn truncate(path: $crate::syscalls::UserStr, len: u64) -> ();
+            /// Takes or releases the lock of `fd`, the operation is a
+            /// [`FlockOperation`](crate::file::FlockOperation)
+            27 => SYS_FLOCK: fn flock(fd: usize, operation: u64) -> ();
+            /// Returns the time of the [`ClockId`](crate::time::ClockId) `clock` in nanoseconds
+            28 => SYS_CLOCK_GETTIME: fn clock_gettime(clock: u64) -> u64;
+            /// Sets the name of `pid`
+            29 => SYS_SET_NAME: fn set_name(pid: u64, name: $crate::syscalls::UserStr) -> ();
+            /// Copies the name of `pid` into `buf`, returns its length
+            30 => SYS_GET_NAME: fn get_name(pid: u64, buf: $crate::syscalls::UserOut) -> usize;
+            /// Creates a watch, returns its descriptor, see [`watch`](crate::watch)
+            31 => SYS_WATCH_CREATE: fn watch_create() -> usize;
+            /// Watches the directory at `path` for the `WATCH_*` changes in `mask`, returns the
+            /// `wd` of its events, the same if it was added before, with the new `mask`
+            32 => SYS_WATCH_ADD: fn watch_add(watch_fd: usize, path: $crate::syscalls::UserStr, mask: u64) -> u64;
+            /// Copies the current process, its memory is shared until one of them writes to it,
+            /// returns the pid of the child, and `0` in the child
+            33 => SYS_FORK: fn fork() -> u64;
+            /// Changes the access of the pages of `addr..addr + len` to the `PROT_*` in `prot`, `addr`
+            /// must be page aligned, and the range all memory of the process, not the time page, shared memory or files,
+            /// see [`PROT_READ`](crate::process::PROT_READ)
+            34 => SYS_MPROTECT: fn mprotect(addr: u64, len: u64, prot: u64) -> ();
+            /// Opens the shared memory object named `name`, or creates it with `size` bytes with
+            /// [`SHM_CREATE`](crate::process::SHM_CREATE) in `flags`, an empty `name` always
+            /// creates one which is only reached through its descriptor, returns the descriptor
+            35 => SYS_SHM_CREATE: fn shm_create(name: $crate::syscalls::UserStr, size: u64, flags: u64) -> usize;
+            /// Maps all of the shared memory object `fd` with the `PROT_*` in `prot`, returns
+            /// the address, the pages are the same in every process that maps it
+            36 => SYS_SHM_MAP: fn shm_map(fd: usize, prot: u64) -> u64;
+            /// Unmaps the shared memory mapped at `addr` by `SYS_SHM_MAP`
+            37 => SYS_SHM_UNMAP: fn shm_unmap(addr: u64) -> ();
+            /// Maps `len` bytes of the file `fd` from `offset`, which must be page aligned, with the
+            /// `PROT_*` in `prot`, returns the address. Only read-only for now, each page is read
+            /// from the file on its first access, past the end of the file it's zeroed
+            38 => SYS_MMAP: fn mmap(fd: usize, offset: u64, len: u64, prot: u64) -> u64;
+            /// Unmaps the file mapped at `addr` by `SYS_MMAP`
+            39 => SYS_MUNMAP: fn munmap(addr: u64) -> ();
+            /// Sets the scheduling priority of `pid`, see
+            /// [`PRIORITY_DEFAULT`](crate::process::PRIORITY_DEFAULT)
+            40 => SYS_SET_PRIORITY: fn set_priority(pid: u64, priority: u64) -> ();
+            /// Returns the scheduling priority of `pid`
+            41 => SYS_GET_PRIORITY: fn get_priority(pid: u64) -> u64;
+            /// Replaces the program of the current process with the ELF at `path`, `argv` is
+            /// like the one of `SYS_SPAWN`. The pid, the files and the environment stay, it only
+            /// returns if it fails
+            42 => SYS_EXEC: fn exec(path: $crate::syscalls::UserStr, argv: u64) -> ();
+            /// Starts a thread in the current process at `entry`, with `stack_end` as its stack
+            /// pointer, `arg` as its first argument and `tls` (or `0`) as its FS base, returns
+            /// its id. The threads share everything but the registers, and take turns on the
+            /// CPU of the process
+            43 => SYS_THREAD_CREATE: fn thread_create(entry: u64, stack_end: u64, arg: u64, tls: u64) -> u64;
+            /// Exits the current thread, the last one exits the process with `0`
+            44 => SYS_THREAD_EXIT: fn thread_exit() -> ();
+            /// Waits for the thread `id` to exit, each thread can be joined once
+            45 => SYS_THREAD_JOIN: fn thread_join(id: u64) -> ();
+            /// Sends `signal` to the process `pid`, which must be the current one or one of
+            /// its children, unless the current one is privileged. `init` can't be signaled
+            46 => SYS_KILL: fn kill(pid: u64, signal: u64) -> ();
+            /// Sets what `signal` does: `SIG_DFL`, `SIG_IGN`, or the address of a handler,
+            /// which returns to `restorer`, returns the one before. See `signal`
+            47 => SYS_SIGACTION: fn sigaction(signal: u64, handler: u64, restorer: u64) -> u64;
+            /// Returns from a signal handler, the interrupted code continues, only called by
+            /// the `restorer` of `SYS_SIGACTION`
+            48 => SYS_SIGRETURN: fn sigreturn() -> ();
+            /// Blocks the current thread for `nanos` nanoseconds of the monotonic clock at
+            /// least, until the timer tick after that, the others run meanwhile. A handled
+            /// signal stops it early with `Interrupted`
+            49 => SYS_SLEEP: fn sleep(nanos: u64) -> ();
+            /// Blocks the current thread if the `u32` at `addr` (aligned to 4) is `expected`,
+            /// until another thread of the process wakes `addr` with `SYS_FUTEX_WAKE`, otherwise
+            /// fails with `WouldBlock`. A handled signal stops it early with `Interrupted`, the
+            /// value must be checked again after it returns
+            50 => SYS_FUTEX_WAIT: fn futex_wait(addr: u64, expected: u64) -> ();
+            /// Wakes `count` of the threads of the current process waiting for `addr` with
+            /// `SYS_FUTEX_WAIT`, returns how many it did
+            51 => SYS_FUTEX_WAKE: fn futex_wake(addr: u64, count: u64) -> u64;
+        }
+    };
+}
+
+/// Generates the constants of the syscalls, [`NUM_SYSCALLS`](super::NUM_SYSCALLS),
+/// [`SYSCALL_NAMES`](super::SYSCALL_NAMES) and [`SyscallTrace`](super::SyscallTrace) from a
+/// table like [`syscall_table!`](crate::syscall_table).
+///
+/// The numbers must go from `0` up, a gap or a number used twice fails the build:
+///
+/// ```compile_fail,E0080
+/// mod table {
+///     kernel_user_link::define_syscalls! {
+///         0 => SYS_A: fn a() -> ();
+///         0 => SYS_B: fn b() -> ();
+///     }
+/// }
+/// ```
+///
+/// ```compile_fail,E0080
+/// mod table {
+///     kernel_user_link::define_syscalls! {
+///         0 => SYS_A: fn a() -> ();
+///         2 => SYS_B: fn b() -> ();
+///     }
+/// }
+/// ```
+///
+/// And so do more arguments than the registers:
+///
+/// ```compile_fail,E0080
+/// mod table {
+///     kernel_user_link::define_syscalls! {
+///         0 => SYS_A: fn a(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: u64, h: u64) -> ();
+///     }
+/// }
+/// ```
+///
+/// ```
+/// mod table {
+///     kernel_user_link::define_syscalls! {
+///         1 => SYS_B: fn b(buf: kernel_user_link::syscalls::UserIn) -> u64;
+///         0 => SYS_A: fn a() -> ();
+///     }
+/// }
+/// assert_eq!(table::SYSCALL_NAMES, ["a", "b"]);
+/// ```
+#[macro_export]
+macro_rules! define_syscalls {
+    ($($(#[doc = $doc:literal])* $number:literal => $constant:ident: fn $name:ident($($arg:ident: $kind:ty),* $(,)?) -> $ret:ty;)*) => {
+        $(
+            $(#[doc = $doc])*
+            pub const $constant: u64 = $number;
+        )*
+
+        pub const NUM_SYSCALLS: usize = [$($number),*].len();
+
+        /// The names of the syscalls, by number
+        pub const SYSCALL_NAMES: [&str; NUM_SYSCALLS] = {
+            let mut names = [""; NUM_SYSCALLS];
+            $(
+                assert!(
+                    $number < NUM_SYSCALLS,
+                    concat!("the syscall `", stringify!($name), "` has the number ", stringify!($number),
+                        ", which leaves a gap, the numbers must go from 0 up without gaps")
+                );
+                assert!(
+                    names[$number].is_empty(),
+                    concat!("the syscall `", stringify!($name), "` has the number ", stringify!($number),
+                        ", which another syscall has already")
+                );
+                names[$number] = stringify!($name);
+                assert!(
+                    $crate::syscalls::registers_used(&[$(<$kind as $crate::syscalls::SyscallArg>::REGISTERS),*])
+                        <= $crate::syscalls::SYSCALL_ARG_REGISTERS,
+                    concat!("the arguments of the syscall `", stringify!($name), "` don't fit in the registers")
+                );
+            )*
+            names
+        };
+        // checked even if the names are not used
+        const _: [&str; NUM_SYSCALLS] = SYSCALL_NAMES;
+
+        /// A syscall with the arguments in its registers, shown with the names and kinds of
+        /// the table, i.e. `write(fd: 1, buf: 0x1000[12])`
+        #[derive(Debug, Clone, Copy)]
+        pub struct SyscallTrace {
+            pub number: u64,
+            pub registers: [u64; $crate::syscalls::SYSCALL_ARG_REGISTERS],
+        }
+
+        impl ::core::fmt::Display for SyscallTrace {
+            #[allow(unused_mut, unused_variables)]
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self.number {
+                    $(
+                        $number => {
+                            f.write_str(concat!(stringify!($name), "("))?;
+                            let mut separator = "";
+                            let mut register = 0;
+                            $(
+                                f.write_str(separator)?;
+                                f.write_str(concat!(stringify!($arg), ": "))?;
+                                <$kind as $crate::syscalls::SyscallArg>::format(&self.registers[register..], f)?;
+                                register += <$kind as $crate::syscalls::SyscallArg>::REGISTERS;
+                                separator = ", ";
+                            )*
+                            let _ = (separator, register);
+                            f.write_str(")")
+                        }
+                    )*
+                    number => write!(f, "unknown syscall {number}"),
+                }
+            }
+        }
+    };
+}
+
+/// Generates an `unsafe` wrapper for each syscall of a table like
+/// [`syscall_table!`](crate::syscall_table), named like the syscall and taking the
+/// [`SyscallArg::User`] of each argument, for `user_std`.
+#[macro_export]
+macro_rules! syscall_wrappers {
+    ($($(#[doc = $doc:literal])* $number:literal => $constant:ident: fn $name:ident($($arg:ident: $kind:ty),* $(,)?) -> $ret:ty;)*) => {
+        $(
+            $(#[doc = $doc])*
+            ///
+            /// # Safety
+            /// The arguments are passed to the kernel as they are, the files must be the
+            /// caller's to use, and the raw pointers valid.
+            #[allow(unused_mut, unused_variables)]
+            pub unsafe fn $name(
+                $($arg: <$kind as $crate::syscalls::SyscallArg>::User<'_>),*
+            ) -> ::core::result::Result<$ret, $crate::syscalls::SyscallError> {
+                let mut registers = [0; $crate::syscalls::SYSCALL_ARG_REGISTERS];
+                let mut register = 0;
+                $(
+                    <$kind as $crate::syscalls::SyscallArg>::encode($arg, &mut registers[register..]);
+                    register += <$kind as $crate::syscalls::SyscallArg>::REGISTERS;
+                )*
+                let _ = register;
+                unsafe { $crate::syscalls::raw_syscall($crate::syscalls::$constant, registers) }
+                    .map(<$ret as $crate::syscalls::SyscallReturn>::from_u64)
+            }
+        )*
+    };
+}
+
+/// The registers for the arguments, RCX, RDX, RSI, RDI, R8, R9, R10
+pub const SYSCALL_ARG_REGISTERS: usize = 7;
+
+/// The registers taken by arguments of kinds taking `registers` each
+pub const fn registers_used(registers: &[usize]) -> usize {
+    let mut total = 0;
+    let mut i = 0;
+    while i < registers.len() {
+        total += registers[i];
+        i += 1;
+    }
+    total
+}
+
+/// A kind of argument in the table, how it is passed in the registers.
+///
+/// The kernel decodes them in its own trait, checking the user memory ones.
+pub trait SyscallArg {
+    /// The number of registers it takes
+    const REGISTERS: usize;
+    /// The type the userspace wrappers take for it
+    type User<'a>
+    where
+        Self: 'a;
+
+    /// Writes `value` to the first [`Self::REGISTERS`] of `registers`
+    fn encode(value: Self::User<'_>, registers: &mut [u64]);
+    /// Shows the argument in the first [`Self::REGISTERS`] of `registers`, for
+    /// [`SyscallTrace`](super::SyscallTrace)
+    fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+macro_rules! impl_integer_arg {
+    ($($ty:ty),*) => {
+        $(
+            impl SyscallArg for $ty {
+                const REGISTERS: usize = 1;
+                type User<'a> = $ty;
+
+                fn encode(value: Self, registers: &mut [u64]) {
+                    registers[0] = value as u64;
+                }
+
+                fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{}", registers[0] as $ty)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_arg![u64, usize, i64, i32];
+
+/// A null terminated UTF-8 string the kernel reads, of at most a page with the terminator
+pub struct UserStr;
+
+impl SyscallArg for UserStr {
+    const REGISTERS: usize = 1;
+    type User<'a> = &'a CStr;
+
+    fn encode(value: &CStr, registers: &mut [u64]) {
+        registers[0] = value.as_ptr() as u64;
+    }
+
+    fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", registers[0])
+    }
+}
+
+/// Bytes the kernel reads, the pointer then the length
+pub struct UserIn;
+
+impl SyscallArg for UserIn {
+    const REGISTERS: usize = 2;
+    type User<'a> = &'a [u8];
+
+    fn encode(value: &[u8], registers: &mut [u64]) {
+        registers[0] = value.as_ptr() as u64;
+        registers[1] = value.len() as u64;
+    }
+
+    fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}[{}]", registers[0], registers[1])
+    }
+}
+
+/// Bytes the kernel writes, the pointer then the length, all of them must be writable even
+/// if less are written
+pub struct UserOut;
+
+impl SyscallArg for UserOut {
+    const REGISTERS: usize = 2;
+    type User<'a> = &'a mut [u8];
+
+    fn encode(value: &mut [u8], registers: &mut [u64]) {
+        registers[0] = value.as_mut_ptr() as u64;
+        registers[1] = value.len() as u64;
+    }
+
+    fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}[{}]", registers[0], registers[1])
+    }
+}
+
+/// A `T` the kernel writes
+pub struct UserOutValue<T>(PhantomData<T>);
+
+impl<T> SyscallArg for UserOutValue<T> {
+    const REGISTERS: usize = 1;
+    type User<'a>
+        = &'a mut T
+    where
+        T: 'a;
+
+    fn encode(value: &mut T, registers: &mut [u64]) {
+        registers[0] = value as *mut T as u64;
+    }
+
+    fn format(registers: &[u64], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", registers[0])
+    }
+}
+
+/// The value a syscall returns on success, in the `u64` of [`SyscallResult`]
+pub trait SyscallReturn {
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+impl SyscallReturn for u64 {
+    fn to_u64(self) -> u64 {
+        self
+    }
+
+    fn from_u64(value: u64) -> Self {
+        value
+    }
+}
+
+impl SyscallReturn for usize {
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+
+    fn from_u64(value: u64) -> Self {
+        value as usize
+    }
+}
+
+impl SyscallReturn for () {
+    fn to_u64(self) -> u64 {
+        0
+    }
+
+    fn from_u64(_value: u64) -> Self {}
+}
+
+/// The error for the arguments that failed to decode, by register
+pub fn invalid_arguments(errors: [Option<SyscallArgError>; SYSCALL_ARG_REGISTERS]) -> SyscallError {
+    let [a, b, c, d, e, f, g] = errors;
+    SyscallError::InvalidArgument(a, b, c, d, e, f, g)
+}
+
+/// Calls the syscall `number` with all the `registers`, the wrappers use this
+///
+/// # Safety
+/// The same as whatever the syscall does with the arguments
+#[inline(always)]
+pub unsafe fn raw_syscall(number: u64, registers: [u64; SYSCALL_ARG_REGISTERS]) -> SyscallResult {
+    let [a, b, c, d, e, f, g] = registers;
+    unsafe { call_syscall!(number, a, b, c, d, e, f, g) }
+}
@@ -0,0 +1,40 @@
+/// The longest [`SysInfo::version`], longer versions are cut
+pub const SYSINFO_VERSION_LEN: usize = 64;
+
+/// Information about the running kernel, filled by `SYS_SYSINFO`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SysInfo {
+    /// The [`ABI_VERSION`](crate::ABI_VERSION) the kernel was built with
+    pub abi_version: u32,
+    /// The size of this structure as the program knows it, set by [`Default`], the kernel
+    /// fills only this much. `0` is the size before this field was added, only
+    /// `abi_version`, so older programs still get it, and fail cleanly
+    pub size: u32,
+    /// The version of the kernel build (see `/devices/version`), padded with NULs
+    pub version: [u8; SYSINFO_VERSION_LEN],
+}
+
+abi_layout!(SysInfo, size = 72, { abi_version @ 0, size @ 4, version @ 8 });
+
+impl Default for SysInfo {
+    fn default() -> Self {
+        Self {
+            abi_version: 0,
+            size: core::mem::size_of::<Self>() as u32,
+            version: [0; SYSINFO_VERSION_LEN],
+        }
+    }
+}
+
+impl SysInfo {
+    /// The version of the kernel build, empty if the kernel didn't fill it
+    pub fn version(&self) -> &str {
+        let len = self
+            .version
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(SYSINFO_VERSION_LEN);
+        core::str::from_utf8(&self.version[..len]).unwrap_or_default()
+    }
+}
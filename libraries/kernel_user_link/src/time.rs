@@ -0,0 +1,128 @@
+//! The clocks of the kernel, read with `SYS_CLOCK_GETTIME`, or without a syscall through the
+//! time page.
+//!
+//! [`ClockId::Monotonic`] is the nanoseconds since boot, it never goes back.
+//! [`ClockId::Realtime`] is the nanoseconds since the Unix epoch, from the CMOS RTC at boot,
+//! it jumps when set through `/devices/time`.
+//!
+//! When the TSC runs at a constant rate, the kernel maps a read-only [`TimePage`] into every
+//! process, at the address in the [`AT_TIME_PAGE`](crate::startup::AT_TIME_PAGE) entry of the
+//! auxiliary vector. Both clocks are computed from `rdtsc` with it (see [`TimeSnapshot`]),
+//! `SYS_CLOCK_GETTIME` computes them the same way, so both agree.
+//!
+//! The kernel updates the page on every timer tick, as a seqlock: `sequence` is odd while it
+//! is being written, and a read is only good if it saw the same even `sequence` before and
+//! after it, see [`TimePage::try_read`].
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// The bits of fraction of [`TimePage::tsc_mult`]
+pub const TSC_MULT_SHIFT: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ClockId {
+    Monotonic = 0,
+    Realtime = 1,
+}
+
+impl ClockId {
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Monotonic),
+            1 => Some(Self::Realtime),
+            _ => None,
+        }
+    }
+}
+
+/// The page shared by the kernel with every process, only the kernel writes it
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct TimePage {
+    pub sequence: AtomicU64,
+    /// The TSC when the monotonic time was `monotonic_base`
+    pub tsc_base: AtomicU64,
+    pub monotonic_base: AtomicU64,
+    /// Nanoseconds per TSC tick, with [`TSC_MULT_SHIFT`] bits of fraction
+    pub tsc_mult: AtomicU64,
+    /// Added (wrapping) to the monotonic time to get the realtime
+    pub realtime_offset: AtomicU64,
+}
+
+abi_layout!(TimePage, size = 40, {
+    sequence @ 0,
+    tsc_base @ 8,
+    monotonic_base @ 16,
+    tsc_mult @ 24,
+    realtime_offset @ 32,
+});
+
+/// The contents of a [`TimePage`] from one update
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSnapshot {
+    pub tsc_base: u64,
+    pub monotonic_base: u64,
+    pub tsc_mult: u64,
+    pub realtime_offset: u64,
+}
+
+impl TimeSnapshot {
+    pub fn monotonic_at(&self, tsc: u64) -> u64 {
+        let elapsed = tsc.wrapping_sub(self.tsc_base) as u128 * self.tsc_mult as u128;
+        self.monotonic_base + (elapsed >> TSC_MULT_SHIFT) as u64
+    }
+
+    pub fn realtime_at(&self, tsc: u64) -> u64 {
+        self.monotonic_at(tsc).wrapping_add(self.realtime_offset)
+    }
+
+    pub fn clock_at(&self, clock: ClockId, tsc: u64) -> u64 {
+        match clock {
+            ClockId::Monotonic => self.monotonic_at(tsc),
+            ClockId::Realtime => self.realtime_at(tsc),
+        }
+    }
+}
+
+impl TimePage {
+    /// The contents of the page, `None` if the kernel was writing it during the read
+    pub fn try_read(&self) -> Option<TimeSnapshot> {
+        let sequence = self.sequence.load(Ordering::Acquire);
+        if sequence & 1 != 0 {
+            return None;
+        }
+        let snapshot = TimeSnapshot {
+            tsc_base: self.tsc_base.load(Ordering::Relaxed),
+            monotonic_base: self.monotonic_base.load(Ordering::Relaxed),
+            tsc_mult: self.tsc_mult.load(Ordering::Relaxed),
+            realtime_offset: self.realtime_offset.load(Ordering::Relaxed),
+        };
+        fence(Ordering::Acquire);
+        (self.sequence.load(Ordering::Relaxed) == sequence).then_some(snapshot)
+    }
+
+    /// The contents of the page, retried until it wasn't written during the read
+    pub fn read(&self) -> TimeSnapshot {
+        loop {
+            if let Some(snapshot) = self.try_read() {
+                return snapshot;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Replaces the contents, only the kernel does this, never two at once
+    pub fn write(&self, snapshot: &TimeSnapshot) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.tsc_base.store(snapshot.tsc_base, Ordering::Relaxed);
+        self.monotonic_base
+            .store(snapshot.monotonic_base, Ordering::Relaxed);
+        self.tsc_mult.store(snapshot.tsc_mult, Ordering::Relaxed);
+        self.realtime_offset
+            .store(snapshot.realtime_offset, Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
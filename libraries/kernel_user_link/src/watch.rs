@@ -0,0 +1,96 @@
+//! Watching directories for changes, with [`SYS_WATCH_CREATE`] and [`SYS_WATCH_ADD`].
+//!
+//! A watch is a file, each directory added to it gets a watch descriptor (`wd`), and every
+//! change to the entries of that directory (not of its subdirectories) is queued in the file
+//! as a fixed size [`WatchEvent`]. Reading it gives as many whole events as fit in the buffer,
+//! blocking while there are none, in non-blocking mode the read gives `0` bytes instead.
+//!
+//! A rename inside the watched directories gives a [`WATCH_MOVED_FROM`] and a
+//! [`WATCH_MOVED_TO`] with the same non-zero `cookie`, one after the other.
+//!
+//! When the queue is full, the events are dropped until it is read, and a [`WATCH_OVERFLOW`]
+//! event with the number dropped in `cookie` is read after the ones that were kept.
+//!
+//! [`SYS_WATCH_CREATE`]: crate::syscalls::SYS_WATCH_CREATE
+//! [`SYS_WATCH_ADD`]: crate::syscalls::SYS_WATCH_ADD
+
+/// An entry was created in the directory
+pub const WATCH_CREATE: u32 = 1 << 0;
+/// An entry was removed from the directory
+pub const WATCH_DELETE: u32 = 1 << 1;
+/// An entry was renamed, this was its old name
+pub const WATCH_MOVED_FROM: u32 = 1 << 2;
+/// An entry was renamed, this is its new name
+pub const WATCH_MOVED_TO: u32 = 1 << 3;
+/// A file in the directory was written past its end or truncated
+pub const WATCH_MODIFY: u32 = 1 << 4;
+/// Everything that can be asked for in [`SYS_WATCH_ADD`](crate::syscalls::SYS_WATCH_ADD)
+pub const WATCH_ALL: u32 =
+    WATCH_CREATE | WATCH_DELETE | WATCH_MOVED_FROM | WATCH_MOVED_TO | WATCH_MODIFY;
+/// Set in the events of entries that are directories
+pub const WATCH_IS_DIR: u32 = 1 << 30;
+/// Events were dropped, this is not asked for, and has no `wd` or name
+pub const WATCH_OVERFLOW: u32 = 1 << 31;
+
+/// The longest name in a [`WatchEvent`], longer ones are cut at a character boundary
+pub const WATCH_NAME_MAX: usize = 240;
+
+/// An event read from a watch file, see the [module docs](self)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct WatchEvent {
+    pub mask: u32,
+    /// Returned by [`SYS_WATCH_ADD`](crate::syscalls::SYS_WATCH_ADD) for the directory
+    pub wd: u32,
+    /// Pairs the two events of a rename, `0` for the others
+    pub cookie: u32,
+    pub name_len: u32,
+    /// UTF-8, the bytes after `name_len` are `0`
+    pub name: [u8; WATCH_NAME_MAX],
+}
+
+abi_layout!(WatchEvent, size = 256, {
+    mask @ 0,
+    wd @ 4,
+    cookie @ 8,
+    name_len @ 12,
+    name @ 16,
+});
+
+impl WatchEvent {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    pub fn new(mask: u32, wd: u32, cookie: u32, name: &str) -> Self {
+        let mut len = name.len().min(WATCH_NAME_MAX);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut event = Self {
+            mask,
+            wd,
+            cookie,
+            name_len: len as u32,
+            name: [0; WATCH_NAME_MAX],
+        };
+        event.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        event
+    }
+
+    pub fn name(&self) -> &str {
+        let len = (self.name_len as usize).min(WATCH_NAME_MAX);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::SIZE] {
+        // SAFETY: the struct has no padding, see the layout above
+        unsafe { &*(self as *const Self as *const [u8; Self::SIZE]) }
+    }
+
+    /// Reads the event at the start of `buf`, `None` if it's shorter than an event
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let bytes = buf.get(..Self::SIZE)?;
+        // SAFETY: we checked the size, any bytes are a valid event, and we don't require
+        // `buf` to be aligned
+        Some(unsafe { (bytes.as_ptr() as *const Self).read_unaligned() })
+    }
+}
@@ -1,25 +1,19 @@
 use core::alloc::{GlobalAlloc, Layout};
 
 use increasing_heap_allocator::{HeapAllocator, HeapStats, PageAllocatorProvider};
-use kernel_user_link::{
-    call_syscall,
-    syscalls::{SyscallError, SYS_INC_HEAP},
-};
+use kernel_user_link::syscalls::SyscallError;
 
-use crate::sync::{once::OnceLock, spin::mutex::Mutex};
+use crate::{
+    sync::{once::OnceLock, Mutex},
+    syscalls,
+};
 
 pub extern crate alloc;
 
 const PAGE_4K: usize = 0x1000;
 
 unsafe fn inc_dec_heap(increment: isize) -> Result<*mut u8, SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_INC_HEAP,
-            increment as u64, // increment
-        )
-        .map(|addr| addr as *mut u8)
-    }
+    unsafe { syscalls::inc_heap(increment as i64).map(|addr| addr as *mut u8) }
 }
 
 pub static ALLOCATOR: LockedKernelHeapAllocator = LockedKernelHeapAllocator::empty();
@@ -0,0 +1,95 @@
+//! The information the kernel passes to the process on startup in the auxiliary vector,
+//! see [`kernel_user_link::startup`] for the layout.
+
+use core::{
+    ffi::c_char,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+pub use kernel_user_link::startup::AuxEntry;
+use kernel_user_link::{
+    startup::{AT_NULL, AT_PAGESZ, AT_RANDOM, AT_RANDOM_SIZE, AT_TIME_PAGE},
+    time::TimePage,
+};
+
+use crate::process::check_abi_version;
+
+// until `init` is called, we use the page size of x86
+const DEFAULT_PAGE_SIZE: usize = 0x1000;
+
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_PAGE_SIZE);
+static RANDOM_SEED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static TIME_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Checks the ABI version with [`check_abi_version`], then reads the auxiliary vector. It must
+/// be called by the runtime start of `std` before `main` (before anything that needs
+/// [`page_size`] or [`random_seed`]).
+///
+/// # Safety
+/// `auxv` must be the auxiliary vector the entry point received
+pub unsafe fn init(auxv: *const AuxEntry) {
+    // the entries below are only known in our version
+    check_abi_version();
+    let mut entry = auxv;
+    loop {
+        let AuxEntry { key, value } = unsafe { entry.read() };
+        match key {
+            AT_NULL => break,
+            AT_PAGESZ => PAGE_SIZE.store(value as usize, Ordering::Relaxed),
+            AT_RANDOM => {
+                // SAFETY: the kernel puts `AT_RANDOM_SIZE` bytes there, and they are never freed
+                let seed = unsafe { (value as *const [u8; AT_RANDOM_SIZE]).read() };
+                let (low, high) = seed.split_at(8);
+                RANDOM_SEED[0].store(
+                    u64::from_le_bytes(low.try_into().unwrap()),
+                    Ordering::Relaxed,
+                );
+                RANDOM_SEED[1].store(
+                    u64::from_le_bytes(high.try_into().unwrap()),
+                    Ordering::Relaxed,
+                );
+            }
+            AT_TIME_PAGE => TIME_PAGE.store(value as usize, Ordering::Relaxed),
+            // unknown keys are skipped, so new ones can be added without breaking anyone
+            _ => {}
+        }
+        entry = unsafe { entry.add(1) };
+    }
+}
+
+/// Finds the auxiliary vector from `argv`, for runtimes that only kept `argc` and `argv`.
+/// It is after the null pointers that end `argv` and the environment.
+///
+/// # Safety
+/// `argv` must be the argument array the entry point received, and `argc` its length
+pub unsafe fn auxv_from_argv(argc: usize, argv: *const *const c_char) -> *const AuxEntry {
+    unsafe {
+        let mut envp = argv.add(argc + 1);
+        while !envp.read().is_null() {
+            envp = envp.add(1);
+        }
+        envp.add(1) as *const AuxEntry
+    }
+}
+
+/// The size of a memory page in bytes
+pub fn page_size() -> usize {
+    PAGE_SIZE.load(Ordering::Relaxed)
+}
+
+/// The page the clocks are read from without a syscall, if the kernel offers it, see
+/// [`crate::time`]
+pub fn time_page() -> Option<&'static TimePage> {
+    let address = TIME_PAGE.load(Ordering::Relaxed) as *const TimePage;
+    // SAFETY: the kernel maps it for the whole life of the process
+    unsafe { address.as_ref() }
+}
+
+/// The random bytes from the kernel, meant to seed hash tables (the keys of `HashMap`'s
+/// `RandomState`), don't use them for anything that must stay secret
+pub fn random_seed() -> (u64, u64) {
+    (
+        RANDOM_SEED[0].load(Ordering::Relaxed),
+        RANDOM_SEED[1].load(Ordering::Relaxed),
+    )
+}
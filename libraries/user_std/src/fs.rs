@@ -0,0 +1,67 @@
+//! Creating, removing and renaming files and directories by path, and mounting filesystems.
+//!
+//! The paths must be absolute, relative paths are resolved by the caller.
+
+use core::ffi::CStr;
+
+use kernel_user_link::syscalls::SyscallError;
+
+pub use kernel_user_link::file::MOUNT_READ_ONLY;
+
+use crate::syscalls;
+
+/// Removes the file at `path`, directories must use [`remove_dir`].
+///
+/// If the file is still open, depending on the filesystem this either fails with
+/// [`SyscallError::Busy`], or the name is removed and the content is freed when the file
+/// is closed
+pub fn remove_file(path: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::unlink(path) }
+}
+
+/// Creates an empty directory at `path`, its parent must exist
+pub fn create_dir(path: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::mkdir(path) }
+}
+
+/// Removes the directory at `path`, it must be empty
+pub fn remove_dir(path: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::rmdir(path) }
+}
+
+/// Moves `old_path` to `new_path`, which must not exist.
+///
+/// Fails with [`SyscallError::CrossDevice`] if they are in different filesystems, then the
+/// file must be copied and removed instead
+pub fn rename(old_path: &CStr, new_path: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::rename(old_path, new_path) }
+}
+
+/// Sets the size of the file at `path` to `len`, same as
+/// [`syscall_ftruncate`](crate::io::syscall_ftruncate) on an open file
+pub fn truncate(path: &CStr, len: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::truncate(path, len) }
+}
+
+/// Makes everything written so far stable, in all the filesystems and block devices
+pub fn sync() -> Result<(), SyscallError> {
+    unsafe { syscalls::sync() }
+}
+
+/// Mounts the `fstype` filesystem (i.e. `fat`) of the block device at `source` on `target`,
+/// `flags` can be [`MOUNT_READ_ONLY`].
+///
+/// For the filesystems in memory (i.e. `ramfs`), `source` is only the name shown in
+/// `/devices/mounts`. Only privileged processes can mount, others get
+/// [`SyscallError::PermissionDenied`]
+pub fn mount(source: &CStr, target: &CStr, fstype: &CStr, flags: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::mount(source, target, fstype, flags) }
+}
+
+/// Unmounts the filesystem mounted at `target`, the files still open in it keep working, and
+/// its device can't be mounted again until they are closed.
+///
+/// Fails with [`SyscallError::Busy`] if other filesystems are mounted inside it
+pub fn unmount(target: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::umount(target) }
+}
@@ -0,0 +1,290 @@
+//! Scripts of keyboard and mouse input, replayed by the kernel through `/devices/input_inject`.
+//!
+//! A script has the lines of [`InputRecord::parse`], with blank lines and `#` comments, and
+//! two more that are expanded here:
+//! - `type <text>`: presses and releases the keys of the text on a US keyboard, with `\n`
+//!   for Enter, `\b` for Backspace, `\t` for Tab and `\\` for `\`
+//! - `pace <delay>`: a delay after every key of the `type` lines that follow, `pace 0ms`
+//!   stops it
+//!
+//! The kernel only takes the lines it knows, so the script is checked whole before any of it
+//! is written, and a line that is not valid is reported by its number.
+
+use core::fmt::{self, Write};
+
+use kernel_user_link::input::InputRecord;
+
+use crate::io::{syscall_close, syscall_open, syscall_read, syscall_write};
+use crate::SyscallError;
+
+const INJECT_PATH: &core::ffi::CStr = c"/devices/input_inject";
+const LEFT_SHIFT: u16 = 0x2A;
+// the most a write to the device takes, in bytes, the records are cut at lines
+const CHUNK_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScriptError {
+    /// The line with this number (from 1) is not a record
+    InvalidLine(usize),
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for ScriptError {
+    fn from(e: SyscallError) -> Self {
+        Self::Syscall(e)
+    }
+}
+
+/// The scancode of a char on a US keyboard, and whether it needs shift
+fn us_key(c: char) -> Option<(u16, bool)> {
+    const ROWS: [(&str, &str, u16); 4] = [
+        ("1234567890-=", "!@#$%^&*()_+", 0x02),
+        ("qwertyuiop[]", "QWERTYUIOP{}", 0x10),
+        ("asdfghjkl;'`", "ASDFGHJKL:\"~", 0x1E),
+        ("zxcvbnm,./", "ZXCVBNM<>?", 0x2C),
+    ];
+    let key = match c {
+        ' ' => (0x39, false),
+        '\n' => (0x1C, false),
+        '\t' => (0x0F, false),
+        '\x08' => (0x0E, false),
+        '\\' => (0x2B, false),
+        '|' => (0x2B, true),
+        _ => ROWS.iter().find_map(|(normal, shifted, first)| {
+            let position = |row: &str| row.chars().position(|key| key == c);
+            position(normal)
+                .map(|i| (first + i as u16, false))
+                .or_else(|| position(shifted).map(|i| (first + i as u16, true)))
+        })?,
+    };
+    Some(key)
+}
+
+/// The records of a script in order, with the `type` lines expanded
+pub struct ScriptRecords<'a> {
+    lines: core::iter::Enumerate<core::str::Lines<'a>>,
+    line_number: usize,
+    // the rest of a `type` line
+    typing: core::str::Chars<'a>,
+    pace: Option<InputRecord>,
+    // the records of the key being typed
+    queued: [Option<InputRecord>; 5],
+    next_queued: usize,
+}
+
+impl<'a> ScriptRecords<'a> {
+    pub fn new(script: &'a str) -> Self {
+        Self {
+            lines: script.lines().enumerate(),
+            line_number: 0,
+            typing: "".chars(),
+            pace: None,
+            queued: [None; 5],
+            next_queued: 0,
+        }
+    }
+
+    /// Queues the records of the next char of the `type` line, `false` at its end
+    fn type_next(&mut self) -> Result<bool, ScriptError> {
+        let Some(mut c) = self.typing.next() else {
+            return Ok(false);
+        };
+        if c == '\\' {
+            c = match self.typing.next() {
+                Some('n') => '\n',
+                Some('b') => '\x08',
+                Some('t') => '\t',
+                Some('\\') => '\\',
+                _ => return Err(ScriptError::InvalidLine(self.line_number)),
+            };
+        }
+        let (scancode, shift) = us_key(c).ok_or(ScriptError::InvalidLine(self.line_number))?;
+        let key = |scancode, pressed| Some(InputRecord::Key { scancode, pressed });
+        self.queued = if shift {
+            [
+                key(LEFT_SHIFT, true),
+                key(scancode, true),
+                key(scancode, false),
+                key(LEFT_SHIFT, false),
+                self.pace,
+            ]
+        } else {
+            [
+                key(scancode, true),
+                key(scancode, false),
+                self.pace,
+                None,
+                None,
+            ]
+        };
+        self.next_queued = 0;
+        Ok(true)
+    }
+
+    fn parse_line(&mut self, line: &'a str) -> Result<Option<InputRecord>, ScriptError> {
+        let invalid = ScriptError::InvalidLine(self.line_number);
+        if let Some(text) = line.strip_prefix("type ") {
+            self.typing = text.chars();
+            return Ok(None);
+        }
+        if let Some(delay) = line.strip_prefix("pace ") {
+            self.pace = match InputRecord::parse(&format_delay(delay).ok_or(invalid)?) {
+                Some(InputRecord::Delay { micros: 0 }) => None,
+                Some(record @ InputRecord::Delay { .. }) => Some(record),
+                _ => return Err(invalid),
+            };
+            return Ok(None);
+        }
+        InputRecord::parse(line).map(Some).ok_or(invalid)
+    }
+}
+
+/// `delay <text>` in a stack buffer, so `pace` is checked like `delay`
+fn format_delay(delay: &str) -> Option<LineBuf> {
+    let mut line = LineBuf::new();
+    write!(line, "delay {}", delay.trim()).ok()?;
+    Some(line)
+}
+
+impl Iterator for ScriptRecords<'_> {
+    type Item = Result<InputRecord, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while let Some(slot) = self.queued.get_mut(self.next_queued) {
+                self.next_queued += 1;
+                if let Some(record) = slot.take() {
+                    return Some(Ok(record));
+                }
+            }
+            match self.type_next() {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            let (index, line) = self.lines.next()?;
+            self.line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match self.parse_line(line) {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A line of text on the stack
+struct LineBuf {
+    bytes: [u8; 64],
+    len: usize,
+}
+
+impl LineBuf {
+    fn new() -> Self {
+        Self {
+            bytes: [0; 64],
+            len: 0,
+        }
+    }
+}
+
+impl core::ops::Deref for LineBuf {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // only `str`s are written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// The device, closed when dropped
+struct Inject(usize);
+
+impl Inject {
+    fn open() -> Result<Self, SyscallError> {
+        unsafe { syscall_open(INJECT_PATH, 0, 0).map(Self) }
+    }
+
+    /// Writes `records`, waiting for the kernel to replay the ones before if it has too many
+    fn write(&self, records: &[u8]) -> Result<(), SyscallError> {
+        loop {
+            match unsafe { syscall_write(self.0, records) } {
+                Err(SyscallError::NoSpace) => wait_replayed()?,
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+}
+
+impl Drop for Inject {
+    fn drop(&mut self) {
+        unsafe { syscall_close(self.0).ok() };
+    }
+}
+
+/// Checks `script`, then gives it to the kernel, returns the number of records. The records
+/// are replayed after this returns if they have delays, see [`wait_replayed`]
+pub fn run_script(script: &str) -> Result<usize, ScriptError> {
+    let mut count = 0;
+    for record in ScriptRecords::new(script) {
+        record?;
+        count += 1;
+    }
+
+    let inject = Inject::open()?;
+    let mut chunk = [0; CHUNK_LEN];
+    let mut len = 0;
+    for record in ScriptRecords::new(script) {
+        let mut line = LineBuf::new();
+        // the longest record fits in the line
+        writeln!(line, "{}", record?).unwrap();
+        if len + line.len() > chunk.len() {
+            inject.write(&chunk[..len])?;
+            len = 0;
+        }
+        chunk[len..len + line.len()].copy_from_slice(line.as_bytes());
+        len += line.len();
+    }
+    if len != 0 {
+        inject.write(&chunk[..len])?;
+    }
+    Ok(count)
+}
+
+/// The number of records the kernel has not replayed yet
+pub fn pending_records() -> Result<u64, SyscallError> {
+    let inject = Inject::open()?;
+    let mut buf = [0; 128];
+    let len = unsafe { syscall_read(inject.0, &mut buf)? } as usize;
+    core::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|info| info.lines().next())
+        .and_then(|line| line.strip_prefix("pending: "))
+        .and_then(|pending| pending.parse().ok())
+        .ok_or(SyscallError::CouldNotReadFromFile)
+}
+
+/// Waits for the kernel to replay all the records, there is no sleeping, so it reads the
+/// device until it's done
+pub fn wait_replayed() -> Result<(), SyscallError> {
+    while pending_records()? != 0 {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
@@ -1,44 +1,35 @@
 use core::ffi::CStr;
 
-use kernel_user_link::call_syscall;
-pub use kernel_user_link::file::BlockingMode;
+pub use kernel_user_link::file::{
+    BlockingMode, FlockOperation, EVENT_SOURCE_LOW_MEMORY, EVENT_SOURCE_NONE, OPEN_CREATE,
+    OPEN_DIRECT,
+};
+pub use kernel_user_link::file::{
+    DirEntryHeader, DirEntryIter, DirEntryKind, FileStat, DIR_ENTRY_ALIGN, READ_DIR_RECURSIVE,
+};
 use kernel_user_link::syscalls::SyscallError;
-use kernel_user_link::syscalls::SYS_BLOCKING_MODE;
-use kernel_user_link::syscalls::SYS_CLOSE;
-use kernel_user_link::syscalls::SYS_CREATE_PIPE;
-use kernel_user_link::syscalls::SYS_OPEN;
-use kernel_user_link::syscalls::SYS_READ;
-use kernel_user_link::syscalls::SYS_WRITE;
+pub use kernel_user_link::watch::{
+    WatchEvent, WATCH_ALL, WATCH_CREATE, WATCH_DELETE, WATCH_IS_DIR, WATCH_MODIFY,
+    WATCH_MOVED_FROM, WATCH_MOVED_TO, WATCH_NAME_MAX, WATCH_OVERFLOW,
+};
 pub use kernel_user_link::FD_STDERR;
 pub use kernel_user_link::FD_STDIN;
 pub use kernel_user_link::FD_STDOUT;
 
+use crate::syscalls;
+
 /// # Safety
 /// This function assumes that `fd` is a valid file descriptor.
 /// And that `buf` is a valid buffer.
 pub unsafe fn syscall_read(fd: usize, buf: &mut [u8]) -> Result<u64, SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_READ,
-            fd,                      // fd
-            buf.as_mut_ptr() as u64, // buf
-            buf.len() as u64         // size
-        )
-    }
+    unsafe { syscalls::read(fd, buf) }
 }
 
 /// # Safety
 /// This function assumes that `fd` is a valid file descriptor.
 /// And that `buf` is a valid buffer.
 pub unsafe fn syscall_write(fd: usize, buf: &[u8]) -> Result<u64, SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_WRITE,
-            fd,                  // fd
-            buf.as_ptr() as u64, // buf
-            buf.len() as u64     // size
-        )
-    }
+    unsafe { syscalls::write(fd, buf) }
 }
 
 /// # Safety
@@ -49,27 +40,63 @@ pub unsafe fn syscall_open(
     access_mode: usize,
     flags: usize,
 ) -> Result<usize, SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_OPEN,
-            path.as_ptr() as u64, // path
-            access_mode as u64,   // access_mode
-            flags as u64          // flags
-        )
-        .map(|fd| fd as usize)
-    }
+    unsafe { syscalls::open(path, access_mode as u64, flags as u64) }
 }
 
 /// # Safety
 /// This function assumes that `fd` is a valid file descriptor.
 pub unsafe fn syscall_close(fd: usize) -> Result<(), SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_CLOSE,
-            fd,                  // fd
-        )
-        .map(|e| assert!(e == 0))
-    }
+    unsafe { syscalls::close(fd) }
+}
+
+/// Makes everything written to `fd` stable on its device, this syncs the whole filesystem
+/// the file is in (or flushes the whole block device)
+///
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+pub unsafe fn syscall_fsync(fd: usize) -> Result<(), SyscallError> {
+    unsafe { syscalls::fsync(fd) }
+}
+
+/// Sets the size of the file `fd` to `len`, reading after the old end gives zeros. The
+/// positions of the files open on it don't move, reading past the new end gives nothing
+///
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+pub unsafe fn syscall_ftruncate(fd: usize, len: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::ftruncate(fd, len) }
+}
+
+/// Takes or releases the advisory lock of the file `fd`, shared with all the files open on it.
+/// Waits for the lock unless the operation doesn't block, which fails with
+/// [`SyscallError::WouldBlock`]
+///
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+pub unsafe fn syscall_flock(fd: usize, operation: FlockOperation) -> Result<(), SyscallError> {
+    let operation = operation.to_u64();
+    unsafe { syscalls::flock(fd, operation) }
+}
+
+/// Copies up to `len` bytes from `fd_in` to `fd_out` inside the kernel, returns the number of
+/// bytes copied, which is less than `len` at the end of `fd_in`.
+///
+/// The offsets are used and advanced if given, otherwise the positions of the files are.
+/// Fails with [`SyscallError::NotSupported`] for files that can't be copied like this
+/// (i.e. devices), they must be read and written instead.
+///
+/// # Safety
+/// This function assumes that `fd_in` and `fd_out` are valid file descriptors.
+pub unsafe fn syscall_copy_file_range(
+    fd_in: usize,
+    off_in: Option<&mut u64>,
+    fd_out: usize,
+    off_out: Option<&mut u64>,
+    len: u64,
+) -> Result<u64, SyscallError> {
+    let off_in = off_in.map_or(0, |offset| offset as *mut u64 as u64);
+    let off_out = off_out.map_or(0, |offset| offset as *mut u64 as u64);
+    unsafe { syscalls::copy_file_range(fd_in, off_in, fd_out, off_out, len) }
 }
 
 /// # Safety
@@ -78,17 +105,38 @@ pub unsafe fn syscall_close(fd: usize) -> Result<(), SyscallError> {
 pub unsafe fn syscall_create_pipe() -> Result<(usize, usize), SyscallError> {
     let mut in_fd: u64 = 0;
     let mut out_fd: u64 = 0;
-    unsafe {
-        call_syscall!(
-            SYS_CREATE_PIPE,
-            &mut in_fd as *mut u64 as u64,  // in_fd
-            &mut out_fd as *mut u64 as u64  // out_fd
-        )?
-    };
+    unsafe { syscalls::create_pipe(&mut in_fd, &mut out_fd)? };
 
     Ok((in_fd as usize, out_fd as usize))
 }
 
+/// Creates an event signaled by `source` (one of the `EVENT_SOURCE_*`), and returns its
+/// descriptor, reads block until the event is signaled.
+///
+/// # Safety
+/// Callers must ensure to use the descriptor correctly.
+pub unsafe fn syscall_event_create(source: u64) -> Result<usize, SyscallError> {
+    unsafe { syscalls::event_create(source) }
+}
+
+/// Creates a watch without directories, returns its file descriptor, reads give
+/// [`WatchEvent`]s, blocking until there is one, see [`syscall_watch_add`].
+///
+/// # Safety
+/// Callers must ensure to use the descriptor correctly.
+pub unsafe fn syscall_watch_create() -> Result<usize, SyscallError> {
+    unsafe { syscalls::watch_create() }
+}
+
+/// Watches the directory at `path` for the `WATCH_*` changes in `mask`, returns the `wd` of
+/// its events. Adding the same directory again gives the same `wd`, with the new `mask`
+///
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+pub unsafe fn syscall_watch_add(fd: usize, path: &CStr, mask: u32) -> Result<u32, SyscallError> {
+    unsafe { syscalls::watch_add(fd, path, mask as u64) }.map(|wd| wd as u32)
+}
+
 /// # Safety
 /// This function assumes that `fd` is a valid file descriptor.
 pub unsafe fn syscall_blocking_mode(
@@ -96,12 +144,32 @@ pub unsafe fn syscall_blocking_mode(
     blocking_mode: BlockingMode,
 ) -> Result<(), SyscallError> {
     let mode = blocking_mode.to_u64();
-    unsafe {
-        call_syscall!(
-            SYS_BLOCKING_MODE,
-            fd,   // fd
-            mode  // mode
-        )
-        .map(|e| assert!(e == 0))
-    }
+    unsafe { syscalls::blocking_mode(fd, mode) }
+}
+
+/// Reads the entries of the directory `fd` into `buf`, returns the number of bytes written,
+/// which can be parsed with [`DirEntryIter`]. `0` means there are no more entries.
+///
+/// `position` is the number of entries read so far, use `0` to start from the beginning.
+/// With [`READ_DIR_RECURSIVE`] in `flags`, the whole tree is traversed.
+///
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+/// And that `buf` is a valid buffer.
+pub unsafe fn syscall_read_dir(
+    fd: usize,
+    buf: &mut [u8],
+    flags: u64,
+    position: u64,
+) -> Result<u64, SyscallError> {
+    unsafe { syscalls::read_dir(fd, buf, flags, position) }
+}
+
+/// # Safety
+/// This function assumes that `fd` is a valid file descriptor.
+pub unsafe fn syscall_stat(fd: usize) -> Result<FileStat, SyscallError> {
+    let mut stat = FileStat::default();
+    unsafe { syscalls::stat(fd, &mut stat)? };
+
+    Ok(stat)
 }
@@ -1,9 +1,23 @@
 #![no_std]
 
 pub mod alloc;
+pub mod env;
+pub mod fs;
+pub mod input;
 pub mod io;
 pub mod process;
-mod sync;
+pub mod signal;
+pub mod sync;
+pub mod thread;
+pub mod time;
+
+/// The raw syscalls, generated from [`kernel_user_link::syscall_table!`], the modules above
+/// wrap them in safer APIs
+pub mod syscalls {
+    use kernel_user_link::syscall_wrappers;
+
+    kernel_user_link::syscall_table!(syscall_wrappers);
+}
 
 pub use kernel_user_link::syscalls::SyscallArgError;
 pub use kernel_user_link::syscalls::SyscallError;
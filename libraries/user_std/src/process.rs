@@ -1,20 +1,24 @@
-use core::ffi::{c_char, CStr};
+use core::{
+    ffi::{c_char, CStr},
+    fmt::{self, Write},
+};
 
-pub use kernel_user_link::process::SpawnFileMapping;
-use kernel_user_link::{
-    call_syscall,
-    syscalls::{SyscallError, SYS_EXIT, SYS_SPAWN, SYS_WAIT_PID},
+pub use kernel_user_link::process::{
+    Resource, SpawnFileMapping, EXIT_CODE_ABI_MISMATCH, PRIORITY_BATCH, PRIORITY_DEFAULT,
+    PRIORITY_HIGHEST, PRIORITY_INTERACTIVE, PRIORITY_LOWEST, PROCESS_NAME_LEN, PROT_EXEC,
+    PROT_READ, PROT_WRITE, RLIMIT_INFINITY, SHM_CREATE, SHM_EXCLUSIVE, SHM_MAX_SIZE, SHM_NAME_LEN,
 };
+pub use kernel_user_link::sysinfo::SysInfo;
+pub use kernel_user_link::ABI_VERSION;
+use kernel_user_link::{syscalls::SyscallError, FD_STDERR};
+
+use crate::{io::syscall_write, syscalls};
 
 /// # Safety
 /// No guarantees are made about the state of the system after this function returns.
 pub unsafe fn exit(code: i32) -> ! {
     unsafe {
-        call_syscall!(
-            SYS_EXIT,
-            code as u64, // code
-        )
-        .unwrap();
+        syscalls::exit(code).unwrap();
     }
     unreachable!("exit syscall should not return")
 }
@@ -31,26 +35,205 @@ pub unsafe fn spawn(
     file_mappings: &[SpawnFileMapping],
 ) -> Result<u64, SyscallError> {
     unsafe {
-        call_syscall!(
-            SYS_SPAWN,
-            path.as_ptr() as u64,          // path
-            argv.as_ptr() as u64,          // argv
-            file_mappings.as_ptr() as u64, // file_mappings
-            file_mappings.len() as u64     // file_mappings_len
+        syscalls::spawn(
+            path,
+            argv.as_ptr() as u64,
+            file_mappings.as_ptr() as u64,
+            file_mappings.len(),
         )
     }
 }
 
+/// Copies the current process, returns the pid of the child in the parent, and `0` in the
+/// child, which continues from here with the same memory, files and environment.
+///
+/// # Safety
+/// Only the calling thread is copied, anything the others were doing, i.e. holding a lock,
+/// is left as it was in the child.
+pub unsafe fn fork() -> Result<u64, SyscallError> {
+    unsafe { syscalls::fork() }
+}
+
+/// Replaces the program of this process with the one at `path`, with the same pid, files and
+/// environment, it only returns if it fails, with the error.
+///
+/// # Safety
+/// path must be a valid C string.
+/// argv must be a valid C string array. ending with a null pointer.
+/// Nothing of this program runs after it succeeds, i.e. the buffers of the std files are lost
+/// if they are not flushed before.
+pub unsafe fn exec(path: &CStr, argv: &[*const c_char]) -> SyscallError {
+    match unsafe { syscalls::exec(path, argv.as_ptr() as u64) } {
+        Ok(()) => unreachable!("exec syscall should not return when it succeeds"),
+        Err(e) => e,
+    }
+}
+
+/// Changes the access of the pages of `addr..addr + len` to the `PROT_*` in `prot`, `addr`
+/// must be page aligned, and the range all mapped memory of this process.
+///
+/// # Safety
+/// Nothing must write to the pages after they are made read-only, i.e. the allocator, or the
+/// process crashes
+pub unsafe fn mprotect(addr: *const u8, len: usize, prot: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::mprotect(addr as u64, len as u64, prot) }
+}
+
+/// Opens the shared memory object `name`, or creates it with `size` bytes if `flags` has
+/// [`SHM_CREATE`], with an empty `name` it's a new one only reached through the returned fd.
+/// Its size is the size of the file.
+///
+/// # Safety
+/// The fd must be closed only once, like any other file.
+pub unsafe fn shm_create(name: &CStr, size: u64, flags: u64) -> Result<usize, SyscallError> {
+    unsafe { syscalls::shm_create(name, size, flags) }
+}
+
+/// Maps all of the shared memory object `fd` with the `PROT_*` in `prot`, returns where. The
+/// mapping stays after `fd` is closed, until [`shm_unmap`], and the children from `fork` have
+/// it too, the same pages, not copies.
+///
+/// # Safety
+/// The other processes can write to the pages at any time.
+pub unsafe fn shm_map(fd: usize, prot: u64) -> Result<*mut u8, SyscallError> {
+    unsafe { syscalls::shm_map(fd, prot).map(|addr| addr as *mut u8) }
+}
+
+/// Unmaps the shared memory mapped at `addr` by [`shm_map`].
+///
+/// # Safety
+/// Nothing must use the pages after this, or the process crashes.
+pub unsafe fn shm_unmap(addr: *mut u8) -> Result<(), SyscallError> {
+    unsafe { syscalls::shm_unmap(addr as u64) }
+}
+
+/// Maps `len` bytes of the file `fd` from `offset` (page aligned), with the `PROT_*` in
+/// `prot`, returns where. The pages are read from the file when they are first used, the
+/// mapping stays after `fd` is closed, until [`munmap`], and the children from `fork` have it
+/// too. With `PROT_WRITE`, the writes only change the pages of this mapping, not the file.
+///
+/// # Safety
+/// The pages are not updated when the file is written to after they are read.
+pub unsafe fn mmap(fd: usize, offset: u64, len: u64, prot: u64) -> Result<*const u8, SyscallError> {
+    unsafe { syscalls::mmap(fd, offset, len, prot).map(|addr| addr as *const u8) }
+}
+
+/// Unmaps the file mapped at `addr` by [`mmap`].
+///
+/// # Safety
+/// Nothing must use the pages after this, or the process crashes.
+pub unsafe fn munmap(addr: *const u8) -> Result<(), SyscallError> {
+    unsafe { syscalls::munmap(addr as u64) }
+}
+
+/// Returns the exit code of the child `pid`, waits for it to exit if `block`, otherwise fails
+/// with [`SyscallError::ProcessStillRunning`] if it's running.
+///
+/// The exit of a child is kept until it's returned here, once, after that or for a process
+/// that is not a child it fails with [`SyscallError::PidNotFound`]. A signal handled while
+/// blocking makes it fail with [`SyscallError::Interrupted`].
+///
 /// # Safety
 /// This is generally safe, it will return error if the pid is not valid, but it might wait for a long
 /// time depending on the process we are waiting for.
 pub unsafe fn wait_for_pid(pid: u64, block: bool) -> Result<i32, SyscallError> {
-    unsafe {
-        call_syscall!(
-            SYS_WAIT_PID,
-            pid,          // pid
-            block as u64  // block
-        )
-        .map(|x| x as i32)
+    unsafe { syscalls::wait_pid(pid, block as u64).map(|x| x as i32) }
+}
+
+/// Waits for the child `pid` to exit and returns its exit code, see [`wait_for_pid`].
+///
+/// # Safety
+/// The same as [`wait_for_pid`], it doesn't return while the child runs.
+pub unsafe fn wait(pid: u64) -> Result<i32, SyscallError> {
+    unsafe { wait_for_pid(pid, true) }
+}
+
+/// # Safety
+/// This is generally safe, it only fills a local structure.
+pub unsafe fn sysinfo() -> Result<SysInfo, SyscallError> {
+    let mut info = SysInfo::default();
+    unsafe { syscalls::sysinfo(&mut info as *mut SysInfo as u64)? };
+
+    Ok(info)
+}
+
+/// Sets the limit of `resource` for `pid`, which must be the current process or one of its
+/// children, only `init` can raise a limit, except [`Resource::CoreSize`].
+///
+/// # Safety
+/// This is generally safe, but the process may not be able to allocate memory or open files
+/// after lowering its own limits.
+pub unsafe fn set_rlimit(pid: u64, resource: Resource, value: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::set_rlimit(pid, resource as u64, value) }
+}
+
+/// # Safety
+/// This is generally safe, it only reads the limit.
+pub unsafe fn get_rlimit(pid: u64, resource: Resource) -> Result<u64, SyscallError> {
+    unsafe { syscalls::get_rlimit(pid, resource as u64) }
+}
+
+/// Sets the scheduling priority of `pid`, which must be the current process or one of its
+/// children, from [`PRIORITY_HIGHEST`] to [`PRIORITY_LOWEST`], only a privileged process (i.e. `init`
+/// and the ones it started) can raise one. The processes it starts after have it too.
+///
+/// # Safety
+/// This is generally safe, but the process may run a lot less after lowering it.
+pub unsafe fn set_priority(pid: u64, priority: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::set_priority(pid, priority) }
+}
+
+/// # Safety
+/// This is generally safe, it only reads the priority.
+pub unsafe fn get_priority(pid: u64) -> Result<u64, SyscallError> {
+    unsafe { syscalls::get_priority(pid) }
+}
+
+/// Sets the name of `pid`, which must be the current process or one of its children, the name
+/// is cut to [`PROCESS_NAME_LEN`] bytes.
+///
+/// # Safety
+/// This is generally safe, the name is only shown in the diagnostics.
+pub unsafe fn set_name(pid: u64, name: &CStr) -> Result<(), SyscallError> {
+    unsafe { syscalls::set_name(pid, name) }
+}
+
+/// Copies the name of `pid` to `buf` and returns its length, a buffer of
+/// [`PROCESS_NAME_LEN`] bytes fits any name.
+///
+/// # Safety
+/// This is generally safe, it only fills `buf`.
+pub unsafe fn get_name(pid: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+    unsafe { syscalls::get_name(pid, buf) }
+}
+
+struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { syscall_write(FD_STDERR, s.as_bytes()) }
+            .map(|_| ())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+/// Checks that the running kernel uses the same [`ABI_VERSION`] this program was built with,
+/// and exits with [`EXIT_CODE_ABI_MISMATCH`] if it doesn't.
+///
+/// This must be called on startup before any other syscall (other than `exit`), it is by
+/// [`env::init`](crate::env::init).
+pub fn check_abi_version() {
+    match unsafe { sysinfo() }.map(|info| info.abi_version) {
+        Ok(ABI_VERSION) => return,
+        Ok(version) => {
+            let _ = writeln!(
+                StderrWriter,
+                "[user_std] ABI version mismatch: kernel={version}, program={ABI_VERSION}"
+            );
+        }
+        Err(e) => {
+            let _ = writeln!(StderrWriter, "[user_std] Could not get ABI version: {e:?}");
+        }
     }
+    unsafe { exit(EXIT_CODE_ABI_MISMATCH) }
 }
@@ -0,0 +1,70 @@
+//! Signals, see [`kernel_user_link::signal`].
+//!
+//! The handlers are `extern "C" fn(signal: u64)`, they return to [`signal_restorer`], which
+//! does `SYS_SIGRETURN` for them.
+
+pub use kernel_user_link::signal::{
+    exit_code, NUM_SIGNALS, SIGBUS, SIGFPE, SIGILL, SIGINT, SIGKILL, SIGSEGV, SIGTERM, SIGUSR1,
+    SIGUSR2, SIG_DFL, SIG_IGN,
+};
+use kernel_user_link::syscalls::{SyscallError, SYSCALL_INTERRUPT_NUMBER, SYS_SIGRETURN};
+
+use crate::syscalls;
+
+/// A handler of a signal, it runs on the stack of the thread that was interrupted
+pub type Handler = extern "C" fn(signal: u64);
+
+/// What a signal does, see `SYS_SIGACTION`
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// The process exits with [`exit_code`]
+    Default,
+    Ignore,
+    Handle(Handler),
+}
+
+core::arch::global_asm!(
+    ".global user_std_signal_restorer",
+    "user_std_signal_restorer:",
+    "mov rax, {sigreturn}",
+    "int {interrupt}",
+    // `SYS_SIGRETURN` doesn't return here
+    "ud2",
+    sigreturn = const SYS_SIGRETURN,
+    interrupt = const SYSCALL_INTERRUPT_NUMBER,
+);
+
+extern "C" {
+    /// Where the handlers return, with the stack pointer after the return address of the
+    /// frame, which `SYS_SIGRETURN` expects. It's never called
+    #[link_name = "user_std_signal_restorer"]
+    pub fn signal_restorer();
+}
+
+/// Sends `signal` to the process `pid`, this one or one of its children.
+///
+/// # Safety
+/// The process can be killed, i.e. this one, and its buffers are not flushed then
+pub unsafe fn kill(pid: u64, signal: u64) -> Result<(), SyscallError> {
+    unsafe { syscalls::kill(pid, signal) }
+}
+
+/// Sets what `signal` does, and returns what it did before.
+///
+/// # Safety
+/// The handler interrupts any code of the process, i.e. the one that prints, or holds a lock,
+/// it must only use what is safe to use from anywhere, like the atomics
+pub unsafe fn set_action(signal: u64, action: Action) -> Result<Action, SyscallError> {
+    let handler = match action {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handle(handler) => handler as *const () as u64,
+    };
+    let old = unsafe { syscalls::sigaction(signal, handler, signal_restorer as *const () as u64)? };
+    Ok(match old {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        // SAFETY: it was set by the code above, from a `Handler`
+        old => Action::Handle(unsafe { core::mem::transmute::<u64, Handler>(old) }),
+    })
+}
@@ -0,0 +1,60 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{futex, MutexGuard};
+
+/// A condition variable for [`Mutex`](super::Mutex), the waiting threads block with a futex
+///
+/// The futex is a counter of the notifications, a thread waits for it to change from what it
+/// was before it unlocked the mutex, so a notification between the two isn't missed.
+pub struct Condvar {
+    notifications: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            notifications: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks the mutex of `guard` and blocks until a notification, then locks it again. It
+    /// can also wake without one (i.e. for a signal), so the condition is checked again in a
+    /// loop, see [`Self::wait_while`]
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let notifications = self.notifications.load(Ordering::Relaxed);
+        let mutex = guard.mutex();
+        drop(guard);
+        futex::wait(&self.notifications, notifications).ok();
+        mutex.lock()
+    }
+
+    /// Waits while `condition` is true for the data of `guard`
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes one of the threads waiting
+    pub fn notify_one(&self) {
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+        futex::wake(&self.notifications, 1);
+    }
+
+    /// Wakes all the threads waiting
+    pub fn notify_all(&self) {
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+        futex::wake(&self.notifications, u64::MAX);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,24 @@
+//! Waiting for a `u32` to change, see `SYS_FUTEX_WAIT` and `SYS_FUTEX_WAKE`.
+//!
+//! The futexes are only shared by the threads of a process, the kernel keeps nothing for
+//! them but the threads waiting, by address.
+
+use core::sync::atomic::AtomicU32;
+
+use kernel_user_link::syscalls::SyscallError;
+
+use crate::syscalls;
+
+/// Blocks the current thread while `futex` is `expected`, until [`wake`]. It can return
+/// without a wake, i.e. for a signal, or with `WouldBlock` if the value was not `expected`,
+/// so the callers check the value again in a loop
+pub fn wait(futex: &AtomicU32, expected: u32) -> Result<(), SyscallError> {
+    // SAFETY: the kernel only reads it
+    unsafe { syscalls::futex_wait(futex.as_ptr() as u64, expected as u64) }
+}
+
+/// Wakes `count` of the threads waiting for `futex`, returns how many it did
+pub fn wake(futex: &AtomicU32, count: u64) -> u64 {
+    // SAFETY: the kernel doesn't touch it
+    unsafe { syscalls::futex_wake(futex.as_ptr() as u64, count) }.unwrap_or(0)
+}
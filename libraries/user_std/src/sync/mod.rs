@@ -1,2 +1,13 @@
+//! Locks for the threads of a process.
+//!
+//! [`Mutex`] and [`Condvar`] block the threads waiting with a [`futex`], so the others run
+//! meanwhile. The [`spin`] locks wait by spinning, for the code that can't use the syscalls.
+
+mod condvar;
+pub mod futex;
+mod mutex;
 pub mod once;
 pub mod spin;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
@@ -0,0 +1,125 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use super::futex;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// locked, and there may be threads waiting for it
+const CONTENDED: u32 = 2;
+
+/// A mutex that blocks the threads waiting for it with a futex, instead of spinning
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> fmt::Debug for Mutex<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("Mutex");
+        if let Some(data) = self.try_lock() {
+            s.field("data", &data);
+        } else {
+            s.field("data", &"[locked]");
+        }
+        s.finish()
+    }
+}
+
+#[must_use]
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    marker: PhantomData<*const ()>, // !Send
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> fmt::Debug for MutexGuard<'_, T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // the one that unlocks it wakes a waiter, we don't know if we are the last one, so
+            // we leave it contended
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                futex::wait(&self.state, CONTENDED).ok();
+            }
+        }
+        MutexGuard {
+            lock: self,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard {
+                lock: self,
+                marker: PhantomData,
+            })
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex of the guard, for [`super::Condvar`]
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.lock
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the mutex is locked, we are the only accessors,
+        //         and the pointer is valid, since it was generated for a valid T
+        unsafe { self.lock.data.get().as_ref().unwrap() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: the mutex is locked, we are the only accessors,
+        //         and the pointer is valid, since it was generated for a valid T
+        unsafe { self.lock.data.get().as_mut().unwrap() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex::wake(&self.lock.state, 1);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Threads of the current process, see `SYS_THREAD_CREATE`.
+//!
+//! They share the memory and the files of the process, and take turns on its CPU. Each gets a
+//! stack of [`STACK_SIZE`] and [`TLS_SIZE`] bytes of its own, found with [`tls`], both from
+//! the heap, they are freed when it's joined.
+
+use core::{alloc::Layout, cell::UnsafeCell};
+
+use kernel_user_link::syscalls::SyscallError;
+
+use crate::{
+    alloc::alloc::{
+        alloc::{alloc, alloc_zeroed, dealloc},
+        boxed::Box,
+        sync::Arc,
+    },
+    syscalls,
+};
+
+/// The stack of each thread
+pub const STACK_SIZE: usize = 256 * 1024;
+/// The space of each thread returned by [`tls`]
+pub const TLS_SIZE: usize = 256;
+
+const STACK_ALIGNMENT: usize = 16;
+// the TLS block starts with its own address, which is how `tls` finds it from the FS base
+const TLS_HEADER_SIZE: usize = 8;
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// Where the thread puts the result of its closure
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+// SAFETY: it's only written by the thread before it exits, and read after it's joined
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+fn stack_layout() -> Layout {
+    Layout::from_size_align(STACK_SIZE, STACK_ALIGNMENT).unwrap()
+}
+
+fn tls_layout() -> Layout {
+    Layout::from_size_align(TLS_HEADER_SIZE + TLS_SIZE, TLS_HEADER_SIZE).unwrap()
+}
+
+/// A thread started by [`spawn`], it must be joined to get the result and free its stack
+pub struct JoinHandle<T> {
+    id: u64,
+    stack: *mut u8,
+    tls: *mut u8,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The id of the thread, unique among the threads and the processes
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits for the thread to exit, and returns what its closure returned, the signals
+    /// handled while waiting don't stop it
+    pub fn join(self) -> Result<T, SyscallError> {
+        loop {
+            match unsafe { syscalls::thread_join(self.id) } {
+                Err(SyscallError::Interrupted) => continue,
+                result => break result?,
+            }
+        }
+        // SAFETY: the thread exited, nothing uses them anymore
+        unsafe {
+            dealloc(self.stack, stack_layout());
+            dealloc(self.tls, tls_layout());
+        }
+        // SAFETY: the thread exited, it can't write it anymore
+        let result = unsafe { (*self.packet.result.get()).take() };
+        Ok(result.expect("the thread exited without returning"))
+    }
+}
+
+/// Where the threads of [`spawn`] start, with the stack aligned like after a `call`
+extern "C" fn thread_start(main: *mut ThreadMain) -> ! {
+    // SAFETY: given by `spawn`, and only used here
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    unsafe {
+        syscalls::thread_exit().unwrap();
+    }
+    unreachable!("thread_exit syscall should not return")
+}
+
+/// Starts a thread that runs `f`, see [`JoinHandle::join`] for its result.
+///
+/// # Safety
+/// The std of this OS expects a single thread, the threads must not use it at the same time,
+/// i.e. print while another prints, only [`crate`] can be used from any of them.
+pub unsafe fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, SyscallError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: UnsafeCell::new(None),
+    });
+    let their_packet = packet.clone();
+    let main: ThreadMain = Box::new(move || {
+        let result = f();
+        // SAFETY: see `Packet`
+        unsafe { *their_packet.result.get() = Some(result) };
+    });
+
+    let stack = unsafe { alloc(stack_layout()) };
+    let tls = unsafe { alloc_zeroed(tls_layout()) };
+    if stack.is_null() || tls.is_null() {
+        unsafe {
+            if !stack.is_null() {
+                dealloc(stack, stack_layout());
+            }
+            if !tls.is_null() {
+                dealloc(tls, tls_layout());
+            }
+        }
+        return Err(SyscallError::NoMemory);
+    }
+    unsafe { (tls as *mut usize).write(tls as usize) };
+
+    let main = Box::into_raw(Box::new(main));
+    // the return address would be at the end, `rsp + 8` is aligned
+    let stack_end = stack as u64 + STACK_SIZE as u64 - 8;
+    let result = unsafe {
+        syscalls::thread_create(
+            thread_start as *const () as u64,
+            stack_end,
+            main as u64,
+            tls as u64,
+        )
+    };
+    match result {
+        Ok(id) => Ok(JoinHandle {
+            id,
+            stack,
+            tls,
+            packet,
+        }),
+        Err(e) => {
+            unsafe {
+                drop(Box::from_raw(main));
+                dealloc(stack, stack_layout());
+                dealloc(tls, tls_layout());
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The [`TLS_SIZE`] bytes of the current thread, zeroed when it starts.
+///
+/// # Safety
+/// Only the threads of [`spawn`] have them, the first thread of the process has no FS base.
+pub unsafe fn tls() -> *mut u8 {
+    let block: *mut u8;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, fs:[0]",
+            out(reg) block,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+    unsafe { block.add(TLS_HEADER_SIZE) }
+}
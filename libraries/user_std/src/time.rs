@@ -0,0 +1,142 @@
+//! The clocks of the kernel, see [`kernel_user_link::time`].
+//!
+//! They are read from the time page with `rdtsc` when the kernel offers it, without entering
+//! the kernel, otherwise with `SYS_CLOCK_GETTIME`. Both give the same times.
+//!
+//! [`Instant`] and [`SystemTime`] are also the ones of `std::time`, with the methods the std port
+//! needs from its `sys::time` (`checked_sub_instant`, `sub_time`, ...), so both give the same
+//! times.
+//!
+//! [`sleep`] blocks the thread with `SYS_SLEEP`, the CPU runs the others meanwhile.
+
+use core::time::Duration;
+
+use kernel_user_link::syscalls::SyscallError;
+pub use kernel_user_link::time::ClockId;
+
+use crate::{env, syscalls};
+
+/// # Safety
+/// This is generally safe, it only reads the clock.
+pub unsafe fn syscall_clock_gettime(clock: ClockId) -> Result<u64, SyscallError> {
+    unsafe { syscalls::clock_gettime(clock as u64) }
+}
+
+pub fn rdtsc() -> u64 {
+    // SAFETY: the kernel doesn't stop the user from reading the TSC
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Whether the clocks are read from the time page, without a syscall
+pub fn has_time_page() -> bool {
+    env::time_page().is_some()
+}
+
+/// The nanoseconds of `clock`, from the time page if there is one
+pub fn clock_nanos(clock: ClockId) -> u64 {
+    match env::time_page() {
+        Some(page) => page.read().clock_at(clock, rdtsc()),
+        // every `ClockId` is valid, it can't fail
+        None => unsafe { syscall_clock_gettime(clock) }.unwrap_or(0),
+    }
+}
+
+/// The nanoseconds of `duration`, `None` if it's too long for the clocks
+fn duration_nanos(duration: &Duration) -> Option<u64> {
+    u64::try_from(duration.as_nanos()).ok()
+}
+
+/// A time of [`ClockId::Monotonic`], the one behind `std::time::Instant`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(clock_nanos(ClockId::Monotonic))
+    }
+
+    /// The nanoseconds since boot
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// The time from `earlier` to this, zero if it is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// The time from `other` to this, `None` if it is later
+    pub fn checked_sub_instant(&self, other: &Instant) -> Option<Duration> {
+        self.0.checked_sub(other.0).map(Duration::from_nanos)
+    }
+
+    pub fn checked_add_duration(&self, other: &Duration) -> Option<Instant> {
+        self.0.checked_add(duration_nanos(other)?).map(Self)
+    }
+
+    pub fn checked_sub_duration(&self, other: &Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_nanos(other)?).map(Self)
+    }
+}
+
+/// Blocks the current thread for `duration` at least, the signals handled meanwhile don't stop
+/// it, it sleeps for the rest after them
+pub fn sleep(duration: Duration) {
+    let start = Instant::now();
+    loop {
+        let left = duration.saturating_sub(start.elapsed());
+        if left.is_zero() {
+            return;
+        }
+        let nanos = left.as_nanos().min(u64::MAX as u128) as u64;
+        // SAFETY: it only blocks
+        match unsafe { syscalls::sleep(nanos) } {
+            Ok(()) => return,
+            Err(SyscallError::Interrupted) => {}
+            // any length is valid
+            Err(e) => unreachable!("sleep failed: {e:?}"),
+        }
+    }
+}
+
+/// A time of [`ClockId::Realtime`], the one behind `std::time::SystemTime`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+impl SystemTime {
+    pub fn now() -> Self {
+        Self(clock_nanos(ClockId::Realtime))
+    }
+
+    /// The time from `earlier` to this, or how much earlier this is as the error, the
+    /// realtime can be set back
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, Duration> {
+        match self.0.checked_sub(earlier.0) {
+            Some(nanos) => Ok(Duration::from_nanos(nanos)),
+            None => Err(Duration::from_nanos(earlier.0 - self.0)),
+        }
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, Duration> {
+        Self::now().duration_since(*self)
+    }
+
+    /// [`SystemTime::duration_since`], by reference
+    pub fn sub_time(&self, other: &SystemTime) -> Result<Duration, Duration> {
+        self.duration_since(*other)
+    }
+
+    pub fn checked_add_duration(&self, other: &Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration_nanos(other)?).map(Self)
+    }
+
+    pub fn checked_sub_duration(&self, other: &Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration_nanos(other)?).map(Self)
+    }
+}
@@ -0,0 +1,87 @@
+//! `init_test`
+//!
+//! A tiny `init` for testing the init stage of the kernel, booted with `init=/init_test`.
+//!
+//! It runs the shell on `/tests/init.sh` with the console it got, checks that a process
+//! whose parent exited is given to `init`, then exits, which the kernel must not survive:
+//! - with `init_exit=reboot debug_exit`, QEMU exits with `75` (reboot, init exited)
+//! - with the default policy, the kernel panics, with `panic=reboot debug_exit` QEMU exits
+//!   with `69` (reboot, panic)
+//!
+//! The same program is the middle and the last of the orphan check, with the arguments
+//! `parent` and `orphan`
+#![feature(restricted_std)]
+
+use std::{
+    fs::File,
+    io::Read,
+    process::{self, Command, ExitCode, Stdio},
+};
+
+use kernel_user_link::{call_syscall, syscalls::SYS_WAIT_PID};
+
+const SELF_PATH: &str = "/init_test";
+const SHELL_TEST_PATH: &str = "/tests/init.sh";
+const ORPHAN_EXIT_CODE: i32 = 42;
+
+/// Spawns the orphan with its stdin from a pipe, and exits with its pid without closing the
+/// pipe, the kernel closes it when dropping us, which ends the orphan. So it exits after us
+fn orphan_parent() -> ! {
+    let child = Command::new(SELF_PATH)
+        .arg("orphan")
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    process::exit(child.id() as i32)
+}
+
+fn orphan() -> ExitCode {
+    let _ = std::io::stdin().read_to_end(&mut Vec::new());
+    ExitCode::from(ORPHAN_EXIT_CODE as u8)
+}
+
+/// `init` can only wait for its children, so this fails unless the orphan was re-parented
+fn check_orphan() -> bool {
+    let status = Command::new(SELF_PATH).arg("parent").status().unwrap();
+    let Some(orphan_pid) = status.code() else {
+        println!("[init_test] orphan parent: no exit code, {status}");
+        return false;
+    };
+    let result = unsafe { call_syscall!(SYS_WAIT_PID, orphan_pid as u64, 1) };
+    println!("[init_test] orphan {orphan_pid}: expected Ok({ORPHAN_EXIT_CODE}), got {result:?}");
+    matches!(result, Ok(code) if code == ORPHAN_EXIT_CODE as u64)
+}
+
+fn run_shell() -> bool {
+    let script = match File::open(SHELL_TEST_PATH) {
+        Ok(script) => script,
+        Err(e) => {
+            println!("[init_test] error: {SHELL_TEST_PATH}: {e}");
+            return false;
+        }
+    };
+    let status = Command::new("/shell").stdin(script).status().unwrap();
+    println!("[init_test] shell: expected 0, got {status}");
+    status.success()
+}
+
+fn main() -> ExitCode {
+    match std::env::args().nth(1).as_deref() {
+        Some("parent") => orphan_parent(),
+        Some("orphan") => return orphan(),
+        _ => {}
+    }
+
+    let shell = run_shell();
+    let orphan = check_orphan();
+    let passed = shell && orphan;
+    println!(
+        "[init_test] {}, exiting, the kernel must not go on",
+        if passed { "passed" } else { "FAILED" }
+    );
+    if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -2,54 +2,92 @@
 //!
 //! This is the initialization user process
 //!
-//! For now it only keeps a shell running
+//! For now it only keeps a shell running on each of the first virtual terminals
 //!
-//! It acts as a wrapper, where it takes stdin stream as bytes,
-//! and forwards it to the shell making sure to render them on each button press
+//! It acts as a wrapper, where it takes the input lines of the terminal, and forwards them
+//! to the shell, the terminal edits and echoes them itself
 #![feature(restricted_std)]
 
 use std::{
     fs::File,
     io::{Read, Write},
-    os::amjad_os::io::{FromRawFd, OwnedFd},
-    process::{Command, Stdio},
+    os::amjad_os::io::{FromRawFd, IntoRawFd, OwnedFd},
+    process::{Child, ChildStdin, Command, Stdio},
 };
 
-fn main() {
-    let owned_stdin = unsafe { OwnedFd::from_raw_fd(0) };
-    owned_stdin.set_nonblocking(true).unwrap();
-    let mut stdin_file = File::from(owned_stdin);
+/// The terminals to run a shell on, switched with `Alt+F1`, `Alt+F2`...
+const TERMINALS: &[&str] = &["/devices/tty1", "/devices/tty2"];
 
-    loop {
+/// A shell running on a terminal
+struct Session {
+    path: &'static str,
+    terminal: File,
+    child: Child,
+    child_stdin: ChildStdin,
+}
+
+impl Session {
+    fn spawn(path: &'static str) -> Self {
+        // SAFETY: the fd was just taken out of the file, so its only owned here
+        let terminal = unsafe { OwnedFd::from_raw_fd(File::open(path).unwrap().into_raw_fd()) };
+        terminal.set_nonblocking(true).unwrap();
+        let terminal = File::from(terminal);
+
+        let (child, child_stdin) = Self::spawn_shell(path);
+        Self {
+            path,
+            terminal,
+            child,
+            child_stdin,
+        }
+    }
+
+    fn spawn_shell(path: &str) -> (Child, ChildStdin) {
         let mut child = Command::new("/shell")
             .stdin(Stdio::piped())
+            .stdout(File::open(path).unwrap())
+            .stderr(File::open(path).unwrap())
             .spawn()
             .unwrap();
-        let child_pid = child.id();
+        let child_stdin = child.stdin.take().unwrap();
+        (child, child_stdin)
+    }
 
-        let mut child_stdin = child.stdin.take().unwrap();
+    /// Forwards the input of the terminal, and starts the shell again if it exited
+    fn poll(&mut self) {
+        if let Some(status) = self.child.try_wait().unwrap() {
+            let message = format!(
+                "\n[init] child {} exited with {} on {}\n",
+                self.child.id(),
+                status,
+                self.path
+            );
+            self.terminal.write_all(message.as_bytes()).unwrap();
+            (self.child, self.child_stdin) = Self::spawn_shell(self.path);
+        }
 
-        // running busy loop
-        let mut line_buffer = Vec::new();
-        let res = loop {
-            if let Some(status) = child.try_wait().unwrap() {
-                break status;
+        let mut buf = [0u8; 64];
+        loop {
+            let len = self.terminal.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
             }
-            let mut buf = [0u8; 1];
-            while stdin_file.read(&mut buf).unwrap() == 0 {
-                core::hint::spin_loop();
-            }
-            // also output to our stdout
-            std::io::stdout().write_all(&buf).unwrap();
-            std::io::stdout().flush().unwrap();
-            line_buffer.push(buf[0]);
-
-            if buf[0] == b'\n' {
-                child_stdin.write_all(&line_buffer).unwrap();
-                line_buffer.clear();
-            }
-        };
+            self.child_stdin.write_all(&buf[..len]).unwrap();
+        }
+    }
+}
 
-        println!("\n[init] child {} exited with {}", child_pid, res);
+fn main() {
+    let mut sessions = TERMINALS
+        .iter()
+        .map(|&path| Session::spawn(path))
+        .collect::<Vec<_>>();
+
+    // running busy loop
+    loop {
+        for session in &mut sessions {
+            session.poll();
+        }
+        core::hint::spin_loop();
     }
 }
@@ -5,31 +5,34 @@ use std::{io::Read, process::ExitCode};
 /// Cat shell program
 ///
 /// Usage: cat [file]
+///
+/// Without a file, it reads stdin until its closed, for pipelines
 
 fn main() -> ExitCode {
     let args = std::env::args().collect::<Vec<_>>();
 
-    if args.len() < 2 {
+    if args.len() > 2 {
         println!("Usage: {} [file]", args[0]);
         return ExitCode::FAILURE;
     }
 
-    let file = &args[1];
-
-    let mut file = match std::fs::File::open(file) {
-        Ok(f) => f,
-        Err(e) => {
-            println!("[!] error: {}", e);
-            return ExitCode::FAILURE;
-        }
+    let mut input: Box<dyn Read> = match args.get(1) {
+        Some(file) => match std::fs::File::open(file) {
+            Ok(f) => Box::new(f),
+            Err(e) => {
+                println!("[!] error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(std::io::stdin()),
     };
 
     let mut buf = [0u8; 1024];
     loop {
-        match file.read(&mut buf) {
+        match input.read(&mut buf) {
             Ok(0) => break,
             Ok(n) => {
-                let s = std::str::from_utf8(&buf[..n]).unwrap();
+                let s = String::from_utf8_lossy(&buf[..n]);
                 print!("{}", s);
             }
             Err(e) => {
@@ -0,0 +1,73 @@
+#![feature(restricted_std)]
+
+use std::{io::Read, process::ExitCode};
+
+/// The CRC-32 of zlib and ethernet, reflected
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+/// The CRC-32 and the size of everything in `input`
+fn checksum(table: &[u32; 256], input: &mut dyn Read) -> std::io::Result<(u32, u64)> {
+    let mut crc = !0u32;
+    let mut size = 0;
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            return Ok((!crc, size));
+        }
+        for &byte in &buf[..n] {
+            crc = table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        size += n as u64;
+    }
+}
+
+/// Checksum shell program
+///
+/// Usage: cksum [file...]
+///
+/// Prints the CRC-32 (the one of zlib) and the size of each file, or of stdin without files
+fn main() -> ExitCode {
+    let table = crc_table();
+    let files = std::env::args().skip(1).collect::<Vec<_>>();
+    if files.is_empty() {
+        return match checksum(&table, &mut std::io::stdin()) {
+            Ok((crc, size)) => {
+                println!("{crc:08x} {size}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                println!("[!] error: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for file in &files {
+        match std::fs::File::open(file).and_then(|mut f| checksum(&table, &mut f)) {
+            Ok((crc, size)) => println!("{crc:08x} {size} {file}"),
+            Err(e) => {
+                println!("[!] error: {file}: {e}");
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
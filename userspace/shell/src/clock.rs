@@ -0,0 +1,252 @@
+#![feature(restricted_std)]
+
+use std::{process::ExitCode, time::Duration};
+
+use user_std::{
+    env,
+    time::{self, ClockId},
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// how far the syscall can be from the reads of the page around it
+const TOLERANCE_NANOS: u64 = 50_000;
+// a gap this long between two reads means another process ran in between
+const SLICE_GAP_NANOS: u64 = 1_000_000;
+// the monotonic time `--compare` runs for, many time slices
+const COMPARE_NANOS: u64 = NANOS_PER_SECOND / 2;
+// the realtimes `--set-loop` switches between, 2001-01-01 and 2031-01-01
+const REALTIME_A: u64 = 978_307_200 * NANOS_PER_SECOND;
+const REALTIME_B: u64 = 1_924_992_000 * NANOS_PER_SECOND;
+const DAY_NANOS: u64 = 86_400 * NANOS_PER_SECOND;
+
+fn usage() -> ExitCode {
+    println!(
+        "Usage: clock [--compare | --torn <reads> | --set-loop <count> | --bench <count> | --elapsed <ms>]"
+    );
+    ExitCode::FAILURE
+}
+
+fn syscall_nanos(clock: ClockId) -> u64 {
+    unsafe { time::syscall_clock_gettime(clock) }.expect("clock_gettime failed")
+}
+
+fn page_nanos(clock: ClockId) -> Option<u64> {
+    env::time_page().map(|page| page.read().clock_at(clock, time::rdtsc()))
+}
+
+fn show() -> ExitCode {
+    for clock in [ClockId::Monotonic, ClockId::Realtime] {
+        match page_nanos(clock) {
+            Some(page) => println!(
+                "{clock:?}: {} (syscall), {page} (time page)",
+                syscall_nanos(clock)
+            ),
+            None => println!(
+                "{clock:?}: {} (syscall), no time page",
+                syscall_nanos(clock)
+            ),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Reads the page, the syscall, then the page again, until many time slices went by, the
+/// syscall must be between the two reads
+fn compare() -> ExitCode {
+    if !time::has_time_page() {
+        println!("clock: no time page, both are the syscall");
+    }
+    let start = time::clock_nanos(ClockId::Monotonic);
+    let mut slices = 0;
+    let mut samples = 0u64;
+    let mut status = ExitCode::SUCCESS;
+    loop {
+        let before = time::clock_nanos(ClockId::Monotonic);
+        let syscall = syscall_nanos(ClockId::Monotonic);
+        let after = time::clock_nanos(ClockId::Monotonic);
+        samples += 1;
+        if after - before >= SLICE_GAP_NANOS {
+            slices += 1;
+        }
+        if syscall + TOLERANCE_NANOS < before || syscall > after + TOLERANCE_NANOS {
+            println!("[!] clock: the syscall gave {syscall}, the page {before} then {after}");
+            status = ExitCode::FAILURE;
+        }
+        if after < before {
+            println!("[!] clock: the page went back from {before} to {after}");
+            status = ExitCode::FAILURE;
+        }
+        if after - start >= COMPARE_NANOS {
+            break;
+        }
+    }
+    println!("clock: {samples} samples, {slices} across time slices");
+    if slices == 0 {
+        println!("[!] clock: no time slice boundary was crossed");
+        status = ExitCode::FAILURE;
+    }
+    status
+}
+
+/// Reads the page `reads` times while `--set-loop` changes the realtime, every read has the
+/// monotonic time of the page not going back, and a realtime that was set
+fn torn(reads: u64) -> ExitCode {
+    let Some(page) = env::time_page() else {
+        println!("clock: no time page, nothing to tear");
+        return ExitCode::SUCCESS;
+    };
+    let boot_realtime = page.read().realtime_at(time::rdtsc());
+    let expected = |realtime: u64| {
+        [REALTIME_A, REALTIME_B, boot_realtime]
+            .iter()
+            .any(|&set| (set..set + DAY_NANOS).contains(&realtime))
+    };
+    let mut last = 0;
+    let mut retries = 0u64;
+    for _ in 0..reads {
+        let snapshot = loop {
+            match page.try_read() {
+                Some(snapshot) => break snapshot,
+                None => retries += 1,
+            }
+        };
+        let tsc = time::rdtsc();
+        let monotonic = snapshot.monotonic_at(tsc);
+        let realtime = snapshot.realtime_at(tsc);
+        if monotonic < last || !expected(realtime) {
+            println!("[!] clock: torn read {snapshot:?}, after {last}");
+            return ExitCode::FAILURE;
+        }
+        last = monotonic;
+    }
+    println!("clock: {reads} reads, none torn, {retries} retried");
+    ExitCode::SUCCESS
+}
+
+fn set_realtime(nanos: u64) -> bool {
+    match std::fs::write("/devices/time", format!("realtime {nanos}")) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("[!] clock: /devices/time: {e}");
+            false
+        }
+    }
+}
+
+/// Switches the realtime between two dates `count` times, then puts it back
+fn set_loop(count: u64) -> ExitCode {
+    let start_realtime = syscall_nanos(ClockId::Realtime);
+    let start = syscall_nanos(ClockId::Monotonic);
+    for i in 0..count {
+        if !set_realtime(if i % 2 == 0 { REALTIME_A } else { REALTIME_B }) {
+            return ExitCode::FAILURE;
+        }
+    }
+    let elapsed = syscall_nanos(ClockId::Monotonic) - start;
+    if !set_realtime(start_realtime + elapsed) {
+        return ExitCode::FAILURE;
+    }
+    println!("clock: set the realtime {count} times");
+    ExitCode::SUCCESS
+}
+
+/// The nanoseconds per call of `f`, called `count` times
+fn per_call(count: u64, mut f: impl FnMut() -> u64) -> u64 {
+    let start = time::clock_nanos(ClockId::Monotonic);
+    let mut sum = 0u64;
+    for _ in 0..count {
+        sum = sum.wrapping_add(f());
+    }
+    std::hint::black_box(sum);
+    (time::clock_nanos(ClockId::Monotonic) - start) / count.max(1)
+}
+
+fn bench(count: u64) -> ExitCode {
+    let syscall = per_call(count, || syscall_nanos(ClockId::Monotonic));
+    match env::time_page() {
+        Some(_) => {
+            let page = per_call(count, || page_nanos(ClockId::Monotonic).unwrap());
+            println!("clock: {page} ns a call with the time page, {syscall} ns with the syscall");
+        }
+        None => println!("clock: no time page, {syscall} ns a call with the syscall"),
+    }
+    ExitCode::SUCCESS
+}
+
+/// Sleeps `millis`, timed with `std::time`, which must be between the clocks of `user_std`
+/// read around it. Its `SystemTime` is also checked against the realtime
+fn elapsed(millis: u64) -> ExitCode {
+    let tolerance = Duration::from_nanos(TOLERANCE_NANOS);
+    let mut status = ExitCode::SUCCESS;
+
+    let realtime_before = time::SystemTime::now().duration_since(time::UNIX_EPOCH);
+    let std_realtime = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    let realtime_after = time::SystemTime::now().duration_since(time::UNIX_EPOCH);
+    match (realtime_before, std_realtime, realtime_after) {
+        (Ok(before), Ok(std_realtime), Ok(after))
+            if before <= std_realtime + tolerance && std_realtime <= after + tolerance => {}
+        results => {
+            println!("[!] clock: std::time::SystemTime is not the realtime: {results:?}");
+            status = ExitCode::FAILURE;
+        }
+    }
+
+    let sleep = Duration::from_millis(millis);
+    let outer_start = time::Instant::now();
+    let system_start = std::time::SystemTime::now();
+    let start = std::time::Instant::now();
+    time::sleep(sleep);
+    let elapsed = start.elapsed();
+    let system_elapsed = system_start.elapsed();
+    let outer = outer_start.elapsed();
+
+    println!(
+        "clock: std::time::Instant: {}ms elapsed, slept {millis}ms",
+        elapsed.as_millis()
+    );
+    if elapsed < sleep || elapsed > outer + tolerance {
+        println!("[!] clock: std::time::Instant gave {elapsed:?}, the monotonic time {outer:?}");
+        status = ExitCode::FAILURE;
+    }
+    match system_elapsed {
+        Ok(system_elapsed)
+            if system_elapsed + tolerance >= elapsed && system_elapsed <= outer + tolerance =>
+        {
+            println!(
+                "clock: std::time::SystemTime: {}ms elapsed",
+                system_elapsed.as_millis()
+            );
+        }
+        result => {
+            println!(
+                "[!] clock: std::time::SystemTime gave {result:?}, the monotonic time {outer:?}"
+            );
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+/// Clock shell program
+///
+/// Usage: clock [--compare | --torn <reads> | --set-loop <count> | --bench <count> |
+/// --elapsed <ms>]
+///
+/// Without options, prints both clocks from the syscall and the time page. The options are
+/// the tests of the time page: `--compare` checks that the syscall agrees with the page
+/// across time slices, `--torn` reads the page while `--set-loop` sets the realtime in
+/// another process, `--bench` compares the cost of both, and `--elapsed` times a sleep with
+/// `std::time`
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let count = |arg: Option<&String>| arg.and_then(|count| count.parse::<u64>().ok());
+    match args.first().map(String::as_str) {
+        None => show(),
+        Some("--compare") if args.len() == 1 => compare(),
+        Some("--torn") => count(args.get(1)).map_or_else(usage, torn),
+        Some("--set-loop") => count(args.get(1)).map_or_else(usage, set_loop),
+        Some("--bench") => count(args.get(1)).map_or_else(usage, bench),
+        Some("--elapsed") => count(args.get(1)).map_or_else(usage, elapsed),
+        _ => usage(),
+    }
+}
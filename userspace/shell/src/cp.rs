@@ -0,0 +1,188 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode};
+
+use kernel_user_link::{
+    call_syscall,
+    file::OPEN_CREATE,
+    syscalls::{
+        SyscallError, SYS_CLOSE, SYS_COPY_FILE_RANGE, SYS_OPEN, SYS_READ, SYS_UNLINK, SYS_WRITE,
+    },
+};
+
+const PROCESSES_PATH: &str = "/devices/processes";
+// the most the kernel moves in one `read` or `write`
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn usage() -> ExitCode {
+    println!("Usage: cp [-n] [-v] <source> <destination>");
+    ExitCode::FAILURE
+}
+
+struct Fd(usize);
+
+impl Fd {
+    fn open(path: &str, flags: u64) -> Result<Self, SyscallError> {
+        let path = CString::new(path).unwrap();
+        unsafe {
+            call_syscall!(
+                SYS_OPEN,
+                path.as_ptr() as u64, // path
+                0,                    // access_mode
+                flags,                // flags
+            )
+            .map(|fd| Self(fd as usize))
+        }
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { call_syscall!(SYS_CLOSE, self.0).ok() };
+    }
+}
+
+#[derive(Default)]
+struct CopyStats {
+    bytes: u64,
+    syscalls: u64,
+}
+
+/// Copies inside the kernel, using and advancing the positions of the files
+fn copy_in_kernel(src: &Fd, dst: &Fd, stats: &mut CopyStats) -> Result<(), SyscallError> {
+    loop {
+        let copied = unsafe {
+            call_syscall!(
+                SYS_COPY_FILE_RANGE,
+                src.0,    // fd_in
+                0,        // off_in
+                dst.0,    // fd_out
+                0,        // off_out
+                u64::MAX, // len
+            )?
+        };
+        stats.syscalls += 1;
+        if copied == 0 {
+            return Ok(());
+        }
+        stats.bytes += copied;
+    }
+}
+
+/// Moves every byte through our buffer
+fn copy_naive(src: &Fd, dst: &Fd, stats: &mut CopyStats) -> Result<(), SyscallError> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = unsafe {
+            call_syscall!(
+                SYS_READ,
+                src.0,                   // fd
+                buf.as_mut_ptr() as u64, // buf
+                buf.len() as u64,        // size
+            )?
+        };
+        stats.syscalls += 1;
+        if read == 0 {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < read {
+            let n = unsafe {
+                call_syscall!(
+                    SYS_WRITE,
+                    dst.0,                                   // fd
+                    buf[written as usize..].as_ptr() as u64, // buf
+                    read - written,                          // size
+                )?
+            };
+            stats.syscalls += 1;
+            if n == 0 {
+                return Err(SyscallError::CouldNotWriteToFile);
+            }
+            written += n;
+        }
+        stats.bytes += read;
+    }
+}
+
+/// Removes `path` if its there, so the copy doesn't keep the end of a longer file
+fn remove_existing(path: &str) -> Result<(), SyscallError> {
+    let path = CString::new(path).unwrap();
+    match unsafe { call_syscall!(SYS_UNLINK, path.as_ptr() as u64) } {
+        Ok(_) | Err(SyscallError::FileNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The CPU time used by this process so far in milliseconds, user and kernel,
+/// from `/devices/processes`
+fn cpu_time_ms() -> Option<u64> {
+    let processes = std::fs::read_to_string(PROCESSES_PATH).ok()?;
+    let pid = std::process::id().to_string();
+    // after the two header lines: PID NAME PPID STATE USER(ms) KERNEL(ms) ...
+    let row = processes
+        .lines()
+        .skip(2)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.first() == Some(&pid.as_str()))?;
+    let user = row.get(4)?.parse::<u64>().ok()?;
+    let kernel = row.get(5)?.parse::<u64>().ok()?;
+    Some(user + kernel)
+}
+
+/// Copy shell program
+///
+/// Usage: cp [-n] [-v] <source> <destination>
+///
+/// Copies the file `source` to `destination`, which is replaced if it exists.
+/// The data is copied inside the kernel with `SYS_COPY_FILE_RANGE`, or by reading and writing
+/// it if the kernel can't copy these files (i.e. devices), `-n` always reads and writes.
+/// `-v` prints the bytes copied, the number of syscalls and the CPU time it took
+fn main() -> ExitCode {
+    let mut naive = false;
+    let mut verbose = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-n" => naive = true,
+            "-v" => verbose = true,
+            _ if arg.starts_with('-') => return usage(),
+            _ => paths.push(arg),
+        }
+    }
+    let [source, destination] = paths.as_slice() else {
+        return usage();
+    };
+
+    let start_ms = cpu_time_ms();
+    let mut stats = CopyStats::default();
+    let mut method = "copy_file_range";
+    let result = Fd::open(source, 0).and_then(|src| {
+        remove_existing(destination)?;
+        let dst = Fd::open(destination, OPEN_CREATE)?;
+        if !naive {
+            match copy_in_kernel(&src, &dst, &mut stats) {
+                Err(SyscallError::NotSupported) => {}
+                result => return result,
+            }
+        }
+        method = "read/write";
+        copy_naive(&src, &dst, &mut stats)
+    });
+    if let Err(e) = result {
+        println!("[!] error: {source} -> {destination}: {e:?}");
+        return ExitCode::FAILURE;
+    }
+
+    if verbose {
+        let cpu_time = start_ms
+            .zip(cpu_time_ms())
+            .map(|(start, end)| format!("{}ms", end - start))
+            .unwrap_or_else(|| String::from("?"));
+        println!(
+            "cp: {} bytes with {method}, {} syscalls, {cpu_time} of CPU time",
+            stats.bytes, stats.syscalls
+        );
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,502 @@
+#![feature(restricted_std)]
+
+use std::{
+    hint::black_box,
+    process::ExitCode,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use kernel_user_link::{
+    call_syscall,
+    core_dump::{self, CoreInfo, PrStatus, NT_CORE_INFO, NT_FILE, NT_PRSTATUS, PF_W, PT_LOAD},
+    crash_dump::CrashDump,
+    process::{Resource, EXIT_CODE_CRASH, RLIMIT_INFINITY},
+    syscalls::SYS_SET_RLIMIT,
+};
+
+/// Crashinfo shell program
+///
+/// Usage: crashinfo <dump>
+///        crashinfo --crash
+///        crashinfo --test
+///        crashinfo --core-test
+///
+/// Prints a crash dump the kernel left in `/tmp/crash-<pid>-<name>.dump`, the words of the
+/// stack that point into a function of the program are shown with its name.
+/// `--crash` crashes on purpose, and `--test` runs `--crash` as a child and checks its dump.
+/// `--core-test` crashes a child with a known value in a global, and finds it in the ELF core
+/// file `/tmp/core.<pid>`, whole and then cut by a small limit
+
+const PROGRAM_PATH: &str = "/crashinfo";
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// Written to [`CORE_MARKER`] before crashing, for `--core-test` to find in the core
+const CORE_MARKER_VALUE: u64 = 0x0C0D_E0F0_CAFE_F00D;
+/// The cut core, enough for the first pages of the data only
+const SMALL_CORE_LIMIT: u64 = 16 * 1024;
+
+static CORE_MARKER: AtomicU64 = AtomicU64::new(0);
+
+/// The functions (or the objects) of a program, from the `.symtab` of its ELF file, sorted
+/// by address
+struct Symbols {
+    functions: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+    fn read(path: &str) -> Option<Self> {
+        Self::read_kind(path, STT_FUNC)
+    }
+
+    /// The symbols of the type `kind`, i.e. `STT_OBJECT` for the globals
+    fn read_kind(path: &str, kind: u8) -> Option<Self> {
+        let elf = std::fs::read(path).ok()?;
+        let u16_at = |offset: usize| {
+            Some(u16::from_le_bytes(
+                elf.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let u32_at = |offset: usize| {
+            Some(u32::from_le_bytes(
+                elf.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+        let u64_at = |offset: usize| {
+            Some(u64::from_le_bytes(
+                elf.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+
+        if elf.get(..4)? != b"\x7fELF" || elf[4] != 2 {
+            return None;
+        }
+        let section_headers = u64_at(0x28)? as usize;
+        let section_header_size = u16_at(0x3A)? as usize;
+        let sections = u16_at(0x3C)? as usize;
+        let section = |i: usize| section_headers + i * section_header_size;
+
+        const SHT_SYMTAB: u32 = 2;
+        const SYMBOL_SIZE: usize = 24;
+
+        let symtab = (0..sections)
+            .map(section)
+            .find(|&s| u32_at(s + 4) == Some(SHT_SYMTAB))?;
+        let symbols_start = u64_at(symtab + 0x18)? as usize;
+        let symbols_size = u64_at(symtab + 0x20)? as usize;
+        let strtab = section(u32_at(symtab + 0x28)? as usize);
+        let strings_start = u64_at(strtab + 0x18)? as usize;
+
+        let mut functions = Vec::new();
+        for symbol in (symbols_start..symbols_start + symbols_size).step_by(SYMBOL_SIZE) {
+            let info = *elf.get(symbol + 4)?;
+            let value = u64_at(symbol + 8)?;
+            let size = u64_at(symbol + 16)?;
+            if info & 0xF != kind || value == 0 {
+                continue;
+            }
+            let name_start = strings_start + u32_at(symbol)? as usize;
+            let name = elf.get(name_start..)?;
+            let name_len = name.iter().position(|&b| b == 0)?;
+            let name = String::from_utf8_lossy(&name[..name_len]).into_owned();
+            functions.push((value, size.max(1), name));
+        }
+        functions.sort_unstable_by_key(|&(start, _, _)| start);
+        Some(Self { functions })
+    }
+
+    /// The function containing `address` and the offset in it
+    fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        let i = self
+            .functions
+            .partition_point(|&(start, _, _)| start <= address)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.functions[i];
+        (address < start + size).then(|| (name.as_str(), address - start))
+    }
+
+    /// The address of the first symbol with `name` in its (mangled) name
+    fn find(&self, name: &str) -> Option<u64> {
+        self.functions
+            .iter()
+            .find(|(_, _, symbol)| symbol.contains(name))
+            .map(|&(start, _, _)| start)
+    }
+}
+
+/// A `PT_LOAD` or `PT_NOTE` of a core file
+struct CoreSegment {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+/// The ELF core files of the kernel, as a debugger reads them
+struct CoreFile<'a> {
+    bytes: &'a [u8],
+    segments: Vec<CoreSegment>,
+}
+
+impl<'a> CoreFile<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        let u16_at = |offset: usize| {
+            bytes
+                .get(offset..offset + 2)
+                .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+                .ok_or("the headers are cut")
+        };
+        let u32_at = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or("the headers are cut")
+        };
+        let u64_at = |offset: usize| {
+            bytes
+                .get(offset..offset + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .ok_or("the headers are cut")
+        };
+
+        if !bytes.starts_with(b"\x7fELF\x02\x01") {
+            return Err(String::from("not a 64 bit little endian ELF"));
+        }
+        if u16_at(16)? != core_dump::ET_CORE || u16_at(18)? != core_dump::EM_X86_64 {
+            return Err(String::from("not an x86_64 core file"));
+        }
+        let program_headers = u64_at(32)? as usize;
+        let program_header_size = u16_at(54)? as usize;
+        let count = u16_at(56)? as usize;
+        let mut segments = Vec::with_capacity(count);
+        for i in 0..count {
+            let header = program_headers + i * program_header_size;
+            let segment = CoreSegment {
+                ty: u32_at(header)?,
+                flags: u32_at(header + 4)?,
+                offset: u64_at(header + 8)?,
+                address: u64_at(header + 16)?,
+                file_size: u64_at(header + 32)?,
+                memory_size: u64_at(header + 40)?,
+            };
+            if segment.offset + segment.file_size > bytes.len() as u64 {
+                return Err(format!("segment {i} goes after the end of the file"));
+            }
+            if segment.ty == PT_LOAD && segment.file_size > segment.memory_size {
+                return Err(format!("segment {i} has more in the file than in memory"));
+            }
+            segments.push(segment);
+        }
+        Ok(Self { bytes, segments })
+    }
+
+    fn notes(&self) -> impl Iterator<Item = core_dump::Note<'a>> + '_ {
+        self.segments
+            .iter()
+            .filter(|segment| segment.ty == core_dump::PT_NOTE)
+            .flat_map(|segment| {
+                let start = segment.offset as usize;
+                core_dump::notes(&self.bytes[start..start + segment.file_size as usize])
+            })
+    }
+
+    fn note(&self, name: &str, ty: u32) -> Result<core_dump::Note<'a>, String> {
+        self.notes()
+            .find(|note| note.name == name && note.ty == ty)
+            .ok_or(format!("no {name} note {ty:#x}"))
+    }
+
+    /// The `len` bytes at `address` in the contents, with the flags of their segment, `None`
+    /// if they are not in the file
+    fn read(&self, address: u64, len: u64) -> Option<(&'a [u8], u32)> {
+        let segment = self.segments.iter().find(|segment| {
+            segment.ty == PT_LOAD
+                && segment.address <= address
+                && address + len <= segment.address + segment.file_size
+        })?;
+        let start = (segment.offset + address - segment.address) as usize;
+        Some((&self.bytes[start..start + len as usize], segment.flags))
+    }
+}
+
+/// The stack words that point into functions, these are most likely return addresses
+fn stack_trace<'a>(dump: &CrashDump, symbols: &'a Symbols) -> Vec<(u64, u64, &'a str, u64)> {
+    dump.stack_words()
+        .filter_map(|(at, value)| {
+            let (name, offset) = symbols.resolve(value)?;
+            Some((at, value, name, offset))
+        })
+        .collect()
+}
+
+fn print_dump(dump: &CrashDump) {
+    println!(
+        "Process {} ({}) was killed by exception {}, error: {:#X}, address: {:#X}",
+        dump.header.pid,
+        dump.path,
+        dump.header.vector,
+        dump.header.error_code,
+        dump.header.fault_address
+    );
+    println!("Registers:");
+    for (name, value) in dump.registers.named() {
+        println!("  {name:>6}: {value:016X}");
+    }
+    println!("Maps:");
+    for line in dump.maps.lines() {
+        println!("  {line}");
+    }
+
+    let Some(symbols) = Symbols::read(dump.path) else {
+        println!("[!] could not read the symbols of {}", dump.path);
+        return;
+    };
+    match symbols.resolve(dump.registers.rip) {
+        Some((name, offset)) => println!("Crashed in: {name}+{offset:#x}"),
+        None => println!("Crashed in: ??"),
+    }
+    println!(
+        "Stack ({} bytes from {:#X}):",
+        dump.stack.len(),
+        dump.stack_start
+    );
+    for (at, value, name, offset) in stack_trace(dump, &symbols) {
+        println!("  {at:016X}: {value:016X} {name}+{offset:#x}");
+    }
+}
+
+#[inline(never)]
+fn crash_level_three(depth: u64) -> u64 {
+    // nothing is mapped there in userspace
+    let address = black_box(0xDEAD_0000usize) as *mut u64;
+    unsafe { core::ptr::write_volatile(address, depth) };
+    depth
+}
+
+#[inline(never)]
+fn crash_level_two(depth: u64) -> u64 {
+    black_box(crash_level_three(depth + 1)) + 1
+}
+
+#[inline(never)]
+fn crash_level_one(depth: u64) -> u64 {
+    black_box(crash_level_two(depth + 1)) + 1
+}
+
+fn self_test() -> Result<(), String> {
+    let mut child = std::process::Command::new(PROGRAM_PATH)
+        .arg("--crash")
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))?;
+    let pid = child.id();
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    if status.code() != Some(EXIT_CODE_CRASH) {
+        return Err(format!(
+            "expected exit code {EXIT_CODE_CRASH}, got {status:?}"
+        ));
+    }
+
+    let path = format!("/tmp/crash-{pid}-crashinfo.dump");
+    let bytes = std::fs::read(&path).map_err(|e| format!("{path}: {e}"))?;
+    let dump = CrashDump::parse(&bytes).map_err(|e| format!("{path}: {e:?}"))?;
+    if dump.header.pid != pid as u64 || dump.header.vector != 14 {
+        return Err(format!("unexpected header {:?}", dump.header));
+    }
+    if dump.header.fault_address != 0xDEAD_0000 {
+        return Err(format!(
+            "unexpected fault address {:#X}",
+            dump.header.fault_address
+        ));
+    }
+    if !dump.maps.contains("[stack]") {
+        return Err(format!(
+            "the stack is missing from the maps:\n{}",
+            dump.maps
+        ));
+    }
+
+    let symbols = Symbols::read(dump.path).ok_or("could not read the symbols")?;
+    let crashed_in = symbols.resolve(dump.registers.rip).map(|(name, _)| name);
+    if !crashed_in.is_some_and(|name| name.contains("crash_level_three")) {
+        return Err(format!(
+            "expected to crash in crash_level_three, got {crashed_in:?}"
+        ));
+    }
+    let trace = stack_trace(&dump, &symbols);
+    for expected in ["crash_level_two", "crash_level_one"] {
+        if !trace.iter().any(|(_, _, name, _)| name.contains(expected)) {
+            print_dump(&dump);
+            return Err(format!("{expected} is missing from the stack"));
+        }
+    }
+
+    std::fs::remove_file(&path).map_err(|e| format!("{path}: {e}"))?;
+    Ok(())
+}
+
+/// Sets the core limit of this process, the children get it
+fn set_core_limit(limit: u64) -> Result<(), String> {
+    let pid = std::process::id() as u64;
+    unsafe { call_syscall!(SYS_SET_RLIMIT, pid, Resource::CoreSize as u64, limit) }
+        .map(|_| ())
+        .map_err(|e| format!("set core limit: {e:?}"))
+}
+
+/// Runs `--crash` as a child with the core `limit`, returns its pid and its core file
+fn crash_with_core(limit: u64) -> Result<(u64, Vec<u8>), String> {
+    set_core_limit(limit)?;
+    let mut child = std::process::Command::new(PROGRAM_PATH)
+        .arg("--crash")
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))?;
+    let pid = child.id();
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    if status.code() != Some(EXIT_CODE_CRASH) {
+        return Err(format!(
+            "expected exit code {EXIT_CODE_CRASH}, got {status:?}"
+        ));
+    }
+
+    let path = format!("/tmp/core.{pid}");
+    let bytes = std::fs::read(&path).map_err(|e| format!("{path}: {e}"))?;
+    std::fs::remove_file(&path).map_err(|e| format!("{path}: {e}"))?;
+    let dump_path = format!("/tmp/crash-{pid}-crashinfo.dump");
+    std::fs::remove_file(&dump_path).map_err(|e| format!("{dump_path}: {e}"))?;
+    Ok((pid as u64, bytes))
+}
+
+fn core_test() -> Result<(), String> {
+    let globals =
+        Symbols::read_kind(PROGRAM_PATH, STT_OBJECT).ok_or("could not read the symbols")?;
+    let marker = globals.find("CORE_MARKER").ok_or("no CORE_MARKER symbol")?;
+
+    let (pid, bytes) = crash_with_core(RLIMIT_INFINITY)?;
+    let core = CoreFile::parse(&bytes)?;
+    let status = PrStatus::from_bytes(core.note("CORE", NT_PRSTATUS)?.desc)
+        .ok_or("NT_PRSTATUS is too small")?;
+    if status.pid as u64 != pid || status.signal != 11 {
+        return Err(format!(
+            "NT_PRSTATUS: pid {}, signal {}",
+            status.pid, status.signal
+        ));
+    }
+    let functions = Symbols::read(PROGRAM_PATH).ok_or("could not read the symbols")?;
+    let crashed_in = functions.resolve(status.regs.rip).map(|(name, _)| name);
+    if !crashed_in.is_some_and(|name| name.contains("crash_level_three")) {
+        return Err(format!("NT_PRSTATUS: rip in {crashed_in:?}"));
+    }
+    let files = core.note("CORE", NT_FILE)?;
+    if !files
+        .desc
+        .windows(PROGRAM_PATH.len())
+        .any(|w| w == PROGRAM_PATH.as_bytes())
+    {
+        return Err(String::from("the program is missing from NT_FILE"));
+    }
+    let info = CoreInfo::from_bytes(core.note("KERNEL", NT_CORE_INFO)?.desc)
+        .ok_or("NT_CORE_INFO is too small")?;
+    if info.truncated != 0 || info.fault_address != 0xDEAD_0000 {
+        return Err(format!("unexpected {info:?}"));
+    }
+
+    // the global, at its address in the program, in a writable segment of the core
+    let (value, flags) = core
+        .read(marker, 8)
+        .ok_or(format!("{marker:#x} is not in the core"))?;
+    let value = u64::from_le_bytes(value.try_into().unwrap());
+    if value != CORE_MARKER_VALUE || flags & PF_W == 0 {
+        return Err(format!(
+            "CORE_MARKER is {value:#x} (flags {flags:#x}), expected {CORE_MARKER_VALUE:#x}"
+        ));
+    }
+
+    // cut, but still a valid core, saying how much is missing
+    let (_, bytes) = crash_with_core(SMALL_CORE_LIMIT)?;
+    let core = CoreFile::parse(&bytes)?;
+    let info = CoreInfo::from_bytes(core.note("KERNEL", NT_CORE_INFO)?.desc)
+        .ok_or("NT_CORE_INFO is too small")?;
+    if info.size_limit != SMALL_CORE_LIMIT || info.truncated == 0 {
+        return Err(format!("the small core: {info:?}"));
+    }
+    if bytes.len() as u64 > SMALL_CORE_LIMIT {
+        return Err(format!("the small core is {} bytes", bytes.len()));
+    }
+    let missing: u64 = core
+        .segments
+        .iter()
+        .filter(|segment| segment.ty == PT_LOAD)
+        .map(|segment| segment.memory_size - segment.file_size)
+        .sum();
+    if missing < info.truncated {
+        return Err(format!(
+            "the small core: {missing} bytes missing from the segments, {} in NT_CORE_INFO",
+            info.truncated
+        ));
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    if args.len() != 2 {
+        println!("Usage: {} <dump> | --crash | --test | --core-test", args[0]);
+        return ExitCode::FAILURE;
+    }
+
+    match args[1].as_str() {
+        "--crash" => {
+            CORE_MARKER.store(black_box(CORE_MARKER_VALUE), Ordering::Relaxed);
+            black_box(crash_level_one(1));
+            println!("[!] error: did not crash");
+            ExitCode::FAILURE
+        }
+        "--test" => match self_test() {
+            Ok(()) => {
+                println!("crashinfo: test passed");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                println!("[!] crashinfo test failed: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        "--core-test" => {
+            let result = core_test();
+            // back to no core files, whatever happened
+            set_core_limit(0).ok();
+            match result {
+                Ok(()) => {
+                    println!("crashinfo: core test passed");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] crashinfo core test failed: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        path => {
+            let bytes = match std::fs::read(path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("[!] error: {path}: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            match CrashDump::parse(&bytes) {
+                Ok(dump) => {
+                    print_dump(&dump);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] error: {path}: {e:?}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode, time::Duration};
+
+use kernel_user_link::{
+    call_syscall,
+    file::{LOCK_EXCLUSIVE, LOCK_NONBLOCK, LOCK_SHARED, LOCK_UNLOCK, OPEN_CREATE},
+    syscalls::{SyscallError, SYS_CLOSE, SYS_FLOCK, SYS_OPEN, SYS_READ, SYS_WRITE},
+};
+
+// the spins between two appended lines, so a writer without the lock would be in the middle
+const SPINS_PER_LINE: u64 = 200_000;
+// the sleep between two tries of waiting for a path
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+fn usage() -> ExitCode {
+    println!(
+        "Usage: flock [-s] [-n] [-W path]... [-m path] [-a tag:count] [-k lines] [-h path] [-u | -e] <file>"
+    );
+    ExitCode::FAILURE
+}
+
+fn open(path: &str, flags: u64) -> Result<usize, SyscallError> {
+    let path = CString::new(path).unwrap();
+    unsafe {
+        call_syscall!(
+            SYS_OPEN,
+            path.as_ptr() as u64, // path
+            0,                    // access_mode
+            flags,                // flags
+        )
+        .map(|fd| fd as usize)
+    }
+}
+
+fn close(fd: usize) {
+    unsafe { call_syscall!(SYS_CLOSE, fd).ok() };
+}
+
+fn flock(fd: usize, operation: u64) -> Result<(), SyscallError> {
+    unsafe { call_syscall!(SYS_FLOCK, fd, operation).map(|_| ()) }
+}
+
+fn spin(count: u64) {
+    for _ in 0..count {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for another process to create `path`, it sleeps between tries
+fn wait_for(path: &str) {
+    loop {
+        if let Ok(fd) = open(path, 0) {
+            close(fd);
+            return;
+        }
+        user_std::time::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Reads from the position of `fd` to the end
+fn read_rest(fd: usize) -> Result<Vec<u8>, SyscallError> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let read = unsafe {
+            call_syscall!(
+                SYS_READ,
+                fd,                      // fd
+                buf.as_mut_ptr() as u64, // buf
+                buf.len() as u64,        // size
+            )?
+        };
+        if read == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..read as usize]);
+    }
+}
+
+fn write_all(fd: usize, data: &[u8]) -> Result<(), SyscallError> {
+    let mut written = 0;
+    while written < data.len() {
+        let rest = &data[written..];
+        written += unsafe {
+            call_syscall!(
+                SYS_WRITE,
+                fd,                   // fd
+                rest.as_ptr() as u64, // buf
+                rest.len() as u64,    // size
+            )?
+        } as usize;
+    }
+    Ok(())
+}
+
+/// Appends `count` lines `<tag> <n>`, each after reading to the end, so the writers sharing
+/// the file without the lock write over each other
+fn append(fd: usize, tag: &str, count: u64) -> Result<(), SyscallError> {
+    for n in 0..count {
+        read_rest(fd)?;
+        spin(SPINS_PER_LINE);
+        write_all(fd, format!("{tag} {n}\n").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Checks the file has `lines` lines, and that the lines of each tag are together and in order
+fn check(fd: usize, lines: usize) -> Result<bool, SyscallError> {
+    let data = read_rest(fd)?;
+    let text = String::from_utf8_lossy(&data);
+    let mut count = 0;
+    let mut done_tags: Vec<&str> = Vec::new();
+    let mut current: Option<(&str, u64)> = None;
+    for line in text.lines() {
+        count += 1;
+        let Some((tag, n)) = line.split_once(' ') else {
+            println!("[!] flock: bad line {line:?}");
+            return Ok(false);
+        };
+        let n = n.parse::<u64>().unwrap_or(u64::MAX);
+        match current {
+            Some((current_tag, last)) if current_tag == tag => {
+                if n != last + 1 {
+                    println!("[!] flock: {tag} {n} after {tag} {last}");
+                    return Ok(false);
+                }
+            }
+            _ => {
+                if done_tags.contains(&tag) || n != 0 {
+                    println!("[!] flock: the lines of {tag} are split");
+                    return Ok(false);
+                }
+                if let Some((current_tag, _)) = current {
+                    done_tags.push(current_tag);
+                }
+            }
+        }
+        current = Some((tag, n));
+    }
+    if count != lines {
+        println!("[!] flock: {count} lines, expected {lines}");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Flock shell program
+///
+/// Usage: flock [-s] [-n] [-W path]... [-m path] [-a tag:count] [-k lines] [-h path] [-u | -e] <file>
+///
+/// Takes the lock of the file, exclusive unless `-s`, waiting for it unless `-n`, then:
+/// - `-W path`: waits for each path to exist before taking the lock
+/// - `-m path`: creates the path once the lock is ours
+/// - `-a tag:count`: appends `count` lines `<tag> <n>` slowly
+/// - `-k lines`: checks the file has that many lines, the lines of each tag together
+/// - `-h path`: holds the lock until the path exists
+///
+/// The lock goes when the file is closed, or with `-u` by unlocking it first, or with `-e` when
+/// the process exits with the file open
+fn main() -> ExitCode {
+    let mut shared = false;
+    let mut block = true;
+    let mut wait_paths = Vec::new();
+    let mut marker = None;
+    let mut appends = None;
+    let mut check_lines = None;
+    let mut hold_until = None;
+    let mut unlock = false;
+    let mut exit_open = false;
+    let mut file = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" => shared = true,
+            "-n" => block = false,
+            "-u" => unlock = true,
+            "-e" => exit_open = true,
+            "-W" | "-m" | "-a" | "-k" | "-h" => {
+                let Some(value) = args.next() else {
+                    return usage();
+                };
+                match arg.as_str() {
+                    "-W" => wait_paths.push(value),
+                    "-m" => marker = Some(value),
+                    "-h" => hold_until = Some(value),
+                    "-a" => {
+                        let Some((tag, count)) = value.split_once(':') else {
+                            return usage();
+                        };
+                        let Ok(count) = count.parse::<u64>() else {
+                            return usage();
+                        };
+                        appends = Some((String::from(tag), count));
+                    }
+                    _ => match value.parse::<usize>() {
+                        Ok(lines) => check_lines = Some(lines),
+                        Err(_) => return usage(),
+                    },
+                }
+            }
+            _ if arg.starts_with('-') => return usage(),
+            _ if file.is_none() => file = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(file) = file else {
+        return usage();
+    };
+    if unlock && exit_open {
+        return usage();
+    }
+
+    for path in &wait_paths {
+        wait_for(path);
+    }
+    let fd = match open(&file, OPEN_CREATE) {
+        Ok(fd) => fd,
+        Err(e) => {
+            println!("[!] flock: {file}: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut operation = if shared { LOCK_SHARED } else { LOCK_EXCLUSIVE };
+    if !block {
+        operation |= LOCK_NONBLOCK;
+    }
+    if let Err(e) = flock(fd, operation) {
+        println!("[!] flock: {file}: {e:?}");
+        return ExitCode::FAILURE;
+    }
+    if let Some(marker) = &marker {
+        match open(marker, OPEN_CREATE) {
+            Ok(marker_fd) => close(marker_fd),
+            Err(e) => println!("[!] flock: {marker}: {e:?}"),
+        }
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    if let Some((tag, count)) = &appends {
+        if let Err(e) = append(fd, tag, *count) {
+            println!("[!] flock: {file}: {e:?}");
+            status = ExitCode::FAILURE;
+        }
+    }
+    if let Some(lines) = check_lines {
+        match check(fd, lines) {
+            Ok(true) => {}
+            Ok(false) => status = ExitCode::FAILURE,
+            Err(e) => {
+                println!("[!] flock: {file}: {e:?}");
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    if let Some(path) = &hold_until {
+        wait_for(path);
+    }
+
+    if unlock {
+        if let Err(e) = flock(fd, LOCK_UNLOCK) {
+            println!("[!] flock: {file}: {e:?}");
+            status = ExitCode::FAILURE;
+        }
+    }
+    if !exit_open {
+        close(fd);
+    }
+    status
+}
@@ -0,0 +1,285 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, hint::black_box, process::ExitCode};
+
+use kernel_user_link::syscalls::SyscallError;
+use user_std::{io, process};
+
+// read into the shared buffer by the child, the kernel writes there, not the child itself
+const FILE_PATH: &str = "/message.txt";
+// a few pages, so the writes land on different ones
+const BUF_SIZE: usize = 3 * 4096;
+const DEFAULT_CHILDREN: usize = 4;
+// the exit code of the child when `exec` fails, like the shells for a missing program
+const EXEC_FAILED: i32 = 127;
+// created by `-w` when its child can exit
+const GO_PATH: &str = "/tmp/fork_go";
+const INIT_PID: u64 = 0;
+
+static mut SHARED: [u8; BUF_SIZE] = [0; BUF_SIZE];
+
+fn usage() -> ExitCode {
+    println!("Usage: fork [-n children] | -e <program> [args...] | -w");
+    ExitCode::FAILURE
+}
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+fn shared() -> &'static mut [u8; BUF_SIZE] {
+    // SAFETY: only the main thread uses it
+    unsafe { &mut *core::ptr::addr_of_mut!(SHARED) }
+}
+
+fn has_pattern(buf: &[u8], offset: u8) -> bool {
+    buf.iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == pattern(i).wrapping_add(offset))
+}
+
+/// Reads the start of [`FILE_PATH`] into `buf` with `SYS_READ`
+fn read_file(buf: &mut [u8]) -> Result<usize, SyscallError> {
+    let path = CString::new(FILE_PATH).unwrap();
+    let fd = unsafe { io::syscall_open(&path, 0, 0) }?;
+    let read = unsafe { io::syscall_read(fd, buf) };
+    unsafe { io::syscall_close(fd).ok() };
+    read.map(|read| read as usize)
+}
+
+/// What the child `index` checks, it must see the memory of the parent as it was when it
+/// forked, then it changes it, the exit code is the first check that failed, `0` if none
+fn child(index: usize, heap: &mut [u8], stack: &mut [u8; 64]) -> i32 {
+    if !has_pattern(shared(), 0) {
+        return 1;
+    }
+    if !has_pattern(heap, 0) || !has_pattern(stack, 0) {
+        return 2;
+    }
+    for byte in shared().iter_mut().chain(heap.iter_mut()) {
+        *byte = byte.wrapping_add(index as u8 + 1);
+    }
+    stack.fill(0xFF);
+    if !has_pattern(&shared()[..], index as u8 + 1) || !has_pattern(heap, index as u8 + 1) {
+        return 3;
+    }
+    // a page in the middle, written by the kernel
+    match read_file(&mut shared()[4096..]) {
+        Ok(read) if read > 0 => {}
+        _ => return 4,
+    }
+    // and a child of our own, which sees our changes
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            let ok = has_pattern(&shared()[..4096], index as u8 + 1);
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(0) => 0,
+            _ => 5,
+        },
+        Err(_) => 6,
+    }
+}
+
+/// Forks a child which replaces itself with `program`, returns the exit code of the child,
+/// which has the same pid in both programs
+fn fork_exec(program: &str, args: &[String]) -> ExitCode {
+    let path = CString::new(program).unwrap();
+    let argv = std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| CString::new(arg).unwrap())
+        .collect::<Vec<_>>();
+    let argv_ptrs = argv
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain(std::iter::once(core::ptr::null()))
+        .collect::<Vec<_>>();
+
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            let e = unsafe { process::exec(&path, &argv_ptrs) };
+            println!("[!] fork: exec {program}: {e:?}");
+            std::process::exit(EXEC_FAILED);
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(code) => ExitCode::from(code as u8),
+            Err(e) => {
+                println!("[!] fork: {program} (pid {pid}): {e:?}");
+                ExitCode::FAILURE
+            }
+        },
+        Err(e) => {
+            println!("[!] fork: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Forks a child which exits with `code` once `go` returns `true`
+fn fork_exiting(code: i32, go: impl Fn() -> bool) -> Result<u64, String> {
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            while !go() {
+                core::hint::spin_loop();
+            }
+            std::process::exit(code)
+        }
+        Ok(pid) => Ok(pid),
+        Err(e) => Err(format!("fork: {e:?}")),
+    }
+}
+
+/// The exit of a child is returned once, whether it's waited for before or after it exits
+fn test_wait() -> Result<(), String> {
+    // the errors can't be compared, their fields are only printed
+    let expect = |what: &str, result: Result<i32, SyscallError>, expected: Result<i32, _>| {
+        if format!("{result:?}") == format!("{expected:?}") {
+            Ok(())
+        } else {
+            Err(format!("{what}: expected {expected:?}, got {result:?}"))
+        }
+    };
+
+    for code in [3, -5] {
+        let pid = fork_exiting(code, || true)?;
+        expect("waiting", unsafe { process::wait(pid) }, Ok(code))?;
+        expect(
+            "waiting again",
+            unsafe { process::wait(pid) },
+            Err(SyscallError::PidNotFound),
+        )?;
+    }
+
+    std::fs::remove_file(GO_PATH).ok();
+    let pid = fork_exiting(7, || std::fs::File::open(GO_PATH).is_ok())?;
+    expect(
+        "a running child",
+        unsafe { process::wait_for_pid(pid, false) },
+        Err(SyscallError::ProcessStillRunning),
+    )?;
+    std::fs::write(GO_PATH, "go").map_err(|e| format!("{GO_PATH}: {e}"))?;
+    // it's kept once it exited, until we take it
+    let result = loop {
+        match unsafe { process::wait_for_pid(pid, false) } {
+            Err(SyscallError::ProcessStillRunning) => core::hint::spin_loop(),
+            result => break result,
+        }
+    };
+    std::fs::remove_file(GO_PATH).map_err(|e| format!("{GO_PATH}: {e}"))?;
+    expect("an exited child", result, Ok(7))?;
+    expect(
+        "an exited child again",
+        unsafe { process::wait_for_pid(pid, false) },
+        Err(SyscallError::PidNotFound),
+    )?;
+
+    let own_pid = std::process::id() as u64;
+    for (what, pid) in [("ourselves", own_pid), ("init", INIT_PID)] {
+        expect(
+            what,
+            unsafe { process::wait(pid) },
+            Err(SyscallError::PidNotFound),
+        )?;
+    }
+    Ok(())
+}
+
+/// Fork shell program
+///
+/// Usage: fork [-n children]
+///        fork -e <program> [args...]
+///        fork -w
+///
+/// Forks `children` processes (4 by default), each checks that it sees the memory of the
+/// parent, then writes to it and forks again, the parent changes its memory before waiting
+/// for them, then checks that none of their writes reached it. Prints the first check that
+/// failed in each child.
+///
+/// With `-e`, the child runs `program` with `exec` instead, and the exit code is the one
+/// of `program`, or 127 if it couldn't be run.
+///
+/// With `-w`, checks the exit codes of the children, from before and after they exit, and
+/// that each is returned once, and only to the parent.
+fn main() -> ExitCode {
+    let all_args = std::env::args().skip(1).collect::<Vec<_>>();
+    match all_args.as_slice() {
+        [flag, program, args @ ..] if flag == "-e" => return fork_exec(program, args),
+        [flag] if flag == "-w" => {
+            return match test_wait() {
+                Ok(()) => {
+                    println!("fork: the exits of the children were returned once");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] fork: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let mut count = DEFAULT_CHILDREN;
+    let mut args = all_args.into_iter();
+    while let Some(arg) = args.next() {
+        match (
+            arg.as_str(),
+            args.next().map(|value| value.parse::<usize>()),
+        ) {
+            ("-n", Some(Ok(value))) if value > 0 => count = value,
+            _ => return usage(),
+        }
+    }
+
+    for (i, byte) in shared().iter_mut().enumerate() {
+        *byte = pattern(i);
+    }
+    let mut heap: Vec<u8> = (0..BUF_SIZE).map(pattern).collect();
+    let mut stack = [0u8; 64];
+    for (i, byte) in stack.iter_mut().enumerate() {
+        *byte = pattern(i);
+    }
+
+    let mut children = Vec::new();
+    for index in 0..count {
+        match unsafe { process::fork() } {
+            Ok(0) => std::process::exit(child(index, &mut heap, black_box(&mut stack))),
+            Ok(pid) => children.push((index, pid)),
+            Err(e) => {
+                println!("[!] fork: {e:?}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    // the children may not have run yet, they must still see the old memory
+    shared()[0] = shared()[0].wrapping_add(100);
+    heap[BUF_SIZE - 1] = heap[BUF_SIZE - 1].wrapping_add(100);
+
+    let mut ok = true;
+    for (index, pid) in children {
+        match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(0) => {}
+            Ok(code) => {
+                println!("[!] fork: child {index} (pid {pid}) failed check {code}");
+                ok = false;
+            }
+            Err(e) => {
+                println!("[!] fork: child {index} (pid {pid}): {e:?}");
+                ok = false;
+            }
+        }
+    }
+    shared()[0] = shared()[0].wrapping_sub(100);
+    heap[BUF_SIZE - 1] = heap[BUF_SIZE - 1].wrapping_sub(100);
+    if !has_pattern(&shared()[..], 0) || !has_pattern(&heap, 0) || !has_pattern(&stack, 0) {
+        println!("[!] fork: the writes of a child reached the parent");
+        ok = false;
+    }
+    if ok {
+        println!("fork: {count} children saw the memory of the parent, and kept their writes");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
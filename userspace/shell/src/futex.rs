@@ -0,0 +1,253 @@
+#![feature(restricted_std)]
+
+use std::{
+    process::{Command, ExitCode},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use kernel_user_link::{
+    process::{Resource, EXIT_CODE_CPU_LIMIT},
+    syscalls::{SyscallArgError, SyscallError},
+};
+use user_std::{
+    process,
+    signal::{self, Action, SIGUSR1},
+    sync::{futex, Condvar, Mutex},
+    syscalls, thread,
+    time::{self, Instant},
+};
+
+const PROGRAM_PATH: &str = "/futex";
+const THREADS: u64 = 4;
+const INCREMENTS: u64 = 5_000;
+// how long a thread holds the mutex for the others to block on it
+const HOLD_TIME: Duration = Duration::from_millis(300);
+// for `--limited`, it would use more than its CPU limit if the wait spun
+const LIMITED_HOLD: Duration = Duration::from_secs(1);
+const LIMITED_CPU_MS: u64 = 200;
+
+static FUTEX: AtomicU32 = AtomicU32::new(0);
+static COUNTER: Mutex<u64> = Mutex::new(0);
+// the items of `test_condvar`, and whether the producer is done
+static QUEUE: Mutex<(Vec<u64>, bool)> = Mutex::new((Vec::new(), false));
+static QUEUE_CHANGED: Condvar = Condvar::new();
+// the signals `count_signal` got
+static SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+fn usage() -> ExitCode {
+    println!("Usage: futex --test");
+    ExitCode::FAILURE
+}
+
+fn own_pid() -> u64 {
+    std::process::id() as u64
+}
+
+extern "C" fn count_signal(_sig: u64) {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Starts a thread, the ones of the tests only use the atomics and the locks of `user_std`
+fn spawn_thread<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<thread::JoinHandle<T>, String> {
+    // SAFETY: see above
+    unsafe { thread::spawn(f) }.map_err(|e| format!("thread: {e:?}"))
+}
+
+fn join<T>(handle: thread::JoinHandle<T>) -> Result<T, String> {
+    handle.join().map_err(|e| format!("join: {e:?}"))
+}
+
+/// The errors of the syscalls, and the waits that return right away
+fn test_rules() -> Result<(), String> {
+    let addr = FUTEX.as_ptr() as u64;
+    match unsafe { syscalls::futex_wait(addr, 1) } {
+        Err(SyscallError::WouldBlock) => {}
+        result => return Err(format!("a wait for another value gave {result:?}")),
+    }
+    match unsafe { syscalls::futex_wait(addr + 1, 0) } {
+        Err(SyscallError::InvalidArgument(Some(SyscallArgError::InvalidUserPointer), ..)) => {}
+        result => return Err(format!("an unaligned futex gave {result:?}")),
+    }
+    match unsafe { syscalls::futex_wake(0, 1) } {
+        Err(SyscallError::InvalidArgument(Some(SyscallArgError::InvalidUserPointer), ..)) => {}
+        result => return Err(format!("a null futex gave {result:?}")),
+    }
+    match unsafe { syscalls::futex_wait(addr, u64::MAX) } {
+        Err(SyscallError::InvalidArgument(None, Some(SyscallArgError::GeneralInvalid), ..)) => {}
+        result => return Err(format!("a value over 32 bits gave {result:?}")),
+    }
+    match futex::wake(&FUTEX, 1) {
+        0 => Ok(()),
+        woken => Err(format!("a wake without waiters woke {woken}")),
+    }
+}
+
+/// A thread waits until it's woken, and a handled signal stops the wait early
+fn test_wait_wake() -> Result<(), String> {
+    FUTEX.store(0, Ordering::SeqCst);
+    let waiter = spawn_thread(|| {
+        let result = futex::wait(&FUTEX, 0);
+        (result, FUTEX.load(Ordering::SeqCst))
+    })?;
+    time::sleep(Duration::from_millis(100));
+    FUTEX.store(1, Ordering::SeqCst);
+    let woken = futex::wake(&FUTEX, u64::MAX);
+    let (result, value) = join(waiter)?;
+    if woken != 1 || result.is_err() || value != 1 {
+        return Err(format!(
+            "woke {woken}, the wait gave {result:?}, saw {value}"
+        ));
+    }
+
+    SIGNALS.store(0, Ordering::SeqCst);
+    unsafe { signal::set_action(SIGUSR1, Action::Handle(count_signal)) }
+        .map_err(|e| format!("sigaction: {e:?}"))?;
+    let pid = own_pid();
+    let sender = spawn_thread(move || {
+        time::sleep(Duration::from_millis(100));
+        unsafe { signal::kill(pid, SIGUSR1) }
+    })?;
+    let result = futex::wait(&FUTEX, 1);
+    join(sender)?.map_err(|e| format!("kill: {e:?}"))?;
+    unsafe { signal::set_action(SIGUSR1, Action::Default) }
+        .map_err(|e| format!("sigaction: {e:?}"))?;
+    if !matches!(result, Err(SyscallError::Interrupted)) || SIGNALS.load(Ordering::SeqCst) != 1 {
+        return Err(format!("the interrupted wait gave {result:?}"));
+    }
+    Ok(())
+}
+
+/// No increment is lost, with the threads blocking on the mutex while one sleeps holding it
+fn test_mutex() -> Result<(), String> {
+    *COUNTER.lock() = 0;
+    let holder = COUNTER.lock();
+    let mut handles = Vec::new();
+    for _ in 0..THREADS {
+        handles.push(spawn_thread(|| {
+            for _ in 0..INCREMENTS {
+                *COUNTER.lock() += 1;
+            }
+        })?);
+    }
+    let start = Instant::now();
+    time::sleep(HOLD_TIME);
+    drop(holder);
+    for handle in handles {
+        join(handle)?;
+    }
+    let count = *COUNTER.lock();
+    if count != THREADS * INCREMENTS {
+        return Err(format!("counted {count}, not {}", THREADS * INCREMENTS));
+    }
+    if start.elapsed() < HOLD_TIME {
+        return Err("the threads didn't wait for the mutex".into());
+    }
+    Ok(())
+}
+
+/// The consumers wait for the items of the producer, and all of them for its end
+fn test_condvar() -> Result<(), String> {
+    const ITEMS: u64 = 1000;
+    *QUEUE.lock() = (Vec::new(), false);
+    let mut consumers = Vec::new();
+    for _ in 0..THREADS {
+        consumers.push(spawn_thread(|| {
+            let mut sum = 0;
+            loop {
+                let mut queue = QUEUE_CHANGED
+                    .wait_while(QUEUE.lock(), |(items, done)| items.is_empty() && !*done);
+                match queue.0.pop() {
+                    Some(item) => sum += item,
+                    None => return sum,
+                }
+            }
+        })?);
+    }
+    for item in 1..=ITEMS {
+        QUEUE.lock().0.push(item);
+        QUEUE_CHANGED.notify_one();
+        if item % 100 == 0 {
+            time::sleep(Duration::from_millis(10));
+        }
+    }
+    QUEUE.lock().1 = true;
+    QUEUE_CHANGED.notify_all();
+
+    let mut sum = 0;
+    for consumer in consumers {
+        sum += join(consumer)?;
+    }
+    if sum != ITEMS * (ITEMS + 1) / 2 {
+        return Err(format!("the consumers got {sum}"));
+    }
+    Ok(())
+}
+
+/// A thread blocked on the mutex uses no CPU time
+fn test_no_spinning() -> Result<(), String> {
+    let status = Command::new(PROGRAM_PATH)
+        .arg("--limited")
+        .status()
+        .map_err(|e| format!("spawn: {e}"))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(EXIT_CODE_CPU_LIMIT) => Err("the wait for the mutex used the CPU".into()),
+        code => Err(format!("the limited child exited with {code:?}")),
+    }
+}
+
+fn self_test() -> Result<(), String> {
+    test_rules()?;
+    test_wait_wake()?;
+    test_mutex()?;
+    test_condvar()?;
+    test_no_spinning()
+}
+
+/// Waits for a mutex held for longer than its CPU time limit
+fn limited() -> Result<(), String> {
+    unsafe { process::set_rlimit(own_pid(), Resource::CpuTimeMs, LIMITED_CPU_MS) }
+        .map_err(|e| format!("set_rlimit: {e:?}"))?;
+    let holder = COUNTER.lock();
+    let waiter = spawn_thread(|| {
+        drop(COUNTER.lock());
+    })?;
+    time::sleep(LIMITED_HOLD);
+    drop(holder);
+    join(waiter)
+}
+
+/// Futex shell program
+///
+/// Usage: futex --test
+///        futex --limited
+///
+/// `--test` checks the rules of `SYS_FUTEX_WAIT` and `SYS_FUTEX_WAKE`, a wait that is woken and
+/// one a signal stops, that the threads sharing `user_std::sync::Mutex` lose no increment
+/// while they block on it, that the consumers of a `Condvar` queue get all the items, and
+/// with a child (`--limited`) that a thread blocked on the mutex for longer than the CPU time
+/// limit of the process is not killed for it
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        ["--test"] => self_test(),
+        ["--limited"] => limited(),
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => {
+            if args.as_slice() == ["--test"] {
+                println!("futex: test passed");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] futex: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,110 @@
+#![feature(restricted_std)]
+
+use std::{fs::File, io::Read, process::ExitCode};
+
+use user_std::input::{self, ScriptError};
+
+// the reads of a terminal before giving up on the line, the keys are moved to it between
+// processes, so it can take a few switches
+const LINE_TRIES: usize = 1_000_000;
+
+fn usage() -> ExitCode {
+    println!("Usage: input_script [-t <terminal> <line>]... <script>");
+    ExitCode::FAILURE
+}
+
+/// Reads the next line typed on `terminal`, `None` if it doesn't come
+fn read_line(terminal: &mut File) -> Option<String> {
+    let mut line = Vec::new();
+    let mut buf = [0u8; 256];
+    for _ in 0..LINE_TRIES {
+        let n = terminal.read(&mut buf).ok()?;
+        line.extend_from_slice(&buf[..n]);
+        if line.ends_with(b"\n") {
+            line.pop();
+            return Some(String::from_utf8_lossy(&line).into_owned());
+        }
+        if n == 0 {
+            std::hint::spin_loop();
+        }
+    }
+    None
+}
+
+/// Input script shell program
+///
+/// Usage: input_script [-t <terminal> <line>]... <script>
+///
+/// Replays the keyboard and mouse input of the script through `/devices/input_inject`, see
+/// `user_std::input` for its lines, and waits until it's all replayed. Each `-t` then reads
+/// a line from the terminal device, which must be the one given
+fn main() -> ExitCode {
+    let mut expected = Vec::new();
+    let mut script_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" => match (args.next(), args.next()) {
+                (Some(terminal), Some(line)) => expected.push((terminal, line)),
+                _ => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ if script_path.is_none() => script_path = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(script_path) = script_path else {
+        return usage();
+    };
+
+    let script = match std::fs::read_to_string(&script_path) {
+        Ok(script) => script,
+        Err(e) => {
+            println!("[!] input_script: {script_path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // the terminals are opened first, so nothing typed is read by someone else
+    let mut terminals = Vec::new();
+    for (terminal, line) in &expected {
+        match File::open(terminal) {
+            Ok(file) => terminals.push((terminal, file, line)),
+            Err(e) => {
+                println!("[!] input_script: {terminal}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let replayed = input::run_script(&script).and_then(|count| {
+        input::wait_replayed()?;
+        Ok(count)
+    });
+    match replayed {
+        Ok(count) => println!("input_script: replayed {count} records"),
+        Err(ScriptError::InvalidLine(line)) => {
+            println!("[!] input_script: {script_path}:{line}: not an input record");
+            return ExitCode::FAILURE;
+        }
+        Err(ScriptError::Syscall(e)) => {
+            println!("[!] input_script: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for (terminal, file, line) in &mut terminals {
+        match read_line(file) {
+            Some(got) if got == **line => {}
+            Some(got) => {
+                println!("[!] input_script: {terminal}: got {got:?}, expected {line:?}");
+                status = ExitCode::FAILURE;
+            }
+            None => {
+                println!("[!] input_script: {terminal}: no line");
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
@@ -0,0 +1,133 @@
+#![feature(restricted_std)]
+
+use std::process::ExitCode;
+
+use kernel_user_link::{
+    call_syscall,
+    file::{BlockingMode, EVENT_SOURCE_LOW_MEMORY},
+    syscalls::{
+        SyscallError, SYS_BLOCKING_MODE, SYS_CLOSE, SYS_EVENT_CREATE, SYS_INC_HEAP, SYS_READ,
+    },
+};
+
+// the memory taken at each step
+const STEP: usize = 64 * 0x1000;
+
+struct LowMemoryEvent {
+    fd: usize,
+}
+
+impl LowMemoryEvent {
+    fn new() -> Result<Self, SyscallError> {
+        let fd = unsafe { call_syscall!(SYS_EVENT_CREATE, EVENT_SOURCE_LOW_MEMORY)? } as usize;
+        // so we can poll it
+        unsafe { call_syscall!(SYS_BLOCKING_MODE, fd, BlockingMode::None.to_u64())? };
+        Ok(Self { fd })
+    }
+
+    /// The number of times the event was signaled since the last poll
+    fn poll(&self) -> Result<u64, SyscallError> {
+        let mut counter = [0u8; 8];
+        let read = unsafe {
+            call_syscall!(
+                SYS_READ,
+                self.fd,
+                counter.as_mut_ptr() as u64,
+                counter.len() as u64
+            )?
+        };
+        Ok(if read == 0 {
+            0
+        } else {
+            u64::from_le_bytes(counter)
+        })
+    }
+}
+
+impl Drop for LowMemoryEvent {
+    fn drop(&mut self) {
+        unsafe { call_syscall!(SYS_CLOSE, self.fd).ok() };
+    }
+}
+
+fn inc_heap(increment: isize) -> Result<u64, SyscallError> {
+    unsafe { call_syscall!(SYS_INC_HEAP, increment as u64) }
+}
+
+/// What happened while taking memory, printed after it is all given back
+struct Outcome {
+    taken: usize,
+    signaled: u64,
+    // given back first, the rest is given back right after
+    given_back: usize,
+    stopped_by: Option<SyscallError>,
+}
+
+fn balloon(event: &LowMemoryEvent) -> Result<Outcome, SyscallError> {
+    let mut outcome = Outcome {
+        taken: 0,
+        signaled: event.poll()?,
+        given_back: 0,
+        stopped_by: None,
+    };
+    while outcome.signaled == 0 {
+        if let Err(e) = inc_heap(STEP as isize) {
+            outcome.stopped_by = Some(e);
+            break;
+        }
+        outcome.taken += STEP;
+        outcome.signaled = event.poll()?;
+    }
+
+    // react by freeing half, like a cache that drops its cold half
+    let half = outcome.taken / 2 / STEP * STEP;
+    if half != 0 {
+        inc_heap(-(half as isize))?;
+        outcome.given_back = half;
+    }
+    inc_heap(-((outcome.taken - outcome.given_back) as isize))?;
+    Ok(outcome)
+}
+
+/// Lowmem shell program
+///
+/// Usage: lowmem
+///
+/// A cooperative reclaim demo, it takes memory until the kernel signals the low memory event,
+/// then gives it back. The event is polled in non-blocking mode after each step.
+///
+/// The memory is taken with `SYS_INC_HEAP` after the end of the heap of `std`, which can't give
+/// memory back, so nothing must allocate until it is all given back.
+fn main() -> ExitCode {
+    let event = match LowMemoryEvent::new() {
+        Ok(event) => event,
+        Err(e) => {
+            println!("[!] error: could not create the low memory event: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // everything printed is allocated here, before the heap is used for the balloon
+    println!("lowmem: taking memory in steps of {} KB", STEP / 1024);
+
+    let outcome = match balloon(&event) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            println!("[!] error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(e) = outcome.stopped_by {
+        println!(
+            "[!] could not take more memory after {} KB ({e:?}) before getting low on memory",
+            outcome.taken / 1024
+        );
+        return ExitCode::FAILURE;
+    }
+    println!(
+        "lowmem: signaled {} time(s) after taking {} KB, gave back {} KB then the rest",
+        outcome.signaled,
+        outcome.taken / 1024,
+        outcome.given_back / 1024
+    );
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,194 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode};
+
+use kernel_user_link::{process::EXIT_CODE_CRASH, syscalls::SyscallError};
+use user_std::{
+    io,
+    process::{self, PROT_READ, PROT_WRITE},
+};
+
+const PAGE: usize = 4096;
+const DEFAULT_PATH: &str = "/shell";
+// given to `SYS_WRITE` from a part of the mapping never touched by us
+const KERNEL_READ_LEN: usize = 64;
+
+fn usage() -> ExitCode {
+    println!("Usage: mmap [file]");
+    ExitCode::FAILURE
+}
+
+fn open(path: &str) -> Result<usize, String> {
+    let path = CString::new(path).unwrap();
+    unsafe { io::syscall_open(&path, 0, 0) }.map_err(|e| format!("open: {e:?}"))
+}
+
+/// Maps `len` bytes of `fd` from `offset`, the pages as a slice
+fn map(fd: usize, offset: usize, len: usize) -> Result<&'static [u8], String> {
+    let addr = unsafe { process::mmap(fd, offset as u64, len as u64, PROT_READ) }
+        .map_err(|e| format!("map at {offset}: {e:?}"))?;
+    // SAFETY: the kernel mapped all of it, and it stays until `munmap`
+    Ok(unsafe { core::slice::from_raw_parts(addr, len) })
+}
+
+fn unmap(pages: &[u8]) -> Result<(), String> {
+    unsafe { process::munmap(pages.as_ptr()) }.map_err(|e| format!("unmap: {e:?}"))
+}
+
+/// The mapping must have the bytes of the file, and zeroes after its end
+fn check_content(pages: &[u8], content: &[u8]) -> Result<(), String> {
+    let (start, end) = pages.split_at(content.len().min(pages.len()));
+    if start != &content[..start.len()] {
+        return Err(String::from("the mapping is not the content of the file"));
+    }
+    if end.iter().any(|&byte| byte != 0) {
+        return Err(String::from(
+            "the mapping is not zeroed after the end of the file",
+        ));
+    }
+    Ok(())
+}
+
+/// The arguments the kernel must refuse, a directory, an unaligned offset and an empty mapping
+fn check_errors(fd: usize) -> Result<(), String> {
+    let dir = open("/")?;
+    let results = [
+        unsafe { process::mmap(dir, 0, PAGE as u64, PROT_READ) },
+        unsafe { process::mmap(fd, 1, PAGE as u64, PROT_READ) },
+        unsafe { process::mmap(fd, 0, 0, PROT_READ) },
+    ];
+    unsafe { io::syscall_close(dir).ok() };
+    for (i, result) in results.into_iter().enumerate() {
+        if !matches!(result, Err(SyscallError::InvalidArgument(..))) {
+            return Err(format!("the bad mapping {i} gave {result:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// A child reads the pages it inherited, which we never touched, then crashes writing to them
+fn check_child(pages: &'static [u8], content: &[u8]) -> Result<(), String> {
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            if check_content(pages, content).is_err() {
+                std::process::exit(2);
+            }
+            // SAFETY: it's not, the child must crash here
+            unsafe { (pages.as_ptr() as *mut u8).write_volatile(1) };
+            std::process::exit(3);
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(EXIT_CODE_CRASH) => Ok(()),
+            other => Err(format!("the child ended with {other:?}")),
+        },
+        Err(e) => Err(format!("fork: {e:?}")),
+    }
+}
+
+/// Writes to a writable mapping, which only changes its pages, not the ones of the other
+/// mappings (which share the pages of the page cache with it until the write), or the file
+fn check_private_write(path: &str, pages: &[u8], content: &[u8]) -> Result<(), String> {
+    let fd = open(path)?;
+    let addr = unsafe { process::mmap(fd, 0, PAGE as u64, PROT_READ | PROT_WRITE) };
+    unsafe { io::syscall_close(fd).ok() };
+    let addr = addr.map_err(|e| format!("writable map: {e:?}"))?;
+    // SAFETY: the kernel mapped the page writable, and it stays until `munmap`
+    let writable = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, PAGE) };
+    check_content(writable, &content[..content.len().min(PAGE)])?;
+    writable[0] = !content[0];
+    let first = writable[0];
+    unmap(writable)?;
+    let file = std::fs::read(path).map_err(|e| format!("read {path} again: {e}"))?;
+    if first == content[0] || pages[0] != content[0] || file[0] != content[0] {
+        return Err(String::from(
+            "the write to a writable mapping is not private",
+        ));
+    }
+    Ok(())
+}
+
+/// Gives an untouched part of the mapping to `SYS_WRITE`, the kernel must read the pages too
+fn check_kernel_read(pages: &[u8], content: &[u8]) -> Result<(), String> {
+    let len = KERNEL_READ_LEN.min(content.len());
+    let (read_fd, write_fd) =
+        unsafe { io::syscall_create_pipe() }.map_err(|e| format!("pipe: {e:?}"))?;
+    let written = unsafe { io::syscall_write(write_fd, &pages[..len]) };
+    let mut back = [0; KERNEL_READ_LEN];
+    let read = unsafe { io::syscall_read(read_fd, &mut back[..len]) };
+    unsafe {
+        io::syscall_close(read_fd).ok();
+        io::syscall_close(write_fd).ok();
+    }
+    let whole = |result: &Result<u64, SyscallError>| matches!(result, Ok(n) if *n == len as u64);
+    if !whole(&written) || !whole(&read) || back[..len] != content[..len] {
+        return Err(format!(
+            "SYS_WRITE from the mapping: {written:?}, read back {read:?}"
+        ));
+    }
+    Ok(())
+}
+
+fn run(path: &str) -> Result<usize, String> {
+    let content = std::fs::read(path).map_err(|e| format!("read {path}: {e}"))?;
+    if content.is_empty() {
+        return Err(format!("{path} is empty"));
+    }
+    let fd = open(path)?;
+    check_errors(fd)?;
+
+    // a page more than the file, past its end
+    let len = content.len() + PAGE;
+    let pages = map(fd, 0, len)?;
+    let for_child = map(fd, 0, len)?;
+    let for_kernel = map(fd, 0, len)?;
+    let after_first = (content.len() > PAGE).then(|| map(fd, PAGE, content.len() - PAGE));
+    // the mappings keep the file
+    unsafe { io::syscall_close(fd).ok() };
+    check_content(pages, &content)?;
+    check_private_write(path, pages, &content)?;
+    check_child(for_child, &content)?;
+    check_kernel_read(for_kernel, &content)?;
+    if let Some(pages) = after_first {
+        let pages = pages?;
+        check_content(pages, &content[PAGE..])?;
+        unmap(pages)?;
+    }
+
+    unmap(pages)?;
+    match unsafe { process::munmap(pages.as_ptr()) } {
+        Err(SyscallError::InvalidArgument(..)) => {}
+        other => return Err(format!("unmapped twice: {other:?}")),
+    }
+    unmap(for_child)?;
+    unmap(for_kernel)?;
+    Ok(content.len())
+}
+
+/// Mmap shell program
+///
+/// Usage: mmap [file]
+///
+/// Maps `file` (`/shell` by default) read-only, a page more than its size, and checks that the
+/// pages are its content and zeroes after it, read after its fd is closed. Also from an offset
+/// of a page, from a child, which must then crash writing to them, and by the kernel, for a
+/// `SYS_WRITE` from a part we never touched, and that a write to a writable mapping is not seen
+/// by the others, or the file. Checks the errors of mapping a directory, an unaligned offset,
+/// an empty mapping, and unmapping twice.
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let path = match (args.next(), args.next()) {
+        (None, _) => String::from(DEFAULT_PATH),
+        (Some(path), None) if !path.starts_with('-') => path,
+        _ => return usage(),
+    };
+    match run(&path) {
+        Ok(size) => {
+            println!("mmap: the {size} bytes of {path} mapped");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] mmap: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
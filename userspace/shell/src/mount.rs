@@ -0,0 +1,101 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode};
+
+use kernel_user_link::{
+    call_syscall,
+    file::MOUNT_READ_ONLY,
+    syscalls::{SyscallError, SYS_MOUNT, SYS_UMOUNT},
+};
+
+const MOUNTS_PATH: &str = "/devices/mounts";
+
+fn usage() -> ExitCode {
+    println!("Usage: mount [-t <fstype>] [-r] <source> <target>");
+    println!("       mount -u <target>");
+    ExitCode::FAILURE
+}
+
+fn mount(source: &str, target: &str, fstype: &str, flags: u64) -> Result<(), SyscallError> {
+    let source = CString::new(source).unwrap();
+    let target = CString::new(target).unwrap();
+    let fstype = CString::new(fstype).unwrap();
+    unsafe {
+        call_syscall!(
+            SYS_MOUNT,
+            source.as_ptr() as u64, // source
+            target.as_ptr() as u64, // target
+            fstype.as_ptr() as u64, // fstype
+            flags,                  // flags
+        )
+        .map(|e| assert!(e == 0))
+    }
+}
+
+fn unmount(target: &str) -> Result<(), SyscallError> {
+    let target = CString::new(target).unwrap();
+    unsafe {
+        call_syscall!(
+            SYS_UMOUNT,
+            target.as_ptr() as u64, // target
+        )
+        .map(|e| assert!(e == 0))
+    }
+}
+
+/// Mount shell program
+///
+/// Usage: mount
+///        mount [-t <fstype>] [-r] <source> <target>
+///        mount -u <target>
+///
+/// Without arguments, prints the mounted filesystems from `/devices/mounts`.
+/// Otherwise mounts the filesystem on the block device `source` (i.e. `/devices/ide_ata_1`)
+/// at `target`, the type is `fat` by default, and `-r` mounts it read-only.
+/// `-u` unmounts the filesystem at `target`
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() {
+        return match std::fs::read_to_string(MOUNTS_PATH) {
+            Ok(mounts) => {
+                print!("{mounts}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                println!("[!] error: could not read {MOUNTS_PATH}: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut fstype = "fat";
+    let mut flags = 0;
+    let mut unmount_target = false;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" => match args.next() {
+                Some(name) => fstype = name,
+                None => return usage(),
+            },
+            "-r" => flags |= MOUNT_READ_ONLY,
+            "-u" => unmount_target = true,
+            _ if arg.starts_with('-') => return usage(),
+            path => paths.push(path),
+        }
+    }
+
+    let result = match (unmount_target, paths.as_slice()) {
+        (true, [target]) => unmount(target),
+        (false, [source, target]) => mount(source, target, fstype, flags),
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("[!] error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
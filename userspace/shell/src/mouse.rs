@@ -0,0 +1,58 @@
+#![feature(restricted_std)]
+
+use std::{fs::File, io::Read, process::ExitCode};
+
+use kernel_user_link::input::MouseEvent;
+
+/// Mouse shell program
+///
+/// Usage: mouse [count]
+///
+/// Waits for `count` (default 1) events of `/devices/mouse`, and prints each as
+/// `dx dy wheel buttons`
+fn main() -> ExitCode {
+    let count = match std::env::args().nth(1).map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => {
+            println!("Usage: mouse [count]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut file = match File::open("/devices/mouse") {
+        Ok(file) => file,
+        Err(e) => {
+            println!("[!] mouse: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut buf = [0u8; MouseEvent::SIZE * 16];
+    let mut printed = 0;
+    while printed < count {
+        let n = match file.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                println!("[!] mouse: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if n == 0 {
+            // no event yet
+            std::hint::spin_loop();
+            continue;
+        }
+        for record in buf[..n].chunks_exact(MouseEvent::SIZE) {
+            if printed == count {
+                break;
+            }
+            let event = MouseEvent::from_bytes(record.try_into().unwrap());
+            println!(
+                "{} {} {} {:03b}",
+                event.dx, event.dy, event.wheel, event.buttons
+            );
+            printed += 1;
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,165 @@
+#![feature(restricted_std)]
+
+use std::{
+    alloc::{self, Layout},
+    ffi::CString,
+    process::ExitCode,
+};
+
+use kernel_user_link::{
+    process::EXIT_CODE_CRASH,
+    syscalls::{SyscallArgError, SyscallError},
+};
+use user_std::{
+    io,
+    process::{self, PROT_EXEC, PROT_READ, PROT_WRITE},
+};
+
+const PAGE: usize = 4096;
+const PAGES: usize = 3;
+// read into the read-only page by the kernel, which must refuse
+const FILE_PATH: &str = "/message.txt";
+// far after the heap, not mapped
+const UNMAPPED_ADDRESS: usize = 0x7000_0000_0000;
+
+fn usage() -> ExitCode {
+    println!("Usage: mprotect [--crash]");
+    ExitCode::FAILURE
+}
+
+fn protect(addr: *const u8, len: usize, prot: u64) -> Result<(), SyscallError> {
+    unsafe { process::mprotect(addr, len, prot) }
+}
+
+fn is_arg_error(result: Result<(), SyscallError>, arg: usize, error: SyscallArgError) -> bool {
+    match result {
+        Err(SyscallError::InvalidArgument(a, b, c, ..)) => [a, b, c][arg] == Some(error),
+        _ => false,
+    }
+}
+
+/// Reads the start of [`FILE_PATH`] into `buf` with `SYS_READ`
+fn read_file(buf: &mut [u8]) -> Result<u64, SyscallError> {
+    let path = CString::new(FILE_PATH).unwrap();
+    let fd = unsafe { io::syscall_open(&path, 0, 0) }?;
+    let read = unsafe { io::syscall_read(fd, buf) };
+    unsafe { io::syscall_close(fd).ok() };
+    read
+}
+
+/// Writes to the read-only middle page of `pages` in a child, which must be killed for it
+fn check_crash(pages: &mut [u8]) -> Result<(), String> {
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            // SAFETY: it's mapped, only not writable, so we are killed here
+            unsafe { pages.as_mut_ptr().add(PAGE).write_volatile(1) };
+            std::process::exit(0);
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(EXIT_CODE_CRASH) => Ok(()),
+            other => Err(format!("the child that wrote to it ended with {other:?}")),
+        },
+        Err(e) => Err(format!("fork: {e:?}")),
+    }
+}
+
+fn run(crash: bool) -> Result<(), String> {
+    let layout = Layout::from_size_align(PAGE * PAGES, PAGE).unwrap();
+    // SAFETY: the layout is not empty, and the pages are freed below
+    let pages =
+        unsafe { core::slice::from_raw_parts_mut(alloc::alloc_zeroed(layout), PAGE * PAGES) };
+    let middle = pages[PAGE..].as_ptr();
+
+    protect(middle, PAGE, PROT_READ).map_err(|e| format!("read-only: {e:?}"))?;
+    if pages[PAGE] != 0 {
+        return Err(String::from("the read-only page changed"));
+    }
+    match read_file(&mut pages[PAGE..PAGE * 2]) {
+        Err(SyscallError::InvalidArgument(..)) => {}
+        other => return Err(format!("SYS_READ into the read-only page: {other:?}")),
+    }
+    if crash {
+        check_crash(pages)?;
+    }
+    // the length is rounded up, and `PROT_EXEC` changes nothing
+    protect(middle, 1, PROT_READ | PROT_WRITE | PROT_EXEC)
+        .map_err(|e| format!("writable again: {e:?}"))?;
+    pages[PAGE] = 0xAB;
+    match read_file(&mut pages[PAGE..PAGE * 2]) {
+        Ok(read) if read > 0 => {}
+        other => return Err(format!("SYS_READ into the writable page: {other:?}")),
+    }
+
+    let checks = [
+        (
+            "unaligned",
+            is_arg_error(
+                protect(pages[1..].as_ptr(), PAGE, PROT_READ),
+                0,
+                SyscallArgError::InvalidUserPointer,
+            ),
+        ),
+        (
+            "not mapped",
+            is_arg_error(
+                protect(UNMAPPED_ADDRESS as *const u8, PAGE, PROT_READ),
+                0,
+                SyscallArgError::InvalidUserPointer,
+            ),
+        ),
+        (
+            "without PROT_READ",
+            is_arg_error(
+                protect(middle, PAGE, PROT_WRITE),
+                2,
+                SyscallArgError::GeneralInvalid,
+            ),
+        ),
+        (
+            "unknown bits",
+            is_arg_error(
+                protect(middle, PAGE, PROT_READ | (1 << 8)),
+                2,
+                SyscallArgError::GeneralInvalid,
+            ),
+        ),
+        ("empty", protect(middle, 0, PROT_READ).is_ok()),
+    ];
+    if let Some((name, _)) = checks.iter().find(|(_, ok)| !ok) {
+        return Err(format!("{name}: the range was not refused as expected"));
+    }
+    if pages[PAGE] != 0xAB || pages[0] != 0 || pages[PAGE * 2] != 0 {
+        return Err(String::from("the pages changed"));
+    }
+
+    // SAFETY: allocated above with the same layout, and all writable again
+    unsafe { alloc::dealloc(pages.as_mut_ptr(), layout) };
+    Ok(())
+}
+
+/// Mprotect shell program
+///
+/// Usage: mprotect [--crash]
+///
+/// Makes a page of the heap read-only with `SYS_MPROTECT`, checks that the kernel doesn't
+/// write to it, then makes it writable again, and that bad ranges and flags are refused.
+/// With `--crash`, a child also writes to the read-only page and must crash for it
+fn main() -> ExitCode {
+    let mut crash = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--crash" => crash = true,
+            _ => return usage(),
+        }
+    }
+    match run(crash) {
+        Ok(()) => {
+            println!("mprotect: the pages followed their protection");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] mprotect: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,231 @@
+#![feature(restricted_std)]
+
+use std::{
+    hint::black_box,
+    process::{Command, ExitCode},
+    time::Duration,
+};
+
+use kernel_user_link::syscalls::{SyscallArgError, SyscallError};
+use user_std::{
+    process::{self, PRIORITY_BATCH, PRIORITY_HIGHEST, PRIORITY_LOWEST},
+    time::Instant,
+};
+
+const PROGRAM_PATH: &str = "/nice";
+const INIT_PID: u64 = 0;
+// created by the test when the spinning children can start
+const GO_PATH: &str = "/tmp/nice_go";
+// long enough for many timer ticks, each child spins for this long after the go
+const SPIN_TIME: Duration = Duration::from_secs(2);
+
+fn usage() -> ExitCode {
+    println!("Usage: nice [-p <pid> [<priority>]] | -n <priority> <program> [args...] | --test");
+    ExitCode::FAILURE
+}
+
+fn own_pid() -> u64 {
+    std::process::id() as u64
+}
+
+fn get_priority(pid: u64) -> Result<u64, SyscallError> {
+    unsafe { process::get_priority(pid) }
+}
+
+fn set_priority(pid: u64, priority: u64) -> Result<(), SyscallError> {
+    unsafe { process::set_priority(pid, priority) }
+}
+
+/// Spins until `done` or `timeout`, whether it was done
+fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+/// Waits for `go`, then counts for [`SPIN_TIME`], the count is written to `out`
+fn spin(go: &str, out: &str) -> Result<(), String> {
+    wait_until(Duration::MAX, || std::fs::File::open(go).is_ok());
+    let start = Instant::now();
+    let mut count = 0u64;
+    while start.elapsed() < SPIN_TIME {
+        count = black_box(count + 1);
+    }
+    std::fs::write(out, count.to_string()).map_err(|e| format!("{out}: {e}"))
+}
+
+/// Invalid priorities and the processes that are not ours are refused
+fn test_rules() -> Result<(), String> {
+    let pid = own_pid();
+    let old = get_priority(pid).map_err(|e| format!("get: {e:?}"))?;
+    set_priority(pid, PRIORITY_BATCH).map_err(|e| format!("set: {e:?}"))?;
+    match get_priority(pid) {
+        Ok(PRIORITY_BATCH) => {}
+        result => return Err(format!("expected {PRIORITY_BATCH}, got {result:?}")),
+    }
+
+    match set_priority(pid, PRIORITY_LOWEST + 1) {
+        Err(SyscallError::InvalidArgument(None, Some(SyscallArgError::GeneralInvalid), ..)) => {}
+        result => return Err(format!("a priority too low gave {result:?}")),
+    }
+    // `init` is not our child
+    match set_priority(INIT_PID, PRIORITY_BATCH) {
+        Err(SyscallError::PermissionDenied) => {}
+        result => return Err(format!("changing init gave {result:?}")),
+    }
+    match get_priority(u64::MAX) {
+        Err(SyscallError::PidNotFound) => {}
+        result => return Err(format!("a missing pid gave {result:?}")),
+    }
+
+    // the children start with ours
+    let status = Command::new(PROGRAM_PATH)
+        .args(["--expect", &PRIORITY_BATCH.to_string()])
+        .status()
+        .map_err(|e| format!("spawn: {e}"))?;
+    if !status.success() {
+        return Err(format!("the child didn't have our priority, {status:?}"));
+    }
+    set_priority(pid, old).map_err(|e| format!("set back: {e:?}"))
+}
+
+/// Two children spin for the same time, the one with the highest priority counts more
+fn test_spinning() -> Result<(), String> {
+    std::fs::remove_file(GO_PATH).ok();
+    let spawn = |priority: u64| {
+        let out = format!("/tmp/nice_{priority}");
+        let child = Command::new(PROGRAM_PATH)
+            .args(["--spin", GO_PATH, &out])
+            .spawn()
+            .map_err(|e| format!("spawn: {e}"))?;
+        set_priority(child.id() as u64, priority)
+            .map_err(|e| format!("set the child to {priority}: {e:?}"))?;
+        Ok::<_, String>((child, out))
+    };
+    let children = [spawn(PRIORITY_HIGHEST)?, spawn(PRIORITY_LOWEST)?];
+    std::fs::write(GO_PATH, "go").map_err(|e| format!("{GO_PATH}: {e}"))?;
+
+    let mut counts = Vec::new();
+    for (mut child, out) in children {
+        let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+        if !status.success() {
+            return Err(format!("a spinning child exited with {status:?}"));
+        }
+        let count = std::fs::read_to_string(&out).map_err(|e| format!("{out}: {e}"))?;
+        std::fs::remove_file(&out).map_err(|e| format!("{out}: {e}"))?;
+        counts.push(count.parse::<u64>().map_err(|e| format!("{out}: {e}"))?);
+    }
+    std::fs::remove_file(GO_PATH).map_err(|e| format!("{GO_PATH}: {e}"))?;
+    // it runs about once for every 40 of the other
+    if counts[0] <= counts[1] * 2 {
+        return Err(format!(
+            "the highest counted {}, the lowest {}",
+            counts[0], counts[1]
+        ));
+    }
+    Ok(())
+}
+
+fn self_test() -> Result<(), String> {
+    test_rules()?;
+    test_spinning()
+}
+
+/// Nice shell program
+///
+/// Usage: nice [-p <pid> [<priority>]]
+///        nice -n <priority> <program> [args...]
+///        nice --expect <priority>
+///        nice --spin <go_path> <out_path>
+///        nice --test
+///
+/// Prints the scheduling priority of this process, or of `pid` with `-p`, or sets it to
+/// `priority`, from 0 (the highest) to 39 (the lowest), 20 is the default. `-n` runs `program`
+/// with `priority`, i.e. `nice -n 30 <program>` for a program that would take the CPU from the
+/// others for long. `--expect` fails if this process doesn't have `priority`, and `--spin` counts
+/// for a while once `go_path` exists and writes the count to `out_path`, for `--test`, which
+/// checks the rules, that the children start with the priority of their parent, and that of two
+/// spinning children the one with the highest priority counts more
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        [] => get_priority(own_pid()).map(|priority| println!("{priority}")),
+        ["-p", pid, rest @ ..] if rest.len() <= 1 => {
+            let Ok(pid) = pid.parse::<u64>() else {
+                return usage();
+            };
+            match rest.first() {
+                Some(priority) => {
+                    let Ok(priority) = priority.parse::<u64>() else {
+                        return usage();
+                    };
+                    set_priority(pid, priority)
+                }
+                None => get_priority(pid).map(|priority| println!("{priority}")),
+            }
+        }
+        ["-n", priority, program, program_args @ ..] => {
+            let Ok(priority) = priority.parse::<u64>() else {
+                return usage();
+            };
+            if let Err(e) = set_priority(own_pid(), priority) {
+                println!("[!] nice: {e:?}");
+                return ExitCode::FAILURE;
+            }
+            return match Command::new(program).args(program_args).status() {
+                Ok(status) => ExitCode::from(status.code().unwrap_or(1) as u8),
+                Err(e) => {
+                    println!("[!] nice: {program}: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        ["--expect", priority] => {
+            let Ok(priority) = priority.parse::<u64>() else {
+                return usage();
+            };
+            return match get_priority(own_pid()) {
+                Ok(own) if own == priority => ExitCode::SUCCESS,
+                result => {
+                    println!("[!] nice: expected {priority}, got {result:?}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        ["--spin", go, out] => {
+            return match spin(go, out) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    println!("[!] nice: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        ["--test"] => {
+            return match self_test() {
+                Ok(()) => {
+                    println!("nice: test passed");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] nice test failed: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("[!] nice: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
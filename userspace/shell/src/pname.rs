@@ -0,0 +1,242 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, hint::black_box, process::ExitCode, time::Duration};
+
+use kernel_user_link::{
+    crash_dump::CrashDump,
+    process::EXIT_CODE_CRASH,
+    syscalls::{SyscallArgError, SyscallError},
+};
+use user_std::{
+    process::{self, PROCESS_NAME_LEN},
+    time::Instant,
+};
+
+const PROGRAM_PATH: &str = "/pname";
+const INIT_PID: u64 = 0;
+const PROCESSES_PATH: &str = "/devices/processes";
+// created by the test when the waiting child can exit
+const GO_PATH: &str = "/tmp/pname_go";
+// the listing is updated every second
+const LISTING_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn usage() -> ExitCode {
+    println!("Usage: pname [<pid> [<name>]] | --wait <name> <path> | --crash <name> | --test");
+    ExitCode::FAILURE
+}
+
+fn get_name(pid: u64) -> Result<String, SyscallError> {
+    let mut buf = [0u8; PROCESS_NAME_LEN];
+    let len = unsafe { process::get_name(pid, &mut buf) }?;
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+fn set_name(pid: u64, name: &str) -> Result<(), SyscallError> {
+    let name = CString::new(name).unwrap();
+    unsafe { process::set_name(pid, &name) }
+}
+
+fn own_pid() -> u64 {
+    std::process::id() as u64
+}
+
+/// Spins until `done` or `timeout`, whether it was done
+fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+/// The NAME column of `pid` in the processes listing
+fn listed_name(pid: u64) -> Option<String> {
+    let processes = std::fs::read_to_string(PROCESSES_PATH).ok()?;
+    let pid = pid.to_string();
+    // after the two header lines: PID NAME PPID ...
+    processes
+        .lines()
+        .skip(2)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.first() == Some(&pid.as_str()))
+        .and_then(|columns| columns.get(1).map(|name| name.to_string()))
+}
+
+fn expect_name(pid: u64, expected: &str) -> Result<(), String> {
+    match get_name(pid) {
+        Ok(name) if name == expected => Ok(()),
+        result => Err(format!(
+            "pid {pid}: expected the name {expected:?}, got {result:?}"
+        )),
+    }
+}
+
+/// A child names itself, the parent finds the name in the listing, then renames it
+fn test_child_name() -> Result<(), String> {
+    std::fs::remove_file(GO_PATH).ok();
+    let mut child = std::process::Command::new(PROGRAM_PATH)
+        .args(["--wait", "pname-child", GO_PATH])
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))?;
+    let pid = child.id() as u64;
+    let result = (|| {
+        if !wait_until(LISTING_TIMEOUT, || {
+            listed_name(pid).as_deref() == Some("pname-child")
+        }) {
+            return Err(format!(
+                "the child is listed as {:?}, not pname-child",
+                listed_name(pid)
+            ));
+        }
+        expect_name(pid, "pname-child")?;
+        set_name(pid, "pname-renamed").map_err(|e| format!("rename the child: {e:?}"))?;
+        expect_name(pid, "pname-renamed")
+    })();
+    std::fs::write(GO_PATH, "go").map_err(|e| format!("{GO_PATH}: {e}"))?;
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    std::fs::remove_file(GO_PATH).map_err(|e| format!("{GO_PATH}: {e}"))?;
+    result?;
+    if !status.success() {
+        return Err(format!("the waiting child exited with {status:?}"));
+    }
+    Ok(())
+}
+
+/// A child crashes with a name it set, the dump is named after it
+fn test_crash_name() -> Result<(), String> {
+    let mut child = std::process::Command::new(PROGRAM_PATH)
+        .args(["--crash", "pname-crashed"])
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))?;
+    let pid = child.id();
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    if status.code() != Some(EXIT_CODE_CRASH) {
+        return Err(format!(
+            "expected exit code {EXIT_CODE_CRASH}, got {status:?}"
+        ));
+    }
+
+    let path = format!("/tmp/crash-{pid}-pname-crashed.dump");
+    let bytes = std::fs::read(&path).map_err(|e| format!("{path}: {e}"))?;
+    let dump = CrashDump::parse(&bytes).map_err(|e| format!("{path}: {e:?}"))?;
+    if dump.header.pid != pid as u64 {
+        return Err(format!("unexpected header {:?}", dump.header));
+    }
+    std::fs::remove_file(&path).map_err(|e| format!("{path}: {e}"))?;
+    Ok(())
+}
+
+/// Long names are cut at a character, the bad ones and the processes that are not ours are
+/// refused
+fn test_rules() -> Result<(), String> {
+    let pid = own_pid();
+    set_name(pid, "abcdefghijklmnopqrstuvwxyz").map_err(|e| format!("long name: {e:?}"))?;
+    expect_name(pid, "abcdefghijklmnop")?;
+    // the `é` is the 16th and 17th bytes
+    set_name(pid, "abcdefghijklmnoé").map_err(|e| format!("multibyte name: {e:?}"))?;
+    expect_name(pid, "abcdefghijklmno")?;
+    set_name(pid, "pnamé").map_err(|e| format!("short multibyte name: {e:?}"))?;
+    expect_name(pid, "pnamé")?;
+
+    for bad in ["", "has space", "a/b", "tab\there"] {
+        let result = set_name(pid, bad);
+        if !matches!(
+            result,
+            Err(SyscallError::InvalidArgument(
+                None,
+                Some(SyscallArgError::GeneralInvalid),
+                ..
+            ))
+        ) {
+            return Err(format!("the name {bad:?} gave {result:?}"));
+        }
+    }
+    expect_name(pid, "pnamé")?;
+
+    let mut small = [0u8; 2];
+    if unsafe { process::get_name(pid, &mut small) }.is_ok() {
+        return Err(String::from("the name fit in 2 bytes"));
+    }
+    // `init` is not our child
+    match set_name(INIT_PID, "not-init") {
+        Err(SyscallError::PermissionDenied) => {}
+        result => return Err(format!("renaming init gave {result:?}")),
+    }
+    match set_name(u64::MAX, "nobody") {
+        Err(SyscallError::PidNotFound) => {}
+        result => return Err(format!("renaming a missing pid gave {result:?}")),
+    }
+    set_name(pid, "pname").map_err(|e| format!("rename back: {e:?}"))
+}
+
+fn self_test() -> Result<(), String> {
+    expect_name(own_pid(), "pname")?;
+    test_child_name()?;
+    test_crash_name()?;
+    test_rules()
+}
+
+/// Pname shell program
+///
+/// Usage: pname [<pid> [<name>]]
+///        pname --wait <name> <path>
+///        pname --crash <name>
+///        pname --test
+///
+/// Prints the name of `pid`, this process without it, or sets it to `name`, only this process
+/// and its children can be renamed. `--wait` names itself and waits for `path` to be created,
+/// `--crash` names itself and crashes. `--test` runs both as children, and checks the name in
+/// the processes listing and in the crash dump, and how long and bad names are handled
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        [] => get_name(own_pid()).map(|name| println!("{name}")),
+        ["--wait", name, path] => set_name(own_pid(), name).map(|()| {
+            wait_until(Duration::MAX, || std::fs::File::open(path).is_ok());
+        }),
+        ["--crash", name] => {
+            if let Err(e) = set_name(own_pid(), name) {
+                println!("[!] pname: {e:?}");
+                return ExitCode::FAILURE;
+            }
+            // nothing is mapped there in userspace
+            let address = black_box(0xDEAD_0000usize) as *mut u64;
+            unsafe { core::ptr::write_volatile(address, 0) };
+            println!("[!] error: did not crash");
+            return ExitCode::FAILURE;
+        }
+        ["--test"] => {
+            return match self_test() {
+                Ok(()) => {
+                    println!("pname: test passed");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] pname test failed: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        [pid, rest @ ..] if rest.len() <= 1 => {
+            let Ok(pid) = pid.parse::<u64>() else {
+                return usage();
+            };
+            match rest.first() {
+                Some(name) => set_name(pid, name),
+                None => get_name(pid).map(|name| println!("{name}")),
+            }
+        }
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("[!] pname: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,52 +1,697 @@
+//! `shell`
+//!
+//! Reads a line at a time from stdin (forwarded by `init`) and runs it.
+//!
+//! Supports quoting, `$?` and `$NAME` expansion, `<` and `>` redirections, `|` pipelines
+//! and `&` background jobs, with the builtins `cd`, `pwd`, `exit`, `exec`, `export`, `jobs`
+//! and `expect`, and `mkdir`, `rmdir`, `rm` and `mv` for files.
+//!
+//! `expect <code> <what>` checks the result of the last command, `!0` is any but `0`,
+//! `expect = <expected> <value> <what>` checks a value, and at the end of a pipeline,
+//! `<command> | expect ~ <text> [!~ <text>...] <what>` checks that a line of the output of the
+//! command has each text after `~` and none has one after `!~`, a `*` in a text matches
+//! anything on the line. The output is read by the shell and printed before the check.
+//!
+//! This is what the scripts of `/tests` use, a shell that ran a check that failed exits with
+//! `1` when its stdin ends, so `shell < /tests/<name>.sh` fails if one of the checks of the
+//! script did.
+//!
+//! The variables start as the environment the shell got (i.e. `PATH` from `init`). They and
+//! the current directory are only known to the shell, they are used to find the commands and
+//! the redirected files, but not passed to the commands.
+//!
+//! It ignores `SIGINT`, so `Ctrl+C` only interrupts the commands it runs.
 #![feature(restricted_std)]
 
 use std::{
-    io::{self, Write},
-    process::Command,
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    iter::Peekable,
+    mem,
+    process::{Child, Command, Stdio},
+    str::Chars,
     string::String,
 };
 
-fn main() {
-    let mut old_result = None;
+use user_std::{
+    process,
+    signal::{self, Action, SIGINT},
+};
 
-    loop {
-        if let Some(result) = old_result.take() {
-            print!("{result} ");
+/// The result of commands that could not run
+const FAILED_RESULT: i32 = 0x7F;
+/// Where the commands are looked up if `PATH` is not set, separated by `:`
+const DEFAULT_PATH: &str = "/";
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Pipe,
+    RedirectIn,
+    RedirectOut,
+    Background,
+}
+
+/// Expands the variable after a `$`, a `$` not followed by a name is kept as is
+fn expand_variable(
+    chars: &mut Peekable<Chars>,
+    word: &mut String,
+    lookup: &impl Fn(&str) -> Option<String>,
+) {
+    if chars.next_if_eq(&'?').is_some() {
+        word.push_str(&lookup("?").unwrap_or_default());
+        return;
+    }
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    if name.is_empty() {
+        word.push('$');
+    } else {
+        word.push_str(&lookup(&name).unwrap_or_default());
+    }
+}
+
+/// Splits `line` into tokens, expanding the variables with `lookup`.
+///
+/// Nothing is special inside `'`, inside `"` only `$` and `\` are
+fn tokenize(line: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    // `None` if we are not inside a word, so that `""` gives an empty word
+    let mut word: Option<String> = None;
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => tokens.extend(word.take().map(Token::Word)),
+            '|' | '<' | '>' | '&' => {
+                tokens.extend(word.take().map(Token::Word));
+                tokens.push(match c {
+                    '|' => Token::Pipe,
+                    '<' => Token::RedirectIn,
+                    '>' => Token::RedirectOut,
+                    _ => Token::Background,
+                });
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("unterminated `'`")),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(String::from("unterminated `\"`")),
+                        },
+                        Some('$') => expand_variable(&mut chars, word, &lookup),
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("unterminated `\"`")),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(String::from("trailing `\\`")),
+            },
+            '$' => expand_variable(&mut chars, word.get_or_insert_with(String::new), &lookup),
+            c => word.get_or_insert_with(String::new).push(c),
         }
-        print!("$ ");
-        io::stdout().flush().unwrap();
+    }
+    tokens.extend(word.take().map(Token::Word));
+    Ok(tokens)
+}
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+#[derive(Debug, Default)]
+struct SimpleCommand {
+    args: Vec<String>,
+    stdin: Option<String>,
+    stdout: Option<String>,
+}
+
+#[derive(Debug)]
+struct Pipeline {
+    commands: Vec<SimpleCommand>,
+    background: bool,
+}
 
-        let input = input.trim();
-        let args = input.split_whitespace().collect::<Vec<_>>();
+/// Returns `None` for an empty line
+fn parse(tokens: Vec<Token>) -> Result<Option<Pipeline>, String> {
+    let mut commands = Vec::new();
+    let mut current = SimpleCommand::default();
+    let mut background = false;
 
-        if args.len() == 0 {
-            continue;
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if background {
+            return Err(String::from("`&` must be at the end"));
         }
+        match token {
+            Token::Word(word) => current.args.push(word),
+            Token::Pipe => {
+                if current.args.is_empty() {
+                    return Err(String::from("empty command in pipeline"));
+                }
+                commands.push(mem::take(&mut current));
+            }
+            Token::RedirectIn | Token::RedirectOut => {
+                let Some(Token::Word(path)) = tokens.next() else {
+                    return Err(String::from("expected a file after redirection"));
+                };
+                if token == Token::RedirectIn {
+                    current.stdin = Some(path);
+                } else {
+                    current.stdout = Some(path);
+                }
+            }
+            Token::Background => background = true,
+        }
+    }
 
-        let cmd = args[0];
-        // TODO: add support for relative paths and other stuff, for now we must specify the full path
-        let cmd_path = format!("/{cmd}");
-        let remaining_args = &args[1..];
+    if current.args.is_empty() {
+        if commands.is_empty() && !background && current.stdin.is_none() {
+            return Ok(None);
+        }
+        return Err(String::from("empty command"));
+    }
+    commands.push(current);
+    Ok(Some(Pipeline {
+        commands,
+        background,
+    }))
+}
 
-        let result = match Command::new(cmd_path).args(remaining_args).spawn() {
-            Ok(mut proc) => proc.wait().unwrap(),
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => {
-                    println!("[!] command not found: {cmd}");
-                    old_result = Some(0x7F);
-                    continue;
+struct Job {
+    id: usize,
+    children: Vec<Child>,
+    command: String,
+}
+
+impl Job {
+    /// Returns `true` if all the processes of the job have exited
+    fn is_done(&mut self) -> bool {
+        self.children
+            .iter_mut()
+            .all(|child| matches!(child.try_wait(), Ok(Some(_)) | Err(_)))
+    }
+}
+
+struct Shell {
+    cwd: String,
+    variables: BTreeMap<String, String>,
+    last_result: Option<i32>,
+    jobs: Vec<Job>,
+    next_job_id: usize,
+    // the `expect` checks that failed
+    failed_checks: usize,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Self {
+            cwd: String::from("/"),
+            variables: std::env::vars().collect(),
+            last_result: None,
+            jobs: Vec::new(),
+            next_job_id: 1,
+            failed_checks: 0,
+        }
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        if name == "?" {
+            return Some(self.last_result.unwrap_or(0).to_string());
+        }
+        self.variables.get(name).cloned()
+    }
+
+    /// Makes `path` absolute using the current directory, and removes `.` and `..`
+    fn resolve(&self, path: &str) -> String {
+        let full = if path.starts_with('/') {
+            String::from(path)
+        } else {
+            format!("{}/{path}", self.cwd)
+        };
+        let mut components = Vec::new();
+        for component in full.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
                 }
-                _ => {
-                    println!("[!] error: {e}");
-                    old_result = Some(0x7F);
-                    continue;
+                _ => components.push(component),
+            }
+        }
+        format!("/{}", components.join("/"))
+    }
+
+    /// Finds the program for `name`, names with `/` are paths, otherwise it is looked
+    /// up in the directories of `PATH`
+    fn find_program(&self, name: &str) -> Option<String> {
+        if name.contains('/') {
+            let path = self.resolve(name);
+            return File::open(&path).is_ok().then_some(path);
+        }
+        let path = self
+            .variables
+            .get("PATH")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_PATH);
+        path.split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| format!("{}/{name}", self.resolve(dir).trim_end_matches('/')))
+            .find(|candidate| File::open(candidate).is_ok())
+    }
+
+    /// `capture` pipes the output of the last command too, to be read by the shell
+    fn spawn_pipeline(&self, pipeline: &Pipeline, capture: bool) -> Result<Vec<Child>, String> {
+        let last = pipeline.commands.len() - 1;
+        let mut children = Vec::new();
+        let mut previous_stdout: Option<Stdio> = None;
+
+        for (i, command) in pipeline.commands.iter().enumerate() {
+            let result =
+                self.spawn_command(command, previous_stdout.take(), i == 0, i == last, capture);
+            match result {
+                Ok(mut child) => {
+                    previous_stdout = child.stdout.take().map(Stdio::from);
+                    children.push(child);
                 }
-            },
+                Err(e) => {
+                    // the ones before will get an error on their next write
+                    for mut child in children {
+                        let _ = child.wait();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(children)
+    }
+
+    fn spawn_command(
+        &self,
+        command: &SimpleCommand,
+        stdin: Option<Stdio>,
+        is_first: bool,
+        is_last: bool,
+        capture: bool,
+    ) -> Result<Child, String> {
+        let name = &command.args[0];
+        if command.stdin.is_some() && !is_first {
+            return Err(format!(
+                "{name}: only the first command can redirect its input"
+            ));
+        }
+        if command.stdout.is_some() && !is_last {
+            return Err(format!(
+                "{name}: only the last command can redirect its output"
+            ));
+        }
+        let program = self
+            .find_program(name)
+            .ok_or_else(|| format!("command not found: {name}"))?;
+
+        let mut process = Command::new(program);
+        process.args(&command.args[1..]);
+
+        if let Some(path) = &command.stdin {
+            let file = File::open(self.resolve(path)).map_err(|e| format!("{path}: {e}"))?;
+            process.stdin(file);
+        } else if let Some(stdin) = stdin {
+            process.stdin(stdin);
+        }
+        if let Some(path) = &command.stdout {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(self.resolve(path))
+                .map_err(|e| format!("{path}: {e}"))?;
+            process.stdout(file);
+        } else if !is_last || capture {
+            process.stdout(Stdio::piped());
+        }
+
+        process.spawn().map_err(|e| format!("{name}: {e}"))
+    }
+
+    /// Returns `None` if `args` is not a builtin
+    fn run_builtin(&mut self, args: &[String]) -> Option<i32> {
+        let result = match args[0].as_str() {
+            "cd" => {
+                let path = self.resolve(args.get(1).map(String::as_str).unwrap_or("/"));
+                match std::fs::read_dir(&path) {
+                    Ok(_) => {
+                        self.cwd = path;
+                        0
+                    }
+                    Err(e) => {
+                        println!("[!] cd: {path}: {e}");
+                        1
+                    }
+                }
+            }
+            "pwd" => {
+                println!("{}", self.cwd);
+                0
+            }
+            "exit" => {
+                let code = match args.get(1).map(|code| code.parse()) {
+                    Some(Ok(code)) => code,
+                    Some(Err(_)) => {
+                        println!("[!] exit: invalid exit code");
+                        return Some(1);
+                    }
+                    None => self.last_result.unwrap_or(0),
+                };
+                std::process::exit(code)
+            }
+            "exec" => {
+                let Some(name) = args.get(1) else {
+                    println!("[!] exec: missing program");
+                    return Some(1);
+                };
+                let Some(program) = self.find_program(name) else {
+                    println!("[!] exec: command not found: {name}");
+                    return Some(FAILED_RESULT);
+                };
+                let path = CString::new(program.as_str()).unwrap();
+                let argv = std::iter::once(program.as_str())
+                    .chain(args[2..].iter().map(String::as_str))
+                    .map(|arg| CString::new(arg).unwrap())
+                    .collect::<Vec<_>>();
+                let argv_ptrs = argv
+                    .iter()
+                    .map(|arg| arg.as_ptr())
+                    .chain(std::iter::once(core::ptr::null()))
+                    .collect::<Vec<_>>();
+                // the program takes our place, with our pid and files, the jobs are left
+                // running
+                io::stdout().flush().ok();
+                let e = unsafe { process::exec(&path, &argv_ptrs) };
+                println!("[!] exec: {name}: {e:?}");
+                FAILED_RESULT
+            }
+            "export" => {
+                if args.len() == 1 {
+                    for (name, value) in &self.variables {
+                        println!("{name}={value}");
+                    }
+                }
+                let mut result = 0;
+                for arg in &args[1..] {
+                    match arg.split_once('=') {
+                        Some((name, value)) if !name.is_empty() => {
+                            self.variables.insert(name.into(), value.into());
+                        }
+                        _ => {
+                            println!("[!] export: expected NAME=VALUE, got `{arg}`");
+                            result = 1;
+                        }
+                    }
+                }
+                result
+            }
+            "jobs" => {
+                self.report_jobs(true);
+                0
+            }
+            "expect" => self.expect(&args[1..], None),
+            "mkdir" | "rmdir" | "rm" => {
+                let name = args[0].as_str();
+                if args.len() == 1 {
+                    println!("[!] {name}: missing path");
+                    return Some(1);
+                }
+                let mut result = 0;
+                for arg in &args[1..] {
+                    let path = self.resolve(arg);
+                    let done = match name {
+                        "mkdir" => std::fs::create_dir(&path),
+                        "rmdir" => std::fs::remove_dir(&path),
+                        _ => std::fs::remove_file(&path),
+                    };
+                    if let Err(e) = done {
+                        println!("[!] {name}: {path}: {e}");
+                        result = 1;
+                    }
+                }
+                result
+            }
+            "mv" => {
+                let [_, from, to] = args else {
+                    println!("[!] mv: expected a source and a destination");
+                    return Some(1);
+                };
+                let (from, to) = (self.resolve(from), self.resolve(to));
+                // there is no copy if they are in different filesystems, `cp` and `rm` do that
+                match std::fs::rename(&from, &to) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        println!("[!] mv: {from} -> {to}: {e}");
+                        1
+                    }
+                }
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// The `expect` builtin, prints whether the check passed, it results in `1` if it didn't.
+    ///
+    /// `output` is the output of the commands before it in the pipeline, if it is in one
+    fn expect(&mut self, args: &[String], output: Option<&str>) -> i32 {
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        let (passed, expected, got, what) = match args.as_slice() {
+            ["~" | "!~", ..] => {
+                let Some(output) = output else {
+                    println!("[!] expect: `~` must be at the end of a pipeline");
+                    return 1;
+                };
+                // the texts, and whether they must be in the output
+                let mut rest = args.as_slice();
+                let mut texts = Vec::new();
+                while let [kind @ ("~" | "!~"), text, tail @ ..] = rest {
+                    texts.push((*text, *kind == "~"));
+                    rest = tail;
+                }
+                if texts.is_empty() {
+                    println!("[!] expect: expected a text after `~`");
+                    return 1;
+                }
+                let describe = |(text, present): &(&str, bool)| {
+                    format!("{}`{text}`", if *present { "" } else { "no " })
+                };
+                let wrong = texts
+                    .iter()
+                    .filter(|(text, present)| {
+                        output.lines().any(|line| line_has(line, text)) != *present
+                    })
+                    .map(|&(text, present)| describe(&(text, !present)))
+                    .collect::<Vec<_>>();
+                let texts = texts.iter().map(describe).collect::<Vec<_>>();
+                (
+                    wrong.is_empty(),
+                    format!("{} in the output", texts.join(", ")),
+                    wrong.join(", "),
+                    rest,
+                )
+            }
+            ["=", expected, value, what @ ..] => (
+                expected == value,
+                expected.to_string(),
+                value.to_string(),
+                what,
+            ),
+            [code, what @ ..] => {
+                let last = self.last_result.unwrap_or(0);
+                let passed = match *code {
+                    "!0" => last != 0,
+                    code => match code.parse::<i32>() {
+                        Ok(code) => last == code,
+                        Err(_) => {
+                            println!("[!] expect: invalid exit code `{code}`");
+                            return 1;
+                        }
+                    },
+                };
+                (passed, code.to_string(), last.to_string(), what)
+            }
+            [] => {
+                println!("[!] expect: expected a code, `= <expected> <value>` or `~ <text>`");
+                return 1;
+            }
+        };
+        let what = what.join(" ");
+        if passed {
+            println!("ok: {what}");
+            0
+        } else {
+            println!("[!] FAILED: {what}: expected {expected}, got {got}");
+            self.failed_checks += 1;
+            1
+        }
+    }
+
+    /// Prints the finished jobs and removes them, `all` prints the running ones as well
+    fn report_jobs(&mut self, all: bool) {
+        self.jobs.retain_mut(|job| {
+            let done = job.is_done();
+            if done {
+                println!("[{}] Done     {}", job.id, job.command);
+            } else if all {
+                println!("[{}] Running  {}", job.id, job.command);
+            }
+            !done
+        });
+    }
+
+    fn run_line(&mut self, line: &str) {
+        let pipeline = tokenize(line, |name| self.variable(name)).and_then(parse);
+        let mut pipeline = match pipeline {
+            Ok(Some(pipeline)) => pipeline,
+            Ok(None) => return,
+            Err(e) => {
+                println!("[!] syntax error: {e}");
+                self.last_result = Some(FAILED_RESULT);
+                return;
+            }
         };
 
-        old_result = Some(result.code().unwrap());
+        let first = &pipeline.commands[0];
+        let is_simple = pipeline.commands.len() == 1 && !pipeline.background;
+        if is_simple && first.stdin.is_none() && first.stdout.is_none() {
+            if let Some(result) = self.run_builtin(&first.args) {
+                self.last_result = Some(result);
+                return;
+            }
+        }
+        if let Some(result) = self.run_expect_pipeline(&mut pipeline) {
+            self.last_result = Some(result);
+            return;
+        }
+
+        let mut children = match self.spawn_pipeline(&pipeline, false) {
+            Ok(children) => children,
+            Err(e) => {
+                println!("[!] {e}");
+                self.last_result = Some(FAILED_RESULT);
+                return;
+            }
+        };
+
+        if pipeline.background {
+            let id = self.next_job_id;
+            self.next_job_id += 1;
+            println!("[{id}] {}", children.last().unwrap().id());
+            self.jobs.push(Job {
+                id,
+                children,
+                command: String::from(line.trim().trim_end_matches('&').trim_end()),
+            });
+            self.last_result = Some(0);
+            return;
+        }
+
+        self.last_result = Some(wait_pipeline(&mut children));
+    }
+
+    /// Runs `<commands> | expect ...`, with the output of the commands given to `expect`,
+    /// `None` if `pipeline` doesn't end with `expect`
+    fn run_expect_pipeline(&mut self, pipeline: &mut Pipeline) -> Option<i32> {
+        let last = pipeline.commands.last()?;
+        if pipeline.commands.len() == 1 || pipeline.background || last.args[0] != "expect" {
+            return None;
+        }
+        let expect = pipeline.commands.pop().unwrap();
+        if expect.stdin.is_some() || expect.stdout.is_some() {
+            println!("[!] expect: can't be redirected");
+            return Some(FAILED_RESULT);
+        }
+        // a pipeline that can't run has no output, so the check fails and is counted
+        let mut output = Vec::new();
+        match self.spawn_pipeline(pipeline, true) {
+            Ok(mut children) => {
+                let stdout = children.last_mut().and_then(|child| child.stdout.take());
+                if let Some(mut stdout) = stdout {
+                    let _ = stdout.read_to_end(&mut output);
+                }
+                wait_pipeline(&mut children);
+            }
+            Err(e) => println!("[!] {e}"),
+        }
+        let output = String::from_utf8_lossy(&output);
+        print!("{output}");
+        if !output.is_empty() && !output.ends_with('\n') {
+            println!();
+        }
+        Some(self.expect(&expect.args[1..], Some(&output)))
+    }
+}
+
+/// Whether `line` has `pattern`, a `*` of the pattern matches any text
+fn line_has(line: &str, pattern: &str) -> bool {
+    let mut rest = line;
+    for part in pattern.split('*') {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Waits for all the processes of a pipeline, its result is the result of its last command
+fn wait_pipeline(children: &mut [Child]) -> i32 {
+    let mut result = FAILED_RESULT;
+    for child in children.iter_mut() {
+        result = match child.wait() {
+            Ok(status) => status.code().unwrap_or(FAILED_RESULT),
+            Err(_) => FAILED_RESULT,
+        };
+    }
+    result
+}
+
+fn main() {
+    // SAFETY: no handler
+    if let Err(e) = unsafe { signal::set_action(SIGINT, Action::Ignore) } {
+        eprintln!("shell: can't ignore SIGINT: {e:?}");
+    }
+    let mut shell = Shell::new();
+
+    loop {
+        shell.report_jobs(false);
+        if let Some(result) = shell.last_result {
+            print!("{result} ");
+        }
+        print!("{} $ ", shell.cwd);
+        io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).unwrap() == 0 {
+            // stdin closed
+            break;
+        }
+        shell.run_line(&input);
+    }
+    if shell.failed_checks != 0 {
+        println!("[!] {} checks failed", shell.failed_checks);
+        std::process::exit(1);
     }
 }
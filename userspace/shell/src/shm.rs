@@ -0,0 +1,143 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode};
+
+use kernel_user_link::syscalls::SyscallError;
+use user_std::{
+    io,
+    process::{self, PROT_READ, PROT_WRITE, SHM_CREATE, SHM_EXCLUSIVE},
+};
+
+const PAGE: usize = 4096;
+const DEFAULT_PAGES: usize = 4;
+// the bytes written by each side, at the start of the pages
+const PARENT_MARK: u8 = 0x5A;
+const CHILD_MARK: u8 = 0xC3;
+const CHILD_BY_NAME_MARK: u8 = 0x3C;
+
+fn usage() -> ExitCode {
+    println!("Usage: shm [-p pages]");
+    ExitCode::FAILURE
+}
+
+fn create(name: &CString, size: usize, flags: u64) -> Result<usize, SyscallError> {
+    unsafe { process::shm_create(name, size as u64, flags) }
+}
+
+/// Maps `fd` writable, the pages as a slice
+fn map(fd: usize, size: usize) -> Result<&'static mut [u8], String> {
+    let addr = unsafe { process::shm_map(fd, PROT_READ | PROT_WRITE) }
+        .map_err(|e| format!("map: {e:?}"))?;
+    // SAFETY: the kernel mapped all of it, and it stays until `shm_unmap`
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr, size) })
+}
+
+/// Writes to the inherited mapping `pages` in a child, and to its own mapping of the object
+/// opened by `name`, the parent must see both
+fn check_child(name: &CString, pages: &mut [u8]) -> Result<(), String> {
+    let size = pages.len();
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            if pages[0] != PARENT_MARK {
+                std::process::exit(2);
+            }
+            pages[size - 1] = CHILD_MARK;
+            let Ok(fd) = create(name, 0, 0) else {
+                std::process::exit(3);
+            };
+            let Ok(own) = map(fd, size) else {
+                std::process::exit(4);
+            };
+            own[PAGE] = CHILD_BY_NAME_MARK;
+            std::process::exit(0);
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(0) => {}
+            other => return Err(format!("the child ended with {other:?}")),
+        },
+        Err(e) => return Err(format!("fork: {e:?}")),
+    }
+    if pages[size - 1] != CHILD_MARK || pages[PAGE] != CHILD_BY_NAME_MARK {
+        return Err(String::from("the writes of the child are not in the pages"));
+    }
+    Ok(())
+}
+
+fn run(count: usize) -> Result<(), String> {
+    let size = count * PAGE;
+    let name = CString::new(format!("shm_tool_{}", std::process::id())).unwrap();
+    let fd =
+        create(&name, size, SHM_CREATE | SHM_EXCLUSIVE).map_err(|e| format!("create: {e:?}"))?;
+
+    match unsafe { io::syscall_stat(fd) } {
+        Ok(stat) if stat.size == size as u64 => {}
+        other => return Err(format!("the size is not {size}: {other:?}")),
+    }
+    match create(&name, size, SHM_CREATE | SHM_EXCLUSIVE) {
+        Err(SyscallError::AlreadyExists) => {}
+        other => return Err(format!("created twice: {other:?}")),
+    }
+    let missing = CString::new("shm_tool_missing").unwrap();
+    match create(&missing, 0, 0) {
+        Err(SyscallError::FileNotFound) => {}
+        other => return Err(format!("opened a missing name: {other:?}")),
+    }
+
+    let pages = map(fd, size)?;
+    if pages.iter().any(|&byte| byte != 0) {
+        return Err(String::from("the new pages are not zeroed"));
+    }
+    pages[0] = PARENT_MARK;
+    check_child(&name, pages)?;
+    unsafe { io::syscall_close(fd).ok() };
+
+    // the pages stay mapped after the object is gone
+    if pages[0] != PARENT_MARK {
+        return Err(String::from("the pages changed after closing the object"));
+    }
+    let addr = pages.as_mut_ptr();
+    unsafe { process::shm_unmap(addr) }.map_err(|e| format!("unmap: {e:?}"))?;
+    match unsafe { process::shm_unmap(addr) } {
+        Err(SyscallError::InvalidArgument(..)) => {}
+        other => return Err(format!("unmapped twice: {other:?}")),
+    }
+    match create(&name, 0, 0) {
+        Err(SyscallError::FileNotFound) => Ok(()),
+        other => Err(format!(
+            "the name stayed after closing the object: {other:?}"
+        )),
+    }
+}
+
+/// Shm shell program
+///
+/// Usage: shm [-p pages]
+///
+/// Creates a named shared memory object of `pages` pages (4 by default), maps it, and forks a
+/// child that writes to the inherited mapping and to its own mapping of the object opened by
+/// name, the parent must see both writes. Also checks the errors of creating it twice,
+/// opening a missing name and unmapping twice, and that the name goes away with the last fd.
+fn main() -> ExitCode {
+    let mut count = DEFAULT_PAGES;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // the child writes to the second page
+            "-p" => match args.next().map(|value| value.parse::<usize>()) {
+                Some(Ok(value)) if value >= 2 => count = value,
+                _ => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+    match run(count) {
+        Ok(()) => {
+            println!("shm: {count} pages shared with the child");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] shm: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,286 @@
+#![feature(restricted_std)]
+
+use std::{
+    hint::black_box,
+    process::{Command, ExitCode},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use kernel_user_link::{
+    process::EXIT_CODE_CRASH,
+    syscalls::{SyscallArgError, SyscallError},
+};
+use user_std::{
+    input, process,
+    signal::{self, Action, SIGINT, SIGKILL, SIGSEGV, SIGTERM, SIGUSR1, SIGUSR2},
+    thread,
+    time::{self, Instant},
+};
+
+const PROGRAM_PATH: &str = "/signal";
+const INIT_PID: u64 = 0;
+// the exit code of `--segv handle`, from its handler
+const SEGV_HANDLED: i32 = 42;
+// how long `--spin` waits to be killed, so a failed test doesn't leave it running
+const SPIN_TIME: Duration = Duration::from_secs(10);
+// `Ctrl+C` on the active terminal, for `--interrupt`
+const CTRL_C_SCRIPT: &str = "key press 0x1D\nkey press 0x2E\nkey release 0x2E\nkey release 0x1D\n";
+
+// what `count_signal` saw
+static COUNT: AtomicU64 = AtomicU64::new(0);
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+fn usage() -> ExitCode {
+    println!("Usage: signal <pid> [<signal>] | --test | --interrupt");
+    ExitCode::FAILURE
+}
+
+fn own_pid() -> u64 {
+    std::process::id() as u64
+}
+
+fn kill(pid: u64, sig: u64) -> Result<(), SyscallError> {
+    unsafe { signal::kill(pid, sig) }
+}
+
+fn set_action(sig: u64, action: Action) -> Result<Action, String> {
+    unsafe { signal::set_action(sig, action) }
+        .map_err(|e| format!("set the action of {sig}: {e:?}"))
+}
+
+extern "C" fn count_signal(sig: u64) {
+    LAST.store(sig, Ordering::SeqCst);
+    COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn exit_on_segv(_sig: u64) {
+    unsafe { process::exit(SEGV_HANDLED) }
+}
+
+fn spawn(args: &[&str]) -> Result<std::process::Child, String> {
+    Command::new(PROGRAM_PATH)
+        .args(args)
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))
+}
+
+/// Runs `signal <args>` and returns its exit code
+fn run(args: &[&str]) -> Result<Option<i32>, String> {
+    let status = spawn(args)?.wait().map_err(|e| format!("wait: {e}"))?;
+    Ok(status.code())
+}
+
+/// The handler runs before `kill` returns to us
+fn test_handler() -> Result<(), String> {
+    COUNT.store(0, Ordering::SeqCst);
+    set_action(SIGUSR1, Action::Handle(count_signal))?;
+    // a value kept in the registers across the handler
+    let before = black_box(0x1234_5678u64);
+    kill(own_pid(), SIGUSR1).map_err(|e| format!("kill: {e:?}"))?;
+    if COUNT.load(Ordering::SeqCst) != 1 || LAST.load(Ordering::SeqCst) != SIGUSR1 {
+        return Err(format!(
+            "the handler ran {} times, with {}",
+            COUNT.load(Ordering::SeqCst),
+            LAST.load(Ordering::SeqCst)
+        ));
+    }
+    if black_box(before) != 0x1234_5678 {
+        return Err("the registers were not restored".into());
+    }
+    match set_action(SIGUSR1, Action::Default)? {
+        Action::Handle(handler) if handler as usize == count_signal as *const () as usize => Ok(()),
+        old => Err(format!("the old action was {old:?}")),
+    }
+}
+
+/// An ignored one does nothing, not even later when handled
+fn test_ignore() -> Result<(), String> {
+    COUNT.store(0, Ordering::SeqCst);
+    set_action(SIGUSR2, Action::Ignore)?;
+    kill(own_pid(), SIGUSR2).map_err(|e| format!("kill: {e:?}"))?;
+    match set_action(SIGUSR2, Action::Handle(count_signal))? {
+        Action::Ignore => {}
+        old => return Err(format!("the old action was {old:?}")),
+    }
+    set_action(SIGUSR2, Action::Default)?;
+    if COUNT.load(Ordering::SeqCst) != 0 {
+        return Err("the ignored signal was kept".into());
+    }
+    Ok(())
+}
+
+fn test_rules() -> Result<(), String> {
+    match unsafe { signal::set_action(SIGKILL, Action::Ignore) } {
+        Err(SyscallError::InvalidArgument(Some(SyscallArgError::GeneralInvalid), ..)) => {}
+        result => return Err(format!("ignoring SIGKILL gave {result:?}")),
+    }
+    match kill(own_pid(), 0) {
+        Err(SyscallError::InvalidArgument(None, Some(SyscallArgError::GeneralInvalid), ..)) => {}
+        result => return Err(format!("signal 0 gave {result:?}")),
+    }
+    match kill(INIT_PID, SIGTERM) {
+        Err(SyscallError::PermissionDenied) => {}
+        result => return Err(format!("signaling init gave {result:?}")),
+    }
+    match kill(u64::MAX, SIGTERM) {
+        Err(SyscallError::PidNotFound) => {}
+        result => return Err(format!("a missing pid gave {result:?}")),
+    }
+    Ok(())
+}
+
+/// The default action exits with `128 + signal`
+fn test_default_kill() -> Result<(), String> {
+    for sig in [SIGKILL, SIGTERM] {
+        let mut child = spawn(&["--spin"])?;
+        kill(child.id() as u64, sig).map_err(|e| format!("kill: {e:?}"))?;
+        let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+        if status.code() != Some(signal::exit_code(sig)) {
+            return Err(format!("killed with {sig}, it exited with {status:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// A handler of `SIGSEGV` runs for the page fault, a process without one crashes
+fn test_segv() -> Result<(), String> {
+    match run(&["--segv", "handle"])? {
+        Some(SEGV_HANDLED) => {}
+        code => return Err(format!("the one with a handler exited with {code:?}")),
+    }
+    match run(&["--segv"])? {
+        Some(EXIT_CODE_CRASH) => Ok(()),
+        code => Err(format!("the one without a handler exited with {code:?}")),
+    }
+}
+
+/// A handled signal stops the wait for a child, after the handler
+fn test_interrupted_wait() -> Result<(), String> {
+    COUNT.store(0, Ordering::SeqCst);
+    set_action(SIGUSR1, Action::Handle(count_signal))?;
+    let child = spawn(&["--spin"])?.id() as u64;
+    let pid = own_pid();
+    // SAFETY: it only does syscalls
+    let sender = unsafe {
+        thread::spawn(move || {
+            // the main thread waits by then
+            time::sleep(Duration::from_millis(100));
+            (signal::kill(pid, SIGUSR1), signal::kill(child, SIGKILL))
+        })
+    }
+    .map_err(|e| format!("thread: {e:?}"))?;
+
+    let interrupted = unsafe { process::wait(child) };
+    let sent = sender.join().map_err(|e| format!("join: {e:?}"))?;
+    // the handler can run on either thread, it did by the time both returned
+    let count = COUNT.load(Ordering::SeqCst);
+    let exited = unsafe { process::wait(child) };
+    set_action(SIGUSR1, Action::Default)?;
+    if !matches!(sent, (Ok(()), Ok(()))) {
+        return Err(format!("the kills gave {sent:?}"));
+    }
+    if !matches!(interrupted, Err(SyscallError::Interrupted)) || count != 1 {
+        return Err(format!("the wait gave {interrupted:?}, {count} handled"));
+    }
+    if !matches!(exited, Ok(code) if code == signal::exit_code(SIGKILL)) {
+        return Err(format!("the second wait gave {exited:?}"));
+    }
+    Ok(())
+}
+
+fn self_test() -> Result<(), String> {
+    test_handler()?;
+    test_ignore()?;
+    test_rules()?;
+    test_default_kill()?;
+    test_segv()?;
+    test_interrupted_wait()
+}
+
+/// `Ctrl+C` kills a child on our terminal, we ignore it
+fn interrupt() -> Result<(), String> {
+    set_action(SIGINT, Action::Ignore)?;
+    let mut child = spawn(&["--spin"])?;
+    input::run_script(CTRL_C_SCRIPT).map_err(|e| format!("input: {e:?}"))?;
+    input::wait_replayed().map_err(|e| format!("input: {e:?}"))?;
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    if status.code() != Some(signal::exit_code(SIGINT)) {
+        return Err(format!("the child exited with {status:?}"));
+    }
+    Ok(())
+}
+
+/// Waits for a signal to kill us, for [`SPIN_TIME`]
+fn spin() -> ExitCode {
+    let start = Instant::now();
+    while start.elapsed() < SPIN_TIME {
+        black_box(());
+    }
+    println!("[!] signal: not killed");
+    ExitCode::FAILURE
+}
+
+/// Faults on an address nothing is mapped at, with `exit_on_segv` as the handler if `handle`
+fn segv(handle: bool) -> ExitCode {
+    if handle {
+        if let Err(e) = set_action(SIGSEGV, Action::Handle(exit_on_segv)) {
+            println!("[!] signal: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let address = black_box(0xDEAD_0000usize) as *mut u64;
+    unsafe { core::ptr::write_volatile(address, 1) };
+    println!("[!] signal: did not fault");
+    ExitCode::FAILURE
+}
+
+/// Signal shell program
+///
+/// Usage: signal <pid> [<signal>]
+///        signal --spin
+///        signal --segv [handle]
+///        signal --test
+///        signal --interrupt
+///
+/// Sends `signal` (`SIGTERM` by default) to the process `pid`. `--spin` waits for a while to be
+/// killed, and `--segv` faults, with a handler that exits with 42 with `handle`, for `--test`,
+/// which checks that the handlers run and return to the interrupted code, ignoring, the rules,
+/// the default action of `SIGKILL` and `SIGTERM`, the handler of `SIGSEGV`, and that a handled
+/// signal interrupts the wait for a child. `--interrupt` types `Ctrl+C` on the active terminal,
+/// which must be the one of this program, and checks that it kills a child
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        ["--spin"] => return spin(),
+        ["--segv"] => return segv(false),
+        ["--segv", "handle"] => return segv(true),
+        ["--test"] => self_test(),
+        ["--interrupt"] => interrupt(),
+        [pid, rest @ ..] if rest.len() <= 1 && !pid.starts_with('-') => {
+            let Ok(pid) = pid.parse::<u64>() else {
+                return usage();
+            };
+            let sig = match rest.first().map(|sig| sig.parse::<u64>()) {
+                None => SIGTERM,
+                Some(Ok(sig)) => sig,
+                Some(Err(_)) => return usage(),
+            };
+            kill(pid, sig).map_err(|e| format!("{e:?}"))
+        }
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => {
+            if matches!(args.as_slice(), ["--test"] | ["--interrupt"]) {
+                println!("signal: test passed");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] signal: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
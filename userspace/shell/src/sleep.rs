@@ -0,0 +1,225 @@
+#![feature(restricted_std)]
+
+use std::{
+    process::{Command, ExitCode},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use kernel_user_link::{
+    process::{Resource, EXIT_CODE_CPU_LIMIT},
+    syscalls::SyscallError,
+};
+use user_std::{
+    process,
+    signal::{self, Action, SIGTERM, SIGUSR1},
+    syscalls, thread,
+    time::{self, Instant},
+};
+
+const PROGRAM_PATH: &str = "/sleep";
+// what a sleep can take more than asked, the next tick and the turns of the others
+const LATE: Duration = Duration::from_secs(1);
+// for `--limited`, it would use more than its CPU limit if it spun
+const LIMITED_SLEEP: Duration = Duration::from_secs(1);
+const LIMITED_CPU_MS: u64 = 200;
+
+// the signals `count_signal` got
+static COUNT: AtomicU64 = AtomicU64::new(0);
+// the sleeps of `test_order`, in the order the threads woke
+static WOKEN: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+static WOKEN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn usage() -> ExitCode {
+    println!("Usage: sleep <seconds>[ms] | --test");
+    ExitCode::FAILURE
+}
+
+fn own_pid() -> u64 {
+    std::process::id() as u64
+}
+
+extern "C" fn count_signal(_sig: u64) {
+    COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// `10` is 10 seconds, and `10ms` 10 milliseconds
+fn parse_duration(arg: &str) -> Option<Duration> {
+    match arg.strip_suffix("ms") {
+        Some(ms) => ms.parse().ok().map(Duration::from_millis),
+        None => arg.parse().ok().map(Duration::from_secs),
+    }
+}
+
+/// Runs `f` and checks it took `expected` at least, and not much more
+fn check_takes(what: &str, expected: Duration, f: impl FnOnce()) -> Result<(), String> {
+    let start = Instant::now();
+    f();
+    let took = start.elapsed();
+    if took < expected || took > expected + LATE {
+        return Err(format!("{what} took {took:?}, not {expected:?}"));
+    }
+    Ok(())
+}
+
+fn test_durations() -> Result<(), String> {
+    check_takes("a sleep of 0", Duration::ZERO, || {
+        time::sleep(Duration::ZERO)
+    })?;
+    for ms in [1, 50, 300] {
+        let duration = Duration::from_millis(ms);
+        check_takes(&format!("a sleep of {ms}ms"), duration, || {
+            time::sleep(duration)
+        })?;
+    }
+    Ok(())
+}
+
+/// The threads sleeping for different times wake in the order of their deadlines
+fn test_order() -> Result<(), String> {
+    WOKEN_COUNT.store(0, Ordering::SeqCst);
+    let mut handles = Vec::new();
+    for ms in [300u64, 100, 200] {
+        // SAFETY: they only use the atomics
+        let handle = unsafe {
+            thread::spawn(move || {
+                time::sleep(Duration::from_millis(ms));
+                WOKEN[WOKEN_COUNT.fetch_add(1, Ordering::SeqCst)].store(ms, Ordering::SeqCst);
+            })
+        }
+        .map_err(|e| format!("thread: {e:?}"))?;
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().map_err(|e| format!("join: {e:?}"))?;
+    }
+    let woken = WOKEN.each_ref().map(|ms| ms.load(Ordering::SeqCst));
+    if woken != [100, 200, 300] {
+        return Err(format!("the threads woke in the order {woken:?}"));
+    }
+    Ok(())
+}
+
+/// A handled signal stops `SYS_SLEEP` early, `time::sleep` sleeps for the rest after it
+fn test_interrupted() -> Result<(), String> {
+    COUNT.store(0, Ordering::SeqCst);
+    unsafe { signal::set_action(SIGUSR1, Action::Handle(count_signal)) }
+        .map_err(|e| format!("sigaction: {e:?}"))?;
+    let pid = own_pid();
+    let send_later = |delay| unsafe {
+        thread::spawn(move || {
+            time::sleep(delay);
+            signal::kill(pid, SIGUSR1)
+        })
+    };
+
+    let sender = send_later(Duration::from_millis(100)).map_err(|e| format!("thread: {e:?}"))?;
+    let start = Instant::now();
+    let result = unsafe { syscalls::sleep(Duration::from_secs(10).as_nanos() as u64) };
+    let took = start.elapsed();
+    let sent = sender.join().map_err(|e| format!("join: {e:?}"))?;
+    if !matches!(result, Err(SyscallError::Interrupted)) || took > LATE || sent.is_err() {
+        return Err(format!(
+            "the syscall gave {result:?} after {took:?}, kill {sent:?}"
+        ));
+    }
+
+    let sender = send_later(Duration::from_millis(100)).map_err(|e| format!("thread: {e:?}"))?;
+    let duration = Duration::from_millis(300);
+    check_takes("an interrupted sleep", duration, || time::sleep(duration))?;
+    sender
+        .join()
+        .map_err(|e| format!("join: {e:?}"))?
+        .map_err(|e| format!("kill: {e:?}"))?;
+    unsafe { signal::set_action(SIGUSR1, Action::Default) }
+        .map_err(|e| format!("sigaction: {e:?}"))?;
+
+    let count = COUNT.load(Ordering::SeqCst);
+    if count != 2 {
+        return Err(format!("the handler ran {count} times"));
+    }
+    Ok(())
+}
+
+fn spawn(args: &[&str]) -> Result<std::process::Child, String> {
+    Command::new(PROGRAM_PATH)
+        .args(args)
+        .spawn()
+        .map_err(|e| format!("spawn: {e}"))
+}
+
+/// A sleeping process uses no CPU time, and can be killed
+fn test_children() -> Result<(), String> {
+    let status = spawn(&["--limited"])?
+        .wait()
+        .map_err(|e| format!("wait: {e}"))?;
+    match status.code() {
+        Some(0) => {}
+        Some(EXIT_CODE_CPU_LIMIT) => return Err("the sleep used the CPU".into()),
+        code => return Err(format!("the limited child exited with {code:?}")),
+    }
+
+    let mut child = spawn(&["10"])?;
+    time::sleep(Duration::from_millis(100));
+    unsafe { signal::kill(child.id() as u64, SIGTERM) }.map_err(|e| format!("kill: {e:?}"))?;
+    let status = child.wait().map_err(|e| format!("wait: {e}"))?;
+    if status.code() != Some(signal::exit_code(SIGTERM)) {
+        return Err(format!("the killed child exited with {status:?}"));
+    }
+    Ok(())
+}
+
+fn self_test() -> Result<(), String> {
+    test_durations()?;
+    test_order()?;
+    test_interrupted()?;
+    test_children()
+}
+
+/// Sleeps for longer than its CPU time limit
+fn limited() -> Result<(), String> {
+    unsafe { process::set_rlimit(own_pid(), Resource::CpuTimeMs, LIMITED_CPU_MS) }
+        .map_err(|e| format!("set_rlimit: {e:?}"))?;
+    time::sleep(LIMITED_SLEEP);
+    Ok(())
+}
+
+/// Sleep shell program
+///
+/// Usage: sleep <seconds>[ms]
+///        sleep --test
+///        sleep --limited
+///
+/// Sleeps for `seconds`, or milliseconds with `ms`. `--test` checks the lengths of the sleeps,
+/// that threads wake in the order of their deadlines, that a handled signal stops the syscall
+/// early but not `user_std::time::sleep`, and with children that a sleep uses no CPU time (with
+/// `--limited`, which sleeps for longer than its CPU time limit) and that a sleeping process
+/// can be killed
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        ["--test"] => self_test(),
+        ["--limited"] => limited(),
+        [duration] => match parse_duration(duration) {
+            Some(duration) => {
+                time::sleep(duration);
+                return ExitCode::SUCCESS;
+            }
+            None => return usage(),
+        },
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => {
+            if args.as_slice() == ["--test"] {
+                println!("sleep: test passed");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] sleep: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,129 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, hint::black_box, process::ExitCode};
+
+use kernel_user_link::{process::EXIT_CODE_CRASH, syscalls::SyscallError};
+use user_std::{
+    io,
+    process::{self, Resource},
+};
+
+const PAGE: usize = 4096;
+// read into the stack far below what it used so far, by the kernel
+const FILE_PATH: &str = "/message.txt";
+// how far below, more than the initial stack
+const UNTOUCHED_OFFSET: usize = 256 * 1024;
+// the stack taken by each call of `use_stack`, its array and a bit more
+const FRAME: usize = PAGE;
+const DEFAULT_USE_KB: usize = 2048;
+// the limit of the child that overflows, below what the parent grew to
+const OVERFLOW_LIMIT: u64 = 1024 * 1024;
+
+fn usage() -> ExitCode {
+    println!("Usage: stackgrow [-k used_kb] [--overflow]");
+    ExitCode::FAILURE
+}
+
+/// Recurses `depth` times with a page of stack in each call, returns the sum of a byte of
+/// each, so none of the frames is optimized away
+#[inline(never)]
+fn use_stack(depth: usize) -> u64 {
+    let mut frame = [0u8; FRAME];
+    frame[0] = depth as u8;
+    frame[FRAME - 1] = 1;
+    black_box(&mut frame);
+    if depth == 0 {
+        return frame[FRAME - 1] as u64;
+    }
+    black_box(use_stack(depth - 1)) + frame[FRAME - 1] as u64
+}
+
+/// Reads the start of [`FILE_PATH`] into `buf` with `SYS_READ`
+fn read_file(buf: &mut [u8]) -> Result<u64, SyscallError> {
+    let path = CString::new(FILE_PATH).unwrap();
+    let fd = unsafe { io::syscall_open(&path, 0, 0) }?;
+    let read = unsafe { io::syscall_read(fd, buf) };
+    unsafe { io::syscall_close(fd).ok() };
+    read
+}
+
+/// Makes the kernel write below the stack, where the process never went, it must grow the
+/// stack there instead of refusing the pointer
+fn check_kernel_write() -> Result<(), String> {
+    let here = black_box(0u8);
+    let address = (&here as *const u8 as usize - UNTOUCHED_OFFSET) & !(PAGE - 1);
+    // SAFETY: far below the stack pointer, which nothing else uses while we run, and
+    // only kept for this function
+    let buf = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, 64) };
+    match read_file(buf) {
+        Ok(read) if read > 0 => Ok(()),
+        other => Err(format!(
+            "SYS_READ below the stack at {address:#x}: {other:?}"
+        )),
+    }
+}
+
+/// A child past the [`OVERFLOW_LIMIT`] of its stack, which must be killed for it
+fn check_overflow() -> Result<(), String> {
+    let pid = std::process::id() as u64;
+    // the children get it, we only return from here
+    unsafe { process::set_rlimit(pid, Resource::StackSize, OVERFLOW_LIMIT) }
+        .map_err(|e| format!("set the stack limit: {e:?}"))?;
+    match unsafe { process::fork() } {
+        Ok(0) => {
+            let used = use_stack(usize::MAX / 2);
+            std::process::exit(used as i32);
+        }
+        Ok(pid) => match unsafe { process::wait_for_pid(pid, true) } {
+            Ok(EXIT_CODE_CRASH) => Ok(()),
+            other => Err(format!("the child that overflowed ended with {other:?}")),
+        },
+        Err(e) => Err(format!("fork: {e:?}")),
+    }
+}
+
+/// Stackgrow shell program
+///
+/// Usage: stackgrow [-k used_kb] [--overflow]
+///
+/// Has the kernel write far below the stack before the process touched it, which grows the
+/// stack instead of failing the syscall, then recurses until it used `used_kb` KB of stack
+/// (2048 by default), more than the process starts with. With `--overflow`, the stack limit
+/// is lowered to 1 MB, and a child that recurses forever must crash for it.
+fn main() -> ExitCode {
+    let mut used_kb = DEFAULT_USE_KB;
+    let mut overflow = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--overflow" => overflow = true,
+            "-k" => match args.next().map(|value| value.parse::<usize>()) {
+                Some(Ok(value)) if value > 0 => used_kb = value,
+                _ => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+
+    let depth = used_kb * 1024 / FRAME;
+    let result = check_kernel_write().and_then(|()| {
+        // one for each frame, and the last one
+        if use_stack(depth) != depth as u64 + 1 {
+            return Err(String::from("the frames changed"));
+        }
+        if overflow {
+            check_overflow()?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => {
+            println!("stackgrow: the stack grew to {used_kb} KB");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] stackgrow: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
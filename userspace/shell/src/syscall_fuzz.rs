@@ -0,0 +1,783 @@
+#![feature(restricted_std)]
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::amjad_os::io::{FromRawFd, IntoRawFd, OwnedFd},
+    process::{Child, ChildStdin, Command, ExitCode, Stdio},
+};
+
+use kernel_user_link::{
+    call_syscall,
+    file::{
+        self, BlockingMode, EVENT_SOURCE_NONE, LOCK_EXCLUSIVE, LOCK_NONBLOCK, LOCK_SHARED,
+        LOCK_UNLOCK,
+    },
+    process::{SpawnFileMapping, PROT_EXEC, PROT_READ, PROT_WRITE},
+    syscalls::{
+        SyscallArgError, SyscallError, SyscallResult, SyscallTrace, NUM_SYSCALLS,
+        SYS_BLOCKING_MODE, SYS_CLOCK_GETTIME, SYS_CLOSE, SYS_COPY_FILE_RANGE, SYS_CREATE_PIPE,
+        SYS_EVENT_CREATE, SYS_EXEC, SYS_EXIT, SYS_FLOCK, SYS_FORK, SYS_FSYNC, SYS_FTRUNCATE,
+        SYS_FUTEX_WAIT, SYS_FUTEX_WAKE, SYS_GET_NAME, SYS_GET_PRIORITY, SYS_GET_RLIMIT,
+        SYS_INC_HEAP, SYS_KILL, SYS_MKDIR, SYS_MMAP, SYS_MOUNT, SYS_MPROTECT, SYS_MUNMAP, SYS_OPEN,
+        SYS_READ, SYS_READ_DIR, SYS_RENAME, SYS_RMDIR, SYS_SET_NAME, SYS_SET_PRIORITY,
+        SYS_SET_RLIMIT, SYS_SHM_CREATE, SYS_SHM_MAP, SYS_SHM_UNMAP, SYS_SIGACTION, SYS_SIGRETURN,
+        SYS_SLEEP, SYS_SPAWN, SYS_STAT, SYS_SYNC, SYS_SYSINFO, SYS_THREAD_CREATE, SYS_THREAD_EXIT,
+        SYS_TRUNCATE, SYS_UMOUNT, SYS_UNLINK, SYS_WAIT_PID, SYS_WRITE,
+    },
+    sysinfo::SysInfo,
+};
+
+const PAGE: u64 = 0x1000;
+// the kernel writes at most 64 KB at a time, starting in the first page, so it all stays in here
+const SCRATCH_SIZE: usize = 17 * PAGE as usize;
+const DEFAULT_ITERATIONS: u64 = 1_000_000;
+// close all the files we got every this many iterations
+const CLOSE_EVERY: u64 = 256;
+const HEARTBEAT_EVERY: u64 = 10_000;
+// how many times to poll for the heartbeat before calling it a hang
+const HEARTBEAT_POLLS: u64 = 1_000_000;
+
+const KERNEL_ADDRESS: u64 = 0xFFFF_FFFF_8000_0000;
+const KERNEL_HEAP_ADDRESS: u64 = 0xFFFF_8000_0000_0000;
+const NON_CANONICAL_ADDRESS: u64 = 0x0000_8000_0000_0000;
+/// Pointers that are never ours, so the kernel can't corrupt us when writing to them
+const BAD_POINTERS: &[u64] = &[
+    0,
+    8,
+    KERNEL_ADDRESS,
+    KERNEL_HEAP_ADDRESS,
+    NON_CANONICAL_ADDRESS,
+    NON_CANONICAL_ADDRESS - 8,
+    u64::MAX,
+    u64::MAX - 7,
+];
+const LENGTHS: &[u64] = &[
+    0,
+    1,
+    7,
+    8,
+    24,
+    PAGE,
+    PAGE + 1,
+    64 * 1024,
+    64 * 1024 + 1,
+    1 << 47,
+    u64::MAX / 2,
+    u64::MAX - 7,
+    u64::MAX,
+];
+// only under `/tmp/fuzz`, so nothing else is touched
+const PATHS: &[&[u8]] = &[
+    b"/tmp/fuzz\0",
+    b"/tmp/fuzz/a\0",
+    b"/tmp/fuzz/a/b\0",
+    b"/tmp/fuzz/c\0",
+    b"/tmp/fuzz_file\0",
+    b"/tmp/fuzz/\xff\xfe\0",
+    b"\0",
+];
+const FSTYPES: &[&[u8]] = &[b"ramfs\0", b"fat\0", b"devices\0", b"nope\0"];
+// the shared memory objects go away with the files, when they are all closed
+const SHM_NAMES: &[&[u8]] = &[b"fuzz\0", b"fuzz_b\0", b"\0"];
+
+/// `xorshift64`, the same seed gives the same syscalls
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must not be `0`
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15 | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
+
+fn syscall(num: u64, args: [u64; 7]) -> SyscallResult {
+    unsafe { call_syscall!(num, args[0], args[1], args[2], args[3], args[4], args[5], args[6]) }
+}
+
+fn set_non_blocking(fd: u64) {
+    unsafe { call_syscall!(SYS_BLOCKING_MODE, fd, BlockingMode::None.to_u64()).ok() };
+}
+
+/// A child that answers each byte on its stdin with a byte on its stdout after a syscall, if it
+/// stops answering the kernel hangs
+struct Heartbeat {
+    child: Child,
+    ping: Option<ChildStdin>,
+    pong_fd: u64,
+}
+
+impl Heartbeat {
+    fn start() -> Result<Self, String> {
+        let mut fds = [0u64; 2];
+        unsafe {
+            call_syscall!(
+                SYS_CREATE_PIPE,
+                fds.as_mut_ptr() as u64,
+                fds.as_mut_ptr().add(1) as u64
+            )
+        }
+        .map_err(|e| format!("could not create the heartbeat pipe: {e:?}"))?;
+        set_non_blocking(fds[0]);
+        // SAFETY: the kernel just gave us this fd, and its not used anywhere else
+        let pong_write = File::from(unsafe { OwnedFd::from_raw_fd(fds[1] as _) });
+
+        let mut child = Command::new("/syscall_fuzz")
+            .arg("--heartbeat")
+            .stdin(Stdio::piped())
+            .stdout(pong_write)
+            .spawn()
+            .map_err(|e| format!("could not spawn the heartbeat: {e}"))?;
+        Ok(Self {
+            ping: child.stdin.take(),
+            child,
+            pong_fd: fds[0],
+        })
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        let ping = self.ping.as_mut().unwrap();
+        ping.write_all(b"p")
+            .map_err(|e| format!("could not ping the heartbeat: {e}"))?;
+        let mut pong = [0u8; 1];
+        for _ in 0..HEARTBEAT_POLLS {
+            let read =
+                unsafe { call_syscall!(SYS_READ, self.pong_fd, pong.as_mut_ptr() as u64, 1) }
+                    .map_err(|e| format!("could not read the heartbeat: {e:?}"))?;
+            if read == 1 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(format!("no heartbeat after {HEARTBEAT_POLLS} polls"))
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // closing its stdin makes it exit
+        self.ping.take();
+        self.child.wait().ok();
+        unsafe { call_syscall!(SYS_CLOSE, self.pong_fd).ok() };
+    }
+}
+
+struct Fuzzer {
+    rng: Rng,
+    scratch: *mut u8,
+    // more than the longest string argument without a null terminator
+    no_null: u64,
+    argv: [u64; 2],
+    own_pid: u64,
+    heartbeat_pid: u64,
+    heap_end: u64,
+    // the fds before this are not ours to touch, like std's and the heartbeat's
+    first_fd: u64,
+    closed_up_to: u64,
+    recent_fds: Vec<u64>,
+    successes: u64,
+}
+
+impl Fuzzer {
+    fn new(seed: u64, heartbeat_pid: u64) -> Result<Self, SyscallError> {
+        // fds are never reused, so all the later ones are opened here
+        let first_fd = unsafe { call_syscall!(SYS_EVENT_CREATE, EVENT_SOURCE_NONE)? };
+        unsafe { call_syscall!(SYS_CLOSE, first_fd)? };
+
+        let scratch = Box::leak(vec![0u8; SCRATCH_SIZE].into_boxed_slice());
+        let no_null = Box::leak(vec![b'a'; 2 * PAGE as usize].into_boxed_slice());
+        Ok(Self {
+            rng: Rng::new(seed),
+            scratch: scratch.as_mut_ptr(),
+            no_null: no_null.as_ptr() as u64,
+            argv: [PATHS[0].as_ptr() as u64, 0],
+            own_pid: std::process::id() as u64,
+            heartbeat_pid,
+            heap_end: unsafe { call_syscall!(SYS_INC_HEAP, 0)? },
+            first_fd,
+            closed_up_to: first_fd,
+            recent_fds: Vec::new(),
+            successes: 0,
+        })
+    }
+
+    fn any(&mut self) -> u64 {
+        match self.rng.below(4) {
+            0 => self.rng.below(16),
+            1 => self.rng.pick(LENGTHS),
+            _ => self.rng.next(),
+        }
+    }
+
+    /// A pointer the kernel can write to, so our own memory is only the first page of the
+    /// scratch buffer
+    fn write_ptr(&mut self) -> u64 {
+        match self.rng.below(8) {
+            0..=3 => self.scratch as u64 + (self.rng.below(PAGE) & !7),
+            4 | 5 => self.rng.pick(BAD_POINTERS),
+            // read only
+            6 => main as *const () as u64,
+            _ => self.rng.next() | KERNEL_HEAP_ADDRESS,
+        }
+    }
+
+    fn read_ptr(&mut self) -> u64 {
+        match self.rng.below(8) {
+            0 => self.heap_end,
+            1 => self.heap_end - 8,
+            2 => self.no_null,
+            3 => self.path(),
+            4 => self.rng.next(),
+            _ => self.write_ptr(),
+        }
+    }
+
+    fn path(&mut self) -> u64 {
+        match self.rng.below(8) {
+            0 => self.no_null,
+            1 => self.rng.pick(BAD_POINTERS),
+            _ => self.rng.pick(PATHS).as_ptr() as u64,
+        }
+    }
+
+    fn len(&mut self) -> u64 {
+        match self.rng.below(3) {
+            0 => self.rng.below(2 * PAGE),
+            _ => self.rng.pick(LENGTHS),
+        }
+    }
+
+    fn fd(&mut self) -> u64 {
+        let fd = match self.rng.below(6) {
+            0..=2 if !self.recent_fds.is_empty() => self.rng.pick(&self.recent_fds),
+            0..=3 => self.first_fd + self.rng.below(64),
+            4 => self.rng.pick(&[u64::MAX, 1 << 32, u64::MAX / 2]),
+            _ => self.rng.next(),
+        };
+        if fd < self.first_fd {
+            fd + self.first_fd
+        } else {
+            fd
+        }
+    }
+
+    fn pid(&mut self) -> u64 {
+        match self.rng.below(6) {
+            0 => self.own_pid,
+            1 => self.heartbeat_pid,
+            2 => 0,
+            3 => self.rng.below(64),
+            4 => u64::MAX,
+            _ => self.rng.next(),
+        }
+    }
+
+    fn syscall_number(&mut self) -> u64 {
+        let num = match self.rng.below(16) {
+            0 => self.rng.next(),
+            1 => NUM_SYSCALLS as u64 + self.rng.below(4),
+            _ => self.rng.below(NUM_SYSCALLS as u64),
+        };
+        match num {
+            // we would be gone, or be two, or another program, or run from anywhere
+            SYS_EXIT | SYS_FORK | SYS_EXEC | SYS_THREAD_CREATE | SYS_THREAD_EXIT | SYS_KILL
+            | SYS_SIGACTION | SYS_SIGRETURN => SYS_SYSINFO,
+            // the random lengths are years
+            SYS_SLEEP => SYS_CLOCK_GETTIME,
+            // no other thread would wake it
+            SYS_FUTEX_WAIT => SYS_FUTEX_WAKE,
+            // each one writes back all the dirty caches, so keep them rare
+            SYS_SYNC if self.rng.below(64) != 0 => SYS_STAT,
+            num => num,
+        }
+    }
+
+    fn args(&mut self, num: u64) -> [u64; 7] {
+        let mut args = [0; 7];
+        for arg in &mut args {
+            *arg = self.any();
+        }
+        match num {
+            SYS_OPEN => {
+                args[0] = self.path();
+                args[2] = self.rng.pick(&[0, 1, 2, 3, 4, args[2]]);
+            }
+            SYS_WRITE => {
+                args[0] = self.fd();
+                args[1] = self.read_ptr();
+                args[2] = self.len();
+            }
+            SYS_READ | SYS_READ_DIR => {
+                args[0] = self.fd();
+                args[1] = self.write_ptr();
+                args[2] = self.len();
+                args[3] = self.rng.pick(&[0, 1, 2, args[3]]);
+            }
+            SYS_CLOSE | SYS_FSYNC => args[0] = self.fd(),
+            SYS_BLOCKING_MODE => {
+                args[0] = self.fd();
+                args[1] = self.rng.pick(&[0, 1, 2, 3, 7, args[1]]);
+                // a blocking read could wait forever
+                if !matches!(
+                    file::parse_blocking_mode(args[1]),
+                    None | Some(BlockingMode::None)
+                ) {
+                    args[1] = BlockingMode::None.to_u64();
+                }
+            }
+            SYS_SPAWN => {
+                args[0] = self.path();
+                args[1] = match self.rng.below(3) {
+                    0 => self.argv.as_ptr() as u64,
+                    _ => self.read_ptr(),
+                };
+                args[2] = self.read_ptr();
+                args[3] = self.rng.pick(&[0, 1, 2, 16, 1 << 40, args[3]]);
+            }
+            SYS_CREATE_PIPE => {
+                args[0] = self.write_ptr();
+                args[1] = self.write_ptr();
+            }
+            SYS_WAIT_PID => {
+                args[0] = self.pid();
+                args[1] = self.rng.pick(&[0, 1, 2, args[1]]);
+                // it never exits while we run
+                if args[0] == self.heartbeat_pid {
+                    args[1] = 0;
+                }
+            }
+            SYS_SYSINFO => args[0] = self.write_ptr(),
+            SYS_SET_RLIMIT | SYS_GET_RLIMIT => {
+                args[0] = self.pid();
+                args[1] = self.rng.below(5);
+                // lowering our limits (or the heartbeat's) would break the run, not the kernel
+                if num == SYS_SET_RLIMIT && [self.own_pid, self.heartbeat_pid].contains(&args[0]) {
+                    args[0] = u64::MAX;
+                }
+            }
+            SYS_SET_PRIORITY | SYS_GET_PRIORITY => {
+                args[0] = self.pid();
+                args[1] = self.rng.pick(&[0, 39, 40, args[1]]);
+                // lowering our priority (or the heartbeat's) would slow the run, not the kernel
+                if num == SYS_SET_PRIORITY && [self.own_pid, self.heartbeat_pid].contains(&args[0])
+                {
+                    args[0] = u64::MAX;
+                }
+            }
+            SYS_STAT => {
+                args[0] = self.fd();
+                args[1] = self.write_ptr();
+            }
+            SYS_UNLINK | SYS_MKDIR | SYS_RMDIR => args[0] = self.path(),
+            SYS_RENAME => {
+                args[0] = self.path();
+                args[1] = self.path();
+            }
+            SYS_EVENT_CREATE => args[0] = self.rng.pick(&[0, 1, 2, args[0]]),
+            SYS_CLOCK_GETTIME => args[0] = self.rng.pick(&[0, 1, 2, args[0]]),
+            // any of our pages can be there, so they stay writable
+            SYS_MPROTECT => {
+                args[2] = self
+                    .rng
+                    .pick(&[PROT_READ | PROT_WRITE | PROT_EXEC, 0, args[2]]);
+                if args[2] & PROT_READ != 0 {
+                    args[2] |= PROT_WRITE;
+                }
+            }
+            SYS_SET_NAME => {
+                args[0] = self.pid();
+                args[1] = self.path();
+            }
+            SYS_SHM_CREATE => {
+                args[0] = match self.rng.below(4) {
+                    0 => self.path(),
+                    _ => self.rng.pick(SHM_NAMES).as_ptr() as u64,
+                };
+                args[1] = self.len();
+                args[2] = self.rng.pick(&[0, 1, 3, args[2]]);
+            }
+            SYS_SHM_MAP => {
+                args[0] = self.fd();
+                args[1] = self
+                    .rng
+                    .pick(&[PROT_READ, PROT_READ | PROT_WRITE, 0, args[1]]);
+            }
+            SYS_MMAP => {
+                args[0] = self.fd();
+                args[1] = self.rng.pick(&[0, PAGE, 1, args[1]]);
+                args[2] = self.len();
+                args[3] = self
+                    .rng
+                    .pick(&[PROT_READ, PROT_READ | PROT_WRITE, 0, args[3]]);
+            }
+            SYS_GET_NAME => {
+                args[0] = self.pid();
+                args[1] = self.write_ptr();
+                args[2] = self.len();
+            }
+            // the targets are only under `/tmp/fuzz` too, they are all unmounted at the end
+            SYS_MOUNT => {
+                args[0] = self.path();
+                args[1] = self.path();
+                args[2] = match self.rng.below(8) {
+                    0 => self.path(),
+                    _ => self.rng.pick(FSTYPES).as_ptr() as u64,
+                };
+                args[3] = self.rng.pick(&[0, 1, 2, args[3]]);
+            }
+            SYS_UMOUNT => args[0] = self.path(),
+            SYS_COPY_FILE_RANGE => {
+                args[0] = self.fd();
+                args[2] = self.fd();
+                args[4] = self.len();
+                for offset in [1, 3] {
+                    args[offset] = match self.rng.below(3) {
+                        0 => 0,
+                        _ => self.write_ptr(),
+                    };
+                }
+            }
+            SYS_FTRUNCATE => {
+                args[0] = self.fd();
+                args[1] = self.len();
+            }
+            SYS_TRUNCATE => {
+                args[0] = self.path();
+                args[1] = self.len();
+            }
+            SYS_FLOCK => {
+                args[0] = self.fd();
+                // never waiting, it could be for a lock of our own files that we never release
+                args[1] = self.rng.pick(&[
+                    LOCK_SHARED | LOCK_NONBLOCK,
+                    LOCK_EXCLUSIVE | LOCK_NONBLOCK,
+                    LOCK_UNLOCK,
+                    LOCK_UNLOCK | LOCK_NONBLOCK,
+                    args[1] | LOCK_NONBLOCK,
+                ]);
+            }
+            _ => {}
+        }
+        args
+    }
+
+    /// Only grows the heap, and gives it back right away, shrinking it would free `std`'s memory
+    fn inc_heap(&mut self) -> SyscallResult {
+        let increment = match self.rng.below(4) {
+            0 => self.rng.below(17) * PAGE,
+            1 => 1 << 40,
+            2 => i64::MAX as u64 & !(PAGE - 1),
+            // never aligned
+            _ => self.rng.next() | 1,
+        };
+        let result = unsafe { call_syscall!(SYS_INC_HEAP, increment) };
+        if result.is_ok() {
+            unsafe { call_syscall!(SYS_INC_HEAP, -(increment as i64) as u64) }
+                .expect("could not give back the heap");
+        }
+        result
+    }
+
+    /// Maps with `num`, and unmaps what it mapped right away with `unmap`, so the memory
+    /// doesn't build up
+    fn map(&mut self, num: u64, unmap: u64, args: [u64; 7]) -> SyscallResult {
+        let result = syscall(num, args);
+        if let Ok(addr) = result {
+            unsafe { call_syscall!(unmap, addr) }.expect("could not unmap the mapping");
+        }
+        result
+    }
+
+    fn new_fd(&mut self, fd: u64) {
+        set_non_blocking(fd);
+        if self.recent_fds.len() == 8 {
+            self.recent_fds.remove(0);
+        }
+        self.recent_fds.push(fd);
+    }
+
+    fn after(&mut self, num: u64, args: &[u64; 7], result: &SyscallResult) {
+        let Ok(value) = result else {
+            return;
+        };
+        self.successes += 1;
+        match num {
+            SYS_OPEN | SYS_EVENT_CREATE | SYS_SHM_CREATE => self.new_fd(*value),
+            SYS_CREATE_PIPE => {
+                // it only succeeds with pointers into the scratch buffer
+                let read_fd = unsafe { (args[0] as *const u64).read_volatile() };
+                let write_fd = unsafe { (args[1] as *const u64).read_volatile() };
+                self.new_fd(read_fd);
+                self.new_fd(write_fd);
+            }
+            _ => {}
+        }
+    }
+
+    fn close_fds(&mut self) -> Result<(), SyscallError> {
+        let next_fd = unsafe { call_syscall!(SYS_EVENT_CREATE, EVENT_SOURCE_NONE)? };
+        for fd in self.closed_up_to..=next_fd {
+            unsafe { call_syscall!(SYS_CLOSE, fd).ok() };
+        }
+        self.closed_up_to = next_fd + 1;
+        self.recent_fds.clear();
+        Ok(())
+    }
+
+    fn run(&mut self, iterations: u64, heartbeat: &mut Heartbeat) -> Result<(), String> {
+        for i in 1..=iterations {
+            let num = self.syscall_number();
+            let args = self.args(num);
+            let result = match num {
+                SYS_INC_HEAP => self.inc_heap(),
+                SYS_SHM_MAP => self.map(SYS_SHM_MAP, SYS_SHM_UNMAP, args),
+                SYS_MMAP => self.map(SYS_MMAP, SYS_MUNMAP, args),
+                _ => syscall(num, args),
+            };
+            self.after(num, &args, &result);
+
+            if i % CLOSE_EVERY == 0 {
+                self.close_fds()
+                    .map_err(|e| format!("could not close the files: {e:?}"))?;
+            }
+            if i % HEARTBEAT_EVERY == 0 {
+                let last = SyscallTrace {
+                    number: num,
+                    registers: args,
+                };
+                heartbeat
+                    .check()
+                    .map_err(|e| format!("at iteration {i}, after {last}: {e}"))?;
+                if i % (HEARTBEAT_EVERY * 10) == 0 {
+                    println!("syscall_fuzz: {i} syscalls, {} succeeded", self.successes);
+                }
+            }
+        }
+        self.close_fds()
+            .map_err(|e| format!("could not close the files: {e:?}"))?;
+        // the deepest first, a mount with others inside can't be unmounted
+        for path in PATHS.iter().rev() {
+            unsafe { call_syscall!(SYS_UMOUNT, path.as_ptr() as u64).ok() };
+        }
+        heartbeat.check()
+    }
+}
+
+fn heartbeat() -> ExitCode {
+    let mut ping = [0u8; 1];
+    let mut stdout = std::io::stdout();
+    while std::io::stdin().read(&mut ping).is_ok_and(|n| n == 1) {
+        let mut info = SysInfo::default();
+        if unsafe { call_syscall!(SYS_SYSINFO, &mut info as *mut SysInfo as u64) }.is_err() {
+            return ExitCode::FAILURE;
+        }
+        if stdout.write_all(b"h").and_then(|_| stdout.flush()).is_err() {
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn is_invalid_arg(result: &SyscallResult, arg: usize, expected: SyscallArgError) -> bool {
+    let Err(SyscallError::InvalidArgument(a0, a1, a2, a3, a4, a5, a6)) = result else {
+        return false;
+    };
+    [a0, a1, a2, a3, a4, a5, a6]
+        .iter()
+        .enumerate()
+        .all(|(i, a)| {
+            if i == arg {
+                **a == Some(expected)
+            } else {
+                a.is_none()
+            }
+        })
+}
+
+fn check_regression(name: &str, result: SyscallResult, ok: fn(&SyscallResult) -> bool) -> bool {
+    if ok(&result) {
+        println!("regression {name}: ok");
+        true
+    } else {
+        println!("[!] regression {name}: got {result:?}");
+        false
+    }
+}
+
+/// Each of these crashed or hung the kernel before
+fn regressions() -> ExitCode {
+    let scratch = [0u8; 64];
+    let scratch_ptr = scratch.as_ptr() as u64;
+    let mappings = [SpawnFileMapping {
+        src_fd: 1,
+        dst_fd: usize::MAX,
+    }];
+    let argv = [c"/echo".as_ptr() as u64, 0];
+    let mut ok = true;
+
+    ok &= check_regression(
+        "write with ptr + len overflowing",
+        unsafe { call_syscall!(SYS_WRITE, 1, scratch_ptr, u64::MAX - scratch_ptr + 2) },
+        |r| is_invalid_arg(r, 1, SyscallArgError::InvalidUserPointer),
+    );
+    ok &= check_regression(
+        "sysinfo into the kernel",
+        unsafe { call_syscall!(SYS_SYSINFO, KERNEL_ADDRESS) },
+        |r| is_invalid_arg(r, 0, SyscallArgError::InvalidUserPointer),
+    );
+    ok &= check_regression(
+        "stat into read only memory",
+        unsafe { call_syscall!(SYS_STAT, 1, main as *const () as u64) },
+        |r| is_invalid_arg(r, 1, SyscallArgError::InvalidUserPointer),
+    );
+    ok &= check_regression(
+        "wait for init",
+        unsafe { call_syscall!(SYS_WAIT_PID, 0, 1) },
+        |r| matches!(r, Err(SyscallError::PidNotFound)),
+    );
+    ok &= check_regression(
+        "wait for itself",
+        unsafe { call_syscall!(SYS_WAIT_PID, std::process::id() as u64, 1) },
+        |r| matches!(r, Err(SyscallError::PidNotFound)),
+    );
+    ok &= check_regression(
+        "spawn with a huge dst_fd",
+        unsafe {
+            call_syscall!(
+                SYS_SPAWN,
+                argv[0],
+                argv.as_ptr() as u64,
+                mappings.as_ptr() as u64,
+                mappings.len() as u64
+            )
+        },
+        |r| is_invalid_arg(r, 2, SyscallArgError::GeneralInvalid),
+    );
+
+    match File::options().write(true).open("/devices/keyboard_layout") {
+        Ok(file) => {
+            let fd = file.into_raw_fd() as u64;
+            let name = b"no_such_layout";
+            let result =
+                unsafe { call_syscall!(SYS_WRITE, fd, name.as_ptr() as u64, name.len() as u64) };
+            unsafe { call_syscall!(SYS_CLOSE, fd).ok() };
+            ok &= check_regression("write an unknown keyboard layout", result, |r| {
+                is_invalid_arg(r, 1, SyscallArgError::GeneralInvalid)
+            });
+        }
+        Err(e) => println!("[!] could not open the keyboard layout: {e}"),
+    }
+
+    // a string that ends at the end of the heap, nothing must allocate until its given back
+    match unsafe { call_syscall!(SYS_INC_HEAP, PAGE) } {
+        Ok(page) => {
+            unsafe { core::ptr::write_bytes(page as *mut u8, b'a', PAGE as usize) };
+            let result = unsafe { call_syscall!(SYS_OPEN, page + PAGE - 16, 0, 0) };
+            unsafe { call_syscall!(SYS_INC_HEAP, -(PAGE as i64) as u64) }
+                .expect("could not give back the heap");
+            ok &= check_regression(
+                "open a path without a null terminator at the end of the memory",
+                result,
+                |r| is_invalid_arg(r, 0, SyscallArgError::InvalidUserPointer),
+            );
+        }
+        Err(e) => println!("[!] could not grow the heap: {e:?}"),
+    }
+
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Syscall fuzzer shell program
+///
+/// Usage: syscall_fuzz [--seed N] [--iterations N] [--regressions]
+///
+/// Issues syscalls with pseudo-random numbers and arguments from the seed, so a run can be
+/// repeated, while a child process answers a heartbeat every few thousand syscalls to check
+/// the kernel is still responsive. The kernel must not crash or hang whatever the arguments.
+///
+/// The arguments are random, but not everything is allowed, so the fuzzer doesn't break itself:
+/// it never exits, the kernel only writes to a scratch buffer of ours, it never touches the
+/// files it had before starting, the heap is only grown and given back, and files and waits
+/// are never blocking. Paths are all under `/tmp/fuzz`.
+///
+/// `--regressions` runs the fixed cases that crashed or hung the kernel instead.
+fn main() -> ExitCode {
+    let mut seed = 1;
+    let mut iterations = DEFAULT_ITERATIONS;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().and_then(|v| v.parse::<u64>().ok());
+        match arg.as_str() {
+            "--heartbeat" => return heartbeat(),
+            "--regressions" => return regressions(),
+            "--seed" => match value() {
+                Some(v) => seed = v,
+                None => {
+                    println!("[!] --seed needs a number");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--iterations" => match value() {
+                Some(v) => iterations = v,
+                None => {
+                    println!("[!] --iterations needs a number");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => {
+                println!("[!] unknown argument: {arg}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let mut heartbeat = match Heartbeat::start() {
+        Ok(heartbeat) => heartbeat,
+        Err(e) => {
+            println!("[!] error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut fuzzer = match Fuzzer::new(seed, heartbeat.child.id() as u64) {
+        Ok(fuzzer) => fuzzer,
+        Err(e) => {
+            println!("[!] error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("syscall_fuzz: seed {seed}, {iterations} syscalls");
+    match fuzzer.run(iterations, &mut heartbeat) {
+        Ok(()) => {
+            println!(
+                "syscall_fuzz: done, {} of {iterations} syscalls succeeded",
+                fuzzer.successes
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] seed {seed}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,204 @@
+#![feature(restricted_std)]
+
+use std::{
+    hint::black_box,
+    process::{Command, ExitCode},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use kernel_user_link::syscalls::{SyscallArgError, SyscallError};
+use user_std::{
+    syscalls,
+    thread::{self, JoinHandle},
+};
+
+const PROGRAM_PATH: &str = "/threads";
+const DEFAULT_THREADS: usize = 4;
+// long enough for the timer to switch between the threads in the middle
+const INCREMENTS: u64 = 200_000;
+// written by the thread left running with `--last`
+const LAST_MESSAGE: &str = "done";
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+// the threads that stored their index in their TLS
+static STORED: AtomicUsize = AtomicUsize::new(0);
+
+fn usage() -> ExitCode {
+    println!("Usage: threads [-n threads] | --test");
+    ExitCode::FAILURE
+}
+
+fn spawn<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<JoinHandle<T>, String> {
+    // SAFETY: the threads don't use std, only the main one prints
+    unsafe { thread::spawn(f) }.map_err(|e| format!("spawn: {e:?}"))
+}
+
+/// Counts in [`COUNTER`] with `n` threads, they must not lose any increment
+fn test_counting(n: usize) -> Result<(), String> {
+    COUNTER.store(0, Ordering::SeqCst);
+    let handles = (0..n)
+        .map(|i| {
+            spawn(move || {
+                for _ in 0..INCREMENTS {
+                    COUNTER.fetch_add(1, Ordering::Relaxed);
+                }
+                i * 10
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(result) if result == i * 10 => {}
+            result => return Err(format!("thread {i} returned {result:?}")),
+        }
+    }
+    let count = COUNTER.load(Ordering::SeqCst);
+    if count != n as u64 * INCREMENTS {
+        return Err(format!("counted {count} of {}", n as u64 * INCREMENTS));
+    }
+    Ok(())
+}
+
+/// Each thread keeps its index in its TLS until all have stored theirs, none must see another
+fn test_tls(n: usize) -> Result<(), String> {
+    STORED.store(0, Ordering::SeqCst);
+    let handles = (0..n)
+        .map(|i| {
+            spawn(move || {
+                // SAFETY: we are a thread of `spawn`
+                let tls = unsafe { thread::tls() } as *mut u64;
+                let zeroed = unsafe { tls.read_volatile() } == 0;
+                unsafe { tls.write_volatile(i as u64 + 1) };
+                STORED.fetch_add(1, Ordering::SeqCst);
+                while STORED.load(Ordering::SeqCst) < n {
+                    black_box(());
+                }
+                zeroed && unsafe { tls.read_volatile() } == i as u64 + 1
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(true) => {}
+            result => return Err(format!("the TLS of thread {i} was wrong, {result:?}")),
+        }
+    }
+    Ok(())
+}
+
+/// A thread is joined once, and only the threads of the process can be
+fn test_join_rules() -> Result<(), String> {
+    let handle = spawn(|| ())?;
+    let id = handle.id();
+    handle.join().map_err(|e| format!("join: {e:?}"))?;
+    match unsafe { syscalls::thread_join(id) } {
+        Err(SyscallError::PidNotFound) => {}
+        result => return Err(format!("joining twice gave {result:?}")),
+    }
+    match unsafe { syscalls::thread_join(u64::MAX) } {
+        Err(SyscallError::PidNotFound) => {}
+        result => return Err(format!("joining a missing thread gave {result:?}")),
+    }
+    // the first thread has the id of the process
+    match unsafe { syscalls::thread_join(std::process::id() as u64) } {
+        Err(SyscallError::InvalidArgument(Some(SyscallArgError::GeneralInvalid), ..)) => {}
+        result => return Err(format!("joining ourselves gave {result:?}")),
+    }
+    match unsafe { syscalls::thread_create(0, 0, 0, 0) } {
+        Err(SyscallError::InvalidArgument(Some(SyscallArgError::InvalidUserPointer), ..)) => {}
+        result => return Err(format!("a null entry gave {result:?}")),
+    }
+    Ok(())
+}
+
+/// The first thread exits before the other, the process exits when that one does
+fn test_last_thread() -> Result<(), String> {
+    let out = "/tmp/threads_last";
+    std::fs::remove_file(out).ok();
+    let status = Command::new(PROGRAM_PATH)
+        .args(["--last", out])
+        .status()
+        .map_err(|e| format!("spawn: {e}"))?;
+    if !status.success() {
+        return Err(format!("the process exited with {status:?}"));
+    }
+    let message = std::fs::read_to_string(out).map_err(|e| format!("{out}: {e}"))?;
+    std::fs::remove_file(out).map_err(|e| format!("{out}: {e}"))?;
+    if message != LAST_MESSAGE {
+        return Err(format!("the last thread wrote {message:?}"));
+    }
+    Ok(())
+}
+
+/// For `--last`, leaves a thread that writes `out` once the first one exited
+fn last(out: String) -> ExitCode {
+    let spawned = spawn(move || {
+        // by then, the first thread exited, so std is ours
+        for _ in 0..INCREMENTS {
+            black_box(());
+        }
+        std::fs::write(&out, LAST_MESSAGE).ok();
+    });
+    if let Err(e) = spawned {
+        println!("[!] threads: {e}");
+        return ExitCode::FAILURE;
+    }
+    unsafe { syscalls::thread_exit().unwrap() };
+    unreachable!("thread_exit syscall should not return")
+}
+
+fn self_test() -> Result<(), String> {
+    test_counting(DEFAULT_THREADS)?;
+    test_tls(DEFAULT_THREADS)?;
+    test_join_rules()?;
+    test_last_thread()
+}
+
+/// Threads shell program
+///
+/// Usage: threads [-n threads]
+///        threads --last <out_path>
+///        threads --test
+///
+/// Counts with `threads` threads (4 by default) in the same variable and prints the total.
+/// `--test` checks that no increment is lost, that each thread has its own TLS and that the
+/// results are returned by the joins, the rules of the joins, and that the process runs until
+/// its last thread exits, with `--last`, which exits the first thread and leaves one that
+/// writes to `out_path`
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let n = match args.as_slice() {
+        [] => DEFAULT_THREADS,
+        ["-n", n] => match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => return usage(),
+        },
+        ["--last", out] => return last(out.to_string()),
+        ["--test"] => {
+            return match self_test() {
+                Ok(()) => {
+                    println!("threads: test passed");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("[!] threads test failed: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ => return usage(),
+    };
+    match test_counting(n) {
+        Ok(()) => {
+            println!("{}", COUNTER.load(Ordering::SeqCst));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("[!] threads: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
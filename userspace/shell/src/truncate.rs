@@ -0,0 +1,99 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode};
+
+use kernel_user_link::{
+    call_syscall,
+    syscalls::{SyscallError, SYS_CLOSE, SYS_FTRUNCATE, SYS_OPEN, SYS_TRUNCATE},
+};
+
+fn usage() -> ExitCode {
+    println!("Usage: truncate [-o] -s <size> <file>...");
+    ExitCode::FAILURE
+}
+
+/// Parses sizes like `512`, `4K` or `2M`
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, multiplier) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 1 << 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 1 << 20),
+        b'G' | b'g' => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn truncate_path(path: &CString, size: u64) -> Result<(), SyscallError> {
+    unsafe {
+        call_syscall!(
+            SYS_TRUNCATE,
+            path.as_ptr() as u64, // path
+            size,                 // len
+        )
+        .map(|_| ())
+    }
+}
+
+/// Opens the file and truncates it with `SYS_FTRUNCATE`
+fn truncate_open(path: &CString, size: u64) -> Result<(), SyscallError> {
+    unsafe {
+        let fd = call_syscall!(
+            SYS_OPEN,
+            path.as_ptr() as u64, // path
+            0,                    // access_mode
+            0,                    // flags
+        )?;
+        let result = call_syscall!(
+            SYS_FTRUNCATE,
+            fd,   // fd
+            size, // len
+        );
+        call_syscall!(SYS_CLOSE, fd).ok();
+        result.map(|_| ())
+    }
+}
+
+/// Truncate shell program
+///
+/// Usage: truncate [-o] -s <size> <file>...
+///
+/// Sets the size of each file, cutting it or extending it with zeros. The size can end with
+/// `K`, `M` or `G`. With `-o`, the file is opened and truncated through the open file
+fn main() -> ExitCode {
+    let mut through_fd = false;
+    let mut size = None;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => through_fd = true,
+            "-s" => match args.next().as_deref().and_then(parse_size) {
+                Some(parsed) => size = Some(parsed),
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => files.push(arg),
+        }
+    }
+    let Some(size) = size else {
+        return usage();
+    };
+    if files.is_empty() {
+        return usage();
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for file in &files {
+        let path = CString::new(file.as_str()).unwrap();
+        let result = if through_fd {
+            truncate_open(&path, size)
+        } else {
+            truncate_path(&path, size)
+        };
+        if let Err(e) = result {
+            println!("[!] truncate: {file}: {e:?}");
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
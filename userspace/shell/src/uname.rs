@@ -0,0 +1,42 @@
+#![feature(restricted_std)]
+
+use std::process::ExitCode;
+
+use kernel_user_link::{call_syscall, syscalls::SYS_SYSINFO, sysinfo::SysInfo};
+
+const VERSION_PATH: &str = "/devices/version";
+
+/// Uname shell program
+///
+/// Usage: uname [-a]
+///
+/// Prints the version of the running kernel from `SYS_SYSINFO`, `-a` prints all the build
+/// information of `/devices/version` after it
+fn main() -> ExitCode {
+    let all = match std::env::args().nth(1).as_deref() {
+        None => false,
+        Some("-a") => true,
+        Some(_) => {
+            println!("Usage: uname [-a]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut info = SysInfo::default();
+    if let Err(e) = unsafe { call_syscall!(SYS_SYSINFO, &mut info as *mut SysInfo as u64) } {
+        println!("[!] error: sysinfo: {e:?}");
+        return ExitCode::FAILURE;
+    }
+    println!("Amjad50/OS {} (ABI {})", info.version(), info.abi_version);
+
+    if all {
+        match std::fs::read_to_string(VERSION_PATH) {
+            Ok(build_info) => print!("{build_info}"),
+            Err(e) => {
+                println!("[!] error: {VERSION_PATH}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,299 @@
+#![feature(restricted_std)]
+
+use std::{ffi::CString, process::ExitCode, time::Duration};
+
+use kernel_user_link::syscalls::SyscallError;
+use user_std::{
+    fs,
+    io::{
+        self, BlockingMode, WatchEvent, OPEN_CREATE, WATCH_ALL, WATCH_CREATE, WATCH_DELETE,
+        WATCH_IS_DIR, WATCH_MODIFY, WATCH_MOVED_FROM, WATCH_MOVED_TO, WATCH_OVERFLOW,
+    },
+    time::{self, Instant},
+};
+
+// how long the expected events can take to come
+const EVENTS_TIMEOUT: Duration = Duration::from_secs(3);
+// the sleep between two tries of waiting for a path
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+fn usage() -> ExitCode {
+    println!(
+        "Usage: watch [-m path] [-W path] [-e events] <dir>... | [-W path] [-m path] (-r | -g count) <dir>"
+    );
+    ExitCode::FAILURE
+}
+
+fn cstring(path: &str) -> CString {
+    CString::new(path).unwrap()
+}
+
+fn open(path: &str, flags: u64) -> Result<usize, SyscallError> {
+    unsafe { io::syscall_open(&cstring(path), 0, flags as usize) }
+}
+
+fn close(fd: usize) {
+    unsafe { io::syscall_close(fd).ok() };
+}
+
+fn create_file(path: &str) -> Result<(), SyscallError> {
+    open(path, OPEN_CREATE).map(close)
+}
+
+/// Waits for another process to create `path`, it sleeps between tries
+fn wait_for(path: &str) {
+    loop {
+        if let Ok(fd) = open(path, 0) {
+            close(fd);
+            return;
+        }
+        time::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// `kind:name` as in the `-e` list, with a `/` after the names of directories
+fn describe(event: &WatchEvent) -> String {
+    if event.mask & WATCH_OVERFLOW != 0 {
+        return format!("overflow:{}", event.cookie);
+    }
+    let kind = match event.mask & WATCH_ALL {
+        WATCH_CREATE => "create",
+        WATCH_DELETE => "delete",
+        WATCH_MOVED_FROM => "moved_from",
+        WATCH_MOVED_TO => "moved_to",
+        WATCH_MODIFY => "modify",
+        _ => "unknown",
+    };
+    let dir = if event.mask & WATCH_IS_DIR != 0 {
+        "/"
+    } else {
+        ""
+    };
+    format!("{kind}:{}{dir}", event.name())
+}
+
+/// Parses the `-e` list, `kind:name#count` stands for `count` events with the names
+/// `name0` to `name<count - 1>`
+fn parse_expected(list: &str) -> Option<Vec<String>> {
+    let mut expected = Vec::new();
+    for item in list.split(',') {
+        match item.split_once('#') {
+            Some((prefix, count)) => {
+                let count = count.parse::<usize>().ok()?;
+                expected.extend((0..count).map(|i| format!("{prefix}{i}")));
+            }
+            None => expected.push(String::from(item)),
+        }
+    }
+    Some(expected)
+}
+
+/// Reads the events queued in `fd` without waiting, until there are none
+fn read_events(fd: usize) -> Result<Vec<WatchEvent>, SyscallError> {
+    let mut buf = [0u8; WatchEvent::SIZE * 4];
+    let mut events = Vec::new();
+    loop {
+        let read = unsafe { io::syscall_read(fd, &mut buf) }? as usize;
+        if read == 0 {
+            return Ok(events);
+        }
+        events.extend(
+            buf[..read]
+                .chunks(WatchEvent::SIZE)
+                .filter_map(WatchEvent::from_bytes),
+        );
+    }
+}
+
+/// Prints the events of `fd` as they come, until `expected` were read or they took too
+/// long, then checks them, and that each `moved_to` has the cookie of the `moved_from` before
+fn check_events(fd: usize, expected: &[String]) -> Result<bool, SyscallError> {
+    let start = Instant::now();
+    let mut events: Vec<WatchEvent> = Vec::new();
+    while events.len() < expected.len() && start.elapsed() < EVENTS_TIMEOUT {
+        for event in read_events(fd)? {
+            println!(
+                "wd {} {} cookie {}",
+                event.wd,
+                describe(&event),
+                event.cookie
+            );
+            events.push(event);
+        }
+    }
+
+    let got: Vec<String> = events.iter().map(describe).collect();
+    if got != expected {
+        println!("[!] watch: expected {expected:?}");
+        return Ok(false);
+    }
+    for pair in events.windows(2) {
+        let [from, to] = pair else { unreachable!() };
+        if to.mask & WATCH_MOVED_TO != 0
+            && (from.mask & WATCH_MOVED_FROM == 0 || from.cookie == 0 || from.cookie != to.cookie)
+        {
+            println!(
+                "[!] watch: {} is not paired with the event before",
+                describe(to)
+            );
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Watches the directories, reads the events after `wait` exists
+fn observe(
+    dirs: &[String],
+    marker: Option<&str>,
+    wait: Option<&str>,
+    expected: Option<&[String]>,
+) -> Result<bool, SyscallError> {
+    let fd = unsafe { io::syscall_watch_create() }?;
+    unsafe { io::syscall_blocking_mode(fd, BlockingMode::None) }?;
+    for dir in dirs {
+        let wd = unsafe { io::syscall_watch_add(fd, &cstring(dir), WATCH_ALL) }?;
+        println!("wd {wd}: {dir}");
+    }
+    if let Some(marker) = marker {
+        create_file(marker)?;
+    }
+    if let Some(path) = wait {
+        wait_for(path);
+    }
+
+    let Some(expected) = expected else {
+        loop {
+            for event in read_events(fd)? {
+                println!(
+                    "wd {} {} cookie {}",
+                    event.wd,
+                    describe(&event),
+                    event.cookie
+                );
+            }
+        }
+    };
+    let result = check_events(fd, expected);
+    close(fd);
+    result
+}
+
+/// Creates, changes, renames and removes a file, and a directory, in `dir`
+fn run_changes(dir: &str) -> Result<(), SyscallError> {
+    let path = |name: &str| format!("{}/{name}", dir.trim_end_matches('/'));
+    create_file(&path("A.TXT"))?;
+    fs::truncate(&cstring(&path("A.TXT")), 5)?;
+    fs::rename(&cstring(&path("A.TXT")), &cstring(&path("B.TXT")))?;
+    fs::create_dir(&cstring(&path("D")))?;
+    fs::remove_dir(&cstring(&path("D")))?;
+    fs::remove_file(&cstring(&path("B.TXT")))
+}
+
+/// Creates the files `n0` to `n<count - 1>` in `dir`, then removes them
+fn generate(dir: &str, count: usize) -> Result<(), SyscallError> {
+    let path = |i: usize| format!("{}/n{i}", dir.trim_end_matches('/'));
+    for i in 0..count {
+        create_file(&path(i))?;
+    }
+    for i in 0..count {
+        fs::remove_file(&cstring(&path(i)))?;
+    }
+    Ok(())
+}
+
+/// Watch shell program
+///
+/// Usage: watch [-m path] [-W path] [-e events] <dir>...
+///
+/// Watches the directories and prints their events, `wd <wd> <kind>:<name> cookie <cookie>`:
+/// - `-m path`: creates the path once it is watching
+/// - `-W path`: waits for the path to exist before reading, so the events can fill the queue
+/// - `-e events`: reads until there are this many events, then checks they are these ones,
+///   a list of `kind:name` separated by `,`, in order. The kinds are `create`, `delete`,
+///   `moved_from`, `moved_to`, `modify` and `overflow` (with the number dropped as the
+///   name), the names of directories end with `/`, and `kind:name#count` stands for `count`
+///   events named `name0` and so on. Without it, it prints the events until killed
+///
+/// Usage: watch [-W path] [-m path] (-r | -g count) <dir>
+///
+/// Makes the changes to watch, after waiting for `-W` and before creating `-m`:
+/// - `-r`: creates `A.TXT`, truncates it, renames it to `B.TXT`, creates and removes the
+///   directory `D`, then removes `B.TXT`
+/// - `-g count`: creates the files `n0` to `n<count - 1>`, then removes them
+fn main() -> ExitCode {
+    let mut marker = None;
+    let mut wait = None;
+    let mut expected = None;
+    let mut changes = false;
+    let mut generated = None;
+    let mut dirs = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" => changes = true,
+            "-m" | "-W" | "-e" | "-g" => {
+                let Some(value) = args.next() else {
+                    return usage();
+                };
+                match arg.as_str() {
+                    "-m" => marker = Some(value),
+                    "-W" => wait = Some(value),
+                    "-e" => match parse_expected(&value) {
+                        Some(list) => expected = Some(list),
+                        None => return usage(),
+                    },
+                    _ => match value.parse::<usize>() {
+                        Ok(count) => generated = Some(count),
+                        Err(_) => return usage(),
+                    },
+                }
+            }
+            _ if arg.starts_with('-') => return usage(),
+            _ => dirs.push(arg),
+        }
+    }
+    if dirs.is_empty() {
+        return usage();
+    }
+
+    if changes || generated.is_some() {
+        let [dir] = dirs.as_slice() else {
+            return usage();
+        };
+        if expected.is_some() || (changes && generated.is_some()) {
+            return usage();
+        }
+        if let Some(path) = &wait {
+            wait_for(path);
+        }
+        let result = match generated {
+            Some(count) => generate(dir, count),
+            None => run_changes(dir),
+        };
+        if let Err(e) = result {
+            println!("[!] watch: {dir}: {e:?}");
+            return ExitCode::FAILURE;
+        }
+        if let Some(marker) = &marker {
+            if let Err(e) = create_file(marker) {
+                println!("[!] watch: {marker}: {e:?}");
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    match observe(
+        &dirs,
+        marker.as_deref(),
+        wait.as_deref(),
+        expected.as_deref(),
+    ) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            println!("[!] watch: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}